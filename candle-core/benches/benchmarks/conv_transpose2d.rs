@@ -11,7 +11,7 @@ fn run(
     stride: usize,
     dilation: usize,
 ) {
-    x.conv_transpose2d(k, padding, output_padding, stride, dilation)
+    x.conv_transpose2d(k, padding, output_padding, stride, dilation, 1)
         .unwrap();
 }
 
@@ -0,0 +1,185 @@
+//! Tensor exchange with other libraries in the same process using the
+//! [CUDA array interface](https://numba.readthedocs.io/en/stable/cuda/cuda_array_interface.html).
+//!
+//! [`Tensor::array_interface`] describes the memory of a cuda tensor, its device pointer, shape,
+//! byte strides, element type and the stream the tensor is computed on, without copying it. The
+//! descriptor maps one to one to the `__cuda_array_interface__` dictionary (version 3) that
+//! CuPy, Numba, PyTorch or TensorRT consume. Cpu tensors are described in the same way, which
+//! corresponds to the numpy `__array_interface__`. The descriptor does not keep the tensor alive,
+//! the tensor has to outlive the consumer's use of the memory.
+//!
+//! [`Tensor::from_array_interface`] imports a tensor described by such a descriptor without
+//! copying it, the caller provides an owner that keeps the memory alive for as long as the
+//! imported tensor and its views are used. The kernels queued by candle wait for the producer's
+//! stream, without blocking the host.
+//!
+//! ```ignore
+//! let ai = tensor.array_interface()?;
+//! // Pass the device pointer to a custom kernel or another library.
+//! let ptr = ai.data as *mut std::ffi::c_void;
+//! ```
+use crate::{DType, Device, DeviceLocation, Result, Storage, Tensor};
+
+/// A description of the memory of a tensor, see the module documentation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArrayInterface {
+    pub shape: Vec<usize>,
+    /// The numpy type string of the elements, e.g. `<f4`.
+    pub typestr: String,
+    /// The address of the first element, a device pointer for cuda tensors.
+    pub data: u64,
+    pub read_only: bool,
+    /// The strides in bytes, `None` for contiguous row-major tensors.
+    pub strides: Option<Vec<i64>>,
+    /// The cuda stream on which the data is produced and that consumers have to synchronize
+    /// with, `None` when no synchronization is required. As in the specification, 1 is the
+    /// legacy default stream and 2 the per-thread default stream.
+    pub stream: Option<u64>,
+    pub version: u32,
+}
+
+/// The numpy type string for `dtype`. bf16 has no numpy equivalent and is not supported.
+pub fn typestr(dtype: DType) -> Result<&'static str> {
+    let typestr = match dtype {
+        DType::U8 => "|u1",
+        DType::U32 => "<u4",
+        DType::I64 => "<i8",
+        DType::F16 => "<f2",
+        DType::F32 => "<f4",
+        DType::F64 => "<f8",
+        DType::BF16 => crate::bail!("bf16 cannot be described by an array interface"),
+    };
+    Ok(typestr)
+}
+
+/// The dtype for a numpy type string, booleans are mapped to u8.
+pub fn dtype_from_typestr(typestr: &str) -> Result<DType> {
+    let (byte_order, kind) = match typestr.as_bytes() {
+        [b, rest @ ..] if b"<>|=".contains(b) => (*b, rest),
+        _ => crate::bail!("invalid array interface type string {typestr}"),
+    };
+    let dtype = match kind {
+        b"u1" | b"b1" => DType::U8,
+        b"u4" => DType::U32,
+        b"i8" => DType::I64,
+        b"f2" => DType::F16,
+        b"f4" => DType::F32,
+        b"f8" => DType::F64,
+        _ => crate::bail!("unsupported array interface type string {typestr}"),
+    };
+    if byte_order == b'>' && dtype.size_in_bytes() > 1 {
+        crate::bail!("big endian data is not supported, got {typestr}")
+    }
+    Ok(dtype)
+}
+
+impl ArrayInterface {
+    pub fn dtype(&self) -> Result<DType> {
+        dtype_from_typestr(&self.typestr)
+    }
+
+    /// The strides in number of elements, computed from the shape for contiguous tensors.
+    pub fn elem_strides(&self) -> Result<Vec<i64>> {
+        let size = self.dtype()?.size_in_bytes() as i64;
+        match &self.strides {
+            None => {
+                let stride = crate::Shape::from(self.shape.as_slice()).stride_contiguous();
+                Ok(stride.into_iter().map(|s| s as i64).collect())
+            }
+            Some(strides) => {
+                if strides.len() != self.shape.len() {
+                    crate::bail!(
+                        "array interface with {} strides for shape {:?}",
+                        strides.len(),
+                        self.shape
+                    )
+                }
+                if let Some(s) = strides.iter().find(|&&s| s % size != 0) {
+                    crate::bail!("array interface stride {s} is not a multiple of {size}")
+                }
+                Ok(strides.iter().map(|s| s / size).collect())
+            }
+        }
+    }
+}
+
+impl Tensor {
+    /// Describes the memory of the tensor without copying it, see the
+    /// [module](crate::array_interface) documentation.
+    pub fn array_interface(&self) -> Result<ArrayInterface> {
+        let typestr = typestr(self.dtype())?;
+        let (base, stream) = match &*self.storage() {
+            Storage::Cpu(storage) => (crate::dlpack::cpu_data_ptr(storage) as u64, None),
+            #[cfg(feature = "cuda")]
+            Storage::Cuda(storage) => {
+                let stream = *storage.device.cu_stream() as u64;
+                // The specification reserves 0, the legacy default stream is 1.
+                let stream = if stream == 0 { 1 } else { stream };
+                (crate::dlpack::cuda_data_ptr(storage) as u64, Some(stream))
+            }
+            _ => crate::bail!("array interface is not supported on {:?}", self.device()),
+        };
+        let layout = self.layout();
+        let size = self.dtype().size_in_bytes();
+        let strides = if layout.is_contiguous() {
+            None
+        } else {
+            Some(layout.stride().iter().map(|&s| (s * size) as i64).collect())
+        };
+        Ok(ArrayInterface {
+            shape: self.dims().to_vec(),
+            typestr: typestr.to_string(),
+            data: base + (layout.start_offset() * size) as u64,
+            read_only: false,
+            strides,
+            stream,
+            version: 3,
+        })
+    }
+
+    /// Imports the tensor described by `ai` on `device` without copying its data, the tensor
+    /// keeps the strides of the descriptor. `owner` is dropped once the tensor and its views
+    /// have been dropped, it should keep the memory alive, e.g. a reference to the producing
+    /// python object. The data is copied when it cannot be shared: negative strides or a pointer
+    /// that is not aligned for its dtype. Cuda pointers must belong to the context of `device`.
+    ///
+    /// Read-only memory is shared as well, in-place operations must not be used on the result.
+    ///
+    /// # Safety
+    ///
+    /// `ai` must describe valid memory on `device`, which has to stay valid until `owner` is
+    /// dropped.
+    pub unsafe fn from_array_interface<O: Send + Sync + 'static>(
+        ai: &ArrayInterface,
+        device: &Device,
+        owner: O,
+    ) -> Result<Tensor> {
+        let dtype = ai.dtype()?;
+        let strides = ai.elem_strides()?;
+        if ai.data == 0 && ai.shape.iter().product::<usize>() != 0 {
+            crate::bail!("null pointer in array interface")
+        }
+        match (device, ai.stream) {
+            #[cfg(feature = "cuda")]
+            (Device::Cuda(dev), Some(stream)) => {
+                let stream = stream as cudarc::driver::sys::CUstream;
+                dev.record_event_on(stream)?.wait()?
+            }
+            (Device::Cpu, Some(_)) => crate::bail!("unexpected stream in a cpu array interface"),
+            _ => {}
+        }
+        let location = match device.location() {
+            location @ (DeviceLocation::Cpu | DeviceLocation::Cuda { .. }) => location,
+            location => crate::bail!("array interface import is not supported on {location:?}"),
+        };
+        crate::dlpack::import_strided(
+            ai.data as *mut u8,
+            dtype,
+            &ai.shape,
+            &strides,
+            location,
+            device,
+            Box::new(move || drop(owner)),
+        )
+    }
+}
@@ -27,6 +27,61 @@ thread_local! {
     }
 }
 
+thread_local! {
+    static ANOMALY_MODE: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+}
+
+/// Enables or disables the anomaly mode for the current thread, returning the previous state.
+///
+/// In anomaly mode, the backtrace of the creation of each op is recorded and the backward pass
+/// checks the gradients produced by each op, returning an [`Error::AnomalousGradient`] that
+/// points at the forward op when a NaN or infinite gradient appears. This is similar to
+/// `torch.autograd.set_detect_anomaly` and slows down both passes significantly, so it should
+/// only be used for debugging.
+pub fn set_detect_anomaly(enabled: bool) -> bool {
+    ANOMALY_MODE.with(|m| m.replace(enabled))
+}
+
+pub fn is_anomaly_enabled() -> bool {
+    ANOMALY_MODE.with(|m| m.get())
+}
+
+/// Enables the anomaly mode until dropped, see [`set_detect_anomaly`].
+///
+/// ```rust
+/// use candle_core::{backprop::DetectAnomaly, Device, Var};
+/// let x = Var::new(&[0f32, 1.], &Device::Cpu)?;
+/// let _guard = DetectAnomaly::new();
+/// let err = x.sqrt()?.sum_all()?.backward().unwrap_err();
+/// assert!(err.to_string().contains("sqrt"));
+/// # Ok::<(), candle_core::Error>(())
+/// ```
+pub struct DetectAnomaly {
+    previous: bool,
+}
+
+impl DetectAnomaly {
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        Self {
+            previous: set_detect_anomaly(true),
+        }
+    }
+}
+
+impl Drop for DetectAnomaly {
+    fn drop(&mut self) {
+        set_detect_anomaly(self.previous);
+    }
+}
+
+// Returns true when some of the values are NaN or infinite, `0 * x` is only non-zero for these.
+fn has_non_finite(t: &Tensor) -> Result<bool> {
+    let v = t.detach().affine(0., 0.)?.sum_all()?;
+    let v = v.to_dtype(crate::DType::F32)?;
+    Ok(v.to_scalar::<f32>()? != 0.)
+}
+
 impl Tensor {
     /// Return all the nodes that lead to this value in a topologically sorted vec, the first
     /// elements having dependencies on the latter ones, e.g. the first element if any is the
@@ -127,6 +182,7 @@ impl Tensor {
                     | Op::Transpose(node, _, _)
                     | Op::Permute(node, _)
                     | Op::Narrow(node, _, _, _)
+                    | Op::NarrowStep(node, _, _, _, _)
                     | Op::Unary(node, _)
                     | Op::Elu(node, _)
                     | Op::Powf(node, _)
@@ -161,8 +217,12 @@ impl Tensor {
     }
 
     pub fn backward(&self) -> Result<GradStore> {
+        let anomaly_mode = is_anomaly_enabled();
         let sorted_nodes = self.sorted_nodes();
         let mut grads = GradStore::new();
+        if anomaly_mode {
+            grads.produced = Some(vec![]);
+        }
         grads.insert(self, self.ones_like()?.contiguous()?);
         for node in sorted_nodes.iter() {
             if node.is_variable() {
@@ -280,18 +340,23 @@ impl Tensor {
                     } => {
                         // The output height for conv_transpose2d is:
                         // (i_h - 1) * stride - 2 * padding + dilation * (k_h - 1) + out_padding + 1
-                        let grad_h = grad.dim(2)?;
-                        let k_h = kernel.dim(2)?;
-                        let out_size =
-                            (grad_h - 1) * stride + dilation * (k_h - 1) + 1 - 2 * padding;
-                        let out_padding = arg.dim(2)? - out_size;
+                        // The output padding can differ between the height and the width, the
+                        // largest one is used and the result is narrowed to the input size.
+                        let (_, _, grad_h, grad_w) = grad.dims4()?;
+                        let (_, _, k_h, k_w) = kernel.dims4()?;
+                        let (_, _, i_h, i_w) = arg.dims4()?;
+                        let out_h = (grad_h - 1) * stride + dilation * (k_h - 1) + 1 - 2 * padding;
+                        let out_w = (grad_w - 1) * stride + dilation * (k_w - 1) + 1 - 2 * padding;
+                        let out_padding = usize::max(i_h - out_h, i_w - out_w);
                         let grad_arg = grad.conv_transpose2d(
                             kernel,
                             *padding,
                             out_padding,
                             *stride,
                             *dilation,
+                            /* groups */ 1,
                         )?;
+                        let grad_arg = grad_arg.narrow(2, 0, i_h)?.narrow(3, 0, i_w)?;
                         let sum_grad = grads.or_insert(arg)?;
                         *sum_grad = sum_grad.add(&grad_arg)?;
 
@@ -309,9 +374,32 @@ impl Tensor {
                         };
                         *sum_grad = sum_grad.add(&grad_kernel)?;
                     }
-                    Op::ConvTranspose1D { .. } => Err(Error::BackwardNotSupported {
-                        op: "conv-transpose1d",
-                    })?,
+                    Op::ConvTranspose1D {
+                        arg,
+                        kernel,
+                        padding,
+                        stride,
+                        dilation,
+                        output_padding: _output_padding,
+                    } => {
+                        let grad_arg = grad.conv1d(kernel, *padding, *stride, *dilation, 1)?;
+                        let sum_grad = grads.or_insert(arg)?;
+                        *sum_grad = sum_grad.add(&grad_arg)?;
+
+                        let grad_kernel = grad
+                            .transpose(0, 1)?
+                            .conv1d(&arg.transpose(0, 1)?, *padding, *dilation, *stride, 1)?
+                            .transpose(0, 1)?;
+                        let sum_grad = grads.or_insert(kernel)?;
+                        let (_, _, k0) = kernel.dims3()?;
+                        let (_, _, g_k0) = grad_kernel.dims3()?;
+                        let grad_kernel = if g_k0 != k0 {
+                            grad_kernel.narrow(2, 0, k0)?
+                        } else {
+                            grad_kernel
+                        };
+                        *sum_grad = sum_grad.add(&grad_kernel)?;
+                    }
                     Op::ConvTranspose2D {
                         arg,
                         kernel,
@@ -578,6 +666,16 @@ impl Tensor {
                         let sum_grad = grads.or_insert(arg)?;
                         *sum_grad = sum_grad.add(&arg_grad)?
                     }
+                    &Op::NarrowStep(ref arg, dim, start_idx, len, step) => {
+                        let indexes = (0..len)
+                            .map(|i| (start_idx + i * step) as u32)
+                            .collect::<Vec<_>>();
+                        let indexes = Tensor::from_vec(indexes, len, grad.device())?;
+                        let arg_grad = Tensor::zeros(arg.dims(), grad.dtype(), grad.device())?
+                            .index_add(&indexes, &grad, dim)?;
+                        let sum_grad = grads.or_insert(arg)?;
+                        *sum_grad = sum_grad.add(&arg_grad)?
+                    }
                     Op::Unary(_, UnaryOp::Floor)
                     | Op::Unary(_, UnaryOp::Round)
                     | Op::Reduce(_, ReduceOp::ArgMin, _)
@@ -707,47 +805,82 @@ impl Tensor {
                         *sum_grad = sum_grad.add(&arg_grad)?
                     }
                 };
+                // The gradients were all finite before processing this node, so a non-finite
+                // gradient has been produced by the backward of its op. Only the gradients
+                // updated by this node are checked, so each update is only checked once.
+                if let Some(produced) = grads.produced.as_mut() {
+                    for id in std::mem::take(produced) {
+                        let grad = match grads.grads.get(&id) {
+                            Some(grad) => grad,
+                            None => continue,
+                        };
+                        if has_non_finite(grad)? {
+                            let trace = match node.op_trace() {
+                                Some(trace) => trace.to_string(),
+                                None => "<not recorded, the op was created before enabling the anomaly mode>".to_string(),
+                            };
+                            Err(Error::AnomalousGradient {
+                                op: op.name(),
+                                trace,
+                            })?
+                        }
+                    }
+                }
             }
         }
+        grads.produced = None;
         Ok(grads)
     }
 }
 
 /// A store for gradients, associating a tensor id to the corresponding gradient tensor, used for back propagation.
 #[derive(Debug)]
-pub struct GradStore(HashMap<TensorId, Tensor>);
+pub struct GradStore {
+    grads: HashMap<TensorId, Tensor>,
+    // The ids of the gradients updated since the last anomaly check, only tracked in anomaly
+    // mode.
+    produced: Option<Vec<TensorId>>,
+}
 
 impl GradStore {
     /// Create a new gradient store
     fn new() -> Self {
-        GradStore(HashMap::new())
+        GradStore {
+            grads: HashMap::new(),
+            produced: None,
+        }
     }
 
     /// Get the gradient tensor corresponding to the given tensor id
     pub fn get_id(&self, id: TensorId) -> Option<&Tensor> {
-        self.0.get(&id)
+        self.grads.get(&id)
     }
 
     /// Get the gradient tensor associated with the given tensor
     pub fn get(&self, tensor: &Tensor) -> Option<&Tensor> {
-        self.0.get(&tensor.id())
+        self.grads.get(&tensor.id())
     }
 
     /// Remove the gradient tensor associated with the given tensor, returning it if it exists
     pub fn remove(&mut self, tensor: &Tensor) -> Option<Tensor> {
-        self.0.remove(&tensor.id())
+        self.grads.remove(&tensor.id())
     }
 
     /// Insert a gradient tensor associated with the given tensor, returning the previous gradient tensor if it existed
     pub fn insert(&mut self, tensor: &Tensor, grad: Tensor) -> Option<Tensor> {
-        self.0.insert(tensor.id(), grad)
+        self.grads.insert(tensor.id(), grad)
     }
 
     /// Get the gradient tensor associated with the given tensor, or, if it does not exist,
     /// insert a tensor of zeroes, with the same shape and type as the given tensors and return it
     fn or_insert(&mut self, tensor: &Tensor) -> Result<&mut Tensor> {
         use std::collections::hash_map::Entry;
-        let grad = match self.0.entry(tensor.id()) {
+        if let Some(produced) = self.produced.as_mut() {
+            if !produced.contains(&tensor.id()) {
+                produced.push(tensor.id())
+            }
+        }
+        let grad = match self.grads.entry(tensor.id()) {
             Entry::Occupied(entry) => entry.into_mut(),
             Entry::Vacant(entry) => {
                 let grad = tensor.zeros_like()?;
@@ -759,6 +892,6 @@ impl GradStore {
 
     /// Get the tensor ids of the stored gradient tensors
     pub fn get_ids(&self) -> impl Iterator<Item = &TensorId> {
-        self.0.keys()
+        self.grads.keys()
     }
 }
@@ -0,0 +1,37 @@
+//! Cooperative cancellation for long running jobs.
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// A token shared between a long running job, e.g. a generation or training loop, and the code
+/// that may want to abort it such as a server handling a client disconnection.
+///
+/// Cancellation is cooperative: the job checks the token between its steps, stops and returns
+/// what it has produced so far. The tensors of the job are dropped as usual so no device memory
+/// is leaked. The clones of a token share the same state.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Requests the cancellation of the jobs using this token.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed)
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Returns `Error::Cancelled` if the cancellation has been requested, this can be used with
+    /// `?` in jobs that have no partial result to return.
+    pub fn check(&self) -> crate::Result<()> {
+        if self.is_cancelled() {
+            Err(crate::Error::Cancelled.bt())
+        } else {
+            Ok(())
+        }
+    }
+}
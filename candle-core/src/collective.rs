@@ -0,0 +1,453 @@
+//! Collective communication primitives, typically used for tensor parallelism.
+//!
+//! The `Communicator` trait abstracts a group of processes (or threads) that each own a rank and
+//! exchange tensors. An NCCL based implementation is available for cuda devices when the `nccl`
+//! feature is enabled, `LocalCommunicator` provides an in-process implementation where each
+//! rank runs on its own thread and `TcpCommunicator` exchanges tensors between processes over tcp.
+//!
+//! `init_process_group` creates a communicator for the current process given a rendezvous
+//! address, similar to `torch.distributed.init_process_group`.
+use crate::{Device, Result, Tensor};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Barrier, Mutex};
+
+/// The reduction applied by `all_reduce` and `reduce_scatter`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReduceOp {
+    Sum,
+    Prod,
+    Max,
+    Min,
+    Avg,
+}
+
+impl ReduceOp {
+    /// Reduces a non-empty list of tensors with the same shape.
+    pub fn reduce(&self, xs: &[Tensor]) -> Result<Tensor> {
+        let (first, rest) = match xs.split_first() {
+            None => return Err(crate::Error::OpRequiresAtLeastOneTensor { op: "reduce" }.bt()),
+            Some(v) => v,
+        };
+        let mut acc = first.clone();
+        for x in rest.iter() {
+            acc = match self {
+                Self::Sum | Self::Avg => (acc + x)?,
+                Self::Prod => (acc * x)?,
+                Self::Max => acc.maximum(x)?,
+                Self::Min => acc.minimum(x)?,
+            }
+        }
+        match self {
+            Self::Avg => acc.affine(1. / xs.len() as f64, 0.),
+            _ => Ok(acc),
+        }
+    }
+}
+
+/// A group of ranks that can exchange tensors.
+///
+/// All the methods are collective: they have to be called by every rank of the group in the same
+/// order, with tensors of the same shape and dtype.
+pub trait Communicator: Send + Sync {
+    /// The rank of the current process in the group.
+    fn rank(&self) -> usize;
+
+    /// The number of ranks in the group.
+    fn world_size(&self) -> usize;
+
+    /// Reduces the tensors from all the ranks, every rank gets the reduced value.
+    fn all_reduce(&self, xs: &Tensor, op: ReduceOp) -> Result<Tensor>;
+
+    /// Concatenates the tensors from all the ranks along `dim`, ordered by rank.
+    fn all_gather(&self, xs: &Tensor, dim: usize) -> Result<Tensor>;
+
+    /// Returns the tensor from the `root` rank on every rank.
+    fn broadcast(&self, xs: &Tensor, root: usize) -> Result<Tensor>;
+
+    /// Reduces the tensors from all the ranks and splits the result along `dim`, the rank `i`
+    /// gets the `i`-th chunk. The size of `dim` has to be divisible by the world size.
+    fn reduce_scatter(&self, xs: &Tensor, dim: usize, op: ReduceOp) -> Result<Tensor>;
+}
+
+struct LocalGroup {
+    slots: Mutex<Vec<Option<Tensor>>>,
+    barrier: Barrier,
+}
+
+/// An in-process communicator, each rank of the group is expected to run on its own thread.
+///
+/// This is mostly useful for testing tensor parallel models without multiple devices, or to
+/// shard a model across devices driven from a single process.
+#[derive(Clone)]
+pub struct LocalCommunicator {
+    rank: usize,
+    world_size: usize,
+    group: Arc<LocalGroup>,
+}
+
+impl LocalCommunicator {
+    /// Creates the communicators for a group of `world_size` ranks, the returned vector is
+    /// indexed by rank.
+    pub fn group(world_size: usize) -> Vec<Self> {
+        let group = Arc::new(LocalGroup {
+            slots: Mutex::new(vec![None; world_size]),
+            barrier: Barrier::new(world_size),
+        });
+        (0..world_size)
+            .map(|rank| Self {
+                rank,
+                world_size,
+                group: group.clone(),
+            })
+            .collect()
+    }
+
+    // Shares a tensor with the other ranks and returns the tensors from all the ranks, moved to
+    // the device of `xs`.
+    fn exchange(&self, xs: &Tensor) -> Result<Vec<Tensor>> {
+        self.group.slots.lock().unwrap()[self.rank] = Some(xs.clone());
+        self.group.barrier.wait();
+        let all = self.group.slots.lock().unwrap().clone();
+        // The second barrier ensures that no rank overwrites its slot with the next collective
+        // before all the other ranks have read the current values.
+        self.group.barrier.wait();
+        all.into_iter()
+            .map(|t| match t {
+                None => crate::bail!("local communicator: missing tensor"),
+                Some(t) => t.to_device(xs.device()),
+            })
+            .collect()
+    }
+}
+
+impl Communicator for LocalCommunicator {
+    fn rank(&self) -> usize {
+        self.rank
+    }
+
+    fn world_size(&self) -> usize {
+        self.world_size
+    }
+
+    fn all_reduce(&self, xs: &Tensor, op: ReduceOp) -> Result<Tensor> {
+        op.reduce(&self.exchange(xs)?)
+    }
+
+    fn all_gather(&self, xs: &Tensor, dim: usize) -> Result<Tensor> {
+        Tensor::cat(&self.exchange(xs)?, dim)
+    }
+
+    fn broadcast(&self, xs: &Tensor, root: usize) -> Result<Tensor> {
+        match self.exchange(xs)?.into_iter().nth(root) {
+            None => crate::bail!("broadcast: invalid root {root}"),
+            Some(t) => Ok(t),
+        }
+    }
+
+    fn reduce_scatter(&self, xs: &Tensor, dim: usize, op: ReduceOp) -> Result<Tensor> {
+        let world_size = self.world_size();
+        let size = xs.dim(dim)?;
+        if size % world_size != 0 {
+            return Err(crate::Error::ShapeMismatchSplit {
+                shape: xs.shape().clone(),
+                dim,
+                n_parts: world_size,
+            }
+            .bt());
+        }
+        let reduced = op.reduce(&self.exchange(xs)?)?;
+        let chunk = size / world_size;
+        reduced.narrow(dim, self.rank * chunk, chunk)
+    }
+}
+
+fn write_tensor(stream: &mut TcpStream, xs: &Tensor) -> Result<()> {
+    let data = safetensors::tensor::serialize([("xs", xs)], &None)?;
+    stream.write_all(&(data.len() as u64).to_le_bytes())?;
+    stream.write_all(&data)?;
+    Ok(())
+}
+
+// An upper bound on the size of a message carrying at most `n_elems` elements of the dtype of
+// `xs`, the safetensors header for a single tensor only takes a few bytes per dimension.
+fn max_message_len(xs: &Tensor, n_elems: usize) -> u64 {
+    let data = n_elems.saturating_mul(xs.dtype().size_in_bytes());
+    (data as u64).saturating_add(256 + 32 * xs.rank() as u64)
+}
+
+// Reads a tensor written with `write_tensor`, the length sent by the peer is checked against
+// `max_len` before allocating the buffer.
+fn read_tensor(stream: &mut TcpStream, device: &Device, max_len: u64) -> Result<Tensor> {
+    let mut len = [0u8; 8];
+    stream.read_exact(&mut len)?;
+    let len = u64::from_le_bytes(len);
+    if len > max_len {
+        crate::bail!("tcp communicator: message of {len} bytes, expected at most {max_len}")
+    }
+    let mut data = vec![0u8; len as usize];
+    stream.read_exact(&mut data)?;
+    match crate::safetensors::load_buffer(&data, device)?.remove("xs") {
+        None => crate::bail!("tcp communicator: unexpected message"),
+        Some(xs) => Ok(xs),
+    }
+}
+
+/// A communicator exchanging tensors over tcp, each rank is typically a separate process, possibly
+/// on a different machine.
+///
+/// The ranks are connected with a star topology: rank 0 listens on the rendezvous address, the
+/// other ranks connect to it. For each collective, the tensors are sent to rank 0 which computes
+/// and sends back the result for each rank. This is simple but not bandwidth optimal, NCCL should
+/// be preferred for gpus.
+pub struct TcpCommunicator {
+    rank: usize,
+    world_size: usize,
+    // For rank 0, the connections to ranks 1 to world_size - 1. For the other ranks, the
+    // connection to rank 0.
+    streams: Vec<Mutex<TcpStream>>,
+}
+
+impl TcpCommunicator {
+    /// Joins the group using `addr` as the rendezvous address. Rank 0 listens on this address
+    /// and the other ranks connect to it, retrying until `timeout` is elapsed.
+    pub fn new<A: ToSocketAddrs>(
+        addr: A,
+        rank: usize,
+        world_size: usize,
+        timeout: std::time::Duration,
+    ) -> Result<Self> {
+        if rank >= world_size {
+            crate::bail!("invalid rank {rank} for world size {world_size}")
+        }
+        if rank == 0 {
+            Self::listen(TcpListener::bind(addr)?, world_size)
+        } else {
+            Self::connect(addr, rank, world_size, timeout)
+        }
+    }
+
+    /// Creates the communicator for rank 0, waiting for the other ranks to connect.
+    pub fn listen(listener: TcpListener, world_size: usize) -> Result<Self> {
+        let mut streams = (1..world_size).map(|_| None).collect::<Vec<_>>();
+        for _ in 1..world_size {
+            let (mut stream, _) = listener.accept()?;
+            stream.set_nodelay(true)?;
+            let mut rank = [0u8; 8];
+            stream.read_exact(&mut rank)?;
+            let rank = u64::from_le_bytes(rank) as usize;
+            match streams.get_mut(rank.wrapping_sub(1)) {
+                Some(slot @ None) => *slot = Some(Mutex::new(stream)),
+                _ => crate::bail!("tcp communicator: unexpected connection from rank {rank}"),
+            }
+        }
+        let streams = streams.into_iter().flatten().collect();
+        Ok(Self {
+            rank: 0,
+            world_size,
+            streams,
+        })
+    }
+
+    /// Creates the communicator for a non-zero rank by connecting to rank 0.
+    pub fn connect<A: ToSocketAddrs>(
+        addr: A,
+        rank: usize,
+        world_size: usize,
+        timeout: std::time::Duration,
+    ) -> Result<Self> {
+        let start = std::time::Instant::now();
+        let mut stream = loop {
+            match TcpStream::connect(&addr) {
+                Ok(stream) => break stream,
+                Err(err) => {
+                    if start.elapsed() > timeout {
+                        Err(err)?
+                    }
+                    std::thread::sleep(std::time::Duration::from_millis(100))
+                }
+            }
+        };
+        stream.set_nodelay(true)?;
+        stream.write_all(&(rank as u64).to_le_bytes())?;
+        Ok(Self {
+            rank,
+            world_size,
+            streams: vec![Mutex::new(stream)],
+        })
+    }
+
+    // Sends `xs` to rank 0 which applies `f` to the tensors from all the ranks, `f` returns the
+    // results for each rank.
+    fn collective<F>(&self, xs: &Tensor, f: F) -> Result<Tensor>
+    where
+        F: Fn(Vec<Tensor>) -> Result<Vec<Tensor>>,
+    {
+        let device = xs.device();
+        if self.rank == 0 {
+            let mut streams = self
+                .streams
+                .iter()
+                .map(|s| s.lock().unwrap())
+                .collect::<Vec<_>>();
+            let mut all = Vec::with_capacity(self.world_size);
+            all.push(xs.clone());
+            let max_len = max_message_len(xs, xs.elem_count());
+            for stream in streams.iter_mut() {
+                let t = read_tensor(stream, device, max_len)?;
+                if t.shape() != xs.shape() || t.dtype() != xs.dtype() {
+                    crate::bail!(
+                        "tcp communicator: got a {:?} tensor of shape {:?} instead of {:?} {:?}",
+                        t.dtype(),
+                        t.shape(),
+                        xs.dtype(),
+                        xs.shape()
+                    )
+                }
+                all.push(t)
+            }
+            let mut results = f(all)?.into_iter();
+            let result = results.next();
+            for (stream, xs) in streams.iter_mut().zip(results) {
+                write_tensor(stream, &xs)?
+            }
+            match result {
+                None => crate::bail!("tcp communicator: missing result"),
+                Some(xs) => Ok(xs),
+            }
+        } else {
+            let mut stream = self.streams[0].lock().unwrap();
+            write_tensor(&mut stream, xs)?;
+            // The largest result is the output of all_gather.
+            let n_elems = xs.elem_count().saturating_mul(self.world_size);
+            read_tensor(&mut stream, device, max_message_len(xs, n_elems))
+        }
+    }
+}
+
+impl Communicator for TcpCommunicator {
+    fn rank(&self) -> usize {
+        self.rank
+    }
+
+    fn world_size(&self) -> usize {
+        self.world_size
+    }
+
+    fn all_reduce(&self, xs: &Tensor, op: ReduceOp) -> Result<Tensor> {
+        self.collective(xs, |all| {
+            let reduced = op.reduce(&all)?;
+            Ok(vec![reduced; all.len()])
+        })
+    }
+
+    fn all_gather(&self, xs: &Tensor, dim: usize) -> Result<Tensor> {
+        self.collective(xs, |all| {
+            let gathered = Tensor::cat(&all, dim)?;
+            Ok(vec![gathered; all.len()])
+        })
+    }
+
+    fn broadcast(&self, xs: &Tensor, root: usize) -> Result<Tensor> {
+        self.collective(xs, |all| match all.get(root) {
+            None => crate::bail!("broadcast: invalid root {root}"),
+            Some(t) => Ok(vec![t.clone(); all.len()]),
+        })
+    }
+
+    fn reduce_scatter(&self, xs: &Tensor, dim: usize, op: ReduceOp) -> Result<Tensor> {
+        let world_size = self.world_size;
+        let size = xs.dim(dim)?;
+        if size % world_size != 0 {
+            return Err(crate::Error::ShapeMismatchSplit {
+                shape: xs.shape().clone(),
+                dim,
+                n_parts: world_size,
+            }
+            .bt());
+        }
+        let chunk = size / world_size;
+        self.collective(xs, |all| {
+            let reduced = op.reduce(&all)?;
+            (0..world_size)
+                .map(|rank| reduced.narrow(dim, rank * chunk, chunk))
+                .collect()
+        })
+    }
+}
+
+/// The communication backend used by `init_process_group`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    /// Exchanges the tensors over tcp, see `TcpCommunicator`.
+    Tcp,
+    /// Uses NCCL for the collectives, the tcp rendezvous is only used to share the NCCL id. This
+    /// requires the `nccl` feature and a cuda device.
+    Nccl,
+}
+
+/// Creates the communicator for the current process.
+///
+/// All the processes of the group have to call this function with the same `addr` and
+/// `world_size`, rank 0 listens on `addr` and the other ranks connect to it.
+pub fn init_process_group(
+    backend: Backend,
+    device: &Device,
+    addr: &str,
+    rank: usize,
+    world_size: usize,
+) -> Result<Arc<dyn Communicator>> {
+    let timeout = std::time::Duration::from_secs(300);
+    let tcp = TcpCommunicator::new(addr, rank, world_size, timeout)?;
+    match backend {
+        Backend::Tcp => Ok(Arc::new(tcp)),
+        #[cfg(feature = "nccl")]
+        Backend::Nccl => {
+            use crate::cuda_backend::nccl::{Id, NcclCommunicator};
+            let cuda_device = match device {
+                Device::Cuda(d) => d,
+                _ => crate::bail!("the nccl backend requires a cuda device, got {device:?}"),
+            };
+            let id = if rank == 0 {
+                Some(Id::new().map_err(|e| crate::Error::debug(e.0))?)
+            } else {
+                None
+            };
+            let internal = match &id {
+                Some(id) => id.internal().iter().map(|&c| c as u8).collect::<Vec<_>>(),
+                None => vec![0u8; 128],
+            };
+            let internal = Tensor::new(internal, &Device::Cpu)?;
+            let internal = tcp.broadcast(&internal, 0)?.to_vec1::<u8>()?;
+            let mut id = [0; 128];
+            for (dst, &src) in id.iter_mut().zip(internal.iter()) {
+                *dst = src as std::ffi::c_char
+            }
+            let comm = NcclCommunicator::from_rank(cuda_device, rank, world_size, Id::uninit(id))?;
+            Ok(Arc::new(comm))
+        }
+        #[cfg(not(feature = "nccl"))]
+        Backend::Nccl => {
+            let _ = device;
+            crate::bail!("the nccl backend requires the nccl feature")
+        }
+    }
+}
+
+/// Creates the communicator for the current process using the `MASTER_ADDR`, `MASTER_PORT`, `RANK`
+/// and `WORLD_SIZE` environment variables, as set by launchers such as `torchrun`.
+pub fn init_process_group_from_env(
+    backend: Backend,
+    device: &Device,
+) -> Result<Arc<dyn Communicator>> {
+    fn var(name: &str) -> Result<String> {
+        std::env::var(name).map_err(|_| crate::Error::Msg(format!("missing env var {name}")).bt())
+    }
+    fn parse(name: &str) -> Result<usize> {
+        var(name)?
+            .parse()
+            .map_err(|_| crate::Error::Msg(format!("invalid value for env var {name}")).bt())
+    }
+    let addr = format!("{}:{}", var("MASTER_ADDR")?, var("MASTER_PORT")?);
+    init_process_group(backend, device, &addr, parse("RANK")?, parse("WORLD_SIZE")?)
+}
@@ -311,6 +311,29 @@ impl Tensor {
         }
     }
 
+    fn conv_transpose2d_single_group(
+        &self,
+        kernel: &Self,
+        params: &ParamsConvTranspose2D,
+    ) -> Result<Self> {
+        let storage = self.storage().conv_transpose2d(
+            self.layout(),
+            &kernel.storage(),
+            kernel.layout(),
+            params,
+        )?;
+        let op = BackpropOp::new2(self, kernel, |arg, kernel| Op::ConvTranspose2D {
+            arg,
+            kernel,
+            padding: params.padding,
+            output_padding: params.output_padding,
+            stride: params.stride,
+            dilation: params.dilation,
+        });
+        let out_dims = params.out_dims();
+        Ok(crate::tensor::from_storage(storage, out_dims, op, false))
+    }
+
     /// Applies a 2D transposed convolution over the input tensor.
     pub fn conv_transpose2d(
         &self,
@@ -319,12 +342,16 @@ impl Tensor {
         output_padding: usize,
         stride: usize,
         dilation: usize,
+        groups: usize,
     ) -> Result<Self> {
         let (b_size, c_in, i_h, i_w) = self.dims4()?;
         let (c_in_k, c_out, k_h, k_w) = kernel.dims4()?;
         if c_in != c_in_k {
             crate::bail!("in_channel mismatch between input ({c_in}) and kernel ({c_in_k})")
         }
+        if c_in % groups != 0 {
+            crate::bail!("in_channel {c_in} is not divisible by the number of groups")
+        }
         let params = ParamsConvTranspose2D {
             b_size,
             i_h,
@@ -332,27 +359,79 @@ impl Tensor {
             k_h,
             k_w,
             c_out,
-            c_in,
+            c_in: c_in / groups,
             padding,
             output_padding,
             stride,
             dilation,
         };
-        let storage = self.storage().conv_transpose2d(
-            self.layout(),
-            &kernel.storage(),
-            kernel.layout(),
-            &params,
-        )?;
-        let op = BackpropOp::new2(self, kernel, |arg, kernel| Op::ConvTranspose2D {
-            arg,
-            kernel,
-            padding: params.padding,
-            output_padding: params.output_padding,
-            stride: params.stride,
-            dilation: params.dilation,
-        });
-        let out_dims = params.out_dims();
-        Ok(crate::tensor::from_storage(storage, out_dims, op, false))
+        if groups == 1 {
+            self.conv_transpose2d_single_group(kernel, &params)
+        } else {
+            let blocks = self.chunk(groups, 1)?;
+            let kernel = kernel.chunk(groups, 0)?;
+            let blocks = blocks
+                .iter()
+                .zip(&kernel)
+                .map(|(block, kernel)| block.conv_transpose2d_single_group(kernel, &params))
+                .collect::<Result<Vec<_>>>()?;
+            Tensor::cat(&blocks, 1)
+        }
+    }
+
+    /// Applies a 3D convolution over the input tensor.
+    ///
+    /// The input has shape `(b_size, c_in, d, h, w)` and the kernel `(c_out, c_in / groups,
+    /// k_d, k_h, k_w)`. The convolution is computed as a sum of 2D convolutions over the depth
+    /// of the kernel so it is supported on all the backends, as well as the backward pass.
+    pub fn conv3d(
+        &self,
+        kernel: &Self,
+        padding: usize,
+        stride: usize,
+        dilation: usize,
+        groups: usize,
+    ) -> Result<Self> {
+        let (b_size, c_in, i_d, i_h, i_w) = self.dims5()?;
+        let (c_out, c_in_k, k_d, _k_h, _k_w) = kernel.dims5()?;
+        if c_in != c_in_k * groups {
+            crate::bail!(
+                "in_channel mismatch between input ({c_in}, groups {groups}) and kernel ({c_in_k})"
+            )
+        }
+        if k_d == 0 {
+            crate::bail!("conv3d: the kernel depth cannot be zero")
+        }
+        let k_extent = dilation * (k_d - 1) + 1;
+        if i_d + 2 * padding < k_extent {
+            crate::bail!("conv3d: input depth {i_d} is smaller than the kernel extent {k_extent}")
+        }
+        let o_d = (i_d + 2 * padding - k_extent) / stride + 1;
+        let xs = self.pad_with_zeros(2, padding, padding)?;
+        let conv_planes = |k_idx: usize| -> Result<Tensor> {
+            let start = k_idx * dilation;
+            let planes = if stride == 1 {
+                xs.narrow(2, start, o_d)?
+            } else {
+                let end = start + stride * (o_d - 1) + 1;
+                let index =
+                    Tensor::arange_step(start as u32, end as u32, stride as u32, xs.device())?;
+                xs.index_select(&index, 2)?
+            };
+            // Move the depth before the channels so that the planes can be folded in the batch.
+            let planes =
+                planes
+                    .transpose(1, 2)?
+                    .contiguous()?
+                    .reshape((b_size * o_d, c_in, i_h, i_w))?;
+            let kernel = kernel.narrow(2, k_idx, 1)?.squeeze(2)?;
+            planes.conv2d(&kernel, padding, stride, dilation, groups)
+        };
+        let mut ys = conv_planes(0)?;
+        for k_idx in 1..k_d {
+            ys = (ys + conv_planes(k_idx)?)?;
+        }
+        let (_, _, o_h, o_w) = ys.dims4()?;
+        ys.reshape((b_size, o_d, c_out, o_h, o_w))?.transpose(1, 2)
     }
 }
@@ -0,0 +1,397 @@
+use crate::quantized::{gguf_file, GgmlDType, QTensor};
+use crate::safetensors::{SafetensorsInspector, StreamingSaveOptions};
+use crate::{DType, Device, Error, Result, Shape, Tensor};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// The checkpoint formats handled by [`convert`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Safetensors,
+    Gguf,
+    /// PyTorch checkpoints, these can only be read.
+    Pth,
+}
+
+impl Format {
+    /// Infers the format from the file extension.
+    pub fn infer<P: AsRef<Path>>(p: P) -> Option<Self> {
+        match p.as_ref().extension()?.to_str()? {
+            "safetensors" | "safetensor" => Some(Self::Safetensors),
+            "gguf" => Some(Self::Gguf),
+            "pth" | "pt" => Some(Self::Pth),
+            _ => None,
+        }
+    }
+
+    fn infer_or_bail<P: AsRef<Path>>(p: P) -> Result<Self> {
+        let p = p.as_ref();
+        match Self::infer(p) {
+            Some(format) => Ok(format),
+            None => crate::bail!("cannot infer the checkpoint format of {p:?}"),
+        }
+    }
+}
+
+/// A tensor of a [`Checkpoint`], as described by the file headers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CheckpointTensor {
+    pub name: String,
+    /// The dtype of the tensor once loaded, `f32` for the quantized gguf tensors.
+    pub dtype: DType,
+    /// The stored dtype for gguf tensors.
+    pub ggml_dtype: Option<GgmlDType>,
+    pub shape: Shape,
+}
+
+enum Reader {
+    Safetensors(SafetensorsInspector),
+    Gguf {
+        path: PathBuf,
+        content: gguf_file::Content,
+    },
+    Pth(crate::pickle::PthTensors),
+}
+
+/// A checkpoint opened for conversion, only the headers are read when opening it and the
+/// tensors are then loaded one at a time.
+pub struct Checkpoint {
+    reader: Reader,
+    tensors: Vec<CheckpointTensor>,
+}
+
+impl Checkpoint {
+    /// Opens a checkpoint, the format is inferred from the extension of the first path. Models
+    /// sharded in multiple files are only supported for safetensors.
+    pub fn open<P: AsRef<Path>>(paths: &[P]) -> Result<Self> {
+        let first = match paths.first() {
+            None => crate::bail!("no checkpoint files to open"),
+            Some(p) => p.as_ref(),
+        };
+        let format = Format::infer_or_bail(first)?;
+        if paths.len() > 1 && format != Format::Safetensors {
+            crate::bail!("only safetensors checkpoints can be split in multiple files")
+        }
+        match format {
+            Format::Safetensors => {
+                let inspector = unsafe { SafetensorsInspector::multi(paths)? };
+                let tensors = inspector
+                    .tensors()?
+                    .into_iter()
+                    .map(|info| {
+                        let dtype = match info.candle_dtype() {
+                            Some(dtype) if !info.compressed => dtype,
+                            _ => {
+                                crate::bail!("unsupported dtype {:?} for {}", info.dtype, info.name)
+                            }
+                        };
+                        Ok(CheckpointTensor {
+                            name: info.name,
+                            dtype,
+                            ggml_dtype: None,
+                            shape: Shape::from(info.shape),
+                        })
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                Ok(Self {
+                    reader: Reader::Safetensors(inspector),
+                    tensors,
+                })
+            }
+            Format::Gguf => {
+                let mut file =
+                    std::fs::File::open(first).map_err(|e| Error::from(e).with_path(first))?;
+                let content =
+                    gguf_file::Content::read(&mut file).map_err(|e| e.with_path(first))?;
+                let mut tensors = content
+                    .tensor_infos
+                    .iter()
+                    .map(|(name, info)| CheckpointTensor {
+                        name: name.to_string(),
+                        dtype: DType::F32,
+                        ggml_dtype: Some(info.ggml_dtype),
+                        shape: info.shape.clone(),
+                    })
+                    .collect::<Vec<_>>();
+                tensors.sort_by(|t1, t2| t1.name.cmp(&t2.name));
+                let path = first.to_path_buf();
+                Ok(Self {
+                    reader: Reader::Gguf { path, content },
+                    tensors,
+                })
+            }
+            Format::Pth => {
+                let pth = crate::pickle::PthTensors::new(first, None)?;
+                let mut tensors = pth
+                    .tensor_infos()
+                    .iter()
+                    .map(|(name, info)| CheckpointTensor {
+                        name: name.to_string(),
+                        dtype: info.dtype,
+                        ggml_dtype: None,
+                        shape: info.layout.shape().clone(),
+                    })
+                    .collect::<Vec<_>>();
+                tensors.sort_by(|t1, t2| t1.name.cmp(&t2.name));
+                Ok(Self {
+                    reader: Reader::Pth(pth),
+                    tensors,
+                })
+            }
+        }
+    }
+
+    pub fn format(&self) -> Format {
+        match self.reader {
+            Reader::Safetensors(_) => Format::Safetensors,
+            Reader::Gguf { .. } => Format::Gguf,
+            Reader::Pth(_) => Format::Pth,
+        }
+    }
+
+    /// The tensors of the checkpoint, sorted by name.
+    pub fn tensors(&self) -> &[CheckpointTensor] {
+        &self.tensors
+    }
+
+    /// Loads a tensor on the cpu, gguf tensors are dequantized.
+    pub fn load(&self, name: &str) -> Result<Tensor> {
+        match &self.reader {
+            Reader::Safetensors(inspector) => inspector.load(name, &Device::Cpu),
+            Reader::Gguf { .. } => self.load_quantized(name)?.dequantize(&Device::Cpu),
+            Reader::Pth(pth) => match pth.get(name)? {
+                Some(tensor) => Ok(tensor),
+                None => Err(Error::CannotFindTensor {
+                    path: name.to_string(),
+                }
+                .bt()),
+            },
+        }
+    }
+
+    /// Loads a tensor from a gguf checkpoint without dequantizing it.
+    pub fn load_quantized(&self, name: &str) -> Result<QTensor> {
+        match &self.reader {
+            Reader::Gguf { path, content } => {
+                let mut file =
+                    std::fs::File::open(path).map_err(|e| Error::from(e).with_path(path))?;
+                content.tensor(&mut file, name, &Device::Cpu)
+            }
+            _ => crate::bail!("{name} is not stored in a quantized format"),
+        }
+    }
+}
+
+/// The transformations applied by [`convert`].
+#[derive(Debug, Clone, Default)]
+pub struct ConvertOptions {
+    /// Casts the float tensors to this dtype, the integer tensors are left unchanged.
+    pub dtype: Option<DType>,
+    /// Quantizes the 2d tensors which last dimension is a multiple of the block size, this is
+    /// only supported for gguf outputs.
+    pub quantize: Option<GgmlDType>,
+    /// Replacements applied in order to the tensor names, each `(from, to)` replaces all the
+    /// occurrences of `from`.
+    pub renames: Vec<(String, String)>,
+    /// Only the tensors matching one of these patterns are kept, all of them if empty. The
+    /// patterns apply to the original names and `*` matches any sequence of characters.
+    pub include: Vec<String>,
+    /// The tensors matching one of these patterns are dropped.
+    pub exclude: Vec<String>,
+}
+
+impl ConvertOptions {
+    fn check(&self, format: Format) -> Result<()> {
+        if format == Format::Pth {
+            crate::bail!("writing pth checkpoints is not supported")
+        }
+        if self.quantize.is_some() && format != Format::Gguf {
+            crate::bail!("quantization is only supported when writing gguf checkpoints")
+        }
+        Ok(())
+    }
+
+    fn keep(&self, name: &str) -> bool {
+        let matches = |patterns: &[String]| patterns.iter().any(|p| glob_match(p, name));
+        (self.include.is_empty() || matches(&self.include)) && !matches(&self.exclude)
+    }
+
+    fn rename(&self, name: &str) -> String {
+        let mut name = name.to_string();
+        for (from, to) in self.renames.iter() {
+            name = name.replace(from.as_str(), to)
+        }
+        name
+    }
+}
+
+fn glob_match(pattern: &str, name: &str) -> bool {
+    fn go(pattern: &[u8], name: &[u8]) -> bool {
+        match pattern.split_first() {
+            None => name.is_empty(),
+            Some((b'*', rest)) => (0..=name.len()).any(|i| go(rest, &name[i..])),
+            Some((c, rest)) => name.first() == Some(c) && go(rest, &name[1..]),
+        }
+    }
+    go(pattern.as_bytes(), name.as_bytes())
+}
+
+/// A summary of a conversion.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConvertReport {
+    /// The number of tensors written.
+    pub written: usize,
+    /// The original names of the tensors dropped by the include and exclude patterns.
+    pub excluded: Vec<String>,
+    /// The number of tensors that have been quantized.
+    pub quantized: usize,
+}
+
+struct Entry<'a> {
+    src: &'a CheckpointTensor,
+    dtype: DType,
+    ggml_dtype: GgmlDType,
+    quantize: bool,
+}
+
+impl Checkpoint {
+    /// Writes the converted checkpoint to `w`, see [`convert`].
+    pub fn convert_to<W: std::io::Write + std::io::Seek>(
+        &self,
+        w: &mut W,
+        format: Format,
+        options: &ConvertOptions,
+    ) -> Result<ConvertReport> {
+        options.check(format)?;
+        let mut report = ConvertReport::default();
+        let mut entries: HashMap<String, Entry> = HashMap::new();
+        let mut names = Vec::new();
+        for src in self.tensors.iter() {
+            if !options.keep(&src.name) {
+                report.excluded.push(src.name.clone());
+                continue;
+            }
+            let dtype = match options.dtype {
+                Some(dtype) if src.dtype.is_float() => dtype,
+                _ => src.dtype,
+            };
+            let quantize = match options.quantize {
+                None => None,
+                Some(q) => match src.shape.dims() {
+                    [_, d] if d % q.block_size() == 0 => Some(q),
+                    _ => None,
+                },
+            };
+            let ggml_dtype = match (quantize, src.ggml_dtype) {
+                (Some(q), _) => q,
+                (None, Some(ggml_dtype)) if options.dtype.is_none() => ggml_dtype,
+                (None, _) if dtype == DType::F16 => GgmlDType::F16,
+                (None, _) => GgmlDType::F32,
+            };
+            let name = options.rename(&src.name);
+            let entry = Entry {
+                src,
+                dtype,
+                ggml_dtype,
+                quantize: quantize.is_some(),
+            };
+            report.quantized += entry.quantize as usize;
+            if let Some(prev) = entries.insert(name.clone(), entry) {
+                crate::bail!(
+                    "{} and {} are both renamed to {name}",
+                    prev.src.name,
+                    src.name
+                )
+            }
+            names.push(name)
+        }
+        report.written = names.len();
+
+        match format {
+            Format::Safetensors => {
+                let tensors = names
+                    .iter()
+                    .map(|name| {
+                        let e = &entries[name];
+                        (name.as_str(), e.dtype, e.src.shape.clone())
+                    })
+                    .collect::<Vec<_>>();
+                // The checksums and compression entries are specific to each tensor so they are
+                // not carried over.
+                let metadata = match &self.reader {
+                    Reader::Safetensors(inspector) => inspector
+                        .metadata()
+                        .into_iter()
+                        .filter(|(k, _)| !k.starts_with("crc32:") && !k.starts_with("zstd:"))
+                        .collect(),
+                    _ => HashMap::new(),
+                };
+                let save_options = StreamingSaveOptions {
+                    metadata,
+                    ..Default::default()
+                };
+                let load = |name: &str| {
+                    let e = &entries[name];
+                    self.load(&e.src.name)?.to_dtype(e.dtype)
+                };
+                crate::safetensors::save_streaming_with(&tensors, load, w, &save_options)?
+            }
+            Format::Gguf => {
+                let tensors = names
+                    .iter()
+                    .map(|name| {
+                        let e = &entries[name];
+                        (name.as_str(), e.ggml_dtype, e.src.shape.clone())
+                    })
+                    .collect::<Vec<_>>();
+                let metadata = match &self.reader {
+                    Reader::Gguf { content, .. } => {
+                        let mut metadata = content.metadata.iter().collect::<Vec<_>>();
+                        metadata.sort_by_key(|(k, _)| *k);
+                        metadata
+                    }
+                    _ => vec![],
+                };
+                let metadata = metadata
+                    .iter()
+                    .map(|(k, v)| (k.as_str(), *v))
+                    .collect::<Vec<_>>();
+                let load = |name: &str| {
+                    let e = &entries[name];
+                    if e.src.ggml_dtype == Some(e.ggml_dtype) {
+                        return self.load_quantized(&e.src.name);
+                    }
+                    let tensor = self.load(&e.src.name)?.to_dtype(e.dtype)?;
+                    QTensor::quantize(&tensor.to_dtype(DType::F32)?, e.ggml_dtype)
+                };
+                gguf_file::write_with(w, &metadata, &tensors, load)?
+            }
+            Format::Pth => unreachable!(),
+        }
+        Ok(report)
+    }
+}
+
+/// Converts a checkpoint tensor by tensor, so that a single tensor is held in memory at a time.
+///
+/// The input can be a safetensors, possibly sharded in multiple files, a gguf, or a PyTorch
+/// checkpoint. The output is written in the safetensors or gguf format depending on its
+/// extension. The safetensors metadata is kept when converting safetensors files and the gguf
+/// metadata is kept when converting gguf files.
+pub fn convert<P: AsRef<Path>, Q: AsRef<Path>>(
+    inputs: &[P],
+    output: Q,
+    options: &ConvertOptions,
+) -> Result<ConvertReport> {
+    let output = output.as_ref();
+    let format = Format::infer_or_bail(output)?;
+    options.check(format)?;
+    let checkpoint = Checkpoint::open(inputs)?;
+    let file = std::fs::File::create(output).map_err(|e| Error::from(e).with_path(output))?;
+    let mut file = std::io::BufWriter::new(file);
+    let report = checkpoint
+        .convert_to(&mut file, format, options)
+        .map_err(|e| e.with_path(output))?;
+    std::io::Write::flush(&mut file)?;
+    Ok(report)
+}
@@ -1,5 +1,26 @@
-//! Implement conversion traits for tensors
+//! Conversion traits for tensors, and conversion of whole checkpoints between formats.
+//!
+//! ```no_run
+//! use candle_core::convert::{convert, ConvertOptions};
+//! use candle_core::{quantized::GgmlDType, DType};
+//!
+//! let options = ConvertOptions {
+//!     dtype: Some(DType::F16),
+//!     quantize: Some(GgmlDType::Q4K),
+//!     renames: vec![("model.".to_string(), "".to_string())],
+//!     exclude: vec!["*.rotary_emb.inv_freq".to_string()],
+//!     ..Default::default()
+//! };
+//! let report = convert(&["model.safetensors"], "model-q4k.gguf", &options)?;
+//! println!("wrote {} tensors, {} quantized", report.written, report.quantized);
+//! # Ok::<(), candle_core::Error>(())
+//! ```
+mod checkpoint;
+
 use crate::{DType, Device, Error, Tensor, WithDType};
+pub use checkpoint::{
+    convert, Checkpoint, CheckpointTensor, ConvertOptions, ConvertReport, Format,
+};
 use half::{bf16, f16, slice::HalfFloatSliceExt};
 use std::convert::TryFrom;
 
@@ -929,6 +929,58 @@ impl Map1 for Im2Col {
     }
 }
 
+// Same as `Im2Col` for inputs using the channels last layout, the columns are ordered as
+// `(h_k, w_k, c)` so that the channels can be copied in contiguous blocks.
+struct Im2ColChannelsLast(Im2Col);
+
+impl Map1 for Im2ColChannelsLast {
+    fn f<T: WithDType>(&self, vs: &[T], layout: &Layout) -> Result<Vec<T>> {
+        let &Im2Col {
+            h_k,
+            w_k,
+            stride,
+            dilation,
+            padding,
+        } = &self.0;
+        let (b, c, h, w) = layout.shape().dims4()?;
+        let (h_out, w_out) = self.0.hw_out(h, w);
+        let src = &vs[layout.start_offset()..];
+        let mut dst = vec![T::zero(); b * h_out * w_out * h_k * w_k * c];
+        let (src_s0, src_s2, src_s3) = {
+            let s = layout.stride();
+            (s[0], s[2], s[3])
+        };
+        for b_idx in 0..b {
+            let src_idx = b_idx * src_s0;
+            let dst_idx = b_idx * h_out * w_out * h_k * w_k * c;
+            for h_idx in 0..h_out {
+                let dst_idx = dst_idx + h_idx * w_out * h_k * w_k * c;
+                for w_idx in 0..w_out {
+                    let dst_idx = dst_idx + w_idx * h_k * w_k * c;
+                    for h_k_idx in 0..h_k {
+                        let src_h = h_idx * stride + h_k_idx * dilation;
+                        if src_h < padding || src_h >= h + padding {
+                            continue;
+                        }
+                        let src_idx = src_idx + (src_h - padding) * src_s2;
+                        let dst_idx = dst_idx + h_k_idx * w_k * c;
+                        for w_k_idx in 0..w_k {
+                            let src_w = w_idx * stride + w_k_idx * dilation;
+                            if src_w < padding || src_w >= w + padding {
+                                continue;
+                            }
+                            let src_idx = src_idx + (src_w - padding) * src_s3;
+                            let dst_idx = dst_idx + w_k_idx * c;
+                            dst[dst_idx..dst_idx + c].copy_from_slice(&src[src_idx..src_idx + c])
+                        }
+                    }
+                }
+            }
+        }
+        Ok(dst)
+    }
+}
+
 struct Col2Im1D {
     stride: usize,
 }
@@ -2228,10 +2280,10 @@ impl BackendStorage for CpuStorage {
                     .alloc_uninit(kernel_l.shape(), kernel.dtype())?
             };
             kernel.copy_strided_src(&mut kernel_c, 0, kernel_l)?;
-            let kernel_l = Layout::contiguous_with_offset((1, n, k), kernel_l.start_offset())
+            let kernel_l = Layout::contiguous((1, n, k))
                 .transpose(1, 2)?
                 .broadcast_as((b, k, n))?;
-            col.matmul(kernel, (b, m, n, k), &col_l, &kernel_l)?
+            col.matmul(&kernel_c, (b, m, n, k), &col_l, &kernel_l)?
         };
         let res_l = Layout::contiguous((b, l_out, params.c_out)).transpose(1, 2)?;
         let mut res_t = unsafe { self.device().alloc_uninit(res_l.shape(), res.dtype())? };
@@ -2311,13 +2363,33 @@ impl BackendStorage for CpuStorage {
             stride: params.stride,
             dilation: params.dilation,
         };
-        let col = op.map(self, l)?;
         let b = params.b_size;
         let n = params.c_out;
         let (h_out, w_out) = (params.out_h(), params.out_w());
         let k = op.h_k * op.w_k * params.c_in;
         let m = h_out * w_out;
         let col_l = Layout::contiguous((b, m, k));
+        if l.is_channels_last() && params.c_in > 1 {
+            // The columns are ordered as (h_k, w_k, c_in) so the kernel is reordered to match.
+            let col = Im2ColChannelsLast(op).map(self, l)?;
+            let kernel_p = kernel_l.permute(&[0, 2, 3, 1])?;
+            let mut kernel_c = unsafe {
+                self.device()
+                    .alloc_uninit(kernel_p.shape(), kernel.dtype())?
+            };
+            kernel.copy_strided_src(&mut kernel_c, 0, &kernel_p)?;
+            let kernel_l = Layout::contiguous((1, n, k))
+                .transpose(1, 2)?
+                .broadcast_as((b, k, n))?;
+            let res = col.matmul(&kernel_c, (b, m, n, k), &col_l, &kernel_l)?;
+            let res_l = Layout::contiguous((b, h_out, w_out, params.c_out))
+                .transpose(1, 2)?
+                .transpose(1, 3)?;
+            let mut res_t = unsafe { self.device().alloc_uninit(res_l.shape(), res.dtype())? };
+            res.copy_strided_src(&mut res_t, 0, &res_l)?;
+            return Ok(res_t);
+        }
+        let col = op.map(self, l)?;
         let res = if kernel_l.is_contiguous() {
             let kernel_l = Layout::contiguous_with_offset((1, n, k), kernel_l.start_offset())
                 .transpose(1, 2)?
@@ -2330,10 +2402,10 @@ impl BackendStorage for CpuStorage {
                     .alloc_uninit(kernel_l.shape(), kernel.dtype())?
             };
             kernel.copy_strided_src(&mut kernel_c, 0, kernel_l)?;
-            let kernel_l = Layout::contiguous_with_offset((1, n, k), kernel_l.start_offset())
+            let kernel_l = Layout::contiguous((1, n, k))
                 .transpose(1, 2)?
                 .broadcast_as((b, k, n))?;
-            col.matmul(kernel, (b, m, n, k), &col_l, &kernel_l)?
+            col.matmul(&kernel_c, (b, m, n, k), &col_l, &kernel_l)?
         };
         let res_l = Layout::contiguous((b, h_out, w_out, params.c_out))
             .transpose(1, 2)?
@@ -30,6 +30,7 @@ pub struct CudaDevice {
     device: Arc<cudarc::driver::CudaDevice>,
     pub(crate) blas: Arc<cudarc::cublas::CudaBlas>,
     curand: Arc<Mutex<CudaRng>>,
+    pub(crate) layout_infos: Arc<Mutex<super::graph::LayoutInfoCache>>,
 }
 
 impl std::fmt::Debug for CudaDevice {
@@ -55,6 +56,53 @@ impl CudaDevice {
         self.id
     }
 
+    /// Creates a device running its kernels on a new stream rather than on the default one,
+    /// this is required to capture graphs, see [`CudaDevice::capture_graph`].
+    pub fn new_with_stream(ordinal: usize) -> Result<Self> {
+        let device = cudarc::driver::CudaDevice::new_with_stream(ordinal).w()?;
+        Self::from_cudarc(device)
+    }
+
+    fn from_cudarc(device: Arc<cudarc::driver::CudaDevice>) -> Result<Self> {
+        let blas = cudarc::cublas::CudaBlas::new(device.clone()).w()?;
+        let curand = cudarc::curand::CudaRng::new(299792458, device.clone()).w()?;
+        let layout_infos = super::graph::LayoutInfoCache::new(device.clone());
+        Ok(Self {
+            id: DeviceId::new(),
+            device,
+            blas: Arc::new(blas),
+            curand: Arc::new(Mutex::new(CudaRng(curand))),
+            layout_infos: Arc::new(Mutex::new(layout_infos)),
+        })
+    }
+
+    pub(crate) fn capabilities(&self) -> Result<crate::DeviceCapabilities> {
+        use cudarc::driver::sys::CUdevice_attribute as A;
+        let attribute = |a: A| self.device.attribute(a).w();
+        let major = attribute(A::CU_DEVICE_ATTRIBUTE_COMPUTE_CAPABILITY_MAJOR)? as usize;
+        let minor = attribute(A::CU_DEVICE_ATTRIBUTE_COMPUTE_CAPABILITY_MINOR)? as usize;
+        let shared_memory = attribute(A::CU_DEVICE_ATTRIBUTE_MAX_SHARED_MEMORY_PER_BLOCK_OPTIN)?;
+        let integrated = attribute(A::CU_DEVICE_ATTRIBUTE_INTEGRATED)?;
+        // Most of the bf16 kernels are only compiled for compute capability 8.0 and above.
+        let mut matmul_dtypes = vec![DType::F16, DType::F32, DType::F64];
+        let mut dtypes = vec![DType::U8, DType::U32, DType::I64];
+        if major >= 8 {
+            dtypes.push(DType::BF16);
+            matmul_dtypes.insert(0, DType::BF16)
+        }
+        dtypes.extend([DType::F16, DType::F32, DType::F64]);
+        Ok(crate::DeviceCapabilities {
+            name: self.device.name().w()?,
+            dtypes,
+            matmul_dtypes,
+            tensor_cores: major >= 7,
+            compute_capability: Some((major, minor)),
+            max_shared_memory: Some(shared_memory as usize),
+            unified_memory: integrated != 0,
+            unsupported_ops: vec![],
+        })
+    }
+
     fn const_impl(&self, v: f64, shape: &Shape, dtype: DType) -> Result<CudaStorage> {
         let elem_count = shape.elem_count();
         let cfg = LaunchConfig::for_num_elems(elem_count as u32);
@@ -149,14 +197,7 @@ impl BackendDevice for CudaDevice {
 
     fn new(ordinal: usize) -> Result<Self> {
         let device = cudarc::driver::CudaDevice::new(ordinal).w()?;
-        let blas = cudarc::cublas::CudaBlas::new(device.clone()).w()?;
-        let curand = cudarc::curand::CudaRng::new(299792458, device.clone()).w()?;
-        Ok(Self {
-            id: DeviceId::new(),
-            device,
-            blas: Arc::new(blas),
-            curand: Arc::new(Mutex::new(CudaRng(curand))),
-        })
+        Self::from_cudarc(device)
     }
 
     fn set_seed(&self, seed: u64) -> Result<()> {
@@ -0,0 +1,189 @@
+//! Capture and replay of the kernels launched on a device as a CUDA graph.
+use super::{CudaDevice, WrapErr};
+use crate::Result;
+use cudarc::driver::{sys, CudaSlice, DeviceRepr};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// An instantiated CUDA graph, see [`CudaDevice::capture_graph`].
+pub struct CudaGraph {
+    device: CudaDevice,
+    graph: sys::CUgraph,
+    exec: sys::CUgraphExec,
+}
+
+// The graph handles are only used through the driver api which is thread safe.
+unsafe impl Send for CudaGraph {}
+unsafe impl Sync for CudaGraph {}
+
+impl std::fmt::Debug for CudaGraph {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "CudaGraph({:?})", self.device)
+    }
+}
+
+impl CudaGraph {
+    pub fn device(&self) -> &CudaDevice {
+        &self.device
+    }
+
+    /// Replays the captured kernels on the device stream, this is asynchronous.
+    pub fn launch(&self) -> Result<()> {
+        let stream = *self.device.cu_stream();
+        unsafe { sys::lib().cuGraphLaunch(self.exec, stream).result() }.w()
+    }
+}
+
+impl Drop for CudaGraph {
+    fn drop(&mut self) {
+        unsafe {
+            let lib = sys::lib();
+            let _ = lib.cuGraphExecDestroy(self.exec);
+            let _ = lib.cuGraphDestroy(self.graph);
+        }
+    }
+}
+
+impl CudaDevice {
+    /// Runs `f` while recording the kernels that it launches on this device rather than
+    /// executing them, and returns the resulting graph together with the value returned by `f`.
+    ///
+    /// Nothing is computed until the graph is launched, the tensors returned by `f` then hold
+    /// the results of the last launch. `f` must not transfer data between the host and the
+    /// device nor synchronize, and the pointers and scalar values used by the kernels are frozen
+    /// so the inputs have to be updated in place between launches. The buffers allocated while
+    /// capturing are freed and allocated again at the same addresses on each launch. The default
+    /// stream cannot be captured so the device has to be created with `new_with_stream`.
+    pub fn capture_graph<T, F: FnOnce() -> Result<T>>(&self, f: F) -> Result<(CudaGraph, T)> {
+        use sys::CUstreamCaptureMode_enum::CU_STREAM_CAPTURE_MODE_RELAXED;
+        let stream = *self.cu_stream();
+        if stream.is_null() {
+            crate::bail!("graph capture requires a device created with new_cuda_with_stream")
+        }
+        let lib = unsafe { sys::lib() };
+        // The relaxed mode allows the synchronous allocations and copies of `layout_info`.
+        unsafe { lib.cuStreamBeginCapture_v2(stream, CU_STREAM_CAPTURE_MODE_RELAXED) }
+            .result()
+            .w()?;
+        let value = f();
+        // The capture has to be ended even on errors so that the stream remains usable.
+        let mut graph = std::ptr::null_mut();
+        let ended = unsafe { lib.cuStreamEndCapture(stream, &mut graph) }.result();
+        let destroy = |graph: sys::CUgraph| {
+            if !graph.is_null() {
+                let _ = unsafe { lib.cuGraphDestroy(graph) };
+            }
+        };
+        let value = match (value, ended) {
+            (Ok(value), Ok(())) => value,
+            (Err(err), _) => {
+                destroy(graph);
+                return Err(err);
+            }
+            (Ok(_), Err(err)) => {
+                destroy(graph);
+                return Err(err).w();
+            }
+        };
+        let mut exec = std::ptr::null_mut();
+        let flags = sys::CUgraphInstantiate_flags::CUDA_GRAPH_INSTANTIATE_FLAG_AUTO_FREE_ON_LAUNCH;
+        let instantiated =
+            unsafe { lib.cuGraphInstantiateWithFlags(&mut exec, graph, flags as u64) }.result();
+        if let Err(err) = instantiated {
+            destroy(graph);
+            return Err(err).w();
+        }
+        let graph = CudaGraph {
+            device: self.clone(),
+            graph,
+            exec,
+        };
+        Ok((graph, value))
+    }
+
+    /// Whether the kernels launched on the device stream are being captured.
+    pub fn is_capturing(&self) -> Result<bool> {
+        use sys::CUstreamCaptureStatus::CU_STREAM_CAPTURE_STATUS_NONE;
+        let mut status = CU_STREAM_CAPTURE_STATUS_NONE;
+        unsafe { sys::lib().cuStreamIsCapturing(*self.cu_stream(), &mut status) }
+            .result()
+            .w()?;
+        Ok(status != CU_STREAM_CAPTURE_STATUS_NONE)
+    }
+
+    /// Copies the dims and strides of a layout, or some other small array read by the strided
+    /// kernels, to the device.
+    ///
+    /// A copy on the stream would be recorded while capturing a graph and read the host buffer,
+    /// freed by then, on each launch. So while capturing, the arrays are rather copied
+    /// synchronously to buffers that live as long as the device and that are reused for the
+    /// same values.
+    pub fn layout_info(&self, info: Vec<usize>) -> Result<LayoutInfo> {
+        if !self.is_capturing()? {
+            let info = self.htod_copy(info).w()?;
+            return Ok(LayoutInfo(Info::Copied(info)));
+        }
+        let mut cache = self.layout_infos.lock().unwrap();
+        if let Some(&ptr) = cache.buffers.get(&info) {
+            return Ok(LayoutInfo(Info::Cached(ptr)));
+        }
+        let bytes = std::mem::size_of_val(info.as_slice());
+        let mut ptr = 0;
+        unsafe {
+            let lib = sys::lib();
+            lib.cuMemAlloc_v2(&mut ptr, bytes).result().w()?;
+            let src = info.as_ptr() as *const std::ffi::c_void;
+            if let Err(err) = lib.cuMemcpyHtoD_v2(ptr, src, bytes).result() {
+                let _ = lib.cuMemFree_v2(ptr);
+                return Err(err).w();
+            }
+        }
+        cache.buffers.insert(info, ptr);
+        Ok(LayoutInfo(Info::Cached(ptr)))
+    }
+}
+
+/// An array of metadata on the device passed to a kernel, see [`CudaDevice::layout_info`].
+pub struct LayoutInfo(Info);
+
+enum Info {
+    Copied(CudaSlice<usize>),
+    // A buffer owned by the device cache.
+    Cached(sys::CUdeviceptr),
+}
+
+unsafe impl DeviceRepr for &LayoutInfo {
+    fn as_kernel_param(&self) -> *mut std::ffi::c_void {
+        match &self.0 {
+            Info::Copied(slice) => slice.as_kernel_param(),
+            Info::Cached(ptr) => ptr as *const sys::CUdeviceptr as *mut std::ffi::c_void,
+        }
+    }
+}
+
+// The buffers created by `layout_info` while capturing, they are freed with the device as the
+// graphs hold a reference to it.
+pub(crate) struct LayoutInfoCache {
+    device: Arc<cudarc::driver::CudaDevice>,
+    buffers: HashMap<Vec<usize>, sys::CUdeviceptr>,
+}
+
+impl LayoutInfoCache {
+    pub(crate) fn new(device: Arc<cudarc::driver::CudaDevice>) -> Self {
+        Self {
+            device,
+            buffers: HashMap::new(),
+        }
+    }
+}
+
+impl Drop for LayoutInfoCache {
+    fn drop(&mut self) {
+        if self.device.bind_to_thread().is_err() {
+            return;
+        }
+        for &ptr in self.buffers.values() {
+            let _ = unsafe { sys::lib().cuMemFree_v2(ptr) };
+        }
+    }
+}
@@ -0,0 +1,208 @@
+//! NCCL based implementation of the collective communication primitives.
+use super::{CudaDType, CudaStorage, WrapErr};
+use crate::backend::BackendStorage;
+use crate::collective::{Communicator, ReduceOp};
+use crate::{CpuStorage, CustomOp1, DType, Layout, Result, Shape, Tensor};
+use cudarc::driver::DeviceRepr;
+use cudarc::nccl::safe::{Comm, NcclType};
+use half::{bf16, f16};
+use std::sync::{Arc, Mutex};
+
+pub use cudarc::nccl::safe::Id;
+
+fn nccl_op(op: ReduceOp) -> cudarc::nccl::safe::ReduceOp {
+    use cudarc::nccl::safe::ReduceOp as R;
+    match op {
+        ReduceOp::Sum => R::Sum,
+        ReduceOp::Prod => R::Prod,
+        ReduceOp::Max => R::Max,
+        ReduceOp::Min => R::Min,
+        ReduceOp::Avg => R::Avg,
+    }
+}
+
+// NCCL communicators are not thread safe, the collective calls for a given communicator should
+// not be interleaved between multiple threads but a communicator can be used from different
+// threads one at a time. The communicator is only accessed through a mutex.
+// https://docs.nvidia.com/deeplearning/nccl/user-guide/docs/usage/threadsafety.html
+struct SendComm(Comm);
+unsafe impl Send for SendComm {}
+
+/// A communicator using NCCL, each rank is typically a separate process driving a single gpu.
+/// The collective calls from multiple threads are serialized.
+#[derive(Clone)]
+pub struct NcclCommunicator {
+    comm: Arc<Mutex<SendComm>>,
+    rank: usize,
+    world_size: usize,
+}
+
+impl NcclCommunicator {
+    pub fn new(comm: Comm) -> Self {
+        Self {
+            rank: comm.rank(),
+            world_size: comm.world_size(),
+            comm: Arc::new(Mutex::new(SendComm(comm))),
+        }
+    }
+
+    /// Joins the group identified by `id`, this has to be called by all the ranks.
+    pub fn from_rank(
+        device: &super::CudaDevice,
+        rank: usize,
+        world_size: usize,
+        id: Id,
+    ) -> Result<Self> {
+        let device: Arc<cudarc::driver::CudaDevice> = (**device).clone();
+        let comm = Comm::from_rank(device, rank, world_size, id)
+            .map_err(|err| crate::Error::debug(err.0))?;
+        Ok(Self::new(comm))
+    }
+
+    /// Runs `f` with exclusive access to the underlying NCCL communicator.
+    pub fn with_comm<R, F: FnOnce(&Comm) -> R>(&self, f: F) -> R {
+        let comm = self.comm.lock().unwrap_or_else(|e| e.into_inner());
+        f(&comm.0)
+    }
+
+    fn apply(&self, xs: &Tensor, kind: Collective) -> Result<Tensor> {
+        let op = CollectiveOp {
+            comm: self.comm.clone(),
+            kind,
+        };
+        xs.contiguous()?.apply_op1_no_bwd(&op)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Collective {
+    AllReduce(ReduceOp),
+    // The gather and scatter operations only work on the first dimension, the other dimensions
+    // are handled by transposing.
+    AllGather,
+    Broadcast(usize),
+    ReduceScatter(ReduceOp),
+}
+
+struct CollectiveOp {
+    comm: Arc<Mutex<SendComm>>,
+    kind: Collective,
+}
+
+impl CollectiveOp {
+    fn fwd<T: CudaDType + NcclType + DeviceRepr>(
+        &self,
+        s: &CudaStorage,
+        l: &Layout,
+    ) -> Result<(CudaStorage, Shape)> {
+        let dev = s.device.clone();
+        let (o1, o2) = match l.contiguous_offsets() {
+            Some(offsets) => offsets,
+            None => crate::bail!("nccl ops require contiguous inputs"),
+        };
+        let slice = s.as_cuda_slice::<T>()?;
+        let src = slice.slice(o1..o2);
+        let comm = self.comm.lock().unwrap_or_else(|e| e.into_inner());
+        let comm = &comm.0;
+        let world_size = comm.world_size();
+        let elem_count = l.shape().elem_count();
+        let mut dims = l.dims().to_vec();
+        let (dst, shape) = match self.kind {
+            Collective::AllReduce(op) => {
+                let mut dst = unsafe { dev.alloc::<T>(elem_count) }.w()?;
+                comm.all_reduce(&src, &mut dst, &nccl_op(op))
+                    .map_err(|err| crate::Error::debug(err.0))?;
+                (dst, l.shape().clone())
+            }
+            Collective::AllGather => {
+                if dims.is_empty() {
+                    crate::bail!("all-gather: cannot gather a tensor with no dimensions")
+                }
+                let mut dst = unsafe { dev.alloc::<T>(elem_count * world_size) }.w()?;
+                comm.all_gather(&src, &mut dst)
+                    .map_err(|err| crate::Error::debug(err.0))?;
+                dims[0] *= world_size;
+                (dst, Shape::from(dims))
+            }
+            Collective::Broadcast(root) => {
+                let mut dst = unsafe { dev.alloc::<T>(elem_count) }.w()?;
+                let send = (comm.rank() == root).then(|| slice.slice(o1..o2));
+                comm.broadcast(&send, &mut dst, root as i32)
+                    .map_err(|err| crate::Error::debug(err.0))?;
+                (dst, l.shape().clone())
+            }
+            Collective::ReduceScatter(op) => {
+                if dims.is_empty() {
+                    crate::bail!("reduce-scatter: cannot split a tensor with no dimensions")
+                }
+                if dims[0] % world_size != 0 {
+                    crate::bail!("reduce-scatter: cannot split {dims:?} in {world_size} parts")
+                }
+                let mut dst = unsafe { dev.alloc::<T>(elem_count / world_size) }.w()?;
+                comm.reduce_scatter(&src, &mut dst, &nccl_op(op))
+                    .map_err(|err| crate::Error::debug(err.0))?;
+                dims[0] /= world_size;
+                (dst, Shape::from(dims))
+            }
+        };
+        Ok((CudaStorage::wrap_cuda_slice(dst, dev), shape))
+    }
+}
+
+impl CustomOp1 for CollectiveOp {
+    fn name(&self) -> &'static str {
+        "nccl"
+    }
+
+    fn cpu_fwd(&self, _: &CpuStorage, _: &Layout) -> Result<(CpuStorage, Shape)> {
+        crate::bail!("nccl collectives are only supported on cuda devices")
+    }
+
+    fn cuda_fwd(&self, s: &CudaStorage, l: &Layout) -> Result<(CudaStorage, Shape)> {
+        match s.dtype() {
+            DType::U8 => self.fwd::<u8>(s, l),
+            DType::U32 => self.fwd::<u32>(s, l),
+            DType::I64 => self.fwd::<i64>(s, l),
+            DType::BF16 => self.fwd::<bf16>(s, l),
+            DType::F16 => self.fwd::<f16>(s, l),
+            DType::F32 => self.fwd::<f32>(s, l),
+            DType::F64 => self.fwd::<f64>(s, l),
+        }
+    }
+}
+
+impl Communicator for NcclCommunicator {
+    fn rank(&self) -> usize {
+        self.rank
+    }
+
+    fn world_size(&self) -> usize {
+        self.world_size
+    }
+
+    fn all_reduce(&self, xs: &Tensor, op: ReduceOp) -> Result<Tensor> {
+        self.apply(xs, Collective::AllReduce(op))
+    }
+
+    fn all_gather(&self, xs: &Tensor, dim: usize) -> Result<Tensor> {
+        if dim == 0 {
+            self.apply(xs, Collective::AllGather)
+        } else {
+            self.apply(&xs.transpose(0, dim)?, Collective::AllGather)?
+                .transpose(0, dim)
+        }
+    }
+
+    fn broadcast(&self, xs: &Tensor, root: usize) -> Result<Tensor> {
+        self.apply(xs, Collective::Broadcast(root))
+    }
+
+    fn reduce_scatter(&self, xs: &Tensor, dim: usize, op: ReduceOp) -> Result<Tensor> {
+        if dim == 0 {
+            self.apply(xs, Collective::ReduceScatter(op))
+        } else {
+            self.apply(&xs.transpose(0, dim)?, Collective::ReduceScatter(op))?
+                .transpose(0, dim)
+        }
+    }
+}
@@ -0,0 +1,283 @@
+//! Secondary streams and events, used to overlap host to device copies with the kernels running
+//! on the default stream of a device.
+use super::{CudaDevice, CudaStorage, CudaStorageSlice, WrapErr};
+use crate::{CpuStorage, Result};
+use cudarc::driver::{sys, CudaSlice, DevicePtrMut, DeviceRepr};
+use std::sync::{Arc, Mutex};
+
+// A page-locked host buffer used to stage the host to device copies, the driver can only copy
+// asynchronously from such memory.
+struct StagingBuffer {
+    ptr: *mut std::ffi::c_void,
+    size: usize,
+    // Recorded after the last copy reading from the buffer.
+    copied: Option<CudaEvent>,
+}
+
+impl StagingBuffer {
+    fn new(size: usize) -> Result<Self> {
+        let mut ptr = std::ptr::null_mut();
+        unsafe { sys::lib().cuMemHostAlloc(&mut ptr, size, 0) }
+            .result()
+            .w()?;
+        Ok(Self {
+            ptr,
+            size,
+            copied: None,
+        })
+    }
+
+    fn is_free(&self) -> Result<bool> {
+        match &self.copied {
+            None => Ok(true),
+            Some(event) => event.is_complete(),
+        }
+    }
+}
+
+impl Drop for StagingBuffer {
+    fn drop(&mut self) {
+        if let Some(event) = self.copied.take() {
+            let _ = event.synchronize();
+        }
+        let _ = unsafe { sys::lib().cuMemFreeHost(self.ptr) };
+    }
+}
+
+struct StreamInner {
+    device: CudaDevice,
+    stream: sys::CUstream,
+    // Reused across copies, a buffer is free once the copy reading from it has completed.
+    staging: Mutex<Vec<StagingBuffer>>,
+}
+
+impl Drop for StreamInner {
+    fn drop(&mut self) {
+        self.staging
+            .get_mut()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+        let _ = unsafe { sys::lib().cuStreamDestroy_v2(self.stream) };
+    }
+}
+
+/// A stream running concurrently with the default stream of a device, see
+/// [`CudaDevice::new_stream`].
+#[derive(Clone)]
+pub struct CudaStream(Arc<StreamInner>);
+
+// The stream handles are only used through the driver api which is thread safe.
+unsafe impl Send for StreamInner {}
+unsafe impl Sync for StreamInner {}
+
+impl std::fmt::Debug for CudaStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "CudaStream({:?})", self.0.device)
+    }
+}
+
+/// A marker recorded on a stream, completed once all the work queued on the stream before it has
+/// been executed.
+pub struct CudaEvent {
+    device: CudaDevice,
+    event: sys::CUevent,
+}
+
+unsafe impl Send for CudaEvent {}
+unsafe impl Sync for CudaEvent {}
+
+impl std::fmt::Debug for CudaEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "CudaEvent({:?})", self.device)
+    }
+}
+
+impl Drop for CudaEvent {
+    fn drop(&mut self) {
+        let _ = unsafe { sys::lib().cuEventDestroy_v2(self.event) };
+    }
+}
+
+fn record_event(device: &CudaDevice, stream: sys::CUstream, timing: bool) -> Result<CudaEvent> {
+    let lib = unsafe { sys::lib() };
+    let mut event = std::ptr::null_mut();
+    let flags = if timing {
+        sys::CUevent_flags::CU_EVENT_DEFAULT as u32
+    } else {
+        sys::CUevent_flags::CU_EVENT_DISABLE_TIMING as u32
+    };
+    unsafe { lib.cuEventCreate(&mut event, flags) }
+        .result()
+        .w()?;
+    let event = CudaEvent {
+        device: device.clone(),
+        event,
+    };
+    unsafe { lib.cuEventRecord(event.event, stream) }
+        .result()
+        .w()?;
+    Ok(event)
+}
+
+impl CudaEvent {
+    pub fn device(&self) -> &CudaDevice {
+        &self.device
+    }
+
+    /// Makes the kernels queued on the default stream of the device after this call wait for the
+    /// event, this does not block the host.
+    pub fn wait(&self) -> Result<()> {
+        self.wait_on(*self.device.cu_stream())
+    }
+
+    /// Same as [`CudaEvent::wait`] for a stream created outside of candle, e.g. by another
+    /// framework.
+    pub fn wait_on(&self, stream: sys::CUstream) -> Result<()> {
+        unsafe { sys::lib().cuStreamWaitEvent(stream, self.event, 0) }
+            .result()
+            .w()
+    }
+
+    /// Blocks the host until the event has completed.
+    pub fn synchronize(&self) -> Result<()> {
+        unsafe { sys::lib().cuEventSynchronize(self.event) }
+            .result()
+            .w()
+    }
+
+    pub fn is_complete(&self) -> Result<bool> {
+        match unsafe { sys::lib().cuEventQuery(self.event) } {
+            sys::CUresult::CUDA_SUCCESS => Ok(true),
+            sys::CUresult::CUDA_ERROR_NOT_READY => Ok(false),
+            err => err.result().map(|_| false).w(),
+        }
+    }
+
+    /// The time elapsed on the device between `start` and this event, both events have to be
+    /// created with [`CudaDevice::record_timing_event`]. This blocks until this event has
+    /// completed.
+    pub fn elapsed_since(&self, start: &CudaEvent) -> Result<std::time::Duration> {
+        self.synchronize()?;
+        let mut ms = 0f32;
+        unsafe { sys::lib().cuEventElapsedTime(&mut ms, start.event, self.event) }
+            .result()
+            .w()?;
+        Ok(std::time::Duration::from_secs_f64(ms.max(0.) as f64 / 1e3))
+    }
+}
+
+impl CudaDevice {
+    /// Creates a stream that does not synchronize with the default stream of the device, the work
+    /// queued on it can run while kernels are executing on the default stream.
+    pub fn new_stream(&self) -> Result<CudaStream> {
+        let mut stream = std::ptr::null_mut();
+        let flags = sys::CUstream_flags::CU_STREAM_NON_BLOCKING as u32;
+        unsafe { sys::lib().cuStreamCreate(&mut stream, flags) }
+            .result()
+            .w()?;
+        Ok(CudaStream(Arc::new(StreamInner {
+            device: self.clone(),
+            stream,
+            staging: Mutex::new(vec![]),
+        })))
+    }
+
+    /// Records an event on the default stream of the device.
+    pub fn record_event(&self) -> Result<CudaEvent> {
+        record_event(self, *self.cu_stream(), false)
+    }
+
+    /// Records an event on a stream of this device created outside of candle, e.g. by another
+    /// framework.
+    pub fn record_event_on(&self, stream: sys::CUstream) -> Result<CudaEvent> {
+        record_event(self, stream, false)
+    }
+
+    /// Records an event on the default stream of the device that can be used to measure the
+    /// execution time of the kernels, see [`CudaEvent::elapsed_since`].
+    pub fn record_timing_event(&self) -> Result<CudaEvent> {
+        record_event(self, *self.cu_stream(), true)
+    }
+}
+
+impl CudaStream {
+    pub fn device(&self) -> &CudaDevice {
+        &self.0.device
+    }
+
+    /// Records an event on this stream, waiting for it on the default stream ensures that the
+    /// copies queued before have completed.
+    pub fn record_event(&self) -> Result<CudaEvent> {
+        record_event(&self.0.device, self.0.stream, false)
+    }
+
+    pub fn synchronize(&self) -> Result<()> {
+        unsafe { sys::lib().cuStreamSynchronize(self.0.stream) }
+            .result()
+            .w()
+    }
+
+    /// Queues a copy of `src` to a new device buffer on this stream. The buffer must not be used
+    /// on the default stream before waiting for an event recorded after the copy.
+    ///
+    /// The data is first copied to a page-locked staging buffer so that this returns without
+    /// waiting for the transfer, which then overlaps with both the host and the kernels running
+    /// on the default stream. The staging buffers are reused once their copy has completed, so
+    /// the page-locked memory grows with the amount of data in flight.
+    pub fn htod_copy<T: DeviceRepr>(&self, src: &[T]) -> Result<CudaSlice<T>> {
+        let device = &self.0.device;
+        let mut dst = unsafe { device.alloc::<T>(src.len()) }.w()?;
+        if src.is_empty() {
+            return Ok(dst);
+        }
+        // The allocation is ordered on the default stream.
+        let allocated = record_event(device, *device.cu_stream(), false)?;
+        unsafe { sys::lib().cuStreamWaitEvent(self.0.stream, allocated.event, 0) }
+            .result()
+            .w()?;
+        let size = std::mem::size_of_val(src);
+        let mut staging = self.0.staging.lock().unwrap_or_else(|e| e.into_inner());
+        // Use the smallest free buffer that is large enough.
+        let mut index = None;
+        for (i, buffer) in staging.iter().enumerate() {
+            if buffer.size >= size && buffer.is_free()? {
+                match index {
+                    Some(j) if staging[j].size <= buffer.size => {}
+                    _ => index = Some(i),
+                }
+            }
+        }
+        let buffer = match index {
+            Some(index) => &mut staging[index],
+            None => {
+                staging.push(StagingBuffer::new(size)?);
+                staging.last_mut().unwrap()
+            }
+        };
+        unsafe {
+            std::ptr::copy_nonoverlapping(src.as_ptr() as *const u8, buffer.ptr as *mut u8, size);
+            sys::lib().cuMemcpyHtoDAsync_v2(*dst.device_ptr_mut(), buffer.ptr, size, self.0.stream)
+        }
+        .result()
+        .w()?;
+        buffer.copied = Some(record_event(device, self.0.stream, false)?);
+        Ok(dst)
+    }
+
+    /// Same as `CudaDevice::storage_from_cpu_storage` but with the copy queued on this stream.
+    pub fn storage_from_cpu_storage(&self, storage: &CpuStorage) -> Result<CudaStorage> {
+        let slice = match storage {
+            CpuStorage::U8(storage) => CudaStorageSlice::U8(self.htod_copy(storage)?),
+            CpuStorage::U32(storage) => CudaStorageSlice::U32(self.htod_copy(storage)?),
+            CpuStorage::I64(storage) => CudaStorageSlice::I64(self.htod_copy(storage)?),
+            CpuStorage::BF16(storage) => CudaStorageSlice::BF16(self.htod_copy(storage)?),
+            CpuStorage::F16(storage) => CudaStorageSlice::F16(self.htod_copy(storage)?),
+            CpuStorage::F32(storage) => CudaStorageSlice::F32(self.htod_copy(storage)?),
+            CpuStorage::F64(storage) => CudaStorageSlice::F64(self.htod_copy(storage)?),
+        };
+        Ok(CudaStorage {
+            slice,
+            device: self.0.device.clone(),
+        })
+    }
+}
@@ -1,3 +1,4 @@
+use crate::meta_backend::MetaStorage;
 use crate::op::{BackpropOp, Op};
 use crate::tensor::from_storage;
 use crate::{CpuStorage, CudaStorage, Layout, MetalStorage, Result, Shape, Tensor};
@@ -15,9 +16,12 @@ pub trait CustomOp1 {
     /// The forward pass, as run on a gpu device. Note that the storage can use arbitrary strides,
     /// offsets etc so the associated layout should be used to access it.
     fn cuda_fwd(&self, _storage: &CudaStorage, _layout: &Layout) -> Result<(CudaStorage, Shape)> {
-        Err(crate::Error::Cuda(
-            format!("no cuda implementation for {}", self.name()).into(),
-        ))
+        Err(crate::Error::UnsupportedOp {
+            backend: "cuda",
+            op: self.name(),
+            dtype: None,
+        }
+        .bt())
     }
 
     /// The forward pass, as run on a metal gpu device. Note that the storage can use arbitrary strides,
@@ -27,9 +31,23 @@ pub trait CustomOp1 {
         _storage: &MetalStorage,
         _layout: &Layout,
     ) -> Result<(MetalStorage, Shape)> {
-        Err(crate::Error::Metal(
-            format!("no metal implementation for {}", self.name()).into(),
-        ))
+        Err(crate::Error::UnsupportedOp {
+            backend: "metal",
+            op: self.name(),
+            dtype: None,
+        }
+        .bt())
+    }
+
+    /// The forward pass on a meta device, this only has to return the output dtype with
+    /// [`MetaStorage::record`] and the output shape.
+    fn meta_fwd(&self, _storage: &MetaStorage, _layout: &Layout) -> Result<(MetaStorage, Shape)> {
+        Err(crate::Error::UnsupportedOp {
+            backend: "meta",
+            op: self.name(),
+            dtype: None,
+        }
+        .bt())
     }
 
     /// This function takes as argument the argument `arg` used in the forward pass, the result
@@ -62,9 +80,12 @@ pub trait CustomOp2 {
         _: &CudaStorage,
         _: &Layout,
     ) -> Result<(CudaStorage, Shape)> {
-        Err(crate::Error::Cuda(
-            format!("no cuda implementation for {}", self.name()).into(),
-        ))
+        Err(crate::Error::UnsupportedOp {
+            backend: "cuda",
+            op: self.name(),
+            dtype: None,
+        }
+        .bt())
     }
 
     /// The forward pass, as run on a metal gpu device. Note that the storage can use arbitrary strides,
@@ -76,9 +97,29 @@ pub trait CustomOp2 {
         _: &MetalStorage,
         _: &Layout,
     ) -> Result<(MetalStorage, Shape)> {
-        Err(crate::Error::Metal(
-            format!("no metal implementation for {}", self.name()).into(),
-        ))
+        Err(crate::Error::UnsupportedOp {
+            backend: "metal",
+            op: self.name(),
+            dtype: None,
+        }
+        .bt())
+    }
+
+    /// The forward pass on a meta device, this only has to return the output dtype with
+    /// [`MetaStorage::record`] and the output shape.
+    fn meta_fwd(
+        &self,
+        _: &MetaStorage,
+        _: &Layout,
+        _: &MetaStorage,
+        _: &Layout,
+    ) -> Result<(MetaStorage, Shape)> {
+        Err(crate::Error::UnsupportedOp {
+            backend: "meta",
+            op: self.name(),
+            dtype: None,
+        }
+        .bt())
     }
 
     fn bwd(
@@ -118,9 +159,12 @@ pub trait CustomOp3 {
         _: &CudaStorage,
         _: &Layout,
     ) -> Result<(CudaStorage, Shape)> {
-        Err(crate::Error::Cuda(
-            format!("no cuda implementation for {}", self.name()).into(),
-        ))
+        Err(crate::Error::UnsupportedOp {
+            backend: "cuda",
+            op: self.name(),
+            dtype: None,
+        }
+        .bt())
     }
 
     /// The forward pass, as run on a metal gpu device. Note that the storage can use arbitrary strides,
@@ -134,9 +178,31 @@ pub trait CustomOp3 {
         _: &MetalStorage,
         _: &Layout,
     ) -> Result<(MetalStorage, Shape)> {
-        Err(crate::Error::Metal(
-            format!("no metal implementation for {}", self.name()).into(),
-        ))
+        Err(crate::Error::UnsupportedOp {
+            backend: "metal",
+            op: self.name(),
+            dtype: None,
+        }
+        .bt())
+    }
+
+    /// The forward pass on a meta device, this only has to return the output dtype with
+    /// [`MetaStorage::record`] and the output shape.
+    fn meta_fwd(
+        &self,
+        _: &MetaStorage,
+        _: &Layout,
+        _: &MetaStorage,
+        _: &Layout,
+        _: &MetaStorage,
+        _: &Layout,
+    ) -> Result<(MetaStorage, Shape)> {
+        Err(crate::Error::UnsupportedOp {
+            backend: "meta",
+            op: self.name(),
+            dtype: None,
+        }
+        .bt())
     }
 
     fn bwd(
@@ -258,17 +324,23 @@ pub trait InplaceOp1 {
     /// The forward pass, as run on a gpu device. Note that the storage can use arbitrary strides,
     /// offsets etc so the associated layout should be used to access it.
     fn cuda_fwd(&self, _storage: &mut CudaStorage, _layout: &Layout) -> Result<()> {
-        Err(crate::Error::Cuda(
-            format!("no cuda implementation for {}", self.name()).into(),
-        ))
+        Err(crate::Error::UnsupportedOp {
+            backend: "cuda",
+            op: self.name(),
+            dtype: None,
+        }
+        .bt())
     }
 
     /// The forward pass, as run on a metal gpu device. Note that the storage can use arbitrary strides,
     /// offsets etc so the associated layout should be used to access it.
     fn metal_fwd(&self, _storage: &mut MetalStorage, _layout: &Layout) -> Result<()> {
-        Err(crate::Error::Metal(
-            format!("no metal implementation for {}", self.name()).into(),
-        ))
+        Err(crate::Error::UnsupportedOp {
+            backend: "metal",
+            op: self.name(),
+            dtype: None,
+        }
+        .bt())
     }
 }
 
@@ -283,9 +355,12 @@ pub trait InplaceOp2 {
     /// The forward pass, as run on a gpu device. Note that the storage can use arbitrary strides,
     /// offsets etc so the associated layout should be used to access it.
     fn cuda_fwd(&self, _: &mut CudaStorage, _: &Layout, _: &CudaStorage, _: &Layout) -> Result<()> {
-        Err(crate::Error::Cuda(
-            format!("no cuda implementation for {}", self.name()).into(),
-        ))
+        Err(crate::Error::UnsupportedOp {
+            backend: "cuda",
+            op: self.name(),
+            dtype: None,
+        }
+        .bt())
     }
 
     /// The forward pass, as run on a metal gpu device. Note that the storage can use arbitrary strides,
@@ -297,9 +372,12 @@ pub trait InplaceOp2 {
         _: &MetalStorage,
         _: &Layout,
     ) -> Result<()> {
-        Err(crate::Error::Metal(
-            format!("no metal implementation for {}", self.name()).into(),
-        ))
+        Err(crate::Error::UnsupportedOp {
+            backend: "metal",
+            op: self.name(),
+            dtype: None,
+        }
+        .bt())
     }
 }
 
@@ -329,9 +407,12 @@ pub trait InplaceOp3 {
         _: &CudaStorage,
         _: &Layout,
     ) -> Result<()> {
-        Err(crate::Error::Cuda(
-            format!("no cuda implementation for {}", self.name()).into(),
-        ))
+        Err(crate::Error::UnsupportedOp {
+            backend: "cuda",
+            op: self.name(),
+            dtype: None,
+        }
+        .bt())
     }
 
     /// The forward pass, as run on a metal gpu device. Note that the storage can use arbitrary strides,
@@ -345,9 +426,12 @@ pub trait InplaceOp3 {
         _: &MetalStorage,
         _: &Layout,
     ) -> Result<()> {
-        Err(crate::Error::Metal(
-            format!("no metal implementation for {}", self.name()).into(),
-        ))
+        Err(crate::Error::UnsupportedOp {
+            backend: "metal",
+            op: self.name(),
+            dtype: None,
+        }
+        .bt())
     }
 }
 
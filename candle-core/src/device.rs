@@ -9,6 +9,47 @@ pub enum DeviceLocation {
     Cpu,
     Cuda { gpu_id: usize },
     Metal { gpu_id: usize },
+    Meta,
+}
+
+/// What a device supports, as returned by [`Device::capabilities`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceCapabilities {
+    pub name: String,
+    /// The dtypes that are supported by most operations.
+    pub dtypes: Vec<DType>,
+    /// The dtypes that are supported by matmul.
+    pub matmul_dtypes: Vec<DType>,
+    /// Hardware matrix multiplication units: tensor cores on cuda, simdgroup matrices on metal.
+    pub tensor_cores: bool,
+    /// The cuda compute capability `(major, minor)`.
+    pub compute_capability: Option<(usize, usize)>,
+    /// The maximum shared memory per block (threadgroup memory on metal), in bytes.
+    pub max_shared_memory: Option<usize>,
+    /// The device memory is shared with the host so transfers to and from the cpu are cheap.
+    pub unified_memory: bool,
+    /// The operations that are not implemented on this device, using the same names as
+    /// [`crate::fallback::fallback_counts`].
+    pub unsupported_ops: Vec<&'static str>,
+}
+
+impl DeviceCapabilities {
+    pub fn supports_dtype(&self, dtype: DType) -> bool {
+        self.dtypes.contains(&dtype)
+    }
+
+    pub fn supports_op(&self, op: &str) -> bool {
+        !self.unsupported_ops.contains(&op)
+    }
+
+    /// The most compact float dtype that is supported by all the operations including matmul, in
+    /// the order bf16, f16 and f32.
+    pub fn preferred_float_dtype(&self) -> DType {
+        [DType::BF16, DType::F16]
+            .into_iter()
+            .find(|dtype| self.supports_dtype(*dtype) && self.matmul_dtypes.contains(dtype))
+            .unwrap_or(DType::F32)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -16,6 +57,9 @@ pub enum Device {
     Cpu,
     Cuda(crate::CudaDevice),
     Metal(crate::MetalDevice),
+    /// A device on which the tensors only track their shape and dtype, see
+    /// [`crate::shape_trace::ShapeTracer`].
+    Meta(crate::MetaDevice),
 }
 
 pub trait NdArray {
@@ -130,6 +174,11 @@ impl Device {
         Ok(Self::Cuda(crate::CudaDevice::new(ordinal)?))
     }
 
+    /// A cuda device running its kernels on its own stream, as required to capture graphs.
+    pub fn new_cuda_with_stream(ordinal: usize) -> Result<Self> {
+        Ok(Self::Cuda(crate::CudaDevice::new_with_stream(ordinal)?))
+    }
+
     pub fn new_metal(ordinal: usize) -> Result<Self> {
         Ok(Self::Metal(crate::MetalDevice::new(ordinal)?))
     }
@@ -139,6 +188,7 @@ impl Device {
             Self::Cpu => CpuDevice.set_seed(seed),
             Self::Cuda(c) => c.set_seed(seed),
             Self::Metal(m) => m.set_seed(seed),
+            Self::Meta(m) => m.set_seed(seed),
         }
     }
 
@@ -147,6 +197,7 @@ impl Device {
             (Self::Cpu, Self::Cpu) => true,
             (Self::Cuda(lhs), Self::Cuda(rhs)) => lhs.same_device(rhs),
             (Self::Metal(lhs), Self::Metal(rhs)) => lhs.same_device(rhs),
+            (Self::Meta(lhs), Self::Meta(rhs)) => lhs.same_device(rhs),
             _ => false,
         }
     }
@@ -156,6 +207,7 @@ impl Device {
             Self::Cpu => DeviceLocation::Cpu,
             Self::Cuda(device) => device.location(),
             Device::Metal(device) => device.location(),
+            Device::Meta(device) => device.location(),
         }
     }
 
@@ -171,13 +223,49 @@ impl Device {
         matches!(self, Self::Metal(_))
     }
 
+    pub fn is_meta(&self) -> bool {
+        matches!(self, Self::Meta(_))
+    }
+
     pub fn supports_bf16(&self) -> bool {
         match self {
-            Self::Cuda(_) => true,
+            Self::Cuda(_) | Self::Meta(_) => true,
             Self::Metal(_) | Self::Cpu => false,
         }
     }
 
+    /// Queries the capabilities of the device so that applications can select the models and
+    /// dtypes to use.
+    pub fn capabilities(&self) -> Result<DeviceCapabilities> {
+        match self {
+            Self::Cpu => Ok(DeviceCapabilities {
+                name: "cpu".to_string(),
+                dtypes: vec![
+                    DType::U8,
+                    DType::U32,
+                    DType::I64,
+                    DType::BF16,
+                    DType::F16,
+                    DType::F32,
+                    DType::F64,
+                ],
+                matmul_dtypes: if cfg!(feature = "accelerate") {
+                    vec![DType::F32, DType::F64]
+                } else {
+                    vec![DType::F16, DType::F32, DType::F64]
+                },
+                tensor_cores: false,
+                compute_capability: None,
+                max_shared_memory: None,
+                unified_memory: true,
+                unsupported_ops: vec![],
+            }),
+            Self::Cuda(d) => d.capabilities(),
+            Self::Metal(d) => d.capabilities(),
+            Self::Meta(d) => d.capabilities(),
+        }
+    }
+
     /// Return `BF16` for devices that support it, otherwise default to `F32`.
     pub fn bf16_default_to_f32(&self) -> DType {
         if self.supports_bf16() {
@@ -221,6 +309,10 @@ impl Device {
                 let storage = device.rand_uniform(shape, dtype, lo, up)?;
                 Ok(Storage::Metal(storage))
             }
+            Device::Meta(device) => {
+                let storage = device.rand_uniform(shape, dtype, lo, up)?;
+                Ok(Storage::Meta(storage))
+            }
         }
     }
 
@@ -259,6 +351,10 @@ impl Device {
                 let storage = device.rand_normal(shape, dtype, mean, std)?;
                 Ok(Storage::Metal(storage))
             }
+            Device::Meta(device) => {
+                let storage = device.rand_normal(shape, dtype, mean, std)?;
+                Ok(Storage::Meta(storage))
+            }
         }
     }
 
@@ -285,6 +381,10 @@ impl Device {
                 let storage = device.ones_impl(shape, dtype)?;
                 Ok(Storage::Metal(storage))
             }
+            Device::Meta(device) => {
+                let storage = device.ones_impl(shape, dtype)?;
+                Ok(Storage::Meta(storage))
+            }
         }
     }
 
@@ -302,6 +402,10 @@ impl Device {
                 let storage = device.zeros_impl(shape, dtype)?;
                 Ok(Storage::Metal(storage))
             }
+            Device::Meta(device) => {
+                let storage = device.zeros_impl(shape, dtype)?;
+                Ok(Storage::Meta(storage))
+            }
         }
     }
 
@@ -319,6 +423,10 @@ impl Device {
                 let storage = device.alloc_uninit(shape, dtype)?;
                 Ok(Storage::Metal(storage))
             }
+            Device::Meta(device) => {
+                let storage = device.alloc_uninit(shape, dtype)?;
+                Ok(Storage::Meta(storage))
+            }
         }
     }
 
@@ -333,6 +441,10 @@ impl Device {
                 let storage = device.storage_from_slice(data)?;
                 Ok(Storage::Metal(storage))
             }
+            Device::Meta(device) => {
+                let storage = device.storage_from_slice(data)?;
+                Ok(Storage::Meta(storage))
+            }
         }
     }
 
@@ -349,6 +461,11 @@ impl Device {
                 let storage = device.storage_from_cpu_storage_owned(storage)?;
                 Ok(Storage::Metal(storage))
             }
+            Device::Meta(device) => {
+                let storage = array.to_cpu_storage();
+                let storage = device.storage_from_cpu_storage_owned(storage)?;
+                Ok(Storage::Meta(storage))
+            }
         }
     }
 
@@ -365,6 +482,11 @@ impl Device {
                 let storage = device.storage_from_cpu_storage_owned(storage)?;
                 Ok(Storage::Metal(storage))
             }
+            Device::Meta(device) => {
+                let storage = S::to_cpu_storage_owned(data);
+                let storage = device.storage_from_cpu_storage_owned(storage)?;
+                Ok(Storage::Meta(storage))
+            }
         }
     }
 
@@ -373,6 +495,7 @@ impl Device {
             Self::Cpu => Ok(()),
             Self::Cuda(d) => d.synchronize(),
             Self::Metal(d) => d.synchronize(),
+            Self::Meta(d) => d.synchronize(),
         }
     }
 }
@@ -17,6 +17,7 @@ impl Tensor {
             crate::DeviceLocation::Metal { gpu_id } => {
                 format!(", metal:{}", gpu_id)
             }
+            crate::DeviceLocation::Meta => ", meta".to_owned(),
         };
 
         write!(f, "Tensor[")?;
@@ -90,6 +91,12 @@ impl PrinterOptions {
     }
 }
 
+impl Default for PrinterOptions {
+    fn default() -> Self {
+        Self::const_default()
+    }
+}
+
 pub fn print_options() -> &'static std::sync::Mutex<PrinterOptions> {
     &PRINT_OPTS
 }
@@ -199,13 +206,15 @@ trait TensorFormatter {
     ) -> std::fmt::Result {
         let dims = t.dims();
         let edge_items = po.edge_items;
+        // 0-dim tensors are displayed as a plain scalar.
+        if dims.is_empty() {
+            if let Ok(v) = t.to_scalar::<Self::Elem>() {
+                self.fmt(v, max_w, f)?
+            }
+            return Ok(());
+        }
         write!(f, "[")?;
         match dims {
-            [] => {
-                if let Ok(v) = t.to_scalar::<Self::Elem>() {
-                    self.fmt(v, max_w, f)?
-                }
-            }
             [v] if summarize && *v > 2 * edge_items => {
                 if let Ok(vs) = t
                     .narrow(0, 0, edge_items)
@@ -440,7 +449,53 @@ fn get_summarized_data(t: &Tensor, edge_items: usize) -> Result<Tensor> {
 
 impl std::fmt::Display for Tensor {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        let po = PRINT_OPTS.lock().unwrap();
+        let po = PRINT_OPTS.lock().unwrap().clone();
+        self.fmt_with_options(&po, f)
+    }
+}
+
+/// A tensor displayed with specific printing options, see [`Tensor::display_with`].
+pub struct TensorDisplay<'a> {
+    tensor: &'a Tensor,
+    options: &'a PrinterOptions,
+}
+
+impl std::fmt::Display for TensorDisplay<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.tensor.fmt_with_options(self.options, f)
+    }
+}
+
+impl Tensor {
+    /// Displays the tensor with the given options rather than the global ones.
+    ///
+    /// ```rust
+    /// use candle_core::{display::PrinterOptions, Device, Tensor};
+    /// let t = Tensor::new(&[1.23456f32, 2.5], &Device::Cpu)?;
+    /// let po = PrinterOptions { precision: 2, ..Default::default() };
+    /// assert_eq!(format!("{}", t.display_with(&po)), "[1.23, 2.50]\nTensor[[2], f32]");
+    /// # Ok::<(), candle_core::Error>(())
+    /// ```
+    pub fn display_with<'a>(&'a self, options: &'a PrinterOptions) -> TensorDisplay<'a> {
+        TensorDisplay {
+            tensor: self,
+            options,
+        }
+    }
+
+    fn fmt_with_options(
+        &self,
+        po: &PrinterOptions,
+        f: &mut std::fmt::Formatter,
+    ) -> std::fmt::Result {
+        if self.device().is_meta() {
+            return write!(
+                f,
+                "Tensor[{:?}, {}, meta]",
+                self.dims(),
+                self.dtype().as_str()
+            );
+        }
         let summarize = self.elem_count() > po.threshold;
         let to_display = if summarize {
             match get_summarized_data(self, po.edge_items) {
@@ -454,46 +509,46 @@ impl std::fmt::Display for Tensor {
             DType::U8 => {
                 let tf: IntFormatter<u8> = IntFormatter::new();
                 let max_w = tf.max_width(&to_display);
-                tf.fmt_tensor(self, 1, max_w, summarize, &po, f)?;
+                tf.fmt_tensor(self, 1, max_w, summarize, po, f)?;
                 writeln!(f)?;
             }
             DType::U32 => {
                 let tf: IntFormatter<u32> = IntFormatter::new();
                 let max_w = tf.max_width(&to_display);
-                tf.fmt_tensor(self, 1, max_w, summarize, &po, f)?;
+                tf.fmt_tensor(self, 1, max_w, summarize, po, f)?;
                 writeln!(f)?;
             }
             DType::I64 => {
                 let tf: IntFormatter<i64> = IntFormatter::new();
                 let max_w = tf.max_width(&to_display);
-                tf.fmt_tensor(self, 1, max_w, summarize, &po, f)?;
+                tf.fmt_tensor(self, 1, max_w, summarize, po, f)?;
                 writeln!(f)?;
             }
             DType::BF16 => {
-                if let Ok(tf) = FloatFormatter::<bf16>::new(&to_display, &po) {
+                if let Ok(tf) = FloatFormatter::<bf16>::new(&to_display, po) {
                     let max_w = tf.max_width(&to_display);
-                    tf.fmt_tensor(self, 1, max_w, summarize, &po, f)?;
+                    tf.fmt_tensor(self, 1, max_w, summarize, po, f)?;
                     writeln!(f)?;
                 }
             }
             DType::F16 => {
-                if let Ok(tf) = FloatFormatter::<f16>::new(&to_display, &po) {
+                if let Ok(tf) = FloatFormatter::<f16>::new(&to_display, po) {
                     let max_w = tf.max_width(&to_display);
-                    tf.fmt_tensor(self, 1, max_w, summarize, &po, f)?;
+                    tf.fmt_tensor(self, 1, max_w, summarize, po, f)?;
                     writeln!(f)?;
                 }
             }
             DType::F64 => {
-                if let Ok(tf) = FloatFormatter::<f64>::new(&to_display, &po) {
+                if let Ok(tf) = FloatFormatter::<f64>::new(&to_display, po) {
                     let max_w = tf.max_width(&to_display);
-                    tf.fmt_tensor(self, 1, max_w, summarize, &po, f)?;
+                    tf.fmt_tensor(self, 1, max_w, summarize, po, f)?;
                     writeln!(f)?;
                 }
             }
             DType::F32 => {
-                if let Ok(tf) = FloatFormatter::<f32>::new(&to_display, &po) {
+                if let Ok(tf) = FloatFormatter::<f32>::new(&to_display, po) {
                     let max_w = tf.max_width(&to_display);
-                    tf.fmt_tensor(self, 1, max_w, summarize, &po, f)?;
+                    tf.fmt_tensor(self, 1, max_w, summarize, po, f)?;
                     writeln!(f)?;
                 }
             }
@@ -507,6 +562,7 @@ impl std::fmt::Display for Tensor {
             crate::DeviceLocation::Metal { gpu_id } => {
                 format!(", metal:{}", gpu_id)
             }
+            crate::DeviceLocation::Meta => ", meta".to_owned(),
         };
 
         write!(
@@ -518,3 +574,99 @@ impl std::fmt::Display for Tensor {
         )
     }
 }
+
+/// Statistics over the elements of a tensor, see [`Tensor::summary`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct TensorSummary {
+    pub shape: crate::Shape,
+    pub dtype: DType,
+    /// The minimum of the finite values, NaN if there are none.
+    pub min: f64,
+    /// The maximum of the finite values, NaN if there are none.
+    pub max: f64,
+    /// The mean of the finite values, NaN if there are none.
+    pub mean: f64,
+    /// The population standard deviation of the finite values, NaN if there are none.
+    pub std: f64,
+    pub nan_count: usize,
+    pub inf_count: usize,
+}
+
+impl std::fmt::Display for TensorSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "Tensor[{:?}, {}] min={:.4e} max={:.4e} mean={:.4e} std={:.4e} nan={} inf={}",
+            self.shape.dims(),
+            self.dtype.as_str(),
+            self.min,
+            self.max,
+            self.mean,
+            self.std,
+            self.nan_count,
+            self.inf_count,
+        )
+    }
+}
+
+impl Tensor {
+    /// Computes statistics over the elements of the tensor on its device, which is more useful
+    /// than printing large tensors when debugging.
+    ///
+    /// ```rust
+    /// use candle_core::{Device, Tensor};
+    /// let t = Tensor::new(&[1f32, 3., f32::NAN, f32::INFINITY], &Device::Cpu)?;
+    /// let summary = t.summary()?;
+    /// assert_eq!((summary.min, summary.max, summary.mean), (1., 3., 2.));
+    /// assert_eq!((summary.nan_count, summary.inf_count), (1, 1));
+    /// # Ok::<(), candle_core::Error>(())
+    /// ```
+    pub fn summary(&self) -> Result<TensorSummary> {
+        let dtype = match self.dtype() {
+            DType::F64 => DType::F64,
+            _ => DType::F32,
+        };
+        let t = self.detach().flatten_all()?.to_dtype(dtype)?;
+        let scalar = |t: Tensor| -> Result<f64> {
+            match dtype {
+                DType::F64 => t.to_scalar::<f64>(),
+                _ => Ok(t.to_scalar::<f32>()? as f64),
+            }
+        };
+        let count = |mask: &Tensor| -> Result<usize> {
+            Ok(mask.to_dtype(DType::U32)?.sum_all()?.to_scalar::<u32>()? as usize)
+        };
+        let nan = t.ne(&t)?;
+        let inf = t.abs()?.eq(f64::INFINITY)?;
+        let finite = nan.maximum(&inf)?.eq(0f64)?;
+        let (nan_count, inf_count, finite_count) = (count(&nan)?, count(&inf)?, count(&finite)?);
+        let (min, max, mean, std) = if finite_count == 0 {
+            (f64::NAN, f64::NAN, f64::NAN, f64::NAN)
+        } else {
+            let fill = |v: f64| Tensor::full(v, t.shape(), t.device())?.to_dtype(dtype);
+            let min = finite.where_cond(&t, &fill(f64::INFINITY)?)?.min(0)?;
+            let max = finite.where_cond(&t, &fill(f64::NEG_INFINITY)?)?.max(0)?;
+            let zeros = t.zeros_like()?;
+            let values = finite.where_cond(&t, &zeros)?;
+            let mean = (values.sum_all()? / finite_count as f64)?;
+            let centered = finite.where_cond(&values.broadcast_sub(&mean)?, &zeros)?;
+            let var = (centered.sqr()?.sum_all()? / finite_count as f64)?;
+            (
+                scalar(min)?,
+                scalar(max)?,
+                scalar(mean)?,
+                scalar(var)?.sqrt(),
+            )
+        };
+        Ok(TensorSummary {
+            shape: self.shape().clone(),
+            dtype: self.dtype(),
+            min,
+            max,
+            mean,
+            std,
+            nan_count,
+            inf_count,
+        })
+    }
+}
@@ -0,0 +1,603 @@
+//! Tensor exchange with other frameworks using [DLPack](https://dmlc.github.io/dlpack/latest/).
+//!
+//! [`Tensor::to_dlpack`] shares the memory of a cpu or cuda tensor without copying it, the
+//! returned [`DLPackTensor`] keeps the tensor alive until the consumer calls its deleter. Use
+//! [`DLPackTensor::into_raw`] to hand the `DLManagedTensor` to a consumer, e.g. in a
+//! `dltensor` python capsule for `torch.from_dlpack`. The stream on which the consumer reads a
+//! cuda tensor is passed to [`Tensor::to_dlpack_with_stream`], as for the `stream` argument of
+//! `__dlpack__`.
+//!
+//! [`Tensor::from_dlpack`] imports a tensor from another framework without copying it either,
+//! the resulting tensor and its views use the producer's buffer and the producer's deleter is
+//! only called once all of them have been dropped. The data is copied in the rare cases where
+//! it cannot be shared: negative strides or a buffer that is not aligned for its dtype.
+//!
+//! ```ignore
+//! // Rust side of a pyo3 binding.
+//! let managed = tensor.to_dlpack()?.into_raw();
+//! let capsule = unsafe { pyo3::ffi::PyCapsule_New(managed.cast(), c"dltensor".as_ptr(), None) };
+//! ```
+use crate::op::BackpropOp;
+use crate::tensor::{from_foreign_storage, from_storage};
+use crate::{CpuStorage, DType, Device, DeviceLocation, Layout, Result, Shape, Storage, Tensor};
+use std::ffi::c_void;
+use std::sync::{Arc, RwLock};
+
+pub const DL_CPU: i32 = 1;
+pub const DL_CUDA: i32 = 2;
+pub const DL_CUDA_HOST: i32 = 3;
+
+pub const DL_INT: u8 = 0;
+pub const DL_UINT: u8 = 1;
+pub const DL_FLOAT: u8 = 2;
+pub const DL_BFLOAT: u8 = 4;
+pub const DL_BOOL: u8 = 6;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DLDevice {
+    pub device_type: i32,
+    pub device_id: i32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DLDataType {
+    pub code: u8,
+    pub bits: u8,
+    pub lanes: u16,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct DLTensor {
+    pub data: *mut c_void,
+    pub device: DLDevice,
+    pub ndim: i32,
+    pub dtype: DLDataType,
+    pub shape: *mut i64,
+    /// The strides in number of elements, a null pointer means a contiguous row-major tensor.
+    pub strides: *mut i64,
+    pub byte_offset: u64,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct DLManagedTensor {
+    pub dl_tensor: DLTensor,
+    pub manager_ctx: *mut c_void,
+    pub deleter: Option<unsafe extern "C" fn(*mut DLManagedTensor)>,
+}
+
+impl From<DType> for DLDataType {
+    fn from(dtype: DType) -> Self {
+        let code = match dtype {
+            DType::U8 | DType::U32 => DL_UINT,
+            DType::I64 => DL_INT,
+            DType::BF16 => DL_BFLOAT,
+            DType::F16 | DType::F32 | DType::F64 => DL_FLOAT,
+        };
+        Self {
+            code,
+            bits: (dtype.size_in_bytes() * 8) as u8,
+            lanes: 1,
+        }
+    }
+}
+
+impl TryFrom<DLDataType> for DType {
+    type Error = crate::Error;
+
+    fn try_from(dtype: DLDataType) -> Result<Self> {
+        let dt = match (dtype.code, dtype.bits, dtype.lanes) {
+            (DL_UINT, 8, 1) | (DL_BOOL, 8, 1) => DType::U8,
+            (DL_UINT, 32, 1) => DType::U32,
+            (DL_INT, 64, 1) => DType::I64,
+            (DL_BFLOAT, 16, 1) => DType::BF16,
+            (DL_FLOAT, 16, 1) => DType::F16,
+            (DL_FLOAT, 32, 1) => DType::F32,
+            (DL_FLOAT, 64, 1) => DType::F64,
+            _ => crate::bail!("unsupported dlpack dtype {dtype:?}"),
+        };
+        Ok(dt)
+    }
+}
+
+// Owns the exported tensor and the shape and strides arrays pointed to by the `DLTensor`.
+struct ExportContext {
+    _tensor: Tensor,
+    shape: Vec<i64>,
+    strides: Vec<i64>,
+}
+
+unsafe extern "C" fn delete_exported(managed: *mut DLManagedTensor) {
+    if managed.is_null() {
+        return;
+    }
+    let managed = Box::from_raw(managed);
+    drop(Box::from_raw(managed.manager_ctx as *mut ExportContext));
+}
+
+/// An owned `DLManagedTensor`, the deleter is called on drop unless the tensor has been passed
+/// to a consumer with [`DLPackTensor::into_raw`].
+#[derive(Debug)]
+pub struct DLPackTensor(std::ptr::NonNull<DLManagedTensor>);
+
+impl DLPackTensor {
+    /// Takes ownership of a `DLManagedTensor` produced by another framework.
+    ///
+    /// # Safety
+    ///
+    /// The pointer must point to a valid `DLManagedTensor` that is not used elsewhere, its data
+    /// must remain valid until the deleter is called.
+    pub unsafe fn from_raw(managed: *mut DLManagedTensor) -> Result<Self> {
+        match std::ptr::NonNull::new(managed) {
+            None => crate::bail!("null dlpack tensor"),
+            Some(managed) => Ok(Self(managed)),
+        }
+    }
+
+    /// Returns the underlying pointer, the receiver is responsible for calling its deleter.
+    pub fn into_raw(self) -> *mut DLManagedTensor {
+        let ptr = self.0.as_ptr();
+        std::mem::forget(self);
+        ptr
+    }
+
+    pub fn dl_tensor(&self) -> &DLTensor {
+        // SAFETY: the pointer is valid until the deleter is called in drop.
+        unsafe { &self.0.as_ref().dl_tensor }
+    }
+
+    pub fn dims(&self) -> Vec<usize> {
+        let t = self.dl_tensor();
+        if t.ndim == 0 {
+            return vec![];
+        }
+        // SAFETY: shape points to ndim values.
+        let shape = unsafe { std::slice::from_raw_parts(t.shape, t.ndim as usize) };
+        shape.iter().map(|&d| d as usize).collect()
+    }
+
+    /// The strides in number of elements, computed from the shape for row-major tensors without
+    /// strides.
+    pub fn strides(&self) -> Vec<i64> {
+        let t = self.dl_tensor();
+        if t.strides.is_null() || t.ndim == 0 {
+            let dims = self.dims();
+            let stride = Shape::from(dims).stride_contiguous();
+            return stride.into_iter().map(|s| s as i64).collect();
+        }
+        // SAFETY: strides points to ndim values.
+        unsafe { std::slice::from_raw_parts(t.strides, t.ndim as usize) }.to_vec()
+    }
+}
+
+// SAFETY: the DLPack tensors are only read through shared references and the deleter can be
+// called from any thread.
+unsafe impl Send for DLPackTensor {}
+unsafe impl Sync for DLPackTensor {}
+
+impl Drop for DLPackTensor {
+    fn drop(&mut self) {
+        // SAFETY: the pointer is owned and has not been deleted yet.
+        unsafe {
+            if let Some(deleter) = self.0.as_ref().deleter {
+                deleter(self.0.as_ptr())
+            }
+        }
+    }
+}
+
+/// A buffer allocated by another framework and wrapped in a candle storage, held by all the
+/// tensors using this storage.
+///
+/// The storage cannot be dropped as candle did not allocate its memory. Once the last tensor
+/// using it is gone, the storage is forgotten and `release` hands the buffer back to its owner.
+pub(crate) struct ForeignBuffer {
+    storage: Option<Arc<RwLock<Storage>>>,
+    release: Option<Box<dyn FnOnce() + Send + Sync>>,
+}
+
+impl ForeignBuffer {
+    pub(crate) fn new(
+        storage: Arc<RwLock<Storage>>,
+        release: Box<dyn FnOnce() + Send + Sync>,
+    ) -> Self {
+        Self {
+            storage: Some(storage),
+            release: Some(release),
+        }
+    }
+}
+
+impl Drop for ForeignBuffer {
+    fn drop(&mut self) {
+        let storage = match self.storage.take() {
+            None => return,
+            Some(storage) => storage,
+        };
+        match Arc::try_unwrap(storage) {
+            Ok(storage) => {
+                // This only leaks the storage handle, e.g. the `Vec` header or the device
+                // reference of a cuda slice, the memory is released by its owner.
+                std::mem::forget(storage.into_inner().unwrap_or_else(|e| e.into_inner()));
+                if let Some(release) = self.release.take() {
+                    release()
+                }
+            }
+            // The storage is still referenced so the buffer cannot be released, this should not
+            // happen as all the tensors sharing a storage share its foreign buffer.
+            Err(storage) => std::mem::forget(storage),
+        }
+    }
+}
+
+pub(crate) fn cpu_data_ptr(storage: &CpuStorage) -> *mut c_void {
+    let ptr = match storage {
+        CpuStorage::U8(v) => v.as_ptr() as *const c_void,
+        CpuStorage::U32(v) => v.as_ptr() as *const c_void,
+        CpuStorage::I64(v) => v.as_ptr() as *const c_void,
+        CpuStorage::BF16(v) => v.as_ptr() as *const c_void,
+        CpuStorage::F16(v) => v.as_ptr() as *const c_void,
+        CpuStorage::F32(v) => v.as_ptr() as *const c_void,
+        CpuStorage::F64(v) => v.as_ptr() as *const c_void,
+    };
+    ptr as *mut c_void
+}
+
+#[cfg(feature = "cuda")]
+pub(crate) fn cuda_data_ptr(storage: &crate::CudaStorage) -> *mut c_void {
+    use crate::cuda_backend::CudaStorageSlice as S;
+    use cudarc::driver::DevicePtr;
+    let ptr = match &storage.slice {
+        S::U8(s) => *s.device_ptr(),
+        S::U32(s) => *s.device_ptr(),
+        S::I64(s) => *s.device_ptr(),
+        S::BF16(s) => *s.device_ptr(),
+        S::F16(s) => *s.device_ptr(),
+        S::F32(s) => *s.device_ptr(),
+        S::F64(s) => *s.device_ptr(),
+    };
+    ptr as *mut c_void
+}
+
+/// Checks that a device pointer has been allocated in the context of `dev`.
+#[cfg(feature = "cuda")]
+pub(crate) fn check_cuda_ptr(dev: &crate::CudaDevice, ptr: *const u8) -> Result<()> {
+    use crate::cuda_backend::WrapErr;
+    use cudarc::driver::sys;
+    let mut ordinal = -1i32;
+    unsafe {
+        sys::lib().cuPointerGetAttribute(
+            (&mut ordinal as *mut i32).cast(),
+            sys::CUpointer_attribute::CU_POINTER_ATTRIBUTE_DEVICE_ORDINAL,
+            ptr as sys::CUdeviceptr,
+        )
+    }
+    .result()
+    .w()?;
+    if ordinal as usize != dev.ordinal() {
+        crate::bail!(
+            "the pointer belongs to device {ordinal}, expected device {}",
+            dev.ordinal()
+        )
+    }
+    Ok(())
+}
+
+// Gathers the elements of a strided tensor in row-major order.
+//
+// SAFETY: `base` must be valid for all the offsets given by `dims` and `strides`.
+unsafe fn gather<T: Copy>(base: *const T, dims: &[usize], strides: &[i64]) -> Vec<T> {
+    let elem_count = dims.iter().product::<usize>();
+    let mut data = Vec::with_capacity(elem_count);
+    let mut index = vec![0usize; dims.len()];
+    let mut offset = 0i64;
+    for _ in 0..elem_count {
+        data.push(base.offset(offset as isize).read_unaligned());
+        for d in (0..dims.len()).rev() {
+            index[d] += 1;
+            offset += strides[d];
+            if index[d] < dims[d] {
+                break;
+            }
+            offset -= strides[d] * dims[d] as i64;
+            index[d] = 0;
+        }
+    }
+    data
+}
+
+impl Tensor {
+    /// Exports the tensor as DLPack without copying its data, see the [module](crate::dlpack)
+    /// documentation. Cuda tensors are made ready for consumers using the legacy default stream.
+    pub fn to_dlpack(&self) -> Result<DLPackTensor> {
+        self.to_dlpack_with_stream(None)
+    }
+
+    /// Exports the tensor as DLPack for a consumer that reads it on `stream`, using the values of
+    /// the `stream` argument of `__dlpack__` for cuda: `None` or 1 for the legacy default stream,
+    /// 2 for the per-thread default stream, -1 to skip the synchronization and otherwise the
+    /// `CUstream` handle. The consumer stream waits for the kernels queued by candle on the
+    /// tensor, without blocking the host. The stream is ignored for cpu tensors.
+    pub fn to_dlpack_with_stream(&self, stream: Option<i64>) -> Result<DLPackTensor> {
+        let (data, device) = match &*self.storage() {
+            Storage::Cpu(storage) => {
+                let device = DLDevice {
+                    device_type: DL_CPU,
+                    device_id: 0,
+                };
+                (cpu_data_ptr(storage), device)
+            }
+            #[cfg(feature = "cuda")]
+            Storage::Cuda(storage) => {
+                let device_id = match self.device().location() {
+                    DeviceLocation::Cuda { gpu_id } => gpu_id as i32,
+                    _ => 0,
+                };
+                let device = DLDevice {
+                    device_type: DL_CUDA,
+                    device_id,
+                };
+                match stream.unwrap_or(1) {
+                    -1 => {}
+                    0 => crate::bail!("dlpack stream 0 is ambiguous, use 1 or 2"),
+                    stream if stream < 0 => crate::bail!("invalid dlpack stream {stream}"),
+                    stream => {
+                        let stream = stream as usize as cudarc::driver::sys::CUstream;
+                        storage.device.record_event()?.wait_on(stream)?
+                    }
+                }
+                (cuda_data_ptr(storage), device)
+            }
+            _ => crate::bail!("dlpack export is not supported on {:?}", self.device()),
+        };
+        let layout = self.layout();
+        let mut ctx = Box::new(ExportContext {
+            _tensor: self.clone(),
+            shape: self.dims().iter().map(|&d| d as i64).collect(),
+            strides: layout.stride().iter().map(|&s| s as i64).collect(),
+        });
+        let dl_tensor = DLTensor {
+            data,
+            device,
+            ndim: self.rank() as i32,
+            dtype: self.dtype().into(),
+            shape: ctx.shape.as_mut_ptr(),
+            strides: ctx.strides.as_mut_ptr(),
+            byte_offset: (layout.start_offset() * self.dtype().size_in_bytes()) as u64,
+        };
+        let managed = Box::new(DLManagedTensor {
+            dl_tensor,
+            manager_ctx: Box::into_raw(ctx) as *mut c_void,
+            deleter: Some(delete_exported),
+        });
+        // SAFETY: the pointer comes from a box so is not null.
+        unsafe { DLPackTensor::from_raw(Box::into_raw(managed)) }
+    }
+
+    /// Imports a DLPack tensor on `device`, which must be the device of the DLPack tensor. The
+    /// data is shared rather than copied, see the [module](crate::dlpack) documentation, and the
+    /// tensor keeps the strides of the DLPack tensor.
+    ///
+    /// For cuda tensors, the producer should have made the data ready on the stream used by
+    /// `device`, e.g. by passing this stream to `__dlpack__`.
+    pub fn from_dlpack(tensor: DLPackTensor, device: &Device) -> Result<Tensor> {
+        let t = tensor.dl_tensor();
+        let dtype = DType::try_from(t.dtype)?;
+        let dims = tensor.dims();
+        let strides = tensor.strides();
+        let location = match (t.device.device_type, device.location()) {
+            (DL_CPU | DL_CUDA_HOST, DeviceLocation::Cpu) => DeviceLocation::Cpu,
+            (DL_CUDA, DeviceLocation::Cuda { gpu_id }) if t.device.device_id as usize == gpu_id => {
+                DeviceLocation::Cuda { gpu_id }
+            }
+            _ => crate::bail!(
+                "cannot import a dlpack tensor from {:?} on {:?}",
+                t.device,
+                device.location()
+            ),
+        };
+        // SAFETY: the producer guarantees that the data is valid until the deleter is called,
+        // which only happens once the imported tensor is not used anymore.
+        unsafe {
+            let base = (t.data as *mut u8).add(t.byte_offset as usize);
+            import_strided(
+                base,
+                dtype,
+                &dims,
+                &strides,
+                location,
+                device,
+                Box::new(move || drop(tensor)),
+            )
+        }
+    }
+}
+
+/// Creates a tensor sharing the strided data at `base`, `location` being the location of `device`
+/// and `strides` being in number of elements. `release` is called once the tensor and its views
+/// have been dropped. The data is copied to a new tensor, and `release` called right away, when
+/// it cannot be shared: negative strides or a pointer that is not aligned for `dtype`.
+///
+/// # Safety
+///
+/// `base` must be valid for all the offsets given by `dims` and `strides` on `location` until
+/// `release` is called.
+pub(crate) unsafe fn import_strided(
+    base: *mut u8,
+    dtype: DType,
+    dims: &[usize],
+    strides: &[i64],
+    location: DeviceLocation,
+    device: &Device,
+    release: Box<dyn FnOnce() + Send + Sync>,
+) -> Result<Tensor> {
+    let elem_count = dims.iter().product::<usize>();
+    #[cfg(feature = "cuda")]
+    if let (Device::Cuda(dev), true) = (device, elem_count > 0) {
+        check_cuda_ptr(dev, base)?
+    }
+    let shared = elem_count > 0
+        && strides.iter().all(|&s| s >= 0)
+        && base as usize % dtype.size_in_bytes() == 0;
+    if !shared {
+        let tensor = copy_strided(base, dtype, dims, strides, location, device)?;
+        release();
+        return Ok(tensor);
+    }
+    let strides = strides.iter().map(|&s| s as usize).collect::<Vec<_>>();
+    let len = 1 + dims
+        .iter()
+        .zip(strides.iter())
+        .map(|(&d, &s)| (d - 1) * s)
+        .sum::<usize>();
+    let storage = foreign_storage(base, dtype, len, location, device)?;
+    let layout = Layout::new(dims.into(), strides, 0);
+    Ok(from_foreign_storage(storage, layout, release))
+}
+
+/// Wraps the `len` elements at `base` in a storage without copying them, the storage must never
+/// be dropped, see [`ForeignBuffer`].
+///
+/// # Safety
+///
+/// `base` must be non-null, aligned for `dtype` and valid for `len` elements on `location`.
+pub(crate) unsafe fn foreign_storage(
+    base: *mut u8,
+    dtype: DType,
+    len: usize,
+    location: DeviceLocation,
+    device: &Device,
+) -> Result<Storage> {
+    match location {
+        DeviceLocation::Cpu => {
+            macro_rules! share {
+                ($ty:ty, $variant:ident) => {
+                    CpuStorage::$variant(Vec::from_raw_parts(base as *mut $ty, len, len))
+                };
+            }
+            let storage = match dtype {
+                DType::U8 => share!(u8, U8),
+                DType::U32 => share!(u32, U32),
+                DType::I64 => share!(i64, I64),
+                DType::BF16 => share!(half::bf16, BF16),
+                DType::F16 => share!(half::f16, F16),
+                DType::F32 => share!(f32, F32),
+                DType::F64 => share!(f64, F64),
+            };
+            Ok(Storage::Cpu(storage))
+        }
+        #[cfg(feature = "cuda")]
+        DeviceLocation::Cuda { .. } => {
+            use crate::cuda_backend::CudaStorageSlice as S;
+            let dev = match device {
+                Device::Cuda(dev) => dev,
+                _ => unreachable!(),
+            };
+            let ptr = base as cudarc::driver::sys::CUdeviceptr;
+            macro_rules! share {
+                ($ty:ty, $variant:ident) => {
+                    S::$variant(dev.upgrade_device_ptr::<$ty>(ptr, len))
+                };
+            }
+            let slice = match dtype {
+                DType::U8 => share!(u8, U8),
+                DType::U32 => share!(u32, U32),
+                DType::I64 => share!(i64, I64),
+                DType::BF16 => share!(half::bf16, BF16),
+                DType::F16 => share!(half::f16, F16),
+                DType::F32 => share!(f32, F32),
+                DType::F64 => share!(f64, F64),
+            };
+            let storage = crate::CudaStorage {
+                slice,
+                device: dev.clone(),
+            };
+            Ok(Storage::Cuda(storage))
+        }
+        _ => crate::bail!("import is not supported on {:?}", device.location()),
+    }
+}
+
+/// Copies the strided data at `base` to a new contiguous tensor on `device`, `location` being the
+/// location of `device` and `strides` being in number of elements. The data is gathered on cpu
+/// whereas cuda data has to be contiguous.
+///
+/// # Safety
+///
+/// `base` must be valid for all the offsets given by `dims` and `strides` on `location`.
+pub(crate) unsafe fn copy_strided(
+    base: *const u8,
+    dtype: DType,
+    dims: &[usize],
+    strides: &[i64],
+    location: DeviceLocation,
+    device: &Device,
+) -> Result<Tensor> {
+    if dims.iter().product::<usize>() == 0 {
+        return Tensor::zeros(dims, dtype, device);
+    }
+    match location {
+        DeviceLocation::Cpu => {
+            let storage = match dtype {
+                DType::U8 => CpuStorage::U8(gather(base as *const _, dims, strides)),
+                DType::U32 => CpuStorage::U32(gather(base as *const _, dims, strides)),
+                DType::I64 => CpuStorage::I64(gather(base as *const _, dims, strides)),
+                DType::BF16 => CpuStorage::BF16(gather(base as *const _, dims, strides)),
+                DType::F16 => CpuStorage::F16(gather(base as *const _, dims, strides)),
+                DType::F32 => CpuStorage::F32(gather(base as *const _, dims, strides)),
+                DType::F64 => CpuStorage::F64(gather(base as *const _, dims, strides)),
+            };
+            let storage = Storage::Cpu(storage);
+            Ok(from_storage(storage, dims, BackpropOp::none(), false))
+        }
+        #[cfg(feature = "cuda")]
+        DeviceLocation::Cuda { .. } => {
+            use crate::cuda_backend::{CudaStorageSlice as S, WrapErr};
+            use cudarc::driver::DevicePtr;
+            let contiguous = Shape::from(dims).stride_contiguous();
+            if dims
+                .iter()
+                .zip(contiguous.iter().zip(strides.iter()))
+                .any(|(&d, (&c, &s))| d > 1 && c as i64 != s)
+            {
+                crate::bail!("only contiguous cuda tensors can be imported")
+            }
+            let dev = match device {
+                Device::Cuda(dev) => dev,
+                _ => unreachable!(),
+            };
+            let elem_count = dims.iter().product::<usize>();
+            let num_bytes = elem_count * dtype.size_in_bytes();
+            let src = base as cudarc::driver::sys::CUdeviceptr;
+            macro_rules! copy {
+                ($ty:ty, $variant:ident) => {{
+                    let dst = unsafe { dev.alloc::<$ty>(elem_count) }.w()?;
+                    unsafe {
+                        cudarc::driver::result::memcpy_dtod_sync(*dst.device_ptr(), src, num_bytes)
+                    }
+                    .w()?;
+                    S::$variant(dst)
+                }};
+            }
+            let slice = match dtype {
+                DType::U8 => copy!(u8, U8),
+                DType::U32 => copy!(u32, U32),
+                DType::I64 => copy!(i64, I64),
+                DType::BF16 => copy!(half::bf16, BF16),
+                DType::F16 => copy!(half::f16, F16),
+                DType::F32 => copy!(f32, F32),
+                DType::F64 => copy!(f64, F64),
+            };
+            let storage = crate::CudaStorage {
+                slice,
+                device: dev.clone(),
+            };
+            let storage = Storage::Cuda(storage);
+            Ok(from_storage(storage, dims, BackpropOp::none(), false))
+        }
+        _ => crate::bail!("import is not supported on {:?}", device.location()),
+    }
+}
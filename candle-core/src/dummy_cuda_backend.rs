@@ -184,6 +184,91 @@ impl crate::backend::BackendStorage for CudaStorage {
     }
 }
 
+impl CudaDevice {
+    pub fn new_with_stream(_: usize) -> Result<Self> {
+        Err(Error::NotCompiledWithCudaSupport)
+    }
+
+    pub(crate) fn capabilities(&self) -> Result<crate::DeviceCapabilities> {
+        Err(Error::NotCompiledWithCudaSupport)
+    }
+
+    pub fn capture_graph<T, F: FnOnce() -> Result<T>>(&self, _: F) -> Result<(CudaGraph, T)> {
+        Err(Error::NotCompiledWithCudaSupport)
+    }
+
+    pub fn new_stream(&self) -> Result<CudaStream> {
+        Err(Error::NotCompiledWithCudaSupport)
+    }
+
+    pub fn record_event(&self) -> Result<CudaEvent> {
+        Err(Error::NotCompiledWithCudaSupport)
+    }
+
+    pub fn record_timing_event(&self) -> Result<CudaEvent> {
+        Err(Error::NotCompiledWithCudaSupport)
+    }
+}
+
+#[derive(Debug)]
+pub struct CudaGraph;
+
+impl CudaGraph {
+    pub fn device(&self) -> &CudaDevice {
+        fail!()
+    }
+
+    pub fn launch(&self) -> Result<()> {
+        Err(Error::NotCompiledWithCudaSupport)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CudaStream;
+
+impl CudaStream {
+    pub fn device(&self) -> &CudaDevice {
+        fail!()
+    }
+
+    pub fn record_event(&self) -> Result<CudaEvent> {
+        Err(Error::NotCompiledWithCudaSupport)
+    }
+
+    pub fn synchronize(&self) -> Result<()> {
+        Err(Error::NotCompiledWithCudaSupport)
+    }
+
+    pub fn storage_from_cpu_storage(&self, _: &CpuStorage) -> Result<CudaStorage> {
+        Err(Error::NotCompiledWithCudaSupport)
+    }
+}
+
+#[derive(Debug)]
+pub struct CudaEvent;
+
+impl CudaEvent {
+    pub fn device(&self) -> &CudaDevice {
+        fail!()
+    }
+
+    pub fn wait(&self) -> Result<()> {
+        Err(Error::NotCompiledWithCudaSupport)
+    }
+
+    pub fn synchronize(&self) -> Result<()> {
+        Err(Error::NotCompiledWithCudaSupport)
+    }
+
+    pub fn is_complete(&self) -> Result<bool> {
+        Err(Error::NotCompiledWithCudaSupport)
+    }
+
+    pub fn elapsed_since(&self, _: &CudaEvent) -> Result<std::time::Duration> {
+        Err(Error::NotCompiledWithCudaSupport)
+    }
+}
+
 impl crate::backend::BackendDevice for CudaDevice {
     type Storage = CudaStorage;
     fn new(_: usize) -> Result<Self> {
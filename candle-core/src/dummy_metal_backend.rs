@@ -196,6 +196,12 @@ impl crate::backend::BackendStorage for MetalStorage {
     }
 }
 
+impl MetalDevice {
+    pub(crate) fn capabilities(&self) -> Result<crate::DeviceCapabilities> {
+        Err(Error::NotCompiledWithMetalSupport)
+    }
+}
+
 impl crate::backend::BackendDevice for MetalDevice {
     type Storage = MetalStorage;
     fn new(_: usize) -> Result<Self> {
@@ -1,4 +1,4 @@
-use crate::{DType, DeviceLocation, Layout, MetalError, Shape};
+use crate::{DType, DeviceLocation, Layout, MetalError, Shape, Tensor};
 
 #[derive(Debug, Clone)]
 pub struct MatMulUnexpectedStriding {
@@ -62,7 +62,7 @@ pub enum Error {
     #[error(
         "Shape mismatch, got buffer of size {buffer_size} which is compatible with shape {shape:?}"
     )]
-    ShapeMismatch { buffer_size: usize, shape: Shape },
+    BufferSizeMismatch { buffer_size: usize, shape: Shape },
 
     #[error("shape mismatch in {op}, lhs: {lhs:?}, rhs: {rhs:?}")]
     ShapeMismatchBinaryOp {
@@ -71,6 +71,13 @@ pub enum Error {
         op: &'static str,
     },
 
+    #[error("shape mismatch in {op}, expected: {expected:?}, got: {got:?}")]
+    ShapeMismatch {
+        expected: Shape,
+        got: Shape,
+        op: &'static str,
+    },
+
     #[error("shape mismatch in cat for dim {dim}, shape for arg 1: {first_shape:?} shape for arg {n}: {nth_shape:?}")]
     ShapeMismatchCat {
         dim: usize,
@@ -100,6 +107,15 @@ pub enum Error {
         op: &'static str,
     },
 
+    /// An operation that a backend does not implement, or only implements for some dtypes in
+    /// which case `dtype` is the dtype that the operation was called with.
+    #[error("{op} is not supported on {backend}{}", dtype.map(|d| format!(" for {d:?}")).unwrap_or_default())]
+    UnsupportedOp {
+        backend: &'static str,
+        op: &'static str,
+        dtype: Option<DType>,
+    },
+
     // === Op Specific Errors ===
     #[error("narrow invalid args {msg}: {shape:?}, dim: {dim}, start: {start}, len:{len}")]
     NarrowInvalidArgs {
@@ -158,6 +174,14 @@ pub enum Error {
     #[error("cannot find tensor {path}")]
     CannotFindTensor { path: String },
 
+    #[error("the operation has been cancelled")]
+    Cancelled,
+
+    #[error(
+        "the backward of {op} produced a non-finite gradient, the op was created at:\n{trace}"
+    )]
+    AnomalousGradient { op: String, trace: String },
+
     // === Wrapped Errors ===
     #[error(transparent)]
     Cuda(Box<dyn std::error::Error + Send + Sync>),
@@ -207,6 +231,13 @@ pub enum Error {
         backtrace: Box<std::backtrace::Backtrace>,
     },
 
+    /// Adding a stack of operation and module contexts to an error, innermost first.
+    #[error("{inner}{}", context.iter().map(|c| format!("\n  in {c}")).collect::<String>())]
+    WithContext {
+        inner: Box<Self>,
+        context: Vec<ErrorContext>,
+    },
+
     /// User generated error message, typically created via `bail!`.
     #[error("{0}")]
     Msg(String),
@@ -214,7 +245,113 @@ pub enum Error {
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// A machine readable classification of errors, the string returned by [`ErrorCode::as_str`]
+/// is stable so that it can be used by downstream services, e.g. in logs or api responses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ErrorCode {
+    DTypeMismatch,
+    UnsupportedDType,
+    ShapeMismatch,
+    RankMismatch,
+    InvalidDim,
+    InvalidArgument,
+    UnsupportedLayout,
+    DeviceMismatch,
+    UnsupportedOp,
+    BackwardNotSupported,
+    BackendNotAvailable,
+    BackendError,
+    NotFound,
+    Cancelled,
+    AnomalousGradient,
+    Io,
+    Serialization,
+    Other,
+}
+
+impl ErrorCode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::DTypeMismatch => "dtype_mismatch",
+            Self::UnsupportedDType => "unsupported_dtype",
+            Self::ShapeMismatch => "shape_mismatch",
+            Self::RankMismatch => "rank_mismatch",
+            Self::InvalidDim => "invalid_dim",
+            Self::InvalidArgument => "invalid_argument",
+            Self::UnsupportedLayout => "unsupported_layout",
+            Self::DeviceMismatch => "device_mismatch",
+            Self::UnsupportedOp => "unsupported_op",
+            Self::BackwardNotSupported => "backward_not_supported",
+            Self::BackendNotAvailable => "backend_not_available",
+            Self::BackendError => "backend_error",
+            Self::NotFound => "not_found",
+            Self::Cancelled => "cancelled",
+            Self::AnomalousGradient => "anomalous_gradient",
+            Self::Io => "io",
+            Self::Serialization => "serialization",
+            Self::Other => "other",
+        }
+    }
+}
+
+impl std::fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
 impl Error {
+    /// The code for this error, the path, backtrace and context wrappers are looked through.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::UnexpectedDType { .. } | Self::DTypeMismatchBinaryOp { .. } => {
+                ErrorCode::DTypeMismatch
+            }
+            Self::UnsupportedDTypeForOp(..) | Self::UnsupportedSafeTensorDtype(_) => {
+                ErrorCode::UnsupportedDType
+            }
+            Self::DimOutOfRange { .. } | Self::DuplicateDimIndex { .. } => ErrorCode::InvalidDim,
+            Self::UnexpectedNumberOfDims { .. } => ErrorCode::RankMismatch,
+            Self::UnexpectedShape { .. }
+            | Self::BufferSizeMismatch { .. }
+            | Self::ShapeMismatch { .. }
+            | Self::ShapeMismatchBinaryOp { .. }
+            | Self::ShapeMismatchCat { .. }
+            | Self::ShapeMismatchSplit { .. }
+            | Self::BroadcastIncompatibleShapes { .. } => ErrorCode::ShapeMismatch,
+            Self::OnlySingleDimension { .. }
+            | Self::EmptyTensor { .. }
+            | Self::NarrowInvalidArgs { .. }
+            | Self::Conv1dInvalidArgs { .. }
+            | Self::InvalidIndex { .. }
+            | Self::CannotSetVar { .. }
+            | Self::OpRequiresAtLeastOneTensor { .. }
+            | Self::OpRequiresAtLeastTwoTensors { .. }
+            | Self::TryFromIntError(_)
+            | Self::ParseInt(_) => ErrorCode::InvalidArgument,
+            Self::MatMulUnexpectedStriding(_) | Self::RequiresContiguous { .. } => {
+                ErrorCode::UnsupportedLayout
+            }
+            Self::DeviceMismatchBinaryOp { .. } => ErrorCode::DeviceMismatch,
+            Self::UnsupportedOp { .. } => ErrorCode::UnsupportedOp,
+            Self::BackwardNotSupported { .. } => ErrorCode::BackwardNotSupported,
+            Self::NotCompiledWithCudaSupport | Self::NotCompiledWithMetalSupport => {
+                ErrorCode::BackendNotAvailable
+            }
+            Self::Cuda(_) | Self::Metal(_) => ErrorCode::BackendError,
+            Self::CannotFindTensor { .. } => ErrorCode::NotFound,
+            Self::Cancelled => ErrorCode::Cancelled,
+            Self::AnomalousGradient { .. } => ErrorCode::AnomalousGradient,
+            Self::Io(_) => ErrorCode::Io,
+            Self::Npy(_) | Self::Zip(_) | Self::SafeTensor(_) => ErrorCode::Serialization,
+            Self::Wrapped(_) | Self::Msg(_) => ErrorCode::Other,
+            Self::WithPath { inner, .. }
+            | Self::WithBacktrace { inner, .. }
+            | Self::WithContext { inner, .. } => inner.code(),
+        }
+    }
+
     pub fn wrap(err: impl std::error::Error + Send + Sync + 'static) -> Self {
         Self::Wrapped(Box::new(err)).bt()
     }
@@ -245,6 +382,141 @@ impl Error {
             path: p.as_ref().to_path_buf(),
         }
     }
+
+    /// Pushes a context frame on the error context stack, frames are added from the innermost
+    /// to the outermost one as the error is propagated.
+    pub fn add_context(self, context: ErrorContext) -> Self {
+        match self {
+            Self::WithContext {
+                inner,
+                context: mut stack,
+            } => {
+                stack.push(context);
+                Self::WithContext {
+                    inner,
+                    context: stack,
+                }
+            }
+            inner => Self::WithContext {
+                inner: Box::new(inner),
+                context: vec![context],
+            },
+        }
+    }
+
+    /// The context stack attached to this error, innermost first. This is empty if no context
+    /// was added.
+    pub fn context(&self) -> &[ErrorContext] {
+        match self {
+            Self::WithContext { context, .. } => context,
+            Self::WithPath { inner, .. } | Self::WithBacktrace { inner, .. } => inner.context(),
+            _ => &[],
+        }
+    }
+}
+
+/// A frame of the error context stack: the operation that failed together with the shapes of
+/// its inputs, and/or the path of the module it was called from, e.g. `model.layers.3.mlp`.
+///
+/// This is cheap to record as it is only built on the error path, and points at the failing
+/// layer without requiring `RUST_BACKTRACE` to be set.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorContext {
+    pub op: Option<&'static str>,
+    pub module: Option<String>,
+    pub shapes: Vec<Shape>,
+}
+
+impl ErrorContext {
+    pub fn op(op: &'static str, shapes: Vec<Shape>) -> Self {
+        Self {
+            op: Some(op),
+            module: None,
+            shapes,
+        }
+    }
+
+    pub fn module(path: impl Into<String>) -> Self {
+        Self {
+            op: None,
+            module: Some(path.into()),
+            shapes: vec![],
+        }
+    }
+
+    /// Records the path of the module that ran the operation in the same frame.
+    pub fn in_module(self, path: impl Into<String>) -> Self {
+        Self {
+            module: Some(path.into()),
+            ..self
+        }
+    }
+}
+
+impl std::fmt::Display for ErrorContext {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(module) = &self.module {
+            write!(f, "{module}")?;
+            if self.op.is_some() {
+                write!(f, ": ")?;
+            }
+        }
+        if let Some(op) = self.op {
+            let shapes = self
+                .shapes
+                .iter()
+                .map(|s| format!("{:?}", s.dims()))
+                .collect::<Vec<_>>();
+            write!(f, "{op}({})", shapes.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Attaches context frames to the error of a result, see [`ErrorContext`].
+pub trait ResultExt<T> {
+    /// Records the name of the failing operation and the shapes of its inputs.
+    fn op_context(self, op: &'static str, inputs: &[&Tensor]) -> Result<T>;
+
+    /// Records the path of the module the error went through.
+    fn module_context(self, path: &str) -> Result<T>;
+
+    /// Same as `op_context` for an operation run by the module at `path`, e.g. a layer built
+    /// through a `VarBuilder`, the operation and the path being recorded in a single frame.
+    /// Empty paths are ignored.
+    fn module_op_context(
+        self,
+        path: Option<&str>,
+        op: &'static str,
+        inputs: &[&Tensor],
+    ) -> Result<T>;
+}
+
+impl<T> ResultExt<T> for Result<T> {
+    fn op_context(self, op: &'static str, inputs: &[&Tensor]) -> Result<T> {
+        self.module_op_context(None, op, inputs)
+    }
+
+    fn module_context(self, path: &str) -> Result<T> {
+        self.map_err(|err| err.add_context(ErrorContext::module(path)))
+    }
+
+    fn module_op_context(
+        self,
+        path: Option<&str>,
+        op: &'static str,
+        inputs: &[&Tensor],
+    ) -> Result<T> {
+        self.map_err(|err| {
+            let shapes = inputs.iter().map(|t| t.shape().clone()).collect();
+            let context = ErrorContext::op(op, shapes);
+            let context = match path {
+                Some(path) if !path.is_empty() => context.in_module(path),
+                _ => context,
+            };
+            err.add_context(context)
+        })
+    }
 }
 
 #[macro_export]
@@ -0,0 +1,74 @@
+//! Cpu fallback for the operations that a backend does not support.
+//!
+//! By default, an operation that is not implemented on a device, e.g. some dtype/op combinations
+//! on metal, results in an error. With the [`FallbackPolicy::Cpu`] policy, the inputs are instead
+//! copied to the cpu, the operation is run there and the result is moved back to the original
+//! device. A warning is printed the first time an operation falls back and the number of
+//! fallbacks per operation can be retrieved with [`fallback_counts`].
+//!
+//! Only the [`ErrorCode::UnsupportedOp`](crate::ErrorCode::UnsupportedOp) errors fall back, this
+//! includes the custom ops that do not implement the device forward pass. Other backend errors,
+//! e.g. running out of memory, are returned as is.
+//!
+//! ```ignore
+//! candle::fallback::set_fallback_policy(candle::fallback::FallbackPolicy::Cpu);
+//! let ys = model.forward(&xs)?;
+//! for (op, count) in candle::fallback::fallback_counts() {
+//!     println!("{op} ran {count} times on the cpu");
+//! }
+//! ```
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+/// What to do when a backend does not support an operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FallbackPolicy {
+    /// Return the backend error.
+    #[default]
+    Error,
+    /// Run the operation on the cpu and move the result back to the original device.
+    Cpu,
+}
+
+static CPU_FALLBACK: AtomicBool = AtomicBool::new(false);
+static COUNTS: Mutex<BTreeMap<&'static str, usize>> = Mutex::new(BTreeMap::new());
+
+/// Sets the fallback policy for all the devices, this is [`FallbackPolicy::Error`] by default.
+pub fn set_fallback_policy(policy: FallbackPolicy) {
+    CPU_FALLBACK.store(policy == FallbackPolicy::Cpu, Ordering::Relaxed)
+}
+
+pub fn fallback_policy() -> FallbackPolicy {
+    if CPU_FALLBACK.load(Ordering::Relaxed) {
+        FallbackPolicy::Cpu
+    } else {
+        FallbackPolicy::Error
+    }
+}
+
+/// The number of times each operation fell back to the cpu, sorted by operation name.
+pub fn fallback_counts() -> Vec<(&'static str, usize)> {
+    let counts = COUNTS.lock().unwrap_or_else(|e| e.into_inner());
+    counts.iter().map(|(&op, &count)| (op, count)).collect()
+}
+
+pub fn reset_fallback_counts() {
+    COUNTS.lock().unwrap_or_else(|e| e.into_inner()).clear()
+}
+
+pub(crate) fn cpu_fallback_enabled() -> bool {
+    CPU_FALLBACK.load(Ordering::Relaxed)
+}
+
+pub(crate) fn record(op: &'static str, device: &crate::Device, err: &crate::Error) {
+    let mut counts = COUNTS.lock().unwrap_or_else(|e| e.into_inner());
+    let count = counts.entry(op).or_default();
+    if *count == 0 {
+        eprintln!(
+            "warning: {op} is not supported on {:?}, running it on the cpu: {err}",
+            device.location()
+        )
+    }
+    *count += 1;
+}
@@ -0,0 +1,215 @@
+//! Content fingerprinting for tensors.
+use crate::{CpuStorage, Device, Result, Storage, Tensor};
+use half::{bf16, f16};
+
+const PRIME1: u64 = 0x9E3779B185EBCA87;
+const PRIME2: u64 = 0xC2B2AE3D27D4EB4F;
+const PRIME3: u64 = 0x165667B19E3779F9;
+const PRIME4: u64 = 0x85EBCA77C2B2AE63;
+const PRIME5: u64 = 0x27D4EB2F165667C5;
+
+fn read_u64(b: &[u8]) -> u64 {
+    u64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]])
+}
+
+fn read_u32(b: &[u8]) -> u32 {
+    u32::from_le_bytes([b[0], b[1], b[2], b[3]])
+}
+
+fn round(acc: u64, input: u64) -> u64 {
+    acc.wrapping_add(input.wrapping_mul(PRIME2))
+        .rotate_left(31)
+        .wrapping_mul(PRIME1)
+}
+
+fn merge_round(acc: u64, val: u64) -> u64 {
+    (acc ^ round(0, val))
+        .wrapping_mul(PRIME1)
+        .wrapping_add(PRIME4)
+}
+
+/// A streaming implementation of the XXH64 hash function.
+struct Xxh64 {
+    acc: [u64; 4],
+    buffer: [u8; 32],
+    buffered: usize,
+    total_len: u64,
+    seed: u64,
+}
+
+impl Xxh64 {
+    fn new(seed: u64) -> Self {
+        Self {
+            acc: [
+                seed.wrapping_add(PRIME1).wrapping_add(PRIME2),
+                seed.wrapping_add(PRIME2),
+                seed,
+                seed.wrapping_sub(PRIME1),
+            ],
+            buffer: [0u8; 32],
+            buffered: 0,
+            total_len: 0,
+            seed,
+        }
+    }
+
+    fn stripe(&mut self, b: &[u8]) {
+        for (i, acc) in self.acc.iter_mut().enumerate() {
+            *acc = round(*acc, read_u64(&b[8 * i..]))
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        self.total_len += data.len() as u64;
+        if self.buffered > 0 {
+            let n = usize::min(32 - self.buffered, data.len());
+            self.buffer[self.buffered..self.buffered + n].copy_from_slice(&data[..n]);
+            self.buffered += n;
+            data = &data[n..];
+            if self.buffered < 32 {
+                return;
+            }
+            let buffer = self.buffer;
+            self.stripe(&buffer);
+            self.buffered = 0;
+        }
+        let mut chunks = data.chunks_exact(32);
+        for chunk in chunks.by_ref() {
+            self.stripe(chunk)
+        }
+        let rem = chunks.remainder();
+        self.buffer[..rem.len()].copy_from_slice(rem);
+        self.buffered = rem.len();
+    }
+
+    fn finish(&self) -> u64 {
+        let mut h = if self.total_len >= 32 {
+            let [a1, a2, a3, a4] = self.acc;
+            let h = a1
+                .rotate_left(1)
+                .wrapping_add(a2.rotate_left(7))
+                .wrapping_add(a3.rotate_left(12))
+                .wrapping_add(a4.rotate_left(18));
+            let h = merge_round(h, a1);
+            let h = merge_round(h, a2);
+            let h = merge_round(h, a3);
+            merge_round(h, a4)
+        } else {
+            self.seed.wrapping_add(PRIME5)
+        };
+        h = h.wrapping_add(self.total_len);
+        let mut rem = &self.buffer[..self.buffered];
+        while rem.len() >= 8 {
+            h ^= round(0, read_u64(rem));
+            h = h.rotate_left(27).wrapping_mul(PRIME1).wrapping_add(PRIME4);
+            rem = &rem[8..];
+        }
+        if rem.len() >= 4 {
+            h ^= (read_u32(rem) as u64).wrapping_mul(PRIME1);
+            h = h.rotate_left(23).wrapping_mul(PRIME2).wrapping_add(PRIME3);
+            rem = &rem[4..];
+        }
+        for &b in rem {
+            h ^= (b as u64).wrapping_mul(PRIME5);
+            h = h.rotate_left(11).wrapping_mul(PRIME1);
+        }
+        h ^= h >> 33;
+        h = h.wrapping_mul(PRIME2);
+        h ^= h >> 29;
+        h = h.wrapping_mul(PRIME3);
+        h ^ (h >> 32)
+    }
+}
+
+fn as_bytes<T>(vs: &[T]) -> &[u8] {
+    // SAFETY: the slice is only used to read the underlying bytes of plain-old-data values.
+    unsafe { std::slice::from_raw_parts(vs.as_ptr() as *const u8, std::mem::size_of_val(vs)) }
+}
+
+// Hashes the values as little-endian bytes, on little-endian targets this reads the slice
+// memory directly.
+fn update_le<T: Copy, const N: usize>(hasher: &mut Xxh64, vs: &[T], to_le: fn(T) -> [u8; N]) {
+    if cfg!(target_endian = "little") {
+        hasher.update(as_bytes(vs))
+    } else {
+        for &v in vs.iter() {
+            hasher.update(&to_le(v))
+        }
+    }
+}
+
+impl Tensor {
+    /// Returns a fast, non-cryptographic hash of the tensor content.
+    ///
+    /// The fingerprint covers the dtype, the shape and the element values in row-major order. It
+    /// does not depend on the device or on the memory layout of the tensor, so two tensors with
+    /// the same fingerprint can be considered identical for caching or deduplication purposes.
+    /// The hash is computed with XXH64 on the little-endian representation of the values and is
+    /// stable across runs. Tensors that are not on the cpu are copied to the host first.
+    ///
+    /// ```rust
+    /// use candle_core::{Tensor, DType, Device};
+    /// let a = Tensor::arange(0f32, 6f32, &Device::Cpu)?.reshape((2, 3))?;
+    /// let b = Tensor::new(&[[0f32, 1., 2.], [3., 4., 5.]], &Device::Cpu)?;
+    /// assert_eq!(a.fingerprint()?, b.fingerprint()?);
+    /// assert_ne!(a.fingerprint()?, a.t()?.fingerprint()?);
+    /// assert_ne!(a.fingerprint()?, a.to_dtype(DType::F64)?.fingerprint()?);
+    /// # Ok::<(), candle_core::Error>(())
+    /// ```
+    pub fn fingerprint(&self) -> Result<u64> {
+        let mut hasher = Xxh64::new(0);
+        hasher.update(self.dtype().as_str().as_bytes());
+        hasher.update(&(self.rank() as u64).to_le_bytes());
+        for &d in self.dims() {
+            hasher.update(&(d as u64).to_le_bytes())
+        }
+        let tensor = match self.device() {
+            Device::Cpu => self.contiguous()?,
+            _ => self.to_device(&Device::Cpu)?.contiguous()?,
+        };
+        let (storage, layout) = tensor.storage_and_layout();
+        let (start, end) = match layout.contiguous_offsets() {
+            Some(offsets) => offsets,
+            None => crate::bail!("fingerprint: unexpected non-contiguous layout"),
+        };
+        let h = &mut hasher;
+        match &*storage {
+            Storage::Cpu(CpuStorage::U8(vs)) => h.update(&vs[start..end]),
+            Storage::Cpu(CpuStorage::U32(vs)) => update_le(h, &vs[start..end], u32::to_le_bytes),
+            Storage::Cpu(CpuStorage::I64(vs)) => update_le(h, &vs[start..end], i64::to_le_bytes),
+            Storage::Cpu(CpuStorage::BF16(vs)) => update_le(h, &vs[start..end], bf16::to_le_bytes),
+            Storage::Cpu(CpuStorage::F16(vs)) => update_le(h, &vs[start..end], f16::to_le_bytes),
+            Storage::Cpu(CpuStorage::F32(vs)) => update_le(h, &vs[start..end], f32::to_le_bytes),
+            Storage::Cpu(CpuStorage::F64(vs)) => update_le(h, &vs[start..end], f64::to_le_bytes),
+            _ => crate::bail!("fingerprint: expected a cpu tensor"),
+        }
+        Ok(hasher.finish())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Xxh64;
+
+    fn xxh64(data: &[u8], seed: u64) -> u64 {
+        let mut h = Xxh64::new(seed);
+        h.update(data);
+        h.finish()
+    }
+
+    #[test]
+    fn xxh64_reference() {
+        // Reference values from the xxHash implementation.
+        assert_eq!(xxh64(b"", 0), 0xEF46DB3751D8E999);
+        assert_eq!(xxh64(b"a", 0), 0xD24EC4F1A98C6E5B);
+        assert_eq!(xxh64(b"abc", 0), 0x44BC2CF5AD770999);
+        let data = b"Nobody inspects the spammish repetition";
+        assert_eq!(xxh64(data, 0), 0xFBCEA83C8A378BF1);
+        let data = (0..100u8).collect::<Vec<_>>();
+        let mut h = Xxh64::new(0);
+        for chunk in data.chunks(7) {
+            h.update(chunk)
+        }
+        assert_eq!(h.finish(), xxh64(&data, 0));
+    }
+}
@@ -46,6 +46,11 @@ impl Tensor {
                     current_dim += 1;
                     out
                 }
+                TensorIndexer::Slice(slice) => {
+                    let out = x.slice_step(current_dim, slice.start, slice.stop, slice.step)?;
+                    current_dim += 1;
+                    out
+                }
                 TensorIndexer::IndexSelect(indexes) => {
                     if indexes.rank() != 1 {
                         crate::bail!("multi-dimensional tensor indexing is not supported")
@@ -68,6 +73,8 @@ pub enum TensorIndexer {
     Select(usize),
     /// This is a regular slice, purely indexing a chunk of the tensor
     Narrow(Bound<usize>, Bound<usize>),
+    /// A slice with a step, following the NumPy semantics
+    Slice(Slice),
     /// Indexing via a 1d tensor
     IndexSelect(Tensor),
     Err(Error),
@@ -79,6 +86,42 @@ impl From<usize> for TensorIndexer {
     }
 }
 
+/// A NumPy style `start:stop:step` slice, see [`Tensor::slice_step`].
+///
+/// ```rust
+/// use candle_core::{Tensor, Device, IndexOp, Slice};
+/// let a = Tensor::new(&[[0u32, 1, 2, 3], [4, 5, 6, 7]], &Device::Cpu)?;
+/// // a[:, ::2]
+/// assert_eq!(a.i((.., Slice::step(2)))?.to_vec2::<u32>()?, &[[0, 2], [4, 6]]);
+/// // a[::-1, 1:]
+/// let b = a.i((Slice::step(-1), Slice::new(Some(1), None, 1)))?;
+/// assert_eq!(b.to_vec2::<u32>()?, &[[5, 6, 7], [1, 2, 3]]);
+/// # Ok::<(), candle_core::Error>(())
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Slice {
+    pub start: Option<isize>,
+    pub stop: Option<isize>,
+    pub step: isize,
+}
+
+impl Slice {
+    pub fn new(start: Option<isize>, stop: Option<isize>, step: isize) -> Self {
+        Self { start, stop, step }
+    }
+
+    /// The whole dimension with a step, e.g. `::-1` for `Slice::step(-1)`.
+    pub fn step(step: isize) -> Self {
+        Self::new(None, None, step)
+    }
+}
+
+impl From<Slice> for TensorIndexer {
+    fn from(slice: Slice) -> Self {
+        TensorIndexer::Slice(slice)
+    }
+}
+
 impl From<&[u32]> for TensorIndexer {
     fn from(index: &[u32]) -> Self {
         match Tensor::new(index, &crate::Device::Cpu) {
@@ -70,6 +70,17 @@ impl Layout {
         self.shape.is_fortran_contiguous(&self.stride)
     }
 
+    /// Returns true if the layout has four dimensions `(b, c, h, w)` and the data is stored in
+    /// the `(b, h, w, c)` order, aka NHWC or channels last.
+    pub fn is_channels_last(&self) -> bool {
+        match (self.dims(), self.stride()) {
+            (&[b, c, h, w], &[s0, s1, s2, s3]) => {
+                Shape::from((b, h, w, c)).is_contiguous(&[s0, s2, s3, s1])
+            }
+            _ => false,
+        }
+    }
+
     pub fn narrow(&self, dim: usize, start: usize, len: usize) -> Result<Self> {
         let dims = self.shape().dims();
         if dim >= dims.len() {
@@ -99,6 +110,52 @@ impl Layout {
         })
     }
 
+    /// Same as `narrow` but only keeping every `step` element, the `len` elements are at indexes
+    /// `start + i * step` of the dimension.
+    pub fn narrow_step(&self, dim: usize, start: usize, len: usize, step: usize) -> Result<Self> {
+        if step == 1 {
+            return self.narrow(dim, start, len);
+        }
+        let dims = self.shape().dims();
+        if dim >= dims.len() {
+            Err(Error::DimOutOfRange {
+                shape: self.shape().clone(),
+                dim: dim as i32,
+                op: "narrow-step",
+            }
+            .bt())?
+        }
+        let err = |msg| {
+            Err::<(), _>(
+                Error::NarrowInvalidArgs {
+                    shape: self.shape.clone(),
+                    dim,
+                    start,
+                    len,
+                    msg,
+                }
+                .bt(),
+            )
+        };
+        if step == 0 {
+            err("step must be positive")?
+        }
+        if len > 0 && start + (len - 1) * step >= dims[dim] {
+            err("start + (len - 1) * step >= dim_len")?
+        }
+        let mut dims = dims.to_vec();
+        dims[dim] = len;
+        let mut stride = self.stride.clone();
+        stride[dim] *= step;
+        // Empty views can have a start beyond the end of the dimension.
+        let start = usize::min(start, self.dims()[dim]);
+        Ok(Self {
+            shape: Shape::from(dims),
+            stride,
+            start_offset: self.start_offset + self.stride[dim] * start,
+        })
+    }
+
     pub fn transpose(&self, dim1: usize, dim2: usize) -> Result<Self> {
         let rank = self.shape.rank();
         if rank <= dim1 || rank <= dim2 {
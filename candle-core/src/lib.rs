@@ -35,10 +35,13 @@
 
 #[cfg(feature = "accelerate")]
 mod accelerate;
+pub mod array_interface;
 pub mod backend;
 pub mod backprop;
+mod cancel;
+pub mod collective;
 pub mod conv;
-mod convert;
+pub mod convert;
 pub mod cpu;
 pub mod cpu_backend;
 #[cfg(feature = "cuda")]
@@ -46,43 +49,56 @@ pub mod cuda_backend;
 mod custom_op;
 mod device;
 pub mod display;
+pub mod dlpack;
 mod dtype;
 pub mod dummy_cuda_backend;
 mod dummy_metal_backend;
 pub mod error;
+pub mod fallback;
+mod fingerprint;
 mod indexer;
 pub mod layout;
+pub mod meta_backend;
 #[cfg(feature = "metal")]
 pub mod metal_backend;
 #[cfg(feature = "mkl")]
 mod mkl;
 pub mod npy;
 pub mod op;
+pub mod op_manifest;
+pub mod philox;
 pub mod pickle;
+pub mod profile;
+pub mod promotion;
 pub mod quantized;
 pub mod safetensors;
 pub mod scalar;
 pub mod shape;
+pub mod shape_trace;
 mod sort;
 mod storage;
+pub mod stream_reduce;
 pub mod streaming;
 mod strided_index;
 mod tensor;
 mod tensor_cat;
 pub mod test_utils;
+pub mod typed;
 pub mod utils;
 mod variable;
 
 #[cfg(feature = "cudnn")]
 pub use cuda_backend::cudnn;
 
+pub use cancel::CancelToken;
 pub use cpu_backend::{CpuStorage, CpuStorageRef};
 pub use custom_op::{CustomOp1, CustomOp2, CustomOp3, InplaceOp1, InplaceOp2, InplaceOp3};
-pub use device::{Device, DeviceLocation, NdArray};
+pub use device::{Device, DeviceCapabilities, DeviceLocation, NdArray};
 pub use dtype::{DType, DTypeParseError, FloatDType, IntDType, WithDType};
-pub use error::{Error, Result};
-pub use indexer::IndexOp;
+pub use error::{Error, ErrorCode, ErrorContext, Result, ResultExt};
+pub use indexer::{IndexOp, Slice};
 pub use layout::Layout;
+pub use meta_backend::{MetaDevice, MetaStorage};
 pub use shape::{Shape, D};
 pub use storage::Storage;
 pub use streaming::{StreamTensor, StreamingBinOp, StreamingModule};
@@ -96,7 +112,7 @@ pub use cuda_backend as cuda;
 #[cfg(not(feature = "cuda"))]
 pub use dummy_cuda_backend as cuda;
 
-pub use cuda::{CudaDevice, CudaStorage};
+pub use cuda::{CudaDevice, CudaEvent, CudaGraph, CudaStorage, CudaStream};
 
 #[cfg(feature = "metal")]
 pub use metal_backend::{MetalDevice, MetalError, MetalStorage};
@@ -0,0 +1,414 @@
+//! A backend that only tracks the dtypes, the tensors on a meta device do not hold any data.
+//!
+//! The shapes are inferred by the tensor operations as on the other devices, so running a model
+//! on a meta device checks the shapes and dtypes of all the operations without allocating or
+//! computing anything. Each operation that produces a new storage is appended to the trace of
+//! the device, see [`crate::shape_trace::ShapeTracer`].
+use crate::backend::BackendStorage;
+use crate::op::{BinaryOpT, CmpOp, ReduceOp, UnaryOpT};
+use crate::shape_trace::{TensorSpec, TraceOp};
+use crate::{CpuStorage, DType, Layout, Result, Shape};
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone)]
+pub struct MetaDevice {
+    trace: Arc<Mutex<Vec<TraceOp>>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct MetaStorage {
+    dtype: DType,
+    device: MetaDevice,
+}
+
+fn spec(l: &Layout, dtype: DType) -> TensorSpec {
+    TensorSpec::new(l.shape().clone(), dtype)
+}
+
+impl MetaDevice {
+    /// Removes and returns the operations that have been recorded so far.
+    pub fn take_trace(&self) -> Vec<TraceOp> {
+        let mut trace = self.trace.lock().unwrap_or_else(|e| e.into_inner());
+        std::mem::take(&mut *trace)
+    }
+
+    pub(crate) fn capabilities(&self) -> Result<crate::DeviceCapabilities> {
+        Ok(crate::DeviceCapabilities {
+            name: "meta".to_string(),
+            dtypes: vec![
+                DType::U8,
+                DType::U32,
+                DType::I64,
+                DType::BF16,
+                DType::F16,
+                DType::F32,
+                DType::F64,
+            ],
+            matmul_dtypes: vec![DType::BF16, DType::F16, DType::F32, DType::F64],
+            tensor_cores: false,
+            compute_capability: None,
+            max_shared_memory: None,
+            unified_memory: false,
+            unsupported_ops: vec![],
+        })
+    }
+
+    fn storage(&self, dtype: DType) -> MetaStorage {
+        MetaStorage {
+            dtype,
+            device: self.clone(),
+        }
+    }
+}
+
+impl MetaStorage {
+    /// A storage with the same device and the given dtype, for the meta implementation of
+    /// custom ops.
+    pub fn with_dtype(&self, dtype: DType) -> Self {
+        self.device.storage(dtype)
+    }
+
+    /// Records an operation with the given inputs and output on the device trace and returns
+    /// the storage for the output.
+    pub fn record(&self, op: &'static str, inputs: Vec<TensorSpec>, output: TensorSpec) -> Self {
+        let storage = self.with_dtype(output.dtype);
+        let mut trace = self.device.trace.lock().unwrap_or_else(|e| e.into_inner());
+        trace.push(TraceOp { op, inputs, output });
+        storage
+    }
+
+    /// Records an operation whose output has the shape and dtype of its first input, e.g. a
+    /// normalization, for the meta implementation of custom ops.
+    pub fn record_like_first(
+        &self,
+        op: &'static str,
+        l: &Layout,
+        others: &[(&Self, &Layout)],
+    ) -> (Self, Shape) {
+        let output = spec(l, self.dtype);
+        let inputs = std::iter::once(output.clone())
+            .chain(others.iter().map(|(s, l)| spec(l, s.dtype)))
+            .collect();
+        (self.record(op, inputs, output), l.shape().clone())
+    }
+
+    fn record1(&self, op: &'static str, l: &Layout, shape: Shape, dtype: DType) -> Self {
+        let output = TensorSpec::new(shape, dtype);
+        self.record(op, vec![spec(l, self.dtype)], output)
+    }
+
+    fn record2(
+        &self,
+        op: &'static str,
+        l: &Layout,
+        rhs: &Self,
+        rhs_l: &Layout,
+        output: TensorSpec,
+    ) -> Self {
+        self.record(
+            op,
+            vec![spec(l, self.dtype), spec(rhs_l, rhs.dtype)],
+            output,
+        )
+    }
+
+    fn pool_shape(l: &Layout, kernel_size: (usize, usize), stride: (usize, usize)) -> Shape {
+        let dims = l.dims();
+        let h_out = (dims[2] - kernel_size.0) / stride.0 + 1;
+        let w_out = (dims[3] - kernel_size.1) / stride.1 + 1;
+        Shape::from((dims[0], dims[1], h_out, w_out))
+    }
+}
+
+impl crate::backend::BackendStorage for MetaStorage {
+    type Device = MetaDevice;
+
+    fn try_clone(&self, _: &Layout) -> Result<Self> {
+        Ok(self.clone())
+    }
+
+    fn dtype(&self) -> DType {
+        self.dtype
+    }
+
+    fn device(&self) -> &Self::Device {
+        &self.device
+    }
+
+    fn to_cpu_storage(&self) -> Result<CpuStorage> {
+        // Meta tensors do not hold any data.
+        Err(crate::Error::UnsupportedOp {
+            backend: "meta",
+            op: "to-cpu",
+            dtype: None,
+        }
+        .bt())
+    }
+
+    fn affine(&self, l: &Layout, _: f64, _: f64) -> Result<Self> {
+        Ok(self.record1("affine", l, l.shape().clone(), self.dtype))
+    }
+
+    fn powf(&self, l: &Layout, _: f64) -> Result<Self> {
+        Ok(self.record1("powf", l, l.shape().clone(), self.dtype))
+    }
+
+    fn elu(&self, l: &Layout, _: f64) -> Result<Self> {
+        Ok(self.record1("elu", l, l.shape().clone(), self.dtype))
+    }
+
+    fn reduce_op(&self, op: ReduceOp, l: &Layout, sum_dims: &[usize]) -> Result<Self> {
+        let mut dims = l.dims().to_vec();
+        for &d in sum_dims.iter() {
+            dims[d] = 1
+        }
+        let dtype = match op {
+            ReduceOp::ArgMin | ReduceOp::ArgMax => DType::U32,
+            ReduceOp::Sum | ReduceOp::Min | ReduceOp::Max => self.dtype,
+        };
+        Ok(self.record1(op.name(), l, Shape::from(dims), dtype))
+    }
+
+    fn cmp(&self, _: CmpOp, rhs: &Self, l: &Layout, rhs_l: &Layout) -> Result<Self> {
+        let output = spec(l, DType::U8);
+        Ok(self.record2("cmp", l, rhs, rhs_l, output))
+    }
+
+    fn to_dtype(&self, l: &Layout, dtype: DType) -> Result<Self> {
+        Ok(self.record1("to-dtype", l, l.shape().clone(), dtype))
+    }
+
+    fn unary_impl<B: UnaryOpT>(&self, l: &Layout) -> Result<Self> {
+        Ok(self.record1(B::NAME, l, l.shape().clone(), self.dtype))
+    }
+
+    fn binary_impl<B: BinaryOpT>(&self, rhs: &Self, l: &Layout, rhs_l: &Layout) -> Result<Self> {
+        let output = spec(l, self.dtype);
+        Ok(self.record2(B::NAME, l, rhs, rhs_l, output))
+    }
+
+    fn where_cond(
+        &self,
+        l: &Layout,
+        t: &Self,
+        t_l: &Layout,
+        f: &Self,
+        f_l: &Layout,
+    ) -> Result<Self> {
+        let inputs = vec![spec(l, self.dtype), spec(t_l, t.dtype), spec(f_l, f.dtype)];
+        Ok(self.record("where", inputs, spec(l, t.dtype)))
+    }
+
+    fn conv1d(
+        &self,
+        l: &Layout,
+        kernel: &Self,
+        kernel_l: &Layout,
+        params: &crate::conv::ParamsConv1D,
+    ) -> Result<Self> {
+        let output = TensorSpec::new(params.out_dims(), self.dtype);
+        Ok(self.record2("conv1d", l, kernel, kernel_l, output))
+    }
+
+    fn conv_transpose1d(
+        &self,
+        l: &Layout,
+        kernel: &Self,
+        kernel_l: &Layout,
+        params: &crate::conv::ParamsConvTranspose1D,
+    ) -> Result<Self> {
+        let output = TensorSpec::new(params.out_dims(), self.dtype);
+        Ok(self.record2("conv-transpose1d", l, kernel, kernel_l, output))
+    }
+
+    fn conv2d(
+        &self,
+        l: &Layout,
+        kernel: &Self,
+        kernel_l: &Layout,
+        params: &crate::conv::ParamsConv2D,
+    ) -> Result<Self> {
+        let output = TensorSpec::new(params.out_dims(), self.dtype);
+        Ok(self.record2("conv2d", l, kernel, kernel_l, output))
+    }
+
+    fn conv_transpose2d(
+        &self,
+        l: &Layout,
+        kernel: &Self,
+        kernel_l: &Layout,
+        params: &crate::conv::ParamsConvTranspose2D,
+    ) -> Result<Self> {
+        let output = TensorSpec::new(params.out_dims(), self.dtype);
+        Ok(self.record2("conv-transpose2d", l, kernel, kernel_l, output))
+    }
+
+    fn index_select(&self, ids: &Self, l: &Layout, ids_l: &Layout, dim: usize) -> Result<Self> {
+        let mut dims = l.dims().to_vec();
+        dims[dim] = ids_l.shape().elem_count();
+        let output = TensorSpec::new(dims, self.dtype);
+        Ok(self.record2("index-select", l, ids, ids_l, output))
+    }
+
+    fn gather(&self, l: &Layout, ids: &Self, ids_l: &Layout, _: usize) -> Result<Self> {
+        let output = spec(ids_l, self.dtype);
+        Ok(self.record2("gather", l, ids, ids_l, output))
+    }
+
+    fn scatter_add(
+        &self,
+        l: &Layout,
+        ids: &Self,
+        ids_l: &Layout,
+        src: &Self,
+        src_l: &Layout,
+        _: usize,
+    ) -> Result<Self> {
+        let inputs = vec![
+            spec(l, self.dtype),
+            spec(ids_l, ids.dtype),
+            spec(src_l, src.dtype),
+        ];
+        Ok(self.record("scatter-add", inputs, spec(l, self.dtype)))
+    }
+
+    fn index_add(
+        &self,
+        l: &Layout,
+        ids: &Self,
+        ids_l: &Layout,
+        src: &Self,
+        src_l: &Layout,
+        _: usize,
+    ) -> Result<Self> {
+        let inputs = vec![
+            spec(l, self.dtype),
+            spec(ids_l, ids.dtype),
+            spec(src_l, src.dtype),
+        ];
+        Ok(self.record("index-add", inputs, spec(l, self.dtype)))
+    }
+
+    fn matmul(
+        &self,
+        rhs: &Self,
+        (_, m, n, _): (usize, usize, usize, usize),
+        lhs_l: &Layout,
+        rhs_l: &Layout,
+    ) -> Result<Self> {
+        let dims = lhs_l.dims();
+        let mut dims = dims[..dims.len() - 2].to_vec();
+        dims.push(m);
+        dims.push(n);
+        let output = TensorSpec::new(dims, self.dtype);
+        Ok(self.record2("matmul", lhs_l, rhs, rhs_l, output))
+    }
+
+    // The copies do not produce new storages so they are not recorded.
+    fn copy_strided_src(&self, _: &mut Self, _: usize, _: &Layout) -> Result<()> {
+        Ok(())
+    }
+
+    fn copy2d(
+        &self,
+        _: &mut Self,
+        _: usize,
+        _: usize,
+        _: usize,
+        _: usize,
+        _: usize,
+        _: usize,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    fn avg_pool2d(
+        &self,
+        l: &Layout,
+        kernel_size: (usize, usize),
+        s: (usize, usize),
+    ) -> Result<Self> {
+        let shape = Self::pool_shape(l, kernel_size, s);
+        Ok(self.record1("avg-pool2d", l, shape, self.dtype))
+    }
+
+    fn max_pool2d(
+        &self,
+        l: &Layout,
+        kernel_size: (usize, usize),
+        s: (usize, usize),
+    ) -> Result<Self> {
+        let shape = Self::pool_shape(l, kernel_size, s);
+        Ok(self.record1("max-pool2d", l, shape, self.dtype))
+    }
+
+    fn upsample_nearest1d(&self, l: &Layout, sz: usize) -> Result<Self> {
+        let dims = l.dims();
+        let shape = Shape::from((dims[0], dims[1], sz));
+        Ok(self.record1("upsample-nearest1d", l, shape, self.dtype))
+    }
+
+    fn upsample_nearest2d(&self, l: &Layout, h: usize, w: usize) -> Result<Self> {
+        let dims = l.dims();
+        let shape = Shape::from((dims[0], dims[1], h, w));
+        Ok(self.record1("upsample-nearest2d", l, shape, self.dtype))
+    }
+}
+
+impl crate::backend::BackendDevice for MetaDevice {
+    type Storage = MetaStorage;
+
+    fn new(_: usize) -> Result<Self> {
+        Ok(Self {
+            trace: Arc::new(Mutex::new(vec![])),
+        })
+    }
+
+    fn set_seed(&self, _: u64) -> Result<()> {
+        Ok(())
+    }
+
+    fn location(&self) -> crate::DeviceLocation {
+        crate::DeviceLocation::Meta
+    }
+
+    fn same_device(&self, rhs: &Self) -> bool {
+        Arc::ptr_eq(&self.trace, &rhs.trace)
+    }
+
+    fn zeros_impl(&self, _: &Shape, dtype: DType) -> Result<Self::Storage> {
+        Ok(self.storage(dtype))
+    }
+
+    fn ones_impl(&self, _: &Shape, dtype: DType) -> Result<Self::Storage> {
+        Ok(self.storage(dtype))
+    }
+
+    unsafe fn alloc_uninit(&self, _: &Shape, dtype: DType) -> Result<Self::Storage> {
+        Ok(self.storage(dtype))
+    }
+
+    fn storage_from_slice<T: crate::WithDType>(&self, _: &[T]) -> Result<Self::Storage> {
+        Ok(self.storage(T::DTYPE))
+    }
+
+    fn storage_from_cpu_storage(&self, storage: &CpuStorage) -> Result<Self::Storage> {
+        Ok(self.storage(storage.dtype()))
+    }
+
+    fn storage_from_cpu_storage_owned(&self, storage: CpuStorage) -> Result<Self::Storage> {
+        Ok(self.storage(storage.dtype()))
+    }
+
+    fn rand_uniform(&self, _: &Shape, dtype: DType, _: f64, _: f64) -> Result<Self::Storage> {
+        Ok(self.storage(dtype))
+    }
+
+    fn rand_normal(&self, _: &Shape, dtype: DType, _: f64, _: f64) -> Result<Self::Storage> {
+        Ok(self.storage(dtype))
+    }
+
+    fn synchronize(&self) -> Result<()> {
+        Ok(())
+    }
+}
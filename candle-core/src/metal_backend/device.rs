@@ -91,6 +91,27 @@ impl MetalDevice {
         self.id
     }
 
+    pub(crate) fn capabilities(&self) -> Result<crate::DeviceCapabilities> {
+        let device = &self.device;
+        Ok(crate::DeviceCapabilities {
+            name: device.name().to_string(),
+            dtypes: vec![
+                DType::U8,
+                DType::U32,
+                DType::I64,
+                DType::BF16,
+                DType::F16,
+                DType::F32,
+            ],
+            matmul_dtypes: vec![DType::BF16, DType::F16, DType::F32],
+            tensor_cores: device.supports_family(metal::MTLGPUFamily::Apple7),
+            compute_capability: None,
+            max_shared_memory: Some(device.max_threadgroup_memory_length() as usize),
+            unified_memory: device.has_unified_memory(),
+            unsupported_ops: vec!["upsample-nearest1d"],
+        })
+    }
+
     pub fn metal_device(&self) -> &metal::Device {
         &self.device
     }
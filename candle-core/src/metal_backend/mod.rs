@@ -11,6 +11,17 @@ use std::sync::{Arc, Mutex, PoisonError, RwLock, TryLockError};
 mod device;
 pub use device::{DeviceId, MetalDevice};
 
+// The error for the kernels that are not available for some dtypes, the cpu fallback relies on
+// these being reported as unsupported ops.
+fn unsupported(op: &'static str, dtype: DType) -> crate::Error {
+    crate::Error::UnsupportedOp {
+        backend: "metal",
+        op,
+        dtype: Some(dtype),
+    }
+    .bt()
+}
+
 pub fn buffer_o<'a>(buffer: &'a Buffer, l: &Layout, dtype: DType) -> BufferOffset<'a> {
     BufferOffset {
         buffer,
@@ -121,7 +132,7 @@ impl BackendStorage for MetalStorage {
                 DType::BF16 => "affine_bf16",
                 DType::U8 => "affine_u8",
                 DType::U32 => "affine_u32",
-                dtype => crate::bail!("Metal contiguous affine {dtype:?} not implemented"),
+                dtype => Err(unsupported("affine", dtype))?,
             };
             candle_metal_kernels::call_affine(
                 &device.device,
@@ -140,7 +151,7 @@ impl BackendStorage for MetalStorage {
                 DType::F32 => "affine_f32_strided",
                 DType::F16 => "affine_f16_strided",
                 DType::BF16 => "affine_bf16_strided",
-                dtype => crate::bail!("Metal strided affine {dtype:?} not implemented"),
+                dtype => Err(unsupported("affine", dtype))?,
             };
             candle_metal_kernels::call_affine_strided(
                 &device.device,
@@ -174,7 +185,7 @@ impl BackendStorage for MetalStorage {
                 DType::F32 => "powf_f32",
                 DType::F16 => "powf_f16",
                 DType::BF16 => "powf_bf16",
-                dtype => crate::bail!("Metal contiguous powf {dtype:?} not implemented"),
+                dtype => Err(unsupported("powf", dtype))?,
             };
             candle_metal_kernels::call_powf(
                 &device.device,
@@ -192,7 +203,7 @@ impl BackendStorage for MetalStorage {
                 DType::F32 => "powf_f32_strided",
                 DType::F16 => "powf_f16_strided",
                 DType::BF16 => "powf_bf16_strided",
-                dtype => crate::bail!("Metal strided powf {dtype:?} not implemented"),
+                dtype => Err(unsupported("powf", dtype))?,
             };
             candle_metal_kernels::call_powf_strided(
                 &device.device,
@@ -225,7 +236,7 @@ impl BackendStorage for MetalStorage {
                 DType::F32 => "elu_f32",
                 DType::F16 => "elu_f16",
                 DType::BF16 => "elu_bf16",
-                dtype => crate::bail!("Metal contiguous elu {dtype:?} not implemented"),
+                dtype => Err(unsupported("elu", dtype))?,
             };
             candle_metal_kernels::call_elu(
                 &device.device,
@@ -243,7 +254,7 @@ impl BackendStorage for MetalStorage {
                 DType::F32 => "elu_f32_strided",
                 DType::F16 => "elu_f16_strided",
                 DType::BF16 => "elu_bf16_strided",
-                dtype => crate::bail!("Metal strided elu {dtype:?} not implemented"),
+                dtype => Err(unsupported("elu", dtype))?,
             };
             candle_metal_kernels::call_elu_strided(
                 &device.device,
@@ -314,7 +325,7 @@ impl BackendStorage for MetalStorage {
             (ReduceOp::Max, DType::U8) => ("fast_max_u8_strided", true, false),
             (ReduceOp::ArgMin, DType::U8) => ("fast_argmin_u8_strided", true, true),
             (ReduceOp::ArgMax, DType::U8) => ("fast_argmax_u8_strided", true, true),
-            (k, dtype) => crate::bail!("Metal reduce op {k:?} {dtype:?} not implemented"),
+            (k, dtype) => Err(unsupported(k.name(), dtype))?,
         };
         if check_empty && layout.shape().elem_count() == 0 {
             Err(crate::Error::EmptyTensor { op: "reduce" }.bt())?
@@ -396,9 +407,7 @@ impl BackendStorage for MetalStorage {
                 (DType::BF16, DType::U32) => "cast_bf16_u32",
                 (DType::BF16, DType::U8) => "cast_bf16_u8",
 
-                (left, right) => {
-                    crate::bail!("Metal contiguous to_dtype {left:?} {right:?} not implemented")
-                }
+                (_, dtype) => Err(unsupported("to_dtype", dtype))?,
             };
             candle_metal_kernels::call_cast_contiguous(
                 &device.device,
@@ -423,9 +432,7 @@ impl BackendStorage for MetalStorage {
                 (DType::I64, DType::F32) => "cast_i64_f32_strided",
                 (DType::F32, DType::BF16) => "cast_f32_bf16_strided",
                 (DType::BF16, DType::F32) => "cast_bf16_f32_strided",
-                (left, right) => {
-                    crate::bail!("Metal strided to_dtype {left:?} {right:?} not implemented")
-                }
+                (_, dtype) => Err(unsupported("to_dtype", dtype))?,
             };
             candle_metal_kernels::call_cast_strided(
                 &device.device,
@@ -515,11 +522,7 @@ impl BackendStorage for MetalStorage {
                     ("usign", DType::F32) => contiguous_tiled::sign::FLOAT,
                     ("usign", DType::BF16) => contiguous_tiled::sign::BFLOAT,
                     ("usign", DType::I64) => contiguous_tiled::sign::I64,
-                    (name, dtype) => {
-                        crate::bail!(
-                            "Metal contiguous_tiled unary {name} {dtype:?} not implemented"
-                        )
-                    }
+                    (name, dtype) => Err(unsupported(name, dtype))?,
                 };
                 candle_metal_kernels::call_unary_contiguous_tiled(
                     &device.device,
@@ -593,9 +596,7 @@ impl BackendStorage for MetalStorage {
                     ("usign", DType::F32) => contiguous::sign::FLOAT,
                     ("usign", DType::BF16) => contiguous::sign::BFLOAT,
                     ("usign", DType::I64) => contiguous::sign::I64,
-                    (name, dtype) => {
-                        crate::bail!("Metal contiguous unary {name} {dtype:?} not implemented")
-                    }
+                    (name, dtype) => Err(unsupported(name, dtype))?,
                 };
                 candle_metal_kernels::call_unary_contiguous(
                     &device.device,
@@ -665,9 +666,7 @@ impl BackendStorage for MetalStorage {
                     ("uround", DType::BF16) => strided::round::BFLOAT,
                     ("utanh", DType::BF16) => strided::tanh::BFLOAT,
 
-                    (name, dtype) => {
-                        crate::bail!("Metal strided unary {name} {dtype:?} not implemented")
-                    }
+                    (name, dtype) => Err(unsupported(name, dtype))?,
                 };
                 let dst = BufferOffset::zero_offset(&buffer);
                 candle_metal_kernels::call_unary_strided(
@@ -726,7 +725,7 @@ impl BackendStorage for MetalStorage {
             (DType::U8, DType::I64) => "where_u8_i64",
             (DType::U8, DType::U32) => "where_u8_u32",
             (DType::U8, DType::U8) => "where_u8_u8",
-            (left, right) => crate::bail!("Metal where_cond {left:?} {right:?} not implemented"),
+            (_, dtype) => Err(unsupported("where_cond", dtype))?,
         };
         let src = buffer_o(&self.buffer, layout, self.dtype);
         let t = buffer_o(&t.buffer, t_l, t.dtype);
@@ -773,7 +772,7 @@ impl BackendStorage for MetalStorage {
         let command_buffer = self.device.command_buffer()?;
         let name = match self.dtype {
             DType::F32 => "im2col1d_f32",
-            dtype => crate::bail!("Metal conv1d {dtype:?} not implemented"),
+            dtype => Err(unsupported("conv1d", dtype))?,
         };
         let src = buffer_o(&self.buffer, layout, self.dtype);
         candle_metal_kernels::call_im2col1d_strided(
@@ -809,10 +808,10 @@ impl BackendStorage for MetalStorage {
             // Make the kernel contiguous if not already the case.
             let mut kernel_c = self.device().zeros_impl(kernel_l.shape(), kernel.dtype())?;
             kernel.copy_strided_src(&mut kernel_c, 0, kernel_l)?;
-            let kernel_l = Layout::contiguous_with_offset((1, n, k), kernel_l.start_offset())
+            let kernel_l = Layout::contiguous((1, n, k))
                 .transpose(1, 2)?
                 .broadcast_as((b, k, n))?;
-            col.matmul(kernel, (b, m, n, k), &col_l, &kernel_l)?
+            col.matmul(&kernel_c, (b, m, n, k), &col_l, &kernel_l)?
         };
         let res_l = Layout::contiguous((b, l_out, n)).transpose(1, 2)?;
         let mut res_t = self.device().zeros_impl(res_l.shape(), res.dtype())?;
@@ -854,7 +853,7 @@ impl BackendStorage for MetalStorage {
                 DType::F32 => "col2im1d_f32",
                 DType::U32 => "col2im1d_u32",
                 DType::U8 => "col2im1d_u8",
-                dtype => crate::bail!("metal col2im1d {dtype:?} not implemented"),
+                dtype => Err(unsupported("col2im1d", dtype))?,
             };
             let col = {
                 // This merges the last two dimensions of the kernel together.
@@ -901,7 +900,7 @@ impl BackendStorage for MetalStorage {
                 DType::BF16 => "conv_transpose1d_bf16",
                 DType::U32 => "conv_transpose1d_u32",
                 DType::U8 => "conv_transpose1d_u8",
-                dtype => crate::bail!("Metal conv_transpose1d {dtype:?} not implemented"),
+                dtype => Err(unsupported("conv_transpose1d", dtype))?,
             };
             candle_metal_kernels::call_conv_transpose1d(
                 &self.device.device,
@@ -963,7 +962,7 @@ impl BackendStorage for MetalStorage {
             DType::BF16 => "im2col_bf16",
             DType::U8 => "im2col_u8",
             DType::U32 => "im2col_u32",
-            dtype => crate::bail!("Metal conv2d {dtype:?} not implemented"),
+            dtype => Err(unsupported("conv2d", dtype))?,
         };
         let src = buffer_o(&self.buffer, layout, self.dtype);
         candle_metal_kernels::call_im2col_strided(
@@ -1000,10 +999,10 @@ impl BackendStorage for MetalStorage {
             // Make the kernel contiguous if not already the case.
             let mut kernel_c = self.device().zeros_impl(kernel_l.shape(), kernel.dtype())?;
             kernel.copy_strided_src(&mut kernel_c, 0, kernel_l)?;
-            let kernel_l = Layout::contiguous_with_offset((1, n, k), kernel_l.start_offset())
+            let kernel_l = Layout::contiguous((1, n, k))
                 .transpose(1, 2)?
                 .broadcast_as((b, k, n))?;
-            col.matmul(kernel, (b, m, n, k), &col_l, &kernel_l)?
+            col.matmul(&kernel_c, (b, m, n, k), &col_l, &kernel_l)?
         };
         let res_l = Layout::contiguous((b, h_out, w_out, n))
             .transpose(1, 2)?
@@ -1045,7 +1044,7 @@ impl BackendStorage for MetalStorage {
             DType::F32 => "conv_transpose2d_f32",
             DType::F16 => "conv_transpose2d_f16",
             DType::BF16 => "conv_transpose2d_bf16",
-            dtype => crate::bail!("Metal conv_transpose2d {dtype:?} not implemented"),
+            dtype => Err(unsupported("conv_transpose2d", dtype))?,
         };
 
         candle_metal_kernels::call_conv_transpose2d(
@@ -1092,7 +1091,7 @@ impl BackendStorage for MetalStorage {
             DType::BF16 => "avg_pool2d_bf16",
             DType::U8 => "avg_pool2d_u8",
             DType::U32 => "avg_pool2d_u32",
-            dtype => crate::bail!("Metal avg_pool2d {dtype:?} not implemented"),
+            dtype => Err(unsupported("avg_pool2d", dtype))?,
         };
         let out_w = (width - w_k) / w_stride + 1;
         let out_h = (height - h_k) / h_stride + 1;
@@ -1134,7 +1133,7 @@ impl BackendStorage for MetalStorage {
             DType::BF16 => "max_pool2d_bf16",
             DType::U8 => "max_pool2d_u8",
             DType::U32 => "max_pool2d_u32",
-            dtype => crate::bail!("Metal max_pool2d {dtype:?} not implemented"),
+            dtype => Err(unsupported("max_pool2d", dtype))?,
         };
         let out_w = (width - w_k) / w_stride + 1;
         let out_h = (height - h_k) / h_stride + 1;
@@ -1162,7 +1161,12 @@ impl BackendStorage for MetalStorage {
     }
 
     fn upsample_nearest1d(&self, _: &Layout, _: usize) -> Result<Self> {
-        crate::bail!("Metal upsample_nearest1d not implemented")
+        Err(crate::Error::UnsupportedOp {
+            backend: "metal",
+            op: "upsample-nearest1d",
+            dtype: None,
+        }
+        .bt())
     }
 
     fn upsample_nearest2d(&self, inp_l: &Layout, out_w: usize, out_h: usize) -> Result<Self> {
@@ -1179,7 +1183,7 @@ impl BackendStorage for MetalStorage {
             DType::BF16 => "upsample_nearest2d_bf16",
             DType::U8 => "upsample_nearest2d_u8",
             DType::U32 => "upsample_nearest2d_u32",
-            dtype => crate::bail!("Metal upsample_nearest2d {dtype:?} not implemented"),
+            dtype => Err(unsupported("upsample_nearest2d", dtype))?,
         };
 
         let dst_el = out_w * out_h * dims[0] * dims[1];
@@ -1217,7 +1221,7 @@ impl BackendStorage for MetalStorage {
             (DType::U32, DType::F32) => "gather_u32_f32",
             (DType::U32, DType::F16) => "gather_u32_f16",
             (DType::U32, DType::BF16) => "gather_u32_bf16",
-            (left, right) => crate::bail!("Metal gather {left:?} {right:?} not implemented"),
+            (_, dtype) => Err(unsupported("gather", dtype))?,
         };
         let command_buffer = self.device.command_buffer()?;
         let src = buffer_o(&self.buffer, src_l, dtype);
@@ -1311,9 +1315,7 @@ impl BackendStorage for MetalStorage {
             (DType::I64, DType::F16) => "is_i64_f16",
             (DType::I64, DType::BF16) => "is_i64_bf16",
 
-            (left, right) => {
-                crate::bail!("Metal contiguous index_select {left:?} {right:?} not implemented")
-            }
+            (_, dtype) => Err(unsupported("index_select", dtype))?,
         };
         let command_buffer = self.device.command_buffer()?;
         let src = buffer_o(&self.buffer, src_l, dtype);
@@ -1479,7 +1481,7 @@ impl BackendStorage for MetalStorage {
                 DType::I64 => candle_metal_kernels::copy2d::I64,
                 DType::U32 => candle_metal_kernels::copy2d::U32,
                 DType::U8 => candle_metal_kernels::copy2d::U8,
-                dtype => crate::bail!("Metal copy2d {dtype:?} not implemented"),
+                dtype => Err(unsupported("copy2d", dtype))?,
             };
             candle_metal_kernels::call_copy2d(
                 &self.device.device,
@@ -1525,7 +1527,7 @@ impl BackendStorage for MetalStorage {
                 DType::I64 => candle_metal_kernels::unary::strided::copy::I64,
                 DType::U32 => candle_metal_kernels::unary::strided::copy::U32,
                 DType::U8 => candle_metal_kernels::unary::strided::copy::U8,
-                dtype => crate::bail!("Metal copy_strided {dtype:?} not implemented"),
+                dtype => Err(unsupported("copy_strided", dtype))?,
             };
             let src = buffer_o(&self.buffer, src_l, self.dtype);
             let dst = BufferOffset {
@@ -1646,9 +1648,7 @@ impl MetalStorage {
                 ("ge", DType::U8) => (contiguous::ge::U8, DType::U8),
                 ("gt", DType::U8) => (contiguous::gt::U8, DType::U8),
 
-                (name, dtype) => {
-                    crate::bail!("Metal contiguous binary {name} {dtype:?} not implemented")
-                }
+                (name, dtype) => Err(unsupported(name, dtype))?,
             };
             let buffer = device.new_buffer(el_count, dtype, op)?;
             candle_metal_kernels::call_binary_contiguous(
@@ -1745,9 +1745,7 @@ impl MetalStorage {
                 ("ge", DType::U8) => (strided::ge::U8, DType::U8),
                 ("gt", DType::U8) => (strided::gt::U8, DType::U8),
 
-                (name, dtype) => {
-                    crate::bail!("Metal strided binary {name} {dtype:?} not implemented")
-                }
+                (name, dtype) => Err(unsupported(name, dtype))?,
             };
             let buffer = device.new_buffer(el_count, dtype, op)?;
             candle_metal_kernels::call_binary_strided(
@@ -1903,7 +1901,7 @@ impl BackendDevice for MetalDevice {
             DType::F32 => "rand_uniform_f32",
             DType::F16 => "rand_uniform_f16",
             DType::BF16 => "rand_uniform_bf16",
-            dtype => crate::bail!("rand_uniform not implemented for {dtype:?}"),
+            dtype => Err(unsupported("rand_uniform", dtype))?,
         };
         let buffer = self.new_buffer(shape.elem_count(), dtype, "rand_uniform")?;
         let command_buffer = self.command_buffer()?;
@@ -1939,7 +1937,7 @@ impl BackendDevice for MetalDevice {
             DType::F32 => "rand_normal_f32",
             DType::F16 => "rand_normal_f16",
             DType::BF16 => "rand_normal_bf16",
-            dtype => crate::bail!("rand_uniform not implemented for {dtype:?}"),
+            dtype => Err(unsupported("rand_normal", dtype))?,
         };
         let buffer = self.new_buffer(shape.elem_count(), dtype, "rand_normal")?;
         let command_buffer = self.command_buffer()?;
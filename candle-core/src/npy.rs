@@ -6,7 +6,10 @@
 //! or write tensors to these files. A npy file contains a single tensor (unnamed)
 //! whereas a npz file can contain multiple named tensors. npz files are also compressed.
 //!
-//! These two formats are easy to use in Python using the numpy library.
+//! These two formats are easy to use in Python using the numpy library. Arrays in fortran order,
+//! big-endian arrays and the compressed npz files are supported when reading. The integer types
+//! that have no candle equivalent are converted to `i64`, or to `u32` for `uint16`. Numpy has no
+//! bf16 type so bf16 tensors are written as f32.
 //!
 //! ```python
 //! import numpy as np
@@ -46,7 +49,7 @@ fn read_header<R: Read>(reader: &mut R) -> Result<String> {
     reader.read_exact(&mut version)?;
     let header_len_len = match version[0] {
         1 => 2,
-        2 => 4,
+        2 | 3 => 4,
         otherwise => return Err(Error::Npy(format!("unsupported version {otherwise}"))),
     };
     let mut header_len = vec![0u8; header_len_len];
@@ -65,6 +68,20 @@ struct Header {
     descr: DType,
     fortran_order: bool,
     shape: Vec<usize>,
+    // How the values are stored when this differs from the little-endian layout of `descr`.
+    source: Option<Source>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Source {
+    // Big-endian values of the header dtype.
+    BigEndian,
+    // Integers of the given size in bytes, converted to the header dtype.
+    Int {
+        size: usize,
+        signed: bool,
+        big_endian: bool,
+    },
 }
 
 impl Header {
@@ -148,28 +165,35 @@ impl Header {
                 if descr.is_empty() {
                     return Err(Error::Npy("empty descr".to_string()));
                 }
-                if descr.starts_with('>') {
-                    return Err(Error::Npy(format!("little-endian descr {descr}")));
-                }
-                // the only supported types in tensor are:
-                //     float64, float32, float16,
-                //     complex64, complex128,
-                //     int64, int32, int16, int8,
-                //     uint8, and bool.
-                match descr.trim_matches(|c: char| c == '=' || c == '<' || c == '|') {
-                    "e" | "f2" => DType::F16,
-                    "f" | "f4" => DType::F32,
-                    "d" | "f8" => DType::F64,
-                    // "i" | "i4" => DType::S32,
-                    "q" | "i8" => DType::I64,
-                    // "h" | "i2" => DType::S16,
-                    // "b" | "i1" => DType::S8,
-                    "B" | "u1" => DType::U8,
-                    "I" | "u4" => DType::U32,
-                    "?" | "b1" => DType::U8,
-                    // "F" | "F4" => DType::C64,
-                    // "D" | "F8" => DType::C128,
+                let big_endian = descr.starts_with('>');
+                let int = |size, signed| Source::Int {
+                    size,
+                    signed,
+                    big_endian,
+                };
+                // The complex types are not supported.
+                let (descr, source) = match descr
+                    .trim_matches(|c: char| c == '=' || c == '<' || c == '>' || c == '|')
+                {
+                    "e" | "f2" => (DType::F16, None),
+                    "f" | "f4" => (DType::F32, None),
+                    "d" | "f8" => (DType::F64, None),
+                    "i" | "i4" => (DType::I64, Some(int(4, true))),
+                    "q" | "i8" => (DType::I64, None),
+                    "h" | "i2" => (DType::I64, Some(int(2, true))),
+                    "b" | "i1" => (DType::I64, Some(int(1, true))),
+                    "B" | "u1" => (DType::U8, None),
+                    "H" | "u2" => (DType::U32, Some(int(2, false))),
+                    "I" | "u4" => (DType::U32, None),
+                    "Q" | "u8" => (DType::I64, Some(int(8, false))),
+                    "?" | "b1" => (DType::U8, None),
                     descr => return Err(Error::Npy(format!("unrecognized descr {descr}"))),
+                };
+                match source {
+                    None if big_endian && descr.size_in_bytes() > 1 => {
+                        (descr, Some(Source::BigEndian))
+                    }
+                    source => (descr, source),
                 }
             }
         };
@@ -187,11 +211,86 @@ impl Header {
                 }
             }
         };
+        let (descr, source) = descr;
         Ok(Header {
             descr,
             fortran_order,
             shape,
+            source,
+        })
+    }
+}
+
+fn read_ints<R: Read>(
+    reader: &mut R,
+    elem_count: usize,
+    size: usize,
+    signed: bool,
+    big_endian: bool,
+) -> Result<Vec<i64>> {
+    let mut bytes = vec![0u8; elem_count * size];
+    reader.read_exact(&mut bytes)?;
+    bytes
+        .chunks_exact(size)
+        .map(|c| {
+            let mut v = [0u8; 8];
+            if big_endian {
+                v[..size]
+                    .iter_mut()
+                    .zip(c.iter().rev())
+                    .for_each(|(v, c)| *v = *c)
+            } else {
+                v[..size].copy_from_slice(c)
+            }
+            if signed && c[if big_endian { 0 } else { size - 1 }] & 0x80 != 0 {
+                v[size..].fill(0xff)
+            }
+            let v = u64::from_le_bytes(v);
+            if signed {
+                Ok(v as i64)
+            } else {
+                i64::try_from(v).map_err(|_| Error::Npy(format!("{v} does not fit in an i64")))
+            }
         })
+        .collect()
+}
+
+// Reads the data following a header, the arrays in fortran order are transposed to the c order.
+fn read_tensor<R: Read>(header: &Header, reader: &mut R) -> Result<Tensor> {
+    let mut dims = header.shape.clone();
+    if header.fortran_order {
+        dims.reverse()
+    }
+    let elem_count = dims.iter().product::<usize>();
+    let tensor = match header.source {
+        None => Tensor::from_reader(dims.as_slice().into(), header.descr, reader)?,
+        Some(Source::BigEndian) => {
+            let size = header.descr.size_in_bytes();
+            let mut bytes = vec![0u8; elem_count * size];
+            reader.read_exact(&mut bytes)?;
+            bytes.chunks_exact_mut(size).for_each(|c| c.reverse());
+            Tensor::from_raw_buffer(&bytes, header.descr, &dims, &Device::Cpu)?
+        }
+        Some(Source::Int {
+            size,
+            signed,
+            big_endian,
+        }) => {
+            let data = read_ints(reader, elem_count, size, signed, big_endian)?;
+            match header.descr {
+                DType::U32 => {
+                    let data = data.into_iter().map(|v| v as u32).collect::<Vec<_>>();
+                    Tensor::from_vec(data, dims.as_slice(), &Device::Cpu)?
+                }
+                _ => Tensor::from_vec(data, dims.as_slice(), &Device::Cpu)?,
+            }
+        }
+    };
+    if header.fortran_order && dims.len() > 1 {
+        let rev = (0..dims.len()).rev().collect::<Vec<_>>();
+        tensor.permute(rev)?.contiguous()
+    } else {
+        Ok(tensor)
     }
 }
 
@@ -247,10 +346,7 @@ impl Tensor {
         let mut reader = File::open(path.as_ref())?;
         let header = read_header(&mut reader)?;
         let header = Header::parse(&header)?;
-        if header.fortran_order {
-            return Err(Error::Npy("fortran order not supported".to_string()));
-        }
-        Self::from_reader(header.shape(), header.descr, &mut reader)
+        read_tensor(&header, &mut reader)
     }
 
     /// Reads a npz file and returns the stored multi-dimensional arrays together with their names.
@@ -266,10 +362,7 @@ impl Tensor {
             };
             let header = read_header(&mut reader)?;
             let header = Header::parse(&header)?;
-            if header.fortran_order {
-                return Err(Error::Npy("fortran order not supported".to_string()));
-            }
-            let s = Self::from_reader(header.shape(), header.descr, &mut reader)?;
+            let s = read_tensor(&header, &mut reader)?;
             result.push((name, s))
         }
         Ok(result)
@@ -290,30 +383,42 @@ impl Tensor {
             };
             let header = read_header(&mut reader)?;
             let header = Header::parse(&header)?;
-            if header.fortran_order {
-                return Err(Error::Npy("fortran order not supported".to_string()));
-            }
-            let s = Self::from_reader(header.shape(), header.descr, &mut reader)?;
+            let s = read_tensor(&header, &mut reader)?;
             result.push(s)
         }
         Ok(result)
     }
 
     fn write<T: Write>(&self, f: &mut T) -> Result<()> {
-        f.write_all(NPY_MAGIC_STRING)?;
-        f.write_all(&[1u8, 0u8])?;
+        if self.dtype() == DType::BF16 {
+            return self.to_dtype(DType::F32)?.write(f);
+        }
         let header = Header {
             descr: self.dtype(),
             fortran_order: false,
             shape: self.dims().to_vec(),
+            source: None,
         };
         let mut header = header.to_string()?;
-        let pad = 16 - (NPY_MAGIC_STRING.len() + 5 + header.len()) % 16;
+        // The version 2 has a 4 bytes header length for the very large shapes.
+        let header_len_len = if header.len() + 11 > u16::MAX as usize {
+            4
+        } else {
+            2
+        };
+        let pad = 16 - (NPY_MAGIC_STRING.len() + 3 + header_len_len + header.len()) % 16;
         for _ in 0..pad % 16 {
             header.push(' ')
         }
         header.push('\n');
-        f.write_all(&[(header.len() % 256) as u8, (header.len() / 256) as u8])?;
+        f.write_all(NPY_MAGIC_STRING)?;
+        if header_len_len == 2 {
+            f.write_all(&[1u8, 0u8])?;
+            f.write_all(&(header.len() as u16).to_le_bytes())?;
+        } else {
+            f.write_all(&[2u8, 0u8])?;
+            f.write_all(&(header.len() as u32).to_le_bytes())?;
+        }
         f.write_all(header.as_bytes())?;
         self.write_bytes(f)
     }
@@ -399,10 +504,7 @@ impl NpzTensors {
         let mut reader = zip.by_index(index)?;
         let header = read_header(&mut reader)?;
         let header = Header::parse(&header)?;
-        if header.fortran_order {
-            return Err(Error::Npy("fortran order not supported".to_string()));
-        }
-        let tensor = Tensor::from_reader(header.shape(), header.descr, &mut reader)?;
+        let tensor = read_tensor(&header, &mut reader)?;
         Ok(Some(tensor))
     }
 }
@@ -419,7 +521,8 @@ mod tests {
             Header {
                 descr: crate::DType::F64,
                 fortran_order: false,
-                shape: vec![128]
+                shape: vec![128],
+                source: None,
             }
         );
         let h = "{'descr': '<f4', 'fortran_order': True, 'shape': (256,1,128), }";
@@ -429,7 +532,8 @@ mod tests {
             Header {
                 descr: crate::DType::F32,
                 fortran_order: true,
-                shape: vec![256, 1, 128]
+                shape: vec![256, 1, 128],
+                source: None,
             }
         );
         assert_eq!(
@@ -441,6 +545,7 @@ mod tests {
             descr: crate::DType::U32,
             fortran_order: false,
             shape: vec![],
+            source: None,
         };
         assert_eq!(
             h.to_string().unwrap(),
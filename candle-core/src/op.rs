@@ -155,6 +155,8 @@ pub enum Op {
     Copy(Tensor),
     Broadcast(Tensor),
     Narrow(Tensor, usize, usize, usize),
+    // The dimension, start, length and step.
+    NarrowStep(Tensor, usize, usize, usize, usize),
     SliceScatter0(Tensor, Tensor, usize),
     Reshape(Tensor),
     ToDevice(Tensor),
@@ -179,6 +181,50 @@ pub enum Op {
     ),
 }
 
+impl Op {
+    /// A short name for the op, used when reporting errors.
+    pub(crate) fn name(&self) -> String {
+        let name = match self {
+            Self::Binary(_, _, op) => return format!("{op:?}").to_lowercase(),
+            Self::Unary(_, op) => return format!("{op:?}").to_lowercase(),
+            Self::Cmp(_, _) => "cmp",
+            Self::Reduce(_, op, _) => op.name(),
+            Self::Matmul(_, _) => "matmul",
+            Self::Gather(_, _, _) => "gather",
+            Self::ScatterAdd(_, _, _, _) => "scatter-add",
+            Self::IndexSelect(_, _, _) => "index-select",
+            Self::IndexAdd(_, _, _, _) => "index-add",
+            Self::WhereCond(_, _, _) => "where-cond",
+            Self::Conv1D { .. } => "conv1d",
+            Self::ConvTranspose1D { .. } => "conv-transpose1d",
+            Self::Conv2D { .. } => "conv2d",
+            Self::ConvTranspose2D { .. } => "conv-transpose2d",
+            Self::AvgPool2D { .. } => "avg-pool2d",
+            Self::MaxPool2D { .. } => "max-pool2d",
+            Self::UpsampleNearest1D { .. } => "upsample-nearest1d",
+            Self::UpsampleNearest2D { .. } => "upsample-nearest2d",
+            Self::Cat(_, _) => "cat",
+            Self::Affine { .. } => "affine",
+            Self::ToDType(_) => "to-dtype",
+            Self::Copy(_) => "copy",
+            Self::Broadcast(_) => "broadcast",
+            Self::Narrow(_, _, _, _) => "narrow",
+            Self::NarrowStep(_, _, _, _, _) => "narrow-step",
+            Self::SliceScatter0(_, _, _) => "slice-scatter",
+            Self::Reshape(_) => "reshape",
+            Self::ToDevice(_) => "to-device",
+            Self::Transpose(_, _, _) => "transpose",
+            Self::Permute(_, _) => "permute",
+            Self::Elu(_, _) => "elu",
+            Self::Powf(_, _) => "powf",
+            Self::CustomOp1(_, c) => c.name(),
+            Self::CustomOp2(_, _, c) => c.name(),
+            Self::CustomOp3(_, _, _, c) => c.name(),
+        };
+        name.to_string()
+    }
+}
+
 pub trait UnaryOpT {
     const NAME: &'static str;
     const KERNEL: &'static str;
@@ -865,13 +911,31 @@ impl UnaryOpT for Relu {
 }
 
 /// `BackpropOp` is a wrapper around `Option<Op>`. The main goal is to ensure that dependencies are
-/// properly checked when creating a new value
+/// properly checked when creating a new value. In anomaly mode, the backtrace of the creation of
+/// the op is also recorded.
 #[derive(Clone)]
-pub struct BackpropOp(Option<Op>);
+pub struct BackpropOp {
+    op: Option<Op>,
+    trace: Option<std::sync::Arc<std::backtrace::Backtrace>>,
+}
 
 impl BackpropOp {
     pub(crate) fn none() -> Self {
-        BackpropOp(None)
+        Self {
+            op: None,
+            trace: None,
+        }
+    }
+
+    fn record(op: Option<Op>) -> Self {
+        let trace = if op.is_some() && crate::backprop::is_anomaly_enabled() {
+            Some(std::sync::Arc::new(
+                std::backtrace::Backtrace::force_capture(),
+            ))
+        } else {
+            None
+        };
+        Self { op, trace }
     }
 
     pub(crate) fn new1(arg: &Tensor, f: impl Fn(Tensor) -> Op) -> Self {
@@ -880,7 +944,7 @@ impl BackpropOp {
         } else {
             None
         };
-        Self(op)
+        Self::record(op)
     }
 
     pub(crate) fn new2(arg1: &Tensor, arg2: &Tensor, f: impl Fn(Tensor, Tensor) -> Op) -> Self {
@@ -889,7 +953,7 @@ impl BackpropOp {
         } else {
             None
         };
-        Self(op)
+        Self::record(op)
     }
 
     pub(crate) fn new3(
@@ -903,7 +967,7 @@ impl BackpropOp {
         } else {
             None
         };
-        Self(op)
+        Self::record(op)
     }
 
     pub(crate) fn new<A: AsRef<Tensor>>(args: &[A], f: impl Fn(Vec<Tensor>) -> Op) -> Self {
@@ -913,18 +977,23 @@ impl BackpropOp {
         } else {
             None
         };
-        Self(op)
+        Self::record(op)
     }
 
     pub(crate) fn is_none(&self) -> bool {
-        self.0.is_none()
+        self.op.is_none()
+    }
+
+    /// The backtrace of the creation of the op, only recorded in anomaly mode.
+    pub(crate) fn trace(&self) -> Option<&std::backtrace::Backtrace> {
+        self.trace.as_deref()
     }
 }
 
 impl std::ops::Deref for BackpropOp {
     type Target = Option<Op>;
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.op
     }
 }
 
@@ -0,0 +1,350 @@
+//! Per operation checksums to detect changes in the outputs of a model.
+//!
+//! While a [`ManifestRecorder`] is running, the output of each tensor operation is hashed with
+//! [`Tensor::fingerprint`] and recorded with the name of the operation, the dtype and the shape
+//! of the output. The resulting [`OpManifest`] can be saved, e.g. with the model tests, and
+//! compared with the manifest of a later run, typically after upgrading candle, to check that the
+//! model still produces bit identical outputs and to locate the first operation that diverges.
+//!
+//! The operations that only create views, e.g. `reshape`, `narrow` or `transpose`, do not run on
+//! the device and are not recorded. Recording requires copying the output of each operation to
+//! the host so it is much slower than a normal run. The operations of all the threads are
+//! recorded, the model should be run from a single thread so that the order is deterministic.
+//!
+//! ```ignore
+//! let recorder = ManifestRecorder::new()?;
+//! model.forward(&input)?;
+//! let manifest = recorder.finish();
+//! if update_manifest {
+//!     manifest.save("tests/model.manifest")?;
+//! } else {
+//!     let diff = manifest.compare(&OpManifest::load("tests/model.manifest")?);
+//!     assert!(diff.is_identical(), "{diff}");
+//! }
+//! ```
+use crate::{DType, DeviceLocation, Result, Tensor};
+use std::cell::Cell;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+const HEADER: &str = "# candle op manifest v1";
+
+/// The output of a recorded operation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpRecord {
+    pub op: String,
+    pub dtype: DType,
+    pub shape: Vec<usize>,
+    /// The fingerprint of the output, `None` if it could not be computed.
+    pub checksum: Option<u64>,
+}
+
+impl std::fmt::Display for OpRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} {}{:?}", self.op, self.dtype.as_str(), self.shape)?;
+        match self.checksum {
+            None => write!(f, " -"),
+            Some(checksum) => write!(f, " {checksum:016x}"),
+        }
+    }
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static STATE: Mutex<Option<Vec<OpRecord>>> = Mutex::new(None);
+
+thread_local! {
+    // The last operation run by this thread, until its output is created.
+    static PENDING_OP: Cell<Option<&'static str>> = const { Cell::new(None) };
+    // Set while hashing an output, the operations used for hashing are not recorded.
+    static HASHING: Cell<bool> = const { Cell::new(false) };
+}
+
+fn lock() -> std::sync::MutexGuard<'static, Option<Vec<OpRecord>>> {
+    STATE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Records the checksums of the operation outputs while alive, only one recorder can run at a
+/// time.
+pub struct ManifestRecorder {
+    finished: bool,
+}
+
+impl ManifestRecorder {
+    pub fn new() -> Result<Self> {
+        let mut state = lock();
+        if state.is_some() {
+            crate::bail!("a manifest recorder is already running")
+        }
+        *state = Some(vec![]);
+        ENABLED.store(true, Ordering::Relaxed);
+        Ok(Self { finished: false })
+    }
+
+    /// Stops the recording and returns the recorded operations.
+    pub fn finish(mut self) -> OpManifest {
+        self.finished = true;
+        OpManifest {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            ops: stop().unwrap_or_default(),
+        }
+    }
+}
+
+impl Drop for ManifestRecorder {
+    fn drop(&mut self) {
+        if !self.finished {
+            stop();
+        }
+    }
+}
+
+fn stop() -> Option<Vec<OpRecord>> {
+    ENABLED.store(false, Ordering::Relaxed);
+    lock().take()
+}
+
+pub fn is_recording() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+// Called when an operation completes, its output is recorded when the resulting tensor is
+// created.
+pub(crate) fn record_op(op: &'static str, ok: bool) {
+    if is_recording() {
+        PENDING_OP.with(|p| p.set(ok.then_some(op)))
+    }
+}
+
+// Called when a new tensor is created from a storage, records it if it is the output of the last
+// operation of the thread.
+pub(crate) fn record_output(t: &Tensor) {
+    if !is_recording() || HASHING.with(|h| h.get()) {
+        return;
+    }
+    let op = match PENDING_OP.with(|p| p.take()) {
+        None => return,
+        Some(op) => op,
+    };
+    let checksum = match t.device().location() {
+        DeviceLocation::Meta => None,
+        _ => {
+            HASHING.with(|h| h.set(true));
+            let checksum = t.fingerprint().ok();
+            HASHING.with(|h| h.set(false));
+            checksum
+        }
+    };
+    if let Some(ops) = lock().as_mut() {
+        ops.push(OpRecord {
+            op: op.to_string(),
+            dtype: t.dtype(),
+            shape: t.dims().to_vec(),
+            checksum,
+        })
+    }
+}
+
+/// The operations recorded by a [`ManifestRecorder`], in the order in which they ran.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpManifest {
+    /// The candle version used for the recording.
+    pub version: String,
+    pub ops: Vec<OpRecord>,
+}
+
+/// A difference between two manifests at the operation `index`. `expected` is the operation in
+/// the reference manifest and `got` the one in the compared manifest, `None` when the manifests
+/// have different lengths.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpDiff {
+    pub index: usize,
+    pub expected: Option<OpRecord>,
+    pub got: Option<OpRecord>,
+}
+
+impl OpDiff {
+    /// Whether the operations differ by their name, dtype or shape rather than only by the value
+    /// of their output, which indicates that the graph itself has changed.
+    pub fn is_structural(&self) -> bool {
+        match (&self.expected, &self.got) {
+            (Some(e), Some(g)) => e.op != g.op || e.dtype != g.dtype || e.shape != g.shape,
+            _ => true,
+        }
+    }
+}
+
+/// The result of [`OpManifest::compare`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestDiff {
+    pub expected_version: String,
+    pub got_version: String,
+    pub diffs: Vec<OpDiff>,
+}
+
+impl ManifestDiff {
+    pub fn is_identical(&self) -> bool {
+        self.diffs.is_empty()
+    }
+
+    /// The first operation that differs, the following differences are often consequences of
+    /// this one.
+    pub fn first(&self) -> Option<&OpDiff> {
+        self.diffs.first()
+    }
+}
+
+impl std::fmt::Display for ManifestDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        const MAX_DIFFS: usize = 10;
+        if self.diffs.is_empty() {
+            return write!(f, "identical outputs");
+        }
+        writeln!(
+            f,
+            "{} differing ops between candle {} and {}",
+            self.diffs.len(),
+            self.expected_version,
+            self.got_version
+        )?;
+        let show = |r: &Option<OpRecord>| match r {
+            None => "missing".to_string(),
+            Some(r) => r.to_string(),
+        };
+        for diff in self.diffs.iter().take(MAX_DIFFS) {
+            writeln!(
+                f,
+                "{:>6}: expected {}, got {}",
+                diff.index,
+                show(&diff.expected),
+                show(&diff.got)
+            )?;
+        }
+        if self.diffs.len() > MAX_DIFFS {
+            writeln!(f, "...")?;
+        }
+        Ok(())
+    }
+}
+
+impl OpManifest {
+    /// Records the operations run by `f`.
+    pub fn record<T, F: FnOnce() -> Result<T>>(f: F) -> Result<(T, Self)> {
+        let recorder = ManifestRecorder::new()?;
+        let res = f()?;
+        Ok((res, recorder.finish()))
+    }
+
+    /// Compares the manifest with a `reference` one, operation by operation. The operations
+    /// without a checksum only have their name, dtype and shape compared.
+    pub fn compare(&self, reference: &Self) -> ManifestDiff {
+        let len = self.ops.len().max(reference.ops.len());
+        let diffs = (0..len)
+            .filter_map(|index| {
+                let expected = reference.ops.get(index);
+                let got = self.ops.get(index);
+                let same = match (expected, got) {
+                    (Some(e), Some(g)) => {
+                        e.op == g.op
+                            && e.dtype == g.dtype
+                            && e.shape == g.shape
+                            && (e.checksum.is_none()
+                                || g.checksum.is_none()
+                                || e.checksum == g.checksum)
+                    }
+                    _ => false,
+                };
+                (!same).then(|| OpDiff {
+                    index,
+                    expected: expected.cloned(),
+                    got: got.cloned(),
+                })
+            })
+            .collect();
+        ManifestDiff {
+            expected_version: reference.version.clone(),
+            got_version: self.version.clone(),
+            diffs,
+        }
+    }
+
+    /// Parses a manifest in the format written by `save`.
+    pub fn parse(s: &str) -> Result<Self> {
+        let mut lines = s.lines();
+        if lines.next() != Some(HEADER) {
+            crate::bail!("invalid op manifest header")
+        }
+        let version = match lines.next().and_then(|l| l.strip_prefix("# version ")) {
+            None => crate::bail!("missing version in op manifest"),
+            Some(version) => version.to_string(),
+        };
+        let mut ops = vec![];
+        for (i, line) in lines.enumerate() {
+            if line.is_empty() {
+                continue;
+            }
+            let fields = line.split('\t').collect::<Vec<_>>();
+            let (op, dtype, shape, checksum) = match fields.as_slice() {
+                [op, dtype, shape, checksum] => (op, dtype, shape, checksum),
+                _ => crate::bail!("invalid op manifest line {}: {line}", i + 3),
+            };
+            let dtype = match dtype.parse::<DType>() {
+                Ok(dtype) => dtype,
+                Err(_) => crate::bail!("invalid dtype in op manifest: {dtype}"),
+            };
+            let shape = match shape.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+                None => crate::bail!("invalid shape in op manifest: {shape}"),
+                Some("") => vec![],
+                Some(dims) => dims
+                    .split(',')
+                    .map(|d| d.trim().parse::<usize>())
+                    .collect::<std::result::Result<Vec<_>, _>>()
+                    .map_err(|_| crate::Error::Msg(format!("invalid shape {shape}")))?,
+            };
+            let checksum = match *checksum {
+                "-" => None,
+                c => match u64::from_str_radix(c, 16) {
+                    Ok(c) => Some(c),
+                    Err(_) => crate::bail!("invalid checksum in op manifest: {c}"),
+                },
+            };
+            ops.push(OpRecord {
+                op: op.to_string(),
+                dtype,
+                shape,
+                checksum,
+            })
+        }
+        Ok(Self { version, ops })
+    }
+
+    /// Writes the manifest as text, one tab separated line per operation.
+    pub fn save<P: AsRef<std::path::Path>>(&self, path: P) -> Result<()> {
+        std::fs::write(path, self.to_string())?;
+        Ok(())
+    }
+
+    pub fn load<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+}
+
+impl std::fmt::Display for OpManifest {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "{HEADER}")?;
+        writeln!(f, "# version {}", self.version)?;
+        for op in self.ops.iter() {
+            let shape = op
+                .shape
+                .iter()
+                .map(|d| d.to_string())
+                .collect::<Vec<_>>()
+                .join(",");
+            let checksum = match op.checksum {
+                None => "-".to_string(),
+                Some(c) => format!("{c:016x}"),
+            };
+            writeln!(f, "{}\t{}\t[{shape}]\t{checksum}", op.op, op.dtype.as_str())?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,113 @@
+//! Stateless counter-based random numbers using the Philox4x32-10 generator.
+//!
+//! Rather than advancing a generator state, each value is a function of a key: a seed, an op
+//! id identifying the random op, e.g. a dropout layer, and the offset of the element. The same
+//! key always gives the same values whatever the device, the order in which the ops run, or how
+//! a tensor is split in chunks, e.g. a chunk starting at element `k` of a larger tensor gets the
+//! values of the full tensor by using an offset of `k`.
+//!
+//! The values are generated on the host and copied to the target device.
+use crate::{Device, Result, Shape, Tensor};
+
+const M0: u32 = 0xD2511F53;
+const M1: u32 = 0xCD9E8D57;
+const W0: u32 = 0x9E3779B9;
+const W1: u32 = 0xBB67AE85;
+
+fn mulhilo(a: u32, b: u32) -> (u32, u32) {
+    let p = a as u64 * b as u64;
+    ((p >> 32) as u32, p as u32)
+}
+
+/// The Philox4x32 bijection with 10 rounds as described in "Parallel Random Numbers: As Easy as
+/// 1, 2, 3", Salmon et al.
+pub fn philox4x32_10(counter: [u32; 4], key: [u32; 2]) -> [u32; 4] {
+    let [mut c0, mut c1, mut c2, mut c3] = counter;
+    let [mut k0, mut k1] = key;
+    for _ in 0..10 {
+        let (hi0, lo0) = mulhilo(M0, c0);
+        let (hi1, lo1) = mulhilo(M1, c2);
+        (c0, c1, c2, c3) = (hi1 ^ c1 ^ k0, lo1, hi0 ^ c3 ^ k1, lo0);
+        k0 = k0.wrapping_add(W0);
+        k1 = k1.wrapping_add(W1);
+    }
+    [c0, c1, c2, c3]
+}
+
+/// The key identifying a stream of random values, see the module documentation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct PhiloxKey {
+    pub seed: u64,
+    pub op_id: u64,
+    /// The index of the first element.
+    pub offset: u64,
+}
+
+impl PhiloxKey {
+    pub fn new(seed: u64, op_id: u64) -> Self {
+        Self {
+            seed,
+            op_id,
+            offset: 0,
+        }
+    }
+
+    pub fn with_offset(self, offset: u64) -> Self {
+        Self { offset, ..self }
+    }
+
+    /// The random 32 bits words for the elements `offset..offset + n`.
+    pub fn u32s(&self, n: usize) -> Vec<u32> {
+        let key = [self.seed as u32, (self.seed >> 32) as u32];
+        let (op0, op1) = (self.op_id as u32, (self.op_id >> 32) as u32);
+        let mut values = Vec::with_capacity(n);
+        let mut index = self.offset;
+        let end = self.offset + n as u64;
+        while index < end {
+            let block = index / 4;
+            let counter = [block as u32, (block >> 32) as u32, op0, op1];
+            let words = philox4x32_10(counter, key);
+            let start = (index % 4) as usize;
+            let len = usize::min(4 - start, (end - index) as usize);
+            values.extend_from_slice(&words[start..start + len]);
+            index += len as u64
+        }
+        values
+    }
+
+    /// Uniform values in `[0, 1)` for the elements `offset..offset + n`.
+    pub fn uniform_vec(&self, n: usize) -> Vec<f32> {
+        // The top 24 bits give all the floats of the form k / 2^24.
+        self.u32s(n)
+            .into_iter()
+            .map(|v| (v >> 8) as f32 / (1u32 << 24) as f32)
+            .collect()
+    }
+
+    /// Standard normal values for the elements `offset..offset + n`, element `i` is computed with
+    /// the Box-Muller transform from the words `2i` and `2i + 1`.
+    pub fn normal_vec(&self, n: usize) -> Vec<f32> {
+        let words = self.with_offset(2 * self.offset).u32s(2 * n);
+        words
+            .chunks_exact(2)
+            .map(|w| {
+                // Map u1 to (0, 1] so that the log is finite.
+                let u1 = ((w[0] >> 8) + 1) as f32 / (1u32 << 24) as f32;
+                let u2 = (w[1] >> 8) as f32 / (1u32 << 24) as f32;
+                (-2. * u1.ln()).sqrt() * (2. * std::f32::consts::PI * u2).cos()
+            })
+            .collect()
+    }
+
+    /// A f32 tensor with values uniformly distributed in `[0, 1)`.
+    pub fn uniform<S: Into<Shape>>(&self, shape: S, device: &Device) -> Result<Tensor> {
+        let shape = shape.into();
+        Tensor::from_vec(self.uniform_vec(shape.elem_count()), shape, device)
+    }
+
+    /// A f32 tensor with values drawn from a standard normal distribution.
+    pub fn normal<S: Into<Shape>>(&self, shape: S, device: &Device) -> Result<Tensor> {
+        let shape = shape.into();
+        Tensor::from_vec(self.normal_vec(shape.elem_count()), shape, device)
+    }
+}
@@ -0,0 +1,396 @@
+//! Profiling of the tensor operations.
+//!
+//! While a [`Profiler`] is running, the wall time of each tensor operation, the size of the
+//! tensors that they allocate and the user defined [`scope`]s are recorded. The resulting
+//! [`Profile`] can be summarized per operation or per scope, or exported as a chrome trace to be
+//! opened with `chrome://tracing` or <https://ui.perfetto.dev>.
+//!
+//! The cuda and metal operations run asynchronously so their wall time only measures the kernel
+//! launches by default. [`Profiler::cuda_events`] measures the cuda operations with events
+//! recorded on the device stream, the durations being resolved when the profiler finishes, so
+//! that the device execution time is measured without stalling the host.
+//! [`Profiler::synchronize`] instead makes the profiler wait for the device after each operation
+//! so that the execution time is attributed to the right operation on all the devices, at the
+//! price of a slower execution.
+//!
+//! ```ignore
+//! let profiler = candle::profile::Profiler::new()?.cuda_events(true);
+//! for (i, layer) in layers.iter().enumerate() {
+//!     let _scope = candle::profile::scope(format!("layer-{i}"));
+//!     xs = layer.forward(&xs)?;
+//! }
+//! let profile = profiler.finish();
+//! for stats in profile.scope_stats() {
+//!     println!("{}: {:?} over {} calls", stats.name, stats.total, stats.count);
+//! }
+//! profile.write_chrome_trace("trace.json")?;
+//! ```
+use crate::backend::BackendStorage;
+use crate::{DeviceLocation, Result};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    /// A tensor operation, `bytes` is the size of its output.
+    Op,
+    /// A user defined scope, see [`scope`].
+    Scope,
+    /// A tensor allocated outside of an operation, e.g. with `Tensor::zeros` or `Tensor::new`.
+    Alloc,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    pub name: String,
+    pub kind: EventKind,
+    /// The device of the operation or allocation, `None` for the scopes.
+    pub device: Option<DeviceLocation>,
+    /// The start of the event relative to the start of the profiler.
+    pub start: Duration,
+    pub duration: Duration,
+    pub bytes: usize,
+    /// An identifier for the thread that recorded the event.
+    pub thread: usize,
+}
+
+/// The statistics aggregated over the events with the same name.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EventStats {
+    pub name: String,
+    pub count: usize,
+    pub total: Duration,
+    pub bytes: usize,
+}
+
+struct State {
+    origin: Instant,
+    events: Vec<Event>,
+    // Incremented for each profiler so that the pending ops of a previous one are ignored.
+    generation: usize,
+    // The index of the op events timed with cuda events, with their start and end events.
+    cuda_timings: Vec<(usize, crate::CudaEvent, crate::CudaEvent)>,
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static SYNCHRONIZE: AtomicBool = AtomicBool::new(false);
+static CUDA_EVENTS: AtomicBool = AtomicBool::new(false);
+static STATE: Mutex<Option<State>> = Mutex::new(None);
+static GENERATION: AtomicUsize = AtomicUsize::new(0);
+static THREAD_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static THREAD_ID: usize = THREAD_COUNTER.fetch_add(1, Ordering::Relaxed);
+    // The generation and index of the last op event of this thread, until its output is
+    // allocated.
+    static PENDING_OP: std::cell::Cell<Option<(usize, usize)>> = const { std::cell::Cell::new(None) };
+}
+
+fn lock() -> std::sync::MutexGuard<'static, Option<State>> {
+    STATE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn push_event(state: &mut State, event: Event) -> usize {
+    state.events.push(event);
+    state.events.len() - 1
+}
+
+/// Records the tensor operations while alive, only one profiler can run at a time and the
+/// operations of all the threads are recorded.
+pub struct Profiler {
+    finished: bool,
+}
+
+impl Profiler {
+    pub fn new() -> Result<Self> {
+        let mut state = lock();
+        if state.is_some() {
+            crate::bail!("a profiler is already running")
+        }
+        *state = Some(State {
+            origin: Instant::now(),
+            events: vec![],
+            generation: GENERATION.fetch_add(1, Ordering::Relaxed) + 1,
+            cuda_timings: vec![],
+        });
+        ENABLED.store(true, Ordering::Relaxed);
+        Ok(Self { finished: false })
+    }
+
+    /// Waits for the device to complete each operation before recording its duration.
+    pub fn synchronize(self, synchronize: bool) -> Self {
+        SYNCHRONIZE.store(synchronize, Ordering::Relaxed);
+        self
+    }
+
+    /// Measures the duration of the cuda operations with events recorded before and after each
+    /// operation on the default stream of the device. The operations on other devices use the
+    /// wall time, or the synchronized wall time with [`Profiler::synchronize`].
+    pub fn cuda_events(self, cuda_events: bool) -> Self {
+        CUDA_EVENTS.store(cuda_events, Ordering::Relaxed);
+        self
+    }
+
+    /// Stops the recording and returns the recorded events, this waits for the cuda events.
+    pub fn finish(mut self) -> Profile {
+        self.finished = true;
+        let mut state = match stop() {
+            None => return Profile { events: vec![] },
+            Some(state) => state,
+        };
+        for (index, start, end) in state.cuda_timings.iter() {
+            // The wall time is kept if the events cannot be resolved.
+            if let Ok(duration) = end.elapsed_since(start) {
+                state.events[*index].duration = duration
+            }
+        }
+        Profile {
+            events: state.events,
+        }
+    }
+}
+
+impl Drop for Profiler {
+    fn drop(&mut self) {
+        if !self.finished {
+            stop();
+        }
+    }
+}
+
+fn stop() -> Option<State> {
+    ENABLED.store(false, Ordering::Relaxed);
+    SYNCHRONIZE.store(false, Ordering::Relaxed);
+    CUDA_EVENTS.store(false, Ordering::Relaxed);
+    lock().take()
+}
+
+pub fn is_profiling() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// A user defined scope, e.g. a layer of a model, recorded when dropped.
+pub struct Scope {
+    name: String,
+    start: Option<Instant>,
+}
+
+/// Starts a scope that ends when the returned value is dropped. This does nothing when no
+/// profiler is running.
+pub fn scope(name: impl Into<String>) -> Scope {
+    let start = is_profiling().then(Instant::now);
+    Scope {
+        name: name.into(),
+        start,
+    }
+}
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        let start = match self.start {
+            None => return,
+            Some(start) => start,
+        };
+        let duration = start.elapsed();
+        if let Some(state) = lock().as_mut() {
+            let event = Event {
+                name: std::mem::take(&mut self.name),
+                kind: EventKind::Scope,
+                device: None,
+                start: start.saturating_duration_since(state.origin),
+                duration,
+                bytes: 0,
+                thread: THREAD_ID.with(|t| *t),
+            };
+            push_event(state, event);
+        }
+    }
+}
+
+// The start of an operation when profiling, see `op_start`.
+pub(crate) struct OpStart {
+    instant: Instant,
+    cuda_event: Option<crate::CudaEvent>,
+}
+
+fn timing_event(storage: &crate::Storage) -> Option<crate::CudaEvent> {
+    match storage {
+        // Failing to record an event falls back to the wall time.
+        crate::Storage::Cuda(s) => s.device().record_timing_event().ok(),
+        _ => None,
+    }
+}
+
+// Returns the start of an operation on `storage` when profiling.
+pub(crate) fn op_start(storage: &crate::Storage) -> Option<OpStart> {
+    if !is_profiling() {
+        return None;
+    }
+    let cuda_event = if CUDA_EVENTS.load(Ordering::Relaxed) {
+        timing_event(storage)
+    } else {
+        None
+    };
+    Some(OpStart {
+        instant: Instant::now(),
+        cuda_event,
+    })
+}
+
+// Records an operation started with `op_start`, the device is only looked up when profiling.
+pub(crate) fn record_op(start: Option<OpStart>, name: &str, storage: &crate::Storage) {
+    let OpStart {
+        instant: start,
+        cuda_event,
+    } = match start {
+        None => return,
+        Some(start) => start,
+    };
+    let cuda_events = match cuda_event {
+        Some(start_event) => timing_event(storage).map(|end_event| (start_event, end_event)),
+        None => None,
+    };
+    let device = storage.device();
+    if cuda_events.is_none() && SYNCHRONIZE.load(Ordering::Relaxed) {
+        // Errors are reported by the following operations.
+        let _ = device.synchronize();
+    }
+    let duration = start.elapsed();
+    if let Some(state) = lock().as_mut() {
+        let event = Event {
+            name: name.to_string(),
+            kind: EventKind::Op,
+            device: Some(device.location()),
+            start: start.saturating_duration_since(state.origin),
+            duration,
+            bytes: 0,
+            thread: THREAD_ID.with(|t| *t),
+        };
+        let index = push_event(state, event);
+        if let Some((start_event, end_event)) = cuda_events {
+            state.cuda_timings.push((index, start_event, end_event))
+        }
+        PENDING_OP.with(|p| p.set(Some((state.generation, index))));
+    }
+}
+
+// Called when a new tensor is created, the size is attributed to the last operation of the
+// thread if it has not been attributed yet.
+pub(crate) fn record_alloc(bytes: usize, device: &crate::Device) {
+    if !is_profiling() {
+        return;
+    }
+    let pending = PENDING_OP.with(|p| p.take());
+    if let Some(state) = lock().as_mut() {
+        match pending {
+            Some((generation, index)) if generation == state.generation => {
+                state.events[index].bytes += bytes
+            }
+            _ => {
+                let event = Event {
+                    name: "alloc".to_string(),
+                    kind: EventKind::Alloc,
+                    device: Some(device.location()),
+                    start: state.origin.elapsed(),
+                    duration: Duration::ZERO,
+                    bytes,
+                    thread: THREAD_ID.with(|t| *t),
+                };
+                push_event(state, event);
+            }
+        }
+    }
+}
+
+/// The events recorded by a [`Profiler`], in the order in which they ended.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Profile {
+    pub events: Vec<Event>,
+}
+
+fn escape_json(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+impl Profile {
+    fn stats(&self, kind: EventKind) -> Vec<EventStats> {
+        let mut stats: Vec<EventStats> = vec![];
+        let mut indexes = std::collections::HashMap::new();
+        for event in self.events.iter().filter(|e| e.kind == kind) {
+            let index = *indexes.entry(event.name.as_str()).or_insert_with(|| {
+                stats.push(EventStats {
+                    name: event.name.clone(),
+                    count: 0,
+                    total: Duration::ZERO,
+                    bytes: 0,
+                });
+                stats.len() - 1
+            });
+            let s = &mut stats[index];
+            s.count += 1;
+            s.total += event.duration;
+            s.bytes += event.bytes;
+        }
+        stats.sort_by(|s1, s2| s2.total.cmp(&s1.total).then_with(|| s1.name.cmp(&s2.name)));
+        stats
+    }
+
+    /// The statistics for each operation, sorted by decreasing total time.
+    pub fn op_stats(&self) -> Vec<EventStats> {
+        self.stats(EventKind::Op)
+    }
+
+    /// The statistics for each scope name, sorted by decreasing total time.
+    pub fn scope_stats(&self) -> Vec<EventStats> {
+        self.stats(EventKind::Scope)
+    }
+
+    /// The total size of the tensors allocated while profiling, including the operation outputs.
+    pub fn total_bytes(&self) -> usize {
+        self.events.iter().map(|e| e.bytes).sum()
+    }
+
+    /// The events in the chrome trace event format, the operations and scopes are complete
+    /// events and the allocations outside of operations are instant events.
+    pub fn to_chrome_trace(&self) -> String {
+        let events = self
+            .events
+            .iter()
+            .map(|e| {
+                let (cat, ph) = match e.kind {
+                    EventKind::Op => ("op", "\"ph\":\"X\""),
+                    EventKind::Scope => ("scope", "\"ph\":\"X\""),
+                    EventKind::Alloc => ("alloc", "\"ph\":\"i\",\"s\":\"t\""),
+                };
+                let device = match e.device {
+                    None => String::new(),
+                    Some(d) => format!(",\"device\":\"{}\"", escape_json(&format!("{d:?}"))),
+                };
+                format!(
+                    "{{\"name\":\"{}\",\"cat\":\"{cat}\",{ph},\"ts\":{:.3},\"dur\":{:.3},\"pid\":0,\"tid\":{},\"args\":{{\"bytes\":{}{device}}}}}",
+                    escape_json(&e.name),
+                    e.start.as_secs_f64() * 1e6,
+                    e.duration.as_secs_f64() * 1e6,
+                    e.thread,
+                    e.bytes,
+                )
+            })
+            .collect::<Vec<_>>();
+        format!("{{\"traceEvents\":[{}]}}", events.join(","))
+    }
+
+    pub fn write_chrome_trace<P: AsRef<std::path::Path>>(&self, path: P) -> Result<()> {
+        std::fs::write(path, self.to_chrome_trace())?;
+        Ok(())
+    }
+}
@@ -0,0 +1,113 @@
+//! Type promotion for binary operations on tensors with different dtypes.
+//!
+//! By default the element-wise binary operations, comparisons, `matmul` and `where_cond` return
+//! a [`crate::Error::DTypeMismatchBinaryOp`] error when their operands have different dtypes, on
+//! all the devices. With the [`PromotionPolicy::Promote`] policy, the operands are instead
+//! converted to their common dtype as given by [`promote_types`] before running the operation.
+//! The policy can be set globally and overridden for specific operations.
+//!
+//! ```rust
+//! use candle_core::promotion::{promote_types, set_op_promotion_policy, PromotionPolicy};
+//! use candle_core::{DType, Device, Tensor};
+//! # fn main() -> candle_core::Result<()> {
+//! assert_eq!(promote_types(DType::F16, DType::BF16), DType::F32);
+//! assert_eq!(promote_types(DType::I64, DType::F16), DType::F16);
+//! let xs = Tensor::new(&[1f32, 2.], &Device::Cpu)?;
+//! let ys = Tensor::new(&[1u32, 3], &Device::Cpu)?;
+//! assert!(xs.add(&ys).is_err());
+//! set_op_promotion_policy("add", Some(PromotionPolicy::Promote));
+//! assert_eq!(xs.add(&ys)?.to_vec1::<f32>()?, [2., 5.]);
+//! set_op_promotion_policy("add", None);
+//! # Ok(())
+//! # }
+//! ```
+use crate::{DType, Error, Result, Tensor};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+
+/// What to do when the operands of a binary operation have different dtypes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PromotionPolicy {
+    /// Return a dtype mismatch error.
+    #[default]
+    Error,
+    /// Convert both operands to their common dtype.
+    Promote,
+}
+
+static PROMOTE: AtomicBool = AtomicBool::new(false);
+static OP_POLICIES: RwLock<BTreeMap<&'static str, PromotionPolicy>> = RwLock::new(BTreeMap::new());
+
+/// Sets the policy used by the operations without a specific policy, this is
+/// [`PromotionPolicy::Error`] by default.
+pub fn set_promotion_policy(policy: PromotionPolicy) {
+    PROMOTE.store(policy == PromotionPolicy::Promote, Ordering::Relaxed)
+}
+
+pub fn promotion_policy() -> PromotionPolicy {
+    if PROMOTE.load(Ordering::Relaxed) {
+        PromotionPolicy::Promote
+    } else {
+        PromotionPolicy::Error
+    }
+}
+
+/// Sets the policy for the operation `op`, e.g. `"add"`, `"maximum"`, `"cmp"`, `"matmul"` or
+/// `"where_cond"`, `None` reverts to the global policy.
+pub fn set_op_promotion_policy(op: &'static str, policy: Option<PromotionPolicy>) {
+    let mut policies = OP_POLICIES.write().unwrap_or_else(|e| e.into_inner());
+    match policy {
+        None => policies.remove(op),
+        Some(policy) => policies.insert(op, policy),
+    };
+}
+
+/// The policy applied to the operation `op`.
+pub fn op_promotion_policy(op: &str) -> PromotionPolicy {
+    let policies = OP_POLICIES.read().unwrap_or_else(|e| e.into_inner());
+    match policies.get(op) {
+        Some(&policy) => policy,
+        None => promotion_policy(),
+    }
+}
+
+/// The dtype both operands of a binary operation are converted to when promoting.
+///
+/// - Floats win over integers, e.g. `i64` and `f16` give `f16`.
+/// - Two floats give the largest one, except for `f16` and `bf16` which give `f32` as neither
+///   can represent all the values of the other.
+/// - Two integers give the largest one, `u8` < `u32` < `i64`.
+pub fn promote_types(lhs: DType, rhs: DType) -> DType {
+    use DType::*;
+    match (lhs, rhs) {
+        _ if lhs == rhs => lhs,
+        (F16, BF16) | (BF16, F16) => F32,
+        _ if lhs.is_float() != rhs.is_float() => {
+            if lhs.is_float() {
+                lhs
+            } else {
+                rhs
+            }
+        }
+        _ if lhs.size_in_bytes() >= rhs.size_in_bytes() => lhs,
+        _ => rhs,
+    }
+}
+
+/// Applies the promotion policy of `op` to operands with different dtypes, returning the
+/// converted operands or a dtype mismatch error.
+pub(crate) fn promote(lhs: &Tensor, rhs: &Tensor, op: &'static str) -> Result<(Tensor, Tensor)> {
+    match op_promotion_policy(op) {
+        PromotionPolicy::Error => Err(Error::DTypeMismatchBinaryOp {
+            lhs: lhs.dtype(),
+            rhs: rhs.dtype(),
+            op,
+        }
+        .bt()),
+        PromotionPolicy::Promote => {
+            let dtype = promote_types(lhs.dtype(), rhs.dtype());
+            Ok((lhs.to_dtype(dtype)?, rhs.to_dtype(dtype)?))
+        }
+    }
+}
@@ -581,6 +581,21 @@ pub fn load_quantized<T: super::GgmlType + Send + Sync + 'static>(
     }))
 }
 
+/// Same as [`load_quantized`] for the raw bytes of a quantized tensor, with the copy queued on
+/// `stream`.
+pub fn load_quantized_on_stream(
+    stream: &crate::CudaStream,
+    data: &[u8],
+    dtype: GgmlDType,
+) -> Result<super::QStorage> {
+    let data = stream.htod_copy(data)?;
+    Ok(QStorage::Cuda(QCudaStorage {
+        data,
+        device: stream.device().clone(),
+        dtype,
+    }))
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
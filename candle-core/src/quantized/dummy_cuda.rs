@@ -52,3 +52,11 @@ pub fn load_quantized<T: super::GgmlType + Send + Sync + 'static>(
 ) -> Result<super::QStorage> {
     Err(Error::NotCompiledWithCudaSupport)
 }
+
+pub fn load_quantized_on_stream(
+    _stream: &crate::CudaStream,
+    _data: &[u8],
+    _dtype: GgmlDType,
+) -> Result<super::QStorage> {
+    Err(Error::NotCompiledWithCudaSupport)
+}
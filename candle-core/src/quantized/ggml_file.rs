@@ -130,6 +130,12 @@ fn from_raw_data<T: super::GgmlType + Send + Sync + 'static>(
         Device::Cpu => QStorage::Cpu(Box::new(data.to_vec())),
         Device::Metal(metal) => super::metal::load_quantized(metal, data)?,
         Device::Cuda(cuda) => super::cuda::load_quantized(cuda, data)?,
+        Device::Meta(_) => Err(crate::Error::UnsupportedOp {
+            backend: "meta",
+            op: "quantized",
+            dtype: None,
+        }
+        .bt())?,
     };
     super::QTensor::new(data, dims)
 }
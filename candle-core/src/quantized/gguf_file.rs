@@ -493,6 +493,28 @@ pub fn write<W: std::io::Seek + std::io::Write>(
     metadata: &[(&str, &Value)],
     tensors: &[(&str, &QTensor)],
 ) -> Result<()> {
+    let by_name = tensors.iter().copied().collect::<HashMap<_, _>>();
+    let infos = tensors
+        .iter()
+        .map(|(name, tensor)| (*name, tensor.dtype(), tensor.shape().clone()))
+        .collect::<Vec<_>>();
+    write_with(w, metadata, &infos, |name| Ok(by_name[name]))
+}
+
+/// Same as [`write`] but the tensors are only described by their name, dtype and shape, and
+/// `load` is called to produce each of them when its data is written so that a single tensor
+/// has to be held in memory.
+pub fn write_with<W, T, F>(
+    w: &mut W,
+    metadata: &[(&str, &Value)],
+    tensors: &[(&str, GgmlDType, crate::Shape)],
+    mut load: F,
+) -> Result<()>
+where
+    W: std::io::Seek + std::io::Write,
+    T: std::borrow::Borrow<QTensor>,
+    F: FnMut(&str) -> Result<T>,
+{
     w.write_u32::<LittleEndian>(0x46554747)?;
     w.write_u32::<LittleEndian>(2)?; // version 2.
     w.write_u64::<LittleEndian>(tensors.len() as u64)?;
@@ -504,17 +526,17 @@ pub fn write<W: std::io::Seek + std::io::Write>(
     }
     let mut offset = 0usize;
     let mut offsets = Vec::with_capacity(tensors.len());
-    for (name, tensor) in tensors.iter() {
+    for (name, dtype, shape) in tensors.iter() {
         write_string(w, name)?;
-        let dims = tensor.shape().dims();
+        let dims = shape.dims();
         w.write_u32::<LittleEndian>(dims.len() as u32)?;
         for &dim in dims.iter().rev() {
             w.write_u64::<LittleEndian>(dim as u64)?;
         }
-        w.write_u32::<LittleEndian>(tensor.dtype().to_u32())?;
+        w.write_u32::<LittleEndian>(dtype.to_u32())?;
         w.write_u64::<LittleEndian>(offset as u64)?;
         offsets.push(offset);
-        let size_in_bytes = tensor.storage_size_in_bytes();
+        let size_in_bytes = shape.elem_count() / dtype.block_size() * dtype.type_size();
         let padding = 31 - (31 + size_in_bytes) % 32;
         offset += size_in_bytes + padding;
     }
@@ -522,13 +544,22 @@ pub fn write<W: std::io::Seek + std::io::Write>(
     let padding = 31 - (31 + pos) % 32;
     w.write_all(&vec![0u8; padding])?;
     let tensor_start_pos = w.stream_position()? as usize;
-    for (offset, (_name, tensor)) in offsets.iter().zip(tensors.iter()) {
+    for (offset, (name, dtype, shape)) in offsets.iter().zip(tensors.iter()) {
         let pos = w.stream_position()? as usize;
         if tensor_start_pos + offset != pos {
             crate::bail!(
                 "internal error, unexpected current position {tensor_start_pos} {offset} {pos}"
             )
         }
+        let tensor = load(name)?;
+        let tensor = tensor.borrow();
+        if tensor.dtype() != *dtype || tensor.shape() != shape {
+            crate::bail!(
+                "{name} was expected to be {dtype:?} {shape:?}, got {:?} {:?}",
+                tensor.dtype(),
+                tensor.shape()
+            )
+        }
         let data = tensor.data()?;
         let size_in_bytes = data.len();
         w.write_all(&data)?;
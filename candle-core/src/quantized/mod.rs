@@ -47,6 +47,12 @@ impl Device {
                 let storage = metal::QMetalStorage::zeros(metal, elem_count, dtype)?;
                 Ok(QStorage::Metal(storage))
             }
+            Device::Meta(_) => Err(crate::Error::UnsupportedOp {
+                backend: "meta",
+                op: "quantized",
+                dtype: None,
+            }
+            .bt())?,
             Device::Cuda(cuda) => {
                 let storage = cuda::QCudaStorage::zeros(cuda, elem_count, dtype)?;
                 Ok(QStorage::Cuda(storage))
@@ -387,6 +393,19 @@ impl QTensor {
     pub fn data(&self) -> Result<Cow<'_, [u8]>> {
         self.storage.data()
     }
+
+    /// Copies a cpu tensor to the device of `stream` with the copy queued on this stream, see
+    /// [`Tensor::to_device_async`].
+    pub fn to_device_async(&self, stream: &crate::CudaStream) -> Result<Self> {
+        if !matches!(self.storage, QStorage::Cpu(_)) {
+            crate::bail!("asynchronous copies are only supported from the cpu")
+        }
+        let storage = cuda::load_quantized_on_stream(stream, &self.data()?, self.dtype())?;
+        Ok(Self {
+            storage,
+            shape: self.shape.clone(),
+        })
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -523,10 +542,53 @@ impl crate::CustomOp1 for QTensor {
     }
 }
 
+// Wraps a quantized matmul so that gradients can flow to its input. The quantized weights are
+// frozen and do not get gradients, this is used for training adapters over quantized models.
+struct QMatMulOp(std::sync::Arc<QTensor>);
+
+impl crate::CustomOp1 for QMatMulOp {
+    fn name(&self) -> &'static str {
+        "qmatmul"
+    }
+
+    fn cpu_fwd(
+        &self,
+        storage: &crate::CpuStorage,
+        layout: &crate::Layout,
+    ) -> Result<(crate::CpuStorage, Shape)> {
+        self.0.cpu_fwd(storage, layout)
+    }
+
+    fn metal_fwd(
+        &self,
+        storage: &crate::MetalStorage,
+        layout: &crate::Layout,
+    ) -> Result<(crate::MetalStorage, Shape)> {
+        self.0.metal_fwd(storage, layout)
+    }
+
+    fn cuda_fwd(
+        &self,
+        storage: &crate::CudaStorage,
+        layout: &crate::Layout,
+    ) -> Result<(crate::CudaStorage, Shape)> {
+        self.0.cuda_fwd(storage, layout)
+    }
+
+    fn bwd(&self, arg: &Tensor, _res: &Tensor, grad_res: &Tensor) -> Result<Option<Tensor>> {
+        // The weights have a shape (n, k) and the forward pass computes arg @ w^t.
+        let w = self
+            .0
+            .dequantize(arg.device())?
+            .to_dtype(grad_res.dtype())?;
+        Ok(Some(grad_res.broadcast_matmul(&w)?))
+    }
+}
+
 impl crate::Module for QMatMul {
     fn forward(&self, xs: &Tensor) -> Result<Tensor> {
         match self {
-            Self::QTensor(t) => xs.apply_op1_no_bwd(t.as_ref()),
+            Self::QTensor(t) => xs.apply_op1(QMatMulOp(t.clone())),
             Self::Tensor(w) => {
                 let w = match *xs.dims() {
                     [b1, b2, _, _] => w.broadcast_left((b1, b2))?.t()?,
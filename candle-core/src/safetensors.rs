@@ -169,12 +169,21 @@ fn convert_back_<T: WithDType>(mut vs: Vec<T>) -> Vec<u8> {
 
 pub trait Load {
     fn load(&self, device: &Device) -> Result<Tensor>;
+
+    /// Loads the tensor and converts it to `dtype`.
+    fn load_as(&self, dtype: DType, device: &Device) -> Result<Tensor> {
+        self.load(device)?.to_dtype(dtype)
+    }
 }
 
 impl<'a> Load for st::TensorView<'a> {
     fn load(&self, device: &Device) -> Result<Tensor> {
         convert(self, device)
     }
+
+    fn load_as(&self, dtype: DType, device: &Device) -> Result<Tensor> {
+        convert_as(self, dtype, device)
+    }
 }
 
 impl Tensor {
@@ -217,6 +226,48 @@ fn convert(view: &st::TensorView<'_>, device: &Device) -> Result<Tensor> {
     }
 }
 
+fn cast_<T: WithDType, U: WithDType>(view: &st::TensorView<'_>, device: &Device) -> Result<Tensor> {
+    convert_with_cast_::<T, U, _>(view, device, |v| Ok(U::from_f64(v.to_f64())))
+}
+
+fn convert_as_<T: WithDType>(
+    view: &st::TensorView<'_>,
+    dtype: DType,
+    device: &Device,
+) -> Result<Tensor> {
+    match dtype {
+        DType::BF16 => cast_::<T, half::bf16>(view, device),
+        DType::F16 => cast_::<T, half::f16>(view, device),
+        DType::F32 => cast_::<T, f32>(view, device),
+        DType::F64 => cast_::<T, f64>(view, device),
+        _ => convert_::<T>(view, device)?.to_dtype(dtype),
+    }
+}
+
+// Loads a tensor and converts it to `dtype`. Float conversions are done while reading the stored
+// data when targeting the cpu, or when the target dtype is smaller so that less data has to be
+// copied to the device. This avoids materializing the tensor in its stored dtype.
+fn convert_as(view: &st::TensorView<'_>, dtype: DType, device: &Device) -> Result<Tensor> {
+    let view_dtype = match DType::try_from(view.dtype()) {
+        Ok(view_dtype) => view_dtype,
+        Err(_) => return convert(view, device)?.to_dtype(dtype),
+    };
+    let cast_on_host = view_dtype != dtype
+        && view_dtype.is_float()
+        && dtype.is_float()
+        && (device.is_cpu() || dtype.size_in_bytes() < view_dtype.size_in_bytes());
+    if !cast_on_host {
+        return convert(view, device)?.to_dtype(dtype);
+    }
+    match view_dtype {
+        DType::BF16 => convert_as_::<half::bf16>(view, dtype, device),
+        DType::F16 => convert_as_::<half::f16>(view, dtype, device),
+        DType::F32 => convert_as_::<f32>(view, dtype, device),
+        DType::F64 => convert_as_::<f64>(view, dtype, device),
+        _ => convert(view, device)?.to_dtype(dtype),
+    }
+}
+
 fn convert_back(tensor: &Tensor) -> Result<Vec<u8>> {
     // TODO: This makes an unnecessary copy when the tensor is on the cpu.
     let tensor = tensor.flatten_all()?;
@@ -251,6 +302,324 @@ pub fn save<K: AsRef<str> + Ord + std::fmt::Display, P: AsRef<Path>>(
     Ok(st::serialize_to_file(tensors, &None, filename.as_ref())?)
 }
 
+// The `__metadata__` keys used by `save_streaming`, followed by the tensor name.
+const CRC32_KEY: &str = "crc32:";
+const ZSTD_KEY: &str = "zstd:";
+
+/// The options of [`save_streaming`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StreamingSaveOptions {
+    /// Stores the crc32 of the data of each tensor in the header, the checksums are verified by
+    /// [`load_verified`].
+    pub checksums: bool,
+    /// Compresses each tensor with zstd at the given level, this requires the `zstd` feature.
+    pub compression_level: Option<i32>,
+    /// Additional entries of the `__metadata__` header.
+    pub metadata: HashMap<String, String>,
+}
+
+impl Default for StreamingSaveOptions {
+    fn default() -> Self {
+        Self {
+            checksums: true,
+            compression_level: None,
+            metadata: HashMap::new(),
+        }
+    }
+}
+
+struct StreamingEntry<'a> {
+    name: &'a str,
+    dtype: DType,
+    dims: &'a [usize],
+    // The stored size, an upper bound for the compressed tensors until they are written.
+    size: usize,
+    offsets: (usize, usize),
+    crc32: u32,
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn streaming_header(entries: &[StreamingEntry<'_>], options: &StreamingSaveOptions) -> String {
+    let compressed = options.compression_level.is_some();
+    let mut metadata = options
+        .metadata
+        .iter()
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect::<Vec<_>>();
+    for e in entries.iter() {
+        if options.checksums {
+            metadata.push((format!("{CRC32_KEY}{}", e.name), format!("{:08x}", e.crc32)))
+        }
+        if compressed {
+            let dims = e.dims.iter().map(|d| d.to_string());
+            let dims = dims.collect::<Vec<_>>().join(",");
+            let info = format!("{}:{dims}", e.dtype.as_str());
+            metadata.push((format!("{ZSTD_KEY}{}", e.name), info))
+        }
+    }
+    metadata.sort();
+    let mut fields = vec![];
+    if !metadata.is_empty() {
+        let metadata = metadata
+            .iter()
+            .map(|(k, v)| format!("{}:{}", json_string(k), json_string(v)))
+            .collect::<Vec<_>>();
+        fields.push(format!("\"__metadata__\":{{{}}}", metadata.join(",")))
+    }
+    for e in entries.iter() {
+        let (dtype, shape) = if compressed {
+            (st::Dtype::U8, vec![e.size])
+        } else {
+            (e.dtype.into(), e.dims.to_vec())
+        };
+        let shape = shape.iter().map(|d| d.to_string()).collect::<Vec<_>>();
+        fields.push(format!(
+            "{}:{{\"dtype\":\"{dtype:?}\",\"shape\":[{}],\"data_offsets\":[{},{}]}}",
+            json_string(e.name),
+            shape.join(","),
+            e.offsets.0,
+            e.offsets.1,
+        ))
+    }
+    format!("{{{}}}", fields.join(","))
+}
+
+#[cfg(feature = "zstd")]
+fn zstd_compress_bound(size: usize) -> usize {
+    zstd::zstd_safe::compress_bound(size)
+}
+
+#[cfg(not(feature = "zstd"))]
+fn zstd_compress_bound(size: usize) -> usize {
+    size
+}
+
+#[cfg(feature = "zstd")]
+fn zstd_compress(data: &[u8], level: i32) -> Result<Vec<u8>> {
+    Ok(zstd::bulk::compress(data, level)?)
+}
+
+#[cfg(not(feature = "zstd"))]
+fn zstd_compress(_: &[u8], _: i32) -> Result<Vec<u8>> {
+    crate::bail!("compressing safetensors requires the zstd feature")
+}
+
+#[cfg(feature = "zstd")]
+fn zstd_decompress(data: &[u8], size: usize) -> Result<Vec<u8>> {
+    Ok(zstd::bulk::decompress(data, size)?)
+}
+
+#[cfg(not(feature = "zstd"))]
+fn zstd_decompress(_: &[u8], _: usize) -> Result<Vec<u8>> {
+    crate::bail!("decompressing safetensors requires the zstd feature")
+}
+
+/// Writes the tensors in the safetensors format, the tensors are copied to the host and written
+/// one at a time so that only a single tensor has to be buffered.
+///
+/// The header is written last in some space reserved at the start of the output. The checksums
+/// are stored in `__metadata__` under `crc32:{name}` and are computed on the uncompressed data.
+/// Compressed tensors are stored as `u8` tensors holding a zstd frame, their dtype and shape are
+/// stored under `zstd:{name}` as e.g. `f32:2,3` so that the file remains readable by any
+/// safetensors loader, [`load_verified`] decompresses them.
+pub fn save_streaming<K: AsRef<str>, W: std::io::Write + std::io::Seek>(
+    tensors: &[(K, &Tensor)],
+    w: &mut W,
+    options: &StreamingSaveOptions,
+) -> Result<()> {
+    let by_name = tensors
+        .iter()
+        .map(|(name, tensor)| (name.as_ref(), *tensor))
+        .collect::<HashMap<_, _>>();
+    let infos = tensors
+        .iter()
+        .map(|(name, tensor)| (name.as_ref(), tensor.dtype(), tensor.shape().clone()))
+        .collect::<Vec<_>>();
+    save_streaming_with(&infos, |name| Ok(by_name[name].clone()), w, options)
+}
+
+/// Same as [`save_streaming`] but the tensors are only described by their name, dtype and
+/// shape, and `load` is called to produce each of them when it is written. This makes it
+/// possible to write a checkpoint while holding a single tensor in memory.
+pub fn save_streaming_with<K, W, F>(
+    tensors: &[(K, DType, crate::Shape)],
+    mut load: F,
+    w: &mut W,
+    options: &StreamingSaveOptions,
+) -> Result<()>
+where
+    K: AsRef<str>,
+    W: std::io::Write + std::io::Seek,
+    F: FnMut(&str) -> Result<Tensor>,
+{
+    use std::io::SeekFrom;
+
+    if options.compression_level.is_some() && !cfg!(feature = "zstd") {
+        crate::bail!("compressing safetensors requires the zstd feature")
+    }
+    let mut entries = tensors
+        .iter()
+        .map(|(name, dtype, shape)| {
+            let size = shape.elem_count() * dtype.size_in_bytes();
+            let size = match options.compression_level {
+                None => size,
+                Some(_) => zstd_compress_bound(size),
+            };
+            StreamingEntry {
+                name: name.as_ref(),
+                dtype: *dtype,
+                dims: shape.dims(),
+                size,
+                offsets: (0, 0),
+                crc32: u32::MAX,
+            }
+        })
+        .collect::<Vec<_>>();
+    entries.sort_by(|e1, e2| e1.name.cmp(e2.name));
+    if let Some(w) = entries.windows(2).find(|w| w[0].name == w[1].name) {
+        crate::bail!("duplicate tensor name {} in save_streaming", w[0].name)
+    }
+
+    // Reserves enough space for the header with all the offsets at their upper bound.
+    let total_size = entries.iter().map(|e| e.size).sum::<usize>();
+    for e in entries.iter_mut() {
+        e.offsets = (total_size, total_size);
+        if options.compression_level.is_some() {
+            e.size = total_size
+        }
+    }
+    let header_len = streaming_header(&entries, options)
+        .len()
+        .next_multiple_of(8);
+    let header_start = w.stream_position()?;
+    w.write_all(&(header_len as u64).to_le_bytes())?;
+    w.write_all(&vec![b' '; header_len])?;
+
+    let mut offset = 0;
+    for e in entries.iter_mut() {
+        let tensor = load(e.name)?;
+        if tensor.dtype() != e.dtype || tensor.dims() != e.dims {
+            crate::bail!(
+                "{} was expected to be {:?} {:?}, got {:?} {:?}",
+                e.name,
+                e.dtype,
+                e.dims,
+                tensor.dtype(),
+                tensor.dims()
+            )
+        }
+        let data = convert_back(&tensor)?;
+        e.crc32 = crc32fast::hash(&data);
+        let data = match options.compression_level {
+            None => data,
+            Some(level) => zstd_compress(&data, level)?,
+        };
+        w.write_all(&data)?;
+        e.size = data.len();
+        e.offsets = (offset, offset + data.len());
+        offset += data.len();
+    }
+
+    let header = streaming_header(&entries, options);
+    let end = w.stream_position()?;
+    w.seek(SeekFrom::Start(header_start + 8))?;
+    w.write_all(header.as_bytes())?;
+    w.seek(SeekFrom::Start(end))?;
+    w.flush()?;
+    Ok(())
+}
+
+/// Writes the tensors to a file with [`save_streaming`].
+pub fn save_streaming_file<K: AsRef<str>, P: AsRef<Path>>(
+    tensors: &[(K, &Tensor)],
+    filename: P,
+    options: &StreamingSaveOptions,
+) -> Result<()> {
+    let p = filename.as_ref();
+    let file = std::fs::File::create(p).map_err(|e| Error::from(e).with_path(p))?;
+    let mut file = std::io::BufWriter::new(file);
+    save_streaming(tensors, &mut file, options).map_err(|e| e.with_path(p))
+}
+
+/// Loads a safetensors file, verifying the checksums and decompressing the tensors written by
+/// [`save_streaming`]. The tensors without a checksum are loaded without verification.
+pub fn load_verified<P: AsRef<Path>>(
+    filename: P,
+    device: &Device,
+) -> Result<HashMap<String, Tensor>> {
+    let p = filename.as_ref();
+    let data = std::fs::read(p).map_err(|e| Error::from(e).with_path(p))?;
+    load_buffer_verified(&data[..], device).map_err(|e| e.with_path(p))
+}
+
+/// Same as [`load_verified`] for a buffer.
+pub fn load_buffer_verified(data: &[u8], device: &Device) -> Result<HashMap<String, Tensor>> {
+    let (_, metadata) = SafeTensors::read_metadata(data)?;
+    let metadata = metadata.metadata().clone().unwrap_or_default();
+    let st = SafeTensors::deserialize(data)?;
+    st.tensors()
+        .into_iter()
+        .map(|(name, view)| {
+            let decompressed;
+            let (bytes, dtype, shape) = match metadata.get(&format!("{ZSTD_KEY}{name}")) {
+                None => {
+                    let dtype = DType::try_from(view.dtype())?;
+                    (view.data(), dtype, view.shape().to_vec())
+                }
+                Some(info) => {
+                    let (dtype, shape) = parse_zstd_info(info).ok_or_else(|| {
+                        Error::Msg(format!("invalid zstd info for {name}: {info}"))
+                    })?;
+                    let size = shape.iter().product::<usize>() * dtype.size_in_bytes();
+                    decompressed = zstd_decompress(view.data(), size)?;
+                    if decompressed.len() != size {
+                        crate::bail!(
+                            "unexpected decompressed size for {name}: {} <> {size}",
+                            decompressed.len()
+                        )
+                    }
+                    (decompressed.as_slice(), dtype, shape)
+                }
+            };
+            if let Some(expected) = metadata.get(&format!("{CRC32_KEY}{name}")) {
+                let crc32 = format!("{:08x}", crc32fast::hash(bytes));
+                if &crc32 != expected {
+                    crate::bail!("checksum mismatch for {name}: {crc32} <> {expected}")
+                }
+            }
+            let tensor = Tensor::from_raw_buffer(bytes, dtype, &shape, device)?;
+            Ok((name, tensor))
+        })
+        .collect()
+}
+
+fn parse_zstd_info(info: &str) -> Option<(DType, Vec<usize>)> {
+    let (dtype, dims) = info.split_once(':')?;
+    let dtype = dtype.parse::<DType>().ok()?;
+    let dims = if dims.is_empty() {
+        vec![]
+    } else {
+        dims.split(',')
+            .map(|d| d.parse::<usize>().ok())
+            .collect::<Option<Vec<_>>>()?
+    };
+    Some((dtype, dims))
+}
+
 #[derive(yoke::Yokeable)]
 struct SafeTensors_<'a>(SafeTensors<'a>);
 
@@ -324,6 +693,11 @@ impl MmapedSafetensors {
         self.get(name)?.load(dev)
     }
 
+    /// Loads a tensor and converts it to `dtype`, see `Load::load_as`.
+    pub fn load_as(&self, name: &str, dtype: DType, dev: &Device) -> Result<Tensor> {
+        self.get(name)?.load_as(dtype, dev)
+    }
+
     pub fn tensors(&self) -> Vec<(String, st::TensorView<'_>)> {
         let mut tensors = vec![];
         for safetensors in self.safetensors.iter() {
@@ -364,6 +738,11 @@ impl<'a> SliceSafetensors<'a> {
         self.safetensors.tensor(name)?.load(dev)
     }
 
+    /// Loads a tensor and converts it to `dtype`, see `Load::load_as`.
+    pub fn load_as(&self, name: &str, dtype: DType, dev: &Device) -> Result<Tensor> {
+        self.safetensors.tensor(name)?.load_as(dtype, dev)
+    }
+
     pub fn tensors(&self) -> Vec<(String, st::TensorView<'_>)> {
         self.safetensors.tensors()
     }
@@ -394,6 +773,11 @@ impl BufferedSafetensors {
         self.get(name)?.load(dev)
     }
 
+    /// Loads a tensor and converts it to `dtype`, see `Load::load_as`.
+    pub fn load_as(&self, name: &str, dtype: DType, dev: &Device) -> Result<Tensor> {
+        self.get(name)?.load_as(dtype, dev)
+    }
+
     pub fn tensors(&self) -> Vec<(String, st::TensorView<'_>)> {
         self.safetensors.get().0.tensors()
     }
@@ -434,6 +818,223 @@ impl MmapedFile {
     }
 }
 
+/// The quantization scheme of a tensor, as detected by [`SafetensorsInspector`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quantization {
+    /// 8-bit floats, `F8_E4M3` or `F8_E5M2`.
+    Fp8,
+    /// 8-bit integers.
+    Int8,
+    /// Integers packed in a wider type, e.g. the `qweight` and `qzeros` tensors of GPTQ and AWQ
+    /// checkpoints.
+    Packed,
+}
+
+/// The description of a tensor in a safetensors header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TensorInfo {
+    pub name: String,
+    /// The dtype as stored in the file, this may not be supported by candle.
+    pub dtype: st::Dtype,
+    pub shape: Vec<usize>,
+    pub size_in_bytes: usize,
+    pub quantization: Option<Quantization>,
+    /// Whether the tensor was zstd compressed by [`save_streaming`].
+    pub compressed: bool,
+}
+
+impl TensorInfo {
+    pub fn elem_count(&self) -> usize {
+        self.shape.iter().product()
+    }
+
+    /// The candle dtype if supported.
+    pub fn candle_dtype(&self) -> Option<DType> {
+        DType::try_from(self.dtype).ok()
+    }
+}
+
+struct InspectedFile {
+    mmap: memmap2::Mmap,
+    data_start: usize,
+    metadata: st::Metadata,
+}
+
+/// Reads the headers of safetensors files without deserializing the tensors.
+///
+/// The files are memory mapped and only the headers are parsed, so listing the tensors of a
+/// large model is fast. Single tensors, or a range of rows of a tensor, can then be loaded
+/// without touching the rest of the file.
+pub struct SafetensorsInspector {
+    files: Vec<InspectedFile>,
+    routing: HashMap<String, usize>,
+}
+
+impl SafetensorsInspector {
+    /// # Safety
+    ///
+    /// The unsafe is inherited from [`memmap2::MmapOptions`].
+    pub unsafe fn new<P: AsRef<Path>>(p: P) -> Result<Self> {
+        Self::multi(&[p])
+    }
+
+    /// Inspects the shards of a model, if a tensor name appears in multiple files the last
+    /// entry is used.
+    ///
+    /// # Safety
+    ///
+    /// The unsafe is inherited from [`memmap2::MmapOptions`].
+    pub unsafe fn multi<P: AsRef<Path>>(paths: &[P]) -> Result<Self> {
+        let mut files = Vec::with_capacity(paths.len());
+        let mut routing = HashMap::new();
+        for (index, p) in paths.iter().enumerate() {
+            let p = p.as_ref();
+            let file = std::fs::File::open(p).map_err(|e| Error::from(e).with_path(p))?;
+            let mmap = memmap2::MmapOptions::new()
+                .map(&file)
+                .map_err(|e| Error::from(e).with_path(p))?;
+            let (n, metadata) =
+                SafeTensors::read_metadata(&mmap).map_err(|e| Error::from(e).with_path(p))?;
+            for name in metadata.tensors().into_keys() {
+                routing.insert(name, index);
+            }
+            files.push(InspectedFile {
+                mmap,
+                data_start: 8 + n,
+                metadata,
+            })
+        }
+        Ok(Self { files, routing })
+    }
+
+    fn file(&self, name: &str) -> Result<(&InspectedFile, &st::TensorInfo)> {
+        let file = self
+            .routing
+            .get(name)
+            .and_then(|&index| self.files.get(index));
+        match file.and_then(|f| Some((f, f.metadata.info(name)?))) {
+            Some(v) => Ok(v),
+            None => Err(Error::CannotFindTensor {
+                path: name.to_string(),
+            }
+            .bt()),
+        }
+    }
+
+    /// The `__metadata__` entries of all the files.
+    pub fn metadata(&self) -> HashMap<String, String> {
+        let mut metadata = HashMap::new();
+        for file in self.files.iter() {
+            if let Some(m) = file.metadata.metadata() {
+                metadata.extend(m.iter().map(|(k, v)| (k.clone(), v.clone())))
+            }
+        }
+        metadata
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.routing.contains_key(name)
+    }
+
+    pub fn get(&self, name: &str) -> Result<TensorInfo> {
+        let (file, info) = self.file(name)?;
+        let compressed = file
+            .metadata
+            .metadata()
+            .as_ref()
+            .is_some_and(|m| m.contains_key(&format!("{ZSTD_KEY}{name}")));
+        let quantization = match info.dtype {
+            st::Dtype::F8_E4M3 | st::Dtype::F8_E5M2 => Some(Quantization::Fp8),
+            st::Dtype::I8 => Some(Quantization::Int8),
+            _ if name.ends_with("qweight") || name.ends_with("qzeros") => {
+                Some(Quantization::Packed)
+            }
+            _ => None,
+        };
+        Ok(TensorInfo {
+            name: name.to_string(),
+            dtype: info.dtype,
+            shape: info.shape.clone(),
+            size_in_bytes: info.data_offsets.1.saturating_sub(info.data_offsets.0),
+            quantization,
+            compressed,
+        })
+    }
+
+    /// All the tensors, sorted by name.
+    pub fn tensors(&self) -> Result<Vec<TensorInfo>> {
+        let mut names = self.routing.keys().collect::<Vec<_>>();
+        names.sort();
+        names.into_iter().map(|name| self.get(name)).collect()
+    }
+
+    /// The total number of elements, e.g. to get the number of parameters of a model.
+    pub fn elem_count(&self) -> Result<usize> {
+        Ok(self.tensors()?.iter().map(|t| t.elem_count()).sum())
+    }
+
+    /// The raw bytes of a tensor, an error is returned if the offsets from the header do not
+    /// fit in the file.
+    pub fn data(&self, name: &str) -> Result<&[u8]> {
+        let (file, info) = self.file(name)?;
+        let (start, end) = info.data_offsets;
+        let range = file
+            .data_start
+            .checked_add(start)
+            .zip(file.data_start.checked_add(end))
+            .filter(|(start, end)| start <= end && *end <= file.mmap.len());
+        match range {
+            Some((start, end)) => Ok(&file.mmap[start..end]),
+            None => crate::bail!(
+                "invalid data offsets {start}..{end} for {name} in a file of {} bytes",
+                file.mmap.len()
+            ),
+        }
+    }
+
+    pub fn load(&self, name: &str, dev: &Device) -> Result<Tensor> {
+        let info = self.get(name)?;
+        let dtype = self.loadable_dtype(&info)?;
+        Tensor::from_raw_buffer(self.data(name)?, dtype, &info.shape, dev)
+    }
+
+    /// Loads the rows `start..start + len` of a tensor, i.e. a narrow along the first
+    /// dimension, only reading these rows from the file.
+    pub fn load_rows(&self, name: &str, start: usize, len: usize, dev: &Device) -> Result<Tensor> {
+        let info = self.get(name)?;
+        let dtype = self.loadable_dtype(&info)?;
+        let n_rows = match info.shape.first() {
+            None => crate::bail!("cannot load rows of the scalar tensor {name}"),
+            Some(&n_rows) => n_rows,
+        };
+        let end = match start.checked_add(len) {
+            Some(end) if end <= n_rows => end,
+            _ => crate::bail!("rows {start}..+{len} out of range for {name} with {n_rows} rows"),
+        };
+        let data = self.data(name)?;
+        let row_size = info.shape[1..]
+            .iter()
+            .try_fold(dtype.size_in_bytes(), |acc, &d| acc.checked_mul(d));
+        let range = row_size
+            .and_then(|row_size| Some((start.checked_mul(row_size)?, end.checked_mul(row_size)?)))
+            .filter(|(_, end)| *end <= data.len());
+        let data = match range {
+            Some((start, end)) => &data[start..end],
+            None => crate::bail!("{name} has {} bytes, too few for its shape", data.len()),
+        };
+        let mut shape = info.shape.clone();
+        shape[0] = len;
+        Tensor::from_raw_buffer(data, dtype, &shape, dev)
+    }
+
+    fn loadable_dtype(&self, info: &TensorInfo) -> Result<DType> {
+        if info.compressed {
+            crate::bail!("{} is compressed, use load_verified to load it", info.name)
+        }
+        DType::try_from(info.dtype)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
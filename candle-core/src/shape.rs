@@ -1,6 +1,8 @@
 //! The shape of a tensor is a tuple with the size of each of its dimensions.
 #![allow(clippy::redundant_closure_call)]
 use crate::{Error, Result};
+use std::cell::Cell;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 #[derive(Clone, PartialEq, Eq)]
 pub struct Shape(Vec<usize>);
@@ -13,6 +15,74 @@ impl std::fmt::Debug for Shape {
     }
 }
 
+static STRICT_SHAPES: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    static STRICT_SHAPES_SCOPE: Cell<Option<bool>> = const { Cell::new(None) };
+}
+
+/// Enables or disables the strict shape mode for all the threads, returning the previous state.
+///
+/// The binary ops, comparisons and `where_cond` broadcast a 0-dim operand to the shape of the
+/// other one. In strict mode this returns a [`Error::ShapeMismatchBinaryOp`] error instead, so
+/// that all broadcasting goes through the explicit `broadcast_*` ops, which helps catching shape
+/// bugs during development. Scalars passed as numbers, e.g. `xs.maximum(0f32)`, are still
+/// accepted.
+pub fn set_strict_shapes(enabled: bool) -> bool {
+    STRICT_SHAPES.swap(enabled, Ordering::Relaxed)
+}
+
+/// Whether the strict shape mode applies to the current thread, see [`set_strict_shapes`].
+pub fn is_strict_shapes() -> bool {
+    STRICT_SHAPES_SCOPE
+        .with(|s| s.get())
+        .unwrap_or_else(|| STRICT_SHAPES.load(Ordering::Relaxed))
+}
+
+// Rejects operands of different shapes in strict mode, this runs before any implicit
+// broadcasting of the binary ops so that only the explicit `broadcast_*` ops can broadcast.
+pub(crate) fn check_strict_binary_op(lhs: &Shape, rhs: &Shape, op: &'static str) -> Result<()> {
+    if lhs != rhs && is_strict_shapes() {
+        Err(Error::ShapeMismatchBinaryOp {
+            lhs: lhs.clone(),
+            rhs: rhs.clone(),
+            op,
+        }
+        .bt())?
+    }
+    Ok(())
+}
+
+/// Overrides the strict shape mode for the current thread until dropped, see
+/// [`set_strict_shapes`].
+///
+/// ```rust
+/// use candle_core::{shape::StrictShapes, Device, Tensor};
+/// let xs = Tensor::new(&[1f32, 2.], &Device::Cpu)?;
+/// let two = Tensor::new(2f32, &Device::Cpu)?;
+/// let _guard = StrictShapes::new(true);
+/// assert!(xs.mul(&two).is_err());
+/// assert!(xs.broadcast_mul(&two).is_ok());
+/// # Ok::<(), candle_core::Error>(())
+/// ```
+pub struct StrictShapes {
+    previous: Option<bool>,
+}
+
+impl StrictShapes {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            previous: STRICT_SHAPES_SCOPE.with(|s| s.replace(Some(enabled))),
+        }
+    }
+}
+
+impl Drop for StrictShapes {
+    fn drop(&mut self) {
+        STRICT_SHAPES_SCOPE.with(|s| s.set(self.previous));
+    }
+}
+
 impl<const C: usize> From<&[usize; C]> for Shape {
     fn from(dims: &[usize; C]) -> Self {
         Self(dims.to_vec())
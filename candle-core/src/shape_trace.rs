@@ -0,0 +1,150 @@
+//! Shape inference by running a model on a meta device.
+//!
+//! The tensors created on the device of a [`ShapeTracer`] only track their shape and dtype, so a
+//! model can be built with zero weights on this device and run to validate its configuration
+//! before loading the actual weights. The shape errors are reported as on the other devices and
+//! the trace lists the operations that were run with the specs of their inputs and output.
+//!
+//! ```ignore
+//! let tracer = ShapeTracer::new();
+//! let vb = VarBuilder::zeros(DType::BF16, tracer.device());
+//! let model = Model::new(&config, vb)?;
+//! let trace = tracer.trace_module(&model, &TensorSpec::new((1, 128), DType::U32))?;
+//! println!("{trace}");
+//! ```
+use crate::backend::BackendDevice;
+use crate::meta_backend::MetaDevice;
+use crate::{DType, Device, Module, Result, Shape, Tensor};
+
+/// The shape and dtype of a tensor.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TensorSpec {
+    pub shape: Shape,
+    pub dtype: DType,
+}
+
+impl TensorSpec {
+    pub fn new<S: Into<Shape>>(shape: S, dtype: DType) -> Self {
+        Self {
+            shape: shape.into(),
+            dtype,
+        }
+    }
+
+    pub fn of(t: &Tensor) -> Self {
+        Self::new(t.shape().clone(), t.dtype())
+    }
+}
+
+impl std::fmt::Display for TensorSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:?}{:?}", self.dtype, self.shape.dims())
+    }
+}
+
+/// An operation run on a meta device. The inputs are listed with the shape of their layout,
+/// before any broadcasting done by the operation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceOp {
+    pub op: &'static str,
+    pub inputs: Vec<TensorSpec>,
+    pub output: TensorSpec,
+}
+
+/// The result of a traced run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShapeTrace {
+    pub outputs: Vec<TensorSpec>,
+    pub ops: Vec<TraceOp>,
+}
+
+impl ShapeTrace {
+    /// The number of operations per name.
+    pub fn op_counts(&self) -> Vec<(&'static str, usize)> {
+        let mut counts: Vec<(&'static str, usize)> = vec![];
+        for op in self.ops.iter() {
+            match counts.iter_mut().find(|(name, _)| *name == op.op) {
+                Some((_, count)) => *count += 1,
+                None => counts.push((op.op, 1)),
+            }
+        }
+        counts
+    }
+}
+
+impl std::fmt::Display for ShapeTrace {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for op in self.ops.iter() {
+            let inputs = op
+                .inputs
+                .iter()
+                .map(|s| s.to_string())
+                .collect::<Vec<_>>()
+                .join(", ");
+            writeln!(f, "{:<20} {inputs} -> {}", op.op, op.output)?
+        }
+        let outputs = self
+            .outputs
+            .iter()
+            .map(|s| s.to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        write!(f, "outputs: {outputs}")
+    }
+}
+
+/// Runs models on a meta device and collects the shapes of the operations.
+#[derive(Debug, Clone)]
+pub struct ShapeTracer {
+    device: Device,
+}
+
+impl ShapeTracer {
+    pub fn new() -> Self {
+        // Creating a meta device cannot fail.
+        let device = MetaDevice::new(0).expect("meta device");
+        Self {
+            device: Device::Meta(device),
+        }
+    }
+
+    /// The meta device on which the model weights and inputs should be created.
+    pub fn device(&self) -> &Device {
+        &self.device
+    }
+
+    /// Creates a meta tensor with the given spec.
+    pub fn input(&self, spec: &TensorSpec) -> Result<Tensor> {
+        Tensor::zeros(spec.shape.clone(), spec.dtype, &self.device)
+    }
+
+    fn take_trace(&self) -> Vec<TraceOp> {
+        match &self.device {
+            Device::Meta(device) => device.take_trace(),
+            _ => vec![],
+        }
+    }
+
+    /// Runs `f` and returns the specs of the tensors it returns together with the operations
+    /// that it ran on the meta device.
+    pub fn trace<F: FnOnce() -> Result<Vec<Tensor>>>(&self, f: F) -> Result<ShapeTrace> {
+        // Discard the operations that were run when building the model.
+        self.take_trace();
+        let outputs = f();
+        let ops = self.take_trace();
+        let outputs = outputs?.iter().map(TensorSpec::of).collect();
+        Ok(ShapeTrace { outputs, ops })
+    }
+
+    /// Runs the forward pass of `m` on an input with the given spec.
+    pub fn trace_module<M: Module>(&self, m: &M, input: &TensorSpec) -> Result<ShapeTrace> {
+        let xs = self.input(input)?;
+        self.trace(|| Ok(vec![m.forward(&xs)?]))
+    }
+}
+
+impl Default for ShapeTracer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,7 +1,9 @@
-use crate::backend::BackendStorage;
+use crate::backend::{BackendDevice, BackendStorage};
+use crate::meta_backend::MetaStorage;
 use crate::op::{self, CmpOp, ReduceOp};
-use crate::{CpuStorage, CudaStorage, DType, Device, Error, Layout, MetalStorage, Result, Shape};
+use crate::{CpuStorage, CudaStorage, DType, Device, Error, ErrorCode, Layout, MetalStorage};
 use crate::{CustomOp1, CustomOp2, CustomOp3, InplaceOp1, InplaceOp2, InplaceOp3};
+use crate::{Result, Shape};
 
 // We do not want to implement Clone on Storage as cloning may fail because of
 // out of memory. Instead try_clone should be used.
@@ -10,6 +12,7 @@ pub enum Storage {
     Cpu(CpuStorage),
     Cuda(CudaStorage),
     Metal(MetalStorage),
+    Meta(MetaStorage),
 }
 
 impl Storage {
@@ -24,6 +27,10 @@ impl Storage {
                 let storage = storage.try_clone(layout)?;
                 Ok(Self::Metal(storage))
             }
+            Self::Meta(storage) => {
+                let storage = storage.try_clone(layout)?;
+                Ok(Self::Meta(storage))
+            }
         }
     }
 
@@ -32,6 +39,7 @@ impl Storage {
             Self::Cpu(_) => Device::Cpu,
             Self::Cuda(storage) => Device::Cuda(storage.device().clone()),
             Self::Metal(storage) => Device::Metal(storage.device().clone()),
+            Self::Meta(storage) => Device::Meta(storage.device().clone()),
         }
     }
 
@@ -40,6 +48,7 @@ impl Storage {
             Self::Cpu(storage) => storage.dtype(),
             Self::Cuda(storage) => storage.dtype(),
             Self::Metal(storage) => storage.dtype(),
+            Self::Meta(storage) => storage.dtype(),
         }
     }
 
@@ -73,55 +82,116 @@ impl Storage {
         }
     }
 
+    // When a backend does not support an op and the cpu fallback is enabled, runs the op on cpu
+    // copies of the inputs and moves the result back to the original device. Only the
+    // `UnsupportedOp` errors fall back, and the backend error is returned if the op also fails on
+    // the cpu.
+    // The duration of the op is recorded when profiling, `start` being set by `profile::op_start`.
+    fn or_cpu_fallback<F>(
+        &self,
+        start: Option<crate::profile::OpStart>,
+        res: Result<Self>,
+        op: &'static str,
+        others: &[&Self],
+        f: F,
+    ) -> Result<Self>
+    where
+        F: FnOnce(&[CpuStorage]) -> Result<CpuStorage>,
+    {
+        let res = res.map(|s| (s, ()));
+        let res = self.or_cpu_fallback_(res, op, others, |xs| Ok((f(xs)?, ())));
+        crate::profile::record_op(start, op, self);
+        crate::op_manifest::record_op(op, res.is_ok());
+        res.map(|(s, ())| s)
+    }
+
+    // Same as `or_cpu_fallback` for the ops returning an additional value, e.g. the output shape
+    // of the custom ops, and without recording the op.
+    fn or_cpu_fallback_<F, T>(
+        &self,
+        res: Result<(Self, T)>,
+        op: &'static str,
+        others: &[&Self],
+        f: F,
+    ) -> Result<(Self, T)>
+    where
+        F: FnOnce(&[CpuStorage]) -> Result<(CpuStorage, T)>,
+    {
+        let err = match res {
+            Ok(res) => return Ok(res),
+            Err(err) => err,
+        };
+        if matches!(self, Self::Cpu(_) | Self::Meta(_))
+            || err.code() != ErrorCode::UnsupportedOp
+            || !crate::fallback::cpu_fallback_enabled()
+        {
+            return Err(err);
+        }
+        let inputs = std::iter::once(self)
+            .chain(others.iter().copied())
+            .map(|s| match s {
+                Self::Cpu(s) => Ok(s.clone()),
+                Self::Cuda(s) => s.to_cpu_storage(),
+                Self::Metal(s) => s.to_cpu_storage(),
+                Self::Meta(s) => s.to_cpu_storage(),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let (storage, extra) = match f(&inputs) {
+            Ok(res) => res,
+            Err(_) => return Err(err),
+        };
+        crate::fallback::record(op, &self.device(), &err);
+        let storage = match self {
+            Self::Cpu(_) => Self::Cpu(storage),
+            Self::Cuda(s) => Self::Cuda(s.device().storage_from_cpu_storage_owned(storage)?),
+            Self::Metal(s) => Self::Metal(s.device().storage_from_cpu_storage_owned(storage)?),
+            Self::Meta(_) => return Err(err),
+        };
+        Ok((storage, extra))
+    }
+
     pub(crate) fn affine(&self, layout: &Layout, mul: f64, add: f64) -> Result<Self> {
-        match self {
+        let start = crate::profile::op_start(self);
+        let res = match self {
             Storage::Cpu(storage) => {
                 let storage = storage.affine(layout, mul, add)?;
                 Ok(Self::Cpu(storage))
             }
-            Self::Cuda(storage) => {
-                let storage = storage.affine(layout, mul, add)?;
-                Ok(Self::Cuda(storage))
-            }
-            Self::Metal(storage) => {
-                let storage = storage.affine(layout, mul, add)?;
-                Ok(Self::Metal(storage))
-            }
-        }
+            Self::Cuda(storage) => storage.affine(layout, mul, add).map(Self::Cuda),
+            Self::Metal(storage) => storage.affine(layout, mul, add).map(Self::Metal),
+            Self::Meta(storage) => storage.affine(layout, mul, add).map(Self::Meta),
+        };
+        self.or_cpu_fallback(start, res, "affine", &[], |xs| {
+            xs[0].affine(layout, mul, add)
+        })
     }
 
     pub(crate) fn powf(&self, layout: &Layout, alpha: f64) -> Result<Self> {
-        match self {
+        let start = crate::profile::op_start(self);
+        let res = match self {
             Storage::Cpu(storage) => {
                 let storage = storage.powf(layout, alpha)?;
                 Ok(Self::Cpu(storage))
             }
-            Self::Cuda(storage) => {
-                let storage = storage.powf(layout, alpha)?;
-                Ok(Self::Cuda(storage))
-            }
-            Self::Metal(storage) => {
-                let storage = storage.powf(layout, alpha)?;
-                Ok(Self::Metal(storage))
-            }
-        }
+            Self::Cuda(storage) => storage.powf(layout, alpha).map(Self::Cuda),
+            Self::Metal(storage) => storage.powf(layout, alpha).map(Self::Metal),
+            Self::Meta(storage) => storage.powf(layout, alpha).map(Self::Meta),
+        };
+        self.or_cpu_fallback(start, res, "powf", &[], |xs| xs[0].powf(layout, alpha))
     }
 
     pub(crate) fn elu(&self, layout: &Layout, alpha: f64) -> Result<Self> {
-        match self {
+        let start = crate::profile::op_start(self);
+        let res = match self {
             Storage::Cpu(storage) => {
                 let storage = storage.elu(layout, alpha)?;
                 Ok(Self::Cpu(storage))
             }
-            Self::Cuda(storage) => {
-                let storage = storage.elu(layout, alpha)?;
-                Ok(Self::Cuda(storage))
-            }
-            Self::Metal(storage) => {
-                let storage = storage.elu(layout, alpha)?;
-                Ok(Self::Metal(storage))
-            }
-        }
+            Self::Cuda(storage) => storage.elu(layout, alpha).map(Self::Cuda),
+            Self::Metal(storage) => storage.elu(layout, alpha).map(Self::Metal),
+            Self::Meta(storage) => storage.elu(layout, alpha).map(Self::Meta),
+        };
+        self.or_cpu_fallback(start, res, "elu", &[], |xs| xs[0].elu(layout, alpha))
     }
 
     pub(crate) fn cmp(
@@ -131,20 +201,22 @@ impl Storage {
         lhs_layout: &Layout,
         rhs_layout: &Layout,
     ) -> Result<Self> {
+        let start = crate::profile::op_start(self);
         self.same_device(rhs, "cmp")?;
         self.same_dtype(rhs, "cmp")?;
-        match (self, rhs) {
+        let res = match (self, rhs) {
             (Storage::Cpu(lhs), Storage::Cpu(rhs)) => {
                 let storage = lhs.cmp(op, rhs, lhs_layout, rhs_layout)?;
                 Ok(Self::Cpu(storage))
             }
             (Self::Cuda(lhs), Self::Cuda(rhs)) => {
-                let storage = lhs.cmp(op, rhs, lhs_layout, rhs_layout)?;
-                Ok(Self::Cuda(storage))
+                lhs.cmp(op, rhs, lhs_layout, rhs_layout).map(Self::Cuda)
             }
             (Self::Metal(lhs), Self::Metal(rhs)) => {
-                let storage = lhs.cmp(op, rhs, lhs_layout, rhs_layout)?;
-                Ok(Self::Metal(storage))
+                lhs.cmp(op, rhs, lhs_layout, rhs_layout).map(Self::Metal)
+            }
+            (Self::Meta(lhs), Self::Meta(rhs)) => {
+                lhs.cmp(op, rhs, lhs_layout, rhs_layout).map(Self::Meta)
             }
             (lhs, rhs) => {
                 // Should not happen because of the same device check above but we're defensive
@@ -156,58 +228,66 @@ impl Storage {
                 }
                 .bt())
             }
-        }
+        };
+        self.or_cpu_fallback(start, res, "cmp", &[rhs], |xs| {
+            xs[0].cmp(op, &xs[1], lhs_layout, rhs_layout)
+        })
     }
 
     pub(crate) fn reduce_op(&self, op: ReduceOp, layout: &Layout, s: &[usize]) -> Result<Self> {
-        match self {
+        let start = crate::profile::op_start(self);
+        let res = match self {
             Storage::Cpu(storage) => {
                 let storage = storage.reduce_op(op, layout, s)?;
                 Ok(Self::Cpu(storage))
             }
-            Self::Cuda(storage) => {
-                let storage = storage.reduce_op(op, layout, s)?;
-                Ok(Self::Cuda(storage))
-            }
-            Self::Metal(storage) => {
-                let storage = storage.reduce_op(op, layout, s)?;
-                Ok(Self::Metal(storage))
-            }
-        }
+            Self::Cuda(storage) => storage.reduce_op(op, layout, s).map(Self::Cuda),
+            Self::Metal(storage) => storage.reduce_op(op, layout, s).map(Self::Metal),
+            Self::Meta(storage) => storage.reduce_op(op, layout, s).map(Self::Meta),
+        };
+        self.or_cpu_fallback(start, res, "reduce", &[], |xs| {
+            xs[0].reduce_op(op, layout, s)
+        })
     }
 
     pub(crate) fn to_dtype(&self, layout: &Layout, dtype: DType) -> Result<Self> {
-        match self {
+        let start = crate::profile::op_start(self);
+        let res = match self {
             Storage::Cpu(storage) => {
                 let storage = storage.to_dtype(layout, dtype)?;
                 Ok(Self::Cpu(storage))
             }
-            Self::Cuda(storage) => {
-                let storage = storage.to_dtype(layout, dtype)?;
-                Ok(Self::Cuda(storage))
-            }
-            Self::Metal(storage) => {
-                let storage = storage.to_dtype(layout, dtype)?;
-                Ok(Self::Metal(storage))
-            }
-        }
+            Self::Cuda(storage) => storage.to_dtype(layout, dtype).map(Self::Cuda),
+            Self::Metal(storage) => storage.to_dtype(layout, dtype).map(Self::Metal),
+            Self::Meta(storage) => storage.to_dtype(layout, dtype).map(Self::Meta),
+        };
+        self.or_cpu_fallback(start, res, "to-dtype", &[], |xs| {
+            xs[0].to_dtype(layout, dtype)
+        })
     }
 
     pub(crate) fn apply_op1(&self, l: &Layout, c: &dyn CustomOp1) -> Result<(Self, Shape)> {
-        match self {
+        let start = crate::profile::op_start(self);
+        let res = match self {
             Self::Cpu(storage) => {
                 let (storage, shape) = c.cpu_fwd(storage, l)?;
                 Ok((Self::Cpu(storage), shape))
             }
-            Self::Cuda(storage) => {
-                let (storage, shape) = c.cuda_fwd(storage, l)?;
-                Ok((Self::Cuda(storage), shape))
-            }
-            Self::Metal(storage) => {
-                let (storage, shape) = c.metal_fwd(storage, l)?;
-                Ok((Self::Metal(storage), shape))
+            Self::Cuda(storage) => c
+                .cuda_fwd(storage, l)
+                .map(|(s, shape)| (Self::Cuda(s), shape)),
+            Self::Metal(storage) => c
+                .metal_fwd(storage, l)
+                .map(|(s, shape)| (Self::Metal(s), shape)),
+            Self::Meta(storage) => {
+                let (storage, shape) = c.meta_fwd(storage, l)?;
+                Ok((Self::Meta(storage), shape))
             }
-        }
+        };
+        let res = self.or_cpu_fallback_(res, c.name(), &[], |xs| c.cpu_fwd(&xs[0], l));
+        crate::profile::record_op(start, c.name(), self);
+        crate::op_manifest::record_op(c.name(), res.is_ok());
+        res
     }
 
     pub(crate) fn apply_op2(
@@ -217,22 +297,30 @@ impl Storage {
         l2: &Layout,
         c: &dyn CustomOp2,
     ) -> Result<(Self, Shape)> {
+        let start = crate::profile::op_start(self);
         self.same_device(t2, c.name())?;
-        match (self, t2) {
+        let res = match (self, t2) {
             (Self::Cpu(s1), Self::Cpu(s2)) => {
                 let (s, shape) = c.cpu_fwd(s1, l1, s2, l2)?;
                 Ok((Self::Cpu(s), shape))
             }
-            (Self::Cuda(s1), Self::Cuda(s2)) => {
-                let (s, shape) = c.cuda_fwd(s1, l1, s2, l2)?;
-                Ok((Self::Cuda(s), shape))
-            }
-            (Self::Metal(s1), Self::Metal(s2)) => {
-                let (s, shape) = c.metal_fwd(s1, l1, s2, l2)?;
-                Ok((Self::Metal(s), shape))
+            (Self::Cuda(s1), Self::Cuda(s2)) => c
+                .cuda_fwd(s1, l1, s2, l2)
+                .map(|(s, shape)| (Self::Cuda(s), shape)),
+            (Self::Metal(s1), Self::Metal(s2)) => c
+                .metal_fwd(s1, l1, s2, l2)
+                .map(|(s, shape)| (Self::Metal(s), shape)),
+            (Self::Meta(s1), Self::Meta(s2)) => {
+                let (s, shape) = c.meta_fwd(s1, l1, s2, l2)?;
+                Ok((Self::Meta(s), shape))
             }
             _ => unreachable!(),
-        }
+        };
+        let res =
+            self.or_cpu_fallback_(res, c.name(), &[t2], |xs| c.cpu_fwd(&xs[0], l1, &xs[1], l2));
+        crate::profile::record_op(start, c.name(), self);
+        crate::op_manifest::record_op(c.name(), res.is_ok());
+        res
     }
 
     pub(crate) fn apply_op3(
@@ -244,23 +332,32 @@ impl Storage {
         l3: &Layout,
         c: &dyn CustomOp3,
     ) -> Result<(Self, Shape)> {
+        let start = crate::profile::op_start(self);
         self.same_device(t2, c.name())?;
         self.same_device(t3, c.name())?;
-        match (self, t2, t3) {
+        let res = match (self, t2, t3) {
             (Self::Cpu(s1), Self::Cpu(s2), Self::Cpu(s3)) => {
                 let (s, shape) = c.cpu_fwd(s1, l1, s2, l2, s3, l3)?;
                 Ok((Self::Cpu(s), shape))
             }
-            (Self::Cuda(s1), Self::Cuda(s2), Self::Cuda(s3)) => {
-                let (s, shape) = c.cuda_fwd(s1, l1, s2, l2, s3, l3)?;
-                Ok((Self::Cuda(s), shape))
-            }
-            (Self::Metal(s1), Self::Metal(s2), Self::Metal(s3)) => {
-                let (s, shape) = c.metal_fwd(s1, l1, s2, l2, s3, l3)?;
-                Ok((Self::Metal(s), shape))
+            (Self::Cuda(s1), Self::Cuda(s2), Self::Cuda(s3)) => c
+                .cuda_fwd(s1, l1, s2, l2, s3, l3)
+                .map(|(s, shape)| (Self::Cuda(s), shape)),
+            (Self::Metal(s1), Self::Metal(s2), Self::Metal(s3)) => c
+                .metal_fwd(s1, l1, s2, l2, s3, l3)
+                .map(|(s, shape)| (Self::Metal(s), shape)),
+            (Self::Meta(s1), Self::Meta(s2), Self::Meta(s3)) => {
+                let (s, shape) = c.meta_fwd(s1, l1, s2, l2, s3, l3)?;
+                Ok((Self::Meta(s), shape))
             }
             _ => unreachable!(),
-        }
+        };
+        let res = self.or_cpu_fallback_(res, c.name(), &[t2, t3], |xs| {
+            c.cpu_fwd(&xs[0], l1, &xs[1], l2, &xs[2], l3)
+        });
+        crate::profile::record_op(start, c.name(), self);
+        crate::op_manifest::record_op(c.name(), res.is_ok());
+        res
     }
 
     pub(crate) fn inplace_op1(&mut self, l: &Layout, c: &dyn InplaceOp1) -> Result<()> {
@@ -268,6 +365,8 @@ impl Storage {
             Self::Cpu(storage) => c.cpu_fwd(storage, l),
             Self::Cuda(storage) => c.cuda_fwd(storage, l),
             Self::Metal(storage) => c.metal_fwd(storage, l),
+            // The in-place ops do not change the shape nor the dtype.
+            Self::Meta(_) => Ok(()),
         }
     }
 
@@ -283,6 +382,7 @@ impl Storage {
             (Self::Cpu(s1), Self::Cpu(s2)) => c.cpu_fwd(s1, l1, s2, l2),
             (Self::Cuda(s1), Self::Cuda(s2)) => c.cuda_fwd(s1, l1, s2, l2),
             (Self::Metal(s1), Self::Metal(s2)) => c.metal_fwd(s1, l1, s2, l2),
+            (Self::Meta(_), Self::Meta(_)) => Ok(()),
             _ => unreachable!(),
         }
     }
@@ -304,25 +404,23 @@ impl Storage {
             (Self::Metal(s1), Self::Metal(s2), Self::Metal(s3)) => {
                 c.metal_fwd(s1, l1, s2, l2, s3, l3)
             }
+            (Self::Meta(_), Self::Meta(_), Self::Meta(_)) => Ok(()),
             _ => unreachable!(),
         }
     }
 
     pub(crate) fn unary_impl<B: op::UnaryOpT>(&self, layout: &Layout) -> Result<Self> {
-        match self {
+        let start = crate::profile::op_start(self);
+        let res = match self {
             Storage::Cpu(storage) => {
                 let storage = storage.unary_impl::<B>(layout)?;
                 Ok(Self::Cpu(storage))
             }
-            Self::Cuda(storage) => {
-                let storage = storage.unary_impl::<B>(layout)?;
-                Ok(Self::Cuda(storage))
-            }
-            Self::Metal(storage) => {
-                let storage = storage.unary_impl::<B>(layout)?;
-                Ok(Self::Metal(storage))
-            }
-        }
+            Self::Cuda(storage) => storage.unary_impl::<B>(layout).map(Self::Cuda),
+            Self::Metal(storage) => storage.unary_impl::<B>(layout).map(Self::Metal),
+            Self::Meta(storage) => storage.unary_impl::<B>(layout).map(Self::Meta),
+        };
+        self.or_cpu_fallback(start, res, B::NAME, &[], |xs| xs[0].unary_impl::<B>(layout))
     }
 
     pub(crate) fn binary_impl<B: op::BinaryOpT>(
@@ -331,21 +429,23 @@ impl Storage {
         lhs_layout: &Layout,
         rhs_layout: &Layout,
     ) -> Result<Self> {
+        let start = crate::profile::op_start(self);
         self.same_device(rhs, B::NAME)?;
         self.same_dtype(rhs, B::NAME)?;
-        match (self, rhs) {
+        let res = match (self, rhs) {
             (Storage::Cpu(lhs), Storage::Cpu(rhs)) => {
                 let storage = lhs.binary_impl::<B>(rhs, lhs_layout, rhs_layout)?;
                 Ok(Self::Cpu(storage))
             }
-            (Self::Cuda(lhs), Self::Cuda(rhs)) => {
-                let storage = lhs.binary_impl::<B>(rhs, lhs_layout, rhs_layout)?;
-                Ok(Self::Cuda(storage))
-            }
-            (Self::Metal(lhs), Self::Metal(rhs)) => {
-                let storage = lhs.binary_impl::<B>(rhs, lhs_layout, rhs_layout)?;
-                Ok(Self::Metal(storage))
-            }
+            (Self::Cuda(lhs), Self::Cuda(rhs)) => lhs
+                .binary_impl::<B>(rhs, lhs_layout, rhs_layout)
+                .map(Self::Cuda),
+            (Self::Metal(lhs), Self::Metal(rhs)) => lhs
+                .binary_impl::<B>(rhs, lhs_layout, rhs_layout)
+                .map(Self::Metal),
+            (Self::Meta(lhs), Self::Meta(rhs)) => lhs
+                .binary_impl::<B>(rhs, lhs_layout, rhs_layout)
+                .map(Self::Meta),
             (lhs, rhs) => {
                 // Should not happen because of the same device check above but we're defensive
                 // anyway.
@@ -356,7 +456,10 @@ impl Storage {
                 }
                 .bt())
             }
-        }
+        };
+        self.or_cpu_fallback(start, res, B::NAME, &[rhs], |xs| {
+            xs[0].binary_impl::<B>(&xs[1], lhs_layout, rhs_layout)
+        })
     }
 
     pub(crate) fn conv1d(
@@ -366,20 +469,22 @@ impl Storage {
         kernel_l: &Layout,
         params: &crate::conv::ParamsConv1D,
     ) -> Result<Self> {
+        let start = crate::profile::op_start(self);
         self.same_device(kernel, "conv1d")?;
         self.same_dtype(kernel, "conv1d")?;
-        match (self, &kernel) {
+        let res = match (self, &kernel) {
             (Storage::Cpu(inp), Storage::Cpu(kernel)) => {
                 let s = inp.conv1d(l, kernel, kernel_l, params)?;
                 Ok(Self::Cpu(s))
             }
             (Storage::Cuda(inp), Storage::Cuda(kernel)) => {
-                let s = inp.conv1d(l, kernel, kernel_l, params)?;
-                Ok(Self::Cuda(s))
+                inp.conv1d(l, kernel, kernel_l, params).map(Self::Cuda)
             }
             (Storage::Metal(inp), Storage::Metal(kernel)) => {
-                let s = inp.conv1d(l, kernel, kernel_l, params)?;
-                Ok(Self::Metal(s))
+                inp.conv1d(l, kernel, kernel_l, params).map(Self::Metal)
+            }
+            (Storage::Meta(inp), Storage::Meta(kernel)) => {
+                inp.conv1d(l, kernel, kernel_l, params).map(Self::Meta)
             }
             (lhs, rhs) => Err(Error::DeviceMismatchBinaryOp {
                 lhs: lhs.device().location(),
@@ -387,7 +492,10 @@ impl Storage {
                 op: "conv1d",
             }
             .bt()),
-        }
+        };
+        self.or_cpu_fallback(start, res, "conv1d", &[kernel], |xs| {
+            xs[0].conv1d(l, &xs[1], kernel_l, params)
+        })
     }
 
     pub(crate) fn conv_transpose1d(
@@ -397,28 +505,33 @@ impl Storage {
         kernel_l: &Layout,
         params: &crate::conv::ParamsConvTranspose1D,
     ) -> Result<Self> {
+        let start = crate::profile::op_start(self);
         self.same_device(kernel, "conv-transpose1d")?;
         self.same_dtype(kernel, "conv-transpose1d")?;
-        match (self, &kernel) {
+        let res = match (self, &kernel) {
             (Storage::Cpu(inp), Storage::Cpu(kernel)) => {
                 let s = inp.conv_transpose1d(l, kernel, kernel_l, params)?;
                 Ok(Self::Cpu(s))
             }
-            (Storage::Cuda(inp), Storage::Cuda(kernel)) => {
-                let s = inp.conv_transpose1d(l, kernel, kernel_l, params)?;
-                Ok(Self::Cuda(s))
-            }
-            (Storage::Metal(inp), Storage::Metal(kernel)) => {
-                let s = inp.conv_transpose1d(l, kernel, kernel_l, params)?;
-                Ok(Self::Metal(s))
-            }
+            (Storage::Cuda(inp), Storage::Cuda(kernel)) => inp
+                .conv_transpose1d(l, kernel, kernel_l, params)
+                .map(Self::Cuda),
+            (Storage::Metal(inp), Storage::Metal(kernel)) => inp
+                .conv_transpose1d(l, kernel, kernel_l, params)
+                .map(Self::Metal),
+            (Storage::Meta(inp), Storage::Meta(kernel)) => inp
+                .conv_transpose1d(l, kernel, kernel_l, params)
+                .map(Self::Meta),
             (lhs, rhs) => Err(Error::DeviceMismatchBinaryOp {
                 lhs: lhs.device().location(),
                 rhs: rhs.device().location(),
                 op: "conv-transpose1d",
             }
             .bt()),
-        }
+        };
+        self.or_cpu_fallback(start, res, "conv-transpose1d", &[kernel], |xs| {
+            xs[0].conv_transpose1d(l, &xs[1], kernel_l, params)
+        })
     }
 
     pub(crate) fn conv2d(
@@ -428,20 +541,22 @@ impl Storage {
         kernel_l: &Layout,
         params: &crate::conv::ParamsConv2D,
     ) -> Result<Self> {
+        let start = crate::profile::op_start(self);
         self.same_device(kernel, "conv2d")?;
         self.same_dtype(kernel, "conv2d")?;
-        match (self, &kernel) {
+        let res = match (self, &kernel) {
             (Storage::Cpu(inp), Storage::Cpu(kernel)) => {
                 let s = inp.conv2d(l, kernel, kernel_l, params)?;
                 Ok(Self::Cpu(s))
             }
             (Storage::Cuda(inp), Storage::Cuda(kernel)) => {
-                let s = inp.conv2d(l, kernel, kernel_l, params)?;
-                Ok(Self::Cuda(s))
+                inp.conv2d(l, kernel, kernel_l, params).map(Self::Cuda)
             }
             (Storage::Metal(inp), Storage::Metal(kernel)) => {
-                let s = inp.conv2d(l, kernel, kernel_l, params)?;
-                Ok(Self::Metal(s))
+                inp.conv2d(l, kernel, kernel_l, params).map(Self::Metal)
+            }
+            (Storage::Meta(inp), Storage::Meta(kernel)) => {
+                inp.conv2d(l, kernel, kernel_l, params).map(Self::Meta)
             }
             (lhs, rhs) => Err(Error::DeviceMismatchBinaryOp {
                 lhs: lhs.device().location(),
@@ -449,7 +564,10 @@ impl Storage {
                 op: "conv2d",
             }
             .bt()),
-        }
+        };
+        self.or_cpu_fallback(start, res, "conv2d", &[kernel], |xs| {
+            xs[0].conv2d(l, &xs[1], kernel_l, params)
+        })
     }
 
     pub(crate) fn conv_transpose2d(
@@ -459,28 +577,33 @@ impl Storage {
         kernel_l: &Layout,
         params: &crate::conv::ParamsConvTranspose2D,
     ) -> Result<Self> {
+        let start = crate::profile::op_start(self);
         self.same_device(kernel, "conv_transpose2d")?;
         self.same_dtype(kernel, "conv_transpose2d")?;
-        match (self, &kernel) {
+        let res = match (self, &kernel) {
             (Storage::Cpu(inp), Storage::Cpu(kernel)) => {
                 let s = inp.conv_transpose2d(l, kernel, kernel_l, params)?;
                 Ok(Self::Cpu(s))
             }
-            (Storage::Cuda(inp), Storage::Cuda(kernel)) => {
-                let s = inp.conv_transpose2d(l, kernel, kernel_l, params)?;
-                Ok(Self::Cuda(s))
-            }
-            (Storage::Metal(inp), Storage::Metal(kernel)) => {
-                let s = inp.conv_transpose2d(l, kernel, kernel_l, params)?;
-                Ok(Self::Metal(s))
-            }
+            (Storage::Cuda(inp), Storage::Cuda(kernel)) => inp
+                .conv_transpose2d(l, kernel, kernel_l, params)
+                .map(Self::Cuda),
+            (Storage::Metal(inp), Storage::Metal(kernel)) => inp
+                .conv_transpose2d(l, kernel, kernel_l, params)
+                .map(Self::Metal),
+            (Storage::Meta(inp), Storage::Meta(kernel)) => inp
+                .conv_transpose2d(l, kernel, kernel_l, params)
+                .map(Self::Meta),
             (lhs, rhs) => Err(Error::DeviceMismatchBinaryOp {
                 lhs: lhs.device().location(),
                 rhs: rhs.device().location(),
                 op: "conv_transpose2d",
             }
             .bt()),
-        }
+        };
+        self.or_cpu_fallback(start, res, "conv_transpose2d", &[kernel], |xs| {
+            xs[0].conv_transpose2d(l, &xs[1], kernel_l, params)
+        })
     }
 
     pub(crate) fn avg_pool2d(
@@ -489,20 +612,25 @@ impl Storage {
         kernel_size: (usize, usize),
         stride: (usize, usize),
     ) -> Result<Self> {
-        match self {
+        let start = crate::profile::op_start(self);
+        let res = match self {
             Storage::Cpu(storage) => {
                 let storage = storage.avg_pool2d(layout, kernel_size, stride)?;
                 Ok(Self::Cpu(storage))
             }
-            Self::Cuda(storage) => {
-                let storage = storage.avg_pool2d(layout, kernel_size, stride)?;
-                Ok(Self::Cuda(storage))
-            }
-            Self::Metal(storage) => {
-                let storage = storage.avg_pool2d(layout, kernel_size, stride)?;
-                Ok(Self::Metal(storage))
-            }
-        }
+            Self::Cuda(storage) => storage
+                .avg_pool2d(layout, kernel_size, stride)
+                .map(Self::Cuda),
+            Self::Metal(storage) => storage
+                .avg_pool2d(layout, kernel_size, stride)
+                .map(Self::Metal),
+            Self::Meta(storage) => storage
+                .avg_pool2d(layout, kernel_size, stride)
+                .map(Self::Meta),
+        };
+        self.or_cpu_fallback(start, res, "avg-pool2d", &[], |xs| {
+            xs[0].avg_pool2d(layout, kernel_size, stride)
+        })
     }
 
     pub(crate) fn max_pool2d(
@@ -511,54 +639,57 @@ impl Storage {
         kernel_size: (usize, usize),
         stride: (usize, usize),
     ) -> Result<Self> {
-        match self {
+        let start = crate::profile::op_start(self);
+        let res = match self {
             Storage::Cpu(storage) => {
                 let storage = storage.max_pool2d(layout, kernel_size, stride)?;
                 Ok(Self::Cpu(storage))
             }
-            Self::Cuda(storage) => {
-                let storage = storage.max_pool2d(layout, kernel_size, stride)?;
-                Ok(Self::Cuda(storage))
-            }
-            Self::Metal(storage) => {
-                let storage = storage.max_pool2d(layout, kernel_size, stride)?;
-                Ok(Self::Metal(storage))
-            }
-        }
+            Self::Cuda(storage) => storage
+                .max_pool2d(layout, kernel_size, stride)
+                .map(Self::Cuda),
+            Self::Metal(storage) => storage
+                .max_pool2d(layout, kernel_size, stride)
+                .map(Self::Metal),
+            Self::Meta(storage) => storage
+                .max_pool2d(layout, kernel_size, stride)
+                .map(Self::Meta),
+        };
+        self.or_cpu_fallback(start, res, "max-pool2d", &[], |xs| {
+            xs[0].max_pool2d(layout, kernel_size, stride)
+        })
     }
 
     pub(crate) fn upsample_nearest1d(&self, layout: &Layout, sz: usize) -> Result<Self> {
-        match self {
+        let start = crate::profile::op_start(self);
+        let res = match self {
             Storage::Cpu(storage) => {
                 let storage = storage.upsample_nearest1d(layout, sz)?;
                 Ok(Self::Cpu(storage))
             }
-            Self::Cuda(storage) => {
-                let storage = storage.upsample_nearest1d(layout, sz)?;
-                Ok(Self::Cuda(storage))
-            }
-            Self::Metal(storage) => {
-                let storage = storage.upsample_nearest1d(layout, sz)?;
-                Ok(Self::Metal(storage))
-            }
-        }
+            Self::Cuda(storage) => storage.upsample_nearest1d(layout, sz).map(Self::Cuda),
+            Self::Metal(storage) => storage.upsample_nearest1d(layout, sz).map(Self::Metal),
+            Self::Meta(storage) => storage.upsample_nearest1d(layout, sz).map(Self::Meta),
+        };
+        self.or_cpu_fallback(start, res, "upsample-nearest1d", &[], |xs| {
+            xs[0].upsample_nearest1d(layout, sz)
+        })
     }
 
     pub(crate) fn upsample_nearest2d(&self, layout: &Layout, h: usize, w: usize) -> Result<Self> {
-        match self {
+        let start = crate::profile::op_start(self);
+        let res = match self {
             Storage::Cpu(storage) => {
                 let storage = storage.upsample_nearest2d(layout, h, w)?;
                 Ok(Self::Cpu(storage))
             }
-            Self::Cuda(storage) => {
-                let storage = storage.upsample_nearest2d(layout, h, w)?;
-                Ok(Self::Cuda(storage))
-            }
-            Self::Metal(storage) => {
-                let storage = storage.upsample_nearest2d(layout, h, w)?;
-                Ok(Self::Metal(storage))
-            }
-        }
+            Self::Cuda(storage) => storage.upsample_nearest2d(layout, h, w).map(Self::Cuda),
+            Self::Metal(storage) => storage.upsample_nearest2d(layout, h, w).map(Self::Metal),
+            Self::Meta(storage) => storage.upsample_nearest2d(layout, h, w).map(Self::Meta),
+        };
+        self.or_cpu_fallback(start, res, "upsample-nearest2d", &[], |xs| {
+            xs[0].upsample_nearest2d(layout, h, w)
+        })
     }
 
     pub(crate) fn where_cond(
@@ -569,29 +700,34 @@ impl Storage {
         f: &Self,
         layout_f: &Layout,
     ) -> Result<Self> {
+        let start = crate::profile::op_start(self);
         self.same_device(t, "where")?;
         self.same_device(f, "where")?;
         t.same_dtype(f, "where")?;
-        match (self, t, f) {
+        let res = match (self, t, f) {
             (Storage::Cpu(cond), Storage::Cpu(t), Storage::Cpu(f)) => {
                 let storage = cond.where_cond(layout, t, layout_t, f, layout_f)?;
                 Ok(Self::Cpu(storage))
             }
-            (Self::Cuda(cond), Self::Cuda(t), Self::Cuda(f)) => {
-                let storage = cond.where_cond(layout, t, layout_t, f, layout_f)?;
-                Ok(Self::Cuda(storage))
-            }
-            (Self::Metal(cond), Self::Metal(t), Self::Metal(f)) => {
-                let storage = cond.where_cond(layout, t, layout_t, f, layout_f)?;
-                Ok(Self::Metal(storage))
-            }
+            (Self::Cuda(cond), Self::Cuda(t), Self::Cuda(f)) => cond
+                .where_cond(layout, t, layout_t, f, layout_f)
+                .map(Self::Cuda),
+            (Self::Metal(cond), Self::Metal(t), Self::Metal(f)) => cond
+                .where_cond(layout, t, layout_t, f, layout_f)
+                .map(Self::Metal),
+            (Self::Meta(cond), Self::Meta(t), Self::Meta(f)) => cond
+                .where_cond(layout, t, layout_t, f, layout_f)
+                .map(Self::Meta),
             (_, lhs, rhs) => Err(Error::DeviceMismatchBinaryOp {
                 lhs: lhs.device().location(),
                 rhs: rhs.device().location(),
                 op: "where",
             }
             .bt()),
-        }
+        };
+        self.or_cpu_fallback(start, res, "where", &[t, f], |xs| {
+            xs[0].where_cond(layout, &xs[1], layout_t, &xs[2], layout_f)
+        })
     }
 
     pub(crate) fn gather(
@@ -601,22 +737,27 @@ impl Storage {
         indexes_l: &Layout,
         d: usize,
     ) -> Result<Self> {
+        let start = crate::profile::op_start(self);
         self.same_device(indexes, "index-add")?;
-        match (self, indexes) {
+        let res = match (self, indexes) {
             (Self::Cpu(s), Self::Cpu(indexes)) => {
                 let storage = s.gather(l, indexes, indexes_l, d)?;
                 Ok(Self::Cpu(storage))
             }
             (Self::Cuda(s), Self::Cuda(indexes)) => {
-                let storage = s.gather(l, indexes, indexes_l, d)?;
-                Ok(Self::Cuda(storage))
+                s.gather(l, indexes, indexes_l, d).map(Self::Cuda)
             }
             (Self::Metal(s), Self::Metal(indexes)) => {
-                let storage = s.gather(l, indexes, indexes_l, d)?;
-                Ok(Self::Metal(storage))
+                s.gather(l, indexes, indexes_l, d).map(Self::Metal)
+            }
+            (Self::Meta(s), Self::Meta(indexes)) => {
+                s.gather(l, indexes, indexes_l, d).map(Self::Meta)
             }
             _ => unreachable!(),
-        }
+        };
+        self.or_cpu_fallback(start, res, "gather", &[indexes], |xs| {
+            xs[0].gather(l, &xs[1], indexes_l, d)
+        })
     }
 
     pub(crate) fn scatter_add(
@@ -628,23 +769,28 @@ impl Storage {
         source_l: &Layout,
         d: usize,
     ) -> Result<Self> {
+        let start = crate::profile::op_start(self);
         self.same_device(indexes, "scatter-add")?;
         self.same_device(source, "scatter-add")?;
-        match (self, indexes, source) {
+        let res = match (self, indexes, source) {
             (Self::Cpu(s), Self::Cpu(indexes), Self::Cpu(source)) => {
                 let storage = s.scatter_add(l, indexes, indexes_l, source, source_l, d)?;
                 Ok(Self::Cpu(storage))
             }
-            (Self::Cuda(s), Self::Cuda(indexes), Self::Cuda(source)) => {
-                let storage = s.scatter_add(l, indexes, indexes_l, source, source_l, d)?;
-                Ok(Self::Cuda(storage))
-            }
-            (Self::Metal(s), Self::Metal(indexes), Self::Metal(source)) => {
-                let storage = s.scatter_add(l, indexes, indexes_l, source, source_l, d)?;
-                Ok(Self::Metal(storage))
-            }
+            (Self::Cuda(s), Self::Cuda(indexes), Self::Cuda(source)) => s
+                .scatter_add(l, indexes, indexes_l, source, source_l, d)
+                .map(Self::Cuda),
+            (Self::Metal(s), Self::Metal(indexes), Self::Metal(source)) => s
+                .scatter_add(l, indexes, indexes_l, source, source_l, d)
+                .map(Self::Metal),
+            (Self::Meta(s), Self::Meta(indexes), Self::Meta(source)) => s
+                .scatter_add(l, indexes, indexes_l, source, source_l, d)
+                .map(Self::Meta),
             _ => unreachable!(),
-        }
+        };
+        self.or_cpu_fallback(start, res, "scatter-add", &[indexes, source], |xs| {
+            xs[0].scatter_add(l, &xs[1], indexes_l, &xs[2], source_l, d)
+        })
     }
 
     pub(crate) fn index_add(
@@ -656,23 +802,28 @@ impl Storage {
         source_l: &Layout,
         d: usize,
     ) -> Result<Self> {
+        let start = crate::profile::op_start(self);
         self.same_device(indexes, "index-add")?;
         self.same_device(source, "index-add")?;
-        match (self, indexes, source) {
+        let res = match (self, indexes, source) {
             (Self::Cpu(s), Self::Cpu(indexes), Self::Cpu(source)) => {
                 let storage = s.index_add(l, indexes, indexes_l, source, source_l, d)?;
                 Ok(Self::Cpu(storage))
             }
-            (Self::Cuda(s), Self::Cuda(indexes), Self::Cuda(source)) => {
-                let storage = s.index_add(l, indexes, indexes_l, source, source_l, d)?;
-                Ok(Self::Cuda(storage))
-            }
-            (Self::Metal(s), Self::Metal(indexes), Self::Metal(source)) => {
-                let storage = s.index_add(l, indexes, indexes_l, source, source_l, d)?;
-                Ok(Self::Metal(storage))
-            }
+            (Self::Cuda(s), Self::Cuda(indexes), Self::Cuda(source)) => s
+                .index_add(l, indexes, indexes_l, source, source_l, d)
+                .map(Self::Cuda),
+            (Self::Metal(s), Self::Metal(indexes), Self::Metal(source)) => s
+                .index_add(l, indexes, indexes_l, source, source_l, d)
+                .map(Self::Metal),
+            (Self::Meta(s), Self::Meta(indexes), Self::Meta(source)) => s
+                .index_add(l, indexes, indexes_l, source, source_l, d)
+                .map(Self::Meta),
             _ => unreachable!(),
-        }
+        };
+        self.or_cpu_fallback(start, res, "index-add", &[indexes, source], |xs| {
+            xs[0].index_add(l, &xs[1], indexes_l, &xs[2], source_l, d)
+        })
     }
 
     pub(crate) fn index_select(
@@ -682,19 +833,21 @@ impl Storage {
         rhs_l: &Layout,
         d: usize,
     ) -> Result<Self> {
+        let start = crate::profile::op_start(self);
         self.same_device(rhs, "index-select")?;
-        match (self, rhs) {
+        let res = match (self, rhs) {
             (Self::Cpu(lhs), Self::Cpu(rhs)) => {
                 let storage = lhs.index_select(rhs, lhs_l, rhs_l, d)?;
                 Ok(Self::Cpu(storage))
             }
             (Self::Cuda(lhs), Self::Cuda(rhs)) => {
-                let storage = lhs.index_select(rhs, lhs_l, rhs_l, d)?;
-                Ok(Self::Cuda(storage))
+                lhs.index_select(rhs, lhs_l, rhs_l, d).map(Self::Cuda)
             }
             (Self::Metal(lhs), Self::Metal(rhs)) => {
-                let storage = lhs.index_select(rhs, lhs_l, rhs_l, d)?;
-                Ok(Self::Metal(storage))
+                lhs.index_select(rhs, lhs_l, rhs_l, d).map(Self::Metal)
+            }
+            (Self::Meta(lhs), Self::Meta(rhs)) => {
+                lhs.index_select(rhs, lhs_l, rhs_l, d).map(Self::Meta)
             }
             (lhs, rhs) => Err(Error::DeviceMismatchBinaryOp {
                 lhs: lhs.device().location(),
@@ -702,7 +855,10 @@ impl Storage {
                 op: "index-select",
             }
             .bt()),
-        }
+        };
+        self.or_cpu_fallback(start, res, "index-select", &[rhs], |xs| {
+            xs[0].index_select(&xs[1], lhs_l, rhs_l, d)
+        })
     }
 
     pub(crate) fn matmul(
@@ -712,28 +868,33 @@ impl Storage {
         lhs_layout: &Layout,
         rhs_layout: &Layout,
     ) -> Result<Self> {
+        let start = crate::profile::op_start(self);
         self.same_device(rhs, "matmul")?;
         self.same_dtype(rhs, "matmul")?;
-        match (self, rhs) {
+        let res = match (self, rhs) {
             (Self::Cpu(lhs), Self::Cpu(rhs)) => {
                 let storage = lhs.matmul(rhs, bmnk, lhs_layout, rhs_layout)?;
                 Ok(Self::Cpu(storage))
             }
-            (Self::Cuda(lhs), Self::Cuda(rhs)) => {
-                let storage = lhs.matmul(rhs, bmnk, lhs_layout, rhs_layout)?;
-                Ok(Self::Cuda(storage))
-            }
-            (Self::Metal(lhs), Self::Metal(rhs)) => {
-                let storage = lhs.matmul(rhs, bmnk, lhs_layout, rhs_layout)?;
-                Ok(Self::Metal(storage))
-            }
+            (Self::Cuda(lhs), Self::Cuda(rhs)) => lhs
+                .matmul(rhs, bmnk, lhs_layout, rhs_layout)
+                .map(Self::Cuda),
+            (Self::Metal(lhs), Self::Metal(rhs)) => lhs
+                .matmul(rhs, bmnk, lhs_layout, rhs_layout)
+                .map(Self::Metal),
+            (Self::Meta(lhs), Self::Meta(rhs)) => lhs
+                .matmul(rhs, bmnk, lhs_layout, rhs_layout)
+                .map(Self::Meta),
             (lhs, rhs) => Err(Error::DeviceMismatchBinaryOp {
                 lhs: lhs.device().location(),
                 rhs: rhs.device().location(),
                 op: "matmul",
             }
             .bt()),
-        }
+        };
+        self.or_cpu_fallback(start, res, "matmul", &[rhs], |xs| {
+            xs[0].matmul(&xs[1], bmnk, lhs_layout, rhs_layout)
+        })
     }
 
     // self, the source can be strided whereas dst is contiguous.
@@ -749,6 +910,7 @@ impl Storage {
             (Self::Metal(src), Self::Metal(dst)) => {
                 Ok(src.copy_strided_src(dst, dst_offset, src_l)?)
             }
+            (Self::Meta(src), Self::Meta(dst)) => Ok(src.copy_strided_src(dst, dst_offset, src_l)?),
             (lhs, rhs) => Err(Error::DeviceMismatchBinaryOp {
                 lhs: lhs.device().location(),
                 rhs: rhs.device().location(),
@@ -777,6 +939,9 @@ impl Storage {
             (Self::Metal(src), Self::Metal(dst)) => {
                 Ok(src.copy2d(dst, d1, d2, src_s, dst_s, src_o, dst_o)?)
             }
+            (Self::Meta(src), Self::Meta(dst)) => {
+                Ok(src.copy2d(dst, d1, d2, src_s, dst_s, src_o, dst_o)?)
+            }
             (lhs, rhs) => Err(Error::DeviceMismatchBinaryOp {
                 lhs: lhs.device().location(),
                 rhs: rhs.device().location(),
@@ -0,0 +1,249 @@
+//! Reductions over a stream of chunks.
+//!
+//! These helpers compute statistics over data that does not fit in memory, e.g. the per-feature
+//! mean and variance of a large corpus used to normalize the inputs of a model. The data is
+//! provided as an iterator of chunks that are considered as concatenated along their first
+//! dimension, only the running statistics are kept on the device.
+//!
+//! ```rust
+//! use candle_core::{stream_reduce::RunningMoments, Device, Tensor};
+//! let mut moments = RunningMoments::new();
+//! for i in 0..4 {
+//!     // In practice the chunks would be loaded from disk.
+//!     let chunk = Tensor::arange(4. * i as f32, 4. * i as f32 + 4., &Device::Cpu)?;
+//!     moments.update(&chunk.reshape((2, 2))?)?;
+//! }
+//! assert_eq!(moments.count(), 8);
+//! assert_eq!(moments.mean()?.to_vec1::<f32>()?, &[7., 8.]);
+//! # Ok::<(), candle_core::Error>(())
+//! ```
+use crate::{DType, Result, Tensor};
+
+// Half precision statistics are accumulated in f32.
+fn acc_dtype(dtype: DType) -> DType {
+    match dtype {
+        DType::F64 => DType::F64,
+        _ => DType::F32,
+    }
+}
+
+impl Tensor {
+    /// Reduces a stream of chunks: `map` is applied to each chunk to get a partial result and
+    /// the partial results are combined with `combine`. Returns `None` for an empty stream.
+    ///
+    /// Only the current chunk and the accumulated result are kept in memory.
+    pub fn stream_reduce<I, M, C>(chunks: I, mut map: M, mut combine: C) -> Result<Option<Tensor>>
+    where
+        I: IntoIterator<Item = Result<Tensor>>,
+        M: FnMut(&Tensor) -> Result<Tensor>,
+        C: FnMut(&Tensor, &Tensor) -> Result<Tensor>,
+    {
+        let mut acc: Option<Tensor> = None;
+        for chunk in chunks {
+            let partial = map(&chunk?)?;
+            acc = Some(match acc {
+                None => partial,
+                Some(acc) => combine(&acc, &partial)?,
+            })
+        }
+        Ok(acc)
+    }
+
+    /// The sum over the first dimension of a stream of chunks.
+    pub fn stream_sum<I>(chunks: I) -> Result<Option<Tensor>>
+    where
+        I: IntoIterator<Item = Result<Tensor>>,
+    {
+        Self::stream_reduce(chunks, |c| c.sum(0), |a, b| a + b)
+    }
+
+    /// The maximum over the first dimension of a stream of chunks.
+    pub fn stream_max<I>(chunks: I) -> Result<Option<Tensor>>
+    where
+        I: IntoIterator<Item = Result<Tensor>>,
+    {
+        Self::stream_reduce(chunks, |c| c.max(0), |a, b| a.maximum(b))
+    }
+
+    /// The minimum over the first dimension of a stream of chunks.
+    pub fn stream_min<I>(chunks: I) -> Result<Option<Tensor>>
+    where
+        I: IntoIterator<Item = Result<Tensor>>,
+    {
+        Self::stream_reduce(chunks, |c| c.min(0), |a, b| a.minimum(b))
+    }
+}
+
+/// Running mean and variance over the first dimension of a stream of chunks.
+///
+/// The statistics of each chunk are merged with the running ones using the parallel algorithm
+/// from Chan et al., which is numerically stable even for a large number of samples. The
+/// statistics are accumulated in f32, or f64 for f64 inputs.
+#[derive(Debug, Clone, Default)]
+pub struct RunningMoments {
+    count: usize,
+    mean: Option<Tensor>,
+    // The sum of the squared differences to the mean.
+    m2: Option<Tensor>,
+}
+
+impl RunningMoments {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The number of samples seen so far.
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// Adds the samples from `chunk`, the samples are indexed by the first dimension.
+    pub fn update(&mut self, chunk: &Tensor) -> Result<()> {
+        let n = chunk.dim(0)?;
+        if n == 0 {
+            return Ok(());
+        }
+        let chunk = chunk.to_dtype(acc_dtype(chunk.dtype()))?;
+        let mean = chunk.mean_keepdim(0)?;
+        let m2 = chunk.broadcast_sub(&mean)?.sqr()?.sum(0)?;
+        let other = Self {
+            count: n,
+            mean: Some(mean.squeeze(0)?),
+            m2: Some(m2),
+        };
+        self.merge(&other)
+    }
+
+    /// Merges the statistics from `other`, e.g. computed on another shard of the data.
+    pub fn merge(&mut self, other: &Self) -> Result<()> {
+        let (mean_b, m2_b) = match (&other.mean, &other.m2) {
+            (Some(mean), Some(m2)) => (mean, m2),
+            _ => return Ok(()),
+        };
+        let (mean_a, m2_a) = match (&self.mean, &self.m2) {
+            (Some(mean), Some(m2)) => (mean, m2),
+            _ => {
+                *self = other.clone();
+                return Ok(());
+            }
+        };
+        let (n_a, n_b) = (self.count as f64, other.count as f64);
+        let n = n_a + n_b;
+        let delta = (mean_b - mean_a)?;
+        let mean = (mean_a + (&delta * (n_b / n))?)?;
+        let m2 = ((m2_a + m2_b)? + (delta.sqr()? * (n_a * n_b / n))?)?;
+        self.count += other.count;
+        self.mean = Some(mean);
+        self.m2 = Some(m2);
+        Ok(())
+    }
+
+    fn get(&self, t: &Option<Tensor>) -> Result<Tensor> {
+        match t {
+            Some(t) => Ok(t.clone()),
+            None => crate::bail!("no samples have been added to the running moments"),
+        }
+    }
+
+    pub fn mean(&self) -> Result<Tensor> {
+        self.get(&self.mean)
+    }
+
+    /// The variance of the samples, using Bessel's correction when `unbiased` is true.
+    pub fn var(&self, unbiased: bool) -> Result<Tensor> {
+        let m2 = self.get(&self.m2)?;
+        let denom = if unbiased { self.count - 1 } else { self.count };
+        if denom == 0 {
+            crate::bail!("not enough samples to compute the variance")
+        }
+        m2 / denom as f64
+    }
+
+    pub fn std(&self, unbiased: bool) -> Result<Tensor> {
+        self.var(unbiased)?.sqrt()
+    }
+}
+
+/// Running mean and covariance matrix of the features of a stream of chunks.
+///
+/// The chunks have a shape `(samples, features)`. The covariance is accumulated in f32, or f64
+/// for f64 inputs, using the same merging scheme as `RunningMoments`.
+#[derive(Debug, Clone, Default)]
+pub struct RunningCovariance {
+    count: usize,
+    mean: Option<Tensor>,
+    // The sum of the outer products of the differences to the mean.
+    comoment: Option<Tensor>,
+}
+
+impl RunningCovariance {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    pub fn update(&mut self, chunk: &Tensor) -> Result<()> {
+        let (n, _) = chunk.dims2()?;
+        if n == 0 {
+            return Ok(());
+        }
+        let chunk = chunk.to_dtype(acc_dtype(chunk.dtype()))?;
+        let mean = chunk.mean_keepdim(0)?;
+        let centered = chunk.broadcast_sub(&mean)?;
+        let comoment = centered.t()?.matmul(&centered)?;
+        let other = Self {
+            count: n,
+            mean: Some(mean.squeeze(0)?),
+            comoment: Some(comoment),
+        };
+        self.merge(&other)
+    }
+
+    pub fn merge(&mut self, other: &Self) -> Result<()> {
+        let (mean_b, c_b) = match (&other.mean, &other.comoment) {
+            (Some(mean), Some(c)) => (mean, c),
+            _ => return Ok(()),
+        };
+        let (mean_a, c_a) = match (&self.mean, &self.comoment) {
+            (Some(mean), Some(c)) => (mean, c),
+            _ => {
+                *self = other.clone();
+                return Ok(());
+            }
+        };
+        let (n_a, n_b) = (self.count as f64, other.count as f64);
+        let n = n_a + n_b;
+        let delta = (mean_b - mean_a)?;
+        let mean = (mean_a + (&delta * (n_b / n))?)?;
+        let outer = delta.unsqueeze(1)?.matmul(&delta.unsqueeze(0)?)?;
+        let comoment = ((c_a + c_b)? + (outer * (n_a * n_b / n))?)?;
+        self.count += other.count;
+        self.mean = Some(mean);
+        self.comoment = Some(comoment);
+        Ok(())
+    }
+
+    pub fn mean(&self) -> Result<Tensor> {
+        match &self.mean {
+            Some(mean) => Ok(mean.clone()),
+            None => crate::bail!("no samples have been added to the running covariance"),
+        }
+    }
+
+    /// The covariance matrix of shape `(features, features)`, using Bessel's correction when
+    /// `unbiased` is true.
+    pub fn covariance(&self, unbiased: bool) -> Result<Tensor> {
+        let comoment = match &self.comoment {
+            Some(c) => c,
+            None => crate::bail!("no samples have been added to the running covariance"),
+        };
+        let denom = if unbiased { self.count - 1 } else { self.count };
+        if denom == 0 {
+            crate::bail!("not enough samples to compute the covariance")
+        }
+        comoment / denom as f64
+    }
+}
@@ -40,6 +40,9 @@ pub struct Tensor_ {
     is_variable: bool,
     dtype: DType,
     device: Device,
+    // Set when the storage wraps a buffer owned by another framework, shared by all the tensors
+    // using the storage. This is declared after `storage` so that it is dropped last.
+    foreign: Option<Arc<crate::dlpack::ForeignBuffer>>,
 }
 
 impl AsRef<Tensor> for Tensor {
@@ -94,6 +97,14 @@ macro_rules! unary_op {
 macro_rules! binary_op {
     ($fn_name:ident, $op_name:ident) => {
         pub fn $fn_name(&self, rhs: &Self) -> Result<Self> {
+            if self.dtype() != rhs.dtype() {
+                let (lhs, rhs) = crate::promotion::promote(self, rhs, stringify!($fn_name))?;
+                return lhs.$fn_name(&rhs);
+            }
+            crate::shape::check_strict_binary_op(self.shape(), rhs.shape(), stringify!($fn_name))?;
+            if let Some((lhs, rhs)) = self.broadcast_scalar_operand(rhs)? {
+                return lhs.$fn_name(&rhs);
+            }
             let shape = self.same_shape_binary_op(rhs, stringify!($fn_name))?;
             if shape.elem_count() == 0 {
                 return Ok(self.clone());
@@ -119,6 +130,14 @@ macro_rules! binary_op_scalar {
                     .to_device(self.device())?
                     .broadcast_as(self.shape())?,
             };
+            if self.dtype() != rhs.dtype() {
+                let (lhs, rhs) = crate::promotion::promote(self, &rhs, stringify!($fn_name))?;
+                return lhs.$fn_name(&rhs);
+            }
+            crate::shape::check_strict_binary_op(self.shape(), rhs.shape(), stringify!($fn_name))?;
+            if let Some((lhs, rhs)) = self.broadcast_scalar_operand(&rhs)? {
+                return lhs.$fn_name(&rhs);
+            }
             let shape = self.same_shape_binary_op(&rhs, stringify!($fn_name))?;
             if self.elem_count() == 0 {
                 return Ok(self.clone());
@@ -164,6 +183,8 @@ pub(crate) fn from_storage<S: Into<Shape>>(
 ) -> Tensor {
     let dtype = storage.dtype();
     let device = storage.device();
+    let shape = shape.into();
+    crate::profile::record_alloc(shape.elem_count() * dtype.size_in_bytes(), &device);
     let tensor_ = Tensor_ {
         id: TensorId::new(),
         storage: Arc::new(RwLock::new(storage)),
@@ -172,6 +193,33 @@ pub(crate) fn from_storage<S: Into<Shape>>(
         is_variable,
         dtype,
         device,
+        foreign: None,
+    };
+    let tensor = Tensor(Arc::new(tensor_));
+    crate::op_manifest::record_output(&tensor);
+    tensor
+}
+
+/// Creates a tensor using a storage that wraps a buffer owned by another framework, `release`
+/// hands the buffer back once no tensor uses the storage anymore, see `crate::dlpack`.
+pub(crate) fn from_foreign_storage(
+    storage: Storage,
+    layout: Layout,
+    release: Box<dyn FnOnce() + Send + Sync>,
+) -> Tensor {
+    let dtype = storage.dtype();
+    let device = storage.device();
+    let storage = Arc::new(RwLock::new(storage));
+    let foreign = crate::dlpack::ForeignBuffer::new(storage.clone(), release);
+    let tensor_ = Tensor_ {
+        id: TensorId::new(),
+        storage,
+        layout,
+        op: BackpropOp::none(),
+        is_variable: false,
+        dtype,
+        device,
+        foreign: Some(Arc::new(foreign)),
     };
     Tensor(Arc::new(tensor_))
 }
@@ -355,7 +403,7 @@ impl Tensor {
         let n: usize = shape.elem_count();
         let buffer_size: usize = array.shape()?.elem_count();
         if buffer_size != n {
-            return Err(Error::ShapeMismatch { buffer_size, shape }.bt());
+            return Err(Error::BufferSizeMismatch { buffer_size, shape }.bt());
         }
         let storage = device.storage(array)?;
         let none = BackpropOp::none();
@@ -461,7 +509,7 @@ impl Tensor {
         let shape = shape.into();
         let buffer_size = data.len();
         if buffer_size != shape.elem_count() {
-            return Err(Error::ShapeMismatch { buffer_size, shape }.bt());
+            return Err(Error::BufferSizeMismatch { buffer_size, shape }.bt());
         }
         let storage = device.storage_owned(data)?;
         let none = BackpropOp::none();
@@ -511,13 +559,24 @@ impl Tensor {
         let n: usize = shape.elem_count();
         let buffer_size: usize = array.len();
         if buffer_size != n {
-            return Err(Error::ShapeMismatch { buffer_size, shape }.bt());
+            return Err(Error::BufferSizeMismatch { buffer_size, shape }.bt());
         }
         let storage = device.storage_from_slice(array)?;
         let none = BackpropOp::none();
         Ok(from_storage(storage, shape, none, false))
     }
 
+    // When exactly one of the operands of a binary op is a 0-dim tensor, returns the operands
+    // with this one broadcasted to the shape of the other.
+    fn broadcast_scalar_operand(&self, rhs: &Self) -> Result<Option<(Self, Self)>> {
+        match (self.rank(), rhs.rank()) {
+            (0, 0) => Ok(None),
+            (0, _) => Ok(Some((self.broadcast_as(rhs.shape())?, rhs.clone()))),
+            (_, 0) => Ok(Some((self.clone(), rhs.broadcast_as(self.shape())?))),
+            _ => Ok(None),
+        }
+    }
+
     pub(crate) fn same_shape_binary_op(&self, rhs: &Self, op: &'static str) -> Result<&Shape> {
         let lhs = self.shape();
         let rhs = rhs.shape();
@@ -608,6 +667,7 @@ impl Tensor {
             Storage::Cpu(cpu_storage) => from_cpu_storage(cpu_storage),
             Storage::Cuda(storage) => from_cpu_storage(&storage.to_cpu_storage()?),
             Storage::Metal(storage) => from_cpu_storage(&storage.to_cpu_storage()?),
+            Storage::Meta(storage) => from_cpu_storage(&storage.to_cpu_storage()?),
         }
     }
 
@@ -616,6 +676,31 @@ impl Tensor {
         self.to_scalar::<S>()
     }
 
+    /// Returns true for 0-dim tensors, i.e. tensors holding a single scalar value.
+    pub fn is_scalar(&self) -> bool {
+        self.rank() == 0
+    }
+
+    /// Retrieves the value of a tensor with a single element, whatever its number of dimensions,
+    /// converted to `S`. This is typically used for loss values or thresholds.
+    ///
+    /// ```rust
+    /// use candle_core::{DType, Device, Tensor};
+    /// let loss = Tensor::new(&[[0.25f32]], &Device::Cpu)?.to_dtype(DType::BF16)?;
+    /// assert_eq!(loss.item::<f32>()?, 0.25);
+    /// assert!(Tensor::new(&[1f32, 2.], &Device::Cpu)?.item::<f32>().is_err());
+    /// # Ok::<(), candle_core::Error>(())
+    /// ```
+    pub fn item<S: crate::WithDType>(&self) -> Result<S> {
+        if self.elem_count() != 1 {
+            crate::bail!(
+                "item expects a tensor with a single element, got shape {:?}",
+                self.shape()
+            )
+        }
+        self.reshape(())?.to_dtype(S::DTYPE)?.to_scalar::<S>()
+    }
+
     /// Repeat this tensor along the specified dimensions.
     pub fn repeat<S: Into<Shape>>(&self, shape: S) -> Result<Tensor> {
         // Similar to PyTorch, we extend the number of dimensions of self if needed.
@@ -841,11 +926,126 @@ impl Tensor {
                 is_variable: false,
                 dtype: self.dtype,
                 device: self.device.clone(),
+                foreign: self.foreign.clone(),
             };
             Ok(Tensor(Arc::new(tensor_)))
         }
     }
 
+    /// Returns a view of every `step` element of dimension `dim`: the `len` elements at indexes
+    /// `start + i * step`. As with `narrow`, no data is copied.
+    ///
+    /// ```rust
+    /// use candle_core::{Tensor, Device};
+    /// let a = Tensor::arange(0u32, 10, &Device::Cpu)?;
+    /// assert_eq!(a.narrow_step(0, 1, 3, 3)?.to_vec1::<u32>()?, &[1, 4, 7]);
+    /// # Ok::<(), candle_core::Error>(())
+    /// ```
+    pub fn narrow_step<D: Dim>(
+        &self,
+        dim: D,
+        start: usize,
+        len: usize,
+        step: usize,
+    ) -> Result<Self> {
+        let dim = dim.to_index(self.shape(), "narrow-step")?;
+        if step == 1 {
+            return self.narrow(dim, start, len);
+        }
+        let layout = self.layout().narrow_step(dim, start, len, step)?;
+        let op = BackpropOp::new1(self, |t| Op::NarrowStep(t, dim, start, len, step));
+        let tensor_ = Tensor_ {
+            id: TensorId::new(),
+            storage: self.storage.clone(),
+            layout,
+            op,
+            is_variable: false,
+            dtype: self.dtype,
+            device: self.device.clone(),
+            foreign: self.foreign.clone(),
+        };
+        Ok(Tensor(Arc::new(tensor_)))
+    }
+
+    /// Reverses the order of the elements along the given dimensions.
+    ///
+    /// This copies the data: `Layout` only supports non-negative strides for now, so a reversed
+    /// dimension is materialized with `index_select` rather than returned as a view.
+    pub fn flip<D: Dims>(&self, dims: D) -> Result<Self> {
+        let dims = dims.to_indexes(self.shape(), "flip")?;
+        let mut xs = self.contiguous()?;
+        for dim in dims {
+            let len = self.dims()[dim];
+            if len <= 1 {
+                continue;
+            }
+            let indexes = (0..len as u32).rev().collect::<Vec<_>>();
+            let indexes = Tensor::from_vec(indexes, len, self.device())?;
+            xs = xs.index_select(&indexes, dim)?;
+        }
+        Ok(xs)
+    }
+
+    /// Slices dimension `dim` with the NumPy semantics of `start:stop:step`: negative indexes
+    /// count from the end, out of range bounds are clamped, missing bounds cover the whole
+    /// dimension in the direction of `step`, and a negative `step` walks the dimension backwards.
+    ///
+    /// Positive steps return a view, see [`Tensor::narrow_step`]. Negative steps are not views
+    /// yet as `Layout` has no negative strides, they copy the data, see [`Tensor::flip`].
+    ///
+    /// ```rust
+    /// use candle_core::{Tensor, Device};
+    /// let a = Tensor::arange(0u32, 6, &Device::Cpu)?;
+    /// assert_eq!(a.slice_step(0, None, None, 2)?.to_vec1::<u32>()?, &[0, 2, 4]);
+    /// assert_eq!(a.slice_step(0, None, None, -1)?.to_vec1::<u32>()?, &[5, 4, 3, 2, 1, 0]);
+    /// assert_eq!(a.slice_step(0, Some(-2), Some(0), -2)?.to_vec1::<u32>()?, &[4, 2]);
+    /// # Ok::<(), candle_core::Error>(())
+    /// ```
+    pub fn slice_step<D: Dim>(
+        &self,
+        dim: D,
+        start: Option<isize>,
+        stop: Option<isize>,
+        step: isize,
+    ) -> Result<Self> {
+        let dim = dim.to_index(self.shape(), "slice-step")?;
+        if step == 0 {
+            bail!("slice step cannot be zero")
+        }
+        let n = self.dims()[dim] as isize;
+        let resolve = |i: isize, lo: isize, hi: isize| {
+            let i = if i < 0 { i + n } else { i };
+            i.clamp(lo, hi)
+        };
+        if step > 0 {
+            let start = start.map_or(0, |i| resolve(i, 0, n));
+            let stop = stop.map_or(n, |i| resolve(i, 0, n));
+            let len = if stop > start {
+                (stop - start + step - 1) / step
+            } else {
+                0
+            };
+            self.narrow_step(dim, start as usize, len as usize, step as usize)
+        } else {
+            let step = -step;
+            let start = start.map_or(n - 1, |i| resolve(i, -1, n - 1));
+            let stop = stop.map_or(-1, |i| resolve(i, -1, n - 1));
+            let len = if start > stop {
+                (start - stop + step - 1) / step
+            } else {
+                0
+            };
+            // Select the same elements in increasing order, then reverse them.
+            let first = if len == 0 {
+                0
+            } else {
+                start - (len - 1) * step
+            };
+            let xs = self.narrow_step(dim, first as usize, len as usize, step as usize)?;
+            xs.flip(dim)
+        }
+    }
+
     fn squeeze_dims(self, dims: &[usize]) -> Result<Self> {
         match dims {
             [] => Ok(self),
@@ -887,8 +1087,39 @@ impl Tensor {
         }
     }
 
+    // Returns the dtype to which this tensor should be converted to accumulate its values in
+    // `acc`, or `None` if it should be used as is.
+    fn upcast_dtype(&self, acc: Option<DType>) -> Option<DType> {
+        let dtype = self.dtype();
+        match acc {
+            Some(acc)
+                if acc.is_float()
+                    && dtype.is_float()
+                    && acc.size_in_bytes() > dtype.size_in_bytes() =>
+            {
+                Some(acc)
+            }
+            _ => None,
+        }
+    }
+
     fn sum_impl<D: Dims>(&self, sum_dims: D, keepdim: bool) -> Result<Self> {
+        self.sum_impl_acc(sum_dims, keepdim, crate::utils::accumulation_dtype())
+    }
+
+    fn sum_impl_acc<D: Dims>(
+        &self,
+        sum_dims: D,
+        keepdim: bool,
+        acc: Option<DType>,
+    ) -> Result<Self> {
         let sum_dims = sum_dims.to_indexes(self.shape(), "sum")?;
+        if let Some(acc) = self.upcast_dtype(acc) {
+            return self
+                .to_dtype(acc)?
+                .sum_impl_acc(sum_dims, keepdim, None)?
+                .to_dtype(self.dtype());
+        }
         let storage = self
             .storage()
             .reduce_op(ReduceOp::Sum, self.layout(), &sum_dims)?;
@@ -962,6 +1193,26 @@ impl Tensor {
         self.sum_impl(sum_dims, false)
     }
 
+    /// Similar to `sum_keepdim` but the values are accumulated in `acc` when it is a larger float
+    /// dtype than the dtype of this tensor, the result uses the dtype of this tensor. This
+    /// overrides the global setting from `utils::set_accumulation_dtype`.
+    ///
+    /// ```rust
+    /// use candle_core::{DType, Tensor, Device};
+    /// let a = Tensor::ones(4096, DType::F16, &Device::Cpu)?;
+    /// let s = a.sum_keepdim_acc(0, DType::F32)?;
+    /// assert_eq!(s.to_dtype(DType::F32)?.to_vec1::<f32>()?, &[4096.]);
+    /// # Ok::<(), candle_core::Error>(())
+    /// ```
+    pub fn sum_keepdim_acc<D: Dims>(&self, sum_dims: D, acc: DType) -> Result<Self> {
+        self.sum_impl_acc(sum_dims, true, Some(acc))
+    }
+
+    /// Similar to `sum` but accumulating the values in `acc`, see `sum_keepdim_acc`.
+    pub fn sum_acc<D: Dims>(&self, sum_dims: D, acc: DType) -> Result<Self> {
+        self.sum_impl_acc(sum_dims, false, Some(acc))
+    }
+
     /// Returns the mean of all elements in the input tensor. The mean is performed over all the
     /// input dimensions.
     ///
@@ -980,20 +1231,37 @@ impl Tensor {
     /// # Ok::<(), candle_core::Error>(())
     /// ```
     pub fn mean_keepdim<D: Dims>(&self, mean_dims: D) -> Result<Self> {
-        let mean_dims = mean_dims.to_indexes(self.shape(), "mean-keepdim")?;
+        self.mean_impl(mean_dims, true, crate::utils::accumulation_dtype())
+    }
+
+    fn mean_impl<D: Dims>(&self, mean_dims: D, keepdim: bool, acc: Option<DType>) -> Result<Self> {
+        let mean_dims = mean_dims.to_indexes(self.shape(), "mean")?;
+        if let Some(acc) = self.upcast_dtype(acc) {
+            return self
+                .to_dtype(acc)?
+                .mean_impl(mean_dims, keepdim, None)?
+                .to_dtype(self.dtype());
+        }
         let reduced_dim: usize = mean_dims.iter().map(|i| self.dims()[*i]).product();
         let scale = 1f64 / (reduced_dim as f64);
-        self.sum_impl(mean_dims, true)? * scale
+        self.sum_impl_acc(mean_dims, keepdim, None)? * scale
     }
 
     /// Returns the mean of all elements in the input tensor. The mean is performed over all the
     /// input dimensions and compared to `mean_keepdim` these dimensions are squeezed rather than
     /// kept.
     pub fn mean<D: Dims>(&self, mean_dims: D) -> Result<Self> {
-        let mean_dims = mean_dims.to_indexes(self.shape(), "mean")?;
-        let reduced_dim: usize = mean_dims.iter().map(|i| self.dims()[*i]).product();
-        let scale = 1f64 / (reduced_dim as f64);
-        self.sum_impl(mean_dims, false)? * scale
+        self.mean_impl(mean_dims, false, crate::utils::accumulation_dtype())
+    }
+
+    /// Similar to `mean_keepdim` but accumulating the values in `acc`, see `sum_keepdim_acc`.
+    pub fn mean_keepdim_acc<D: Dims>(&self, mean_dims: D, acc: DType) -> Result<Self> {
+        self.mean_impl(mean_dims, true, Some(acc))
+    }
+
+    /// Similar to `mean` but accumulating the values in `acc`, see `sum_keepdim_acc`.
+    pub fn mean_acc<D: Dims>(&self, mean_dims: D, acc: DType) -> Result<Self> {
+        self.mean_impl(mean_dims, false, Some(acc))
     }
 
     /// Returns the unbiased variance over the selected dimension.
@@ -1062,6 +1330,14 @@ impl Tensor {
                 .to_device(self.device())?
                 .broadcast_as(self.shape())?,
         };
+        if self.dtype() != rhs.dtype() {
+            let (lhs, rhs) = crate::promotion::promote(self, &rhs, "cmp")?;
+            return lhs.cmp(&rhs, op);
+        }
+        crate::shape::check_strict_binary_op(self.shape(), rhs.shape(), "cmp")?;
+        if let Some((lhs, rhs)) = self.broadcast_scalar_operand(&rhs)? {
+            return lhs.cmp(&rhs, op);
+        }
         let shape = self.same_shape_binary_op(&rhs, "cmp")?;
         let storage = self
             .storage()
@@ -1235,6 +1511,21 @@ impl Tensor {
     ///
     /// The resulting tensor has dimensions `b1, b2, ..., bi, m, n`.
     pub fn matmul(&self, rhs: &Self) -> Result<Self> {
+        self.matmul_impl(rhs, crate::utils::accumulation_dtype())
+    }
+
+    /// Similar to `matmul` but the products are accumulated in `acc` when it is a larger float
+    /// dtype than the dtype of the inputs, the result uses the dtype of the inputs. This
+    /// overrides the global setting from `utils::set_accumulation_dtype`.
+    pub fn matmul_acc(&self, rhs: &Self, acc: DType) -> Result<Self> {
+        self.matmul_impl(rhs, Some(acc))
+    }
+
+    fn matmul_impl(&self, rhs: &Self, acc: Option<DType>) -> Result<Self> {
+        if self.dtype() != rhs.dtype() {
+            let (lhs, rhs) = crate::promotion::promote(self, rhs, "matmul")?;
+            return lhs.matmul_impl(&rhs, acc);
+        }
         let a_dims = self.shape().dims();
         let b_dims = rhs.shape().dims();
 
@@ -1269,6 +1560,17 @@ impl Tensor {
             .bt())?
         }
 
+        if let Some(acc) = self.upcast_dtype(acc) {
+            if self.dtype() == rhs.dtype() {
+                let res = self.to_dtype(acc)?.matmul_impl(&rhs.to_dtype(acc)?, None)?;
+                return res.to_dtype(self.dtype());
+            }
+        }
+
+        if m > 1 && crate::utils::batch_invariant() {
+            return self.matmul_batch_invariant(rhs, c_shape);
+        }
+
         let storage = self.storage().matmul(
             &rhs.storage(),
             (batching, m, n, k),
@@ -1279,6 +1581,35 @@ impl Tensor {
         Ok(from_storage(storage, c_shape, op, false))
     }
 
+    // Computes each row of the result with a separate single row matmul, so that the values for
+    // a row do not depend on the number of rows or on the batch size.
+    fn matmul_batch_invariant(&self, rhs: &Self, c_shape: Shape) -> Result<Self> {
+        let dim = self.rank();
+        let k = self.dim(dim - 1)?;
+        let rhs_shared = rhs.stride()[..dim - 2]
+            .iter()
+            .zip(rhs.dims()[..dim - 2].iter())
+            .all(|(&stride, &size)| stride == 0 || size == 1);
+        if rhs_shared {
+            // All the batch elements use the same rhs matrix, e.g. for linear layers. The rows
+            // are processed as a batch of single row matmuls sharing the rhs.
+            let mut rhs = rhs.clone();
+            for _ in 0..dim - 2 {
+                rhs = rhs.narrow(0, 0, 1)?.squeeze(0)?
+            }
+            let rows = c_shape.elem_count() / c_shape.dims()[dim - 1];
+            let lhs = self.reshape((rows, 1, k))?;
+            let rhs = rhs.broadcast_left(rows)?;
+            lhs.matmul_impl(&rhs, None)?.reshape(c_shape)
+        } else {
+            let m = self.dim(dim - 2)?;
+            let rows = (0..m)
+                .map(|i| self.narrow(dim - 2, i, 1)?.matmul_impl(rhs, None))
+                .collect::<Result<Vec<_>>>()?;
+            Tensor::cat(&rows, dim - 2)
+        }
+    }
+
     /// Matrix-multiplication with broadcasting support.
     ///
     /// Compared to `matmul` the two matrixes are allowed to have different dimensions as long as
@@ -1305,6 +1636,17 @@ impl Tensor {
     /// `on_true` if the input tensor value is not zero, and `on_false` at the positions where the
     /// input tensor is equal to zero.
     pub fn where_cond(&self, on_true: &Self, on_false: &Self) -> Result<Self> {
+        if on_true.dtype() != on_false.dtype() {
+            let (on_true, on_false) = crate::promotion::promote(on_true, on_false, "where_cond")?;
+            return self.where_cond(&on_true, &on_false);
+        }
+        crate::shape::check_strict_binary_op(self.shape(), on_true.shape(), "where_cond")?;
+        crate::shape::check_strict_binary_op(self.shape(), on_false.shape(), "where_cond")?;
+        if self.rank() != 0 && (on_true.rank() == 0 || on_false.rank() == 0) {
+            let on_true = on_true.broadcast_as(self.shape())?;
+            let on_false = on_false.broadcast_as(self.shape())?;
+            return self.where_cond(&on_true, &on_false);
+        }
         let _shap = self.same_shape_binary_op(on_true, "where_cond")?;
         let shape = self.same_shape_binary_op(on_false, "where_cond")?;
         let storage = self.storage().where_cond(
@@ -1623,6 +1965,7 @@ impl Tensor {
             Storage::Cpu(storage) => from_cpu_storage(storage),
             Storage::Cuda(storage) => from_cpu_storage(&storage.to_cpu_storage()?),
             Storage::Metal(storage) => from_cpu_storage(&storage.to_cpu_storage()?),
+            Storage::Meta(storage) => from_cpu_storage(&storage.to_cpu_storage()?),
         }
     }
 
@@ -1654,6 +1997,7 @@ impl Tensor {
             Storage::Cpu(storage) => from_cpu_storage(storage),
             Storage::Cuda(storage) => from_cpu_storage(&storage.to_cpu_storage()?),
             Storage::Metal(storage) => from_cpu_storage(&storage.to_cpu_storage()?),
+            Storage::Meta(storage) => from_cpu_storage(&storage.to_cpu_storage()?),
         }
     }
 
@@ -1695,6 +2039,7 @@ impl Tensor {
             Storage::Cpu(storage) => from_cpu_storage(storage),
             Storage::Cuda(storage) => from_cpu_storage(&storage.to_cpu_storage()?),
             Storage::Metal(storage) => from_cpu_storage(&storage.to_cpu_storage()?),
+            Storage::Meta(storage) => from_cpu_storage(&storage.to_cpu_storage()?),
         }
     }
 
@@ -1759,6 +2104,10 @@ impl Tensor {
         &self.op
     }
 
+    pub(crate) fn op_trace(&self) -> Option<&std::backtrace::Backtrace> {
+        self.op.trace()
+    }
+
     /// Computes the sum of all the elements in this tensor and returns a tensor holding this
     /// scalar with zero dimensions.
     ///
@@ -1916,6 +2265,7 @@ impl Tensor {
             is_variable: false,
             dtype: self.dtype,
             device: self.device.clone(),
+            foreign: self.foreign.clone(),
         };
         Ok(Tensor(Arc::new(tensor_)))
     }
@@ -1952,6 +2302,7 @@ impl Tensor {
             is_variable: false,
             dtype: self.dtype,
             device: self.device.clone(),
+            foreign: self.foreign.clone(),
         };
         Ok(Tensor(Arc::new(tensor_)))
     }
@@ -1966,6 +2317,50 @@ impl Tensor {
         self.layout.is_fortran_contiguous()
     }
 
+    /// Returns true if the tensor has four dimensions `(b, c, h, w)` and the data is stored in
+    /// the `(b, h, w, c)` order, aka NHWC or channels last.
+    pub fn is_channels_last(&self) -> bool {
+        self.layout.is_channels_last()
+    }
+
+    /// Returns a tensor with the same `(b, c, h, w)` shape where the data is stored in the
+    /// `(b, h, w, c)` order. This is the same as the original tensor if it already used this
+    /// layout, otherwise a copy is triggered.
+    ///
+    /// The convolutions accept both layouts and return contiguous `(b, c, h, w)` tensors, the
+    /// cpu backend has a faster path for channels last inputs.
+    pub fn to_channels_last(&self) -> Result<Tensor> {
+        if self.rank() != 4 {
+            Err(Error::UnexpectedNumberOfDims {
+                expected: 4,
+                got: self.rank(),
+                shape: self.shape().clone(),
+            }
+            .bt())?
+        }
+        if self.is_channels_last() {
+            Ok(self.clone())
+        } else {
+            self.permute((0, 2, 3, 1))?
+                .contiguous()?
+                .permute((0, 3, 1, 2))
+        }
+    }
+
+    /// Returns a tensor with the same `(b, c, h, w)` shape where the data is stored in the
+    /// `(b, c, h, w)` order, this is the same as [`Tensor::contiguous`].
+    pub fn to_channels_first(&self) -> Result<Tensor> {
+        if self.rank() != 4 {
+            Err(Error::UnexpectedNumberOfDims {
+                expected: 4,
+                got: self.rank(),
+                shape: self.shape().clone(),
+            }
+            .bt())?
+        }
+        self.contiguous()
+    }
+
     /// Compared to clone, this copies the actual storage but may fail because of running out of
     /// memory.
     pub fn copy(&self) -> Result<Tensor> {
@@ -1978,6 +2373,7 @@ impl Tensor {
             is_variable: false,
             dtype: self.dtype,
             device: self.device.clone(),
+            foreign: None,
         };
         Ok(Tensor(Arc::new(tensor_)))
     }
@@ -1998,11 +2394,36 @@ impl Tensor {
                 is_variable: false,
                 dtype: self.dtype,
                 device: self.device.clone(),
+                foreign: self.foreign.clone(),
             };
             Tensor(Arc::new(tensor_))
         }
     }
 
+    /// Copies a cpu tensor to the device of `stream`, with the copy queued on `stream` so that it
+    /// runs concurrently with the kernels of the device default stream.
+    ///
+    /// The returned tensor must not be used before an event recorded on `stream` after this call
+    /// has been waited for, see [`crate::CudaStream::record_event`].
+    pub fn to_device_async(&self, stream: &crate::CudaStream) -> Result<Tensor> {
+        let storage = match &*self.storage() {
+            Storage::Cpu(storage) => Storage::Cuda(stream.storage_from_cpu_storage(storage)?),
+            _ => bail!("asynchronous copies are only supported from the cpu"),
+        };
+        let op = BackpropOp::new1(self, Op::ToDevice);
+        let tensor_ = Tensor_ {
+            id: TensorId::new(),
+            storage: Arc::new(RwLock::new(storage)),
+            layout: self.layout.clone(),
+            op,
+            is_variable: false,
+            dtype: self.dtype,
+            device: Device::Cuda(stream.device().clone()),
+            foreign: None,
+        };
+        Ok(Tensor(Arc::new(tensor_)))
+    }
+
     /// If the target device is the same as the tensor device, only a shallow copy is performed.
     pub fn to_device(&self, device: &Device) -> Result<Tensor> {
         if self.device().same_device(device) {
@@ -2024,6 +2445,10 @@ impl Tensor {
                     Storage::Cuda(cuda.storage_from_cpu_storage(&cpu_storage)?)
                 }
                 (Storage::Cpu(storage), Device::Cpu) => Storage::Cpu(storage.clone()),
+                // Only the dtype is kept when moving a tensor to a meta device.
+                (_, Device::Meta(meta)) => {
+                    Storage::Meta(meta.zeros_impl(self.shape(), self.dtype)?)
+                }
                 _ => {
                     bail!("not implemented yet")
                 }
@@ -2037,6 +2462,7 @@ impl Tensor {
                 is_variable: false,
                 dtype: self.dtype,
                 device: device.clone(),
+                foreign: None,
             };
             Ok(Tensor(Arc::new(tensor_)))
         }
@@ -2067,6 +2493,7 @@ impl Tensor {
             is_variable: false,
             dtype: self.dtype,
             device: self.device.clone(),
+            foreign: self.foreign.clone(),
         };
         Ok(Tensor(Arc::new(tensor_)))
     }
@@ -2176,6 +2603,7 @@ impl Tensor {
                 is_variable: false,
                 dtype: self.dtype,
                 device: self.device.clone(),
+                foreign: self.foreign.clone(),
             };
             Ok(Tensor(Arc::new(tensor_)))
         } else {
@@ -2217,6 +2645,7 @@ impl Tensor {
                 is_variable: false,
                 dtype: self.dtype,
                 device: self.device.clone(),
+                foreign: self.foreign.clone(),
             };
             Ok(Tensor(Arc::new(tensor_)))
         } else {
@@ -2255,6 +2684,7 @@ impl Tensor {
             is_variable: false,
             dtype: self.dtype,
             device: self.device.clone(),
+            foreign: self.foreign.clone(),
         };
         Ok(Tensor(Arc::new(tensor_)))
     }
@@ -1,4 +1,4 @@
-use crate::{Result, Tensor};
+use crate::{DType, Result, Tensor, Var};
 
 #[macro_export]
 macro_rules! test_device {
@@ -60,3 +60,248 @@ pub fn to_vec3_round(t: &Tensor, digits: i32) -> Result<Vec<Vec<Vec<f32>>>> {
         .collect();
     Ok(t)
 }
+
+/// The tolerances used by [`gradcheck_with`], an analytic gradient `a` matches the numerical
+/// gradient `n` when `|a - n| <= atol + rtol * |n|`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GradCheckConfig {
+    /// The step of the central finite differences.
+    pub eps: f64,
+    pub atol: f64,
+    pub rtol: f64,
+}
+
+impl GradCheckConfig {
+    /// Default tolerances for inputs of the given dtype, the half precision dtypes only catch
+    /// gross errors so the checks are best done in f64 when the op supports it.
+    pub fn for_dtype(dtype: DType) -> Self {
+        match dtype {
+            DType::F64 => Self {
+                eps: 1e-6,
+                atol: 1e-5,
+                rtol: 1e-3,
+            },
+            DType::F32 => Self {
+                eps: 1e-3,
+                atol: 1e-2,
+                rtol: 1e-2,
+            },
+            DType::U8 | DType::U32 | DType::I64 | DType::BF16 | DType::F16 => Self {
+                eps: 1e-1,
+                atol: 2e-1,
+                rtol: 1e-1,
+            },
+        }
+    }
+}
+
+fn to_host_f64(t: &Tensor) -> Result<Vec<f64>> {
+    let t = t.flatten_all()?;
+    match t.dtype() {
+        DType::F64 => t.to_vec1::<f64>(),
+        _ => Ok(t
+            .to_dtype(DType::F32)?
+            .to_vec1::<f32>()?
+            .into_iter()
+            .map(|v| v as f64)
+            .collect()),
+    }
+}
+
+fn from_host_f64(vs: &[f64], like: &Tensor) -> Result<Tensor> {
+    match like.dtype() {
+        DType::F64 => Tensor::from_slice(vs, like.shape(), like.device()),
+        dtype => {
+            let vs = vs.iter().map(|&v| v as f32).collect::<Vec<_>>();
+            Tensor::from_slice(&vs, like.shape(), like.device())?.to_dtype(dtype)
+        }
+    }
+}
+
+// The value actually stored when writing `v` in a tensor of this dtype.
+fn round_to_dtype(v: f64, dtype: DType) -> f64 {
+    match dtype {
+        DType::F64 => v,
+        DType::F32 => v as f32 as f64,
+        DType::F16 => half::f16::from_f64(v).to_f64(),
+        DType::BF16 => half::bf16::from_f64(v).to_f64(),
+        DType::U8 | DType::U32 | DType::I64 => v.round(),
+    }
+}
+
+/// Checks the gradients computed by backpropagation through `f` against central finite
+/// differences, using the tolerances for the least precise floating point input.
+///
+/// `f` is evaluated on `inputs`, its output is reduced to a scalar with fixed pseudo-random
+/// weights so that each output element contributes to the checked gradients. Only the floating
+/// point inputs are checked, the other ones, e.g. indexes, are passed through. An error describing
+/// the first mismatch is returned when a gradient is wrong.
+///
+/// ```rust
+/// use candle_core::{test_utils::gradcheck, Device, Tensor};
+/// let xs = Tensor::new(&[[0.5f64, -1.], [2., 0.3]], &Device::Cpu)?;
+/// gradcheck(|xs| xs[0].sqr()?.exp()?.sum(1), &[xs])?;
+/// # Ok::<(), candle_core::Error>(())
+/// ```
+pub fn gradcheck<F>(f: F, inputs: &[Tensor]) -> Result<()>
+where
+    F: Fn(&[Tensor]) -> Result<Tensor>,
+{
+    let cfg = inputs
+        .iter()
+        .filter(|t| t.dtype().is_float())
+        .map(|t| GradCheckConfig::for_dtype(t.dtype()))
+        .max_by(|c1, c2| c1.eps.total_cmp(&c2.eps))
+        .unwrap_or(GradCheckConfig::for_dtype(DType::F64));
+    gradcheck_with(f, inputs, &cfg)
+}
+
+/// Same as [`gradcheck`] with explicit tolerances.
+pub fn gradcheck_with<F>(f: F, inputs: &[Tensor], cfg: &GradCheckConfig) -> Result<()>
+where
+    F: Fn(&[Tensor]) -> Result<Tensor>,
+{
+    let vars = inputs
+        .iter()
+        .map(|t| {
+            if t.dtype().is_float() {
+                Ok(Some(Var::from_tensor(t)?))
+            } else {
+                Ok(None)
+            }
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let xs = vars
+        .iter()
+        .zip(inputs.iter())
+        .map(|(v, t)| match v {
+            Some(v) => v.as_tensor().clone(),
+            None => t.clone(),
+        })
+        .collect::<Vec<_>>();
+    let ys = f(&xs)?;
+    if !ys.dtype().is_float() {
+        crate::bail!(
+            "gradcheck expects a floating point output, got {:?}",
+            ys.dtype()
+        )
+    }
+    // A small linear congruential generator, the weights are in [0.5, 1.5) and have both signs.
+    let weights = (0..ys.elem_count() as u64)
+        .map(|i| {
+            let r = (i
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407)
+                >> 33) as f64
+                / (1u64 << 31) as f64;
+            if i % 2 == 0 {
+                0.5 + r
+            } else {
+                -0.5 - r
+            }
+        })
+        .collect::<Vec<_>>();
+    let weights = from_host_f64(&weights, &ys)?;
+    let loss = |ys: &Tensor| -> Result<Tensor> {
+        let acc_dtype = match ys.dtype() {
+            DType::F64 => DType::F64,
+            _ => DType::F32,
+        };
+        (ys * &weights)?.to_dtype(acc_dtype)?.sum_all()
+    };
+    let grads = loss(&ys)?.backward()?;
+    for (index, (var, x)) in vars.iter().zip(xs.iter()).enumerate() {
+        let var = match var {
+            None => continue,
+            Some(var) => var,
+        };
+        let analytic = match grads.get(var) {
+            None => vec![0.; x.elem_count()],
+            Some(grad) => {
+                if grad.shape() != x.shape() {
+                    crate::bail!(
+                        "gradcheck: gradient for input {index} has shape {:?}, expected {:?}",
+                        grad.shape(),
+                        x.shape()
+                    )
+                }
+                to_host_f64(grad)?
+            }
+        };
+        let values = to_host_f64(x)?;
+        let mut perturbed = values.clone();
+        let mut eval = |i: usize, v: f64| -> Result<f64> {
+            perturbed[i] = v;
+            let mut xs = xs.to_vec();
+            xs[index] = from_host_f64(&perturbed, x)?;
+            let l = loss(&f(&xs)?)?.to_dtype(DType::F64)?.to_scalar::<f64>()?;
+            perturbed[i] = values[i];
+            Ok(l)
+        };
+        for (i, &analytic) in analytic.iter().enumerate() {
+            let plus = round_to_dtype(values[i] + cfg.eps, x.dtype());
+            let minus = round_to_dtype(values[i] - cfg.eps, x.dtype());
+            let numeric = (eval(i, plus)? - eval(i, minus)?) / (plus - minus);
+            if (analytic - numeric).abs() > cfg.atol + cfg.rtol * numeric.abs() {
+                crate::bail!(
+                    "gradcheck: gradient mismatch for input {index} at element {i}, analytic {analytic}, numeric {numeric}"
+                )
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Checks that `a` and `b` have the same shape and that `|a - b| <= atol + rtol * |b|` for all
+/// the elements, the infinities have to be equal and NaN values are never close. The error
+/// describes the number of mismatches and the largest one, so this can be used in tests with `?`.
+///
+/// ```rust
+/// use candle_core::{test_utils::assert_close, Device, Tensor};
+/// let a = Tensor::new(&[1f32, 2., 3.], &Device::Cpu)?;
+/// assert_close(&a, &(&a + 1e-6)?, 1e-5, 1e-5)?;
+/// assert!(assert_close(&a, &(&a + 1e-3)?, 1e-5, 1e-5).is_err());
+/// # Ok::<(), candle_core::Error>(())
+/// ```
+pub fn assert_close(a: &Tensor, b: &Tensor, rtol: f64, atol: f64) -> Result<()> {
+    if a.shape() != b.shape() {
+        crate::bail!(
+            "assert_close: shape mismatch {:?} and {:?}",
+            a.shape(),
+            b.shape()
+        )
+    }
+    let (va, vb) = (to_host_f64(a)?, to_host_f64(b)?);
+    let mut mismatches = 0;
+    // The index, the values and the excess over the tolerance of the largest mismatch.
+    let mut worst: Option<(usize, f64, f64, f64)> = None;
+    for (i, (&x, &y)) in va.iter().zip(vb.iter()).enumerate() {
+        let excess = if x == y {
+            continue;
+        } else if x.is_finite() && y.is_finite() {
+            (x - y).abs() - atol - rtol * y.abs()
+        } else {
+            f64::INFINITY
+        };
+        if excess > 0. {
+            mismatches += 1;
+            if worst.map_or(true, |w| excess > w.3) {
+                worst = Some((i, x, y, excess))
+            }
+        }
+    }
+    if let Some((index, x, y, _)) = worst {
+        // Converts the flat index to a multi-dimensional one.
+        let mut index = index;
+        let mut position = vec![0; a.rank()];
+        for (p, &d) in position.iter_mut().zip(a.dims()).rev() {
+            *p = index % d;
+            index /= d;
+        }
+        crate::bail!(
+            "assert_close: {mismatches}/{} elements are not close (rtol {rtol}, atol {atol}), the largest mismatch is at {position:?}: {x} vs {y}",
+            va.len()
+        )
+    }
+    Ok(())
+}
@@ -0,0 +1,346 @@
+//! Tensors with their rank tracked in the type system.
+//!
+//! An [`STensor<R>`] is a [`Tensor`] of rank `R`, the rank is checked once when wrapping a tensor
+//! and the operations only accept operands of compatible ranks, so that using a matrix where a
+//! batch of matrices is expected fails to compile rather than at runtime. The operations that
+//! change the rank take the output rank as a const parameter, which is checked at compile time
+//! against the input rank. The wrapper has no runtime cost and [`STensor::untyped`] returns the
+//! underlying tensor for the operations that are not covered.
+//!
+//! ```rust
+//! use candle_core::{typed::STensor, Device, Tensor};
+//! # fn main() -> candle_core::Result<()> {
+//! let xs: STensor<3> = STensor::new(Tensor::zeros((2, 4, 8), candle_core::DType::F32, &Device::Cpu)?)?;
+//! let w: STensor<2> = STensor::new(Tensor::zeros((8, 16), candle_core::DType::F32, &Device::Cpu)?)?;
+//! let ys = xs.broadcast_matmul(&w)?;
+//! assert_eq!(ys.dims(), [2, 4, 16]);
+//! // Flattening the batch dimension, the output rank is inferred from the dims.
+//! let ys = ys.reshape([8, 16])?;
+//! let ys: STensor<1> = ys.sum(1)?;
+//! assert_eq!(ys.dims(), [8]);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Mismatched ranks are rejected when compiling:
+//!
+//! ```compile_fail
+//! use candle_core::{typed::STensor, Device, Tensor};
+//! # fn main() -> candle_core::Result<()> {
+//! let xs: STensor<3> = STensor::new(Tensor::zeros((2, 4, 8), candle_core::DType::F32, &Device::Cpu)?)?;
+//! let w: STensor<2> = STensor::new(Tensor::zeros((8, 16), candle_core::DType::F32, &Device::Cpu)?)?;
+//! let ys = xs.matmul(&w)?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The output ranks given as const parameters are checked against the input rank too, here the
+//! rank has to grow by exactly one:
+//!
+//! ```compile_fail
+//! use candle_core::{typed::STensor, Device, Tensor};
+//! # fn main() -> candle_core::Result<()> {
+//! let xs: STensor<2> = STensor::new(Tensor::zeros((2, 4), candle_core::DType::F32, &Device::Cpu)?)?;
+//! let ys: STensor<4> = xs.unsqueeze(0)?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! And broadcasting cannot lower the rank:
+//!
+//! ```compile_fail
+//! use candle_core::{typed::STensor, Device, Tensor};
+//! # fn main() -> candle_core::Result<()> {
+//! let xs: STensor<3> = STensor::new(Tensor::zeros((1, 2, 4), candle_core::DType::F32, &Device::Cpu)?)?;
+//! let ys: STensor<2> = xs.broadcast_as([2, 4])?;
+//! # Ok(())
+//! # }
+//! ```
+use crate::{DType, Device, Error, Result, Tensor};
+
+// Compile time checks on the ranks, these are evaluated when the methods using them are
+// instantiated so an invalid rank is reported as a compilation error.
+struct AtLeast<const R: usize, const MIN: usize>;
+
+impl<const R: usize, const MIN: usize> AtLeast<R, MIN> {
+    const OK: () = assert!(R >= MIN, "the tensor rank is too small for this operation");
+}
+
+struct PlusOne<const R: usize, const S: usize>;
+
+impl<const R: usize, const S: usize> PlusOne<R, S> {
+    const OK: () = assert!(
+        S == R + 1,
+        "the output rank has to be the input rank plus one"
+    );
+}
+
+/// A tensor of rank `R`, see the module documentation.
+#[derive(Clone, Debug)]
+pub struct STensor<const R: usize>(Tensor);
+
+/// A rank 0 tensor.
+pub type Scalar = STensor<0>;
+/// A rank 1 tensor.
+pub type Vector = STensor<1>;
+/// A rank 2 tensor.
+pub type Matrix = STensor<2>;
+
+impl<const R: usize> STensor<R> {
+    /// Wraps `t`, returning an error if its rank is not `R`.
+    pub fn new(t: Tensor) -> Result<Self> {
+        if t.rank() != R {
+            Err(Error::UnexpectedNumberOfDims {
+                expected: R,
+                got: t.rank(),
+                shape: t.shape().clone(),
+            }
+            .bt())?
+        }
+        Ok(Self(t))
+    }
+
+    pub fn zeros(dims: [usize; R], dtype: DType, device: &Device) -> Result<Self> {
+        Ok(Self(Tensor::zeros(dims.as_slice(), dtype, device)?))
+    }
+
+    pub fn ones(dims: [usize; R], dtype: DType, device: &Device) -> Result<Self> {
+        Ok(Self(Tensor::ones(dims.as_slice(), dtype, device)?))
+    }
+
+    /// The underlying tensor.
+    pub fn untyped(self) -> Tensor {
+        self.0
+    }
+
+    pub fn as_untyped(&self) -> &Tensor {
+        &self.0
+    }
+
+    /// Applies an untyped operation, checking that its output has rank `S`.
+    pub fn map<const S: usize, F: FnOnce(&Tensor) -> Result<Tensor>>(
+        &self,
+        f: F,
+    ) -> Result<STensor<S>> {
+        STensor::new(f(&self.0)?)
+    }
+
+    pub fn dims(&self) -> [usize; R] {
+        let mut dims = [0; R];
+        dims.copy_from_slice(self.0.dims());
+        dims
+    }
+
+    /// The size of dimension `dim`, an error is returned if `dim` is not smaller than `R`.
+    pub fn dim(&self, dim: usize) -> Result<usize> {
+        self.0.dim(dim)
+    }
+
+    pub fn dtype(&self) -> DType {
+        self.0.dtype()
+    }
+
+    pub fn device(&self) -> &Device {
+        self.0.device()
+    }
+
+    pub fn elem_count(&self) -> usize {
+        self.0.elem_count()
+    }
+
+    pub fn to_dtype(&self, dtype: DType) -> Result<Self> {
+        Ok(Self(self.0.to_dtype(dtype)?))
+    }
+
+    pub fn to_device(&self, device: &Device) -> Result<Self> {
+        Ok(Self(self.0.to_device(device)?))
+    }
+
+    pub fn contiguous(&self) -> Result<Self> {
+        Ok(Self(self.0.contiguous()?))
+    }
+
+    pub fn detach(&self) -> Self {
+        Self(self.0.detach())
+    }
+
+    /// Reshapes the tensor, the output rank is the number of dims.
+    pub fn reshape<const S: usize>(&self, dims: [usize; S]) -> Result<STensor<S>> {
+        Ok(STensor(self.0.reshape(dims.as_slice())?))
+    }
+
+    /// Broadcasts the tensor to `dims`, which cannot have a lower rank.
+    pub fn broadcast_as<const S: usize>(&self, dims: [usize; S]) -> Result<STensor<S>> {
+        #[allow(clippy::let_unit_value)]
+        let () = AtLeast::<S, R>::OK;
+        Ok(STensor(self.0.broadcast_as(dims.as_slice())?))
+    }
+
+    /// Inserts a dimension of size one at position `dim`.
+    pub fn unsqueeze<const S: usize>(&self, dim: usize) -> Result<STensor<S>> {
+        #[allow(clippy::let_unit_value)]
+        let () = PlusOne::<R, S>::OK;
+        Ok(STensor(self.0.unsqueeze(dim)?))
+    }
+
+    /// Removes the dimension `dim`, which must have a size of one.
+    pub fn squeeze<const S: usize>(&self, dim: usize) -> Result<STensor<S>> {
+        #[allow(clippy::let_unit_value)]
+        let () = PlusOne::<S, R>::OK;
+        if self.0.dim(dim)? != 1 {
+            crate::bail!("cannot squeeze dim {dim} of size {}", self.0.dim(dim)?)
+        }
+        Ok(STensor(self.0.squeeze(dim)?))
+    }
+
+    /// Sums over `dim`, removing it.
+    pub fn sum<const S: usize>(&self, dim: usize) -> Result<STensor<S>> {
+        #[allow(clippy::let_unit_value)]
+        let () = PlusOne::<S, R>::OK;
+        Ok(STensor(self.0.sum(dim)?))
+    }
+
+    pub fn sum_keepdim(&self, dim: usize) -> Result<Self> {
+        Ok(Self(self.0.sum_keepdim(dim)?))
+    }
+
+    pub fn mean_keepdim(&self, dim: usize) -> Result<Self> {
+        Ok(Self(self.0.mean_keepdim(dim)?))
+    }
+
+    pub fn max_keepdim(&self, dim: usize) -> Result<Self> {
+        Ok(Self(self.0.max_keepdim(dim)?))
+    }
+
+    pub fn sum_all(&self) -> Result<Scalar> {
+        Ok(STensor(self.0.sum_all()?))
+    }
+
+    pub fn mean_all(&self) -> Result<Scalar> {
+        Ok(STensor(self.0.mean_all()?))
+    }
+
+    pub fn flatten_all(&self) -> Result<Vector> {
+        Ok(STensor(self.0.flatten_all()?))
+    }
+
+    pub fn narrow(&self, dim: usize, start: usize, len: usize) -> Result<Self> {
+        Ok(Self(self.0.narrow(dim, start, len)?))
+    }
+
+    pub fn transpose(&self, dim1: usize, dim2: usize) -> Result<Self> {
+        Ok(Self(self.0.transpose(dim1, dim2)?))
+    }
+
+    /// Transposes the last two dimensions.
+    pub fn t(&self) -> Result<Self> {
+        #[allow(clippy::let_unit_value)]
+        let () = AtLeast::<R, 2>::OK;
+        Ok(Self(self.0.t()?))
+    }
+
+    pub fn permute(&self, dims: [usize; R]) -> Result<Self> {
+        Ok(Self(self.0.permute(dims.as_slice())?))
+    }
+
+    /// Matrix multiplication over the last two dimensions, both operands have the same rank and
+    /// the same batch dimensions.
+    pub fn matmul(&self, rhs: &Self) -> Result<Self> {
+        #[allow(clippy::let_unit_value)]
+        let () = AtLeast::<R, 2>::OK;
+        Ok(Self(self.0.matmul(&rhs.0)?))
+    }
+
+    /// Matrix multiplication where the batch dimensions of `rhs` are broadcasted, `rhs` cannot
+    /// have a higher rank than `self`.
+    pub fn broadcast_matmul<const S: usize>(&self, rhs: &STensor<S>) -> Result<Self> {
+        #[allow(clippy::let_unit_value)]
+        let () = AtLeast::<S, 2>::OK;
+        #[allow(clippy::let_unit_value)]
+        let () = AtLeast::<R, S>::OK;
+        Ok(Self(self.0.broadcast_matmul(&rhs.0)?))
+    }
+
+    pub fn affine(&self, mul: f64, add: f64) -> Result<Self> {
+        Ok(Self(self.0.affine(mul, add)?))
+    }
+
+    pub fn exp(&self) -> Result<Self> {
+        Ok(Self(self.0.exp()?))
+    }
+
+    pub fn sqr(&self) -> Result<Self> {
+        Ok(Self(self.0.sqr()?))
+    }
+
+    pub fn sqrt(&self) -> Result<Self> {
+        Ok(Self(self.0.sqrt()?))
+    }
+
+    pub fn relu(&self) -> Result<Self> {
+        Ok(Self(self.0.relu()?))
+    }
+}
+
+macro_rules! binary_op {
+    ($fn_name:ident, $broadcast_fn_name:ident) => {
+        impl<const R: usize> STensor<R> {
+            pub fn $fn_name(&self, rhs: &Self) -> Result<Self> {
+                Ok(Self(self.0.$fn_name(&rhs.0)?))
+            }
+
+            /// Same as the non broadcasting version, `rhs` is broadcasted to the shape of `self`
+            /// and cannot have a higher rank.
+            pub fn $broadcast_fn_name<const S: usize>(&self, rhs: &STensor<S>) -> Result<Self> {
+                #[allow(clippy::let_unit_value)]
+                let () = AtLeast::<R, S>::OK;
+                Ok(Self(self.0.$broadcast_fn_name(&rhs.0)?))
+            }
+        }
+    };
+}
+
+binary_op!(add, broadcast_add);
+binary_op!(sub, broadcast_sub);
+binary_op!(mul, broadcast_mul);
+binary_op!(div, broadcast_div);
+binary_op!(maximum, broadcast_maximum);
+binary_op!(minimum, broadcast_minimum);
+
+impl Scalar {
+    pub fn to_scalar<T: crate::WithDType>(&self) -> Result<T> {
+        self.0.to_scalar()
+    }
+}
+
+impl Vector {
+    pub fn to_vec1<T: crate::WithDType>(&self) -> Result<Vec<T>> {
+        self.0.to_vec1()
+    }
+}
+
+impl Matrix {
+    pub fn to_vec2<T: crate::WithDType>(&self) -> Result<Vec<Vec<T>>> {
+        self.0.to_vec2()
+    }
+}
+
+impl<const R: usize> AsRef<Tensor> for STensor<R> {
+    fn as_ref(&self) -> &Tensor {
+        &self.0
+    }
+}
+
+impl<const R: usize> From<STensor<R>> for Tensor {
+    fn from(t: STensor<R>) -> Self {
+        t.0
+    }
+}
+
+impl<const R: usize> TryFrom<Tensor> for STensor<R> {
+    type Error = Error;
+
+    fn try_from(t: Tensor) -> Result<Self> {
+        Self::new(t)
+    }
+}
@@ -1,5 +1,63 @@
 use std::str::FromStr;
 
+static BATCH_INVARIANT: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+/// When enabled, the matmul result for one row does not depend on the other rows, so a sequence
+/// gives the same results whether it is processed alone or in a batch. This is achieved by
+/// computing each row of a matmul separately so that the reduction order and split sizes are the
+/// same for all the batch sizes, at the cost of slower matmuls.
+///
+/// Only matmul is affected by this setting. On the cpu backend, the reductions over the
+/// non-batch dimensions, e.g. `sum_keepdim(D::Minus1)` or `max_keepdim`, and the softmax and
+/// normalization ops of `candle-nn` already process each row with the same order whatever the
+/// batch size, so models built from these ops are batch invariant, reductions over the batch
+/// dimension are obviously not. The guarantee is only exact on the cpu backend, on other backends
+/// the kernels selected for the matmuls and reductions may still depend on the batch size.
+pub fn set_batch_invariant(b: bool) {
+    BATCH_INVARIANT.store(b, std::sync::atomic::Ordering::Relaxed)
+}
+
+/// Returns whether the batch invariant mode is enabled, see `set_batch_invariant`.
+pub fn batch_invariant() -> bool {
+    BATCH_INVARIANT.load(std::sync::atomic::Ordering::Relaxed)
+}
+
+// The accumulation dtype, 0 for none and otherwise 1 + the index in `ACCUMULATION_DTYPES`.
+static ACCUMULATION_DTYPE: std::sync::atomic::AtomicU8 = std::sync::atomic::AtomicU8::new(0);
+const ACCUMULATION_DTYPES: [crate::DType; 4] = [
+    crate::DType::BF16,
+    crate::DType::F16,
+    crate::DType::F32,
+    crate::DType::F64,
+];
+
+/// Sets the dtype used to accumulate the values in `sum`, `mean` and `matmul`.
+///
+/// With the default of `None`, each backend uses its native behavior: on the cpu the values are
+/// accumulated in the dtype of the inputs, e.g. f16 sums are computed in f16, whereas cuda
+/// matmuls accumulate half precision inputs in f32 unless reduced precision is enabled. When set
+/// to a float dtype, inputs using a smaller float dtype are converted to it before the operation
+/// and the result is converted back to the input dtype, e.g. `Some(DType::F32)` accumulates f16
+/// and bf16 inputs in f32 on all backends, trading some speed for accuracy. Non-float dtypes
+/// are ignored.
+///
+/// The `*_acc` variants of these operations, e.g. `Tensor::sum_acc`, override this setting for
+/// a single operation.
+pub fn set_accumulation_dtype(dtype: Option<crate::DType>) {
+    let v = dtype
+        .and_then(|dtype| ACCUMULATION_DTYPES.iter().position(|&d| d == dtype))
+        .map_or(0, |i| i as u8 + 1);
+    ACCUMULATION_DTYPE.store(v, std::sync::atomic::Ordering::Relaxed)
+}
+
+/// Returns the accumulation dtype, see `set_accumulation_dtype`.
+pub fn accumulation_dtype() -> Option<crate::DType> {
+    match ACCUMULATION_DTYPE.load(std::sync::atomic::Ordering::Relaxed) {
+        0 => None,
+        v => Some(ACCUMULATION_DTYPES[v as usize - 1]),
+    }
+}
+
 pub fn get_num_threads() -> usize {
     // Respond to the same environment variable as rayon.
     match std::env::var("RAYON_NUM_THREADS")
@@ -139,9 +139,9 @@ impl Var {
         }
         let (src, src_l) = src.storage_and_layout();
         if layout.shape() != src_l.shape() {
-            Err(Error::ShapeMismatchBinaryOp {
-                lhs: layout.shape().clone(),
-                rhs: src_l.shape().clone(),
+            Err(Error::ShapeMismatch {
+                expected: layout.shape().clone(),
+                got: src_l.shape().clone(),
                 op: "set",
             }
             .bt())?
@@ -0,0 +1,53 @@
+// The accumulation dtype is a global setting so these tests live in their own binary.
+use candle_core::{DType, Device, Result, Tensor};
+
+// f16 cannot represent 2049 so a sum accumulated in f16 stops increasing at 2048.
+fn ones_f16(n: usize) -> Result<Tensor> {
+    Tensor::ones(n, DType::F16, &Device::Cpu)
+}
+
+fn to_f32(t: &Tensor) -> Result<f32> {
+    t.to_dtype(DType::F32)?.to_vec0::<f32>()
+}
+
+#[test]
+fn accumulation_dtype() -> Result<()> {
+    let xs = ones_f16(4096)?;
+    assert_eq!(candle_core::utils::accumulation_dtype(), None);
+    assert_eq!(to_f32(&xs.sum(0)?)?, 2048.);
+    assert_eq!(to_f32(&xs.sum_acc(0, DType::F32)?)?, 4096.);
+    assert_eq!(to_f32(&xs.mean_acc(0, DType::F32)?)?, 1.);
+    let acc = xs.sum_keepdim_acc(0, DType::F32)?;
+    assert_eq!(acc.dtype(), DType::F16);
+    assert_eq!(acc.dims(), [1]);
+
+    let lhs = xs.reshape((1, 4096))?;
+    let rhs = xs.reshape((4096, 1))?;
+    let mm = lhs.matmul_acc(&rhs, DType::F32)?;
+    assert_eq!(mm.dtype(), DType::F16);
+    assert_eq!(to_f32(&mm.squeeze(0)?.squeeze(0)?)?, 4096.);
+
+    candle_core::utils::set_accumulation_dtype(Some(DType::F32));
+    assert_eq!(candle_core::utils::accumulation_dtype(), Some(DType::F32));
+    assert_eq!(to_f32(&xs.sum(0)?)?, 4096.);
+    assert_eq!(to_f32(&xs.sum_all()?)?, 4096.);
+    assert_eq!(to_f32(&xs.mean(0)?)?, 1.);
+    // The per-op setting overrides the global one.
+    assert_eq!(to_f32(&xs.sum_acc(0, DType::F16)?)?, 2048.);
+    // Integer and larger dtypes are not affected.
+    let us = Tensor::ones(4, DType::U32, &Device::Cpu)?;
+    assert_eq!(us.sum(0)?.dtype(), DType::U32);
+    let fs = Tensor::ones(4, DType::F64, &Device::Cpu)?;
+    assert_eq!(fs.sum(0)?.dtype(), DType::F64);
+
+    // Gradients flow through the conversions.
+    let var = candle_core::Var::from_tensor(&xs)?;
+    let grads = var.sum_all()?.backward()?;
+    let grad = grads.get(&var).unwrap();
+    assert_eq!(grad.dtype(), DType::F16);
+    assert_eq!(to_f32(&grad.sum_acc(0, DType::F32)?)?, 4096.);
+
+    candle_core::utils::set_accumulation_dtype(None);
+    assert_eq!(to_f32(&xs.sum(0)?)?, 2048.);
+    Ok(())
+}
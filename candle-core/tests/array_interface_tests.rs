@@ -0,0 +1,73 @@
+use candle_core::array_interface::{dtype_from_typestr, typestr, ArrayInterface};
+use candle_core::{DType, Device, IndexOp, Result, Tensor};
+
+#[test]
+fn array_interface_typestr() -> Result<()> {
+    for dtype in [
+        DType::U8,
+        DType::U32,
+        DType::I64,
+        DType::F16,
+        DType::F32,
+        DType::F64,
+    ] {
+        assert_eq!(dtype_from_typestr(typestr(dtype)?)?, dtype)
+    }
+    assert!(typestr(DType::BF16).is_err());
+    assert_eq!(dtype_from_typestr("|b1")?, DType::U8);
+    assert!(dtype_from_typestr(">f4").is_err());
+    assert!(dtype_from_typestr("<c8").is_err());
+    Ok(())
+}
+
+#[test]
+fn array_interface_round_trip() -> Result<()> {
+    let dev = &Device::Cpu;
+    let t = Tensor::arange(0f32, 12., dev)?.reshape((3, 4))?;
+    let ai = t.array_interface()?;
+    assert_eq!(ai.shape, [3, 4]);
+    assert_eq!(ai.typestr, "<f4");
+    assert_eq!(
+        (ai.strides.as_ref(), ai.stream, ai.version),
+        (None, None, 3)
+    );
+
+    // A strided view shares the memory of the tensor.
+    let view = t.t()?.i(1..)?;
+    let view_ai = view.array_interface()?;
+    assert_eq!(view_ai.shape, [3, 3]);
+    assert_eq!(view_ai.strides, Some(vec![4, 16]));
+    assert_eq!(view_ai.data, ai.data + 4);
+    assert_eq!(view_ai.elem_strides()?, [1, 4]);
+    let imported = unsafe { Tensor::from_array_interface(&view_ai, dev, t.clone())? };
+    assert_eq!(imported.stride(), [1, 4]);
+    assert_eq!(imported.to_vec2::<f32>()?, view.to_vec2::<f32>()?);
+    unsafe { *(ai.data as *mut f32).add(10) = -1. };
+    assert_eq!(imported.to_vec2::<f32>()?[1][2], -1.);
+
+    // Memory owned by another library, the owner is dropped with the last view.
+    let data = std::sync::Arc::new([1i64, 2, 3, 4, 5, 6]);
+    let ai = ArrayInterface {
+        shape: vec![3, 2],
+        typestr: "<i8".to_string(),
+        data: data.as_ptr() as u64,
+        read_only: true,
+        strides: Some(vec![8, 24]),
+        stream: None,
+        version: 3,
+    };
+    let imported = unsafe { Tensor::from_array_interface(&ai, dev, data.clone())? };
+    assert_eq!(imported.to_vec2::<i64>()?, [[1, 4], [2, 5], [3, 6]]);
+    let column = imported.i((.., 1))?;
+    drop(imported);
+    assert_eq!(std::sync::Arc::strong_count(&data), 2);
+    assert_eq!(column.to_vec1::<i64>()?, [4, 5, 6]);
+    drop(column);
+    assert_eq!(std::sync::Arc::strong_count(&data), 1);
+    let bad = ArrayInterface {
+        strides: Some(vec![3, 24]),
+        ..ai
+    };
+    assert!(unsafe { Tensor::from_array_interface(&bad, dev, ()) }.is_err());
+    Ok(())
+}
@@ -0,0 +1,85 @@
+// The batch invariant mode is a global setting so these tests live in their own binary.
+use candle_core::{Device, Result, Tensor, Var};
+
+#[test]
+fn batch_invariant_matmul() -> Result<()> {
+    candle_core::utils::set_batch_invariant(true);
+    let dev = &Device::Cpu;
+
+    // Linear layer style matmul, the rhs is shared by all the batch elements.
+    // Without the batch invariant mode, the single row results differ for this size.
+    let xs = Tensor::randn(0f32, 1., (4, 33, 1024), dev)?;
+    let w = Tensor::randn(0f32, 1., (96, 1024), dev)?;
+    let ys = xs.matmul(&w.broadcast_left(4)?.t()?)?;
+    for b in 0..4 {
+        for len in [1, 7] {
+            let alone = xs.narrow(0, b, 1)?.narrow(1, 0, len)?;
+            let alone = alone.matmul(&w.broadcast_left(1)?.t()?)?;
+            assert_eq!(
+                alone.to_vec3::<f32>()?,
+                ys.narrow(0, b, 1)?.narrow(1, 0, len)?.to_vec3::<f32>()?
+            );
+        }
+    }
+    let flat = xs.reshape((4 * 33, 1024))?.matmul(&w.t()?)?;
+    assert_eq!(
+        flat.reshape((4, 33, 96))?.to_vec3::<f32>()?,
+        ys.to_vec3::<f32>()?
+    );
+
+    // Attention style matmul, each batch element has its own rhs.
+    let q = Tensor::randn(0f32, 1., (3, 2, 17, 256), dev)?;
+    let k = Tensor::randn(0f32, 1., (3, 2, 17, 256), dev)?;
+    let att = q.matmul(&k.t()?)?;
+    let alone = q.narrow(0, 2, 1)?.narrow(2, 0, 1)?;
+    let alone = alone.matmul(&k.narrow(0, 2, 1)?.t()?)?;
+    assert_eq!(
+        alone.flatten_all()?.to_vec1::<f32>()?,
+        att.narrow(0, 2, 1)?
+            .narrow(2, 0, 1)?
+            .flatten_all()?
+            .to_vec1::<f32>()?
+    );
+
+    // Gradients flow through the row-wise matmuls.
+    let x = Var::from_tensor(&xs.narrow(0, 0, 1)?.squeeze(0)?)?;
+    let loss = x.matmul(&w.t()?)?.sum_all()?;
+    let grads = loss.backward()?;
+    let grad = grads.get(&x).unwrap();
+    candle_core::utils::set_batch_invariant(false);
+    let expected = w.sum_keepdim(0)?.broadcast_as((33, 1024))?;
+    let diff = (grad - expected)?.abs()?.flatten_all()?.max(0)?;
+    assert!(diff.to_scalar::<f32>()? < 1e-4);
+    Ok(())
+}
+
+#[test]
+fn batch_invariant_reduce() -> Result<()> {
+    use candle_core::D;
+    // The cpu reductions do not depend on the batch size, whether the batch invariant mode is
+    // enabled or not.
+    let dev = &Device::Cpu;
+    let xs = Tensor::randn(0f32, 1., (5, 9, 1000), dev)?;
+    let alone = xs.narrow(0, 3, 1)?;
+    type Reduce = fn(&Tensor) -> Result<Tensor>;
+    let reductions: [Reduce; 5] = [
+        |xs| xs.sum_keepdim(D::Minus1),
+        |xs| xs.mean_keepdim(D::Minus1),
+        |xs| xs.max_keepdim(D::Minus1),
+        |xs| {
+            xs.argmin_keepdim(D::Minus1)?
+                .to_dtype(candle_core::DType::F32)
+        },
+        |xs| xs.sum_keepdim(1),
+    ];
+    for reduce in reductions {
+        assert_eq!(
+            reduce(&alone)?.flatten_all()?.to_vec1::<f32>()?,
+            reduce(&xs)?
+                .narrow(0, 3, 1)?
+                .flatten_all()?
+                .to_vec1::<f32>()?
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,117 @@
+use candle_core::collective::{Communicator, LocalCommunicator, ReduceOp};
+use candle_core::{Device, Result, Tensor};
+
+fn run<F>(world_size: usize, f: F) -> Result<Vec<Tensor>>
+where
+    F: Fn(&LocalCommunicator) -> Result<Tensor> + Send + Sync,
+{
+    let comms = LocalCommunicator::group(world_size);
+    std::thread::scope(|s| {
+        let handles = comms
+            .iter()
+            .map(|comm| s.spawn(|| f(comm)))
+            .collect::<Vec<_>>();
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    })
+}
+
+#[test]
+fn all_reduce() -> Result<()> {
+    let res = run(3, |comm| {
+        let xs = Tensor::new(&[1f32, 2.], &Device::Cpu)?.affine(comm.rank() as f64 + 1., 0.)?;
+        let sum = comm.all_reduce(&xs, ReduceOp::Sum)?;
+        let max = comm.all_reduce(&xs, ReduceOp::Max)?;
+        let avg = comm.all_reduce(&xs, ReduceOp::Avg)?;
+        Tensor::stack(&[sum, max, avg], 0)
+    })?;
+    for r in res {
+        assert_eq!(r.to_vec2::<f32>()?, [[6., 12.], [3., 6.], [2., 4.]]);
+    }
+    Ok(())
+}
+
+#[test]
+fn all_gather_and_reduce_scatter() -> Result<()> {
+    let res = run(2, |comm| {
+        let xs =
+            Tensor::new(&[[1f32], [2.]], &Device::Cpu)?.affine(1., comm.rank() as f64 * 10.)?;
+        comm.all_gather(&xs, 1)
+    })?;
+    for r in res {
+        assert_eq!(r.to_vec2::<f32>()?, [[1., 11.], [2., 12.]]);
+    }
+
+    let res = run(2, |comm| {
+        let xs = Tensor::new(&[1f32, 2., 3., 4.], &Device::Cpu)?;
+        let xs = xs.affine(comm.rank() as f64 + 1., 0.)?;
+        comm.reduce_scatter(&xs, 0, ReduceOp::Sum)
+    })?;
+    assert_eq!(res[0].to_vec1::<f32>()?, [3., 6.]);
+    assert_eq!(res[1].to_vec1::<f32>()?, [9., 12.]);
+
+    let res = run(3, |comm| {
+        let xs = Tensor::new(&[comm.rank() as u32], &Device::Cpu)?;
+        comm.broadcast(&xs, 1)
+    })?;
+    for r in res {
+        assert_eq!(r.to_vec1::<u32>()?, [1]);
+    }
+    Ok(())
+}
+
+#[test]
+fn tcp_communicator() -> Result<()> {
+    use candle_core::collective::TcpCommunicator;
+    let world_size = 3;
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let timeout = std::time::Duration::from_secs(10);
+    let f = |comm: TcpCommunicator| {
+        let rank = comm.rank() as f64;
+        let xs = Tensor::new(&[1f32, 2., 3.], &Device::Cpu)?.affine(rank + 1., 0.)?;
+        let sum = comm.all_reduce(&xs, ReduceOp::Sum)?;
+        let gathered = comm.all_gather(&xs.narrow(0, 0, 1)?, 0)?;
+        let scattered = comm.reduce_scatter(&xs, 0, ReduceOp::Max)?;
+        let root = comm.broadcast(&xs, 2)?;
+        Ok::<_, candle_core::Error>((sum, gathered, scattered, root))
+    };
+    let res = std::thread::scope(|s| {
+        let mut handles = vec![s.spawn(|| f(TcpCommunicator::listen(listener, world_size)?))];
+        for rank in 1..world_size {
+            handles.push(
+                s.spawn(move || f(TcpCommunicator::connect(addr, rank, world_size, timeout)?)),
+            )
+        }
+        handles
+            .into_iter()
+            .map(|h| h.join().unwrap())
+            .collect::<Result<Vec<_>>>()
+    })?;
+    for (rank, (sum, gathered, scattered, root)) in res.into_iter().enumerate() {
+        assert_eq!(sum.to_vec1::<f32>()?, [6., 12., 18.]);
+        assert_eq!(gathered.to_vec1::<f32>()?, [1., 2., 3.]);
+        assert_eq!(scattered.to_vec1::<f32>()?, [3. * (rank as f32 + 1.)]);
+        assert_eq!(root.to_vec1::<f32>()?, [3., 6., 9.]);
+    }
+    Ok(())
+}
+
+#[test]
+fn tcp_communicator_bad_length() -> Result<()> {
+    use candle_core::collective::TcpCommunicator;
+    use std::io::Write;
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    // A peer announcing a huge message is rejected before allocating the buffer.
+    let peer = std::thread::spawn(move || {
+        let mut stream = std::net::TcpStream::connect(addr)?;
+        stream.write_all(&1u64.to_le_bytes())?;
+        stream.write_all(&u64::MAX.to_le_bytes())?;
+        Ok::<_, std::io::Error>(stream)
+    });
+    let comm = TcpCommunicator::listen(listener, 2)?;
+    let xs = Tensor::new(&[1f32, 2., 3.], &Device::Cpu)?;
+    assert!(comm.all_reduce(&xs, ReduceOp::Sum).is_err());
+    peer.join().unwrap()?;
+    Ok(())
+}
@@ -164,7 +164,7 @@ fn conv2d(dev: &Device) -> Result<()> {
         ]
     );
 
-    let res = t.conv_transpose2d(&w.transpose(0, 1)?, 0, 0, 1, 1)?;
+    let res = t.conv_transpose2d(&w.transpose(0, 1)?, 0, 0, 1, 1, 1)?;
 
     assert_eq!(res.dims(), [1, 2, 7, 7]);
     assert_eq!(
@@ -200,7 +200,7 @@ fn conv2d(dev: &Device) -> Result<()> {
     );
 
     // Transpose and dilations.
-    let res = t.conv_transpose2d(&w.transpose(0, 1)?, 0, 0, 1, 2)?;
+    let res = t.conv_transpose2d(&w.transpose(0, 1)?, 0, 0, 1, 2, 1)?;
     assert_eq!(res.dims(), [1, 2, 9, 9]);
     assert_eq!(
         test_utils::to_vec3_round(&res.i(0)?, 4)?,
@@ -283,13 +283,13 @@ fn conv2d_small(dev: &Device) -> Result<()> {
         ]
     );
 
-    let res = t.conv_transpose2d(&w.transpose(0, 1)?, 0, 0, 1, 1)?;
+    let res = t.conv_transpose2d(&w.transpose(0, 1)?, 0, 0, 1, 1, 1)?;
     assert_eq!(res.dims(), [1, 1, 3, 3]);
     assert_eq!(
         test_utils::to_vec1_round(&res.flatten_all()?, 4)?,
         [0.164, -0.0111, -0.1742, 2.6437, -2.0268, 1.1823, 3.2855, -1.0324, 0.2539],
     );
-    let res = t.transpose(0, 1)?.conv_transpose2d(&w, 0, 0, 1, 1)?;
+    let res = t.transpose(0, 1)?.conv_transpose2d(&w, 0, 0, 1, 1, 1)?;
     assert_eq!(res.dims(), [2, 2, 3, 3]);
     assert_eq!(
         test_utils::to_vec1_round(&res.flatten_all()?, 4)?,
@@ -659,7 +659,7 @@ fn conv2d_grad(dev: &Device) -> Result<()> {
         (4, 2, 3, 5),
         dev,
     )?;
-    let res = t.conv_transpose2d(&w, padding, outpadding, stride, dilation)?;
+    let res = t.conv_transpose2d(&w, padding, outpadding, stride, dilation, 1)?;
     let loss = res.sqr()?.sum_all()?;
     assert_eq!(test_utils::to_vec0_round(&loss, 0)?, 2904.0);
     let grads = loss.backward()?;
@@ -737,7 +737,7 @@ fn conv2d_grad(dev: &Device) -> Result<()> {
     let dilation = 1;
     let stride = 2;
 
-    let res = t.conv_transpose2d(&w, padding, outpadding, stride, dilation)?;
+    let res = t.conv_transpose2d(&w, padding, outpadding, stride, dilation, 1)?;
     let loss = res.sqr()?.sum_all()?;
     assert_eq!(test_utils::to_vec0_round(&loss, 0)?, 3627.0); // torch gives 3626.8560
 
@@ -830,6 +830,222 @@ fn conv2d_grad(dev: &Device) -> Result<()> {
     Ok(())
 }
 
+fn dot(a: &Tensor, b: &Tensor) -> Result<f32> {
+    Ok((a * b)?.sum_all()?.to_scalar::<f32>()?)
+}
+
+fn assert_close(a: f32, b: f32) {
+    assert!(
+        (a - b).abs() <= 1e-4 * a.abs().max(b.abs()).max(1.),
+        "{a} {b}"
+    );
+}
+
+// A naive implementation of conv3d on the cpu, used as a reference.
+fn conv3d_reference(
+    t: &Tensor,
+    w: &Tensor,
+    padding: usize,
+    stride: usize,
+    dilation: usize,
+    groups: usize,
+) -> Result<Tensor> {
+    let (b, c_in, d, h, w_) = t.dims5()?;
+    let (c_out, c_in_g, k_d, k_h, k_w) = w.dims5()?;
+    let t = t.flatten_all()?.to_vec1::<f32>()?;
+    let w = w.flatten_all()?.to_vec1::<f32>()?;
+    let out = |i: usize, k: usize| (i + 2 * padding - dilation * (k - 1) - 1) / stride + 1;
+    let (o_d, o_h, o_w) = (out(d, k_d), out(h, k_h), out(w_, k_w));
+    let c_out_g = c_out / groups;
+    let mut res = vec![0f32; b * c_out * o_d * o_h * o_w];
+    let mut idx = 0;
+    for b_idx in 0..b {
+        for co in 0..c_out {
+            for od in 0..o_d {
+                for oh in 0..o_h {
+                    for ow in 0..o_w {
+                        let mut acc = 0f32;
+                        for ci in 0..c_in_g {
+                            let c = (co / c_out_g) * c_in_g + ci;
+                            for kd in 0..k_d {
+                                for kh in 0..k_h {
+                                    for kw in 0..k_w {
+                                        let id = (od * stride + kd * dilation) as isize
+                                            - padding as isize;
+                                        let ih = (oh * stride + kh * dilation) as isize
+                                            - padding as isize;
+                                        let iw = (ow * stride + kw * dilation) as isize
+                                            - padding as isize;
+                                        if id < 0
+                                            || ih < 0
+                                            || iw < 0
+                                            || id >= d as isize
+                                            || ih >= h as isize
+                                            || iw >= w_ as isize
+                                        {
+                                            continue;
+                                        }
+                                        let (id, ih, iw) = (id as usize, ih as usize, iw as usize);
+                                        let t_idx =
+                                            (((b_idx * c_in + c) * d + id) * h + ih) * w_ + iw;
+                                        let w_idx =
+                                            (((co * c_in_g + ci) * k_d + kd) * k_h + kh) * k_w + kw;
+                                        acc += t[t_idx] * w[w_idx];
+                                    }
+                                }
+                            }
+                        }
+                        res[idx] = acc;
+                        idx += 1;
+                    }
+                }
+            }
+        }
+    }
+    Ok(Tensor::from_vec(
+        res,
+        (b, c_out, o_d, o_h, o_w),
+        &Device::Cpu,
+    )?)
+}
+
+fn conv3d(dev: &Device) -> Result<()> {
+    let t = Tensor::randn(0f32, 1., (2, 4, 5, 6, 5), &Device::Cpu)?;
+    let w = Tensor::randn(0f32, 1., (6, 2, 3, 2, 3), &Device::Cpu)?;
+    for (padding, stride, dilation) in [(0, 1, 1), (1, 2, 1), (1, 1, 2), (2, 3, 2)] {
+        let expected = conv3d_reference(&t, &w, padding, stride, dilation, 2)?;
+        let res = t
+            .to_device(dev)?
+            .conv3d(&w.to_device(dev)?, padding, stride, dilation, 2)?;
+        assert_eq!(res.dims(), expected.dims());
+        let diff = (res.to_device(&Device::Cpu)? - &expected)?
+            .abs()?
+            .flatten_all()?
+            .max(0)?;
+        assert!(diff.to_scalar::<f32>()? < 1e-4);
+    }
+    Ok(())
+}
+
+// The gradients of a convolution are checked using the adjoint identity: for a loss
+// `sum(conv(t, w) * g)`, the gradient with respect to `t` satisfies
+// `<grad_t, dt> = sum(conv(dt, w) * g)` and similarly for `w`.
+fn conv_grads_adjoint(dev: &Device) -> Result<()> {
+    use candle_core::Var;
+    type Conv = Box<dyn Fn(&Tensor, &Tensor) -> candle_core::Result<Tensor>>;
+    let convs: Vec<(Vec<usize>, Vec<usize>, Conv)> = vec![
+        (
+            vec![2, 4, 7],
+            vec![4, 3, 3],
+            Box::new(|t, w| t.conv_transpose1d(w, 1, 1, 2, 1, 1)),
+        ),
+        (
+            vec![2, 4, 7],
+            vec![4, 2, 3],
+            Box::new(|t, w| t.conv_transpose1d(w, 0, 0, 1, 2, 2)),
+        ),
+        (
+            vec![1, 4, 5, 6],
+            vec![4, 3, 3, 2],
+            Box::new(|t, w| t.conv_transpose2d(w, 1, 1, 2, 1, 2)),
+        ),
+        (
+            vec![1, 4, 6, 7],
+            vec![2, 4, 3, 3],
+            Box::new(|t, w| t.conv2d(w, 1, 2, 1, 1)),
+        ),
+        (
+            vec![2, 4, 4, 5, 4],
+            vec![6, 2, 2, 3, 2],
+            Box::new(|t, w| t.conv3d(w, 1, 2, 1, 2)),
+        ),
+    ];
+    for (t_shape, w_shape, conv) in convs.iter() {
+        let t = Var::randn(0f32, 1., t_shape.as_slice(), dev)?;
+        let w = Var::randn(0f32, 1., w_shape.as_slice(), dev)?;
+        let res = conv(&t, &w)?;
+        let g = Tensor::randn(0f32, 1., res.shape(), dev)?;
+        let grads = (res * &g)?.sum_all()?.backward()?;
+        let grad_t = grads.get(&t).unwrap();
+        let grad_w = grads.get(&w).unwrap();
+        assert_eq!(grad_t.dims(), t.dims());
+        assert_eq!(grad_w.dims(), w.dims());
+        let dt = Tensor::randn(0f32, 1., t.shape(), dev)?;
+        let dw = Tensor::randn(0f32, 1., w.shape(), dev)?;
+        assert_close(dot(grad_t, &dt)?, dot(&conv(&dt, &w)?, &g)?);
+        assert_close(dot(grad_w, &dw)?, dot(&conv(&t, &dw)?, &g)?);
+    }
+    Ok(())
+}
+
+fn conv_transpose2d_groups(dev: &Device) -> Result<()> {
+    let t = Tensor::randn(0f32, 1., (2, 4, 5, 5), dev)?;
+    let w = Tensor::randn(0f32, 1., (4, 3, 3, 3), dev)?;
+    let res = t.conv_transpose2d(&w, 1, 1, 2, 1, 2)?;
+    assert_eq!(res.dims(), [2, 6, 10, 10]);
+    for g in 0..2 {
+        let expected =
+            t.narrow(1, 2 * g, 2)?
+                .conv_transpose2d(&w.narrow(0, 2 * g, 2)?, 1, 1, 2, 1, 1)?;
+        let diff = (res.narrow(1, 3 * g, 3)? - expected)?
+            .abs()?
+            .flatten_all()?
+            .max(0)?;
+        assert!(diff.to_scalar::<f32>()? < 1e-5);
+    }
+    Ok(())
+}
+
+fn conv_non_contiguous_kernel(dev: &Device) -> Result<()> {
+    let t = Tensor::randn(0f32, 1., (2, 4, 7), dev)?;
+    let w = Tensor::randn(0f32, 1., (4, 6, 3), dev)?.transpose(0, 1)?;
+    assert!(!w.is_contiguous());
+    let res = t.conv1d(&w, 1, 1, 1, 1)?;
+    let expected = t.conv1d(&w.contiguous()?, 1, 1, 1, 1)?;
+    let diff = (res - expected)?.abs()?.flatten_all()?.max(0)?;
+    assert!(diff.to_scalar::<f32>()? < 1e-5);
+
+    let t = Tensor::randn(0f32, 1., (2, 4, 7, 6), dev)?;
+    let w = Tensor::randn(0f32, 1., (4, 4, 3, 6), dev)?.transpose(0, 3)?;
+    assert!(!w.is_contiguous());
+    let res = t.conv2d(&w, 1, 2, 1, 1)?;
+    let expected = t.conv2d(&w.contiguous()?, 1, 2, 1, 1)?;
+    let diff = (res - expected)?.abs()?.flatten_all()?.max(0)?;
+    assert!(diff.to_scalar::<f32>()? < 1e-5);
+    Ok(())
+}
+
+fn conv2d_channels_last(dev: &Device) -> Result<()> {
+    let t = Tensor::randn(0f32, 1., (2, 5, 7, 6), dev)?;
+    let t_cl = t.to_channels_last()?;
+    assert!(t_cl.is_channels_last() && !t.is_channels_last());
+    assert_eq!(t_cl.dims(), t.dims());
+    assert_eq!(t_cl.stride(), [210, 1, 30, 5]);
+    assert!(t_cl.to_channels_first()?.is_contiguous());
+    let w = Tensor::randn(0f32, 1., (4, 5, 3, 2), dev)?;
+    for (padding, stride, dilation) in [(0, 1, 1), (1, 2, 1), (2, 1, 2)] {
+        let res = t_cl.conv2d(&w, padding, stride, dilation, 1)?;
+        let expected = t.conv2d(&w, padding, stride, dilation, 1)?;
+        assert!(res.is_contiguous());
+        let diff = (res - expected)?.abs()?.flatten_all()?.max(0)?;
+        assert!(diff.to_scalar::<f32>()? < 1e-5);
+    }
+
+    // The gradients flow through the layout conversion.
+    let t = candle_core::Var::from_tensor(&t)?;
+    let loss = t
+        .to_channels_last()?
+        .conv2d(&w, 1, 1, 1, 1)?
+        .sqr()?
+        .sum_all()?;
+    let grad = loss.backward()?.remove(&t).unwrap();
+    let loss = t.conv2d(&w, 1, 1, 1, 1)?.sqr()?.sum_all()?;
+    let expected = loss.backward()?.remove(&t).unwrap();
+    let diff = (grad - expected)?.abs()?.flatten_all()?.max(0)?;
+    assert!(diff.to_scalar::<f32>()? < 1e-3);
+    Ok(())
+}
+
 test_device!(conv1d, conv1d_cpu, conv1d_gpu, conv1d_metal);
 test_device!(
     conv1d_small,
@@ -862,3 +1078,28 @@ test_device!(
     conv2d_grad_gpu,
     conv2_grad_metal
 );
+test_device!(conv3d, conv3d_cpu, conv3d_gpu, conv3d_metal);
+test_device!(
+    conv_grads_adjoint,
+    conv_grads_adjoint_cpu,
+    conv_grads_adjoint_gpu,
+    conv_grads_adjoint_metal
+);
+test_device!(
+    conv_transpose2d_groups,
+    conv_transpose2d_groups_cpu,
+    conv_transpose2d_groups_gpu,
+    conv_transpose2d_groups_metal
+);
+test_device!(
+    conv_non_contiguous_kernel,
+    conv_non_contiguous_kernel_cpu,
+    conv_non_contiguous_kernel_gpu,
+    conv_non_contiguous_kernel_metal
+);
+test_device!(
+    conv2d_channels_last,
+    conv2d_channels_last_cpu,
+    conv2d_channels_last_gpu,
+    conv2d_channels_last_metal
+);
@@ -113,6 +113,37 @@ fn custom_op1_with_backward() -> Result<()> {
     Ok(())
 }
 
+// The same op as EluWithBackward with a gradient that ignores alpha.
+struct EluWrongBackward(Elu);
+
+impl CustomOp1 for EluWrongBackward {
+    fn name(&self) -> &'static str {
+        "elu"
+    }
+
+    fn cpu_fwd(&self, s: &CpuStorage, l: &Layout) -> Result<(CpuStorage, Shape)> {
+        self.0.cpu_fwd(s, l)
+    }
+
+    fn bwd(&self, arg: &Tensor, _res: &Tensor, grad_res: &Tensor) -> Result<Option<Tensor>> {
+        let bwd = arg.apply_op1(EluBackward { alpha: 1. })?;
+        Ok(Some(grad_res.mul(&bwd)?))
+    }
+}
+
+#[test]
+fn custom_op1_gradcheck() -> Result<()> {
+    use candle_core::test_utils::gradcheck;
+    let t = Tensor::new(&[-2f64, -0.5, 0.7, 2.], &Device::Cpu)?;
+    gradcheck(|v| v[0].apply_op1(EluWithBackward::new(2.)), &[t.clone()])?;
+    assert!(gradcheck(
+        |v| v[0].apply_op1(EluWrongBackward(Elu { alpha: 2. })),
+        &[t]
+    )
+    .is_err());
+    Ok(())
+}
+
 impl candle_core::InplaceOp1 for Elu {
     fn name(&self) -> &'static str {
         "elu"
@@ -143,3 +174,58 @@ fn inplace_op1() -> Result<()> {
     );
     Ok(())
 }
+
+#[cfg(any(feature = "cuda", feature = "metal"))]
+#[test]
+fn custom_op_cpu_fallback() -> Result<()> {
+    use candle_core::fallback::{self, FallbackPolicy};
+    use candle_core::{CudaStorage, ErrorCode, MetalStorage};
+
+    // An op that fails on the gpus for a reason other than a missing implementation.
+    struct GpuFailure;
+
+    impl CustomOp1 for GpuFailure {
+        fn name(&self) -> &'static str {
+            "gpu-failure"
+        }
+
+        fn cpu_fwd(&self, s: &CpuStorage, l: &Layout) -> Result<(CpuStorage, Shape)> {
+            Ok((s.clone(), l.shape().clone()))
+        }
+
+        fn cuda_fwd(&self, _: &CudaStorage, _: &Layout) -> Result<(CudaStorage, Shape)> {
+            candle_core::bail!("out of memory")
+        }
+
+        fn metal_fwd(&self, _: &MetalStorage, _: &Layout) -> Result<(MetalStorage, Shape)> {
+            candle_core::bail!("out of memory")
+        }
+    }
+
+    let device = if cfg!(feature = "cuda") {
+        Device::new_cuda(0)?
+    } else {
+        Device::new_metal(0)?
+    };
+    let t = Tensor::arange(0u32, 12u32, &device)?.to_dtype(DType::F32)?;
+    let t = (t - 5.)?;
+    // Elu only has a cpu implementation.
+    let err = t.apply_op1_no_bwd(&Elu { alpha: 1. }).unwrap_err();
+    assert_eq!(err.code(), ErrorCode::UnsupportedOp);
+
+    fallback::reset_fallback_counts();
+    fallback::set_fallback_policy(FallbackPolicy::Cpu);
+    let elu_t = t.apply_op1_no_bwd(&Elu { alpha: 1. });
+    let failure = t.apply_op1_no_bwd(&GpuFailure);
+    fallback::set_fallback_policy(FallbackPolicy::Error);
+    let elu_t = elu_t?;
+    assert!(!elu_t.device().is_cpu());
+    assert_eq!(
+        to_vec1_round(&elu_t, 4)?,
+        &[-0.9933, -0.9817, -0.9502, -0.8647, -0.6321, 0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0]
+    );
+    // Only the unsupported ops fall back.
+    assert!(failure.is_err());
+    assert_eq!(fallback::fallback_counts(), [("elu", 1)]);
+    Ok(())
+}
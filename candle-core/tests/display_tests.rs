@@ -5,16 +5,16 @@ use candle_core::{DType, Device::Cpu, Tensor};
 fn display_scalar() -> Result<()> {
     let t = Tensor::new(1234u32, &Cpu)?;
     let s = format!("{t}");
-    assert_eq!(&s, "[1234]\nTensor[[], u32]");
+    assert_eq!(&s, "1234\nTensor[[], u32]");
     let t = t.to_dtype(DType::F32)?.neg()?;
     let s = format!("{}", (&t / 10.0)?);
-    assert_eq!(&s, "[-123.4000]\nTensor[[], f32]");
+    assert_eq!(&s, "-123.4000\nTensor[[], f32]");
     let s = format!("{}", (&t / 1e8)?);
-    assert_eq!(&s, "[-1.2340e-5]\nTensor[[], f32]");
+    assert_eq!(&s, "-1.2340e-5\nTensor[[], f32]");
     let s = format!("{}", (&t * 1e8)?);
-    assert_eq!(&s, "[-1.2340e11]\nTensor[[], f32]");
+    assert_eq!(&s, "-1.2340e11\nTensor[[], f32]");
     let s = format!("{}", (&t * 0.)?);
-    assert_eq!(&s, "[0.]\nTensor[[], f32]");
+    assert_eq!(&s, "0.\nTensor[[], f32]");
     Ok(())
 }
 
@@ -82,3 +82,52 @@ Tensor[[2, 1, 1, 100, 100], f32]"#;
     assert_eq!(&t, expected);
     Ok(())
 }
+
+#[test]
+fn display_with_options() -> Result<()> {
+    use candle_core::display::PrinterOptions;
+    let t = Tensor::arange(0f32, 100., &Cpu)?.affine(0.5, 0.)?;
+    let po = PrinterOptions {
+        precision: 1,
+        threshold: 10,
+        edge_items: 2,
+        ..Default::default()
+    };
+    assert_eq!(
+        format!("{}", t.display_with(&po)),
+        "[ 0.0,  0.5, ..., 49.0, 49.5]\nTensor[[100], f32]"
+    );
+    let po = PrinterOptions {
+        sci_mode: Some(true),
+        ..po
+    };
+    assert_eq!(
+        format!("{}", t.narrow(0, 1, 2)?.display_with(&po)),
+        "[5.0e-1,  1.0e0]\nTensor[[2], f32]"
+    );
+    Ok(())
+}
+
+#[test]
+fn summary() -> Result<()> {
+    let t = Tensor::new(&[[1f32, 2., 3.], [4., 5., 9.]], &Cpu)?;
+    let s = t.summary()?;
+    assert_eq!((s.min, s.max, s.mean), (1., 9., 4.));
+    // The population std of [1, 2, 3, 4, 5, 9].
+    assert!((s.std - (40f64 / 6.).sqrt()).abs() < 1e-6);
+    assert_eq!((s.nan_count, s.inf_count), (0, 0));
+    assert_eq!(
+        s.to_string(),
+        "Tensor[[2, 3], f32] min=1.0000e0 max=9.0000e0 mean=4.0000e0 std=2.5820e0 nan=0 inf=0"
+    );
+
+    let t = Tensor::new(&[f64::NAN, f64::NEG_INFINITY, -2.], &Cpu)?;
+    let s = t.summary()?;
+    assert_eq!((s.min, s.max, s.mean, s.std), (-2., -2., -2., 0.));
+    assert_eq!((s.nan_count, s.inf_count, s.dtype), (1, 1, DType::F64));
+    let s = Tensor::new(&[f32::NAN], &Cpu)?.summary()?;
+    assert!(s.min.is_nan() && s.mean.is_nan());
+    let s = Tensor::new(&[3u8, 7], &Cpu)?.summary()?;
+    assert_eq!((s.mean, s.std), (5., 2.));
+    Ok(())
+}
@@ -0,0 +1,109 @@
+use candle_core::dlpack::{DLDataType, DLDevice, DLManagedTensor, DLPackTensor, DLTensor};
+use candle_core::{DType, Device, IndexOp, Result, Tensor};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[test]
+fn dlpack_export() -> Result<()> {
+    let t = Tensor::arange(0f32, 12., &Device::Cpu)?.reshape((3, 4))?;
+    let view = t.t()?.i(1..)?;
+    let exported = view.to_dlpack()?;
+    assert_eq!(exported.dims(), [3, 3]);
+    assert_eq!(exported.strides(), [1, 4]);
+    let dl = exported.dl_tensor();
+    assert_eq!(dl.dtype, DLDataType::from(DType::F32));
+    assert_eq!(dl.byte_offset, 4);
+    // The memory is shared with the tensor.
+    unsafe { *(dl.data as *mut f32).add(5) = -1. };
+    assert_eq!(t.to_vec2::<f32>()?[1][1], -1.);
+
+    // Round trip, the import shares the strided data.
+    let data = dl.data as *mut f32;
+    let imported = Tensor::from_dlpack(exported, &Device::Cpu)?;
+    assert_eq!(imported.stride(), [1, 4]);
+    assert_eq!(imported.to_vec2::<f32>()?, view.to_vec2::<f32>()?);
+    unsafe { *data.add(10) = -2. };
+    assert_eq!(imported.to_vec2::<f32>()?[1][2], -2.);
+
+    // The exported tensor outlives the original one.
+    let exported = Tensor::new(&[1u32, 2, 3], &Device::Cpu)?.to_dlpack()?;
+    let raw = exported.into_raw();
+    let exported = unsafe { DLPackTensor::from_raw(raw)? };
+    let imported = Tensor::from_dlpack(exported, &Device::Cpu)?;
+    assert_eq!(imported.to_vec1::<u32>()?, [1, 2, 3]);
+    let scalar = Tensor::from_dlpack(
+        Tensor::new(2.5f64, &Device::Cpu)?.to_dlpack()?,
+        &Device::Cpu,
+    )?;
+    assert_eq!(scalar.to_vec0::<f64>()?, 2.5);
+    Ok(())
+}
+
+static DELETED: AtomicUsize = AtomicUsize::new(0);
+
+struct Producer {
+    data: Vec<i64>,
+    shape: Vec<i64>,
+    strides: Vec<i64>,
+}
+
+unsafe extern "C" fn deleter(managed: *mut DLManagedTensor) {
+    let managed = Box::from_raw(managed);
+    drop(Box::from_raw(managed.manager_ctx as *mut Producer));
+    DELETED.fetch_add(1, Ordering::SeqCst);
+}
+
+#[test]
+fn dlpack_import() -> Result<()> {
+    // A (2, 3) tensor in column-major order, as produced by e.g. `torch.randn(3, 2).t()`.
+    let mut producer = Box::new(Producer {
+        data: vec![0, 3, 1, 4, 2, 5],
+        shape: vec![2, 3],
+        strides: vec![1, 2],
+    });
+    let dl_tensor = DLTensor {
+        data: producer.data.as_mut_ptr().cast(),
+        device: DLDevice {
+            device_type: candle_core::dlpack::DL_CPU,
+            device_id: 0,
+        },
+        ndim: 2,
+        dtype: DLDataType::from(DType::I64),
+        shape: producer.shape.as_mut_ptr(),
+        strides: producer.strides.as_mut_ptr(),
+        byte_offset: 0,
+    };
+    let managed = Box::new(DLManagedTensor {
+        dl_tensor,
+        manager_ctx: Box::into_raw(producer).cast(),
+        deleter: Some(deleter),
+    });
+    let managed = unsafe { DLPackTensor::from_raw(Box::into_raw(managed))? };
+    let t = Tensor::from_dlpack(managed, &Device::Cpu)?;
+    assert_eq!(t.to_vec2::<i64>()?, [[0, 1, 2], [3, 4, 5]]);
+    // The producer's buffer is released once the tensor and its views have been dropped.
+    let row = t.i(1)?;
+    drop(t);
+    assert_eq!(DELETED.load(Ordering::SeqCst), 0);
+    assert_eq!(row.to_vec1::<i64>()?, [3, 4, 5]);
+    assert_eq!(row.sum_all()?.to_vec0::<i64>()?, 12);
+    drop(row);
+    assert_eq!(DELETED.load(Ordering::SeqCst), 1);
+    Ok(())
+}
+
+#[cfg(feature = "cuda")]
+#[test]
+fn dlpack_cuda() -> Result<()> {
+    let dev = &Device::new_cuda(0)?;
+    let t = Tensor::arange(0f32, 6., dev)?.reshape((2, 3))?;
+    assert!(t.to_dlpack_with_stream(Some(0)).is_err());
+    let exported = t.t()?.to_dlpack_with_stream(Some(2))?;
+    let imported = Tensor::from_dlpack(exported, dev)?;
+    assert_eq!(imported.stride(), [1, 3]);
+    assert_eq!(imported.to_vec2::<f32>()?, [[0., 3.], [1., 4.], [2., 5.]]);
+    drop(t);
+    // The imported tensor keeps the exported one alive.
+    assert_eq!(imported.sum_all()?.to_vec0::<f32>()?, 15.);
+    assert!(Tensor::from_dlpack(imported.to_dlpack()?, &Device::Cpu).is_err());
+    Ok(())
+}
@@ -0,0 +1,97 @@
+use candle_core::shape_trace::ShapeTracer;
+use candle_core::{DType, Device, Error, ErrorCode, Result, Tensor};
+
+#[test]
+fn typed_errors() -> Result<()> {
+    let cpu = Device::Cpu;
+    let x = Tensor::zeros((2, 3), DType::F32, &cpu)?;
+    let y = Tensor::zeros((2, 3), DType::F32, &cpu)?;
+    match x.matmul(&y).map_err(|e| e.code()) {
+        Err(ErrorCode::ShapeMismatch) => {}
+        res => panic!("unexpected {res:?}"),
+    }
+    let err = x
+        .broadcast_add(&Tensor::zeros(4, DType::F32, &cpu)?)
+        .unwrap_err();
+    assert_eq!(err.code(), ErrorCode::ShapeMismatch);
+
+    let err = x.add(&y.to_dtype(DType::F64)?).unwrap_err();
+    assert_eq!(err.code(), ErrorCode::DTypeMismatch);
+    assert_eq!(err.code().as_str(), "dtype_mismatch");
+    assert!(err.to_string().contains("lhs: F32, rhs: F64"));
+
+    let meta = ShapeTracer::new().device().clone();
+    let err = x.add(&y.to_device(&meta)?).unwrap_err();
+    assert_eq!(err.code(), ErrorCode::DeviceMismatch);
+    Ok(())
+}
+
+#[test]
+fn unsupported_op() -> Result<()> {
+    let meta = ShapeTracer::new().device().clone();
+    let x = Tensor::zeros((2, 3), DType::F32, &meta)?;
+    let err = x.to_vec2::<f32>().unwrap_err();
+    assert_eq!(err.code(), ErrorCode::UnsupportedOp);
+    match &err {
+        Error::UnsupportedOp { backend, op, .. } => {
+            assert_eq!((*backend, *op), ("meta", "to-cpu"))
+        }
+        Error::WithBacktrace { inner, .. } => {
+            assert!(matches!(
+                **inner,
+                Error::UnsupportedOp {
+                    backend: "meta",
+                    ..
+                }
+            ))
+        }
+        err => panic!("unexpected {err:?}"),
+    }
+    // The kernels missing for some dtypes report the dtype.
+    let err = Error::UnsupportedOp {
+        backend: "metal",
+        op: "affine",
+        dtype: Some(DType::I64),
+    };
+    assert_eq!(err.to_string(), "affine is not supported on metal for I64");
+    assert_eq!(err.code(), ErrorCode::UnsupportedOp);
+    Ok(())
+}
+
+#[test]
+fn code_through_wrappers() {
+    let err = Error::Msg("boom".to_string()).with_path("model.safetensors");
+    assert_eq!(err.code(), ErrorCode::Other);
+    let err = Error::CannotFindTensor {
+        path: "w".to_string(),
+    }
+    .bt()
+    .with_path("model.safetensors");
+    assert_eq!(err.code(), ErrorCode::NotFound);
+    assert_eq!(err.code().to_string(), "not_found");
+}
+
+#[test]
+fn context_stack() -> Result<()> {
+    use candle_core::ResultExt;
+    let x = Tensor::zeros((2, 3), DType::F32, &Device::Cpu)?;
+    let w = Tensor::zeros((4, 5), DType::F32, &Device::Cpu)?;
+    let err = x
+        .matmul(&w)
+        .op_context("linear", &[&x, &w])
+        .module_context("model.layers.3.mlp")
+        .unwrap_err()
+        .with_path("model.safetensors");
+    assert_eq!(err.code(), ErrorCode::ShapeMismatch);
+    let context = err.context();
+    assert_eq!(context.len(), 2);
+    assert_eq!(context[0].op, Some("linear"));
+    assert_eq!(context[0].shapes[1].dims(), [4, 5]);
+    assert_eq!(context[1].module.as_deref(), Some("model.layers.3.mlp"));
+    let msg = err.to_string();
+    assert!(msg.contains("in linear([2, 3], [4, 5])\n  in model.layers.3.mlp"));
+
+    let err = Error::Msg("boom".to_string());
+    assert!(err.context().is_empty());
+    Ok(())
+}
@@ -511,7 +511,27 @@ test_device!(
     simple_grad_gpu,
     simple_grad_metal
 );
+fn narrow_step_grad(device: &Device) -> Result<()> {
+    let x = Var::new(&[[1f32, 2., 3., 4., 5.], [6., 7., 8., 9., 10.]], device)?;
+    let y = x.narrow_step(1, 0, 3, 2)?;
+    // The first column of the flipped tensor is the last column of x.
+    let z = x.slice_step(1, None, None, -1)?.narrow(1, 0, 1)?;
+    let grads = (y.sqr()?.sum_all()? + z.sum_all()?)?.backward()?;
+    let grad_x = grads.get(&x).context("no grad for x")?;
+    assert_eq!(
+        grad_x.to_vec2::<f32>()?,
+        [[2., 0., 6., 0., 11.], [12., 0., 16., 0., 21.]]
+    );
+    Ok(())
+}
+
 test_device!(sum_grad, sum_grad_cpu, sum_grad_gpu, sum_grad_metal);
+test_device!(
+    narrow_step_grad,
+    narrow_step_grad_cpu,
+    narrow_step_grad_gpu,
+    narrow_step_grad_metal
+);
 test_device!(
     matmul_grad,
     matmul_grad_cpu,
@@ -531,3 +551,78 @@ test_device!(
     binary_grad_gpu,
     binary_grad_metal
 );
+
+#[test]
+fn gradcheck() -> Result<()> {
+    use test_utils::gradcheck;
+    let dev = &Device::Cpu;
+    let xs = Tensor::new(&[[0.5f64, -1.2, 2.], [0.3, 1.1, -0.7]], dev)?;
+    let ws = Tensor::new(&[[0.2f64, -0.4], [1.3, 0.1], [-0.6, 0.9]], dev)?;
+    gradcheck(|v| v[0].matmul(&v[1])?.tanh(), &[xs.clone(), ws])?;
+    gradcheck(
+        |v| {
+            let m = v[0].max_keepdim(1)?;
+            v[0].broadcast_sub(&m)?
+                .exp()?
+                .sum_keepdim(1)?
+                .log()?
+                .broadcast_add(&m)
+        },
+        &[xs.clone()],
+    )?;
+    // The integer inputs are passed through.
+    let ids = Tensor::new(&[2u32, 0, 2], dev)?;
+    gradcheck(|v| v[0].index_select(&v[1], 1)?.sqr(), &[xs.clone(), ids])?;
+    gradcheck(
+        |v| v[0].sin()?.sum_all(),
+        &[xs.to_dtype(candle_core::DType::F32)?],
+    )?;
+    // A wrong gradient is reported.
+    let err = gradcheck(|v| v[0].detach().sqr()?.add(&v[0]), &[xs]).unwrap_err();
+    assert!(
+        err.to_string().contains("gradient mismatch for input 0"),
+        "{err}"
+    );
+    Ok(())
+}
+
+fn log_of_relu(x: &Tensor) -> candle_core::Result<Tensor> {
+    x.relu()?.log()
+}
+
+#[test]
+fn anomaly_mode() -> Result<()> {
+    use candle_core::backprop::{is_anomaly_enabled, DetectAnomaly};
+    let x = Var::new(&[-1f32, 2., 3.], &Device::Cpu)?;
+    let y = (log_of_relu(&x)? * 2.)?.sum_all()?;
+    // The NaN gradient goes unnoticed outside of the anomaly mode.
+    let grads = y.backward()?;
+    assert!(grads.get(&x).context("no grad for x")?.to_vec1::<f32>()?[0].is_nan());
+
+    {
+        let _guard = DetectAnomaly::new();
+        assert!(is_anomaly_enabled());
+        let y = (log_of_relu(&x)? * 2.)?.sum_all()?;
+        match y.backward() {
+            Err(candle_core::Error::AnomalousGradient { op, trace }) => {
+                assert_eq!(op, "log");
+                assert!(trace.contains("log_of_relu"), "{trace}");
+            }
+            res => panic!("unexpected result {res:?}"),
+        }
+        // Finite gradients are not reported.
+        let y = x.sqr()?.sum_all()?;
+        assert_eq!(
+            y.backward()?.get(&x).context("no grad")?.to_vec1::<f32>()?,
+            [-2., 4., 6.]
+        );
+        // A node updating the same gradient twice, with the NaN coming from another branch.
+        let y = ((&x * &x)? + log_of_relu(&x)?)?.sum_all()?;
+        match y.backward() {
+            Err(candle_core::Error::AnomalousGradient { op, .. }) => assert_eq!(op, "log"),
+            res => panic!("unexpected result {res:?}"),
+        }
+    }
+    assert!(!is_anomaly_enabled());
+    Ok(())
+}
@@ -120,3 +120,43 @@ fn slice_assign() -> Result<()> {
     );
     Ok(())
 }
+
+#[test]
+fn step_slices() -> Result<()> {
+    use candle_core::Slice;
+    let dev = Device::Cpu;
+
+    let tensor = Tensor::arange(0u32, 4 * 5, &dev)?.reshape((4, 5))?;
+    let view = tensor.narrow_step(1, 1, 2, 2)?;
+    assert_eq!(view.dims(), &[4, 2]);
+    assert_eq!(view.stride(), &[5, 2]);
+    assert_eq!(
+        view.to_vec2::<u32>()?,
+        &[[1, 3], [6, 8], [11, 13], [16, 18]]
+    );
+    // Ops work on the strided view directly.
+    let sum = (&view + &view)?.sum(1)?;
+    assert_eq!(sum.to_vec1::<u32>()?, &[8, 28, 48, 68]);
+    assert!(tensor.narrow_step(1, 1, 3, 2).is_err());
+    assert_eq!(tensor.narrow_step(0, 4, 0, 3)?.dims(), &[0, 5]);
+
+    // tensor[::-2, 3:0:-1]
+    let result = tensor.i((Slice::step(-2), Slice::new(Some(3), Some(0), -1)))?;
+    assert_eq!(result.to_vec2::<u32>()?, &[[18, 17, 16], [8, 7, 6]]);
+    // tensor[-3:, ::3]
+    let result = tensor.i((Slice::new(Some(-3), None, 1), Slice::step(3)))?;
+    assert_eq!(result.to_vec2::<u32>()?, &[[5, 8], [10, 13], [15, 18]]);
+    // Out of range bounds are clamped, empty slices are allowed.
+    let result = tensor.slice_step(1, Some(-100), Some(100), 4)?;
+    assert_eq!(
+        result.to_vec2::<u32>()?,
+        &[[0, 4], [5, 9], [10, 14], [15, 19]]
+    );
+    assert_eq!(tensor.slice_step(0, Some(2), Some(1), 1)?.dims(), &[0, 5]);
+    assert_eq!(tensor.slice_step(0, Some(-5), None, -1)?.dims(), &[0, 5]);
+    assert!(tensor.slice_step(0, None, None, 0).is_err());
+
+    let flipped = tensor.flip((0, 1))?;
+    assert_eq!(flipped.i(0)?.to_vec1::<u32>()?, &[19, 18, 17, 16, 15]);
+    Ok(())
+}
@@ -0,0 +1,68 @@
+use candle_core::op_manifest::{self, ManifestRecorder, OpManifest};
+use candle_core::{DType, Device, Result, Tensor};
+
+fn forward(xs: &Tensor) -> Result<Tensor> {
+    let ys = xs.matmul(&xs.t()?)?.exp()?;
+    (ys + 1.)?.sum_keepdim(1)
+}
+
+// The recorder state is global so everything runs in a single test.
+#[test]
+fn op_manifest() -> Result<()> {
+    let dev = &Device::Cpu;
+    let xs = Tensor::new(&[[1f32, 2., 3.], [4., 5., 6.]], dev)?;
+    let recorder = ManifestRecorder::new()?;
+    assert!(ManifestRecorder::new().is_err());
+    assert!(op_manifest::is_recording());
+    let ys = forward(&xs)?;
+    let manifest = recorder.finish();
+    assert!(!op_manifest::is_recording());
+
+    let ops = manifest
+        .ops
+        .iter()
+        .map(|r| r.op.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(ops, ["matmul", "exp", "affine", "reduce"]);
+    let last = manifest.ops.last().unwrap();
+    assert_eq!(
+        (last.dtype, last.shape.as_slice()),
+        (DType::F32, [2, 1].as_slice())
+    );
+    assert_eq!(last.checksum, Some(ys.fingerprint()?));
+
+    // Running the same model again gives the same manifest.
+    let (_, again) = OpManifest::record(|| forward(&xs))?;
+    let diff = again.compare(&manifest);
+    assert!(diff.is_identical(), "{diff}");
+
+    // Round trip through the text format.
+    let parsed = OpManifest::parse(&manifest.to_string())?;
+    assert_eq!(parsed, manifest);
+    let tmp = std::env::temp_dir().join(format!("candle-op-manifest-{}", std::process::id()));
+    manifest.save(&tmp)?;
+    assert_eq!(OpManifest::load(&tmp)?, manifest);
+    std::fs::remove_file(&tmp)?;
+
+    // Changing an input value changes the checksums but not the structure.
+    let xs2 = Tensor::new(&[[1f32, 2., 3.], [4., 5., 7.]], dev)?;
+    let (_, other) = OpManifest::record(|| forward(&xs2))?;
+    let diff = other.compare(&manifest);
+    assert_eq!(diff.diffs.len(), 4);
+    assert_eq!(diff.first().unwrap().index, 0);
+    assert!(!diff.first().unwrap().is_structural());
+
+    // A different graph is reported as a structural difference.
+    let (_, other) = OpManifest::record(|| xs.matmul(&xs.t()?)?.sqr())?;
+    let diff = other.compare(&manifest);
+    assert_eq!(
+        diff.diffs.iter().map(|d| d.index).collect::<Vec<_>>(),
+        [1, 2, 3]
+    );
+    assert!(diff.diffs.iter().all(|d| d.is_structural()));
+    assert!(diff.diffs[1].got.is_none());
+    assert!(diff.to_string().contains("expected exp f32[2, 2]"));
+
+    assert!(OpManifest::parse("not a manifest").is_err());
+    Ok(())
+}
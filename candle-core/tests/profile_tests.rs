@@ -0,0 +1,59 @@
+use candle_core::profile::{self, EventKind, Profiler};
+use candle_core::{Device, Result, Tensor};
+
+// The profiler state is global so everything runs in a single test.
+#[test]
+fn profile() -> Result<()> {
+    let dev = &Device::Cpu;
+    // The cpu ops use the wall time when cuda events are requested.
+    let profiler = Profiler::new()?.cuda_events(true);
+    assert!(Profiler::new().is_err());
+    let xs = Tensor::new(&[[1f32, 2., 3.], [4., 5., 6.]], dev)?;
+    {
+        let _scope = profile::scope("layer");
+        let ys = xs.matmul(&xs.t()?)?.exp()?;
+        let _ys = (ys + 1.)?;
+    }
+    let _zs = xs.sqr()?;
+    let profile = profiler.finish();
+    assert!(!profile::is_profiling());
+
+    let ops = profile.op_stats();
+    let names = |stats: &[profile::EventStats]| {
+        let mut names = stats.iter().map(|s| s.name.clone()).collect::<Vec<_>>();
+        names.sort();
+        names
+    };
+    assert_eq!(names(&ops), ["affine", "exp", "matmul", "sqr"]);
+    let matmul = ops.iter().find(|s| s.name == "matmul").unwrap();
+    assert_eq!((matmul.count, matmul.bytes), (1, 2 * 2 * 4));
+    let scopes = profile.scope_stats();
+    assert_eq!(names(&scopes), ["layer"]);
+    // The ops of the scope are included in its duration.
+    let ops_in_scope = ops
+        .iter()
+        .filter(|s| s.name != "sqr")
+        .map(|s| s.total)
+        .sum::<std::time::Duration>();
+    assert!(scopes[0].total >= ops_in_scope);
+    // The input tensor is an allocation outside of any op.
+    let allocs = profile
+        .events
+        .iter()
+        .filter(|e| e.kind == EventKind::Alloc)
+        .map(|e| e.bytes)
+        .collect::<Vec<_>>();
+    assert_eq!(allocs, [6 * 4]);
+    assert_eq!(profile.total_bytes(), 6 * 4 + 4 * 4 * 3 + 6 * 4);
+
+    let trace = profile.to_chrome_trace();
+    assert!(trace.starts_with("{\"traceEvents\":["));
+    assert!(trace.contains("\"name\":\"matmul\",\"cat\":\"op\",\"ph\":\"X\""));
+    assert!(trace.contains("\"name\":\"layer\",\"cat\":\"scope\""));
+
+    // Nothing is recorded once the profiler is finished.
+    let _ys = xs.exp()?;
+    let profiler = Profiler::new()?;
+    assert!(profiler.finish().events.is_empty());
+    Ok(())
+}
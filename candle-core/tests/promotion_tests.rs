@@ -0,0 +1,70 @@
+use candle_core::promotion::{
+    op_promotion_policy, promote_types, set_op_promotion_policy, set_promotion_policy,
+    PromotionPolicy,
+};
+use candle_core::{DType, Device, Result, Tensor};
+
+#[test]
+fn promotion_rules() {
+    use DType::*;
+    let cases = [
+        (F16, F16, F16),
+        (F16, F32, F32),
+        (BF16, F16, F32),
+        (F64, BF16, F64),
+        (I64, F32, F32),
+        (U8, BF16, BF16),
+        (U8, U32, U32),
+        (I64, U32, I64),
+    ];
+    for (lhs, rhs, expected) in cases {
+        assert_eq!(promote_types(lhs, rhs), expected, "{lhs:?} {rhs:?}");
+        assert_eq!(promote_types(rhs, lhs), expected, "{rhs:?} {lhs:?}");
+    }
+}
+
+// The policies are global so they are all checked in a single test.
+#[test]
+fn promotion_policies() -> Result<()> {
+    let dev = &Device::Cpu;
+    let xs = Tensor::new(&[1f32, 2., 3.], dev)?.to_dtype(DType::F16)?;
+    let ys = Tensor::new(&[2f32, 2., 2.], dev)?;
+    let ids = Tensor::new(&[3i64, 1, 2], dev)?;
+    assert_eq!(op_promotion_policy("add"), PromotionPolicy::Error);
+    let err = xs.add(&ys).unwrap_err();
+    assert!(err.to_string().contains("dtype mismatch in add"), "{err}");
+    assert!(xs.broadcast_mul(&ys).is_err());
+
+    set_op_promotion_policy("mul", Some(PromotionPolicy::Promote));
+    let zs = xs.broadcast_mul(&ys.reshape((1, 3))?)?;
+    assert_eq!(zs.dtype(), DType::F32);
+    assert_eq!(zs.to_vec2::<f32>()?, [[2., 4., 6.]]);
+    assert!(xs.add(&ys).is_err());
+
+    set_promotion_policy(PromotionPolicy::Promote);
+    assert_eq!(
+        (&xs + &ids)?.to_vec1::<half::f16>()?,
+        [4., 3., 5.].map(half::f16::from_f32)
+    );
+    assert_eq!(ids.maximum(&ys)?.to_vec1::<f32>()?, [3., 2., 2.]);
+    assert_eq!(ids.gt(&xs)?.to_vec1::<u8>()?, [1, 0, 0]);
+    let mask = Tensor::new(&[1u8, 0, 1], dev)?;
+    assert_eq!(mask.where_cond(&ids, &ys)?.to_vec1::<f32>()?, [3., 2., 2.]);
+    let m = Tensor::new(&[[1u32, 2]], dev)?;
+    let w = Tensor::new(&[[0.5f32], [1.]], dev)?;
+    assert_eq!(m.matmul(&w)?.to_vec2::<f32>()?, [[2.5]]);
+    // The gradients flow back to the original dtypes.
+    let v = candle_core::Var::new(&[1f32, 2., 3.], dev)?;
+    let grads = v.as_tensor().mul(&xs)?.sum_all()?.backward()?;
+    assert_eq!(grads.get(&v).unwrap().to_vec1::<f32>()?, [1., 2., 3.]);
+
+    // A per-op policy overrides the global one.
+    set_op_promotion_policy("sub", Some(PromotionPolicy::Error));
+    assert!(xs.sub(&ys).is_err());
+    set_op_promotion_policy("sub", None);
+    assert!(xs.sub(&ys).is_ok());
+    set_op_promotion_policy("mul", None);
+    set_promotion_policy(PromotionPolicy::Error);
+    assert!(xs.mul(&ys).is_err());
+    Ok(())
+}
@@ -93,6 +93,7 @@ fn quantized_matmul(device: &Device) -> Result<()> {
                 [342030.0, 994630.0, 1656248.0, 2302250.0]
             ]
         ),
+        Device::Meta(_) => unreachable!(),
         Device::Cpu => assert_eq!(
             to_vec2_round(&res, 0)?,
             &[
@@ -157,6 +158,7 @@ fn quantized_matmul_neg(device: &Device) -> Result<()> {
                 [-196045.0, 63030.0, 324120.0, 587079.0]
             ]
         ),
+        Device::Meta(_) => unreachable!(),
         Device::Cpu => assert_eq!(
             to_vec2_round(&res, 0)?,
             &[
@@ -49,6 +49,120 @@ fn npz() -> Result<()> {
     Ok(())
 }
 
+// A npy file as written by numpy, `shape` is a python tuple.
+fn npy_bytes(descr: &str, fortran_order: bool, shape: &str, data: &[u8]) -> Vec<u8> {
+    let fortran_order = if fortran_order { "True" } else { "False" };
+    let header =
+        format!("{{'descr': '{descr}', 'fortran_order': {fortran_order}, 'shape': {shape}, }}\n");
+    let mut bytes = b"\x93NUMPY\x01\x00".to_vec();
+    bytes.extend_from_slice(&(header.len() as u16).to_le_bytes());
+    bytes.extend_from_slice(header.as_bytes());
+    bytes.extend_from_slice(data);
+    bytes
+}
+
+#[test]
+fn npy_round_trip() -> Result<()> {
+    let cpu = candle_core::Device::Cpu;
+    let tmp_file = TmpFile::create("npy_round_trip");
+    let t = (Tensor::arange(0f32, 6f32, &cpu)? - 2.)?.reshape((2, 3))?;
+    for dtype in [
+        DType::U8,
+        DType::U32,
+        DType::I64,
+        DType::BF16,
+        DType::F16,
+        DType::F32,
+        DType::F64,
+    ] {
+        let t = t.abs()?.to_dtype(dtype)?;
+        t.write_npy(&tmp_file)?;
+        let loaded = Tensor::read_npy(&tmp_file)?;
+        // bf16 tensors are written as f32.
+        let expected_dtype = if dtype == DType::BF16 {
+            DType::F32
+        } else {
+            dtype
+        };
+        assert_eq!(loaded.dtype(), expected_dtype);
+        assert_eq!(
+            loaded.to_dtype(DType::F64)?.to_vec2::<f64>()?,
+            t.to_dtype(DType::F64)?.to_vec2::<f64>()?
+        );
+    }
+    // The transposed tensors are written in c order.
+    let t = t.t()?;
+    Tensor::write_npz(&[("t", &t), ("s", &Tensor::new(3u32, &cpu)?)], &tmp_file)?;
+    let npz = Tensor::read_npz(&tmp_file)?;
+    assert_eq!(npz[0].1.to_vec2::<f32>()?, t.to_vec2::<f32>()?);
+    assert_eq!(npz[1].1.to_vec0::<u32>()?, 3);
+    Ok(())
+}
+
+#[test]
+fn npy_layouts() -> Result<()> {
+    let tmp_file = TmpFile::create("npy_layouts");
+    let read = |bytes: Vec<u8>| {
+        std::fs::write(&tmp_file, bytes)?;
+        Tensor::read_npy(&tmp_file)
+    };
+    // np.arange(6, dtype=np.float32).reshape(2, 3) in fortran order.
+    let data = [0f32, 3., 1., 4., 2., 5.]
+        .iter()
+        .flat_map(|v| v.to_le_bytes())
+        .collect::<Vec<_>>();
+    let t = read(npy_bytes("<f4", true, "(2, 3)", &data))?;
+    assert_eq!(t.to_vec2::<f32>()?, [[0., 1., 2.], [3., 4., 5.]]);
+    assert!(t.is_contiguous());
+
+    let data = [1.5f64, -2.]
+        .iter()
+        .flat_map(|v| v.to_be_bytes())
+        .collect::<Vec<_>>();
+    let t = read(npy_bytes(">f8", false, "(2,)", &data))?;
+    assert_eq!(t.to_vec1::<f64>()?, [1.5, -2.]);
+
+    // The integer types without a candle equivalent are converted.
+    let t = read(npy_bytes(
+        "<i4",
+        false,
+        "(2,)",
+        &[0xfe, 0xff, 0xff, 0xff, 7, 0, 0, 0],
+    ))?;
+    assert_eq!(t.to_vec1::<i64>()?, [-2, 7]);
+    let t = read(npy_bytes(">i2", false, "(2,)", &[0xff, 0xfd, 0x01, 0x00]))?;
+    assert_eq!(t.to_vec1::<i64>()?, [-3, 256]);
+    let t = read(npy_bytes("|i1", false, "(2, 1)", &[0x80, 0x7f]))?;
+    assert_eq!(t.to_vec2::<i64>()?, [[-128], [127]]);
+    let t = read(npy_bytes("<u2", false, "()", &[0xff, 0xff]))?;
+    assert_eq!(t.to_vec0::<u32>()?, 65535);
+    let t = read(npy_bytes("<u8", false, "(1,)", &[0xff; 8]));
+    assert!(t.is_err());
+    Ok(())
+}
+
+#[test]
+fn npz_compressed() -> Result<()> {
+    use std::io::Write;
+    let tmp_file = TmpFile::create("npz_compressed");
+    // np.savez_compressed uses deflate.
+    let mut zip = zip::ZipWriter::new(std::fs::File::create(&tmp_file)?);
+    let options: zip::write::FileOptions<()> =
+        zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+    zip.start_file("x.npy", options)?;
+    zip.write_all(&npy_bytes(
+        "<u4",
+        false,
+        "(3,)",
+        &[1, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0],
+    ))?;
+    zip.finish()?;
+    let npz = Tensor::read_npz(&tmp_file)?;
+    assert_eq!(npz[0].0, "x");
+    assert_eq!(npz[0].1.to_vec1::<u32>()?, [1, 2, 3]);
+    Ok(())
+}
+
 #[test]
 fn safetensors() -> Result<()> {
     use candle_core::safetensors::Load;
@@ -69,3 +183,205 @@ fn safetensors() -> Result<()> {
     assert_eq!(diff, 0f32);
     Ok(())
 }
+
+#[test]
+fn safetensors_load_as() -> Result<()> {
+    use candle_core::safetensors::MmapedSafetensors;
+    let cpu = candle_core::Device::Cpu;
+    let tmp_file = TmpFile::create("st_load_as");
+    let t = (Tensor::arange(0f32, 12f32, &cpu)? / 3.)?.reshape((3, 4))?;
+    let ts = std::collections::HashMap::from([
+        ("bf16".to_string(), t.to_dtype(DType::BF16)?),
+        ("f32".to_string(), t.clone()),
+        ("u32".to_string(), t.to_dtype(DType::U32)?),
+    ]);
+    candle_core::safetensors::save(&ts, &tmp_file)?;
+    let st = unsafe { MmapedSafetensors::new(&tmp_file)? };
+    for name in ["bf16", "f32", "u32"] {
+        for dtype in [DType::BF16, DType::F16, DType::F32, DType::F64, DType::U32] {
+            let expected = st.load(name, &cpu)?.to_dtype(dtype)?;
+            let loaded = st.load_as(name, dtype, &cpu)?;
+            assert_eq!(loaded.dtype(), dtype);
+            assert_eq!(loaded.dims(), [3, 4]);
+            assert_eq!(
+                loaded.to_dtype(DType::F64)?.to_vec2::<f64>()?,
+                expected.to_dtype(DType::F64)?.to_vec2::<f64>()?,
+                "{name} {dtype:?}"
+            );
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn safetensors_streaming() -> Result<()> {
+    use candle_core::safetensors::{self, StreamingSaveOptions};
+    let cpu = candle_core::Device::Cpu;
+    let tmp_file = TmpFile::create("st_streaming");
+    let t = (Tensor::arange(0f32, 12f32, &cpu)? / 3.)?.reshape((3, 4))?;
+    let u = Tensor::new(&[1u32, 2, 3], &cpu)?.to_dtype(DType::I64)?;
+    let s = Tensor::new(1.5f64, &cpu)?;
+    let tensors = [("t", &t), ("u", &u), ("s", &s)];
+    let mut options = StreamingSaveOptions::default();
+    options
+        .metadata
+        .insert("format".to_string(), "pt".to_string());
+    safetensors::save_streaming_file(&tensors, &tmp_file, &options)?;
+
+    // The file can be read by the standard loaders.
+    let loaded = safetensors::load(&tmp_file, &cpu)?;
+    assert_eq!(loaded["t"].to_vec2::<f32>()?, t.to_vec2::<f32>()?);
+    assert_eq!(loaded["u"].to_vec1::<i64>()?, [1, 2, 3]);
+    assert_eq!(loaded["s"].to_vec0::<f64>()?, 1.5);
+    let loaded = safetensors::load_verified(&tmp_file, &cpu)?;
+    assert_eq!(loaded.len(), 3);
+    assert_eq!(loaded["t"].to_vec2::<f32>()?, t.to_vec2::<f32>()?);
+
+    // Corrupting the data is detected by the checksums.
+    let mut bytes = std::fs::read(&tmp_file)?;
+    let n = bytes.len();
+    bytes[n - 1] ^= 1;
+    assert!(safetensors::load_buffer(&bytes, &cpu).is_ok());
+    let err = safetensors::load_buffer_verified(&bytes, &cpu).unwrap_err();
+    assert!(err.to_string().contains("checksum mismatch"), "{err}");
+
+    // Without the zstd feature compression is an error rather than a silent fallback.
+    let options = StreamingSaveOptions {
+        compression_level: Some(3),
+        ..Default::default()
+    };
+    let mut buffer = std::io::Cursor::new(vec![]);
+    let res = safetensors::save_streaming(&tensors, &mut buffer, &options);
+    if cfg!(feature = "zstd") {
+        res?;
+        let zeros = Tensor::zeros((256, 256), DType::F32, &cpu)?;
+        let mut large = std::io::Cursor::new(vec![]);
+        safetensors::save_streaming(&[("z", &zeros)], &mut large, &options)?;
+        assert!(large.get_ref().len() < 4096);
+        let loaded = safetensors::load_buffer_verified(large.get_ref(), &cpu)?;
+        assert_eq!(loaded["z"].dims(), [256, 256]);
+        let loaded = safetensors::load_buffer_verified(buffer.get_ref(), &cpu)?;
+        assert_eq!(loaded["t"].to_vec2::<f32>()?, t.to_vec2::<f32>()?);
+        assert_eq!(loaded["s"].dims(), [0usize; 0]);
+    } else {
+        assert!(res.is_err())
+    }
+    Ok(())
+}
+
+#[test]
+fn safetensors_inspector() -> Result<()> {
+    use candle_core::safetensors::{
+        self, Quantization, SafetensorsInspector, StreamingSaveOptions,
+    };
+    let cpu = candle_core::Device::Cpu;
+    let tmp_file = TmpFile::create("st_inspector");
+    let t = Tensor::arange(0f32, 12f32, &cpu)?.reshape((4, 3))?;
+    let qweight = Tensor::zeros((2, 2), DType::U32, &cpu)?;
+    let s = Tensor::new(1.5f64, &cpu)?;
+    let mut options = StreamingSaveOptions::default();
+    options
+        .metadata
+        .insert("format".to_string(), "pt".to_string());
+    let tensors = [("t", &t), ("layer.qweight", &qweight), ("s", &s)];
+    safetensors::save_streaming_file(&tensors, &tmp_file, &options)?;
+
+    let inspector = unsafe { SafetensorsInspector::new(&tmp_file)? };
+    let infos = inspector.tensors()?;
+    let names = infos.iter().map(|i| i.name.as_str()).collect::<Vec<_>>();
+    assert_eq!(names, ["layer.qweight", "s", "t"]);
+    assert_eq!(infos[0].quantization, Some(Quantization::Packed));
+    assert_eq!(infos[2].shape, [4, 3]);
+    assert_eq!(infos[2].size_in_bytes, 48);
+    assert_eq!(infos[2].candle_dtype(), Some(DType::F32));
+    assert_eq!(infos[2].quantization, None);
+    assert!(!infos[2].compressed);
+    assert_eq!(inspector.elem_count()?, 12 + 4 + 1);
+    assert_eq!(inspector.metadata()["format"], "pt");
+
+    assert_eq!(
+        inspector.load("t", &cpu)?.to_vec2::<f32>()?,
+        t.to_vec2::<f32>()?
+    );
+    let rows = inspector.load_rows("t", 1, 2, &cpu)?;
+    assert_eq!(rows.to_vec2::<f32>()?, [[3., 4., 5.], [6., 7., 8.]]);
+    assert!(inspector.load_rows("t", 3, 2, &cpu).is_err());
+    assert!(inspector.load_rows("s", 0, 1, &cpu).is_err());
+    assert!(inspector.get("missing").is_err());
+    drop(inspector);
+
+    // The offsets of the last tensor go past the end of a truncated file.
+    let file = std::fs::OpenOptions::new().write(true).open(&tmp_file.0)?;
+    file.set_len(file.metadata()?.len() - 1)?;
+    let inspector = unsafe { SafetensorsInspector::new(&tmp_file)? };
+    let errors = names.iter().filter(|n| inspector.data(n).is_err()).count();
+    assert_eq!(errors, 1);
+    Ok(())
+}
+
+#[test]
+fn convert_checkpoint() -> Result<()> {
+    use candle_core::convert::{convert, Checkpoint, ConvertOptions};
+    use candle_core::quantized::GgmlDType;
+    let cpu = candle_core::Device::Cpu;
+    let tmp = |ext: &str| {
+        let name = format!("candle-convert-{}.{ext}", std::process::id());
+        TmpFile(std::env::temp_dir().join(name))
+    };
+    let (src, dst_st, dst_gguf) = (tmp("safetensors"), tmp("st.safetensors"), tmp("gguf"));
+    let w = Tensor::arange(0f32, 64f32, &cpu)?.reshape((2, 32))?;
+    let b = Tensor::new(&[1f32, 2.], &cpu)?;
+    let ids = Tensor::new(&[3u32, 4], &cpu)?;
+    let freq = Tensor::new(&[0.5f32], &cpu)?;
+    let tensors = [
+        ("model.proj.weight", &w),
+        ("model.proj.bias", &b),
+        ("model.ids", &ids),
+        ("model.rotary.inv_freq", &freq),
+    ];
+    let options = candle_core::safetensors::StreamingSaveOptions::default();
+    candle_core::safetensors::save_streaming_file(&tensors, &src, &options)?;
+
+    let options = ConvertOptions {
+        dtype: Some(DType::F16),
+        renames: vec![("model.".to_string(), "".to_string())],
+        exclude: vec!["*.inv_freq".to_string()],
+        ..Default::default()
+    };
+    let report = convert(&[&src], &dst_st, &options)?;
+    assert_eq!(report.written, 3);
+    assert_eq!(report.excluded, ["model.rotary.inv_freq"]);
+    let loaded = candle_core::safetensors::load(&dst_st, &cpu)?;
+    let mut names = loaded.keys().map(|k| k.as_str()).collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(names, ["ids", "proj.bias", "proj.weight"]);
+    assert_eq!(loaded["proj.weight"].dtype(), DType::F16);
+    assert_eq!(loaded["ids"].to_vec1::<u32>()?, [3, 4]);
+    // Quantization is only available for gguf outputs.
+    let q8 = ConvertOptions {
+        quantize: Some(GgmlDType::Q8_0),
+        ..Default::default()
+    };
+    assert!(convert(&[&src], &dst_st, &q8).is_err());
+
+    let report = convert(&[&dst_st], &dst_gguf, &q8)?;
+    assert_eq!(report.written, 3);
+    assert_eq!(report.quantized, 1);
+    let checkpoint = Checkpoint::open(&[&dst_gguf])?;
+    let dtypes = checkpoint
+        .tensors()
+        .iter()
+        .map(|t| (t.name.as_str(), t.ggml_dtype))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        dtypes,
+        [
+            ("ids", Some(GgmlDType::F32)),
+            ("proj.bias", Some(GgmlDType::F16)),
+            ("proj.weight", Some(GgmlDType::Q8_0))
+        ]
+    );
+    let diff = (checkpoint.load("proj.weight")? - &w)?.abs()?;
+    assert!(diff.flatten_all()?.max(0)?.to_scalar::<f32>()? < 0.5);
+    Ok(())
+}
@@ -0,0 +1,76 @@
+use candle_core::shape_trace::{ShapeTracer, TensorSpec};
+use candle_core::{DType, Device, Module, Result, Tensor, D};
+
+struct Mlp {
+    w1: Tensor,
+    w2: Tensor,
+}
+
+impl Module for Mlp {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let xs = xs.broadcast_matmul(&self.w1.t()?)?.relu()?;
+        xs.broadcast_matmul(&self.w2.t()?)?.max_keepdim(D::Minus1)
+    }
+}
+
+#[test]
+fn shape_trace_module() -> Result<()> {
+    let tracer = ShapeTracer::new();
+    let dev = tracer.device();
+    assert!(dev.is_meta());
+    let mlp = Mlp {
+        w1: Tensor::zeros((1024, 256), DType::BF16, dev)?,
+        w2: Tensor::zeros((10, 1024), DType::BF16, dev)?,
+    };
+    let trace = tracer.trace_module(&mlp, &TensorSpec::new((4, 7, 256), DType::BF16))?;
+    assert_eq!(trace.outputs, [TensorSpec::new((4, 7, 1), DType::BF16)]);
+    let ops = trace.ops.iter().map(|op| op.op).collect::<Vec<_>>();
+    assert_eq!(ops, ["matmul", "relu", "matmul", "max"]);
+    assert_eq!(
+        trace.ops[0].inputs,
+        [
+            TensorSpec::new((4, 7, 256), DType::BF16),
+            TensorSpec::new((4, 256, 1024), DType::BF16),
+        ]
+    );
+    assert_eq!(
+        trace.ops[0].output,
+        TensorSpec::new((4, 7, 1024), DType::BF16)
+    );
+    assert_eq!(trace.op_counts(), [("matmul", 2), ("relu", 1), ("max", 1)]);
+    assert!(trace.to_string().ends_with("outputs: BF16[4, 7, 1]"));
+
+    // Shape errors are reported as on the other devices.
+    let err = tracer.trace_module(&mlp, &TensorSpec::new((4, 7, 128), DType::BF16));
+    assert!(err.is_err());
+    Ok(())
+}
+
+#[test]
+fn shape_trace_dtypes() -> Result<()> {
+    let tracer = ShapeTracer::new();
+    let cpu = Tensor::new(&[[1u32, 2], [3, 4]], &Device::Cpu)?;
+    let trace = tracer.trace(|| {
+        let ids = cpu.to_device(tracer.device())?;
+        let emb = Tensor::zeros((16, 8), DType::F16, tracer.device())?;
+        let xs = emb.embedding(&ids.flatten_all()?)?.to_dtype(DType::F32)?;
+        let mask = xs.ge(0.)?;
+        let kernel = Tensor::zeros((2, 4, 3), DType::F32, tracer.device())?;
+        let conv = xs.reshape((1, 4, 8))?.conv1d(&kernel, 0, 1, 1, 1)?;
+        Ok(vec![xs.argmax(1)?, mask, conv])
+    })?;
+    assert_eq!(
+        trace.outputs,
+        [
+            TensorSpec::new(4, DType::U32),
+            TensorSpec::new((4, 8), DType::U8),
+            TensorSpec::new((1, 2, 6), DType::F32),
+        ]
+    );
+    // Meta tensors do not hold data and cannot be moved back.
+    let xs = tracer.input(&TensorSpec::new((2, 3), DType::F32))?;
+    assert!(xs.to_vec2::<f32>().is_err());
+    assert!(xs.to_device(&Device::Cpu).is_err());
+    assert_eq!(xs.to_string(), "Tensor[[2, 3], f32, meta]");
+    Ok(())
+}
@@ -0,0 +1,85 @@
+use candle_core::stream_reduce::{RunningCovariance, RunningMoments};
+use candle_core::{DType, Device, Result, Tensor};
+
+fn chunks(xs: &Tensor, chunk_size: usize) -> impl Iterator<Item = Result<Tensor>> + '_ {
+    let n = xs.dim(0).unwrap();
+    (0..n)
+        .step_by(chunk_size)
+        .map(move |i| xs.narrow(0, i, chunk_size.min(n - i)))
+}
+
+#[test]
+fn stream_reduce() -> Result<()> {
+    let xs = Tensor::randn(0f32, 1., (37, 5), &Device::Cpu)?;
+    let sum = Tensor::stream_sum(chunks(&xs, 8))?.unwrap();
+    let diff = (sum - xs.sum(0)?)?.abs()?.max(0)?.to_vec0::<f32>()?;
+    assert!(diff < 1e-4, "{diff}");
+    let max = Tensor::stream_max(chunks(&xs, 8))?.unwrap();
+    assert_eq!(max.to_vec1::<f32>()?, xs.max(0)?.to_vec1::<f32>()?);
+    let min = Tensor::stream_min(chunks(&xs, 8))?.unwrap();
+    assert_eq!(min.to_vec1::<f32>()?, xs.min(0)?.to_vec1::<f32>()?);
+    assert!(Tensor::stream_sum(std::iter::empty())?.is_none());
+    // Errors from the iterator are propagated.
+    let err = std::iter::once(Err(candle_core::Error::Msg("bad chunk".to_string())));
+    assert!(Tensor::stream_sum(err).is_err());
+    Ok(())
+}
+
+#[test]
+fn running_moments() -> Result<()> {
+    let xs = (Tensor::randn(0f32, 1., (101, 3), &Device::Cpu)? * 3.)?;
+    let xs = (xs + 1000.)?;
+    let mut moments = RunningMoments::new();
+    for chunk in chunks(&xs, 10) {
+        moments.update(&chunk?)?;
+    }
+    assert_eq!(moments.count(), 101);
+    let xs64 = xs.to_dtype(DType::F64)?;
+    let mean = xs64.mean(0)?;
+    let var = xs64.broadcast_sub(&mean)?.sqr()?.sum(0)?;
+    let diff = |a: &Tensor, b: &Tensor| -> Result<f32> {
+        (a.to_dtype(DType::F32)? - b.to_dtype(DType::F32)?)?
+            .abs()?
+            .max(0)?
+            .to_scalar::<f32>()
+    };
+    assert!(diff(&moments.mean()?, &mean)? < 1e-3);
+    assert!(diff(&moments.var(false)?, &(&var / 101.)?)? < 1e-2);
+    assert!(diff(&moments.var(true)?, &(&var / 100.)?)? < 1e-2);
+
+    // Merging the statistics of two shards gives the same result.
+    let mut a = RunningMoments::new();
+    a.update(&xs.narrow(0, 0, 40)?)?;
+    let mut b = RunningMoments::new();
+    b.update(&xs.narrow(0, 40, 61)?)?;
+    a.merge(&b)?;
+    assert_eq!(a.count(), 101);
+    assert!(diff(&a.mean()?, &mean)? < 1e-3);
+    assert!(RunningMoments::new().mean().is_err());
+
+    // Half precision inputs are accumulated in f32.
+    let mut moments = RunningMoments::new();
+    moments.update(&Tensor::ones((4096, 2), DType::F16, &Device::Cpu)?)?;
+    assert_eq!(moments.mean()?.dtype(), DType::F32);
+    assert_eq!(moments.mean()?.to_vec1::<f32>()?, [1., 1.]);
+    Ok(())
+}
+
+#[test]
+fn running_covariance() -> Result<()> {
+    let xs = Tensor::randn(0f64, 1., (64, 3), &Device::Cpu)?;
+    let mut cov = RunningCovariance::new();
+    for chunk in chunks(&xs, 7) {
+        cov.update(&chunk?)?;
+    }
+    let centered = xs.broadcast_sub(&xs.mean_keepdim(0)?)?;
+    let expected = (centered.t()?.matmul(&centered)? / 63.)?;
+    let diff = (cov.covariance(true)? - expected)?
+        .abs()?
+        .flatten_all()?
+        .max(0)?
+        .to_scalar::<f64>()?;
+    assert!(diff < 1e-10, "{diff}");
+    assert_eq!(cov.mean()?.dims(), [3]);
+    Ok(())
+}
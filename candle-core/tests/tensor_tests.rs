@@ -1162,7 +1162,35 @@ fn zero_dim(device: &Device) -> Result<()> {
     Ok(())
 }
 
+fn capabilities(device: &Device) -> Result<()> {
+    let caps = device.capabilities()?;
+    assert!(caps.supports_dtype(DType::F32));
+    assert!(caps.dtypes.contains(&caps.preferred_float_dtype()));
+    for &dtype in caps.dtypes.iter() {
+        let t = Tensor::ones((2, 2), dtype, device)?;
+        let t = (&t + &t)?.to_dtype(DType::F32)?;
+        assert_eq!(t.to_vec2::<f32>()?, [[2., 2.], [2., 2.]]);
+        if caps.matmul_dtypes.contains(&dtype) {
+            let t = Tensor::ones((2, 2), dtype, device)?;
+            let t = t.matmul(&t)?.to_dtype(DType::F32)?;
+            assert_eq!(t.to_vec2::<f32>()?, [[2., 2.], [2., 2.]]);
+        }
+    }
+    if device.is_cpu() {
+        assert!(caps.unsupported_ops.is_empty());
+        assert_eq!(caps.dtypes.len(), 7);
+    }
+    assert_eq!(caps.supports_op("upsample-nearest1d"), !device.is_metal());
+    Ok(())
+}
+
 test_device!(zeros, zeros_cpu, zeros_gpu, zeros_metal);
+test_device!(
+    capabilities,
+    capabilities_cpu,
+    capabilities_gpu,
+    capabilities_metal
+);
 test_device!(ones, ones_cpu, ones_gpu, ones_metal);
 test_device!(full, full_cpu, full_gpu, full_metal);
 test_device!(arange, arange_cpu, arange_gpu, arange_metal);
@@ -1363,3 +1391,135 @@ fn pow() -> Result<()> {
     );
     Ok(())
 }
+
+#[test]
+fn fingerprint() -> Result<()> {
+    let t = Tensor::arange(0u32, 24, &Device::Cpu)?.reshape((2, 3, 4))?;
+    let fp = t.fingerprint()?;
+    assert_eq!(fp, t.copy()?.fingerprint()?);
+    let strided = t.transpose(1, 2)?;
+    assert!(!strided.is_contiguous());
+    assert_eq!(strided.fingerprint()?, strided.contiguous()?.fingerprint()?);
+    assert_ne!(fp, strided.fingerprint()?);
+    assert_ne!(fp, t.reshape((6, 4))?.fingerprint()?);
+    assert_eq!(
+        t.narrow(0, 1, 1)?.fingerprint()?,
+        Tensor::arange(12u32, 24, &Device::Cpu)?
+            .reshape((1, 3, 4))?
+            .fingerprint()?
+    );
+    Ok(())
+}
+
+#[test]
+fn cpu_fallback_policy() -> Result<()> {
+    use candle_core::fallback::{self, FallbackPolicy};
+    assert_eq!(fallback::fallback_policy(), FallbackPolicy::Error);
+    fallback::set_fallback_policy(FallbackPolicy::Cpu);
+    assert_eq!(fallback::fallback_policy(), FallbackPolicy::Cpu);
+    // The cpu supports all the ops so nothing falls back, and errors are still reported.
+    let t = Tensor::arange(0f32, 6., &Device::Cpu)?.reshape((2, 3))?;
+    assert_eq!(
+        t.matmul(&t.t()?)?.to_vec2::<f32>()?,
+        [[5., 14.], [14., 50.]]
+    );
+    assert!(t.matmul(&t).is_err());
+    assert!(fallback::fallback_counts().is_empty());
+    fallback::set_fallback_policy(FallbackPolicy::Error);
+    Ok(())
+}
+
+#[test]
+fn assert_close_helper() -> Result<()> {
+    use candle_core::test_utils::assert_close;
+    let a = Tensor::new(&[[1f64, 2.], [f64::INFINITY, 100.]], &Device::Cpu)?;
+    let b = Tensor::new(&[[1f64, 2.001], [f64::INFINITY, 100.1]], &Device::Cpu)?;
+    assert_close(&a, &b, 1e-3, 1e-3)?;
+    let err = assert_close(&a, &b, 1e-5, 1e-5).unwrap_err().to_string();
+    assert!(err.contains("2/4 elements are not close"), "{err}");
+    assert!(err.contains("at [1, 1]: 100 vs 100.1"), "{err}");
+    let nan = Tensor::new(&[f32::NAN], &Device::Cpu)?;
+    assert!(assert_close(&nan, &nan, 1., 1.).is_err());
+    assert!(assert_close(&a, &a.t()?.reshape(4)?, 1., 1.).is_err());
+    Ok(())
+}
+
+#[test]
+fn philox() -> Result<()> {
+    use candle_core::philox::{philox4x32_10, PhiloxKey};
+    // Known answers from the Random123 test suite.
+    assert_eq!(
+        philox4x32_10([0; 4], [0; 2]),
+        [0x6627e8d5, 0xe169c58d, 0xbc57ac4c, 0x9b00dbd8]
+    );
+    assert_eq!(
+        philox4x32_10([u32::MAX; 4], [u32::MAX; 2]),
+        [0x408f276d, 0x41c83b0e, 0xa20bc7c6, 0x6d5451fd]
+    );
+    let key = PhiloxKey::new(42, 7);
+    let full = key.u32s(11);
+    assert_eq!(key.with_offset(3).u32s(6), full[3..9]);
+    assert_ne!(PhiloxKey::new(42, 8).u32s(11), full);
+
+    let u = key.uniform((100, 100), &Device::Cpu)?;
+    let mean = u.mean_all()?.to_scalar::<f32>()?;
+    assert!((mean - 0.5).abs() < 0.01, "{mean}");
+    assert!(u.flatten_all()?.min(0)?.to_scalar::<f32>()? >= 0.);
+    assert!(u.flatten_all()?.max(0)?.to_scalar::<f32>()? < 1.);
+    let n = key.normal(10000, &Device::Cpu)?;
+    let mean = n.mean_all()?.to_scalar::<f32>()?;
+    let var = n.sqr()?.mean_all()?.to_scalar::<f32>()?;
+    assert!(mean.abs() < 0.05 && (var - 1.).abs() < 0.05, "{mean} {var}");
+    assert_eq!(key.with_offset(5).normal_vec(3), key.normal_vec(8)[5..]);
+    Ok(())
+}
+
+#[test]
+fn scalar_tensors() -> Result<()> {
+    let dev = &Device::Cpu;
+    let xs = Tensor::new(&[[1f32, 2.], [3., 4.]], dev)?;
+    let two = Tensor::new(2f32, dev)?;
+    assert!(two.is_scalar() && !xs.is_scalar());
+    // 0-dim tensors are broadcasted in all the binary ops, on either side.
+    assert_eq!((&xs * &two)?.to_vec2::<f32>()?, [[2., 4.], [6., 8.]]);
+    assert_eq!(two.sub(&xs)?.to_vec2::<f32>()?, [[1., 0.], [-1., -2.]]);
+    assert_eq!(xs.maximum(&two)?.to_vec2::<f32>()?, [[2., 2.], [3., 4.]]);
+    assert_eq!(xs.gt(&two)?.to_vec2::<u8>()?, [[0, 0], [1, 1]]);
+    let zero = Tensor::new(0f32, dev)?;
+    let ys = xs.ge(&two)?.where_cond(&xs, &zero)?;
+    assert_eq!(ys.to_vec2::<f32>()?, [[0., 2.], [3., 4.]]);
+    assert!(xs.add(&Tensor::new(&[1f32], dev)?).is_err());
+    assert!(xs.mul(&two).is_ok());
+
+    // The gradient of a scalar operand sums over the broadcasted dims.
+    let w = candle_core::Var::new(3f32, dev)?;
+    let loss = (&xs * w.as_tensor())?.sum_all()?;
+    assert!(loss.is_scalar());
+    assert_eq!(loss.item::<f32>()?, 30.);
+    let grads = loss.backward()?;
+    assert_eq!(grads.get(&w).unwrap().item::<f64>()?, 10.);
+    assert_eq!(Tensor::new(&[[7u32]], dev)?.item::<i64>()?, 7);
+    assert!(xs.item::<f32>().is_err());
+    Ok(())
+}
+
+#[test]
+fn strict_shapes() -> Result<()> {
+    let dev = &Device::Cpu;
+    let xs = Tensor::new(&[[1f32, 2.], [3., 4.]], dev)?;
+    let two = Tensor::new(2f32, dev)?;
+    let zero = Tensor::new(0f32, dev)?;
+    // In strict mode only the explicit broadcasts are allowed.
+    let _guard = candle_core::shape::StrictShapes::new(true);
+    let err = xs.mul(&two).unwrap_err();
+    assert_eq!(err.code(), candle_core::ErrorCode::ShapeMismatch);
+    assert!(xs.gt(&two).is_err());
+    assert!(xs.ge(&xs)?.where_cond(&xs, &zero).is_err());
+    assert_eq!(
+        xs.broadcast_mul(&two)?.to_vec2::<f32>()?,
+        [[2., 4.], [6., 8.]]
+    );
+    assert_eq!(xs.maximum(2f32)?.to_vec2::<f32>()?, [[2., 2.], [3., 4.]]);
+    assert_eq!(xs.mul(&xs)?.to_vec2::<f32>()?, [[1., 4.], [9., 16.]]);
+    Ok(())
+}
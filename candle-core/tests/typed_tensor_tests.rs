@@ -0,0 +1,57 @@
+use candle_core::typed::{Matrix, STensor, Vector};
+use candle_core::{DType, Device, Result, Tensor};
+
+#[test]
+fn typed_rank_check() -> Result<()> {
+    let t = Tensor::zeros((2, 3), DType::F32, &Device::Cpu)?;
+    assert!(STensor::<3>::new(t.clone()).is_err());
+    let m: Matrix = t.try_into()?;
+    assert_eq!(m.dims(), [2, 3]);
+    assert_eq!(m.dim(1)?, 3);
+    assert!(m.dim(2).is_err());
+    let t: Tensor = m.untyped();
+    assert_eq!(t.dims(), [2, 3]);
+    // The rank of the output of untyped operations is checked at runtime.
+    let m = Matrix::new(t)?;
+    let v: Vector = m.map(|t| t.flatten_all())?;
+    assert_eq!(v.dims(), [6]);
+    assert!(m.map::<3, _>(|t| t.flatten_all()).is_err());
+    Ok(())
+}
+
+#[test]
+fn typed_ops() -> Result<()> {
+    let dev = &Device::Cpu;
+    let xs = Tensor::arange(0f32, 12., dev)?.reshape((2, 2, 3))?;
+    let xs = STensor::<3>::new(xs)?;
+    let w = Matrix::new(Tensor::new(&[[1f32, 0.], [0., 1.], [1., 1.]], dev)?)?;
+    let ys = xs.broadcast_matmul(&w)?;
+    assert_eq!(ys.dims(), [2, 2, 2]);
+    assert_eq!(
+        ys.as_untyped().to_vec3::<f32>()?,
+        [[[2., 3.], [8., 9.]], [[14., 15.], [20., 21.]]]
+    );
+    let same = ys.matmul(&ys.transpose(1, 2)?)?;
+    assert_eq!(same.dims(), [2, 2, 2]);
+
+    let bias = Vector::new(Tensor::new(&[1f32, -1.], dev)?)?;
+    let ys = ys.broadcast_add(&bias)?;
+    let ys: Matrix = ys.reshape([4, 2])?;
+    assert_eq!(
+        ys.to_vec2::<f32>()?,
+        [[3., 2.], [9., 8.], [15., 14.], [21., 20.]]
+    );
+    let sums: Vector = ys.sum(1)?;
+    assert_eq!(sums.to_vec1::<f32>()?, [5., 17., 29., 41.]);
+    assert_eq!(ys.sum_all()?.to_scalar::<f32>()?, 92.);
+
+    let col: Matrix = sums.unsqueeze(1)?;
+    assert_eq!(col.dims(), [4, 1]);
+    let back: Vector = col.squeeze(1)?;
+    assert_eq!(back.dims(), [4]);
+    assert!(ys.squeeze::<1>(1).is_err());
+    let b: STensor<3> = col.broadcast_as([2, 4, 3])?;
+    assert_eq!(b.dims(), [2, 4, 3]);
+    assert_eq!(ys.t()?.dims(), [2, 4]);
+    Ok(())
+}
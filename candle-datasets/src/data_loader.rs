@@ -0,0 +1,423 @@
+//! A data loader producing batches from a dataset with parallel workers.
+//!
+//! The samples of each batch are loaded, mapped and collated by a pool of worker threads, and
+//! the resulting batches are optionally moved to a device, so that the data preparation
+//! overlaps with the training. The number of batches prepared in advance is bounded. The
+//! batches are always returned in the same order, which only depends on the seed and the epoch
+//! when shuffling, regardless of the number of workers.
+//!
+//! ```ignore
+//! let loader = DataLoader::new((m.train_images, m.train_labels), stack_pairs)
+//!     .batch_size(64)
+//!     .shuffle(true)
+//!     .seed(42)
+//!     .num_workers(4)
+//!     .device(Device::new_cuda(0)?);
+//! for epoch in 0..10 {
+//!     for batch in loader.iter(epoch) {
+//!         let (xs, ys) = batch?;
+//!         // ...
+//!     }
+//! }
+//! ```
+use crate::sampler::Sampler;
+use candle::{Device, Result, Tensor};
+use rand::{seq::SliceRandom, SeedableRng};
+use std::sync::mpsc::{sync_channel, Receiver};
+use std::sync::Arc;
+
+/// A collection of samples that can be accessed by index from multiple threads.
+pub trait Dataset: Send + Sync {
+    type Item: Send;
+
+    fn len(&self) -> usize;
+
+    fn get(&self, index: usize) -> Result<Self::Item>;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T: Clone + Send + Sync> Dataset for Vec<T> {
+    type Item = T;
+
+    fn len(&self) -> usize {
+        self.as_slice().len()
+    }
+
+    fn get(&self, index: usize) -> Result<T> {
+        match self.as_slice().get(index) {
+            Some(item) => Ok(item.clone()),
+            None => candle::bail!(
+                "index {index} out of range for dataset of size {}",
+                self.len()
+            ),
+        }
+    }
+}
+
+/// The samples are the slices along the first dimension.
+impl Dataset for Tensor {
+    type Item = Tensor;
+
+    fn len(&self) -> usize {
+        self.dims().first().copied().unwrap_or(0)
+    }
+
+    fn get(&self, index: usize) -> Result<Tensor> {
+        Tensor::get(self, index)
+    }
+}
+
+/// Pairs of samples, e.g. inputs and labels, sliced along the first dimension of both tensors.
+impl Dataset for (Tensor, Tensor) {
+    type Item = (Tensor, Tensor);
+
+    fn len(&self) -> usize {
+        self.0.len().min(self.1.len())
+    }
+
+    fn get(&self, index: usize) -> Result<(Tensor, Tensor)> {
+        Ok((self.0.get(index)?, self.1.get(index)?))
+    }
+}
+
+/// Batches that can be moved to a device by the workers.
+pub trait ToDevice: Sized {
+    fn to_device(&self, device: &Device) -> Result<Self>;
+}
+
+impl ToDevice for Tensor {
+    fn to_device(&self, device: &Device) -> Result<Self> {
+        Tensor::to_device(self, device)
+    }
+}
+
+impl<A: ToDevice, B: ToDevice> ToDevice for (A, B) {
+    fn to_device(&self, device: &Device) -> Result<Self> {
+        Ok((self.0.to_device(device)?, self.1.to_device(device)?))
+    }
+}
+
+impl<T: ToDevice> ToDevice for Vec<T> {
+    fn to_device(&self, device: &Device) -> Result<Self> {
+        self.iter().map(|t| t.to_device(device)).collect()
+    }
+}
+
+/// Collates samples by stacking them along a new first dimension.
+pub fn stack(items: Vec<Tensor>) -> Result<Tensor> {
+    Tensor::stack(&items, 0)
+}
+
+/// Collates pairs of samples by stacking both elements along a new first dimension.
+pub fn stack_pairs(items: Vec<(Tensor, Tensor)>) -> Result<(Tensor, Tensor)> {
+    let (xs, ys): (Vec<_>, Vec<_>) = items.into_iter().unzip();
+    Ok((Tensor::stack(&xs, 0)?, Tensor::stack(&ys, 0)?))
+}
+
+type MapFn<T> = dyn Fn(T) -> Result<T> + Send + Sync;
+type CollateFn<T, B> = dyn Fn(Vec<T>) -> Result<B> + Send + Sync;
+
+struct Inner<D: Dataset, B> {
+    dataset: Arc<D>,
+    map: Option<Arc<MapFn<D::Item>>>,
+    collate: Arc<CollateFn<D::Item, B>>,
+    device: Option<Device>,
+}
+
+// The fields are shared so that the builder methods can copy the inner state when an iterator
+// still holds it.
+impl<D: Dataset, B> Clone for Inner<D, B> {
+    fn clone(&self) -> Self {
+        Self {
+            dataset: self.dataset.clone(),
+            map: self.map.clone(),
+            collate: self.collate.clone(),
+            device: self.device.clone(),
+        }
+    }
+}
+
+impl<D: Dataset, B: ToDevice> Inner<D, B> {
+    fn load(&self, indexes: &[usize]) -> Result<B> {
+        let mut items = Vec::with_capacity(indexes.len());
+        for &index in indexes.iter() {
+            let item = self.dataset.get(index)?;
+            let item = match &self.map {
+                None => item,
+                Some(map) => map(item)?,
+            };
+            items.push(item)
+        }
+        let batch = (self.collate)(items)?;
+        match &self.device {
+            None => Ok(batch),
+            Some(device) => batch.to_device(device),
+        }
+    }
+}
+
+/// Produces batches from a dataset, see the module documentation.
+pub struct DataLoader<D: Dataset, B> {
+    inner: Arc<Inner<D, B>>,
+    batch_size: usize,
+    shuffle: bool,
+    sampler: Option<Arc<dyn Sampler>>,
+    seed: u64,
+    drop_last: bool,
+    num_workers: usize,
+    prefetch: usize,
+}
+
+impl<D, B> DataLoader<D, B>
+where
+    D: Dataset + 'static,
+    B: ToDevice + Send + 'static,
+{
+    /// Creates a data loader, `collate` builds a batch from the samples, e.g. `stack`.
+    pub fn new<F>(dataset: D, collate: F) -> Self
+    where
+        F: Fn(Vec<D::Item>) -> Result<B> + Send + Sync + 'static,
+    {
+        let inner = Inner {
+            dataset: Arc::new(dataset),
+            map: None,
+            collate: Arc::new(collate),
+            device: None,
+        };
+        Self {
+            inner: Arc::new(inner),
+            batch_size: 16,
+            shuffle: false,
+            sampler: None,
+            seed: 0,
+            drop_last: false,
+            num_workers: 0,
+            prefetch: 2,
+        }
+    }
+
+    fn inner_mut(&mut self) -> &mut Inner<D, B> {
+        Arc::make_mut(&mut self.inner)
+    }
+
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Shuffle the samples at each epoch, the order is derived from the seed and the epoch.
+    pub fn shuffle(mut self, shuffle: bool) -> Self {
+        self.shuffle = shuffle;
+        self
+    }
+
+    /// Use `sampler` to select the samples of each epoch and their order, see the
+    /// [`sampler`](crate::sampler) module. The `shuffle` setting is ignored when a sampler is set.
+    /// An error is returned if the sampler draws from more samples than the dataset has.
+    pub fn sampler<S: Sampler + 'static>(mut self, sampler: S) -> Result<Self> {
+        let len = self.inner.dataset.len();
+        if sampler.num_items() > len {
+            candle::bail!(
+                "the sampler draws from {} samples but the dataset has {len}",
+                sampler.num_items()
+            )
+        }
+        self.sampler = Some(Arc::new(sampler));
+        Ok(self)
+    }
+
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Skip the last batch of an epoch when it has less than `batch_size` samples.
+    pub fn drop_last(mut self, drop_last: bool) -> Self {
+        self.drop_last = drop_last;
+        self
+    }
+
+    /// The number of worker threads, the batches are prepared on the iterating thread when 0.
+    pub fn num_workers(mut self, num_workers: usize) -> Self {
+        self.num_workers = num_workers;
+        self
+    }
+
+    /// The maximum number of batches prepared in advance by the workers, at least one batch per
+    /// worker is prepared in advance.
+    pub fn prefetch(mut self, prefetch: usize) -> Self {
+        self.prefetch = prefetch;
+        self
+    }
+
+    /// A function applied to each sample before collating, e.g. for data augmentation.
+    pub fn map<F>(mut self, map: F) -> Self
+    where
+        F: Fn(D::Item) -> Result<D::Item> + Send + Sync + 'static,
+    {
+        self.inner_mut().map = Some(Arc::new(map));
+        self
+    }
+
+    /// Move the batches to `device` in the workers, so that the transfer overlaps with the
+    /// computations on the iterating thread. Candle does not use pinned host memory so the
+    /// transfers themselves are synchronous.
+    pub fn device(mut self, device: Device) -> Self {
+        self.inner_mut().device = Some(device);
+        self
+    }
+
+    pub fn dataset(&self) -> &D {
+        &self.inner.dataset
+    }
+
+    /// The number of batches per epoch.
+    pub fn len(&self) -> usize {
+        let n = match &self.sampler {
+            None => self.inner.dataset.len(),
+            Some(sampler) => sampler.len(),
+        };
+        if self.drop_last {
+            n / self.batch_size
+        } else {
+            n.div_ceil(self.batch_size)
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The sample indexes for each batch of an epoch.
+    pub fn batch_indexes(&self, epoch: usize) -> Vec<Vec<usize>> {
+        // Derive a different generator for each epoch.
+        let seed = self
+            .seed
+            .wrapping_add((epoch as u64).wrapping_mul(0x9E3779B97F4A7C15));
+        let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+        let indexes = match &self.sampler {
+            Some(sampler) => sampler.indexes(epoch, &mut rng),
+            None => {
+                let mut indexes = (0..self.inner.dataset.len()).collect::<Vec<_>>();
+                if self.shuffle {
+                    indexes.shuffle(&mut rng);
+                }
+                indexes
+            }
+        };
+        let mut batches = indexes
+            .chunks(self.batch_size)
+            .map(|c| c.to_vec())
+            .collect::<Vec<_>>();
+        if self.drop_last && batches.last().is_some_and(|b| b.len() < self.batch_size) {
+            batches.pop();
+        }
+        batches
+    }
+
+    /// Iterates over the batches of an epoch.
+    pub fn iter(&self, epoch: usize) -> DataLoaderIter<D, B> {
+        let batches = self.batch_indexes(epoch);
+        if self.num_workers == 0 {
+            return DataLoaderIter {
+                inner: self.inner.clone(),
+                len: batches.len(),
+                batches: Some(batches),
+                receivers: vec![],
+                workers: vec![],
+                index: 0,
+            };
+        }
+        let len = batches.len();
+        let num_workers = self.num_workers.min(len).max(1);
+        let capacity = self.prefetch.div_ceil(num_workers).max(1);
+        let mut receivers = Vec::with_capacity(num_workers);
+        let mut workers = Vec::with_capacity(num_workers);
+        for worker_id in 0..num_workers {
+            let (sender, receiver) = sync_channel(capacity);
+            // Batch `i` is prepared by worker `i % num_workers`, so that the batches can be
+            // received in order.
+            let indexes = batches
+                .iter()
+                .skip(worker_id)
+                .step_by(num_workers)
+                .cloned()
+                .collect::<Vec<_>>();
+            let inner = self.inner.clone();
+            let worker = std::thread::spawn(move || {
+                for indexes in indexes {
+                    if sender.send(inner.load(&indexes)).is_err() {
+                        // The iterator has been dropped.
+                        break;
+                    }
+                }
+            });
+            receivers.push(receiver);
+            workers.push(worker);
+        }
+        DataLoaderIter {
+            inner: self.inner.clone(),
+            len,
+            batches: None,
+            receivers,
+            workers,
+            index: 0,
+        }
+    }
+}
+
+/// The iterator over the batches of an epoch, see `DataLoader::iter`.
+pub struct DataLoaderIter<D: Dataset, B> {
+    inner: Arc<Inner<D, B>>,
+    len: usize,
+    // The batches to load on the iterating thread when there are no workers.
+    batches: Option<Vec<Vec<usize>>>,
+    receivers: Vec<Receiver<Result<B>>>,
+    workers: Vec<std::thread::JoinHandle<()>>,
+    index: usize,
+}
+
+impl<D: Dataset, B: ToDevice> DataLoaderIter<D, B> {
+    /// The number of remaining batches.
+    pub fn remaining(&self) -> usize {
+        self.len - self.index
+    }
+}
+
+impl<D: Dataset, B: ToDevice> Iterator for DataLoaderIter<D, B> {
+    type Item = Result<B>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let index = self.index;
+        if index >= self.len {
+            return None;
+        }
+        self.index += 1;
+        if let Some(batches) = &self.batches {
+            return Some(self.inner.load(&batches[index]));
+        }
+        let receiver = &self.receivers[index % self.receivers.len()];
+        match receiver.recv() {
+            Ok(batch) => Some(batch),
+            Err(_) => {
+                self.index = self.len;
+                Some(Err(candle::Error::Msg(
+                    "data loader worker panicked".to_string(),
+                )))
+            }
+        }
+    }
+}
+
+impl<D: Dataset, B> Drop for DataLoaderIter<D, B> {
+    fn drop(&mut self) {
+        // Dropping the receivers unblocks the workers waiting to send a batch.
+        self.receivers.clear();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
@@ -1,7 +1,12 @@
 //! Datasets & Dataloaders for Candle
 pub mod batcher;
+pub mod data_loader;
 pub mod hub;
 pub mod nlp;
+pub mod sampler;
+pub mod streaming;
 pub mod vision;
 
 pub use batcher::Batcher;
+pub use data_loader::{DataLoader, Dataset};
+pub use sampler::Sampler;
@@ -0,0 +1,309 @@
+//! Samplers deciding which samples a `DataLoader` returns at each epoch, and in which order.
+//!
+//! By default the data loader goes over all the samples once per epoch, optionally shuffled. A
+//! sampler set with `DataLoader::sampler` replaces this order, e.g. to draw the samples with
+//! some weights, to balance the classes of an imbalanced dataset or to follow a curriculum where
+//! the easy samples are seen first. The random generator given to the samplers is derived from
+//! the data loader seed and the epoch, so that the order of an epoch is reproducible.
+//!
+//! ```ignore
+//! let sampler = ClassBalancedSampler::new(&labels, labels.len())?;
+//! let loader = DataLoader::new((xs, ys), stack_pairs)
+//!     .batch_size(64)
+//!     .sampler(sampler)?;
+//! ```
+use candle::Result;
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::Rng;
+
+/// Produces the dataset indexes of the samples of each epoch.
+pub trait Sampler: Send + Sync {
+    /// The number of samples per epoch.
+    fn len(&self) -> usize;
+
+    /// The size of the dataset the sampler draws from, all the indexes are smaller than this.
+    fn num_items(&self) -> usize;
+
+    /// The dataset indexes of the samples of `epoch`, in order. An index can appear more than
+    /// once.
+    fn indexes(&self, epoch: usize, rng: &mut StdRng) -> Vec<usize>;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Draws each sample with a probability proportional to its weight.
+#[derive(Debug, Clone)]
+pub struct WeightedRandomSampler {
+    weights: Vec<f64>,
+    num_samples: usize,
+    replacement: bool,
+}
+
+impl WeightedRandomSampler {
+    /// Creates a sampler drawing `num_samples` samples per epoch, `weights` having one
+    /// non-negative weight per sample of the dataset. Without replacement, each sample is drawn
+    /// at most once per epoch so `num_samples` cannot exceed the number of samples with a
+    /// positive weight.
+    pub fn new(weights: Vec<f64>, num_samples: usize, replacement: bool) -> Result<Self> {
+        if let Some(w) = weights.iter().find(|w| !w.is_finite() || **w < 0.) {
+            candle::bail!("sampler weights must be finite and non-negative, got {w}")
+        }
+        let positive = weights.iter().filter(|&&w| w > 0.).count();
+        if num_samples > 0 && positive == 0 {
+            candle::bail!("sampler weights must not all be zero")
+        }
+        if !replacement && num_samples > positive {
+            candle::bail!(
+                "cannot draw {num_samples} samples without replacement from {positive} samples with a positive weight"
+            )
+        }
+        Ok(Self {
+            weights,
+            num_samples,
+            replacement,
+        })
+    }
+
+    pub fn weights(&self) -> &[f64] {
+        &self.weights
+    }
+}
+
+impl Sampler for WeightedRandomSampler {
+    fn len(&self) -> usize {
+        self.num_samples
+    }
+
+    fn num_items(&self) -> usize {
+        self.weights.len()
+    }
+
+    fn indexes(&self, _epoch: usize, rng: &mut StdRng) -> Vec<usize> {
+        if self.num_samples == 0 {
+            return vec![];
+        }
+        if self.replacement {
+            // The weights have been validated when creating the sampler.
+            let dist = WeightedIndex::new(&self.weights).expect("valid sampler weights");
+            return (0..self.num_samples).map(|_| dist.sample(rng)).collect();
+        }
+        // Efraimidis-Spirakis, keep the samples with the largest u^(1/w) keys.
+        let mut keys = self
+            .weights
+            .iter()
+            .enumerate()
+            .filter(|(_, &w)| w > 0.)
+            .map(|(i, &w)| (rng.gen::<f64>().powf(1. / w), i))
+            .collect::<Vec<_>>();
+        keys.sort_by(|a, b| b.0.total_cmp(&a.0));
+        keys.into_iter()
+            .take(self.num_samples)
+            .map(|(_, i)| i)
+            .collect()
+    }
+}
+
+/// Draws the samples so that all the classes are seen equally often, each sample having a weight
+/// inversely proportional to the number of samples of its class. The samples are drawn with
+/// replacement.
+#[derive(Debug, Clone)]
+pub struct ClassBalancedSampler {
+    sampler: WeightedRandomSampler,
+    class_counts: Vec<usize>,
+}
+
+impl ClassBalancedSampler {
+    /// Creates a sampler drawing `num_samples` samples per epoch, `labels` being the class of
+    /// each sample of the dataset.
+    pub fn new(labels: &[usize], num_samples: usize) -> Result<Self> {
+        let num_classes = labels.iter().max().map_or(0, |&l| l + 1);
+        let mut class_counts = vec![0; num_classes];
+        for &label in labels.iter() {
+            class_counts[label] += 1
+        }
+        let weights = labels
+            .iter()
+            .map(|&label| 1. / class_counts[label] as f64)
+            .collect();
+        let sampler = WeightedRandomSampler::new(weights, num_samples, true)?;
+        Ok(Self {
+            sampler,
+            class_counts,
+        })
+    }
+
+    /// The number of samples of each class in the dataset.
+    pub fn class_counts(&self) -> &[usize] {
+        &self.class_counts
+    }
+}
+
+impl Sampler for ClassBalancedSampler {
+    fn len(&self) -> usize {
+        self.sampler.len()
+    }
+
+    fn num_items(&self) -> usize {
+        self.sampler.num_items()
+    }
+
+    fn indexes(&self, epoch: usize, rng: &mut StdRng) -> Vec<usize> {
+        self.sampler.indexes(epoch, rng)
+    }
+}
+
+/// The fraction of the dataset, starting from the easiest samples, that a curriculum uses at
+/// each epoch.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CurriculumSchedule {
+    /// Grows linearly from `start` to the whole dataset over `epochs` epochs.
+    Linear { start: f64, epochs: usize },
+    /// Grows as the square root of the epoch from `start` to the whole dataset over `epochs`
+    /// epochs, adding the harder samples more slowly as the pool gets larger,
+    /// [Competence-based Curriculum Learning](https://arxiv.org/abs/1903.09848).
+    Root { start: f64, epochs: usize },
+    /// The fraction for each epoch, the last one being used for the following epochs.
+    Steps(Vec<f64>),
+}
+
+impl CurriculumSchedule {
+    /// The fraction of the dataset used at `epoch`, between 0 and 1.
+    pub fn competence(&self, epoch: usize) -> f64 {
+        let progress = |epochs: usize| {
+            if epochs == 0 {
+                1.
+            } else {
+                (epoch as f64 / epochs as f64).min(1.)
+            }
+        };
+        let c = match self {
+            Self::Linear { start, epochs } => start + (1. - start) * progress(*epochs),
+            Self::Root { start, epochs } => {
+                (start * start + (1. - start * start) * progress(*epochs)).sqrt()
+            }
+            Self::Steps(steps) => match steps.get(epoch).or(steps.last()) {
+                None => 1.,
+                Some(&c) => c,
+            },
+        };
+        c.clamp(0., 1.)
+    }
+}
+
+/// Orders the samples by difficulty and only uses the easiest ones in the first epochs.
+///
+/// At each epoch, the pool of the easiest samples covering the fraction of the dataset given by
+/// the schedule is used. A `mix` fraction of the samples of each epoch is drawn from the harder
+/// samples outside of the pool, so that they are not entirely ignored. The samples are drawn
+/// without replacement, cycling over the pool when the epoch is larger than it, and are shuffled
+/// unless `ordered` is set in which case they are returned from the easiest to the hardest.
+#[derive(Debug, Clone)]
+pub struct CurriculumSampler {
+    // The dataset indexes sorted by increasing difficulty.
+    order: Vec<usize>,
+    schedule: CurriculumSchedule,
+    num_samples: usize,
+    mix: f64,
+    ordered: bool,
+}
+
+impl CurriculumSampler {
+    /// Creates a sampler from the difficulty of each sample of the dataset, lower is easier.
+    /// Each epoch has as many samples as the dataset.
+    pub fn new(difficulties: &[f64], schedule: CurriculumSchedule) -> Result<Self> {
+        if difficulties.iter().any(|d| d.is_nan()) {
+            candle::bail!("curriculum difficulties cannot be NaN")
+        }
+        let mut order = (0..difficulties.len()).collect::<Vec<_>>();
+        // The sort is stable so samples with the same difficulty keep the dataset order.
+        order.sort_by(|&i, &j| difficulties[i].total_cmp(&difficulties[j]));
+        Ok(Self {
+            order,
+            schedule,
+            num_samples: difficulties.len(),
+            mix: 0.,
+            ordered: false,
+        })
+    }
+
+    /// The number of samples per epoch.
+    pub fn num_samples(mut self, num_samples: usize) -> Self {
+        self.num_samples = num_samples;
+        self
+    }
+
+    /// The fraction of each epoch drawn from the samples harder than the pool.
+    pub fn mix(mut self, mix: f64) -> Self {
+        self.mix = mix.clamp(0., 1.);
+        self
+    }
+
+    /// Return the samples of each epoch from the easiest to the hardest rather than shuffled.
+    pub fn ordered(mut self, ordered: bool) -> Self {
+        self.ordered = ordered;
+        self
+    }
+
+    pub fn schedule(&self) -> &CurriculumSchedule {
+        &self.schedule
+    }
+
+    /// The number of easiest samples in the pool at `epoch`, at least one.
+    pub fn pool_size(&self, epoch: usize) -> usize {
+        let n = self.order.len();
+        let size = (self.schedule.competence(epoch) * n as f64).ceil() as usize;
+        size.clamp(1, n.max(1))
+    }
+}
+
+// Draws `count` samples from `pool`, without replacement while possible.
+fn draw(pool: &[usize], count: usize, rng: &mut StdRng) -> Vec<usize> {
+    let mut samples = Vec::with_capacity(count);
+    while samples.len() < count && !pool.is_empty() {
+        let take = (count - samples.len()).min(pool.len());
+        samples.extend(pool.choose_multiple(rng, take).copied())
+    }
+    samples
+}
+
+impl Sampler for CurriculumSampler {
+    fn len(&self) -> usize {
+        if self.order.is_empty() {
+            0
+        } else {
+            self.num_samples
+        }
+    }
+
+    fn num_items(&self) -> usize {
+        self.order.len()
+    }
+
+    fn indexes(&self, epoch: usize, rng: &mut StdRng) -> Vec<usize> {
+        if self.order.is_empty() {
+            return vec![];
+        }
+        // The samples are drawn as ranks in the difficulty order, so that they can be sorted.
+        let n = self.order.len();
+        let pool_size = self.pool_size(epoch);
+        let num_hard = if pool_size == n {
+            0
+        } else {
+            (self.mix * self.num_samples as f64).round() as usize
+        };
+        let pool = (0..pool_size).collect::<Vec<_>>();
+        let rest = (pool_size..n).collect::<Vec<_>>();
+        let mut samples = draw(&pool, self.num_samples - num_hard, rng);
+        samples.extend(draw(&rest, num_hard, rng));
+        if self.ordered {
+            samples.sort()
+        } else {
+            samples.shuffle(rng)
+        }
+        samples.into_iter().map(|rank| self.order[rank]).collect()
+    }
+}
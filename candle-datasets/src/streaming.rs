@@ -0,0 +1,449 @@
+//! Streaming datasets made of parquet or WebDataset shards.
+//!
+//! The shards are read sequentially, either from local files or over HTTP, so that datasets that
+//! do not fit on disk can be used for training. Parquet shards are read using HTTP range
+//! requests, only fetching the row groups that are needed, and WebDataset tar shards are
+//! streamed. The samples are decoded by a user provided function on a background thread, a
+//! bounded number of samples being prefetched.
+//!
+//! The position in the stream is tracked by a `StreamCursor`, which can be saved along with a
+//! training checkpoint and used to resume the stream later on.
+//!
+//! ```ignore
+//! let api = hf_hub::api::sync::Api::new()?;
+//! let shards = hub_shards(&api, "allenai/c4", None, ".parquet")?;
+//! let stream = StreamingDataset::parquet(shards, |row| {
+//!     let text = row.get_string(0).map_err(candle::Error::wrap)?;
+//!     tokenize(text)
+//! })
+//! .resume_from(cursor);
+//! for batch in Batcher::new_r2(stream).batch_size(32) {
+//!     let (xs, ys) = batch?;
+//!     // ...
+//! }
+//! ```
+use candle::{Error, Result};
+use parquet::file::reader::{ChunkReader, FileReader, Length, SerializedFileReader};
+use parquet::record::Row;
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::PathBuf;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+
+/// The location of a shard.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Shard {
+    Path(PathBuf),
+    /// A file served over HTTP, the token is sent as a bearer token if set.
+    Url {
+        url: String,
+        token: Option<String>,
+    },
+}
+
+impl Shard {
+    fn open(&self) -> Result<Box<dyn Read + Send>> {
+        match self {
+            Self::Path(path) => Ok(Box::new(std::fs::File::open(path)?)),
+            Self::Url { url, token } => {
+                let response = request(url, token, None)?;
+                Ok(Box::new(response.into_reader()))
+            }
+        }
+    }
+}
+
+fn request(url: &str, token: &Option<String>, range: Option<String>) -> Result<ureq::Response> {
+    let mut request = ureq::get(url);
+    if let Some(token) = token {
+        request = request.set("Authorization", &format!("Bearer {token}"))
+    }
+    if let Some(range) = range {
+        request = request.set("Range", &range)
+    }
+    request
+        .call()
+        .map_err(|e| Error::Msg(format!("request to {url} failed: {e}")))
+}
+
+/// Returns the shards of a hub dataset whose name ends with `extension`, e.g. `.parquet` or
+/// `.tar`, sorted by name. When no revision is given, the parquet conversion of the dataset is
+/// used for parquet shards and the main branch otherwise.
+pub fn hub_shards(
+    api: &hf_hub::api::sync::Api,
+    dataset_id: &str,
+    revision: Option<&str>,
+    extension: &str,
+) -> Result<Vec<Shard>> {
+    let revision = match revision {
+        Some(revision) => revision.to_string(),
+        None if extension == ".parquet" => "refs/convert/parquet".to_string(),
+        None => "main".to_string(),
+    };
+    let repo =
+        hf_hub::Repo::with_revision(dataset_id.to_string(), hf_hub::RepoType::Dataset, revision);
+    let repo = api.repo(repo);
+    let info = repo.info().map_err(Error::wrap)?;
+    let mut files = info
+        .siblings
+        .into_iter()
+        .map(|s| s.rfilename)
+        .filter(|f| f.ends_with(extension))
+        .collect::<Vec<_>>();
+    files.sort();
+    let token = hf_hub::Cache::default().token();
+    let shards = files
+        .iter()
+        .map(|f| Shard::Url {
+            url: repo.url(f),
+            token: token.clone(),
+        })
+        .collect();
+    Ok(shards)
+}
+
+// A remote parquet file read using range requests.
+struct HttpFile {
+    url: String,
+    token: Option<String>,
+    len: u64,
+}
+
+impl HttpFile {
+    fn new(url: &str, token: &Option<String>) -> Result<Self> {
+        // Only request the last byte, the total size is in the content-range header.
+        let response = request(url, token, Some("bytes=-1".to_string()))?;
+        let len = response
+            .header("Content-Range")
+            .and_then(|r| r.rsplit('/').next())
+            .and_then(|len| len.parse::<u64>().ok());
+        let len = match len {
+            Some(len) => len,
+            None => candle::bail!("{url} does not support range requests"),
+        };
+        Ok(Self {
+            url: url.to_string(),
+            token: token.clone(),
+            len,
+        })
+    }
+
+    fn range(&self, range: String) -> parquet::errors::Result<ureq::Response> {
+        request(&self.url, &self.token, Some(range))
+            .map_err(|e| parquet::errors::ParquetError::External(Box::new(e)))
+    }
+}
+
+impl Length for HttpFile {
+    fn len(&self) -> u64 {
+        self.len
+    }
+}
+
+impl ChunkReader for HttpFile {
+    type T = Box<dyn Read + Send>;
+
+    fn get_read(&self, start: u64) -> parquet::errors::Result<Self::T> {
+        let response = self.range(format!("bytes={start}-"))?;
+        Ok(Box::new(response.into_reader()))
+    }
+
+    fn get_bytes(&self, start: u64, length: usize) -> parquet::errors::Result<bytes::Bytes> {
+        if length == 0 {
+            return Ok(bytes::Bytes::new());
+        }
+        let end = start + length as u64 - 1;
+        let response = self.range(format!("bytes={start}-{end}"))?;
+        let mut buf = Vec::with_capacity(length);
+        response
+            .into_reader()
+            .take(length as u64)
+            .read_to_end(&mut buf)?;
+        if buf.len() != length {
+            return Err(parquet::errors::ParquetError::EOF(format!(
+                "expected {length} bytes from {}, got {}",
+                self.url,
+                buf.len()
+            )));
+        }
+        Ok(buf.into())
+    }
+}
+
+/// The position in a stream: the index of the shard and of the sample in this shard.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StreamCursor {
+    pub shard: usize,
+    pub offset: usize,
+}
+
+/// A WebDataset sample: the files sharing the same key in a tar shard, indexed by extension,
+/// e.g. `jpg` or `cls` for `images/0001.jpg` and `images/0001.cls`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Sample {
+    pub key: String,
+    pub files: HashMap<String, Vec<u8>>,
+}
+
+impl Sample {
+    /// The content of the file with extension `ext` as utf8.
+    pub fn text(&self, ext: &str) -> Result<&str> {
+        match self.files.get(ext) {
+            None => candle::bail!("no {ext} file in sample {}", self.key),
+            Some(bytes) => std::str::from_utf8(bytes).map_err(Error::wrap),
+        }
+    }
+}
+
+// Splits a path from a tar shard in a sample key and an extension, the extension starts at the
+// first dot of the file name as in the WebDataset convention.
+fn split_key(path: &str) -> Option<(&str, &str)> {
+    let file_start = path.rfind('/').map_or(0, |i| i + 1);
+    let dot = path[file_start..].find('.')? + file_start;
+    Some((&path[..dot], &path[dot + 1..]))
+}
+
+type ParquetDecode<T> = Box<dyn FnMut(Row) -> Result<T> + Send>;
+type SampleDecode<T> = Box<dyn FnMut(Sample) -> Result<T> + Send>;
+
+enum Decoder<T> {
+    Parquet(ParquetDecode<T>),
+    WebDataset(SampleDecode<T>),
+}
+
+type Message<T> = Result<(StreamCursor, T)>;
+
+// Sends a decoded item, returns false when the stream has been dropped.
+fn send<T>(sender: &SyncSender<Message<T>>, msg: Message<T>) -> bool {
+    sender.send(msg).is_ok()
+}
+
+fn stream_parquet<T, R: ChunkReader + 'static>(
+    reader: R,
+    shard: usize,
+    skip: usize,
+    decode: &mut ParquetDecode<T>,
+    sender: &SyncSender<Message<T>>,
+) -> Result<bool> {
+    let reader = SerializedFileReader::new(reader).map_err(Error::wrap)?;
+    let mut offset = 0;
+    for i in 0..reader.num_row_groups() {
+        let num_rows = reader.metadata().row_group(i).num_rows() as usize;
+        // Row groups before the cursor are not fetched at all.
+        if offset + num_rows <= skip {
+            offset += num_rows;
+            continue;
+        }
+        let row_group = reader.get_row_group(i).map_err(Error::wrap)?;
+        for row in row_group.get_row_iter(None).map_err(Error::wrap)? {
+            if offset >= skip {
+                let row = row.map_err(Error::wrap)?;
+                let cursor = StreamCursor { shard, offset };
+                if !send(sender, decode(row).map(|v| (cursor, v))) {
+                    return Ok(false);
+                }
+            }
+            offset += 1;
+        }
+    }
+    Ok(true)
+}
+
+fn stream_tar<T>(
+    reader: Box<dyn Read + Send>,
+    shard: usize,
+    skip: usize,
+    decode: &mut SampleDecode<T>,
+    sender: &SyncSender<Message<T>>,
+) -> Result<bool> {
+    let mut archive = tar::Archive::new(reader);
+    let mut offset = 0;
+    let mut current: Option<Sample> = None;
+    let mut emit = |sample: Sample, offset: &mut usize| -> bool {
+        let cursor = StreamCursor {
+            shard,
+            offset: *offset,
+        };
+        *offset += 1;
+        if cursor.offset < skip {
+            return true;
+        }
+        send(sender, decode(sample).map(|v| (cursor, v)))
+    };
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let path = entry.path()?.to_string_lossy().to_string();
+        let (key, ext) = match split_key(&path) {
+            None => continue,
+            Some((key, ext)) => (key.to_string(), ext.to_string()),
+        };
+        if current.as_ref().is_some_and(|s| s.key != key) {
+            if let Some(sample) = current.take() {
+                if !emit(sample, &mut offset) {
+                    return Ok(false);
+                }
+            }
+        }
+        let sample = current.get_or_insert_with(|| Sample {
+            key,
+            files: HashMap::new(),
+        });
+        let mut data = Vec::with_capacity(entry.size() as usize);
+        entry.read_to_end(&mut data)?;
+        sample.files.insert(ext, data);
+    }
+    if let Some(sample) = current.take() {
+        if !emit(sample, &mut offset) {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+fn stream_shards<T>(
+    shards: Vec<Shard>,
+    cursor: StreamCursor,
+    mut decoder: Decoder<T>,
+    sender: SyncSender<Message<T>>,
+) {
+    for (index, shard) in shards.iter().enumerate().skip(cursor.shard) {
+        let skip = if index == cursor.shard {
+            cursor.offset
+        } else {
+            0
+        };
+        let res = match &mut decoder {
+            Decoder::Parquet(decode) => match shard {
+                Shard::Path(path) => std::fs::File::open(path)
+                    .map_err(Error::from)
+                    .and_then(|f| stream_parquet(f, index, skip, decode, &sender)),
+                Shard::Url { url, token } => HttpFile::new(url, token)
+                    .and_then(|f| stream_parquet(f, index, skip, decode, &sender)),
+            },
+            Decoder::WebDataset(decode) => shard
+                .open()
+                .and_then(|r| stream_tar(r, index, skip, decode, &sender)),
+        };
+        match res {
+            Ok(true) => {}
+            // The stream has been dropped.
+            Ok(false) => return,
+            Err(err) => {
+                let err = Error::Msg(format!("reading shard {index} ({shard:?}): {err}")).bt();
+                sender.send(Err(err)).ok();
+                return;
+            }
+        }
+    }
+}
+
+/// An iterator over the decoded samples of a list of shards, see the module documentation.
+///
+/// The background thread is started on the first call to `next`, so the builder methods have
+/// to be called before iterating.
+pub struct StreamingDataset<T> {
+    shards: Vec<Shard>,
+    decoder: Option<Decoder<T>>,
+    cursor: StreamCursor,
+    prefetch: usize,
+    receiver: Option<Receiver<Message<T>>>,
+    done: bool,
+}
+
+impl<T: Send + 'static> StreamingDataset<T> {
+    fn new(shards: Vec<Shard>, decoder: Decoder<T>) -> Self {
+        Self {
+            shards,
+            decoder: Some(decoder),
+            cursor: StreamCursor::default(),
+            prefetch: 64,
+            receiver: None,
+            done: false,
+        }
+    }
+
+    /// Streams the rows of parquet shards.
+    pub fn parquet<F>(shards: Vec<Shard>, decode: F) -> Self
+    where
+        F: FnMut(Row) -> Result<T> + Send + 'static,
+    {
+        Self::new(shards, Decoder::Parquet(Box::new(decode)))
+    }
+
+    /// Streams the samples of WebDataset tar shards.
+    pub fn webdataset<F>(shards: Vec<Shard>, decode: F) -> Self
+    where
+        F: FnMut(Sample) -> Result<T> + Send + 'static,
+    {
+        Self::new(shards, Decoder::WebDataset(Box::new(decode)))
+    }
+
+    /// Starts the stream at `cursor`, e.g. as returned by `cursor` when saving a checkpoint.
+    pub fn resume_from(mut self, cursor: StreamCursor) -> Self {
+        self.cursor = cursor;
+        self
+    }
+
+    /// The maximum number of decoded samples buffered ahead of the iteration.
+    pub fn prefetch(mut self, prefetch: usize) -> Self {
+        self.prefetch = prefetch.max(1);
+        self
+    }
+
+    pub fn shards(&self) -> &[Shard] {
+        &self.shards
+    }
+
+    /// The position of the next sample, resuming from this cursor continues the stream after
+    /// the samples returned so far.
+    pub fn cursor(&self) -> StreamCursor {
+        self.cursor
+    }
+
+    fn start(&mut self) -> Option<&Receiver<Message<T>>> {
+        if self.receiver.is_none() {
+            let decoder = self.decoder.take()?;
+            let (sender, receiver) = sync_channel(self.prefetch);
+            let shards = self.shards.clone();
+            let cursor = self.cursor;
+            std::thread::spawn(move || stream_shards(shards, cursor, decoder, sender));
+            self.receiver = Some(receiver);
+        }
+        self.receiver.as_ref()
+    }
+}
+
+impl<T: Send + 'static> Iterator for StreamingDataset<T> {
+    type Item = Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let msg = self.start()?.recv();
+        match msg {
+            Ok(Ok((cursor, item))) => {
+                self.cursor = StreamCursor {
+                    shard: cursor.shard,
+                    offset: cursor.offset + 1,
+                };
+                Some(Ok(item))
+            }
+            Ok(Err(err)) => {
+                self.done = true;
+                Some(Err(err))
+            }
+            Err(_) => {
+                self.done = true;
+                self.cursor = StreamCursor {
+                    shard: self.shards.len(),
+                    offset: 0,
+                };
+                None
+            }
+        }
+    }
+}
@@ -10,3 +10,4 @@ pub struct Dataset {
 
 pub mod cifar;
 pub mod mnist;
+pub mod transforms;
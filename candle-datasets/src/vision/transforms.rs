@@ -0,0 +1,911 @@
+//! Image decoding and data augmentation.
+//!
+//! Images are `(channels, height, width)` tensors on the cpu. `decode_image` returns u8 tensors
+//! and `ToFloat` converts them to f32 values in `[0, 1]`, which is what the other transforms
+//! expect. The per-sample transforms implement the `Transform` trait and can be chained with
+//! `Compose`, the batch level augmentations `MixUp` and `CutMix` are applied to the collated
+//! batches.
+//!
+//! The transforms are meant to run on the workers of a `DataLoader`, e.g.
+//! ```ignore
+//! let transform = Compose::new()
+//!     .then(ToFloat)
+//!     .then(RandomResizedCrop::new(224, 224))
+//!     .then(RandomHorizontalFlip::new(0.5))
+//!     .then(RandAugment::new(2, 9))
+//!     .then(Normalize::imagenet());
+//! let loader = DataLoader::new(dataset, stack_pairs)
+//!     .map(transform.map_pairs())
+//!     .num_workers(8);
+//! ```
+use candle::{DType, Device, Result, Tensor};
+use rand::{Rng, RngCore};
+use rand_distr::Distribution;
+use std::sync::Arc;
+
+/// Decodes a JPEG or PNG image to a u8 tensor of shape `(3, height, width)`.
+pub fn decode_image(bytes: &[u8]) -> Result<Tensor> {
+    let img = image::load_from_memory(bytes)
+        .map_err(candle::Error::wrap)?
+        .to_rgb8();
+    let (width, height) = img.dimensions();
+    let (height, width) = (height as usize, width as usize);
+    Tensor::from_vec(img.into_raw(), (height, width, 3), &Device::Cpu)?
+        .permute((2, 0, 1))?
+        .contiguous()
+}
+
+/// Reads and decodes a JPEG or PNG file, see `decode_image`.
+pub fn load_image<P: AsRef<std::path::Path>>(p: P) -> Result<Tensor> {
+    decode_image(&std::fs::read(p)?)
+}
+
+/// A transformation applied to a single image, the randomness comes from `rng`.
+pub trait Transform: Send + Sync {
+    fn apply(&self, img: &Tensor, rng: &mut dyn RngCore) -> Result<Tensor>;
+
+    /// A function applying the transform with the thread local generator, to be used with
+    /// `DataLoader::map`. The augmentations are then not reproducible.
+    fn map_images(self) -> impl Fn(Tensor) -> Result<Tensor> + Send + Sync + 'static
+    where
+        Self: Sized + 'static,
+    {
+        move |img| self.apply(&img, &mut rand::thread_rng())
+    }
+
+    /// Same as `map_images` for samples made of an image and a label.
+    #[allow(clippy::type_complexity)]
+    fn map_pairs(
+        self,
+    ) -> impl Fn((Tensor, Tensor)) -> Result<(Tensor, Tensor)> + Send + Sync + 'static
+    where
+        Self: Sized + 'static,
+    {
+        move |(img, label)| Ok((self.apply(&img, &mut rand::thread_rng())?, label))
+    }
+}
+
+impl<T: Transform + ?Sized> Transform for Arc<T> {
+    fn apply(&self, img: &Tensor, rng: &mut dyn RngCore) -> Result<Tensor> {
+        self.as_ref().apply(img, rng)
+    }
+}
+
+impl<T: Transform + ?Sized> Transform for Box<T> {
+    fn apply(&self, img: &Tensor, rng: &mut dyn RngCore) -> Result<Tensor> {
+        self.as_ref().apply(img, rng)
+    }
+}
+
+/// Applies a sequence of transforms.
+#[derive(Default)]
+pub struct Compose {
+    transforms: Vec<Box<dyn Transform>>,
+}
+
+impl Compose {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn then<T: Transform + 'static>(mut self, transform: T) -> Self {
+        self.transforms.push(Box::new(transform));
+        self
+    }
+}
+
+impl Transform for Compose {
+    fn apply(&self, img: &Tensor, rng: &mut dyn RngCore) -> Result<Tensor> {
+        let mut img = img.clone();
+        for transform in self.transforms.iter() {
+            img = transform.apply(&img, rng)?
+        }
+        Ok(img)
+    }
+}
+
+/// Converts u8 images to f32 values in `[0, 1]`.
+#[derive(Debug, Clone, Copy)]
+pub struct ToFloat;
+
+impl Transform for ToFloat {
+    fn apply(&self, img: &Tensor, _: &mut dyn RngCore) -> Result<Tensor> {
+        match img.dtype() {
+            DType::U8 => img.to_dtype(DType::F32)? / 255.,
+            _ => img.to_dtype(DType::F32),
+        }
+    }
+}
+
+/// Normalizes each channel with `(x - mean) / std`.
+#[derive(Debug, Clone)]
+pub struct Normalize {
+    mean: Vec<f32>,
+    std: Vec<f32>,
+}
+
+impl Normalize {
+    pub fn new(mean: &[f32], std: &[f32]) -> Self {
+        Self {
+            mean: mean.to_vec(),
+            std: std.to_vec(),
+        }
+    }
+
+    /// The mean and standard deviation of the ImageNet training set.
+    pub fn imagenet() -> Self {
+        Self::new(&[0.485, 0.456, 0.406], &[0.229, 0.224, 0.225])
+    }
+}
+
+impl Transform for Normalize {
+    fn apply(&self, img: &Tensor, _: &mut dyn RngCore) -> Result<Tensor> {
+        let c = img.dim(0)?;
+        if self.mean.len() != c || self.std.len() != c {
+            candle::bail!(
+                "normalize: {} means and {} stds for {c} channels",
+                self.mean.len(),
+                self.std.len()
+            )
+        }
+        let mean = Tensor::new(self.mean.as_slice(), img.device())?.reshape((c, 1, 1))?;
+        let std = Tensor::new(self.std.as_slice(), img.device())?.reshape((c, 1, 1))?;
+        img.to_dtype(DType::F32)?
+            .broadcast_sub(&mean)?
+            .broadcast_div(&std)
+    }
+}
+
+// An f32 image in channels, height, width order used to implement the transforms.
+struct Image {
+    data: Vec<f32>,
+    c: usize,
+    h: usize,
+    w: usize,
+}
+
+impl Image {
+    fn new(img: &Tensor) -> Result<Self> {
+        let (c, h, w) = img.dims3()?;
+        let data = img.to_dtype(DType::F32)?.flatten_all()?.to_vec1::<f32>()?;
+        Ok(Self { data, c, h, w })
+    }
+
+    fn to_tensor(&self) -> Result<Tensor> {
+        Tensor::from_slice(&self.data, (self.c, self.h, self.w), &Device::Cpu)
+    }
+
+    fn map(&self, f: impl Fn(f32) -> f32) -> Self {
+        Self {
+            data: self.data.iter().map(|&v| f(v)).collect(),
+            c: self.c,
+            h: self.h,
+            w: self.w,
+        }
+    }
+
+    // Interpolates between `other` (factor 0) and `self` (factor 1), clamping to [0, 1].
+    fn blend(&self, other: &Image, factor: f32) -> Self {
+        let data = self
+            .data
+            .iter()
+            .zip(other.data.iter())
+            .map(|(&s, &o)| (o + factor * (s - o)).clamp(0., 1.))
+            .collect();
+        Self {
+            data,
+            c: self.c,
+            h: self.h,
+            w: self.w,
+        }
+    }
+
+    fn grayscale(&self) -> Self {
+        let hw = self.h * self.w;
+        let gray = if self.c == 3 {
+            (0..hw)
+                .map(|i| {
+                    0.2989 * self.data[i]
+                        + 0.587 * self.data[hw + i]
+                        + 0.114 * self.data[2 * hw + i]
+                })
+                .collect::<Vec<_>>()
+        } else {
+            self.data[..hw].to_vec()
+        };
+        let data = (0..self.c).flat_map(|_| gray.iter().copied()).collect();
+        Self {
+            data,
+            c: self.c,
+            h: self.h,
+            w: self.w,
+        }
+    }
+
+    // Samples the image at the source position given by `inv` for each destination pixel, the
+    // matrix maps destination coordinates relative to the center of the image to source ones.
+    fn affine(&self, inv: [f32; 4], translate: (f32, f32)) -> Self {
+        let (c, h, w) = (self.c, self.h, self.w);
+        let (cx, cy) = ((w as f32 - 1.) * 0.5, (h as f32 - 1.) * 0.5);
+        let mut data = vec![0f32; c * h * w];
+        for y in 0..h {
+            for x in 0..w {
+                let dx = x as f32 - cx - translate.0;
+                let dy = y as f32 - cy - translate.1;
+                let sx = (inv[0] * dx + inv[1] * dy + cx).round();
+                let sy = (inv[2] * dx + inv[3] * dy + cy).round();
+                if sx < 0. || sy < 0. || sx >= w as f32 || sy >= h as f32 {
+                    continue;
+                }
+                let (sx, sy) = (sx as usize, sy as usize);
+                for ch in 0..c {
+                    data[ch * h * w + y * w + x] = self.data[ch * h * w + sy * w + sx]
+                }
+            }
+        }
+        Self { data, c, h, w }
+    }
+}
+
+/// The interpolation used when resizing images.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interpolation {
+    Nearest,
+    Bilinear,
+    Bicubic,
+}
+
+// Cubic convolution with a = -0.75 as in PyTorch.
+fn cubic_weight(x: f32) -> f32 {
+    const A: f32 = -0.75;
+    let x = x.abs();
+    if x <= 1. {
+        ((A + 2.) * x - (A + 3.)) * x * x + 1.
+    } else if x < 2. {
+        ((A * x - 5. * A) * x + 8. * A) * x - 4. * A
+    } else {
+        0.
+    }
+}
+
+// The source indexes and weights for each output position, using the pixel centers like
+// PyTorch with `align_corners=False` and no antialiasing.
+fn resize_weights(src: usize, dst: usize, interpolation: Interpolation) -> Vec<Vec<(usize, f32)>> {
+    let scale = src as f32 / dst as f32;
+    let clamp = |i: isize| i.clamp(0, src as isize - 1) as usize;
+    (0..dst)
+        .map(|i| match interpolation {
+            Interpolation::Nearest => {
+                vec![(((i as f32 * scale).floor() as usize).min(src - 1), 1.)]
+            }
+            Interpolation::Bilinear => {
+                let s = ((i as f32 + 0.5) * scale - 0.5).max(0.);
+                let i0 = s.floor() as isize;
+                let t = s - i0 as f32;
+                vec![(clamp(i0), 1. - t), (clamp(i0 + 1), t)]
+            }
+            Interpolation::Bicubic => {
+                let s = (i as f32 + 0.5) * scale - 0.5;
+                let i0 = s.floor() as isize;
+                let t = s - i0 as f32;
+                (-1..3)
+                    .map(|k| (clamp(i0 + k), cubic_weight(t - k as f32)))
+                    .collect()
+            }
+        })
+        .collect()
+}
+
+/// Resizes a `(channels, height, width)` image to `(channels, height, width)`, the result is
+/// an f32 image.
+pub fn resize(
+    img: &Tensor,
+    height: usize,
+    width: usize,
+    interpolation: Interpolation,
+) -> Result<Tensor> {
+    let src = Image::new(img)?;
+    let (c, h, w) = (src.c, src.h, src.w);
+    if h == 0 || w == 0 {
+        candle::bail!("cannot resize an empty image {:?}", img.shape())
+    }
+    let wx = resize_weights(w, width, interpolation);
+    let wy = resize_weights(h, height, interpolation);
+    // Resize along the width, then along the height.
+    let mut tmp = vec![0f32; c * h * width];
+    for ch in 0..c {
+        for y in 0..h {
+            let row = &src.data[(ch * h + y) * w..(ch * h + y + 1) * w];
+            for (x, weights) in wx.iter().enumerate() {
+                tmp[(ch * h + y) * width + x] = weights.iter().map(|&(i, v)| row[i] * v).sum();
+            }
+        }
+    }
+    let mut data = vec![0f32; c * height * width];
+    for ch in 0..c {
+        for (y, weights) in wy.iter().enumerate() {
+            for x in 0..width {
+                data[(ch * height + y) * width + x] = weights
+                    .iter()
+                    .map(|&(i, v)| tmp[(ch * h + i) * width + x] * v)
+                    .sum();
+            }
+        }
+    }
+    Tensor::from_vec(data, (c, height, width), &Device::Cpu)
+}
+
+/// Resizes images to a fixed size.
+#[derive(Debug, Clone, Copy)]
+pub struct Resize {
+    height: usize,
+    width: usize,
+    interpolation: Interpolation,
+}
+
+impl Resize {
+    pub fn new(height: usize, width: usize) -> Self {
+        Self {
+            height,
+            width,
+            interpolation: Interpolation::Bilinear,
+        }
+    }
+
+    pub fn interpolation(mut self, interpolation: Interpolation) -> Self {
+        self.interpolation = interpolation;
+        self
+    }
+}
+
+impl Transform for Resize {
+    fn apply(&self, img: &Tensor, _: &mut dyn RngCore) -> Result<Tensor> {
+        resize(img, self.height, self.width, self.interpolation)
+    }
+}
+
+/// Resizes images so that their shorter side has a given size, preserving the aspect ratio.
+#[derive(Debug, Clone, Copy)]
+pub struct ResizeShorter {
+    size: usize,
+    interpolation: Interpolation,
+}
+
+impl ResizeShorter {
+    pub fn new(size: usize) -> Self {
+        Self {
+            size,
+            interpolation: Interpolation::Bilinear,
+        }
+    }
+
+    pub fn interpolation(mut self, interpolation: Interpolation) -> Self {
+        self.interpolation = interpolation;
+        self
+    }
+}
+
+impl Transform for ResizeShorter {
+    fn apply(&self, img: &Tensor, _: &mut dyn RngCore) -> Result<Tensor> {
+        let (_, h, w) = img.dims3()?;
+        let (height, width) = if h <= w {
+            (self.size, (self.size * w) / h.max(1))
+        } else {
+            ((self.size * h) / w.max(1), self.size)
+        };
+        resize(img, height, width, self.interpolation)
+    }
+}
+
+fn crop(img: &Tensor, top: usize, left: usize, height: usize, width: usize) -> Result<Tensor> {
+    img.narrow(1, top, height)?.narrow(2, left, width)
+}
+
+/// Crops the center of images, the images have to be at least as large as the crop.
+#[derive(Debug, Clone, Copy)]
+pub struct CenterCrop {
+    height: usize,
+    width: usize,
+}
+
+impl CenterCrop {
+    pub fn new(height: usize, width: usize) -> Self {
+        Self { height, width }
+    }
+}
+
+impl Transform for CenterCrop {
+    fn apply(&self, img: &Tensor, _: &mut dyn RngCore) -> Result<Tensor> {
+        let (_, h, w) = img.dims3()?;
+        if h < self.height || w < self.width {
+            candle::bail!(
+                "center crop of {}x{} larger than the image {h}x{w}",
+                self.height,
+                self.width
+            )
+        }
+        crop(
+            img,
+            (h - self.height) / 2,
+            (w - self.width) / 2,
+            self.height,
+            self.width,
+        )
+    }
+}
+
+/// Crops images at a random position, after padding them with zeros on each side.
+#[derive(Debug, Clone, Copy)]
+pub struct RandomCrop {
+    height: usize,
+    width: usize,
+    padding: usize,
+}
+
+impl RandomCrop {
+    pub fn new(height: usize, width: usize) -> Self {
+        Self {
+            height,
+            width,
+            padding: 0,
+        }
+    }
+
+    pub fn padding(mut self, padding: usize) -> Self {
+        self.padding = padding;
+        self
+    }
+}
+
+impl Transform for RandomCrop {
+    fn apply(&self, img: &Tensor, rng: &mut dyn RngCore) -> Result<Tensor> {
+        let img = if self.padding > 0 {
+            img.pad_with_zeros(1, self.padding, self.padding)?
+                .pad_with_zeros(2, self.padding, self.padding)?
+        } else {
+            img.clone()
+        };
+        let (_, h, w) = img.dims3()?;
+        if h < self.height || w < self.width {
+            candle::bail!(
+                "random crop of {}x{} larger than the image {h}x{w}",
+                self.height,
+                self.width
+            )
+        }
+        let top = rng.gen_range(0..=h - self.height);
+        let left = rng.gen_range(0..=w - self.width);
+        crop(&img, top, left, self.height, self.width)
+    }
+}
+
+/// Crops a random area of images with a random aspect ratio and resizes it to a fixed size, as
+/// used to train Inception and ResNet models.
+#[derive(Debug, Clone, Copy)]
+pub struct RandomResizedCrop {
+    height: usize,
+    width: usize,
+    scale: (f32, f32),
+    ratio: (f32, f32),
+    interpolation: Interpolation,
+}
+
+impl RandomResizedCrop {
+    pub fn new(height: usize, width: usize) -> Self {
+        Self {
+            height,
+            width,
+            scale: (0.08, 1.),
+            ratio: (3. / 4., 4. / 3.),
+            interpolation: Interpolation::Bilinear,
+        }
+    }
+
+    /// The range for the area of the crop relative to the area of the image.
+    pub fn scale(mut self, min: f32, max: f32) -> Self {
+        self.scale = (min, max);
+        self
+    }
+
+    /// The range for the aspect ratio of the crop, sampled uniformly in log space.
+    pub fn ratio(mut self, min: f32, max: f32) -> Self {
+        self.ratio = (min, max);
+        self
+    }
+
+    pub fn interpolation(mut self, interpolation: Interpolation) -> Self {
+        self.interpolation = interpolation;
+        self
+    }
+
+    fn crop_box(&self, h: usize, w: usize, rng: &mut dyn RngCore) -> (usize, usize, usize, usize) {
+        let area = (h * w) as f32;
+        let (log_min, log_max) = (self.ratio.0.ln(), self.ratio.1.ln());
+        for _ in 0..10 {
+            let target = area * rng.gen_range(self.scale.0..=self.scale.1);
+            let ratio = rng.gen_range(log_min..=log_max).exp();
+            let cw = (target * ratio).sqrt().round() as usize;
+            let ch = (target / ratio).sqrt().round() as usize;
+            if cw > 0 && ch > 0 && cw <= w && ch <= h {
+                let top = rng.gen_range(0..=h - ch);
+                let left = rng.gen_range(0..=w - cw);
+                return (top, left, ch, cw);
+            }
+        }
+        // Fallback to a center crop clamped to the ratio range.
+        let ratio = w as f32 / h as f32;
+        let (ch, cw) = if ratio < self.ratio.0 {
+            ((w as f32 / self.ratio.0).round() as usize, w)
+        } else if ratio > self.ratio.1 {
+            (h, (h as f32 * self.ratio.1).round() as usize)
+        } else {
+            (h, w)
+        };
+        ((h - ch) / 2, (w - cw) / 2, ch, cw)
+    }
+}
+
+impl Transform for RandomResizedCrop {
+    fn apply(&self, img: &Tensor, rng: &mut dyn RngCore) -> Result<Tensor> {
+        let (_, h, w) = img.dims3()?;
+        let (top, left, ch, cw) = self.crop_box(h, w, rng);
+        let img = crop(img, top, left, ch, cw)?;
+        resize(&img, self.height, self.width, self.interpolation)
+    }
+}
+
+/// Flips images horizontally with probability `p`.
+#[derive(Debug, Clone, Copy)]
+pub struct RandomHorizontalFlip {
+    p: f64,
+}
+
+impl RandomHorizontalFlip {
+    pub fn new(p: f64) -> Self {
+        Self { p }
+    }
+}
+
+/// Flips an image along its last dimension.
+pub fn hflip(img: &Tensor) -> Result<Tensor> {
+    let w = img.dim(candle::D::Minus1)?;
+    let indexes = (0..w as u32).rev().collect::<Vec<_>>();
+    let indexes = Tensor::new(indexes.as_slice(), img.device())?;
+    img.index_select(&indexes, candle::D::Minus1)
+}
+
+impl Transform for RandomHorizontalFlip {
+    fn apply(&self, img: &Tensor, rng: &mut dyn RngCore) -> Result<Tensor> {
+        if rng.gen_bool(self.p) {
+            hflip(img)
+        } else {
+            Ok(img.clone())
+        }
+    }
+}
+
+/// The operations sampled by `RandAugment`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AugmentOp {
+    Identity,
+    ShearX,
+    ShearY,
+    TranslateX,
+    TranslateY,
+    Rotate,
+    Brightness,
+    Color,
+    Contrast,
+    Sharpness,
+    Posterize,
+    Solarize,
+    AutoContrast,
+    Equalize,
+}
+
+impl AugmentOp {
+    pub const ALL: [AugmentOp; 14] = [
+        Self::Identity,
+        Self::ShearX,
+        Self::ShearY,
+        Self::TranslateX,
+        Self::TranslateY,
+        Self::Rotate,
+        Self::Brightness,
+        Self::Color,
+        Self::Contrast,
+        Self::Sharpness,
+        Self::Posterize,
+        Self::Solarize,
+        Self::AutoContrast,
+        Self::Equalize,
+    ];
+
+    // Whether the magnitude is applied with a random sign.
+    fn signed(&self) -> bool {
+        matches!(
+            self,
+            Self::ShearX
+                | Self::ShearY
+                | Self::TranslateX
+                | Self::TranslateY
+                | Self::Rotate
+                | Self::Brightness
+                | Self::Color
+                | Self::Contrast
+                | Self::Sharpness
+        )
+    }
+
+    // The magnitude of the op for a level in `[0, 1]`, using the ranges of torchvision.
+    fn magnitude(&self, level: f32, h: usize, w: usize) -> f32 {
+        match self {
+            Self::Identity | Self::AutoContrast | Self::Equalize => 0.,
+            Self::ShearX | Self::ShearY => 0.3 * level,
+            Self::TranslateX => 150. / 331. * w as f32 * level,
+            Self::TranslateY => 150. / 331. * h as f32 * level,
+            Self::Rotate => 30. * level,
+            Self::Brightness | Self::Color | Self::Contrast | Self::Sharpness => 0.9 * level,
+            Self::Posterize => 8. - (4. * level).round(),
+            Self::Solarize => 1. - level,
+        }
+    }
+
+    /// Applies the op with magnitude `m` to an f32 image with values in `[0, 1]`.
+    pub fn apply(&self, img: &Tensor, m: f32) -> Result<Tensor> {
+        let img = Image::new(img)?;
+        let img = match self {
+            Self::Identity => return img.to_tensor(),
+            Self::ShearX => img.affine([1., -m, 0., 1.], (0., 0.)),
+            Self::ShearY => img.affine([1., 0., -m, 1.], (0., 0.)),
+            Self::TranslateX => img.affine([1., 0., 0., 1.], (m, 0.)),
+            Self::TranslateY => img.affine([1., 0., 0., 1.], (0., m)),
+            Self::Rotate => {
+                // Counter-clockwise rotation of the content.
+                let (sin, cos) = m.to_radians().sin_cos();
+                img.affine([cos, -sin, sin, cos], (0., 0.))
+            }
+            Self::Brightness => img.blend(&img.map(|_| 0.), 1. + m),
+            Self::Color => img.blend(&img.grayscale(), 1. + m),
+            Self::Contrast => {
+                let gray = img.grayscale();
+                let mean = gray.data.iter().sum::<f32>() / gray.data.len().max(1) as f32;
+                img.blend(&img.map(|_| mean), 1. + m)
+            }
+            Self::Sharpness => img.blend(&smooth(&img), 1. + m),
+            Self::Posterize => {
+                let shift = 8 - (m as u32).clamp(1, 8);
+                img.map(|v| {
+                    let v = (v.clamp(0., 1.) * 255.).round() as u32;
+                    ((v >> shift) << shift) as f32 / 255.
+                })
+            }
+            Self::Solarize => img.map(|v| if v >= m { 1. - v } else { v }),
+            Self::AutoContrast => autocontrast(&img),
+            Self::Equalize => equalize(&img),
+        };
+        img.to_tensor()
+    }
+}
+
+// Smooths an image with the PIL smoothing filter, leaving the borders unchanged.
+fn smooth(img: &Image) -> Image {
+    let (c, h, w) = (img.c, img.h, img.w);
+    let mut data = img.data.clone();
+    for ch in 0..c {
+        let plane = &img.data[ch * h * w..(ch + 1) * h * w];
+        for y in 1..h.saturating_sub(1) {
+            for x in 1..w.saturating_sub(1) {
+                let mut sum = 4. * plane[y * w + x];
+                for dy in 0..3 {
+                    for dx in 0..3 {
+                        sum += plane[(y + dy - 1) * w + x + dx - 1]
+                    }
+                }
+                data[ch * h * w + y * w + x] = sum / 13.
+            }
+        }
+    }
+    Image { data, c, h, w }
+}
+
+// Rescales each channel so that its minimum maps to 0 and its maximum to 1.
+fn autocontrast(img: &Image) -> Image {
+    let hw = img.h * img.w;
+    let mut data = img.data.clone();
+    for plane in data.chunks_mut(hw.max(1)) {
+        let min = plane.iter().copied().fold(f32::INFINITY, f32::min);
+        let max = plane.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        if max > min {
+            plane.iter_mut().for_each(|v| *v = (*v - min) / (max - min))
+        }
+    }
+    Image {
+        data,
+        c: img.c,
+        h: img.h,
+        w: img.w,
+    }
+}
+
+// Equalizes the histogram of each channel using 256 bins, as PIL does.
+fn equalize(img: &Image) -> Image {
+    let hw = img.h * img.w;
+    let mut data = img.data.clone();
+    for plane in data.chunks_mut(hw.max(1)) {
+        let bins = plane
+            .iter()
+            .map(|v| (v.clamp(0., 1.) * 255.).round() as usize)
+            .collect::<Vec<_>>();
+        let mut hist = [0usize; 256];
+        bins.iter().for_each(|&b| hist[b] += 1);
+        let last = hist.iter().rposition(|&n| n > 0).map_or(0, |i| hist[i]);
+        let step = (hw - last) / 255;
+        if step == 0 {
+            continue;
+        }
+        let mut lut = [0f32; 256];
+        let mut acc = step / 2;
+        for (b, &n) in hist.iter().enumerate() {
+            lut[b] = (acc / step).min(255) as f32 / 255.;
+            acc += n;
+        }
+        plane
+            .iter_mut()
+            .zip(bins.iter())
+            .for_each(|(v, &b)| *v = lut[b]);
+    }
+    Image {
+        data,
+        c: img.c,
+        h: img.h,
+        w: img.w,
+    }
+}
+
+/// RandAugment from "RandAugment: Practical automated data augmentation with a reduced search
+/// space" <https://arxiv.org/abs/1909.13719>: applies `num_ops` operations sampled uniformly,
+/// all with the same magnitude. The images have to be f32 with values in `[0, 1]`.
+#[derive(Debug, Clone)]
+pub struct RandAugment {
+    num_ops: usize,
+    magnitude: usize,
+    num_magnitude_bins: usize,
+    ops: Vec<AugmentOp>,
+}
+
+impl RandAugment {
+    /// `magnitude` is between 0 and 30 by default, 9 is a common choice.
+    pub fn new(num_ops: usize, magnitude: usize) -> Self {
+        Self {
+            num_ops,
+            magnitude,
+            num_magnitude_bins: 31,
+            ops: AugmentOp::ALL.to_vec(),
+        }
+    }
+
+    pub fn num_magnitude_bins(mut self, num_magnitude_bins: usize) -> Self {
+        self.num_magnitude_bins = num_magnitude_bins.max(2);
+        self
+    }
+
+    /// Restricts the operations that can be sampled.
+    pub fn ops(mut self, ops: &[AugmentOp]) -> Self {
+        self.ops = ops.to_vec();
+        self
+    }
+}
+
+impl Transform for RandAugment {
+    fn apply(&self, img: &Tensor, rng: &mut dyn RngCore) -> Result<Tensor> {
+        let (_, h, w) = img.dims3()?;
+        let level = self.magnitude as f32 / (self.num_magnitude_bins - 1) as f32;
+        let mut img = img.to_dtype(DType::F32)?;
+        for _ in 0..self.num_ops {
+            if self.ops.is_empty() {
+                break;
+            }
+            let op = self.ops[rng.gen_range(0..self.ops.len())];
+            let mut m = op.magnitude(level.min(1.), h, w);
+            if op.signed() && rng.gen_bool(0.5) {
+                m = -m
+            }
+            img = op.apply(&img, m)?
+        }
+        Ok(img)
+    }
+}
+
+// Converts class indexes of shape (batch,) to one-hot targets, soft targets of shape
+// (batch, num_classes) are returned as is.
+fn soft_targets(labels: &Tensor, num_classes: usize) -> Result<Tensor> {
+    if labels.rank() == 2 {
+        return labels.to_dtype(DType::F32);
+    }
+    let labels = labels.to_dtype(DType::U32)?;
+    candle_nn::encoding::one_hot(labels, num_classes, 1f32, 0f32)
+}
+
+fn sample_beta(alpha: f64, rng: &mut dyn RngCore) -> Result<f64> {
+    let beta = rand_distr::Beta::new(alpha, alpha).map_err(candle::Error::wrap)?;
+    Ok(beta.sample(rng))
+}
+
+/// MixUp from "mixup: Beyond Empirical Risk Minimization" <https://arxiv.org/abs/1710.09412>:
+/// each image of a batch is blended with the next one in the batch, and so are the targets.
+///
+/// `apply` takes images of shape `(batch, channels, height, width)` and class indexes of shape
+/// `(batch,)` or soft targets of shape `(batch, num_classes)`, and returns the mixed images and
+/// soft targets.
+#[derive(Debug, Clone, Copy)]
+pub struct MixUp {
+    alpha: f64,
+    num_classes: usize,
+}
+
+impl MixUp {
+    pub fn new(alpha: f64, num_classes: usize) -> Self {
+        Self { alpha, num_classes }
+    }
+
+    pub fn apply(
+        &self,
+        images: &Tensor,
+        labels: &Tensor,
+        rng: &mut dyn RngCore,
+    ) -> Result<(Tensor, Tensor)> {
+        let lambda = sample_beta(self.alpha, rng)?;
+        let targets = soft_targets(labels, self.num_classes)?;
+        let images = images.to_dtype(DType::F32)?;
+        let mixed = ((&images * lambda)? + (images.roll(1, 0)? * (1. - lambda))?)?;
+        let targets = ((&targets * lambda)? + (targets.roll(1, 0)? * (1. - lambda))?)?;
+        Ok((mixed, targets))
+    }
+}
+
+/// CutMix from "CutMix: Regularization Strategy to Train Strong Classifiers with Localizable
+/// Features" <https://arxiv.org/abs/1905.04899>: a random box of each image of a batch is
+/// replaced by the same box from the next image in the batch, the targets are mixed in
+/// proportion of the areas. The inputs and outputs are the same as for `MixUp`.
+#[derive(Debug, Clone, Copy)]
+pub struct CutMix {
+    alpha: f64,
+    num_classes: usize,
+}
+
+impl CutMix {
+    pub fn new(alpha: f64, num_classes: usize) -> Self {
+        Self { alpha, num_classes }
+    }
+
+    pub fn apply(
+        &self,
+        images: &Tensor,
+        labels: &Tensor,
+        rng: &mut dyn RngCore,
+    ) -> Result<(Tensor, Tensor)> {
+        let (_, _, h, w) = images.dims4()?;
+        let lambda = sample_beta(self.alpha, rng)?;
+        let cut = (1. - lambda).sqrt();
+        let (cut_h, cut_w) = ((h as f64 * cut) as usize, (w as f64 * cut) as usize);
+        let (cy, cx) = (rng.gen_range(0..h), rng.gen_range(0..w));
+        let (y0, y1) = (cy.saturating_sub(cut_h / 2), (cy + cut_h / 2).min(h));
+        let (x0, x1) = (cx.saturating_sub(cut_w / 2), (cx + cut_w / 2).min(w));
+        let mask = (0..h)
+            .flat_map(|y| (0..w).map(move |x| (y0..y1).contains(&y) && (x0..x1).contains(&x)))
+            .map(|inside| inside as u8)
+            .collect::<Vec<_>>();
+        let mask = Tensor::from_vec(mask, (1, 1, h, w), images.device())?;
+        let images = images.to_dtype(DType::F32)?;
+        let rolled = images.roll(1, 0)?;
+        let mixed = mask
+            .broadcast_as(images.shape())?
+            .where_cond(&rolled, &images)?;
+        // The proportion of the original image that is kept.
+        let lambda = 1. - ((y1 - y0) * (x1 - x0)) as f64 / (h * w) as f64;
+        let targets = soft_targets(labels, self.num_classes)?;
+        let targets = ((&targets * lambda)? + (targets.roll(1, 0)? * (1. - lambda))?)?;
+        Ok((mixed, targets))
+    }
+}
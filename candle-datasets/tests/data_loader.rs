@@ -0,0 +1,95 @@
+use candle::{Device, Result, Tensor};
+use candle_datasets::data_loader::{stack, stack_pairs};
+use candle_datasets::DataLoader;
+
+fn first_column(batch: &Tensor) -> Result<Vec<u32>> {
+    batch.narrow(1, 0, 1)?.flatten_all()?.to_vec1::<u32>()
+}
+
+#[test]
+fn data_loader_order() -> Result<()> {
+    let data = Tensor::arange(0u32, 20, &Device::Cpu)?.reshape((10, 2))?;
+    let loader = DataLoader::new(data.clone(), stack).batch_size(4);
+    assert_eq!(loader.len(), 3);
+    let batches = loader.iter(0).collect::<Result<Vec<_>>>()?;
+    assert_eq!(batches.len(), 3);
+    assert_eq!(first_column(&batches[0])?, [0, 2, 4, 6]);
+    assert_eq!(first_column(&batches[2])?, [16, 18]);
+
+    let loader = DataLoader::new(data, stack).batch_size(4).drop_last(true);
+    assert_eq!(loader.iter(0).count(), 2);
+
+    // The builder methods can still be used while an iterator holds the loader state.
+    let mut iter = loader.iter(0);
+    let loader = loader.map(|x| x + 1.);
+    assert_eq!(first_column(&iter.next().unwrap()?)?, [0, 2, 4, 6]);
+    assert_eq!(
+        first_column(&loader.iter(0).next().unwrap()?)?,
+        [1, 3, 5, 7]
+    );
+    Ok(())
+}
+
+#[test]
+fn data_loader_workers_and_shuffle() -> Result<()> {
+    let xs = Tensor::arange(0u32, 100, &Device::Cpu)?.reshape((100, 1))?;
+    let ys = Tensor::arange(100u32, 200, &Device::Cpu)?;
+    let loader = |num_workers| {
+        DataLoader::new((xs.clone(), ys.clone()), stack_pairs)
+            .batch_size(8)
+            .shuffle(true)
+            .seed(42)
+            .num_workers(num_workers)
+            .prefetch(3)
+            .map(|(x, y)| Ok(((x * 2.)?, y)))
+    };
+    type Loader = DataLoader<(Tensor, Tensor), (Tensor, Tensor)>;
+    let epoch = |loader: &Loader, epoch| -> Result<Vec<u32>> {
+        let mut samples = vec![];
+        for batch in loader.iter(epoch) {
+            let (x, y) = batch?;
+            let x = x.flatten_all()?.to_vec1::<u32>()?;
+            let y = y.to_vec1::<u32>()?;
+            for (x, y) in x.iter().zip(y.iter()) {
+                assert_eq!(*x, 2 * (y - 100));
+            }
+            samples.extend(y)
+        }
+        Ok(samples)
+    };
+    let sequential = loader(0);
+    let parallel = loader(3);
+    let epoch0 = epoch(&sequential, 0)?;
+    // The order only depends on the seed and epoch, not on the number of workers.
+    assert_eq!(epoch(&parallel, 0)?, epoch0);
+    assert_ne!(epoch(&parallel, 1)?, epoch0);
+    assert_ne!(epoch0, (100..200).collect::<Vec<_>>());
+    let mut sorted = epoch0.clone();
+    sorted.sort();
+    assert_eq!(sorted, (100..200).collect::<Vec<_>>());
+
+    // Dropping an iterator early stops the workers.
+    let mut iter = parallel.iter(0);
+    assert!(iter.next().is_some());
+    assert_eq!(iter.remaining(), 12);
+    drop(iter);
+    Ok(())
+}
+
+#[test]
+fn data_loader_errors() -> Result<()> {
+    let data = vec![1u32, 2, 3, 4];
+    let loader = DataLoader::new(data, |items: Vec<u32>| {
+        if items.contains(&3) {
+            candle::bail!("bad sample")
+        }
+        Tensor::new(items, &Device::Cpu)
+    })
+    .batch_size(2)
+    .num_workers(2);
+    let batches = loader.iter(0).collect::<Vec<_>>();
+    assert_eq!(batches.len(), 2);
+    assert!(batches[0].is_ok());
+    assert!(batches[1].is_err());
+    Ok(())
+}
@@ -0,0 +1,112 @@
+use candle::{Device, Result, Tensor};
+use candle_datasets::data_loader::stack;
+use candle_datasets::sampler::{
+    ClassBalancedSampler, CurriculumSampler, CurriculumSchedule, WeightedRandomSampler,
+};
+use candle_datasets::{DataLoader, Sampler};
+use rand::SeedableRng;
+
+fn rng() -> rand::rngs::StdRng {
+    rand::rngs::StdRng::seed_from_u64(42)
+}
+
+#[test]
+fn weighted_random_sampler() -> Result<()> {
+    let sampler = WeightedRandomSampler::new(vec![0., 1., 3.], 4000, true)?;
+    let indexes = sampler.indexes(0, &mut rng());
+    assert_eq!(indexes.len(), 4000);
+    let count = |i| indexes.iter().filter(|&&j| j == i).count();
+    assert_eq!(count(0), 0);
+    assert!((800..1200).contains(&count(1)), "{}", count(1));
+
+    // Without replacement each sample with a positive weight is drawn at most once.
+    let sampler = WeightedRandomSampler::new(vec![1., 0., 2., 5.], 3, false)?;
+    let mut indexes = sampler.indexes(0, &mut rng());
+    indexes.sort();
+    assert_eq!(indexes, [0, 2, 3]);
+
+    assert!(WeightedRandomSampler::new(vec![1., 0.], 3, false).is_err());
+    assert!(WeightedRandomSampler::new(vec![1., -1.], 3, true).is_err());
+    assert!(WeightedRandomSampler::new(vec![0., 0.], 3, true).is_err());
+    Ok(())
+}
+
+#[test]
+fn class_balanced_sampler() -> Result<()> {
+    let labels = [vec![0; 90], vec![1; 10]].concat();
+    let sampler = ClassBalancedSampler::new(&labels, 2000)?;
+    assert_eq!(sampler.class_counts(), [90, 10]);
+    let indexes = sampler.indexes(0, &mut rng());
+    let minority = indexes.iter().filter(|&&i| labels[i] == 1).count();
+    assert!((850..1150).contains(&minority), "{minority}");
+    Ok(())
+}
+
+#[test]
+fn curriculum_sampler() -> Result<()> {
+    let schedule = CurriculumSchedule::Linear {
+        start: 0.25,
+        epochs: 3,
+    };
+    assert_eq!(schedule.competence(0), 0.25);
+    assert_eq!(schedule.competence(3), 1.);
+    assert_eq!(schedule.competence(10), 1.);
+    let root = CurriculumSchedule::Root {
+        start: 0.,
+        epochs: 4,
+    };
+    assert_eq!(root.competence(1), 0.5);
+    let steps = CurriculumSchedule::Steps(vec![0.1, 0.5]);
+    assert_eq!(steps.competence(5), 0.5);
+
+    // The difficulty of sample i is 7 - i.
+    let difficulties = (0..8).rev().map(|d| d as f64).collect::<Vec<_>>();
+    let sampler = CurriculumSampler::new(&difficulties, schedule)?.ordered(true);
+    assert_eq!(sampler.pool_size(0), 2);
+    // The epoch cycles over the two easiest samples.
+    let indexes = sampler.indexes(0, &mut rng());
+    assert_eq!(indexes, [7, 7, 7, 7, 6, 6, 6, 6]);
+    let indexes = sampler.indexes(3, &mut rng());
+    assert_eq!(indexes, [7, 6, 5, 4, 3, 2, 1, 0]);
+
+    // Mix in some harder samples.
+    let sampler = sampler.mix(0.25);
+    let indexes = sampler.indexes(0, &mut rng());
+    assert_eq!(indexes.iter().filter(|&&i| i < 6).count(), 2);
+    assert_eq!(indexes.iter().filter(|&&i| i >= 6).count(), 6);
+    Ok(())
+}
+
+#[test]
+fn data_loader_sampler() -> Result<()> {
+    let data = Tensor::arange(0u32, 10, &Device::Cpu)?;
+    let sampler =
+        WeightedRandomSampler::new(vec![0., 0., 1., 0., 0., 0., 0., 0., 0., 0.], 6, true)?;
+    let loader = DataLoader::new(data, stack)
+        .batch_size(4)
+        .shuffle(true)
+        .sampler(sampler)?
+        .num_workers(2);
+    assert_eq!(loader.len(), 2);
+    let batches = loader.iter(0).collect::<Result<Vec<_>>>()?;
+    assert_eq!(batches[0].to_vec1::<u32>()?, [2, 2, 2, 2]);
+    assert_eq!(batches[1].to_vec1::<u32>()?, [2, 2]);
+
+    let difficulties = (0..10).map(|d| d as f64).collect::<Vec<_>>();
+    let schedule = CurriculumSchedule::Steps(vec![0.5, 1.]);
+    let sampler = CurriculumSampler::new(&difficulties, schedule)?.num_samples(5);
+    let loader = DataLoader::new(Tensor::arange(0u32, 10, &Device::Cpu)?, stack)
+        .batch_size(5)
+        .seed(3)
+        .sampler(sampler)?;
+    let mut epoch0 = loader.iter(0).next().unwrap()?.to_vec1::<u32>()?;
+    epoch0.sort();
+    assert_eq!(epoch0, [0, 1, 2, 3, 4]);
+    // The order only depends on the seed and the epoch.
+    assert_eq!(loader.batch_indexes(1), loader.batch_indexes(1),);
+
+    let sampler = WeightedRandomSampler::new(vec![1.; 12], 4, true)?;
+    let data = Tensor::arange(0u32, 10, &Device::Cpu)?;
+    assert!(DataLoader::new(data, stack).sampler(sampler).is_err());
+    Ok(())
+}
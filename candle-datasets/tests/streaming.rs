@@ -0,0 +1,171 @@
+use candle::{Device, Error, Result, Tensor};
+use candle_datasets::streaming::{Shard, StreamCursor, StreamingDataset};
+use candle_datasets::Batcher;
+use parquet::data_type::Int64Type;
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::record::RowAccessor;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+fn tmp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("candle-streaming-{}-{name}", std::process::id()))
+}
+
+// Writes a parquet file with an `id` column, one row group per element of `row_groups`.
+fn write_parquet(name: &str, row_groups: &[Vec<i64>]) -> Result<Shard> {
+    let path = tmp_path(name);
+    let schema =
+        parquet::schema::parser::parse_message_type("message schema { REQUIRED INT64 id; }")
+            .map_err(Error::wrap)?;
+    let props = WriterProperties::builder().build();
+    let file = std::fs::File::create(&path)?;
+    let mut writer =
+        SerializedFileWriter::new(file, Arc::new(schema), Arc::new(props)).map_err(Error::wrap)?;
+    for ids in row_groups.iter() {
+        let mut row_group = writer.next_row_group().map_err(Error::wrap)?;
+        while let Some(mut column) = row_group.next_column().map_err(Error::wrap)? {
+            column
+                .typed::<Int64Type>()
+                .write_batch(ids, None, None)
+                .map_err(Error::wrap)?;
+            column.close().map_err(Error::wrap)?;
+        }
+        row_group.close().map_err(Error::wrap)?;
+    }
+    writer.close().map_err(Error::wrap)?;
+    Ok(Shard::Path(path))
+}
+
+// Writes a tar file with a text and a label file for each key.
+fn write_tar(name: &str, keys: &[&str]) -> Result<Shard> {
+    let path = tmp_path(name);
+    let mut builder = tar::Builder::new(std::fs::File::create(&path)?);
+    for (i, key) in keys.iter().enumerate() {
+        for (ext, content) in [("txt", format!("text {key}")), ("cls", i.to_string())] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, format!("{key}.{ext}"), content.as_bytes())?;
+        }
+    }
+    builder.finish()?;
+    Ok(Shard::Path(path))
+}
+
+fn parquet_ids(shards: Vec<Shard>) -> StreamingDataset<i64> {
+    StreamingDataset::parquet(shards, |row| row.get_long(0).map_err(Error::wrap))
+}
+
+#[test]
+fn streaming_parquet() -> Result<()> {
+    let shards = vec![
+        write_parquet("a.parquet", &[vec![0, 1, 2], vec![3, 4]])?,
+        write_parquet("b.parquet", &[vec![5], vec![6, 7, 8]])?,
+    ];
+    let ids = parquet_ids(shards.clone()).collect::<Result<Vec<_>>>()?;
+    assert_eq!(ids, (0..9).collect::<Vec<_>>());
+
+    // Stop in the second row group of the first shard and resume from there.
+    let mut stream = parquet_ids(shards.clone()).prefetch(1);
+    let first = stream.by_ref().take(4).collect::<Result<Vec<_>>>()?;
+    assert_eq!(first, [0, 1, 2, 3]);
+    let cursor = stream.cursor();
+    assert_eq!(
+        cursor,
+        StreamCursor {
+            shard: 0,
+            offset: 4
+        }
+    );
+    drop(stream);
+    let rest = parquet_ids(shards.clone())
+        .resume_from(cursor)
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(rest, [4, 5, 6, 7, 8]);
+
+    let cursor = StreamCursor {
+        shard: 1,
+        offset: 2,
+    };
+    let rest = parquet_ids(shards.clone())
+        .resume_from(cursor)
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(rest, [7, 8]);
+    for shard in shards {
+        if let Shard::Path(path) = shard {
+            std::fs::remove_file(path)?
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn streaming_webdataset() -> Result<()> {
+    let shards = vec![
+        write_tar("a.tar", &["s/0000", "s/0001"])?,
+        write_tar("b.tar", &["s.v1/0002", "s.v1/0003"])?,
+    ];
+    let decode = |sample: candle_datasets::streaming::Sample| {
+        let label = sample.text("cls")?.parse::<u32>().map_err(Error::wrap)?;
+        Ok((sample.key.clone(), sample.text("txt")?.to_string(), label))
+    };
+    let samples =
+        StreamingDataset::webdataset(shards.clone(), decode).collect::<Result<Vec<_>>>()?;
+    assert_eq!(samples.len(), 4);
+    assert_eq!(
+        samples[0],
+        ("s/0000".to_string(), "text s/0000".to_string(), 0)
+    );
+    assert_eq!(samples[1].2, 1);
+    // The extension starts at the first dot of the file name.
+    assert_eq!(samples[2].0, "s.v1/0002");
+    let keys = StreamingDataset::webdataset(shards.clone(), decode)
+        .resume_from(StreamCursor {
+            shard: 0,
+            offset: 2,
+        })
+        .map(|s| s.map(|s| s.0))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(keys, ["s.v1/0002", "s.v1/0003"]);
+
+    // A missing file results in an error that ends the stream.
+    let stream = StreamingDataset::webdataset(shards.clone(), |s| Ok(s.text("jpg")?.len()));
+    let res = stream.collect::<Vec<_>>();
+    assert_eq!(res.len(), 1);
+    assert!(res[0].is_err());
+    for shard in shards {
+        if let Shard::Path(path) = shard {
+            std::fs::remove_file(path)?
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn streaming_batcher() -> Result<()> {
+    let shard = write_parquet("c.parquet", &[(0..10).collect()])?;
+    let stream = StreamingDataset::parquet(vec![shard.clone()], |row| {
+        let id = row.get_long(0).map_err(Error::wrap)?;
+        Tensor::new(&[id as f32, -id as f32], &Device::Cpu)
+    });
+    let batches = Batcher::new_r1(stream)
+        .batch_size(4)
+        .return_last_incomplete_batch(true)
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(batches.len(), 3);
+    assert_eq!(batches[0].dims(), [4, 2]);
+    assert_eq!(batches[2].dims(), [2, 2]);
+    assert_eq!(
+        batches[1]
+            .narrow(1, 0, 1)?
+            .flatten_all()?
+            .to_vec1::<f32>()?,
+        [4., 5., 6., 7.]
+    );
+    if let Shard::Path(path) = shard {
+        std::fs::remove_file(path)?
+    }
+    Ok(())
+}
@@ -0,0 +1,164 @@
+use candle::{DType, Device, Result, Tensor};
+use candle_datasets::data_loader::stack_pairs;
+use candle_datasets::vision::transforms::{
+    decode_image, hflip, resize, AugmentOp, CenterCrop, Compose, CutMix, Interpolation, MixUp,
+    Normalize, RandAugment, RandomCrop, RandomHorizontalFlip, RandomResizedCrop, ToFloat,
+    Transform,
+};
+use candle_datasets::DataLoader;
+use rand::SeedableRng;
+
+fn rng() -> rand::rngs::StdRng {
+    rand::rngs::StdRng::seed_from_u64(42)
+}
+
+#[test]
+fn decode() -> Result<()> {
+    let img = image::RgbImage::from_fn(3, 2, |x, y| image::Rgb([x as u8, y as u8, 7]));
+    let mut bytes = std::io::Cursor::new(vec![]);
+    img.write_to(&mut bytes, image::ImageFormat::Png)
+        .map_err(candle::Error::wrap)?;
+    let img = decode_image(bytes.get_ref())?;
+    assert_eq!(img.dims(), [3, 2, 3]);
+    assert_eq!(img.dtype(), DType::U8);
+    assert_eq!(img.get(0)?.to_vec2::<u8>()?, [[0, 1, 2], [0, 1, 2]]);
+    assert_eq!(img.get(1)?.to_vec2::<u8>()?, [[0, 0, 0], [1, 1, 1]]);
+    assert_eq!(img.get(2)?.to_vec2::<u8>()?, [[7, 7, 7], [7, 7, 7]]);
+    let img = ToFloat.apply(&img, &mut rng())?;
+    assert_eq!(img.get(1)?.get(1)?.to_vec1::<f32>()?, [1. / 255.; 3]);
+    Ok(())
+}
+
+#[test]
+fn resize_crop_flip() -> Result<()> {
+    let dev = &Device::Cpu;
+    let img = Tensor::arange(0f32, 16., dev)?.reshape((1, 4, 4))?;
+    // Downsampling by 2 averages the 2x2 blocks.
+    let ys = resize(&img, 2, 2, Interpolation::Bilinear)?;
+    assert_eq!(ys.to_vec3::<f32>()?, [[[2.5, 4.5], [10.5, 12.5]]]);
+    let ys = resize(&img, 2, 2, Interpolation::Nearest)?;
+    assert_eq!(ys.to_vec3::<f32>()?, [[[0., 2.], [8., 10.]]]);
+    // Constant images stay constant.
+    let ones = Tensor::ones((3, 5, 7), DType::F32, dev)?;
+    for interpolation in [Interpolation::Bilinear, Interpolation::Bicubic] {
+        let ys = resize(&ones, 9, 4, interpolation)?;
+        assert_eq!(ys.dims(), [3, 9, 4]);
+        let diff = (ys - 1.)?
+            .abs()?
+            .flatten_all()?
+            .max(0)?
+            .to_scalar::<f32>()?;
+        assert!(diff < 1e-5, "{interpolation:?} {diff}");
+    }
+
+    let ys = CenterCrop::new(2, 2).apply(&img, &mut rng())?;
+    assert_eq!(ys.to_vec3::<f32>()?, [[[5., 6.], [9., 10.]]]);
+    let ys = RandomCrop::new(4, 4).padding(1).apply(&img, &mut rng())?;
+    assert_eq!(ys.dims(), [1, 4, 4]);
+    assert!(ys.sum_all()?.to_scalar::<f32>()? <= 120.);
+    let ys = RandomResizedCrop::new(3, 3).apply(&img, &mut rng())?;
+    assert_eq!(ys.dims(), [1, 3, 3]);
+    assert_eq!(
+        hflip(&img)?.get(0)?.get(0)?.to_vec1::<f32>()?,
+        [3., 2., 1., 0.]
+    );
+    let ys = RandomHorizontalFlip::new(1.).apply(&img, &mut rng())?;
+    assert_eq!(ys.get(0)?.get(1)?.to_vec1::<f32>()?, [7., 6., 5., 4.]);
+
+    let ys = Normalize::new(&[1.], &[2.]).apply(&img, &mut rng())?;
+    assert_eq!(ys.get(0)?.get(0)?.to_vec1::<f32>()?, [-0.5, 0., 0.5, 1.]);
+    Ok(())
+}
+
+#[test]
+fn rand_augment() -> Result<()> {
+    let dev = &Device::Cpu;
+    let img = (Tensor::arange(0f32, 48., dev)?.reshape((3, 4, 4))? / 48.)?;
+    // Only the values above the threshold are inverted.
+    let ys = AugmentOp::Solarize.apply(&img, 0.5)?;
+    let diff = ((&ys + &img)? - 1.)?.get(2)?.get(3)?.abs()?.max(0)?;
+    assert!(diff.to_scalar::<f32>()? < 1e-6);
+    assert_eq!(ys.get(0)?.to_vec2::<f32>()?, img.get(0)?.to_vec2::<f32>()?);
+    let ys = AugmentOp::Posterize.apply(&img, 1.)?;
+    let values = ys.flatten_all()?.to_vec1::<f32>()?;
+    assert!(values.iter().all(|&v| v == 0. || v == 128. / 255.));
+    let ys = AugmentOp::AutoContrast.apply(&img, 0.)?;
+    assert_eq!(ys.get(2)?.get(3)?.get(3)?.to_scalar::<f32>()?, 1.);
+    // A translation by the full width leaves an empty image.
+    let ys = AugmentOp::TranslateX.apply(&img, 4.)?;
+    assert_eq!(ys.sum_all()?.to_scalar::<f32>()?, 0.);
+
+    let transform = RandAugment::new(3, 9);
+    for _ in 0..20 {
+        let ys = transform.apply(&img, &mut rand::thread_rng())?;
+        assert_eq!(ys.dims(), [3, 4, 4]);
+        let min = ys.flatten_all()?.min(0)?.to_scalar::<f32>()?;
+        let max = ys.flatten_all()?.max(0)?.to_scalar::<f32>()?;
+        assert!(min >= 0. && max <= 1.);
+    }
+    // The same seed results in the same augmentations.
+    let ys1 = transform
+        .apply(&img, &mut rng())?
+        .flatten_all()?
+        .to_vec1::<f32>()?;
+    let ys2 = transform
+        .apply(&img, &mut rng())?
+        .flatten_all()?
+        .to_vec1::<f32>()?;
+    assert_eq!(ys1, ys2);
+    Ok(())
+}
+
+#[test]
+fn mixup_cutmix() -> Result<()> {
+    let dev = &Device::Cpu;
+    let images = Tensor::arange(0f32, 4., dev)?
+        .reshape((4, 1, 1, 1))?
+        .broadcast_as((4, 3, 8, 8))?
+        .contiguous()?;
+    let labels = Tensor::new(&[0u32, 1, 2, 3], dev)?;
+    for mixed in [
+        MixUp::new(1., 5).apply(&images, &labels, &mut rng())?,
+        CutMix::new(1., 5).apply(&images, &labels, &mut rng())?,
+    ] {
+        let (xs, targets) = mixed;
+        assert_eq!(xs.dims(), [4, 3, 8, 8]);
+        assert_eq!(targets.dims(), [4, 5]);
+        let sums = targets.sum(1)?.to_vec1::<f32>()?;
+        assert!(sums.iter().all(|s| (s - 1.).abs() < 1e-5));
+        // The targets match the mean of the mixed images.
+        let t = targets.to_vec2::<f32>()?;
+        let means = xs.mean_keepdim(3)?.mean_keepdim(2)?.mean_keepdim(1)?;
+        let means = means.flatten_all()?.to_vec1::<f32>()?;
+        for (i, mean) in means.iter().enumerate() {
+            let expected = t[i]
+                .iter()
+                .enumerate()
+                .map(|(c, w)| c as f32 * w)
+                .sum::<f32>();
+            assert!((mean - expected).abs() < 1e-4, "{i} {mean} {expected}");
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn transforms_data_loader() -> Result<()> {
+    let dev = &Device::Cpu;
+    let images = Tensor::arange(0u8, 120, dev)?.reshape((10, 3, 2, 2))?;
+    let labels = Tensor::arange(0u32, 10, dev)?;
+    let transform = Compose::new()
+        .then(ToFloat)
+        .then(RandomHorizontalFlip::new(0.5))
+        .then(Normalize::new(&[0.5; 3], &[0.5; 3]));
+    let loader = DataLoader::new((images, labels), stack_pairs)
+        .batch_size(4)
+        .map(transform.map_pairs())
+        .num_workers(2);
+    let batches = loader.iter(0).collect::<Result<Vec<_>>>()?;
+    assert_eq!(batches.len(), 3);
+    assert_eq!(batches[0].0.dims(), [4, 3, 2, 2]);
+    assert_eq!(batches[0].0.dtype(), DType::F32);
+    assert_eq!(batches[2].1.to_vec1::<u32>()?, [8, 9]);
+    Ok(())
+}
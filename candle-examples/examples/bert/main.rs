@@ -8,9 +8,10 @@ use candle_transformers::models::bert::{BertModel, Config, HiddenAct, DTYPE};
 use anyhow::{Error as E, Result};
 use candle::Tensor;
 use candle_nn::VarBuilder;
+use candle_transformers::tokenization::TokenizedBatch;
 use clap::Parser;
 use hf_hub::{api::sync::Api, Repo, RepoType};
-use tokenizers::{PaddingParams, Tokenizer};
+use tokenizers::Tokenizer;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -144,35 +145,12 @@ fn main() -> Result<()> {
             "Do you like pizza?",
         ];
         let n_sentences = sentences.len();
-        if let Some(pp) = tokenizer.get_padding_mut() {
-            pp.strategy = tokenizers::PaddingStrategy::BatchLongest
-        } else {
-            let pp = PaddingParams {
-                strategy: tokenizers::PaddingStrategy::BatchLongest,
-                ..Default::default()
-            };
-            tokenizer.with_padding(Some(pp));
-        }
-        let tokens = tokenizer
-            .encode_batch(sentences.to_vec(), true)
-            .map_err(E::msg)?;
-        let token_ids = tokens
-            .iter()
-            .map(|tokens| {
-                let tokens = tokens.get_ids().to_vec();
-                Ok(Tensor::new(tokens.as_slice(), device)?)
-            })
-            .collect::<Result<Vec<_>>>()?;
-        let attention_mask = tokens
-            .iter()
-            .map(|tokens| {
-                let tokens = tokens.get_attention_mask().to_vec();
-                Ok(Tensor::new(tokens.as_slice(), device)?)
-            })
-            .collect::<Result<Vec<_>>>()?;
-
-        let token_ids = Tensor::stack(&token_ids, 0)?;
-        let attention_mask = Tensor::stack(&attention_mask, 0)?;
+        let pad_id = tokenizer.get_padding().map_or(0, |pp| pp.pad_id);
+        let batch = TokenizedBatch::builder(pad_id)
+            .device(device)
+            .encode(&tokenizer, &sentences, true)?;
+        let token_ids = batch.input_ids;
+        let attention_mask = batch.attention_mask;
         let token_type_ids = token_ids.zeros_like()?;
         println!("running inference on batch {:?}", token_ids.shape());
         let embeddings = model.forward(&token_ids, &token_type_ids, Some(&attention_mask))?;
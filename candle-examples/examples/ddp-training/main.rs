@@ -0,0 +1,116 @@
+// Data parallel training of a linear regression model.
+//
+// Each rank generates its own shard of the training data and the gradients are averaged across
+// the ranks at each step. To run on two machines, start rank 0 first:
+//   cargo run --example ddp-training --release -- --rank 0 --world-size 2 --master-addr 10.0.0.1:29500
+//   cargo run --example ddp-training --release -- --rank 1 --world-size 2 --master-addr 10.0.0.1:29500
+// When no rank is specified, all the ranks are run as threads of the current process.
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use anyhow::Result;
+use clap::{Parser, ValueEnum};
+
+use candle::collective::{init_process_group, Backend};
+use candle::{DType, Device, Tensor, D};
+use candle_nn::ddp::DistributedDataParallel;
+use candle_nn::{Module, Optimizer, VarBuilder, VarMap, SGD};
+
+#[derive(Clone, Debug, Copy, PartialEq, Eq, ValueEnum)]
+enum Which {
+    Tcp,
+    Nccl,
+}
+
+#[derive(Parser, Debug, Clone)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// The rank of the current process, all the ranks are run locally if not specified.
+    #[arg(long)]
+    rank: Option<usize>,
+
+    #[arg(long, default_value_t = 2)]
+    world_size: usize,
+
+    /// The address used by rank 0 to listen for the other ranks.
+    #[arg(long, default_value = "127.0.0.1:29500")]
+    master_addr: String,
+
+    /// The communication backend, nccl requires a cuda device per rank.
+    #[arg(long, value_enum, default_value = "tcp")]
+    backend: Which,
+
+    /// The number of samples per rank.
+    #[arg(long, default_value_t = 256)]
+    samples: usize,
+
+    #[arg(long, default_value_t = 100)]
+    steps: usize,
+
+    #[arg(long, default_value_t = 0.1)]
+    learning_rate: f64,
+}
+
+fn train(args: &Args, rank: usize) -> Result<()> {
+    let (backend, device) = match args.backend {
+        Which::Tcp => (Backend::Tcp, Device::Cpu),
+        Which::Nccl => (Backend::Nccl, Device::new_cuda(rank)?),
+    };
+    let comm = init_process_group(backend, &device, &args.master_addr, rank, args.world_size)?;
+
+    // The ground truth is y = 2 x0 - 3.4 x1 + 4.2, each rank has its own samples.
+    let xs = Tensor::randn(0f32, 1., (args.samples, 2), &device)?;
+    let w = Tensor::new(&[[2f32], [-3.4]], &device)?;
+    let noise = Tensor::randn(0f32, 0.01, (args.samples, 1), &device)?;
+    let ys = ((xs.matmul(&w)? + 4.2)? + noise)?;
+
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    let model = candle_nn::linear(2, 1, vb)?;
+    let ddp = DistributedDataParallel::from_varmap(&varmap, comm)?;
+    let mut opt = SGD::new(varmap.all_vars(), args.learning_rate)?;
+    for step in 0..args.steps {
+        let loss = candle_nn::loss::mse(&model.forward(&xs)?, &ys)?;
+        ddp.backward_step(&mut opt, &loss)?;
+        if rank == 0 && (step + 1) % 10 == 0 {
+            println!("step {:4} loss {:8.5}", step + 1, loss.to_scalar::<f32>()?);
+        }
+    }
+    if rank == 0 {
+        let w = model.weight().flatten_all()?.to_vec1::<f32>()?;
+        let b = model.bias().unwrap().to_vec1::<f32>()?;
+        println!("learned w: {w:?}, b: {b:?}");
+    }
+    // All the ranks should end up with the same weights.
+    let w = model.weight().flatten_all()?;
+    let max_w = ddp
+        .comm()
+        .all_reduce(&w, candle::collective::ReduceOp::Max)?;
+    let diff = (max_w - &w)?.abs()?.max(D::Minus1)?.to_scalar::<f32>()?;
+    if diff > 0. {
+        anyhow::bail!("rank {rank}: weights diverged from the other ranks by {diff}")
+    }
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    match args.rank {
+        Some(rank) => train(&args, rank),
+        None => std::thread::scope(|s| {
+            let handles = (0..args.world_size)
+                .map(|rank| {
+                    let args = &args;
+                    s.spawn(move || train(args, rank))
+                })
+                .collect::<Vec<_>>();
+            for handle in handles {
+                handle.join().unwrap()?
+            }
+            Ok(())
+        }),
+    }
+}
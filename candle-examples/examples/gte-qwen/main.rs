@@ -9,13 +9,11 @@ use clap::Parser;
 
 use candle_transformers::models::qwen2::{Config, Model};
 
-use candle::{DType, Tensor};
+use candle::DType;
 use candle_nn::VarBuilder;
+use candle_transformers::tokenization::{PaddingSide, TokenizedBatch};
 use hf_hub::{api::sync::Api, Repo, RepoType};
-use tokenizers::{
-    utils::padding::{PaddingDirection, PaddingParams, PaddingStrategy},
-    Tokenizer,
-};
+use tokenizers::Tokenizer;
 
 // gte-Qwen1.5-7B-instruct use EOS token as padding token
 const EOS_TOKEN: &str = "<|endoftext|>";
@@ -115,19 +113,8 @@ fn main() -> Result<()> {
     };
     println!("Model file retrieved in {:?}", start.elapsed());
 
-    // Inputs will be padded to the longest sequence in the batch.
-    let padding = PaddingParams {
-        strategy: PaddingStrategy::BatchLongest,
-        direction: PaddingDirection::Left,
-        pad_to_multiple_of: None,
-        pad_id: EOS_TOKEN_ID,
-        pad_type_id: 0,
-        pad_token: String::from(EOS_TOKEN),
-    };
-
     // Tokenizer setup
-    let mut tokenizer = Tokenizer::from_file(config_files.tokenizer).map_err(E::msg)?;
-    tokenizer.with_padding(Some(padding));
+    let tokenizer = Tokenizer::from_file(config_files.tokenizer).map_err(E::msg)?;
 
     // Model initialization
     let device = candle_examples::device(args.cpu)?;
@@ -149,11 +136,13 @@ fn main() -> Result<()> {
         format!("As a general guideline, the CDC's average requirement of protein for women ages 19 to 70 is 46 grams per day. But, as you can see from this chart, you'll need to increase that if you're expecting or training for a marathon. Check out the chart below to see how much protein you should be eating each day.{EOS_TOKEN}"),
         format!("Definition of summit for English Language Learners. : 1  the highest point of a mountain : the top of a mountain. : 2  the highest level. : 3  a meeting or series of meetings between the leaders of two or more governments.{EOS_TOKEN}"),
     ];
-    let encoded = tokenizer.encode_batch(documents, true).map_err(E::msg)?;
-    let tokens: Vec<&[u32]> = encoded.iter().map(|x| x.get_ids()).collect();
-    let tokens = Tensor::new(tokens, &device)?;
-    let mask: Vec<&[u32]> = encoded.iter().map(|x| x.get_attention_mask()).collect();
-    let mask = Tensor::new(mask, &device)?;
+    // Inputs are padded on the left to the longest sequence in the batch.
+    let batch = TokenizedBatch::builder(EOS_TOKEN_ID)
+        .padding_side(PaddingSide::Left)
+        .device(&device)
+        .encode(&tokenizer, &documents, true)?;
+    let tokens = batch.input_ids;
+    let mask = batch.attention_mask;
 
     // Inference
     let start_gen = std::time::Instant::now();
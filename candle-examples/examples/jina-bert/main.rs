@@ -5,6 +5,7 @@ extern crate intel_mkl_src;
 extern crate accelerate_src;
 
 use candle_transformers::models::jina_bert::{BertModel, Config, PositionEmbeddingType};
+use candle_transformers::tokenization::TokenizedBatch;
 
 use anyhow::Error as E;
 use candle::{DType, Module, Tensor};
@@ -142,27 +143,11 @@ fn main() -> anyhow::Result<()> {
             "Do you like pizza?",
         ];
         let n_sentences = sentences.len();
-        if let Some(pp) = tokenizer.get_padding_mut() {
-            pp.strategy = tokenizers::PaddingStrategy::BatchLongest
-        } else {
-            let pp = tokenizers::PaddingParams {
-                strategy: tokenizers::PaddingStrategy::BatchLongest,
-                ..Default::default()
-            };
-            tokenizer.with_padding(Some(pp));
-        }
-        let tokens = tokenizer
-            .encode_batch(sentences.to_vec(), true)
-            .map_err(E::msg)?;
-        let token_ids = tokens
-            .iter()
-            .map(|tokens| {
-                let tokens = tokens.get_ids().to_vec();
-                Tensor::new(tokens.as_slice(), device)
-            })
-            .collect::<candle::Result<Vec<_>>>()?;
-
-        let token_ids = Tensor::stack(&token_ids, 0)?;
+        let pad_id = tokenizer.get_padding().map_or(0, |pp| pp.pad_id);
+        let token_ids = TokenizedBatch::builder(pad_id)
+            .device(device)
+            .encode(&tokenizer, &sentences, true)?
+            .input_ids;
         println!("running inference on batch {:?}", token_ids.shape());
         let embeddings = model.forward(&token_ids)?;
         println!("generated embeddings {:?}", embeddings.shape());
@@ -12,6 +12,7 @@ use candle_transformers::models::llava::config::{
     HFGenerationConfig, HFLLaVAConfig, HFPreProcessorConfig,
 };
 use candle_transformers::models::llava::{config::LLaVAConfig, LLaVA};
+use candle_transformers::models::siglip;
 use clap::Parser;
 use constants::*;
 use conversation::Conversation;
@@ -203,7 +204,18 @@ fn main() -> Result<()> {
     let weight_filenames =
         candle_examples::hub_load_safetensors(&api, "model.safetensors.index.json")?;
     let vb = unsafe { VarBuilder::from_mmaped_safetensors(&weight_filenames, dtype, &device)? };
-    let llava: LLaVA = LLaVA::load(vb, &llava_config, clip_vision_config)?;
+    // Some LLaVA variants replace the CLIP image encoder with a SigLIP one.
+    let llava: LLaVA = match llava_config.mm_vision_tower.as_deref() {
+        Some(tower) if tower.contains("siglip") => {
+            let siglip_config = if tower.contains("so400m") {
+                siglip::VisionConfig::so400m_patch14_384()
+            } else {
+                siglip::VisionConfig::base_patch16_224()
+            };
+            LLaVA::load_siglip(vb, &llava_config, &siglip_config)?
+        }
+        _ => LLaVA::load(vb, &llava_config, clip_vision_config)?,
+    };
 
     println!("generating conv template");
     let image_token_se = format!(
@@ -125,12 +125,13 @@ pub fn main() -> anyhow::Result<()> {
         model.encoder().forward(&tokens, 0)?
     };
 
+    let cross_kv = model.cross_kv(&encoder_xs)?;
     let mut token_ids = vec![config.decoder_start_token_id];
     for index in 0..1000 {
         let context_size = if index >= 1 { 1 } else { token_ids.len() };
         let start_pos = token_ids.len().saturating_sub(context_size);
         let input_ids = Tensor::new(&token_ids[start_pos..], &device)?.unsqueeze(0)?;
-        let logits = model.decode(&input_ids, &encoder_xs, start_pos)?;
+        let logits = model.decode_with_cross_kv(&input_ids, &cross_kv, start_pos)?;
         let logits = logits.squeeze(0)?;
         let logits = logits.get(logits.dim(0)? - 1)?;
         let token = logits_processor.sample(&logits)?;
@@ -186,6 +186,7 @@ fn main() -> Result<()> {
     };
     let mut logits_processor = LogitsProcessor::new(299792458, temperature, args.top_p);
     let encoder_output = model.encode(&input_token_ids)?;
+    let cross_kv = model.cross_kv(&encoder_output)?;
     let start = std::time::Instant::now();
 
     for index in 0.. {
@@ -199,7 +200,7 @@ fn main() -> Result<()> {
             Tensor::new(&[last_token], device)?.unsqueeze(0)?
         };
         let logits = model
-            .decode(&decoder_token_ids, &encoder_output)?
+            .decode_with_cross_kv(&decoder_token_ids, &cross_kv)?
             .squeeze(0)?;
         let logits = if args.repeat_penalty == 1. {
             logits
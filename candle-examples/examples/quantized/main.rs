@@ -11,6 +11,7 @@ use tokenizers::Tokenizer;
 use candle::quantized::{ggml_file, gguf_file};
 use candle::Tensor;
 use candle_transformers::generation::{LogitsProcessor, Sampling};
+use candle_transformers::utils::DeviceMap;
 
 use candle_examples::token_output_stream::TokenOutputStream;
 use candle_transformers::models::quantized_llama as model;
@@ -236,6 +237,16 @@ struct Args {
     #[arg(long)]
     cpu: bool,
 
+    /// The number of transformer blocks to run on the GPU, the other ones are run on the CPU.
+    /// By default all the blocks are run on the GPU, this only applies to gguf files.
+    #[arg(long)]
+    n_gpu_layers: Option<usize>,
+
+    /// Keep the weights of the blocks beyond `n_gpu_layers` on the CPU but run these blocks on
+    /// the GPU, copying the weights of the next block while the current one runs.
+    #[arg(long)]
+    prefetch_layers: bool,
+
     /// Penalty to be applied for repeating tokens, 1. means no penalty.
     #[arg(long, default_value_t = 1.1)]
     repeat_penalty: f32,
@@ -426,9 +437,17 @@ fn main() -> anyhow::Result<()> {
                 &format_size(total_size_in_bytes),
                 start.elapsed().as_secs_f32(),
             );
-            ModelWeights::from_gguf(model, &mut file, &device)?
+            let device_map = match args.n_gpu_layers {
+                None => DeviceMap::all(&device),
+                Some(n_gpu_layers) => DeviceMap::new(&device, n_gpu_layers),
+            };
+            let device_map = device_map.with_prefetch(args.prefetch_layers);
+            ModelWeights::from_gguf_with_device_map(model, &mut file, &device_map)?
         }
         Some("ggml" | "bin") | Some(_) | None => {
+            if args.n_gpu_layers.is_some() || args.prefetch_layers {
+                anyhow::bail!("--n-gpu-layers and --prefetch-layers only apply to gguf files")
+            }
             let model = ggml_file::Content::read(&mut file, &device)
                 .map_err(|e| e.with_path(model_path))?;
             let mut total_size_in_bytes = 0;
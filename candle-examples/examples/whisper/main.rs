@@ -63,6 +63,24 @@ impl Model {
     }
 }
 
+impl m::streaming::WhisperModel for Model {
+    fn config(&self) -> &Config {
+        self.config()
+    }
+
+    fn encoder_forward(&mut self, x: &Tensor, flush: bool) -> candle::Result<Tensor> {
+        self.encoder_forward(x, flush)
+    }
+
+    fn decoder_forward(&mut self, x: &Tensor, xa: &Tensor, flush: bool) -> candle::Result<Tensor> {
+        self.decoder_forward(x, xa, flush)
+    }
+
+    fn decoder_final_linear(&self, x: &Tensor) -> candle::Result<Tensor> {
+        self.decoder_final_linear(x)
+    }
+}
+
 #[allow(dead_code)]
 #[derive(Debug, Clone)]
 struct DecodingResult {
@@ -340,6 +358,50 @@ impl Decoder {
     }
 }
 
+// Feeds the audio to the streaming transcriber in one second chunks, as a live source would.
+fn run_streaming(
+    model: Model,
+    tokenizer: &Tokenizer,
+    mel_filters: Vec<f32>,
+    pcm_data: &[f32],
+    config: m::streaming::StreamingConfig,
+    device: &Device,
+) -> Result<()> {
+    let special_tokens = m::streaming::SpecialTokens::from_tokenizer(tokenizer)?;
+    let mut st = m::streaming::StreamingTranscriber::new(
+        model,
+        special_tokens,
+        mel_filters,
+        config,
+        device,
+    )?;
+    let print_segments = |segments: Vec<m::streaming::Segment>| -> Result<()> {
+        for segment in segments.iter() {
+            let text = tokenizer.decode(&segment.tokens, true).map_err(E::msg)?;
+            println!(
+                "{:.2}s -- {:.2}s: {}",
+                segment.start,
+                segment.end,
+                text.trim()
+            );
+            let words = segment.words(|tokens| {
+                tokenizer
+                    .decode(tokens, true)
+                    .map_err(|e| candle::Error::Msg(e.to_string()))
+            })?;
+            for word in words.iter() {
+                println!("  {:.2}s-{:.2}s: {}", word.start, word.end, word.text)
+            }
+        }
+        Ok(())
+    };
+    for chunk in pcm_data.chunks(m::SAMPLE_RATE) {
+        print_segments(st.push(chunk)?)?;
+    }
+    print_segments(st.flush()?)?;
+    Ok(())
+}
+
 pub fn token_id(tokenizer: &Tokenizer, token: &str) -> candle::Result<u32> {
     match tokenizer.token_to_id(token) {
         None => candle::bail!("no token-id for {token}"),
@@ -468,6 +530,11 @@ struct Args {
     /// Print the full DecodingResult structure rather than just the text.
     #[arg(long)]
     verbose: bool,
+
+    /// Transcribe the audio with the streaming api, feeding it in one second chunks and
+    /// printing segments and word timestamps as they get completed.
+    #[arg(long)]
+    streaming: bool,
 }
 
 fn main() -> Result<()> {
@@ -567,6 +634,32 @@ fn main() -> Result<()> {
         Model::Normal(m::model::Whisper::load(&vb, config)?)
     };
 
+    if args.streaming {
+        let language = match &args.language {
+            None => None,
+            Some(language) => Some(token_id(&tokenizer, &format!("<|{language}|>"))?),
+        };
+        let language_candidates = if args.model.is_multilingual() {
+            multilingual::LANGUAGES
+                .iter()
+                .map(|(t, _)| token_id(&tokenizer, &format!("<|{t}|>")))
+                .collect::<candle::Result<Vec<_>>>()?
+        } else {
+            vec![]
+        };
+        let task = match args.task {
+            None | Some(Task::Transcribe) => m::streaming::Task::Transcribe,
+            Some(Task::Translate) => m::streaming::Task::Translate,
+        };
+        let config = m::streaming::StreamingConfig {
+            task,
+            language,
+            language_candidates,
+            ..Default::default()
+        };
+        return run_streaming(model, &tokenizer, mel_filters, &pcm_data, config, &device);
+    }
+
     let language_token = match (args.model.is_multilingual(), args.language) {
         (true, None) => Some(multilingual::detect_language(&mut model, &tokenizer, &mel)?),
         (false, None) => None,
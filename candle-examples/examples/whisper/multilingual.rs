@@ -1,7 +1,7 @@
 use candle::{IndexOp, Result, Tensor, D};
 use tokenizers::Tokenizer;
 
-const LANGUAGES: [(&str, &str); 99] = [
+pub const LANGUAGES: [(&str, &str); 99] = [
     ("en", "english"),
     ("zh", "chinese"),
     ("de", "german"),
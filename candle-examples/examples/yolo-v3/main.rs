@@ -4,7 +4,7 @@ extern crate intel_mkl_src;
 #[cfg(feature = "accelerate")]
 extern crate accelerate_src;
 
-use candle_transformers::object_detection::{non_maximum_suppression, Bbox};
+use candle_transformers::object_detection::{batched_nms_bboxes, Bbox};
 mod darknet;
 
 use anyhow::Result;
@@ -43,36 +43,27 @@ pub fn report(
     confidence_threshold: f32,
     nms_threshold: f32,
 ) -> Result<DynamicImage> {
-    let pred = pred.to_device(&Device::Cpu)?;
-    let (npreds, pred_size) = pred.dims2()?;
+    let (_npreds, pred_size) = pred.dims2()?;
     let nclasses = pred_size - 5;
+    // The predictions are filtered and suppressed on the device, only the kept boxes are copied
+    // back to the host.
+    let centers = pred.narrow(1, 0, 2)?;
+    let half_sizes = (pred.narrow(1, 2, 2)? / 2.)?;
+    let boxes = Tensor::cat(&[(&centers - &half_sizes)?, (&centers + &half_sizes)?], 1)?;
+    let class_scores = pred.narrow(1, 5, nclasses)?;
+    // Boxes for which the best class has a non-positive score are discarded.
+    let positive = class_scores.max(1)?.gt(0.)?.to_dtype(pred.dtype())?;
+    let confidences = (pred.narrow(1, 4, 1)?.squeeze(1)? * positive)?;
+    let classes = class_scores.argmax(1)?;
     // The bounding boxes grouped by (maximum) class index.
-    let mut bboxes: Vec<Vec<Bbox<()>>> = (0..nclasses).map(|_| vec![]).collect();
-    // Extract the bounding boxes for which confidence is above the threshold.
-    for index in 0..npreds {
-        let pred = Vec::<f32>::try_from(pred.get(index)?)?;
-        let confidence = pred[4];
-        if confidence > confidence_threshold {
-            let mut class_index = 0;
-            for i in 0..nclasses {
-                if pred[5 + i] > pred[5 + class_index] {
-                    class_index = i
-                }
-            }
-            if pred[class_index + 5] > 0. {
-                let bbox = Bbox {
-                    xmin: pred[0] - pred[2] / 2.,
-                    ymin: pred[1] - pred[3] / 2.,
-                    xmax: pred[0] + pred[2] / 2.,
-                    ymax: pred[1] + pred[3] / 2.,
-                    confidence,
-                    data: (),
-                };
-                bboxes[class_index].push(bbox)
-            }
-        }
-    }
-    non_maximum_suppression(&mut bboxes, nms_threshold);
+    let bboxes: Vec<Vec<Bbox<()>>> = batched_nms_bboxes(
+        &boxes,
+        &confidences,
+        &classes,
+        nclasses,
+        confidence_threshold,
+        nms_threshold,
+    )?;
     // Annotate the original image and print boxes information.
     let (initial_h, initial_w) = (img.height(), img.width());
     let w_ratio = initial_w as f32 / w as f32;
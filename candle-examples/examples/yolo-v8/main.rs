@@ -9,7 +9,9 @@ use model::{Multiples, YoloV8, YoloV8Pose};
 
 use candle::{DType, Device, IndexOp, Result, Tensor};
 use candle_nn::{Module, VarBuilder};
-use candle_transformers::object_detection::{non_maximum_suppression, Bbox, KeyPoint};
+use candle_transformers::object_detection::{
+    batched_nms_bboxes, non_maximum_suppression, Bbox, KeyPoint,
+};
 use clap::{Parser, ValueEnum};
 use image::DynamicImage;
 
@@ -61,37 +63,26 @@ pub fn report_detect(
     nms_threshold: f32,
     legend_size: u32,
 ) -> Result<DynamicImage> {
-    let pred = pred.to_device(&Device::Cpu)?;
-    let (pred_size, npreds) = pred.dims2()?;
+    let (pred_size, _npreds) = pred.dims2()?;
     let nclasses = pred_size - 4;
+    // The predictions are filtered and suppressed on the device, only the kept boxes are copied
+    // back to the host.
+    let pred = pred.t()?;
+    let centers = pred.narrow(1, 0, 2)?;
+    let half_sizes = (pred.narrow(1, 2, 2)? / 2.)?;
+    let boxes = Tensor::cat(&[(&centers - &half_sizes)?, (&centers + &half_sizes)?], 1)?;
+    let class_scores = pred.narrow(1, 4, nclasses)?;
+    let confidences = class_scores.max(1)?;
+    let classes = class_scores.argmax(1)?;
     // The bounding boxes grouped by (maximum) class index.
-    let mut bboxes: Vec<Vec<Bbox<Vec<KeyPoint>>>> = (0..nclasses).map(|_| vec![]).collect();
-    // Extract the bounding boxes for which confidence is above the threshold.
-    for index in 0..npreds {
-        let pred = Vec::<f32>::try_from(pred.i((.., index))?)?;
-        let confidence = *pred[4..].iter().max_by(|x, y| x.total_cmp(y)).unwrap();
-        if confidence > confidence_threshold {
-            let mut class_index = 0;
-            for i in 0..nclasses {
-                if pred[4 + i] > pred[4 + class_index] {
-                    class_index = i
-                }
-            }
-            if pred[class_index + 4] > 0. {
-                let bbox = Bbox {
-                    xmin: pred[0] - pred[2] / 2.,
-                    ymin: pred[1] - pred[3] / 2.,
-                    xmax: pred[0] + pred[2] / 2.,
-                    ymax: pred[1] + pred[3] / 2.,
-                    confidence,
-                    data: vec![],
-                };
-                bboxes[class_index].push(bbox)
-            }
-        }
-    }
-
-    non_maximum_suppression(&mut bboxes, nms_threshold);
+    let bboxes: Vec<Vec<Bbox<Vec<KeyPoint>>>> = batched_nms_bboxes(
+        &boxes,
+        &confidences,
+        &classes,
+        nclasses,
+        confidence_threshold,
+        nms_threshold,
+    )?;
 
     // Annotate the original image and print boxes information.
     let (initial_h, initial_w) = (img.height(), img.width());
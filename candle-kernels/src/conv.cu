@@ -37,7 +37,7 @@ __device__ void conv1d(
   const size_t src_idx0 = b_idx * src_s[0];
   A d = 0;
   for (size_t offset = 0; offset < k_size; ++offset) {
-    size_t src_l = (stride * dst_l + offset) * dilation;
+    size_t src_l = stride * dst_l + offset * dilation;
     if (src_l < padding || src_l >= padding + l_in) {
       continue;
     }
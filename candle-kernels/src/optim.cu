@@ -0,0 +1,80 @@
+// Fused optimizer updates, these operate in place on contiguous buffers. The half precision
+// types are updated using f32 arithmetic.
+#include "cuda_utils.cuh"
+#include<stdint.h>
+
+// acc = beta * acc + (1 - beta) * grad, or grad^2 when square is set, as for the first and second
+// moments of Adam.
+template<typename T, typename A>
+__device__ void adamw_moment(
+    const size_t numel,
+    T *acc,
+    const T *grad,
+    const double beta,
+    const bool square
+) {
+    const A b = static_cast<A>(beta);
+    for (unsigned int i = blockIdx.x * blockDim.x + threadIdx.x; i < numel; i += blockDim.x * gridDim.x) {
+        A g = static_cast<A>(grad[i]);
+        if (square) g = g * g;
+        acc[i] = static_cast<T>(b * static_cast<A>(acc[i]) + (static_cast<A>(1) - b) * g);
+    }
+}
+
+// theta = theta * (1 - lr * lambda) - lr * m_hat / (sqrt(v_hat) + eps), with the bias
+// corrected moments m_hat = m * scale_m and v_hat = v * scale_v.
+template<typename T, typename A>
+__device__ void adamw_update(
+    const size_t numel,
+    T *theta,
+    const T *m,
+    const T *v,
+    const double lr,
+    const double lr_lambda,
+    const double scale_m,
+    const double scale_v,
+    const double eps
+) {
+    const A decay = static_cast<A>(1. - lr_lambda);
+    for (unsigned int i = blockIdx.x * blockDim.x + threadIdx.x; i < numel; i += blockDim.x * gridDim.x) {
+        A m_hat = static_cast<A>(m[i]) * static_cast<A>(scale_m);
+        A v_hat = static_cast<A>(v[i]) * static_cast<A>(scale_v);
+        A step = m_hat / (sqrtg(v_hat) + static_cast<A>(eps));
+        theta[i] = static_cast<T>(static_cast<A>(theta[i]) * decay - static_cast<A>(lr) * step);
+    }
+}
+
+#define ADAMW_OPS(TYPENAME, ACC, RUST_NAME) \
+extern "C" __global__ void adamw_moment_##RUST_NAME( \
+    const size_t numel, \
+    TYPENAME *acc, \
+    const TYPENAME *grad, \
+    const double beta, \
+    const bool square \
+) { \
+    adamw_moment<TYPENAME, ACC>(numel, acc, grad, beta, square); \
+} \
+extern "C" __global__ void adamw_update_##RUST_NAME( \
+    const size_t numel, \
+    TYPENAME *theta, \
+    const TYPENAME *m, \
+    const TYPENAME *v, \
+    const double lr, \
+    const double lr_lambda, \
+    const double scale_m, \
+    const double scale_v, \
+    const double eps \
+) { \
+    adamw_update<TYPENAME, ACC>(numel, theta, m, v, lr, lr_lambda, scale_m, scale_v, eps); \
+} \
+
+#if __CUDA_ARCH__ >= 800
+ADAMW_OPS(__nv_bfloat16, float, bf16)
+#endif
+
+#if __CUDA_ARCH__ >= 530
+ADAMW_OPS(__half, float, f16)
+#endif
+
+ADAMW_OPS(float, float, f32)
+ADAMW_OPS(double, double, f64)
@@ -220,6 +220,65 @@ __device__ void softmax(const T * x, T * dst, const int ncols) {
     }
 }
 
+// Same as softmax but skipping the positions where the mask is non-zero, these are set to zero
+// in the output. The mask is broadcast to the shape of x, info holds its dims and strides.
+template <typename T, typename ACC>
+__device__ void masked_softmax(
+    const T * x,
+    const uint8_t * mask,
+    T * dst,
+    const int ncols,
+    const size_t num_dims,
+    const size_t * info
+) {
+    const int row = blockDim.x*blockIdx.x + threadIdx.x;
+    const int block_size = blockDim.y;
+    const int tid = threadIdx.y;
+    const size_t *dims = info;
+    const size_t *strides = info + num_dims;
+    // The offset of the row in the mask only depends on the leading dimensions.
+    const uint8_t * mask_row = mask + get_strided_index(row, num_dims - 1, dims, strides);
+    const size_t mask_stride = strides[num_dims - 1];
+
+    T max_val = -INFINITY;
+
+    for (int col = tid; col < ncols; col += block_size) {
+        if (mask_row[col * mask_stride] == 0) {
+            max_val = maxg(max_val, x[row*ncols + col]);
+        }
+    }
+
+#pragma unroll
+    for (int lane_mask = 16; lane_mask > 0; lane_mask >>= 1) {
+        max_val = maxg(max_val, __shfl_xor_sync(0xffffffff, max_val, lane_mask, 32));
+    }
+
+    ACC tmp = 0.;
+
+    for (int col = tid; col < ncols; col += block_size) {
+        const int i = row*ncols + col;
+        T val = 0.;
+        if (mask_row[col * mask_stride] == 0) {
+            val = expg(x[i] - max_val);
+        }
+        tmp += static_cast<ACC>(val);
+        dst[i] = val;
+    }
+
+#pragma unroll
+    for (int lane_mask = 16; lane_mask > 0; lane_mask >>= 1) {
+        tmp += __shfl_xor_sync(0xffffffff, tmp, lane_mask, 32);
+    }
+
+    // Rows where all the values are masked are left as zeros.
+    const ACC inv_tmp = tmp > static_cast<ACC>(0.) ? static_cast<ACC>(1.) / tmp : static_cast<ACC>(0.);
+
+    for (int col = tid; col < ncols; col += block_size) {
+        const int i = row*ncols + col;
+        dst[i] *= inv_tmp;
+    }
+}
+
 template <typename T>
 __device__ void ropei(const T * src, const T * cos, const T * sin, T * dst, const uint32_t bh, const uint32_t td) {
     const int idx = blockIdx.x * blockDim.x + threadIdx.x;
@@ -279,6 +338,74 @@ __device__ void rope_thd(
     dst[i2] = src[i1] * s + src[i2] * c;
 }
 
+// Attention for a single query per sequence with an ALiBi bias computed on the fly, one warp
+// per batch element and head. q has shape (b, h, 1, d) and k, v have shape (b, h_kv, seq, d),
+// all contiguous. The query is at the last position so the bias for key j is
+// slope * (j - seq + 1). The softmax is computed online with f32 accumulation, each thread
+// keeping ALIBI_D_PER_THREAD values of the head in registers.
+#define ALIBI_D_PER_THREAD 8
+template <typename T>
+__device__ void alibi_decode_attn(
+    const T * q,
+    const T * k,
+    const T * v,
+    const float * slopes,
+    T * dst,
+    const uint32_t h,
+    const uint32_t h_kv,
+    const uint32_t seq,
+    const uint32_t d,
+    const float scale
+) {
+    const uint32_t bh = blockIdx.x;
+    const uint32_t i_b = bh / h;
+    const uint32_t i_h = bh % h;
+    const uint32_t i_kv = i_b * h_kv + i_h / (h / h_kv);
+    const int tid = threadIdx.x;
+    const T * q_ = q + bh * d;
+    const T * k_ = k + (size_t)i_kv * seq * d;
+    const T * v_ = v + (size_t)i_kv * seq * d;
+    const float slope = slopes[i_h];
+
+    float q_reg[ALIBI_D_PER_THREAD];
+    float acc[ALIBI_D_PER_THREAD];
+#pragma unroll
+    for (int i = 0; i < ALIBI_D_PER_THREAD; ++i) {
+        const uint32_t c = tid + i * WARP_SIZE;
+        q_reg[i] = c < d ? static_cast<float>(q_[c]) : 0.0f;
+        acc[i] = 0.0f;
+    }
+
+    float max_score = -INFINITY;
+    float sum_exp = 0.0f;
+    for (uint32_t j = 0; j < seq; ++j) {
+        float dot = 0.0f;
+#pragma unroll
+        for (int i = 0; i < ALIBI_D_PER_THREAD; ++i) {
+            const uint32_t c = tid + i * WARP_SIZE;
+            if (c < d) dot += q_reg[i] * static_cast<float>(k_[j * d + c]);
+        }
+        dot = warp_reduce_sum(dot);
+        const float score = dot * scale + slope * (static_cast<float>(j) - static_cast<float>(seq - 1));
+        const float new_max = fmaxf(max_score, score);
+        const float correction = expf(max_score - new_max);
+        const float p = expf(score - new_max);
+        sum_exp = sum_exp * correction + p;
+#pragma unroll
+        for (int i = 0; i < ALIBI_D_PER_THREAD; ++i) {
+            const uint32_t c = tid + i * WARP_SIZE;
+            if (c < d) acc[i] = acc[i] * correction + p * static_cast<float>(v_[j * d + c]);
+        }
+        max_score = new_max;
+    }
+
+#pragma unroll
+    for (int i = 0; i < ALIBI_D_PER_THREAD; ++i) {
+        const uint32_t c = tid + i * WARP_SIZE;
+        if (c < d) dst[bh * d + c] = static_cast<T>(acc[i] / sum_exp);
+    }
+}
+
 template <typename T>
 __device__ void
 fast_max(const size_t src_numel, const size_t el_to_sum_per_block,
@@ -527,6 +654,13 @@ fast_argmax(const size_t src_numel, const size_t el_to_sum_per_block,
     softmax<TYPENAME, ACC_TYPENAME>(src, dst, n_cols);                         \
   }                                                                            \
 
+#define MASKED_SOFTMAX_OP(TYPENAME, ACC_TYPENAME, FN_NAME) \
+  extern "C" __global__ void FN_NAME(                                          \
+      const TYPENAME *src, const uint8_t *mask, TYPENAME *dst,                 \
+      const int n_cols, const size_t num_dims, const size_t *info) {           \
+    masked_softmax<TYPENAME, ACC_TYPENAME>(src, mask, dst, n_cols, num_dims, info); \
+  }                                                                            \
+
 #define RMSNORM_OP(TYPENAME, FN_NAME) \
   extern "C" __global__ void FN_NAME(                                          \
       const TYPENAME *src, TYPENAME *dst, const TYPENAME *alpha,               \
@@ -573,20 +707,39 @@ fast_argmax(const size_t src_numel, const size_t el_to_sum_per_block,
     rope_thd<TYPENAME>(src, cos, sin, dst, b, t, h, d); \
   } \
 
+#define ALIBI_DECODE_OP(TYPENAME, FN_NAME) \
+  extern "C" __global__ void FN_NAME( \
+      const TYPENAME *q, \
+      const TYPENAME *k, \
+      const TYPENAME *v, \
+      const float *slopes, \
+      TYPENAME *dst, \
+      const uint32_t h, \
+      const uint32_t h_kv, \
+      const uint32_t seq, \
+      const uint32_t d, \
+      const float scale) { \
+    alibi_decode_attn<TYPENAME>(q, k, v, slopes, dst, h, h_kv, seq, d, scale); \
+  } \
+
 #if __CUDA_ARCH__ >= 800
 SOFTMAX_OP(__nv_bfloat16, float, softmax_bf16)
+MASKED_SOFTMAX_OP(__nv_bfloat16, float, masked_softmax_bf16)
 RMSNORM_OP(__nv_bfloat16, rmsnorm_bf16)
 LAYERNORM_OP(__nv_bfloat16, layernorm_bf16)
 ROPE_OP(__nv_bfloat16, rope_bf16, rope_i_bf16, rope_thd_bf16)
+ALIBI_DECODE_OP(__nv_bfloat16, alibi_decode_attn_bf16)
 SUM_OP(__nv_bfloat16, sum_bf16)
 FAST_OP(__nv_bfloat16, fast_min_bf16, fast_max_bf16, fast_argmin_bf16, fast_argmax_bf16, fast_sum_bf16)
 #endif
 
 #if __CUDA_ARCH__ >= 530
 SOFTMAX_OP(__half, float, softmax_f16)
+MASKED_SOFTMAX_OP(__half, float, masked_softmax_f16)
 RMSNORM_OP(__half, rmsnorm_f16)
 LAYERNORM_OP(__half, layernorm_f16)
 ROPE_OP(__half, rope_f16, rope_i_f16, rope_thd_f16)
+ALIBI_DECODE_OP(__half, alibi_decode_attn_f16)
 SUM_OP(__half, sum_f16)
 FAST_OP(__half, fast_min_f16, fast_max_f16, fast_argmin_f16, fast_argmax_f16, fast_sum_f16)
 #endif
@@ -596,12 +749,16 @@ SUM_OP(double, sum_f64)
 SUM_OP(uint32_t, sum_u32)
 SOFTMAX_OP(float, float, softmax_f32)
 SOFTMAX_OP(double, double, softmax_f64)
+MASKED_SOFTMAX_OP(float, float, masked_softmax_f32)
+MASKED_SOFTMAX_OP(double, double, masked_softmax_f64)
 RMSNORM_OP(float, rmsnorm_f32)
 RMSNORM_OP(double, rmsnorm_f64)
 LAYERNORM_OP(float, layernorm_f32)
 LAYERNORM_OP(double, layernorm_f64)
 ROPE_OP(float, rope_f32, rope_i_f32, rope_thd_f32)
 ROPE_OP(double, rope_f64, rope_i_f64, rope_thd_f64)
+ALIBI_DECODE_OP(float, alibi_decode_attn_f32)
+ALIBI_DECODE_OP(double, alibi_decode_attn_f64)
 
 FAST_OP(float, fast_min_f32, fast_max_f32, fast_argmin_f32, fast_argmax_f32, fast_sum_f32)
 FAST_OP(double, fast_min_f64, fast_max_f64, fast_argmin_f64, fast_argmax_f64, fast_sum_f64)
@@ -32,16 +32,44 @@ extern "C" __global__ void FN_NAME(  \
     } \
 } \
 
+// Sets the values of the contiguous inp_out to value where the broadcast mask is non-zero, in
+// place. info holds the dims and strides of the mask.
+#define MASKED_FILL_OP(TYPENAME, FN_NAME) \
+extern "C" __global__ void FN_NAME(  \
+    const size_t numel,  \
+    const size_t num_dims, \
+    const size_t *info, \
+    const uint8_t *mask, \
+    TYPENAME *inp_out, \
+    const TYPENAME value \
+) {  \
+    const size_t *dims = info; \
+    const size_t *strides = info + num_dims; \
+    if (is_contiguous(num_dims, dims, strides)) { \
+        for (unsigned int i = blockIdx.x * blockDim.x + threadIdx.x; i < numel; i += blockDim.x * gridDim.x) { \
+            if (mask[i]) inp_out[i] = value; \
+        } \
+    } \
+    else { \
+        for (unsigned int i = blockIdx.x * blockDim.x + threadIdx.x; i < numel; i += blockDim.x * gridDim.x) { \
+            unsigned strided_i = get_strided_index(i, num_dims, dims, strides); \
+            if (mask[strided_i]) inp_out[i] = value; \
+        } \
+    } \
+} \
+
 #if __CUDA_ARCH__ >= 800
 WHERE_OP(__nv_bfloat16, int64_t, where_i64_bf16)
 WHERE_OP(__nv_bfloat16, uint32_t, where_u32_bf16)
 WHERE_OP(__nv_bfloat16, uint8_t, where_u8_bf16)
+MASKED_FILL_OP(__nv_bfloat16, masked_fill_bf16)
 #endif
 
 #if __CUDA_ARCH__ >= 530
 WHERE_OP(__half, int64_t, where_i64_f16)
 WHERE_OP(__half, uint32_t, where_u32_f16)
 WHERE_OP(__half, uint8_t, where_u8_f16)
+MASKED_FILL_OP(__half, masked_fill_f16)
 #endif
 
 WHERE_OP(float, int64_t, where_i64_f32)
@@ -61,3 +89,9 @@ WHERE_OP(double, uint8_t, where_u8_f64)
 WHERE_OP(uint8_t, uint8_t, where_u8_u8)
 WHERE_OP(uint32_t, uint8_t, where_u8_u32)
 WHERE_OP(int64_t, uint8_t, where_u8_i64)
+
+MASKED_FILL_OP(float, masked_fill_f32)
+MASKED_FILL_OP(double, masked_fill_f64)
+MASKED_FILL_OP(uint8_t, masked_fill_u8)
+MASKED_FILL_OP(uint32_t, masked_fill_u32)
+MASKED_FILL_OP(int64_t, masked_fill_i64)
@@ -687,6 +687,68 @@ pub fn call_last_softmax(
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
+pub fn call_masked_softmax(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    kernel_name: &'static str,
+    length: usize,
+    elements_to_sum: usize,
+    input: &Buffer,
+    input_offset: usize,
+    mask_shape: &[usize],
+    mask_stride: &[usize],
+    mask: BufferOffset,
+    output: &Buffer,
+) -> Result<(), MetalKernelError> {
+    let pipeline = kernels.load_pipeline(device, Source::Reduce, kernel_name)?;
+    let encoder = ep.encoder();
+    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+    encoder.set_compute_pipeline_state(&pipeline);
+
+    let rank = mask_shape.len();
+    set_params!(
+        encoder,
+        (
+            length,
+            elements_to_sum,
+            rank,
+            mask_shape,
+            mask_stride,
+            (input, input_offset),
+            &mask,
+            output
+        )
+    );
+
+    let out_length = length / elements_to_sum;
+
+    let thread_group_count = MTLSize {
+        width: out_length as u64,
+        height: 1,
+        depth: 1,
+    };
+
+    let width = std::cmp::min(
+        pipeline.max_total_threads_per_threadgroup(),
+        elements_to_sum as u64,
+    )
+    .next_power_of_two();
+
+    let thread_group_size = MTLSize {
+        width,
+        height: 1,
+        depth: 1,
+    };
+
+    encoder.use_resource(input, metal::MTLResourceUsage::Read);
+    encoder.use_resource(mask.buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(output, metal::MTLResourceUsage::Write);
+    encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
+    Ok(())
+}
+
 #[allow(clippy::too_many_arguments)]
 pub fn call_rms_norm(
     device: &Device,
@@ -1169,6 +1231,52 @@ pub fn call_where_cond_strided(
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
+pub fn call_masked_fill(
+    device: &Device,
+    ep: impl EncoderProvider,
+    kernels: &Kernels,
+    name: &'static str,
+    mask_shape: &[usize],
+    mask_stride: &[usize],
+    mask: BufferOffset,
+    inp_out: &Buffer,
+    inp_out_offset: usize,
+    value: f32,
+) -> Result<(), MetalKernelError> {
+    let pipeline = kernels.load_pipeline(device, Source::Ternary, name)?;
+
+    let encoder = ep.encoder();
+    let encoder: &ComputeCommandEncoderRef = encoder.as_ref();
+    encoder.set_compute_pipeline_state(&pipeline);
+
+    let size: usize = mask_shape.iter().product();
+    let rank = mask_shape.len();
+
+    set_params!(
+        encoder,
+        (
+            size,
+            rank,
+            mask_shape,
+            mask_stride,
+            &mask,
+            (inp_out, inp_out_offset),
+            value
+        )
+    );
+
+    let (thread_group_count, thread_group_size) = linear_split(&pipeline, size);
+
+    encoder.use_resource(mask.buffer, metal::MTLResourceUsage::Read);
+    encoder.use_resource(
+        inp_out,
+        metal::MTLResourceUsage::Read | metal::MTLResourceUsage::Write,
+    );
+    encoder.dispatch_thread_groups(thread_group_count, thread_group_size);
+    Ok(())
+}
+
 #[allow(clippy::too_many_arguments)]
 pub fn call_index_select(
     device: &Device,
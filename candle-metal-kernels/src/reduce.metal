@@ -303,6 +303,112 @@ kernel void NAME( \
     softmax<T>(src_numel, el_to_sum_per_block, src, dst, id, tid, dst_id, block_dim, shared_memory); \
 } \
 
+// Same as softmax but skipping the positions where the mask is non-zero, these are set to zero
+// in the output. The mask is broadcast to the shape of src.
+template<typename T>
+METAL_FUNC void masked_softmax(
+    constant size_t & src_numel,
+    constant size_t & el_to_sum_per_block,
+    constant size_t & num_dims,
+    constant size_t * mask_dims,
+    constant size_t * mask_strides,
+    device const T * src,
+    device const uint8_t * mask,
+    device T * dst,
+    uint id,
+    uint tid,
+    uint dst_id,
+    uint block_dim,
+    threadgroup float * shared_memory
+) {
+    size_t start_idx = dst_id * el_to_sum_per_block;
+    size_t stop_idx = min(start_idx + el_to_sum_per_block, src_numel);
+    size_t idx = start_idx + tid;
+
+    // The offset of the row in the mask only depends on the leading dimensions.
+    uint row = dst_id;
+    uint mask_offset = 0;
+    for (uint d = 1; d < num_dims; d++) {
+        uint dim_idx = num_dims - 1 - d;
+        mask_offset += (row % mask_dims[dim_idx]) * mask_strides[dim_idx];
+        row /= mask_dims[dim_idx];
+    }
+    const size_t mask_stride = mask_strides[num_dims - 1];
+
+    float tmp = -INFINITY;
+    while (idx < stop_idx) {
+        if (mask[mask_offset + (idx - start_idx) * mask_stride] == 0) {
+            tmp = MAX(tmp, float(src[idx]));
+        }
+        idx += block_dim;
+    }
+    shared_memory[tid] = tmp;
+
+    threadgroup_barrier(mem_flags::mem_threadgroup);
+
+    for (uint s = block_dim / 2; s > 0; s >>= 1) {
+        if (tid < s) {
+            shared_memory[tid] = MAX(shared_memory[tid], shared_memory[tid + s]);
+        }
+        threadgroup_barrier(mem_flags::mem_threadgroup);
+    }
+
+    /* wait for shared_memory[0] to be filled */
+    threadgroup_barrier(mem_flags::mem_threadgroup);
+
+    float _max = shared_memory[0];
+
+    /* prevent tid=0 from overwriting _max before other threads have written it */
+    threadgroup_barrier(mem_flags::mem_threadgroup);
+    shared_memory[tid] = 0;
+
+    idx = start_idx + tid;
+    while (idx < stop_idx) {
+        float val = 0;
+        if (mask[mask_offset + (idx - start_idx) * mask_stride] == 0) {
+            val = exp(float(src[idx]) - _max);
+        }
+        dst[idx] = T(val);
+        shared_memory[tid] += val;
+        idx += block_dim;
+    }
+    threadgroup_barrier(mem_flags::mem_threadgroup);
+    for (uint s = block_dim / 2; s > 0; s >>= 1) {
+        if (tid < s) {
+            shared_memory[tid] += shared_memory[tid + s];
+        }
+        threadgroup_barrier(mem_flags::mem_threadgroup);
+    }
+
+    /* rows where all the values are masked are left as zeros */
+    const T inv_acc = shared_memory[0] > 0 ? T(1.0 / shared_memory[0]) : T(0);
+    idx = start_idx + tid;
+    while (idx < stop_idx) {
+        dst[idx] *= inv_acc;
+        idx += block_dim;
+    }
+}
+
+#define MASKED_SOFTMAX(NAME, T) \
+kernel void NAME( \
+    constant size_t &src_numel, \
+    constant size_t &el_to_sum_per_block, \
+    constant size_t &num_dims, \
+    constant size_t *mask_dims, \
+    constant size_t *mask_strides, \
+    device const T *src, \
+    device const uint8_t *mask, \
+    device T *dst, \
+    uint id [[ thread_position_in_grid ]], \
+    uint tid [[ thread_index_in_threadgroup ]], \
+    uint dst_id [[ threadgroup_position_in_grid ]], \
+    uint block_dim [[ threads_per_threadgroup ]] \
+) { \
+    threadgroup float shared_memory[THREADGROUP_SIZE]; \
+    shared_memory[tid] = -INFINITY; \
+    masked_softmax<T>(src_numel, el_to_sum_per_block, num_dims, mask_dims, mask_strides, src, mask, dst, id, tid, dst_id, block_dim, shared_memory); \
+} \
+
 template<typename T>
 METAL_FUNC void rmsnorm(
     constant size_t & src_numel,
@@ -587,6 +693,8 @@ ARGMAX(fast_argmax_u8_strided, uint8_t, 0)
 
 SOFTMAX(softmax_f32, float)
 SOFTMAX(softmax_f16, half)
+MASKED_SOFTMAX(masked_softmax_f32, float)
+MASKED_SOFTMAX(masked_softmax_f16, half)
 RMSNORM(rmsnorm_f32, float)
 RMSNORM(rmsnorm_f16, half)
 LAYERNORM(layernorm_f32, float)
@@ -614,6 +722,7 @@ REDUCE(MIN(x, y), fast_min_bf16_strided, bfloat, HUGE_VALBF)
 ARGMIN(fast_argmin_bf16, bfloat, HUGE_VALBF)
 ARGMAX(fast_argmax_bf16, bfloat, -HUGE_VALBF)
 SOFTMAX(softmax_bf16, bfloat)
+MASKED_SOFTMAX(masked_softmax_bf16, bfloat)
 RMSNORM(rmsnorm_bf16, bfloat)
 LAYERNORM(layernorm_bf16, bfloat)
 ROPE(rope_bf16, rope_i_bf16, rope_thd_bf16, bfloat)
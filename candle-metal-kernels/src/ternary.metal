@@ -56,6 +56,42 @@ kernel void FN_NAME(
    where_cond<T, ID>(numel, num_dims, dims, strides, strides_t, strides_f, ids, t, f, out, i);  \
 }                                                                                               \
 
+// Sets the values of the contiguous inp_out to value where the broadcast mask is non-zero, in
+// place.
+template<typename T>
+METAL_FUNC void masked_fill(
+    constant size_t &numel,
+    constant size_t &num_dims,
+    constant size_t *dims,
+    constant size_t *strides,
+    device const uint8_t *mask,
+    device T *inp_out,
+    constant float &value,
+    uint i [[ thread_position_in_grid ]]
+) {
+    if (i >= numel){
+       return;
+    }
+    uint strided_i = get_strided_index(i, num_dims, dims, strides);
+    if (mask[strided_i]) {
+        inp_out[i] = T(value);
+    }
+}
+
+#define MASKED_FILL_OP(T, FN_NAME)                                                              \
+kernel void FN_NAME(                                                                            \
+    constant size_t &numel,                                                                     \
+    constant size_t &num_dims,                                                                  \
+    constant size_t *dims,                                                                      \
+    constant size_t *strides,                                                                   \
+    device const uint8_t *mask,                                                                 \
+    device T *inp_out,                                                                          \
+    constant float &value,                                                                      \
+    uint i [[ thread_position_in_grid ]]                                                        \
+) {                                                                                             \
+   masked_fill<T>(numel, num_dims, dims, strides, mask, inp_out, value, i);                     \
+}                                                                                               \
+
 WHERE_OP(half, uint32_t, where_u32_f16)
 WHERE_OP(float, uint32_t, where_u32_f32)
 WHERE_OP(uint8_t, uint32_t, where_u32_u8)
@@ -66,6 +102,9 @@ WHERE_OP(float, uint8_t, where_u8_f32)
 WHERE_OP(uint8_t, uint8_t, where_u8_u8)
 WHERE_OP(uint32_t, uint8_t, where_u8_u32)
 
+MASKED_FILL_OP(half, masked_fill_f16)
+MASKED_FILL_OP(float, masked_fill_f32)
+
 #if __METAL_VERSION__ >= 220
 WHERE_OP(int64_t, uint8_t, where_u8_i64)
 WHERE_OP(int64_t, uint32_t, where_u32_i64)
@@ -83,4 +122,5 @@ WHERE_OP(bfloat, int64_t, where_i64_bf16)
 #if defined(__HAVE_BFLOAT__)
 WHERE_OP(bfloat, uint8_t, where_u8_bf16)
 WHERE_OP(bfloat, uint32_t, where_u32_bf16)
+MASKED_FILL_OP(bfloat, masked_fill_bf16)
 #endif
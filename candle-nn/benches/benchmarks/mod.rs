@@ -25,6 +25,7 @@ impl BenchDevice for Device {
                 #[cfg(not(feature = "metal"))]
                 panic!("Metal device without metal feature enabled: {:?}", device)
             }
+            Device::Meta(_) => Ok(()),
         }
     }
 
@@ -42,6 +43,7 @@ impl BenchDevice for Device {
             }
             Device::Cuda(_) => format!("cuda_{}", name.into()),
             Device::Metal(_) => format!("metal_{}", name.into()),
+            Device::Meta(_) => format!("meta_{}", name.into()),
         }
     }
 }
@@ -0,0 +1,331 @@
+//! Activation statistics for calibration and numerical debugging.
+//!
+//! While an [`ActivationRecorder`] is running, the tensors passed to [`record`] are summarized
+//! per name: the extrema, mean and standard deviation are computed over all the recorded values
+//! and the percentiles are estimated from a uniform sample of these values. The layers wrapped
+//! with [`Instrumented`] record their outputs, and the linear layers of `candle-transformers`
+//! record their inputs under their variable prefix. When no recorder is running, [`record`]
+//! returns immediately.
+//!
+//! ```ignore
+//! let recorder = ActivationRecorder::new(StatsConfig::default())?;
+//! for batch in calibration_batches {
+//!     model.forward(&batch)?;
+//! }
+//! let report = recorder.finish();
+//! println!("{report}");
+//! // Clip the activations to the 0.1% and 99.9% percentiles when quantizing.
+//! let (lo, hi) = report.get("model.layers.0.mlp.down_proj").unwrap().clip_range(99.9);
+//! ```
+use candle::{DType, Module, Result, Tensor};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StatsConfig {
+    /// The number of values kept per name to estimate the percentiles.
+    pub max_samples: usize,
+    pub seed: u64,
+}
+
+impl Default for StatsConfig {
+    fn default() -> Self {
+        Self {
+            max_samples: 16384,
+            seed: 299792458,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Accumulator {
+    name: String,
+    calls: usize,
+    count: usize,
+    nan_count: usize,
+    inf_count: usize,
+    min: f64,
+    max: f64,
+    // The running mean and sum of squared deviations from the mean, updated with Welford's
+    // algorithm to avoid the cancellation of `E[x^2] - E[x]^2` for large activations.
+    mean: f64,
+    m2: f64,
+    // A reservoir sample of the finite values.
+    samples: Vec<f32>,
+}
+
+struct State {
+    config: StatsConfig,
+    rng: u64,
+    accumulators: Vec<Accumulator>,
+    indexes: std::collections::HashMap<String, usize>,
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static STATE: Mutex<Option<State>> = Mutex::new(None);
+
+fn lock() -> std::sync::MutexGuard<'static, Option<State>> {
+    STATE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// The xorshift64* generator.
+fn next_u64(state: &mut u64) -> u64 {
+    *state ^= *state >> 12;
+    *state ^= *state << 25;
+    *state ^= *state >> 27;
+    state.wrapping_mul(0x2545f4914f6cdd1d)
+}
+
+/// Records the activation statistics while alive, only one recorder can run at a time and the
+/// activations of all the threads are recorded.
+pub struct ActivationRecorder {
+    finished: bool,
+}
+
+impl ActivationRecorder {
+    pub fn new(config: StatsConfig) -> Result<Self> {
+        let mut state = lock();
+        if state.is_some() {
+            candle::bail!("an activation recorder is already running")
+        }
+        *state = Some(State {
+            config,
+            rng: config.seed.max(1),
+            accumulators: vec![],
+            indexes: std::collections::HashMap::new(),
+        });
+        ENABLED.store(true, Ordering::Relaxed);
+        Ok(Self { finished: false })
+    }
+
+    /// Stops the recording and returns the statistics.
+    pub fn finish(mut self) -> ActivationReport {
+        self.finished = true;
+        let accumulators = stop().map_or(vec![], |s| s.accumulators);
+        let layers = accumulators.into_iter().map(LayerStats::new).collect();
+        ActivationReport { layers }
+    }
+}
+
+impl Drop for ActivationRecorder {
+    fn drop(&mut self) {
+        if !self.finished {
+            stop();
+        }
+    }
+}
+
+fn stop() -> Option<State> {
+    ENABLED.store(false, Ordering::Relaxed);
+    lock().take()
+}
+
+pub fn is_recording() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Adds the values of `xs` to the statistics for `name`, this does nothing when no recorder is
+/// running. The values are copied to the host.
+pub fn record(name: &str, xs: &Tensor) -> Result<()> {
+    if !is_recording() {
+        return Ok(());
+    }
+    let values = xs.flatten_all()?.to_dtype(DType::F32)?.to_vec1::<f32>()?;
+    let mut state = lock();
+    let state = match state.as_mut() {
+        None => return Ok(()),
+        Some(state) => state,
+    };
+    let index = match state.indexes.get(name) {
+        Some(&index) => index,
+        None => {
+            state.accumulators.push(Accumulator {
+                name: name.to_string(),
+                calls: 0,
+                count: 0,
+                nan_count: 0,
+                inf_count: 0,
+                min: f64::INFINITY,
+                max: f64::NEG_INFINITY,
+                mean: 0.,
+                m2: 0.,
+                samples: vec![],
+            });
+            let index = state.accumulators.len() - 1;
+            state.indexes.insert(name.to_string(), index);
+            index
+        }
+    };
+    let max_samples = state.config.max_samples;
+    let acc = &mut state.accumulators[index];
+    acc.calls += 1;
+    for v in values {
+        if v.is_nan() {
+            acc.nan_count += 1;
+            continue;
+        }
+        if v.is_infinite() {
+            acc.inf_count += 1;
+            continue;
+        }
+        let v64 = v as f64;
+        acc.min = acc.min.min(v64);
+        acc.max = acc.max.max(v64);
+        acc.count += 1;
+        let delta = v64 - acc.mean;
+        acc.mean += delta / acc.count as f64;
+        acc.m2 += delta * (v64 - acc.mean);
+        if acc.samples.len() < max_samples {
+            acc.samples.push(v)
+        } else {
+            let j = next_u64(&mut state.rng) % acc.count as u64;
+            if (j as usize) < max_samples {
+                acc.samples[j as usize] = v
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A module that records the statistics of its outputs under `name`.
+#[derive(Debug, Clone)]
+pub struct Instrumented<M> {
+    name: String,
+    inner: M,
+}
+
+impl<M> Instrumented<M> {
+    pub fn new(name: impl Into<String>, inner: M) -> Self {
+        Self {
+            name: name.into(),
+            inner,
+        }
+    }
+
+    pub fn inner(&self) -> &M {
+        &self.inner
+    }
+}
+
+impl<M: Module> Module for Instrumented<M> {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let ys = self.inner.forward(xs)?;
+        record(&self.name, &ys)?;
+        Ok(ys)
+    }
+}
+
+/// The statistics for a name, computed over the finite values.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LayerStats {
+    pub name: String,
+    /// The number of calls to `record`.
+    pub calls: usize,
+    /// The number of finite values.
+    pub count: usize,
+    pub nan_count: usize,
+    pub inf_count: usize,
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    pub std: f64,
+    // The sorted sample of the values.
+    samples: Vec<f32>,
+}
+
+impl LayerStats {
+    fn new(acc: Accumulator) -> Self {
+        let var = acc.m2 / acc.count.max(1) as f64;
+        let mut samples = acc.samples;
+        samples.sort_by(|a, b| a.total_cmp(b));
+        let (min, max) = if acc.count == 0 {
+            (f64::NAN, f64::NAN)
+        } else {
+            (acc.min, acc.max)
+        };
+        Self {
+            name: acc.name,
+            calls: acc.calls,
+            count: acc.count,
+            nan_count: acc.nan_count,
+            inf_count: acc.inf_count,
+            min,
+            max,
+            mean: acc.mean,
+            std: var.sqrt(),
+            samples,
+        }
+    }
+
+    /// The largest absolute value.
+    pub fn abs_max(&self) -> f64 {
+        self.min.abs().max(self.max.abs())
+    }
+
+    /// Estimates the `p`-th percentile, with `p` between 0 and 100, using a linear interpolation
+    /// between the sampled values. Returns NaN when there are no finite values.
+    pub fn percentile(&self, p: f64) -> f64 {
+        let n = self.samples.len();
+        if n == 0 {
+            return f64::NAN;
+        }
+        let pos = (p.clamp(0., 100.) / 100.) * (n - 1) as f64;
+        let lo = pos.floor() as usize;
+        let hi = pos.ceil() as usize;
+        let frac = pos - lo as f64;
+        self.samples[lo] as f64 * (1. - frac) + self.samples[hi] as f64 * frac
+    }
+
+    /// The range between the `100 - p` and `p` percentiles, e.g. to clip the activations before
+    /// quantizing them.
+    pub fn clip_range(&self, p: f64) -> (f64, f64) {
+        (self.percentile(100. - p), self.percentile(p))
+    }
+}
+
+/// The statistics recorded by an [`ActivationRecorder`], in the order in which the names were
+/// first recorded.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ActivationReport {
+    pub layers: Vec<LayerStats>,
+}
+
+impl ActivationReport {
+    pub fn get(&self, name: &str) -> Option<&LayerStats> {
+        self.layers.iter().find(|l| l.name == name)
+    }
+
+    /// The layers with some NaN or infinite values.
+    pub fn non_finite(&self) -> Vec<&LayerStats> {
+        self.layers
+            .iter()
+            .filter(|l| l.nan_count + l.inf_count > 0)
+            .collect()
+    }
+}
+
+impl std::fmt::Display for ActivationReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{:<40} {:>6} {:>11} {:>11} {:>11} {:>11} {:>11} {:>11} {:>6}",
+            "name", "calls", "min", "max", "mean", "std", "p1", "p99", "nan/inf"
+        )?;
+        for l in self.layers.iter() {
+            writeln!(
+                f,
+                "{:<40} {:>6} {:>11.4e} {:>11.4e} {:>11.4e} {:>11.4e} {:>11.4e} {:>11.4e} {:>6}",
+                l.name,
+                l.calls,
+                l.min,
+                l.max,
+                l.mean,
+                l.std,
+                l.percentile(1.),
+                l.percentile(99.),
+                l.nan_count + l.inf_count,
+            )?
+        }
+        Ok(())
+    }
+}
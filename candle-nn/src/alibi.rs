@@ -0,0 +1,333 @@
+//! Attention with linear biases (ALiBi).
+//!
+//! ALiBi replaces the position embeddings by a bias `slope * (j - i)` added to the attention
+//! scores between query position `i` and key position `j`, with a slope that depends on the
+//! head, see "Train Short, Test Long" <https://arxiv.org/abs/2108.12409>. It is used by the MPT
+//! and BLOOM families of models.
+//!
+//! During decoding there is a single query per sequence, `alibi_decode_attention` computes the
+//! attention for this query in a single fused kernel that applies the bias on the fly, so that
+//! neither the bias nor the attention scores get materialized.
+use candle::{CpuStorage, DType, Layout, Result, Shape, Tensor};
+use rayon::prelude::*;
+
+/// The slopes for each head, `max_bias` is usually 8. When the number of heads is not a power
+/// of two, the slopes for the closest power of two are interleaved as in the reference
+/// implementation.
+pub fn alibi_slopes(n_heads: usize, max_bias: f64) -> Vec<f32> {
+    let mut n_heads2 = 1;
+    while n_heads2 < n_heads {
+        n_heads2 *= 2
+    }
+    let slopes = (1..=n_heads2)
+        .map(|v| (1. / 2f64.powf(v as f64 * max_bias / n_heads2 as f64)) as f32)
+        .collect::<Vec<_>>();
+    if n_heads2 == n_heads {
+        slopes
+    } else {
+        slopes
+            .iter()
+            .skip(1)
+            .step_by(2)
+            .chain(slopes.iter().step_by(2))
+            .take(n_heads)
+            .cloned()
+            .collect()
+    }
+}
+
+// The largest head dimension supported by the cuda kernel, each of the 32 threads of a warp
+// keeps 8 values of the head in registers.
+#[cfg(feature = "cuda")]
+const CUDA_MAX_HEAD_DIM: usize = 256;
+
+#[derive(Debug, Clone)]
+struct AlibiDecodeAttention {
+    slopes: Vec<f32>,
+    scale: f32,
+}
+
+impl AlibiDecodeAttention {
+    // Returns `(b, h, h_kv, seq_len, head_dim)`.
+    fn dims(
+        &self,
+        q: &Layout,
+        k: &Layout,
+        v: &Layout,
+    ) -> Result<(usize, usize, usize, usize, usize)> {
+        let (b, h, q_len, d) = q.shape().dims4()?;
+        let (k_b, h_kv, seq_len, k_d) = k.shape().dims4()?;
+        if q_len != 1 {
+            candle::bail!("alibi-decode-attention expects a single query, got {q_len}")
+        }
+        if k.shape() != v.shape() || k_b != b || k_d != d {
+            candle::bail!(
+                "shape mismatch in alibi-decode-attention, q: {:?}, k: {:?}, v: {:?}",
+                q.shape(),
+                k.shape(),
+                v.shape()
+            )
+        }
+        if h_kv == 0 || h % h_kv != 0 || self.slopes.len() != h {
+            candle::bail!(
+                "alibi-decode-attention: {h} heads, {h_kv} kv heads and {} slopes",
+                self.slopes.len()
+            )
+        }
+        if seq_len == 0 {
+            candle::bail!("alibi-decode-attention: empty key-value sequence")
+        }
+        Ok((b, h, h_kv, seq_len, d))
+    }
+}
+
+impl candle::CustomOp3 for AlibiDecodeAttention {
+    fn name(&self) -> &'static str {
+        "alibi-decode-attention"
+    }
+
+    fn cpu_fwd(
+        &self,
+        s1: &CpuStorage,
+        l1: &Layout,
+        s2: &CpuStorage,
+        l2: &Layout,
+        s3: &CpuStorage,
+        l3: &Layout,
+    ) -> Result<(CpuStorage, Shape)> {
+        fn inner<T: candle::WithDType + num_traits::Float>(
+            op: &AlibiDecodeAttention,
+            q: &[T],
+            l_q: &Layout,
+            k: &[T],
+            l_k: &Layout,
+            v: &[T],
+            l_v: &Layout,
+        ) -> Result<(CpuStorage, Shape)> {
+            let (b, h, h_kv, seq_len, d) = op.dims(l_q, l_k, l_v)?;
+            let q = match l_q.contiguous_offsets() {
+                None => candle::bail!("q has to be contiguous"),
+                Some((o1, o2)) => &q[o1..o2],
+            };
+            let k = match l_k.contiguous_offsets() {
+                None => candle::bail!("k has to be contiguous"),
+                Some((o1, o2)) => &k[o1..o2],
+            };
+            let v = match l_v.contiguous_offsets() {
+                None => candle::bail!("v has to be contiguous"),
+                Some((o1, o2)) => &v[o1..o2],
+            };
+            let mut dst = vec![T::zero(); b * h * d];
+            dst.par_chunks_mut(d).enumerate().for_each(|(bh, dst)| {
+                let (i_b, i_h) = (bh / h, bh % h);
+                let i_kv = i_b * h_kv + i_h / (h / h_kv);
+                let q = &q[bh * d..(bh + 1) * d];
+                let k = &k[i_kv * seq_len * d..(i_kv + 1) * seq_len * d];
+                let v = &v[i_kv * seq_len * d..(i_kv + 1) * seq_len * d];
+                let slope = op.slopes[i_h];
+                // Online softmax, the output is rescaled whenever the running maximum changes.
+                let mut max_score = f32::NEG_INFINITY;
+                let mut sum_exp = 0f32;
+                let mut acc = vec![0f32; d];
+                for (j, (k, v)) in k.chunks(d).zip(v.chunks(d)).enumerate() {
+                    let dot = q
+                        .iter()
+                        .zip(k.iter())
+                        .map(|(q, k)| q.to_f32().unwrap_or(0.) * k.to_f32().unwrap_or(0.))
+                        .sum::<f32>();
+                    let score = dot * op.scale + slope * (j as f32 - (seq_len - 1) as f32);
+                    let new_max = max_score.max(score);
+                    let correction = (max_score - new_max).exp();
+                    let p = (score - new_max).exp();
+                    sum_exp = sum_exp * correction + p;
+                    for (acc, v) in acc.iter_mut().zip(v.iter()) {
+                        *acc = *acc * correction + p * v.to_f32().unwrap_or(0.)
+                    }
+                    max_score = new_max;
+                }
+                for (dst, acc) in dst.iter_mut().zip(acc.iter()) {
+                    *dst = T::from(acc / sum_exp).unwrap_or_else(T::zero)
+                }
+            });
+            let storage = candle::WithDType::to_cpu_storage_owned(dst);
+            Ok((storage, (b, h, 1, d).into()))
+        }
+
+        use candle::backend::BackendStorage;
+        use CpuStorage as C;
+        match (s1, s2, s3) {
+            (C::BF16(q), C::BF16(k), C::BF16(v)) => inner(self, q, l1, k, l2, v, l3),
+            (C::F16(q), C::F16(k), C::F16(v)) => inner(self, q, l1, k, l2, v, l3),
+            (C::F32(q), C::F32(k), C::F32(v)) => inner(self, q, l1, k, l2, v, l3),
+            (C::F64(q), C::F64(k), C::F64(v)) => inner(self, q, l1, k, l2, v, l3),
+            _ => candle::bail!(
+                "unsupported dtypes for alibi-decode-attention {:?} {:?} {:?}",
+                s1.dtype(),
+                s2.dtype(),
+                s3.dtype()
+            ),
+        }
+    }
+
+    #[cfg(feature = "cuda")]
+    fn cuda_fwd(
+        &self,
+        s1: &candle::CudaStorage,
+        l1: &Layout,
+        s2: &candle::CudaStorage,
+        l2: &Layout,
+        s3: &candle::CudaStorage,
+        l3: &Layout,
+    ) -> Result<(candle::CudaStorage, Shape)> {
+        use candle::cuda_backend::cudarc::driver::{
+            CudaSlice, DeviceRepr, LaunchAsync, LaunchConfig,
+        };
+        use candle::cuda_backend::{kernel_name, kernels, Map3, WrapErr};
+        use candle::{CudaDevice, WithDType};
+
+        struct S<'a>(&'a AlibiDecodeAttention);
+        impl<'a> Map3 for S<'a> {
+            fn f<T: DeviceRepr + WithDType>(
+                &self,
+                q: &CudaSlice<T>,
+                l_q: &Layout,
+                k: &CudaSlice<T>,
+                l_k: &Layout,
+                v: &CudaSlice<T>,
+                l_v: &Layout,
+                dev: &CudaDevice,
+            ) -> Result<CudaSlice<T>> {
+                let (b, h, h_kv, seq_len, d) = self.0.dims(l_q, l_k, l_v)?;
+                if d > CUDA_MAX_HEAD_DIM {
+                    candle::bail!(
+                        "alibi-decode-attention supports head dims up to {CUDA_MAX_HEAD_DIM}"
+                    )
+                }
+                let q = match l_q.contiguous_offsets() {
+                    None => candle::bail!("q has to be contiguous"),
+                    Some((o1, o2)) => q.slice(o1..o2),
+                };
+                let k = match l_k.contiguous_offsets() {
+                    None => candle::bail!("k has to be contiguous"),
+                    Some((o1, o2)) => k.slice(o1..o2),
+                };
+                let v = match l_v.contiguous_offsets() {
+                    None => candle::bail!("v has to be contiguous"),
+                    Some((o1, o2)) => v.slice(o1..o2),
+                };
+                let slopes = dev.htod_sync_copy(&self.0.slopes).w()?;
+                // One warp per batch element and head.
+                let cfg = LaunchConfig {
+                    grid_dim: ((b * h) as u32, 1, 1),
+                    block_dim: (32, 1, 1),
+                    shared_mem_bytes: 0,
+                };
+                let name = kernel_name::<T>("alibi_decode_attn");
+                let func = dev.get_or_load_func(&name, kernels::REDUCE)?;
+                // SAFETY: Set later by running the kernel.
+                let dst = unsafe { dev.alloc::<T>(b * h * d) }.w()?;
+                let params = (
+                    &q,
+                    &k,
+                    &v,
+                    &slopes,
+                    &dst,
+                    h as u32,
+                    h_kv as u32,
+                    seq_len as u32,
+                    d as u32,
+                    self.0.scale,
+                );
+                // SAFETY: ffi.
+                unsafe { func.launch(cfg, params) }.w()?;
+                Ok(dst)
+            }
+        }
+
+        use candle::backend::BackendStorage;
+        let dev = s1.device();
+        let slice = S(self).map(&s1.slice, l1, &s2.slice, l2, &s3.slice, l3, dev)?;
+        let dst = candle::cuda_backend::CudaStorage {
+            slice,
+            device: dev.clone(),
+        };
+        let (b, h, _, d) = l1.shape().dims4()?;
+        Ok((dst, (b, h, 1, d).into()))
+    }
+}
+
+/// The attention for a single query per sequence with an ALiBi bias.
+///
+/// `q` has shape `(batch, n_heads, 1, head_dim)` and `k`, `v` have shape
+/// `(batch, n_kv_heads, seq_len, head_dim)`, where `n_heads` is a multiple of `n_kv_heads` for
+/// grouped-query attention. The query is assumed to be at the last position so the bias for key
+/// position `j` is `slopes[head] * (j - seq_len + 1)`. Returns a tensor of shape
+/// `(batch, n_heads, 1, head_dim)`.
+///
+/// The scores are accumulated in f32 using an online softmax, the inputs are made contiguous if
+/// needed. Devices without a fused kernel use `alibi_attention_slow`.
+pub fn alibi_decode_attention(
+    q: &Tensor,
+    k: &Tensor,
+    v: &Tensor,
+    slopes: &[f32],
+    scale: f32,
+) -> Result<Tensor> {
+    let op = AlibiDecodeAttention {
+        slopes: slopes.to_vec(),
+        scale,
+    };
+    #[cfg(feature = "cuda")]
+    let fused = q.device().is_cpu() || (q.device().is_cuda() && q.dim(3)? <= CUDA_MAX_HEAD_DIM);
+    #[cfg(not(feature = "cuda"))]
+    let fused = q.device().is_cpu();
+    if !fused {
+        return alibi_attention_slow(q, k, v, slopes, scale);
+    }
+    let (q, k, v) = (q.contiguous()?, k.contiguous()?, v.contiguous()?);
+    q.apply_op3_no_bwd(&k, &v, &op)
+}
+
+/// The reference implementation of `alibi_decode_attention`, this materializes the bias and the
+/// attention scores and also supports multiple queries, the queries being aligned with the last
+/// keys. No causal mask is applied.
+pub fn alibi_attention_slow(
+    q: &Tensor,
+    k: &Tensor,
+    v: &Tensor,
+    slopes: &[f32],
+    scale: f32,
+) -> Result<Tensor> {
+    let (b, h, q_len, d) = q.dims4()?;
+    let (_, h_kv, seq_len, _) = k.dims4()?;
+    let dtype = q.dtype();
+    let internal_dtype = match dtype {
+        DType::F16 | DType::BF16 => DType::F32,
+        d => d,
+    };
+    let n_rep = h / h_kv;
+    let repeat = |x: &Tensor| -> Result<Tensor> {
+        x.to_dtype(internal_dtype)?
+            .unsqueeze(2)?
+            .broadcast_as((b, h_kv, n_rep, seq_len, d))?
+            .reshape((b, h, seq_len, d))
+    };
+    let (k, v) = (repeat(k)?, repeat(v)?);
+    let q = q.to_dtype(internal_dtype)?;
+    let scores = (q.matmul(&k.t()?)? * scale as f64)?;
+    let device = q.device();
+    let k_pos = Tensor::arange(0u32, seq_len as u32, device)?.to_dtype(internal_dtype)?;
+    let q_pos = Tensor::arange((seq_len - q_len) as u32, seq_len as u32, device)?
+        .to_dtype(internal_dtype)?;
+    let rel = k_pos
+        .unsqueeze(0)?
+        .broadcast_sub(&q_pos.unsqueeze(1)?)?
+        .unsqueeze(0)?;
+    let slopes = Tensor::new(slopes, device)?
+        .to_dtype(internal_dtype)?
+        .reshape((h, 1, 1))?;
+    let bias = rel.broadcast_mul(&slopes)?.unsqueeze(0)?;
+    let scores = scores.broadcast_add(&bias)?;
+    let probs = crate::ops::softmax_last_dim(&scores)?;
+    probs.matmul(&v)?.to_dtype(dtype)
+}
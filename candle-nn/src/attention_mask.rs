@@ -0,0 +1,95 @@
+//! Attention mask builders.
+//!
+//! The masks are built on the device from position comparisons, they are u8 tensors of shape
+//! `(tgt_len, src_len)` where 1 means that the query can attend to the key. The keys include
+//! `seqlen_offset` positions from the kv cache followed by the `tgt_len` positions of the
+//! queries. [`to_additive`] converts a mask to the `0` / `-inf` form that is added to the
+//! attention scores.
+//!
+//! ```ignore
+//! let mask = attention_mask::sliding_window_mask(seq_len, seqlen_offset, 4096, &device)?;
+//! let mask = attention_mask::to_additive(&mask, DType::F32)?;
+//! let attn_weights = attn_weights.broadcast_add(&mask)?;
+//! ```
+use candle::{DType, Device, Result, Tensor};
+
+// The query positions with shape (tgt_len, 1) and the key positions with shape (1, src_len).
+fn positions(tgt_len: usize, seqlen_offset: usize, device: &Device) -> Result<(Tensor, Tensor)> {
+    let src_len = (tgt_len + seqlen_offset) as u32;
+    let q = Tensor::arange(seqlen_offset as u32, src_len, device)?.unsqueeze(1)?;
+    let k = Tensor::arange(0, src_len, device)?.unsqueeze(0)?;
+    Ok((q, k))
+}
+
+/// Each query attends to the keys at the same or previous positions.
+pub fn causal_mask(tgt_len: usize, seqlen_offset: usize, device: &Device) -> Result<Tensor> {
+    let (q, k) = positions(tgt_len, seqlen_offset, device)?;
+    k.broadcast_le(&q)
+}
+
+/// Each query attends to its own position and to the `sliding_window` previous positions, as in
+/// Mistral.
+pub fn sliding_window_mask(
+    tgt_len: usize,
+    seqlen_offset: usize,
+    sliding_window: usize,
+    device: &Device,
+) -> Result<Tensor> {
+    let src_len = tgt_len + seqlen_offset;
+    // A window covering all the keys, e.g. `usize::MAX`, is the same as the causal mask.
+    if sliding_window >= src_len {
+        return causal_mask(tgt_len, seqlen_offset, device);
+    }
+    let (q, k) = positions(tgt_len, seqlen_offset, device)?;
+    let shifted_k = Tensor::arange(
+        sliding_window as u32,
+        (src_len + sliding_window) as u32,
+        device,
+    )?
+    .unsqueeze(0)?;
+    k.broadcast_le(&q)?.mul(&shifted_k.broadcast_ge(&q)?)
+}
+
+/// The queries attend to all the positions of the prefix and causally to the other positions, as
+/// in the prefix language models where the prompt is encoded bidirectionally.
+pub fn prefix_lm_mask(
+    tgt_len: usize,
+    seqlen_offset: usize,
+    prefix_len: usize,
+    device: &Device,
+) -> Result<Tensor> {
+    let (q, k) = positions(tgt_len, seqlen_offset, device)?;
+    let in_prefix = k.lt(prefix_len as u32 as f64)?;
+    k.broadcast_le(&q)?.broadcast_maximum(&in_prefix)
+}
+
+/// A mask for multiple sequences packed in a single row, with lengths `seq_lens`: the queries
+/// only attend to the keys of their own sequence, causally if `causal` is true. The mask has a
+/// shape `(n, n)` where `n` is the sum of the lengths.
+pub fn block_diagonal_mask(seq_lens: &[usize], causal: bool, device: &Device) -> Result<Tensor> {
+    let segments = seq_lens
+        .iter()
+        .enumerate()
+        .flat_map(|(i, &len)| std::iter::repeat_n(i as u32, len))
+        .collect::<Vec<_>>();
+    let n = segments.len();
+    let segments = Tensor::from_vec(segments, n, device)?;
+    let mask = segments
+        .unsqueeze(1)?
+        .broadcast_eq(&segments.unsqueeze(0)?)?;
+    if causal {
+        mask.mul(&causal_mask(n, 0, device)?)
+    } else {
+        Ok(mask)
+    }
+}
+
+/// Converts a u8 mask to an additive mask of the given dtype, with `0` where the mask is 1 and
+/// `-inf` elsewhere.
+pub fn to_additive(mask: &Tensor, dtype: DType) -> Result<Tensor> {
+    let zeros = Tensor::zeros((), dtype, mask.device())?.broadcast_as(mask.shape())?;
+    let neg_inf = Tensor::new(f32::NEG_INFINITY, mask.device())?
+        .to_dtype(dtype)?
+        .broadcast_as(mask.shape())?;
+    mask.where_cond(&zeros, &neg_inf)
+}
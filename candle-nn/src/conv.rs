@@ -1,6 +1,6 @@
 //! Convolution Layers.
 use crate::BatchNorm;
-use candle::{Result, Tensor};
+use candle::{Result, ResultExt, Tensor};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Conv1dConfig {
@@ -26,6 +26,7 @@ pub struct Conv1d {
     weight: Tensor,
     bias: Option<Tensor>,
     config: Conv1dConfig,
+    prefix: Option<String>,
 }
 
 impl Conv1d {
@@ -34,6 +35,16 @@ impl Conv1d {
             weight,
             bias,
             config,
+            prefix: None,
+        }
+    }
+
+    /// Sets the path of the layer reported in errors, this is the `VarBuilder` prefix for the
+    /// layers created with [`conv1d`].
+    pub fn with_prefix(self, prefix: impl Into<String>) -> Self {
+        Self {
+            prefix: Some(prefix.into()),
+            ..self
         }
     }
 
@@ -52,6 +63,13 @@ impl Conv1d {
 
 impl crate::Module for Conv1d {
     fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        self.forward_(x)
+            .module_op_context(self.prefix.as_deref(), "conv1d", &[x, &self.weight])
+    }
+}
+
+impl Conv1d {
+    fn forward_(&self, x: &Tensor) -> Result<Tensor> {
         let x = x.conv1d(
             &self.weight,
             self.config.padding,
@@ -96,6 +114,7 @@ pub struct ConvTranspose1d {
     weight: Tensor,
     bias: Option<Tensor>,
     config: ConvTranspose1dConfig,
+    prefix: Option<String>,
 }
 
 impl ConvTranspose1d {
@@ -104,6 +123,16 @@ impl ConvTranspose1d {
             weight,
             bias,
             config,
+            prefix: None,
+        }
+    }
+
+    /// Sets the path of the layer reported in errors, this is the `VarBuilder` prefix for the
+    /// layers created with [`conv_transpose1d`].
+    pub fn with_prefix(self, prefix: impl Into<String>) -> Self {
+        Self {
+            prefix: Some(prefix.into()),
+            ..self
         }
     }
 
@@ -122,6 +151,16 @@ impl ConvTranspose1d {
 
 impl crate::Module for ConvTranspose1d {
     fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        self.forward_(x).module_op_context(
+            self.prefix.as_deref(),
+            "conv-transpose1d",
+            &[x, &self.weight],
+        )
+    }
+}
+
+impl ConvTranspose1d {
+    fn forward_(&self, x: &Tensor) -> Result<Tensor> {
         let x = x.conv_transpose1d(
             &self.weight,
             self.config.padding,
@@ -165,6 +204,7 @@ pub struct Conv2d {
     weight: Tensor,
     bias: Option<Tensor>,
     config: Conv2dConfig,
+    prefix: Option<String>,
 }
 
 impl Conv2d {
@@ -173,9 +213,23 @@ impl Conv2d {
             weight,
             bias,
             config,
+            prefix: None,
+        }
+    }
+
+    /// Sets the path of the layer reported in errors, this is the `VarBuilder` prefix for the
+    /// layers created with [`conv2d`].
+    pub fn with_prefix(self, prefix: impl Into<String>) -> Self {
+        Self {
+            prefix: Some(prefix.into()),
+            ..self
         }
     }
 
+    pub fn prefix(&self) -> Option<&str> {
+        self.prefix.as_deref()
+    }
+
     pub fn config(&self) -> &Conv2dConfig {
         &self.config
     }
@@ -202,6 +256,7 @@ impl Conv2d {
                 weight,
                 bias: Some(bias),
                 config: self.config,
+                prefix: self.prefix.clone(),
             })
         } else {
             candle::bail!("batch norm does not have weight_and_bias")
@@ -211,6 +266,13 @@ impl Conv2d {
 
 impl crate::Module for Conv2d {
     fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        self.forward_(x)
+            .module_op_context(self.prefix.as_deref(), "conv2d", &[x, &self.weight])
+    }
+}
+
+impl Conv2d {
+    fn forward_(&self, x: &Tensor) -> Result<Tensor> {
         let x = x.conv2d(
             &self.weight,
             self.config.padding,
@@ -235,7 +297,7 @@ pub struct ConvTranspose2dConfig {
     pub output_padding: usize,
     pub stride: usize,
     pub dilation: usize,
-    // TODO: support groups.
+    pub groups: usize,
 }
 
 impl Default for ConvTranspose2dConfig {
@@ -245,6 +307,7 @@ impl Default for ConvTranspose2dConfig {
             output_padding: 0,
             stride: 1,
             dilation: 1,
+            groups: 1,
         }
     }
 }
@@ -254,6 +317,7 @@ pub struct ConvTranspose2d {
     weight: Tensor,
     bias: Option<Tensor>,
     config: ConvTranspose2dConfig,
+    prefix: Option<String>,
 }
 
 impl ConvTranspose2d {
@@ -262,6 +326,16 @@ impl ConvTranspose2d {
             weight,
             bias,
             config,
+            prefix: None,
+        }
+    }
+
+    /// Sets the path of the layer reported in errors, this is the `VarBuilder` prefix for the
+    /// layers created with [`conv_transpose2d`].
+    pub fn with_prefix(self, prefix: impl Into<String>) -> Self {
+        Self {
+            prefix: Some(prefix.into()),
+            ..self
         }
     }
 
@@ -280,12 +354,23 @@ impl ConvTranspose2d {
 
 impl crate::Module for ConvTranspose2d {
     fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        self.forward_(x).module_op_context(
+            self.prefix.as_deref(),
+            "conv-transpose2d",
+            &[x, &self.weight],
+        )
+    }
+}
+
+impl ConvTranspose2d {
+    fn forward_(&self, x: &Tensor) -> Result<Tensor> {
         let x = x.conv_transpose2d(
             &self.weight,
             self.config.padding,
             self.config.output_padding,
             self.config.stride,
             self.config.dilation,
+            self.config.groups,
         )?;
         match &self.bias {
             None => Ok(x),
@@ -298,6 +383,92 @@ impl crate::Module for ConvTranspose2d {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Conv3dConfig {
+    pub padding: usize,
+    pub stride: usize,
+    pub dilation: usize,
+    pub groups: usize,
+}
+
+impl Default for Conv3dConfig {
+    fn default() -> Self {
+        Self {
+            padding: 0,
+            stride: 1,
+            dilation: 1,
+            groups: 1,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Conv3d {
+    weight: Tensor,
+    bias: Option<Tensor>,
+    config: Conv3dConfig,
+    prefix: Option<String>,
+}
+
+impl Conv3d {
+    pub fn new(weight: Tensor, bias: Option<Tensor>, config: Conv3dConfig) -> Self {
+        Self {
+            weight,
+            bias,
+            config,
+            prefix: None,
+        }
+    }
+
+    /// Sets the path of the layer reported in errors, this is the `VarBuilder` prefix for the
+    /// layers created with [`conv3d`].
+    pub fn with_prefix(self, prefix: impl Into<String>) -> Self {
+        Self {
+            prefix: Some(prefix.into()),
+            ..self
+        }
+    }
+
+    pub fn config(&self) -> &Conv3dConfig {
+        &self.config
+    }
+
+    pub fn weight(&self) -> &Tensor {
+        &self.weight
+    }
+
+    pub fn bias(&self) -> Option<&Tensor> {
+        self.bias.as_ref()
+    }
+}
+
+impl crate::Module for Conv3d {
+    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        self.forward_(x)
+            .module_op_context(self.prefix.as_deref(), "conv3d", &[x, &self.weight])
+    }
+}
+
+impl Conv3d {
+    fn forward_(&self, x: &Tensor) -> Result<Tensor> {
+        let x = x.conv3d(
+            &self.weight,
+            self.config.padding,
+            self.config.stride,
+            self.config.dilation,
+            self.config.groups,
+        )?;
+        match &self.bias {
+            None => Ok(x),
+            Some(bias) => {
+                let b = bias.dims1()?;
+                let bias = bias.reshape((1, b, 1, 1, 1))?;
+                Ok(x.broadcast_add(&bias)?)
+            }
+        }
+    }
+}
+
 pub fn conv1d(
     in_channels: usize,
     out_channels: usize,
@@ -317,7 +488,7 @@ pub fn conv1d(
         up: bound,
     };
     let bs = vb.get_with_hints(out_channels, "bias", init_bs)?;
-    Ok(Conv1d::new(ws, Some(bs), cfg))
+    Ok(Conv1d::new(ws, Some(bs), cfg).with_prefix(vb.prefix()))
 }
 
 pub fn conv1d_no_bias(
@@ -333,7 +504,7 @@ pub fn conv1d_no_bias(
         "weight",
         init_ws,
     )?;
-    Ok(Conv1d::new(ws, None, cfg))
+    Ok(Conv1d::new(ws, None, cfg).with_prefix(vb.prefix()))
 }
 
 pub fn conv_transpose1d(
@@ -354,7 +525,7 @@ pub fn conv_transpose1d(
         init,
     )?;
     let bs = vb.get_with_hints(out_channels, "bias", init)?;
-    Ok(ConvTranspose1d::new(ws, Some(bs), cfg))
+    Ok(ConvTranspose1d::new(ws, Some(bs), cfg).with_prefix(vb.prefix()))
 }
 
 pub fn conv_transpose1d_no_bias(
@@ -374,7 +545,7 @@ pub fn conv_transpose1d_no_bias(
         "weight",
         init,
     )?;
-    Ok(ConvTranspose1d::new(ws, None, cfg))
+    Ok(ConvTranspose1d::new(ws, None, cfg).with_prefix(vb.prefix()))
 }
 
 pub fn conv2d(
@@ -401,7 +572,7 @@ pub fn conv2d(
         up: bound,
     };
     let bs = vb.get_with_hints(out_channels, "bias", init_bs)?;
-    Ok(Conv2d::new(ws, Some(bs), cfg))
+    Ok(Conv2d::new(ws, Some(bs), cfg).with_prefix(vb.prefix()))
 }
 
 pub fn conv2d_no_bias(
@@ -422,7 +593,7 @@ pub fn conv2d_no_bias(
         "weight",
         init_ws,
     )?;
-    Ok(Conv2d::new(ws, None, cfg))
+    Ok(Conv2d::new(ws, None, cfg).with_prefix(vb.prefix()))
 }
 
 pub fn conv_transpose2d(
@@ -438,12 +609,17 @@ pub fn conv_transpose2d(
         up: bound,
     };
     let ws = vb.get_with_hints(
-        (in_channels, out_channels, kernel_size, kernel_size),
+        (
+            in_channels,
+            out_channels / cfg.groups,
+            kernel_size,
+            kernel_size,
+        ),
         "weight",
         init,
     )?;
     let bs = vb.get_with_hints(out_channels, "bias", init)?;
-    Ok(ConvTranspose2d::new(ws, Some(bs), cfg))
+    Ok(ConvTranspose2d::new(ws, Some(bs), cfg).with_prefix(vb.prefix()))
 }
 
 pub fn conv_transpose2d_no_bias(
@@ -459,9 +635,64 @@ pub fn conv_transpose2d_no_bias(
         up: bound,
     };
     let ws = vb.get_with_hints(
-        (in_channels, out_channels, kernel_size, kernel_size),
+        (
+            in_channels,
+            out_channels / cfg.groups,
+            kernel_size,
+            kernel_size,
+        ),
         "weight",
         init,
     )?;
-    Ok(ConvTranspose2d::new(ws, None, cfg))
+    Ok(ConvTranspose2d::new(ws, None, cfg).with_prefix(vb.prefix()))
+}
+
+pub fn conv3d(
+    in_channels: usize,
+    out_channels: usize,
+    kernel_size: usize,
+    cfg: Conv3dConfig,
+    vb: crate::VarBuilder,
+) -> Result<Conv3d> {
+    let init_ws = crate::init::DEFAULT_KAIMING_NORMAL;
+    let ws = vb.get_with_hints(
+        (
+            out_channels,
+            in_channels / cfg.groups,
+            kernel_size,
+            kernel_size,
+            kernel_size,
+        ),
+        "weight",
+        init_ws,
+    )?;
+    let bound = 1. / (in_channels as f64).sqrt();
+    let init_bs = crate::Init::Uniform {
+        lo: -bound,
+        up: bound,
+    };
+    let bs = vb.get_with_hints(out_channels, "bias", init_bs)?;
+    Ok(Conv3d::new(ws, Some(bs), cfg).with_prefix(vb.prefix()))
+}
+
+pub fn conv3d_no_bias(
+    in_channels: usize,
+    out_channels: usize,
+    kernel_size: usize,
+    cfg: Conv3dConfig,
+    vb: crate::VarBuilder,
+) -> Result<Conv3d> {
+    let init_ws = crate::init::DEFAULT_KAIMING_NORMAL;
+    let ws = vb.get_with_hints(
+        (
+            out_channels,
+            in_channels / cfg.groups,
+            kernel_size,
+            kernel_size,
+            kernel_size,
+        ),
+        "weight",
+        init_ws,
+    )?;
+    Ok(Conv3d::new(ws, None, cfg).with_prefix(vb.prefix()))
 }
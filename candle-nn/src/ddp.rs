@@ -0,0 +1,157 @@
+//! Distributed data parallel training.
+//!
+//! Each rank holds a full copy of the model and processes its own part of the batch, the
+//! gradients are then averaged across the ranks before the optimizer step so that the model
+//! copies stay in sync.
+use crate::{Optimizer, VarMap};
+use candle::backprop::GradStore;
+use candle::collective::{Communicator, ReduceOp};
+use candle::{Result, Tensor, Var};
+use std::sync::Arc;
+
+/// The default size of the gradient buckets, in bytes.
+pub const DEFAULT_BUCKET_SIZE: usize = 25 * 1024 * 1024;
+
+/// A data parallel wrapper around the trainable variables of a model.
+///
+/// The gradients are flattened and concatenated into buckets of roughly `bucket_size` bytes, so
+/// that each all-reduce call operates on a large buffer rather than on many small tensors.
+/// Variables without gradients contribute zeros so that the bucket layout is the same on all the
+/// ranks. The variables have to be provided in the same order on all the ranks, note that this
+/// is not the case for `VarMap::all_vars`, `from_varmap` should be used instead.
+///
+/// ```ignore
+/// let comm = candle::collective::init_process_group(Backend::Tcp, &device, addr, rank, world_size)?;
+/// let ddp = DistributedDataParallel::from_varmap(&varmap, comm)?;
+/// let mut opt = SGD::new(varmap.all_vars(), 0.1)?;
+/// for batch in batches {
+///     let loss = model.forward(&batch)?;
+///     ddp.backward_step(&mut opt, &loss)?;
+/// }
+/// ```
+pub struct DistributedDataParallel {
+    vars: Vec<Var>,
+    comm: Arc<dyn Communicator>,
+    bucket_size: usize,
+}
+
+impl DistributedDataParallel {
+    /// Creates the wrapper and copies the variable values from rank 0 to the other ranks so that
+    /// all the model copies start from the same weights.
+    pub fn new(vars: Vec<Var>, comm: Arc<dyn Communicator>) -> Result<Self> {
+        let ddp = Self {
+            vars,
+            comm,
+            bucket_size: DEFAULT_BUCKET_SIZE,
+        };
+        ddp.sync_parameters()?;
+        Ok(ddp)
+    }
+
+    /// Creates the wrapper for all the variables of a `VarMap`, sorted by name.
+    pub fn from_varmap(varmap: &VarMap, comm: Arc<dyn Communicator>) -> Result<Self> {
+        let vars = varmap.data().lock().unwrap();
+        let mut vars = vars.iter().collect::<Vec<_>>();
+        vars.sort_by_key(|(name, _)| *name);
+        let vars = vars.into_iter().map(|(_, v)| v.clone()).collect();
+        Self::new(vars, comm)
+    }
+
+    pub fn with_bucket_size(mut self, bucket_size: usize) -> Self {
+        self.bucket_size = bucket_size;
+        self
+    }
+
+    pub fn vars(&self) -> &[Var] {
+        &self.vars
+    }
+
+    pub fn comm(&self) -> &Arc<dyn Communicator> {
+        &self.comm
+    }
+
+    pub fn rank(&self) -> usize {
+        self.comm.rank()
+    }
+
+    pub fn world_size(&self) -> usize {
+        self.comm.world_size()
+    }
+
+    /// Overwrites the variables with their values on rank 0.
+    pub fn sync_parameters(&self) -> Result<()> {
+        if self.comm.world_size() == 1 {
+            return Ok(());
+        }
+        for var in self.vars.iter() {
+            let value = self.comm.broadcast(var.as_tensor(), 0)?;
+            // On rank 0 the value is already up to date and may share the variable storage.
+            if self.comm.rank() != 0 {
+                var.set(&value)?
+            }
+        }
+        Ok(())
+    }
+
+    // Averages the gradients of the variables in a bucket, the variables all have the same dtype
+    // and device.
+    fn reduce_bucket(&self, bucket: &[&Var], grads: &mut GradStore) -> Result<()> {
+        let flat = bucket
+            .iter()
+            .map(|var| match grads.get(var) {
+                Some(grad) => grad.flatten_all(),
+                None => var.zeros_like()?.flatten_all(),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let flat = Tensor::cat(&flat, 0)?;
+        let flat = self.comm.all_reduce(&flat, ReduceOp::Avg)?;
+        let mut offset = 0;
+        for var in bucket.iter() {
+            let elem_count = var.elem_count();
+            let grad = flat.narrow(0, offset, elem_count)?.reshape(var.shape())?;
+            grads.insert(var, grad);
+            offset += elem_count
+        }
+        Ok(())
+    }
+
+    /// Averages the gradients of all the variables across the ranks, in place.
+    pub fn all_reduce_grads(&self, grads: &mut GradStore) -> Result<()> {
+        if self.comm.world_size() == 1 {
+            return Ok(());
+        }
+        let mut bucket: Vec<&Var> = vec![];
+        let mut bucket_bytes = 0;
+        // As in PyTorch, the buckets are filled in reverse order, the last layers being the first
+        // ones to get their gradients.
+        for var in self.vars.iter().rev() {
+            let same_kind = bucket.first().map_or(true, |v| {
+                v.dtype() == var.dtype() && v.device().same_device(var.device())
+            });
+            if !same_kind || bucket_bytes >= self.bucket_size {
+                self.reduce_bucket(&bucket, grads)?;
+                bucket.clear();
+                bucket_bytes = 0;
+            }
+            bucket_bytes += var.elem_count() * var.dtype().size_in_bytes();
+            bucket.push(var);
+        }
+        if !bucket.is_empty() {
+            self.reduce_bucket(&bucket, grads)?;
+        }
+        Ok(())
+    }
+
+    /// Runs the backward pass for `loss` and averages the resulting gradients across the ranks.
+    pub fn backward(&self, loss: &Tensor) -> Result<GradStore> {
+        let mut grads = loss.backward()?;
+        self.all_reduce_grads(&mut grads)?;
+        Ok(grads)
+    }
+
+    /// Runs the backward pass, averages the gradients and applies the optimizer step.
+    pub fn backward_step<O: Optimizer>(&self, opt: &mut O, loss: &Tensor) -> Result<()> {
+        let grads = self.backward(loss)?;
+        opt.step(&grads)
+    }
+}
@@ -0,0 +1,348 @@
+//! Differential weight patches between checkpoints.
+//!
+//! A `CheckpointDelta` stores the difference between a base checkpoint and a fine-tuned version
+//! of it, using a sparse or low-rank representation for each tensor. Distributing the delta
+//! rather than the full fine-tuned weights can be much cheaper, the delta can then be applied
+//! when loading the base weights with `VarBuilder::with_delta`.
+use crate::var_builder::{SimpleBackend, VarBuilder};
+use candle::philox::PhiloxKey;
+use candle::{DType, Device, Result, Shape, Tensor};
+use std::collections::HashMap;
+
+// The number of power iterations used to extract each component of a low-rank delta.
+const POWER_ITERATIONS: usize = 32;
+
+// The seed for the starting vectors of the power iterations, fixed so that computing the delta
+// between the same checkpoints always gives the same result.
+const POWER_ITERATIONS_SEED: u64 = 299792458;
+
+/// The representation to use when computing a delta.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DeltaMethod {
+    /// Store the full difference.
+    Dense,
+    /// Only store the elements for which the absolute difference is above `threshold`.
+    Sparse { threshold: f64 },
+    /// Approximate the difference with a product of two matrices of rank `rank`. Tensors with
+    /// more than two dimensions are flattened to `(dim0, rest)`.
+    LowRank { rank: usize },
+}
+
+/// The difference between two versions of a tensor.
+///
+/// The deltas use the dtype of the fine-tuned tensor.
+#[derive(Debug, Clone)]
+pub enum Delta {
+    Dense(Tensor),
+    /// `indices` contains positions in the flattened tensor and `values` the associated
+    /// differences.
+    Sparse {
+        indices: Tensor,
+        values: Tensor,
+    },
+    /// The difference is `a.matmul(b)`, with `a` of shape `(dim0, rank)` and `b` of shape
+    /// `(rank, rest)`.
+    LowRank {
+        a: Tensor,
+        b: Tensor,
+    },
+}
+
+fn normalize(xs: &Tensor) -> Result<Tensor> {
+    let norm = (xs.sqr()?.sum_all()?.sqrt()? + 1e-12)?;
+    xs.broadcast_div(&norm)
+}
+
+// Extracts the main `rank` components of a matrix using power iterations with deflation.
+fn low_rank(xs: &Tensor, rank: usize) -> Result<(Tensor, Tensor)> {
+    let (m, n) = xs.dims2()?;
+    let mut residual = xs.clone();
+    let mut us = Vec::with_capacity(rank);
+    let mut vs = Vec::with_capacity(rank);
+    for component in 0..rank {
+        let key = PhiloxKey::new(POWER_ITERATIONS_SEED, component as u64);
+        let mut v = normalize(&key.normal((n, 1), xs.device())?)?;
+        let mut u = Tensor::zeros((m, 1), DType::F32, xs.device())?;
+        for _ in 0..POWER_ITERATIONS {
+            u = normalize(&residual.matmul(&v)?)?;
+            v = normalize(&residual.t()?.matmul(&u)?)?;
+        }
+        // With `u` normalized, `residual.t() * u` holds the singular value.
+        let v = residual.t()?.matmul(&u)?;
+        residual = (residual - u.matmul(&v.t()?)?)?;
+        us.push(u);
+        vs.push(v);
+    }
+    let a = Tensor::cat(&us, 1)?;
+    let b = Tensor::cat(&vs, 1)?.t()?.contiguous()?;
+    Ok((a, b))
+}
+
+impl Delta {
+    /// Computes the delta between `base` and `target`, returns `None` if both tensors are
+    /// identical or if no element is above the threshold for sparse deltas.
+    ///
+    /// When the requested representation would not be smaller than the dense one, e.g. for
+    /// one-dimensional tensors with a low-rank method, a dense delta is returned instead.
+    pub fn compute(base: &Tensor, target: &Tensor, method: DeltaMethod) -> Result<Option<Self>> {
+        if base.shape() != target.shape() {
+            return Err(candle::Error::ShapeMismatchBinaryOp {
+                lhs: base.shape().clone(),
+                rhs: target.shape().clone(),
+                op: "delta",
+            }
+            .bt());
+        }
+        if base.fingerprint()? == target.fingerprint()? {
+            return Ok(None);
+        }
+        let dtype = target.dtype();
+        if !dtype.is_float() {
+            candle::bail!("delta: unsupported dtype {dtype:?}, only float tensors can be patched")
+        }
+        let elem_count = target.elem_count();
+        let diff = (target.to_dtype(DType::F32)? - base.to_dtype(DType::F32)?)?;
+        let dense = || Ok(Some(Self::Dense(diff.to_dtype(dtype)?)));
+        match method {
+            DeltaMethod::Dense => dense(),
+            DeltaMethod::Sparse { threshold } => {
+                let values = diff.flatten_all()?.to_vec1::<f32>()?;
+                let indices = values
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, v)| v.abs() as f64 > threshold)
+                    .map(|(i, _)| i as i64)
+                    .collect::<Vec<_>>();
+                if indices.is_empty() {
+                    return Ok(None);
+                }
+                if 2 * indices.len() >= elem_count {
+                    return dense();
+                }
+                let indices = Tensor::new(indices, diff.device())?;
+                let values = diff.flatten_all()?.gather(&indices, 0)?.to_dtype(dtype)?;
+                let indices = if elem_count <= u32::MAX as usize {
+                    indices.to_dtype(DType::U32)?
+                } else {
+                    indices
+                };
+                Ok(Some(Self::Sparse { indices, values }))
+            }
+            DeltaMethod::LowRank { rank } => {
+                let dims = diff.dims();
+                if dims.len() < 2 {
+                    return dense();
+                }
+                let m = dims[0];
+                let n = elem_count / m;
+                if rank * (m + n) >= m * n {
+                    return dense();
+                }
+                let (a, b) = low_rank(&diff.reshape((m, n))?, rank)?;
+                Ok(Some(Self::LowRank {
+                    a: a.to_dtype(dtype)?,
+                    b: b.to_dtype(dtype)?,
+                }))
+            }
+        }
+    }
+
+    /// Returns the dense difference for a tensor of shape `shape`.
+    pub fn to_dense<S: Into<Shape>>(&self, shape: S) -> Result<Tensor> {
+        let shape = shape.into();
+        match self {
+            Self::Dense(diff) => diff.reshape(shape),
+            Self::Sparse { indices, values } => {
+                Tensor::zeros(shape.elem_count(), values.dtype(), values.device())?
+                    .index_add(indices, values, 0)?
+                    .reshape(shape)
+            }
+            Self::LowRank { a, b } => a.matmul(b)?.reshape(shape),
+        }
+    }
+
+    /// Applies the delta to `base`, the result has the same dtype and device as `base`.
+    pub fn apply(&self, base: &Tensor) -> Result<Tensor> {
+        let acc_dtype = match base.dtype() {
+            DType::F64 => DType::F64,
+            _ => DType::F32,
+        };
+        let diff = self
+            .to_dense(base.shape())?
+            .to_device(base.device())?
+            .to_dtype(acc_dtype)?;
+        (base.to_dtype(acc_dtype)? + diff)?.to_dtype(base.dtype())
+    }
+
+    /// The number of bytes used to store the delta.
+    pub fn size_in_bytes(&self) -> usize {
+        let size = |t: &Tensor| t.elem_count() * t.dtype().size_in_bytes();
+        match self {
+            Self::Dense(diff) => size(diff),
+            Self::Sparse { indices, values } => size(indices) + size(values),
+            Self::LowRank { a, b } => size(a) + size(b),
+        }
+    }
+}
+
+/// A set of deltas between two checkpoints, indexed by tensor name.
+///
+/// When saved, each delta is stored as one or two tensors named after the patched tensor with a
+/// suffix describing the representation, e.g. `weight:lora_a` and `weight:lora_b`.
+#[derive(Debug, Clone, Default)]
+pub struct CheckpointDelta {
+    deltas: HashMap<String, Delta>,
+}
+
+impl CheckpointDelta {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Computes the deltas for all the tensors of `target`, all these tensors have to be present
+    /// in `base` with the same shape. Tensors that are only present in `base` are ignored.
+    pub fn compute(
+        base: &HashMap<String, Tensor>,
+        target: &HashMap<String, Tensor>,
+        method: DeltaMethod,
+    ) -> Result<Self> {
+        let mut deltas = Self::new();
+        for (name, target) in target.iter() {
+            let base = match base.get(name) {
+                None => candle::bail!("cannot find tensor {name} in the base checkpoint"),
+                Some(base) => base,
+            };
+            if let Some(delta) = Delta::compute(base, target, method)? {
+                deltas.insert(name, delta)
+            }
+        }
+        Ok(deltas)
+    }
+
+    pub fn insert<S: ToString>(&mut self, name: S, delta: Delta) {
+        self.deltas.insert(name.to_string(), delta);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Delta> {
+        self.deltas.get(name)
+    }
+
+    pub fn deltas(&self) -> &HashMap<String, Delta> {
+        &self.deltas
+    }
+
+    pub fn len(&self) -> usize {
+        self.deltas.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deltas.is_empty()
+    }
+
+    /// The number of bytes used to store all the deltas.
+    pub fn size_in_bytes(&self) -> usize {
+        self.deltas.values().map(|d| d.size_in_bytes()).sum()
+    }
+
+    /// Applies the delta for tensor `name` if any, otherwise returns `tensor` unchanged.
+    pub fn apply(&self, name: &str, tensor: &Tensor) -> Result<Tensor> {
+        match self.deltas.get(name) {
+            None => Ok(tensor.clone()),
+            Some(delta) => delta.apply(tensor),
+        }
+    }
+
+    /// Converts the deltas to a flat list of named tensors, suitable for serialization.
+    pub fn to_tensors(&self) -> HashMap<String, Tensor> {
+        let mut tensors = HashMap::new();
+        for (name, delta) in self.deltas.iter() {
+            match delta {
+                Delta::Dense(diff) => {
+                    tensors.insert(format!("{name}:dense"), diff.clone());
+                }
+                Delta::Sparse { indices, values } => {
+                    tensors.insert(format!("{name}:sparse_indices"), indices.clone());
+                    tensors.insert(format!("{name}:sparse_values"), values.clone());
+                }
+                Delta::LowRank { a, b } => {
+                    tensors.insert(format!("{name}:lora_a"), a.clone());
+                    tensors.insert(format!("{name}:lora_b"), b.clone());
+                }
+            }
+        }
+        tensors
+    }
+
+    /// Builds the deltas from a list of named tensors as produced by `to_tensors`.
+    pub fn from_tensors(mut tensors: HashMap<String, Tensor>) -> Result<Self> {
+        let mut deltas = Self::new();
+        let keys = tensors.keys().cloned().collect::<Vec<_>>();
+        for key in keys.iter() {
+            // The tensors are removed when processed so the second tensor of a pair is skipped.
+            if !tensors.contains_key(key) {
+                continue;
+            }
+            let (name, kind) = match key.rsplit_once(':') {
+                None => candle::bail!("unexpected tensor name in delta checkpoint {key}"),
+                Some(v) => v,
+            };
+            let mut take = |suffix: &str| match tensors.remove(&format!("{name}:{suffix}")) {
+                None => candle::bail!("missing tensor {name}:{suffix} in delta checkpoint"),
+                Some(t) => Ok(t),
+            };
+            let delta = match kind {
+                "dense" => Delta::Dense(take("dense")?),
+                "sparse_indices" | "sparse_values" => Delta::Sparse {
+                    indices: take("sparse_indices")?,
+                    values: take("sparse_values")?,
+                },
+                "lora_a" | "lora_b" => Delta::LowRank {
+                    a: take("lora_a")?,
+                    b: take("lora_b")?,
+                },
+                _ => candle::bail!("unexpected tensor name in delta checkpoint {key}"),
+            };
+            deltas.insert(name, delta)
+        }
+        Ok(deltas)
+    }
+
+    pub fn load<P: AsRef<std::path::Path>>(path: P, device: &Device) -> Result<Self> {
+        Self::from_tensors(candle::safetensors::load(path, device)?)
+    }
+
+    pub fn save<P: AsRef<std::path::Path>>(&self, path: P) -> Result<()> {
+        candle::safetensors::save(&self.to_tensors(), path)
+    }
+}
+
+/// A backend that applies a `CheckpointDelta` to the tensors of an inner `VarBuilder`, see
+/// `VarBuilder::with_delta`.
+pub struct Patched<'a> {
+    inner: VarBuilder<'a>,
+    delta: CheckpointDelta,
+}
+
+impl<'a> Patched<'a> {
+    pub fn new(inner: VarBuilder<'a>, delta: CheckpointDelta) -> Self {
+        Self { inner, delta }
+    }
+}
+
+impl<'a> SimpleBackend for Patched<'a> {
+    fn get(
+        &self,
+        s: Shape,
+        name: &str,
+        h: crate::Init,
+        dtype: DType,
+        dev: &Device,
+    ) -> Result<Tensor> {
+        let tensor = self.inner.get_with_hints_dtype(s, name, h, dtype)?;
+        self.delta.apply(name, &tensor)?.to_device(dev)
+    }
+
+    fn contains_tensor(&self, name: &str) -> bool {
+        self.inner.contains_tensor(name)
+    }
+}
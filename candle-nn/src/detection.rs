@@ -0,0 +1,269 @@
+//! Box operations for object detection models: intersection over union, non-maximum suppression
+//! and ROI align.
+//!
+//! The boxes use the `(x1, y1, x2, y2)` format and the results match the torchvision operations
+//! with the same names. The computations run on the device of the inputs, except for the greedy
+//! selection of the non-maximum suppression that runs on the host using the suppression mask
+//! computed on the device, and for the grouping of the ROI align regions by image.
+use candle::{DType, Result, Tensor};
+
+fn areas(boxes: &Tensor) -> Result<Tensor> {
+    let w = (boxes.narrow(1, 2, 1)? - boxes.narrow(1, 0, 1)?)?;
+    let h = (boxes.narrow(1, 3, 1)? - boxes.narrow(1, 1, 1)?)?;
+    (w * h)?.squeeze(1)
+}
+
+/// The intersection over union of each pair of boxes, `boxes1` and `boxes2` have shapes
+/// `(n, 4)` and `(m, 4)`, the result has shape `(n, m)`.
+pub fn box_iou(boxes1: &Tensor, boxes2: &Tensor) -> Result<Tensor> {
+    let (_, four1) = boxes1.dims2()?;
+    let (_, four2) = boxes2.dims2()?;
+    if four1 != 4 || four2 != 4 {
+        candle::bail!(
+            "box_iou expects boxes with 4 coordinates, got {:?} and {:?}",
+            boxes1.shape(),
+            boxes2.shape()
+        )
+    }
+    let b1 = boxes1.unsqueeze(1)?;
+    let b2 = boxes2.unsqueeze(0)?;
+    let top_left = b1
+        .narrow(2, 0, 2)?
+        .broadcast_maximum(&b2.narrow(2, 0, 2)?)?;
+    let bottom_right = b1
+        .narrow(2, 2, 2)?
+        .broadcast_minimum(&b2.narrow(2, 2, 2)?)?;
+    let wh = (bottom_right - top_left)?.relu()?;
+    let inter = (wh.narrow(2, 0, 1)? * wh.narrow(2, 1, 1)?)?.squeeze(2)?;
+    let union = areas(boxes1)?
+        .unsqueeze(1)?
+        .broadcast_add(&areas(boxes2)?.unsqueeze(0)?)?;
+    inter.div(&(union - &inter)?)
+}
+
+/// Non-maximum suppression: the boxes are visited by decreasing score and a box is discarded
+/// when its intersection over union with a previously kept box is above `iou_threshold`.
+///
+/// `boxes` has shape `(n, 4)` and `scores` has shape `(n,)`. The result is a u32 tensor with the
+/// indexes of the kept boxes, sorted by decreasing score.
+///
+/// The intersections over union are computed on the device but the greedy selection is
+/// sequential and runs on the host, so the `(n, n)` suppression mask is copied there. The boxes
+/// should be filtered by score first, e.g. with a confidence threshold, when `n` is large.
+pub fn nms(boxes: &Tensor, scores: &Tensor, iou_threshold: f32) -> Result<Tensor> {
+    let n = scores.dims1()?;
+    if boxes.dims() != [n, 4] {
+        candle::bail!(
+            "nms expects boxes with shape ({n}, 4), got {:?}",
+            boxes.shape()
+        )
+    }
+    if n == 0 {
+        return Tensor::zeros(0, DType::U32, boxes.device());
+    }
+    let order = scores.contiguous()?.arg_sort_last_dim(false)?;
+    let sorted = boxes.contiguous()?.index_select(&order, 0)?;
+    let suppress = box_iou(&sorted, &sorted)?
+        .gt(iou_threshold as f64)?
+        .to_vec2::<u8>()?;
+    let order = order.to_vec1::<u32>()?;
+    let mut removed = vec![false; n];
+    let mut keep = vec![];
+    for i in 0..n {
+        if removed[i] {
+            continue;
+        }
+        keep.push(order[i]);
+        for (j, removed) in removed.iter_mut().enumerate().skip(i + 1) {
+            *removed |= suppress[i][j] != 0
+        }
+    }
+    Tensor::new(keep, boxes.device())
+}
+
+/// Non-maximum suppression applied independently to each category, `idxs` is a u32 tensor of
+/// shape `(n,)` with the category of each box. The boxes of the different categories never
+/// suppress each other, the result is sorted by decreasing score over all the categories.
+pub fn batched_nms(
+    boxes: &Tensor,
+    scores: &Tensor,
+    idxs: &Tensor,
+    iou_threshold: f32,
+) -> Result<Tensor> {
+    if boxes.elem_count() == 0 {
+        return nms(boxes, scores, iou_threshold);
+    }
+    // Shift the boxes of each category so that boxes from different categories do not overlap.
+    let max_coordinate = boxes.flatten_all()?.max(0)?;
+    let offsets = idxs
+        .to_dtype(boxes.dtype())?
+        .broadcast_mul(&(max_coordinate + 1.)?)?;
+    let boxes = boxes.broadcast_add(&offsets.unsqueeze(1)?)?;
+    nms(&boxes, scores, iou_threshold)
+}
+
+/// The configuration of [`roi_align`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RoiAlignConfig {
+    /// The size `(height, width)` of the output for each region.
+    pub output_size: (usize, usize),
+    /// The scale from the box coordinates to the input coordinates, e.g. 1/16 for a feature map
+    /// with a stride of 16.
+    pub spatial_scale: f32,
+    /// The number of sampling points per output bin along each dimension. When `None`, this is
+    /// `ceil(roi_size / output_size)` for each region.
+    pub sampling_ratio: Option<usize>,
+    /// Shifts the box coordinates by half a pixel, this is the correct behavior and the
+    /// torchvision default for the more recent models.
+    pub aligned: bool,
+}
+
+impl RoiAlignConfig {
+    pub fn new(output_size: (usize, usize), spatial_scale: f32) -> Self {
+        Self {
+            output_size,
+            spatial_scale,
+            sampling_ratio: None,
+            aligned: true,
+        }
+    }
+}
+
+// The interpolation matrix of shape `(k, out, size)` along one spatial dimension: each output bin
+// averages the bilinear interpolation weights of its sampling points. `start`, `bin` and `grid`
+// have shape `(k,)`, the sampling points beyond `grid` for a region are masked out.
+fn roi_weights(
+    start: &Tensor,
+    bin: &Tensor,
+    grid: &Tensor,
+    max_grid: usize,
+    out: usize,
+    size: usize,
+) -> Result<Tensor> {
+    let dev = start.device();
+    let k = start.dim(0)?;
+    let start = start.reshape((k, 1, 1))?;
+    let bin = bin.reshape((k, 1, 1))?;
+    let grid = grid.reshape((k, 1, 1))?;
+    let bins = Tensor::arange(0f32, out as f32, dev)?.reshape((1, out, 1))?;
+    let samples = Tensor::arange(0f32, max_grid as f32, dev)?.reshape((1, 1, max_grid))?;
+    // The coordinates of the sampling points, shape (k, out, max_grid).
+    let coords = bins
+        .broadcast_mul(&bin)?
+        .broadcast_add(&start)?
+        .broadcast_add(&(&samples + 0.5)?.broadcast_mul(&bin.broadcast_div(&grid)?)?)?;
+    // Points that are more than a pixel outside of the input do not contribute.
+    let valid = (coords.ge(-1.)?.to_dtype(DType::F32)?
+        * coords.le(size as f64)?.to_dtype(DType::F32)?)?
+    .broadcast_mul(&samples.broadcast_lt(&grid)?.to_dtype(DType::F32)?)?;
+    let coords = coords.clamp(0f32, (size - 1) as f32)?;
+    let low = coords.floor()?;
+    let high = (&low + 1.)?.clamp(0f32, (size - 1) as f32)?;
+    let frac = (&coords - &low)?;
+    let low_w = (&valid * (1. - &frac)?)?;
+    let high_w = (&valid * &frac)?;
+    // Each sampling point contributes to its two neighbouring positions.
+    let indexes = Tensor::cat(&[&low, &high], 2)?.to_dtype(DType::U32)?;
+    let weights = Tensor::cat(&[&low_w, &high_w], 2)?;
+    Tensor::zeros((k, out, size), DType::F32, dev)?.scatter_add(&indexes, &weights, 2)
+}
+
+/// ROI align: extracts a feature map of size `output_size` for each region using bilinear
+/// interpolation, as used by Mask R-CNN.
+///
+/// `xs` has shape `(b, c, h, w)` and `rois` has shape `(k, 5)`, each row being
+/// `(batch_index, x1, y1, x2, y2)`. The result has shape `(k, c, out_h, out_w)`.
+///
+/// The batch indexes are read on the host to group the regions by image, the features of each
+/// image are then gathered for all its regions at once without copying the feature maps.
+pub fn roi_align(xs: &Tensor, rois: &Tensor, cfg: RoiAlignConfig) -> Result<Tensor> {
+    let (b_sz, c, h, w) = xs.dims4()?;
+    let (k, five) = rois.dims2()?;
+    if five != 5 {
+        candle::bail!(
+            "roi_align expects rois with shape (k, 5), got {:?}",
+            rois.shape()
+        )
+    }
+    let (out_h, out_w) = cfg.output_size;
+    if out_h == 0 || out_w == 0 {
+        candle::bail!(
+            "roi_align expects a non-empty output size, got {:?}",
+            cfg.output_size
+        )
+    }
+    if k == 0 {
+        return Tensor::zeros((0, c, out_h, out_w), xs.dtype(), xs.device());
+    }
+    let dtype = xs.dtype();
+    let rois = rois.to_dtype(DType::F32)?;
+    let offset = if cfg.aligned { 0.5 } else { 0. };
+    let coord = |i: usize| -> Result<Tensor> {
+        (rois.narrow(1, i, 1)?.squeeze(1)? * cfg.spatial_scale as f64)? - offset
+    };
+    let (x1, y1, x2, y2) = (coord(1)?, coord(2)?, coord(3)?, coord(4)?);
+    let (mut roi_w, mut roi_h) = ((x2 - &x1)?, (y2 - &y1)?);
+    if !cfg.aligned {
+        // The legacy behavior forces the regions to be at least 1x1.
+        roi_w = roi_w.maximum(1f32)?;
+        roi_h = roi_h.maximum(1f32)?;
+    }
+    let bin_w = (roi_w / out_w as f64)?;
+    let bin_h = (roi_h / out_h as f64)?;
+    let (grid_h, grid_w, max_grid_h, max_grid_w) = match cfg.sampling_ratio {
+        Some(ratio) => {
+            let ratio = ratio.max(1);
+            let grid = Tensor::full(ratio as f32, k, xs.device())?;
+            (grid.clone(), grid, ratio, ratio)
+        }
+        None => {
+            let grid_h = bin_h.ceil()?.maximum(1f32)?;
+            let grid_w = bin_w.ceil()?.maximum(1f32)?;
+            let max_grid_h = grid_h.max(0)?.to_scalar::<f32>()? as usize;
+            let max_grid_w = grid_w.max(0)?.to_scalar::<f32>()? as usize;
+            (grid_h, grid_w, max_grid_h, max_grid_w)
+        }
+    };
+    let count = (&grid_h * &grid_w)?.reshape((k, 1, 1, 1))?;
+    let weights_h = roi_weights(&y1, &bin_h, &grid_h, max_grid_h, out_h, h)?;
+    let weights_w = roi_weights(&x1, &bin_w, &grid_w, max_grid_w, out_w, w)?;
+    let batch_indexes = rois.narrow(1, 0, 1)?.squeeze(1)?.to_vec1::<f32>()?;
+    let mut by_image = vec![vec![]; b_sz];
+    for (i, &b) in batch_indexes.iter().enumerate() {
+        match by_image.get_mut(b as usize) {
+            Some(regions) if b >= 0. && b.fract() == 0. => regions.push(i as u32),
+            _ => candle::bail!("roi_align got a batch index {b} for {b_sz} images"),
+        }
+    }
+    let xs = xs.to_dtype(DType::F32)?;
+    let mut ys = Vec::with_capacity(b_sz);
+    let mut order = Vec::with_capacity(k);
+    for (b, regions) in by_image.into_iter().enumerate() {
+        if regions.is_empty() {
+            continue;
+        }
+        let n = regions.len();
+        let regions_t = Tensor::new(regions.as_slice(), xs.device())?;
+        let weights_h = weights_h.index_select(&regions_t, 0)?;
+        let weights_w = weights_w.index_select(&regions_t, 0)?;
+        // (n * out_h, h) x (h, c * w) -> (n, c, out_h, w)
+        let x = xs.get(b)?.transpose(0, 1)?.reshape((h, c * w))?;
+        let y = weights_h
+            .reshape((n * out_h, h))?
+            .matmul(&x)?
+            .reshape((n, out_h, c, w))?
+            .transpose(1, 2)?;
+        // (n, c, out_h, w) x (n, 1, w, out_w)
+        let y = y.broadcast_matmul(&weights_w.unsqueeze(1)?.transpose(2, 3)?)?;
+        ys.push(y);
+        order.extend(regions);
+    }
+    // Back to the order of the regions.
+    let mut positions = vec![0u32; k];
+    for (position, &i) in order.iter().enumerate() {
+        positions[i as usize] = position as u32
+    }
+    let positions = Tensor::new(positions, xs.device())?;
+    let ys = Tensor::cat(&ys, 0)?.index_select(&positions, 0)?;
+    ys.broadcast_div(&count)?.to_dtype(dtype)
+}
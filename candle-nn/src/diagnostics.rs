@@ -0,0 +1,111 @@
+//! Training diagnostics.
+//!
+//! These helpers are used to monitor the optimization, e.g. to tune the batch size and learning
+//! rate. They can be enabled in the `Trainer` via `TrainerConfig::diagnostics`, in which case
+//! the values are reported to the callbacks in `StepMetrics::diagnostics`.
+//!
+//! - The per-variable gradient norms, to spot exploding or vanishing gradients in a given layer.
+//! - The update to weight ratio, i.e. the norm of the update applied by the optimizer divided by
+//!   the norm of the weights, values far from `1e-3` usually indicate a poorly tuned learning
+//!   rate.
+//! - The gradient noise scale from "An Empirical Model of Large-Batch Training"
+//!   <https://arxiv.org/abs/1812.06162>, an estimate of the critical batch size above which
+//!   increasing the batch size does not speed up training much.
+use candle::{DType, Result, Tensor};
+
+// The squared l2 norm of a tensor as an f32 scalar tensor.
+pub(crate) fn sq_norm(x: &Tensor) -> Result<Tensor> {
+    x.to_dtype(DType::F32)?.sqr()?.sum_all()
+}
+
+// Retrieves some scalar tensors with a single transfer.
+pub(crate) fn fetch_scalars(xs: &[Tensor]) -> Result<Vec<f64>> {
+    let device = match xs.first() {
+        None => return Ok(vec![]),
+        Some(x) => x.device(),
+    };
+    let xs = xs
+        .iter()
+        .map(|x| x.to_device(device)?.to_dtype(DType::F32)?.reshape(1))
+        .collect::<Result<Vec<_>>>()?;
+    Tensor::cat(&xs, 0)?.to_dtype(DType::F64)?.to_vec1::<f64>()
+}
+
+/// Returns the squared l2 norms of some tensors. The norms are computed on the device and
+/// retrieved with a single transfer.
+pub fn sq_norms(xs: &[&Tensor]) -> Result<Vec<f64>> {
+    let norms = xs.iter().map(|x| sq_norm(x)).collect::<Result<Vec<_>>>()?;
+    fetch_scalars(&norms)
+}
+
+/// Estimates the gradient noise scale from the squared gradient norms measured at two batch
+/// sizes, e.g. the micro-batches and the full batch when accumulating gradients.
+///
+/// The unbiased estimates of the true gradient squared norm `|G|^2` and of the trace of the
+/// gradient covariance `S` are noisy so both are smoothed with an exponential moving average
+/// before computing the noise scale `S / |G|^2`.
+#[derive(Debug, Clone)]
+pub struct NoiseScaleEstimator {
+    decay: f64,
+    g2: Option<f64>,
+    s: Option<f64>,
+}
+
+impl NoiseScaleEstimator {
+    /// Creates an estimator, `decay` is the decay of the moving averages, e.g. `0.99`.
+    pub fn new(decay: f64) -> Self {
+        Self {
+            decay,
+            g2: None,
+            s: None,
+        }
+    }
+
+    /// Adds a measurement: `small_sq_norm` is the average squared gradient norm for batches of
+    /// size `b_small` and `big_sq_norm` the squared gradient norm for a batch of size `b_big`.
+    /// Returns the updated estimate, in the same unit as the batch sizes.
+    pub fn update(
+        &mut self,
+        small_sq_norm: f64,
+        big_sq_norm: f64,
+        b_small: f64,
+        b_big: f64,
+    ) -> Option<f64> {
+        if b_big <= b_small {
+            return self.estimate();
+        }
+        let g2 = (b_big * big_sq_norm - b_small * small_sq_norm) / (b_big - b_small);
+        let s = (small_sq_norm - big_sq_norm) / (1. / b_small - 1. / b_big);
+        let ema = |prev: Option<f64>, v: f64| match prev {
+            None => v,
+            Some(prev) => self.decay * prev + (1. - self.decay) * v,
+        };
+        self.g2 = Some(ema(self.g2, g2));
+        self.s = Some(ema(self.s, s));
+        self.estimate()
+    }
+
+    /// The current estimate of the noise scale, `None` before the first update or when the
+    /// estimated gradient norm is not positive.
+    pub fn estimate(&self) -> Option<f64> {
+        match (self.g2, self.s) {
+            (Some(g2), Some(s)) if g2 > 0. => Some(s / g2),
+            _ => None,
+        }
+    }
+}
+
+/// The diagnostics for an optimizer step.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StepDiagnostics {
+    /// The gradient norm for each variable, before clipping, sorted by variable name.
+    pub grad_norms: Vec<(String, f64)>,
+    /// The norm of the update divided by the norm of the weights before the update, for each
+    /// variable with a gradient. This is 0 for variables whose weights were all zeros.
+    pub update_ratios: Vec<(String, f64)>,
+    /// The gradient norm over all the variables, before clipping.
+    pub global_grad_norm: f64,
+    /// The smoothed gradient noise scale, in number of batches (micro-batches when accumulating
+    /// gradients). This requires at least two accumulation steps.
+    pub noise_scale: Option<f64>,
+}
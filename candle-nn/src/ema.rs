@@ -0,0 +1,223 @@
+//! Exponential moving average of model weights.
+//!
+//! Many training recipes, e.g. for diffusion models, evaluate and ship an exponential moving
+//! average of the weights rather than the raw weights, the average being less noisy. A `ModelEma`
+//! tracks a shadow copy of all the variables of a `VarMap`:
+//!
+//! ```ignore
+//! let mut ema = ModelEma::new(&varmap, 0.9999)?;
+//! for batch in batches {
+//!     opt.backward_step(&loss)?;
+//!     ema.update()?;
+//! }
+//! ema.apply()?;
+//! // ... evaluate the model using the averaged weights ...
+//! ema.restore()?;
+//! ```
+use crate::VarMap;
+use candle::{DType, Device, Result, Tensor};
+use std::collections::HashMap;
+
+// The name under which the number of updates is stored in the serialized shadow weights.
+const NUM_UPDATES_KEY: &str = "__ema_num_updates";
+
+// Half precision shadows lose most of the small updates, so they are kept in f32.
+fn shadow_dtype(dtype: DType) -> DType {
+    match dtype {
+        DType::F16 | DType::BF16 => DType::F32,
+        dtype => dtype,
+    }
+}
+
+/// Shadow copies of the variables of a `VarMap` updated with an exponential moving average.
+pub struct ModelEma {
+    varmap: VarMap,
+    shadow: HashMap<String, Tensor>,
+    backup: Option<HashMap<String, Tensor>>,
+    decay: f64,
+    warmup: bool,
+    num_updates: usize,
+}
+
+impl ModelEma {
+    /// Creates the shadow weights, initialized with the current values of the variables.
+    pub fn new(varmap: &VarMap, decay: f64) -> Result<Self> {
+        if !(0. ..=1.).contains(&decay) {
+            candle::bail!("ema decay should be between 0 and 1, got {decay}")
+        }
+        let mut ema = Self {
+            varmap: varmap.clone(),
+            shadow: HashMap::new(),
+            backup: None,
+            decay,
+            warmup: false,
+            num_updates: 0,
+        };
+        ema.add_missing()?;
+        Ok(ema)
+    }
+
+    /// When enabled, the decay used for the n-th update is `min(decay, (1 + n) / (10 + n))` so
+    /// that the average follows the weights more closely at the start of training.
+    pub fn with_warmup(mut self, warmup: bool) -> Self {
+        self.warmup = warmup;
+        self
+    }
+
+    /// The decay that will be used by the next update.
+    pub fn decay(&self) -> f64 {
+        if self.warmup {
+            let n = self.num_updates as f64;
+            self.decay.min((1. + n) / (10. + n))
+        } else {
+            self.decay
+        }
+    }
+
+    pub fn set_decay(&mut self, decay: f64) {
+        self.decay = decay
+    }
+
+    pub fn num_updates(&self) -> usize {
+        self.num_updates
+    }
+
+    /// The shadow value for the variable `name`.
+    pub fn get(&self, name: &str) -> Option<&Tensor> {
+        self.shadow.get(name)
+    }
+
+    pub fn shadow(&self) -> &HashMap<String, Tensor> {
+        &self.shadow
+    }
+
+    // Variables added to the var map after the ema was created start from their current value.
+    fn add_missing(&mut self) -> Result<()> {
+        let data = self.varmap.data().lock().unwrap();
+        for (name, var) in data.iter() {
+            if !self.shadow.contains_key(name) {
+                let value = var.to_dtype(shadow_dtype(var.dtype()))?.copy()?;
+                self.shadow.insert(name.clone(), value);
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves the shadow weights towards the current values of the variables. Variables that are
+    /// not floating point are copied as is.
+    pub fn update(&mut self) -> Result<()> {
+        if self.backup.is_some() {
+            candle::bail!("ema update while the shadow weights are applied")
+        }
+        self.add_missing()?;
+        let decay = self.decay();
+        let data = self.varmap.data().lock().unwrap();
+        for (name, var) in data.iter() {
+            let shadow = match self.shadow.get_mut(name) {
+                Some(shadow) => shadow,
+                None => continue,
+            };
+            *shadow = if var.dtype().is_float() {
+                let value = var.to_dtype(shadow.dtype())?;
+                ((&*shadow * decay)? + (value * (1. - decay))?)?
+            } else {
+                var.as_tensor().copy()?
+            };
+        }
+        self.num_updates += 1;
+        Ok(())
+    }
+
+    /// Sets the variables to the shadow weights, the current values are kept so that they can be
+    /// put back with `restore`.
+    pub fn apply(&mut self) -> Result<()> {
+        if self.backup.is_some() {
+            candle::bail!("the ema shadow weights are already applied")
+        }
+        let data = self.varmap.data().lock().unwrap();
+        let mut backup = HashMap::with_capacity(data.len());
+        for (name, var) in data.iter() {
+            if let Some(shadow) = self.shadow.get(name) {
+                backup.insert(name.clone(), var.as_tensor().copy()?);
+                var.set(&shadow.to_dtype(var.dtype())?)?
+            }
+        }
+        self.backup = Some(backup);
+        Ok(())
+    }
+
+    /// Puts back the variable values saved by `apply`.
+    pub fn restore(&mut self) -> Result<()> {
+        let backup = match self.backup.take() {
+            None => candle::bail!("the ema shadow weights have not been applied"),
+            Some(backup) => backup,
+        };
+        let data = self.varmap.data().lock().unwrap();
+        for (name, value) in backup.iter() {
+            if let Some(var) = data.get(name) {
+                var.set(value)?
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns whether the shadow weights are currently applied to the variables.
+    pub fn is_applied(&self) -> bool {
+        self.backup.is_some()
+    }
+
+    /// Creates a new `VarMap` holding a copy of the shadow weights, using the same dtypes as the
+    /// tracked variables.
+    pub fn to_varmap(&self) -> Result<VarMap> {
+        let varmap = VarMap::new();
+        {
+            let data = self.varmap.data().lock().unwrap();
+            let mut ema_data = varmap.data().lock().unwrap();
+            for (name, shadow) in self.shadow.iter() {
+                let dtype = data.get(name).map_or(shadow.dtype(), |v| v.dtype());
+                let var = candle::Var::from_tensor(&shadow.to_dtype(dtype)?)?;
+                ema_data.insert(name.clone(), var);
+            }
+        }
+        Ok(varmap)
+    }
+
+    /// Saves the shadow weights in the safetensors format, using the variable names so that the
+    /// file can also be loaded as regular model weights.
+    pub fn save<P: AsRef<std::path::Path>>(&self, path: P) -> Result<()> {
+        let num_updates = Tensor::new(self.num_updates as u32, &Device::Cpu)?;
+        let data = self
+            .shadow
+            .iter()
+            .chain(std::iter::once((
+                &NUM_UPDATES_KEY.to_string(),
+                &num_updates,
+            )))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect::<HashMap<_, _>>();
+        candle::safetensors::save(&data, path)
+    }
+
+    /// Loads shadow weights saved with `save`. Variables that are not in the file keep their
+    /// current shadow value.
+    pub fn load<P: AsRef<std::path::Path>>(&mut self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let data = unsafe { candle::safetensors::MmapedSafetensors::new(path)? };
+        for (name, shadow) in self.shadow.iter_mut() {
+            if let Ok(value) = data.load(name, shadow.device()) {
+                if value.shape() != shadow.shape() {
+                    candle::bail!(
+                        "shape mismatch for {name} in {path:?}: {:?} <> {:?}",
+                        value.shape(),
+                        shadow.shape()
+                    )
+                }
+                *shadow = value.to_dtype(shadow.dtype())?
+            }
+        }
+        if let Ok(num_updates) = data.load(NUM_UPDATES_KEY, &Device::Cpu) {
+            self.num_updates = num_updates.to_scalar::<u32>()? as usize
+        }
+        Ok(())
+    }
+}
@@ -1,10 +1,11 @@
 //! Embedding Layer.
-use candle::{Result, Tensor};
+use candle::{Result, ResultExt, Tensor};
 
 #[derive(Clone, Debug)]
 pub struct Embedding {
     embeddings: Tensor,
     hidden_size: usize,
+    prefix: Option<String>,
 }
 
 impl Embedding {
@@ -12,6 +13,16 @@ impl Embedding {
         Self {
             embeddings,
             hidden_size,
+            prefix: None,
+        }
+    }
+
+    /// Sets the path of the layer reported in errors, this is the `VarBuilder` prefix for the
+    /// layers created with [`embedding`].
+    pub fn with_prefix(self, prefix: impl Into<String>) -> Self {
+        Self {
+            prefix: Some(prefix.into()),
+            ..self
         }
     }
 
@@ -27,6 +38,16 @@ impl Embedding {
 
 impl crate::Module for Embedding {
     fn forward(&self, indexes: &Tensor) -> Result<Tensor> {
+        self.forward_(indexes).module_op_context(
+            self.prefix.as_deref(),
+            "embedding",
+            &[indexes, &self.embeddings],
+        )
+    }
+}
+
+impl Embedding {
+    fn forward_(&self, indexes: &Tensor) -> Result<Tensor> {
         let mut final_dims = indexes.dims().to_vec();
         final_dims.push(self.hidden_size);
         let indexes = indexes.flatten_all()?;
@@ -45,5 +66,5 @@ pub fn embedding(in_size: usize, out_size: usize, vb: crate::VarBuilder) -> Resu
             stdev: 1.,
         },
     )?;
-    Ok(Embedding::new(embeddings, out_size))
+    Ok(Embedding::new(embeddings, out_size).with_prefix(vb.prefix()))
 }
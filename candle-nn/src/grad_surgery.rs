@@ -0,0 +1,351 @@
+//! Gradient combination methods for multi-task training.
+//!
+//! When a model is trained on several objectives, summing the losses lets the tasks with the
+//! largest gradients dominate and the gradients of different tasks can point in conflicting
+//! directions. The combiners in this module run the backward pass once per task and merge the
+//! resulting gradients into a single `GradStore` that can be given to any optimizer:
+//!
+//! - [`PCGrad`] projects the gradient of each task on the normal plane of the gradients of the
+//!   other tasks it conflicts with, i.e. when their inner product is negative,
+//!   [Gradient Surgery for Multi-Task Learning](https://arxiv.org/abs/2001.06782).
+//! - [`GradNorm`] weights the tasks and learns the weights so that the tasks train at similar
+//!   rates, [GradNorm](https://arxiv.org/abs/1711.02257).
+//!
+//! The inner products are computed over the gradients of all the variables as if they were
+//! concatenated in a single vector.
+//!
+//! ```ignore
+//! let vars = varmap.all_vars();
+//! let mut opt = AdamW::new_lr(vars.clone(), 1e-3)?;
+//! let mut pcgrad = PCGrad::new(42);
+//! for batch in batches {
+//!     let losses = [loss::mse(&depth, &batch.depth)?, loss::nll(&seg, &batch.seg)?];
+//!     pcgrad.backward_step(&mut opt, &vars, &losses)?;
+//! }
+//! ```
+use crate::diagnostics::fetch_scalars;
+use crate::Optimizer;
+use candle::backprop::GradStore;
+use candle::{DType, Result, Tensor, Var};
+
+/// Merges the gradients of several tasks into a single set of gradients.
+pub trait GradCombiner {
+    /// Combines the gradients of each task for the variables in `vars`. `task_grads` and
+    /// `losses`, the values of the task losses, are in the same task order. Only the gradients of
+    /// `vars` in the returned store are meaningful.
+    fn combine(
+        &mut self,
+        vars: &[Var],
+        task_grads: Vec<GradStore>,
+        losses: &[f64],
+    ) -> Result<GradStore>;
+
+    /// Runs the backward pass of each task loss and combines the resulting gradients.
+    fn backward(&mut self, vars: &[Var], losses: &[Tensor]) -> Result<GradStore> {
+        let task_grads = losses
+            .iter()
+            .map(|loss| loss.backward())
+            .collect::<Result<Vec<_>>>()?;
+        let values = fetch_scalars(losses)?;
+        self.combine(vars, task_grads, &values)
+    }
+
+    /// Runs the backward pass of each task loss and an optimizer step on the combined gradients.
+    fn backward_step<O: Optimizer>(
+        &mut self,
+        opt: &mut O,
+        vars: &[Var],
+        losses: &[Tensor],
+    ) -> Result<()>
+    where
+        Self: Sized,
+    {
+        let grads = self.backward(vars, losses)?;
+        opt.step(&grads)
+    }
+}
+
+// The gradients of each task for the variables that have a gradient in at least one task, the
+// missing ones being zeros.
+fn collect_grads(vars: &[Var], task_grads: &[GradStore]) -> Result<(Vec<Var>, Vec<Vec<Tensor>>)> {
+    let vars = vars
+        .iter()
+        .filter(|var| task_grads.iter().any(|grads| grads.get(var).is_some()))
+        .cloned()
+        .collect::<Vec<_>>();
+    let grads = task_grads
+        .iter()
+        .map(|grads| {
+            vars.iter()
+                .map(|var| match grads.get(var) {
+                    Some(g) => Ok(g.clone()),
+                    None => var.zeros_like(),
+                })
+                .collect::<Result<Vec<_>>>()
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok((vars, grads))
+}
+
+// The inner product of two gradients, as a f32 scalar tensor on the device of the first
+// variable.
+fn dot(lhs: &[Tensor], rhs: &[Tensor]) -> Result<Tensor> {
+    let mut acc: Option<Tensor> = None;
+    for (l, r) in lhs.iter().zip(rhs.iter()) {
+        let s = (l.to_dtype(DType::F32)? * r.to_dtype(DType::F32)?)?.sum_all()?;
+        acc = Some(match acc {
+            None => s,
+            Some(acc) => (&acc + s.to_device(acc.device())?)?,
+        })
+    }
+    match acc {
+        None => Tensor::new(0f32, &candle::Device::Cpu),
+        Some(acc) => Ok(acc),
+    }
+}
+
+// Removes from `grad` its component along `other` when the two conflict. The coefficient is
+// computed on the device so that no synchronization is required.
+fn project(grad: &[Tensor], other: &[Tensor]) -> Result<Vec<Tensor>> {
+    let coef = (dot(grad, other)?.minimum(0f64)? / (dot(other, other)? + 1e-12)?)?;
+    grad.iter()
+        .zip(other.iter())
+        .map(|(g, o)| {
+            let coef = coef.to_device(g.device())?.to_dtype(g.dtype())?;
+            g - o.broadcast_mul(&coef)?
+        })
+        .collect()
+}
+
+/// Removes in place the component of the gradients of `vars` that conflicts with the
+/// `reference` gradients, i.e. when the inner product of both is negative the gradients are
+/// projected on the normal plane of the reference. This is used for example to keep the updates
+/// on a new task from increasing the loss on a reference task. The variables without a gradient
+/// in `grads` are left untouched.
+pub fn project_conflicting(
+    vars: &[Var],
+    grads: &mut GradStore,
+    reference: &GradStore,
+) -> Result<()> {
+    let vars = vars
+        .iter()
+        .filter(|var| grads.get(var).is_some())
+        .collect::<Vec<_>>();
+    let grad = vars
+        .iter()
+        .map(|var| grads.get(var).unwrap().clone())
+        .collect::<Vec<_>>();
+    let other = vars
+        .iter()
+        .map(|var| match reference.get(var) {
+            Some(g) => Ok(g.clone()),
+            None => var.zeros_like(),
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let projected = project(&grad, &other)?;
+    for (var, g) in vars.into_iter().zip(projected) {
+        grads.insert(var, g);
+    }
+    Ok(())
+}
+
+// The xorshift64* generator.
+fn next_u64(state: &mut u64) -> u64 {
+    *state ^= *state >> 12;
+    *state ^= *state << 25;
+    *state ^= *state >> 27;
+    state.wrapping_mul(0x2545f4914f6cdd1d)
+}
+
+/// Projecting Conflicting Gradients.
+///
+/// The gradient of each task is successively projected against the original gradients of the
+/// other tasks, in a random order, and the projected gradients are summed.
+#[derive(Debug, Clone)]
+pub struct PCGrad {
+    rng: u64,
+}
+
+impl PCGrad {
+    pub fn new(seed: u64) -> Self {
+        // The xorshift state must not be zero.
+        Self { rng: seed | 1 }
+    }
+
+    fn shuffle(&mut self, xs: &mut [usize]) {
+        for i in (1..xs.len()).rev() {
+            let j = (next_u64(&mut self.rng) % (i as u64 + 1)) as usize;
+            xs.swap(i, j)
+        }
+    }
+}
+
+impl GradCombiner for PCGrad {
+    fn combine(
+        &mut self,
+        vars: &[Var],
+        mut task_grads: Vec<GradStore>,
+        _losses: &[f64],
+    ) -> Result<GradStore> {
+        let (vars, grads) = collect_grads(vars, &task_grads)?;
+        let mut combined: Option<Vec<Tensor>> = None;
+        for (i, grad) in grads.iter().enumerate() {
+            let mut order = (0..grads.len()).filter(|&j| j != i).collect::<Vec<_>>();
+            self.shuffle(&mut order);
+            let mut grad = grad.clone();
+            for j in order {
+                grad = project(&grad, &grads[j])?
+            }
+            combined = Some(match combined {
+                None => grad,
+                Some(acc) => acc
+                    .iter()
+                    .zip(grad.iter())
+                    .map(|(a, g)| a + g)
+                    .collect::<Result<Vec<_>>>()?,
+            })
+        }
+        let mut out = match task_grads.pop() {
+            None => candle::bail!("no task gradients to combine"),
+            Some(out) => out,
+        };
+        if let Some(combined) = combined {
+            for (var, g) in vars.iter().zip(combined) {
+                out.insert(var, g);
+            }
+        }
+        Ok(out)
+    }
+}
+
+/// GradNorm, adaptive task weighting.
+///
+/// The combined gradient is the weighted sum of the task gradients. After each step the weights
+/// are updated so that the norm of the weighted gradient of each task on the `shared` variables,
+/// typically the last shared layer, moves towards the average norm scaled by the relative inverse
+/// training rate of the task to the power `alpha`. Tasks whose loss decreased less than the
+/// others since the first step get larger weights. The weights are kept positive and summing to
+/// the number of tasks.
+#[derive(Debug, Clone)]
+pub struct GradNorm {
+    shared: Vec<Var>,
+    weights: Vec<f64>,
+    initial_losses: Option<Vec<f64>>,
+    alpha: f64,
+    lr: f64,
+}
+
+impl GradNorm {
+    /// Creates the combiner with all the weights set to one. `alpha` controls how strongly the
+    /// training rates are balanced, 1.5 is used in the paper, and `lr` is the learning rate of the
+    /// weights.
+    pub fn new(num_tasks: usize, shared: Vec<Var>, alpha: f64, lr: f64) -> Result<Self> {
+        if num_tasks == 0 {
+            candle::bail!("GradNorm requires at least one task")
+        }
+        if shared.is_empty() {
+            candle::bail!("GradNorm requires some shared variables")
+        }
+        Ok(Self {
+            shared,
+            weights: vec![1.; num_tasks],
+            initial_losses: None,
+            alpha,
+            lr,
+        })
+    }
+
+    /// The current task weights.
+    pub fn weights(&self) -> &[f64] {
+        &self.weights
+    }
+
+    /// The task losses recorded on the first step, used to measure the training rates.
+    pub fn initial_losses(&self) -> Option<&[f64]> {
+        self.initial_losses.as_deref()
+    }
+
+    /// Resets the weights to one and forgets the initial losses.
+    pub fn reset(&mut self) {
+        self.weights.iter_mut().for_each(|w| *w = 1.);
+        self.initial_losses = None
+    }
+
+    fn update_weights(&mut self, norms: &[f64], losses: &[f64]) {
+        let n = self.weights.len() as f64;
+        let initial = self.initial_losses.get_or_insert_with(|| losses.to_vec());
+        let rates = losses
+            .iter()
+            .zip(initial.iter())
+            .map(|(l, l0)| if *l0 > 0. { l / l0 } else { 1. })
+            .collect::<Vec<_>>();
+        let mean_rate = rates.iter().sum::<f64>() / n;
+        let wnorms = norms
+            .iter()
+            .zip(self.weights.iter())
+            .map(|(norm, w)| w * norm)
+            .collect::<Vec<_>>();
+        let mean_norm = wnorms.iter().sum::<f64>() / n;
+        for (i, w) in self.weights.iter_mut().enumerate() {
+            let rate = if mean_rate > 0. {
+                rates[i] / mean_rate
+            } else {
+                1.
+            };
+            let target = mean_norm * rate.powf(self.alpha);
+            // The gradient of |w_i * norm_i - target_i| with respect to w_i, the target being
+            // treated as a constant.
+            let grad = (wnorms[i] - target).signum() * norms[i];
+            *w = (*w - self.lr * grad).max(1e-6)
+        }
+        let scale = n / self.weights.iter().sum::<f64>();
+        self.weights.iter_mut().for_each(|w| *w *= scale)
+    }
+}
+
+impl GradCombiner for GradNorm {
+    fn combine(
+        &mut self,
+        vars: &[Var],
+        mut task_grads: Vec<GradStore>,
+        losses: &[f64],
+    ) -> Result<GradStore> {
+        let num_tasks = self.weights.len();
+        if task_grads.len() != num_tasks || losses.len() != num_tasks {
+            candle::bail!(
+                "GradNorm expects {num_tasks} tasks, got {} gradients and {} losses",
+                task_grads.len(),
+                losses.len()
+            )
+        }
+        let shared_norms = task_grads
+            .iter()
+            .map(|grads| {
+                let grad = self
+                    .shared
+                    .iter()
+                    .filter_map(|var| grads.get(var).cloned())
+                    .collect::<Vec<_>>();
+                dot(&grad, &grad)?.sqrt()
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let norms = fetch_scalars(&shared_norms)?;
+        let (vars, grads) = collect_grads(vars, &task_grads)?;
+        let mut out = task_grads.pop().unwrap();
+        for (v, var) in vars.iter().enumerate() {
+            let mut acc: Option<Tensor> = None;
+            for (grad, w) in grads.iter().zip(self.weights.iter()) {
+                let g = (&grad[v] * *w)?;
+                acc = Some(match acc {
+                    None => g,
+                    Some(acc) => (acc + g)?,
+                })
+            }
+            if let Some(acc) = acc {
+                out.insert(var, acc);
+            }
+        }
+        self.update_weights(&norms, losses);
+        Ok(out)
+    }
+}
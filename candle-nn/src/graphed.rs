@@ -0,0 +1,153 @@
+//! Replaying a decode step as a CUDA graph.
+//!
+//! Decoding a single token launches hundreds of small kernels and at small batch sizes the
+//! cpu overhead of launching them dominates. A `GraphedModule` captures the kernels launched by
+//! one step into a CUDA graph on the first calls, then replays the whole graph at once for the
+//! following steps after copying the new inputs in the buffers used during the capture.
+//!
+//! ```ignore
+//! let mut step = GraphedModule::new(model);
+//! for _ in 0..max_tokens {
+//!     let logits = step.run(&[&input_ids, &positions], |model, xs| model.step(&xs[0], &xs[1]))?;
+//!     // ... sample and update input_ids and positions ...
+//! }
+//! ```
+//!
+//! Only the kernels are replayed so the step has to be a function of its input tensors and of
+//! buffers living on the device: the host side values used while capturing, e.g. the offset of
+//! a growing kv cache, are frozen. This requires a kv cache of a fixed size indexed by position
+//! tensors such as [`crate::kv_cache::StaticKvCache`], and the step must not copy data to or
+//! from the host. The device has to be created with `Device::new_cuda_with_stream` as the
+//! default stream cannot be captured. Other devices than cuda run the step eagerly.
+use candle::{Device, Module, Result, Shape, Tensor};
+
+struct Captured {
+    // The buffers used as inputs while capturing, updated in place before each replay.
+    inputs: Vec<Tensor>,
+    output: Tensor,
+    graph: candle::CudaGraph,
+}
+
+/// Runs a module step, capturing it as a CUDA graph and replaying it, see the module
+/// documentation.
+pub struct GraphedModule<M> {
+    module: M,
+    // The input shapes of the last eager run, the capture happens on the second call with the
+    // same shapes so that the kernels are loaded and the buffers warmed up beforehand.
+    warmed_up: Option<Vec<Shape>>,
+    captured: Option<Captured>,
+    replays: usize,
+}
+
+impl<M> GraphedModule<M> {
+    pub fn new(module: M) -> Self {
+        Self {
+            module,
+            warmed_up: None,
+            captured: None,
+            replays: 0,
+        }
+    }
+
+    pub fn module(&self) -> &M {
+        &self.module
+    }
+
+    /// Mutable access to the module, this drops the captured graph as it may reference buffers
+    /// of the module that get replaced.
+    pub fn module_mut(&mut self) -> &mut M {
+        self.reset();
+        &mut self.module
+    }
+
+    pub fn into_inner(self) -> M {
+        self.module
+    }
+
+    pub fn is_captured(&self) -> bool {
+        self.captured.is_some()
+    }
+
+    /// The number of steps that have been run by replaying the graph.
+    pub fn replays(&self) -> usize {
+        self.replays
+    }
+
+    /// Drops the captured graph, the next steps are warmed up and captured again.
+    pub fn reset(&mut self) {
+        self.captured = None;
+        self.warmed_up = None;
+    }
+
+    /// Runs one step on `inputs`. On cuda devices, the first call runs `f` eagerly, the second
+    /// one captures it, and the following ones replay the graph without calling `f`. A change
+    /// in the input shapes triggers a new capture.
+    ///
+    /// The returned tensor is a copy of the graph output so it is not modified by the next
+    /// replays.
+    pub fn run<F>(&mut self, inputs: &[&Tensor], f: F) -> Result<Tensor>
+    where
+        F: FnOnce(&mut M, &[Tensor]) -> Result<Tensor>,
+    {
+        let device = match inputs.first() {
+            None => candle::bail!("graphed modules require at least one input"),
+            Some(x) => x.device().clone(),
+        };
+        let cuda_device = match &device {
+            Device::Cuda(cuda_device) => cuda_device.clone(),
+            _ => {
+                let inputs = inputs.iter().map(|&x| x.clone()).collect::<Vec<_>>();
+                return f(&mut self.module, &inputs);
+            }
+        };
+        // The inputs are updated with `slice_set` which does not apply to scalars.
+        if inputs.iter().any(|x| x.rank() == 0) {
+            candle::bail!("graphed modules do not support scalar inputs")
+        }
+        let shapes = inputs.iter().map(|x| x.shape().clone()).collect::<Vec<_>>();
+        if let Some(captured) = &self.captured {
+            let same_inputs = captured.inputs.iter().zip(inputs.iter()).all(|(c, x)| {
+                c.shape() == x.shape() && c.dtype() == x.dtype() && x.device().same_device(&device)
+            });
+            if same_inputs && captured.inputs.len() == inputs.len() {
+                for (c, x) in captured.inputs.iter().zip(inputs.iter()) {
+                    c.slice_set(&x.contiguous()?, 0, 0)?
+                }
+                captured.graph.launch()?;
+                self.replays += 1;
+                return captured.output.copy();
+            }
+            self.captured = None
+        }
+        if self.warmed_up.as_ref() != Some(&shapes) {
+            let inputs = inputs.iter().map(|&x| x.clone()).collect::<Vec<_>>();
+            let output = f(&mut self.module, &inputs)?;
+            self.warmed_up = Some(shapes);
+            return Ok(output);
+        }
+
+        let static_inputs = inputs
+            .iter()
+            .map(|x| x.copy())
+            .collect::<Result<Vec<_>>>()?;
+        device.synchronize()?;
+        let module = &mut self.module;
+        let (graph, output) = cuda_device.capture_graph(|| f(module, &static_inputs))?;
+        // Capturing does not run the kernels, the first launch computes the output.
+        graph.launch()?;
+        let captured = Captured {
+            inputs: static_inputs,
+            output,
+            graph,
+        };
+        let output = captured.output.copy();
+        self.captured = Some(captured);
+        output
+    }
+}
+
+impl<M: Module> GraphedModule<M> {
+    pub fn forward(&mut self, xs: &Tensor) -> Result<Tensor> {
+        self.run(&[xs], |m, xs| m.forward(&xs[0]))
+    }
+}
@@ -145,3 +145,358 @@ impl KvCache {
         self.v.reset();
     }
 }
+
+/// A kv cache of a fixed size where the keys and values are written at the positions given by a
+/// tensor rather than at a host side offset.
+///
+/// The cached tensors always hold `max_seq_len` slots along `dim` and are updated in place, so
+/// a decoding step using this cache launches the same kernels on the same buffers for every
+/// token and can be captured as a CUDA graph, see [`crate::GraphedModule`]. The slots that have
+/// not been written yet are zeros and are hidden by the attention mask returned by `mask`, so
+/// restarting a sequence at position zero does not require clearing the cache.
+#[derive(Debug, Clone)]
+pub struct StaticKvCache {
+    k: Option<Tensor>,
+    v: Option<Tensor>,
+    // The u32 indexes of the slots, created on first use to avoid host to device copies in the
+    // following steps.
+    slots: Option<Tensor>,
+    dim: usize,
+    max_seq_len: usize,
+}
+
+impl StaticKvCache {
+    pub fn new(dim: usize, max_seq_len: usize) -> Self {
+        Self {
+            k: None,
+            v: None,
+            slots: None,
+            dim,
+            max_seq_len,
+        }
+    }
+
+    pub fn dim(&self) -> usize {
+        self.dim
+    }
+
+    pub fn max_seq_len(&self) -> usize {
+        self.max_seq_len
+    }
+
+    pub fn k(&self) -> Option<&Tensor> {
+        self.k.as_ref()
+    }
+
+    pub fn v(&self) -> Option<&Tensor> {
+        self.v.as_ref()
+    }
+
+    fn slots(&mut self, device: &candle::Device) -> Result<Tensor> {
+        match &self.slots {
+            Some(slots) if slots.device().same_device(device) => Ok(slots.clone()),
+            _ => {
+                let slots = Tensor::arange(0u32, self.max_seq_len as u32, device)?;
+                self.slots = Some(slots.clone());
+                Ok(slots)
+            }
+        }
+    }
+
+    /// Writes `k` and `v` at the positions in the u32 tensor `positions`, one per element along
+    /// `dim`, and returns all the cached keys and values. The positions are shared by the other
+    /// dimensions, e.g. by all the sequences of a batch.
+    pub fn append(
+        &mut self,
+        k: &Tensor,
+        v: &Tensor,
+        positions: &Tensor,
+    ) -> Result<(Tensor, Tensor)> {
+        let seq_len = positions.dims1()?;
+        if k.dim(self.dim)? != seq_len || v.dim(self.dim)? != seq_len {
+            candle::bail!(
+                "static kv-cache: {seq_len} positions for keys {:?} and values {:?}",
+                k.shape(),
+                v.shape()
+            )
+        }
+        // onehot[s, i] is set when the element i is written to the slot s.
+        let slots = self.slots(k.device())?;
+        let onehot = slots.unsqueeze(1)?.broadcast_eq(&positions.unsqueeze(0)?)?;
+        let written = onehot.max_keepdim(1)?;
+        let (dim, max_seq_len) = (self.dim, self.max_seq_len);
+        let k = write_slots(&mut self.k, k, &onehot, &written, dim, max_seq_len)?;
+        let v = write_slots(&mut self.v, v, &onehot, &written, dim, max_seq_len)?;
+        Ok((k, v))
+    }
+
+    /// The attention mask for queries at `positions`, a u8 tensor of shape
+    /// `(seq_len, max_seq_len)` set to one for the slots after the position of each query.
+    pub fn mask(&mut self, positions: &Tensor) -> Result<Tensor> {
+        let slots = self.slots(positions.device())?;
+        slots.unsqueeze(0)?.broadcast_gt(&positions.unsqueeze(1)?)
+    }
+}
+
+// Writes the elements of `src` along `dim` to the slots selected by `onehot` in place and returns
+// the whole cache.
+fn write_slots(
+    cache: &mut Option<Tensor>,
+    src: &Tensor,
+    onehot: &Tensor,
+    written: &Tensor,
+    dim: usize,
+    max_seq_len: usize,
+) -> Result<Tensor> {
+    if cache.is_none() {
+        let mut shape = src.dims().to_vec();
+        shape[dim] = max_seq_len;
+        *cache = Some(Tensor::zeros(shape, src.dtype(), src.device())?)
+    }
+    let cache = cache.as_ref().unwrap();
+    // The cached dimension is moved first so that the scatter is a matmul with the one-hot
+    // matrix.
+    let src = src.transpose(0, dim)?.contiguous()?;
+    let src = src.reshape((src.dim(0)?, ()))?;
+    let scattered = onehot.to_dtype(src.dtype())?.matmul(&src)?;
+    let current = cache.transpose(0, dim)?;
+    let shape = current.shape().clone();
+    let current = current.contiguous()?.reshape((max_seq_len, ()))?;
+    let written = written.broadcast_as(current.shape())?;
+    let updated = written.where_cond(&scattered, &current)?.reshape(shape)?;
+    cache.slice_set(&updated.transpose(0, dim)?.contiguous()?, 0, 0)?;
+    Ok(cache.clone())
+}
+
+/// A policy deciding which tokens to keep in an `EvictingKvCache` when it goes above its
+/// budget.
+pub trait CachePolicy: Send + Sync {
+    /// Records the attention probabilities over the cached tokens, with shape
+    /// `(batch, num_heads, q_len, kv_len)` where `kv_len` is the current length of the cache.
+    fn observe(&mut self, _attn: &Tensor) -> Result<()> {
+        Ok(())
+    }
+
+    /// Returns the indexes of the tokens to keep in a cache holding `seq_len` tokens, as a u32
+    /// tensor of shape `(batch, num_heads, n)` with the indexes in increasing order, or `None`
+    /// when there is nothing to evict.
+    fn select(
+        &mut self,
+        batch: usize,
+        num_heads: usize,
+        seq_len: usize,
+        device: &candle::Device,
+    ) -> Result<Option<Tensor>>;
+
+    fn reset(&mut self) {}
+}
+
+/// Keeps the first `sink` tokens and the `window` most recent ones, as in "Efficient Streaming
+/// Language Models with Attention Sinks" <https://arxiv.org/abs/2309.17453>.
+#[derive(Debug, Clone, Copy)]
+pub struct SlidingWindow {
+    sink: usize,
+    window: usize,
+}
+
+impl SlidingWindow {
+    pub fn new(sink: usize, window: usize) -> Self {
+        Self { sink, window }
+    }
+}
+
+impl CachePolicy for SlidingWindow {
+    fn select(
+        &mut self,
+        batch: usize,
+        num_heads: usize,
+        seq_len: usize,
+        device: &candle::Device,
+    ) -> Result<Option<Tensor>> {
+        if seq_len <= self.sink + self.window {
+            return Ok(None);
+        }
+        let keep = (0..self.sink as u32)
+            .chain((seq_len - self.window) as u32..seq_len as u32)
+            .collect::<Vec<_>>();
+        let n = keep.len();
+        let keep = Tensor::from_vec(keep, (1, 1, n), device)?;
+        Ok(Some(
+            keep.broadcast_as((batch, num_heads, n))?.contiguous()?,
+        ))
+    }
+}
+
+/// The heavy hitter oracle (H2O) from "H2O: Heavy-Hitter Oracle for Efficient Generative
+/// Inference of Large Language Models" <https://arxiv.org/abs/2306.14048>.
+///
+/// The score of each cached token is the attention it received, accumulated over the decoding
+/// steps. Above the budget, each head keeps the `recent` most recent tokens and the `heavy`
+/// tokens with the highest scores among the others.
+#[derive(Debug, Clone)]
+pub struct HeavyHitter {
+    heavy: usize,
+    recent: usize,
+    // The accumulated attention, with shape (batch, num_heads, seq_len).
+    scores: Option<Tensor>,
+}
+
+impl HeavyHitter {
+    pub fn new(heavy: usize, recent: usize) -> Self {
+        Self {
+            heavy,
+            recent,
+            scores: None,
+        }
+    }
+
+    pub fn budget(&self) -> usize {
+        self.heavy + self.recent
+    }
+
+    pub fn scores(&self) -> Option<&Tensor> {
+        self.scores.as_ref()
+    }
+}
+
+impl CachePolicy for HeavyHitter {
+    fn observe(&mut self, attn: &Tensor) -> Result<()> {
+        let attn = attn.to_dtype(candle::DType::F32)?.sum(2)?;
+        let kv_len = attn.dim(2)?;
+        let scores = match &self.scores {
+            None => attn,
+            Some(scores) => {
+                // The tokens added since the last step start with a zero score.
+                let scores = scores.pad_with_zeros(2, 0, kv_len - scores.dim(2)?)?;
+                (scores + attn)?
+            }
+        };
+        self.scores = Some(scores);
+        Ok(())
+    }
+
+    fn select(
+        &mut self,
+        batch: usize,
+        num_heads: usize,
+        seq_len: usize,
+        device: &candle::Device,
+    ) -> Result<Option<Tensor>> {
+        if seq_len <= self.budget() {
+            return Ok(None);
+        }
+        let scores = match &self.scores {
+            Some(scores) if scores.dim(2)? == seq_len => scores.clone(),
+            _ => {
+                candle::bail!("heavy-hitter: the attention of the last step has not been observed")
+            }
+        };
+        // The recent tokens are always kept, ranking them first.
+        let n_old = seq_len - self.recent.min(seq_len);
+        let recent = Tensor::full(f32::INFINITY, (batch, num_heads, seq_len - n_old), device)?;
+        let ranked = Tensor::cat(&[&scores.narrow(2, 0, n_old)?, &recent], 2)?.contiguous()?;
+        let keep = ranked
+            .arg_sort_last_dim(false)?
+            .narrow(2, 0, self.budget())?
+            .contiguous()?;
+        let (keep, _) = keep.sort_last_dim(true)?;
+        self.scores = Some(scores.contiguous()?.gather(&keep, 2)?);
+        Ok(Some(keep))
+    }
+
+    fn reset(&mut self) {
+        self.scores = None
+    }
+}
+
+/// A kv cache with a bounded number of tokens, evicting tokens according to a `CachePolicy`.
+///
+/// The keys and values have shape `(batch, num_heads, seq_len, head_dim)`. The attention
+/// probabilities computed with the keys returned by `append` have to be passed to `observe`,
+/// which then evicts the tokens that the policy does not keep. As the evicted tokens depend on
+/// the head, the positional information has to be applied before caching, e.g. with rotary
+/// embeddings.
+pub struct EvictingKvCache {
+    k: Option<Tensor>,
+    v: Option<Tensor>,
+    policy: Box<dyn CachePolicy>,
+}
+
+impl std::fmt::Debug for EvictingKvCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EvictingKvCache")
+            .field("seq_len", &self.current_seq_len())
+            .finish()
+    }
+}
+
+impl EvictingKvCache {
+    pub fn new<P: CachePolicy + 'static>(policy: P) -> Self {
+        Self {
+            k: None,
+            v: None,
+            policy: Box::new(policy),
+        }
+    }
+
+    pub fn k(&self) -> Option<&Tensor> {
+        self.k.as_ref()
+    }
+
+    pub fn v(&self) -> Option<&Tensor> {
+        self.v.as_ref()
+    }
+
+    pub fn current_seq_len(&self) -> usize {
+        self.k.as_ref().map_or(0, |k| k.dims()[2])
+    }
+
+    /// Appends new keys and values and returns all the cached ones.
+    pub fn append(&mut self, k: &Tensor, v: &Tensor) -> Result<(Tensor, Tensor)> {
+        let (k, v) = (k.contiguous()?, v.contiguous()?);
+        let (k, v) = match (&self.k, &self.v) {
+            (Some(pk), Some(pv)) => (Tensor::cat(&[pk, &k], 2)?, Tensor::cat(&[pv, &v], 2)?),
+            _ => (k, v),
+        };
+        self.k = Some(k.clone());
+        self.v = Some(v.clone());
+        Ok((k, v))
+    }
+
+    /// Records the attention probabilities for the keys returned by the last call to `append`
+    /// and evicts tokens if needed, returns the number of evicted tokens.
+    pub fn observe(&mut self, attn: &Tensor) -> Result<usize> {
+        let (k, v) = match (&self.k, &self.v) {
+            (Some(k), Some(v)) => (k, v),
+            _ => candle::bail!("evicting kv-cache: observe called on an empty cache"),
+        };
+        let (b, h, seq_len, d) = k.dims4()?;
+        if attn.dim(3)? != seq_len {
+            candle::bail!(
+                "evicting kv-cache: attention over {} tokens, {seq_len} in cache",
+                attn.dim(3)?
+            )
+        }
+        self.policy.observe(attn)?;
+        let keep = match self.policy.select(b, h, seq_len, k.device())? {
+            None => return Ok(0),
+            Some(keep) => keep,
+        };
+        let n = keep.dim(2)?;
+        let index = keep
+            .unsqueeze(3)?
+            .broadcast_as((b, h, n, d))?
+            .contiguous()?;
+        let k = k.gather(&index, 2)?;
+        let v = v.gather(&index, 2)?;
+        self.k = Some(k);
+        self.v = Some(v);
+        Ok(seq_len - n)
+    }
+
+    pub fn reset(&mut self) {
+        self.k = None;
+        self.v = None;
+        self.policy.reset();
+    }
+}
@@ -28,7 +28,7 @@
 //! ```
 //!
 //! [`Layer Normalization`]: https://arxiv.org/abs/1607.06450
-use candle::{DType, Module, Result, Tensor, D};
+use candle::{DType, Module, Result, ResultExt, Tensor, D};
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct LayerNormConfig {
@@ -66,6 +66,7 @@ pub struct LayerNorm {
     bias: Option<Tensor>,
     remove_mean: bool,
     eps: f64,
+    prefix: Option<String>,
 }
 
 impl LayerNorm {
@@ -75,6 +76,7 @@ impl LayerNorm {
             bias: Some(bias),
             remove_mean: true,
             eps,
+            prefix: None,
         }
     }
 
@@ -84,6 +86,7 @@ impl LayerNorm {
             bias: None,
             remove_mean: true,
             eps,
+            prefix: None,
         }
     }
 
@@ -93,6 +96,7 @@ impl LayerNorm {
             bias: None,
             remove_mean: false,
             eps,
+            prefix: None,
         }
     }
 
@@ -103,10 +107,26 @@ impl LayerNorm {
     pub fn bias(&self) -> Option<&Tensor> {
         self.bias.as_ref()
     }
+
+    /// Sets the path of the layer reported in errors, this is the `VarBuilder` prefix for the
+    /// layers created with [`layer_norm`] or [`rms_norm`].
+    pub fn with_prefix(self, prefix: impl Into<String>) -> Self {
+        Self {
+            prefix: Some(prefix.into()),
+            ..self
+        }
+    }
 }
 
 impl Module for LayerNorm {
     fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        self.forward_(x)
+            .module_op_context(self.prefix.as_deref(), "layer-norm", &[x, &self.weight])
+    }
+}
+
+impl LayerNorm {
+    fn forward_(&self, x: &Tensor) -> Result<Tensor> {
         if x.is_contiguous() && self.remove_mean {
             if let Some(bias) = self.bias.as_ref() {
                 return crate::ops::layer_norm(x, &self.weight, bias, self.eps as f32);
@@ -152,6 +172,7 @@ pub fn layer_norm<C: Into<LayerNormConfig>>(
         bias,
         remove_mean: config.remove_mean,
         eps: config.eps,
+        prefix: Some(vb.prefix()),
     })
 }
 
@@ -168,6 +189,11 @@ impl RmsNorm {
         self.0
     }
 
+    /// Sets the path of the layer reported in errors, see [`LayerNorm::with_prefix`].
+    pub fn with_prefix(self, prefix: impl Into<String>) -> Self {
+        Self(self.0.with_prefix(prefix))
+    }
+
     /// Faster variant of the forward kernel, this can only be used on contiguous tensors though.
     pub fn forward_diff(&self, xs: &Tensor) -> Result<Tensor> {
         self.0.forward(xs)
@@ -177,7 +203,11 @@ impl RmsNorm {
 impl Module for RmsNorm {
     fn forward(&self, xs: &Tensor) -> Result<Tensor> {
         if xs.is_contiguous() {
-            crate::ops::rms_norm(xs, &self.0.weight, self.0.eps as f32)
+            crate::ops::rms_norm(xs, &self.0.weight, self.0.eps as f32).module_op_context(
+                self.0.prefix.as_deref(),
+                "rms-norm",
+                &[xs, &self.0.weight],
+            )
         } else {
             self.0.forward(xs)
         }
@@ -1,40 +1,71 @@
 pub mod activation;
+pub mod activation_stats;
+pub mod alibi;
+pub mod attention_mask;
 pub mod batch_norm;
 pub mod conv;
+pub mod ddp;
+pub mod delta;
+pub mod detection;
+pub mod diagnostics;
+pub mod ema;
 pub mod embedding;
 pub mod encoding;
 pub mod func;
+pub mod grad_surgery;
+pub mod graphed;
 pub mod group_norm;
 pub mod init;
 pub mod kv_cache;
 pub mod layer_norm;
 pub mod linear;
+pub mod lora;
 pub mod loss;
+pub mod metrics;
 pub mod ops;
 pub mod optim;
+pub mod peft;
+pub mod pipeline;
+pub mod pruning;
 pub mod rnn;
 pub mod rotary_emb;
 pub mod sequential;
+pub mod session;
+pub mod sparse24;
+pub mod tensor_parallel;
+pub mod trainer;
 pub mod var_builder;
 pub mod var_map;
 
 pub use activation::{prelu, Activation, PReLU};
 pub use batch_norm::{batch_norm, BatchNorm, BatchNormConfig};
 pub use conv::{
-    conv1d, conv1d_no_bias, conv2d, conv2d_no_bias, conv_transpose1d, conv_transpose1d_no_bias,
-    conv_transpose2d, conv_transpose2d_no_bias, Conv1d, Conv1dConfig, Conv2d, Conv2dConfig,
-    ConvTranspose1d, ConvTranspose1dConfig, ConvTranspose2d, ConvTranspose2dConfig,
+    conv1d, conv1d_no_bias, conv2d, conv2d_no_bias, conv3d, conv3d_no_bias, conv_transpose1d,
+    conv_transpose1d_no_bias, conv_transpose2d, conv_transpose2d_no_bias, Conv1d, Conv1dConfig,
+    Conv2d, Conv2dConfig, Conv3d, Conv3dConfig, ConvTranspose1d, ConvTranspose1dConfig,
+    ConvTranspose2d, ConvTranspose2dConfig,
 };
+pub use ema::ModelEma;
 pub use embedding::{embedding, Embedding};
 pub use func::{func, func_t, Func, FuncT};
+pub use grad_surgery::{GradCombiner, GradNorm, PCGrad};
+pub use graphed::GraphedModule;
 pub use group_norm::{group_norm, GroupNorm};
 pub use init::Init;
 pub use layer_norm::{layer_norm, rms_norm, LayerNorm, LayerNormConfig, RmsNorm};
 pub use linear::{linear, linear_b, linear_no_bias, Linear};
+pub use lora::{Lora, LoraConfig, LoraConv2d, LoraEmbedding, LoraLinear};
 pub use ops::Dropout;
-pub use optim::{AdamW, Optimizer, ParamsAdamW, SGD};
+pub use optim::{
+    Adafactor, AdamW, Lamb, Lion, Optimizer, ParamGroup, ParamsAdafactor, ParamsAdamW, ParamsLamb,
+    ParamsLion, SGD,
+};
+pub use peft::{Ia3, Ia3Config, Ia3Linear, PrefixTuning, PromptTuning};
+pub use pipeline::{Pipeline, PipelineStage};
 pub use rnn::{gru, lstm, GRUConfig, LSTMConfig, GRU, LSTM, RNN};
 pub use sequential::{seq, Sequential};
+pub use session::InferenceSession;
+pub use trainer::{Trainer, TrainerConfig};
 pub use var_builder::VarBuilder;
 pub use var_map::VarMap;
 
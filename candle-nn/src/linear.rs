@@ -17,17 +17,35 @@
 //! assert_eq!(ys.to_vec2::<f32>()?, &[[210.0, 430.0, 650.0]]);
 //! # Ok(()) }
 //! ```
-use candle::{Result, Tensor};
+use candle::{Result, ResultExt, Tensor};
 
 #[derive(Clone, Debug)]
 pub struct Linear {
     weight: Tensor,
     bias: Option<Tensor>,
+    prefix: Option<String>,
 }
 
 impl Linear {
     pub fn new(weight: Tensor, bias: Option<Tensor>) -> Self {
-        Self { weight, bias }
+        Self {
+            weight,
+            bias,
+            prefix: None,
+        }
+    }
+
+    /// Sets the path of the layer reported in errors, this is the `VarBuilder` prefix for the
+    /// layers created with [`linear`].
+    pub fn with_prefix(self, prefix: impl Into<String>) -> Self {
+        Self {
+            prefix: Some(prefix.into()),
+            ..self
+        }
+    }
+
+    pub fn prefix(&self) -> Option<&str> {
+        self.prefix.as_deref()
     }
 
     pub fn weight(&self) -> &Tensor {
@@ -41,6 +59,13 @@ impl Linear {
 
 impl super::Module for Linear {
     fn forward(&self, x: &Tensor) -> candle::Result<Tensor> {
+        self.forward_(x)
+            .module_op_context(self.prefix.as_deref(), "linear", &[x, &self.weight])
+    }
+}
+
+impl Linear {
+    fn forward_(&self, x: &Tensor) -> candle::Result<Tensor> {
         let w = match *x.dims() {
             [b1, b2, _, _] => self.weight.broadcast_left((b1, b2))?.t()?,
             [bsize, _, _] => self.weight.broadcast_left(bsize)?.t()?,
@@ -66,14 +91,14 @@ pub fn linear(in_dim: usize, out_dim: usize, vb: crate::VarBuilder) -> Result<Li
         up: bound,
     };
     let bs = vb.get_with_hints(out_dim, "bias", init_bs)?;
-    Ok(Linear::new(ws, Some(bs)))
+    Ok(Linear::new(ws, Some(bs)).with_prefix(vb.prefix()))
 }
 
 /// Create or initialize a new linear layer without biases.
 pub fn linear_no_bias(in_dim: usize, out_dim: usize, vb: crate::VarBuilder) -> Result<Linear> {
     let init_ws = crate::init::DEFAULT_KAIMING_NORMAL;
     let ws = vb.get_with_hints((out_dim, in_dim), "weight", init_ws)?;
-    Ok(Linear::new(ws, None))
+    Ok(Linear::new(ws, None).with_prefix(vb.prefix()))
 }
 
 pub fn linear_b(
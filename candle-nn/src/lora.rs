@@ -0,0 +1,607 @@
+//! Low-rank adapters (LoRA) for fine-tuning.
+//!
+//! LoRA freezes the weights of a pre-trained layer and learns a low-rank update `scale * B @ A`
+//! instead, see "LoRA: Low-Rank Adaptation of Large Language Models"
+//! <https://arxiv.org/abs/2106.09685>. When the base weights are quantized this is known as
+//! QLoRA, the adapters are then trained through the quantized matmul.
+//!
+//! The adapter weights use the same names as the PEFT library, e.g.
+//! `base_model.model.model.layers.0.self_attn.q_proj.lora_A.weight`, so adapters can be
+//! exchanged with PyTorch. Saving the `VarMap` used to train the adapters produces a file in this
+//! layout.
+//!
+//! ```ignore
+//! let varmap = VarMap::new();
+//! let lora = Lora::from_varmap(config, &varmap, DType::F32, &device);
+//! // When building the model, wrap the layers that should be adapted.
+//! let q_proj = lora.linear(linear_no_bias(dim, dim, vb.pp("q_proj"))?, &vb.pp("q_proj"))?;
+//! // ... train using the variables from `varmap.all_vars()` ...
+//! varmap.save("adapter_model.safetensors")?;
+//! ```
+use crate::var_builder::{SimpleBackend, VarBuilder};
+use crate::{Conv2d, Conv2dConfig, Embedding, Linear, Module, VarMap};
+use candle::quantized::{QMatMul, QTensor};
+use candle::{DType, Device, Result, Shape, Tensor};
+use std::sync::Arc;
+
+/// The prefix used by PEFT in front of the module names of the adapted model.
+pub const PEFT_PREFIX: &str = "base_model.model";
+
+/// The configuration of a set of adapters, compatible with the PEFT `adapter_config.json` file.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+pub struct LoraConfig {
+    #[serde(rename = "r")]
+    pub rank: usize,
+    #[serde(rename = "lora_alpha")]
+    pub alpha: f64,
+    /// The modules to adapt. A module is adapted if its name is one of the targets or ends with
+    /// `.` followed by one of the targets, e.g. `q_proj` matches `model.layers.0.attn.q_proj`.
+    pub target_modules: Vec<String>,
+}
+
+impl LoraConfig {
+    pub fn new<S: ToString>(rank: usize, alpha: f64, target_modules: &[S]) -> Self {
+        Self {
+            rank,
+            alpha,
+            target_modules: target_modules.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    /// The scale applied to the low-rank update.
+    pub fn scale(&self) -> f64 {
+        self.alpha / self.rank as f64
+    }
+
+    /// Returns whether the module `name` should be adapted.
+    pub fn matches(&self, name: &str) -> bool {
+        matches_target(&self.target_modules, name)
+    }
+}
+
+// Returns whether `name` is one of the targets or ends with `.` followed by one of them.
+pub(crate) fn matches_target(targets: &[String], name: &str) -> bool {
+    targets.iter().any(|target| {
+        name == target
+            || name
+                .strip_suffix(target.as_str())
+                .is_some_and(|prefix| prefix.ends_with('.'))
+    })
+}
+
+// The weight update of an adapter, reshaped to `shape`.
+fn lora_delta(a: &Tensor, b: &Tensor, scale: f64, shape: &Shape) -> Result<Tensor> {
+    let a = a.flatten_from(1)?.to_dtype(DType::F32)?;
+    let b = b.flatten_from(1)?.to_dtype(DType::F32)?;
+    (b.matmul(&a)? * scale)?.reshape(shape)
+}
+
+// Adds `sign * delta` to `weight`, keeping its dtype.
+fn add_delta(weight: &Tensor, delta: &Tensor, sign: f64) -> Result<Tensor> {
+    let delta = (delta.to_device(weight.device())? * sign)?;
+    (weight.to_dtype(DType::F32)? + delta)?.to_dtype(weight.dtype())
+}
+
+#[derive(Debug, Clone)]
+enum LinearBase {
+    Linear(Linear),
+    Quantized {
+        weight: QMatMul,
+        bias: Option<Tensor>,
+    },
+}
+
+/// A linear layer with an optional low-rank adapter.
+#[derive(Debug, Clone)]
+pub struct LoraLinear {
+    base: LinearBase,
+    // The `lora_A` and `lora_B` weights, of shape `(rank, in_dim)` and `(out_dim, rank)`.
+    adapter: Option<(Linear, Linear)>,
+    scale: f64,
+    merged: bool,
+}
+
+impl LoraLinear {
+    /// Wraps `base` without an adapter.
+    pub fn new(base: Linear) -> Self {
+        Self {
+            base: LinearBase::Linear(base),
+            adapter: None,
+            scale: 1.,
+            merged: false,
+        }
+    }
+
+    /// Wraps a linear layer with quantized weights, the weights stay frozen.
+    pub fn new_quantized(weight: QMatMul, bias: Option<Tensor>) -> Self {
+        Self {
+            base: LinearBase::Quantized { weight, bias },
+            adapter: None,
+            scale: 1.,
+            merged: false,
+        }
+    }
+
+    /// Sets the adapter weights, `a` has shape `(rank, in_dim)` and `b` `(out_dim, rank)`.
+    pub fn with_adapter(mut self, a: Tensor, b: Tensor, scale: f64) -> Self {
+        self.adapter = Some((Linear::new(a, None), Linear::new(b, None)));
+        self.scale = scale;
+        self
+    }
+
+    pub fn has_adapter(&self) -> bool {
+        self.adapter.is_some()
+    }
+
+    pub fn is_merged(&self) -> bool {
+        self.merged
+    }
+
+    /// The weight update `scale * B @ A` of the adapter if any.
+    pub fn delta(&self) -> Result<Option<Tensor>> {
+        match &self.adapter {
+            None => Ok(None),
+            Some((a, b)) => {
+                let (out_dim, _) = b.weight().dims2()?;
+                let (_, in_dim) = a.weight().dims2()?;
+                let delta = lora_delta(
+                    a.weight(),
+                    b.weight(),
+                    self.scale,
+                    &(out_dim, in_dim).into(),
+                )?;
+                Ok(Some(delta))
+            }
+        }
+    }
+
+    // Adds `sign * delta` to the base weights.
+    fn patch(&mut self, sign: f64) -> Result<()> {
+        let delta = match self.delta()? {
+            None => return Ok(()),
+            Some(delta) => delta,
+        };
+        match &self.base {
+            LinearBase::Linear(l) => {
+                let weight = add_delta(l.weight(), &delta, sign)?;
+                self.base = LinearBase::Linear(Linear::new(weight, l.bias().cloned()));
+                Ok(())
+            }
+            LinearBase::Quantized { .. } => {
+                candle::bail!("lora adapters cannot be merged into quantized weights")
+            }
+        }
+    }
+
+    /// Adds the adapter update to the base weights so that the forward pass does not have to
+    /// compute it separately. This is not supported for quantized weights.
+    pub fn merge(&mut self) -> Result<()> {
+        if !self.merged {
+            self.patch(1.)?;
+            self.merged = true;
+        }
+        Ok(())
+    }
+
+    /// Removes the adapter update from the base weights after a `merge`.
+    pub fn unmerge(&mut self) -> Result<()> {
+        if self.merged {
+            self.patch(-1.)?;
+            self.merged = false;
+        }
+        Ok(())
+    }
+
+    /// The base layer, this includes the adapter update when merged.
+    pub fn base(&self) -> Option<&Linear> {
+        match &self.base {
+            LinearBase::Linear(l) => Some(l),
+            LinearBase::Quantized { .. } => None,
+        }
+    }
+}
+
+impl Module for LoraLinear {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let ys = match &self.base {
+            LinearBase::Linear(l) => l.forward(xs)?,
+            LinearBase::Quantized { weight, bias } => {
+                let ys = weight.forward(xs)?;
+                match bias {
+                    None => ys,
+                    Some(bias) => ys.broadcast_add(bias)?,
+                }
+            }
+        };
+        match &self.adapter {
+            Some((a, b)) if !self.merged => ys + (xs.apply(a)?.apply(b)? * self.scale)?,
+            _ => Ok(ys),
+        }
+    }
+}
+
+/// A 2d convolution with an optional low-rank adapter. The `lora_A` weights are a convolution
+/// using the same configuration as the base layer and `lora_B` a 1x1 convolution.
+#[derive(Debug, Clone)]
+pub struct LoraConv2d {
+    base: Conv2d,
+    adapter: Option<(Conv2d, Conv2d)>,
+    scale: f64,
+    merged: bool,
+}
+
+impl LoraConv2d {
+    pub fn new(base: Conv2d) -> Self {
+        Self {
+            base,
+            adapter: None,
+            scale: 1.,
+            merged: false,
+        }
+    }
+
+    /// Sets the adapter weights, `a` has shape `(rank, in_c, k, k)` and `b` `(out_c, rank, 1, 1)`.
+    pub fn with_adapter(mut self, a: Tensor, b: Tensor, scale: f64) -> Result<Self> {
+        let config = *self.base.config();
+        if config.groups != 1 {
+            candle::bail!("lora adapters are not supported for grouped convolutions")
+        }
+        let a = Conv2d::new(a, None, config);
+        let b = Conv2d::new(b, None, Conv2dConfig::default());
+        self.adapter = Some((a, b));
+        self.scale = scale;
+        Ok(self)
+    }
+
+    pub fn has_adapter(&self) -> bool {
+        self.adapter.is_some()
+    }
+
+    pub fn is_merged(&self) -> bool {
+        self.merged
+    }
+
+    pub fn delta(&self) -> Result<Option<Tensor>> {
+        match &self.adapter {
+            None => Ok(None),
+            Some((a, b)) => {
+                let shape = self.base.weight().shape();
+                Ok(Some(lora_delta(a.weight(), b.weight(), self.scale, shape)?))
+            }
+        }
+    }
+
+    fn patch(&mut self, sign: f64) -> Result<()> {
+        if let Some(delta) = self.delta()? {
+            let weight = add_delta(self.base.weight(), &delta, sign)?;
+            let bias = self.base.bias().cloned();
+            self.base = Conv2d::new(weight, bias, *self.base.config());
+        }
+        Ok(())
+    }
+
+    pub fn merge(&mut self) -> Result<()> {
+        if !self.merged {
+            self.patch(1.)?;
+            self.merged = true;
+        }
+        Ok(())
+    }
+
+    pub fn unmerge(&mut self) -> Result<()> {
+        if self.merged {
+            self.patch(-1.)?;
+            self.merged = false;
+        }
+        Ok(())
+    }
+
+    pub fn base(&self) -> &Conv2d {
+        &self.base
+    }
+}
+
+impl Module for LoraConv2d {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let ys = self.base.forward(xs)?;
+        match &self.adapter {
+            Some((a, b)) if !self.merged => ys + (xs.apply(a)?.apply(b)? * self.scale)?,
+            _ => Ok(ys),
+        }
+    }
+}
+
+/// An embedding layer with an optional low-rank adapter. Following PEFT, the adapter weights
+/// are named `lora_embedding_A` with shape `(rank, num_embeddings)` and `lora_embedding_B` with
+/// shape `(hidden_size, rank)`.
+#[derive(Debug, Clone)]
+pub struct LoraEmbedding {
+    base: Embedding,
+    // The `lora_embedding_A` weights and the `lora_embedding_B` projection.
+    adapter: Option<(Tensor, Linear)>,
+    scale: f64,
+    merged: bool,
+}
+
+impl LoraEmbedding {
+    pub fn new(base: Embedding) -> Self {
+        Self {
+            base,
+            adapter: None,
+            scale: 1.,
+            merged: false,
+        }
+    }
+
+    pub fn with_adapter(mut self, a: Tensor, b: Tensor, scale: f64) -> Result<Self> {
+        let (_, num) = a.dims2()?;
+        let (base_num, _) = self.base.embeddings().dims2()?;
+        if num != base_num {
+            candle::bail!("lora embedding adapter for {num} embeddings, expected {base_num}")
+        }
+        self.adapter = Some((a, Linear::new(b, None)));
+        self.scale = scale;
+        Ok(self)
+    }
+
+    pub fn has_adapter(&self) -> bool {
+        self.adapter.is_some()
+    }
+
+    pub fn is_merged(&self) -> bool {
+        self.merged
+    }
+
+    pub fn delta(&self) -> Result<Option<Tensor>> {
+        match &self.adapter {
+            None => Ok(None),
+            Some((a, b)) => {
+                let (dim, num) = (self.base.hidden_size(), a.dim(1)?);
+                let delta = lora_delta(a, b.weight(), self.scale, &(dim, num).into())?;
+                Ok(Some(delta.t()?))
+            }
+        }
+    }
+
+    fn patch(&mut self, sign: f64) -> Result<()> {
+        if let Some(delta) = self.delta()? {
+            let embeddings = add_delta(self.base.embeddings(), &delta, sign)?;
+            self.base = Embedding::new(embeddings, self.base.hidden_size());
+        }
+        Ok(())
+    }
+
+    pub fn merge(&mut self) -> Result<()> {
+        if !self.merged {
+            self.patch(1.)?;
+            self.merged = true;
+        }
+        Ok(())
+    }
+
+    pub fn unmerge(&mut self) -> Result<()> {
+        if self.merged {
+            self.patch(-1.)?;
+            self.merged = false;
+        }
+        Ok(())
+    }
+
+    pub fn base(&self) -> &Embedding {
+        &self.base
+    }
+}
+
+impl Module for LoraEmbedding {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let ys = self.base.forward(xs)?;
+        match &self.adapter {
+            Some((a, b)) if !self.merged => {
+                // The columns of `lora_embedding_A` are the low-rank embeddings.
+                let (rank, _) = a.dims2()?;
+                let mut dims = xs.dims().to_vec();
+                dims.push(rank);
+                let hs = a.index_select(&xs.flatten_all()?, 1)?.t()?.reshape(dims)?;
+                ys + (hs.apply(b)? * self.scale)?
+            }
+            _ => Ok(ys),
+        }
+    }
+}
+
+/// Injects adapters in the layers of a model.
+///
+/// The layers whose name matches the configuration get an adapter whose weights are retrieved
+/// from `vb`, the other layers are returned without adapter. The names are the full paths of the
+/// layers in the model, which is usually the prefix of the `VarBuilder` used to create them.
+/// When `vb` is backed by a `VarMap`, the `lora_A` weights are initialized with a kaiming
+/// distribution and the `lora_B` weights with zeros so that the adapters start as a no-op.
+#[derive(Clone)]
+pub struct Lora<'a> {
+    config: LoraConfig,
+    vb: VarBuilder<'a>,
+}
+
+impl<'a> Lora<'a> {
+    /// Creates the adapters using the weights from `vb`, the names are used without prefix.
+    pub fn new(config: LoraConfig, vb: VarBuilder<'a>) -> Self {
+        Self { config, vb }
+    }
+
+    /// Creates trainable adapters stored in `varmap` using the PEFT names.
+    pub fn from_varmap(config: LoraConfig, varmap: &VarMap, dtype: DType, dev: &Device) -> Self {
+        let vb = VarBuilder::from_varmap(varmap, dtype, dev).pp(PEFT_PREFIX);
+        Self::new(config, vb)
+    }
+
+    /// Loads the adapters from a PEFT safetensors file, e.g. `adapter_model.safetensors`.
+    ///
+    /// # Safety
+    ///
+    /// The unsafe is inherited from [`memmap2::MmapOptions`].
+    pub unsafe fn from_mmaped_safetensors<P: AsRef<std::path::Path>>(
+        config: LoraConfig,
+        paths: &[P],
+        dtype: DType,
+        dev: &Device,
+    ) -> Result<Self> {
+        let vb = VarBuilder::from_mmaped_safetensors(paths, dtype, dev)?.pp(PEFT_PREFIX);
+        Ok(Self::new(config, vb))
+    }
+
+    pub fn config(&self) -> &LoraConfig {
+        &self.config
+    }
+
+    fn adapter_vb(&self, name: &str) -> Option<VarBuilder<'a>> {
+        if self.config.matches(name) {
+            Some(self.vb.pp(name))
+        } else {
+            None
+        }
+    }
+
+    pub fn linear(&self, base: Linear, name: &str) -> Result<LoraLinear> {
+        let (out_dim, in_dim) = base.weight().dims2()?;
+        let layer = LoraLinear::new(base);
+        match self.adapter_vb(name) {
+            None => Ok(layer),
+            Some(vb) => {
+                let (a, b) = self.linear_adapter(in_dim, out_dim, vb)?;
+                Ok(layer.with_adapter(a, b, self.config.scale()))
+            }
+        }
+    }
+
+    /// Wraps a linear layer with quantized weights of shape `(out_dim, in_dim)` (QLoRA).
+    pub fn quantized_linear(
+        &self,
+        weight: Arc<QTensor>,
+        bias: Option<Tensor>,
+        name: &str,
+    ) -> Result<LoraLinear> {
+        let (out_dim, in_dim) = weight.shape().dims2()?;
+        let layer = LoraLinear::new_quantized(QMatMul::from_arc(weight)?, bias);
+        match self.adapter_vb(name) {
+            None => Ok(layer),
+            Some(vb) => {
+                let (a, b) = self.linear_adapter(in_dim, out_dim, vb)?;
+                Ok(layer.with_adapter(a, b, self.config.scale()))
+            }
+        }
+    }
+
+    fn linear_adapter(
+        &self,
+        in_dim: usize,
+        out_dim: usize,
+        vb: VarBuilder,
+    ) -> Result<(Tensor, Tensor)> {
+        let rank = self.config.rank;
+        let init_a = crate::init::DEFAULT_KAIMING_NORMAL;
+        let a = vb.get_with_hints((rank, in_dim), "lora_A.weight", init_a)?;
+        let b = vb.get_with_hints((out_dim, rank), "lora_B.weight", crate::Init::Const(0.))?;
+        Ok((a, b))
+    }
+
+    pub fn conv2d(&self, base: Conv2d, name: &str) -> Result<LoraConv2d> {
+        let (out_c, in_c, k1, k2) = base.weight().dims4()?;
+        let layer = LoraConv2d::new(base);
+        match self.adapter_vb(name) {
+            None => Ok(layer),
+            Some(vb) => {
+                let rank = self.config.rank;
+                let init_a = crate::init::DEFAULT_KAIMING_NORMAL;
+                let a = vb.get_with_hints((rank, in_c, k1, k2), "lora_A.weight", init_a)?;
+                let init_b = crate::Init::Const(0.);
+                let b = vb.get_with_hints((out_c, rank, 1, 1), "lora_B.weight", init_b)?;
+                layer.with_adapter(a, b, self.config.scale())
+            }
+        }
+    }
+
+    pub fn embedding(&self, base: Embedding, name: &str) -> Result<LoraEmbedding> {
+        let (num, dim) = base.embeddings().dims2()?;
+        let layer = LoraEmbedding::new(base);
+        match self.adapter_vb(name) {
+            None => Ok(layer),
+            Some(vb) => {
+                let rank = self.config.rank;
+                // PEFT initializes the embeddings the other way around compared to the linear
+                // layers.
+                let a =
+                    vb.get_with_hints((rank, num), "lora_embedding_A", crate::Init::Const(0.))?;
+                let init_b = crate::Init::Randn {
+                    mean: 0.,
+                    stdev: 1.,
+                };
+                let b = vb.get_with_hints((dim, rank), "lora_embedding_B", init_b)?;
+                layer.with_adapter(a, b, self.config.scale())
+            }
+        }
+    }
+}
+
+/// A backend that merges adapters in the weights of an inner `VarBuilder`, see
+/// `VarBuilder::with_lora`.
+pub struct LoraMerged<'a> {
+    inner: VarBuilder<'a>,
+    lora: Lora<'a>,
+}
+
+impl<'a> LoraMerged<'a> {
+    pub fn new(inner: VarBuilder<'a>, lora: Lora<'a>) -> Self {
+        Self { inner, lora }
+    }
+
+    fn delta(&self, name: &str, shape: &Shape) -> Result<Option<Tensor>> {
+        let module = match name.strip_suffix(".weight") {
+            Some(module) if self.lora.config.matches(module) => module,
+            _ => return Ok(None),
+        };
+        let vb = self.lora.vb.pp(module);
+        let rank = self.lora.config.rank;
+        let scale = self.lora.config.scale();
+        let dims = shape.dims();
+        if vb.contains_tensor("lora_embedding_A") {
+            let (num, dim) = shape.dims2()?;
+            let a = vb.get((rank, num), "lora_embedding_A")?;
+            let b = vb.get((dim, rank), "lora_embedding_B")?;
+            let delta = lora_delta(&a, &b, scale, &(dim, num).into())?;
+            return Ok(Some(delta.t()?));
+        }
+        if !vb.contains_tensor("lora_A.weight") {
+            return Ok(None);
+        }
+        let (a_shape, b_shape): (Shape, Shape) = match dims {
+            [out_dim, in_dim] => ((rank, *in_dim).into(), (*out_dim, rank).into()),
+            [out_c, in_c, k1, k2] => ((rank, *in_c, *k1, *k2).into(), (*out_c, rank, 1, 1).into()),
+            _ => candle::bail!("unexpected shape {shape:?} for lora weights {name}"),
+        };
+        let a = vb.get(a_shape, "lora_A.weight")?;
+        let b = vb.get(b_shape, "lora_B.weight")?;
+        Ok(Some(lora_delta(&a, &b, scale, shape)?))
+    }
+}
+
+impl<'a> SimpleBackend for LoraMerged<'a> {
+    fn get(
+        &self,
+        s: Shape,
+        name: &str,
+        h: crate::Init,
+        dtype: DType,
+        dev: &Device,
+    ) -> Result<Tensor> {
+        let tensor = self.inner.get_with_hints_dtype(s.clone(), name, h, dtype)?;
+        let tensor = match self.delta(name, &s)? {
+            None => tensor,
+            Some(delta) => add_delta(&tensor, &delta, 1.)?,
+        };
+        tensor.to_device(dev)
+    }
+
+    fn contains_tensor(&self, name: &str) -> bool {
+        self.inner.contains_tensor(name)
+    }
+}
@@ -1,4 +1,25 @@
-use candle::{Result, Tensor};
+use candle::{DType, Result, Tensor};
+
+/// How the per-sample losses are combined.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Reduction {
+    /// The losses are returned without reduction.
+    None,
+    Sum,
+    /// The average over all the elements.
+    #[default]
+    Mean,
+}
+
+impl Reduction {
+    pub fn apply(&self, losses: &Tensor) -> Result<Tensor> {
+        match self {
+            Self::None => Ok(losses.clone()),
+            Self::Sum => losses.sum_all(),
+            Self::Mean => losses.mean_all(),
+        }
+    }
+}
 
 /// The negative log likelihood loss.
 ///
@@ -70,3 +91,449 @@ pub fn binary_cross_entropy_with_logit(inp: &Tensor, target: &Tensor) -> Result<
 
     Ok(loss)
 }
+
+// A large negative value used instead of `-inf` for the impossible alignments, this avoids
+// getting NaN values in the log-sum-exp and in the gradients.
+const CTC_NEG: f64 = -1e30;
+
+/// The connectionist temporal classification (CTC) loss.
+///
+/// Arguments
+///
+/// * [log_probs]: The input tensor of dimensions `T, N, C` where `T` is the input length, `N` the
+///          batch size and `C` the number of categories including the blank. This is expected to
+///          contain log probabilities, e.g. the output of a log-softmax.
+/// * [targets]: The target labels as a tensor of u32 of dimension `N, S`, padded to the maximum
+///          target length `S`. The targets should not contain the blank.
+/// * [input_lengths]: The number of valid time steps for each sequence in the batch.
+/// * [target_lengths]: The number of labels for each sequence in the batch.
+/// * [blank]: The index of the blank label.
+///
+/// The loss for each sequence, the negative log likelihood of its target, is divided by the
+/// target length and the resulting tensor is a scalar containing the average value over the batch,
+/// as with the PyTorch `mean` reduction. The alignments are summed over in log space with the CTC
+/// forward algorithm, the gradients are obtained by back-propagating through it. The targets that
+/// cannot be aligned with their input result in a very large loss.
+pub fn ctc_loss(
+    log_probs: &Tensor,
+    targets: &Tensor,
+    input_lengths: &[usize],
+    target_lengths: &[usize],
+    blank: u32,
+) -> Result<Tensor> {
+    let (t_len, b_sz, _c) = log_probs.dims3()?;
+    let (targets_b_sz, s_len) = targets.dims2()?;
+    if targets_b_sz != b_sz || input_lengths.len() != b_sz || target_lengths.len() != b_sz {
+        candle::bail!(
+            "batch size mismatch between log_probs ({b_sz}), targets ({targets_b_sz}), input lengths ({}) and target lengths ({})",
+            input_lengths.len(),
+            target_lengths.len()
+        )
+    }
+    if let Some(&l) = input_lengths.iter().find(|&&l| l == 0 || l > t_len) {
+        candle::bail!("input length {l} is not between 1 and {t_len}")
+    }
+    if let Some(&l) = target_lengths.iter().find(|&&l| l > s_len) {
+        candle::bail!("target length {l} is larger than the targets size {s_len}")
+    }
+    let dev = log_probs.device();
+    // The forward algorithm runs in f32 as the f16 range is too small for `CTC_NEG`.
+    let out_dtype = log_probs.dtype();
+    let dtype = candle::DType::F32;
+    // The targets extended with blanks: blank, y1, blank, y2, ..., blank.
+    let l_len = 2 * s_len + 1;
+    let targets = targets.to_vec2::<u32>()?;
+    let mut extended = vec![blank; b_sz * l_len];
+    let mut skip_mask = vec![CTC_NEG; b_sz * l_len];
+    let mut final_mask = vec![CTC_NEG; b_sz * l_len];
+    for (b, targets) in targets.iter().enumerate() {
+        let ext = &mut extended[b * l_len..(b + 1) * l_len];
+        for (s, &label) in targets[..target_lengths[b]].iter().enumerate() {
+            ext[2 * s + 1] = label;
+            // A label can be reached from the previous one, skipping the blank, unless the
+            // two labels are the same.
+            if s > 0 && targets[s - 1] != label {
+                skip_mask[b * l_len + 2 * s + 1] = 0.
+            }
+        }
+        let end = 2 * target_lengths[b];
+        final_mask[b * l_len + end] = 0.;
+        if end > 0 {
+            final_mask[b * l_len + end - 1] = 0.;
+        }
+    }
+    let extended = Tensor::from_vec(extended, (1, b_sz, l_len), dev)?
+        .broadcast_as((t_len, b_sz, l_len))?
+        .contiguous()?;
+    // The log probabilities of the extended labels, shape (t, b, l).
+    let emissions = log_probs
+        .to_dtype(dtype)?
+        .contiguous()?
+        .gather(&extended, 2)?;
+    let to_tensor = |vs: Vec<f64>| Tensor::from_vec(vs, (b_sz, l_len), dev)?.to_dtype(dtype);
+    let skip_mask = to_tensor(skip_mask)?;
+    let final_mask = to_tensor(final_mask)?;
+    let init_mask = (0..b_sz * l_len)
+        .map(|i| if i % l_len < 2 { 0. } else { CTC_NEG })
+        .collect();
+    let neg = |n: usize| Tensor::full(CTC_NEG, (b_sz, n), dev)?.to_dtype(dtype);
+    let (neg1, neg2) = (neg(1)?, neg(2)?);
+
+    let mut alpha = (emissions.get(0)? + to_tensor(init_mask)?)?;
+    for t in 1..t_len {
+        let prev1 = Tensor::cat(&[&neg1, &alpha.narrow(1, 0, l_len - 1)?], 1)?;
+        let prev2 = if l_len > 2 {
+            (Tensor::cat(&[&neg2, &alpha.narrow(1, 0, l_len - 2)?], 1)? + &skip_mask)?
+        } else {
+            neg(l_len)?
+        };
+        let next =
+            (Tensor::stack(&[&alpha, &prev1, &prev2], 0)?.log_sum_exp(0)? + emissions.get(t)?)?;
+        // The sequences that are already complete keep their last value.
+        let active = input_lengths
+            .iter()
+            .map(|&l| if t < l { 1. } else { 0. })
+            .collect::<Vec<f64>>();
+        let active = Tensor::from_vec(active, (b_sz, 1), dev)?.to_dtype(dtype)?;
+        alpha = (next.broadcast_mul(&active)? + alpha.broadcast_mul(&(1. - active)?)?)?;
+    }
+    let nll = (alpha + final_mask)?.log_sum_exp(1)?.neg()?;
+    let target_lengths = target_lengths
+        .iter()
+        .map(|&l| l.max(1) as f64)
+        .collect::<Vec<_>>();
+    let target_lengths = Tensor::from_vec(target_lengths, b_sz, dev)?.to_dtype(dtype)?;
+    (nll / target_lengths)?.mean_all()?.to_dtype(out_dtype)
+}
+
+/// The cross-entropy loss with label smoothing and class weights, see [`cross_entropy_with`].
+#[derive(Debug, Clone, Default)]
+pub struct CrossEntropyConfig {
+    /// The amount of smoothing between 0 and 1: the target distribution is a mix of the one-hot
+    /// target with weight `1 - label_smoothing` and of the uniform distribution.
+    pub label_smoothing: f64,
+    /// A weight for each class, as a tensor of dimension `C`.
+    pub weight: Option<Tensor>,
+    /// With `Reduction::Mean` and class weights, the losses are divided by the sum of the weights
+    /// of the targets rather than by the batch size.
+    pub reduction: Reduction,
+}
+
+/// The cross-entropy loss with label smoothing and class weights, this matches PyTorch
+/// `F.cross_entropy` with the `weight` and `label_smoothing` arguments.
+///
+/// Arguments
+///
+/// * [inp]: The input tensor of dimensions `N, C` where `N` is the batch size and `C` the number
+///          of categories. This is expected to raw logits.
+/// * [target]: The ground truth labels as a tensor of u32 of dimension `N`.
+pub fn cross_entropy_with(
+    inp: &Tensor,
+    target: &Tensor,
+    cfg: &CrossEntropyConfig,
+) -> Result<Tensor> {
+    let (b_sz, n_classes) = inp.dims2()?;
+    if target.dims1()? != b_sz {
+        candle::bail!(
+            "batch size mismatch between inp ({b_sz}) and target ({})",
+            target.dims1()?
+        )
+    }
+    let log_probs = crate::ops::log_softmax(inp, 1)?;
+    let (log_probs, target_weight) = match &cfg.weight {
+        None => (log_probs, None),
+        Some(weight) => {
+            let weight = weight.to_dtype(inp.dtype())?;
+            let target_weight = weight.index_select(target, 0)?;
+            (
+                log_probs.broadcast_mul(&weight.unsqueeze(0)?)?,
+                Some(target_weight),
+            )
+        }
+    };
+    let nll = log_probs
+        .gather(&target.unsqueeze(1)?, 1)?
+        .squeeze(1)?
+        .neg()?;
+    let losses = if cfg.label_smoothing > 0. {
+        let smooth = log_probs.sum(1)?.neg()?;
+        let eps = cfg.label_smoothing;
+        ((nll * (1. - eps))? + (smooth * (eps / n_classes as f64))?)?
+    } else {
+        nll
+    };
+    match (cfg.reduction, target_weight) {
+        (Reduction::Mean, Some(target_weight)) => losses.sum_all()? / target_weight.sum_all()?,
+        (reduction, _) => reduction.apply(&losses),
+    }
+}
+
+/// The cross-entropy loss of the logits `hidden @ weight^T`, computed by chunks of rows so that
+/// only `chunk_size, V` logits are materialized at a time rather than the full `N, V` logits.
+/// This is useful for language models with large vocabularies, `weight` being the output
+/// embeddings.
+///
+/// The gradients with respect to `hidden` and `weight` are computed chunk by chunk during the
+/// forward pass when these are tracked, and attached to the result so that the backward pass does
+/// not recompute the logits. The loss and gradients are the same as with
+/// `cross_entropy(&hidden.matmul(&weight.t()?)?, target)`, second order derivatives are not
+/// supported.
+///
+/// Arguments
+///
+/// * [hidden]: The hidden states of dimensions `N, D`, the batch and sequence dimensions have to
+///          be flattened.
+/// * [weight]: The output embeddings of dimensions `V, D` where `V` is the vocabulary size.
+/// * [target]: The ground truth labels as a tensor of u32 of dimension `N`.
+/// * [chunk_size]: The number of rows for which the logits are computed at once.
+///
+/// The resulting tensor is a scalar containing the average value over the batch.
+pub fn chunked_cross_entropy(
+    hidden: &Tensor,
+    weight: &Tensor,
+    target: &Tensor,
+    chunk_size: usize,
+) -> Result<Tensor> {
+    let (b_sz, hidden_size) = hidden.dims2()?;
+    let (vocab_size, weight_hidden_size) = weight.dims2()?;
+    if hidden_size != weight_hidden_size {
+        candle::bail!(
+            "hidden size mismatch between hidden {:?} and weight {:?}",
+            hidden.shape(),
+            weight.shape()
+        )
+    }
+    if target.dims1()? != b_sz {
+        candle::bail!(
+            "batch size mismatch between hidden ({b_sz}) and target ({})",
+            target.dims1()?
+        )
+    }
+    if chunk_size == 0 {
+        candle::bail!("chunked_cross_entropy expects a non-zero chunk size")
+    }
+    let dtype = hidden.dtype();
+    let compute_dtype = match dtype {
+        DType::F64 => DType::F64,
+        _ => DType::F32,
+    };
+    let (track_hidden, track_weight) = (hidden.track_op(), weight.track_op());
+    let h_all = hidden.detach().to_dtype(compute_dtype)?;
+    let w = weight.detach().to_dtype(compute_dtype)?;
+    let w_t = w.t()?;
+    let scale = 1. / b_sz as f64;
+    let vocab = Tensor::arange(0u32, vocab_size as u32, hidden.device())?.unsqueeze(0)?;
+    let mut loss = Tensor::zeros((), compute_dtype, hidden.device())?;
+    let mut grad_hidden = vec![];
+    let mut grad_weight = Tensor::zeros((vocab_size, hidden_size), compute_dtype, w.device())?;
+    for start in (0..b_sz).step_by(chunk_size) {
+        let len = chunk_size.min(b_sz - start);
+        let h = h_all.narrow(0, start, len)?;
+        let t = target.narrow(0, start, len)?.unsqueeze(1)?;
+        let logits = h.matmul(&w_t)?;
+        let lse = logits.log_sum_exp(1)?.unsqueeze(1)?;
+        let target_logits = logits.gather(&t, 1)?;
+        loss = (loss + (&lse - target_logits)?.sum_all()?)?;
+        if !track_hidden && !track_weight {
+            continue;
+        }
+        // The gradient of the loss with respect to the logits is `softmax(logits) - one_hot`.
+        let one_hot = vocab.broadcast_eq(&t)?.to_dtype(compute_dtype)?;
+        let grad_logits = ((logits.broadcast_sub(&lse)?.exp()? - one_hot)? * scale)?;
+        if track_hidden {
+            grad_hidden.push(grad_logits.matmul(&w)?)
+        }
+        if track_weight {
+            grad_weight = (grad_weight + grad_logits.t()?.matmul(&h)?)?
+        }
+    }
+    let mut loss = (loss * scale)?.to_dtype(dtype)?;
+    // The surrogates have a zero value and the precomputed gradients.
+    if track_hidden {
+        let grad_hidden = Tensor::cat(&grad_hidden, 0)?.to_dtype(dtype)?;
+        let surrogate = (hidden * grad_hidden)?.sum_all()?;
+        loss = (loss + (&surrogate - surrogate.detach())?)?;
+    }
+    if track_weight {
+        let grad_weight = grad_weight.to_dtype(weight.dtype())?;
+        let surrogate = (weight * grad_weight)?.sum_all()?;
+        loss = (loss + (&surrogate - surrogate.detach())?.to_dtype(dtype)?)?;
+    }
+    Ok(loss)
+}
+
+/// The focal loss for multi-class classification, `-alpha_t (1 - p_t)^gamma log(p_t)` where
+/// `p_t` is the predicted probability of the target class. This down-weights the easy examples,
+/// with `gamma = 0` and no `alpha`, this is the cross-entropy loss.
+///
+/// Arguments
+///
+/// * [inp]: The input tensor of dimensions `N, C`, this is expected to contain raw logits.
+/// * [target]: The ground truth labels as a tensor of u32 of dimension `N`.
+/// * [alpha]: An optional weight for each class, as a tensor of dimension `C`.
+pub fn focal_loss(
+    inp: &Tensor,
+    target: &Tensor,
+    gamma: f64,
+    alpha: Option<&Tensor>,
+    reduction: Reduction,
+) -> Result<Tensor> {
+    let log_probs = crate::ops::log_softmax(inp, 1)?;
+    let log_pt = log_probs.gather(&target.unsqueeze(1)?, 1)?.squeeze(1)?;
+    let modulation = (1. - log_pt.exp()?)?.relu()?.powf(gamma)?;
+    let mut losses = (modulation * log_pt)?.neg()?;
+    if let Some(alpha) = alpha {
+        let alpha_t = alpha.to_dtype(inp.dtype())?.index_select(target, 0)?;
+        losses = (losses * alpha_t)?
+    }
+    reduction.apply(&losses)
+}
+
+/// The binary focal loss from "Focal Loss for Dense Object Detection", this matches torchvision
+/// `sigmoid_focal_loss`.
+///
+/// Arguments
+///
+/// * [inp]: The input tensor of arbitrary shape, this is expected to contain raw logits.
+/// * [target]: The binary targets as a float tensor with the same shape as `inp`.
+/// * [alpha]: The weight of the positive examples, the negative ones being weighted by
+///          `1 - alpha`, no weighting is applied when `None`.
+pub fn sigmoid_focal_loss(
+    inp: &Tensor,
+    target: &Tensor,
+    alpha: Option<f64>,
+    gamma: f64,
+    reduction: Reduction,
+) -> Result<Tensor> {
+    let p = crate::ops::sigmoid(inp)?;
+    let ce = binary_cross_entropy_with_logit_elementwise(inp, target)?;
+    // 1 - p_t = p + target - 2 p target
+    let one_minus_pt = ((&p + target)? - (p * target)?.affine(2., 0.)?)?;
+    let mut losses = (ce * one_minus_pt.relu()?.powf(gamma)?)?;
+    if let Some(alpha) = alpha {
+        let alpha_t = target.affine(2. * alpha - 1., 1. - alpha)?;
+        losses = (losses * alpha_t)?
+    }
+    reduction.apply(&losses)
+}
+
+// The numerically stable binary cross-entropy with logits, without reduction:
+// max(x, 0) - x * target + log(1 + exp(-|x|))
+fn binary_cross_entropy_with_logit_elementwise(inp: &Tensor, target: &Tensor) -> Result<Tensor> {
+    let softplus = (inp.abs()?.neg()?.exp()? + 1.)?.log()?;
+    (inp.relu()? - (inp * target)?)? + softplus
+}
+
+/// The Huber loss: `0.5 d^2` when `|d| < delta` and `delta (|d| - 0.5 delta)` otherwise, where
+/// `d` is the difference between the input and the target.
+pub fn huber_loss(
+    inp: &Tensor,
+    target: &Tensor,
+    delta: f64,
+    reduction: Reduction,
+) -> Result<Tensor> {
+    let diff = (inp - target)?.abs()?;
+    // The quadratic part is the difference clamped to delta, the remainder is linear.
+    let quadratic = diff.clamp(0f64, delta)?;
+    let linear = (&diff - &quadratic)?;
+    let losses = ((quadratic.sqr()? * 0.5)? + (linear * delta)?)?;
+    reduction.apply(&losses)
+}
+
+/// The smooth L1 loss: `0.5 d^2 / beta` when `|d| < beta` and `|d| - 0.5 beta` otherwise. This is
+/// the Huber loss divided by `beta`, and the L1 loss when `beta` is 0.
+pub fn smooth_l1_loss(
+    inp: &Tensor,
+    target: &Tensor,
+    beta: f64,
+    reduction: Reduction,
+) -> Result<Tensor> {
+    if beta == 0. {
+        return reduction.apply(&(inp - target)?.abs()?);
+    }
+    let losses = (huber_loss(inp, target, beta, Reduction::None)? / beta)?;
+    reduction.apply(&losses)
+}
+
+/// The Kullback-Leibler divergence loss, pointwise `target * (log(target) - inp)`, this matches
+/// PyTorch `F.kl_div`.
+///
+/// Arguments
+///
+/// * [inp]: The input tensor, this is expected to contain log probabilities.
+/// * [target]: The target probabilities, or log probabilities when `log_target` is true.
+///
+/// Note that `Reduction::Mean` averages over all the elements, the mathematically correct KL
+/// divergence for a batch of distributions is obtained with `Reduction::Sum` divided by the batch
+/// size.
+pub fn kl_div(
+    inp: &Tensor,
+    target: &Tensor,
+    log_target: bool,
+    reduction: Reduction,
+) -> Result<Tensor> {
+    let losses = if log_target {
+        (target.exp()? * (target - inp)?)?
+    } else {
+        // 0 log(0) is taken to be 0, the zeros are replaced by ones before taking the log so
+        // that no infinite values appear, including in the gradients.
+        let positive = target.gt(0f64)?;
+        let safe_target = positive.where_cond(target, &target.ones_like()?)?;
+        (target * (safe_target.log()? - inp)?)?
+    };
+    reduction.apply(&losses)
+}
+
+/// The margin ranking loss `max(0, -y (x1 - x2) + margin)`, where `y` is 1 when `x1` should be
+/// ranked higher than `x2` and -1 otherwise.
+pub fn margin_ranking_loss(
+    x1: &Tensor,
+    x2: &Tensor,
+    y: &Tensor,
+    margin: f64,
+    reduction: Reduction,
+) -> Result<Tensor> {
+    let losses = ((x1 - x2)? * y.to_dtype(x1.dtype())?)?
+        .neg()?
+        .affine(1., margin)?
+        .relu()?;
+    reduction.apply(&losses)
+}
+
+// The p-norm distance between the rows, PyTorch adds `eps` to the differences.
+fn pairwise_distance(x1: &Tensor, x2: &Tensor, p: f64, eps: f64) -> Result<Tensor> {
+    let diff = ((x1 - x2)? + eps)?.abs()?;
+    let last = diff.rank() - 1;
+    if p == 2. {
+        diff.sqr()?.sum(last)?.sqrt()
+    } else {
+        diff.powf(p)?.sum(last)?.powf(1. / p)
+    }
+}
+
+/// The triplet margin loss `max(d(a, p) - d(a, n) + margin, 0)` where `d` is the `p`-norm
+/// distance, this matches PyTorch `F.triplet_margin_loss` with `eps = 1e-6`.
+///
+/// Arguments
+///
+/// * [anchor], [positive], [negative]: The embeddings, of dimensions `N, D`.
+/// * [swap]: Uses the distance between the positive and negative examples when it is smaller
+///          than the one between the anchor and the negative example.
+pub fn triplet_margin_loss(
+    anchor: &Tensor,
+    positive: &Tensor,
+    negative: &Tensor,
+    margin: f64,
+    p: f64,
+    swap: bool,
+    reduction: Reduction,
+) -> Result<Tensor> {
+    const EPS: f64 = 1e-6;
+    let d_pos = pairwise_distance(anchor, positive, p, EPS)?;
+    let mut d_neg = pairwise_distance(anchor, negative, p, EPS)?;
+    if swap {
+        d_neg = d_neg.minimum(&pairwise_distance(positive, negative, p, EPS)?)?;
+    }
+    let losses = ((d_pos - d_neg)? + margin)?.relu()?;
+    reduction.apply(&losses)
+}
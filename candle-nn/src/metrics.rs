@@ -0,0 +1,201 @@
+//! Evaluation metrics for classifiers.
+//!
+//! All the metrics are computed with tensor operations on the device of the inputs so that no
+//! host transfers are required, the results are returned as tensors with a static shape that
+//! only depends on the input shapes.
+use candle::{DType, Result, Tensor};
+
+// Cumulative sum for a 1d tensor. `Tensor::cumsum` relies on a matmul with a triangular matrix
+// which is quadratic in the number of elements, so the sum is computed per block and the block
+// offsets are added recursively.
+fn cumsum_1d(xs: &Tensor) -> Result<Tensor> {
+    const BLOCK: usize = 256;
+    let n = xs.dim(0)?;
+    if n <= BLOCK {
+        return xs.cumsum(0);
+    }
+    let n_blocks = n.div_ceil(BLOCK);
+    let xs = xs
+        .pad_with_zeros(0, 0, n_blocks * BLOCK - n)?
+        .reshape((n_blocks, BLOCK))?;
+    let within = xs.cumsum(1)?;
+    let totals = within.narrow(1, BLOCK - 1, 1)?.squeeze(1)?;
+    let offsets = (cumsum_1d(&totals)? - &totals)?;
+    within
+        .broadcast_add(&offsets.unsqueeze(1)?)?
+        .flatten_all()?
+        .narrow(0, 0, n)
+}
+
+/// Computes the confusion matrix for some predicted and target class indexes.
+///
+/// Arguments
+///
+/// * [preds]: The predicted class indexes as a u32 tensor of dimension `N`.
+/// * [targets]: The ground truth class indexes as a u32 tensor of dimension `N`.
+/// * [num_classes]: The number of classes `C`.
+///
+/// The resulting tensor has shape `C, C` and dtype f32, the element at `(i, j)` is the number of
+/// samples with target class `i` that were predicted as class `j`.
+pub fn confusion_matrix(preds: &Tensor, targets: &Tensor, num_classes: usize) -> Result<Tensor> {
+    let n = preds.dims1()?;
+    let n_targets = targets.dims1()?;
+    if n != n_targets {
+        candle::bail!(
+            "confusion_matrix: size mismatch between preds ({n}) and targets ({n_targets})"
+        )
+    }
+    let preds = preds.to_dtype(DType::U32)?;
+    let targets = targets.to_dtype(DType::U32)?;
+    let indexes = (targets.affine(num_classes as f64, 0.)? + &preds)?;
+    let ones = Tensor::ones(n, DType::F32, preds.device())?;
+    Tensor::zeros(num_classes * num_classes, DType::F32, preds.device())?
+        .scatter_add(&indexes, &ones, 0)?
+        .reshape((num_classes, num_classes))
+}
+
+/// The cumulative true and false positive counts for each distinct threshold.
+struct BinaryCounts {
+    tps: Tensor,
+    fps: Tensor,
+    thresholds: Tensor,
+}
+
+// Sorts the scores by decreasing values and accumulates the positive and negative counts for each
+// group of tied scores. The returned tensors have the same size as the input, the first elements
+// correspond to the distinct thresholds and the remaining ones repeat the final counts, with the
+// threshold set to the lowest score.
+fn binary_counts(scores: &Tensor, labels: &Tensor) -> Result<BinaryCounts> {
+    let n = scores.dims1()?;
+    let n_labels = labels.dims1()?;
+    if n != n_labels {
+        candle::bail!("size mismatch between scores ({n}) and labels ({n_labels})")
+    }
+    if n == 0 {
+        return Err(candle::Error::EmptyTensor {
+            op: "binary-counts",
+        }
+        .bt());
+    }
+    let scores = scores.to_dtype(DType::F32)?;
+    let labels = labels.to_dtype(DType::F32)?;
+    let (scores, indexes) = scores.sort_last_dim(false)?;
+    let labels = labels.gather(&indexes, 0)?;
+    let starts = scores
+        .narrow(0, 1, n - 1)?
+        .ne(&scores.narrow(0, 0, n - 1)?)?
+        .to_dtype(DType::F32)?;
+    let starts = Tensor::cat(&[Tensor::ones(1, DType::F32, scores.device())?, starts], 0)?;
+    let group_ids = (cumsum_1d(&starts)? - 1.)?.to_dtype(DType::U32)?;
+    let zeros = scores.zeros_like()?;
+    let pos = zeros.scatter_add(&group_ids, &labels, 0)?;
+    let neg = zeros.scatter_add(&group_ids, &labels.affine(-1., 1.)?, 0)?;
+    let exists = zeros.scatter_add(&group_ids, &starts, 0)?;
+    let thresholds = zeros.scatter_add(&group_ids, &(&scores * &starts)?, 0)?;
+    let min_score = scores.narrow(0, n - 1, 1)?.broadcast_as(n)?;
+    let thresholds = exists
+        .to_dtype(DType::U8)?
+        .where_cond(&thresholds, &min_score)?;
+    Ok(BinaryCounts {
+        tps: cumsum_1d(&pos)?,
+        fps: cumsum_1d(&neg)?,
+        thresholds,
+    })
+}
+
+/// A receiver operating characteristic curve.
+#[derive(Debug, Clone)]
+pub struct RocCurve {
+    /// The false positive rates.
+    pub fpr: Tensor,
+    /// The true positive rates.
+    pub tpr: Tensor,
+    /// The decreasing thresholds used to compute the rates, the first threshold is infinite.
+    pub thresholds: Tensor,
+}
+
+/// Computes the receiver operating characteristic curve for a binary classifier.
+///
+/// Arguments
+///
+/// * [scores]: The predicted scores as a tensor of dimension `N`, higher scores are more likely to
+///             be positive.
+/// * [labels]: The ground truth labels as a tensor of dimension `N` containing 0 or 1.
+///
+/// The curve has `N + 1` points, starting at `(0, 0)` followed by one point per distinct score in
+/// decreasing order. When some scores are tied, the last points are repeated so that the shape of
+/// the result does not depend on the data.
+pub fn roc_curve(scores: &Tensor, labels: &Tensor) -> Result<RocCurve> {
+    let BinaryCounts {
+        tps,
+        fps,
+        thresholds,
+    } = binary_counts(scores, labels)?;
+    let n = tps.dim(0)?;
+    let zero = Tensor::zeros(1, DType::F32, tps.device())?;
+    let tpr = Tensor::cat(&[&zero, &tps], 0)?.broadcast_div(&tps.narrow(0, n - 1, 1)?)?;
+    let fpr = Tensor::cat(&[&zero, &fps], 0)?.broadcast_div(&fps.narrow(0, n - 1, 1)?)?;
+    let inf = Tensor::full(f32::INFINITY, 1, tps.device())?;
+    let thresholds = Tensor::cat(&[&inf, &thresholds], 0)?;
+    Ok(RocCurve {
+        fpr,
+        tpr,
+        thresholds,
+    })
+}
+
+/// Computes the area under the receiver operating characteristic curve using the trapezoidal rule.
+///
+/// See `roc_curve` for the arguments. The result is a f32 scalar tensor.
+pub fn roc_auc(scores: &Tensor, labels: &Tensor) -> Result<Tensor> {
+    let RocCurve { fpr, tpr, .. } = roc_curve(scores, labels)?;
+    let n = fpr.dim(0)?;
+    let dx = (fpr.narrow(0, 1, n - 1)? - fpr.narrow(0, 0, n - 1)?)?;
+    let y = (tpr.narrow(0, 1, n - 1)? + tpr.narrow(0, 0, n - 1)?)?;
+    (dx * y)?.sum_all()? * 0.5
+}
+
+/// A precision-recall curve.
+#[derive(Debug, Clone)]
+pub struct PrCurve {
+    /// The precision values.
+    pub precision: Tensor,
+    /// The increasing recall values.
+    pub recall: Tensor,
+    /// The decreasing thresholds used to compute precision and recall.
+    pub thresholds: Tensor,
+}
+
+/// Computes the precision-recall curve for a binary classifier.
+///
+/// See `roc_curve` for the arguments. The curve has `N` points, one per distinct score in
+/// decreasing order, the last points being repeated when some scores are tied.
+pub fn precision_recall_curve(scores: &Tensor, labels: &Tensor) -> Result<PrCurve> {
+    let BinaryCounts {
+        tps,
+        fps,
+        thresholds,
+    } = binary_counts(scores, labels)?;
+    let n = tps.dim(0)?;
+    let precision = (&tps / (&tps + &fps)?)?;
+    let recall = tps.broadcast_div(&tps.narrow(0, n - 1, 1)?)?;
+    Ok(PrCurve {
+        precision,
+        recall,
+        thresholds,
+    })
+}
+
+/// Computes the average precision, i.e. the sum of the precisions at each threshold weighted by
+/// the increase in recall from the previous threshold.
+///
+/// See `roc_curve` for the arguments. The result is a f32 scalar tensor.
+pub fn average_precision(scores: &Tensor, labels: &Tensor) -> Result<Tensor> {
+    let PrCurve {
+        precision, recall, ..
+    } = precision_recall_curve(scores, labels)?;
+    let zero = Tensor::zeros(1, DType::F32, recall.device())?;
+    let n = recall.dim(0)?;
+    let prev_recall = Tensor::cat(&[&zero, &recall.narrow(0, 0, n - 1)?], 0)?;
+    ((recall - prev_recall)? * precision)?.sum_all()
+}
@@ -1,5 +1,8 @@
-use candle::{CpuStorage, DType, Layout, Module, Result, Shape, Tensor, D};
+use candle::philox::PhiloxKey;
+use candle::{CpuStorage, DType, Layout, MetaStorage, Module, Result, Shape, Tensor, D};
 use rayon::prelude::*;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::Mutex;
 
 /// Applies the softmax function to the input tensor, rescaling the element so that elements on
 /// a slice of fixed index on dimension `dim` are between 0 and 1 and sum to 1.
@@ -50,6 +53,10 @@ impl candle::CustomOp1 for Sigmoid {
         "sigmoid"
     }
 
+    fn meta_fwd(&self, s: &MetaStorage, l: &Layout) -> Result<(MetaStorage, Shape)> {
+        Ok(s.record_like_first(self.name(), l, &[]))
+    }
+
     fn cpu_fwd(&self, storage: &CpuStorage, layout: &Layout) -> Result<(CpuStorage, Shape)> {
         use candle::backend::BackendStorage;
 
@@ -242,39 +249,179 @@ pub fn leaky_relu(xs: &Tensor, negative_slope: f64) -> Result<Tensor> {
     xs.maximum(&zeros)? + xs.minimum(&zeros)? * negative_slope
 }
 
+// The state used by the keyed dropout masks, see `set_dropout_seed`.
+static DROPOUT_KEYED: AtomicBool = AtomicBool::new(false);
+static DROPOUT_SEED: AtomicU64 = AtomicU64::new(0);
+static DROPOUT_STEP: AtomicU64 = AtomicU64::new(0);
+static DROPOUT_GENERATION: AtomicU64 = AtomicU64::new(0);
+static DROPOUT_OFFSET: AtomicU64 = AtomicU64::new(0);
+static DROPOUT_OP_ID: AtomicU32 = AtomicU32::new(1);
+
+// The successive calls of a `Dropout` layer within a step use disjoint ranges of the random
+// stream, call `i` starting at element `i << CALL_SHIFT`.
+const CALL_SHIFT: u32 = 40;
+
+/// Switches dropout to masks generated by a counter-based Philox generator with this seed, see
+/// `candle::philox`, and resets the step and the offset used by [`dropout`]. The masks then only
+/// depend on the seed and on the sequence of calls so training runs are bitwise reproducible.
+///
+/// Keyed masks are generated on the host and copied to the device, and do not depend on the
+/// seed set with `Device::set_seed`. By default the masks are generated on the device with
+/// `Tensor::rand`.
+pub fn set_dropout_seed(seed: u64) {
+    DROPOUT_SEED.store(seed, Ordering::Relaxed);
+    DROPOUT_STEP.store(0, Ordering::Relaxed);
+    DROPOUT_OFFSET.store(0, Ordering::Relaxed);
+    DROPOUT_GENERATION.fetch_add(1, Ordering::Relaxed);
+    DROPOUT_KEYED.store(true, Ordering::Relaxed);
+}
+
+/// The seed and the offset used by the next [`dropout`] call when keyed masks are enabled, this
+/// is saved in the `Trainer` checkpoints.
+pub fn dropout_rng_state() -> Option<(u64, u64)> {
+    if !DROPOUT_KEYED.load(Ordering::Relaxed) {
+        return None;
+    }
+    let seed = DROPOUT_SEED.load(Ordering::Relaxed);
+    Some((seed, DROPOUT_OFFSET.load(Ordering::Relaxed)))
+}
+
+/// Restores a state returned by [`dropout_rng_state`], enabling the keyed masks.
+pub fn set_dropout_rng_state(seed: u64, offset: u64) {
+    set_dropout_seed(seed);
+    DROPOUT_OFFSET.store(offset, Ordering::Relaxed);
+}
+
+/// Goes back to the default masks generated on the device with `Tensor::rand`.
+pub fn clear_dropout_seed() {
+    DROPOUT_KEYED.store(false, Ordering::Relaxed);
+}
+
+/// Sets the training step used by the keyed [`Dropout`] masks, `Trainer` calls this before
+/// each batch. Each call of a layer gets a different mask, the calls being counted from the
+/// last `set_dropout_step`. Setting the same step again replays the same masks, e.g. when
+/// recomputing activations for gradient checkpointing.
+pub fn set_dropout_step(step: u64) {
+    DROPOUT_STEP.store(step, Ordering::Relaxed);
+    DROPOUT_GENERATION.fetch_add(1, Ordering::Relaxed);
+}
+
+fn check_drop_p(drop_p: f32) -> Result<()> {
+    if !(0. ..1.).contains(&drop_p) {
+        candle::bail!("dropout probability has to be in [0, 1), got {drop_p}")
+    }
+    Ok(())
+}
+
+fn apply_mask(xs: &Tensor, drop_p: f32, rand: Tensor) -> Result<Tensor> {
+    let scale = 1.0 / (1.0 - drop_p as f64);
+    let drop_p = Tensor::new(drop_p, xs.device())?.broadcast_as(xs.shape())?;
+    let mask = (rand.ge(&drop_p)?.to_dtype(xs.dtype())? * scale)?;
+    xs * mask
+}
+
+/// Applies dropout using the random values for `key`, the values of element `i` of `xs` being
+/// the ones at offset `key.offset + i`. The mask is generated on the host.
+pub fn dropout_with_key(xs: &Tensor, drop_p: f32, key: PhiloxKey) -> Result<Tensor> {
+    check_drop_p(drop_p)?;
+    apply_mask(xs, drop_p, key.uniform(xs.shape(), xs.device())?)
+}
+
+/// Applies dropout with a mask generated on the device, or with a keyed mask when a seed has
+/// been set with [`set_dropout_seed`]. In that case successive calls use successive offsets of
+/// the same random stream.
 pub fn dropout(xs: &Tensor, drop_p: f32) -> Result<Tensor> {
     // This implementation is inefficient as it stores the full mask for the backward pass.
     // Instead we could just store the seed and have a specialized kernel that would both
     // generate the random mask and apply it.
     // Another easier optimization would be to be able to generate boolean mask using just a bit of
     // entropy per element rather than generating a full float per element.
-    if !(0. ..1.).contains(&drop_p) {
-        candle::bail!("dropout probability has to be in [0, 1), got {drop_p}")
+    if DROPOUT_KEYED.load(Ordering::Relaxed) {
+        let offset = DROPOUT_OFFSET.fetch_add(xs.elem_count() as u64, Ordering::Relaxed);
+        let key = PhiloxKey::new(DROPOUT_SEED.load(Ordering::Relaxed), 0).with_offset(offset);
+        return dropout_with_key(xs, drop_p, key);
     }
+    check_drop_p(drop_p)?;
     let rand = Tensor::rand(0f32, 1f32, xs.shape(), xs.device())?;
-    let scale = 1.0 / (1.0 - drop_p as f64);
-    let drop_p = Tensor::new(drop_p, xs.device())?.broadcast_as(xs.shape())?;
-    let mask = (rand.ge(&drop_p)?.to_dtype(xs.dtype())? * scale)?;
-    xs * mask
+    apply_mask(xs, drop_p, rand)
 }
 
-#[derive(Clone, Debug)]
+/// A dropout layer.
+///
+/// By default the mask is generated on the device, see [`dropout`]. With keyed masks, see
+/// [`set_dropout_seed`], each layer gets an op id when created, so that models built in the
+/// same order use the same ids, and its mask is keyed by the seed, this op id, the step set
+/// with [`set_dropout_step`] and the number of calls of the layer since that step. The mask
+/// does not depend on the other ops that have run before. A clone is a separate layer with a
+/// new op id.
+#[derive(Debug)]
 pub struct Dropout {
     drop_p: f32,
+    op_id: u32,
+    // The dropout generation and the number of calls in that generation.
+    calls: Mutex<(u64, u64)>,
+}
+
+impl Clone for Dropout {
+    fn clone(&self) -> Self {
+        Self::new(self.drop_p)
+    }
 }
 
 impl Dropout {
     pub fn new(drop_p: f32) -> Dropout {
-        Self { drop_p }
+        let op_id = DROPOUT_OP_ID.fetch_add(1, Ordering::Relaxed);
+        Self {
+            drop_p,
+            op_id,
+            calls: Mutex::new((0, 0)),
+        }
+    }
+
+    /// Uses an explicit op id rather than the one based on the creation order.
+    pub fn with_op_id(self, op_id: u32) -> Self {
+        Self { op_id, ..self }
+    }
+
+    pub fn op_id(&self) -> u32 {
+        self.op_id
+    }
+
+    /// Returns the index of the next call since the last [`set_dropout_step`] and counts it.
+    pub fn next_call(&self) -> u64 {
+        let generation = DROPOUT_GENERATION.load(Ordering::Relaxed);
+        let mut calls = self.calls.lock().unwrap();
+        if calls.0 != generation {
+            *calls = (generation, 0)
+        }
+        calls.1 += 1;
+        calls.1 - 1
+    }
+
+    /// The key for the mask of call `call` in the current step.
+    pub fn key(&self, call: u64) -> PhiloxKey {
+        let step = DROPOUT_STEP.load(Ordering::Relaxed);
+        let op_id = (step << 32) | self.op_id as u64;
+        PhiloxKey::new(DROPOUT_SEED.load(Ordering::Relaxed), op_id).with_offset(call << CALL_SHIFT)
     }
 
     pub fn forward(&self, xs: &Tensor, train: bool) -> Result<Tensor> {
-        if train {
-            dropout(xs, self.drop_p)
-        } else {
+        if !train {
             Ok(xs.clone())
+        } else if DROPOUT_KEYED.load(Ordering::Relaxed) {
+            dropout_with_key(xs, self.drop_p, self.key(self.next_call()))
+        } else {
+            dropout(xs, self.drop_p)
         }
     }
+
+    /// Same as `forward` in training mode with a keyed mask, for a chunk of a batch starting at
+    /// element `offset` of the full batch, so that the mask does not depend on how the batch is
+    /// split. All the chunks of a batch use the same `call`, e.g. from `next_call`.
+    pub fn forward_chunk(&self, xs: &Tensor, call: u64, offset: u64) -> Result<Tensor> {
+        let key = self.key(call);
+        dropout_with_key(xs, self.drop_p, key.with_offset(key.offset + offset))
+    }
 }
 
 impl candle::ModuleT for Dropout {
@@ -290,6 +437,10 @@ impl candle::CustomOp1 for SoftmaxLastDim {
         "softmax-last-dim"
     }
 
+    fn meta_fwd(&self, s: &MetaStorage, l: &Layout) -> Result<(MetaStorage, Shape)> {
+        Ok(s.record_like_first(self.name(), l, &[]))
+    }
+
     fn cpu_fwd(&self, storage: &CpuStorage, layout: &Layout) -> Result<(CpuStorage, Shape)> {
         fn softmax<T: candle::WithDType + num_traits::Float>(
             src: &[T],
@@ -431,6 +582,427 @@ pub fn softmax_last_dim(xs: &Tensor) -> Result<Tensor> {
     xs.apply_op1_no_bwd(&SoftmaxLastDim)
 }
 
+// The offset of the mask values for each row of the last dimension, the mask layout being
+// broadcast to the shape of the input.
+fn mask_row_offset(mask_layout: &Layout, row: usize) -> usize {
+    let dims = mask_layout.dims();
+    let stride = mask_layout.stride();
+    let mut row = row;
+    let mut offset = mask_layout.start_offset();
+    for d in (0..dims.len() - 1).rev() {
+        offset += (row % dims[d]) * stride[d];
+        row /= dims[d];
+    }
+    offset
+}
+
+#[derive(Debug, Clone)]
+struct MaskedSoftmaxLastDim;
+
+impl candle::CustomOp2 for MaskedSoftmaxLastDim {
+    fn name(&self) -> &'static str {
+        "masked-softmax-last-dim"
+    }
+
+    fn meta_fwd(
+        &self,
+        s1: &MetaStorage,
+        l1: &Layout,
+        s2: &MetaStorage,
+        l2: &Layout,
+    ) -> Result<(MetaStorage, Shape)> {
+        Ok(s1.record_like_first(self.name(), l1, &[(s2, l2)]))
+    }
+
+    fn cpu_fwd(
+        &self,
+        s1: &CpuStorage,
+        l1: &Layout,
+        s2: &CpuStorage,
+        l2: &Layout,
+    ) -> Result<(CpuStorage, Shape)> {
+        fn softmax<T: candle::WithDType + num_traits::Float>(
+            src: &[T],
+            layout: &Layout,
+            mask: &[u8],
+            mask_layout: &Layout,
+        ) -> Result<(CpuStorage, Shape)> {
+            let src = match layout.contiguous_offsets() {
+                None => candle::bail!("input has to be contiguous"),
+                Some((o1, o2)) => &src[o1..o2],
+            };
+            let el_count = layout.shape().elem_count();
+            let dims = layout.shape().dims();
+            let dim_m1 = dims[dims.len() - 1];
+            let mask_stride = mask_layout.stride()[dims.len() - 1];
+            let mut dst = vec![T::zero(); el_count];
+            src.par_chunks(dim_m1)
+                .zip(dst.par_chunks_mut(dim_m1))
+                .enumerate()
+                .for_each(|(row, (src, dst))| {
+                    let offset = mask_row_offset(mask_layout, row);
+                    let masked = |i: usize| mask[offset + i * mask_stride] != 0;
+                    let mut max = T::neg_infinity();
+                    for (i, &s) in src.iter().enumerate() {
+                        if !masked(i) && s > max {
+                            max = s
+                        }
+                    }
+                    let mut sum_exp = T::zero();
+                    for (i, (s, d)) in src.iter().zip(dst.iter_mut()).enumerate() {
+                        if !masked(i) {
+                            *d = (*s - max).exp();
+                            sum_exp += *d
+                        }
+                    }
+                    // Rows where all the values are masked are left as zeros.
+                    if sum_exp > T::zero() {
+                        for d in dst.iter_mut() {
+                            *d /= sum_exp
+                        }
+                    }
+                });
+            let storage = candle::WithDType::to_cpu_storage_owned(dst);
+            Ok((storage, Shape::from_dims(dims)))
+        }
+
+        let mask = s2.as_slice::<u8>()?;
+        match s1 {
+            CpuStorage::BF16(slice) => softmax::<half::bf16>(slice, l1, mask, l2),
+            CpuStorage::F16(slice) => softmax::<half::f16>(slice, l1, mask, l2),
+            CpuStorage::F32(slice) => softmax::<f32>(slice, l1, mask, l2),
+            CpuStorage::F64(slice) => softmax::<f64>(slice, l1, mask, l2),
+            _ => candle::bail!("unsupported dtype for masked-softmax {:?}", s1),
+        }
+    }
+
+    #[cfg(feature = "cuda")]
+    fn cuda_fwd(
+        &self,
+        s1: &candle::CudaStorage,
+        l1: &Layout,
+        s2: &candle::CudaStorage,
+        l2: &Layout,
+    ) -> Result<(candle::CudaStorage, Shape)> {
+        use candle::cuda_backend::cudarc::driver::{
+            CudaSlice, DeviceRepr, LaunchAsync, LaunchConfig,
+        };
+        use candle::cuda_backend::{kernel_name, kernels, Map1, WrapErr};
+        use candle::{CudaDevice, WithDType};
+
+        struct S<'a> {
+            mask: &'a CudaSlice<u8>,
+            mask_layout: &'a Layout,
+        }
+        impl<'a> Map1 for S<'a> {
+            fn f<T: DeviceRepr + WithDType>(
+                &self,
+                src: &CudaSlice<T>,
+                dev: &CudaDevice,
+                layout: &Layout,
+            ) -> Result<CudaSlice<T>> {
+                let src = match layout.contiguous_offsets() {
+                    None => candle::bail!("input has to be contiguous"),
+                    Some((o1, o2)) => src.slice(o1..o2),
+                };
+                let el = layout.shape().elem_count();
+                let dims = layout.shape().dims();
+                let dim_m1 = dims[dims.len() - 1];
+                let (n_rows, n_cols) = (el / dim_m1, dim_m1);
+                let mask = self.mask.slice(self.mask_layout.start_offset()..);
+                let ml = self.mask_layout;
+                let info = dev.layout_info([ml.dims(), ml.stride()].concat())?;
+
+                let cfg = LaunchConfig {
+                    grid_dim: (n_rows as u32, 1, 1),
+                    block_dim: (1, 32, 1),
+                    shared_mem_bytes: 0,
+                };
+                let name = kernel_name::<T>("masked_softmax");
+                let func = dev.get_or_load_func(&name, kernels::REDUCE)?;
+                // SAFETY: Set later by running the kernel.
+                let dst = unsafe { dev.alloc::<T>(el) }.w()?;
+                let params = (&src, &mask, &dst, n_cols as i32, dims.len(), &info);
+                // SAFETY: ffi.
+                unsafe { func.launch(cfg, params) }.w()?;
+                Ok(dst)
+            }
+        }
+
+        use candle::backend::BackendStorage;
+        let dev = s1.device();
+        let mask = s2.as_cuda_slice::<u8>()?;
+        let slice = S {
+            mask,
+            mask_layout: l2,
+        }
+        .map(&s1.slice, dev, l1)?;
+        let dst = candle::cuda_backend::CudaStorage {
+            slice,
+            device: dev.clone(),
+        };
+        Ok((dst, l1.shape().clone()))
+    }
+
+    #[cfg(feature = "metal")]
+    fn metal_fwd(
+        &self,
+        s1: &candle::MetalStorage,
+        l1: &Layout,
+        s2: &candle::MetalStorage,
+        l2: &Layout,
+    ) -> Result<(candle::MetalStorage, Shape)> {
+        use candle::backend::BackendStorage;
+        let device = s1.device();
+        let command_buffer = device.command_buffer()?;
+        let kernels = device.kernels();
+        let name = match s1.dtype() {
+            DType::F32 => "masked_softmax_f32",
+            DType::F16 => "masked_softmax_f16",
+            DType::BF16 => "masked_softmax_bf16",
+            dtype => candle::bail!("masked-softmax-last-dim is not implemented for {dtype:?}"),
+        };
+        if s2.dtype() != DType::U8 {
+            candle::bail!(
+                "masked-softmax-last-dim expects a u8 mask, got {:?}",
+                s2.dtype()
+            )
+        }
+        let n = l1.stride().len();
+        if !(l1.is_contiguous() && l1.stride()[n - 1] == 1) {
+            candle::bail!("Non contiguous masked-softmax-last-dim is not implemented");
+        }
+
+        let last_dim = l1.dims()[n - 1];
+        let elem_count = l1.shape().elem_count();
+        let output = device.new_buffer(elem_count, s1.dtype(), "masked-softmax")?;
+        candle_metal_kernels::call_masked_softmax(
+            device.metal_device(),
+            &command_buffer,
+            kernels,
+            name,
+            elem_count,
+            last_dim,
+            s1.buffer(),
+            l1.start_offset() * s1.dtype().size_in_bytes(),
+            l2.dims(),
+            l2.stride(),
+            candle_metal_kernels::BufferOffset {
+                buffer: s2.buffer(),
+                offset_in_bytes: l2.start_offset(),
+            },
+            &output,
+        )
+        .map_err(candle::Error::wrap)?;
+        let newstorage = candle::MetalStorage::new(output, device.clone(), elem_count, s1.dtype());
+        Ok((newstorage, l1.shape().clone()))
+    }
+
+    fn bwd(
+        &self,
+        _arg: &Tensor,
+        _mask: &Tensor,
+        res: &Tensor,
+        grad_res: &Tensor,
+    ) -> Result<(Option<Tensor>, Option<Tensor>)> {
+        // Same as for the softmax, the gradient is zero at the masked positions as the output
+        // is zero there.
+        let dot = (res * grad_res)?.sum_keepdim(D::Minus1)?;
+        let grad = (res * grad_res.broadcast_sub(&dot)?)?;
+        Ok((Some(grad), None))
+    }
+}
+
+/// Softmax over the last dimension of `xs` ignoring the positions where `mask` is non-zero,
+/// this is the same as filling these positions with `-inf` before applying `softmax_last_dim`
+/// but without materializing the filled tensor.
+///
+/// `mask` is a u8 tensor that gets broadcast to the shape of `xs`, e.g. a `(seq_len, seq_len)`
+/// causal mask for attention scores of shape `(b, h, seq_len, seq_len)`. The output is zero at
+/// the masked positions, and on rows where all the values are masked. The gradient only flows
+/// back to `xs`.
+pub fn masked_softmax_last_dim(xs: &Tensor, mask: &Tensor) -> Result<Tensor> {
+    if mask.dtype() != DType::U8 {
+        candle::bail!("masked-softmax expects a u8 mask, got {:?}", mask.dtype())
+    }
+    let mask = mask.broadcast_as(xs.shape())?;
+    xs.apply_op2(&mask, MaskedSoftmaxLastDim)
+}
+
+#[derive(Debug, Clone)]
+struct MaskedFill {
+    value: f64,
+}
+
+impl candle::InplaceOp2 for MaskedFill {
+    fn name(&self) -> &'static str {
+        "masked-fill"
+    }
+
+    fn cpu_fwd(
+        &self,
+        s1: &mut CpuStorage,
+        l1: &Layout,
+        s2: &CpuStorage,
+        l2: &Layout,
+    ) -> Result<()> {
+        fn fill<T: candle::WithDType>(
+            dst: &mut [T],
+            layout: &Layout,
+            mask: &[u8],
+            mask_layout: &Layout,
+            value: T,
+        ) -> Result<()> {
+            let dst = match layout.contiguous_offsets() {
+                None => candle::bail!("input has to be contiguous"),
+                Some((o1, o2)) => &mut dst[o1..o2],
+            };
+            let dims = layout.dims();
+            let dim_m1 = dims[dims.len() - 1];
+            let mask_stride = mask_layout.stride()[dims.len() - 1];
+            dst.par_chunks_mut(dim_m1)
+                .enumerate()
+                .for_each(|(row, dst)| {
+                    let offset = mask_row_offset(mask_layout, row);
+                    for (i, d) in dst.iter_mut().enumerate() {
+                        if mask[offset + i * mask_stride] != 0 {
+                            *d = value
+                        }
+                    }
+                });
+            Ok(())
+        }
+
+        let mask = s2.as_slice::<u8>()?;
+        let v = self.value;
+        match s1 {
+            CpuStorage::U8(s) => fill(s, l1, mask, l2, v as u8),
+            CpuStorage::U32(s) => fill(s, l1, mask, l2, v as u32),
+            CpuStorage::I64(s) => fill(s, l1, mask, l2, v as i64),
+            CpuStorage::BF16(s) => fill(s, l1, mask, l2, half::bf16::from_f64(v)),
+            CpuStorage::F16(s) => fill(s, l1, mask, l2, half::f16::from_f64(v)),
+            CpuStorage::F32(s) => fill(s, l1, mask, l2, v as f32),
+            CpuStorage::F64(s) => fill(s, l1, mask, l2, v),
+        }
+    }
+
+    #[cfg(feature = "cuda")]
+    fn cuda_fwd(
+        &self,
+        s1: &mut candle::CudaStorage,
+        l1: &Layout,
+        s2: &candle::CudaStorage,
+        l2: &Layout,
+    ) -> Result<()> {
+        use candle::cuda_backend::cudarc::driver::{
+            CudaSlice, DeviceRepr, LaunchAsync, LaunchConfig,
+        };
+        use candle::cuda_backend::{kernel_name, kernels, CudaStorageSlice as S, WrapErr};
+        use candle::{CudaDevice, WithDType};
+
+        fn fill<T: DeviceRepr + WithDType>(
+            dst: &mut CudaSlice<T>,
+            layout: &Layout,
+            mask: &CudaSlice<u8>,
+            mask_layout: &Layout,
+            value: f64,
+            dev: &CudaDevice,
+        ) -> Result<()> {
+            let (o1, o2) = match layout.contiguous_offsets() {
+                None => candle::bail!("input has to be contiguous"),
+                Some(offsets) => offsets,
+            };
+            let mut dst = dst.slice_mut(o1..o2);
+            let mask = mask.slice(mask_layout.start_offset()..);
+            let info = [mask_layout.dims(), mask_layout.stride()].concat();
+            let info = dev.layout_info(info)?;
+            let el = o2 - o1;
+            let cfg = LaunchConfig::for_num_elems(el as u32);
+            let func = dev.get_or_load_func(&kernel_name::<T>("masked_fill"), kernels::TERNARY)?;
+            let params = (
+                el,
+                mask_layout.dims().len(),
+                &info,
+                &mask,
+                &mut dst,
+                T::from_f64(value),
+            );
+            // SAFETY: ffi.
+            unsafe { func.launch(cfg, params) }.w()?;
+            Ok(())
+        }
+
+        let dev = s1.device.clone();
+        let mask = s2.as_cuda_slice::<u8>()?;
+        let v = self.value;
+        match &mut s1.slice {
+            S::U8(s) => fill(s, l1, mask, l2, v, &dev),
+            S::U32(s) => fill(s, l1, mask, l2, v, &dev),
+            S::I64(s) => fill(s, l1, mask, l2, v, &dev),
+            S::BF16(s) => fill(s, l1, mask, l2, v, &dev),
+            S::F16(s) => fill(s, l1, mask, l2, v, &dev),
+            S::F32(s) => fill(s, l1, mask, l2, v, &dev),
+            S::F64(s) => fill(s, l1, mask, l2, v, &dev),
+        }
+    }
+
+    #[cfg(feature = "metal")]
+    fn metal_fwd(
+        &self,
+        s1: &mut candle::MetalStorage,
+        l1: &Layout,
+        s2: &candle::MetalStorage,
+        l2: &Layout,
+    ) -> Result<()> {
+        use candle::backend::BackendStorage;
+        let device = s1.device();
+        let command_buffer = device.command_buffer()?;
+        let kernels = device.kernels();
+        let name = match s1.dtype() {
+            DType::F32 => "masked_fill_f32",
+            DType::F16 => "masked_fill_f16",
+            DType::BF16 => "masked_fill_bf16",
+            dtype => candle::bail!("masked-fill is not implemented for {dtype:?}"),
+        };
+        if !l1.is_contiguous() {
+            candle::bail!("Non contiguous masked-fill is not implemented");
+        }
+        candle_metal_kernels::call_masked_fill(
+            device.metal_device(),
+            &command_buffer,
+            kernels,
+            name,
+            l2.dims(),
+            l2.stride(),
+            candle_metal_kernels::BufferOffset {
+                buffer: s2.buffer(),
+                offset_in_bytes: l2.start_offset(),
+            },
+            s1.buffer(),
+            l1.start_offset() * s1.dtype().size_in_bytes(),
+            self.value as f32,
+        )
+        .map_err(candle::Error::wrap)
+    }
+}
+
+/// Sets the values of `xs` where `mask` is non-zero to `value`, in place.
+///
+/// `mask` is a u8 tensor that gets broadcast to the shape of `xs` so a small mask can be applied
+/// to a large tensor without materializing the broadcast mask nor a new output. `xs` has to be
+/// contiguous. As the values are overwritten, this returns an error when `xs` is tracked for
+/// backpropagation, `Tensor::where_cond` can be used in this case.
+pub fn masked_fill_(xs: &Tensor, mask: &Tensor, value: f64) -> Result<()> {
+    if mask.dtype() != DType::U8 {
+        candle::bail!("masked-fill expects a u8 mask, got {:?}", mask.dtype())
+    }
+    if xs.track_op() {
+        return Err(candle::Error::BackwardNotSupported { op: "masked-fill" }.bt());
+    }
+    let mask = mask.broadcast_as(xs.shape())?;
+    xs.inplace_op2(&mask, &MaskedFill { value })
+}
+
 #[derive(Debug, Clone)]
 struct RmsNorm {
     eps: f32,
@@ -441,6 +1013,16 @@ impl candle::CustomOp2 for RmsNorm {
         "rms-norm"
     }
 
+    fn meta_fwd(
+        &self,
+        s1: &MetaStorage,
+        l1: &Layout,
+        s2: &MetaStorage,
+        l2: &Layout,
+    ) -> Result<(MetaStorage, Shape)> {
+        Ok(s1.record_like_first(self.name(), l1, &[(s2, l2)]))
+    }
+
     fn cpu_fwd(
         &self,
         s1: &CpuStorage,
@@ -650,6 +1232,18 @@ impl candle::CustomOp3 for LayerNorm {
         "layer-norm"
     }
 
+    fn meta_fwd(
+        &self,
+        s1: &MetaStorage,
+        l1: &Layout,
+        s2: &MetaStorage,
+        l2: &Layout,
+        s3: &MetaStorage,
+        l3: &Layout,
+    ) -> Result<(MetaStorage, Shape)> {
+        Ok(s1.record_like_first(self.name(), l1, &[(s2, l2), (s3, l3)]))
+    }
+
     fn cpu_fwd(
         &self,
         s1: &CpuStorage,
@@ -927,6 +1521,217 @@ pub fn replication_pad2d(xs: &Tensor, pad: usize) -> Result<Tensor> {
     }
 }
 
+// Applies the `(out, in)` matrix `weights` along the dimension `dim` of `xs`.
+fn apply_along_dim(xs: &Tensor, weights: &[f32], out: usize, dim: usize) -> Result<Tensor> {
+    let in_ = xs.dim(dim)?;
+    let weights = Tensor::from_slice(weights, (out, in_), xs.device())?
+        .to_dtype(xs.dtype())?
+        .t()?;
+    let last = xs.rank() - 1;
+    let xs = xs.transpose(dim, last)?.contiguous()?;
+    let mut dims = xs.dims().to_vec();
+    let ys = xs.reshape(((), in_))?.matmul(&weights)?;
+    dims[last] = out;
+    ys.reshape(dims)?.transpose(dim, last)?.contiguous()
+}
+
+// https://pytorch.org/docs/stable/generated/torch.nn.AdaptiveAvgPool2d.html
+/// Average pooling of a `(b, c, h, w)` tensor to the target size. The output element `i` is the
+/// average of the input elements `floor(i * in / out)` to `ceil((i + 1) * in / out)`, so that
+/// the windows may overlap when the input size is not a multiple of the output size.
+pub fn adaptive_avg_pool2d(xs: &Tensor, (out_h, out_w): (usize, usize)) -> Result<Tensor> {
+    let (_b, _c, h, w) = xs.dims4()?;
+    if out_h == 0 || out_w == 0 {
+        candle::bail!("adaptive-avg-pool2d: invalid output size {out_h}x{out_w}")
+    }
+    let weights = |in_: usize, out: usize| {
+        let mut weights = vec![0f32; out * in_];
+        for i in 0..out {
+            let start = i * in_ / out;
+            let end = ((i + 1) * in_).div_ceil(out);
+            for j in start..end {
+                weights[i * in_ + j] = 1. / (end - start) as f32;
+            }
+        }
+        weights
+    };
+    let xs = apply_along_dim(xs, &weights(w, out_w), out_w, 3)?;
+    apply_along_dim(&xs, &weights(h, out_h), out_h, 2)
+}
+
+/// Max pooling of a `(b, c, h, w)` tensor that also returns the argmax indices, the indices are
+/// u32 positions in the flattened `h * w` input planes as used by [`max_unpool2d`].
+pub fn max_pool2d_with_indices<T: candle::ToUsize2>(
+    xs: &Tensor,
+    kernel_size: T,
+    stride: T,
+) -> Result<(Tensor, Tensor)> {
+    let (k_h, k_w) = kernel_size.to_usize2();
+    let (s_h, s_w) = stride.to_usize2();
+    let (b, c, h, w) = xs.dims4()?;
+    if h < k_h || w < k_w {
+        candle::bail!("max-pool2d: kernel {k_h}x{k_w} is larger than the input {h}x{w}")
+    }
+    let (o_h, o_w) = ((h - k_h) / s_h + 1, (w - k_w) / s_w + 1);
+    // The flat input index for each output position and kernel offset.
+    let mut table = Vec::with_capacity(o_h * o_w * k_h * k_w);
+    for i in 0..o_h {
+        for j in 0..o_w {
+            for ki in 0..k_h {
+                for kj in 0..k_w {
+                    table.push(((i * s_h + ki) * w + j * s_w + kj) as u32)
+                }
+            }
+        }
+    }
+    let table = Tensor::from_vec(table, (o_h * o_w, k_h * k_w), xs.device())?;
+    let windows = xs
+        .reshape((b, c, h * w))?
+        .index_select(&table.flatten_all()?, 2)?
+        .reshape((b, c, o_h * o_w, k_h * k_w))?;
+    let values = windows.max_keepdim(3)?;
+    let argmax = windows.argmax_keepdim(3)?;
+    let indices = table
+        .broadcast_as((b, c, o_h * o_w, k_h * k_w))?
+        .contiguous()?
+        .gather(&argmax, 3)?;
+    Ok((
+        values.reshape((b, c, o_h, o_w))?,
+        indices.reshape((b, c, o_h, o_w))?,
+    ))
+}
+
+/// The inverse of [`max_pool2d_with_indices`], the values are put back at the position of the
+/// maxima in a zero tensor with `(out_h, out_w)` planes. Values that share an index are summed,
+/// this can only happen when the pooling windows overlap.
+pub fn max_unpool2d(
+    xs: &Tensor,
+    indices: &Tensor,
+    (out_h, out_w): (usize, usize),
+) -> Result<Tensor> {
+    let (b, c, h, w) = xs.dims4()?;
+    let xs = xs.reshape((b, c, h * w))?;
+    let indices = indices.reshape((b, c, h * w))?;
+    Tensor::zeros((b, c, out_h * out_w), xs.dtype(), xs.device())?
+        .scatter_add(&indices, &xs, 2)?
+        .reshape((b, c, out_h, out_w))
+}
+
+/// The algorithm used by [`interpolate`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterpolateMode {
+    /// Nearest neighbor on any number of spatial dimensions.
+    Nearest,
+    /// Linear interpolation on `(b, c, l)` tensors.
+    Linear,
+    /// Linear interpolation on `(b, c, h, w)` tensors.
+    Bilinear,
+    /// Cubic convolution with `a = -0.75` on `(b, c, h, w)` tensors.
+    Bicubic,
+    /// Linear interpolation on `(b, c, d, h, w)` tensors.
+    Trilinear,
+}
+
+impl InterpolateMode {
+    fn spatial_dims(&self) -> Option<usize> {
+        match self {
+            Self::Nearest => None,
+            Self::Linear => Some(1),
+            Self::Bilinear | Self::Bicubic => Some(2),
+            Self::Trilinear => Some(3),
+        }
+    }
+}
+
+fn cubic_weights(t: f32) -> [f32; 4] {
+    const A: f32 = -0.75;
+    let near = |x: f32| ((A + 2.) * x - (A + 3.)) * x * x + 1.;
+    let far = |x: f32| ((A * x - 5. * A) * x + 8. * A) * x - 4. * A;
+    [far(t + 1.), near(t), near(1. - t), far(2. - t)]
+}
+
+// The `(out, in)` interpolation matrix for a single dimension.
+fn interpolation_weights(
+    in_: usize,
+    out: usize,
+    mode: InterpolateMode,
+    align_corners: bool,
+) -> Vec<f32> {
+    let mut weights = vec![0f32; out * in_];
+    let scale = in_ as f32 / out as f32;
+    for i in 0..out {
+        let row = &mut weights[i * in_..(i + 1) * in_];
+        if mode == InterpolateMode::Nearest {
+            let src = ((i as f32 * scale).floor() as usize).min(in_ - 1);
+            row[src] = 1.;
+            continue;
+        }
+        let src = if align_corners {
+            if out > 1 {
+                i as f32 * (in_ - 1) as f32 / (out - 1) as f32
+            } else {
+                0.
+            }
+        } else {
+            let src = (i as f32 + 0.5) * scale - 0.5;
+            if mode == InterpolateMode::Bicubic {
+                src
+            } else {
+                src.max(0.)
+            }
+        };
+        let i0 = src.floor();
+        let t = src - i0;
+        let i0 = i0 as isize;
+        let clamp = |i: isize| i.clamp(0, in_ as isize - 1) as usize;
+        if mode == InterpolateMode::Bicubic {
+            for (k, weight) in cubic_weights(t).iter().enumerate() {
+                row[clamp(i0 - 1 + k as isize)] += weight;
+            }
+        } else {
+            row[clamp(i0)] += 1. - t;
+            row[clamp(i0 + 1)] += t;
+        }
+    }
+    weights
+}
+
+/// Resizes the spatial dimensions of a `(b, c, *spatial)` tensor to `size`, with the same
+/// semantics as `torch.nn.functional.interpolate`. With `align_corners`, the corner elements of
+/// the input and output are aligned, otherwise the elements are treated as pixel areas.
+/// `align_corners` is not supported for the nearest mode.
+pub fn interpolate(
+    xs: &Tensor,
+    size: &[usize],
+    mode: InterpolateMode,
+    align_corners: bool,
+) -> Result<Tensor> {
+    let rank = xs.rank();
+    if rank != size.len() + 2 || mode.spatial_dims().is_some_and(|d| d != size.len()) {
+        candle::bail!(
+            "interpolate: unexpected size {size:?} for input {:?} with mode {mode:?}",
+            xs.shape()
+        )
+    }
+    if mode == InterpolateMode::Nearest && align_corners {
+        candle::bail!("interpolate: align_corners is not supported with the nearest mode")
+    }
+    let mut xs = xs.clone();
+    for (i, &out) in size.iter().enumerate() {
+        let dim = i + 2;
+        let in_ = xs.dim(dim)?;
+        if in_ == out {
+            continue;
+        }
+        if in_ == 0 || out == 0 {
+            candle::bail!("interpolate: cannot resize from {in_} to {out}")
+        }
+        let weights = interpolation_weights(in_, out, mode, align_corners);
+        xs = apply_along_dim(&xs, &weights, out, dim)?;
+    }
+    Ok(xs)
+}
+
 #[derive(Clone, Debug)]
 pub struct Identity;
 
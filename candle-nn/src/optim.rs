@@ -1,5 +1,212 @@
 //! Various optimization algorithms.
-use candle::{Result, Tensor, Var};
+use candle::backend::BackendStorage;
+use candle::backprop::GradStore;
+use candle::{CpuStorage, DType, Layout, Result, Tensor, Var, WithDType, D};
+use std::collections::HashMap;
+
+/// A set of variables sharing the same optimizer hyper-parameters.
+#[derive(Clone, Debug)]
+pub struct ParamGroup {
+    pub vars: Vec<Var>,
+    /// The learning rate for this group is the optimizer learning rate multiplied by this scale,
+    /// so that learning rate schedules apply to all the groups.
+    pub lr_scale: f64,
+    /// Overrides the optimizer weight decay for this group.
+    pub weight_decay: Option<f64>,
+}
+
+impl ParamGroup {
+    pub fn new(vars: Vec<Var>) -> Self {
+        Self {
+            vars,
+            lr_scale: 1.,
+            weight_decay: None,
+        }
+    }
+
+    pub fn with_lr_scale(mut self, lr_scale: f64) -> Self {
+        self.lr_scale = lr_scale;
+        self
+    }
+
+    pub fn with_weight_decay(mut self, weight_decay: f64) -> Self {
+        self.weight_decay = Some(weight_decay);
+        self
+    }
+
+    fn is_default(&self) -> bool {
+        self.lr_scale == 1. && self.weight_decay.is_none()
+    }
+}
+
+// The per-group hyper-parameters, stored alongside the state of each variable.
+#[derive(Clone, Copy, Debug)]
+struct GroupParams {
+    lr_scale: f64,
+    weight_decay: Option<f64>,
+}
+
+impl GroupParams {
+    fn lr(&self, lr: f64) -> f64 {
+        lr * self.lr_scale
+    }
+
+    fn weight_decay(&self, weight_decay: f64) -> f64 {
+        self.weight_decay.unwrap_or(weight_decay)
+    }
+}
+
+// Creates the state for all the float variables of the groups.
+fn group_states<S, F>(groups: Vec<ParamGroup>, mut f: F) -> Result<Vec<S>>
+where
+    F: FnMut(Var, GroupParams) -> Result<S>,
+{
+    let mut states = vec![];
+    for group in groups {
+        let params = GroupParams {
+            lr_scale: group.lr_scale,
+            weight_decay: group.weight_decay,
+        };
+        for var in group.vars {
+            if var.dtype().is_float() {
+                states.push(f(var, params)?)
+            }
+        }
+    }
+    Ok(states)
+}
+
+/// Scales the gradients of `vars` in place so that their global l2 norm is at most `max_norm`.
+///
+/// The norm is computed over the gradients of all the variables as if they were concatenated in
+/// a single vector. The norm before clipping is returned as a f32 scalar tensor, the whole
+/// computation happens on the device so that no synchronization is required.
+pub fn clip_grad_norm(vars: &[Var], grads: &mut GradStore, max_norm: f64) -> Result<Tensor> {
+    let mut sum_sqr: Option<Tensor> = None;
+    for var in vars.iter() {
+        if let Some(g) = grads.get(var) {
+            let s = g.to_dtype(DType::F32)?.sqr()?.sum_all()?;
+            sum_sqr = Some(match sum_sqr {
+                None => s,
+                Some(acc) => (&acc + s.to_device(acc.device())?)?,
+            })
+        }
+    }
+    let norm = match sum_sqr {
+        None => return Tensor::new(0f32, &candle::Device::Cpu),
+        Some(s) => s.sqrt()?,
+    };
+    let scale = ((norm.clone() + 1e-6)?.recip()? * max_norm)?.minimum(1f64)?;
+    for var in vars.iter() {
+        if let Some(g) = grads.get(var) {
+            let scale = scale.to_device(g.device())?.to_dtype(g.dtype())?;
+            let g = g.broadcast_mul(&scale)?;
+            grads.insert(var, g);
+        }
+    }
+    Ok(norm)
+}
+
+// The name of the step counter in the optimizer state.
+const STEP_KEY: &str = "step_t";
+
+fn step_state(step_t: usize) -> Result<Tensor> {
+    Tensor::new(step_t as u32, &candle::Device::Cpu)
+}
+
+fn load_step(state: &HashMap<String, Tensor>) -> Result<usize> {
+    match state.get(STEP_KEY) {
+        None => candle::bail!("missing {STEP_KEY} in optimizer state"),
+        Some(t) => Ok(t.to_dtype(DType::U32)?.to_scalar::<u32>()? as usize),
+    }
+}
+
+// Sets `var` to the value of `name` in the optimizer state.
+fn load_var(state: &HashMap<String, Tensor>, name: &str, var: &Var) -> Result<()> {
+    let value = match state.get(name) {
+        None => candle::bail!("missing {name} in optimizer state"),
+        Some(value) => value,
+    };
+    if value.shape() != var.shape() {
+        candle::bail!(
+            "shape mismatch for {name} in optimizer state: {:?} <> {:?}",
+            value.shape(),
+            var.shape()
+        )
+    }
+    // The copy ensures that the value does not share its storage with the variable.
+    var.set(
+        &value
+            .to_device(var.device())?
+            .to_dtype(var.dtype())?
+            .copy()?,
+    )
+}
+
+/// The version of the format used by `save_state`, files with a more recent version are
+/// rejected by `load_state`.
+pub const STATE_FORMAT_VERSION: u32 = 1;
+const STATE_FORMAT: &str = "candle-optimizer-state";
+
+// The optimizer name recorded in the state files, e.g. `AdamW`.
+pub(crate) fn optimizer_name<O: ?Sized>() -> &'static str {
+    let name = std::any::type_name::<O>();
+    name.rsplit("::").next().unwrap_or(name)
+}
+
+/// Saves an optimizer state in the safetensors format.
+///
+/// The file metadata records the format version and the optimizer type, `metadata` can be used
+/// to store additional values, e.g. the progress of the training loop or the seed of a random
+/// number generator.
+pub fn save_state<P: AsRef<std::path::Path>>(
+    state: &HashMap<String, Tensor>,
+    optimizer: &str,
+    metadata: &HashMap<String, String>,
+    path: P,
+) -> Result<()> {
+    let mut metadata = metadata.clone();
+    metadata.insert("format".to_string(), STATE_FORMAT.to_string());
+    metadata.insert(
+        "format_version".to_string(),
+        STATE_FORMAT_VERSION.to_string(),
+    );
+    metadata.insert("optimizer".to_string(), optimizer.to_string());
+    safetensors::tensor::serialize_to_file(state, &Some(metadata), path.as_ref())?;
+    Ok(())
+}
+
+/// Loads an optimizer state saved with `save_state`, returns the tensors and the file
+/// metadata. When `optimizer` is set, the file has to have been written for this optimizer type.
+pub fn load_state<P: AsRef<std::path::Path>>(
+    path: P,
+    optimizer: Option<&str>,
+) -> Result<(HashMap<String, Tensor>, HashMap<String, String>)> {
+    let path = path.as_ref();
+    let data = std::fs::read(path)?;
+    let (_, st_metadata) = safetensors::SafeTensors::read_metadata(&data)?;
+    let metadata = st_metadata.metadata().clone().unwrap_or_default();
+    if metadata.get("format").map(|s| s.as_str()) != Some(STATE_FORMAT) {
+        candle::bail!("{path:?} is not an optimizer state file")
+    }
+    let version = metadata
+        .get("format_version")
+        .and_then(|v| v.parse::<u32>().ok());
+    match version {
+        Some(version) if version <= STATE_FORMAT_VERSION => {}
+        _ => candle::bail!(
+            "unsupported optimizer state version {version:?} in {path:?}, expected at most {STATE_FORMAT_VERSION}"
+        ),
+    }
+    if let Some(optimizer) = optimizer {
+        let saved = metadata.get("optimizer").map(|s| s.as_str());
+        if saved != Some(optimizer) {
+            candle::bail!("{path:?} contains the state of {saved:?}, expected {optimizer}")
+        }
+    }
+    let state = candle::safetensors::load_buffer(&data, &candle::Device::Cpu)?;
+    Ok((state, metadata))
+}
 
 /// The interface optimizers should implement.
 pub trait Optimizer: Sized {
@@ -7,6 +214,17 @@ pub trait Optimizer: Sized {
 
     fn new(vars: Vec<Var>, config: Self::Config) -> Result<Self>;
 
+    /// Creates an optimizer where each group of variables can use its own learning rate scale and
+    /// weight decay. Optimizers that do not support groups only accept groups using the default
+    /// hyper-parameters.
+    fn from_groups(groups: Vec<ParamGroup>, config: Self::Config) -> Result<Self> {
+        if groups.iter().any(|g| !g.is_default()) {
+            candle::bail!("this optimizer does not support parameter groups")
+        }
+        let vars = groups.into_iter().flat_map(|g| g.vars).collect();
+        Self::new(vars, config)
+    }
+
     fn step(&mut self, grads: &candle::backprop::GradStore) -> Result<()>;
 
     fn learning_rate(&self) -> f64;
@@ -26,6 +244,40 @@ pub trait Optimizer: Sized {
         let vars: Vec<_> = vars.iter().map(|&v| v.clone()).collect();
         Self::new(vars, config)
     }
+
+    /// Returns the internal state of the optimizer, e.g. the moments of each variable and the
+    /// step counter, so that training can be resumed. The variables are identified by their
+    /// position so the state can only be restored in an optimizer created with the same
+    /// variables in the same order.
+    fn state(&self) -> Result<HashMap<String, Tensor>> {
+        Ok(HashMap::new())
+    }
+
+    /// Restores the internal state returned by `state`.
+    fn set_state(&mut self, state: &HashMap<String, Tensor>) -> Result<()> {
+        if !state.is_empty() {
+            candle::bail!("this optimizer does not have any state")
+        }
+        Ok(())
+    }
+
+    /// Saves the internal state, e.g. the moments and step counter, so that training can be
+    /// resumed exactly with `load`, see `save_state`.
+    fn save<P: AsRef<std::path::Path>>(&self, path: P) -> Result<()> {
+        save_state(
+            &self.state()?,
+            optimizer_name::<Self>(),
+            &HashMap::new(),
+            path,
+        )
+    }
+
+    /// Loads the internal state saved with `save`, the file has to have been written by the same
+    /// optimizer type for the same variables.
+    fn load<P: AsRef<std::path::Path>>(&mut self, path: P) -> Result<()> {
+        let (state, _) = load_state(path, Some(optimizer_name::<Self>()))?;
+        self.set_state(&state)
+    }
 }
 
 /// Optimizer for Stochastic Gradient Descent.
@@ -105,6 +357,7 @@ struct VarAdamW {
     var: Var,
     first_moment: Var,
     second_moment: Var,
+    group: GroupParams,
 }
 
 #[derive(Debug)]
@@ -118,22 +371,23 @@ impl Optimizer for AdamW {
     type Config = ParamsAdamW;
 
     fn new(vars: Vec<Var>, params: ParamsAdamW) -> Result<Self> {
-        let vars = vars
-            .into_iter()
-            .filter(|var| var.dtype().is_float())
-            .map(|var| {
-                let dtype = var.dtype();
-                let shape = var.shape();
-                let device = var.device();
-                let first_moment = Var::zeros(shape, dtype, device)?;
-                let second_moment = Var::zeros(shape, dtype, device)?;
-                Ok(VarAdamW {
-                    var,
-                    first_moment,
-                    second_moment,
-                })
+        Self::from_groups(vec![ParamGroup::new(vars)], params)
+    }
+
+    fn from_groups(groups: Vec<ParamGroup>, params: ParamsAdamW) -> Result<Self> {
+        let vars = group_states(groups, |var, group| {
+            let dtype = var.dtype();
+            let shape = var.shape();
+            let device = var.device();
+            let first_moment = Var::zeros(shape, dtype, device)?;
+            let second_moment = Var::zeros(shape, dtype, device)?;
+            Ok(VarAdamW {
+                var,
+                first_moment,
+                second_moment,
+                group,
             })
-            .collect::<Result<Vec<_>>>()?;
+        })?;
         Ok(Self {
             vars,
             params,
@@ -151,9 +405,6 @@ impl Optimizer for AdamW {
 
     fn step(&mut self, grads: &candle::backprop::GradStore) -> Result<()> {
         self.step_t += 1;
-        let lr = self.params.lr;
-        let lambda = self.params.weight_decay;
-        let lr_lambda = lr * lambda;
         let beta1 = self.params.beta1;
         let beta2 = self.params.beta2;
         let scale_m = 1f64 / (1f64 - beta1.powi(self.step_t as i32));
@@ -162,7 +413,31 @@ impl Optimizer for AdamW {
             let theta = &var.var;
             let m = &var.first_moment;
             let v = &var.second_moment;
+            let lr = var.group.lr(self.params.lr);
+            let lr_lambda = lr * var.group.weight_decay(self.params.weight_decay);
             if let Some(g) = grads.get(theta) {
+                if use_fused_adamw(theta) {
+                    let g = g.contiguous()?;
+                    let moment_m = AdamWMoment {
+                        beta: beta1,
+                        square: false,
+                    };
+                    let moment_v = AdamWMoment {
+                        beta: beta2,
+                        square: true,
+                    };
+                    m.inplace_op2(&g, &moment_m)?;
+                    v.inplace_op2(&g, &moment_v)?;
+                    let update = AdamWUpdate {
+                        lr,
+                        lr_lambda,
+                        scale_m,
+                        scale_v,
+                        eps: self.params.eps,
+                    };
+                    theta.inplace_op3(m, v, &update)?;
+                    continue;
+                }
                 // This involves locking 3 RWLocks per params, if the parameters are large this
                 // should not be an issue but this may be problematic with models with lots of
                 // small parameters.
@@ -180,6 +455,266 @@ impl Optimizer for AdamW {
         }
         Ok(())
     }
+
+    fn state(&self) -> Result<HashMap<String, Tensor>> {
+        let mut state = HashMap::new();
+        for (i, var) in self.vars.iter().enumerate() {
+            let m = var.first_moment.as_tensor().clone();
+            let v = var.second_moment.as_tensor().clone();
+            state.insert(format!("{i}.first_moment"), m);
+            state.insert(format!("{i}.second_moment"), v);
+        }
+        state.insert(STEP_KEY.to_string(), step_state(self.step_t)?);
+        Ok(state)
+    }
+
+    fn set_state(&mut self, state: &HashMap<String, Tensor>) -> Result<()> {
+        for (i, var) in self.vars.iter().enumerate() {
+            load_var(state, &format!("{i}.first_moment"), &var.first_moment)?;
+            load_var(state, &format!("{i}.second_moment"), &var.second_moment)?;
+        }
+        self.step_t = load_step(state)?;
+        Ok(())
+    }
+}
+
+// The AdamW updates on cuda use fused kernels from `candle-kernels`: one for each moment and one
+// for the weights, rather than a dozen of tensor ops and temporary buffers per variable. The
+// other devices use the tensor ops.
+fn use_fused_adamw(var: &Var) -> bool {
+    var.device().is_cuda() && var.is_contiguous() && var.dtype().is_float()
+}
+
+// The offsets of the contiguous tensors used by the fused updates, which all have the same
+// number of elements.
+fn fused_offsets<const N: usize>(name: &str, layouts: [&Layout; N]) -> Result<[(usize, usize); N]> {
+    let mut offsets = [(0, 0); N];
+    for (o, l) in offsets.iter_mut().zip(layouts.iter()) {
+        *o = match l.contiguous_offsets() {
+            Some(offsets) => offsets,
+            None => candle::bail!("{name} requires contiguous tensors"),
+        };
+    }
+    let el = layouts[0].shape().elem_count();
+    if layouts.iter().any(|l| l.shape().elem_count() != el) {
+        candle::bail!("{name} requires tensors with the same number of elements")
+    }
+    Ok(offsets)
+}
+
+// acc = beta * acc + (1 - beta) * g, or g^2 when square is set.
+struct AdamWMoment {
+    beta: f64,
+    square: bool,
+}
+
+impl candle::InplaceOp2 for AdamWMoment {
+    fn name(&self) -> &'static str {
+        "adamw-moment"
+    }
+
+    fn cpu_fwd(
+        &self,
+        s1: &mut CpuStorage,
+        l1: &Layout,
+        s2: &CpuStorage,
+        l2: &Layout,
+    ) -> Result<()> {
+        fn moment<T: WithDType>(
+            acc: &mut [T],
+            g: &[T],
+            offsets: [(usize, usize); 2],
+            op: &AdamWMoment,
+        ) -> Result<()> {
+            let [(a1, a2), (g1, g2)] = offsets;
+            for (a, g) in acc[a1..a2].iter_mut().zip(g[g1..g2].iter()) {
+                let g = g.to_f64();
+                let g = if op.square { g * g } else { g };
+                *a = T::from_f64(op.beta * a.to_f64() + (1. - op.beta) * g)
+            }
+            Ok(())
+        }
+
+        let offsets = fused_offsets(self.name(), [l1, l2])?;
+        match (s1, s2) {
+            (CpuStorage::BF16(a), CpuStorage::BF16(g)) => moment(a, g, offsets, self),
+            (CpuStorage::F16(a), CpuStorage::F16(g)) => moment(a, g, offsets, self),
+            (CpuStorage::F32(a), CpuStorage::F32(g)) => moment(a, g, offsets, self),
+            (CpuStorage::F64(a), CpuStorage::F64(g)) => moment(a, g, offsets, self),
+            (s1, _) => Err(candle::Error::UnsupportedDTypeForOp(
+                s1.dtype(),
+                self.name(),
+            )),
+        }
+    }
+
+    #[cfg(feature = "cuda")]
+    fn cuda_fwd(
+        &self,
+        s1: &mut candle::CudaStorage,
+        l1: &Layout,
+        s2: &candle::CudaStorage,
+        l2: &Layout,
+    ) -> Result<()> {
+        use candle::cuda_backend::cudarc::driver::{
+            CudaSlice, DeviceRepr, LaunchAsync, LaunchConfig,
+        };
+        use candle::cuda_backend::{kernel_name, kernels, CudaStorageSlice as S, WrapErr};
+        use candle::CudaDevice;
+
+        fn moment<T: DeviceRepr + WithDType>(
+            acc: &mut CudaSlice<T>,
+            g: &CudaSlice<T>,
+            offsets: [(usize, usize); 2],
+            op: &AdamWMoment,
+            dev: &CudaDevice,
+        ) -> Result<()> {
+            let [(a1, a2), (g1, g2)] = offsets;
+            let mut acc = acc.slice_mut(a1..a2);
+            let g = g.slice(g1..g2);
+            let el = a2 - a1;
+            let cfg = LaunchConfig::for_num_elems(el as u32);
+            let func = dev.get_or_load_func(&kernel_name::<T>("adamw_moment"), kernels::OPTIM)?;
+            let params = (el, &mut acc, &g, op.beta, op.square);
+            // SAFETY: ffi.
+            unsafe { func.launch(cfg, params) }.w()?;
+            Ok(())
+        }
+
+        let offsets = fused_offsets(self.name(), [l1, l2])?;
+        let dev = s1.device.clone();
+        let dtype = s1.dtype();
+        match (&mut s1.slice, &s2.slice) {
+            (S::BF16(a), S::BF16(g)) => moment(a, g, offsets, self, &dev),
+            (S::F16(a), S::F16(g)) => moment(a, g, offsets, self, &dev),
+            (S::F32(a), S::F32(g)) => moment(a, g, offsets, self, &dev),
+            (S::F64(a), S::F64(g)) => moment(a, g, offsets, self, &dev),
+            _ => Err(candle::Error::UnsupportedDTypeForOp(dtype, self.name())),
+        }
+    }
+}
+
+// theta = theta * (1 - lr_lambda) - lr * m_hat / (sqrt(v_hat) + eps), with the bias corrected
+// moments m_hat = m * scale_m and v_hat = v * scale_v.
+struct AdamWUpdate {
+    lr: f64,
+    lr_lambda: f64,
+    scale_m: f64,
+    scale_v: f64,
+    eps: f64,
+}
+
+impl candle::InplaceOp3 for AdamWUpdate {
+    fn name(&self) -> &'static str {
+        "adamw-update"
+    }
+
+    fn cpu_fwd(
+        &self,
+        s1: &mut CpuStorage,
+        l1: &Layout,
+        s2: &CpuStorage,
+        l2: &Layout,
+        s3: &CpuStorage,
+        l3: &Layout,
+    ) -> Result<()> {
+        fn update<T: WithDType>(
+            theta: &mut [T],
+            m: &[T],
+            v: &[T],
+            offsets: [(usize, usize); 3],
+            op: &AdamWUpdate,
+        ) -> Result<()> {
+            let [(t1, t2), (m1, m2), (v1, v2)] = offsets;
+            let mv = m[m1..m2].iter().zip(v[v1..v2].iter());
+            for (theta, (m, v)) in theta[t1..t2].iter_mut().zip(mv) {
+                let m_hat = m.to_f64() * op.scale_m;
+                let v_hat = v.to_f64() * op.scale_v;
+                let step = m_hat / (v_hat.sqrt() + op.eps);
+                *theta = T::from_f64(theta.to_f64() * (1. - op.lr_lambda) - op.lr * step)
+            }
+            Ok(())
+        }
+
+        let offsets = fused_offsets(self.name(), [l1, l2, l3])?;
+        match (s1, s2, s3) {
+            (CpuStorage::BF16(t), CpuStorage::BF16(m), CpuStorage::BF16(v)) => {
+                update(t, m, v, offsets, self)
+            }
+            (CpuStorage::F16(t), CpuStorage::F16(m), CpuStorage::F16(v)) => {
+                update(t, m, v, offsets, self)
+            }
+            (CpuStorage::F32(t), CpuStorage::F32(m), CpuStorage::F32(v)) => {
+                update(t, m, v, offsets, self)
+            }
+            (CpuStorage::F64(t), CpuStorage::F64(m), CpuStorage::F64(v)) => {
+                update(t, m, v, offsets, self)
+            }
+            (s1, _, _) => Err(candle::Error::UnsupportedDTypeForOp(
+                s1.dtype(),
+                self.name(),
+            )),
+        }
+    }
+
+    #[cfg(feature = "cuda")]
+    fn cuda_fwd(
+        &self,
+        s1: &mut candle::CudaStorage,
+        l1: &Layout,
+        s2: &candle::CudaStorage,
+        l2: &Layout,
+        s3: &candle::CudaStorage,
+        l3: &Layout,
+    ) -> Result<()> {
+        use candle::cuda_backend::cudarc::driver::{
+            CudaSlice, DeviceRepr, LaunchAsync, LaunchConfig,
+        };
+        use candle::cuda_backend::{kernel_name, kernels, CudaStorageSlice as S, WrapErr};
+        use candle::CudaDevice;
+
+        fn update<T: DeviceRepr + WithDType>(
+            theta: &mut CudaSlice<T>,
+            m: &CudaSlice<T>,
+            v: &CudaSlice<T>,
+            offsets: [(usize, usize); 3],
+            op: &AdamWUpdate,
+            dev: &CudaDevice,
+        ) -> Result<()> {
+            let [(t1, t2), (m1, m2), (v1, v2)] = offsets;
+            let mut theta = theta.slice_mut(t1..t2);
+            let m = m.slice(m1..m2);
+            let v = v.slice(v1..v2);
+            let el = t2 - t1;
+            let cfg = LaunchConfig::for_num_elems(el as u32);
+            let func = dev.get_or_load_func(&kernel_name::<T>("adamw_update"), kernels::OPTIM)?;
+            let params = (
+                el,
+                &mut theta,
+                &m,
+                &v,
+                op.lr,
+                op.lr_lambda,
+                op.scale_m,
+                op.scale_v,
+                op.eps,
+            );
+            // SAFETY: ffi.
+            unsafe { func.launch(cfg, params) }.w()?;
+            Ok(())
+        }
+
+        let offsets = fused_offsets(self.name(), [l1, l2, l3])?;
+        let dev = s1.device.clone();
+        let dtype = s1.dtype();
+        match (&mut s1.slice, &s2.slice, &s3.slice) {
+            (S::BF16(t), S::BF16(m), S::BF16(v)) => update(t, m, v, offsets, self, &dev),
+            (S::F16(t), S::F16(m), S::F16(v)) => update(t, m, v, offsets, self, &dev),
+            (S::F32(t), S::F32(m), S::F32(v)) => update(t, m, v, offsets, self, &dev),
+            (S::F64(t), S::F64(m), S::F64(v)) => update(t, m, v, offsets, self, &dev),
+            _ => Err(candle::Error::UnsupportedDTypeForOp(dtype, self.name())),
+        }
+    }
 }
 
 impl AdamW {
@@ -199,3 +734,505 @@ impl AdamW {
         self.params = params;
     }
 }
+
+#[derive(Clone, Debug)]
+pub struct ParamsLion {
+    pub lr: f64,
+    pub beta1: f64,
+    pub beta2: f64,
+    pub weight_decay: f64,
+}
+
+impl Default for ParamsLion {
+    fn default() -> Self {
+        Self {
+            lr: 1e-4,
+            beta1: 0.9,
+            beta2: 0.99,
+            weight_decay: 0.,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct VarLion {
+    var: Var,
+    momentum: Var,
+    group: GroupParams,
+}
+
+/// The Lion optimizer, see "Symbolic Discovery of Optimization Algorithms"
+/// <https://arxiv.org/abs/2302.06675>.
+///
+/// The update only uses the sign of an interpolation between the momentum and the gradient so
+/// all the coordinates move by the same amount, the learning rate is typically 3-10x smaller than
+/// for AdamW.
+#[derive(Debug)]
+pub struct Lion {
+    vars: Vec<VarLion>,
+    params: ParamsLion,
+}
+
+impl Optimizer for Lion {
+    type Config = ParamsLion;
+
+    fn new(vars: Vec<Var>, params: ParamsLion) -> Result<Self> {
+        Self::from_groups(vec![ParamGroup::new(vars)], params)
+    }
+
+    fn from_groups(groups: Vec<ParamGroup>, params: ParamsLion) -> Result<Self> {
+        let vars = group_states(groups, |var, group| {
+            let momentum = Var::zeros(var.shape(), var.dtype(), var.device())?;
+            Ok(VarLion {
+                var,
+                momentum,
+                group,
+            })
+        })?;
+        Ok(Self { vars, params })
+    }
+
+    fn learning_rate(&self) -> f64 {
+        self.params.lr
+    }
+
+    fn set_learning_rate(&mut self, lr: f64) {
+        self.params.lr = lr
+    }
+
+    fn step(&mut self, grads: &GradStore) -> Result<()> {
+        let beta1 = self.params.beta1;
+        let beta2 = self.params.beta2;
+        for var in self.vars.iter() {
+            let theta = &var.var;
+            let m = &var.momentum;
+            if let Some(g) = grads.get(theta) {
+                let lr = var.group.lr(self.params.lr);
+                let lambda = var.group.weight_decay(self.params.weight_decay);
+                let update = ((m.as_tensor() * beta1)? + (g * (1. - beta1))?)?.sign()?;
+                let next_theta = ((theta.as_tensor() * (1. - lr * lambda))? - (update * lr)?)?;
+                let next_m = ((m.as_tensor() * beta2)? + (g * (1. - beta2))?)?;
+                m.set(&next_m)?;
+                theta.set(&next_theta)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn state(&self) -> Result<HashMap<String, Tensor>> {
+        let state = self
+            .vars
+            .iter()
+            .enumerate()
+            .map(|(i, var)| (format!("{i}.momentum"), var.momentum.as_tensor().clone()))
+            .collect();
+        Ok(state)
+    }
+
+    fn set_state(&mut self, state: &HashMap<String, Tensor>) -> Result<()> {
+        for (i, var) in self.vars.iter().enumerate() {
+            load_var(state, &format!("{i}.momentum"), &var.momentum)?;
+        }
+        Ok(())
+    }
+}
+
+impl Lion {
+    pub fn new_lr(vars: Vec<Var>, learning_rate: f64) -> Result<Self> {
+        let params = ParamsLion {
+            lr: learning_rate,
+            ..ParamsLion::default()
+        };
+        Self::new(vars, params)
+    }
+
+    pub fn params(&self) -> &ParamsLion {
+        &self.params
+    }
+
+    pub fn set_params(&mut self, params: ParamsLion) {
+        self.params = params;
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ParamsLamb {
+    pub lr: f64,
+    pub beta1: f64,
+    pub beta2: f64,
+    pub eps: f64,
+    pub weight_decay: f64,
+}
+
+impl Default for ParamsLamb {
+    fn default() -> Self {
+        Self {
+            lr: 0.001,
+            beta1: 0.9,
+            beta2: 0.999,
+            eps: 1e-6,
+            weight_decay: 0.01,
+        }
+    }
+}
+
+/// The LAMB optimizer, see "Large Batch Optimization for Deep Learning: Training BERT in 76
+/// minutes" <https://arxiv.org/abs/1904.00962>.
+///
+/// The AdamW update of each variable is rescaled by the ratio between the norm of the variable
+/// and the norm of the update, which keeps training stable with very large batch sizes.
+#[derive(Debug)]
+pub struct Lamb {
+    vars: Vec<VarAdamW>,
+    step_t: usize,
+    params: ParamsLamb,
+}
+
+impl Optimizer for Lamb {
+    type Config = ParamsLamb;
+
+    fn new(vars: Vec<Var>, params: ParamsLamb) -> Result<Self> {
+        Self::from_groups(vec![ParamGroup::new(vars)], params)
+    }
+
+    fn from_groups(groups: Vec<ParamGroup>, params: ParamsLamb) -> Result<Self> {
+        let vars = group_states(groups, |var, group| {
+            let first_moment = Var::zeros(var.shape(), var.dtype(), var.device())?;
+            let second_moment = Var::zeros(var.shape(), var.dtype(), var.device())?;
+            Ok(VarAdamW {
+                var,
+                first_moment,
+                second_moment,
+                group,
+            })
+        })?;
+        Ok(Self {
+            vars,
+            params,
+            step_t: 0,
+        })
+    }
+
+    fn learning_rate(&self) -> f64 {
+        self.params.lr
+    }
+
+    fn set_learning_rate(&mut self, lr: f64) {
+        self.params.lr = lr
+    }
+
+    fn step(&mut self, grads: &GradStore) -> Result<()> {
+        self.step_t += 1;
+        let beta1 = self.params.beta1;
+        let beta2 = self.params.beta2;
+        let scale_m = 1f64 / (1f64 - beta1.powi(self.step_t as i32));
+        let scale_v = 1f64 / (1f64 - beta2.powi(self.step_t as i32));
+        for var in self.vars.iter() {
+            let theta = &var.var;
+            let m = &var.first_moment;
+            let v = &var.second_moment;
+            if let Some(g) = grads.get(theta) {
+                let lr = var.group.lr(self.params.lr);
+                let lambda = var.group.weight_decay(self.params.weight_decay);
+                let next_m = ((m.as_tensor() * beta1)? + (g * (1.0 - beta1))?)?;
+                let next_v = ((v.as_tensor() * beta2)? + (g.sqr()? * (1.0 - beta2))?)?;
+                let m_hat = (&next_m * scale_m)?;
+                let v_hat = (&next_v * scale_v)?;
+                let update = (m_hat / (v_hat.sqrt()? + self.params.eps)?)?;
+                let update = (update + (theta.as_tensor() * lambda)?)?;
+                // The trust ratio falls back to 1 when one of the norms is zero.
+                let w_norm = theta.sqr()?.sum_all()?.sqrt()?;
+                let u_norm = update.sqr()?.sum_all()?.sqrt()?;
+                let valid = (w_norm.gt(0.)? * u_norm.gt(0.)?)?;
+                let ratio = valid.where_cond(&(&w_norm / &u_norm)?, &w_norm.ones_like()?)?;
+                let next_theta = (theta.as_tensor() - update.broadcast_mul(&(ratio * lr)?)?)?;
+                m.set(&next_m)?;
+                v.set(&next_v)?;
+                theta.set(&next_theta)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn state(&self) -> Result<HashMap<String, Tensor>> {
+        let mut state = HashMap::new();
+        for (i, var) in self.vars.iter().enumerate() {
+            let m = var.first_moment.as_tensor().clone();
+            let v = var.second_moment.as_tensor().clone();
+            state.insert(format!("{i}.first_moment"), m);
+            state.insert(format!("{i}.second_moment"), v);
+        }
+        state.insert(STEP_KEY.to_string(), step_state(self.step_t)?);
+        Ok(state)
+    }
+
+    fn set_state(&mut self, state: &HashMap<String, Tensor>) -> Result<()> {
+        for (i, var) in self.vars.iter().enumerate() {
+            load_var(state, &format!("{i}.first_moment"), &var.first_moment)?;
+            load_var(state, &format!("{i}.second_moment"), &var.second_moment)?;
+        }
+        self.step_t = load_step(state)?;
+        Ok(())
+    }
+}
+
+impl Lamb {
+    pub fn new_lr(vars: Vec<Var>, learning_rate: f64) -> Result<Self> {
+        let params = ParamsLamb {
+            lr: learning_rate,
+            ..ParamsLamb::default()
+        };
+        Self::new(vars, params)
+    }
+
+    pub fn params(&self) -> &ParamsLamb {
+        &self.params
+    }
+
+    pub fn set_params(&mut self, params: ParamsLamb) {
+        self.params = params;
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ParamsAdafactor {
+    /// The external learning rate, when `None` the relative step size `min(1e-2, 1/sqrt(t))` is
+    /// used.
+    pub lr: Option<f64>,
+    /// Regularization constant added to the squared gradients.
+    pub eps1: f64,
+    /// Lower bound for the parameter scale.
+    pub eps2: f64,
+    /// The updates are rescaled so that their root mean square is at most this threshold.
+    pub clip_threshold: f64,
+    /// The exponent used to compute the running average decay, `1 - t^decay_rate`.
+    pub decay_rate: f64,
+    /// When set, a first moment is maintained with this decay.
+    pub beta1: Option<f64>,
+    pub weight_decay: f64,
+    /// Scale the step size by the root mean square of the variable.
+    pub scale_parameter: bool,
+    /// Use a linear warmup for the relative step size.
+    pub warmup_init: bool,
+}
+
+impl Default for ParamsAdafactor {
+    fn default() -> Self {
+        Self {
+            lr: None,
+            eps1: 1e-30,
+            eps2: 1e-3,
+            clip_threshold: 1.0,
+            decay_rate: -0.8,
+            beta1: None,
+            weight_decay: 0.,
+            scale_parameter: true,
+            warmup_init: false,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum SecondMoment {
+    // The row and column statistics for variables with at least two dimensions.
+    Factored { row: Var, col: Var },
+    Full(Var),
+}
+
+#[derive(Debug)]
+struct VarAdafactor {
+    var: Var,
+    first_moment: Option<Var>,
+    second_moment: SecondMoment,
+    group: GroupParams,
+}
+
+/// The Adafactor optimizer, see "Adafactor: Adaptive Learning Rates with Sublinear Memory Cost"
+/// <https://arxiv.org/abs/1804.04235>.
+///
+/// For variables with two or more dimensions, the second moment is factored in row and column
+/// statistics over the last two dimensions, which makes the optimizer state much smaller than for
+/// Adam. The default parameters match the HuggingFace transformers implementation.
+#[derive(Debug)]
+pub struct Adafactor {
+    vars: Vec<VarAdafactor>,
+    step_t: usize,
+    params: ParamsAdafactor,
+}
+
+fn rms(xs: &Tensor) -> Result<Tensor> {
+    xs.sqr()?.mean_all()?.sqrt()
+}
+
+impl Optimizer for Adafactor {
+    type Config = ParamsAdafactor;
+
+    fn new(vars: Vec<Var>, params: ParamsAdafactor) -> Result<Self> {
+        Self::from_groups(vec![ParamGroup::new(vars)], params)
+    }
+
+    fn from_groups(groups: Vec<ParamGroup>, params: ParamsAdafactor) -> Result<Self> {
+        if params.lr.is_none() && groups.iter().any(|g| g.lr_scale != 1.) {
+            candle::bail!("adafactor: lr scales require an external learning rate")
+        }
+        let vars = group_states(groups, |var, group| {
+            let (dtype, device) = (var.dtype(), var.device());
+            let first_moment = match params.beta1 {
+                None => None,
+                Some(_) => Some(Var::zeros(var.shape(), dtype, device)?),
+            };
+            let dims = var.dims();
+            let second_moment = if dims.len() >= 2 {
+                let n = dims.len();
+                let row = Var::zeros(&dims[..n - 1], dtype, device)?;
+                let mut col_dims = dims[..n - 2].to_vec();
+                col_dims.push(dims[n - 1]);
+                let col = Var::zeros(col_dims, dtype, device)?;
+                SecondMoment::Factored { row, col }
+            } else {
+                SecondMoment::Full(Var::zeros(var.shape(), dtype, device)?)
+            };
+            Ok(VarAdafactor {
+                var,
+                first_moment,
+                second_moment,
+                group,
+            })
+        })?;
+        Ok(Self {
+            vars,
+            params,
+            step_t: 0,
+        })
+    }
+
+    /// The external learning rate, or the relative step size for the next step if no external
+    /// learning rate is set.
+    fn learning_rate(&self) -> f64 {
+        self.rho(self.step_t + 1)
+    }
+
+    fn set_learning_rate(&mut self, lr: f64) {
+        self.params.lr = Some(lr)
+    }
+
+    fn step(&mut self, grads: &GradStore) -> Result<()> {
+        self.step_t += 1;
+        let p = &self.params;
+        let rho = self.rho(self.step_t);
+        let beta2t = 1. - (self.step_t as f64).powf(p.decay_rate);
+        for var in self.vars.iter() {
+            let theta = &var.var;
+            let g = match grads.get(theta) {
+                None => continue,
+                Some(g) => g,
+            };
+            let lr = var.group.lr(rho);
+            let lr = if p.scale_parameter {
+                (rms(theta.as_tensor())?.maximum(p.eps2)? * lr)?
+            } else {
+                Tensor::new(lr, theta.device())?.to_dtype(theta.dtype())?
+            };
+            let g2 = (g.sqr()? + p.eps1)?;
+            let update = match &var.second_moment {
+                SecondMoment::Factored { row, col } => {
+                    let next_row =
+                        ((row.as_tensor() * beta2t)? + (g2.mean(D::Minus1)? * (1. - beta2t))?)?;
+                    let next_col =
+                        ((col.as_tensor() * beta2t)? + (g2.mean(D::Minus2)? * (1. - beta2t))?)?;
+                    let r_factor = next_row
+                        .broadcast_div(&next_row.mean_keepdim(D::Minus1)?)?
+                        .sqrt()?
+                        .recip()?
+                        .unsqueeze(D::Minus1)?;
+                    let c_factor = next_col.sqrt()?.recip()?.unsqueeze(D::Minus2)?;
+                    row.set(&next_row)?;
+                    col.set(&next_col)?;
+                    r_factor.broadcast_mul(&c_factor)?.mul(g)?
+                }
+                SecondMoment::Full(v) => {
+                    let next_v = ((v.as_tensor() * beta2t)? + (g2 * (1. - beta2t))?)?;
+                    v.set(&next_v)?;
+                    next_v.sqrt()?.recip()?.mul(g)?
+                }
+            };
+            let clip = (rms(&update)? / p.clip_threshold)?.maximum(1.)?;
+            let update = update.broadcast_div(&clip)?;
+            let update = match (&var.first_moment, p.beta1) {
+                (Some(m), Some(beta1)) => {
+                    let next_m = ((m.as_tensor() * beta1)? + (update * (1. - beta1))?)?;
+                    m.set(&next_m)?;
+                    next_m
+                }
+                _ => update,
+            };
+            let lambda = var.group.weight_decay(p.weight_decay);
+            let update = (update + (theta.as_tensor() * lambda)?)?;
+            let next_theta = (theta.as_tensor() - update.broadcast_mul(&lr)?)?;
+            theta.set(&next_theta)?;
+        }
+        Ok(())
+    }
+
+    fn state(&self) -> Result<HashMap<String, Tensor>> {
+        let mut state = HashMap::new();
+        for (i, var) in self.vars.iter().enumerate() {
+            if let Some(m) = &var.first_moment {
+                state.insert(format!("{i}.first_moment"), m.as_tensor().clone());
+            }
+            match &var.second_moment {
+                SecondMoment::Factored { row, col } => {
+                    state.insert(format!("{i}.second_moment_row"), row.as_tensor().clone());
+                    state.insert(format!("{i}.second_moment_col"), col.as_tensor().clone());
+                }
+                SecondMoment::Full(v) => {
+                    state.insert(format!("{i}.second_moment"), v.as_tensor().clone());
+                }
+            }
+        }
+        state.insert(STEP_KEY.to_string(), step_state(self.step_t)?);
+        Ok(state)
+    }
+
+    fn set_state(&mut self, state: &HashMap<String, Tensor>) -> Result<()> {
+        for (i, var) in self.vars.iter().enumerate() {
+            if let Some(m) = &var.first_moment {
+                load_var(state, &format!("{i}.first_moment"), m)?;
+            }
+            match &var.second_moment {
+                SecondMoment::Factored { row, col } => {
+                    load_var(state, &format!("{i}.second_moment_row"), row)?;
+                    load_var(state, &format!("{i}.second_moment_col"), col)?;
+                }
+                SecondMoment::Full(v) => load_var(state, &format!("{i}.second_moment"), v)?,
+            }
+        }
+        self.step_t = load_step(state)?;
+        Ok(())
+    }
+}
+
+impl Adafactor {
+    // The step size before scaling by the parameter norm.
+    fn rho(&self, step_t: usize) -> f64 {
+        match self.params.lr {
+            Some(lr) => lr,
+            None => {
+                let min_step = if self.params.warmup_init {
+                    1e-6 * step_t as f64
+                } else {
+                    1e-2
+                };
+                f64::min(min_step, 1. / (step_t as f64).sqrt())
+            }
+        }
+    }
+
+    pub fn params(&self) -> &ParamsAdafactor {
+        &self.params
+    }
+
+    pub fn set_params(&mut self, params: ParamsAdafactor) {
+        self.params = params;
+    }
+}
@@ -0,0 +1,416 @@
+//! Parameter-efficient fine-tuning methods other than LoRA.
+//!
+//! - IA3 learns vectors that rescale the activations of some linear layers, see "Few-Shot
+//!   Parameter-Efficient Fine-Tuning is Better and Cheaper than In-Context Learning"
+//!   <https://arxiv.org/abs/2205.05638>.
+//! - Prefix tuning learns keys and values that are prepended to the attention keys and values of
+//!   each layer, see "Prefix-Tuning: Optimizing Continuous Prompts for Generation"
+//!   <https://arxiv.org/abs/2101.00190>.
+//! - Prompt tuning learns soft prompt embeddings that are prepended to the input embeddings, see
+//!   "The Power of Scale for Parameter-Efficient Prompt Tuning" <https://arxiv.org/abs/2104.08691>.
+//!
+//! As for the LoRA adapters, the weights use the same names as the PEFT library. The IA3 vectors
+//! are named `base_model.model.{module}.ia3_l` and the prefix and prompt embeddings
+//! `prompt_embeddings`, so saving the `VarMap` used for training produces an
+//! `adapter_model.safetensors` file that can be loaded by PEFT and vice versa. The methods can be
+//! combined, e.g. IA3 for the linear layers with a soft prompt, using a single `VarMap`.
+use crate::lora::{matches_target, PEFT_PREFIX};
+use crate::var_builder::VarBuilder;
+use crate::{Linear, Module, VarMap};
+use candle::{DType, Device, Result, Tensor, D};
+
+/// The name of the prefix and prompt embeddings in the PEFT files.
+pub const PROMPT_EMBEDDINGS: &str = "prompt_embeddings";
+
+/// The configuration of IA3 adapters, compatible with the PEFT `adapter_config.json` file.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+pub struct Ia3Config {
+    /// The modules to adapt, matched as in `LoraConfig::matches`.
+    pub target_modules: Vec<String>,
+    /// The targeted modules whose inputs are rescaled rather than their outputs, usually the
+    /// down projection of the feed-forward blocks.
+    #[serde(default)]
+    pub feedforward_modules: Vec<String>,
+}
+
+impl Ia3Config {
+    pub fn new<S: ToString>(target_modules: &[S], feedforward_modules: &[S]) -> Self {
+        Self {
+            target_modules: target_modules.iter().map(|s| s.to_string()).collect(),
+            feedforward_modules: feedforward_modules.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        matches_target(&self.target_modules, name)
+    }
+
+    pub fn is_feedforward(&self, name: &str) -> bool {
+        matches_target(&self.feedforward_modules, name)
+    }
+}
+
+/// A linear layer whose outputs, or inputs for feed-forward layers, are rescaled by a learned
+/// vector. Following PEFT, the vector has shape `(out_dim, 1)`, or `(1, in_dim)` for
+/// feed-forward layers.
+#[derive(Debug, Clone)]
+pub struct Ia3Linear {
+    base: Linear,
+    scale: Option<Tensor>,
+    feedforward: bool,
+    merged: bool,
+}
+
+impl Ia3Linear {
+    pub fn new(base: Linear) -> Self {
+        Self {
+            base,
+            scale: None,
+            feedforward: false,
+            merged: false,
+        }
+    }
+
+    pub fn with_scale(mut self, scale: Tensor, feedforward: bool) -> Result<Self> {
+        let (out_dim, in_dim) = self.base.weight().dims2()?;
+        let expected = if feedforward {
+            (1, in_dim)
+        } else {
+            (out_dim, 1)
+        };
+        if scale.dims2()? != expected {
+            candle::bail!(
+                "unexpected shape {:?} for ia3 vector, expected {expected:?}",
+                scale.shape()
+            )
+        }
+        self.scale = Some(scale);
+        self.feedforward = feedforward;
+        Ok(self)
+    }
+
+    pub fn has_adapter(&self) -> bool {
+        self.scale.is_some()
+    }
+
+    pub fn is_merged(&self) -> bool {
+        self.merged
+    }
+
+    // Multiplies (or divides when `inverse` is set) the base weights by the scaling vector.
+    fn patch(&mut self, inverse: bool) -> Result<()> {
+        let scale = match &self.scale {
+            None => return Ok(()),
+            Some(scale) => scale.to_dtype(DType::F32)?,
+        };
+        let scale = if inverse { scale.recip()? } else { scale };
+        let weight = self.base.weight();
+        let dtype = weight.dtype();
+        let new_weight = weight
+            .to_dtype(DType::F32)?
+            .broadcast_mul(&scale.to_device(weight.device())?)?
+            .to_dtype(dtype)?;
+        // The bias is only rescaled when the outputs are.
+        let bias = match self.base.bias() {
+            Some(bias) if !self.feedforward => {
+                let scale = scale.flatten_all()?.to_device(bias.device())?;
+                Some((bias.to_dtype(DType::F32)? * scale)?.to_dtype(bias.dtype())?)
+            }
+            bias => bias.cloned(),
+        };
+        self.base = Linear::new(new_weight, bias);
+        Ok(())
+    }
+
+    /// Folds the scaling vector in the base weights.
+    pub fn merge(&mut self) -> Result<()> {
+        if !self.merged {
+            self.patch(false)?;
+            self.merged = true;
+        }
+        Ok(())
+    }
+
+    pub fn unmerge(&mut self) -> Result<()> {
+        if self.merged {
+            self.patch(true)?;
+            self.merged = false;
+        }
+        Ok(())
+    }
+
+    pub fn base(&self) -> &Linear {
+        &self.base
+    }
+}
+
+impl Module for Ia3Linear {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        match &self.scale {
+            Some(scale) if !self.merged => {
+                let scale = scale.flatten_all()?;
+                if self.feedforward {
+                    xs.broadcast_mul(&scale)?.apply(&self.base)
+                } else {
+                    xs.apply(&self.base)?.broadcast_mul(&scale)
+                }
+            }
+            _ => self.base.forward(xs),
+        }
+    }
+}
+
+/// Injects IA3 vectors in the linear layers of a model, see `Lora` for the naming. When `vb` is
+/// backed by a `VarMap` the vectors are initialized with ones so that they start as a no-op.
+#[derive(Clone)]
+pub struct Ia3<'a> {
+    config: Ia3Config,
+    vb: VarBuilder<'a>,
+}
+
+impl<'a> Ia3<'a> {
+    /// Creates the adapters using the weights from `vb`, the names are used without prefix.
+    pub fn new(config: Ia3Config, vb: VarBuilder<'a>) -> Self {
+        Self { config, vb }
+    }
+
+    /// Creates trainable adapters stored in `varmap` using the PEFT names.
+    pub fn from_varmap(config: Ia3Config, varmap: &VarMap, dtype: DType, dev: &Device) -> Self {
+        let vb = VarBuilder::from_varmap(varmap, dtype, dev).pp(PEFT_PREFIX);
+        Self::new(config, vb)
+    }
+
+    /// Loads the adapters from a PEFT safetensors file.
+    ///
+    /// # Safety
+    ///
+    /// The unsafe is inherited from [`memmap2::MmapOptions`].
+    pub unsafe fn from_mmaped_safetensors<P: AsRef<std::path::Path>>(
+        config: Ia3Config,
+        paths: &[P],
+        dtype: DType,
+        dev: &Device,
+    ) -> Result<Self> {
+        let vb = VarBuilder::from_mmaped_safetensors(paths, dtype, dev)?.pp(PEFT_PREFIX);
+        Ok(Self::new(config, vb))
+    }
+
+    pub fn config(&self) -> &Ia3Config {
+        &self.config
+    }
+
+    pub fn linear(&self, base: Linear, name: &str) -> Result<Ia3Linear> {
+        let (out_dim, in_dim) = base.weight().dims2()?;
+        let layer = Ia3Linear::new(base);
+        if !self.config.matches(name) {
+            return Ok(layer);
+        }
+        let feedforward = self.config.is_feedforward(name);
+        let shape = if feedforward {
+            (1, in_dim)
+        } else {
+            (out_dim, 1)
+        };
+        let vb = self.vb.pp(name);
+        let scale = vb.get_with_hints(shape, "ia3_l", crate::Init::Const(1.))?;
+        layer.with_scale(scale, feedforward)
+    }
+}
+
+/// The configuration for prefix tuning, compatible with the PEFT `adapter_config.json` file.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+pub struct PrefixTuningConfig {
+    pub num_virtual_tokens: usize,
+    pub num_layers: usize,
+    /// The number of key-value heads of the attention layers.
+    pub num_attention_heads: usize,
+    /// The hidden size of the keys and values, i.e. the number of heads times the head size.
+    pub token_dim: usize,
+}
+
+/// Learned keys and values prepended to the attention of each layer.
+///
+/// The weights are stored as a single `prompt_embeddings` tensor of shape
+/// `(num_virtual_tokens, num_layers * 2 * token_dim)` as done by PEFT when the prefix projection
+/// is disabled.
+#[derive(Debug, Clone)]
+pub struct PrefixTuning {
+    config: PrefixTuningConfig,
+    embeddings: Tensor,
+}
+
+impl PrefixTuning {
+    /// Retrieves the prefix from `vb`, e.g. backed by a `VarMap` for training in which case the
+    /// embeddings are initialized with a normal distribution.
+    pub fn new(config: PrefixTuningConfig, vb: VarBuilder) -> Result<Self> {
+        if config.num_attention_heads == 0 {
+            candle::bail!("prefix tuning requires at least one attention head")
+        }
+        if config.token_dim % config.num_attention_heads != 0 {
+            candle::bail!(
+                "token_dim {} is not a multiple of num_attention_heads {}",
+                config.token_dim,
+                config.num_attention_heads
+            )
+        }
+        let shape = (
+            config.num_virtual_tokens,
+            config.num_layers * 2 * config.token_dim,
+        );
+        let init = crate::Init::Randn {
+            mean: 0.,
+            stdev: 1.,
+        };
+        let embeddings = vb.get_with_hints(shape, PROMPT_EMBEDDINGS, init)?;
+        Ok(Self { config, embeddings })
+    }
+
+    pub fn config(&self) -> &PrefixTuningConfig {
+        &self.config
+    }
+
+    pub fn embeddings(&self) -> &Tensor {
+        &self.embeddings
+    }
+
+    /// The number of positions added in front of the keys and values.
+    pub fn prefix_len(&self) -> usize {
+        self.config.num_virtual_tokens
+    }
+
+    /// The keys and values for each layer, with shape
+    /// `(batch, num_attention_heads, num_virtual_tokens, head_dim)`.
+    pub fn past_key_values(&self, batch: usize) -> Result<Vec<(Tensor, Tensor)>> {
+        let c = &self.config;
+        let head_dim = c.token_dim / c.num_attention_heads;
+        let kvs = self
+            .embeddings
+            .reshape((
+                c.num_virtual_tokens,
+                c.num_layers * 2,
+                c.num_attention_heads,
+                head_dim,
+            ))?
+            .permute((1, 2, 0, 3))?;
+        let mut past = Vec::with_capacity(c.num_layers);
+        for layer in 0..c.num_layers {
+            let kv = |i: usize| {
+                kvs.get(2 * layer + i)?
+                    .unsqueeze(0)?
+                    .broadcast_as((batch, c.num_attention_heads, c.num_virtual_tokens, head_dim))?
+                    .contiguous()
+            };
+            past.push((kv(0)?, kv(1)?))
+        }
+        Ok(past)
+    }
+
+    /// Prepends the prefix of `layer` to keys and values of shape
+    /// `(batch, num_attention_heads, seq_len, head_dim)`.
+    pub fn extend_kv(&self, layer: usize, k: &Tensor, v: &Tensor) -> Result<(Tensor, Tensor)> {
+        let c = &self.config;
+        if layer >= c.num_layers {
+            candle::bail!(
+                "layer {layer} out of range for a prefix with {} layers",
+                c.num_layers
+            )
+        }
+        let (b, h, _, d) = k.dims4()?;
+        let kv = |i: usize| {
+            self.embeddings
+                .narrow(1, (2 * layer + i) * c.token_dim, c.token_dim)?
+                .reshape((c.num_virtual_tokens, h, d))?
+                .transpose(0, 1)?
+                .unsqueeze(0)?
+                .broadcast_as((b, h, c.num_virtual_tokens, d))?
+                .to_dtype(k.dtype())
+        };
+        let k = Tensor::cat(&[&kv(0)?, k], 2)?;
+        let v = Tensor::cat(&[&kv(1)?, v], 2)?;
+        Ok((k, v))
+    }
+
+    /// Saves the prefix in the PEFT format.
+    pub fn save<P: AsRef<std::path::Path>>(&self, path: P) -> Result<()> {
+        self.embeddings.save_safetensors(PROMPT_EMBEDDINGS, path)
+    }
+}
+
+/// The configuration for prompt tuning, compatible with the PEFT `adapter_config.json` file.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+pub struct PromptTuningConfig {
+    pub num_virtual_tokens: usize,
+    /// The size of the input embeddings.
+    pub token_dim: usize,
+}
+
+/// Soft prompt embeddings prepended to the input embeddings of a model.
+#[derive(Debug, Clone)]
+pub struct PromptTuning {
+    config: PromptTuningConfig,
+    embeddings: Tensor,
+}
+
+impl PromptTuning {
+    /// Retrieves the prompt embeddings from `vb`, of shape `(num_virtual_tokens, token_dim)`.
+    pub fn new(config: PromptTuningConfig, vb: VarBuilder) -> Result<Self> {
+        let shape = (config.num_virtual_tokens, config.token_dim);
+        let init = crate::Init::Randn {
+            mean: 0.,
+            stdev: 1.,
+        };
+        let embeddings = vb.get_with_hints(shape, PROMPT_EMBEDDINGS, init)?;
+        Ok(Self { config, embeddings })
+    }
+
+    /// Initializes the prompt from the embeddings of some tokens, e.g. a textual description of
+    /// the task, as done by the PEFT `TEXT` initialization. The variable has to come from a
+    /// `VarMap` for this to be effective.
+    pub fn init_from_tokens(&self, varmap: &mut VarMap, token_embeddings: &Tensor) -> Result<()> {
+        let (n, _) = token_embeddings.dims2()?;
+        if n == 0 {
+            candle::bail!("cannot initialize the prompt from an empty set of tokens")
+        }
+        let num = self.config.num_virtual_tokens;
+        // The tokens are repeated when there are fewer tokens than virtual tokens.
+        let ids = (0..num as u32).map(|i| i % n as u32).collect::<Vec<_>>();
+        let ids = Tensor::new(ids, token_embeddings.device())?;
+        let init = token_embeddings.index_select(&ids, 0)?;
+        varmap.set_one(PROMPT_EMBEDDINGS, init)
+    }
+
+    pub fn config(&self) -> &PromptTuningConfig {
+        &self.config
+    }
+
+    pub fn embeddings(&self) -> &Tensor {
+        &self.embeddings
+    }
+
+    /// Prepends the soft prompt to input embeddings of shape `(batch, seq_len, token_dim)`.
+    pub fn prepend(&self, input_embeds: &Tensor) -> Result<Tensor> {
+        let (b, _, dim) = input_embeds.dims3()?;
+        let prompt = self
+            .embeddings
+            .to_dtype(input_embeds.dtype())?
+            .unsqueeze(0)?
+            .broadcast_as((b, self.config.num_virtual_tokens, dim))?;
+        Tensor::cat(&[&prompt, input_embeds], 1)
+    }
+
+    /// Prepends ones for the virtual tokens to an attention mask of shape `(batch, seq_len)`.
+    pub fn extend_mask(&self, mask: &Tensor) -> Result<Tensor> {
+        let (b, _) = mask.dims2()?;
+        let ones = Tensor::ones(
+            (b, self.config.num_virtual_tokens),
+            mask.dtype(),
+            mask.device(),
+        )?;
+        Tensor::cat(&[&ones, mask], D::Minus1)
+    }
+
+    /// Saves the prompt in the PEFT format.
+    pub fn save<P: AsRef<std::path::Path>>(&self, path: P) -> Result<()> {
+        self.embeddings.save_safetensors(PROMPT_EMBEDDINGS, path)
+    }
+}
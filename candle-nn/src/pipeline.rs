@@ -0,0 +1,250 @@
+//! Pipeline parallel inference across devices.
+//!
+//! A model that does not fit on a single device is split into stages, each stage holding some
+//! consecutive blocks on its own device. Every stage runs on a dedicated thread and the inputs
+//! are split into micro-batches that are streamed through the stages: while the second stage
+//! processes the first micro-batch, the first stage already processes the second one, so the
+//! devices are kept busy rather than waiting for each other.
+//!
+//! ```ignore
+//! let stages = model.into_pipeline_stages(&device_map)?;
+//! let mut pipeline = Pipeline::new(stages)?;
+//! // Two micro-batches of prompts, then one token for each of them.
+//! let logits = pipeline.forward(&[prompts0, prompts1], 0)?;
+//! let logits = pipeline.forward(&[next0, next1], seq_len)?;
+//! ```
+//!
+//! The kv cache is partitioned per stage, each stage holds the cache of its own blocks with one
+//! cache per micro-batch, the micro-batch being identified by its position in the inputs. Only
+//! the activations at the stage boundaries move between devices.
+use candle::{Device, Result, Tensor};
+use std::collections::HashMap;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread::JoinHandle;
+
+/// A stage of a pipeline, i.e. a range of consecutive blocks of a model on a single device.
+pub trait PipelineStage {
+    /// The kv cache of the blocks of this stage for a single micro-batch.
+    type Cache;
+
+    /// The device on which the stage runs, the inputs are moved to this device before calling
+    /// `forward`.
+    fn device(&self) -> &Device;
+
+    /// An empty cache, used for the first step of each micro-batch.
+    fn new_cache(&self) -> Result<Self::Cache>;
+
+    /// Runs the blocks of the stage on the output of the previous stage, or on the pipeline
+    /// inputs for the first stage. `index_pos` is the position of the first element of the
+    /// sequence.
+    fn forward(&mut self, xs: &Tensor, index_pos: usize, cache: &mut Self::Cache)
+        -> Result<Tensor>;
+}
+
+enum Message {
+    Forward {
+        micro_batch: usize,
+        index_pos: usize,
+        xs: Result<Tensor>,
+    },
+    Reset,
+}
+
+type Spawn = Box<dyn FnOnce(Receiver<Message>, Sender<Message>) -> Result<JoinHandle<()>>>;
+
+/// Collects the stages of a [`Pipeline`], see [`Pipeline::builder`].
+#[derive(Default)]
+pub struct PipelineBuilder {
+    stages: Vec<Spawn>,
+}
+
+impl PipelineBuilder {
+    /// Adds a stage built by `build` on the stage thread, the stages can have different types.
+    pub fn stage<S, F>(mut self, build: F) -> Self
+    where
+        S: PipelineStage + 'static,
+        F: FnOnce() -> Result<S> + Send + 'static,
+    {
+        let idx = self.stages.len();
+        let spawn = move |receiver: Receiver<Message>, sender: Sender<Message>| {
+            let (init_sender, init_receiver) = mpsc::channel();
+            let handle = std::thread::Builder::new()
+                .name(format!("candle-pipeline-{idx}"))
+                .spawn(move || {
+                    let stage = match build() {
+                        Ok(stage) => {
+                            let _ = init_sender.send(Ok(()));
+                            stage
+                        }
+                        Err(err) => {
+                            let _ = init_sender.send(Err(err));
+                            return;
+                        }
+                    };
+                    run_stage(stage, receiver, sender)
+                })?;
+            match init_receiver.recv() {
+                Ok(Ok(())) => Ok(handle),
+                Ok(Err(err)) => Err(err),
+                Err(_) => candle::bail!("building pipeline stage {idx} panicked"),
+            }
+        };
+        self.stages.push(Box::new(spawn));
+        self
+    }
+
+    /// Starts the stage threads, returning the error of the first stage that failed to build.
+    pub fn build(self) -> Result<Pipeline> {
+        if self.stages.is_empty() {
+            candle::bail!("a pipeline needs at least one stage")
+        }
+        let n_stages = self.stages.len();
+        let (input, mut receiver) = mpsc::channel();
+        let mut handles = Vec::with_capacity(n_stages);
+        for spawn in self.stages {
+            let (sender, next_receiver) = mpsc::channel();
+            handles.push(spawn(receiver, sender)?);
+            receiver = next_receiver;
+        }
+        Ok(Pipeline {
+            input: Some(input),
+            output: receiver,
+            handles,
+        })
+    }
+}
+
+fn run_stage<S: PipelineStage>(mut stage: S, receiver: Receiver<Message>, sender: Sender<Message>) {
+    let mut caches: HashMap<usize, S::Cache> = HashMap::new();
+    while let Ok(message) = receiver.recv() {
+        let message = match message {
+            Message::Reset => {
+                caches.clear();
+                Message::Reset
+            }
+            Message::Forward {
+                micro_batch,
+                index_pos,
+                xs,
+            } => {
+                let step = || {
+                    let xs = xs?.to_device(stage.device())?;
+                    let cache = match caches.entry(micro_batch) {
+                        std::collections::hash_map::Entry::Occupied(e) => e.into_mut(),
+                        std::collections::hash_map::Entry::Vacant(e) => {
+                            e.insert(stage.new_cache()?)
+                        }
+                    };
+                    stage.forward(&xs, index_pos, cache)
+                };
+                // Errors, including panics, are passed along so that the outputs stay in sync
+                // with the inputs.
+                let xs = std::panic::catch_unwind(std::panic::AssertUnwindSafe(step))
+                    .unwrap_or_else(|_| Err(candle::Error::Msg("pipeline stage panicked".into())));
+                Message::Forward {
+                    micro_batch,
+                    index_pos,
+                    xs,
+                }
+            }
+        };
+        if sender.send(message).is_err() {
+            break;
+        }
+    }
+}
+
+/// A model split into stages running on their own threads, see the module documentation.
+pub struct Pipeline {
+    input: Option<Sender<Message>>,
+    output: Receiver<Message>,
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl Pipeline {
+    pub fn builder() -> PipelineBuilder {
+        PipelineBuilder::default()
+    }
+
+    /// A pipeline made of already built stages of the same type.
+    pub fn new<S: PipelineStage + Send + 'static>(stages: Vec<S>) -> Result<Self> {
+        stages
+            .into_iter()
+            .fold(Self::builder(), |builder, stage| {
+                builder.stage(move || Ok(stage))
+            })
+            .build()
+    }
+
+    pub fn n_stages(&self) -> usize {
+        self.handles.len()
+    }
+
+    fn send(&self, message: Message) -> Result<()> {
+        match &self.input {
+            Some(input) if input.send(message).is_ok() => Ok(()),
+            _ => candle::bail!("the pipeline has stopped"),
+        }
+    }
+
+    fn recv(&self) -> Result<Message> {
+        match self.output.recv() {
+            Ok(message) => Ok(message),
+            Err(_) => candle::bail!("the pipeline has stopped"),
+        }
+    }
+
+    /// Streams the micro-batches through the stages and returns their outputs, in order. The
+    /// micro-batch at position `i` uses the kv caches of the previous calls at position `i`.
+    pub fn forward(&mut self, micro_batches: &[Tensor], index_pos: usize) -> Result<Vec<Tensor>> {
+        for (micro_batch, xs) in micro_batches.iter().enumerate() {
+            self.send(Message::Forward {
+                micro_batch,
+                index_pos,
+                xs: Ok(xs.clone()),
+            })?
+        }
+        // All the outputs are received, even after an error, so that the next call does not
+        // get the outputs of this one.
+        let mut outputs = Vec::with_capacity(micro_batches.len());
+        for _ in micro_batches.iter() {
+            match self.recv()? {
+                Message::Forward { xs, .. } => outputs.push(xs),
+                Message::Reset => candle::bail!("unexpected reset in the pipeline outputs"),
+            }
+        }
+        outputs.into_iter().collect()
+    }
+
+    /// Splits `xs` into `n_micro_batches` chunks along the batch dimension, runs them through the
+    /// pipeline, and concatenates the outputs.
+    pub fn forward_batch(
+        &mut self,
+        xs: &Tensor,
+        n_micro_batches: usize,
+        index_pos: usize,
+    ) -> Result<Tensor> {
+        let micro_batches = xs.chunk(n_micro_batches, 0)?;
+        let ys = self.forward(&micro_batches, index_pos)?;
+        Tensor::cat(&ys, 0)
+    }
+
+    /// Clears the kv caches of all the stages, e.g. before processing new sequences.
+    pub fn reset(&mut self) -> Result<()> {
+        self.send(Message::Reset)?;
+        match self.recv()? {
+            Message::Reset => Ok(()),
+            Message::Forward { .. } => candle::bail!("unexpected output in the pipeline"),
+        }
+    }
+}
+
+impl Drop for Pipeline {
+    fn drop(&mut self) {
+        // Closing the input channel stops the stages one after the other.
+        self.input.take();
+        for handle in self.handles.drain(..) {
+            let _ = handle.join();
+        }
+    }
+}
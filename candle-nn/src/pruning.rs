@@ -0,0 +1,395 @@
+//! Weight pruning.
+//!
+//! Unstructured pruning zeroes the weights with the smallest magnitude. `PrunedLinear` and
+//! `PrunedConv2d` wrap a layer and apply a binary mask to its weight on each forward pass, so
+//! that the mask can be updated while fine-tuning, then `apply` bakes the mask in the weights.
+//!
+//! Semi-structured pruning keeps the `n` largest weights in each group of `m` consecutive
+//! weights of a row, the 2:4 pattern can then be stored compressed and run with sparse kernels,
+//! see the `sparse24` module.
+//!
+//! Structured pruning removes whole output channels, input channels or attention heads and
+//! returns smaller layers, which reduces the compute rather than only the number of non-zero
+//! weights. The pruned layers can be saved with `PrunedTensors` and loaded back with a
+//! `VarBuilder` once the model configuration is updated with the new sizes.
+//!
+//! ```ignore
+//! // Unstructured: zero 50% of the weights of a layer.
+//! let fc1 = PrunedLinear::new(fc1).prune_magnitude(0.5)?.apply()?;
+//! // Structured: keep the 2048 most important hidden units of a MLP.
+//! let keep = top_channels(&channel_importance(up.weight())?, 2048)?;
+//! let up = prune_linear_outputs(&up, &keep)?;
+//! let down = prune_linear_inputs(&down, &keep)?;
+//! let mut tensors = PrunedTensors::new();
+//! tensors.insert_linear("mlp.up_proj", &up);
+//! tensors.insert_linear("mlp.down_proj", &down);
+//! tensors.save("pruned.safetensors")?;
+//! ```
+use crate::{Conv2d, Linear, Module};
+use candle::{DType, Result, Tensor, D};
+use std::collections::HashMap;
+
+/// The smallest absolute value kept when pruning a `sparsity` fraction of the values of
+/// `tensors`, the threshold is shared between all the tensors (global pruning).
+pub fn magnitude_threshold(tensors: &[&Tensor], sparsity: f64) -> Result<f32> {
+    if !(0. ..=1.).contains(&sparsity) {
+        candle::bail!("sparsity should be between 0 and 1, got {sparsity}")
+    }
+    let mut values = Vec::new();
+    for t in tensors.iter() {
+        let abs = t.abs()?.to_dtype(DType::F32)?.flatten_all()?;
+        values.extend(abs.to_vec1::<f32>()?)
+    }
+    let n_pruned = (values.len() as f64 * sparsity).round() as usize;
+    if n_pruned == 0 {
+        return Ok(f32::NEG_INFINITY);
+    }
+    values.sort_by(|a, b| a.total_cmp(b));
+    match values.get(n_pruned) {
+        Some(&v) => Ok(v),
+        None => Ok(f32::INFINITY),
+    }
+}
+
+/// A mask with the shape and dtype of `weight`, 0 for the `sparsity` fraction of the weights
+/// with the smallest magnitude and 1 elsewhere.
+pub fn magnitude_mask(weight: &Tensor, sparsity: f64) -> Result<Tensor> {
+    let threshold = magnitude_threshold(&[weight], sparsity)?;
+    mask_from_threshold(weight, threshold)
+}
+
+/// A mask with the shape and dtype of `weight`, 1 where the absolute value of the weight is at
+/// least `threshold`.
+pub fn mask_from_threshold(weight: &Tensor, threshold: f32) -> Result<Tensor> {
+    weight
+        .abs()?
+        .to_dtype(DType::F32)?
+        .ge(threshold)?
+        .to_dtype(weight.dtype())
+}
+
+/// A mask with the shape and dtype of `weight` keeping the `n` weights with the largest
+/// magnitude in each group of `m` consecutive weights along the last dimension, e.g. the 2:4
+/// pattern supported by the sparse tensor cores of NVIDIA GPUs.
+pub fn n_m_mask(weight: &Tensor, n: usize, m: usize) -> Result<Tensor> {
+    let cols = weight.dim(D::Minus1)?;
+    if n > m || m == 0 || cols % m != 0 {
+        candle::bail!("cannot apply a {n}:{m} pattern to rows of {cols} weights")
+    }
+    let abs = weight.abs()?.to_dtype(DType::F32)?.flatten_all()?;
+    let abs = abs.to_vec1::<f32>()?;
+    let mut mask = vec![0f32; abs.len()];
+    for (abs, mask) in abs.chunks(m).zip(mask.chunks_mut(m)) {
+        let mut indexes = (0..m).collect::<Vec<_>>();
+        // Stable sort so that ties keep the first weights.
+        indexes.sort_by(|&i, &j| abs[j].total_cmp(&abs[i]));
+        for &i in indexes[..n].iter() {
+            mask[i] = 1.
+        }
+    }
+    Tensor::from_vec(mask, weight.shape(), weight.device())?.to_dtype(weight.dtype())
+}
+
+/// The fraction of zero values in `xs`.
+pub fn sparsity(xs: &Tensor) -> Result<f64> {
+    let n_zeros = xs
+        .eq(0.)?
+        .to_dtype(DType::F32)?
+        .sum_all()?
+        .to_scalar::<f32>()?;
+    Ok(n_zeros as f64 / xs.elem_count().max(1) as f64)
+}
+
+fn check_mask(weight: &Tensor, mask: &Tensor) -> Result<()> {
+    if weight.shape() != mask.shape() {
+        candle::bail!(
+            "pruning mask shape {:?} does not match the weight shape {:?}",
+            mask.shape(),
+            weight.shape()
+        )
+    }
+    Ok(())
+}
+
+/// A linear layer with an optional pruning mask applied to its weight.
+#[derive(Debug, Clone)]
+pub struct PrunedLinear {
+    base: Linear,
+    mask: Option<Tensor>,
+}
+
+impl PrunedLinear {
+    pub fn new(base: Linear) -> Self {
+        Self { base, mask: None }
+    }
+
+    /// Sets the mask, a tensor with the same shape as the weight.
+    pub fn with_mask(mut self, mask: Tensor) -> Result<Self> {
+        check_mask(self.base.weight(), &mask)?;
+        self.mask = Some(mask.to_dtype(self.base.weight().dtype())?);
+        Ok(self)
+    }
+
+    /// Masks the `sparsity` fraction of the weights with the smallest magnitude.
+    pub fn prune_magnitude(self, sparsity: f64) -> Result<Self> {
+        let mask = magnitude_mask(self.base.weight(), sparsity)?;
+        self.with_mask(mask)
+    }
+
+    /// Masks the weights following the 2:4 pattern, two weights are kept in each group of four
+    /// consecutive input features.
+    pub fn prune_2_4(self) -> Result<Self> {
+        let mask = n_m_mask(self.base.weight(), 2, 4)?;
+        self.with_mask(mask)
+    }
+
+    pub fn mask(&self) -> Option<&Tensor> {
+        self.mask.as_ref()
+    }
+
+    /// The weight with the mask applied.
+    pub fn masked_weight(&self) -> Result<Tensor> {
+        match &self.mask {
+            None => Ok(self.base.weight().clone()),
+            Some(mask) => self.base.weight() * mask,
+        }
+    }
+
+    /// Returns a linear layer with the mask baked in its weight.
+    pub fn apply(&self) -> Result<Linear> {
+        let bias = self.base.bias().cloned();
+        Ok(rebuild_linear(&self.base, self.masked_weight()?, bias))
+    }
+}
+
+impl Module for PrunedLinear {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        match &self.mask {
+            None => self.base.forward(xs),
+            Some(_) => self.apply()?.forward(xs),
+        }
+    }
+}
+
+/// A 2d convolution with an optional pruning mask applied to its weight.
+#[derive(Debug, Clone)]
+pub struct PrunedConv2d {
+    base: Conv2d,
+    mask: Option<Tensor>,
+}
+
+impl PrunedConv2d {
+    pub fn new(base: Conv2d) -> Self {
+        Self { base, mask: None }
+    }
+
+    /// Sets the mask, a tensor with the same shape as the weight.
+    pub fn with_mask(mut self, mask: Tensor) -> Result<Self> {
+        check_mask(self.base.weight(), &mask)?;
+        self.mask = Some(mask.to_dtype(self.base.weight().dtype())?);
+        Ok(self)
+    }
+
+    /// Masks the `sparsity` fraction of the weights with the smallest magnitude.
+    pub fn prune_magnitude(self, sparsity: f64) -> Result<Self> {
+        let mask = magnitude_mask(self.base.weight(), sparsity)?;
+        self.with_mask(mask)
+    }
+
+    pub fn mask(&self) -> Option<&Tensor> {
+        self.mask.as_ref()
+    }
+
+    /// The weight with the mask applied.
+    pub fn masked_weight(&self) -> Result<Tensor> {
+        match &self.mask {
+            None => Ok(self.base.weight().clone()),
+            Some(mask) => self.base.weight() * mask,
+        }
+    }
+
+    /// Returns a convolution with the mask baked in its weight.
+    pub fn apply(&self) -> Result<Conv2d> {
+        let bias = self.base.bias().cloned();
+        Ok(rebuild_conv2d(&self.base, self.masked_weight()?, bias))
+    }
+}
+
+impl Module for PrunedConv2d {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        match &self.mask {
+            None => self.base.forward(xs),
+            Some(_) => self.apply()?.forward(xs),
+        }
+    }
+}
+
+/// The L2 norm of each output channel of a linear or convolution weight, i.e. of each slice
+/// along the first dimension. This is a common importance score for structured pruning.
+pub fn channel_importance(weight: &Tensor) -> Result<Tensor> {
+    weight
+        .to_dtype(DType::F32)?
+        .flatten_from(1)?
+        .sqr()?
+        .sum(D::Minus1)?
+        .sqrt()
+}
+
+/// The indexes of the `n` channels with the highest importance, in increasing order so that
+/// the remaining channels keep their relative positions.
+pub fn top_channels(importance: &Tensor, n: usize) -> Result<Vec<usize>> {
+    let importance = importance.to_dtype(DType::F32)?.to_vec1::<f32>()?;
+    if n > importance.len() {
+        candle::bail!("cannot keep {n} channels out of {}", importance.len())
+    }
+    let mut indexes = (0..importance.len()).collect::<Vec<_>>();
+    indexes.sort_by(|&i, &j| importance[j].total_cmp(&importance[i]));
+    indexes.truncate(n);
+    indexes.sort();
+    Ok(indexes)
+}
+
+// The pruned layers keep the prefix of the original ones so that errors still report the
+// `VarBuilder` path.
+fn rebuild_linear(linear: &Linear, weight: Tensor, bias: Option<Tensor>) -> Linear {
+    let new = Linear::new(weight, bias);
+    match linear.prefix() {
+        None => new,
+        Some(prefix) => new.with_prefix(prefix),
+    }
+}
+
+fn rebuild_conv2d(conv: &Conv2d, weight: Tensor, bias: Option<Tensor>) -> Conv2d {
+    let new = Conv2d::new(weight, bias, *conv.config());
+    match conv.prefix() {
+        None => new,
+        Some(prefix) => new.with_prefix(prefix),
+    }
+}
+
+fn select(xs: &Tensor, keep: &[usize], dim: usize) -> Result<Tensor> {
+    let size = xs.dim(dim)?;
+    if let Some(&i) = keep.iter().find(|&&i| i >= size) {
+        candle::bail!("cannot keep index {i}, dim {dim} has size {size}")
+    }
+    let keep = keep.iter().map(|&i| i as u32).collect::<Vec<_>>();
+    let keep = Tensor::new(keep, xs.device())?;
+    xs.index_select(&keep, dim)
+}
+
+/// Keeps the output features `keep` of a linear layer.
+pub fn prune_linear_outputs(linear: &Linear, keep: &[usize]) -> Result<Linear> {
+    let weight = select(linear.weight(), keep, 0)?;
+    let bias = linear.bias().map(|b| select(b, keep, 0)).transpose()?;
+    Ok(rebuild_linear(linear, weight, bias))
+}
+
+/// Keeps the input features `keep` of a linear layer, e.g. after pruning the outputs of the
+/// previous layer.
+pub fn prune_linear_inputs(linear: &Linear, keep: &[usize]) -> Result<Linear> {
+    let weight = select(linear.weight(), keep, 1)?;
+    Ok(rebuild_linear(linear, weight, linear.bias().cloned()))
+}
+
+/// Keeps the output channels `keep` of a 2d convolution.
+pub fn prune_conv2d_out_channels(conv: &Conv2d, keep: &[usize]) -> Result<Conv2d> {
+    let weight = select(conv.weight(), keep, 0)?;
+    let bias = conv.bias().map(|b| select(b, keep, 0)).transpose()?;
+    Ok(rebuild_conv2d(conv, weight, bias))
+}
+
+/// Keeps the input channels `keep` of a 2d convolution, this is not supported for grouped
+/// convolutions.
+pub fn prune_conv2d_in_channels(conv: &Conv2d, keep: &[usize]) -> Result<Conv2d> {
+    if conv.config().groups != 1 {
+        candle::bail!("cannot prune the input channels of a grouped convolution")
+    }
+    let weight = select(conv.weight(), keep, 1)?;
+    Ok(rebuild_conv2d(conv, weight, conv.bias().cloned()))
+}
+
+/// The feature indexes covered by the attention heads `heads`, each head spanning `head_dim`
+/// consecutive features.
+pub fn head_features(heads: &[usize], head_dim: usize) -> Vec<usize> {
+    heads
+        .iter()
+        .flat_map(|&h| h * head_dim..(h + 1) * head_dim)
+        .collect()
+}
+
+/// The attention projections after head pruning.
+#[derive(Debug, Clone)]
+pub struct PrunedAttention {
+    pub q_proj: Linear,
+    pub k_proj: Linear,
+    pub v_proj: Linear,
+    pub o_proj: Linear,
+}
+
+/// Keeps the attention heads `heads` of a multi-head attention layer with `num_heads` heads,
+/// shrinking the query, key and value outputs and the output projection inputs. The layers
+/// using grouped query attention have to prune the key and value heads separately.
+pub fn prune_attention_heads(
+    q_proj: &Linear,
+    k_proj: &Linear,
+    v_proj: &Linear,
+    o_proj: &Linear,
+    num_heads: usize,
+    heads: &[usize],
+) -> Result<PrunedAttention> {
+    let (dim, _) = q_proj.weight().dims2()?;
+    if dim % num_heads != 0 {
+        candle::bail!("{dim} features cannot be split in {num_heads} heads")
+    }
+    if let Some(&h) = heads.iter().find(|&&h| h >= num_heads) {
+        candle::bail!("cannot keep head {h}, there are {num_heads} heads")
+    }
+    let keep = head_features(heads, dim / num_heads);
+    Ok(PrunedAttention {
+        q_proj: prune_linear_outputs(q_proj, &keep)?,
+        k_proj: prune_linear_outputs(k_proj, &keep)?,
+        v_proj: prune_linear_outputs(v_proj, &keep)?,
+        o_proj: prune_linear_inputs(o_proj, &keep)?,
+    })
+}
+
+/// Collects the weights of pruned layers under their `VarBuilder` names to save them as a
+/// safetensors file.
+#[derive(Debug, Clone, Default)]
+pub struct PrunedTensors {
+    tensors: HashMap<String, Tensor>,
+}
+
+impl PrunedTensors {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, name: impl Into<String>, tensor: &Tensor) {
+        self.tensors.insert(name.into(), tensor.clone());
+    }
+
+    /// Adds `{prefix}.weight` and `{prefix}.bias` if any.
+    pub fn insert_linear(&mut self, prefix: &str, linear: &Linear) {
+        self.insert(format!("{prefix}.weight"), linear.weight());
+        if let Some(bias) = linear.bias() {
+            self.insert(format!("{prefix}.bias"), bias)
+        }
+    }
+
+    /// Adds `{prefix}.weight` and `{prefix}.bias` if any.
+    pub fn insert_conv2d(&mut self, prefix: &str, conv: &Conv2d) {
+        self.insert(format!("{prefix}.weight"), conv.weight());
+        if let Some(bias) = conv.bias() {
+            self.insert(format!("{prefix}.bias"), bias)
+        }
+    }
+
+    pub fn tensors(&self) -> &HashMap<String, Tensor> {
+        &self.tensors
+    }
+
+    pub fn save<P: AsRef<std::path::Path>>(&self, path: P) -> Result<()> {
+        candle::safetensors::save(&self.tensors, path)
+    }
+}
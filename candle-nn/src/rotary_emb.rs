@@ -1,4 +1,4 @@
-use candle::{CpuStorage, Layout, Result, Shape, Tensor, D};
+use candle::{CpuStorage, Layout, MetaStorage, Result, Shape, Tensor, D};
 use rayon::prelude::*;
 
 /// Interleaved variant of rotary embeddings.
@@ -14,6 +14,18 @@ impl candle::CustomOp3 for RotaryEmbI {
         "rotary-emb-int"
     }
 
+    fn meta_fwd(
+        &self,
+        s1: &MetaStorage,
+        l1: &Layout,
+        s2: &MetaStorage,
+        l2: &Layout,
+        s3: &MetaStorage,
+        l3: &Layout,
+    ) -> Result<(MetaStorage, Shape)> {
+        Ok(s1.record_like_first(self.name(), l1, &[(s2, l2), (s3, l3)]))
+    }
+
     fn cpu_fwd(
         &self,
         s1: &CpuStorage,
@@ -255,6 +267,18 @@ impl candle::CustomOp3 for RotaryEmb {
         "rotary-emb"
     }
 
+    fn meta_fwd(
+        &self,
+        s1: &MetaStorage,
+        l1: &Layout,
+        s2: &MetaStorage,
+        l2: &Layout,
+        s3: &MetaStorage,
+        l3: &Layout,
+    ) -> Result<(MetaStorage, Shape)> {
+        Ok(s1.record_like_first(self.name(), l1, &[(s2, l2), (s3, l3)]))
+    }
+
     fn cpu_fwd(
         &self,
         s1: &CpuStorage,
@@ -507,6 +531,18 @@ impl candle::CustomOp3 for RotaryEmbThd {
         "rotary-emb"
     }
 
+    fn meta_fwd(
+        &self,
+        s1: &MetaStorage,
+        l1: &Layout,
+        s2: &MetaStorage,
+        l2: &Layout,
+        s3: &MetaStorage,
+        l3: &Layout,
+    ) -> Result<(MetaStorage, Shape)> {
+        Ok(s1.record_like_first(self.name(), l1, &[(s2, l2), (s3, l3)]))
+    }
+
     fn cpu_fwd(
         &self,
         s1: &CpuStorage,
@@ -0,0 +1,244 @@
+//! Sharing a model between threads, e.g. the handlers of a web server.
+//!
+//! An `InferenceSession` owns the model on a dedicated executor thread that processes the
+//! requests one at a time in the order they were submitted. The session itself is a cheap
+//! `Clone + Send + Sync` handle so it can be stored in the state of an axum or actix server.
+//!
+//! ```ignore
+//! let session = InferenceSession::for_module(16, Device::new_cuda(0)?, |device| {
+//!     let vb = unsafe { VarBuilder::from_mmaped_safetensors(&files, DType::F16, device)? };
+//!     Model::load(vb)
+//! })?;
+//! // In an async handler, waiting does not block the runtime.
+//! let logits = session.try_submit(input_ids)?.await?;
+//! ```
+//!
+//! The queue holds at most `capacity` pending requests. `submit` blocks until there is room in
+//! the queue whereas `try_submit` returns an error right away, which an async server can turn
+//! into a "503 Service Unavailable" response rather than piling up requests. Once all the
+//! handles have been dropped, the executor processes the requests still in the queue, drops
+//! the model and exits.
+use candle::{Device, Module, Result, Tensor};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Waker};
+
+struct Slot<O> {
+    output: Option<Result<O>>,
+    waker: Option<Waker>,
+}
+
+// The place where the executor writes the response to a request.
+struct ResponseSlot<O> {
+    slot: Mutex<Slot<O>>,
+    ready: Condvar,
+}
+
+impl<O> ResponseSlot<O> {
+    fn set(&self, output: Result<O>) {
+        let mut slot = self.slot.lock().unwrap();
+        slot.output = Some(output);
+        if let Some(waker) = slot.waker.take() {
+            waker.wake()
+        }
+        self.ready.notify_all()
+    }
+}
+
+struct Request<I, O> {
+    input: I,
+    response: Arc<ResponseSlot<O>>,
+}
+
+/// The response to a submitted request.
+///
+/// It can either be awaited from an async context or waited for with [`Response::wait`]. The
+/// request is still processed if the response gets dropped.
+pub struct Response<O> {
+    response: Arc<ResponseSlot<O>>,
+}
+
+impl<O> Response<O> {
+    /// Blocks the current thread until the request has been processed.
+    pub fn wait(self) -> Result<O> {
+        let mut slot = self.response.slot.lock().unwrap();
+        loop {
+            if let Some(output) = slot.output.take() {
+                return output;
+            }
+            slot = self.response.ready.wait(slot).unwrap();
+        }
+    }
+
+    /// Returns the output if the request has already been processed.
+    pub fn try_take(&mut self) -> Option<Result<O>> {
+        self.response.slot.lock().unwrap().output.take()
+    }
+}
+
+impl<O> Future for Response<O> {
+    type Output = Result<O>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut slot = self.response.slot.lock().unwrap();
+        match slot.output.take() {
+            Some(output) => Poll::Ready(output),
+            None => {
+                slot.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// A handle to a model running on an executor thread, see the module documentation.
+pub struct InferenceSession<I, O> {
+    sender: SyncSender<Request<I, O>>,
+    pending: Arc<AtomicUsize>,
+    capacity: usize,
+}
+
+impl<I, O> Clone for InferenceSession<I, O> {
+    fn clone(&self) -> Self {
+        Self {
+            sender: self.sender.clone(),
+            pending: self.pending.clone(),
+            capacity: self.capacity,
+        }
+    }
+}
+
+impl<I: Send + 'static, O: Send + 'static> InferenceSession<I, O> {
+    /// Starts the executor thread. `init` runs on this thread and returns the function that
+    /// processes each request, so the model is created, used, and dropped on the executor and
+    /// does not have to be `Send`. The error returned by `init`, if any, is returned here.
+    pub fn new<F, P>(capacity: usize, init: F) -> Result<Self>
+    where
+        F: FnOnce() -> Result<P> + Send + 'static,
+        P: FnMut(I) -> Result<O> + 'static,
+    {
+        if capacity == 0 {
+            candle::bail!("the capacity of an inference session has to be positive")
+        }
+        let (sender, receiver) = mpsc::sync_channel::<Request<I, O>>(capacity);
+        let (init_sender, init_receiver) = mpsc::channel();
+        let pending = Arc::new(AtomicUsize::new(0));
+        let pending_ = pending.clone();
+        std::thread::Builder::new()
+            .name("candle-inference".to_string())
+            .spawn(move || {
+                let mut process = match init() {
+                    Ok(process) => {
+                        let _ = init_sender.send(Ok(()));
+                        process
+                    }
+                    Err(err) => {
+                        let _ = init_sender.send(Err(err));
+                        return;
+                    }
+                };
+                while let Ok(Request { input, response }) = receiver.recv() {
+                    pending_.fetch_sub(1, Ordering::SeqCst);
+                    let output =
+                        std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| process(input)))
+                            .unwrap_or_else(|_| {
+                                Err(candle::Error::Msg("inference request panicked".into()))
+                            });
+                    response.set(output)
+                }
+            })?;
+        match init_receiver.recv() {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => return Err(err),
+            Err(_) => candle::bail!("the inference session initialization panicked"),
+        }
+        Ok(Self {
+            sender,
+            pending,
+            capacity,
+        })
+    }
+
+    fn request(&self, input: I) -> (Request<I, O>, Response<O>) {
+        let response = Arc::new(ResponseSlot {
+            slot: Mutex::new(Slot {
+                output: None,
+                waker: None,
+            }),
+            ready: Condvar::new(),
+        });
+        let request = Request {
+            input,
+            response: response.clone(),
+        };
+        (request, Response { response })
+    }
+
+    /// Queues a request, blocking the current thread while the queue is full.
+    pub fn submit(&self, input: I) -> Result<Response<O>> {
+        let (request, response) = self.request(input);
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        if self.sender.send(request).is_err() {
+            self.pending.fetch_sub(1, Ordering::SeqCst);
+            candle::bail!("the inference session executor has stopped")
+        }
+        Ok(response)
+    }
+
+    /// Queues a request, returning an error without waiting if the queue is full.
+    pub fn try_submit(&self, input: I) -> Result<Response<O>> {
+        let (request, response) = self.request(input);
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        match self.sender.try_send(request) {
+            Ok(()) => Ok(response),
+            Err(err) => {
+                self.pending.fetch_sub(1, Ordering::SeqCst);
+                match err {
+                    TrySendError::Full(_) => candle::bail!(
+                        "the inference session queue is full ({} pending requests)",
+                        self.capacity
+                    ),
+                    TrySendError::Disconnected(_) => {
+                        candle::bail!("the inference session executor has stopped")
+                    }
+                }
+            }
+        }
+    }
+
+    /// Queues a request and waits for its output.
+    pub fn run(&self, input: I) -> Result<O> {
+        self.submit(input)?.wait()
+    }
+
+    /// The number of requests waiting in the queue, excluding the one being processed.
+    pub fn pending(&self) -> usize {
+        self.pending.load(Ordering::SeqCst)
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+impl InferenceSession<Tensor, Tensor> {
+    /// A session running `module.forward` on `device`, the module being built on the executor
+    /// thread by `build`. The inputs are moved to `device` and the outputs are moved back to the
+    /// cpu so that handlers do not have to deal with the device.
+    pub fn for_module<M, F>(capacity: usize, device: Device, build: F) -> Result<Self>
+    where
+        M: Module + 'static,
+        F: FnOnce(&Device) -> Result<M> + Send + 'static,
+    {
+        Self::new(capacity, move || {
+            let module = build(&device)?;
+            Ok(move |xs: Tensor| {
+                let ys = module.forward(&xs.to_device(&device)?)?;
+                ys.to_device(&Device::Cpu)
+            })
+        })
+    }
+}
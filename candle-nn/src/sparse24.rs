@@ -0,0 +1,760 @@
+//! 2:4 structured sparsity.
+//!
+//! A weight follows the 2:4 pattern when at most two weights are non-zero in each group of
+//! four consecutive weights of a row, such weights can be produced with
+//! `pruning::PrunedLinear::prune_2_4` followed by some fine-tuning to recover the accuracy.
+//! `Sparse24Weight` stores such a weight compressed: the non-zero values, half the size of the
+//! dense weight, and one metadata byte per group holding the positions of the two values.
+//!
+//! `Sparse24Linear` runs the linear layer on the compressed weight. On cpu a fused kernel skips
+//! the pruned weights. With the `cusparselt` feature, the f16 and bf16 layers on cuda use the
+//! sparse tensor cores of Ampere and later GPUs through cuSPARSELt. On the other devices the
+//! weight is decompressed on each forward pass.
+//!
+//! ```ignore
+//! let fc1 = PrunedLinear::new(fc1).prune_2_4()?.apply()?;
+//! // ... fine-tune with the mask applied ...
+//! let fc1 = Sparse24Linear::from_linear(&fc1)?;
+//! let mut tensors = PrunedTensors::new();
+//! fc1.insert_tensors("mlp.fc1", &mut tensors);
+//! tensors.save("sparse.safetensors")?;
+//! // Loading back the compressed layer.
+//! let fc1 = Sparse24Linear::load(vb.pp("mlp.fc1"), in_dim, out_dim, true)?;
+//! ```
+use crate::pruning::PrunedTensors;
+use crate::{Linear, Module, VarBuilder};
+use candle::{CpuStorage, DType, Device, Layout, Result, Shape, Tensor, WithDType, D};
+use rayon::prelude::*;
+
+/// A weight of shape `(out_features, in_features)` following the 2:4 pattern, compressed.
+#[derive(Debug, Clone)]
+pub struct Sparse24Weight {
+    // The two kept values of each group, shape `(out_features, in_features / 2)`.
+    values: Tensor,
+    // The positions of the two values in each group, `i0 | i1 << 2` with `i0 < i1`, a u8 tensor
+    // of shape `(out_features, in_features / 4)`.
+    metadata: Tensor,
+}
+
+impl Sparse24Weight {
+    /// Compresses `weight`, returning an error if a group of four weights has more than two
+    /// non-zero values.
+    pub fn compress(weight: &Tensor) -> Result<Self> {
+        let (out_features, in_features) = weight.dims2()?;
+        if in_features % 4 != 0 {
+            candle::bail!("2:4 sparsity requires a multiple of 4 input features, got {in_features}")
+        }
+        let dense = weight
+            .to_dtype(DType::F32)?
+            .flatten_all()?
+            .to_vec1::<f32>()?;
+        let n_groups = dense.len() / 4;
+        let mut values = Vec::with_capacity(2 * n_groups);
+        let mut metadata = Vec::with_capacity(n_groups);
+        for (group_idx, group) in dense.chunks(4).enumerate() {
+            let mut nz = (0..4).filter(|&i| group[i] != 0.).collect::<Vec<_>>();
+            if nz.len() > 2 {
+                let (row, col) = (group_idx * 4 / in_features, group_idx * 4 % in_features);
+                candle::bail!(
+                    "weight does not follow the 2:4 pattern, row {row} has {} non-zero values at columns {col}..{}",
+                    nz.len(),
+                    col + 4
+                )
+            }
+            // Pad with unused positions, the corresponding values are zero.
+            let pad = (0..4).filter(|i| !nz.contains(i)).collect::<Vec<_>>();
+            nz.extend(pad.into_iter().take(2 - nz.len()));
+            nz.sort();
+            values.push(group[nz[0]]);
+            values.push(group[nz[1]]);
+            metadata.push((nz[0] | nz[1] << 2) as u8);
+        }
+        let device = weight.device();
+        let values = Tensor::from_vec(values, (out_features, in_features / 2), device)?
+            .to_dtype(weight.dtype())?;
+        let metadata = Tensor::from_vec(metadata, (out_features, in_features / 4), device)?;
+        Ok(Self { values, metadata })
+    }
+
+    /// Builds a weight from its compressed values and metadata, e.g. as loaded from a file.
+    pub fn from_parts(values: Tensor, metadata: Tensor) -> Result<Self> {
+        let (out_features, half) = values.dims2()?;
+        if metadata.dtype() != DType::U8 || metadata.dims2()? != (out_features, half / 2) {
+            candle::bail!(
+                "2:4 metadata should be a u8 tensor of shape ({out_features}, {}), got {:?} {:?}",
+                half / 2,
+                metadata.dtype(),
+                metadata.shape()
+            )
+        }
+        if half % 2 != 0 {
+            candle::bail!("2:4 values should have an even number of columns, got {half}")
+        }
+        Ok(Self { values, metadata })
+    }
+
+    pub fn values(&self) -> &Tensor {
+        &self.values
+    }
+
+    pub fn metadata(&self) -> &Tensor {
+        &self.metadata
+    }
+
+    pub fn out_features(&self) -> usize {
+        self.values.dims()[0]
+    }
+
+    pub fn in_features(&self) -> usize {
+        self.values.dims()[1] * 2
+    }
+
+    pub fn dtype(&self) -> DType {
+        self.values.dtype()
+    }
+
+    pub fn device(&self) -> &Device {
+        self.values.device()
+    }
+
+    /// The dense weight of shape `(out_features, in_features)`.
+    pub fn decompress(&self) -> Result<Tensor> {
+        let values = self
+            .values
+            .to_dtype(DType::F32)?
+            .flatten_all()?
+            .to_vec1::<f32>()?;
+        let metadata = self.metadata.flatten_all()?.to_vec1::<u8>()?;
+        let mut dense = vec![0f32; values.len() * 2];
+        for (g, &m) in metadata.iter().enumerate() {
+            let (i0, i1) = ((m & 3) as usize, (m >> 2 & 3) as usize);
+            dense[4 * g + i0] = values[2 * g];
+            dense[4 * g + i1] = values[2 * g + 1];
+        }
+        let shape = (self.out_features(), self.in_features());
+        Tensor::from_vec(dense, shape, self.device())?.to_dtype(self.dtype())
+    }
+
+    /// Computes `xs @ w.t()` for `xs` of shape `(n, in_features)`.
+    pub fn matmul_t(&self, xs: &Tensor) -> Result<Tensor> {
+        match xs.device() {
+            Device::Cpu => xs.contiguous()?.apply_op3_no_bwd(
+                &self.values.contiguous()?,
+                &self.metadata.contiguous()?,
+                &Sparse24MatMul,
+            ),
+            _ => xs.matmul(&self.decompress()?.t()?),
+        }
+    }
+}
+
+// The fused cpu kernel for `xs @ w.t()`, only the kept weights are multiplied.
+struct Sparse24MatMul;
+
+impl candle::CustomOp3 for Sparse24MatMul {
+    fn name(&self) -> &'static str {
+        "sparse24-matmul"
+    }
+
+    fn cpu_fwd(
+        &self,
+        s1: &CpuStorage,
+        l1: &Layout,
+        s2: &CpuStorage,
+        l2: &Layout,
+        s3: &CpuStorage,
+        l3: &Layout,
+    ) -> Result<(CpuStorage, Shape)> {
+        use candle::backend::BackendStorage;
+
+        fn inner<T: WithDType + num_traits::Float>(
+            xs: &[T],
+            l_xs: &Layout,
+            values: &[T],
+            l_values: &Layout,
+            metadata: &[u8],
+            l_metadata: &Layout,
+        ) -> Result<(CpuStorage, Shape)> {
+            let (n, k) = l_xs.shape().dims2()?;
+            let (out_features, half) = l_values.shape().dims2()?;
+            if half * 2 != k || l_metadata.shape().dims2()? != (out_features, k / 4) {
+                candle::bail!(
+                    "shape mismatch in sparse24-matmul, xs: {:?}, values: {:?}, metadata: {:?}",
+                    l_xs.shape(),
+                    l_values.shape(),
+                    l_metadata.shape()
+                )
+            }
+            let xs = match l_xs.contiguous_offsets() {
+                None => candle::bail!("xs has to be contiguous"),
+                Some((o1, o2)) => &xs[o1..o2],
+            };
+            let values = match l_values.contiguous_offsets() {
+                None => candle::bail!("values has to be contiguous"),
+                Some((o1, o2)) => &values[o1..o2],
+            };
+            let metadata = match l_metadata.contiguous_offsets() {
+                None => candle::bail!("metadata has to be contiguous"),
+                Some((o1, o2)) => &metadata[o1..o2],
+            };
+            let mut dst = vec![T::zero(); n * out_features];
+            dst.par_chunks_mut(out_features)
+                .zip(xs.par_chunks(k.max(1)))
+                .for_each(|(dst, xs)| {
+                    for (o, dst) in dst.iter_mut().enumerate() {
+                        let values = &values[o * half..(o + 1) * half];
+                        let metadata = &metadata[o * k / 4..(o + 1) * k / 4];
+                        let mut acc = 0f32;
+                        for (g, &m) in metadata.iter().enumerate() {
+                            let (i0, i1) = ((m & 3) as usize, (m >> 2 & 3) as usize);
+                            let v0 = values[2 * g].to_f32().unwrap_or(0.);
+                            let v1 = values[2 * g + 1].to_f32().unwrap_or(0.);
+                            acc += v0 * xs[4 * g + i0].to_f32().unwrap_or(0.)
+                                + v1 * xs[4 * g + i1].to_f32().unwrap_or(0.);
+                        }
+                        *dst = T::from(acc).unwrap_or(T::zero())
+                    }
+                });
+            Ok((T::to_cpu_storage_owned(dst), (n, out_features).into()))
+        }
+
+        let metadata = match s3 {
+            CpuStorage::U8(m) => m,
+            _ => candle::bail!("sparse24-matmul expects u8 metadata, got {:?}", s3.dtype()),
+        };
+        match (s1, s2) {
+            (CpuStorage::F32(xs), CpuStorage::F32(vs)) => inner(xs, l1, vs, l2, metadata, l3),
+            (CpuStorage::F64(xs), CpuStorage::F64(vs)) => inner(xs, l1, vs, l2, metadata, l3),
+            (CpuStorage::F16(xs), CpuStorage::F16(vs)) => inner(xs, l1, vs, l2, metadata, l3),
+            (CpuStorage::BF16(xs), CpuStorage::BF16(vs)) => inner(xs, l1, vs, l2, metadata, l3),
+            _ => candle::bail!(
+                "sparse24-matmul: unsupported dtypes {:?} and {:?}",
+                s1.dtype(),
+                s2.dtype()
+            ),
+        }
+    }
+}
+
+/// A linear layer with a 2:4 sparse weight.
+#[derive(Debug, Clone)]
+pub struct Sparse24Linear {
+    weight: Sparse24Weight,
+    bias: Option<Tensor>,
+    #[cfg(feature = "cusparselt")]
+    cusparselt: Option<std::sync::Arc<cusparselt::SparseMatrix>>,
+}
+
+impl Sparse24Linear {
+    pub fn new(weight: Sparse24Weight, bias: Option<Tensor>) -> Result<Self> {
+        #[cfg(feature = "cusparselt")]
+        let cusparselt = match weight.device() {
+            Device::Cuda(_)
+                if matches!(weight.dtype(), DType::F16 | DType::BF16)
+                    && cusparselt::is_aligned(weight.out_features())
+                    && cusparselt::is_aligned(weight.in_features()) =>
+            {
+                Some(std::sync::Arc::new(cusparselt::SparseMatrix::new(
+                    &weight.decompress()?,
+                )?))
+            }
+            _ => None,
+        };
+        Ok(Self {
+            weight,
+            bias,
+            #[cfg(feature = "cusparselt")]
+            cusparselt,
+        })
+    }
+
+    /// Compresses the weight of a linear layer, which has to follow the 2:4 pattern.
+    pub fn from_linear(linear: &Linear) -> Result<Self> {
+        let weight = Sparse24Weight::compress(linear.weight())?;
+        Self::new(weight, linear.bias().cloned())
+    }
+
+    /// Loads `values`, `metadata` and optionally `bias`, as saved by `insert_tensors`.
+    pub fn load(vb: VarBuilder, in_dim: usize, out_dim: usize, bias: bool) -> Result<Self> {
+        if in_dim % 4 != 0 {
+            candle::bail!("2:4 sparsity requires a multiple of 4 input features, got {in_dim}")
+        }
+        let values = vb.get((out_dim, in_dim / 2), "values")?;
+        let metadata = vb.get_with_hints_dtype(
+            (out_dim, in_dim / 4),
+            "metadata",
+            Default::default(),
+            DType::U8,
+        )?;
+        let bias = if bias {
+            Some(vb.get(out_dim, "bias")?)
+        } else {
+            None
+        };
+        Self::new(Sparse24Weight::from_parts(values, metadata)?, bias)
+    }
+
+    /// Adds `{prefix}.values`, `{prefix}.metadata` and `{prefix}.bias` if any.
+    pub fn insert_tensors(&self, prefix: &str, tensors: &mut PrunedTensors) {
+        tensors.insert(format!("{prefix}.values"), self.weight.values());
+        tensors.insert(format!("{prefix}.metadata"), self.weight.metadata());
+        if let Some(bias) = &self.bias {
+            tensors.insert(format!("{prefix}.bias"), bias)
+        }
+    }
+
+    pub fn weight(&self) -> &Sparse24Weight {
+        &self.weight
+    }
+
+    pub fn bias(&self) -> Option<&Tensor> {
+        self.bias.as_ref()
+    }
+
+    /// The equivalent dense layer.
+    pub fn to_linear(&self) -> Result<Linear> {
+        Ok(Linear::new(self.weight.decompress()?, self.bias.clone()))
+    }
+}
+
+impl Module for Sparse24Linear {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let in_features = xs.dim(D::Minus1)?;
+        let mut out_dims = xs.dims().to_vec();
+        *out_dims.last_mut().unwrap() = self.weight.out_features();
+        let xs = xs.reshape(((), in_features))?;
+        // cuSPARSELt rejects the unaligned row counts, e.g. a single row when decoding, these
+        // go through the generic path.
+        #[cfg(feature = "cusparselt")]
+        let ys = match &self.cusparselt {
+            Some(m) if cusparselt::is_aligned(xs.dim(0)?) => m.matmul_t(&xs)?,
+            _ => self.weight.matmul_t(&xs)?,
+        };
+        #[cfg(not(feature = "cusparselt"))]
+        let ys = self.weight.matmul_t(&xs)?;
+        let ys = ys.reshape(out_dims)?;
+        match &self.bias {
+            None => Ok(ys),
+            Some(bias) => ys.broadcast_add(bias),
+        }
+    }
+}
+
+#[cfg(feature = "cusparselt")]
+mod cusparselt {
+    //! Sparse matmuls on the tensor cores with cuSPARSELt, the library uses its own compressed
+    //! format so the weight is compressed again on the gpu.
+    use candle::cuda_backend::cudarc::driver::{DevicePtr, DevicePtrMut};
+    use candle::cuda_backend::WrapErr;
+    use candle::{DType, Result, Storage, Tensor};
+    use std::collections::HashMap;
+    use std::ffi::c_void;
+    use std::sync::Mutex;
+
+    mod ffi {
+        use std::ffi::c_void;
+
+        // The opaque structures of cusparseLt.h.
+        #[repr(C, align(16))]
+        pub struct Opaque(pub [u8; 1024]);
+
+        pub const CUDA_R_16F: i32 = 2;
+        pub const CUDA_R_16BF: i32 = 14;
+        pub const CUSPARSE_ORDER_COL: i32 = 1;
+        pub const CUSPARSE_ORDER_ROW: i32 = 2;
+        pub const CUSPARSE_OPERATION_NON_TRANSPOSE: i32 = 0;
+        pub const CUSPARSE_COMPUTE_32F: i32 = 2;
+        pub const CUSPARSELT_SPARSITY_50_PERCENT: i32 = 0;
+        pub const CUSPARSELT_MATMUL_ALG_DEFAULT: i32 = 0;
+
+        #[link(name = "cusparseLt")]
+        extern "C" {
+            pub fn cusparseLtInit(handle: *mut Opaque) -> i32;
+            pub fn cusparseLtDestroy(handle: *const Opaque) -> i32;
+            pub fn cusparseLtStructuredDescriptorInit(
+                handle: *const Opaque,
+                mat: *mut Opaque,
+                rows: i64,
+                cols: i64,
+                ld: i64,
+                alignment: u32,
+                value_type: i32,
+                order: i32,
+                sparsity: i32,
+            ) -> i32;
+            pub fn cusparseLtDenseDescriptorInit(
+                handle: *const Opaque,
+                mat: *mut Opaque,
+                rows: i64,
+                cols: i64,
+                ld: i64,
+                alignment: u32,
+                value_type: i32,
+                order: i32,
+            ) -> i32;
+            pub fn cusparseLtMatDescriptorDestroy(mat: *const Opaque) -> i32;
+            pub fn cusparseLtMatmulDescriptorInit(
+                handle: *const Opaque,
+                matmul: *mut Opaque,
+                op_a: i32,
+                op_b: i32,
+                mat_a: *const Opaque,
+                mat_b: *const Opaque,
+                mat_c: *const Opaque,
+                mat_d: *const Opaque,
+                compute_type: i32,
+            ) -> i32;
+            pub fn cusparseLtMatmulAlgSelectionInit(
+                handle: *const Opaque,
+                alg_selection: *mut Opaque,
+                matmul: *const Opaque,
+                alg: i32,
+            ) -> i32;
+            pub fn cusparseLtMatmulPlanInit(
+                handle: *const Opaque,
+                plan: *mut Opaque,
+                matmul: *const Opaque,
+                alg_selection: *const Opaque,
+            ) -> i32;
+            pub fn cusparseLtMatmulPlanDestroy(plan: *const Opaque) -> i32;
+            pub fn cusparseLtMatmulGetWorkspace(
+                handle: *const Opaque,
+                plan: *const Opaque,
+                workspace_size: *mut usize,
+            ) -> i32;
+            pub fn cusparseLtSpMMACompressedSize2(
+                handle: *const Opaque,
+                sparse_mat: *const Opaque,
+                compressed_size: *mut usize,
+                compress_buffer_size: *mut usize,
+            ) -> i32;
+            pub fn cusparseLtSpMMACompress2(
+                handle: *const Opaque,
+                sparse_mat: *const Opaque,
+                is_sparse_a: i32,
+                op: i32,
+                dense: *const c_void,
+                compressed: *mut c_void,
+                compress_buffer: *mut c_void,
+                stream: *mut c_void,
+            ) -> i32;
+            pub fn cusparseLtMatmul(
+                handle: *const Opaque,
+                plan: *const Opaque,
+                alpha: *const c_void,
+                d_a: *const c_void,
+                d_b: *const c_void,
+                beta: *const c_void,
+                d_c: *const c_void,
+                d_d: *mut c_void,
+                workspace: *mut c_void,
+                streams: *mut *mut c_void,
+                num_streams: i32,
+            ) -> i32;
+        }
+    }
+
+    // The dimensions of the 16 bits matrices have to be multiples of 16 for the sparse tensor
+    // cores, see the requirements of `cusparseLtStructuredDescriptorInit`.
+    const ALIGNMENT: usize = 16;
+
+    /// Whether a matrix dimension can be used by cuSPARSELt.
+    pub fn is_aligned(dim: usize) -> bool {
+        dim > 0 && dim % ALIGNMENT == 0
+    }
+
+    fn check(status: i32, what: &str) -> Result<()> {
+        if status != 0 {
+            candle::bail!("cusparselt {what} failed with status {status}")
+        }
+        Ok(())
+    }
+
+    fn opaque() -> Box<ffi::Opaque> {
+        Box::new(ffi::Opaque([0; 1024]))
+    }
+
+    fn device_ptr(t: &Tensor) -> Result<(*const c_void, candle::CudaDevice)> {
+        let (storage, layout) = t.storage_and_layout();
+        let storage = match &*storage {
+            Storage::Cuda(s) => s,
+            _ => candle::bail!("cusparselt expects cuda tensors"),
+        };
+        let offset = layout.start_offset() * t.dtype().size_in_bytes();
+        let ptr = match t.dtype() {
+            DType::F16 => *storage.as_cuda_slice::<half::f16>()?.device_ptr(),
+            DType::BF16 => *storage.as_cuda_slice::<half::bf16>()?.device_ptr(),
+            dtype => candle::bail!("cusparselt only supports f16 and bf16, got {dtype:?}"),
+        };
+        Ok((
+            (ptr + offset as u64) as *const c_void,
+            storage.device.clone(),
+        ))
+    }
+
+    struct Plan {
+        // The plan refers to the matmul descriptor and algorithm selection.
+        #[allow(dead_code)]
+        matmul: Box<ffi::Opaque>,
+        #[allow(dead_code)]
+        alg: Box<ffi::Opaque>,
+        plan: Box<ffi::Opaque>,
+        mat_b: Box<ffi::Opaque>,
+        mat_c: Box<ffi::Opaque>,
+        workspace_size: usize,
+    }
+
+    /// A weight of shape `(out_features, in_features)` compressed by cuSPARSELt.
+    pub struct SparseMatrix {
+        handle: Box<ffi::Opaque>,
+        mat_a: Box<ffi::Opaque>,
+        compressed: candle::cuda_backend::cudarc::driver::CudaSlice<u8>,
+        out_features: usize,
+        in_features: usize,
+        dtype: DType,
+        // The plans depend on the number of rows of the input.
+        plans: Mutex<HashMap<usize, Plan>>,
+    }
+
+    // SAFETY: The handle and descriptors are only read after their initialization, the plans
+    // are protected by a mutex.
+    unsafe impl Send for SparseMatrix {}
+    unsafe impl Sync for SparseMatrix {}
+
+    impl std::fmt::Debug for SparseMatrix {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(
+                f,
+                "SparseMatrix({}, {}, {:?})",
+                self.out_features, self.in_features, self.dtype
+            )
+        }
+    }
+
+    fn value_type(dtype: DType) -> Result<i32> {
+        match dtype {
+            DType::F16 => Ok(ffi::CUDA_R_16F),
+            DType::BF16 => Ok(ffi::CUDA_R_16BF),
+            dtype => candle::bail!("cusparselt only supports f16 and bf16, got {dtype:?}"),
+        }
+    }
+
+    impl SparseMatrix {
+        /// Compresses a dense weight following the 2:4 pattern.
+        pub fn new(weight: &Tensor) -> Result<Self> {
+            let weight = weight.contiguous()?;
+            let (out_features, in_features) = weight.dims2()?;
+            if !is_aligned(out_features) || !is_aligned(in_features) {
+                candle::bail!(
+                    "cusparselt expects weight dims that are multiples of {ALIGNMENT}, got {:?}",
+                    weight.shape()
+                )
+            }
+            let dtype = weight.dtype();
+            let (dense, dev) = device_ptr(&weight)?;
+            let mut handle = opaque();
+            let mut mat_a = opaque();
+            unsafe {
+                check(ffi::cusparseLtInit(&mut *handle), "init")?;
+                check(
+                    ffi::cusparseLtStructuredDescriptorInit(
+                        &*handle,
+                        &mut *mat_a,
+                        out_features as i64,
+                        in_features as i64,
+                        in_features as i64,
+                        16,
+                        value_type(dtype)?,
+                        ffi::CUSPARSE_ORDER_ROW,
+                        ffi::CUSPARSELT_SPARSITY_50_PERCENT,
+                    ),
+                    "structured descriptor init",
+                )?;
+            }
+            let (mut compressed_size, mut buffer_size) = (0, 0);
+            unsafe {
+                check(
+                    ffi::cusparseLtSpMMACompressedSize2(
+                        &*handle,
+                        &*mat_a,
+                        &mut compressed_size,
+                        &mut buffer_size,
+                    ),
+                    "compressed size",
+                )?;
+            }
+            // SAFETY: Set by the compression below.
+            let mut compressed = unsafe { dev.alloc::<u8>(compressed_size) }.w()?;
+            let mut buffer = unsafe { dev.alloc::<u8>(buffer_size.max(1)) }.w()?;
+            unsafe {
+                check(
+                    ffi::cusparseLtSpMMACompress2(
+                        &*handle,
+                        &*mat_a,
+                        1,
+                        ffi::CUSPARSE_OPERATION_NON_TRANSPOSE,
+                        dense,
+                        *compressed.device_ptr_mut() as *mut c_void,
+                        *buffer.device_ptr_mut() as *mut c_void,
+                        *dev.cu_stream() as *mut c_void,
+                    ),
+                    "compress",
+                )?;
+            }
+            dev.synchronize().w()?;
+            Ok(Self {
+                handle,
+                mat_a,
+                compressed,
+                out_features,
+                in_features,
+                dtype,
+                plans: Mutex::new(HashMap::new()),
+            })
+        }
+
+        fn plan(&self, n: usize) -> Result<Plan> {
+            let value_type = value_type(self.dtype)?;
+            let (mut mat_b, mut mat_c) = (opaque(), opaque());
+            let (mut matmul, mut alg, mut plan) = (opaque(), opaque(), opaque());
+            let mut workspace_size = 0;
+            // `ys.t() = w @ xs.t()`, the row major `xs` and `ys` being the column major `xs.t()`
+            // and `ys.t()`.
+            unsafe {
+                check(
+                    ffi::cusparseLtDenseDescriptorInit(
+                        &*self.handle,
+                        &mut *mat_b,
+                        self.in_features as i64,
+                        n as i64,
+                        self.in_features as i64,
+                        16,
+                        value_type,
+                        ffi::CUSPARSE_ORDER_COL,
+                    ),
+                    "dense descriptor init",
+                )?;
+                check(
+                    ffi::cusparseLtDenseDescriptorInit(
+                        &*self.handle,
+                        &mut *mat_c,
+                        self.out_features as i64,
+                        n as i64,
+                        self.out_features as i64,
+                        16,
+                        value_type,
+                        ffi::CUSPARSE_ORDER_COL,
+                    ),
+                    "dense descriptor init",
+                )?;
+                check(
+                    ffi::cusparseLtMatmulDescriptorInit(
+                        &*self.handle,
+                        &mut *matmul,
+                        ffi::CUSPARSE_OPERATION_NON_TRANSPOSE,
+                        ffi::CUSPARSE_OPERATION_NON_TRANSPOSE,
+                        &*self.mat_a,
+                        &*mat_b,
+                        &*mat_c,
+                        &*mat_c,
+                        ffi::CUSPARSE_COMPUTE_32F,
+                    ),
+                    "matmul descriptor init",
+                )?;
+                check(
+                    ffi::cusparseLtMatmulAlgSelectionInit(
+                        &*self.handle,
+                        &mut *alg,
+                        &*matmul,
+                        ffi::CUSPARSELT_MATMUL_ALG_DEFAULT,
+                    ),
+                    "algorithm selection",
+                )?;
+                check(
+                    ffi::cusparseLtMatmulPlanInit(&*self.handle, &mut *plan, &*matmul, &*alg),
+                    "plan init",
+                )?;
+                check(
+                    ffi::cusparseLtMatmulGetWorkspace(&*self.handle, &*plan, &mut workspace_size),
+                    "workspace size",
+                )?;
+            }
+            Ok(Plan {
+                matmul,
+                alg,
+                plan,
+                mat_b,
+                mat_c,
+                workspace_size,
+            })
+        }
+
+        /// Computes `xs @ w.t()` for `xs` of shape `(n, in_features)`.
+        pub fn matmul_t(&self, xs: &Tensor) -> Result<Tensor> {
+            let (n, in_features) = xs.dims2()?;
+            if !is_aligned(n) {
+                candle::bail!("cusparselt expects a multiple of {ALIGNMENT} rows, got {n}")
+            }
+            if in_features != self.in_features || xs.dtype() != self.dtype {
+                candle::bail!(
+                    "cusparselt matmul: expected {} {:?} input features, got {:?} {:?}",
+                    self.in_features,
+                    self.dtype,
+                    xs.shape(),
+                    xs.dtype()
+                )
+            }
+            let xs = xs.contiguous()?;
+            let ys = Tensor::zeros((n, self.out_features), self.dtype, xs.device())?;
+            let (d_b, dev) = device_ptr(&xs)?;
+            let (d_c, _) = device_ptr(&ys)?;
+            let mut plans = self.plans.lock().unwrap();
+            if !plans.contains_key(&n) {
+                plans.insert(n, self.plan(n)?);
+            }
+            let plan = &plans[&n];
+            let mut workspace = unsafe { dev.alloc::<u8>(plan.workspace_size.max(1)) }.w()?;
+            let (alpha, beta) = (1f32, 0f32);
+            let mut stream = *dev.cu_stream() as *mut c_void;
+            unsafe {
+                check(
+                    ffi::cusparseLtMatmul(
+                        &*self.handle,
+                        &*plan.plan,
+                        &alpha as *const f32 as *const c_void,
+                        *self.compressed.device_ptr() as *const c_void,
+                        d_b,
+                        &beta as *const f32 as *const c_void,
+                        d_c,
+                        d_c as *mut c_void,
+                        *workspace.device_ptr_mut() as *mut c_void,
+                        &mut stream,
+                        1,
+                    ),
+                    "matmul",
+                )?;
+            }
+            Ok(ys)
+        }
+    }
+
+    impl Drop for Plan {
+        fn drop(&mut self) {
+            unsafe {
+                ffi::cusparseLtMatmulPlanDestroy(&*self.plan);
+                ffi::cusparseLtMatDescriptorDestroy(&*self.mat_b);
+                ffi::cusparseLtMatDescriptorDestroy(&*self.mat_c);
+            }
+        }
+    }
+
+    impl Drop for SparseMatrix {
+        fn drop(&mut self) {
+            self.plans.lock().unwrap().clear();
+            unsafe {
+                ffi::cusparseLtMatDescriptorDestroy(&*self.mat_a);
+                ffi::cusparseLtDestroy(&*self.handle);
+            }
+        }
+    }
+}
@@ -0,0 +1,215 @@
+//! Tensor parallel layers.
+//!
+//! The weights of these layers are sharded across the ranks of a `Communicator`, each rank only
+//! holds its own shard and the collective operations are used to combine the partial results.
+//! The `load` functions use a `ShardedVarBuilder` so that each rank only reads its shard from
+//! the checkpoint.
+use crate::var_builder::{Shard, ShardedVarBuilder};
+use crate::{Embedding, Linear};
+use candle::collective::{Communicator, ReduceOp};
+use candle::{DType, Module, Result, Tensor, D};
+use std::sync::Arc;
+
+fn shard(dim: usize, comm: &dyn Communicator) -> Shard {
+    Shard {
+        dim,
+        rank: comm.rank(),
+        world_size: comm.world_size(),
+    }
+}
+
+fn shard_size(size: usize, comm: &dyn Communicator) -> Result<usize> {
+    let world_size = comm.world_size();
+    if size % world_size != 0 {
+        candle::bail!("cannot shard a dimension of size {size} on {world_size} ranks")
+    }
+    Ok(size / world_size)
+}
+
+/// A linear layer where the output dimension is sharded across the ranks, i.e. the weight
+/// matrix is split along its first dimension.
+///
+/// The input is expected to be the same on all the ranks. When `gather_output` is false, each
+/// rank returns its own slice of the output features, which can be fed directly to a
+/// `RowParallelLinear` layer.
+#[derive(Clone)]
+pub struct ColumnParallelLinear {
+    linear: Linear,
+    comm: Arc<dyn Communicator>,
+    gather_output: bool,
+}
+
+impl ColumnParallelLinear {
+    pub fn new(linear: Linear, comm: Arc<dyn Communicator>, gather_output: bool) -> Self {
+        Self {
+            linear,
+            comm,
+            gather_output,
+        }
+    }
+
+    pub fn load(
+        in_dim: usize,
+        out_dim: usize,
+        bias: bool,
+        gather_output: bool,
+        vb: ShardedVarBuilder,
+        comm: Arc<dyn Communicator>,
+    ) -> Result<Self> {
+        let out_dim = shard_size(out_dim, comm.as_ref())?;
+        let hints = shard(0, comm.as_ref());
+        let weight = vb.get_with_hints((out_dim, in_dim), "weight", hints)?;
+        let bias = if bias {
+            Some(vb.get_with_hints(out_dim, "bias", hints)?)
+        } else {
+            None
+        };
+        Ok(Self::new(Linear::new(weight, bias), comm, gather_output))
+    }
+
+    pub fn linear(&self) -> &Linear {
+        &self.linear
+    }
+}
+
+impl Module for ColumnParallelLinear {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let ys = self.linear.forward(xs)?;
+        if self.gather_output {
+            self.comm.all_gather(&ys, ys.rank() - 1)
+        } else {
+            Ok(ys)
+        }
+    }
+}
+
+/// A linear layer where the input dimension is sharded across the ranks, i.e. the weight matrix
+/// is split along its second dimension. The partial results are summed with an all-reduce.
+///
+/// When `input_is_parallel` is true, each rank is expected to receive its own slice of the input
+/// features, typically the output of a `ColumnParallelLinear` layer. Otherwise the full input is
+/// expected and each rank selects its slice.
+#[derive(Clone)]
+pub struct RowParallelLinear {
+    linear: Linear,
+    bias: Option<Tensor>,
+    comm: Arc<dyn Communicator>,
+    input_is_parallel: bool,
+}
+
+impl RowParallelLinear {
+    /// Creates the layer from a weight shard, the bias is not sharded and is added after the
+    /// all-reduce.
+    pub fn new(
+        weight: Tensor,
+        bias: Option<Tensor>,
+        comm: Arc<dyn Communicator>,
+        input_is_parallel: bool,
+    ) -> Self {
+        Self {
+            linear: Linear::new(weight, None),
+            bias,
+            comm,
+            input_is_parallel,
+        }
+    }
+
+    pub fn load(
+        in_dim: usize,
+        out_dim: usize,
+        bias: bool,
+        input_is_parallel: bool,
+        vb: ShardedVarBuilder,
+        comm: Arc<dyn Communicator>,
+    ) -> Result<Self> {
+        let in_dim = shard_size(in_dim, comm.as_ref())?;
+        let weight = vb.get_with_hints((out_dim, in_dim), "weight", shard(1, comm.as_ref()))?;
+        let bias = if bias {
+            Some(vb.get_with_hints(out_dim, "bias", Shard::default())?)
+        } else {
+            None
+        };
+        Ok(Self::new(weight, bias, comm, input_is_parallel))
+    }
+
+    pub fn weight(&self) -> &Tensor {
+        self.linear.weight()
+    }
+
+    pub fn bias(&self) -> Option<&Tensor> {
+        self.bias.as_ref()
+    }
+}
+
+impl Module for RowParallelLinear {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let xs = if self.input_is_parallel {
+            xs.clone()
+        } else {
+            let size = shard_size(xs.dim(D::Minus1)?, self.comm.as_ref())?;
+            xs.narrow(D::Minus1, self.comm.rank() * size, size)?
+        };
+        let ys = self.linear.forward(&xs)?;
+        let ys = self.comm.all_reduce(&ys, ReduceOp::Sum)?;
+        match &self.bias {
+            None => Ok(ys),
+            Some(bias) => ys.broadcast_add(bias),
+        }
+    }
+}
+
+/// An embedding layer where the vocabulary is sharded across the ranks. Each rank looks up the
+/// indexes that fall in its vocabulary range and the results are summed with an all-reduce.
+#[derive(Clone)]
+pub struct ParallelEmbedding {
+    embedding: Embedding,
+    vocab_start: usize,
+    comm: Arc<dyn Communicator>,
+}
+
+impl ParallelEmbedding {
+    /// Creates the layer from the embedding shard of the current rank, the shard is expected to
+    /// contain the rows starting at `rank * shard_size`.
+    pub fn new(embeddings: Tensor, comm: Arc<dyn Communicator>) -> Result<Self> {
+        let (vocab_size, hidden_size) = embeddings.dims2()?;
+        let vocab_start = comm.rank() * vocab_size;
+        Ok(Self {
+            embedding: Embedding::new(embeddings, hidden_size),
+            vocab_start,
+            comm,
+        })
+    }
+
+    pub fn load(
+        vocab_size: usize,
+        hidden_size: usize,
+        vb: ShardedVarBuilder,
+        comm: Arc<dyn Communicator>,
+    ) -> Result<Self> {
+        let vocab_size = shard_size(vocab_size, comm.as_ref())?;
+        let hints = shard(0, comm.as_ref());
+        let embeddings = vb.get_with_hints((vocab_size, hidden_size), "weight", hints)?;
+        Self::new(embeddings, comm)
+    }
+
+    pub fn embeddings(&self) -> &Tensor {
+        self.embedding.embeddings()
+    }
+}
+
+impl Module for ParallelEmbedding {
+    fn forward(&self, indexes: &Tensor) -> Result<Tensor> {
+        let shard_size = self.embedding.embeddings().dim(0)?;
+        let start = self.vocab_start;
+        let indexes = indexes.to_dtype(DType::I64)?;
+        let in_shard = (indexes.ge(start as f64)? * indexes.lt((start + shard_size) as f64)?)?;
+        let local = indexes
+            .affine(1., -(start as f64))?
+            .clamp(0f64, (shard_size - 1) as f64)?
+            .to_dtype(DType::U32)?;
+        let ys = self.embedding.forward(&local)?;
+        let mask = in_shard.unsqueeze(D::Minus1)?.to_dtype(ys.dtype())?;
+        let ys = ys.broadcast_mul(&mask)?;
+        self.comm.all_reduce(&ys, ReduceOp::Sum)
+    }
+}
@@ -0,0 +1,701 @@
+//! A training loop with gradient accumulation, checkpointing and callbacks.
+//!
+//! The `Trainer` drives an optimizer over the variables of a `VarMap`. The loss for each batch
+//! is computed by a user provided closure, the trainer takes care of the backward pass, gradient
+//! accumulation and clipping, saving and resuming checkpoints, and calling the callbacks, e.g.
+//! to log metrics or stop training early.
+//!
+//! ```ignore
+//! let config = TrainerConfig {
+//!     epochs: 10,
+//!     grad_accumulation_steps: 4,
+//!     checkpoint_dir: Some("checkpoints".into()),
+//!     checkpoint_every: Some(1000),
+//!     ..Default::default()
+//! };
+//! let opt = AdamW::new_lr(varmap.all_vars(), 1e-3)?;
+//! let mut trainer = Trainer::new(&varmap, opt, config)
+//!     .with_callback(EarlyStopping::new(3, 0.));
+//! trainer.resume_from_latest()?;
+//! trainer.fit(
+//!     |_epoch| Ok(dataset.train_batches()),
+//!     |(xs, ys)| loss::mse(&model.forward(xs)?, ys),
+//!     Some(|| evaluate(&model)),
+//! )?;
+//! ```
+use crate::diagnostics::{fetch_scalars, sq_norm, NoiseScaleEstimator, StepDiagnostics};
+use crate::{Optimizer, VarMap};
+use candle::backprop::GradStore;
+use candle::{CancelToken, DType, Result, Tensor};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+const MODEL_FILE: &str = "model.safetensors";
+const TRAINING_STATE_FILE: &str = "training_state.safetensors";
+const CHECKPOINT_PREFIX: &str = "checkpoint-";
+
+#[derive(Debug, Clone)]
+pub struct TrainerConfig {
+    /// The number of passes over the training data performed by `fit`.
+    pub epochs: usize,
+    /// The number of batches whose gradients are accumulated for each optimizer step. The loss
+    /// of each batch is divided by this number so that the gradients are averaged.
+    pub grad_accumulation_steps: usize,
+    /// When set, the gradients are clipped to this global l2 norm before each optimizer step.
+    pub max_grad_norm: Option<f64>,
+    /// The directory in which the checkpoints are written, each checkpoint being stored in a
+    /// `checkpoint-{step}` sub-directory.
+    pub checkpoint_dir: Option<PathBuf>,
+    /// Save a checkpoint every this number of optimizer steps, a checkpoint is always saved at
+    /// the end of each epoch when a checkpoint directory is set.
+    pub checkpoint_every: Option<usize>,
+    /// The number of checkpoints to keep, older ones are removed. All the checkpoints are kept
+    /// when `None`.
+    pub keep_checkpoints: Option<usize>,
+    /// Run the evaluation every this number of optimizer steps, the evaluation is always run at
+    /// the end of each epoch.
+    pub eval_every: Option<usize>,
+    /// Compute the per-variable gradient norms, update to weight ratios and gradient noise
+    /// scale at each optimizer step, see the `diagnostics` module. This requires a copy of the
+    /// weights during each optimizer step.
+    pub diagnostics: bool,
+}
+
+impl Default for TrainerConfig {
+    fn default() -> Self {
+        Self {
+            epochs: 1,
+            grad_accumulation_steps: 1,
+            max_grad_norm: None,
+            checkpoint_dir: None,
+            checkpoint_every: None,
+            keep_checkpoints: None,
+            eval_every: None,
+            diagnostics: false,
+        }
+    }
+}
+
+/// The progress of the training, this is saved in the checkpoints.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TrainerState {
+    /// The current epoch, starting from 0.
+    pub epoch: usize,
+    /// The number of optimizer steps performed so far.
+    pub step: usize,
+    /// The number of batches processed in the current epoch.
+    pub batch_in_epoch: usize,
+    /// The average loss over the batches of the last optimizer step.
+    pub last_loss: Option<f64>,
+    pub best_eval_loss: Option<f64>,
+    /// A seed for the random number generators used by the training loop, e.g. to shuffle the
+    /// data. Deriving the generators for an epoch from this seed and the epoch index, see
+    /// `epoch_seed`, makes the training reproducible when resuming from a checkpoint.
+    pub seed: u64,
+}
+
+impl TrainerState {
+    /// A seed for the random number generators of the current epoch.
+    pub fn epoch_seed(&self) -> u64 {
+        // splitmix64 so that consecutive epochs get unrelated seeds.
+        let mut z = self
+            .seed
+            .wrapping_add((self.epoch as u64 + 1).wrapping_mul(0x9E3779B97F4A7C15));
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+}
+
+/// The metrics for an optimizer step.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StepMetrics {
+    /// The average loss over the accumulated batches.
+    pub loss: f64,
+    /// The gradient norm before clipping, only available when clipping is enabled.
+    pub grad_norm: Option<f64>,
+    pub learning_rate: f64,
+    /// The training diagnostics, only available when `TrainerConfig::diagnostics` is set.
+    pub diagnostics: Option<StepDiagnostics>,
+}
+
+/// Whether training should continue after a callback.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Control {
+    Continue,
+    Stop,
+}
+
+/// Hooks called by the trainer, all the methods default to doing nothing.
+pub trait Callback {
+    /// Called after each optimizer step.
+    fn on_step(&mut self, _state: &TrainerState, _metrics: &StepMetrics) -> Result<Control> {
+        Ok(Control::Continue)
+    }
+
+    /// Called at the end of each epoch, after the evaluation.
+    fn on_epoch(&mut self, _state: &TrainerState) -> Result<Control> {
+        Ok(Control::Continue)
+    }
+
+    /// Called after each evaluation with the evaluation loss.
+    fn on_eval(&mut self, _state: &TrainerState, _eval_loss: f64) -> Result<Control> {
+        Ok(Control::Continue)
+    }
+}
+
+/// Stops training when the evaluation loss has not improved by at least `min_delta` for
+/// `patience` evaluations.
+#[derive(Debug, Clone)]
+pub struct EarlyStopping {
+    patience: usize,
+    min_delta: f64,
+    best: Option<f64>,
+    bad_evals: usize,
+}
+
+impl EarlyStopping {
+    pub fn new(patience: usize, min_delta: f64) -> Self {
+        Self {
+            patience,
+            min_delta,
+            best: None,
+            bad_evals: 0,
+        }
+    }
+}
+
+impl Callback for EarlyStopping {
+    fn on_eval(&mut self, _state: &TrainerState, eval_loss: f64) -> Result<Control> {
+        match self.best {
+            Some(best) if eval_loss > best - self.min_delta => self.bad_evals += 1,
+            _ => {
+                self.best = Some(eval_loss);
+                self.bad_evals = 0
+            }
+        }
+        if self.bad_evals >= self.patience {
+            Ok(Control::Stop)
+        } else {
+            Ok(Control::Continue)
+        }
+    }
+}
+
+// Adds the gradients from `grads` to the ones from `acc` for the variables in `vars`.
+fn accumulate(acc: &mut GradStore, grads: GradStore, vars: &[candle::Var]) -> Result<()> {
+    for var in vars.iter() {
+        if let Some(g) = grads.get(var) {
+            let g = match acc.get(var) {
+                None => g.clone(),
+                Some(prev) => (prev + g)?,
+            };
+            acc.insert(var, g);
+        }
+    }
+    Ok(())
+}
+
+/// Runs the training loop for the variables of a `VarMap` using optimizer `O`.
+pub struct Trainer<O: Optimizer> {
+    varmap: VarMap,
+    optimizer: O,
+    config: TrainerConfig,
+    callbacks: Vec<Box<dyn Callback>>,
+    state: TrainerState,
+    grads: Option<GradStore>,
+    // The sum of the losses and number of batches since the last optimizer step.
+    loss_sum: f64,
+    micro_steps: usize,
+    // The sum of the squared gradient norms of the batches since the last optimizer step, only
+    // tracked when diagnostics are enabled.
+    micro_sq_norm: Option<Tensor>,
+    noise_scale: NoiseScaleEstimator,
+    cancel: Option<CancelToken>,
+    // The keyed dropout state at the start of the current accumulation window, the batches of
+    // an incomplete window being processed again on resume.
+    window_dropout_state: Option<Option<(u64, u64)>>,
+}
+
+impl<O: Optimizer> Trainer<O> {
+    /// Creates a trainer, `optimizer` should have been created from the variables of `varmap`.
+    pub fn new(varmap: &VarMap, optimizer: O, config: TrainerConfig) -> Self {
+        Self {
+            varmap: varmap.clone(),
+            optimizer,
+            config,
+            callbacks: vec![],
+            state: TrainerState::default(),
+            grads: None,
+            loss_sum: 0.,
+            micro_steps: 0,
+            micro_sq_norm: None,
+            noise_scale: NoiseScaleEstimator::new(0.9),
+            cancel: None,
+            window_dropout_state: None,
+        }
+    }
+
+    /// Sets the seed recorded in the trainer state, see `TrainerState::seed`.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.state.seed = seed;
+        self
+    }
+
+    /// Sets a token to cancel `fit` from another thread. The cancellation is checked before each
+    /// batch, the gradients accumulated since the last optimizer step are then discarded and
+    /// `fit` returns the state after the last optimizer step, no checkpoint is saved.
+    pub fn with_cancel_token(mut self, cancel: CancelToken) -> Self {
+        self.cancel = Some(cancel);
+        self
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancel.as_ref().is_some_and(|c| c.is_cancelled())
+    }
+
+    pub fn with_callback<C: Callback + 'static>(mut self, callback: C) -> Self {
+        self.callbacks.push(Box::new(callback));
+        self
+    }
+
+    pub fn add_callback<C: Callback + 'static>(&mut self, callback: C) {
+        self.callbacks.push(Box::new(callback))
+    }
+
+    pub fn state(&self) -> &TrainerState {
+        &self.state
+    }
+
+    pub fn optimizer(&self) -> &O {
+        &self.optimizer
+    }
+
+    pub fn optimizer_mut(&mut self) -> &mut O {
+        &mut self.optimizer
+    }
+
+    pub fn config(&self) -> &TrainerConfig {
+        &self.config
+    }
+
+    fn run_callbacks<F>(&mut self, mut f: F) -> Result<Control>
+    where
+        F: FnMut(&mut dyn Callback, &TrainerState) -> Result<Control>,
+    {
+        let mut control = Control::Continue;
+        for callback in self.callbacks.iter_mut() {
+            // All the callbacks are run even if one of them asks to stop.
+            if f(callback.as_mut(), &self.state)? == Control::Stop {
+                control = Control::Stop
+            }
+        }
+        Ok(control)
+    }
+
+    /// Runs the backward pass for the loss of a batch and accumulates the gradients, an
+    /// optimizer step is performed once `grad_accumulation_steps` batches have been processed.
+    pub fn step(&mut self, loss: &Tensor) -> Result<Control> {
+        let n_acc = self.config.grad_accumulation_steps.max(1);
+        self.loss_sum += loss.to_dtype(DType::F64)?.to_scalar::<f64>()?;
+        self.micro_steps += 1;
+        self.state.batch_in_epoch += 1;
+        let grads = (loss / n_acc as f64)?.backward()?;
+        let vars = self.varmap.all_vars();
+        if self.config.diagnostics {
+            // The norm of the gradient for this batch alone, kept on the device.
+            let mut sq = Tensor::new(0f32, loss.device())?;
+            for var in vars.iter() {
+                if let Some(g) = grads.get(var) {
+                    sq = (sq + sq_norm(g)?.to_device(loss.device())?)?;
+                }
+            }
+            let sq = (sq * (n_acc * n_acc) as f64)?;
+            self.micro_sq_norm = Some(match self.micro_sq_norm.take() {
+                None => sq,
+                Some(acc) => (acc + sq)?,
+            });
+        }
+        match &mut self.grads {
+            None => self.grads = Some(grads),
+            Some(acc) => accumulate(acc, grads, &vars)?,
+        }
+        if self.micro_steps < n_acc {
+            return Ok(Control::Continue);
+        }
+        self.optimizer_step()
+    }
+
+    // Applies the accumulated gradients, does nothing if there are none.
+    fn optimizer_step(&mut self) -> Result<Control> {
+        let mut grads = match self.grads.take() {
+            None => return Ok(Control::Continue),
+            Some(grads) => grads,
+        };
+        // The gradients before clipping and the weights before the update.
+        let before = if self.config.diagnostics {
+            let mut before = vec![];
+            for (name, var) in self.named_vars() {
+                if let Some(g) = grads.get(&var) {
+                    let g = g.clone();
+                    let w = var.as_tensor().copy()?;
+                    before.push((name, var, g, w))
+                }
+            }
+            Some(before)
+        } else {
+            None
+        };
+        let grad_norm = match self.config.max_grad_norm {
+            None => None,
+            Some(max_norm) => {
+                let vars = self.varmap.all_vars();
+                let norm = crate::optim::clip_grad_norm(&vars, &mut grads, max_norm)?;
+                Some(norm.to_dtype(DType::F64)?.to_scalar::<f64>()?)
+            }
+        };
+        self.optimizer.step(&grads)?;
+        let diagnostics = match before {
+            None => None,
+            Some(before) => Some(self.diagnostics(before)?),
+        };
+        let metrics = StepMetrics {
+            loss: self.loss_sum / self.micro_steps as f64,
+            grad_norm,
+            learning_rate: self.optimizer.learning_rate(),
+            diagnostics,
+        };
+        self.loss_sum = 0.;
+        self.micro_steps = 0;
+        self.micro_sq_norm = None;
+        self.window_dropout_state = None;
+        self.state.step += 1;
+        self.state.last_loss = Some(metrics.loss);
+        let control = self.run_callbacks(|c, s| c.on_step(s, &metrics))?;
+        if let Some(every) = self.config.checkpoint_every {
+            if self.state.step % every == 0 {
+                self.save_checkpoint()?;
+            }
+        }
+        Ok(control)
+    }
+
+    fn named_vars(&self) -> Vec<(String, candle::Var)> {
+        let data = self.varmap.data().lock().unwrap();
+        let mut vars = data
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect::<Vec<_>>();
+        vars.sort_by(|a, b| a.0.cmp(&b.0));
+        vars
+    }
+
+    // Computes the diagnostics after an optimizer step from the gradients and weights before
+    // the step, all the norms are retrieved from the device at once.
+    fn diagnostics(
+        &mut self,
+        before: Vec<(String, candle::Var, Tensor, Tensor)>,
+    ) -> Result<StepDiagnostics> {
+        let n = before.len();
+        let mut scalars = Vec::with_capacity(3 * n + 1);
+        for (_, _, g, _) in before.iter() {
+            scalars.push(sq_norm(g)?)
+        }
+        for (_, var, _, w) in before.iter() {
+            scalars.push(sq_norm(&(var.as_tensor() - w)?)?)
+        }
+        for (_, _, _, w) in before.iter() {
+            scalars.push(sq_norm(w)?)
+        }
+        if let Some(micro) = &self.micro_sq_norm {
+            scalars.push(micro.clone())
+        }
+        let values = fetch_scalars(&scalars)?;
+        let (grad_sq, rest) = values.split_at(n);
+        let (update_sq, rest) = rest.split_at(n);
+        let (weight_sq, micro_sq) = rest.split_at(n);
+        let mut grad_norms = Vec::with_capacity(n);
+        let mut update_ratios = Vec::with_capacity(n);
+        for (i, (name, _, _, _)) in before.into_iter().enumerate() {
+            grad_norms.push((name.clone(), grad_sq[i].sqrt()));
+            // Zero weights, e.g. freshly initialized biases, have no meaningful ratio.
+            let ratio = if weight_sq[i] > 0. {
+                (update_sq[i] / weight_sq[i]).sqrt()
+            } else {
+                0.
+            };
+            update_ratios.push((name, ratio));
+        }
+        let global_sq: f64 = grad_sq.iter().sum();
+        let micro_steps = self.micro_steps as f64;
+        let noise_scale = match micro_sq.first() {
+            Some(micro_sq) if self.micro_steps >= 2 => {
+                // The accumulated gradients are divided by `grad_accumulation_steps` which can
+                // be larger than the number of batches for the last window of an epoch.
+                let n_acc = self.config.grad_accumulation_steps.max(1) as f64;
+                let big_sq = global_sq * (n_acc / micro_steps).powi(2);
+                self.noise_scale
+                    .update(micro_sq / micro_steps, big_sq, 1., micro_steps)
+            }
+            _ => self.noise_scale.estimate(),
+        };
+        Ok(StepDiagnostics {
+            grad_norms,
+            update_ratios,
+            global_grad_norm: global_sq.sqrt(),
+            noise_scale,
+        })
+    }
+
+    /// Records an evaluation loss and runs the callbacks.
+    pub fn eval(&mut self, eval_loss: f64) -> Result<Control> {
+        if self
+            .state
+            .best_eval_loss
+            .is_none_or(|best| eval_loss < best)
+        {
+            self.state.best_eval_loss = Some(eval_loss)
+        }
+        self.run_callbacks(|c, s| c.on_eval(s, eval_loss))
+    }
+
+    /// Trains for the remaining epochs, resuming from the current state.
+    ///
+    /// * [batches]: returns the batches for an epoch. When resuming in the middle of an epoch,
+    ///   the batches that have already been processed are skipped so the iteration order should
+    ///   be deterministic for a given epoch.
+    /// * [loss_fn]: computes the loss for a batch.
+    /// * [eval_fn]: computes the evaluation loss, run at the end of each epoch and every
+    ///   `eval_every` steps.
+    pub fn fit<B, I, BF, LF, EF>(
+        &mut self,
+        mut batches: BF,
+        mut loss_fn: LF,
+        mut eval_fn: Option<EF>,
+    ) -> Result<TrainerState>
+    where
+        I: IntoIterator<Item = Result<B>>,
+        BF: FnMut(usize) -> Result<I>,
+        LF: FnMut(&B) -> Result<Tensor>,
+        EF: FnMut() -> Result<f64>,
+    {
+        while self.state.epoch < self.config.epochs {
+            let skip = self.state.batch_in_epoch;
+            let mut control = Control::Continue;
+            for batch in batches(self.state.epoch)?.into_iter().skip(skip) {
+                if self.is_cancelled() {
+                    control = Control::Stop;
+                    break;
+                }
+                // Each batch gets its own dropout masks, the batch index being restored on
+                // resume so keyed masks are reproducible.
+                let n_acc = self.config.grad_accumulation_steps.max(1);
+                crate::ops::set_dropout_step((self.state.step * n_acc + self.micro_steps) as u64);
+                if self.micro_steps == 0 {
+                    self.window_dropout_state = Some(crate::ops::dropout_rng_state());
+                }
+                let loss = loss_fn(&batch?)?;
+                control = self.step(&loss)?;
+                if self.micro_steps == 0 {
+                    if let (Some(every), Some(eval_fn)) = (self.config.eval_every, &mut eval_fn) {
+                        if self.state.step % every == 0 && self.eval(eval_fn()?)? == Control::Stop {
+                            control = Control::Stop
+                        }
+                    }
+                }
+                if control == Control::Stop {
+                    break;
+                }
+            }
+            if control == Control::Stop {
+                // Pending gradients are dropped so that the state matches the last step.
+                self.discard_pending();
+                break;
+            }
+            // The last batches of the epoch may not fill a full accumulation window.
+            if self.optimizer_step()? == Control::Stop {
+                control = Control::Stop
+            }
+            if let Some(eval_fn) = &mut eval_fn {
+                if self.eval(eval_fn()?)? == Control::Stop {
+                    control = Control::Stop
+                }
+            }
+            if self.run_callbacks(|c, s| c.on_epoch(s))? == Control::Stop {
+                control = Control::Stop
+            }
+            self.state.epoch += 1;
+            self.state.batch_in_epoch = 0;
+            if self.config.checkpoint_dir.is_some() {
+                self.save_checkpoint()?;
+            }
+            if control == Control::Stop {
+                break;
+            }
+        }
+        Ok(self.state.clone())
+    }
+
+    fn discard_pending(&mut self) {
+        self.state.batch_in_epoch -= self.micro_steps;
+        if let Some(Some((seed, offset))) = self.window_dropout_state.take() {
+            if self.micro_steps > 0 {
+                crate::ops::set_dropout_rng_state(seed, offset)
+            }
+        }
+        self.grads = None;
+        self.loss_sum = 0.;
+        self.micro_steps = 0;
+        self.micro_sq_norm = None;
+    }
+
+    // The keyed dropout state matching the batch index saved in the checkpoints.
+    fn checkpoint_dropout_state(&self) -> Option<(u64, u64)> {
+        match self.window_dropout_state {
+            Some(state) if self.micro_steps > 0 => state,
+            _ => crate::ops::dropout_rng_state(),
+        }
+    }
+
+    /// Saves a checkpoint in `checkpoint_dir/checkpoint-{step}`, returns the checkpoint
+    /// directory. Does nothing if no checkpoint directory has been configured.
+    ///
+    /// The checkpoint is made of the model weights in `model.safetensors`, which can be loaded
+    /// as regular weights, and the optimizer and trainer states in `training_state.safetensors`.
+    /// Gradients that have been accumulated but not applied yet are not saved. The training state
+    /// uses the versioned format from `optim::save_state`, the trainer state being stored in the
+    /// metadata together with the seed and offset of the keyed dropout masks, see
+    /// `ops::set_dropout_seed`, which are restored on resume.
+    pub fn save_checkpoint(&self) -> Result<Option<PathBuf>> {
+        let dir = match &self.config.checkpoint_dir {
+            None => return Ok(None),
+            Some(dir) => dir,
+        };
+        let path = dir.join(format!("{CHECKPOINT_PREFIX}{}", self.state.step));
+        self.save_checkpoint_to(&path)?;
+        if let Some(keep) = self.config.keep_checkpoints {
+            let checkpoints = list_checkpoints(dir)?;
+            let n_remove = checkpoints.len().saturating_sub(keep.max(1));
+            for (_, old) in checkpoints.iter().take(n_remove) {
+                std::fs::remove_dir_all(old)?
+            }
+        }
+        Ok(Some(path))
+    }
+
+    /// Saves a checkpoint in directory `path`, see `save_checkpoint`.
+    pub fn save_checkpoint_to<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        std::fs::create_dir_all(path)?;
+        self.varmap.save(path.join(MODEL_FILE))?;
+        let s = &self.state;
+        // Batches from an incomplete accumulation window are processed again on resume.
+        let batch_in_epoch = s.batch_in_epoch - self.micro_steps;
+        let mut metadata = HashMap::new();
+        let mut insert = |k: &str, v: String| metadata.insert(format!("trainer.{k}"), v);
+        insert("epoch", s.epoch.to_string());
+        insert("step", s.step.to_string());
+        insert("batch_in_epoch", batch_in_epoch.to_string());
+        insert("seed", s.seed.to_string());
+        if let Some((seed, offset)) = self.checkpoint_dropout_state() {
+            insert("dropout_seed", seed.to_string());
+            insert("dropout_offset", offset.to_string());
+        }
+        if let Some(v) = s.last_loss {
+            insert("last_loss", v.to_string());
+        }
+        if let Some(v) = s.best_eval_loss {
+            insert("best_eval_loss", v.to_string());
+        }
+        let optimizer = crate::optim::optimizer_name::<O>();
+        crate::optim::save_state(
+            &self.optimizer.state()?,
+            optimizer,
+            &metadata,
+            path.join(TRAINING_STATE_FILE),
+        )
+    }
+
+    /// Restores the model weights, optimizer state and trainer state from checkpoint directory
+    /// `path`.
+    pub fn resume_from_checkpoint<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        self.varmap.load(path.join(MODEL_FILE))?;
+        let optimizer = crate::optim::optimizer_name::<O>();
+        let state_path = path.join(TRAINING_STATE_FILE);
+        let (state, metadata) = crate::optim::load_state(&state_path, Some(optimizer))?;
+        self.optimizer.set_state(&state)?;
+        fn parse<T: std::str::FromStr>(
+            metadata: &HashMap<String, String>,
+            name: &str,
+        ) -> Result<Option<T>> {
+            match metadata.get(&format!("trainer.{name}")) {
+                None => Ok(None),
+                Some(v) => match v.parse::<T>() {
+                    Ok(v) => Ok(Some(v)),
+                    Err(_) => candle::bail!("invalid value for trainer.{name}: {v}"),
+                },
+            }
+        }
+        let get = |name: &str| match parse::<usize>(&metadata, name)? {
+            None => candle::bail!("missing trainer.{name} in {state_path:?}"),
+            Some(v) => Ok(v),
+        };
+        self.state = TrainerState {
+            epoch: get("epoch")?,
+            step: get("step")?,
+            batch_in_epoch: get("batch_in_epoch")?,
+            last_loss: parse(&metadata, "last_loss")?,
+            best_eval_loss: parse(&metadata, "best_eval_loss")?,
+            seed: parse(&metadata, "seed")?.unwrap_or_default(),
+        };
+        let dropout_seed = parse(&metadata, "dropout_seed")?;
+        let dropout_offset = parse(&metadata, "dropout_offset")?;
+        if let (Some(seed), Some(offset)) = (dropout_seed, dropout_offset) {
+            crate::ops::set_dropout_rng_state(seed, offset)
+        }
+        self.window_dropout_state = None;
+        self.grads = None;
+        self.loss_sum = 0.;
+        self.micro_steps = 0;
+        self.micro_sq_norm = None;
+        Ok(())
+    }
+
+    /// Resumes from the most recent checkpoint in the checkpoint directory if any, returns the
+    /// path of the checkpoint that has been loaded.
+    pub fn resume_from_latest(&mut self) -> Result<Option<PathBuf>> {
+        let latest = match &self.config.checkpoint_dir {
+            None => None,
+            Some(dir) => list_checkpoints(dir)?.pop().map(|(_, p)| p),
+        };
+        if let Some(path) = &latest {
+            self.resume_from_checkpoint(path)?
+        }
+        Ok(latest)
+    }
+}
+
+/// Returns the checkpoints in `dir` with their step, sorted by increasing step.
+pub fn list_checkpoints<P: AsRef<Path>>(dir: P) -> Result<Vec<(usize, PathBuf)>> {
+    let dir = dir.as_ref();
+    if !dir.exists() {
+        return Ok(vec![]);
+    }
+    let mut checkpoints = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let step = name
+            .to_str()
+            .and_then(|n| n.strip_prefix(CHECKPOINT_PREFIX))
+            .and_then(|s| s.parse::<usize>().ok());
+        if let Some(step) = step {
+            if entry.path().join(TRAINING_STATE_FILE).exists() {
+                checkpoints.push((step, entry.path()))
+            }
+        }
+    }
+    checkpoints.sort_by_key(|(step, _)| *step);
+    Ok(checkpoints)
+}
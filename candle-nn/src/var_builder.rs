@@ -380,7 +380,7 @@ impl SimpleBackend for candle::safetensors::MmapedSafetensors {
         dtype: DType,
         dev: &Device,
     ) -> Result<Tensor> {
-        let tensor = self.load(name, dev)?.to_dtype(dtype)?;
+        let tensor = self.load_as(name, dtype, dev)?;
         if tensor.shape() != &s {
             Err(candle::Error::UnexpectedShape {
                 msg: format!("shape mismatch for {name}"),
@@ -397,6 +397,56 @@ impl SimpleBackend for candle::safetensors::MmapedSafetensors {
     }
 }
 
+/// Memory mapped safetensors files for which only the tensors selected by a predicate are loaded
+/// on the target device, the other ones being loaded on the cpu.
+///
+/// This is useful for layer offloading setups where only some of the layers should reside on the
+/// accelerator, the other ones being moved there when needed.
+pub struct PinnedSafetensors {
+    tensors: candle::safetensors::MmapedSafetensors,
+    pin: Box<dyn Fn(&str) -> bool + Send + Sync>,
+}
+
+impl PinnedSafetensors {
+    pub fn new<F: Fn(&str) -> bool + Send + Sync + 'static>(
+        tensors: candle::safetensors::MmapedSafetensors,
+        pin: F,
+    ) -> Self {
+        Self {
+            tensors,
+            pin: Box::new(pin),
+        }
+    }
+}
+
+impl SimpleBackend for PinnedSafetensors {
+    fn get(
+        &self,
+        s: Shape,
+        name: &str,
+        _: crate::Init,
+        dtype: DType,
+        dev: &Device,
+    ) -> Result<Tensor> {
+        // Check the shape using the header before reading any data.
+        let view = self.tensors.get(name)?;
+        if view.shape() != s.dims() {
+            Err(candle::Error::UnexpectedShape {
+                msg: format!("shape mismatch for {name}"),
+                expected: s,
+                got: view.shape().into(),
+            }
+            .bt())?
+        }
+        let dev = if (self.pin)(name) { dev } else { &Device::Cpu };
+        self.tensors.load_as(name, dtype, dev)
+    }
+
+    fn contains_tensor(&self, name: &str) -> bool {
+        self.tensors.get(name).is_ok()
+    }
+}
+
 impl SimpleBackend for candle::safetensors::BufferedSafetensors {
     fn get(
         &self,
@@ -406,7 +456,7 @@ impl SimpleBackend for candle::safetensors::BufferedSafetensors {
         dtype: DType,
         dev: &Device,
     ) -> Result<Tensor> {
-        let tensor = self.load(name, dev)?.to_dtype(dtype)?;
+        let tensor = self.load_as(name, dtype, dev)?;
         if tensor.shape() != &s {
             Err(candle::Error::UnexpectedShape {
                 msg: format!("shape mismatch for {name}"),
@@ -432,7 +482,7 @@ impl<'a> SimpleBackend for candle::safetensors::SliceSafetensors<'a> {
         dtype: DType,
         dev: &Device,
     ) -> Result<Tensor> {
-        let tensor = self.load(name, dev)?.to_dtype(dtype)?;
+        let tensor = self.load_as(name, dtype, dev)?;
         if tensor.shape() != &s {
             Err(candle::Error::UnexpectedShape {
                 msg: format!("shape mismatch for {name}"),
@@ -496,6 +546,10 @@ impl<'a> VarBuilder<'a> {
     /// Initializes a `VarBuilder` that retrieves tensors stored in a collection of safetensors
     /// files.
     ///
+    /// The files are memory mapped and each tensor is only read when requested, floating point
+    /// tensors are converted to the target dtype while being read so that they are never
+    /// materialized in their stored dtype.
+    ///
     /// # Safety
     ///
     /// The unsafe is inherited from [`memmap2::MmapOptions`].
@@ -508,6 +562,38 @@ impl<'a> VarBuilder<'a> {
         Ok(Self::from_backend(Box::new(tensors), dtype, dev.clone()))
     }
 
+    /// Similar to `from_mmaped_safetensors` but only the tensors for which `pin` returns true,
+    /// based on their full name, are loaded on `dev`. The other tensors are loaded on the cpu.
+    ///
+    /// ```ignore
+    /// // Only keep the first 8 layers on the gpu.
+    /// let vb = unsafe {
+    ///     VarBuilder::from_mmaped_safetensors_pinned(&paths, DType::BF16, &device, |name| {
+    ///         name.strip_prefix("model.layers.")
+    ///             .and_then(|rest| rest.split('.').next()?.parse::<usize>().ok())
+    ///             .map_or(true, |layer| layer < 8)
+    ///     })?
+    /// };
+    /// ```
+    ///
+    /// # Safety
+    ///
+    /// The unsafe is inherited from [`memmap2::MmapOptions`].
+    pub unsafe fn from_mmaped_safetensors_pinned<P, F>(
+        paths: &[P],
+        dtype: DType,
+        dev: &Device,
+        pin: F,
+    ) -> Result<Self>
+    where
+        P: AsRef<std::path::Path>,
+        F: Fn(&str) -> bool + Send + Sync + 'static,
+    {
+        let tensors = candle::safetensors::MmapedSafetensors::multi(paths)?;
+        let backend = PinnedSafetensors::new(tensors, pin);
+        Ok(Self::from_backend(Box::new(backend), dtype, dev.clone()))
+    }
+
     /// Initializes a `VarBuilder` from a binary buffer in the safetensor format.
     pub fn from_buffered_safetensors(data: Vec<u8>, dtype: DType, dev: &Device) -> Result<Self> {
         let tensors = candle::safetensors::BufferedSafetensors::new(data)?;
@@ -578,6 +664,46 @@ impl<'a> VarBuilder<'a> {
             _phantom: std::marker::PhantomData,
         }
     }
+
+    /// Applies the deltas from a `CheckpointDelta` to the tensors retrieved from this `VarBuilder`,
+    /// e.g. to load fine-tuned weights from a base checkpoint and a delta.
+    pub fn with_delta(self, delta: crate::delta::CheckpointDelta) -> Self {
+        let dtype = self.dtype();
+        let device = self.device().clone();
+        let path = self.path.clone();
+        let backend = crate::delta::Patched::new(self.root(), delta);
+        let backend: Box<dyn SimpleBackend + 'a> = Box::new(backend);
+        let data = TensorData {
+            backend,
+            dtype,
+            device,
+        };
+        Self {
+            data: Arc::new(data),
+            path,
+            _phantom: std::marker::PhantomData,
+        }
+    }
+
+    /// Merges the LoRA adapters from `lora` into the matching weights retrieved from this
+    /// `VarBuilder`, so that a fine-tuned model can be loaded without any adapter overhead.
+    pub fn with_lora(self, lora: crate::lora::Lora<'a>) -> Self {
+        let dtype = self.dtype();
+        let device = self.device().clone();
+        let path = self.path.clone();
+        let backend = crate::lora::LoraMerged::new(self.root(), lora);
+        let backend: Box<dyn SimpleBackend + 'a> = Box::new(backend);
+        let data = TensorData {
+            backend,
+            dtype,
+            device,
+        };
+        Self {
+            data: Arc::new(data),
+            path,
+            _phantom: std::marker::PhantomData,
+        }
+    }
 }
 
 pub struct ShardedSafeTensors(candle::safetensors::MmapedSafetensors);
@@ -20,10 +20,14 @@ impl VarMap {
     }
 
     /// Retrieve all the variables currently stored in the map.
+    ///
+    /// The variables are sorted by name so that the order is the same for all the var maps
+    /// with the same variables, which is required to restore an optimizer state.
     pub fn all_vars(&self) -> Vec<Var> {
         let tensor_data = self.data.lock().unwrap();
-        #[allow(clippy::map_clone)]
-        tensor_data.values().map(|c| c.clone()).collect::<Vec<_>>()
+        let mut vars = tensor_data.iter().collect::<Vec<_>>();
+        vars.sort_by_key(|(name, _)| name.as_str());
+        vars.into_iter().map(|(_, var)| var.clone()).collect()
     }
 
     /// Save the map in the safetensors format.
@@ -34,6 +38,21 @@ impl VarMap {
         Ok(())
     }
 
+    /// Save the map with [`candle::safetensors::save_streaming`], the variables are written one at
+    /// a time with optional compression and checksums.
+    pub fn save_streaming<P: AsRef<std::path::Path>>(
+        &self,
+        path: P,
+        options: &candle::safetensors::StreamingSaveOptions,
+    ) -> Result<()> {
+        let tensor_data = self.data.lock().unwrap();
+        let data = tensor_data
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_tensor()))
+            .collect::<Vec<_>>();
+        candle::safetensors::save_streaming_file(&data, path, options)
+    }
+
     /// Load some values from a safetensors file and modify the existing variables to have these
     /// values.
     ///
@@ -51,6 +70,24 @@ impl VarMap {
         Ok(())
     }
 
+    /// Same as [`VarMap::load`] for the files written by [`VarMap::save_streaming`], the
+    /// checksums are verified and the compressed tensors are decompressed.
+    pub fn load_verified<P: AsRef<std::path::Path>>(&mut self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let data = candle::safetensors::load_verified(path, &Device::Cpu)?;
+        let mut tensor_data = self.data.lock().unwrap();
+        for (name, var) in tensor_data.iter_mut() {
+            let data = match data.get(name) {
+                None => candle::bail!("cannot find {name} in {path:?}"),
+                Some(data) => data.to_device(var.device())?,
+            };
+            if let Err(err) = var.set(&data) {
+                candle::bail!("error setting {name} using data from {path:?}: {err}",)
+            }
+        }
+        Ok(())
+    }
+
     /// Set a named variable to some value.
     pub fn set_one<K: AsRef<str>, V: AsRef<Tensor>>(&mut self, name: K, value: V) -> Result<()> {
         let tensor_data = self.data.lock().unwrap();
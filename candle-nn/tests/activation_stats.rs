@@ -0,0 +1,63 @@
+use candle::{Device, Module, Result, Tensor};
+use candle_nn::activation_stats::{self, ActivationRecorder, Instrumented, StatsConfig};
+
+#[test]
+fn activation_stats() -> Result<()> {
+    let dev = &Device::Cpu;
+    // Nothing is recorded without a recorder.
+    activation_stats::record("ignored", &Tensor::new(&[1f32], dev)?)?;
+    assert!(!activation_stats::is_recording());
+
+    let layer = Instrumented::new("relu", candle_nn::Activation::Relu);
+    let recorder = ActivationRecorder::new(StatsConfig {
+        max_samples: 50,
+        seed: 1,
+    })?;
+    assert!(ActivationRecorder::new(StatsConfig::default()).is_err());
+    for i in 0..4 {
+        // 0, 1, ..., 99 over the 4 calls.
+        let xs = Tensor::arange(25. * i as f32, 25. * (i + 1) as f32, dev)?;
+        activation_stats::record("xs", &xs)?;
+        layer.forward(&(xs - 50.)?)?;
+    }
+    activation_stats::record("nan", &Tensor::new(&[f32::NAN, f32::INFINITY, 2.], dev)?)?;
+    let report = recorder.finish();
+    assert!(!activation_stats::is_recording());
+
+    let names = report
+        .layers
+        .iter()
+        .map(|l| l.name.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["xs", "relu", "nan"]);
+    let xs = report.get("xs").unwrap();
+    assert_eq!((xs.calls, xs.count), (4, 100));
+    assert_eq!((xs.min, xs.max, xs.mean), (0., 99., 49.5));
+    assert!((xs.std - 28.866).abs() < 1e-3, "{}", xs.std);
+    assert_eq!(xs.abs_max(), 99.);
+    // The percentiles are estimated from 50 of the 100 values.
+    let (lo, hi) = xs.clip_range(90.);
+    assert!((0. ..25.).contains(&lo), "{lo}");
+    assert!((75. ..=99.).contains(&hi), "{hi}");
+    assert!(xs.percentile(0.) <= xs.percentile(50.));
+    assert!(xs.percentile(50.) <= xs.percentile(100.));
+
+    let relu = report.get("relu").unwrap();
+    assert_eq!((relu.min, relu.max), (0., 49.));
+    let nan = report.get("nan").unwrap();
+    assert_eq!((nan.count, nan.nan_count, nan.inf_count), (1, 1, 1));
+    assert_eq!(report.non_finite().len(), 1);
+    assert_eq!(report.to_string().lines().count(), 4);
+
+    // A small spread around a large mean, where the naive variance formula cancels out.
+    let recorder = ActivationRecorder::new(StatsConfig::default())?;
+    let xs = Tensor::new(&[1e7f32 - 1., 1e7 + 1.], dev)?;
+    for _ in 0..1000 {
+        activation_stats::record("xs", &xs)?;
+    }
+    let report = recorder.finish();
+    let xs = report.get("xs").unwrap();
+    assert!((xs.mean - 1e7).abs() < 1e-6, "{}", xs.mean);
+    assert!((xs.std - 1.).abs() < 1e-6, "{}", xs.std);
+    Ok(())
+}
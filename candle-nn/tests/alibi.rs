@@ -0,0 +1,60 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use candle::{DType, Device, Result, Tensor, D};
+use candle_nn::alibi::{alibi_attention_slow, alibi_decode_attention, alibi_slopes};
+
+fn max_diff(a: &Tensor, b: &Tensor) -> Result<f32> {
+    (a.to_dtype(DType::F32)? - b.to_dtype(DType::F32)?)?
+        .abs()?
+        .flatten_all()?
+        .max(D::Minus1)?
+        .to_scalar::<f32>()
+}
+
+#[test]
+fn slopes() {
+    assert_eq!(
+        alibi_slopes(4, 8.),
+        [1. / 4., 1. / 16., 1. / 64., 1. / 256.]
+    );
+    assert_eq!(
+        alibi_slopes(6, 8.),
+        [1. / 4., 1. / 16., 1. / 64., 1. / 256., 1. / 2., 1. / 8.]
+    );
+}
+
+#[test]
+fn decode_attention() -> Result<()> {
+    let dev = &Device::Cpu;
+    let (b, h, h_kv, seq_len, d) = (2, 6, 2, 11, 16);
+    let slopes = alibi_slopes(h, 8.);
+    let scale = 1. / (d as f32).sqrt();
+    let q = Tensor::randn(0f32, 1., (b, h, 1, d), dev)?;
+    let k = Tensor::randn(0f32, 1., (b, h_kv, seq_len, d), dev)?;
+    let v = Tensor::randn(0f32, 1., (b, h_kv, seq_len, d), dev)?;
+    let expected = alibi_attention_slow(&q, &k, &v, &slopes, scale)?;
+    let ys = alibi_decode_attention(&q, &k, &v, &slopes, scale)?;
+    assert_eq!(ys.dims(), [b, h, 1, d]);
+    assert!(max_diff(&ys, &expected)? < 1e-5);
+
+    // Half precision inputs are accumulated in f32.
+    let (q, k, v) = (
+        q.to_dtype(DType::BF16)?,
+        k.to_dtype(DType::BF16)?,
+        v.to_dtype(DType::BF16)?,
+    );
+    let ys = alibi_decode_attention(&q, &k, &v, &slopes, scale)?;
+    assert_eq!(ys.dtype(), DType::BF16);
+    assert!(max_diff(&ys, &expected)? < 2e-2);
+
+    // Non-contiguous keys and values, e.g. from a transposed cache.
+    let k_t = k.transpose(2, 3)?.contiguous()?.transpose(2, 3)?;
+    let ys2 = alibi_decode_attention(&q, &k_t, &v, &slopes, scale)?;
+    assert_eq!(max_diff(&ys, &ys2)?, 0.);
+    assert!(alibi_decode_attention(&q, &k, &v, &slopes[..2], scale).is_err());
+    Ok(())
+}
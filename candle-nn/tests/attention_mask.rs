@@ -0,0 +1,60 @@
+use candle::{test_device, DType, Device, Result, Tensor};
+use candle_nn::attention_mask::{
+    block_diagonal_mask, causal_mask, prefix_lm_mask, sliding_window_mask, to_additive,
+};
+
+// A reference mask built on the host from a predicate on the query and key positions.
+fn reference<F: Fn(usize, usize) -> bool>(tgt_len: usize, offset: usize, f: F) -> Vec<Vec<u8>> {
+    (offset..offset + tgt_len)
+        .map(|q| (0..offset + tgt_len).map(|k| f(q, k) as u8).collect())
+        .collect()
+}
+
+fn masks(device: &Device) -> Result<()> {
+    for (tgt_len, offset) in [(5, 0), (3, 4), (1, 6)] {
+        let mask = causal_mask(tgt_len, offset, device)?;
+        assert_eq!(
+            mask.to_vec2::<u8>()?,
+            reference(tgt_len, offset, |q, k| k <= q)
+        );
+        let mask = sliding_window_mask(tgt_len, offset, 2, device)?;
+        let expected = reference(tgt_len, offset, |q, k| k <= q && k + 2 >= q);
+        assert_eq!(mask.to_vec2::<u8>()?, expected);
+        // Windows that cover all the keys do not overflow and give the causal mask.
+        for window in [tgt_len + offset, usize::MAX] {
+            let mask = sliding_window_mask(tgt_len, offset, window, device)?;
+            let expected = reference(tgt_len, offset, |q, k| k <= q);
+            assert_eq!(mask.to_vec2::<u8>()?, expected);
+        }
+        let mask = prefix_lm_mask(tgt_len, offset, 3, device)?;
+        let expected = reference(tgt_len, offset, |q, k| k <= q || k < 3);
+        assert_eq!(mask.to_vec2::<u8>()?, expected);
+    }
+    let mask = block_diagonal_mask(&[2, 1, 3], false, device)?;
+    assert_eq!(
+        mask.to_vec2::<u8>()?,
+        [
+            [1, 1, 0, 0, 0, 0],
+            [1, 1, 0, 0, 0, 0],
+            [0, 0, 1, 0, 0, 0],
+            [0, 0, 0, 1, 1, 1],
+            [0, 0, 0, 1, 1, 1],
+            [0, 0, 0, 1, 1, 1],
+        ]
+    );
+    let mask = block_diagonal_mask(&[2, 0, 2], true, device)?;
+    assert_eq!(
+        mask.to_vec2::<u8>()?,
+        [[1, 0, 0, 0], [1, 1, 0, 0], [0, 0, 1, 0], [0, 0, 1, 1]]
+    );
+
+    let mask = to_additive(&causal_mask(2, 0, device)?, DType::F32)?;
+    assert_eq!(mask.to_vec2::<f32>()?, [[0., f32::NEG_INFINITY], [0., 0.]]);
+    // The masked scores get a zero probability.
+    let scores = Tensor::ones((2, 2), DType::F32, device)?.broadcast_add(&mask)?;
+    let probs = candle_nn::ops::softmax_last_dim(&scores)?;
+    assert_eq!(probs.to_vec2::<f32>()?, [[1., 0.], [0.5, 0.5]]);
+    Ok(())
+}
+
+test_device!(masks, masks_cpu, masks_gpu, masks_metal);
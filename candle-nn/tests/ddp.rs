@@ -0,0 +1,83 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use candle::collective::{Communicator, LocalCommunicator};
+use candle::{DType, Device, Module, Result, Tensor, Var};
+use candle_nn::ddp::DistributedDataParallel;
+use candle_nn::{Linear, Optimizer, SGD};
+use std::sync::Arc;
+
+#[test]
+fn ddp_averages_gradients() -> Result<()> {
+    let world_size = 2;
+    let comms = LocalCommunicator::group(world_size);
+    let xs = Tensor::new(&[[1f32, 2.], [3., 4.], [-1., 0.5], [2., -2.]], &Device::Cpu)?;
+    let ys = Tensor::new(&[[1f32], [0.], [2.], [-1.]], &Device::Cpu)?;
+    let init_w = Tensor::new(&[[0.5f32, -0.25]], &Device::Cpu)?;
+    let init_b = Tensor::new(&[0.1f32], &Device::Cpu)?;
+
+    // Reference: a single rank processing the full batch.
+    let w = Var::from_tensor(&init_w)?;
+    let b = Var::from_tensor(&init_b)?;
+    let model = Linear::new(w.as_tensor().clone(), Some(b.as_tensor().clone()));
+    let mut opt = SGD::new(vec![w.clone(), b.clone()], 0.1)?;
+    let loss = candle_nn::loss::mse(&model.forward(&xs)?, &ys)?;
+    opt.backward_step(&loss)?;
+    let expected_w = w.to_vec2::<f32>()?;
+    let expected_b = b.to_vec1::<f32>()?;
+
+    let results = std::thread::scope(|s| {
+        let handles = comms
+            .into_iter()
+            .map(|comm| {
+                let (xs, ys, init_w, init_b) = (&xs, &ys, &init_w, &init_b);
+                s.spawn(move || {
+                    let rank = comm.rank();
+                    // The weights only match the reference on rank 0, the other ranks get their
+                    // values from the initial sync.
+                    let w = Var::from_tensor(&init_w.affine(1. + rank as f64, 0.)?)?;
+                    let b = Var::from_tensor(init_b)?;
+                    let vars = vec![w.clone(), b.clone()];
+                    let ddp = DistributedDataParallel::new(vars.clone(), Arc::new(comm))?
+                        .with_bucket_size(4);
+                    let model = Linear::new(w.as_tensor().clone(), Some(b.as_tensor().clone()));
+                    let mut opt = SGD::new(vars, 0.1)?;
+                    let xs = xs.narrow(0, 2 * rank, 2)?;
+                    let ys = ys.narrow(0, 2 * rank, 2)?;
+                    let loss = candle_nn::loss::mse(&model.forward(&xs)?, &ys)?;
+                    ddp.backward_step(&mut opt, &loss)?;
+                    Ok::<_, candle::Error>((w.to_vec2::<f32>()?, b.to_vec1::<f32>()?))
+                })
+            })
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .map(|h| h.join().unwrap())
+            .collect::<Result<Vec<_>>>()
+    })?;
+    for (w, b) in results {
+        for (v, e) in w[0].iter().zip(expected_w[0].iter()) {
+            assert!((v - e).abs() < 1e-5, "{w:?} {expected_w:?}")
+        }
+        assert!((b[0] - expected_b[0]).abs() < 1e-5, "{b:?} {expected_b:?}")
+    }
+    Ok(())
+}
+
+#[test]
+fn ddp_single_rank() -> Result<()> {
+    let comm = LocalCommunicator::group(1).remove(0);
+    let w = Var::zeros((2, 2), DType::F32, &Device::Cpu)?;
+    let ddp = DistributedDataParallel::new(vec![w.clone()], Arc::new(comm))?;
+    assert_eq!(ddp.world_size(), 1);
+    let loss = (w.as_tensor() * 2.)?.sum_all()?;
+    let grads = ddp.backward(&loss)?;
+    assert_eq!(
+        grads.get(&w).unwrap().to_vec2::<f32>()?,
+        [[2., 2.], [2., 2.]]
+    );
+    Ok(())
+}
@@ -0,0 +1,116 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use candle::{DType, Device, Result, Tensor};
+use candle_nn::delta::{CheckpointDelta, Delta, DeltaMethod};
+use candle_nn::VarBuilder;
+use std::collections::HashMap;
+
+fn max_abs_diff(a: &Tensor, b: &Tensor) -> Result<f32> {
+    (a - b)?.abs()?.flatten_all()?.max(0)?.to_scalar::<f32>()
+}
+
+#[test]
+fn sparse_delta() -> Result<()> {
+    let cpu = Device::Cpu;
+    let base = Tensor::arange(0f32, 12., &cpu)?.reshape((3, 4))?;
+    let target = Tensor::new(
+        &[[0f32, 1., 2., 3.], [4., 7., 6., 7.], [8., 9., 10., 10.5]],
+        &cpu,
+    )?;
+    let delta = Delta::compute(&base, &target, DeltaMethod::Sparse { threshold: 0. })?.unwrap();
+    match &delta {
+        Delta::Sparse { indices, values } => {
+            assert_eq!(indices.to_vec1::<u32>()?, [5, 11]);
+            assert_eq!(values.to_vec1::<f32>()?, [2., -0.5]);
+        }
+        _ => panic!("unexpected delta {delta:?}"),
+    }
+    assert_eq!(
+        delta.apply(&base)?.to_vec2::<f32>()?,
+        target.to_vec2::<f32>()?
+    );
+
+    // Small differences are dropped.
+    let delta = Delta::compute(&base, &target, DeltaMethod::Sparse { threshold: 1. })?.unwrap();
+    let patched = delta.apply(&base)?;
+    assert_eq!(patched.get(1)?.get(1)?.to_scalar::<f32>()?, 7.);
+    assert_eq!(patched.get(2)?.get(3)?.to_scalar::<f32>()?, 11.);
+
+    assert!(Delta::compute(&base, &base, DeltaMethod::Dense)?.is_none());
+    Ok(())
+}
+
+#[test]
+fn low_rank_delta() -> Result<()> {
+    let cpu = Device::Cpu;
+    let base = Tensor::randn(0f32, 1., (32, 48), &cpu)?;
+    let u = Tensor::randn(0f32, 1., (32, 2), &cpu)?;
+    let v = Tensor::randn(0f32, 1., (2, 48), &cpu)?;
+    let target = (&base + u.matmul(&v)?)?;
+    let delta = Delta::compute(&base, &target, DeltaMethod::LowRank { rank: 2 })?.unwrap();
+    match &delta {
+        Delta::LowRank { a, b } => {
+            assert_eq!(a.dims(), [32, 2]);
+            assert_eq!(b.dims(), [2, 48]);
+        }
+        _ => panic!("unexpected delta {delta:?}"),
+    }
+    assert!(max_abs_diff(&delta.apply(&base)?, &target)? < 1e-3);
+    // The same checkpoints always give the same delta.
+    let again = Delta::compute(&base, &target, DeltaMethod::LowRank { rank: 2 })?.unwrap();
+    assert_eq!(
+        max_abs_diff(&again.apply(&base)?, &delta.apply(&base)?)?,
+        0.
+    );
+
+    // One dimensional tensors fall back to a dense delta.
+    let base = Tensor::zeros(8, DType::F32, &cpu)?;
+    let target = Tensor::ones(8, DType::F32, &cpu)?;
+    let delta = Delta::compute(&base, &target, DeltaMethod::LowRank { rank: 2 })?.unwrap();
+    assert!(matches!(delta, Delta::Dense(_)));
+    Ok(())
+}
+
+#[test]
+fn checkpoint_delta() -> Result<()> {
+    let cpu = Device::Cpu;
+    let w = Tensor::randn(0f32, 1., (16, 16), &cpu)?;
+    let b = Tensor::zeros(16, DType::F32, &cpu)?;
+    let lora = Tensor::randn(0f32, 1., (16, 1), &cpu)?;
+    let base = HashMap::from([
+        ("l.weight".to_string(), w.clone()),
+        ("l.bias".to_string(), b),
+    ]);
+    let tuned_w = (&w + lora.matmul(&lora.t()?)?)?;
+    let tuned_b = Tensor::ones(16, DType::F32, &cpu)?;
+    let target = HashMap::from([
+        ("l.weight".to_string(), tuned_w.clone()),
+        ("l.bias".to_string(), tuned_b.clone()),
+    ]);
+    let delta = CheckpointDelta::compute(&base, &target, DeltaMethod::LowRank { rank: 1 })?;
+    assert_eq!(delta.len(), 2);
+    assert_eq!(delta.size_in_bytes(), (16 + 16 + 16) * 4);
+
+    let tmp = std::env::temp_dir().join(format!(
+        "candle-checkpoint-delta-{}.safetensors",
+        std::process::id()
+    ));
+    delta.save(&tmp)?;
+    let delta = CheckpointDelta::load(&tmp, &cpu)?;
+    std::fs::remove_file(&tmp)?;
+    assert!(matches!(delta.get("l.weight"), Some(Delta::LowRank { .. })));
+    assert!(matches!(delta.get("l.bias"), Some(Delta::Dense(_))));
+
+    let vb = VarBuilder::from_tensors(base, DType::F32, &cpu).with_delta(delta);
+    let linear = candle_nn::linear(16, 16, vb.pp("l"))?;
+    assert!(max_abs_diff(linear.weight(), &tuned_w)? < 1e-3);
+    assert_eq!(
+        linear.bias().unwrap().to_vec1::<f32>()?,
+        tuned_b.to_vec1::<f32>()?
+    );
+    Ok(())
+}
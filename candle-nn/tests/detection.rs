@@ -0,0 +1,155 @@
+use candle::{test_device, Device, Result, Tensor};
+use candle_nn::detection::{batched_nms, box_iou, nms, roi_align, RoiAlignConfig};
+
+fn nms_(device: &Device) -> Result<()> {
+    let boxes = Tensor::new(
+        &[
+            [0f32, 0., 10., 10.],
+            [1., 1., 11., 11.],
+            [20., 20., 30., 30.],
+            [0., 0., 2., 2.],
+        ],
+        device,
+    )?;
+    let iou = box_iou(
+        &boxes.narrow(0, 3, 1)?,
+        &Tensor::new(&[[1f32, 1., 3., 3.]], device)?,
+    )?;
+    assert_eq!(iou.to_vec2::<f32>()?, [[1. / 7.]]);
+    let iou = box_iou(&boxes, &boxes)?;
+    assert_eq!(iou.dims(), [4, 4]);
+    assert!((iou.to_vec2::<f32>()?[0][1] - 81. / 119.).abs() < 1e-6);
+
+    let scores = Tensor::new(&[0.9f32, 0.8, 0.7, 0.95], device)?;
+    let keep = nms(&boxes, &scores, 0.5)?;
+    assert_eq!(keep.to_vec1::<u32>()?, [3, 0, 2]);
+    let keep = nms(&boxes, &scores, 0.01)?;
+    assert_eq!(keep.to_vec1::<u32>()?, [3, 1, 2]);
+    // The second box is in a different category so it is not suppressed by the first one.
+    let idxs = Tensor::new(&[0u32, 1, 0, 0], device)?;
+    let keep = batched_nms(&boxes, &scores, &idxs, 0.5)?;
+    assert_eq!(keep.to_vec1::<u32>()?, [3, 0, 1, 2]);
+    let empty = Tensor::zeros((0, 4), candle::DType::F32, device)?;
+    let keep = nms(&empty, &Tensor::zeros(0, candle::DType::F32, device)?, 0.5)?;
+    assert_eq!(keep.dims(), [0]);
+    Ok(())
+}
+
+// A direct port of the torchvision cpu kernel.
+fn roi_align_reference(
+    xs: &[Vec<Vec<Vec<f32>>>],
+    roi: [f32; 5],
+    cfg: RoiAlignConfig,
+) -> Vec<Vec<Vec<f32>>> {
+    let (h, w) = (xs[0][0].len(), xs[0][0][0].len());
+    let (out_h, out_w) = cfg.output_size;
+    let offset = if cfg.aligned { 0.5 } else { 0. };
+    let [_, x1, y1, x2, y2] = roi.map(|v| v * cfg.spatial_scale - offset);
+    let (mut roi_w, mut roi_h) = (x2 - x1, y2 - y1);
+    if !cfg.aligned {
+        roi_w = roi_w.max(1.);
+        roi_h = roi_h.max(1.);
+    }
+    let (bin_h, bin_w) = (roi_h / out_h as f32, roi_w / out_w as f32);
+    let grid_h = cfg.sampling_ratio.unwrap_or((bin_h.ceil() as usize).max(1));
+    let grid_w = cfg.sampling_ratio.unwrap_or((bin_w.ceil() as usize).max(1));
+    let bilinear = |plane: &[Vec<f32>], y: f32, x: f32| -> f32 {
+        if y < -1. || y > h as f32 || x < -1. || x > w as f32 {
+            return 0.;
+        }
+        let (y, x) = (y.max(0.), x.max(0.));
+        let (y_low, y_high, y) = if y as usize >= h - 1 {
+            (h - 1, h - 1, (h - 1) as f32)
+        } else {
+            (y as usize, y as usize + 1, y)
+        };
+        let (x_low, x_high, x) = if x as usize >= w - 1 {
+            (w - 1, w - 1, (w - 1) as f32)
+        } else {
+            (x as usize, x as usize + 1, x)
+        };
+        let (ly, lx) = (y - y_low as f32, x - x_low as f32);
+        let (hy, hx) = (1. - ly, 1. - lx);
+        hy * hx * plane[y_low][x_low]
+            + hy * lx * plane[y_low][x_high]
+            + ly * hx * plane[y_high][x_low]
+            + ly * lx * plane[y_high][x_high]
+    };
+    xs[roi[0] as usize]
+        .iter()
+        .map(|plane| {
+            (0..out_h)
+                .map(|ph| {
+                    (0..out_w)
+                        .map(|pw| {
+                            let mut sum = 0.;
+                            for iy in 0..grid_h {
+                                let y = y1
+                                    + ph as f32 * bin_h
+                                    + (iy as f32 + 0.5) * bin_h / grid_h as f32;
+                                for ix in 0..grid_w {
+                                    let x = x1
+                                        + pw as f32 * bin_w
+                                        + (ix as f32 + 0.5) * bin_w / grid_w as f32;
+                                    sum += bilinear(plane, y, x)
+                                }
+                            }
+                            sum / (grid_h * grid_w) as f32
+                        })
+                        .collect()
+                })
+                .collect()
+        })
+        .collect()
+}
+
+fn roi_align_(device: &Device) -> Result<()> {
+    let xs = Tensor::randn(0f32, 1., (2, 3, 10, 12), device)?;
+    let xs_vec = (0..2)
+        .map(|i| xs.get(i)?.to_vec3::<f32>())
+        .collect::<Result<Vec<_>>>()?;
+    let rois = [
+        [0f32, 1., 2., 9., 7.],
+        [1., 0.3, 0.7, 4.2, 3.1],
+        [1., -3., -2., 13., 12.],
+        [0., 5., 5., 5.5, 5.2],
+        [1., 20., 20., 30., 30.],
+    ];
+    let rois_t = Tensor::new(&rois, device)?;
+    for cfg in [
+        RoiAlignConfig::new((3, 4), 1.),
+        RoiAlignConfig {
+            sampling_ratio: Some(2),
+            ..RoiAlignConfig::new((2, 2), 0.5)
+        },
+        RoiAlignConfig {
+            aligned: false,
+            ..RoiAlignConfig::new((5, 3), 1.)
+        },
+    ] {
+        let ys = roi_align(&xs, &rois_t, cfg)?;
+        let (k, c, out_h, out_w) = ys.dims4()?;
+        assert_eq!((k, c, (out_h, out_w)), (5, 3, cfg.output_size));
+        for (i, roi) in rois.iter().enumerate() {
+            let expected = roi_align_reference(&xs_vec, *roi, cfg);
+            let ys = ys.get(i)?.to_vec3::<f32>()?;
+            for (y, e) in ys
+                .iter()
+                .flatten()
+                .flatten()
+                .zip(expected.iter().flatten().flatten())
+            {
+                assert!((y - e).abs() < 1e-4, "{cfg:?} {roi:?} {y} {e}");
+            }
+        }
+    }
+    let empty = Tensor::zeros((0, 5), candle::DType::F32, device)?;
+    let ys = roi_align(&xs, &empty, RoiAlignConfig::new((2, 2), 1.))?;
+    assert_eq!(ys.dims(), [0, 3, 2, 2]);
+    let rois = Tensor::new(&[[2f32, 1., 2., 9., 7.]], device)?;
+    assert!(roi_align(&xs, &rois, RoiAlignConfig::new((2, 2), 1.)).is_err());
+    Ok(())
+}
+
+test_device!(nms_, nms_cpu, nms_gpu, nms_metal);
+test_device!(roi_align_, roi_align_cpu, roi_align_gpu, roi_align_metal);
@@ -0,0 +1,78 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use candle::{DType, Device, Result, Tensor};
+use candle_nn::{Init, ModelEma, VarMap};
+
+#[test]
+fn ema_update() -> Result<()> {
+    let mut varmap = VarMap::new();
+    let w = varmap.get(2, "w", Init::Const(0.), DType::F32, &Device::Cpu)?;
+    let mut ema = ModelEma::new(&varmap, 0.5)?;
+    varmap.set_one("w", Tensor::new(&[4f32, 8.], &Device::Cpu)?)?;
+    ema.update()?;
+    assert_eq!(ema.get("w").unwrap().to_vec1::<f32>()?, [2., 4.]);
+    ema.update()?;
+    assert_eq!(ema.get("w").unwrap().to_vec1::<f32>()?, [3., 6.]);
+    assert_eq!(ema.num_updates(), 2);
+
+    // Swap the shadow weights in and out.
+    ema.apply()?;
+    assert_eq!(w.to_vec1::<f32>()?, [3., 6.]);
+    assert!(ema.update().is_err());
+    assert!(ema.apply().is_err());
+    ema.restore()?;
+    assert_eq!(w.to_vec1::<f32>()?, [4., 8.]);
+    assert!(ema.restore().is_err());
+
+    // Variables created after the ema start from their current value.
+    varmap.get(1, "b", Init::Const(1.), DType::F32, &Device::Cpu)?;
+    ema.update()?;
+    assert_eq!(ema.get("b").unwrap().to_vec1::<f32>()?, [1.]);
+    let averaged = ema.to_varmap()?;
+    assert_eq!(averaged.data().lock().unwrap().len(), 2);
+    Ok(())
+}
+
+#[test]
+fn ema_warmup() -> Result<()> {
+    let varmap = VarMap::new();
+    varmap.get(2, "w", Init::Const(0.), DType::BF16, &Device::Cpu)?;
+    let mut ema = ModelEma::new(&varmap, 0.999)?.with_warmup(true);
+    assert_eq!(ema.decay(), 0.1);
+    ema.update()?;
+    assert_eq!(ema.decay(), 2. / 11.);
+    // Half precision variables use f32 shadows.
+    assert_eq!(ema.get("w").unwrap().dtype(), DType::F32);
+    assert!(ModelEma::new(&varmap, 1.5).is_err());
+    Ok(())
+}
+
+#[test]
+fn ema_save_load() -> Result<()> {
+    let mut varmap = VarMap::new();
+    varmap.get((2, 2), "w", Init::Const(1.), DType::F32, &Device::Cpu)?;
+    let mut ema = ModelEma::new(&varmap, 0.9)?;
+    varmap.set_one("w", Tensor::zeros((2, 2), DType::F32, &Device::Cpu)?)?;
+    ema.update()?;
+    ema.update()?;
+    let tmp = std::env::temp_dir().join(format!("candle-ema-{}.safetensors", std::process::id()));
+    ema.save(&tmp)?;
+
+    let mut loaded = ModelEma::new(&varmap, 0.9)?;
+    loaded.load(&tmp)?;
+    assert_eq!(loaded.num_updates(), 2);
+    assert_eq!(
+        loaded.get("w").unwrap().to_vec2::<f32>()?,
+        ema.get("w").unwrap().to_vec2::<f32>()?
+    );
+    // The file can also be used as regular model weights.
+    let weights = candle::safetensors::load(&tmp, &Device::Cpu)?;
+    std::fs::remove_file(&tmp)?;
+    let w = weights.get("w").unwrap().to_vec2::<f32>()?;
+    assert!((w[0][0] - 0.81).abs() < 1e-6);
+    Ok(())
+}
@@ -0,0 +1,79 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use candle::{Device, Result, Tensor, Var};
+use candle_nn::grad_surgery::project_conflicting;
+use candle_nn::{GradCombiner, GradNorm, Optimizer, PCGrad, SGD};
+
+fn task_losses(w: &Var, coefs: &[[f32; 2]]) -> Result<Vec<Tensor>> {
+    coefs
+        .iter()
+        .map(|c| w.broadcast_mul(&Tensor::new(c, &Device::Cpu)?)?.sum_all())
+        .collect()
+}
+
+#[test]
+fn pcgrad() -> Result<()> {
+    let w = Var::new(&[1f32, 1.], &Device::Cpu)?;
+    let vars = vec![w.clone()];
+    let mut pcgrad = PCGrad::new(0);
+
+    // Conflicting gradients [1, 0] and [-1, 1] are projected on each other's normal plane.
+    let losses = task_losses(&w, &[[1., 0.], [-1., 1.]])?;
+    let grads = pcgrad.backward(&vars, &losses)?;
+    assert_eq!(grads.get(&w).unwrap().to_vec1::<f32>()?, [0.5, 1.5]);
+
+    // Gradients that do not conflict are summed.
+    let losses = task_losses(&w, &[[1., 0.], [1., 1.]])?;
+    let grads = pcgrad.backward(&vars, &losses)?;
+    assert_eq!(grads.get(&w).unwrap().to_vec1::<f32>()?, [2., 1.]);
+
+    let mut opt = SGD::new(vars.clone(), 0.5)?;
+    let losses = task_losses(&w, &[[1., 0.], [-1., 1.]])?;
+    pcgrad.backward_step(&mut opt, &vars, &losses)?;
+    assert_eq!(w.to_vec1::<f32>()?, [0.75, 0.25]);
+    Ok(())
+}
+
+#[test]
+fn project_conflicting_grads() -> Result<()> {
+    let w = Var::new(&[1f32, 1.], &Device::Cpu)?;
+    let losses = task_losses(&w, &[[1., 0.], [-1., 1.], [0., 1.]])?;
+    let mut grads = losses[0].backward()?;
+    project_conflicting(&[w.clone()], &mut grads, &losses[1].backward()?)?;
+    assert_eq!(grads.get(&w).unwrap().to_vec1::<f32>()?, [0.5, 0.5]);
+    // The reference is orthogonal, nothing changes.
+    project_conflicting(&[w.clone()], &mut grads, &losses[2].backward()?)?;
+    assert_eq!(grads.get(&w).unwrap().to_vec1::<f32>()?, [0.5, 0.5]);
+    Ok(())
+}
+
+#[test]
+fn grad_norm() -> Result<()> {
+    let w = Var::new(&[1f32, 1.], &Device::Cpu)?;
+    let vars = vec![w.clone()];
+    let mut grad_norm = GradNorm::new(2, vars.clone(), 1.5, 0.1)?;
+    let losses = task_losses(&w, &[[2., 0.], [0., 1.]])?;
+    let grads = grad_norm.backward(&vars, &losses)?;
+    assert_eq!(grads.get(&w).unwrap().to_vec1::<f32>()?, [2., 1.]);
+    assert_eq!(grad_norm.initial_losses(), Some([2., 1.].as_slice()));
+
+    // The task with the largest gradient norm gets a lower weight.
+    let weights = grad_norm.weights().to_vec();
+    assert!((weights[0] - 0.8 / 0.95).abs() < 1e-6);
+    assert!((weights[1] - 1.1 / 0.95).abs() < 1e-6);
+    let grads = grad_norm.backward(&vars, &losses)?;
+    let expected = [2. * weights[0] as f32, weights[1] as f32];
+    let got = grads.get(&w).unwrap().to_vec1::<f32>()?;
+    assert!((got[0] - expected[0]).abs() < 1e-6 && (got[1] - expected[1]).abs() < 1e-6);
+    assert!((grad_norm.weights().iter().sum::<f64>() - 2.).abs() < 1e-9);
+
+    assert!(grad_norm.backward(&vars, &losses[..1]).is_err());
+    grad_norm.reset();
+    assert_eq!(grad_norm.weights(), [1., 1.]);
+    assert!(grad_norm.initial_losses().is_none());
+    Ok(())
+}
@@ -0,0 +1,57 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use candle::{Device, Module, Result, Tensor};
+use candle_nn::{GraphedModule, Linear};
+
+#[test]
+fn graphed_module_cpu() -> Result<()> {
+    let device = Device::Cpu;
+    let w = Tensor::new(&[[1f32, 2.], [3., 4.]], &device)?;
+    let linear = Linear::new(w, None);
+    let mut graphed = GraphedModule::new(linear.clone());
+    // Other devices than cuda run the steps eagerly, without capturing anything.
+    for step in 0..3 {
+        let xs = Tensor::new(&[[step as f32, 1.]], &device)?;
+        let ys = graphed.forward(&xs)?;
+        assert_eq!(ys.to_vec2::<f32>()?, linear.forward(&xs)?.to_vec2::<f32>()?);
+    }
+    assert!(!graphed.is_captured());
+    assert_eq!(graphed.replays(), 0);
+
+    let xs = Tensor::new(&[1f32, 2.], &device)?;
+    let ys = graphed.run(&[&xs, &xs], |_, xs| xs[0].add(&xs[1]))?;
+    assert_eq!(ys.to_vec1::<f32>()?, [2., 4.]);
+    Ok(())
+}
+
+#[cfg(feature = "cuda")]
+#[test]
+fn graphed_module_cuda() -> Result<()> {
+    use candle_nn::kv_cache::StaticKvCache;
+    let device = Device::new_cuda_with_stream(0)?;
+    let w = Tensor::new(&[[1f32, 2.], [3., 4.]], &device)?;
+    let linear = Linear::new(w, None);
+    // Each step caches the projected token at its position and sums over the cached tokens, the
+    // sum runs on a transposed layout so that its dims and strides are read from the device.
+    let mut graphed = GraphedModule::new((linear, StaticKvCache::new(1, 8)));
+    let mut expected = [0f32, 0.];
+    for step in 0..6u32 {
+        let xs = Tensor::new(&[[step as f32, 1.]], &device)?;
+        let positions = Tensor::new(&[step], &device)?;
+        let ys = graphed.run(&[&xs, &positions], |(linear, cache), xs| {
+            let ys = linear.forward(&xs[0])?.unsqueeze(1)?;
+            let (k, _) = cache.append(&ys, &ys, &xs[1])?;
+            k.transpose(1, 2)?.sum(2)
+        })?;
+        expected[0] += step as f32 + 2.;
+        expected[1] += 3. * step as f32 + 4.;
+        assert_eq!(ys.to_vec2::<f32>()?, [expected]);
+    }
+    assert!(graphed.is_captured());
+    assert_eq!(graphed.replays(), 4);
+    Ok(())
+}
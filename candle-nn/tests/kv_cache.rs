@@ -0,0 +1,96 @@
+use candle::{Device, IndexOp, Result, Tensor};
+use candle_nn::kv_cache::{EvictingKvCache, HeavyHitter, SlidingWindow, StaticKvCache};
+
+// A token whose keys and values are filled with `pos`, with shape (1, 2, 1, 3).
+fn token(pos: usize) -> Result<Tensor> {
+    Tensor::full(pos as f32, (1, 2, 1, 3), &Device::Cpu)
+}
+
+// The positions of the cached tokens for each head.
+fn positions(cache: &EvictingKvCache) -> Result<Vec<Vec<f32>>> {
+    cache
+        .k()
+        .unwrap()
+        .narrow(3, 0, 1)?
+        .squeeze(3)?
+        .squeeze(0)?
+        .to_vec2::<f32>()
+}
+
+#[test]
+fn sliding_window() -> Result<()> {
+    let mut cache = EvictingKvCache::new(SlidingWindow::new(1, 2));
+    for pos in 0..6 {
+        let (k, _) = cache.append(&token(pos)?, &token(pos)?)?;
+        let seq_len = k.dim(2)?;
+        let attn =
+            (Tensor::ones((1, 2, 1, seq_len), candle::DType::F32, &Device::Cpu)? / seq_len as f64)?;
+        let evicted = cache.observe(&attn)?;
+        assert_eq!(evicted, usize::from(pos >= 3));
+    }
+    assert_eq!(cache.current_seq_len(), 3);
+    assert_eq!(positions(&cache)?, [[0., 4., 5.], [0., 4., 5.]]);
+    assert_eq!(cache.v().unwrap().dims(), [1, 2, 3, 3]);
+    cache.reset();
+    assert_eq!(cache.current_seq_len(), 0);
+    Ok(())
+}
+
+#[test]
+fn heavy_hitter() -> Result<()> {
+    let mut cache = EvictingKvCache::new(HeavyHitter::new(1, 2));
+    for pos in 0..6 {
+        let (k, _) = cache.append(&token(pos)?, &token(pos)?)?;
+        // The first head attends to the token at position 1 and the second one to the token at
+        // position 2, the remaining attention is spread over the other tokens.
+        let cached = k
+            .narrow(3, 0, 1)?
+            .squeeze(3)?
+            .squeeze(0)?
+            .to_vec2::<f32>()?;
+        let attn = cached
+            .iter()
+            .enumerate()
+            .map(|(head, positions)| {
+                let favorite = (head + 1) as f32;
+                let n = positions.len() as f32;
+                positions
+                    .iter()
+                    .map(|&p| if p == favorite { 0.9 } else { 0.1 / n })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let seq_len = attn[0].len();
+        let attn = Tensor::new(attn, &Device::Cpu)?.reshape((1, 2, 1, seq_len))?;
+        cache.observe(&attn)?;
+        assert!(cache.current_seq_len() <= 3);
+    }
+    assert_eq!(positions(&cache)?, [[1., 4., 5.], [2., 4., 5.]]);
+    Ok(())
+}
+
+#[test]
+fn static_kv_cache() -> Result<()> {
+    let mut cache = StaticKvCache::new(2, 4);
+    // A prompt of two tokens followed by single tokens, the positions are given as tensors.
+    let prompt = Tensor::cat(&[&token(1)?, &token(2)?], 2)?;
+    let positions = Tensor::new(&[0u32, 1], &Device::Cpu)?;
+    let (k, v) = cache.append(&prompt, &(&prompt * 2.)?, &positions)?;
+    assert_eq!(k.dims(), [1, 2, 4, 3]);
+    let cached = |xs: &Tensor| xs.i((0, 0, .., 0))?.to_vec1::<f32>();
+    assert_eq!(cached(&k)?, [1., 2., 0., 0.]);
+    assert_eq!(cached(&v)?, [2., 4., 0., 0.]);
+    let mask = cache.mask(&positions)?;
+    assert_eq!(mask.to_vec2::<u8>()?, [[0, 1, 1, 1], [0, 0, 1, 1]]);
+
+    let position = Tensor::new(&[2u32], &Device::Cpu)?;
+    let (k, _) = cache.append(&token(5)?, &token(5)?, &position)?;
+    assert_eq!(cached(&k)?, [1., 2., 5., 0.]);
+    assert_eq!(cache.mask(&position)?.to_vec2::<u8>()?, [[0, 0, 0, 1]]);
+    // Restarting from the first position overwrites the slots in place.
+    let position = Tensor::new(&[0u32], &Device::Cpu)?;
+    let (k, _) = cache.append(&token(7)?, &token(7)?, &position)?;
+    assert_eq!(cached(&k)?, [7., 2., 5., 0.]);
+    assert_eq!(cached(cache.k().unwrap())?, [7., 2., 5., 0.]);
+    Ok(())
+}
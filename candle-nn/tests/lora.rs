@@ -0,0 +1,182 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use candle::quantized::{GgmlDType, QTensor};
+use candle::{DType, Device, Module, Result, Tensor, D};
+use candle_nn::lora::PEFT_PREFIX;
+use candle_nn::{Conv2d, Conv2dConfig, Embedding, Linear, Lora, LoraConfig, VarBuilder, VarMap};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+fn max_diff(a: &Tensor, b: &Tensor) -> Result<f32> {
+    (a - b)?
+        .abs()?
+        .flatten_all()?
+        .max(D::Minus1)?
+        .to_scalar::<f32>()
+}
+
+// Adapters with non-zero `lora_B` weights so that they have an effect.
+fn random_adapters(names: &[(&str, usize, usize)], rank: usize) -> Result<VarBuilder<'static>> {
+    let mut ts = HashMap::new();
+    for &(name, in_dim, out_dim) in names {
+        let a = Tensor::randn(0f32, 1., (rank, in_dim), &Device::Cpu)?;
+        let b = Tensor::randn(0f32, 1., (out_dim, rank), &Device::Cpu)?;
+        ts.insert(format!("{PEFT_PREFIX}.{name}.lora_A.weight"), a);
+        ts.insert(format!("{PEFT_PREFIX}.{name}.lora_B.weight"), b);
+    }
+    Ok(VarBuilder::from_tensors(ts, DType::F32, &Device::Cpu).pp(PEFT_PREFIX))
+}
+
+#[test]
+fn lora_config() {
+    let config = LoraConfig::new(8, 16., &["q_proj", "v_proj"]);
+    assert_eq!(config.scale(), 2.);
+    assert!(config.matches("q_proj"));
+    assert!(config.matches("model.layers.0.self_attn.q_proj"));
+    assert!(!config.matches("model.layers.0.self_attn.qq_proj"));
+    assert!(!config.matches("model.layers.0.self_attn.k_proj"));
+}
+
+#[test]
+fn lora_linear_init() -> Result<()> {
+    let dev = &Device::Cpu;
+    let varmap = VarMap::new();
+    let lora = Lora::from_varmap(LoraConfig::new(2, 4., &["q"]), &varmap, DType::F32, dev);
+    let base = Linear::new(Tensor::randn(0f32, 1., (3, 4), dev)?, None);
+    let xs = Tensor::randn(0f32, 1., (2, 4), dev)?;
+    let q = lora.linear(base.clone(), "attn.q")?;
+    let k = lora.linear(base.clone(), "attn.k")?;
+    assert!(q.has_adapter());
+    assert!(!k.has_adapter());
+    // The adapters start as a no-op.
+    assert_eq!(max_diff(&q.forward(&xs)?, &base.forward(&xs)?)?, 0.);
+    let mut names = varmap
+        .data()
+        .lock()
+        .unwrap()
+        .keys()
+        .cloned()
+        .collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(
+        names,
+        [
+            "base_model.model.attn.q.lora_A.weight",
+            "base_model.model.attn.q.lora_B.weight"
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn lora_linear_merge() -> Result<()> {
+    let dev = &Device::Cpu;
+    let lora = Lora::new(
+        LoraConfig::new(2, 4., &["q"]),
+        random_adapters(&[("q", 4, 3)], 2)?,
+    );
+    let base = Linear::new(Tensor::randn(0f32, 1., (3, 4), dev)?, None);
+    let xs = Tensor::randn(0f32, 1., (2, 5, 4), dev)?;
+    let mut q = lora.linear(base.clone(), "q")?;
+    let ys = q.forward(&xs)?;
+    assert!(max_diff(&ys, &base.forward(&xs)?)? > 1e-2);
+    q.merge()?;
+    assert!(q.is_merged());
+    assert!(max_diff(&q.forward(&xs)?, &ys)? < 1e-4);
+    q.unmerge()?;
+    assert!(max_diff(q.base().unwrap().weight(), base.weight())? < 1e-5);
+    assert!(max_diff(&q.forward(&xs)?, &ys)? < 1e-4);
+    Ok(())
+}
+
+#[test]
+fn lora_conv_and_embedding() -> Result<()> {
+    let dev = &Device::Cpu;
+    let varmap = VarMap::new();
+    let config = LoraConfig::new(2, 2., &["conv", "emb"]);
+    let lora = Lora::from_varmap(config, &varmap, DType::F32, dev);
+    let cfg = Conv2dConfig {
+        padding: 1,
+        ..Default::default()
+    };
+    let conv = Conv2d::new(Tensor::randn(0f32, 1., (4, 3, 3, 3), dev)?, None, cfg);
+    let emb = Embedding::new(Tensor::randn(0f32, 1., (10, 4), dev)?, 4);
+    let mut conv = lora.conv2d(conv, "conv")?;
+    let mut emb = lora.embedding(emb, "emb")?;
+    // Make the adapters non trivial, the layers share the storage of the variables.
+    for var in varmap.all_vars() {
+        var.set(&Tensor::randn(0f32, 1., var.shape(), dev)?)?
+    }
+
+    let xs = Tensor::randn(0f32, 1., (1, 3, 5, 5), dev)?;
+    let ys = conv.forward(&xs)?;
+    conv.merge()?;
+    assert!(max_diff(&conv.forward(&xs)?, &ys)? < 1e-4);
+
+    let ids = Tensor::new(&[[1u32, 7, 3]], dev)?;
+    let ys = emb.forward(&ids)?;
+    assert_eq!(ys.dims(), [1, 3, 4]);
+    emb.merge()?;
+    assert!(max_diff(&emb.forward(&ids)?, &ys)? < 1e-4);
+    Ok(())
+}
+
+#[test]
+fn lora_var_builder_merge() -> Result<()> {
+    let dev = &Device::Cpu;
+    let w = Tensor::randn(0f32, 1., (3, 4), dev)?;
+    let ts = HashMap::from([
+        ("layer.q.weight".to_string(), w.clone()),
+        ("layer.k.weight".to_string(), w.clone()),
+    ]);
+    let config = LoraConfig::new(2, 4., &["q"]);
+    let lora = Lora::new(config, random_adapters(&[("layer.q", 4, 3)], 2)?);
+    let vb = VarBuilder::from_tensors(ts, DType::F32, dev);
+    let xs = Tensor::randn(0f32, 1., (2, 4), dev)?;
+    let q = lora.linear(Linear::new(w.clone(), None), "layer.q")?;
+
+    let vb = vb.with_lora(lora).pp("layer");
+    let merged_q = candle_nn::linear_no_bias(4, 3, vb.pp("q"))?;
+    let merged_k = candle_nn::linear_no_bias(4, 3, vb.pp("k"))?;
+    assert!(max_diff(&merged_q.forward(&xs)?, &q.forward(&xs)?)? < 1e-4);
+    assert_eq!(max_diff(merged_k.weight(), &w)?, 0.);
+    Ok(())
+}
+
+#[test]
+fn qlora_gradients() -> Result<()> {
+    let dev = &Device::Cpu;
+    let w = Tensor::randn(0f32, 1., (8, 32), dev)?;
+    let qw = Arc::new(QTensor::quantize(&w, GgmlDType::Q8_0)?);
+    let varmap = VarMap::new();
+    let lora = Lora::from_varmap(LoraConfig::new(4, 4., &["proj"]), &varmap, DType::F32, dev);
+    let proj = lora.quantized_linear(qw.clone(), None, "proj")?;
+    let xs = Tensor::randn(0f32, 1., (3, 32), dev)?;
+    let ys = proj.forward(&xs)?;
+    let expected = xs.matmul(&qw.dequantize(dev)?.t()?)?;
+    // The activations are also quantized by the matmul.
+    let scale = expected.abs()?.flatten_all()?.max(0)?.to_scalar::<f32>()?;
+    assert!(max_diff(&ys, &expected)? < 0.02 * scale);
+
+    let loss = ys.sqr()?.sum_all()?;
+    let grads = loss.backward()?;
+    let data = varmap.data().lock().unwrap();
+    // With `lora_B` initialized to zeros, only `lora_B` gets a gradient at the first step.
+    let b = &data[&format!("{PEFT_PREFIX}.proj.lora_B.weight")];
+    let grad_b = grads.get(b).expect("no gradient for lora_B");
+    assert!(grad_b.abs()?.sum_all()?.to_scalar::<f32>()? > 0.);
+    assert!(proj.clone().merge().is_err());
+
+    // The gradient flows through the quantized weights to the input.
+    let xs = candle::Var::from_tensor(&xs)?;
+    let loss = proj.forward(&xs)?.sum_all()?;
+    let grads = loss.backward()?;
+    let grad_xs = grads.get(&xs).expect("no gradient for the input");
+    let expected = qw.dequantize(dev)?.sum_keepdim(0)?.broadcast_as((3, 32))?;
+    assert!(max_diff(grad_xs, &expected)? < 1e-4);
+    Ok(())
+}
@@ -86,3 +86,321 @@ fn binary_cross_entropy_with_logit() -> Result<()> {
     assert_eq!(to_vec0_round(&loss, 4)?, 0.8224);
     Ok(())
 }
+
+// The CTC negative log likelihood computed by enumerating all the alignments.
+fn ctc_brute_force(log_probs: &[Vec<f32>], target: &[u32], blank: u32) -> f64 {
+    let (t_len, c) = (log_probs.len(), log_probs[0].len());
+    let mut total = 0f64;
+    for path in 0..c.pow(t_len as u32) {
+        let labels = (0..t_len)
+            .map(|t| ((path / c.pow(t as u32)) % c) as u32)
+            .collect::<Vec<_>>();
+        let mut collapsed = labels.clone();
+        collapsed.dedup();
+        collapsed.retain(|&l| l != blank);
+        if collapsed == target {
+            let log_p = (0..t_len)
+                .map(|t| log_probs[t][labels[t] as usize] as f64)
+                .sum::<f64>();
+            total += log_p.exp()
+        }
+    }
+    -total.ln()
+}
+
+#[test]
+fn ctc_loss() -> Result<()> {
+    let dev = &Device::Cpu;
+    let (t_len, b_sz, c) = (5, 3, 3);
+    let logits = Tensor::randn(0f32, 1., (t_len, b_sz, c), dev)?;
+    let log_probs = candle_nn::ops::log_softmax(&logits, 2)?;
+    let targets = Tensor::new(&[[1u32, 2, 2], [2, 0, 0], [1, 1, 0]], dev)?;
+    let input_lengths = [5, 3, 4];
+    let target_lengths = [3, 1, 2];
+    let loss = candle_nn::loss::ctc_loss(&log_probs, &targets, &input_lengths, &target_lengths, 0)?;
+
+    let lp = log_probs.to_vec3::<f32>()?;
+    let targets = targets.to_vec2::<u32>()?;
+    let mut expected = 0.;
+    for b in 0..b_sz {
+        let lp = (0..input_lengths[b])
+            .map(|t| lp[t][b].clone())
+            .collect::<Vec<_>>();
+        let nll = ctc_brute_force(&lp, &targets[b][..target_lengths[b]], 0);
+        expected += nll / target_lengths[b] as f64 / b_sz as f64;
+    }
+    let loss_v = loss.to_scalar::<f32>()? as f64;
+    assert!((loss_v - expected).abs() < 1e-4, "{loss_v} {expected}");
+
+    // Compare the gradient with finite differences on the logits.
+    let logits = candle::Var::from_tensor(&logits)?;
+    let f = |logits: &Tensor| -> Result<Tensor> {
+        let log_probs = candle_nn::ops::log_softmax(logits, 2)?;
+        let targets = Tensor::new(&[[1u32, 2, 2], [2, 0, 0], [1, 1, 0]], dev)?;
+        candle_nn::loss::ctc_loss(&log_probs, &targets, &input_lengths, &target_lengths, 0)
+    };
+    let grads = f(logits.as_tensor())?.backward()?;
+    let grad = grads.get(&logits).unwrap().to_vec3::<f32>()?;
+    let eps = 1e-2;
+    for (t, b, k) in [(0, 0, 1), (2, 1, 2), (3, 2, 0), (4, 0, 0), (4, 1, 1)] {
+        let delta = Tensor::zeros((t_len, b_sz, c), candle::DType::F32, dev)?.slice_assign(
+            &[t..t + 1, b..b + 1, k..k + 1],
+            &Tensor::full(eps as f32, (1, 1, 1), dev)?,
+        )?;
+        let plus = f(&(logits.as_tensor() + &delta)?)?.to_scalar::<f32>()?;
+        let minus = f(&(logits.as_tensor() - &delta)?)?.to_scalar::<f32>()?;
+        let numerical = (plus - minus) / (2. * eps as f32);
+        assert!(
+            (numerical - grad[t][b][k]).abs() < 1e-3,
+            "{t} {b} {k} {numerical} {}",
+            grad[t][b][k]
+        );
+    }
+    // The time steps after the input length do not contribute.
+    assert_eq!(grad[4][1], [0., 0., 0.]);
+
+    // A target that is too long for its input cannot be aligned.
+    let targets = Tensor::new(&[[1u32, 1]], dev)?;
+    let log_probs = log_probs.narrow(0, 0, 2)?.narrow(1, 0, 1)?;
+    let loss = candle_nn::loss::ctc_loss(&log_probs, &targets, &[2], &[2], 0)?;
+    assert!(loss.to_scalar::<f32>()? > 1e20);
+    Ok(())
+}
+
+/* Equivalent python code:
+import torch
+import torch.nn.functional as F
+from torchvision.ops import sigmoid_focal_loss
+input = torch.tensor([
+    [ 1.1050,  0.3013, -1.5394, -2.1528, -0.8634],
+    [ 1.0730, -0.9419, -0.1670, -0.6582,  0.5061],
+    [ 0.8318,  1.1154, -0.3610,  0.5351,  1.0830]])
+target = torch.tensor([1, 0, 4])
+weight = torch.tensor([1., 2., 3., 4., 5.])
+print(F.cross_entropy(input, target, weight=weight, label_smoothing=0.1))
+print(F.cross_entropy(input, target, label_smoothing=0.1, reduction="none"))
+binary = torch.tensor([[1., 0., 0., 1., 0.], [0., 0., 1., 0., 0.], [1., 1., 0., 0., 1.]])
+print(sigmoid_focal_loss(input, binary, alpha=0.25, gamma=2., reduction="mean"))
+other = torch.tensor([
+    [0.5, 0.3, -1.0, 0.2, -0.9], [2.5, -1.0, 0.1, -0.6, 0.0], [0.8, -0.5, -0.3, 3.0, 1.0]])
+print(F.huber_loss(input, other, delta=1.0))
+print(F.smooth_l1_loss(input, other, beta=0.5, reduction="sum"))
+probs = torch.tensor([[0.2, 0.3, 0.0, 0.4, 0.1], [0.5, 0.5, 0., 0., 0.], [0.2] * 5])
+print(F.kl_div(F.log_softmax(input, dim=1), probs, reduction="sum"))
+x1, x2 = torch.tensor([0.5, -0.2, 1.3]), torch.tensor([0.1, 0.4, 1.5])
+print(F.margin_ranking_loss(x1, x2, torch.tensor([1., -1., 1.]), margin=0.3))
+a, p, n = input[:2], input[1:], input[[2, 0]]
+print(F.triplet_margin_loss(a, p, n, reduction="none"))
+print(F.triplet_margin_loss(a, p, n, swap=True, reduction="none"))
+*/
+#[test]
+fn losses_with_reduction() -> Result<()> {
+    use candle_nn::loss::{self, CrossEntropyConfig, Reduction};
+    let cpu = Device::Cpu;
+    let input = Tensor::new(
+        &[
+            [1.1050f32, 0.3013, -1.5394, -2.1528, -0.8634],
+            [1.0730, -0.9419, -0.1670, -0.6582, 0.5061],
+            [0.8318, 1.1154, -0.3610, 0.5351, 1.0830],
+        ],
+        &cpu,
+    )?;
+    let target = Tensor::new(&[1u32, 0, 4], &cpu)?;
+    let weight = Tensor::new(&[1f32, 2., 3., 4., 5.], &cpu)?;
+    let cfg = CrossEntropyConfig {
+        label_smoothing: 0.1,
+        weight: Some(weight),
+        reduction: Reduction::Mean,
+    };
+    let ce = loss::cross_entropy_with(&input, &target, &cfg)?;
+    assert_eq!(to_vec0_round(&ce, 4)?, 1.3499);
+    let cfg = CrossEntropyConfig {
+        label_smoothing: 0.1,
+        weight: None,
+        reduction: Reduction::None,
+    };
+    let ce = loss::cross_entropy_with(&input, &target, &cfg)?;
+    assert_eq!(
+        candle::test_utils::to_vec1_round(&ce, 4)?,
+        [1.4256, 0.8844, 1.3321]
+    );
+    // Without smoothing nor weights, this is the usual cross-entropy.
+    let ce = loss::cross_entropy_with(&input, &target, &CrossEntropyConfig::default())?;
+    let expected = loss::cross_entropy(&input, &target)?;
+    assert_eq!(to_vec0_round(&ce, 4)?, to_vec0_round(&expected, 4)?);
+
+    let focal = loss::focal_loss(&input, &target, 2., None, Reduction::Mean)?;
+    assert_eq!(to_vec0_round(&focal, 4)?, 0.5406);
+    let alpha = Tensor::new(&[0.5f32, 1., 1., 1., 2.], &cpu)?;
+    let focal = loss::focal_loss(&input, &target, 2., Some(&alpha), Reduction::Sum)?;
+    assert_eq!(to_vec0_round(&focal, 4)?, 2.1849);
+    let focal = loss::focal_loss(&input, &target, 0., None, Reduction::Mean)?;
+    assert_eq!(to_vec0_round(&focal, 4)?, to_vec0_round(&expected, 4)?);
+
+    let binary = Tensor::new(
+        &[
+            [1f32, 0., 0., 1., 0.],
+            [0., 0., 1., 0., 0.],
+            [1., 1., 0., 0., 1.],
+        ],
+        &cpu,
+    )?;
+    let focal = loss::sigmoid_focal_loss(&input, &binary, Some(0.25), 2., Reduction::Mean)?;
+    assert_eq!(to_vec0_round(&focal, 4)?, 0.1365);
+
+    let other = Tensor::new(
+        &[
+            [0.5f32, 0.3, -1.0, 0.2, -0.9],
+            [2.5, -1.0, 0.1, -0.6, 0.0],
+            [0.8, -0.5, -0.3, 3.0, 1.0],
+        ],
+        &cpu,
+    )?;
+    let huber = loss::huber_loss(&input, &other, 1., Reduction::Mean)?;
+    assert_eq!(to_vec0_round(&huber, 4)?, 0.4241);
+    let smooth_l1 = loss::smooth_l1_loss(&input, &other, 0.5, Reduction::Sum)?;
+    assert_eq!(to_vec0_round(&smooth_l1, 4)?, 7.8516);
+    let l1 = loss::smooth_l1_loss(&input, &other, 0., Reduction::None)?;
+    assert_eq!(l1.dims(), [3, 5]);
+
+    let probs = Tensor::new(
+        &[
+            [0.2f32, 0.3, 0.0, 0.4, 0.1],
+            [0.5, 0.5, 0., 0., 0.],
+            [0.2; 5],
+        ],
+        &cpu,
+    )?;
+    let log_probs = candle_nn::ops::log_softmax(&input, 1)?;
+    let kl = loss::kl_div(&log_probs, &probs, false, Reduction::Sum)?;
+    assert_eq!(to_vec0_round(&kl, 4)?, 2.1982);
+    let kl_sq = loss::kl_div(&log_probs, &log_probs, true, Reduction::Sum)?;
+    assert_eq!(to_vec0_round(&kl_sq, 4)?, 0.);
+
+    let x1 = Tensor::new(&[0.5f32, -0.2, 1.3], &cpu)?;
+    let x2 = Tensor::new(&[0.1f32, 0.4, 1.5], &cpu)?;
+    let y = Tensor::new(&[1f32, -1., 1.], &cpu)?;
+    let mr = loss::margin_ranking_loss(&x1, &x2, &y, 0.3, Reduction::Mean)?;
+    assert_eq!(to_vec0_round(&mr, 4)?, 0.1667);
+
+    let a = input.narrow(0, 0, 2)?;
+    let p = input.narrow(0, 1, 2)?;
+    let n = input.index_select(&Tensor::new(&[2u32, 0], &cpu)?, 0)?;
+    let triplet = loss::triplet_margin_loss(&a, &p, &n, 1., 2., false, Reduction::None)?;
+    assert_eq!(
+        candle::test_utils::to_vec1_round(&triplet, 4)?,
+        [0.121, 0.721]
+    );
+    let triplet = loss::triplet_margin_loss(&a, &p, &n, 1., 2., true, Reduction::None)?;
+    assert_eq!(
+        candle::test_utils::to_vec1_round(&triplet, 4)?,
+        [1.279, 0.721]
+    );
+    Ok(())
+}
+
+// Compares the gradient of a scalar loss with respect to the input with finite differences.
+fn check_grad<F: Fn(&Tensor) -> Result<Tensor>>(input: &Tensor, f: F) -> Result<()> {
+    let var = candle::Var::from_tensor(&input.to_dtype(candle::DType::F64)?)?;
+    let grads = f(var.as_tensor())?.backward()?;
+    let grad = grads.get(&var).unwrap().flatten_all()?.to_vec1::<f64>()?;
+    let n = input.elem_count();
+    let eps = 1e-5;
+    for i in 0..n {
+        let mut delta = vec![0f64; n];
+        delta[i] = eps;
+        let delta = Tensor::from_vec(delta, input.shape(), input.device())?;
+        let plus = f(&(var.as_tensor() + &delta)?)?.to_scalar::<f64>()?;
+        let minus = f(&(var.as_tensor() - &delta)?)?.to_scalar::<f64>()?;
+        let numerical = (plus - minus) / (2. * eps);
+        assert!(
+            (numerical - grad[i]).abs() < 1e-6,
+            "{i} {numerical} {}",
+            grad[i]
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn losses_grad() -> Result<()> {
+    use candle_nn::loss::{self, CrossEntropyConfig, Reduction};
+    let cpu = Device::Cpu;
+    let input = Tensor::randn(0f64, 1., (3, 5), &cpu)?;
+    let other = Tensor::randn(0f64, 1., (3, 5), &cpu)?;
+    let target = Tensor::new(&[1u32, 0, 4], &cpu)?;
+    let cfg = CrossEntropyConfig {
+        label_smoothing: 0.2,
+        weight: Some(Tensor::new(&[1f64, 2., 3., 4., 5.], &cpu)?),
+        reduction: Reduction::Mean,
+    };
+    check_grad(&input, |xs| loss::cross_entropy_with(xs, &target, &cfg))?;
+    check_grad(&input, |xs| {
+        loss::focal_loss(xs, &target, 2., None, Reduction::Sum)
+    })?;
+    let binary = other.gt(0.)?.to_dtype(candle::DType::F64)?;
+    check_grad(&input, |xs| {
+        loss::sigmoid_focal_loss(xs, &binary, Some(0.25), 2., Reduction::Mean)
+    })?;
+    check_grad(&input, |xs| {
+        loss::huber_loss(xs, &other, 0.7, Reduction::Mean)
+    })?;
+    check_grad(&input, |xs| {
+        loss::smooth_l1_loss(xs, &other, 0.7, Reduction::Sum)
+    })?;
+    let probs = candle_nn::ops::softmax(&other, 1)?;
+    check_grad(&input, |xs| {
+        let log_probs = candle_nn::ops::log_softmax(xs, 1)?;
+        loss::kl_div(&log_probs, &probs, false, Reduction::Sum)
+    })?;
+    let p = (&input + 0.5)?;
+    check_grad(&input, |xs| {
+        loss::triplet_margin_loss(xs, &p, &other, 1., 2., true, Reduction::Mean)
+    })?;
+    Ok(())
+}
+
+#[test]
+fn chunked_cross_entropy() -> Result<()> {
+    let dev = &Device::Cpu;
+    let hidden = candle::Var::from_tensor(&Tensor::randn(0f32, 1., (7, 4), dev)?)?;
+    let weight = candle::Var::from_tensor(&Tensor::randn(0f32, 1., (11, 4), dev)?)?;
+    let target = Tensor::new(&[3u32, 0, 10, 3, 5, 1, 7], dev)?;
+    let logits = hidden.matmul(&weight.t()?)?;
+    let expected = candle_nn::loss::cross_entropy(&logits, &target)?;
+    let expected_grads = expected.backward()?;
+    let max_diff = |a: &Tensor, b: &Tensor| -> Result<f32> {
+        (a - b)?.abs()?.flatten_all()?.max(0)?.to_scalar::<f32>()
+    };
+    for chunk_size in [1, 3, 7, 100] {
+        let loss = candle_nn::loss::chunked_cross_entropy(&hidden, &weight, &target, chunk_size)?;
+        assert!(max_diff(&loss, &expected)? < 1e-5);
+        // The gradients flow through a scaling of the loss.
+        let grads = (loss * 2.)?.backward()?;
+        for var in [&hidden, &weight] {
+            let grad = grads.get(var).unwrap();
+            let expected = (expected_grads.get(var).unwrap() * 2.)?;
+            assert!(max_diff(grad, &expected)? < 1e-5, "{chunk_size}");
+        }
+    }
+    // Only the tracked inputs get a gradient.
+    let weight_detached = weight.as_tensor().detach();
+    let loss = candle_nn::loss::chunked_cross_entropy(&hidden, &weight_detached, &target, 2)?;
+    let grads = loss.backward()?;
+    assert!(grads.get(&weight).is_none());
+    assert!(
+        max_diff(
+            grads.get(&hidden).unwrap(),
+            expected_grads.get(&hidden).unwrap()
+        )? < 1e-5
+    );
+    let loss = candle_nn::loss::chunked_cross_entropy(
+        &hidden.as_tensor().detach(),
+        &weight.as_tensor().detach(),
+        &target,
+        2,
+    )?;
+    assert!(!loss.track_op());
+    assert!(max_diff(&loss, &expected)? < 1e-5);
+    Ok(())
+}
@@ -0,0 +1,79 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use candle::test_utils::{to_vec0_round, to_vec1_round};
+use candle::{Device, Result, Tensor};
+
+#[test]
+fn confusion_matrix() -> Result<()> {
+    let cpu = Device::Cpu;
+    let preds = Tensor::new(&[0u32, 1, 2, 2, 1, 0], &cpu)?;
+    let targets = Tensor::new(&[0u32, 1, 1, 2, 2, 0], &cpu)?;
+    let cm = candle_nn::metrics::confusion_matrix(&preds, &targets, 3)?;
+    assert_eq!(
+        cm.to_vec2::<f32>()?,
+        [[2., 0., 0.], [0., 1., 1.], [0., 1., 1.]]
+    );
+    Ok(())
+}
+
+/* Equivalent python code:
+from sklearn.metrics import roc_auc_score, average_precision_score, roc_curve
+y_true = [0, 0, 1, 1, 1, 0, 1, 0]
+y_score = [0.1, 0.4, 0.35, 0.8, 0.4, 0.2, 0.9, 0.7]
+print(roc_auc_score(y_true, y_score))
+print(average_precision_score(y_true, y_score))
+print(roc_curve(y_true, y_score, drop_intermediate=False))
+*/
+#[test]
+fn roc_and_pr() -> Result<()> {
+    let cpu = Device::Cpu;
+    let labels = Tensor::new(&[0u32, 0, 1, 1, 1, 0, 1, 0], &cpu)?;
+    let scores = Tensor::new(&[0.1f32, 0.4, 0.35, 0.8, 0.4, 0.2, 0.9, 0.7], &cpu)?;
+    let auc = candle_nn::metrics::roc_auc(&scores, &labels)?;
+    assert_eq!(to_vec0_round(&auc, 4)?, 0.7813);
+    let ap = candle_nn::metrics::average_precision(&scores, &labels)?;
+    assert_eq!(to_vec0_round(&ap, 4)?, 0.8167);
+
+    let roc = candle_nn::metrics::roc_curve(&scores, &labels)?;
+    assert_eq!(
+        to_vec1_round(&roc.tpr, 4)?,
+        [0.0, 0.25, 0.5, 0.5, 0.75, 1.0, 1.0, 1.0, 1.0]
+    );
+    assert_eq!(
+        to_vec1_round(&roc.fpr, 4)?,
+        [0.0, 0.0, 0.0, 0.25, 0.5, 0.5, 0.75, 1.0, 1.0]
+    );
+    assert_eq!(
+        roc.thresholds.to_vec1::<f32>()?,
+        [f32::INFINITY, 0.9, 0.8, 0.7, 0.4, 0.35, 0.2, 0.1, 0.1]
+    );
+
+    let pr = candle_nn::metrics::precision_recall_curve(&scores, &labels)?;
+    assert_eq!(
+        to_vec1_round(&pr.precision, 4)?,
+        [1.0, 1.0, 0.6667, 0.6, 0.6667, 0.5714, 0.5, 0.5]
+    );
+    assert_eq!(
+        to_vec1_round(&pr.recall, 4)?,
+        [0.25, 0.5, 0.5, 0.75, 1.0, 1.0, 1.0, 1.0]
+    );
+    Ok(())
+}
+
+#[test]
+fn roc_auc_large() -> Result<()> {
+    // Exercise the blocked cumulative sum with perfectly separated scores.
+    let cpu = Device::Cpu;
+    let n = 1000u32;
+    let scores = Tensor::arange(0u32, n, &cpu)?.to_dtype(candle::DType::F32)?;
+    let labels = scores.ge(500f64)?;
+    let auc = candle_nn::metrics::roc_auc(&scores, &labels)?;
+    assert_eq!(to_vec0_round(&auc, 4)?, 1.0);
+    let ap = candle_nn::metrics::average_precision(&scores, &labels)?;
+    assert_eq!(to_vec0_round(&ap, 4)?, 1.0);
+    Ok(())
+}
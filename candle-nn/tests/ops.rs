@@ -4,7 +4,7 @@ extern crate intel_mkl_src;
 #[cfg(feature = "accelerate")]
 extern crate accelerate_src;
 
-use candle::{test_device, test_utils::to_vec3_round, Device, Result, Tensor};
+use candle::{test_device, test_utils::to_vec3_round, Device, IndexOp, Result, Tensor};
 
 fn softmax(device: &Device) -> Result<()> {
     let data = &[[[3f32, 1., 4.], [1., 5., 9.]], [[2., 1., 7.], [8., 2., 8.]]];
@@ -112,6 +112,32 @@ fn softmax_numerical_stability() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn softmax_norm_batch_invariant() -> Result<()> {
+    // The cpu softmax and normalization kernels give the same result for a row whatever the
+    // batch size, see `candle::utils::set_batch_invariant`.
+    let dev = &Device::Cpu;
+    let xs = Tensor::randn(0f32, 1., (6, 5, 777), dev)?;
+    let alpha = Tensor::randn(0f32, 1., 777, dev)?;
+    let beta = Tensor::randn(0f32, 1., 777, dev)?;
+    type Op<'a> = Box<dyn Fn(&Tensor) -> Result<Tensor> + 'a>;
+    let ops: [Op; 4] = [
+        Box::new(candle_nn::ops::softmax_last_dim),
+        Box::new(|xs| candle_nn::ops::softmax(xs, candle::D::Minus1)),
+        Box::new(|xs| candle_nn::ops::rms_norm(xs, &alpha, 1e-5)),
+        Box::new(|xs| candle_nn::ops::layer_norm(xs, &alpha, &beta, 1e-5)),
+    ];
+    for op in ops.iter() {
+        let alone = op(&xs.narrow(0, 4, 1)?.narrow(1, 2, 1)?)?;
+        let batched = op(&xs)?.narrow(0, 4, 1)?.narrow(1, 2, 1)?;
+        assert_eq!(
+            alone.flatten_all()?.to_vec1::<f32>()?,
+            batched.flatten_all()?.to_vec1::<f32>()?
+        );
+    }
+    Ok(())
+}
+
 fn ropei(device: &Device) -> Result<()> {
     use rand::{rngs::StdRng, Rng, SeedableRng};
 
@@ -206,10 +232,234 @@ fn sigmoid(device: &Device) -> Result<()> {
     Ok(())
 }
 
+fn pooling(device: &Device) -> Result<()> {
+    use candle_nn::ops::{adaptive_avg_pool2d, max_pool2d_with_indices, max_unpool2d};
+    let xs = Tensor::arange(0f32, 25., device)?.reshape((1, 1, 5, 5))?;
+    let ys = adaptive_avg_pool2d(&xs, (3, 3))?;
+    assert_eq!(
+        to_vec3_round(&ys.squeeze(0)?, 4)?,
+        [[[3., 4.5, 6.], [10.5, 12., 13.5], [18., 19.5, 21.]]]
+    );
+    let xs = Tensor::randn(0f32, 1., (2, 3, 4, 6), device)?;
+    let ys = adaptive_avg_pool2d(&xs, (2, 3))?;
+    let expected = xs.avg_pool2d(2)?;
+    let diff = (ys - expected)?.abs()?.flatten_all()?.max(0)?;
+    assert!(diff.to_scalar::<f32>()? < 1e-5);
+
+    let xs = Tensor::new(
+        &[
+            [1f32, 5., 2., 0.],
+            [3., 4., 8., 1.],
+            [0., 2., 1., 9.],
+            [7., 6., 3., 2.],
+        ],
+        device,
+    )?
+    .reshape((1, 1, 4, 4))?;
+    let (values, indices) = max_pool2d_with_indices(&xs, 2, 2)?;
+    assert_eq!(values.flatten_all()?.to_vec1::<f32>()?, [5., 8., 7., 9.]);
+    assert_eq!(indices.flatten_all()?.to_vec1::<u32>()?, [1, 6, 12, 11]);
+    let ys = max_unpool2d(&values, &indices, (4, 4))?;
+    assert_eq!(
+        to_vec3_round(&ys.squeeze(0)?, 4)?,
+        [[
+            [0., 5., 0., 0.],
+            [0., 0., 8., 0.],
+            [0., 0., 0., 9.],
+            [7., 0., 0., 0.]
+        ]]
+    );
+    let xs = Tensor::randn(0f32, 1., (2, 3, 7, 6), device)?;
+    let (values, _) = max_pool2d_with_indices(&xs, (3, 2), (2, 1))?;
+    let expected = xs.max_pool2d_with_stride((3, 2), (2, 1))?;
+    assert_eq!(
+        values.flatten_all()?.to_vec1::<f32>()?,
+        expected.flatten_all()?.to_vec1::<f32>()?
+    );
+    Ok(())
+}
+
+fn interpolate(device: &Device) -> Result<()> {
+    use candle_nn::ops::{interpolate, InterpolateMode};
+    let xs = Tensor::new(&[[[0f32, 1., 2.]]], device)?;
+    let ys = interpolate(&xs, &[5], InterpolateMode::Linear, true)?;
+    assert_eq!(to_vec3_round(&ys, 4)?, [[[0., 0.5, 1., 1.5, 2.]]]);
+    let ys = interpolate(&xs, &[6], InterpolateMode::Linear, false)?;
+    assert_eq!(to_vec3_round(&ys, 4)?, [[[0., 0.25, 0.75, 1.25, 1.75, 2.]]]);
+
+    let xs = Tensor::new(&[[[[0f32, 1.]]]], device)?;
+    let ys = interpolate(&xs, &[1, 4], InterpolateMode::Bicubic, false)?;
+    assert_eq!(
+        to_vec3_round(&ys.squeeze(0)?, 4)?,
+        [[[-0.1055, 0.2266, 0.7734, 1.1055]]]
+    );
+    let ones = Tensor::ones((1, 2, 3, 5), candle::DType::F32, device)?;
+    let ys = interpolate(&ones, &[7, 4], InterpolateMode::Bicubic, true)?;
+    let diff = (ys - 1.)?.abs()?.flatten_all()?.max(0)?;
+    assert!(diff.to_scalar::<f32>()? < 1e-5);
+
+    let xs = Tensor::randn(0f32, 1., (2, 3, 4, 6), device)?;
+    let ys = interpolate(&xs, &[2, 3], InterpolateMode::Bilinear, false)?;
+    let diff = (ys - xs.avg_pool2d(2)?)?.abs()?.flatten_all()?.max(0)?;
+    assert!(diff.to_scalar::<f32>()? < 1e-5);
+    let ys = interpolate(&xs, &[8, 18], InterpolateMode::Nearest, false)?;
+    let expected = xs.upsample_nearest2d(8, 18)?;
+    assert_eq!(
+        ys.flatten_all()?.to_vec1::<f32>()?,
+        expected.flatten_all()?.to_vec1::<f32>()?
+    );
+
+    // Trilinear interpolation with aligned corners is exact on linear functions.
+    let linear = |d: usize, h: usize, w: usize| -> Result<Tensor> {
+        let z = Tensor::arange(0f32, d as f32, device)?.reshape((d, 1, 1))?;
+        let y = Tensor::arange(0f32, h as f32, device)?.reshape((1, h, 1))?;
+        let x = Tensor::arange(0f32, w as f32, device)?.reshape((1, 1, w))?;
+        (z.broadcast_add(&(y * 2.)?)?.broadcast_add(&(x * 3.)?)?).reshape((1, 1, d, h, w))
+    };
+    let ys = interpolate(
+        &linear(2, 3, 4)?,
+        &[3, 5, 7],
+        InterpolateMode::Trilinear,
+        true,
+    )?;
+    // Each dimension is upsampled from n to 2n - 1 so the positions are scaled by 1/2.
+    let expected = (linear(3, 5, 7)? * 0.5)?;
+    let diff = (ys - expected)?.abs()?.flatten_all()?.max(0)?;
+    assert!(diff.to_scalar::<f32>()? < 1e-5);
+    assert!(interpolate(&xs, &[2], InterpolateMode::Bilinear, false).is_err());
+    assert!(interpolate(&xs, &[2, 2], InterpolateMode::Nearest, true).is_err());
+    Ok(())
+}
+
+fn masked_softmax(device: &Device) -> Result<()> {
+    use candle::DType;
+    let xs = Tensor::randn(0f32, 1., (2, 3, 4, 4), device)?;
+    let mask: Vec<u8> = (0..4)
+        .flat_map(|i| (0..4).map(move |j| u8::from(j > i)))
+        .collect();
+    let mask = Tensor::from_slice(&mask, (4, 4), device)?;
+    let neg_inf = Tensor::new(f32::NEG_INFINITY, device)?.broadcast_as(xs.shape())?;
+    let filled = mask.broadcast_as(xs.shape())?.where_cond(&neg_inf, &xs)?;
+    let expected = candle_nn::ops::softmax_last_dim(&filled)?;
+    let ys = candle_nn::ops::masked_softmax_last_dim(&xs, &mask)?;
+    let diff = (ys - &expected)?.abs()?.flatten_all()?.max(0)?;
+    assert!(diff.to_scalar::<f32>()? < 1e-5);
+
+    // The gradient matches the one of the softmax applied to the filled tensor.
+    let var = candle::Var::from_tensor(&xs)?;
+    let weights = Tensor::randn(0f32, 1., xs.shape(), device)?;
+    let ys = candle_nn::ops::masked_softmax_last_dim(&var, &mask)?;
+    let grad = (ys * &weights)?.sum_all()?.backward()?;
+    let grad = grad.get(&var).unwrap();
+    let filled = mask.broadcast_as(xs.shape())?.where_cond(&neg_inf, &var)?;
+    let ys = candle_nn::ops::softmax(&filled, candle::D::Minus1)?;
+    let expected = (ys * &weights)?.sum_all()?.backward()?;
+    let expected = expected.get(&var).unwrap();
+    let diff = (grad - expected)?.abs()?.flatten_all()?.max(0)?;
+    assert!(diff.to_scalar::<f32>()? < 1e-5);
+    // The in place fill cannot be used on tracked tensors.
+    assert!(candle_nn::ops::masked_fill_(&var, &mask, 0.).is_err());
+
+    // A mask broadcast over the rows, with all the values of the first head masked.
+    let mask = Tensor::new(&[[[1u8]], [[0]], [[0]]], device)?;
+    let ys = candle_nn::ops::masked_softmax_last_dim(&xs, &mask)?;
+    let expected = candle_nn::ops::softmax_last_dim(&xs)?;
+    assert_eq!(ys.i((.., 0))?.sum_all()?.to_scalar::<f32>()?, 0.);
+    let diff = (ys.i((.., 1..))? - expected.i((.., 1..))?)?.abs()?;
+    assert!(diff.flatten_all()?.max(0)?.to_scalar::<f32>()? < 1e-5);
+
+    let mask = Tensor::new(&[0u8, 1, 1, 0], device)?;
+    assert!(candle_nn::ops::masked_softmax_last_dim(&xs, &mask.to_dtype(DType::U32)?).is_err());
+    let filled = xs.copy()?;
+    candle_nn::ops::masked_fill_(&filled, &mask, -2.)?;
+    let minus_two = Tensor::new(-2f32, device)?.broadcast_as(xs.shape())?;
+    let expected = mask.broadcast_as(xs.shape())?.where_cond(&minus_two, &xs)?;
+    let diff = (filled - expected)?.abs()?.flatten_all()?.max(0)?;
+    assert_eq!(diff.to_scalar::<f32>()?, 0.);
+    Ok(())
+}
+
 test_device!(ropei, ropei_cpu, ropei_gpu, ropei_metal);
+test_device!(
+    masked_softmax,
+    masked_softmax_cpu,
+    masked_softmax_gpu,
+    masked_softmax_metal
+);
 test_device!(rope, rope_cpu, rope_gpu, rope_metal);
 test_device!(rope_thd, rope_thd_cpu, rope_thd_gpu, rope_thd_metal);
 test_device!(softmax, softmax_cpu, softmax_gpu, softmax_metal);
 test_device!(rms_norm, rms_norm_cpu, rms_norm_gpu, rms_norm_metal);
 test_device!(layer_norm, ln_cpu, ln_gpu, ln_metal);
 test_device!(sigmoid, sigmoid_cpu, sigmoid_gpu, sigmoid_metal);
+test_device!(pooling, pooling_cpu, pooling_gpu, pooling_metal);
+test_device!(
+    interpolate,
+    interpolate_cpu,
+    interpolate_gpu,
+    interpolate_metal
+);
+
+#[test]
+fn meta_custom_ops() -> Result<()> {
+    use candle::shape_trace::{ShapeTracer, TensorSpec};
+    use candle::DType;
+
+    let tracer = ShapeTracer::new();
+    let xs = tracer.input(&TensorSpec::new((2, 3, 5, 8), DType::BF16))?;
+    let alpha = tracer.input(&TensorSpec::new(8, DType::BF16))?;
+    let cos = tracer.input(&TensorSpec::new((5, 4), DType::BF16))?;
+    let trace = tracer.trace(|| {
+        let a = candle_nn::ops::softmax_last_dim(&xs)?;
+        let b = candle_nn::ops::rms_norm(&xs, &alpha, 1e-5)?;
+        let c = candle_nn::ops::layer_norm(&xs, &alpha, &alpha, 1e-5)?;
+        let d = candle_nn::rotary_emb::rope(&xs, &cos, &cos)?;
+        Ok(vec![a, b, c, d])
+    })?;
+    for out in trace.outputs.iter() {
+        assert_eq!(out, &TensorSpec::new((2, 3, 5, 8), DType::BF16));
+    }
+    let ops: Vec<_> = trace.ops.iter().map(|op| op.op).collect();
+    assert_eq!(
+        ops,
+        ["softmax-last-dim", "rms-norm", "layer-norm", "rotary-emb"]
+    );
+    Ok(())
+}
+
+#[test]
+fn dropout_keyed() -> Result<()> {
+    use candle_nn::ops::{clear_dropout_seed, set_dropout_seed, set_dropout_step, Dropout};
+    let xs = Tensor::ones((4, 64), candle::DType::F32, &Device::Cpu)?;
+    let layer = Dropout::new(0.5);
+    set_dropout_seed(1234);
+    let ys1 = layer.forward(&xs, true)?.to_vec2::<f32>()?;
+    let kept = ys1.iter().flatten().filter(|&&v| v == 2.).count();
+    assert!((96..160).contains(&kept), "{kept}");
+    assert!(ys1.iter().flatten().all(|&v| v == 0. || v == 2.));
+    // Each call gets a new mask.
+    let ys2 = layer.forward(&xs, true)?.to_vec2::<f32>()?;
+    assert_ne!(ys1, ys2);
+    // Setting the same step again replays the masks, e.g. when recomputing activations.
+    set_dropout_step(0);
+    assert_eq!(layer.forward(&xs, true)?.to_vec2::<f32>()?, ys1);
+    assert_eq!(layer.forward(&xs, true)?.to_vec2::<f32>()?, ys2);
+    // The mask does not depend on how the batch is split.
+    let chunk = layer.forward_chunk(&xs.narrow(0, 2, 2)?, 0, 2 * 64)?;
+    assert_eq!(chunk.to_vec2::<f32>()?, ys1[2..]);
+    set_dropout_step(1);
+    assert_ne!(layer.forward(&xs, true)?.to_vec2::<f32>()?, ys1);
+    set_dropout_step(0);
+    let other = Dropout::new(0.5);
+    assert_ne!(other.forward(&xs, true)?.to_vec2::<f32>()?, ys1);
+    set_dropout_step(0);
+    assert_eq!(
+        other
+            .with_op_id(layer.op_id())
+            .forward(&xs, true)?
+            .to_vec2::<f32>()?,
+        ys1
+    );
+    clear_dropout_seed();
+    Ok(())
+}
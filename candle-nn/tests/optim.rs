@@ -4,11 +4,15 @@ extern crate intel_mkl_src;
 #[cfg(feature = "accelerate")]
 extern crate accelerate_src;
 
-use candle::test_utils::{to_vec0_round, to_vec2_round};
+use candle::test_utils::{to_vec0_round, to_vec1_round, to_vec2_round};
 
 use anyhow::Result;
 use candle::{DType, Device, Tensor, Var};
-use candle_nn::{AdamW, Linear, Module, Optimizer, ParamsAdamW, SGD};
+use candle_nn::{
+    Adafactor, AdamW, Lamb, Linear, Lion, Module, Optimizer, ParamGroup, ParamsAdafactor,
+    ParamsAdamW, ParamsLamb, ParamsLion, SGD,
+};
+use std::collections::HashMap;
 
 #[test]
 fn sgd_optim() -> Result<()> {
@@ -158,3 +162,243 @@ fn adamw_linear_regression_varmap() -> Result<()> {
     assert_eq!(to_vec0_round(lin.bias().unwrap(), 4)?, 1.);
     Ok(())
 }
+
+#[test]
+fn lion_step() -> Result<()> {
+    let x = Var::new(&[1f32, -2., 0.5], &Device::Cpu)?;
+    let c = Tensor::new(&[2f32, -0.1, 0.], &Device::Cpu)?;
+    let params = ParamsLion {
+        lr: 0.1,
+        ..Default::default()
+    };
+    let mut opt = Lion::new(vec![x.clone()], params)?;
+    // The update only depends on the sign of the gradient.
+    opt.backward_step(&(x.as_tensor() * &c)?.sum_all()?)?;
+    assert_eq!(to_vec1_round(x.as_tensor(), 4)?, &[0.9, -1.9, 0.5]);
+    opt.backward_step(&(x.as_tensor() * &c)?.sum_all()?)?;
+    assert_eq!(to_vec1_round(x.as_tensor(), 4)?, &[0.8, -1.8, 0.5]);
+    Ok(())
+}
+
+fn quadratic_target() -> Result<Tensor> {
+    Ok(Tensor::new(
+        &[[1f32, -2., 3.], [0.5, 4., -1.]],
+        &Device::Cpu,
+    )?)
+}
+
+#[test]
+fn lamb_quadratic() -> Result<()> {
+    let target = quadratic_target()?;
+    let x = Var::ones((2, 3), DType::F32, &Device::Cpu)?;
+    let params = ParamsLamb {
+        lr: 0.05,
+        weight_decay: 0.,
+        ..Default::default()
+    };
+    let mut opt = Lamb::new(vec![x.clone()], params)?;
+    for _step in 0..300 {
+        let loss = x.as_tensor().sub(&target)?.sqr()?.sum_all()?;
+        opt.backward_step(&loss)?;
+        // The step size is proportional to the norm of the variable, so the learning rate has to
+        // decay for the optimization to settle.
+        opt.set_learning_rate(opt.learning_rate() * 0.98);
+    }
+    let err = x.as_tensor().sub(&target)?.abs()?.max_keepdim(0)?.max(1)?;
+    assert!(err.to_vec1::<f32>()?[0] < 0.05, "{x}");
+    Ok(())
+}
+
+#[test]
+fn adafactor_quadratic() -> Result<()> {
+    let target = quadratic_target()?;
+    // Both a factored (2d) and a non-factored (1d) variable.
+    let x = Var::ones((2, 3), DType::F32, &Device::Cpu)?;
+    let y = Var::ones(3, DType::F32, &Device::Cpu)?;
+    let params = ParamsAdafactor {
+        lr: Some(0.05),
+        scale_parameter: false,
+        beta1: Some(0.9),
+        ..Default::default()
+    };
+    let mut opt = Adafactor::new(vec![x.clone(), y.clone()], params)?;
+    for _step in 0..500 {
+        let loss = (x.as_tensor().sub(&target)?.sqr()?.sum_all()?
+            + y.as_tensor().sub(&target.get(0)?)?.sqr()?.sum_all()?)?;
+        opt.backward_step(&loss)?;
+    }
+    let err = x.as_tensor().sub(&target)?.abs()?.flatten_all()?.max(0)?;
+    assert!(err.to_scalar::<f32>()? < 0.05, "{x}");
+    let err = y.as_tensor().sub(&target.get(0)?)?.abs()?.max(0)?;
+    assert!(err.to_scalar::<f32>()? < 0.05, "{y}");
+
+    // The relative step size is used when no learning rate is set.
+    let opt = Adafactor::new(vec![], ParamsAdafactor::default())?;
+    assert_eq!(opt.learning_rate(), 1e-2);
+    Ok(())
+}
+
+#[test]
+fn param_groups() -> Result<()> {
+    let x = Var::new(&[1f32, 2.], &Device::Cpu)?;
+    let y = Var::new(&[1f32, 2.], &Device::Cpu)?;
+    let z = Var::new(&[1f32, 2.], &Device::Cpu)?;
+    let params = ParamsAdamW {
+        lr: 0.1,
+        weight_decay: 0.,
+        ..Default::default()
+    };
+    let groups = vec![
+        ParamGroup::new(vec![x.clone()]),
+        ParamGroup::new(vec![y.clone()]).with_lr_scale(0.),
+        ParamGroup::new(vec![z.clone()]).with_weight_decay(0.5),
+    ];
+    let mut opt = AdamW::from_groups(groups, params)?;
+    let loss = (x.as_tensor() + y.as_tensor() + z.as_tensor())?.sum_all()?;
+    opt.backward_step(&loss)?;
+    // The first AdamW step moves each coordinate by the learning rate.
+    assert_eq!(to_vec1_round(x.as_tensor(), 4)?, &[0.9, 1.9]);
+    assert_eq!(to_vec1_round(y.as_tensor(), 4)?, &[1., 2.]);
+    assert_eq!(to_vec1_round(z.as_tensor(), 4)?, &[0.85, 1.8]);
+
+    // SGD does not support groups with custom hyper-parameters.
+    let groups = vec![ParamGroup::new(vec![x.clone()]).with_lr_scale(0.5)];
+    assert!(SGD::from_groups(groups, 0.1).is_err());
+    Ok(())
+}
+
+// The AdamW updates use fused kernels on cuda, the results should match the cpu ones.
+fn adamw_device(device: &Device) -> Result<()> {
+    let run = |device: &Device| -> Result<Vec<f32>> {
+        let w = Var::new(&[[0.5f32, -1.0, 2.0], [3.0, -0.25, 0.0]], device)?;
+        let x = Tensor::new(&[[1f32, 2., 3.], [-1., 0.5, 4.]], device)?;
+        let params = ParamsAdamW {
+            lr: 0.05,
+            weight_decay: 0.1,
+            ..Default::default()
+        };
+        let mut opt = AdamW::new(vec![w.clone()], params)?;
+        for _step in 0..5 {
+            let loss = (w.as_tensor() * &x)?.sqr()?.sum_all()?;
+            opt.backward_step(&loss)?;
+        }
+        Ok(w.flatten_all()?.to_vec1::<f32>()?)
+    };
+    let expected = run(&Device::Cpu)?;
+    let ws = run(device)?;
+    for (w, e) in ws.iter().zip(expected.iter()) {
+        assert!((w - e).abs() < 1e-5, "{ws:?} {expected:?}");
+    }
+    Ok(())
+}
+
+candle::test_device!(
+    adamw_device,
+    adamw_device_cpu,
+    adamw_device_gpu,
+    adamw_device_metal
+);
+
+#[test]
+fn clip_grad_norm() -> Result<()> {
+    let x = Var::new(&[1f32, 2.], &Device::Cpu)?;
+    let y = Var::new(1f32, &Device::Cpu)?;
+    let loss = ((x.as_tensor() * 3.)?.sum_all()? + (y.as_tensor() * 1.)?)?;
+    let mut grads = loss.backward()?;
+    let vars = [x.clone(), y.clone()];
+    let norm = candle_nn::optim::clip_grad_norm(&vars, &mut grads, 1.)?;
+    assert_eq!(to_vec0_round(&norm, 4)?, 4.3589);
+    let gx = grads.get(&x).unwrap();
+    assert_eq!(to_vec1_round(gx, 4)?, &[0.6882, 0.6882]);
+    assert_eq!(to_vec0_round(grads.get(&y).unwrap(), 4)?, 0.2294);
+
+    // Gradients below the threshold are unchanged.
+    let norm = candle_nn::optim::clip_grad_norm(&vars, &mut grads, 2.)?;
+    assert_eq!(to_vec0_round(&norm, 4)?, 1.);
+    assert_eq!(to_vec1_round(grads.get(&x).unwrap(), 4)?, &[0.6882, 0.6882]);
+    Ok(())
+}
+
+#[test]
+fn optimizer_state() -> Result<()> {
+    // Runs a few steps, then checks that an optimizer restored from the state produces the same
+    // updates as the original one.
+    fn check<O: Optimizer>(config: impl Fn() -> O::Config) -> Result<()> {
+        let target = quadratic_target()?;
+        let loss = |x: &Var| x.as_tensor().sub(&target)?.sqr()?.sum_all();
+        let x = Var::ones((2, 3), DType::F32, &Device::Cpu)?;
+        let mut opt = O::new(vec![x.clone()], config())?;
+        for _step in 0..5 {
+            opt.backward_step(&loss(&x)?)?;
+        }
+        let state = opt.state()?;
+        let y = Var::from_tensor(&x.as_tensor().copy()?)?;
+        let mut restored = O::new(vec![y.clone()], config())?;
+        restored.set_state(&state)?;
+        for _step in 0..5 {
+            opt.backward_step(&loss(&x)?)?;
+            restored.backward_step(&loss(&y)?)?;
+        }
+        assert_eq!(x.to_vec2::<f32>()?, y.to_vec2::<f32>()?);
+        Ok(())
+    }
+    check::<AdamW>(|| ParamsAdamW {
+        lr: 0.1,
+        ..Default::default()
+    })?;
+    check::<Lamb>(ParamsLamb::default)?;
+    check::<Lion>(ParamsLion::default)?;
+    check::<Adafactor>(|| ParamsAdafactor {
+        beta1: Some(0.9),
+        ..Default::default()
+    })?;
+    check::<SGD>(|| 0.1)?;
+
+    // The state has to match the variables of the optimizer.
+    let x = Var::ones((2, 3), DType::F32, &Device::Cpu)?;
+    let state = AdamW::new_lr(vec![x.clone()], 0.1)?.state()?;
+    let y = Var::ones(3, DType::F32, &Device::Cpu)?;
+    assert!(AdamW::new_lr(vec![y], 0.1)?.set_state(&state).is_err());
+    assert!(AdamW::new_lr(vec![], 0.1)?.state()?.contains_key("step_t"));
+    Ok(())
+}
+
+#[test]
+fn optimizer_save_load() -> Result<()> {
+    let target = quadratic_target()?;
+    let loss = |x: &Var| x.as_tensor().sub(&target)?.sqr()?.sum_all();
+    let x = Var::ones((2, 3), DType::F32, &Device::Cpu)?;
+    let mut opt = AdamW::new_lr(vec![x.clone()], 0.1)?;
+    for _step in 0..3 {
+        opt.backward_step(&loss(&x)?)?;
+    }
+    let tmp = std::env::temp_dir().join(format!("candle-optim-{}.safetensors", std::process::id()));
+    opt.save(&tmp)?;
+
+    let y = Var::from_tensor(&x.as_tensor().copy()?)?;
+    let mut restored = AdamW::new_lr(vec![y.clone()], 0.1)?;
+    restored.load(&tmp)?;
+    opt.backward_step(&loss(&x)?)?;
+    restored.backward_step(&loss(&y)?)?;
+    assert_eq!(x.to_vec2::<f32>()?, y.to_vec2::<f32>()?);
+
+    // The metadata records the optimizer type and format version.
+    let (state, metadata) = candle_nn::optim::load_state(&tmp, None)?;
+    assert_eq!(state.len(), 3);
+    assert_eq!(metadata["optimizer"], "AdamW");
+    assert_eq!(metadata["format_version"], "1");
+    let mut lamb = Lamb::new_lr(vec![y.clone()], 0.1)?;
+    assert!(lamb.load(&tmp).is_err());
+
+    // Files from a more recent format version are rejected.
+    let newer = HashMap::from([
+        ("format".to_string(), "candle-optimizer-state".to_string()),
+        ("format_version".to_string(), "999".to_string()),
+    ]);
+    candle_nn::optim::save_state(&state, "AdamW", &HashMap::new(), &tmp)?;
+    candle_nn::optim::load_state(&tmp, Some("AdamW"))?;
+    safetensors::tensor::serialize_to_file(&state, &Some(newer), &tmp)?;
+    assert!(candle_nn::optim::load_state(&tmp, None).is_err());
+    std::fs::remove_file(&tmp)?;
+    Ok(())
+}
@@ -0,0 +1,143 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use candle::{DType, Device, Module, Result, Tensor, D};
+use candle_nn::peft::{PrefixTuningConfig, PromptTuningConfig};
+use candle_nn::{Ia3, Ia3Config, Linear, PrefixTuning, PromptTuning, VarBuilder, VarMap};
+
+fn max_diff(a: &Tensor, b: &Tensor) -> Result<f32> {
+    (a - b)?
+        .abs()?
+        .flatten_all()?
+        .max(D::Minus1)?
+        .to_scalar::<f32>()
+}
+
+#[test]
+fn ia3_linear() -> Result<()> {
+    let dev = &Device::Cpu;
+    let varmap = VarMap::new();
+    let config = Ia3Config::new(&["k_proj", "down_proj"], &["down_proj"]);
+    let ia3 = Ia3::from_varmap(config, &varmap, DType::F32, dev);
+    let base = Linear::new(
+        Tensor::randn(0f32, 1., (3, 4), dev)?,
+        Some(Tensor::randn(0f32, 1., 3, dev)?),
+    );
+    let xs = Tensor::randn(0f32, 1., (2, 5, 4), dev)?;
+    let mut k = ia3.linear(base.clone(), "attn.k_proj")?;
+    let mut down = ia3.linear(base.clone(), "mlp.down_proj")?;
+    let q = ia3.linear(base.clone(), "attn.q_proj")?;
+    assert!(k.has_adapter() && down.has_adapter() && !q.has_adapter());
+    // The vectors start as a no-op.
+    assert_eq!(max_diff(&k.forward(&xs)?, &base.forward(&xs)?)?, 0.);
+
+    let mut names = varmap
+        .data()
+        .lock()
+        .unwrap()
+        .keys()
+        .cloned()
+        .collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(
+        names,
+        [
+            "base_model.model.attn.k_proj.ia3_l",
+            "base_model.model.mlp.down_proj.ia3_l"
+        ]
+    );
+    for var in varmap.all_vars() {
+        var.set(&Tensor::rand(0.5f32, 1.5, var.shape(), dev)?)?
+    }
+    let scale_k = varmap.data().lock().unwrap()["base_model.model.attn.k_proj.ia3_l"]
+        .as_tensor()
+        .flatten_all()?;
+    let expected = base.forward(&xs)?.broadcast_mul(&scale_k)?;
+    assert!(max_diff(&k.forward(&xs)?, &expected)? < 1e-5);
+
+    for layer in [&mut k, &mut down] {
+        let ys = layer.forward(&xs)?;
+        layer.merge()?;
+        assert!(max_diff(&layer.forward(&xs)?, &ys)? < 1e-4);
+        layer.unmerge()?;
+        assert!(max_diff(layer.base().weight(), base.weight())? < 1e-5);
+    }
+    Ok(())
+}
+
+#[test]
+fn prefix_tuning() -> Result<()> {
+    let dev = &Device::Cpu;
+    let config = PrefixTuningConfig {
+        num_virtual_tokens: 3,
+        num_layers: 2,
+        num_attention_heads: 2,
+        token_dim: 8,
+    };
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, dev);
+    let prefix = PrefixTuning::new(config.clone(), vb)?;
+    assert_eq!(prefix.embeddings().dims(), [3, 32]);
+    let past = prefix.past_key_values(2)?;
+    assert_eq!(past.len(), 2);
+    assert_eq!(past[1].0.dims(), [2, 2, 3, 4]);
+
+    let k = Tensor::randn(0f32, 1., (2, 2, 5, 4), dev)?;
+    let v = Tensor::randn(0f32, 1., (2, 2, 5, 4), dev)?;
+    let (k2, v2) = prefix.extend_kv(1, &k, &v)?;
+    assert_eq!(k2.dims(), [2, 2, 8, 4]);
+    assert_eq!(max_diff(&k2.narrow(2, 0, 3)?, &past[1].0)?, 0.);
+    assert_eq!(max_diff(&v2.narrow(2, 0, 3)?, &past[1].1)?, 0.);
+    assert_eq!(max_diff(&k2.narrow(2, 3, 5)?, &k)?, 0.);
+
+    // The saved prefix uses the PEFT name and can be loaded back.
+    let path = std::env::temp_dir().join(format!("candle-prefix-{}.st", std::process::id()));
+    prefix.save(&path)?;
+    let vb = unsafe { VarBuilder::from_mmaped_safetensors(&[&path], DType::F32, dev)? };
+    let loaded = PrefixTuning::new(config.clone(), vb)?;
+    assert_eq!(max_diff(loaded.embeddings(), prefix.embeddings())?, 0.);
+    std::fs::remove_file(&path)?;
+
+    let config = PrefixTuningConfig {
+        num_attention_heads: 0,
+        ..config
+    };
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, dev);
+    assert!(PrefixTuning::new(config, vb).is_err());
+    Ok(())
+}
+
+#[test]
+fn prompt_tuning() -> Result<()> {
+    let dev = &Device::Cpu;
+    let config = PromptTuningConfig {
+        num_virtual_tokens: 3,
+        token_dim: 4,
+    };
+    let mut varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, dev);
+    let prompt = PromptTuning::new(config, vb)?;
+    let tokens = Tensor::arange(0f32, 8., dev)?.reshape((2, 4))?;
+    prompt.init_from_tokens(&mut varmap, &tokens)?;
+    assert_eq!(
+        prompt.embeddings().to_vec2::<f32>()?,
+        [[0., 1., 2., 3.], [4., 5., 6., 7.], [0., 1., 2., 3.]]
+    );
+    let no_tokens = Tensor::zeros((0, 4), DType::F32, dev)?;
+    assert!(prompt.init_from_tokens(&mut varmap, &no_tokens).is_err());
+
+    let input_embeds = Tensor::randn(0f32, 1., (2, 5, 4), dev)?;
+    let embeds = prompt.prepend(&input_embeds)?;
+    assert_eq!(embeds.dims(), [2, 8, 4]);
+    assert_eq!(max_diff(&embeds.narrow(1, 3, 5)?, &input_embeds)?, 0.);
+    let mask = Tensor::new(&[[1u8, 1, 0, 0, 0], [1, 1, 1, 1, 1]], dev)?;
+    let mask = prompt.extend_mask(&mask)?;
+    assert_eq!(
+        mask.to_vec2::<u8>()?,
+        [[1, 1, 1, 1, 1, 0, 0, 0], [1, 1, 1, 1, 1, 1, 1, 1]]
+    );
+    Ok(())
+}
@@ -0,0 +1,130 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use candle::{Device, Result, Tensor};
+use candle_nn::{Pipeline, PipelineStage};
+
+// A stage scaling its input and adding the sum of the previous inputs of the micro-batch.
+struct Accumulate {
+    scale: f64,
+    device: Device,
+}
+
+impl PipelineStage for Accumulate {
+    type Cache = Option<Tensor>;
+
+    fn device(&self) -> &Device {
+        &self.device
+    }
+
+    fn new_cache(&self) -> Result<Self::Cache> {
+        Ok(None)
+    }
+
+    fn forward(&mut self, xs: &Tensor, _: usize, cache: &mut Self::Cache) -> Result<Tensor> {
+        let sum = match cache.take() {
+            None => xs.clone(),
+            Some(prev) => (prev + xs)?,
+        };
+        *cache = Some(sum.clone());
+        (xs * self.scale)? + sum
+    }
+}
+
+// The same computation without the pipeline, `caches` holding the sums of the two stages.
+fn expected(xs: &Tensor, caches: &mut Option<(Tensor, Tensor)>) -> Result<Vec<f32>> {
+    let (c1, c2) = match caches.take() {
+        None => (xs.clone(), (xs * 3.)?),
+        Some((c1, c2)) => {
+            let c1 = (c1 + xs)?;
+            let ys = ((xs * 2.)? + &c1)?;
+            let c2 = (c2 + ys)?;
+            (c1, c2)
+        }
+    };
+    let ys = ((xs * 2.)? + &c1)?;
+    let zs = ((&ys * 0.5)? + &c2)?;
+    *caches = Some((c1, c2));
+    zs.to_vec1::<f32>()
+}
+
+#[test]
+fn pipeline() -> Result<()> {
+    let dev = &Device::Cpu;
+    let stage = |scale| Accumulate {
+        scale,
+        device: Device::Cpu,
+    };
+    let mut pipeline = Pipeline::new(vec![stage(2.), stage(0.5)])?;
+    assert_eq!(pipeline.n_stages(), 2);
+    let mut caches = [None, None, None];
+    for step in 0..3 {
+        let micro_batches = (0..3)
+            .map(|i| Tensor::new(&[i as f32, step as f32], dev))
+            .collect::<Result<Vec<_>>>()?;
+        let ys = pipeline.forward(&micro_batches, step)?;
+        for ((xs, ys), caches) in micro_batches.iter().zip(ys.iter()).zip(caches.iter_mut()) {
+            assert_eq!(ys.to_vec1::<f32>()?, expected(xs, caches)?);
+        }
+    }
+
+    // The caches are cleared by a reset.
+    pipeline.reset()?;
+    let xs = Tensor::new(&[1f32, 1.], dev)?;
+    let ys = pipeline.forward_batch(&xs.unsqueeze(0)?, 1, 0)?;
+    assert_eq!(ys.to_vec2::<f32>()?, [expected(&xs, &mut None)?]);
+
+    // An error only affects its own micro-batch.
+    pipeline.reset()?;
+    let mut caches = None;
+    pipeline.forward(&[xs.clone(), xs.clone()], 0)?;
+    expected(&xs, &mut caches)?;
+    let bad = Tensor::new(&[1f32, 2., 3.], dev)?;
+    assert!(pipeline.forward(&[xs.clone(), bad], 1).is_err());
+    expected(&xs, &mut caches)?;
+    let ys = pipeline.forward(&[xs.clone()], 2)?;
+    assert_eq!(ys[0].to_vec1::<f32>()?, expected(&xs, &mut caches)?);
+    Ok(())
+}
+
+#[test]
+fn pipeline_heterogeneous_stages() -> Result<()> {
+    struct Embed;
+    impl PipelineStage for Embed {
+        type Cache = ();
+        fn device(&self) -> &Device {
+            &Device::Cpu
+        }
+        fn new_cache(&self) -> Result<()> {
+            Ok(())
+        }
+        fn forward(&mut self, xs: &Tensor, _: usize, _: &mut ()) -> Result<Tensor> {
+            xs.to_dtype(candle::DType::F32)
+        }
+    }
+    let mut pipeline = Pipeline::builder()
+        .stage(|| Ok(Embed))
+        .stage(|| {
+            Ok(Accumulate {
+                scale: 1.,
+                device: Device::Cpu,
+            })
+        })
+        .build()?;
+    let xs = Tensor::new(&[[1u32, 2], [3, 4], [5, 6], [7, 8]], &Device::Cpu)?;
+    let ys = pipeline.forward_batch(&xs, 2, 0)?;
+    assert_eq!(
+        ys.to_vec2::<f32>()?,
+        [[2., 4.], [6., 8.], [10., 12.], [14., 16.]]
+    );
+
+    let err = Pipeline::builder()
+        .stage(|| Ok(Embed))
+        .stage(|| -> Result<Embed> { candle::bail!("out of memory") })
+        .build();
+    assert!(err.is_err());
+    Ok(())
+}
@@ -0,0 +1,119 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use candle::{DType, Device, Module, Result, Tensor};
+use candle_nn::pruning::{
+    channel_importance, magnitude_mask, magnitude_threshold, prune_attention_heads,
+    prune_conv2d_in_channels, prune_conv2d_out_channels, prune_linear_inputs, prune_linear_outputs,
+    sparsity, top_channels, PrunedConv2d, PrunedLinear, PrunedTensors,
+};
+use candle_nn::{Conv2d, Conv2dConfig, Linear, VarBuilder};
+
+#[test]
+fn magnitude_pruning() -> Result<()> {
+    let dev = &Device::Cpu;
+    let w = Tensor::new(&[[0.1f32, -4., 2.], [-0.5, 3., 0.2]], dev)?;
+    let mask = magnitude_mask(&w, 0.5)?;
+    assert_eq!(mask.to_vec2::<f32>()?, [[0., 1., 1.], [0., 1., 0.]]);
+    assert_eq!(magnitude_threshold(&[&w], 0.)?, f32::NEG_INFINITY);
+    // The threshold is shared between the tensors for global pruning.
+    let w2 = Tensor::new(&[10f32, 20.], dev)?;
+    assert_eq!(magnitude_threshold(&[&w, &w2], 0.5)?, 3.);
+    assert!(magnitude_mask(&w, 1.5).is_err());
+
+    let linear = Linear::new(w.clone(), Some(Tensor::new(&[1f32, 2.], dev)?));
+    let pruned = PrunedLinear::new(linear.clone()).prune_magnitude(0.5)?;
+    let xs = Tensor::new(&[[1f32, 1., 1.]], dev)?;
+    assert_eq!(pruned.forward(&xs)?.to_vec2::<f32>()?, [[-1., 5.]]);
+    let baked = pruned.apply()?;
+    assert_eq!(sparsity(baked.weight())?, 0.5);
+    assert_eq!(baked.forward(&xs)?.to_vec2::<f32>()?, [[-1., 5.]]);
+    assert!(PrunedLinear::new(linear)
+        .with_mask(Tensor::ones(3, DType::F32, dev)?)
+        .is_err());
+
+    let conv = Conv2d::new(
+        Tensor::arange(1f32, 9., dev)?.reshape((2, 1, 2, 2))?,
+        None,
+        Conv2dConfig::default(),
+    );
+    let pruned = PrunedConv2d::new(conv).prune_magnitude(0.25)?;
+    assert_eq!(sparsity(&pruned.masked_weight()?)?, 0.25);
+    let xs = Tensor::ones((1, 1, 2, 2), DType::F32, dev)?;
+    assert_eq!(
+        pruned.forward(&xs)?.flatten_all()?.to_vec1::<f32>()?,
+        [7., 26.]
+    );
+    Ok(())
+}
+
+#[test]
+fn structured_pruning() -> Result<()> {
+    let dev = &Device::Cpu;
+    let up = Linear::new(
+        Tensor::new(&[[1f32, 0.], [0., 0.1], [3., 3.]], dev)?,
+        Some(Tensor::new(&[1f32, 2., 3.], dev)?),
+    );
+    let down = Linear::new(Tensor::new(&[[1f32, 1., 1.]], dev)?, None);
+    let keep = top_channels(&channel_importance(up.weight())?, 2)?;
+    assert_eq!(keep, [0, 2]);
+    let up2 = prune_linear_outputs(&up, &keep)?;
+    let down2 = prune_linear_inputs(&down, &keep)?;
+    assert_eq!(up2.weight().dims(), [2, 2]);
+    assert_eq!(up2.bias().unwrap().to_vec1::<f32>()?, [1., 3.]);
+    assert_eq!(down2.weight().dims(), [1, 2]);
+    let xs = Tensor::new(&[[1f32, 2.]], dev)?;
+    let ys = xs.apply(&up2)?.apply(&down2)?;
+    assert_eq!(ys.to_vec2::<f32>()?, [[14.]]);
+    assert!(prune_linear_outputs(&up, &[3]).is_err());
+    assert!(top_channels(&channel_importance(up.weight())?, 4).is_err());
+
+    let conv = Conv2d::new(
+        Tensor::ones((4, 3, 3, 3), DType::F32, dev)?,
+        Some(Tensor::zeros(4, DType::F32, dev)?),
+        Conv2dConfig::default(),
+    );
+    let conv = prune_conv2d_out_channels(&conv, &[1, 3])?;
+    let conv = prune_conv2d_in_channels(&conv, &[0])?;
+    assert_eq!(conv.weight().dims(), [2, 1, 3, 3]);
+    assert_eq!(conv.bias().unwrap().dims(), [2]);
+    Ok(())
+}
+
+#[test]
+fn head_pruning_and_export() -> Result<()> {
+    let dev = &Device::Cpu;
+    let (num_heads, head_dim) = (3, 2);
+    let dim = num_heads * head_dim;
+    let proj = |seed: f32| -> Result<Linear> {
+        let w = (Tensor::arange(0f32, (dim * dim) as f32, dev)? + seed as f64)?;
+        Ok(Linear::new(w.reshape((dim, dim))?, None))
+    };
+    let (q, k, v, o) = (proj(0.)?, proj(1.)?, proj(2.)?, proj(3.)?);
+    let pruned = prune_attention_heads(&q, &k, &v, &o, num_heads, &[0, 2])?;
+    assert_eq!(pruned.q_proj.weight().dims(), [4, 6]);
+    assert_eq!(pruned.o_proj.weight().dims(), [6, 4]);
+    let rows = pruned.k_proj.weight().narrow(1, 0, 1)?.flatten_all()?;
+    assert_eq!(rows.to_vec1::<f32>()?, [1., 7., 25., 31.]);
+    assert!(prune_attention_heads(&q, &k, &v, &o, num_heads, &[3]).is_err());
+
+    let mut tensors = PrunedTensors::new();
+    tensors.insert_linear("attn.q_proj", &pruned.q_proj);
+    tensors.insert_linear("attn.o_proj", &pruned.o_proj);
+    let path =
+        std::env::temp_dir().join(format!("candle-pruning-{}.safetensors", std::process::id()));
+    tensors.save(&path)?;
+    let vb = unsafe { VarBuilder::from_mmaped_safetensors(&[&path], DType::F32, dev)? };
+    let o_proj = candle_nn::linear_no_bias(4, 6, vb.pp("attn.o_proj"))?;
+    let diff = (o_proj.weight() - pruned.o_proj.weight())?
+        .abs()?
+        .sum_all()?;
+    assert_eq!(diff.to_scalar::<f32>()?, 0.);
+    let pruned = prune_linear_inputs(&o_proj, &[0, 1])?;
+    assert_eq!(pruned.prefix(), Some("attn.o_proj"));
+    std::fs::remove_file(path)?;
+    Ok(())
+}
@@ -0,0 +1,83 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use candle::{Device, Module, Result, Tensor};
+use candle_nn::{InferenceSession, Linear};
+
+fn block_on<F: std::future::Future>(f: F) -> F::Output {
+    struct ThreadWaker(std::thread::Thread);
+    impl std::task::Wake for ThreadWaker {
+        fn wake(self: std::sync::Arc<Self>) {
+            self.0.unpark()
+        }
+    }
+    let waker = std::sync::Arc::new(ThreadWaker(std::thread::current())).into();
+    let mut cx = std::task::Context::from_waker(&waker);
+    let mut f = std::pin::pin!(f);
+    loop {
+        match f.as_mut().poll(&mut cx) {
+            std::task::Poll::Ready(v) => return v,
+            std::task::Poll::Pending => std::thread::park(),
+        }
+    }
+}
+
+#[test]
+fn inference_session() -> Result<()> {
+    let w = Tensor::new(&[[1f32, 2.], [3., 4.]], &Device::Cpu)?;
+    let linear = Linear::new(w.clone(), None);
+    let session = InferenceSession::for_module(4, Device::Cpu, move |_| Ok(Linear::new(w, None)))?;
+    let handles = (0..8)
+        .map(|i| {
+            let session = session.clone();
+            std::thread::spawn(move || {
+                let xs = Tensor::new(&[[i as f32, 1.]], &Device::Cpu)?;
+                session.run(xs)
+            })
+        })
+        .collect::<Vec<_>>();
+    for (i, handle) in handles.into_iter().enumerate() {
+        let xs = Tensor::new(&[[i as f32, 1.]], &Device::Cpu)?;
+        let ys = handle.join().unwrap()?;
+        assert_eq!(ys.to_vec2::<f32>()?, linear.forward(&xs)?.to_vec2::<f32>()?);
+    }
+    let xs = Tensor::new(&[[1f32, 1.]], &Device::Cpu)?;
+    let ys = block_on(session.try_submit(xs)?)?;
+    assert_eq!(ys.to_vec2::<f32>()?, [[3., 7.]]);
+    Ok(())
+}
+
+#[test]
+fn inference_session_backpressure() -> Result<()> {
+    let (start, started) = std::sync::mpsc::channel::<()>();
+    let (release, released) = std::sync::mpsc::channel::<()>();
+    let session = InferenceSession::new(1, move || {
+        Ok(move |x: u32| {
+            let _ = start.send(());
+            let _ = released.recv();
+            if x == 0 {
+                candle::bail!("zero")
+            }
+            Ok(2 * x)
+        })
+    })?;
+    let first = session.submit(1)?;
+    started.recv().unwrap();
+    // The first request is being processed, the second one fills the queue.
+    let second = session.try_submit(0)?;
+    assert_eq!(session.pending(), 1);
+    assert!(session.try_submit(3).is_err());
+    release.send(()).unwrap();
+    release.send(()).unwrap();
+    assert_eq!(first.wait()?, 2);
+    assert!(second.wait().is_err());
+
+    let init_err = InferenceSession::<u32, u32>::new(1, || -> Result<fn(u32) -> Result<u32>> {
+        candle::bail!("no model")
+    });
+    assert!(init_err.is_err());
+    Ok(())
+}
@@ -0,0 +1,115 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use candle::{DType, Device, Module, Result, Tensor};
+use candle_nn::pruning::{n_m_mask, sparsity, PrunedLinear, PrunedTensors};
+use candle_nn::sparse24::{Sparse24Linear, Sparse24Weight};
+use candle_nn::{Linear, VarBuilder};
+
+#[test]
+fn n_m_pruning() -> Result<()> {
+    let dev = &Device::Cpu;
+    let w = Tensor::new(&[[0.1f32, -4., 2., 0.3, 1., 1., 1., 1.]], dev)?;
+    let mask = n_m_mask(&w, 2, 4)?;
+    // Ties keep the first weights.
+    assert_eq!(mask.to_vec2::<f32>()?, [[0., 1., 1., 0., 1., 1., 0., 0.]]);
+    assert!(n_m_mask(&w, 2, 3).is_err());
+    let w = Tensor::randn(0f32, 1., (6, 16), dev)?;
+    let pruned = PrunedLinear::new(Linear::new(w, None))
+        .prune_2_4()?
+        .apply()?;
+    assert_eq!(sparsity(pruned.weight())?, 0.5);
+    Ok(())
+}
+
+#[test]
+fn sparse24_linear() -> Result<()> {
+    let dev = &Device::Cpu;
+    let w = Tensor::new(
+        &[
+            [0f32, 1., 0., 2., 3., 0., 0., 0.],
+            [4., 5., 0., 0., 0., 0., 6., 7.],
+        ],
+        dev,
+    )?;
+    let weight = Sparse24Weight::compress(&w)?;
+    assert_eq!(
+        weight.values().to_vec2::<f32>()?,
+        [[1., 2., 3., 0.], [4., 5., 6., 7.]]
+    );
+    assert_eq!(
+        weight.metadata().to_vec2::<u8>()?,
+        [[1 | 3 << 2, 1 << 2], [1 << 2, 2 | 3 << 2]]
+    );
+    assert_eq!(weight.decompress()?.to_vec2::<f32>()?, w.to_vec2::<f32>()?);
+    assert!(Sparse24Weight::compress(&Tensor::ones((2, 8), DType::F32, dev)?).is_err());
+
+    // The fused kernel matches the dense layer, for inputs with batch dimensions.
+    let bias = Tensor::new(&[0.5f32, -0.5], dev)?;
+    let dense = PrunedLinear::new(Linear::new(Tensor::randn(0f32, 1., (5, 32), dev)?, None))
+        .prune_2_4()?
+        .apply()?;
+    let dense = Linear::new(
+        dense.weight().clone(),
+        Some(Tensor::randn(0f32, 1., 5, dev)?),
+    );
+    let sparse = Sparse24Linear::from_linear(&dense)?;
+    let xs = Tensor::randn(0f32, 1., (2, 3, 32), dev)?;
+    let diff = (sparse.forward(&xs)? - dense.forward(&xs)?)?
+        .abs()?
+        .flatten_all()?
+        .max(0)?
+        .to_scalar::<f32>()?;
+    assert!(diff < 1e-5, "{diff}");
+    let bf16 = Linear::new(dense.weight().to_dtype(DType::BF16)?, None);
+    let ys = Sparse24Linear::from_linear(&bf16)?.forward(&xs.to_dtype(DType::BF16)?)?;
+    assert_eq!(ys.dtype(), DType::BF16);
+    assert_eq!(ys.dims(), [2, 3, 5]);
+
+    // Round trip through the compressed tensors.
+    let sparse = Sparse24Linear::new(weight, Some(bias))?;
+    let mut tensors = PrunedTensors::new();
+    sparse.insert_tensors("fc", &mut tensors);
+    let vb = VarBuilder::from_tensors(tensors.tensors().clone(), DType::F32, dev);
+    let loaded = Sparse24Linear::load(vb.pp("fc"), 8, 2, true)?;
+    let xs = Tensor::arange(0f32, 8., dev)?.reshape((1, 8))?;
+    assert_eq!(loaded.forward(&xs)?.to_vec2::<f32>()?, [[19.5, 89.5]]);
+    Ok(())
+}
+
+// The aligned inputs go through cuSPARSELt and the others through the generic path, both match
+// the dense layer.
+#[cfg(all(feature = "cuda", feature = "cusparselt"))]
+#[test]
+fn sparse24_linear_cusparselt() -> Result<()> {
+    let dev = &Device::new_cuda(0)?;
+    let w = Tensor::randn(0f32, 1., (32, 64), dev)?;
+    let dense = PrunedLinear::new(Linear::new(w, None))
+        .prune_2_4()?
+        .apply()?;
+    let dense = Linear::new(dense.weight().to_dtype(DType::F16)?, None);
+    let sparse = Sparse24Linear::from_linear(&dense)?;
+    for n in [1, 3, 16, 32] {
+        let xs = Tensor::randn(0f32, 1., (n, 64), dev)?.to_dtype(DType::F16)?;
+        let diff = (sparse.forward(&xs)? - dense.forward(&xs)?)?
+            .to_dtype(DType::F32)?
+            .abs()?
+            .flatten_all()?
+            .max(0)?
+            .to_scalar::<f32>()?;
+        assert!(diff < 1e-1, "{n} {diff}");
+    }
+    // The unaligned weights are not handed to cuSPARSELt.
+    let w = Tensor::randn(0f32, 1., (5, 32), dev)?;
+    let dense = PrunedLinear::new(Linear::new(w, None))
+        .prune_2_4()?
+        .apply()?;
+    let dense = Linear::new(dense.weight().to_dtype(DType::F16)?, None);
+    let xs = Tensor::randn(0f32, 1., (16, 32), dev)?.to_dtype(DType::F16)?;
+    let ys = Sparse24Linear::from_linear(&dense)?.forward(&xs)?;
+    assert_eq!(ys.dims(), [16, 5]);
+    Ok(())
+}
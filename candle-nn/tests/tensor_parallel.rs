@@ -0,0 +1,82 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use candle::collective::{Communicator, LocalCommunicator};
+use candle::{Device, Module, Result, Tensor, D};
+use candle_nn::tensor_parallel::{ColumnParallelLinear, ParallelEmbedding, RowParallelLinear};
+use candle_nn::{Embedding, Linear};
+use std::sync::Arc;
+
+fn run<F>(world_size: usize, f: F) -> Result<Vec<Tensor>>
+where
+    F: Fn(Arc<dyn Communicator>) -> Result<Tensor> + Send + Sync,
+{
+    let comms = LocalCommunicator::group(world_size);
+    std::thread::scope(|s| {
+        let handles = comms
+            .into_iter()
+            .map(|comm| s.spawn(|| f(Arc::new(comm))))
+            .collect::<Vec<_>>();
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    })
+}
+
+#[test]
+fn mlp() -> Result<()> {
+    let dev = &Device::Cpu;
+    let w1 = Tensor::randn(0f32, 1., (8, 4), dev)?;
+    let b1 = Tensor::randn(0f32, 1., 8, dev)?;
+    let w2 = Tensor::randn(0f32, 1., (3, 8), dev)?;
+    let b2 = Tensor::randn(0f32, 1., 3, dev)?;
+    let xs = Tensor::randn(0f32, 1., (2, 5, 4), dev)?;
+    let expected = Linear::new(w1.clone(), Some(b1.clone()))
+        .forward(&xs)?
+        .relu()?;
+    let expected = Linear::new(w2.clone(), Some(b2.clone())).forward(&expected)?;
+
+    let res = run(2, |comm| {
+        let rank = comm.rank();
+        let l1 = Linear::new(w1.narrow(0, rank * 4, 4)?, Some(b1.narrow(0, rank * 4, 4)?));
+        let l1 = ColumnParallelLinear::new(l1, comm.clone(), false);
+        let w2 = w2.narrow(1, rank * 4, 4)?;
+        let l2 = RowParallelLinear::new(w2, Some(b2.clone()), comm, true);
+        l2.forward(&l1.forward(&xs)?.relu()?)
+    })?;
+    for r in res {
+        let diff = (r - &expected)?.abs()?.max_keepdim(D::Minus1)?.max(0)?;
+        assert!(diff.to_vec2::<f32>()?.iter().flatten().all(|&v| v < 1e-5));
+    }
+
+    let res = run(2, |comm| {
+        let rank = comm.rank();
+        let l1 = Linear::new(w1.narrow(0, rank * 4, 4)?, Some(b1.narrow(0, rank * 4, 4)?));
+        ColumnParallelLinear::new(l1, comm, true).forward(&xs)
+    })?;
+    let expected = Linear::new(w1, Some(b1)).forward(&xs)?;
+    for r in res {
+        assert_eq!(r.dims(), [2, 5, 8]);
+        let diff = (r - &expected)?.abs()?.flatten_all()?.max(0)?;
+        assert!(diff.to_vec0::<f32>()? < 1e-5);
+    }
+    Ok(())
+}
+
+#[test]
+fn embedding() -> Result<()> {
+    let dev = &Device::Cpu;
+    let embeddings = Tensor::arange(0f32, 18., dev)?.reshape((6, 3))?;
+    let ids = Tensor::new(&[[0u32, 5, 2], [3, 4, 1]], dev)?;
+    let expected = Embedding::new(embeddings.clone(), 3).forward(&ids)?;
+    let res = run(3, |comm| {
+        let rank = comm.rank();
+        let emb = ParallelEmbedding::new(embeddings.narrow(0, rank * 2, 2)?, comm)?;
+        emb.forward(&ids)
+    })?;
+    for r in res {
+        assert_eq!(r.to_vec3::<f32>()?, expected.to_vec3::<f32>()?);
+    }
+    Ok(())
+}
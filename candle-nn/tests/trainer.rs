@@ -0,0 +1,320 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use candle::{DType, Device, Module, Result, Tensor};
+use candle_nn::diagnostics::StepDiagnostics;
+use candle_nn::trainer::{Callback, Control, EarlyStopping, StepMetrics, TrainerState};
+use candle_nn::{AdamW, Linear, Optimizer, Trainer, TrainerConfig, VarBuilder, VarMap};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+// A linear regression problem with 8 batches of 4 samples.
+fn batches() -> Result<Vec<(Tensor, Tensor)>> {
+    let dev = &Device::Cpu;
+    let w = Tensor::new(&[[3f32, -1.]], dev)?;
+    (0..8u32)
+        .map(|i| {
+            let xs = Tensor::arange(i * 8, i * 8 + 8, dev)?
+                .to_dtype(DType::F32)?
+                .reshape((4, 2))?;
+            let xs = (xs / 64.)?;
+            let ys = xs.matmul(&w.t()?)?;
+            Ok((xs, ys))
+        })
+        .collect()
+}
+
+fn model(varmap: &VarMap) -> Result<Linear> {
+    let vb = VarBuilder::from_varmap(varmap, DType::F32, &Device::Cpu);
+    candle_nn::linear(2, 1, vb.pp("lin"))
+}
+
+fn loss(model: &Linear, (xs, ys): &(Tensor, Tensor)) -> Result<Tensor> {
+    candle_nn::loss::mse(&model.forward(xs)?, ys)
+}
+
+fn snapshot(varmap: &VarMap) -> Result<HashMap<String, Tensor>> {
+    let data = varmap.data().lock().unwrap();
+    data.iter()
+        .map(|(k, v)| Ok((k.clone(), v.as_tensor().copy()?)))
+        .collect()
+}
+
+fn weights(varmap: &VarMap) -> Result<Vec<f32>> {
+    let data = varmap.data().lock().unwrap();
+    let mut ws = data["lin.weight"].flatten_all()?.to_vec1::<f32>()?;
+    ws.extend(data["lin.bias"].to_vec1::<f32>()?);
+    Ok(ws)
+}
+
+// Records the steps and evaluation losses seen by the callbacks.
+#[derive(Clone, Default)]
+struct Recorder(Arc<Mutex<Vec<String>>>);
+
+impl Callback for Recorder {
+    fn on_step(&mut self, state: &TrainerState, _metrics: &StepMetrics) -> Result<Control> {
+        self.0.lock().unwrap().push(format!("step {}", state.step));
+        Ok(Control::Continue)
+    }
+
+    fn on_epoch(&mut self, state: &TrainerState) -> Result<Control> {
+        self.0
+            .lock()
+            .unwrap()
+            .push(format!("epoch {}", state.epoch));
+        Ok(Control::Continue)
+    }
+}
+
+#[test]
+fn trainer_grad_accumulation() -> Result<()> {
+    let data = batches()?;
+    // One batch with all the samples.
+    let full = (
+        Tensor::cat(&[&data[0].0, &data[1].0], 0)?,
+        Tensor::cat(&[&data[0].1, &data[1].1], 0)?,
+    );
+    let varmap = VarMap::new();
+    let m = model(&varmap)?;
+    let init = snapshot(&varmap)?;
+    let mut opt = AdamW::new_lr(varmap.all_vars(), 0.1)?;
+    opt.backward_step(&loss(&m, &full)?)?;
+    let expected = weights(&varmap)?;
+
+    // Two batches with two accumulation steps.
+    let mut varmap2 = VarMap::new();
+    let m2 = model(&varmap2)?;
+    varmap2.set(init.iter())?;
+    let opt = AdamW::new_lr(varmap2.all_vars(), 0.1)?;
+    let config = TrainerConfig {
+        grad_accumulation_steps: 2,
+        ..Default::default()
+    };
+    let recorder = Recorder::default();
+    let mut trainer = Trainer::new(&varmap2, opt, config).with_callback(recorder.clone());
+    let state = trainer.fit(
+        |_| Ok(data[..2].iter().cloned().map(Ok)),
+        |b| loss(&m2, b),
+        None::<fn() -> Result<f64>>,
+    )?;
+    assert_eq!(state.step, 1);
+    assert_eq!(state.epoch, 1);
+    for (w, e) in weights(&varmap2)?.iter().zip(expected.iter()) {
+        assert!((w - e).abs() < 1e-5, "{w} {e}")
+    }
+    assert_eq!(*recorder.0.lock().unwrap(), ["step 1", "epoch 0"]);
+    Ok(())
+}
+
+#[test]
+fn trainer_resume() -> Result<()> {
+    let data = batches()?;
+    let dir = std::env::temp_dir().join(format!("candle-trainer-test-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let config = TrainerConfig {
+        epochs: 2,
+        grad_accumulation_steps: 2,
+        checkpoint_dir: Some(dir.clone()),
+        checkpoint_every: Some(3),
+        keep_checkpoints: Some(2),
+        ..Default::default()
+    };
+
+    // The inputs go through a keyed dropout whose state is saved in the checkpoints.
+    let dropout_loss = |m: &Linear, (xs, ys): &(Tensor, Tensor)| -> Result<Tensor> {
+        let xs = candle_nn::ops::dropout(xs, 0.25)?;
+        candle_nn::loss::mse(&m.forward(&xs)?, ys)
+    };
+
+    // Uninterrupted run.
+    let varmap = VarMap::new();
+    let m = model(&varmap)?;
+    let init = snapshot(&varmap)?;
+    let opt = AdamW::new_lr(varmap.all_vars(), 0.05)?;
+    let mut trainer = Trainer::new(&varmap, opt, config.clone()).with_seed(42);
+    candle_nn::ops::set_dropout_seed(1234);
+    trainer.fit(
+        |_| Ok(data.iter().cloned().map(Ok)),
+        |b| dropout_loss(&m, b),
+        None::<fn() -> Result<f64>>,
+    )?;
+    assert_eq!(trainer.state().step, 8);
+    let expected = weights(&varmap)?;
+    // Checkpoints at steps 3, 4 (end of epoch), 6 and 8 with only the last two kept.
+    let steps = candle_nn::trainer::list_checkpoints(&dir)?
+        .into_iter()
+        .map(|(s, _)| s)
+        .collect::<Vec<_>>();
+    assert_eq!(steps, [6, 8]);
+
+    // Resume from the checkpoint in the middle of the second epoch.
+    let mut varmap2 = VarMap::new();
+    let m2 = model(&varmap2)?;
+    varmap2.set(init.iter())?;
+    let opt = AdamW::new_lr(varmap2.all_vars(), 0.05)?;
+    let mut trainer = Trainer::new(&varmap2, opt, config);
+    candle_nn::ops::set_dropout_seed(0);
+    trainer.resume_from_checkpoint(dir.join("checkpoint-6"))?;
+    // 6 steps of 2 batches of 4x2 values.
+    assert_eq!(candle_nn::ops::dropout_rng_state(), Some((1234, 96)));
+    assert_eq!(trainer.state().epoch, 1);
+    assert_eq!(trainer.state().batch_in_epoch, 4);
+    assert_eq!(trainer.state().seed, 42);
+    let epoch_seed = trainer.state().epoch_seed();
+    let first_epoch = TrainerState {
+        epoch: 0,
+        ..trainer.state().clone()
+    };
+    assert_ne!(first_epoch.epoch_seed(), epoch_seed);
+    trainer.fit(
+        |_| Ok(data.iter().cloned().map(Ok)),
+        |b| dropout_loss(&m2, b),
+        None::<fn() -> Result<f64>>,
+    )?;
+    candle_nn::ops::clear_dropout_seed();
+    assert_eq!(trainer.state().step, 8);
+    assert_eq!(weights(&varmap2)?, expected);
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[test]
+fn trainer_early_stopping() -> Result<()> {
+    let data = batches()?;
+    let varmap = VarMap::new();
+    let m = model(&varmap)?;
+    let opt = AdamW::new_lr(varmap.all_vars(), 0.05)?;
+    let config = TrainerConfig {
+        epochs: 100,
+        ..Default::default()
+    };
+    let mut trainer = Trainer::new(&varmap, opt, config).with_callback(EarlyStopping::new(2, 0.));
+    // The evaluation loss never improves after the first evaluation.
+    let mut evals = vec![1.0, 2.0, 3.0, 0.5].into_iter();
+    let state = trainer.fit(
+        |_| Ok(data.iter().cloned().map(Ok)),
+        |b| loss(&m, b),
+        Some(|| Ok(evals.next().unwrap())),
+    )?;
+    assert_eq!(state.epoch, 3);
+    assert_eq!(state.best_eval_loss, Some(1.0));
+    Ok(())
+}
+
+#[derive(Clone, Default)]
+struct DiagnosticsRecorder(Arc<Mutex<Vec<StepDiagnostics>>>);
+
+impl Callback for DiagnosticsRecorder {
+    fn on_step(&mut self, _state: &TrainerState, metrics: &StepMetrics) -> Result<Control> {
+        let diagnostics = metrics.diagnostics.clone().expect("no diagnostics");
+        self.0.lock().unwrap().push(diagnostics);
+        Ok(Control::Continue)
+    }
+}
+
+#[test]
+fn trainer_diagnostics() -> Result<()> {
+    let data = batches()?;
+    let varmap = VarMap::new();
+    let m = model(&varmap)?;
+    // Deterministic weights so that the noise scale estimate is deterministic too.
+    for var in varmap.all_vars() {
+        var.set(&Tensor::full(0.5f32, var.shape(), &Device::Cpu)?)?
+    }
+    // A zero bias has no meaningful update ratio, it is reported as 0.
+    {
+        let data = varmap.data().lock().unwrap();
+        let bias = &data["lin.bias"];
+        bias.set(&bias.zeros_like()?)?;
+    }
+    let before = snapshot(&varmap)?;
+    let lr = 0.1;
+    let opt = candle_nn::SGD::new(varmap.all_vars(), lr)?;
+    let config = TrainerConfig {
+        grad_accumulation_steps: 4,
+        diagnostics: true,
+        ..Default::default()
+    };
+    let recorder = DiagnosticsRecorder::default();
+    let mut trainer = Trainer::new(&varmap, opt, config).with_callback(recorder.clone());
+    trainer.fit(
+        |_| Ok(data[..4].iter().cloned().map(Ok)),
+        |b| loss(&m, b),
+        None::<fn() -> Result<f64>>,
+    )?;
+    let diagnostics = recorder.0.lock().unwrap();
+    assert_eq!(diagnostics.len(), 1);
+    let d = &diagnostics[0];
+    let names = d
+        .grad_norms
+        .iter()
+        .map(|(n, _)| n.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["lin.bias", "lin.weight"]);
+    let global = d.grad_norms.iter().map(|(_, g)| g * g).sum::<f64>().sqrt();
+    assert!((d.global_grad_norm - global).abs() < 1e-6);
+    // With sgd the update is the gradient scaled by the learning rate.
+    let after = snapshot(&varmap)?;
+    for ((name, g), (_, ratio)) in d.grad_norms.iter().zip(d.update_ratios.iter()) {
+        let w = before[name].sqr()?.sum_all()?.to_scalar::<f32>()? as f64;
+        let expected = if w > 0. { lr * g / w.sqrt() } else { 0. };
+        assert!((ratio - expected).abs() < 1e-4, "{name}");
+        assert!(
+            after[name]
+                .ne(&before[name])?
+                .sum_all()?
+                .to_scalar::<u8>()?
+                > 0
+        );
+    }
+    assert!(d.noise_scale.is_some_and(|s| s > 0.), "{:?}", d.noise_scale);
+    Ok(())
+}
+
+#[test]
+fn noise_scale_estimator() {
+    use candle_nn::diagnostics::NoiseScaleEstimator;
+    // With a true gradient norm |G|^2 = 4 and a noise trace S = 12, the expected squared norm
+    // for a batch of size b is |G|^2 + S / b.
+    let sq_norm = |b: f64| 4. + 12. / b;
+    let mut estimator = NoiseScaleEstimator::new(0.9);
+    assert_eq!(estimator.estimate(), None);
+    let estimate = estimator.update(sq_norm(2.), sq_norm(8.), 2., 8.).unwrap();
+    assert!((estimate - 3.).abs() < 1e-9);
+    let estimate = estimator.update(sq_norm(1.), sq_norm(4.), 1., 4.).unwrap();
+    assert!((estimate - 3.).abs() < 1e-9);
+}
+
+#[test]
+fn trainer_cancel() -> Result<()> {
+    let data = batches()?;
+    let varmap = VarMap::new();
+    let m = model(&varmap)?;
+    let opt = AdamW::new_lr(varmap.all_vars(), 0.1)?;
+    let config = TrainerConfig {
+        epochs: 3,
+        grad_accumulation_steps: 2,
+        ..Default::default()
+    };
+    let cancel = candle::CancelToken::new();
+    let mut trainer = Trainer::new(&varmap, opt, config).with_cancel_token(cancel.clone());
+    let mut seen = 0;
+    let state = trainer.fit(
+        |_| Ok(data.iter().cloned().map(Ok)),
+        |b| {
+            seen += 1;
+            if seen == 5 {
+                cancel.cancel()
+            }
+            loss(&m, b)
+        },
+        None::<fn() -> Result<f64>>,
+    )?;
+    // The fifth batch has not been applied as its accumulation window is incomplete.
+    assert_eq!(seen, 5);
+    assert_eq!((state.epoch, state.step, state.batch_in_epoch), (0, 2, 4));
+    Ok(())
+}
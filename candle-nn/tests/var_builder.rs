@@ -0,0 +1,117 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use candle::{DType, Device, Result, Tensor};
+use candle_nn::VarBuilder;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+#[test]
+fn mmaped_pinned() -> Result<()> {
+    let cpu = Device::Cpu;
+    let dir = std::env::temp_dir();
+    let pid = std::process::id();
+    let paths = [
+        dir.join(format!("candle-vb-pinned-{pid}-0.safetensors")),
+        dir.join(format!("candle-vb-pinned-{pid}-1.safetensors")),
+    ];
+    let w0 = Tensor::randn(0f32, 1., (4, 3), &cpu)?;
+    let w1 = Tensor::randn(0f32, 1., (4, 3), &cpu)?;
+    let shard0 = HashMap::from([("layers.0.weight", w0.to_dtype(DType::BF16)?)]);
+    let shard1 = HashMap::from([("layers.1.weight", w1.to_dtype(DType::BF16)?)]);
+    candle::safetensors::save(&shard0, &paths[0])?;
+    candle::safetensors::save(&shard1, &paths[1])?;
+
+    let pinned = Arc::new(Mutex::new(vec![]));
+    let vb = {
+        let pinned = pinned.clone();
+        unsafe {
+            VarBuilder::from_mmaped_safetensors_pinned(&paths, DType::F32, &cpu, move |name| {
+                pinned.lock().unwrap().push(name.to_string());
+                name.starts_with("layers.0.")
+            })?
+        }
+    };
+    let vb = vb.pp("layers");
+    let t0 = vb.get((4, 3), "0.weight")?;
+    let t1 = vb.get((4, 3), "1.weight")?;
+    assert_eq!(t0.dtype(), DType::F32);
+    let diff = (t0 - w0.to_dtype(DType::BF16)?.to_dtype(DType::F32)?)?;
+    assert_eq!(diff.abs()?.sum_all()?.to_scalar::<f32>()?, 0.);
+    let diff = (t1 - w1.to_dtype(DType::BF16)?.to_dtype(DType::F32)?)?;
+    assert_eq!(diff.abs()?.sum_all()?.to_scalar::<f32>()?, 0.);
+    assert_eq!(
+        *pinned.lock().unwrap(),
+        ["layers.0.weight", "layers.1.weight"]
+    );
+    // The shape is checked before loading.
+    assert!(vb.get((3, 4), "0.weight").is_err());
+    assert_eq!(pinned.lock().unwrap().len(), 2);
+    assert!(vb.contains_tensor("1.weight"));
+    assert!(!vb.contains_tensor("2.weight"));
+
+    let vb = unsafe { VarBuilder::from_mmaped_safetensors(&paths, DType::F16, &cpu)? };
+    assert_eq!(vb.get((4, 3), "layers.1.weight")?.dtype(), DType::F16);
+    for path in paths.iter() {
+        std::fs::remove_file(path)?
+    }
+    Ok(())
+}
+
+#[test]
+fn layer_error_context() -> Result<()> {
+    use candle::Module;
+    let varmap = candle_nn::VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu);
+    let proj = candle_nn::linear(4, 2, vb.pp("encoder").pp("proj"))?;
+    let xs = Tensor::zeros((3, 5), DType::F32, &Device::Cpu)?;
+    let err = proj.forward(&xs).unwrap_err();
+    let context = err.context();
+    assert_eq!(context.len(), 1, "{err}");
+    assert_eq!(context[0].op, Some("linear"));
+    assert_eq!(context[0].module.as_deref(), Some("encoder.proj"));
+    assert_eq!(
+        context[0].to_string(),
+        "encoder.proj: linear([3, 5], [2, 4])"
+    );
+    // Layers created without a VarBuilder only record the op.
+    let proj = candle_nn::Linear::new(proj.weight().clone(), None);
+    assert_eq!(proj.forward(&xs).unwrap_err().context()[0].module, None);
+    Ok(())
+}
+
+// The output shown in candle-book/src/error_manage.md, keep both in sync.
+#[test]
+fn layer_error_context_display() -> Result<()> {
+    use candle::{Module, ResultExt};
+    let varmap = candle_nn::VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu);
+    let vb = vb.pp("model.layers.0");
+    let q_proj = candle_nn::linear_no_bias(32, 64, vb.pp("self_attn.q_proj"))?;
+    let xs = Tensor::zeros((1, 3, 16), DType::F32, &Device::Cpu)?;
+    let err = q_proj
+        .forward(&xs)
+        .module_context("model.layers.0")
+        .unwrap_err();
+    let msg = err.to_string();
+    // The backtrace, if enabled, is printed after the first line.
+    assert_eq!(
+        msg.lines().next(),
+        Some("shape mismatch in matmul, lhs: [1, 3, 16], rhs: [1, 32, 64]")
+    );
+    let frames = msg
+        .lines()
+        .filter(|l| l.starts_with("  in "))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        frames,
+        [
+            "  in model.layers.0.self_attn.q_proj: linear([1, 3, 16], [64, 32])",
+            "  in model.layers.0"
+        ]
+    );
+    Ok(())
+}
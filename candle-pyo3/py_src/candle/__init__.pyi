@@ -78,6 +78,63 @@ class DType:
     A `candle` dtype.
     """
 
+class GradStore:
+    """
+
+    The gradients computed by `Tensor.backward`, indexed by the tensors they relate to.
+    """
+    def get(self, tensor: Tensor) -> Optional[Tensor]:
+        """
+        Gets the gradient of a tensor, or None if the loss does not depend on it.
+        """
+        pass
+
+    def remove(self, tensor: Tensor) -> Optional[Tensor]:
+        """
+        Removes the gradient of a tensor from the store and returns it.
+        """
+        pass
+
+class Init:
+    """
+    An initialization scheme for the variables created by a `VarBuilder`.
+    """
+
+    @staticmethod
+    def constant(value: float) -> Init:
+        """
+        Initializes all the values to a constant.
+        """
+        pass
+
+    @staticmethod
+    def kaiming_normal() -> Init:
+        """
+        Kaiming normal initialization.
+        """
+        pass
+
+    @staticmethod
+    def kaiming_uniform() -> Init:
+        """
+        Kaiming uniform initialization, the default for the weights of linear layers.
+        """
+        pass
+
+    @staticmethod
+    def randn(mean: float = 0.0, stdev: float = 1.0) -> Init:
+        """
+        Samples the values from a normal distribution.
+        """
+        pass
+
+    @staticmethod
+    def uniform(lo: float, up: float) -> Init:
+        """
+        Samples the values uniformly between `lo` and `up`.
+        """
+        pass
+
 class QTensor:
     """
     A quantized tensor.
@@ -130,6 +187,15 @@ class Tensor:
         """
         pass
 
+    @property
+    def __cuda_array_interface__(self) -> Dict[str, Any]:
+        """
+        Describes the memory of a cuda tensor following the CUDA array interface (version 3) so
+        that other libraries, e.g. CuPy or Numba, can use it without copying. The attribute does
+        not exist on the other devices.
+        """
+        pass
+
     def __eq__(self, rhs: Union[Tensor, Scalar]) -> "Tensor":
         """
         Compare a tensor with a scalar or one tensor with another.
@@ -226,6 +292,490 @@ class Tensor:
         """
         pass
 
+    def backward(self) -> GradStore:
+        """
+        Computes the gradients of this tensor, usually a scalar loss, with respect to the
+        variables it depends on.
+        """
+        pass
+
+    def broadcast_add(self, rhs: Tensor) -> Tensor:
+        """
+        Adds the two tensors, while broadcasting the right-hand-side tensor to match the shape of the left-hand-side tensor.
+        """
+        pass
+
+    def broadcast_as(self, *shape: Shape) -> Tensor:
+        """
+        Broadcasts the tensor to the given shape.
+        """
+        pass
+
+    def broadcast_div(self, rhs: Tensor) -> Tensor:
+        """
+        Divides the two tensors, while broadcasting the right-hand-side tensor to match the shape of the left-hand-side tensor.
+        """
+        pass
+
+    def broadcast_left(self, *shape: Shape) -> Tensor:
+        """
+        Broadcasts the tensor to the given shape, adding new dimensions on the left.
+        """
+        pass
+
+    def broadcast_mul(self, rhs: Tensor) -> Tensor:
+        """
+        Multiplies the two tensors, while broadcasting the right-hand-side tensor to match the shape of the left-hand-side tensor.
+        """
+        pass
+
+    def broadcast_sub(self, rhs: Tensor) -> Tensor:
+        """
+        Subtracts the two tensors, while broadcasting the right-hand-side tensor to match the shape of the left-hand-side tensor.
+        """
+        pass
+
+    def contiguous(self) -> Tensor:
+        """
+        Makes the tensor contiguous in memory.
+        """
+        pass
+
+    def copy(self) -> Tensor:
+        """
+        Returns a copy of the tensor.
+        """
+        pass
+
+    def cos(self) -> Tensor:
+        """
+        Performs the `cos` operation on the tensor.
+        """
+        pass
+
+    def detach(self) -> Tensor:
+        """
+        Detach the tensor from the computation graph.
+        """
+        pass
+
+    @property
+    def device(self) -> Device:
+        """
+        Gets the tensor's device.
+        """
+        pass
+
+    @property
+    def dtype(self) -> DType:
+        """
+        Gets the tensor's dtype.
+        """
+        pass
+
+    def exp(self) -> Tensor:
+        """
+        Performs the `exp` operation on the tensor.
+        """
+        pass
+
+    def flatten_all(self) -> Tensor:
+        """
+        Flattens the tensor into a 1D tensor.
+        """
+        pass
+
+    def flatten_from(self, dim: int) -> Tensor:
+        """
+        Flattens the tensor on the dimension indexes from `dim` (inclusive) to the last dimension.
+        """
+        pass
+
+    def flatten_to(self, dim: int) -> Tensor:
+        """
+        Flattens the tensor on the dimension indexes from `0` to `dim` (inclusive).
+        """
+        pass
+
+    @staticmethod
+    def from_array_interface(obj: Any, device: Union[str, Device]) -> Tensor:
+        """
+        Creates a tensor sharing the memory described by the `__cuda_array_interface__` of `obj`,
+        or the `__array_interface__` for a cpu `device`. The tensor keeps `obj` alive.
+        """
+        pass
+
+    def gather(self, index, dim):
+        """
+        Gathers values along an axis specified by dim.
+        """
+        pass
+
+    def get(self, index: int) -> Tensor:
+        """
+        Gets the value at the specified index.
+        """
+        pass
+
+    def index_select(self, rhs: Tensor, dim: int) -> Tensor:
+        """
+        Select values for the input tensor at the target indexes across the specified dimension.
+
+        The `indexes` is argument is an int tensor with a single dimension.
+        The output has the same number of dimension as the `self` input. The target dimension of
+        the output has length the length of `indexes` and the values are taken from `self` using
+        the index from `indexes`. Other dimensions have the same number of elements as the input
+        tensor.
+        """
+        pass
+
+    def is_contiguous(self) -> bool:
+        """
+        Returns true if the tensor is contiguous in C order.
+        """
+        pass
+
+    def is_fortran_contiguous(self) -> bool:
+        """
+        Returns true if the tensor is contiguous in Fortran order.
+        """
+        pass
+
+    def log(self) -> Tensor:
+        """
+        Performs the `log` operation on the tensor.
+        """
+        pass
+
+    def matmul(self, rhs: Tensor) -> Tensor:
+        """
+        Performs a matrix multiplication between the two tensors.
+        """
+        pass
+
+    def max_keepdim(self, dim: int) -> Tensor:
+        """
+        Gathers the maximum value across the selected dimension.
+        """
+        pass
+
+    def mean_all(self) -> Tensor:
+        """
+        Returns the mean of the tensor.
+        """
+        pass
+
+    def min_keepdim(self, dim: int) -> Tensor:
+        """
+        Gathers the minimum value across the selected dimension.
+        """
+        pass
+
+    def narrow(self, dim: int, start: int, len: int) -> Tensor:
+        """
+        Returns a new tensor that is a narrowed version of the input, the dimension `dim`
+        ranges from `start` to `start + len`.
+        """
+        pass
+
+    @property
+    def nelement(self) -> int:
+        """
+        Gets the tensor's element count.
+        """
+        pass
+
+    def powf(self, p: float) -> Tensor:
+        """
+        Performs the `pow` operation on the tensor with the given exponent.
+        """
+        pass
+
+    def quantize(self, quantized_dtype: str) -> QTensor:
+        """
+        Quantize the tensor.
+        """
+        pass
+
+    @property
+    def rank(self) -> int:
+        """
+        Gets the tensor's rank.
+        """
+        pass
+
+    def recip(self) -> Tensor:
+        """
+        Get the `recip` of the tensor.
+        """
+        pass
+
+    def reshape(self, *shape: Shape) -> Tensor:
+        """
+        Reshapes the tensor to the given shape.
+        """
+        pass
+
+    @property
+    def shape(self) -> Tuple[int]:
+        """
+        Gets the tensor's shape.
+        """
+        pass
+
+    def sin(self) -> Tensor:
+        """
+        Performs the `sin` operation on the tensor.
+        """
+        pass
+
+    def sqr(self) -> Tensor:
+        """
+        Squares the tensor.
+        """
+        pass
+
+    def sqrt(self) -> Tensor:
+        """
+        Calculates the square root of the tensor.
+        """
+        pass
+
+    def squeeze(self, dim: int) -> Tensor:
+        """
+        Creates a new tensor with the specified dimension removed if its size was one.
+        """
+        pass
+
+    @property
+    def stride(self) -> Tuple[int]:
+        """
+        Gets the tensor's strides.
+        """
+        pass
+
+    def sum_all(self) -> Tensor:
+        """
+        Returns the sum of the tensor.
+        """
+        pass
+
+    def sum_keepdim(self, dim: Union[int, List[int]]) -> Tensor:
+        """
+        Returns the sum of all elements in the input tensor. The sum is performed over all the input dimensions.
+        """
+        pass
+
+    def t(self) -> Tensor:
+        """
+        Transposes the tensor.
+        """
+        pass
+
+    def to(self, *args, **kwargs) -> Tensor:
+        """
+        Performs Tensor dtype and/or device conversion.
+        """
+        pass
+
+    def to_device(self, device: Union[str, Device]) -> Tensor:
+        """
+        Move the tensor to a new device.
+        """
+        pass
+
+    def to_dtype(self, dtype: Union[str, DType]) -> Tensor:
+        """
+        Convert the tensor to a new dtype.
+        """
+        pass
+
+    def to_torch(self) -> torch.Tensor:
+        """
+        Converts candle's tensor to pytorch's tensor
+        """
+        pass
+
+    def transpose(self, dim1: int, dim2: int) -> Tensor:
+        """
+        Returns a tensor that is a transposed version of the input, the given dimensions are swapped.
+        """
+        pass
+
+    def unsqueeze(self, dim: int) -> Tensor:
+        """
+        Creates a new tensor with a dimension of size one inserted at the specified position.
+        """
+        pass
+
+    def values(self) -> _ArrayLike:
+        """
+        Gets the tensor's data as a Python scalar or array-like object.
+        """
+        pass
+
+    def where_cond(self, on_true: Tensor, on_false: Tensor) -> Tensor:
+        """
+        Returns a tensor with the same shape as the input tensor, the values are taken from
+        `on_true` if the input tensor value is not zero, and `on_false` at the positions where the
+        input tensor is equal to zero.
+        """
+        pass
+
+class VarBuilder:
+    """
+    Retrieves the weights of a model by name, either from files or from a `VarMap` in which
+    case the missing variables are created.
+    """
+
+    def contains_tensor(self, name: str) -> bool:
+        """
+        Returns true if the tensor `name` exists.
+        """
+        pass
+
+    @property
+    def device(self) -> Device:
+        """
+        The device of the returned tensors.
+        """
+        pass
+
+    @property
+    def dtype(self) -> DType:
+        """
+        The dtype of the returned tensors.
+        """
+        pass
+
+    @staticmethod
+    def from_safetensors(
+        paths: List[Union[str, PathLike]], dtype: Optional[DType] = None, device: Optional[Device] = None
+    ) -> VarBuilder:
+        """
+        A builder reading the weights from memory mapped safetensors files, the files must not be
+        modified while the builder is in use.
+        """
+        pass
+
+    @staticmethod
+    def from_tensors(
+        tensors: Dict[str, Tensor], dtype: Optional[DType] = None, device: Optional[Device] = None
+    ) -> VarBuilder:
+        """
+        A builder returning the tensors of a dictionary.
+        """
+        pass
+
+    @staticmethod
+    def from_varmap(varmap: VarMap, dtype: Optional[DType] = None, device: Optional[Device] = None) -> VarBuilder:
+        """
+        A builder creating the variables it is asked for in `varmap`, or returning the existing
+        ones.
+        """
+        pass
+
+    def get(self, shape: Shape, name: str, init: Optional[Init] = None) -> Tensor:
+        """
+        Retrieves the tensor `name` checking its shape. When backed by a `VarMap`, a missing
+        variable is created using `init`, zeros by default.
+        """
+        pass
+
+    def pp(self, prefix: str) -> VarBuilder:
+        """
+        Returns a builder for which the names are prefixed by `prefix`.
+        """
+        pass
+
+    @property
+    def prefix(self) -> str:
+        """
+        The prefix of the names.
+        """
+        pass
+
+class VarMap:
+    """
+    A named collection of variables, typically the trainable parameters of a model. The variables
+    are created through a `VarBuilder` and can be saved to and loaded from safetensors files.
+    """
+
+    def __init__():
+        pass
+
+    def all_vars(self) -> List[Var]:
+        """
+        Returns all the variables of the map.
+        """
+        pass
+
+    def load(self, path: Union[str, PathLike]) -> None:
+        """
+        Sets the variables of the map from a safetensors file, all the variables have to be
+        present in the file.
+        """
+        pass
+
+    def save(self, path: Union[str, PathLike]) -> None:
+        """
+        Saves the variables to a safetensors file.
+        """
+        pass
+
+    def set_one(self, name: str, value: Tensor) -> None:
+        """
+        Sets the value of an existing variable.
+        """
+        pass
+
+    def vars(self) -> Dict[str, Var]:
+        """
+        Returns a dictionary mapping the variable names to the variables.
+        """
+        pass
+
+class Var(Tensor):
+    """
+    A tensor which gradients are tracked and that can be updated in place, e.g. by an optimizer.
+    A `Var` can be used wherever a `Tensor` is expected.
+    """
+
+    def __init__(self, data: Union[Tensor, _ArrayLike]):
+        pass
+
+    def abs(self) -> Tensor:
+        """
+        Performs the `abs` operation on the tensor.
+        """
+        pass
+
+    def argmax_keepdim(self, dim: int) -> Tensor:
+        """
+        Returns the indices of the maximum value(s) across the selected dimension.
+        """
+        pass
+
+    def argmin_keepdim(self, dim: int) -> Tensor:
+        """
+        Returns the indices of the minimum value(s) across the selected dimension.
+        """
+        pass
+
+    def as_tensor(self) -> Tensor:
+        """
+        Returns the tensor holding the current value of the variable, operations on this tensor
+        are tracked for the gradient computation.
+        """
+        pass
+
+    def backward(self) -> GradStore:
+        """
+        Computes the gradients of this tensor, usually a scalar loss, with respect to the
+        variables it depends on.
+        """
+        pass
+
     def broadcast_add(self, rhs: Tensor) -> Tensor:
         """
         Adds the two tensors, while broadcasting the right-hand-side tensor to match the shape of the left-hand-side tensor.
@@ -435,6 +985,12 @@ class Tensor:
         """
         pass
 
+    def set(self, value: Tensor) -> None:
+        """
+        Sets the content of the variable to `value` in place, the shape and dtype have to match.
+        """
+        pass
+
     @property
     def shape(self) -> Tuple[int]:
         """
@@ -0,0 +1,7 @@
+# Generated content DO NOT EDIT
+from .. import optim
+
+clip_grad_norm = optim.clip_grad_norm
+AdamW = optim.AdamW
+Lion = optim.Lion
+SGD = optim.SGD
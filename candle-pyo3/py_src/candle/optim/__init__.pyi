@@ -0,0 +1,104 @@
+# Generated content DO NOT EDIT
+from typing import Any, Callable, Dict, List, Optional, Tuple, Union, Sequence
+from os import PathLike
+from candle.typing import _ArrayLike, Device, Scalar, Index, Shape
+from candle import Tensor, DType, QTensor
+
+@staticmethod
+def clip_grad_norm(vars: List[Var], grads: GradStore, max_norm: float) -> Tensor:
+    """
+    Rescales the gradients of `vars` in place so that their global norm is at most `max_norm`.
+    Returns the norm before clipping.
+    """
+    pass
+
+class AdamW:
+    """
+    The AdamW optimizer, i.e. Adam with decoupled weight decay.
+    """
+
+    def __init__(
+        self,
+        vars: List[Var],
+        lr: float = 0.001,
+        beta1: float = 0.9,
+        beta2: float = 0.999,
+        eps: float = 1e-8,
+        weight_decay: float = 0.01,
+    ):
+        pass
+
+    def backward_step(self, loss: Tensor) -> None:
+        """
+        Computes the gradients of `loss` and updates the variables.
+        """
+        pass
+
+    @property
+    def learning_rate(self) -> float:
+        """
+        The learning rate.
+        """
+        pass
+
+    def step(self, grads: GradStore) -> None:
+        """
+        Updates the variables using their gradients.
+        """
+        pass
+
+class Lion:
+    """
+    The Lion optimizer, which only uses the sign of the update.
+    """
+
+    def __init__(
+        self, vars: List[Var], lr: float = 1e-4, beta1: float = 0.9, beta2: float = 0.99, weight_decay: float = 0.0
+    ):
+        pass
+
+    def backward_step(self, loss: Tensor) -> None:
+        """
+        Computes the gradients of `loss` and updates the variables.
+        """
+        pass
+
+    @property
+    def learning_rate(self) -> float:
+        """
+        The learning rate.
+        """
+        pass
+
+    def step(self, grads: GradStore) -> None:
+        """
+        Updates the variables using their gradients.
+        """
+        pass
+
+class SGD:
+    """
+    Stochastic gradient descent, without momentum.
+    """
+
+    def __init__(self, vars: List[Var], lr: float):
+        pass
+
+    def backward_step(self, loss: Tensor) -> None:
+        """
+        Computes the gradients of `loss` and updates the variables.
+        """
+        pass
+
+    @property
+    def learning_rate(self) -> float:
+        """
+        The learning rate.
+        """
+        pass
+
+    def step(self, grads: GradStore) -> None:
+        """
+        Updates the variables using their gradients.
+        """
+        pass
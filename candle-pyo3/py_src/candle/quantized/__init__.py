@@ -0,0 +1,4 @@
+# Generated content DO NOT EDIT
+from .. import quantized
+
+QuantizedLlama = quantized.QuantizedLlama
@@ -0,0 +1,26 @@
+# Generated content DO NOT EDIT
+from typing import Any, Callable, Dict, List, Optional, Tuple, Union, Sequence
+from os import PathLike
+from candle.typing import _ArrayLike, Device, Scalar, Index, Shape
+from candle import Tensor, DType, QTensor
+
+class QuantizedLlama:
+    """
+    A quantized llama model, e.g. llama, mistral or mixtral, loaded from a GGUF file.
+    """
+
+    def forward(self, tokens: Tensor, index_pos: int) -> Tensor:
+        """
+        Runs the model on a batch of token ids of shape (batch, seq_len) and returns the logits
+        for the last position. `index_pos` is the position of the first token, the kv cache is
+        reset when it is 0.
+        """
+        pass
+
+    @staticmethod
+    def from_gguf(path: Union[str, PathLike], device: Optional[Device] = None) -> QuantizedLlama:
+        """
+        Loads the model weights from a GGUF file, the hyper-parameters are read from the file
+        metadata.
+        """
+        pass
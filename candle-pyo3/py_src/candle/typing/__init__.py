@@ -12,8 +12,9 @@ _ArrayLike = Union[
 
 CPU: str = "cpu"
 CUDA: str = "cuda"
+METAL: str = "metal"
 
-Device = TypeVar("Device", CPU, CUDA)
+Device = TypeVar("Device", CPU, CUDA, METAL)
 
 Scalar = Union[int, float]
 
@@ -8,5 +8,8 @@ has_mkl = utils.has_mkl
 load_ggml = utils.load_ggml
 load_gguf = utils.load_gguf
 load_safetensors = utils.load_safetensors
+metal_is_available = utils.metal_is_available
 save_gguf = utils.save_gguf
 save_safetensors = utils.save_safetensors
+set_seed = utils.set_seed
+synchronize = utils.synchronize
@@ -59,6 +59,13 @@ def load_safetensors(path: Union[str, PathLike]) -> Dict[str, Tensor]:
     """
     pass
 
+@staticmethod
+def metal_is_available() -> bool:
+    """
+    Returns true if the 'metal' backend is available.
+    """
+    pass
+
 @staticmethod
 def save_gguf(path: Union[str, PathLike], tensors: Dict[str, QTensor], metadata: Dict[str, Any]):
     """
@@ -72,3 +79,17 @@ def save_safetensors(path: Union[str, PathLike], tensors: Dict[str, Tensor]) ->
     Saves a dictionary of tensors to a safetensors file.
     """
     pass
+
+@staticmethod
+def set_seed(seed: int, device: Device) -> None:
+    """
+    Sets the seed of the random number generator of a device, this is not supported on the cpu.
+    """
+    pass
+
+@staticmethod
+def synchronize(device: Device) -> None:
+    """
+    Waits for all the operations queued on a device to complete.
+    """
+    pass
@@ -5,6 +5,7 @@ use pyo3::pyclass::CompareOp;
 use pyo3::types::{IntoPyDict, PyDict, PyTuple};
 use pyo3::ToPyObject;
 use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
 use std::hash::{Hash, Hasher};
 use std::os::raw::c_long;
 use std::sync::Arc;
@@ -17,7 +18,7 @@ extern crate intel_mkl_src;
 #[cfg(feature = "accelerate")]
 extern crate accelerate_src;
 
-use ::candle::{quantized::QTensor, DType, Device, Module, Tensor, WithDType};
+use ::candle::{quantized::QTensor, DType, Device, DeviceLocation, Module, Tensor, WithDType};
 
 mod utils;
 use utils::wrap_err;
@@ -28,8 +29,13 @@ use shape::{PyShape, PyShapeWithHole};
 #[cfg(feature = "onnx")]
 mod onnx;
 
+mod optim;
+mod quantized;
+mod var;
+use var::{PyGradStore, PyInit, PyVar, PyVarBuilder, PyVarMap};
+
 #[derive(Clone, Debug)]
-#[pyclass(name = "Tensor")]
+#[pyclass(name = "Tensor", subclass)]
 /// A `candle` tensor.
 struct PyTensor(Tensor);
 
@@ -70,46 +76,50 @@ impl PyDType {
     }
 }
 
-static CUDA_DEVICE: std::sync::Mutex<Option<Device>> = std::sync::Mutex::new(None);
-static METAL_DEVICE: std::sync::Mutex<Option<Device>> = std::sync::Mutex::new(None);
+static CUDA_DEVICES: std::sync::Mutex<BTreeMap<usize, Device>> =
+    std::sync::Mutex::new(BTreeMap::new());
+static METAL_DEVICES: std::sync::Mutex<BTreeMap<usize, Device>> =
+    std::sync::Mutex::new(BTreeMap::new());
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 enum PyDevice {
     Cpu,
-    Cuda,
-    Metal,
+    Cuda(usize),
+    Metal(usize),
+    Meta,
 }
 
 impl PyDevice {
     fn from_device(device: &Device) -> Self {
-        match device {
-            Device::Cpu => Self::Cpu,
-            Device::Cuda(_) => Self::Cuda,
-            Device::Metal(_) => Self::Metal,
+        match device.location() {
+            DeviceLocation::Cpu => Self::Cpu,
+            DeviceLocation::Cuda { gpu_id } => Self::Cuda(gpu_id),
+            DeviceLocation::Metal { gpu_id } => Self::Metal(gpu_id),
+            DeviceLocation::Meta => Self::Meta,
         }
     }
 
     fn as_device(&self) -> PyResult<Device> {
+        // The devices are created once per ordinal so that tensors created with the same device
+        // string can be used together.
+        fn cached(
+            devices: &std::sync::Mutex<BTreeMap<usize, Device>>,
+            ordinal: usize,
+            new: fn(usize) -> ::candle::Result<Device>,
+        ) -> PyResult<Device> {
+            let mut devices = devices.lock().unwrap();
+            if let Some(device) = devices.get(&ordinal) {
+                return Ok(device.clone());
+            };
+            let d = new(ordinal).map_err(wrap_err)?;
+            devices.insert(ordinal, d.clone());
+            Ok(d)
+        }
         match self {
             Self::Cpu => Ok(Device::Cpu),
-            Self::Cuda => {
-                let mut device = CUDA_DEVICE.lock().unwrap();
-                if let Some(device) = device.as_ref() {
-                    return Ok(device.clone());
-                };
-                let d = Device::new_cuda(0).map_err(wrap_err)?;
-                *device = Some(d.clone());
-                Ok(d)
-            }
-            Self::Metal => {
-                let mut device = METAL_DEVICE.lock().unwrap();
-                if let Some(device) = device.as_ref() {
-                    return Ok(device.clone());
-                };
-                let d = Device::new_metal(0).map_err(wrap_err)?;
-                *device = Some(d.clone());
-                Ok(d)
-            }
+            Self::Cuda(ordinal) => cached(&CUDA_DEVICES, *ordinal, Device::new_cuda),
+            Self::Metal(ordinal) => cached(&METAL_DEVICES, *ordinal, Device::new_metal),
+            Self::Meta => Err(PyValueError::new_err("meta devices are not supported")),
         }
     }
 }
@@ -117,10 +127,16 @@ impl PyDevice {
 impl<'source> FromPyObject<'source> for PyDevice {
     fn extract(ob: &'source PyAny) -> PyResult<Self> {
         let device: String = ob.extract()?;
-        let device = match device.as_str() {
-            "cpu" => PyDevice::Cpu,
-            "cuda" => PyDevice::Cuda,
-            _ => Err(PyTypeError::new_err(format!("invalid device '{device}'")))?,
+        let invalid = || PyTypeError::new_err(format!("invalid device '{device}'"));
+        let (kind, ordinal) = match device.split_once(':') {
+            None => (device.as_str(), 0),
+            Some((kind, ordinal)) => (kind, ordinal.parse().map_err(|_| invalid())?),
+        };
+        let device = match kind {
+            "cpu" if ordinal == 0 => PyDevice::Cpu,
+            "cuda" => PyDevice::Cuda(ordinal),
+            "metal" => PyDevice::Metal(ordinal),
+            _ => Err(invalid())?,
         };
         Ok(device)
     }
@@ -129,9 +145,12 @@ impl<'source> FromPyObject<'source> for PyDevice {
 impl ToPyObject for PyDevice {
     fn to_object(&self, py: Python<'_>) -> PyObject {
         let str = match self {
-            PyDevice::Cpu => "cpu",
-            PyDevice::Cuda => "cuda",
-            PyDevice::Metal => "metal",
+            PyDevice::Cpu => "cpu".to_string(),
+            PyDevice::Cuda(0) => "cuda".to_string(),
+            PyDevice::Cuda(ordinal) => format!("cuda:{ordinal}"),
+            PyDevice::Metal(0) => "metal".to_string(),
+            PyDevice::Metal(ordinal) => format!("metal:{ordinal}"),
+            PyDevice::Meta => "meta".to_string(),
         };
         str.to_object(py)
     }
@@ -371,6 +390,70 @@ impl PyTensor {
         self.0.rank()
     }
 
+    #[getter]
+    /// Describes the memory of a cuda tensor following the CUDA array interface (version 3) so
+    /// that other libraries, e.g. CuPy or Numba, can use it without copying. The attribute does
+    /// not exist on the other devices.
+    /// &RETURNS&: Dict[str, Any]
+    fn __cuda_array_interface__(&self, py: Python<'_>) -> PyResult<PyObject> {
+        if !self.0.device().is_cuda() {
+            return Err(pyo3::exceptions::PyAttributeError::new_err(
+                "__cuda_array_interface__ is only available for cuda tensors",
+            ));
+        }
+        let ai = self.0.array_interface().map_err(wrap_err)?;
+        let dict = PyDict::new_bound(py);
+        dict.set_item("shape", PyTuple::new_bound(py, ai.shape))?;
+        dict.set_item("typestr", ai.typestr)?;
+        dict.set_item("data", (ai.data, ai.read_only))?;
+        let strides = ai.strides.map(|s| PyTuple::new_bound(py, s));
+        dict.set_item("strides", strides)?;
+        dict.set_item("stream", ai.stream)?;
+        dict.set_item("version", ai.version)?;
+        Ok(dict.into())
+    }
+
+    #[staticmethod]
+    #[pyo3(text_signature = "(obj:Any, device:Union[str,Device])")]
+    /// Creates a tensor sharing the memory described by the `__cuda_array_interface__` of `obj`,
+    /// or the `__array_interface__` for a cpu `device`. The tensor keeps `obj` alive.
+    /// &RETURNS&: Tensor
+    fn from_array_interface(obj: &Bound<PyAny>, device: PyDevice) -> PyResult<Self> {
+        use ::candle::array_interface::ArrayInterface;
+        let device = device.as_device()?;
+        let attr = if device.is_cpu() {
+            "__array_interface__"
+        } else {
+            "__cuda_array_interface__"
+        };
+        let dict = obj.getattr(attr)?;
+        let dict = dict.downcast::<PyDict>()?;
+        let get = |key: &str| dict.get_item(key);
+        let required = |key: &str| {
+            get(key)?.ok_or_else(|| PyValueError::new_err(format!("missing {key} in {attr}")))
+        };
+        if let Some(mask) = get("mask")? {
+            if !mask.is_none() {
+                return Err(PyValueError::new_err("masked arrays are not supported"));
+            }
+        }
+        let (data, read_only): (u64, bool) = required("data")?.extract()?;
+        let ai = ArrayInterface {
+            shape: required("shape")?.extract()?,
+            typestr: required("typestr")?.extract()?,
+            data,
+            read_only,
+            strides: get("strides")?.map(|s| s.extract()).transpose()?,
+            stream: get("stream")?.map(|s| s.extract()).transpose()?,
+            version: required("version")?.extract()?,
+        };
+        // SAFETY: `obj` keeps the memory alive and is only released with the tensor.
+        let owner = obj.clone().unbind();
+        let tensor =
+            unsafe { Tensor::from_array_interface(&ai, &device, owner) }.map_err(wrap_err)?;
+        Ok(PyTensor(tensor))
+    }
+
     fn __repr__(&self) -> String {
         format!("{}", self.0)
     }
@@ -948,6 +1031,14 @@ impl PyTensor {
         PyTensor(self.0.detach())
     }
 
+    /// Computes the gradients of this tensor, usually a scalar loss, with respect to the
+    /// variables it depends on.
+    /// &RETURNS&: GradStore
+    fn backward(&self, py: Python<'_>) -> PyResult<PyGradStore> {
+        let grads = py.allow_threads(|| self.0.backward()).map_err(wrap_err)?;
+        Ok(PyGradStore(grads))
+    }
+
     /// Returns a copy of the tensor.
     /// &RETURNS&: Tensor
     fn copy(&self) -> PyResult<Self> {
@@ -1477,6 +1568,29 @@ fn cuda_is_available() -> bool {
     ::candle::utils::cuda_is_available()
 }
 
+#[pyfunction]
+/// Returns true if the 'metal' backend is available.
+/// &RETURNS&: bool
+fn metal_is_available() -> bool {
+    ::candle::utils::metal_is_available()
+}
+
+#[pyfunction]
+#[pyo3(text_signature = "(seed:int, device:Device)")]
+/// Sets the seed of the random number generator of a device, this is not supported on the cpu.
+/// &RETURNS&: None
+fn set_seed(seed: u64, device: PyDevice) -> PyResult<()> {
+    device.as_device()?.set_seed(seed).map_err(wrap_err)
+}
+
+#[pyfunction]
+#[pyo3(text_signature = "(device:Device)")]
+/// Waits for all the operations queued on a device to complete.
+/// &RETURNS&: None
+fn synchronize(device: PyDevice) -> PyResult<()> {
+    device.as_device()?.synchronize().map_err(wrap_err)
+}
+
 #[pyfunction]
 /// Returns true if candle was compiled with 'accelerate' support.
 /// &RETURNS&: bool
@@ -1503,6 +1617,9 @@ fn candle_utils(_py: Python<'_>, m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_function(wrap_pyfunction!(get_num_threads, m)?)?;
     m.add_function(wrap_pyfunction!(has_accelerate, m)?)?;
     m.add_function(wrap_pyfunction!(has_mkl, m)?)?;
+    m.add_function(wrap_pyfunction!(metal_is_available, m)?)?;
+    m.add_function(wrap_pyfunction!(set_seed, m)?)?;
+    m.add_function(wrap_pyfunction!(synchronize, m)?)?;
     m.add_function(wrap_pyfunction!(load_ggml, m)?)?;
     m.add_function(wrap_pyfunction!(load_gguf, m)?)?;
     m.add_function(wrap_pyfunction!(save_gguf, m)?)?;
@@ -1612,8 +1729,19 @@ fn candle(py: Python<'_>, m: &Bound<'_, PyModule>) -> PyResult<()> {
         candle_onnx_m(py, &onnx)?;
         m.add_submodule(&onnx)?;
     }
+    let optim = PyModule::new_bound(py, "optim")?;
+    optim::candle_optim_m(py, &optim)?;
+    m.add_submodule(&optim)?;
+    let quantized = PyModule::new_bound(py, "quantized")?;
+    quantized::candle_quantized_m(py, &quantized)?;
+    m.add_submodule(&quantized)?;
     m.add_class::<PyTensor>()?;
     m.add_class::<PyQTensor>()?;
+    m.add_class::<PyVar>()?;
+    m.add_class::<PyGradStore>()?;
+    m.add_class::<PyInit>()?;
+    m.add_class::<PyVarMap>()?;
+    m.add_class::<PyVarBuilder>()?;
     m.add_class::<PyDType>()?;
     m.add("u8", PyDType(DType::U8))?;
     m.add("u32", PyDType(DType::U32))?;
@@ -0,0 +1,150 @@
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+
+use ::candle::Var;
+use candle_nn::optim::{AdamW, Lion, Optimizer, ParamsAdamW, ParamsLion, SGD};
+
+use crate::utils::wrap_err;
+use crate::var::{PyGradStore, PyVar};
+use crate::PyTensor;
+
+fn vars(vars: Vec<PyRef<'_, PyVar>>) -> PyResult<Vec<Var>> {
+    if vars.is_empty() {
+        return Err(PyValueError::new_err("no variables to optimize"));
+    }
+    Ok(vars.iter().map(|v| v.0.clone()).collect())
+}
+
+// Adds the methods shared by all the optimizers to the constructor, `#[pymethods]` can only be
+// used once per class.
+macro_rules! optimizer_methods {
+    ($ty:ty, $($ctor:tt)*) => {
+        #[pymethods]
+        impl $ty {
+            $($ctor)*
+
+            #[pyo3(text_signature = "(self, grads:GradStore)")]
+            /// Updates the variables using their gradients.
+            /// &RETURNS&: None
+            fn step(&mut self, grads: &PyGradStore) -> PyResult<()> {
+                self.0.step(&grads.0).map_err(wrap_err)
+            }
+
+            #[pyo3(text_signature = "(self, loss:Tensor)")]
+            /// Computes the gradients of `loss` and updates the variables.
+            /// &RETURNS&: None
+            fn backward_step(&mut self, loss: &PyTensor) -> PyResult<()> {
+                self.0.backward_step(loss).map_err(wrap_err)
+            }
+
+            #[getter]
+            /// The learning rate.
+            /// &RETURNS&: float
+            fn learning_rate(&self) -> f64 {
+                self.0.learning_rate()
+            }
+
+            #[setter]
+            fn set_learning_rate(&mut self, lr: f64) {
+                self.0.set_learning_rate(lr)
+            }
+        }
+    };
+}
+
+#[pyclass(name = "SGD")]
+/// Stochastic gradient descent, without momentum.
+pub(crate) struct PySGD(SGD);
+
+optimizer_methods! {
+    PySGD,
+    #[new]
+    #[pyo3(text_signature = "(self, vars:List[Var], lr:float)")]
+    fn new(vars: Vec<PyRef<'_, PyVar>>, lr: f64) -> PyResult<Self> {
+        let sgd = SGD::new(self::vars(vars)?, lr).map_err(wrap_err)?;
+        Ok(Self(sgd))
+    }
+}
+
+#[pyclass(name = "AdamW")]
+/// The AdamW optimizer, i.e. Adam with decoupled weight decay.
+pub(crate) struct PyAdamW(AdamW);
+
+optimizer_methods! {
+    PyAdamW,
+    #[new]
+    #[pyo3(
+        signature = (vars, lr=0.001, beta1=0.9, beta2=0.999, eps=1e-8, weight_decay=0.01),
+        text_signature = "(self, vars:List[Var], lr:float=0.001, beta1:float=0.9, beta2:float=0.999, eps:float=1e-8, weight_decay:float=0.01)"
+    )]
+    fn new(
+        vars: Vec<PyRef<'_, PyVar>>,
+        lr: f64,
+        beta1: f64,
+        beta2: f64,
+        eps: f64,
+        weight_decay: f64,
+    ) -> PyResult<Self> {
+        let params = ParamsAdamW {
+            lr,
+            beta1,
+            beta2,
+            eps,
+            weight_decay,
+        };
+        let adamw = AdamW::new(self::vars(vars)?, params).map_err(wrap_err)?;
+        Ok(Self(adamw))
+    }
+}
+
+#[pyclass(name = "Lion")]
+/// The Lion optimizer, which only uses the sign of the update.
+pub(crate) struct PyLion(Lion);
+
+optimizer_methods! {
+    PyLion,
+    #[new]
+    #[pyo3(
+        signature = (vars, lr=1e-4, beta1=0.9, beta2=0.99, weight_decay=0.0),
+        text_signature = "(self, vars:List[Var], lr:float=1e-4, beta1:float=0.9, beta2:float=0.99, weight_decay:float=0.0)"
+    )]
+    fn new(
+        vars: Vec<PyRef<'_, PyVar>>,
+        lr: f64,
+        beta1: f64,
+        beta2: f64,
+        weight_decay: f64,
+    ) -> PyResult<Self> {
+        let params = ParamsLion {
+            lr,
+            beta1,
+            beta2,
+            weight_decay,
+        };
+        let lion = Lion::new(self::vars(vars)?, params).map_err(wrap_err)?;
+        Ok(Self(lion))
+    }
+}
+
+#[pyfunction]
+#[pyo3(text_signature = "(vars:List[Var], grads:GradStore, max_norm:float)")]
+/// Rescales the gradients of `vars` in place so that their global norm is at most `max_norm`.
+/// Returns the norm before clipping.
+/// &RETURNS&: Tensor
+fn clip_grad_norm(
+    vars: Vec<PyRef<'_, PyVar>>,
+    mut grads: PyRefMut<'_, PyGradStore>,
+    max_norm: f64,
+) -> PyResult<PyTensor> {
+    let vars = vars.iter().map(|v| v.0.clone()).collect::<Vec<_>>();
+    let norm = candle_nn::optim::clip_grad_norm(&vars, &mut grads.0, max_norm).map_err(wrap_err)?;
+    Ok(PyTensor(norm))
+}
+
+pub(crate) fn candle_optim_m(_py: Python<'_>, m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PySGD>()?;
+    m.add_class::<PyAdamW>()?;
+    m.add_class::<PyLion>()?;
+    m.add_function(wrap_pyfunction!(clip_grad_norm, m)?)?;
+    Ok(())
+}
@@ -0,0 +1,50 @@
+use pyo3::prelude::*;
+
+use ::candle::quantized::gguf_file;
+use candle_transformers::models::quantized_llama::ModelWeights;
+
+use crate::utils::wrap_err;
+use crate::{PyDevice, PyTensor};
+
+#[pyclass(name = "QuantizedLlama")]
+/// A quantized llama model, e.g. llama, mistral or mixtral, loaded from a GGUF file.
+pub(crate) struct PyQuantizedLlama(ModelWeights);
+
+#[pymethods]
+impl PyQuantizedLlama {
+    #[staticmethod]
+    #[pyo3(signature = (path, device=None), text_signature = "(path:Union[str,PathLike], device:Optional[Device]=None)")]
+    /// Loads the model weights from a GGUF file, the hyper-parameters are read from the file
+    /// metadata.
+    /// &RETURNS&: QuantizedLlama
+    fn from_gguf(path: &str, device: Option<PyDevice>) -> PyResult<Self> {
+        let device = device.unwrap_or(PyDevice::Cpu).as_device()?;
+        let mut file = std::fs::File::open(path)?;
+        let content =
+            gguf_file::Content::read(&mut file).map_err(|e| wrap_err(e.with_path(path)))?;
+        let model = ModelWeights::from_gguf(content, &mut file, &device).map_err(wrap_err)?;
+        Ok(Self(model))
+    }
+
+    #[pyo3(text_signature = "(self, tokens:Tensor, index_pos:int)")]
+    /// Runs the model on a batch of token ids of shape (batch, seq_len) and returns the logits
+    /// for the last position. `index_pos` is the position of the first token, the kv cache is
+    /// reset when it is 0.
+    /// &RETURNS&: Tensor
+    fn forward(
+        &mut self,
+        tokens: &PyTensor,
+        index_pos: usize,
+        py: Python<'_>,
+    ) -> PyResult<PyTensor> {
+        let logits = py
+            .allow_threads(|| self.0.forward(tokens, index_pos))
+            .map_err(wrap_err)?;
+        Ok(PyTensor(logits))
+    }
+}
+
+pub(crate) fn candle_quantized_m(_py: Python<'_>, m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyQuantizedLlama>()?;
+    Ok(())
+}
@@ -0,0 +1,328 @@
+use pyo3::exceptions::PyKeyError;
+use pyo3::prelude::*;
+use pyo3::types::IntoPyDict;
+use pyo3::PyClassInitializer;
+use std::collections::HashMap;
+
+use ::candle::{backprop::GradStore, DType, Var};
+use candle_nn::{Init, VarBuilder, VarMap};
+
+use crate::utils::wrap_err;
+use crate::{PyDType, PyDevice, PyTensor};
+
+#[derive(Clone, Debug)]
+#[pyclass(name = "Var", extends = PyTensor)]
+/// A tensor which gradients are tracked and that can be updated in place, e.g. by an optimizer.
+/// A `Var` can be used wherever a `Tensor` is expected.
+pub(crate) struct PyVar(pub Var);
+
+impl PyVar {
+    pub(crate) fn into_py_var(var: Var, py: Python<'_>) -> PyResult<Py<Self>> {
+        let init =
+            PyClassInitializer::from(PyTensor(var.as_tensor().clone())).add_subclass(Self(var));
+        Py::new(py, init)
+    }
+}
+
+#[pymethods]
+impl PyVar {
+    #[new]
+    #[pyo3(text_signature = "(self, data:Union[Tensor, _ArrayLike])")]
+    fn new(py: Python<'_>, data: PyObject) -> PyResult<(Self, PyTensor)> {
+        let tensor = match data.extract::<PyTensor>(py) {
+            Ok(tensor) => tensor,
+            Err(_) => PyTensor::new(py, data)?,
+        };
+        let var = Var::from_tensor(&tensor).map_err(wrap_err)?;
+        let tensor = PyTensor(var.as_tensor().clone());
+        Ok((Self(var), tensor))
+    }
+
+    /// Returns the tensor holding the current value of the variable, operations on this tensor
+    /// are tracked for the gradient computation.
+    /// &RETURNS&: Tensor
+    fn as_tensor(&self) -> PyTensor {
+        PyTensor(self.0.as_tensor().clone())
+    }
+
+    #[pyo3(text_signature = "(self, value:Tensor)")]
+    /// Sets the content of the variable to `value` in place, the shape and dtype have to match.
+    /// &RETURNS&: None
+    fn set(&self, value: &PyTensor) -> PyResult<()> {
+        self.0.set(value).map_err(wrap_err)
+    }
+
+    fn __repr__(&self) -> String {
+        format!("Var({})", self.0.as_tensor())
+    }
+
+    fn __str__(&self) -> String {
+        self.__repr__()
+    }
+}
+
+#[pyclass(name = "GradStore")]
+/// The gradients computed by `Tensor.backward`, indexed by the tensors they relate to.
+pub(crate) struct PyGradStore(pub GradStore);
+
+#[pymethods]
+impl PyGradStore {
+    #[pyo3(text_signature = "(self, tensor:Tensor)")]
+    /// Gets the gradient of a tensor, or None if the loss does not depend on it.
+    /// &RETURNS&: Optional[Tensor]
+    fn get(&self, tensor: &PyTensor) -> Option<PyTensor> {
+        self.0.get(tensor).map(|t| PyTensor(t.clone()))
+    }
+
+    #[pyo3(text_signature = "(self, tensor:Tensor)")]
+    /// Removes the gradient of a tensor from the store and returns it.
+    /// &RETURNS&: Optional[Tensor]
+    fn remove(&mut self, tensor: &PyTensor) -> Option<PyTensor> {
+        self.0.remove(tensor).map(PyTensor)
+    }
+
+    fn __getitem__(&self, tensor: &PyTensor) -> PyResult<PyTensor> {
+        self.get(tensor)
+            .ok_or_else(|| PyKeyError::new_err("no gradient for this tensor"))
+    }
+
+    fn __contains__(&self, tensor: &PyTensor) -> bool {
+        self.0.get(tensor).is_some()
+    }
+
+    fn __len__(&self) -> usize {
+        self.0.get_ids().count()
+    }
+}
+
+#[derive(Clone, Debug)]
+#[pyclass(name = "Init")]
+/// An initialization scheme for the variables created by a `VarBuilder`.
+pub(crate) struct PyInit(pub Init);
+
+#[pymethods]
+impl PyInit {
+    #[staticmethod]
+    #[pyo3(text_signature = "(value:float)")]
+    /// Initializes all the values to a constant.
+    /// &RETURNS&: Init
+    fn constant(value: f64) -> Self {
+        Self(Init::Const(value))
+    }
+
+    #[staticmethod]
+    #[pyo3(signature = (mean=0.0, stdev=1.0), text_signature = "(mean:float=0.0, stdev:float=1.0)")]
+    /// Samples the values from a normal distribution.
+    /// &RETURNS&: Init
+    fn randn(mean: f64, stdev: f64) -> Self {
+        Self(Init::Randn { mean, stdev })
+    }
+
+    #[staticmethod]
+    #[pyo3(text_signature = "(lo:float, up:float)")]
+    /// Samples the values uniformly between `lo` and `up`.
+    /// &RETURNS&: Init
+    fn uniform(lo: f64, up: f64) -> Self {
+        Self(Init::Uniform { lo, up })
+    }
+
+    #[staticmethod]
+    /// Kaiming uniform initialization, the default for the weights of linear layers.
+    /// &RETURNS&: Init
+    fn kaiming_uniform() -> Self {
+        Self(candle_nn::init::DEFAULT_KAIMING_UNIFORM)
+    }
+
+    #[staticmethod]
+    /// Kaiming normal initialization.
+    /// &RETURNS&: Init
+    fn kaiming_normal() -> Self {
+        Self(candle_nn::init::DEFAULT_KAIMING_NORMAL)
+    }
+
+    fn __repr__(&self) -> String {
+        format!("{:?}", self.0)
+    }
+}
+
+#[derive(Clone)]
+#[pyclass(name = "VarMap")]
+/// A named collection of variables, typically the trainable parameters of a model. The variables
+/// are created through a `VarBuilder` and can be saved to and loaded from safetensors files.
+pub(crate) struct PyVarMap(pub VarMap);
+
+#[pymethods]
+impl PyVarMap {
+    #[new]
+    fn new() -> Self {
+        Self(VarMap::new())
+    }
+
+    /// Returns all the variables of the map.
+    /// &RETURNS&: List[Var]
+    fn all_vars(&self, py: Python<'_>) -> PyResult<Vec<Py<PyVar>>> {
+        self.0
+            .all_vars()
+            .into_iter()
+            .map(|v| PyVar::into_py_var(v, py))
+            .collect()
+    }
+
+    /// Returns a dictionary mapping the variable names to the variables.
+    /// &RETURNS&: Dict[str,Var]
+    fn vars(&self, py: Python<'_>) -> PyResult<PyObject> {
+        let data = self.0.data().lock().unwrap();
+        let vars = data
+            .iter()
+            .map(|(name, var)| Ok((name.clone(), PyVar::into_py_var(var.clone(), py)?)))
+            .collect::<PyResult<Vec<_>>>()?;
+        Ok(vars.into_py_dict_bound(py).to_object(py))
+    }
+
+    #[pyo3(text_signature = "(self, path:Union[str,PathLike])")]
+    /// Saves the variables to a safetensors file.
+    /// &RETURNS&: None
+    fn save(&self, path: &str) -> PyResult<()> {
+        self.0.save(path).map_err(wrap_err)
+    }
+
+    #[pyo3(text_signature = "(self, path:Union[str,PathLike])")]
+    /// Sets the variables of the map from a safetensors file, all the variables have to be
+    /// present in the file.
+    /// &RETURNS&: None
+    fn load(&mut self, path: &str) -> PyResult<()> {
+        self.0.load(path).map_err(wrap_err)
+    }
+
+    #[pyo3(text_signature = "(self, name:str, value:Tensor)")]
+    /// Sets the value of an existing variable.
+    /// &RETURNS&: None
+    fn set_one(&mut self, name: &str, value: &PyTensor) -> PyResult<()> {
+        self.0.set_one(name, &value.0).map_err(wrap_err)
+    }
+
+    fn __len__(&self) -> usize {
+        self.0.data().lock().unwrap().len()
+    }
+
+    fn __repr__(&self) -> String {
+        format!("VarMap({} vars)", self.__len__())
+    }
+}
+
+#[derive(Clone)]
+#[pyclass(name = "VarBuilder")]
+/// Retrieves the weights of a model by name, either from files or from a `VarMap` in which
+/// case the missing variables are created.
+pub(crate) struct PyVarBuilder(pub VarBuilder<'static>);
+
+fn dtype_and_device(
+    dtype: Option<PyObject>,
+    device: Option<PyDevice>,
+    py: Python<'_>,
+) -> PyResult<(DType, ::candle::Device)> {
+    let dtype = match dtype {
+        None => DType::F32,
+        Some(dtype) => PyDType::from_pyobject(dtype, py)?.0,
+    };
+    let device = device.unwrap_or(PyDevice::Cpu).as_device()?;
+    Ok((dtype, device))
+}
+
+#[pymethods]
+impl PyVarBuilder {
+    #[staticmethod]
+    #[pyo3(signature = (varmap, dtype=None, device=None), text_signature = "(varmap:VarMap, dtype:Optional[DType]=None, device:Optional[Device]=None)")]
+    /// A builder creating the variables it is asked for in `varmap`, or returning the existing
+    /// ones.
+    /// &RETURNS&: VarBuilder
+    fn from_varmap(
+        varmap: &PyVarMap,
+        dtype: Option<PyObject>,
+        device: Option<PyDevice>,
+        py: Python<'_>,
+    ) -> PyResult<Self> {
+        let (dtype, device) = dtype_and_device(dtype, device, py)?;
+        Ok(Self(VarBuilder::from_varmap(&varmap.0, dtype, &device)))
+    }
+
+    #[staticmethod]
+    #[pyo3(signature = (paths, dtype=None, device=None), text_signature = "(paths:List[Union[str,PathLike]], dtype:Optional[DType]=None, device:Optional[Device]=None)")]
+    /// A builder reading the weights from memory mapped safetensors files, the files must not be
+    /// modified while the builder is in use.
+    /// &RETURNS&: VarBuilder
+    fn from_safetensors(
+        paths: Vec<String>,
+        dtype: Option<PyObject>,
+        device: Option<PyDevice>,
+        py: Python<'_>,
+    ) -> PyResult<Self> {
+        let (dtype, device) = dtype_and_device(dtype, device, py)?;
+        let vb = unsafe { VarBuilder::from_mmaped_safetensors(&paths, dtype, &device) };
+        Ok(Self(vb.map_err(wrap_err)?))
+    }
+
+    #[staticmethod]
+    #[pyo3(signature = (tensors, dtype=None, device=None), text_signature = "(tensors:Dict[str,Tensor], dtype:Optional[DType]=None, device:Optional[Device]=None)")]
+    /// A builder returning the tensors of a dictionary.
+    /// &RETURNS&: VarBuilder
+    fn from_tensors(
+        tensors: HashMap<String, PyTensor>,
+        dtype: Option<PyObject>,
+        device: Option<PyDevice>,
+        py: Python<'_>,
+    ) -> PyResult<Self> {
+        let (dtype, device) = dtype_and_device(dtype, device, py)?;
+        let tensors = tensors.into_iter().map(|(k, v)| (k, v.0)).collect();
+        Ok(Self(VarBuilder::from_tensors(tensors, dtype, &device)))
+    }
+
+    #[pyo3(text_signature = "(self, prefix:str)")]
+    /// Returns a builder for which the names are prefixed by `prefix`.
+    /// &RETURNS&: VarBuilder
+    fn pp(&self, prefix: &str) -> Self {
+        Self(self.0.pp(prefix))
+    }
+
+    #[pyo3(signature = (shape, name, init=None), text_signature = "(self, shape:Shape, name:str, init:Optional[Init]=None)")]
+    /// Retrieves the tensor `name` checking its shape. When backed by a `VarMap`, a missing
+    /// variable is created using `init`, zeros by default.
+    /// &RETURNS&: Tensor
+    fn get(&self, shape: &Bound<PyAny>, name: &str, init: Option<PyInit>) -> PyResult<PyTensor> {
+        let dims: Vec<usize> = match shape.extract::<usize>() {
+            Ok(dim) => vec![dim],
+            Err(_) => shape.extract()?,
+        };
+        let init = init.map_or(Init::Const(0.), |init| init.0);
+        let tensor = self.0.get_with_hints(dims, name, init).map_err(wrap_err)?;
+        Ok(PyTensor(tensor))
+    }
+
+    #[pyo3(text_signature = "(self, name:str)")]
+    /// Returns true if the tensor `name` exists.
+    /// &RETURNS&: bool
+    fn contains_tensor(&self, name: &str) -> bool {
+        self.0.contains_tensor(name)
+    }
+
+    #[getter]
+    /// The prefix of the names.
+    /// &RETURNS&: str
+    fn prefix(&self) -> String {
+        self.0.prefix()
+    }
+
+    #[getter]
+    /// The dtype of the returned tensors.
+    /// &RETURNS&: DType
+    fn dtype(&self) -> PyDType {
+        PyDType(self.0.dtype())
+    }
+
+    #[getter]
+    /// The device of the returned tensors.
+    /// &RETURNS&: Device
+    fn device(&self, py: Python<'_>) -> PyObject {
+        PyDevice::from_device(self.0.device()).to_object(py)
+    }
+}
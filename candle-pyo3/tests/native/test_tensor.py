@@ -321,3 +321,24 @@ def test_tensor_can_be_divided():
         d = candle.rand((3, 4, 5))
         e = candle.rand((4, 6))
         f = d / e
+
+
+def test_tensor_from_array_interface():
+    import array
+
+    buf = array.array("f", range(12))
+    addr, _ = buf.buffer_info()
+
+    class Strided:
+        __array_interface__ = {
+            "shape": (4, 3),
+            "typestr": "<f4",
+            "data": (addr, False),
+            "strides": (4, 16),
+            "version": 3,
+        }
+
+    t = Tensor.from_array_interface(Strided(), "cpu")
+    assert t.shape == (4, 3)
+    assert t.values() == [[0.0, 4, 8], [1, 5, 9], [2, 6, 10], [3, 7, 11]]
+    assert not hasattr(t, "__cuda_array_interface__")
@@ -0,0 +1,115 @@
+import candle
+from candle import Tensor, Var, VarMap, VarBuilder, Init
+from candle.optim import SGD, AdamW, clip_grad_norm
+from pathlib import Path
+import pytest
+
+TEST_DIR = Path(__file__).parent.parent / "_workdir"
+TEST_DIR.mkdir(exist_ok=True)
+
+
+def test_var_is_a_tensor():
+    v = Var([1.0, 2.0, 3.0])
+    assert isinstance(v, Tensor)
+    assert v.shape == (3,)
+    assert (v * 2.0).values() == [2.0, 4.0, 6.0]
+    v.set(Tensor([3.0, 2.0, 1.0]))
+    assert v.values() == [3.0, 2.0, 1.0]
+    assert v.as_tensor().values() == [3.0, 2.0, 1.0]
+
+
+def test_backward():
+    x = Var([1.0, 2.0, 3.0])
+    y = (x * x).sum_all()
+    grads = y.backward()
+    assert x in grads
+    assert grads[x].values() == [2.0, 4.0, 6.0]
+    assert grads.get(Tensor([1.0])) is None
+
+
+def test_sgd_fits_a_linear_model():
+    w = Var(candle.zeros((1, 2)))
+    b = Var(candle.zeros((1,)))
+    xs = Tensor([[1.0, 2.0], [3.0, 4.0], [5.0, 1.0]])
+    ys = xs.matmul(Tensor([[2.0], [-1.0]])).flatten_all() + 0.5
+    sgd = SGD([w, b], 0.01)
+    for _ in range(2000):
+        pred = xs.matmul(w.t()).flatten_all().broadcast_add(b)
+        loss = ((pred - ys) * (pred - ys)).mean_all()
+        sgd.backward_step(loss)
+    assert loss.values() < 1e-4
+    sgd.learning_rate = 0.1
+    assert sgd.learning_rate == 0.1
+
+
+def test_adamw_and_clipping():
+    x = Var([3.0, -4.0])
+    opt = AdamW([x], lr=0.1, weight_decay=0.0)
+    for _ in range(200):
+        loss = (x * x).sum_all()
+        grads = loss.backward()
+        norm = clip_grad_norm([x], grads, 1.0)
+        opt.step(grads)
+    assert norm.values() < 1.0
+    assert abs(x.values()[0]) < 0.1 and abs(x.values()[1]) < 0.1
+    with pytest.raises(ValueError):
+        AdamW([])
+
+
+def test_varmap_and_varbuilder():
+    varmap = VarMap()
+    vb = VarBuilder.from_varmap(varmap)
+    w = vb.pp("linear").get((2, 3), "weight", init=Init.constant(1.0))
+    b = vb.pp("linear").get(2, "bias")
+    assert w.values() == [[1.0] * 3] * 2
+    assert b.values() == [0.0, 0.0]
+    assert len(varmap) == 2
+    assert set(varmap.vars().keys()) == {"linear.weight", "linear.bias"}
+    # The existing variables are returned.
+    assert vb.get((2, 3), "linear.weight", init=Init.randn()).values() == w.values()
+
+    vars = varmap.all_vars()
+    sgd = SGD(vars, 1.0)
+    sgd.backward_step(w.sum_all() + b.sum_all())
+    assert w.values() == [[0.0] * 3] * 2
+
+    file = str(TEST_DIR / "varmap.safetensors")
+    varmap.save(file)
+    varmap.set_one("linear.bias", Tensor([5.0, 6.0]))
+    assert b.values() == [5.0, 6.0]
+    varmap.load(file)
+    assert b.values() == [-1.0, -1.0]
+
+    vb = VarBuilder.from_safetensors([file], dtype="f64")
+    assert vb.contains_tensor("linear.bias")
+    assert str(vb.dtype) == str(candle.f64)
+    assert vb.get(2, "linear.bias").values() == [-1.0, -1.0]
+    with pytest.raises(ValueError):
+        vb.get(3, "linear.bias")
+
+    vb = VarBuilder.from_tensors({"a.b": Tensor([1.0])}).pp("a")
+    assert vb.prefix == "a"
+    assert vb.get(1, "b").values() == [1.0]
+
+
+def test_devices():
+    t = candle.ones((2,), device="cpu")
+    assert t.device == "cpu"
+    with pytest.raises(TypeError):
+        t.to_device("tpu")
+    with pytest.raises(TypeError):
+        t.to_device("cuda:x")
+    if candle.utils.cuda_is_available():
+        assert t.to_device("cuda:0").device == "cuda"
+    with pytest.raises(ValueError):
+        candle.utils.set_seed(42, "cpu")
+    candle.utils.synchronize("cpu")
+
+
+def test_quantized_llama_requires_llama_metadata():
+    from candle.quantized import QuantizedLlama
+
+    file = str(TEST_DIR / "not_llama.gguf")
+    candle.utils.save_gguf(file, {"a": candle.randn((16, 32)).quantize("q4_0")}, {"b": 1})
+    with pytest.raises(ValueError):
+        QuantizedLlama.from_gguf(file)
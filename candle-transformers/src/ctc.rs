@@ -0,0 +1,192 @@
+//! Decoding for the models trained with a CTC loss, e.g. wav2vec2.
+//!
+//! The models output a distribution over the labels and a blank for each frame. The greedy
+//! decoding picks the best label for each frame whereas the prefix beam search keeps the most
+//! likely label sequences over the possible alignments, optionally fusing the scores of a language
+//! model when a label is appended.
+//!
+//! ```ignore
+//! let decoder = CtcBeamSearch::new(CtcBeamSearchConfig { beam_size: 16, ..Default::default() });
+//! let hypotheses = decoder.decode(&log_probs, None)?;
+//! println!("{:?}", hypotheses[0].tokens);
+//! ```
+use candle::{Result, Tensor};
+use std::collections::HashMap;
+
+/// A language model used to rescore the hypotheses during the beam search.
+pub trait CtcLanguageModel {
+    /// The log probability of `token` following the tokens of `prefix`.
+    fn log_prob(&mut self, prefix: &[u32], token: u32) -> Result<f32>;
+}
+
+/// Greedy decoding of log probabilities with shape `(frames, labels)`: the best label is selected
+/// for each frame, then the repeated labels are merged and the blanks removed.
+pub fn ctc_greedy_decode(log_probs: &Tensor, blank: u32) -> Result<Vec<u32>> {
+    let best = log_probs.argmax(1)?.to_vec1::<u32>()?;
+    let mut tokens = vec![];
+    let mut prev = blank;
+    for label in best {
+        if label != blank && label != prev {
+            tokens.push(label)
+        }
+        prev = label
+    }
+    Ok(tokens)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CtcBeamSearchConfig {
+    /// The number of hypotheses kept after each frame.
+    pub beam_size: usize,
+    pub blank: u32,
+    /// The weight of the language model log probabilities.
+    pub lm_weight: f32,
+    /// A bonus added each time a label is appended, to compensate for the language model
+    /// favoring short hypotheses.
+    pub insertion_bonus: f32,
+    /// The labels with a log probability below this threshold for a frame are not considered
+    /// when extending the hypotheses.
+    pub prune_log_prob: f32,
+}
+
+impl Default for CtcBeamSearchConfig {
+    fn default() -> Self {
+        Self {
+            beam_size: 8,
+            blank: 0,
+            lm_weight: 0.5,
+            insertion_bonus: 0.,
+            prune_log_prob: -20.,
+        }
+    }
+}
+
+/// A decoded label sequence.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CtcHypothesis {
+    pub tokens: Vec<u32>,
+    /// The log probability of the hypothesis summed over the alignments, including the language
+    /// model scores and insertion bonuses.
+    pub score: f32,
+}
+
+fn log_add(a: f32, b: f32) -> f32 {
+    if a == f32::NEG_INFINITY {
+        return b;
+    }
+    if b == f32::NEG_INFINITY {
+        return a;
+    }
+    let max = a.max(b);
+    max + ((a - max).exp() + (b - max).exp()).ln()
+}
+
+// The log probabilities of the alignments of a prefix ending with a blank and with a label.
+#[derive(Debug, Clone, Copy)]
+struct PrefixScore {
+    blank: f32,
+    non_blank: f32,
+}
+
+impl PrefixScore {
+    const ZERO: Self = Self {
+        blank: f32::NEG_INFINITY,
+        non_blank: f32::NEG_INFINITY,
+    };
+
+    fn total(&self) -> f32 {
+        log_add(self.blank, self.non_blank)
+    }
+}
+
+/// The CTC prefix beam search, see "First-Pass Large Vocabulary Continuous Speech Recognition
+/// using Bi-Directional Recurrent DNNs" <https://arxiv.org/abs/1408.2873>.
+#[derive(Debug, Clone)]
+pub struct CtcBeamSearch {
+    config: CtcBeamSearchConfig,
+}
+
+impl CtcBeamSearch {
+    pub fn new(config: CtcBeamSearchConfig) -> Self {
+        Self { config }
+    }
+
+    pub fn config(&self) -> &CtcBeamSearchConfig {
+        &self.config
+    }
+
+    /// Decodes log probabilities with shape `(frames, labels)`, the hypotheses are returned by
+    /// decreasing score.
+    pub fn decode(
+        &self,
+        log_probs: &Tensor,
+        mut lm: Option<&mut dyn CtcLanguageModel>,
+    ) -> Result<Vec<CtcHypothesis>> {
+        let cfg = &self.config;
+        let log_probs = log_probs.to_dtype(candle::DType::F32)?.to_vec2::<f32>()?;
+        let mut beams: Vec<(Vec<u32>, PrefixScore)> = vec![(
+            vec![],
+            PrefixScore {
+                blank: 0.,
+                non_blank: f32::NEG_INFINITY,
+            },
+        )];
+        for frame in log_probs.iter() {
+            let mut next: HashMap<Vec<u32>, PrefixScore> = HashMap::new();
+            // The best label of the frame is always considered.
+            let max_lp = frame.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+            let threshold = cfg.prune_log_prob.min(max_lp);
+            let labels = frame
+                .iter()
+                .enumerate()
+                .filter(|(_, &lp)| lp >= threshold)
+                .map(|(label, &lp)| (label as u32, lp));
+            for (label, lp) in labels {
+                for (prefix, score) in beams.iter() {
+                    if label == cfg.blank {
+                        let entry = next.entry(prefix.clone()).or_insert(PrefixScore::ZERO);
+                        entry.blank = log_add(entry.blank, score.total() + lp);
+                        continue;
+                    }
+                    let last = prefix.last().copied();
+                    if last == Some(label) {
+                        // Repeated labels without a blank in between are merged.
+                        let entry = next.entry(prefix.clone()).or_insert(PrefixScore::ZERO);
+                        entry.non_blank = log_add(entry.non_blank, score.non_blank + lp);
+                    }
+                    let mut extended = prefix.clone();
+                    extended.push(label);
+                    let mut bonus = cfg.insertion_bonus;
+                    if let Some(lm) = lm.as_mut() {
+                        bonus += cfg.lm_weight * lm.log_prob(prefix, label)?;
+                    }
+                    // A repeated label can only be appended after a blank.
+                    let from = if last == Some(label) {
+                        score.blank
+                    } else {
+                        score.total()
+                    };
+                    let entry = next.entry(extended).or_insert(PrefixScore::ZERO);
+                    entry.non_blank = log_add(entry.non_blank, from + lp + bonus);
+                }
+            }
+            let mut candidates = next
+                .into_iter()
+                .filter(|(_, s)| s.total() > f32::NEG_INFINITY)
+                .collect::<Vec<_>>();
+            candidates.sort_by(|(p1, s1), (p2, s2)| {
+                s2.total().total_cmp(&s1.total()).then_with(|| p1.cmp(p2))
+            });
+            candidates.truncate(cfg.beam_size.max(1));
+            beams = candidates;
+        }
+        let hypotheses = beams
+            .into_iter()
+            .map(|(tokens, score)| CtcHypothesis {
+                tokens,
+                score: score.total(),
+            })
+            .collect();
+        Ok(hypotheses)
+    }
+}
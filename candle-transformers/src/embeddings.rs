@@ -0,0 +1,456 @@
+//! Sentence embeddings with encoder models such as BERT, E5 or GTE.
+//!
+//! The token hidden states of the encoder are pooled into one vector per text, optionally
+//! normalized, and can then be compared with cosine similarities or stored in a small in-memory
+//! index for top-k retrieval.
+//!
+//! ```ignore
+//! let embedder = Embedder::new(bert_model, Pooling::Mean, pad_id).with_device(&device);
+//! let docs = embedder.embed(&tokenizer, &["The cat sat on the mat.", "Stock prices fell."])?;
+//! let mut index = EmbeddingIndex::new();
+//! index.add(&docs)?;
+//! let query = embedder.embed(&tokenizer, &["Where is the cat?"])?;
+//! let hits = index.search(&query, 1)?; // [[(0, 0.83)]]
+//! ```
+//!
+//! Embedding servers tend to see the same texts over and over, [`Embedder::with_cache`] keeps
+//! the embeddings of the most recently used token sequences so that only the sequences missing
+//! from the cache go through the model.
+use crate::tokenization::{TokenizedBatch, TokenizedBatchBuilder};
+use candle::{DType, Device, Result, Tensor, D};
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+
+/// How the token hidden states are pooled into a single embedding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Pooling {
+    /// The average of the non-padding token states, used by most sentence-transformers models
+    /// as well as E5 and GTE.
+    #[default]
+    Mean,
+    /// The state of the first token, e.g. `[CLS]` for BERT models.
+    Cls,
+    /// The state of the last non-padding token, used by decoder based embedding models.
+    LastToken,
+}
+
+/// Pools hidden states of shape `(batch, seq_len, hidden)` into embeddings of shape
+/// `(batch, hidden)`. `attention_mask` has shape `(batch, seq_len)`, 1 for tokens and 0 for
+/// padding, and can use either padding side.
+pub fn pool(hidden_states: &Tensor, attention_mask: &Tensor, pooling: Pooling) -> Result<Tensor> {
+    let (_b, seq_len, _hidden) = hidden_states.dims3()?;
+    let mask = attention_mask.to_dtype(hidden_states.dtype())?;
+    match pooling {
+        Pooling::Mean => {
+            let sum = hidden_states.broadcast_mul(&mask.unsqueeze(2)?)?.sum(1)?;
+            // Avoid dividing by zero on empty sequences.
+            let count = mask.sum_keepdim(1)?.clamp(1f64, f64::MAX)?;
+            sum.broadcast_div(&count)
+        }
+        Pooling::Cls => hidden_states.narrow(1, 0, 1)?.squeeze(1),
+        Pooling::LastToken => {
+            // The last position where the mask is set, 0 for empty sequences.
+            let positions = Tensor::arange(0u32, seq_len as u32, hidden_states.device())?
+                .to_dtype(DType::F32)?;
+            let last = attention_mask
+                .to_dtype(DType::F32)?
+                .broadcast_mul(&positions)?
+                .max(D::Minus1)?
+                .to_dtype(DType::U32)?;
+            let (b, _, hidden) = hidden_states.dims3()?;
+            let index = last.reshape((b, 1, 1))?.broadcast_as((b, 1, hidden))?;
+            hidden_states
+                .contiguous()?
+                .gather(&index.contiguous()?, 1)?
+                .squeeze(1)
+        }
+    }
+}
+
+/// Divides each row of `xs` by its L2 norm.
+pub fn normalize_l2(xs: &Tensor) -> Result<Tensor> {
+    let norm = xs.sqr()?.sum_keepdim(D::Minus1)?.sqrt()?;
+    xs.broadcast_div(&norm.clamp(1e-12, f64::MAX)?)
+}
+
+/// The cosine similarities between the rows of `lhs`, shape `(n, hidden)`, and the rows of
+/// `rhs`, shape `(m, hidden)`, as a `(n, m)` tensor.
+pub fn cosine_similarity(lhs: &Tensor, rhs: &Tensor) -> Result<Tensor> {
+    normalize_l2(lhs)?.matmul(&normalize_l2(rhs)?.t()?)
+}
+
+/// An encoder returning the token hidden states.
+pub trait EmbeddingModel {
+    /// Maps `input_ids` and `attention_mask`, both of shape `(batch, seq_len)`, to hidden states
+    /// of shape `(batch, seq_len, hidden)`.
+    fn hidden_states(&self, input_ids: &Tensor, attention_mask: &Tensor) -> Result<Tensor>;
+}
+
+impl EmbeddingModel for crate::models::bert::BertModel {
+    fn hidden_states(&self, input_ids: &Tensor, attention_mask: &Tensor) -> Result<Tensor> {
+        let token_type_ids = input_ids.zeros_like()?;
+        self.forward(input_ids, &token_type_ids, Some(attention_mask))
+    }
+}
+
+/// The counters of an [`EmbeddingCache`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct EmbeddingCacheStats {
+    pub hits: usize,
+    pub misses: usize,
+    /// The number of entries dropped to make room for new ones.
+    pub evictions: usize,
+    /// The number of entries currently in the cache.
+    pub len: usize,
+    pub capacity: usize,
+}
+
+impl EmbeddingCacheStats {
+    /// The fraction of the lookups that were found in the cache, 0 when there was no lookup.
+    pub fn hit_rate(&self) -> f64 {
+        let lookups = self.hits + self.misses;
+        if lookups == 0 {
+            0.
+        } else {
+            self.hits as f64 / lookups as f64
+        }
+    }
+}
+
+struct CacheEntry {
+    tokens: Vec<u32>,
+    embedding: Tensor,
+    last_used: u64,
+}
+
+/// A least recently used cache mapping token sequences to their embeddings.
+///
+/// The entries are keyed by a hash of the token sequence, the sequence itself is kept to tell
+/// collisions apart.
+pub struct EmbeddingCache {
+    entries: HashMap<u64, CacheEntry>,
+    // The keys of the entries ordered by their last use.
+    lru: BTreeMap<u64, u64>,
+    tick: u64,
+    stats: EmbeddingCacheStats,
+}
+
+impl EmbeddingCache {
+    /// Creates a cache holding at most `capacity` embeddings, nothing is cached with a capacity
+    /// of 0.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+            stats: EmbeddingCacheStats {
+                capacity,
+                ..Default::default()
+            },
+        }
+    }
+
+    fn key(tokens: &[u32]) -> u64 {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        tokens.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    fn touch(&mut self, key: u64) {
+        if let Some(entry) = self.entries.get_mut(&key) {
+            self.lru.remove(&entry.last_used);
+            self.tick += 1;
+            entry.last_used = self.tick;
+            self.lru.insert(self.tick, key);
+        }
+    }
+
+    /// Returns the embedding of `tokens` if it is in the cache, marking it as the most recently
+    /// used entry.
+    pub fn get(&mut self, tokens: &[u32]) -> Option<Tensor> {
+        let key = Self::key(tokens);
+        match self.entries.get(&key) {
+            Some(entry) if entry.tokens == tokens => {
+                let embedding = entry.embedding.clone();
+                self.touch(key);
+                self.stats.hits += 1;
+                Some(embedding)
+            }
+            _ => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Inserts the embedding of `tokens`, evicting the least recently used entry if the cache
+    /// is full. On a hash collision the previous entry is replaced.
+    pub fn insert(&mut self, tokens: &[u32], embedding: Tensor) {
+        if self.stats.capacity == 0 {
+            return;
+        }
+        let key = Self::key(tokens);
+        if let Some(entry) = self.entries.remove(&key) {
+            self.lru.remove(&entry.last_used);
+        } else if self.entries.len() >= self.stats.capacity {
+            if let Some((_, oldest)) = self.lru.pop_first() {
+                self.entries.remove(&oldest);
+                self.stats.evictions += 1;
+            }
+        }
+        self.tick += 1;
+        let entry = CacheEntry {
+            tokens: tokens.to_vec(),
+            embedding,
+            last_used: self.tick,
+        };
+        self.entries.insert(key, entry);
+        self.lru.insert(self.tick, key);
+        self.stats.len = self.entries.len();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.stats.capacity
+    }
+
+    pub fn stats(&self) -> EmbeddingCacheStats {
+        self.stats
+    }
+
+    /// Removes all the entries, the counters are kept.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.lru.clear();
+        self.stats.len = 0;
+    }
+}
+
+/// Embeds batches of texts with an encoder model, see the module documentation.
+pub struct Embedder<M> {
+    model: M,
+    pooling: Pooling,
+    normalize: bool,
+    batch_builder: TokenizedBatchBuilder,
+    cache: Option<Mutex<EmbeddingCache>>,
+}
+
+impl<M: EmbeddingModel> Embedder<M> {
+    /// Creates an embedder normalizing the embeddings, the texts are right padded with
+    /// `pad_id`.
+    pub fn new(model: M, pooling: Pooling, pad_id: u32) -> Self {
+        Self {
+            model,
+            pooling,
+            normalize: true,
+            batch_builder: TokenizedBatch::builder(pad_id),
+            cache: None,
+        }
+    }
+
+    pub fn with_normalize(mut self, normalize: bool) -> Self {
+        self.normalize = normalize;
+        self.clear_cache();
+        self
+    }
+
+    /// Truncates the texts to `max_len` tokens, usually the maximum number of positions of the
+    /// model.
+    pub fn with_max_len(mut self, max_len: usize) -> Self {
+        self.batch_builder = self.batch_builder.max_len(max_len);
+        self.clear_cache();
+        self
+    }
+
+    /// Caches the embeddings of the last `capacity` distinct token sequences, the cached
+    /// embeddings are returned without running the model.
+    pub fn with_cache(mut self, capacity: usize) -> Self {
+        self.cache = Some(Mutex::new(EmbeddingCache::new(capacity)));
+        self
+    }
+
+    /// The counters of the cache, `None` when caching is not enabled.
+    pub fn cache_stats(&self) -> Option<EmbeddingCacheStats> {
+        self.cache.as_ref().map(|c| c.lock().unwrap().stats())
+    }
+
+    pub fn clear_cache(&self) {
+        if let Some(cache) = self.cache.as_ref() {
+            cache.lock().unwrap().clear()
+        }
+    }
+
+    pub fn with_device(mut self, device: &Device) -> Self {
+        self.batch_builder = self.batch_builder.device(device);
+        self
+    }
+
+    pub fn model(&self) -> &M {
+        &self.model
+    }
+
+    /// Embeds an already tokenized batch, returning a `(batch, hidden)` tensor.
+    pub fn embed_batch(&self, batch: &TokenizedBatch) -> Result<Tensor> {
+        match self.cache.as_ref() {
+            Some(cache) if batch.batch_size() > 0 => self.embed_cached(batch, cache),
+            _ => self.embed_uncached(batch),
+        }
+    }
+
+    fn embed_cached(
+        &self,
+        batch: &TokenizedBatch,
+        cache: &Mutex<EmbeddingCache>,
+    ) -> Result<Tensor> {
+        // Recover the token sequences without their padding.
+        let input_ids = batch.input_ids.to_vec2::<u32>()?;
+        let mask = batch.attention_mask.to_vec2::<u32>()?;
+        let sequences = input_ids
+            .iter()
+            .zip(mask.iter())
+            .map(|(ids, mask)| {
+                ids.iter()
+                    .zip(mask.iter())
+                    .filter(|(_, &m)| m != 0)
+                    .map(|(&id, _)| id)
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let mut rows = {
+            let mut cache = cache.lock().unwrap();
+            sequences.iter().map(|s| cache.get(s)).collect::<Vec<_>>()
+        };
+        // The sequences to embed, a sequence repeated in the batch only goes through the model
+        // once.
+        let mut missing: Vec<&[u32]> = vec![];
+        let mut missing_ids = HashMap::new();
+        for (s, row) in sequences.iter().zip(rows.iter()) {
+            if row.is_none() && !missing_ids.contains_key(s.as_slice()) {
+                missing_ids.insert(s.as_slice(), missing.len());
+                missing.push(s)
+            }
+        }
+        if !missing.is_empty() {
+            let missing_batch = self.batch_builder.build(&missing)?;
+            let embeddings = self.embed_uncached(&missing_batch)?;
+            let embeddings = (0..missing.len())
+                .map(|i| embeddings.get(i))
+                .collect::<Result<Vec<_>>>()?;
+            let mut cache = cache.lock().unwrap();
+            for (s, embedding) in missing.iter().zip(embeddings.iter()) {
+                cache.insert(s, embedding.clone())
+            }
+            for (s, row) in sequences.iter().zip(rows.iter_mut()) {
+                if row.is_none() {
+                    *row = Some(embeddings[missing_ids[s.as_slice()]].clone())
+                }
+            }
+        }
+        let rows = rows.into_iter().flatten().collect::<Vec<_>>();
+        Tensor::stack(&rows, 0)
+    }
+
+    fn embed_uncached(&self, batch: &TokenizedBatch) -> Result<Tensor> {
+        let hidden_states = self
+            .model
+            .hidden_states(&batch.input_ids, &batch.attention_mask)?;
+        let embeddings = pool(&hidden_states, &batch.attention_mask, self.pooling)?;
+        if self.normalize {
+            normalize_l2(&embeddings)
+        } else {
+            Ok(embeddings)
+        }
+    }
+
+    /// Embeds some token sequences, padding them to a common length.
+    pub fn embed_tokens<S: AsRef<[u32]>>(&self, sequences: &[S]) -> Result<Tensor> {
+        self.embed_batch(&self.batch_builder.build(sequences)?)
+    }
+
+    /// Tokenizes and embeds some texts, adding the special tokens.
+    #[cfg(feature = "tokenizers")]
+    pub fn embed<S: AsRef<str>>(
+        &self,
+        tokenizer: &tokenizers::Tokenizer,
+        texts: &[S],
+    ) -> Result<Tensor> {
+        self.embed_batch(&self.batch_builder.encode(tokenizer, texts, true)?)
+    }
+}
+
+/// A brute force in-memory index returning the entries with the highest cosine similarity.
+///
+/// The entries are identified by their insertion order. This is meant for small collections,
+/// up to a few hundred thousand entries, a search being a single matmul followed by a partial
+/// selection of the top scores on the host.
+#[derive(Debug, Clone, Default)]
+pub struct EmbeddingIndex {
+    embeddings: Option<Tensor>,
+}
+
+impl EmbeddingIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        match &self.embeddings {
+            None => 0,
+            Some(e) => e.dims()[0],
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Adds `(n, hidden)` embeddings and returns the ids of the new entries. The embeddings are
+    /// normalized when added.
+    pub fn add(&mut self, embeddings: &Tensor) -> Result<std::ops::Range<usize>> {
+        let start = self.len();
+        let embeddings = normalize_l2(&embeddings.to_dtype(DType::F32)?)?;
+        let embeddings = match &self.embeddings {
+            None => embeddings,
+            Some(prev) => Tensor::cat(&[prev, &embeddings], 0)?,
+        };
+        self.embeddings = Some(embeddings);
+        Ok(start..self.len())
+    }
+
+    /// Returns, for each `(q, hidden)` query, the ids and similarities of the `k` closest
+    /// entries, most similar first.
+    pub fn search(&self, queries: &Tensor, k: usize) -> Result<Vec<Vec<(usize, f32)>>> {
+        let embeddings = match &self.embeddings {
+            None => return Ok(vec![vec![]; queries.dim(0)?]),
+            Some(e) => e,
+        };
+        let queries = normalize_l2(&queries.to_dtype(DType::F32)?)?;
+        let scores = queries.matmul(&embeddings.t()?)?;
+        // The top-k selection is done on the host, sorting all the scores on the device would
+        // not scale to large indexes.
+        let k = usize::min(k, self.len());
+        let hits = scores
+            .to_vec2::<f32>()?
+            .into_iter()
+            .map(|scores| {
+                let mut hits = scores.into_iter().enumerate().collect::<Vec<_>>();
+                let by_score = |a: &(usize, f32), b: &(usize, f32)| b.1.total_cmp(&a.1);
+                if k > 0 && k < hits.len() {
+                    hits.select_nth_unstable_by(k - 1, by_score);
+                }
+                hits.truncate(k);
+                hits.sort_by(by_score);
+                hits
+            })
+            .collect();
+        Ok(hits)
+    }
+}
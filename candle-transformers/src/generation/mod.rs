@@ -1,6 +1,8 @@
 use candle::{DType, Error, Result, Tensor};
 use rand::{distributions::Distribution, SeedableRng};
 
+pub mod watermark;
+
 #[derive(Clone, PartialEq, Debug)]
 pub enum Sampling {
     ArgMax,
@@ -148,3 +150,267 @@ impl LogitsProcessor {
         Ok(next_token)
     }
 }
+
+/// A transformation of the next token logits applied before sampling, e.g. to add biases, to
+/// mask out some tokens or for classifier guidance. Closures with the same signature as
+/// [`LogitsProcessorTrait::process`] implement this trait.
+pub trait LogitsProcessorTrait {
+    /// `logits` is a f32 tensor of shape `(vocab,)` and `tokens` contains the prompt followed by
+    /// the tokens generated so far.
+    fn process(&mut self, logits: &Tensor, tokens: &[u32]) -> Result<Tensor>;
+}
+
+impl<F: FnMut(&Tensor, &[u32]) -> Result<Tensor>> LogitsProcessorTrait for F {
+    fn process(&mut self, logits: &Tensor, tokens: &[u32]) -> Result<Tensor> {
+        self(logits, tokens)
+    }
+}
+
+/// Applies processors one after the other.
+///
+/// ```ignore
+/// let chain = LogitsProcessorChain::new()
+///     .with(RepeatPenalty::new(1.1, 64))
+///     .with(|logits: &Tensor, _: &[u32]| logits.clamp(-10f32, 10f32));
+/// ```
+#[derive(Default)]
+pub struct LogitsProcessorChain {
+    processors: Vec<Box<dyn LogitsProcessorTrait + Send>>,
+}
+
+impl LogitsProcessorChain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with<P: LogitsProcessorTrait + Send + 'static>(mut self, processor: P) -> Self {
+        self.push(processor);
+        self
+    }
+
+    pub fn push<P: LogitsProcessorTrait + Send + 'static>(&mut self, processor: P) {
+        self.processors.push(Box::new(processor))
+    }
+
+    pub fn len(&self) -> usize {
+        self.processors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.processors.is_empty()
+    }
+}
+
+impl LogitsProcessorTrait for LogitsProcessorChain {
+    fn process(&mut self, logits: &Tensor, tokens: &[u32]) -> Result<Tensor> {
+        let mut logits = logits.clone();
+        for processor in self.processors.iter_mut() {
+            logits = processor.process(&logits, tokens)?
+        }
+        Ok(logits)
+    }
+}
+
+/// Penalizes the tokens that appear in the last `last_n` tokens, see
+/// `utils::apply_repeat_penalty`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RepeatPenalty {
+    pub penalty: f32,
+    pub last_n: usize,
+}
+
+impl RepeatPenalty {
+    pub fn new(penalty: f32, last_n: usize) -> Self {
+        Self { penalty, last_n }
+    }
+}
+
+impl LogitsProcessorTrait for RepeatPenalty {
+    fn process(&mut self, logits: &Tensor, tokens: &[u32]) -> Result<Tensor> {
+        if self.penalty == 1. {
+            return Ok(logits.clone());
+        }
+        let start_at = tokens.len().saturating_sub(self.last_n);
+        crate::utils::apply_repeat_penalty(logits, self.penalty, &tokens[start_at..])
+    }
+}
+
+/// The sampling settings of a sequence in a batch, see [`BatchedLogitsProcessor`].
+#[derive(Clone, PartialEq, Debug)]
+pub struct SamplingParams {
+    /// The tokens are picked greedily when the temperature is zero.
+    pub temperature: f64,
+    pub top_k: Option<usize>,
+    pub top_p: Option<f64>,
+    /// Divides the positive logits and multiplies the negative logits of the tokens that appear
+    /// in the context, see `utils::apply_repeat_penalty`.
+    pub repeat_penalty: f32,
+    /// Subtracted from the logits once per occurrence of the token in the context.
+    pub frequency_penalty: f32,
+    /// Subtracted from the logits of the tokens that appear in the context.
+    pub presence_penalty: f32,
+    pub seed: u64,
+}
+
+impl Default for SamplingParams {
+    fn default() -> Self {
+        Self {
+            temperature: 0.,
+            top_k: None,
+            top_p: None,
+            repeat_penalty: 1.,
+            frequency_penalty: 0.,
+            presence_penalty: 0.,
+            seed: 299792458,
+        }
+    }
+}
+
+/// Samples the next token of each sequence of a batch with its own settings.
+///
+/// The penalties and temperatures are applied to all the rows of the logits at once on their
+/// device, the tokens are then drawn on the host. The vocabulary is only sorted for the rows that
+/// use top-k or top-p, with a partial selection for top-k. Each sequence has its own random
+/// generator so the sampled tokens do not depend on the other sequences of the batch.
+pub struct BatchedLogitsProcessor {
+    params: Vec<SamplingParams>,
+    rngs: Vec<rand::rngs::StdRng>,
+}
+
+impl BatchedLogitsProcessor {
+    pub fn new(params: Vec<SamplingParams>) -> Self {
+        let rngs = params
+            .iter()
+            .map(|p| rand::rngs::StdRng::seed_from_u64(p.seed))
+            .collect();
+        Self { params, rngs }
+    }
+
+    pub fn params(&self) -> &[SamplingParams] {
+        &self.params
+    }
+
+    /// Adds a sequence at the end of the batch.
+    pub fn push(&mut self, params: SamplingParams) {
+        self.rngs
+            .push(rand::rngs::StdRng::seed_from_u64(params.seed));
+        self.params.push(params)
+    }
+
+    /// Removes the sequence at `index`, the following sequences are shifted.
+    pub fn remove(&mut self, index: usize) -> SamplingParams {
+        self.rngs.remove(index);
+        self.params.remove(index)
+    }
+
+    // A (batch, 1) tensor with a value per row.
+    fn column(
+        &self,
+        f: impl Fn(&SamplingParams) -> f32,
+        device: &candle::Device,
+    ) -> Result<Tensor> {
+        let values = self.params.iter().map(f).collect::<Vec<_>>();
+        Tensor::from_vec(values, (self.params.len(), 1), device)
+    }
+
+    /// Samples a token for each row of `logits`, a tensor of shape `(batch, vocab)`. `contexts`
+    /// contains the previous tokens of each sequence, used for the penalties.
+    pub fn sample(&mut self, logits: &Tensor, contexts: &[&[u32]]) -> Result<Vec<u32>> {
+        let (b_sz, vocab_size) = logits.dims2()?;
+        if b_sz != self.params.len() || contexts.len() != b_sz {
+            candle::bail!(
+                "batch size mismatch, logits {b_sz}, params {}, contexts {}",
+                self.params.len(),
+                contexts.len()
+            )
+        }
+        let device = logits.device();
+        let mut logits = logits.to_dtype(DType::F32)?;
+        let has_penalty = self.params.iter().any(|p| {
+            p.repeat_penalty != 1. || p.frequency_penalty != 0. || p.presence_penalty != 0.
+        });
+        if has_penalty {
+            // The number of occurrences of each token in the contexts, padded with zero weights.
+            let max_len = contexts.iter().map(|c| c.len()).max().unwrap_or(0).max(1);
+            let mut indexes = vec![0u32; b_sz * max_len];
+            let mut weights = vec![0f32; b_sz * max_len];
+            for (row, context) in contexts.iter().enumerate() {
+                for (i, &token) in context.iter().enumerate() {
+                    if (token as usize) < vocab_size {
+                        indexes[row * max_len + i] = token;
+                        weights[row * max_len + i] = 1.;
+                    }
+                }
+            }
+            let indexes = Tensor::from_vec(indexes, (b_sz, max_len), device)?;
+            let weights = Tensor::from_vec(weights, (b_sz, max_len), device)?;
+            let counts = Tensor::zeros((b_sz, vocab_size), DType::F32, device)?
+                .scatter_add(&indexes, &weights, 1)?;
+            let present = counts.gt(0f64)?;
+            let present_f = present.to_dtype(DType::F32)?;
+            let penalty = self.column(|p| p.repeat_penalty, device)?;
+            let factor = logits.ge(0f64)?.where_cond(
+                &penalty.recip()?.broadcast_as(logits.shape())?,
+                &penalty.broadcast_as(logits.shape())?,
+            )?;
+            let ones = Tensor::ones_like(&logits)?;
+            logits = (logits * present.where_cond(&factor, &ones)?)?;
+            let frequency = self.column(|p| p.frequency_penalty, device)?;
+            let presence = self.column(|p| p.presence_penalty, device)?;
+            logits = (logits - counts.broadcast_mul(&frequency)?)?;
+            logits = (logits - present_f.broadcast_mul(&presence)?)?;
+        }
+        let greedy = logits.argmax(1)?.to_vec1::<u32>()?;
+        if self.params.iter().all(|p| p.temperature <= 0.) {
+            return Ok(greedy);
+        }
+        let temperature = self.column(|p| p.temperature.max(1e-7) as f32, device)?;
+        let prs = candle_nn::ops::softmax_last_dim(&logits.broadcast_div(&temperature)?)?;
+        let prs = prs.to_vec2::<f32>()?;
+        let mut tokens = Vec::with_capacity(b_sz);
+        for (row, params) in self.params.iter().enumerate() {
+            let token = if params.temperature <= 0. {
+                greedy[row]
+            } else {
+                sample_row(&prs[row], params, &mut self.rngs[row])?
+            };
+            tokens.push(token)
+        }
+        Ok(tokens)
+    }
+}
+
+// Draws a token from the probabilities of a row. The tokens are only selected or sorted when the
+// top-k or top-p filters are used, and then only the top-k candidates are sorted for top-p.
+fn sample_row(prs: &[f32], params: &SamplingParams, rng: &mut rand::rngs::StdRng) -> Result<u32> {
+    // As with `LogitsProcessor`, a top-p outside of (0, 1) does not filter any token.
+    let top_p = params.top_p.filter(|&p| p > 0. && p < 1.);
+    let top_k = params.top_k.map(|k| k.max(1)).filter(|&k| k < prs.len());
+    if top_k.is_none() && top_p.is_none() {
+        let distr = rand::distributions::WeightedIndex::new(prs).map_err(Error::wrap)?;
+        return Ok(distr.sample(rng) as u32);
+    }
+    let mut candidates = (0..prs.len() as u32).collect::<Vec<_>>();
+    let by_prob = |i: &u32, j: &u32| prs[*j as usize].total_cmp(&prs[*i as usize]);
+    if let Some(top_k) = top_k {
+        candidates.select_nth_unstable_by(top_k - 1, by_prob);
+        candidates.truncate(top_k);
+    }
+    if let Some(top_p) = top_p {
+        // Keep the most likely tokens until their mass reaches top-p.
+        candidates.sort_by(by_prob);
+        let mut mass = 0f64;
+        let len = candidates
+            .iter()
+            .take_while(|&&i| {
+                let keep = mass < top_p;
+                mass += prs[i as usize] as f64;
+                keep
+            })
+            .count();
+        candidates.truncate(len);
+    }
+    let weights = candidates.iter().map(|&i| prs[i as usize]);
+    let distr = rand::distributions::WeightedIndex::new(weights).map_err(Error::wrap)?;
+    Ok(candidates[distr.sample(rng)])
+}
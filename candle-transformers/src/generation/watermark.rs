@@ -0,0 +1,121 @@
+//! Watermarking of the generated text, following
+//! [A Watermark for Large Language Models](https://arxiv.org/abs/2301.10226).
+//!
+//! At each step the vocabulary is split in a green list and a red list using a hash of the key
+//! and of the previous token, the logits of the green tokens are increased by `delta`. Without
+//! knowledge of the key, a text has a fraction `gamma` of green tokens on average. The detector
+//! counts the green tokens of a text and returns a z-score, a large z-score meaning that the text
+//! was likely generated with the watermark.
+//!
+//! ```ignore
+//! let watermark = Watermark::new(key);
+//! let mut pipeline = TextGeneration::new(model, logits_processor, &device)
+//!     .with_logits_processor(watermark);
+//! let output = pipeline.generate(&prompt, 256)?;
+//! let detection = watermark.detect(&output.tokens);
+//! assert!(detection.is_watermarked(4.0));
+//! ```
+use super::LogitsProcessorTrait;
+use candle::{Result, Tensor};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Watermark {
+    pub key: u64,
+    /// The fraction of the vocabulary in the green list.
+    pub gamma: f64,
+    /// The bias added to the logits of the green tokens.
+    pub delta: f64,
+}
+
+// The splitmix64 finalizer.
+fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+impl Watermark {
+    /// A watermark with the default settings of the paper, `gamma = 0.25` and `delta = 2`.
+    pub fn new(key: u64) -> Self {
+        Self {
+            key,
+            gamma: 0.25,
+            delta: 2.,
+        }
+    }
+
+    /// Whether `token` is in the green list that follows `previous`.
+    pub fn is_green(&self, previous: u32, token: u32) -> bool {
+        let seed = mix(self.key ^ mix(previous as u64));
+        let h = mix(seed.wrapping_add(token as u64));
+        let u = (h >> 11) as f64 / (1u64 << 53) as f64;
+        u < self.gamma
+    }
+
+    /// Counts the green tokens of `tokens`, the first token is only used as context.
+    pub fn detect(&self, tokens: &[u32]) -> WatermarkDetection {
+        let num_tokens_scored = tokens.len().saturating_sub(1);
+        let num_green = tokens
+            .windows(2)
+            .filter(|w| self.is_green(w[0], w[1]))
+            .count();
+        let n = num_tokens_scored as f64;
+        let z_score = if num_tokens_scored == 0 {
+            0.
+        } else {
+            let expected = self.gamma * n;
+            (num_green as f64 - expected) / (n * self.gamma * (1. - self.gamma)).sqrt()
+        };
+        WatermarkDetection {
+            num_tokens_scored,
+            num_green,
+            z_score,
+        }
+    }
+}
+
+impl LogitsProcessorTrait for Watermark {
+    fn process(&mut self, logits: &Tensor, tokens: &[u32]) -> Result<Tensor> {
+        let previous = match tokens.last() {
+            None => return Ok(logits.clone()),
+            Some(&previous) => previous,
+        };
+        let vocab_size = logits.dim(0)?;
+        let bias = (0..vocab_size as u32)
+            .map(|t| {
+                if self.is_green(previous, t) {
+                    self.delta as f32
+                } else {
+                    0.
+                }
+            })
+            .collect::<Vec<_>>();
+        let bias = Tensor::from_vec(bias, vocab_size, logits.device())?.to_dtype(logits.dtype())?;
+        logits + bias
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WatermarkDetection {
+    pub num_tokens_scored: usize,
+    pub num_green: usize,
+    /// The number of standard deviations between the number of green tokens and its expected
+    /// value for a text without watermark.
+    pub z_score: f64,
+}
+
+impl WatermarkDetection {
+    pub fn green_fraction(&self) -> f64 {
+        if self.num_tokens_scored == 0 {
+            0.
+        } else {
+            self.num_green as f64 / self.num_tokens_scored as f64
+        }
+    }
+
+    /// Whether the z-score exceeds `z_threshold`, the paper uses 4 which gives a false positive
+    /// rate of about 3e-5.
+    pub fn is_watermarked(&self, z_threshold: f64) -> bool {
+        self.z_score > z_threshold
+    }
+}
@@ -1,7 +1,12 @@
+pub mod ctc;
+pub mod embeddings;
 pub mod generation;
+pub mod logit_lens;
+pub mod merge;
 pub mod models;
 pub mod object_detection;
 pub mod pipelines;
 pub mod quantized_nn;
 pub mod quantized_var_builder;
+pub mod tokenization;
 pub mod utils;
@@ -0,0 +1,140 @@
+//! Logit lens and activation recording for interpretability.
+//!
+//! The logit lens projects the intermediate hidden states of a language model through its final
+//! norm and language model head, showing which token each layer would predict. The
+//! `LogitLensRecorder` applies this to the outputs of `forward_with_options` at each generation
+//! step, and also records the entropy of the attention probabilities.
+//!
+//! ```ignore
+//! let options = ForwardOptions::new().with_hidden_states(true).with_attentions(true);
+//! let mut recorder = LogitLensRecorder::new(5);
+//! for index_pos in .. {
+//!     let out = model.forward_with_options(&input, index_pos, &mut cache, &options)?;
+//!     recorder.record(&out, |xs| model.project_hidden(xs))?;
+//!     let next_token = logits_processor.sample(&out.output.squeeze(0)?)?;
+//!     ...
+//! }
+//! ```
+use crate::models::outputs::ModelOutput;
+use candle::{DType, IndexOp, Result, Tensor, D};
+
+/// Returns the entropy of the attention probabilities over the key dimension, the result has the
+/// shape of `attn` without its last dimension.
+pub fn attention_entropy(attn: &Tensor) -> Result<Tensor> {
+    let attn = attn.to_dtype(DType::F32)?;
+    // Zero probabilities do not contribute to the entropy.
+    let log = (&attn + 1e-30)?.log()?;
+    (attn * log)?.sum(D::Minus1)?.neg()
+}
+
+/// The predictions of a single layer at the last position.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LayerRecord {
+    /// The most likely tokens, in decreasing order of probability.
+    pub top_tokens: Vec<u32>,
+    pub top_probs: Vec<f32>,
+    /// The attention entropy of each head at the last position. This is not available for the
+    /// embedding layer or when the attentions have not been returned.
+    pub attention_entropy: Option<Vec<f32>>,
+}
+
+/// The per-layer records for one forward pass. The first record corresponds to the embeddings,
+/// the following ones to the output of each layer.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StepRecord {
+    pub layers: Vec<LayerRecord>,
+}
+
+/// Records the logit lens predictions for each forward pass of a generation loop.
+#[derive(Debug, Clone)]
+pub struct LogitLensRecorder {
+    top_k: usize,
+    steps: Vec<StepRecord>,
+}
+
+impl LogitLensRecorder {
+    /// Creates a recorder keeping the `top_k` most likely tokens for each layer.
+    pub fn new(top_k: usize) -> Self {
+        Self {
+            top_k: top_k.max(1),
+            steps: vec![],
+        }
+    }
+
+    /// Projects the hidden states at the last position of `output` using `project`, which
+    /// should apply the final norm and language model head of the model, and records the
+    /// results. The hidden states must have been returned and the batch size must be one.
+    pub fn record<F>(&mut self, output: &ModelOutput, project: F) -> Result<&StepRecord>
+    where
+        F: Fn(&Tensor) -> Result<Tensor>,
+    {
+        let hidden_states = match &output.hidden_states {
+            None => candle::bail!("logit lens requires the hidden states to be returned"),
+            Some(hs) => hs,
+        };
+        let mut layers = Vec::with_capacity(hidden_states.len());
+        for (layer_idx, hs) in hidden_states.iter().enumerate() {
+            let (b_sz, seq_len, _) = hs.dims3()?;
+            if b_sz != 1 {
+                candle::bail!("logit lens only supports a batch size of 1, got {b_sz}")
+            }
+            let logits = project(&hs.i((.., seq_len - 1..))?)?;
+            let logits = logits.flatten_all()?.to_dtype(DType::F32)?;
+            let probs = candle_nn::ops::softmax_last_dim(&logits)?;
+            let top_k = self.top_k.min(probs.elem_count());
+            let (top_probs, top_tokens) = probs.sort_last_dim(false)?;
+            let top_probs = top_probs.narrow(0, 0, top_k)?.to_vec1::<f32>()?;
+            let top_tokens = top_tokens.narrow(0, 0, top_k)?.to_vec1::<u32>()?;
+            let attention_entropy = match (&output.attentions, layer_idx) {
+                (Some(attns), layer_idx) if layer_idx > 0 => {
+                    let attn = &attns[layer_idx - 1];
+                    let q_len = attn.dim(2)?;
+                    let attn = attn.i((0, .., q_len - 1))?;
+                    Some(attention_entropy(&attn)?.to_vec1::<f32>()?)
+                }
+                _ => None,
+            };
+            layers.push(LayerRecord {
+                top_tokens,
+                top_probs,
+                attention_entropy,
+            })
+        }
+        self.steps.push(StepRecord { layers });
+        Ok(&self.steps[self.steps.len() - 1])
+    }
+
+    pub fn steps(&self) -> &[StepRecord] {
+        &self.steps
+    }
+
+    /// The most likely token of `layer` at each recorded step.
+    pub fn layer_predictions(&self, layer: usize) -> Vec<Option<u32>> {
+        self.steps
+            .iter()
+            .map(|s| s.layers.get(layer).map(|l| l.top_tokens[0]))
+            .collect()
+    }
+
+    /// For each recorded step, the first layer from which the most likely token matches the one
+    /// of the last layer for all the following layers.
+    pub fn convergence_layers(&self) -> Vec<usize> {
+        self.steps
+            .iter()
+            .map(|s| {
+                let last = s.layers.last().map(|l| l.top_tokens[0]);
+                let n_agree = s
+                    .layers
+                    .iter()
+                    .rev()
+                    .take_while(|l| Some(l.top_tokens[0]) == last)
+                    .count();
+                s.layers.len() - n_agree
+            })
+            .collect()
+    }
+
+    pub fn clear(&mut self) {
+        self.steps.clear()
+    }
+}
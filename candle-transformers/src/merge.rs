@@ -0,0 +1,218 @@
+//! Model merging utilities.
+//!
+//! These functions combine the weights of several models sharing the same architecture, e.g.
+//! multiple fine-tunes of the same base model. Each merge method is available for individual
+//! tensors and for whole `VarMap`s, in which case all the var maps must contain the same variable
+//! names with the same shapes.
+//!
+//! - `linear`: weighted average of the weights.
+//! - `slerp`: spherical linear interpolation between two models.
+//! - `task_arithmetic`: adds scaled task vectors (fine-tuned minus base weights) to the base
+//!   model, see "Editing Models with Task Arithmetic" <https://arxiv.org/abs/2212.04089>.
+//! - `ties`: TIES-merging, which trims the task vectors, elects a sign per parameter and only
+//!   averages the agreeing values, see <https://arxiv.org/abs/2306.01708>.
+use candle::{DType, Result, Tensor, Var};
+use candle_nn::VarMap;
+
+// Above this cosine similarity between the two tensors, slerp falls back to a linear
+// interpolation.
+const SLERP_DOT_THRESHOLD: f64 = 0.9995;
+
+fn check_same_shape(op: &'static str, xs: &[&Tensor]) -> Result<()> {
+    if let Some((first, rest)) = xs.split_first() {
+        for x in rest.iter() {
+            if x.shape() != first.shape() {
+                return Err(candle::Error::ShapeMismatchBinaryOp {
+                    lhs: first.shape().clone(),
+                    rhs: x.shape().clone(),
+                    op,
+                }
+                .bt());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Computes the weighted sum of some tensors, the weights are not normalized.
+pub fn linear(xs: &[&Tensor], weights: &[f64]) -> Result<Tensor> {
+    if xs.is_empty() {
+        return Err(candle::Error::OpRequiresAtLeastOneTensor { op: "merge-linear" }.bt());
+    }
+    if xs.len() != weights.len() {
+        candle::bail!(
+            "merge-linear: got {} tensors but {} weights",
+            xs.len(),
+            weights.len()
+        )
+    }
+    check_same_shape("merge-linear", xs)?;
+    let dtype = xs[0].dtype();
+    let mut acc = (xs[0].to_dtype(DType::F32)? * weights[0])?;
+    for (x, &w) in xs.iter().zip(weights.iter()).skip(1) {
+        acc = (acc + (x.to_dtype(DType::F32)? * w)?)?
+    }
+    acc.to_dtype(dtype)
+}
+
+/// Spherical linear interpolation between `a` (for `t = 0`) and `b` (for `t = 1`).
+///
+/// The tensors are considered as flat vectors, the interpolation follows the great circle between
+/// their directions. When the two vectors are almost colinear, a linear interpolation is used.
+pub fn slerp(a: &Tensor, b: &Tensor, t: f64) -> Result<Tensor> {
+    check_same_shape("merge-slerp", &[a, b])?;
+    let dtype = a.dtype();
+    let a32 = a.to_dtype(DType::F32)?;
+    let b32 = b.to_dtype(DType::F32)?;
+    let norm =
+        |x: &Tensor| -> Result<f64> { Ok(x.sqr()?.sum_all()?.sqrt()?.to_scalar::<f32>()? as f64) };
+    let (norm_a, norm_b) = (norm(&a32)?, norm(&b32)?);
+    let dot = if norm_a == 0. || norm_b == 0. {
+        1.
+    } else {
+        (&a32 * &b32)?.sum_all()?.to_scalar::<f32>()? as f64 / (norm_a * norm_b)
+    };
+    if dot.abs() > SLERP_DOT_THRESHOLD {
+        return linear(&[a, b], &[1. - t, t]);
+    }
+    let theta = dot.acos();
+    let sin_theta = theta.sin();
+    let wa = ((1. - t) * theta).sin() / sin_theta;
+    let wb = (t * theta).sin() / sin_theta;
+    ((a32 * wa)? + (b32 * wb)?)?.to_dtype(dtype)
+}
+
+/// Returns the task vector of a fine-tuned model, i.e. the difference with the base weights.
+pub fn task_vector(base: &Tensor, finetuned: &Tensor) -> Result<Tensor> {
+    check_same_shape("task-vector", &[base, finetuned])?;
+    finetuned.to_dtype(DType::F32)? - base.to_dtype(DType::F32)?
+}
+
+/// Adds the scaled task vectors of the fine-tuned tensors to the base tensor.
+pub fn task_arithmetic(base: &Tensor, finetuned: &[&Tensor], scales: &[f64]) -> Result<Tensor> {
+    if finetuned.len() != scales.len() {
+        candle::bail!(
+            "task-arithmetic: got {} tensors but {} scales",
+            finetuned.len(),
+            scales.len()
+        )
+    }
+    let mut acc = base.to_dtype(DType::F32)?;
+    for (ft, &scale) in finetuned.iter().zip(scales.iter()) {
+        acc = (acc + (task_vector(base, ft)? * scale)?)?
+    }
+    acc.to_dtype(base.dtype())
+}
+
+// Only keeps the `density` fraction of the values with the largest magnitude.
+fn trim(tv: &Tensor, density: f64) -> Result<Tensor> {
+    let n = tv.elem_count();
+    let k = ((n as f64 * density).ceil() as usize).clamp(1, n);
+    if k == n {
+        return Ok(tv.clone());
+    }
+    let abs = tv.abs()?;
+    let (sorted, _) = abs.flatten_all()?.sort_last_dim(false)?;
+    let threshold = sorted.get(k - 1)?;
+    let mask = abs.broadcast_ge(&threshold)?.to_dtype(tv.dtype())?;
+    tv * mask
+}
+
+/// Merges fine-tuned tensors into the base tensor with TIES-merging.
+///
+/// * [density]: the fraction of each task vector to keep, the values with the largest magnitude
+///   being kept.
+/// * [lambda]: the scale applied to the merged task vector.
+pub fn ties(base: &Tensor, finetuned: &[&Tensor], density: f64, lambda: f64) -> Result<Tensor> {
+    if finetuned.is_empty() {
+        return Err(candle::Error::OpRequiresAtLeastOneTensor { op: "merge-ties" }.bt());
+    }
+    if !(0. ..=1.).contains(&density) {
+        candle::bail!("merge-ties: density should be between 0 and 1, got {density}")
+    }
+    let tvs = finetuned
+        .iter()
+        .map(|ft| trim(&task_vector(base, ft)?, density))
+        .collect::<Result<Vec<_>>>()?;
+    let tvs = Tensor::stack(&tvs, 0)?;
+    // The elected sign is the sign of the sum of the trimmed task vectors.
+    let sign = tvs.sum_keepdim(0)?.sign()?;
+    let agree = tvs.broadcast_mul(&sign)?.gt(0f64)?.to_dtype(DType::F32)?;
+    let count = agree.sum(0)?.maximum(1f64)?;
+    let merged = (tvs * agree)?.sum(0)?.div(&count)?;
+    (base.to_dtype(DType::F32)? + (merged * lambda)?)?.to_dtype(base.dtype())
+}
+
+// Applies `f` to the tensors with the same name in all the var maps. Non-float variables are
+// copied from the first var map.
+fn merge_varmaps<F>(maps: &[&VarMap], f: F) -> Result<VarMap>
+where
+    F: Fn(&[&Tensor]) -> Result<Tensor>,
+{
+    let (first, rest) = match maps.split_first() {
+        None => return Err(candle::Error::OpRequiresAtLeastOneTensor { op: "merge" }.bt()),
+        Some(v) => v,
+    };
+    // The maps are locked one at a time as the same var map may appear multiple times.
+    let first = first.data().lock().unwrap().clone();
+    let rest = rest
+        .iter()
+        .map(|m| m.data().lock().unwrap().clone())
+        .collect::<Vec<_>>();
+    let merged = VarMap::new();
+    {
+        let mut merged_data = merged.data().lock().unwrap();
+        for (name, var) in first.iter() {
+            let mut xs = vec![var.as_tensor()];
+            for other in rest.iter() {
+                match other.get(name) {
+                    None => candle::bail!("merge: cannot find variable {name} in all the models"),
+                    Some(v) => xs.push(v.as_tensor()),
+                }
+            }
+            let tensor = if var.dtype().is_float() {
+                f(&xs)?
+            } else {
+                var.as_tensor().copy()?
+            };
+            merged_data.insert(name.clone(), Var::from_tensor(&tensor)?);
+        }
+        for other in rest.iter() {
+            if let Some(name) = other.keys().find(|n| !first.contains_key(*n)) {
+                candle::bail!("merge: cannot find variable {name} in all the models")
+            }
+        }
+    }
+    Ok(merged)
+}
+
+/// Weighted average of the variables of some models, see `linear`.
+pub fn linear_varmaps(models: &[&VarMap], weights: &[f64]) -> Result<VarMap> {
+    merge_varmaps(models, |xs| linear(xs, weights))
+}
+
+/// Spherical linear interpolation between the variables of two models, see `slerp`.
+pub fn slerp_varmaps(a: &VarMap, b: &VarMap, t: f64) -> Result<VarMap> {
+    merge_varmaps(&[a, b], |xs| slerp(xs[0], xs[1], t))
+}
+
+/// Adds the scaled task vectors of some fine-tuned models to a base model, see `task_arithmetic`.
+pub fn task_arithmetic_varmaps(
+    base: &VarMap,
+    finetuned: &[&VarMap],
+    scales: &[f64],
+) -> Result<VarMap> {
+    let maps = [&[base], finetuned].concat();
+    merge_varmaps(&maps, |xs| task_arithmetic(xs[0], &xs[1..], scales))
+}
+
+/// Merges some fine-tuned models into a base model with TIES-merging, see `ties`.
+pub fn ties_varmaps(
+    base: &VarMap,
+    finetuned: &[&VarMap],
+    density: f64,
+    lambda: f64,
+) -> Result<VarMap> {
+    let maps = [&[base], finetuned].concat();
+    merge_varmaps(&maps, |xs| ties(xs[0], &xs[1..], density, lambda))
+}
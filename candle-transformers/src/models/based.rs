@@ -548,26 +548,18 @@ impl Model {
         seqlen_offset: usize,
     ) -> Result<Tensor> {
         let sliding_window = self.sliding_window / 2;
-        let mask: Vec<_> = (0..tgt_len)
-            .flat_map(|i| {
-                (0..tgt_len).map(move |j| {
-                    if i < j || j + sliding_window < i {
-                        f32::NEG_INFINITY
-                    } else {
-                        0.
-                    }
-                })
-            })
-            .collect();
-        let mask = Tensor::from_slice(&mask, (tgt_len, tgt_len), &self.device)?;
-        let mask = if seqlen_offset > 0 {
-            let mask0 = Tensor::zeros((tgt_len, seqlen_offset), self.dtype, &self.device)?;
-            Tensor::cat(&[&mask0, &mask], D::Minus1)?
-        } else {
-            mask
-        };
-        mask.expand((b_size, 1, tgt_len, tgt_len + seqlen_offset))?
-            .to_dtype(self.dtype)
+        let mask = candle_nn::attention_mask::sliding_window_mask(
+            tgt_len,
+            seqlen_offset,
+            sliding_window,
+            &self.device,
+        )?;
+        candle_nn::attention_mask::to_additive(&mask, self.dtype)?.expand((
+            b_size,
+            1,
+            tgt_len,
+            tgt_len + seqlen_offset,
+        ))
     }
 
     pub fn forward(&mut self, input_ids: &Tensor, seqlen_offset: usize) -> Result<Tensor> {
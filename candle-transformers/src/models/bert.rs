@@ -1,3 +1,4 @@
+use super::outputs::{ForwardOptions, ModelOutput, OutputCollector};
 use super::with_tracing::{layer_norm, linear, LayerNorm, Linear};
 use candle::{DType, Device, Result, Tensor};
 use candle_nn::{embedding, Embedding, Module, VarBuilder};
@@ -231,7 +232,13 @@ impl BertSelfAttention {
         xs.contiguous()
     }
 
-    fn forward(&self, hidden_states: &Tensor, attention_mask: &Tensor) -> Result<Tensor> {
+    // Also returns the attention probabilities when `output_attentions` is set.
+    fn forward(
+        &self,
+        hidden_states: &Tensor,
+        attention_mask: &Tensor,
+        output_attentions: bool,
+    ) -> Result<(Tensor, Option<Tensor>)> {
         let _enter = self.span.enter();
         let query_layer = self.query.forward(hidden_states)?;
         let key_layer = self.key.forward(hidden_states)?;
@@ -253,7 +260,7 @@ impl BertSelfAttention {
         let context_layer = attention_probs.matmul(&value_layer)?;
         let context_layer = context_layer.transpose(1, 2)?.contiguous()?;
         let context_layer = context_layer.flatten_from(candle::D::Minus2)?;
-        Ok(context_layer)
+        Ok((context_layer, output_attentions.then_some(attention_probs)))
     }
 }
 
@@ -307,11 +314,18 @@ impl BertAttention {
         })
     }
 
-    fn forward(&self, hidden_states: &Tensor, attention_mask: &Tensor) -> Result<Tensor> {
+    fn forward(
+        &self,
+        hidden_states: &Tensor,
+        attention_mask: &Tensor,
+        output_attentions: bool,
+    ) -> Result<(Tensor, Option<Tensor>)> {
         let _enter = self.span.enter();
-        let self_outputs = self.self_attention.forward(hidden_states, attention_mask)?;
+        let (self_outputs, attention_probs) =
+            self.self_attention
+                .forward(hidden_states, attention_mask, output_attentions)?;
         let attention_output = self.self_output.forward(&self_outputs, hidden_states)?;
-        Ok(attention_output)
+        Ok((attention_output, attention_probs))
     }
 }
 
@@ -396,9 +410,16 @@ impl BertLayer {
         })
     }
 
-    fn forward(&self, hidden_states: &Tensor, attention_mask: &Tensor) -> Result<Tensor> {
+    fn forward(
+        &self,
+        hidden_states: &Tensor,
+        attention_mask: &Tensor,
+        output_attentions: bool,
+    ) -> Result<(Tensor, Option<Tensor>)> {
         let _enter = self.span.enter();
-        let attention_output = self.attention.forward(hidden_states, attention_mask)?;
+        let (attention_output, attention_probs) =
+            self.attention
+                .forward(hidden_states, attention_mask, output_attentions)?;
         // TODO: Support cross-attention?
         // https://github.com/huggingface/transformers/blob/6eedfa6dd15dc1e22a55ae036f681914e5a0d9a1/src/transformers/models/bert/modeling_bert.py#L523
         // TODO: Support something similar to `apply_chunking_to_forward`?
@@ -406,7 +427,7 @@ impl BertLayer {
         let layer_output = self
             .output
             .forward(&intermediate_output, &attention_output)?;
-        Ok(layer_output)
+        Ok((layer_output, attention_probs))
     }
 }
 
@@ -426,11 +447,26 @@ impl BertEncoder {
     }
 
     fn forward(&self, hidden_states: &Tensor, attention_mask: &Tensor) -> Result<Tensor> {
+        let mut outputs = OutputCollector::new(&ForwardOptions::default());
+        self.forward_with_outputs(hidden_states, attention_mask, &mut outputs)
+    }
+
+    fn forward_with_outputs(
+        &self,
+        hidden_states: &Tensor,
+        attention_mask: &Tensor,
+        outputs: &mut OutputCollector,
+    ) -> Result<Tensor> {
         let _enter = self.span.enter();
         let mut hidden_states = hidden_states.clone();
+        outputs.push_hidden_state(&hidden_states);
         // Use a loop rather than a fold as it's easier to modify when adding debug/...
         for layer in self.layers.iter() {
-            hidden_states = layer.forward(&hidden_states, attention_mask)?
+            let (xs, attention_probs) =
+                layer.forward(&hidden_states, attention_mask, outputs.output_attentions())?;
+            hidden_states = xs;
+            outputs.push_hidden_state(&hidden_states);
+            outputs.push_attention(attention_probs);
         }
         Ok(hidden_states)
     }
@@ -491,6 +527,29 @@ impl BertModel {
         let sequence_output = self.encoder.forward(&embedding_output, &attention_mask)?;
         Ok(sequence_output)
     }
+
+    /// Similar to `forward` but can also return the hidden states and attention probabilities
+    /// of all the layers.
+    pub fn forward_with_options(
+        &self,
+        input_ids: &Tensor,
+        token_type_ids: &Tensor,
+        attention_mask: Option<&Tensor>,
+        options: &ForwardOptions,
+    ) -> Result<ModelOutput> {
+        let _enter = self.span.enter();
+        let embedding_output = self.embeddings.forward(input_ids, token_type_ids)?;
+        let attention_mask = match attention_mask {
+            Some(attention_mask) => attention_mask.clone(),
+            None => input_ids.ones_like()?,
+        };
+        let attention_mask = get_extended_attention_mask(&attention_mask, DType::F32)?;
+        let mut outputs = OutputCollector::new(options);
+        let sequence_output =
+            self.encoder
+                .forward_with_outputs(&embedding_output, &attention_mask, &mut outputs)?;
+        Ok(outputs.finish(sequence_output))
+    }
 }
 
 fn get_extended_attention_mask(attention_mask: &Tensor, dtype: DType) -> Result<Tensor> {
@@ -552,10 +552,7 @@ pub struct Model {
 }
 
 fn get_mask(size: usize, device: &Device) -> Result<Tensor> {
-    let mask: Vec<_> = (0..size)
-        .flat_map(|i| (0..size).map(move |j| u8::from(j > i)))
-        .collect();
-    Tensor::from_slice(&mask, (size, size), device)
+    candle_nn::attention_mask::causal_mask(size, 0, device)?.eq(0u8)
 }
 
 impl Model {
@@ -390,6 +390,7 @@ impl<'a> DPTHead<'a> {
                     stride: 4,
                     dilation: 1,
                     output_padding: 0,
+                    groups: 1,
                 },
                 vb.pp("resize_layers").pp("0"),
             )?),
@@ -402,6 +403,7 @@ impl<'a> DPTHead<'a> {
                     stride: 2,
                     dilation: 1,
                     output_padding: 0,
+                    groups: 1,
                 },
                 vb.pp("resize_layers").pp("1"),
             )?),
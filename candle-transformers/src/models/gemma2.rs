@@ -389,31 +389,21 @@ impl Model {
         tgt_len: usize,
         seqlen_offset: usize,
     ) -> Result<Tensor> {
-        let mask: Vec<_> = match self.sliding_window {
-            None => (0..tgt_len)
-                .flat_map(|i| (0..tgt_len).map(move |j| if i < j { f32::NEG_INFINITY } else { 0. }))
-                .collect(),
-            Some(sliding_window) => (0..tgt_len)
-                .flat_map(|i| {
-                    (0..tgt_len).map(move |j| {
-                        if i < j || j + sliding_window < i {
-                            f32::NEG_INFINITY
-                        } else {
-                            0.
-                        }
-                    })
-                })
-                .collect(),
+        let mask = match self.sliding_window {
+            Some(sliding_window) => candle_nn::attention_mask::sliding_window_mask(
+                tgt_len,
+                seqlen_offset,
+                sliding_window,
+                &self.device,
+            )?,
+            None => candle_nn::attention_mask::causal_mask(tgt_len, seqlen_offset, &self.device)?,
         };
-        let mask = Tensor::from_slice(&mask, (tgt_len, tgt_len), &self.device)?;
-        let mask = if seqlen_offset > 0 {
-            let mask0 = Tensor::zeros((tgt_len, seqlen_offset), DType::F32, &self.device)?;
-            Tensor::cat(&[&mask0, &mask], D::Minus1)?
-        } else {
-            mask
-        };
-        mask.expand((b_size, 1, tgt_len, tgt_len + seqlen_offset))?
-            .to_dtype(self.dtype)
+        candle_nn::attention_mask::to_additive(&mask, self.dtype)?.expand((
+            b_size,
+            1,
+            tgt_len,
+            tgt_len + seqlen_offset,
+        ))
     }
 
     pub fn forward(&mut self, input_ids: &Tensor, seqlen_offset: usize) -> Result<Tensor> {
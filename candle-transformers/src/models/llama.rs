@@ -1,5 +1,6 @@
+use super::outputs::{ForwardOptions, ModelOutput, OutputCollector};
 use super::with_tracing::{linear_no_bias as linear, Linear, RmsNorm};
-use candle::{DType, Device, IndexOp, Result, Tensor, D};
+use candle::{DType, Device, IndexOp, Result, ResultExt, Tensor, D};
 use candle_nn::{embedding, Embedding, Module, VarBuilder};
 use std::{collections::HashMap, f32::consts::PI};
 
@@ -204,14 +205,24 @@ impl Cache {
         })
     }
 
+    /// Drops the cached keys and values after the first `seq_len` positions, e.g. to discard
+    /// rejected draft tokens.
+    pub fn truncate(&mut self, seq_len: usize) -> Result<()> {
+        for kv in self.kvs.iter_mut() {
+            if let Some((k, v)) = kv {
+                if k.dim(2)? > seq_len {
+                    *kv = Some((k.narrow(2, 0, seq_len)?, v.narrow(2, 0, seq_len)?))
+                }
+            }
+        }
+        Ok(())
+    }
+
     fn mask(&mut self, t: usize) -> Result<Tensor> {
         if let Some(mask) = self.masks.get(&t) {
             Ok(mask.clone())
         } else {
-            let mask: Vec<_> = (0..t)
-                .flat_map(|i| (0..t).map(move |j| u8::from(j > i)))
-                .collect();
-            let mask = Tensor::from_slice(&mask, (t, t), &self.device)?;
+            let mask = candle_nn::attention_mask::causal_mask(t, 0, &self.device)?.eq(0u8)?;
             self.masks.insert(t, mask.clone());
             Ok(mask)
         }
@@ -258,13 +269,15 @@ impl CausalSelfAttention {
         candle_nn::rotary_emb::rope(x, &cos, &sin)
     }
 
-    fn forward(
+    // Also returns the attention probabilities when `output_attentions` is set.
+    fn forward_with_attn(
         &self,
         x: &Tensor,
         index_pos: usize,
         block_idx: usize,
         cache: &mut Cache,
-    ) -> Result<Tensor> {
+        output_attentions: bool,
+    ) -> Result<(Tensor, Option<Tensor>)> {
         let _enter = self.span.enter();
         let (b_sz, seq_len, hidden_size) = x.dims3()?;
         let q = self.q_proj.forward(x)?;
@@ -317,13 +330,17 @@ impl CausalSelfAttention {
         let k = self.repeat_kv(k)?;
         let v = self.repeat_kv(v)?;
 
-        let y = if self.use_flash_attn {
+        let (y, attn) = if self.use_flash_attn {
+            if output_attentions {
+                candle::bail!("attention outputs are not available with flash-attn")
+            }
             // flash-attn expects (b_sz, seq_len, nheads, head_dim)
             let q = q.transpose(1, 2)?;
             let k = k.transpose(1, 2)?;
             let v = v.transpose(1, 2)?;
             let softmax_scale = 1f32 / (self.head_dim as f32).sqrt();
-            flash_attn(&q, &k, &v, softmax_scale, seq_len > 1)?.transpose(1, 2)?
+            let y = flash_attn(&q, &k, &v, softmax_scale, seq_len > 1)?.transpose(1, 2)?;
+            (y, None)
         } else {
             let in_dtype = q.dtype();
             let q = q.to_dtype(DType::F32)?;
@@ -331,18 +348,26 @@ impl CausalSelfAttention {
             let v = v.to_dtype(DType::F32)?;
             let att = (q.matmul(&k.t()?)? / (self.head_dim as f64).sqrt())?;
             let att = if seq_len == 1 {
-                att
+                candle_nn::ops::softmax_last_dim(&att)?
             } else {
-                let mask = cache.mask(seq_len)?.broadcast_as(att.shape())?;
-                masked_fill(&att, &mask, f32::NEG_INFINITY)?
+                let mask = cache.mask(seq_len)?;
+                // When some positions are already in the kv cache, they can all be attended to.
+                let kv_len = att.dim(D::Minus1)?;
+                let mask = if kv_len > seq_len {
+                    let past = Tensor::zeros((seq_len, kv_len - seq_len), DType::U8, x.device())?;
+                    Tensor::cat(&[&past, &mask], 1)?
+                } else {
+                    mask
+                };
+                candle_nn::ops::masked_softmax_last_dim(&att, &mask)?
             };
-            let att = candle_nn::ops::softmax(&att, D::Minus1)?;
             // Convert to contiguous as matmul doesn't support strided vs for now.
-            att.matmul(&v.contiguous()?)?.to_dtype(in_dtype)?
+            let y = att.matmul(&v.contiguous()?)?.to_dtype(in_dtype)?;
+            (y, output_attentions.then_some(att))
         };
         let y = y.transpose(1, 2)?.reshape(&[b_sz, seq_len, hidden_size])?;
         let y = self.o_proj.forward(&y)?;
-        Ok(y)
+        Ok((y, attn))
     }
 
     fn repeat_kv(&self, x: Tensor) -> Result<Tensor> {
@@ -375,13 +400,6 @@ impl CausalSelfAttention {
     }
 }
 
-fn masked_fill(on_false: &Tensor, mask: &Tensor, on_true: f32) -> Result<Tensor> {
-    let shape = mask.shape();
-    let on_true = Tensor::new(on_true, on_false.device())?.broadcast_as(shape.dims())?;
-    let m = mask.where_cond(&on_true, on_false)?;
-    Ok(m)
-}
-
 #[derive(Debug, Clone)]
 struct Mlp {
     c_fc1: Linear,
@@ -420,6 +438,8 @@ struct Block {
     rms_2: RmsNorm,
     mlp: Mlp,
     span: tracing::Span,
+    // The VarBuilder prefix, used to point at the failing layer in errors.
+    prefix: String,
 }
 
 impl Block {
@@ -430,13 +450,40 @@ impl Block {
         block_idx: usize,
         cache: &mut Cache,
     ) -> Result<Tensor> {
+        let (x, _) = self.forward_with_attn(x, index_pos, block_idx, cache, false)?;
+        Ok(x)
+    }
+
+    fn forward_with_attn(
+        &self,
+        x: &Tensor,
+        index_pos: usize,
+        block_idx: usize,
+        cache: &mut Cache,
+        output_attentions: bool,
+    ) -> Result<(Tensor, Option<Tensor>)> {
+        self.forward_with_attn_(x, index_pos, block_idx, cache, output_attentions)
+            .module_context(&self.prefix)
+    }
+
+    fn forward_with_attn_(
+        &self,
+        x: &Tensor,
+        index_pos: usize,
+        block_idx: usize,
+        cache: &mut Cache,
+        output_attentions: bool,
+    ) -> Result<(Tensor, Option<Tensor>)> {
         let _enter = self.span.enter();
         let residual = x;
         let x = self.rms_1.forward(x)?;
-        let x = (self.attn.forward(&x, index_pos, block_idx, cache)? + residual)?;
+        let (x, attn) =
+            self.attn
+                .forward_with_attn(&x, index_pos, block_idx, cache, output_attentions)?;
+        let x = (x + residual)?;
         let residual = &x;
         let x = (self.mlp.forward(&self.rms_2.forward(&x)?)? + residual)?;
-        Ok(x)
+        Ok((x, attn))
     }
 
     fn load(vb: VarBuilder, cfg: &Config) -> Result<Self> {
@@ -455,10 +502,47 @@ impl Block {
             rms_2,
             mlp,
             span,
+            prefix: vb.prefix(),
         })
     }
 }
 
+/// The head used to produce logits when exiting after an intermediate layer.
+///
+/// The hidden states go through an optional adapter before the final norm and the language model
+/// head of the full model. The logits are divided by `temperature`, which can be fitted on some
+/// held-out data so that the early-exit probabilities are calibrated.
+#[derive(Debug, Clone)]
+pub struct ExitHead {
+    adapter: Option<Linear>,
+    temperature: f64,
+}
+
+impl ExitHead {
+    pub fn new(adapter: Option<Linear>, temperature: f64) -> Self {
+        Self {
+            adapter,
+            temperature,
+        }
+    }
+
+    /// Loads the adapter weights if present in `vb`, under the `adapter` name.
+    pub fn load(vb: VarBuilder, cfg: &Config, temperature: f64) -> Result<Self> {
+        let adapter = if vb.contains_tensor("adapter.weight") {
+            Some(linear(cfg.hidden_size, cfg.hidden_size, vb.pp("adapter"))?)
+        } else {
+            None
+        };
+        Ok(Self::new(adapter, temperature))
+    }
+}
+
+impl Default for ExitHead {
+    fn default() -> Self {
+        Self::new(None, 1.0)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Llama {
     wte: Embedding,
@@ -502,6 +586,142 @@ impl Llama {
         logits.to_dtype(DType::F32)
     }
 
+    /// Similar to `forward` but can also return the hidden states and attention probabilities
+    /// of all the layers. The hidden states are taken before the final norm.
+    pub fn forward_with_options(
+        &self,
+        x: &Tensor,
+        index_pos: usize,
+        cache: &mut Cache,
+        options: &ForwardOptions,
+    ) -> Result<ModelOutput> {
+        let (_b_sz, seq_len) = x.dims2()?;
+        let mut outputs = OutputCollector::new(options);
+        let mut x = self.wte.forward(x)?;
+        outputs.push_hidden_state(&x);
+        for (block_idx, block) in self.blocks.iter().enumerate() {
+            let (xs, attn) = block.forward_with_attn(
+                &x,
+                index_pos,
+                block_idx,
+                cache,
+                outputs.output_attentions(),
+            )?;
+            x = xs;
+            outputs.push_hidden_state(&x);
+            outputs.push_attention(attn);
+        }
+        let x = self.ln_f.forward(&x)?;
+        let x = x.i((.., seq_len - 1, ..))?.contiguous()?;
+        let logits = self.lm_head.forward(&x)?.to_dtype(DType::F32)?;
+        Ok(outputs.finish(logits))
+    }
+
+    /// Applies the final norm and the language model head to some hidden states, e.g. the ones
+    /// returned by `forward_with_options` for the logit lens.
+    pub fn project_hidden(&self, xs: &Tensor) -> Result<Tensor> {
+        let xs = self.ln_f.forward(xs)?;
+        self.lm_head.forward(&xs)?.to_dtype(DType::F32)
+    }
+
+    /// Runs the first `exit_layer` blocks and computes the logits for the last position with
+    /// `head`, skipping the remaining layers.
+    ///
+    /// The kv cache is only updated for the layers that have been run, so the cache has to be
+    /// truncated before running the full model on the same positions.
+    pub fn forward_early_exit(
+        &self,
+        x: &Tensor,
+        index_pos: usize,
+        cache: &mut Cache,
+        exit_layer: usize,
+        head: &ExitHead,
+    ) -> Result<Tensor> {
+        if exit_layer == 0 || exit_layer > self.blocks.len() {
+            candle::bail!(
+                "invalid exit layer {exit_layer}, the model has {} layers",
+                self.blocks.len()
+            )
+        }
+        let (_b_sz, seq_len) = x.dims2()?;
+        let mut x = self.wte.forward(x)?;
+        for (block_idx, block) in self.blocks[..exit_layer].iter().enumerate() {
+            x = block.forward(&x, index_pos, block_idx, cache)?;
+        }
+        let x = x.i((.., seq_len - 1, ..))?.contiguous()?;
+        let x = match &head.adapter {
+            None => x,
+            Some(adapter) => adapter.forward(&x)?,
+        };
+        let x = self.ln_f.forward(&x)?;
+        let logits = self.lm_head.forward(&x)?.to_dtype(DType::F32)?;
+        logits / head.temperature
+    }
+
+    /// Similar to `forward` but returns the logits for all the positions, with a shape
+    /// `(b_sz, seq_len, vocab_size)`.
+    pub fn forward_all(&self, x: &Tensor, index_pos: usize, cache: &mut Cache) -> Result<Tensor> {
+        let mut x = self.wte.forward(x)?;
+        for (block_idx, block) in self.blocks.iter().enumerate() {
+            x = block.forward(&x, index_pos, block_idx, cache)?;
+        }
+        let x = self.ln_f.forward(&x)?;
+        let logits = self.lm_head.forward(&x)?;
+        logits.to_dtype(DType::F32)
+    }
+
+    /// Generates tokens greedily with self-speculative decoding.
+    ///
+    /// Up to `n_draft` tokens are drafted using the early exit at `exit_layer`, then verified in
+    /// a single pass of the full model. The accepted draft tokens are returned followed by the
+    /// token predicted by the full model after them, so the output is the same as with greedy
+    /// decoding on the full model.
+    ///
+    /// `last_token` is the last token that has not been processed yet, at position `index_pos`.
+    /// The kv cache must be enabled and on return it covers `last_token` and the accepted draft
+    /// tokens, the last returned token being the next one to process.
+    pub fn self_speculative_step(
+        &self,
+        last_token: u32,
+        index_pos: usize,
+        cache: &mut Cache,
+        n_draft: usize,
+        exit_layer: usize,
+        head: &ExitHead,
+    ) -> Result<Vec<u32>> {
+        if !cache.use_kv_cache {
+            candle::bail!("self-speculative decoding requires the kv cache")
+        }
+        let device = self.wte.embeddings().device();
+        let mut draft = vec![];
+        let mut token = last_token;
+        for i in 0..n_draft {
+            let input = Tensor::new(&[token], device)?.unsqueeze(0)?;
+            let logits = self.forward_early_exit(&input, index_pos + i, cache, exit_layer, head)?;
+            token = logits.squeeze(0)?.argmax(D::Minus1)?.to_scalar::<u32>()?;
+            draft.push(token)
+        }
+        // Discard the draft entries from the early layers and run the full model.
+        cache.truncate(index_pos)?;
+        let input = [&[last_token], &draft[..]].concat();
+        let input = Tensor::new(input.as_slice(), device)?.unsqueeze(0)?;
+        let logits = self.forward_all(&input, index_pos, cache)?;
+        let preds = logits.squeeze(0)?.argmax(D::Minus1)?.to_vec1::<u32>()?;
+        let n_accepted = draft
+            .iter()
+            .zip(preds.iter())
+            .take_while(|(d, p)| d == p)
+            .count();
+        cache.truncate(index_pos + n_accepted + 1)?;
+        let mut tokens = draft[..n_accepted].to_vec();
+        tokens.push(preds[n_accepted]);
+        Ok(tokens)
+    }
+
+    pub fn num_layers(&self) -> usize {
+        self.blocks.len()
+    }
+
     pub fn load(vb: VarBuilder, cfg: &Config) -> Result<Self> {
         let wte = embedding(cfg.vocab_size, cfg.hidden_size, vb.pp("model.embed_tokens"))?;
         let lm_head = linear(cfg.hidden_size, cfg.vocab_size, vb.pp("lm_head"))?;
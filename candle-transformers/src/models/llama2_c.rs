@@ -116,10 +116,7 @@ impl Cache {
         if let Some(mask) = self.masks.get(&t) {
             Ok(mask.clone())
         } else {
-            let mask: Vec<_> = (0..t)
-                .flat_map(|i| (0..t).map(move |j| u8::from(j > i)))
-                .collect();
-            let mask = Tensor::from_slice(&mask, (t, t), &self.device)?;
+            let mask = candle_nn::attention_mask::causal_mask(t, 0, &self.device)?.eq(0u8)?;
             self.masks.insert(t, mask.clone());
             Ok(mask)
         }
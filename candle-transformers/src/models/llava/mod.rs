@@ -3,6 +3,7 @@ pub mod utils;
 
 use crate::models::clip::vision_model::{ClipVisionConfig, ClipVisionTransformer};
 use crate::models::llama::{Cache, Llama};
+use crate::models::siglip::{VisionConfig as SiglipVisionConfig, VisionModel as SiglipVisionModel};
 use crate::models::with_tracing::linear;
 
 use candle::{bail, Device, IndexOp, Result, Tensor};
@@ -167,8 +168,69 @@ impl ClipVisionTower {
     }
 }
 
+/// A SigLIP image encoder used in place of the CLIP one. SigLIP has no class token so all the
+/// patch features are kept whatever the feature selection method.
+pub struct SiglipVisionTower {
+    model: SiglipVisionModel,
+    select_layer: isize,
+    pub config: SiglipVisionConfig,
+}
+
+impl SiglipVisionTower {
+    pub fn new(vb: VarBuilder, select_layer: isize, config: &SiglipVisionConfig) -> Result<Self> {
+        let num_hidden_states = config.num_hidden_layers as isize + 1;
+        if select_layer >= 0 || -select_layer > num_hidden_states {
+            bail!("Unsupported select layer: {}", select_layer)
+        }
+        let model = SiglipVisionModel::new(config, vb)?;
+        Ok(Self {
+            model,
+            select_layer,
+            config: config.clone(),
+        })
+    }
+
+    pub fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        let result = self.model.forward_hidden_states(x)?;
+        let index = result.len() as isize + self.select_layer;
+        Ok(result[index as usize].clone())
+    }
+
+    pub fn num_patches_per_side(&self) -> usize {
+        self.config.num_patches_per_side()
+    }
+}
+
+pub enum VisionTower {
+    Clip(ClipVisionTower),
+    Siglip(SiglipVisionTower),
+}
+
+impl VisionTower {
+    pub fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        match self {
+            Self::Clip(tower) => tower.forward(x),
+            Self::Siglip(tower) => tower.forward(x),
+        }
+    }
+
+    pub fn num_patches_per_side(&self) -> usize {
+        match self {
+            Self::Clip(tower) => tower.num_patches_per_side(),
+            Self::Siglip(tower) => tower.num_patches_per_side(),
+        }
+    }
+
+    pub fn image_size(&self) -> usize {
+        match self {
+            Self::Clip(tower) => tower.config.image_size,
+            Self::Siglip(tower) => tower.config.image_size,
+        }
+    }
+}
+
 pub struct LLaVA {
-    pub clip_vision_tower: ClipVisionTower,
+    pub vision_tower: VisionTower,
     pub image_newline: Tensor,
     pub mm_projector: MMProjector,
     pub llama: Llama,
@@ -181,37 +243,62 @@ impl LLaVA {
         vb: VarBuilder,
         config: &LLaVAConfig,
         clip_vision_config: Option<ClipVisionConfig>,
+    ) -> Result<Self> {
+        let clip_vision_tower = ClipVisionTower::new(
+            vb.pp(Self::vision_tower_prefix(config)),
+            config.mm_vision_select_layer,
+            &config.mm_vision_select_feature,
+            &clip_vision_config,
+        )?;
+        Self::load_with_vision_tower(vb, config, VisionTower::Clip(clip_vision_tower))
+    }
+
+    /// Loads a LLaVA model using a SigLIP image encoder, the weights of the encoder are expected
+    /// at the same place as for the CLIP one.
+    pub fn load_siglip(
+        vb: VarBuilder,
+        config: &LLaVAConfig,
+        siglip_vision_config: &SiglipVisionConfig,
+    ) -> Result<Self> {
+        let siglip_vision_tower = SiglipVisionTower::new(
+            vb.pp(Self::vision_tower_prefix(config)),
+            config.mm_vision_select_layer,
+            siglip_vision_config,
+        )?;
+        Self::load_with_vision_tower(vb, config, VisionTower::Siglip(siglip_vision_tower))
+    }
+
+    fn vision_tower_prefix(config: &LLaVAConfig) -> &'static str {
+        if config.hf {
+            "vision_tower.vision_model"
+        } else {
+            "model.vision_tower.vision_tower.vision_model"
+        }
+    }
+
+    fn load_with_vision_tower(
+        vb: VarBuilder,
+        config: &LLaVAConfig,
+        vision_tower: VisionTower,
     ) -> Result<Self> {
         let device = vb.device().clone();
         let llama_config = config.to_llama_config();
         let mm_projector = MMProjector::load(&vb, config)?;
-        let (clip_vision_tower, image_newline, llama) = if config.hf {
+        let (image_newline, llama) = if config.hf {
             (
-                ClipVisionTower::new(
-                    vb.pp("vision_tower.vision_model"),
-                    config.mm_vision_select_layer,
-                    &config.mm_vision_select_feature,
-                    &clip_vision_config,
-                )?,
                 vb.get(&[config.hidden_size], "image_newline")?
                     .to_device(&device)?,
                 Llama::load(vb.pp("language_model"), &llama_config)?,
             )
         } else {
             (
-                ClipVisionTower::new(
-                    vb.pp("model.vision_tower.vision_tower.vision_model"),
-                    config.mm_vision_select_layer,
-                    &config.mm_vision_select_feature,
-                    &clip_vision_config,
-                )?,
                 vb.get(&[config.hidden_size], "model.image_newline")?
                     .to_device(&device)?,
                 Llama::load(vb, &llama_config)?,
             )
         };
         Ok(Self {
-            clip_vision_tower,
+            vision_tower,
             image_newline,
             mm_projector,
             llama,
@@ -221,7 +308,7 @@ impl LLaVA {
     }
 
     pub fn encode_images(&self, x: &Tensor) -> Result<Tensor> {
-        let image_features = self.clip_vision_tower.forward(x)?;
+        let image_features = self.vision_tower.forward(x)?;
         let image_features = self.mm_projector.forward(&image_features)?;
         Ok(image_features)
     }
@@ -261,7 +348,7 @@ impl LLaVA {
                 let new_image_feature = if image_feature.dims()[0] > 1 {
                     let base_image_feature = image_feature.get(0).unwrap();
                     let patch_image_feature = image_feature.i(1..).unwrap();
-                    let height = self.clip_vision_tower.num_patches_per_side();
+                    let height = self.vision_tower.num_patches_per_side();
                     let width = height;
                     assert_eq!(height * width, base_image_feature.dims()[0]);
                     let image_size = image_sizes[image_idx];
@@ -269,7 +356,7 @@ impl LLaVA {
                         let (num_patch_width, num_patch_height) = get_anyres_image_grid_shape(
                             image_size,
                             &self.config.image_grid_pinpoints,
-                            self.clip_vision_tower.config.image_size as u32,
+                            self.vision_tower.image_size() as u32,
                         );
                         patch_image_feature.reshape((
                             num_patch_height as usize,
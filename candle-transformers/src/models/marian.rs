@@ -1,5 +1,6 @@
 use super::with_tracing::{linear, Embedding, Linear};
-use candle::{Result, Tensor};
+use crate::utils::CrossAttnKv;
+use candle::{DType, Result, Tensor};
 use candle_nn::{layer_norm, LayerNorm, VarBuilder};
 
 #[derive(Debug, Clone)]
@@ -163,16 +164,26 @@ impl Attention {
             .contiguous()
     }
 
+    fn cross_kv(&self, kv_states: &Tensor) -> Result<(Tensor, Tensor)> {
+        let b_sz = kv_states.dim(0)?;
+        let key_states = self._shape(&kv_states.apply(&self.k_proj)?, b_sz)?;
+        let value_states = self._shape(&kv_states.apply(&self.v_proj)?, b_sz)?;
+        Ok((key_states, value_states))
+    }
+
     fn forward(
         &mut self,
         xs: &Tensor,
         kv_states: Option<&Tensor>,
+        cross_kv: Option<&(Tensor, Tensor)>,
         attn_mask: Option<&Tensor>,
     ) -> Result<Tensor> {
         let (b_sz, tgt_len, _) = xs.dims3()?;
         let query_states = (xs.apply(&self.q_proj)? * self.scaling)?;
-        let (key_states, value_states) = match kv_states {
-            None => {
+        let (key_states, value_states) = match (cross_kv, kv_states) {
+            (Some((key_states, value_states)), _) => (key_states.clone(), value_states.clone()),
+            (None, Some(kv_states)) => self.cross_kv(kv_states)?,
+            (None, None) => {
                 let key_states = self._shape(&xs.apply(&self.k_proj)?, b_sz)?;
                 let value_states = self._shape(&xs.apply(&self.v_proj)?, b_sz)?;
                 if self.is_decoder {
@@ -190,11 +201,6 @@ impl Attention {
                     (key_states, value_states)
                 }
             }
-            Some(kv_states) => {
-                let key_states = self._shape(&kv_states.apply(&self.k_proj)?, b_sz)?;
-                let value_states = self._shape(&kv_states.apply(&self.v_proj)?, b_sz)?;
-                (key_states, value_states)
-            }
         };
         let proj_shape = (b_sz * self.num_heads, (), self.head_dim);
         let query_states = self._shape(&query_states, b_sz)?.reshape(proj_shape)?;
@@ -217,6 +223,13 @@ impl Attention {
     fn reset_kv_cache(&mut self) {
         self.kv_cache = None
     }
+
+    fn reorder_kv_cache(&mut self, indexes: &Tensor) -> Result<()> {
+        if let Some((k, v)) = &self.kv_cache {
+            self.kv_cache = Some((k.index_select(indexes, 0)?, v.index_select(indexes, 0)?))
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -248,7 +261,7 @@ impl EncoderLayer {
 
     fn forward(&mut self, xs: &Tensor) -> Result<Tensor> {
         let residual = xs;
-        let xs = (self.self_attn.forward(xs, None, None)? + residual)?
+        let xs = (self.self_attn.forward(xs, None, None, None)? + residual)?
             .apply(&self.self_attn_layer_norm)?;
         let residual = &xs;
         let xs = xs
@@ -301,18 +314,18 @@ impl DecoderLayer {
         &mut self,
         xs: &Tensor,
         encoder_xs: Option<&Tensor>,
+        cross_kv: Option<&(Tensor, Tensor)>,
         attn_mask: &Tensor,
     ) -> Result<Tensor> {
         let residual = xs;
-        let xs = (self.self_attn.forward(xs, None, Some(attn_mask))? + residual)?
+        let xs = (self.self_attn.forward(xs, None, None, Some(attn_mask))? + residual)?
             .apply(&self.self_attn_layer_norm)?;
-        let xs = match encoder_xs {
-            None => xs,
-            Some(encoder_xs) => {
-                let residual = &xs;
-                let xs = self.encoder_attn.forward(&xs, Some(encoder_xs), None)?;
-                (residual + xs)?.apply(&self.encoder_attn_layer_norm)?
-            }
+        let xs = if encoder_xs.is_none() && cross_kv.is_none() {
+            xs
+        } else {
+            let residual = &xs;
+            let xs = self.encoder_attn.forward(&xs, encoder_xs, cross_kv, None)?;
+            (residual + xs)?.apply(&self.encoder_attn_layer_norm)?
         };
         let residual = &xs;
         let xs = xs
@@ -419,6 +432,28 @@ impl Decoder {
         encoder_xs: Option<&Tensor>,
         past_kv_len: usize,
         attn_mask: &Tensor,
+    ) -> Result<Tensor> {
+        self.forward_(xs, encoder_xs, None, past_kv_len, attn_mask)
+    }
+
+    /// Same as `forward` but using the precomputed cross-attention keys and values.
+    pub fn forward_with_cross_kv(
+        &mut self,
+        xs: &Tensor,
+        cross_kv: &CrossAttnKv,
+        past_kv_len: usize,
+        attn_mask: &Tensor,
+    ) -> Result<Tensor> {
+        self.forward_(xs, None, Some(cross_kv), past_kv_len, attn_mask)
+    }
+
+    fn forward_(
+        &mut self,
+        xs: &Tensor,
+        encoder_xs: Option<&Tensor>,
+        cross_kv: Option<&CrossAttnKv>,
+        past_kv_len: usize,
+        attn_mask: &Tensor,
     ) -> Result<Tensor> {
         let xs = xs.apply(&self.embed_tokens)?;
         let xs = match self.embed_scale {
@@ -430,17 +465,37 @@ impl Decoder {
             .forward(&xs, past_kv_len)?
             .unsqueeze(0)?;
         let mut xs = xs.broadcast_add(&embed_pos)?;
-        for layer in self.layers.iter_mut() {
-            xs = layer.forward(&xs, encoder_xs, attn_mask)?;
+        for (i, layer) in self.layers.iter_mut().enumerate() {
+            let cross_kv = cross_kv.map(|c| c.layer(i)).transpose()?;
+            xs = layer.forward(&xs, encoder_xs, cross_kv, attn_mask)?;
         }
         Ok(xs)
     }
 
+    /// Computes the keys and values of the cross-attention layers for an encoder output.
+    pub fn cross_kv(&self, encoder_xs: &Tensor) -> Result<CrossAttnKv> {
+        let kvs = self
+            .layers
+            .iter()
+            .map(|l| l.encoder_attn.cross_kv(encoder_xs))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(CrossAttnKv::new(kvs))
+    }
+
     pub fn reset_kv_cache(&mut self) {
         for layer in self.layers.iter_mut() {
             layer.reset_kv_cache()
         }
     }
+
+    /// Selects the batch elements of the self-attention kv cache at `indexes`, a u32 tensor,
+    /// e.g. to follow the beams selected at a beam search step.
+    pub fn reorder_kv_cache(&mut self, indexes: &Tensor) -> Result<()> {
+        for layer in self.layers.iter_mut() {
+            layer.self_attn.reorder_kv_cache(indexes)?
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -468,6 +523,14 @@ impl Model {
     }
 }
 
+fn causal_mask(xs: &Tensor) -> Result<Tensor> {
+    let seq_len = xs.dim(1)?;
+    candle_nn::attention_mask::to_additive(
+        &candle_nn::attention_mask::causal_mask(seq_len, 0, xs.device())?,
+        DType::F32,
+    )
+}
+
 #[derive(Debug, Clone)]
 pub struct MTModel {
     model: Model,
@@ -480,7 +543,11 @@ impl MTModel {
         let target_vocab_size = cfg.decoder_vocab_size.unwrap_or(cfg.vocab_size);
         let final_logits_bias = vb.get((1, target_vocab_size), "final_logits_bias")?;
         let model = Model::new(cfg, vb.pp("model"))?;
-        let lm_head = Linear::from_weights(model.shared.embeddings().clone(), None);
+        let lm_head = Linear::from_weights(
+            model.shared.embeddings().clone(),
+            None,
+            &vb.pp("lm_head").prefix(),
+        );
         Ok(Self {
             model,
             lm_head,
@@ -502,11 +569,7 @@ impl MTModel {
         encoder_xs: &Tensor,
         past_kv_len: usize,
     ) -> Result<Tensor> {
-        let seq_len = xs.dim(1)?;
-        let mask: Vec<_> = (0..seq_len)
-            .flat_map(|i| (0..seq_len).map(move |j| if j > i { f32::NEG_INFINITY } else { 0f32 }))
-            .collect();
-        let mask = Tensor::from_vec(mask, (seq_len, seq_len), xs.device())?;
+        let mask = causal_mask(xs)?;
         self.model
             .decoder
             .forward(xs, Some(encoder_xs), past_kv_len, &mask)?
@@ -514,7 +577,35 @@ impl MTModel {
             .broadcast_add(&self.final_logits_bias)
     }
 
+    /// Computes the keys and values of the decoder cross-attention layers for an encoder output.
+    /// These can then be used for all the decoding steps with `decode_with_cross_kv` instead of
+    /// projecting the encoder output again at each step.
+    pub fn cross_kv(&self, encoder_xs: &Tensor) -> Result<CrossAttnKv> {
+        self.model.decoder.cross_kv(encoder_xs)
+    }
+
+    /// Same as `decode` but using the precomputed cross-attention keys and values.
+    pub fn decode_with_cross_kv(
+        &mut self,
+        xs: &Tensor,
+        cross_kv: &CrossAttnKv,
+        past_kv_len: usize,
+    ) -> Result<Tensor> {
+        let mask = causal_mask(xs)?;
+        self.model
+            .decoder
+            .forward_with_cross_kv(xs, cross_kv, past_kv_len, &mask)?
+            .apply(&self.lm_head)?
+            .broadcast_add(&self.final_logits_bias)
+    }
+
     pub fn reset_kv_cache(&mut self) {
         self.model.reset_kv_cache();
     }
+
+    /// Selects the batch elements of the decoder kv cache at `indexes`, a u32 tensor, e.g. to
+    /// follow the beams selected at a beam search step.
+    pub fn reorder_kv_cache(&mut self, indexes: &Tensor) -> Result<()> {
+        self.model.decoder.reorder_kv_cache(indexes)
+    }
 }
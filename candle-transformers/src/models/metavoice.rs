@@ -924,10 +924,11 @@ pub mod transformer {
         pub fn forward(&mut self, xs: &Tensor, spk_emb: &Tensor, pos: usize) -> Result<Tensor> {
             let _enter = self.span.enter();
             let (_b_sz, seqlen) = xs.dims2()?;
-            let mask: Vec<_> = (0..seqlen)
-                .flat_map(|i| (0..seqlen).map(move |j| if i < j { f32::NEG_INFINITY } else { 0. }))
-                .collect();
-            let mask = Tensor::from_slice(&mask, (1, 1, seqlen, seqlen), xs.device())?;
+            let mask = candle_nn::attention_mask::to_additive(
+                &candle_nn::attention_mask::causal_mask(seqlen, 0, xs.device())?,
+                DType::F32,
+            )?
+            .reshape((1, 1, seqlen, seqlen))?;
             let input_pos = Tensor::arange(pos as u32, (pos + seqlen) as u32, xs.device())?;
             let tok_embeddings = xs.apply(&self.tok_embeddings)?;
             let pos_embeddings = input_pos.apply(&self.pos_embeddings)?;
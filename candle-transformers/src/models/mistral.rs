@@ -1,6 +1,6 @@
 use crate::models::with_tracing::{linear_no_bias, Linear, RmsNorm};
 /// Mistral LLM, https://github.com/mistralai/mistral-src
-use candle::{DType, Device, Module, Result, Tensor, D};
+use candle::{DType, Device, Module, Result, Tensor};
 use candle_nn::{Activation, VarBuilder};
 use std::sync::Arc;
 
@@ -275,11 +275,11 @@ impl Attention {
             let scale = 1f64 / f64::sqrt(self.head_dim as f64);
             let attn_weights = (query_states.matmul(&key_states.transpose(2, 3)?)? * scale)?;
 
+            // The mask is non-zero for the positions that cannot be attended to.
             let attn_weights = match attention_mask {
-                None => attn_weights,
-                Some(mask) => attn_weights.broadcast_add(mask)?,
+                None => candle_nn::ops::softmax_last_dim(&attn_weights)?,
+                Some(mask) => candle_nn::ops::masked_softmax_last_dim(&attn_weights, mask)?,
             };
-            let attn_weights = candle_nn::ops::softmax_last_dim(&attn_weights)?;
             attn_weights.matmul(&value_states)?
         };
         attn_output
@@ -348,7 +348,6 @@ pub struct Model {
     lm_head: Linear,
     sliding_window: Option<usize>,
     device: Device,
-    dtype: DType,
 }
 
 impl Model {
@@ -372,7 +371,6 @@ impl Model {
             lm_head,
             sliding_window: cfg.sliding_window,
             device: vb.device().clone(),
-            dtype: vb.dtype(),
         })
     }
 
@@ -381,27 +379,17 @@ impl Model {
         tgt_len: usize,
         seqlen_offset: usize,
     ) -> Result<Tensor> {
-        let sliding_window = self.sliding_window.unwrap_or(tgt_len + 1);
-        let mask: Vec<_> = (0..tgt_len)
-            .flat_map(|i| {
-                (0..tgt_len).map(move |j| {
-                    if i < j || j + sliding_window < i {
-                        f32::NEG_INFINITY
-                    } else {
-                        0.
-                    }
-                })
-            })
-            .collect();
-        let mask = Tensor::from_slice(&mask, (tgt_len, tgt_len), &self.device)?;
-        let mask = if seqlen_offset > 0 {
-            let mask0 = Tensor::zeros((tgt_len, seqlen_offset), DType::F32, &self.device)?;
-            Tensor::cat(&[&mask0, &mask], D::Minus1)?
-        } else {
-            mask
+        let mask = match self.sliding_window {
+            Some(sliding_window) => candle_nn::attention_mask::sliding_window_mask(
+                tgt_len,
+                seqlen_offset,
+                sliding_window,
+                &self.device,
+            )?,
+            None => candle_nn::attention_mask::causal_mask(tgt_len, seqlen_offset, &self.device)?,
         };
-        mask.expand((1, 1, tgt_len, tgt_len + seqlen_offset))?
-            .to_dtype(self.dtype)
+        // The positions that cannot be attended to, as expected by `masked_softmax_last_dim`.
+        mask.eq(0u8)
     }
 
     pub fn forward(&mut self, input_ids: &Tensor, seqlen_offset: usize) -> Result<Tensor> {
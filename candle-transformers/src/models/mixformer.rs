@@ -127,10 +127,10 @@ impl Module for Embedding {
 }
 
 fn get_mask(size: usize, dtype: DType, device: &Device) -> Result<Tensor> {
-    let mask: Vec<_> = (0..size)
-        .flat_map(|i| (0..size).map(move |j| if j > i { f32::NEG_INFINITY } else { 0. }))
-        .collect();
-    Tensor::from_slice(&mask, (size, size), device)?.to_dtype(dtype)
+    candle_nn::attention_mask::to_additive(
+        &candle_nn::attention_mask::causal_mask(size, 0, device)?,
+        dtype,
+    )
 }
 
 #[derive(Debug, Clone)]
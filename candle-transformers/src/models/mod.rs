@@ -42,6 +42,7 @@ pub mod mobileone;
 pub mod moondream;
 pub mod mpt;
 pub mod olmo;
+pub mod outputs;
 pub mod parler_tts;
 pub mod persimmon;
 pub mod phi;
@@ -72,6 +73,7 @@ pub mod rwkv_v5;
 pub mod rwkv_v6;
 pub mod segformer;
 pub mod segment_anything;
+pub mod siglip;
 pub mod stable_diffusion;
 pub mod stable_lm;
 pub mod starcoder2;
@@ -51,6 +51,7 @@ struct GroupedQueryAttention {
     n_heads: usize,
     kv_n_heads: usize,
     attn_bias: Tensor,
+    alibi_slopes: Option<Vec<f32>>,
     span: tracing::Span,
 }
 
@@ -62,6 +63,12 @@ impl GroupedQueryAttention {
         let softmax_scale = 1f64 / (head_dim as f64).sqrt();
         let out_proj = linear_no_bias(cfg.d_model, cfg.d_model, vb.pp("out_proj"))?;
         let attn_bias = build_alibi_bias(cfg)?.to_device(vb.device())?;
+        // The fused decode kernel only applies to causal attention.
+        let alibi_slopes = if cfg.attn_alibi && cfg.is_causal() {
+            Some(alibi_slopes(cfg))
+        } else {
+            None
+        };
         Ok(Self {
             wqkv,
             out_proj,
@@ -72,6 +79,7 @@ impl GroupedQueryAttention {
             n_heads: cfg.n_heads,
             kv_n_heads: cfg.kv_n_heads,
             attn_bias,
+            alibi_slopes,
             span: tracing::span!(tracing::Level::TRACE, "gqa"),
         })
     }
@@ -90,21 +98,33 @@ impl GroupedQueryAttention {
             .transpose(1, 2)?; // b,h,s,d
         let key = key
             .reshape((b_size, seq_len, self.kv_n_heads, ()))?
-            .permute((0, 2, 3, 1))?; // b,h,d,s
+            .transpose(1, 2)?; // b,h,s,d
         let value = value
             .reshape((b_size, seq_len, self.kv_n_heads, ()))?
             .transpose(1, 2)?; // b,h,s,d
         let (key, value) = match &self.kv_cache {
             None => (key, value),
             Some((prev_k, prev_v)) => {
-                let k = Tensor::cat(&[prev_k, &key], 3)?;
+                let k = Tensor::cat(&[prev_k, &key], 2)?;
                 let v = Tensor::cat(&[prev_v, &value], 2)?;
                 (k, v)
             }
         };
         self.kv_cache = Some((key.clone(), value.clone()));
         let query = query.contiguous()?;
-        let key = crate::utils::repeat_kv(key, self.n_heads / self.kv_n_heads)?.contiguous()?;
+        if let (Some(slopes), 1, None) = (&self.alibi_slopes, seq_len, mask) {
+            let attn_output = candle_nn::alibi::alibi_decode_attention(
+                &query,
+                &key,
+                &value,
+                slopes,
+                self.softmax_scale as f32,
+            )?;
+            let attn_output = attn_output.transpose(1, 2)?.flatten_from(D::Minus2)?;
+            return attn_output.apply(&self.out_proj);
+        }
+        let key =
+            crate::utils::repeat_kv(key.t()?, self.n_heads / self.kv_n_heads)?.contiguous()?;
         let value = crate::utils::repeat_kv(value, self.n_heads / self.kv_n_heads)?.contiguous()?;
         let attn_weights = (query.matmul(&key)? * self.softmax_scale)?;
         let attn_bias = {
@@ -192,6 +212,10 @@ impl MPTBlock {
     }
 }
 
+fn alibi_slopes(cfg: &Config) -> Vec<f32> {
+    candle_nn::alibi::alibi_slopes(cfg.n_heads, cfg.attn_alibi_bias_max as f64)
+}
+
 pub(crate) fn build_alibi_bias(cfg: &Config) -> Result<Tensor> {
     let full = !cfg.is_causal();
     let seq_len = cfg.max_seq_len;
@@ -203,26 +227,7 @@ pub(crate) fn build_alibi_bias(cfg: &Config) -> Result<Tensor> {
     } else {
         alibi_bias.reshape((1, 1, 1, seq_len))?
     };
-    let mut n_heads2 = 1;
-    while n_heads2 < cfg.n_heads {
-        n_heads2 *= 2
-    }
-    let slopes = (1..=n_heads2)
-        .map(|v| 1f32 / 2f32.powf((v * cfg.attn_alibi_bias_max) as f32 / n_heads2 as f32))
-        .collect::<Vec<_>>();
-    let slopes = if n_heads2 == cfg.n_heads {
-        slopes
-    } else {
-        slopes
-            .iter()
-            .skip(1)
-            .step_by(2)
-            .chain(slopes.iter().step_by(2))
-            .take(cfg.n_heads)
-            .cloned()
-            .collect::<Vec<f32>>()
-    };
-    let slopes = Tensor::new(slopes, &Device::Cpu)?.reshape((1, (), 1, 1))?;
+    let slopes = Tensor::new(alibi_slopes(cfg), &Device::Cpu)?.reshape((1, (), 1, 1))?;
     alibi_bias.to_dtype(DType::F32)?.broadcast_mul(&slopes)
 }
 
@@ -276,10 +281,7 @@ impl Model {
 }
 
 pub(crate) fn get_mask(size: usize, device: &Device) -> Result<Tensor> {
-    let mask: Vec<_> = (0..size)
-        .flat_map(|i| (0..size).map(move |j| u8::from(j > i)))
-        .collect();
-    Tensor::from_slice(&mask, (size, size), device)
+    candle_nn::attention_mask::causal_mask(size, 0, device)?.eq(0u8)
 }
 
 pub(crate) fn masked_fill(on_false: &Tensor, mask: &Tensor, on_true: f32) -> Result<Tensor> {
@@ -0,0 +1,84 @@
+//! Options to return the intermediate states of a model forward pass.
+//!
+//! Models supporting these options expose a `forward_with_options` method returning a
+//! `ModelOutput`, the per-layer hidden states and attention probabilities can then be used for
+//! feature extraction or interpretability without modifying the model code.
+use candle::Tensor;
+
+/// Selects the intermediate values returned by `forward_with_options`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ForwardOptions {
+    /// Return the hidden states at the input of the first layer and at the output of each layer.
+    pub output_hidden_states: bool,
+    /// Return the attention probabilities of each layer, with shape
+    /// `(b_sz, num_heads, seq_len, kv_len)`.
+    pub output_attentions: bool,
+}
+
+impl ForwardOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_hidden_states(mut self, output_hidden_states: bool) -> Self {
+        self.output_hidden_states = output_hidden_states;
+        self
+    }
+
+    pub fn with_attentions(mut self, output_attentions: bool) -> Self {
+        self.output_attentions = output_attentions;
+        self
+    }
+}
+
+/// The output of `forward_with_options`.
+#[derive(Debug, Clone)]
+pub struct ModelOutput {
+    /// The same value as returned by the model `forward` method.
+    pub output: Tensor,
+    /// `num_layers + 1` tensors: the embeddings followed by the output of each layer. Set when
+    /// `output_hidden_states` is enabled.
+    pub hidden_states: Option<Vec<Tensor>>,
+    /// One tensor per layer, set when `output_attentions` is enabled.
+    pub attentions: Option<Vec<Tensor>>,
+}
+
+/// Accumulates the intermediate values while running the layers of a model.
+#[derive(Debug, Clone)]
+pub struct OutputCollector {
+    hidden_states: Option<Vec<Tensor>>,
+    attentions: Option<Vec<Tensor>>,
+}
+
+impl OutputCollector {
+    pub fn new(options: &ForwardOptions) -> Self {
+        Self {
+            hidden_states: options.output_hidden_states.then(Vec::new),
+            attentions: options.output_attentions.then(Vec::new),
+        }
+    }
+
+    pub fn output_attentions(&self) -> bool {
+        self.attentions.is_some()
+    }
+
+    pub fn push_hidden_state(&mut self, xs: &Tensor) {
+        if let Some(hs) = self.hidden_states.as_mut() {
+            hs.push(xs.clone())
+        }
+    }
+
+    pub fn push_attention(&mut self, attn: Option<Tensor>) {
+        if let (Some(attns), Some(attn)) = (self.attentions.as_mut(), attn) {
+            attns.push(attn)
+        }
+    }
+
+    pub fn finish(self, output: Tensor) -> ModelOutput {
+        ModelOutput {
+            output,
+            hidden_states: self.hidden_states,
+            attentions: self.attentions,
+        }
+    }
+}
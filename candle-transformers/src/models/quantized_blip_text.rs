@@ -1,7 +1,7 @@
 use crate::models::with_tracing::QMatMul;
 use crate::quantized_nn::{layer_norm, linear, Embedding, Linear};
 pub use crate::quantized_var_builder::VarBuilder;
-use candle::{Module, Result, Tensor, D};
+use candle::{DType, Module, Result, Tensor, D};
 use candle_nn::LayerNorm;
 
 pub type Config = super::blip_text::Config;
@@ -460,10 +460,10 @@ impl TextLMHeadModel {
         encoder_hidden_states: &Tensor,
     ) -> Result<Tensor> {
         let seq_len = input_ids.dim(1)?;
-        let mask: Vec<_> = (0..seq_len)
-            .flat_map(|i| (0..seq_len).map(move |j| if j > i { f32::NEG_INFINITY } else { 0f32 }))
-            .collect();
-        let mask = Tensor::from_vec(mask, (seq_len, seq_len), input_ids.device())?;
+        let mask = candle_nn::attention_mask::to_additive(
+            &candle_nn::attention_mask::causal_mask(seq_len, 0, input_ids.device())?,
+            DType::F32,
+        )?;
         let sequence_output = self.bert.forward(input_ids, encoder_hidden_states, &mask)?;
         let prediction_scores = self.cls.forward(&sequence_output)?;
         // return_logits is false so we don't discard the last sequence element.
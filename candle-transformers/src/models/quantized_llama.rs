@@ -1,9 +1,11 @@
 use std::collections::HashMap;
 
 use crate::quantized_nn::RmsNorm;
+use crate::utils::{DeviceMap, Prefetched, Prefetcher};
 use candle::quantized::QTensor;
 use candle::quantized::{ggml_file, gguf_file};
 use candle::{DType, Device, IndexOp, Result, Tensor};
+use candle_nn::kv_cache::StaticKvCache;
 use candle_nn::{Embedding, Module};
 
 pub const MAX_SEQ_LEN: usize = 4096;
@@ -26,6 +28,19 @@ impl QMatMul {
         let _enter = self.span.enter();
         self.inner.forward(xs)
     }
+
+    fn prefetch(&self, prefetcher: &Prefetcher) -> Result<Self> {
+        use candle::quantized::QMatMul as Q;
+        let inner = match &self.inner {
+            Q::QTensor(qtensor) => Q::QTensor(prefetcher.qtensor(qtensor)?),
+            Q::Tensor(xs) => Q::Tensor(prefetcher.tensor(xs)?),
+            Q::TensorF16(xs) => Q::TensorF16(prefetcher.tensor(xs)?),
+        };
+        Ok(Self {
+            inner,
+            span: self.span.clone(),
+        })
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -44,6 +59,16 @@ impl Module for Mlp {
     }
 }
 
+impl Mlp {
+    fn prefetch(&self, prefetcher: &Prefetcher) -> Result<Self> {
+        Ok(Self {
+            feed_forward_w1: self.feed_forward_w1.prefetch(prefetcher)?,
+            feed_forward_w2: self.feed_forward_w2.prefetch(prefetcher)?,
+            feed_forward_w3: self.feed_forward_w3.prefetch(prefetcher)?,
+        })
+    }
+}
+
 #[derive(Debug, Clone)]
 enum MlpOrMoe {
     Mlp(Mlp),
@@ -124,6 +149,26 @@ impl Module for MlpOrMoe {
     }
 }
 
+impl MlpOrMoe {
+    fn prefetch(&self, prefetcher: &Prefetcher) -> Result<Self> {
+        match self {
+            Self::Mlp(mlp) => Ok(Self::Mlp(mlp.prefetch(prefetcher)?)),
+            Self::MoE {
+                n_expert_used,
+                feed_forward_gate_inp,
+                experts,
+            } => Ok(Self::MoE {
+                n_expert_used: *n_expert_used,
+                feed_forward_gate_inp: feed_forward_gate_inp.prefetch(prefetcher)?,
+                experts: experts
+                    .iter()
+                    .map(|e| e.prefetch(prefetcher))
+                    .collect::<Result<Vec<_>>>()?,
+            }),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 struct LayerWeights {
     attention_wq: QMatMul,
@@ -140,11 +185,23 @@ struct LayerWeights {
     sin: Tensor,
     neg_inf: Tensor,
     kv_cache: Option<(Tensor, Tensor)>,
+    static_kv_cache: Option<StaticKvCache>,
+    // The device on which the block runs, the weights live on the cpu when they are prefetched.
+    device: Device,
+    prefetched: bool,
     span_attn: tracing::Span,
     span_rot: tracing::Span,
     span_mlp: tracing::Span,
 }
 
+// The positions of the tokens processed by a block, consecutive from an offset with the growing
+// kv cache or given by a u32 tensor with the static one.
+#[derive(Debug, Clone, Copy)]
+enum Positions<'a> {
+    Offset(usize),
+    Tensor(&'a Tensor),
+}
+
 fn masked_fill(on_false: &Tensor, mask: &Tensor, on_true: &Tensor) -> Result<Tensor> {
     let shape = mask.shape();
     let m = mask.where_cond(&on_true.broadcast_as(shape.dims())?, on_false)?;
@@ -152,11 +209,56 @@ fn masked_fill(on_false: &Tensor, mask: &Tensor, on_true: &Tensor) -> Result<Ten
 }
 
 impl LayerWeights {
-    fn apply_rotary_emb(&self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
+    // A copy of the block with its weights on the prefetcher device.
+    fn prefetch(&self, prefetcher: &Prefetcher) -> Result<Prefetched<Self>> {
+        let layer = Self {
+            attention_wq: self.attention_wq.prefetch(prefetcher)?,
+            attention_wk: self.attention_wk.prefetch(prefetcher)?,
+            attention_wv: self.attention_wv.prefetch(prefetcher)?,
+            attention_wo: self.attention_wo.prefetch(prefetcher)?,
+            attention_norm: self.attention_norm.prefetch(prefetcher)?,
+            mlp_or_moe: self.mlp_or_moe.prefetch(prefetcher)?,
+            ffn_norm: self.ffn_norm.prefetch(prefetcher)?,
+            kv_cache: None,
+            static_kv_cache: None,
+            prefetched: false,
+            ..self.clone()
+        };
+        prefetcher.ready(layer)
+    }
+
+    fn forward(
+        &mut self,
+        x: &Tensor,
+        mask: Option<&Tensor>,
+        positions: Positions,
+    ) -> Result<Tensor> {
+        let residual = x;
+        let x = self.attention_norm.forward(x)?;
+        let attn = self.forward_attn(&x, mask, positions)?;
+        let x = (attn + residual)?;
+
+        // MLP
+        let _enter = self.span_mlp.enter();
+        let residual = &x;
+        let x = self.ffn_norm.forward(&x)?;
+        let x = self.mlp_or_moe.forward(&x)?;
+        x + residual
+    }
+
+    fn apply_rotary_emb(&self, x: &Tensor, positions: Positions) -> Result<Tensor> {
         let _enter = self.span_rot.enter();
         let (_b_sz, _n_head, seq_len, _n_embd) = x.dims4()?;
-        let cos = self.cos.narrow(0, index_pos, seq_len)?;
-        let sin = self.sin.narrow(0, index_pos, seq_len)?;
+        let (cos, sin) = match positions {
+            Positions::Offset(index_pos) => (
+                self.cos.narrow(0, index_pos, seq_len)?,
+                self.sin.narrow(0, index_pos, seq_len)?,
+            ),
+            Positions::Tensor(positions) => (
+                self.cos.index_select(positions, 0)?,
+                self.sin.index_select(positions, 0)?,
+            ),
+        };
         // The call to contiguous below is only necessary when processing the prompt.
         // When the seq_len is 1 in the inference loop, this is a no-op.
         candle_nn::rotary_emb::rope_i(&x.contiguous()?, &cos, &sin)
@@ -166,7 +268,7 @@ impl LayerWeights {
         &mut self,
         x: &Tensor,
         mask: Option<&Tensor>,
-        index_pos: usize,
+        positions: Positions,
     ) -> Result<Tensor> {
         let _enter = self.span_attn.enter();
         let (b_sz, seq_len, n_embd) = x.dims3()?;
@@ -188,22 +290,31 @@ impl LayerWeights {
             // impact on performance.
             .contiguous()?;
 
-        let q = self.apply_rotary_emb(&q, index_pos)?;
-        let k = self.apply_rotary_emb(&k, index_pos)?;
+        let q = self.apply_rotary_emb(&q, positions)?;
+        let k = self.apply_rotary_emb(&k, positions)?;
 
-        let (k, v) = match &self.kv_cache {
-            None => (k, v),
-            Some((k_cache, v_cache)) => {
-                if index_pos == 0 {
-                    (k, v)
-                } else {
-                    let k = Tensor::cat(&[k_cache, &k], 2)?;
-                    let v = Tensor::cat(&[v_cache, &v], 2)?;
-                    (k, v)
-                }
+        let (k, v) = match (positions, &mut self.static_kv_cache) {
+            (Positions::Tensor(positions), Some(cache)) => cache.append(&k, &v, positions)?,
+            (Positions::Tensor(_), None) => {
+                candle::bail!("positions tensors require the static kv cache")
+            }
+            (Positions::Offset(index_pos), _) => {
+                let (k, v) = match &self.kv_cache {
+                    None => (k, v),
+                    Some((k_cache, v_cache)) => {
+                        if index_pos == 0 {
+                            (k, v)
+                        } else {
+                            let k = Tensor::cat(&[k_cache, &k], 2)?;
+                            let v = Tensor::cat(&[v_cache, &v], 2)?;
+                            (k, v)
+                        }
+                    }
+                };
+                self.kv_cache = Some((k.clone(), v.clone()));
+                (k, v)
             }
         };
-        self.kv_cache = Some((k.clone(), v.clone()));
 
         // Support for MQA, useful for 70B models and mistral.
         let k = crate::utils::repeat_kv(k, self.n_head / self.n_kv_head)?;
@@ -232,11 +343,17 @@ pub struct ModelWeights {
     layers: Vec<LayerWeights>,
     norm: RmsNorm,
     output: QMatMul,
+    output_device: Device,
     masks: HashMap<usize, Tensor>,
+    prefetcher: Option<Prefetcher>,
     span: tracing::Span,
     span_output: tracing::Span,
 }
 
+fn causal_mask(t: usize, device: &Device) -> Result<Tensor> {
+    candle_nn::attention_mask::causal_mask(t, 0, device)?.eq(0u8)
+}
+
 fn precomput_freqs_cis(
     head_dim: usize,
     freq_base: f32,
@@ -302,6 +419,9 @@ impl ModelWeights {
                 sin: sin.clone(),
                 neg_inf: neg_inf.clone(),
                 kv_cache: None,
+                static_kv_cache: None,
+                device: ct.device.clone(),
+                prefetched: false,
                 span_attn,
                 span_rot,
                 span_mlp,
@@ -314,7 +434,9 @@ impl ModelWeights {
             layers,
             norm,
             output: QMatMul::from_qtensor(output)?,
+            output_device: ct.device.clone(),
             masks: HashMap::new(),
+            prefetcher: None,
             span,
             span_output,
         })
@@ -324,6 +446,16 @@ impl ModelWeights {
         ct: gguf_file::Content,
         reader: &mut R,
         device: &Device,
+    ) -> Result<Self> {
+        Self::from_gguf_with_device_map(ct, reader, &DeviceMap::all(device))
+    }
+
+    /// Loads the model with its blocks split between an accelerator and the cpu according to
+    /// `device_map`.
+    pub fn from_gguf_with_device_map<R: std::io::Seek + std::io::Read>(
+        ct: gguf_file::Content,
+        reader: &mut R,
+        device_map: &DeviceMap,
     ) -> Result<Self> {
         let md_get = |s: &str| match ct.metadata.get(s) {
             None => candle::bail!("cannot find {s} in metadata"),
@@ -348,19 +480,33 @@ impl ModelWeights {
         let rope_freq_base = md_get("llama.rope.freq_base")
             .and_then(|m| m.to_f32())
             .unwrap_or(10000f32);
-        let (cos, sin) = precomput_freqs_cis(rope_dim, rope_freq_base, device)?;
-        let neg_inf = Tensor::new(f32::NEG_INFINITY, device)?;
+        // The rotary embeddings are shared by all the layers on the same device.
+        let mut ropes: Vec<(Device, (Tensor, Tensor, Tensor))> = vec![];
+        let mut rope = |device: &Device| -> Result<(Tensor, Tensor, Tensor)> {
+            if let Some((_, rope)) = ropes.iter().find(|(d, _)| d.same_device(device)) {
+                return Ok(rope.clone());
+            }
+            let (cos, sin) = precomput_freqs_cis(rope_dim, rope_freq_base, device)?;
+            let neg_inf = Tensor::new(f32::NEG_INFINITY, device)?;
+            ropes.push((device.clone(), (cos.clone(), sin.clone(), neg_inf.clone())));
+            Ok((cos, sin, neg_inf))
+        };
 
+        let device = device_map.io_device(block_count);
         let tok_embeddings = ct.tensor(reader, "token_embd.weight", device)?;
         let tok_embeddings = tok_embeddings.dequantize(device)?;
+        let output_device = device_map.output_device(block_count);
         let norm = RmsNorm::from_qtensor(
-            ct.tensor(reader, "output_norm.weight", device)?,
+            ct.tensor(reader, "output_norm.weight", output_device)?,
             rms_norm_eps,
         )?;
-        let output = ct.tensor(reader, "output.weight", device)?;
+        let output = ct.tensor(reader, "output.weight", output_device)?;
         let mut layers = Vec::with_capacity(block_count);
         for layer_idx in 0..block_count {
             let prefix = format!("blk.{layer_idx}");
+            let device = device_map.layer_device(layer_idx);
+            let compute_device = device_map.compute_device(layer_idx);
+            let (cos, sin, neg_inf) = rope(compute_device)?;
             let attention_wq = ct.tensor(reader, &format!("{prefix}.attn_q.weight"), device)?;
             let attention_wk = ct.tensor(reader, &format!("{prefix}.attn_k.weight"), device)?;
             let attention_wv = ct.tensor(reader, &format!("{prefix}.attn_v.weight"), device)?;
@@ -418,15 +564,23 @@ impl ModelWeights {
                 n_head: head_count,
                 n_kv_head: head_count_kv,
                 head_dim: embedding_length / head_count,
-                cos: cos.clone(),
-                sin: sin.clone(),
-                neg_inf: neg_inf.clone(),
+                cos,
+                sin,
+                neg_inf,
                 kv_cache: None,
+                static_kv_cache: None,
+                device: compute_device.clone(),
+                prefetched: device_map.is_prefetched(layer_idx),
                 span_attn,
                 span_rot,
                 span_mlp,
             })
         }
+        let prefetcher = if layers.iter().any(|l| l.prefetched) {
+            Some(Prefetcher::new(device_map.compute_device(block_count))?)
+        } else {
+            None
+        };
         let span = tracing::span!(tracing::Level::TRACE, "model");
         let span_output = tracing::span!(tracing::Level::TRACE, "output");
         Ok(Self {
@@ -434,52 +588,224 @@ impl ModelWeights {
             layers,
             norm,
             output: QMatMul::from_qtensor(output)?,
+            output_device: output_device.clone(),
             masks: HashMap::new(),
+            prefetcher,
             span,
             span_output,
         })
     }
 
+    fn prefetch(&self, layer_idx: usize) -> Result<Option<Prefetched<LayerWeights>>> {
+        match (&self.prefetcher, self.layers.get(layer_idx)) {
+            (Some(prefetcher), Some(layer)) if layer.prefetched => {
+                Ok(Some(layer.prefetch(prefetcher)?))
+            }
+            _ => Ok(None),
+        }
+    }
+
     fn mask(&mut self, t: usize, device: &Device) -> Result<Tensor> {
         if let Some(mask) = self.masks.get(&t) {
             Ok(mask.clone())
         } else {
-            let mask: Vec<_> = (0..t)
-                .flat_map(|i| (0..t).map(move |j| u8::from(j > i)))
-                .collect();
-            let mask = Tensor::from_slice(&mask, (t, t), device)?;
+            let mask = causal_mask(t, device)?;
             self.masks.insert(t, mask.clone());
             Ok(mask)
         }
     }
 
     pub fn forward(&mut self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
+        self.forward_with(x, Positions::Offset(index_pos))
+    }
+
+    /// Uses a kv cache of `max_seq_len` slots in each block, written at the positions passed to
+    /// `forward_positions`.
+    pub fn with_static_kv_cache(mut self, max_seq_len: usize) -> Self {
+        for layer in self.layers.iter_mut() {
+            layer.static_kv_cache = Some(StaticKvCache::new(2, max_seq_len))
+        }
+        self
+    }
+
+    /// Runs the model on tokens at the positions in the u32 tensor `positions`, which requires
+    /// the static kv cache. A step does not depend on host side values so that the decoding can
+    /// be captured as a CUDA graph with [`candle_nn::GraphedModule`], the cache buffers are
+    /// allocated on the first call.
+    pub fn forward_positions(&mut self, x: &Tensor, positions: &Tensor) -> Result<Tensor> {
+        self.forward_with(x, Positions::Tensor(positions))
+    }
+
+    fn forward_with(&mut self, x: &Tensor, positions: Positions) -> Result<Tensor> {
         let (_b_sz, seq_len) = x.dims2()?;
-        let mask = if seq_len == 1 {
-            None
-        } else {
-            Some(self.mask(seq_len, x.device())?)
+        let device = self.tok_embeddings.embeddings().device().clone();
+        let x = x.to_device(&device)?;
+        let mut positions_tensor = match positions {
+            Positions::Tensor(positions) => Some(positions.to_device(&device)?),
+            Positions::Offset(_) => None,
+        };
+        let mut mask = match &positions_tensor {
+            Some(positions) => {
+                let cache = self.layers.first_mut();
+                match cache.and_then(|l| l.static_kv_cache.as_mut()) {
+                    None => candle::bail!("positions tensors require the static kv cache"),
+                    Some(cache) => Some(cache.mask(positions)?),
+                }
+            }
+            None if seq_len == 1 => None,
+            None => Some(self.mask(seq_len, &device)?),
         };
         let _enter = self.span.enter();
-        let mut layer_in = self.tok_embeddings.forward(x)?;
-        for layer in self.layers.iter_mut() {
-            let x = layer_in;
-            let residual = &x;
-            let x = layer.attention_norm.forward(&x)?;
-            let attn = layer.forward_attn(&x, mask.as_ref(), index_pos)?;
-            let x = (attn + residual)?;
-
-            // MLP
-            let _enter = layer.span_mlp.enter();
-            let residual = &x;
-            let x = layer.ffn_norm.forward(&x)?;
-            let x = layer.mlp_or_moe.forward(&x)?;
-            let x = (x + residual)?;
-            layer_in = x
+        let mut layer_in = self.tok_embeddings.forward(&x)?;
+        let mut prefetched = self.prefetch(0)?;
+        for layer_idx in 0..self.layers.len() {
+            let current = prefetched.take();
+            let layer = &mut self.layers[layer_idx];
+            // Move the activations when crossing a device boundary.
+            if !layer_in.device().same_device(&layer.device) {
+                layer_in = layer_in.to_device(&layer.device)?;
+                mask = mask.map(|m| m.to_device(&layer.device)).transpose()?;
+                positions_tensor = positions_tensor
+                    .map(|p| p.to_device(&layer.device))
+                    .transpose()?;
+            }
+            let layer_positions = match &positions_tensor {
+                Some(positions) => Positions::Tensor(positions),
+                None => positions,
+            };
+            layer_in = match current {
+                None => layer.forward(&layer_in, mask.as_ref(), layer_positions)?,
+                Some(current) => {
+                    let mut current = current.wait()?;
+                    current.kv_cache = layer.kv_cache.take();
+                    current.static_kv_cache = layer.static_kv_cache.take();
+                    let xs = current.forward(&layer_in, mask.as_ref(), layer_positions)?;
+                    layer.kv_cache = current.kv_cache.take();
+                    layer.static_kv_cache = current.static_kv_cache.take();
+                    xs
+                }
+            };
+            // Queue the copy of the next block weights once the kernels of this block have been
+            // queued, the transfer then overlaps with their execution.
+            prefetched = self.prefetch(layer_idx + 1)?;
         }
+        let layer_in = layer_in.to_device(&self.output_device)?;
         let x = self.norm.forward(&layer_in)?;
         let x = x.i((.., seq_len - 1, ..))?;
         let _enter = self.span_output.enter();
         self.output.forward(&x)
     }
+
+    /// Splits the model into the pipeline stages of `device_map`, which has to be the map used
+    /// to load the model. The first stage holds the token embeddings and the last one the output
+    /// head, see [`candle_nn::pipeline`].
+    pub fn into_pipeline_stages(self, device_map: &DeviceMap) -> Result<Vec<ModelStage>> {
+        if self.prefetcher.is_some() {
+            candle::bail!("models with prefetched blocks cannot be split into pipeline stages")
+        }
+        let stages = device_map.stages(self.layers.len());
+        let n_stages = stages.len();
+        let mut layers = self.layers.into_iter();
+        let mut tok_embeddings = Some(self.tok_embeddings);
+        let mut head = Some((self.norm, self.output, self.output_device));
+        let mut pipeline_stages = Vec::with_capacity(n_stages);
+        for (stage_idx, (device, range)) in stages.into_iter().enumerate() {
+            let layers = layers.by_ref().take(range.len()).collect::<Vec<_>>();
+            for (layer_idx, layer) in range.zip(layers.iter()) {
+                if !layer.device.same_device(&device) {
+                    candle::bail!(
+                        "block {layer_idx} is on {:?} but its pipeline stage on {device:?}",
+                        layer.device
+                    )
+                }
+            }
+            pipeline_stages.push(ModelStage {
+                tok_embeddings: if stage_idx == 0 {
+                    tok_embeddings.take()
+                } else {
+                    None
+                },
+                layers,
+                head: if stage_idx + 1 == n_stages {
+                    head.take()
+                } else {
+                    None
+                },
+                device,
+                masks: HashMap::new(),
+                span: tracing::span!(tracing::Level::TRACE, "stage", idx = stage_idx),
+            })
+        }
+        Ok(pipeline_stages)
+    }
+}
+
+/// A range of consecutive blocks of a [`ModelWeights`] running on a single device, see
+/// [`ModelWeights::into_pipeline_stages`].
+#[derive(Debug, Clone)]
+pub struct ModelStage {
+    tok_embeddings: Option<Embedding>,
+    layers: Vec<LayerWeights>,
+    head: Option<(RmsNorm, QMatMul, Device)>,
+    device: Device,
+    masks: HashMap<usize, Tensor>,
+    span: tracing::Span,
+}
+
+impl ModelStage {
+    pub fn n_layers(&self) -> usize {
+        self.layers.len()
+    }
+}
+
+impl candle_nn::PipelineStage for ModelStage {
+    /// The keys and values of each block of the stage.
+    type Cache = Vec<Option<(Tensor, Tensor)>>;
+
+    fn device(&self) -> &Device {
+        &self.device
+    }
+
+    fn new_cache(&self) -> Result<Self::Cache> {
+        Ok(vec![None; self.layers.len()])
+    }
+
+    fn forward(
+        &mut self,
+        xs: &Tensor,
+        index_pos: usize,
+        cache: &mut Self::Cache,
+    ) -> Result<Tensor> {
+        let _enter = self.span.enter();
+        let mut xs = match &self.tok_embeddings {
+            None => xs.clone(),
+            Some(tok_embeddings) => {
+                let xs = xs.to_device(tok_embeddings.embeddings().device())?;
+                tok_embeddings.forward(&xs)?.to_device(&self.device)?
+            }
+        };
+        let seq_len = xs.dim(1)?;
+        let mask = if seq_len == 1 {
+            None
+        } else if let Some(mask) = self.masks.get(&seq_len) {
+            Some(mask.clone())
+        } else {
+            let mask = causal_mask(seq_len, &self.device)?;
+            self.masks.insert(seq_len, mask.clone());
+            Some(mask)
+        };
+        for (layer, kv_cache) in self.layers.iter_mut().zip(cache.iter_mut()) {
+            layer.kv_cache = kv_cache.take();
+            let ys = layer.forward(&xs, mask.as_ref(), Positions::Offset(index_pos));
+            *kv_cache = layer.kv_cache.take();
+            xs = ys?;
+        }
+        match &self.head {
+            None => Ok(xs),
+            Some((norm, output, device)) => {
+                let xs = norm.forward(&xs.to_device(device)?)?;
+                output.forward(&xs.i((.., seq_len - 1, ..))?)
+            }
+        }
+    }
 }
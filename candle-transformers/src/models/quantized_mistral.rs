@@ -1,6 +1,6 @@
 use crate::quantized_nn::{linear_no_bias, Embedding, Linear, RmsNorm};
 pub use crate::quantized_var_builder::VarBuilder;
-use candle::{DType, Device, Module, Result, Tensor, D};
+use candle::{DType, Device, Module, Result, Tensor};
 use candle_nn::Activation;
 use std::sync::Arc;
 
@@ -271,27 +271,21 @@ impl Model {
         tgt_len: usize,
         seqlen_offset: usize,
     ) -> Result<Tensor> {
-        let sliding_window = self.sliding_window.unwrap_or(tgt_len + 1);
-        let mask: Vec<_> = (0..tgt_len)
-            .flat_map(|i| {
-                (0..tgt_len).map(move |j| {
-                    if i < j || j + sliding_window < i {
-                        f32::NEG_INFINITY
-                    } else {
-                        0.
-                    }
-                })
-            })
-            .collect();
-        let mask = Tensor::from_slice(&mask, (tgt_len, tgt_len), &self.device)?;
-        let mask = if seqlen_offset > 0 {
-            let mask0 = Tensor::zeros((tgt_len, seqlen_offset), DType::F32, &self.device)?;
-            Tensor::cat(&[&mask0, &mask], D::Minus1)?
-        } else {
-            mask
+        let mask = match self.sliding_window {
+            Some(sliding_window) => candle_nn::attention_mask::sliding_window_mask(
+                tgt_len,
+                seqlen_offset,
+                sliding_window,
+                &self.device,
+            )?,
+            None => candle_nn::attention_mask::causal_mask(tgt_len, seqlen_offset, &self.device)?,
         };
-        mask.expand((1, 1, tgt_len, tgt_len + seqlen_offset))?
-            .to_dtype(DType::F32)
+        candle_nn::attention_mask::to_additive(&mask, DType::F32)?.expand((
+            1,
+            1,
+            tgt_len,
+            tgt_len + seqlen_offset,
+        ))
     }
 
     pub fn forward(&mut self, input_ids: &Tensor, seqlen_offset: usize) -> Result<Tensor> {
@@ -5,6 +5,7 @@ use crate::models::t5::{deserialize_feed_forward_proj_activation, ActivationWith
 use crate::models::with_tracing::QMatMul;
 use crate::quantized_nn::Embedding;
 pub use crate::quantized_var_builder::VarBuilder;
+use crate::utils::CrossAttnKv;
 use candle::{DType, Device, Module, Result, Tensor, D};
 use candle_nn::Activation;
 use serde::Deserialize;
@@ -27,10 +28,7 @@ fn default_tie_word_embeddings() -> bool {
 }
 
 fn get_mask(size: usize, device: &Device) -> Result<Tensor> {
-    let mask: Vec<_> = (0..size)
-        .flat_map(|i| (0..size).map(move |j| u8::from(j > i)))
-        .collect();
-    Tensor::from_slice(&mask, (size, size), device)
+    candle_nn::attention_mask::causal_mask(size, 0, device)?.eq(0u8)
 }
 
 fn masked_fill(on_false: &Tensor, mask: &Tensor, on_true: f32) -> Result<Tensor> {
@@ -304,37 +302,45 @@ impl T5Attention {
         })
     }
 
+    // Projects the keys and values to shape (b_sz, n_heads, kv_len, d_kv).
+    fn project_kv(&self, kv_input: &Tensor) -> Result<(Tensor, Tensor)> {
+        let (b_sz, kv_len) = (kv_input.dim(0)?, kv_input.dim(1)?);
+        let k = self.k.forward(kv_input)?;
+        let v = self.v.forward(kv_input)?;
+        let k = k
+            .reshape((b_sz, kv_len, self.n_heads, self.d_kv))?
+            .transpose(1, 2)?;
+        let v = v
+            .reshape((b_sz, kv_len, self.n_heads, self.d_kv))?
+            .transpose(1, 2)?;
+        Ok((k, v))
+    }
+
     fn forward(
         &mut self,
         xs: &Tensor,
         position_bias: Option<&Tensor>,
         key_value_states: Option<&Tensor>,
+        cross_kv: Option<&(Tensor, Tensor)>,
         mask: Option<&Tensor>,
     ) -> Result<(Tensor, Option<Tensor>)> {
-        // Performs Self-attention (if key_value_states is None) or attention
-        // over source sentence (provided by key_value_states).
+        // Performs Self-attention (if key_value_states and cross_kv are None) or attention
+        // over source sentence (provided by key_value_states or precomputed in cross_kv).
         let _enter = self.span.enter();
-        let kv_input = match key_value_states {
-            None => xs,
-            Some(key_value_states) => key_value_states,
-        };
+        let is_self_attn = key_value_states.is_none() && cross_kv.is_none();
         let (b_sz, q_len) = (xs.dim(0)?, xs.dim(1)?);
-        let kv_len = kv_input.dim(1)?;
         let q = self.q.forward(xs)?;
-        let k = self.k.forward(kv_input)?;
-        let v = self.v.forward(kv_input)?;
         let q = q
             .reshape((b_sz, q_len, self.n_heads, self.d_kv))?
             .transpose(1, 2)?
             .contiguous()?;
-        let mut k = k
-            .reshape((b_sz, kv_len, self.n_heads, self.d_kv))?
-            .transpose(1, 2)?;
-        let mut v = v
-            .reshape((b_sz, kv_len, self.n_heads, self.d_kv))?
-            .transpose(1, 2)?;
+        let (mut k, mut v) = match (cross_kv, key_value_states) {
+            (Some((k, v)), _) => (k.clone(), v.clone()),
+            (None, Some(key_value_states)) => self.project_kv(key_value_states)?,
+            (None, None) => self.project_kv(xs)?,
+        };
 
-        if self.use_cache && key_value_states.is_none() {
+        if self.use_cache && is_self_attn {
             let _enter = self.span_cache.enter();
             if let Some((kv_cache_k, kv_cache_v)) = &self.kv_cache {
                 k = Tensor::cat(&[kv_cache_k, &k], 2)?;
@@ -435,6 +441,13 @@ impl T5Attention {
     fn clear_kv_cache(&mut self) {
         self.kv_cache = None
     }
+
+    fn reorder_kv_cache(&mut self, indexes: &Tensor) -> Result<()> {
+        if let Some((k, v)) = &self.kv_cache {
+            self.kv_cache = Some((k.index_select(indexes, 0)?, v.index_select(indexes, 0)?))
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -466,7 +479,7 @@ impl T5LayerSelfAttention {
         let normed_xs = self.layer_norm.forward(xs)?;
         let (ys, position_bias) =
             self.self_attention
-                .forward(&normed_xs, position_bias, None, mask)?;
+                .forward(&normed_xs, position_bias, None, None, mask)?;
         let ys = (xs + ys)?;
         Ok((ys, position_bias))
     }
@@ -474,6 +487,10 @@ impl T5LayerSelfAttention {
     fn clear_kv_cache(&mut self) {
         self.self_attention.clear_kv_cache()
     }
+
+    fn reorder_kv_cache(&mut self, indexes: &Tensor) -> Result<()> {
+        self.self_attention.reorder_kv_cache(indexes)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -499,14 +516,19 @@ impl T5LayerCrossAttention {
         &mut self,
         hidden_states: &Tensor,
         position_bias: Option<&Tensor>,
-        key_value_states: &Tensor,
+        key_value_states: Option<&Tensor>,
+        cross_kv: Option<&(Tensor, Tensor)>,
     ) -> Result<(Tensor, Option<Tensor>)> {
         let _enter = self.span.enter();
+        if key_value_states.is_none() && cross_kv.is_none() {
+            candle::bail!("cross-attention requires the encoder output or its kv")
+        }
         let normed_hidden_states = self.layer_norm.forward(hidden_states)?;
         let (ys, position_bias) = self.cross_attention.forward(
             &normed_hidden_states,
             position_bias,
-            Some(key_value_states),
+            key_value_states,
+            cross_kv,
             None,
         )?;
         let ys = (hidden_states + ys)?;
@@ -516,6 +538,11 @@ impl T5LayerCrossAttention {
     fn clear_kv_cache(&mut self) {
         self.cross_attention.clear_kv_cache()
     }
+
+    fn cross_kv(&self, encoder_output: &Tensor) -> Result<(Tensor, Tensor)> {
+        let (k, v) = self.cross_attention.project_kv(encoder_output)?;
+        Ok((k.contiguous()?, v.contiguous()?))
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -556,6 +583,7 @@ impl T5Block {
         xs: &Tensor,
         position_bias: Option<&Tensor>,
         encoder_hidden_states: Option<&Tensor>,
+        cross_kv: Option<&(Tensor, Tensor)>,
     ) -> Result<(Tensor, Option<Tensor>)> {
         let _enter = self.span.enter();
         // TODO: Cache masks
@@ -575,7 +603,7 @@ impl T5Block {
         let (mut xs, position_bias) = self.self_attn.forward(xs, position_bias, mask.as_ref())?;
         // TODO: clamp for f16?
         if let Some(cross_attn) = &mut self.cross_attn {
-            (xs, _) = cross_attn.forward(&xs, None, encoder_hidden_states.unwrap())?;
+            (xs, _) = cross_attn.forward(&xs, None, encoder_hidden_states, cross_kv)?;
             // TODO: clamp for f16?
         }
         let xs = self.ff.forward(&xs)?;
@@ -587,6 +615,10 @@ impl T5Block {
         self.self_attn.clear_kv_cache();
         self.cross_attn.iter_mut().for_each(|c| c.clear_kv_cache());
     }
+
+    fn reorder_kv_cache(&mut self, indexes: &Tensor) -> Result<()> {
+        self.self_attn.reorder_kv_cache(indexes)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -619,16 +651,19 @@ impl T5Stack {
         &mut self,
         input_ids: &Tensor,
         encoder_hidden_states: Option<&Tensor>,
+        cross_kv: Option<&CrossAttnKv>,
     ) -> Result<Tensor> {
         let _enter = self.span.enter();
         let input_embeds = self.shared.as_ref().forward(input_ids)?;
         let mut hidden_states = input_embeds;
         let mut position_bias = None;
-        for block in self.block.iter_mut() {
+        for (i, block) in self.block.iter_mut().enumerate() {
+            let cross_kv = cross_kv.map(|c| c.layer(i)).transpose()?;
             (hidden_states, position_bias) = block.forward(
                 &hidden_states,
                 position_bias.as_ref(),
                 encoder_hidden_states,
+                cross_kv,
             )?
         }
         self.final_layer_norm.forward(&hidden_states)
@@ -637,6 +672,24 @@ impl T5Stack {
     fn clear_kv_cache(&mut self) {
         self.block.iter_mut().for_each(|b| b.clear_kv_cache())
     }
+
+    fn reorder_kv_cache(&mut self, indexes: &Tensor) -> Result<()> {
+        self.block
+            .iter_mut()
+            .try_for_each(|b| b.reorder_kv_cache(indexes))
+    }
+
+    fn cross_kv(&self, encoder_output: &Tensor) -> Result<CrossAttnKv> {
+        let kvs = self
+            .block
+            .iter()
+            .map(|b| match &b.cross_attn {
+                None => candle::bail!("cross-attention kv requires a decoder"),
+                Some(cross_attn) => cross_attn.cross_kv(encoder_output),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(CrossAttnKv::new(kvs))
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -665,7 +718,7 @@ impl T5EncoderModel {
 
     pub fn forward(&mut self, input_ids: &Tensor) -> Result<Tensor> {
         let _enter = self.span.enter();
-        self.encoder.forward(input_ids, None)
+        self.encoder.forward(input_ids, None, None)
     }
 
     pub fn device(&self) -> &Device {
@@ -735,7 +788,7 @@ impl T5ForConditionalGeneration {
     }
 
     pub fn encode(&mut self, input_ids: &Tensor) -> Result<Tensor> {
-        self.encoder.forward(input_ids, None)
+        self.encoder.forward(input_ids, None, None)
     }
 
     pub fn decode(
@@ -746,8 +799,31 @@ impl T5ForConditionalGeneration {
         let _enter = self.span_decode.enter();
         let decoder_output = self
             .decoder
-            .forward(decoder_input_ids, Some(encoder_output))?;
+            .forward(decoder_input_ids, Some(encoder_output), None)?;
+        self.lm_head(&decoder_output)
+    }
+
+    /// Computes the keys and values of the decoder cross-attention layers for an encoder output.
+    /// These can then be used for all the decoding steps with `decode_with_cross_kv` instead of
+    /// projecting the encoder output again at each step.
+    pub fn cross_kv(&self, encoder_output: &Tensor) -> Result<CrossAttnKv> {
+        self.decoder.cross_kv(encoder_output)
+    }
+
+    /// Same as `decode` but using the precomputed cross-attention keys and values.
+    pub fn decode_with_cross_kv(
+        &mut self,
+        decoder_input_ids: &Tensor,
+        cross_kv: &CrossAttnKv,
+    ) -> Result<Tensor> {
+        let _enter = self.span_decode.enter();
+        let decoder_output = self
+            .decoder
+            .forward(decoder_input_ids, None, Some(cross_kv))?;
+        self.lm_head(&decoder_output)
+    }
 
+    fn lm_head(&self, decoder_output: &Tensor) -> Result<Tensor> {
         let scaling_factor = if self.tie_word_embeddings {
             // Rescale output before projecting on vocab
             // See https://github.com/tensorflow/mesh/blob/fa19d69eafc9a482aff0b59ddd96b025c0cb207d/mesh_tensorflow/transformer/transformer.py#L586
@@ -782,4 +858,10 @@ impl T5ForConditionalGeneration {
         self.encoder.clear_kv_cache();
         self.decoder.clear_kv_cache();
     }
+
+    /// Selects the batch elements of the decoder kv cache at `indexes`, a u32 tensor, e.g. to
+    /// follow the beams selected at a beam search step.
+    pub fn reorder_kv_cache(&mut self, indexes: &Tensor) -> Result<()> {
+        self.decoder.reorder_kv_cache(indexes)
+    }
 }
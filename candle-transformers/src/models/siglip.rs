@@ -0,0 +1,295 @@
+//! SigLIP vision tower
+//!
+//! SigLIP is a CLIP-like image encoder trained with a sigmoid loss. Its vision transformer is
+//! used as the image encoder of several vision-language models (PaliGemma, Idefics, Moondream2,
+//! LLaVA variants). Only the vision side is implemented here, the patch features are projected
+//! and injected in the language model by `crate::models::llava::LLaVA::load_siglip`.
+//!
+//! https://arxiv.org/abs/2303.15343
+//! https://github.com/huggingface/transformers/blob/main/src/transformers/models/siglip/modeling_siglip.py
+
+use candle::{DType, Result, Tensor};
+use candle_nn::{Activation, Conv2dConfig, Module, VarBuilder};
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct VisionConfig {
+    pub hidden_size: usize,
+    pub intermediate_size: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    #[serde(default = "default_num_channels")]
+    pub num_channels: usize,
+    pub image_size: usize,
+    pub patch_size: usize,
+    #[serde(default = "default_hidden_act")]
+    pub hidden_act: Activation,
+    #[serde(default = "default_layer_norm_eps")]
+    pub layer_norm_eps: f64,
+}
+
+fn default_num_channels() -> usize {
+    3
+}
+
+fn default_hidden_act() -> Activation {
+    Activation::GeluPytorchTanh
+}
+
+fn default_layer_norm_eps() -> f64 {
+    1e-6
+}
+
+impl VisionConfig {
+    // https://huggingface.co/google/siglip-base-patch16-224/blob/main/config.json
+    pub fn base_patch16_224() -> Self {
+        Self {
+            hidden_size: 768,
+            intermediate_size: 3072,
+            num_hidden_layers: 12,
+            num_attention_heads: 12,
+            num_channels: 3,
+            image_size: 224,
+            patch_size: 16,
+            hidden_act: Activation::GeluPytorchTanh,
+            layer_norm_eps: 1e-6,
+        }
+    }
+
+    // https://huggingface.co/google/siglip-so400m-patch14-384/blob/main/config.json
+    pub fn so400m_patch14_384() -> Self {
+        Self {
+            hidden_size: 1152,
+            intermediate_size: 4304,
+            num_hidden_layers: 27,
+            num_attention_heads: 16,
+            num_channels: 3,
+            image_size: 384,
+            patch_size: 14,
+            hidden_act: Activation::GeluPytorchTanh,
+            layer_norm_eps: 1e-6,
+        }
+    }
+
+    pub fn num_patches_per_side(&self) -> usize {
+        self.image_size / self.patch_size
+    }
+
+    pub fn num_patches(&self) -> usize {
+        self.num_patches_per_side().pow(2)
+    }
+}
+
+// Unlike CLIP there is no class token, every position is a patch.
+#[derive(Debug, Clone)]
+struct VisionEmbeddings {
+    patch_embedding: candle_nn::Conv2d,
+    position_embedding: candle_nn::Embedding,
+    position_ids: Tensor,
+}
+
+impl VisionEmbeddings {
+    fn new(cfg: &VisionConfig, vb: VarBuilder) -> Result<Self> {
+        let conv_cfg = Conv2dConfig {
+            stride: cfg.patch_size,
+            ..Default::default()
+        };
+        let patch_embedding = candle_nn::conv2d(
+            cfg.num_channels,
+            cfg.hidden_size,
+            cfg.patch_size,
+            conv_cfg,
+            vb.pp("patch_embedding"),
+        )?;
+        let num_patches = cfg.num_patches();
+        let position_embedding =
+            candle_nn::embedding(num_patches, cfg.hidden_size, vb.pp("position_embedding"))?;
+        let position_ids = Tensor::arange(0u32, num_patches as u32, vb.device())?;
+        Ok(Self {
+            patch_embedding,
+            position_embedding,
+            position_ids,
+        })
+    }
+}
+
+impl Module for VisionEmbeddings {
+    fn forward(&self, pixel_values: &Tensor) -> Result<Tensor> {
+        let xs = self
+            .patch_embedding
+            .forward(pixel_values)?
+            .flatten_from(2)?
+            .transpose(1, 2)?;
+        let position_embedding = self.position_embedding.forward(&self.position_ids)?;
+        xs.broadcast_add(&position_embedding)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Attention {
+    q_proj: candle_nn::Linear,
+    k_proj: candle_nn::Linear,
+    v_proj: candle_nn::Linear,
+    out_proj: candle_nn::Linear,
+    num_heads: usize,
+    head_dim: usize,
+    scale: f64,
+}
+
+impl Attention {
+    fn new(cfg: &VisionConfig, vb: VarBuilder) -> Result<Self> {
+        let embed_dim = cfg.hidden_size;
+        let q_proj = candle_nn::linear(embed_dim, embed_dim, vb.pp("q_proj"))?;
+        let k_proj = candle_nn::linear(embed_dim, embed_dim, vb.pp("k_proj"))?;
+        let v_proj = candle_nn::linear(embed_dim, embed_dim, vb.pp("v_proj"))?;
+        let out_proj = candle_nn::linear(embed_dim, embed_dim, vb.pp("out_proj"))?;
+        let num_heads = cfg.num_attention_heads;
+        let head_dim = embed_dim / num_heads;
+        Ok(Self {
+            q_proj,
+            k_proj,
+            v_proj,
+            out_proj,
+            num_heads,
+            head_dim,
+            scale: (head_dim as f64).powf(-0.5),
+        })
+    }
+
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let (b_sz, seq_len, embed_dim) = xs.dims3()?;
+        let shape = |xs: Tensor| {
+            xs.reshape((b_sz, seq_len, self.num_heads, self.head_dim))?
+                .transpose(1, 2)?
+                .contiguous()
+        };
+        let q = shape((self.q_proj.forward(xs)? * self.scale)?)?;
+        let k = shape(self.k_proj.forward(xs)?)?;
+        let v = shape(self.v_proj.forward(xs)?)?;
+
+        let in_dtype = q.dtype();
+        let attn_weights = q
+            .to_dtype(DType::F32)?
+            .matmul(&k.to_dtype(DType::F32)?.t()?)?;
+        let attn_weights = candle_nn::ops::softmax_last_dim(&attn_weights)?.to_dtype(in_dtype)?;
+        let xs = attn_weights
+            .matmul(&v)?
+            .transpose(1, 2)?
+            .reshape((b_sz, seq_len, embed_dim))?;
+        self.out_proj.forward(&xs)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Mlp {
+    fc1: candle_nn::Linear,
+    fc2: candle_nn::Linear,
+    act: Activation,
+}
+
+impl Mlp {
+    fn new(cfg: &VisionConfig, vb: VarBuilder) -> Result<Self> {
+        let fc1 = candle_nn::linear(cfg.hidden_size, cfg.intermediate_size, vb.pp("fc1"))?;
+        let fc2 = candle_nn::linear(cfg.intermediate_size, cfg.hidden_size, vb.pp("fc2"))?;
+        Ok(Self {
+            fc1,
+            fc2,
+            act: cfg.hidden_act,
+        })
+    }
+}
+
+impl Module for Mlp {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        xs.apply(&self.fc1)?.apply(&self.act)?.apply(&self.fc2)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct EncoderLayer {
+    self_attn: Attention,
+    layer_norm1: candle_nn::LayerNorm,
+    mlp: Mlp,
+    layer_norm2: candle_nn::LayerNorm,
+}
+
+impl EncoderLayer {
+    fn new(cfg: &VisionConfig, vb: VarBuilder) -> Result<Self> {
+        let self_attn = Attention::new(cfg, vb.pp("self_attn"))?;
+        let layer_norm1 =
+            candle_nn::layer_norm(cfg.hidden_size, cfg.layer_norm_eps, vb.pp("layer_norm1"))?;
+        let mlp = Mlp::new(cfg, vb.pp("mlp"))?;
+        let layer_norm2 =
+            candle_nn::layer_norm(cfg.hidden_size, cfg.layer_norm_eps, vb.pp("layer_norm2"))?;
+        Ok(Self {
+            self_attn,
+            layer_norm1,
+            mlp,
+            layer_norm2,
+        })
+    }
+}
+
+impl Module for EncoderLayer {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let residual = xs;
+        let xs = (self.self_attn.forward(&xs.apply(&self.layer_norm1)?)? + residual)?;
+        let residual = &xs;
+        xs.apply(&self.layer_norm2)?.apply(&self.mlp)? + residual
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct VisionModel {
+    embeddings: VisionEmbeddings,
+    layers: Vec<EncoderLayer>,
+    post_layernorm: candle_nn::LayerNorm,
+    config: VisionConfig,
+}
+
+impl VisionModel {
+    /// Loads the vision transformer, `vb` should point at the `vision_model` prefix.
+    pub fn new(cfg: &VisionConfig, vb: VarBuilder) -> Result<Self> {
+        let embeddings = VisionEmbeddings::new(cfg, vb.pp("embeddings"))?;
+        let vb_l = vb.pp("encoder").pp("layers");
+        let layers = (0..cfg.num_hidden_layers)
+            .map(|i| EncoderLayer::new(cfg, vb_l.pp(i)))
+            .collect::<Result<Vec<_>>>()?;
+        let post_layernorm =
+            candle_nn::layer_norm(cfg.hidden_size, cfg.layer_norm_eps, vb.pp("post_layernorm"))?;
+        Ok(Self {
+            embeddings,
+            layers,
+            post_layernorm,
+            config: cfg.clone(),
+        })
+    }
+
+    pub fn config(&self) -> &VisionConfig {
+        &self.config
+    }
+
+    /// Returns the embeddings followed by the output of each encoder layer, before the final
+    /// layer norm. Vision-language models usually select one of these, e.g. the second to last.
+    pub fn forward_hidden_states(&self, pixel_values: &Tensor) -> Result<Vec<Tensor>> {
+        let mut xs = self.embeddings.forward(pixel_values)?;
+        let mut hidden_states = Vec::with_capacity(self.layers.len() + 1);
+        hidden_states.push(xs.clone());
+        for layer in self.layers.iter() {
+            xs = layer.forward(&xs)?;
+            hidden_states.push(xs.clone());
+        }
+        Ok(hidden_states)
+    }
+}
+
+impl Module for VisionModel {
+    /// Maps `(batch, channels, image_size, image_size)` pixel values to patch features of shape
+    /// `(batch, num_patches, hidden_size)`.
+    fn forward(&self, pixel_values: &Tensor) -> Result<Tensor> {
+        let mut xs = self.embeddings.forward(pixel_values)?;
+        for layer in self.layers.iter() {
+            xs = layer.forward(&xs)?;
+        }
+        xs.apply(&self.post_layernorm)
+    }
+}
@@ -2,12 +2,15 @@ pub mod attention;
 pub mod clip;
 pub mod ddim;
 pub mod ddpm;
+pub mod dpm_solver_multistep;
 pub mod embeddings;
 pub mod euler_ancestral_discrete;
+pub mod pipeline;
 pub mod resnet;
 pub mod schedulers;
 pub mod unet_2d;
 pub mod unet_2d_blocks;
+pub mod uni_pc;
 pub mod utils;
 pub mod vae;
 
@@ -441,6 +444,13 @@ impl StableDiffusionConfig {
         Ok(unet)
     }
 
+    /// Replaces the default scheduler, e.g. with [`uni_pc::UniPCMultistepSchedulerConfig`], the
+    /// prediction type has to match the one of the model.
+    pub fn with_scheduler(mut self, scheduler: Arc<dyn SchedulerConfig>) -> Self {
+        self.scheduler = scheduler;
+        self
+    }
+
     pub fn build_scheduler(&self, n_steps: usize) -> Result<Box<dyn Scheduler>> {
         self.scheduler.build(n_steps)
     }
@@ -0,0 +1,116 @@
+//! # Denoising loops
+//!
+//! The sampling loop shared by text-to-image, image-to-image and inpainting. The denoising model
+//! is passed as a closure returning the predicted noise for a scaled latent input and a
+//! timestep, this is where the unet and the classifier-free guidance are applied.
+//!
+//! Image-to-image starts from the latents of an init image noised to an intermediate timestep,
+//! the `strength` sets how far in the schedule this starts, 1 ignoring the image completely.
+//! Inpainting additionally replaces, after each step, the latents outside of the mask by the
+//! init latents noised to the next timestep so that only the masked area gets repainted.
+use super::schedulers::Scheduler;
+use candle::{Result, Tensor};
+
+/// How the initial latents are obtained and constrained during sampling.
+#[derive(Debug, Clone)]
+pub enum DiffusionMode {
+    /// Start from pure noise.
+    TextToImage,
+    /// Start from the latents of an init image with some added noise.
+    ImageToImage {
+        init_latents: Tensor,
+        /// Between 0 and 1, the fraction of the schedule that is run.
+        strength: f64,
+    },
+    /// Repaint the area of the init image where the mask is 1, see [`prepare_mask`].
+    Inpainting {
+        init_latents: Tensor,
+        /// A mask with the spatial size of the latents, broadcastable to them.
+        mask: Tensor,
+        strength: f64,
+    },
+}
+
+/// The index of the first timestep to run for a given strength.
+pub fn start_step(n_steps: usize, strength: f64) -> usize {
+    n_steps - (n_steps as f64 * strength.clamp(0., 1.)) as usize
+}
+
+/// Resizes a mask of shape `(h, w)` or `(b, 1, h, w)` with values between 0 and 1, e.g. built
+/// from a grayscale image, to the latent resolution. The result has shape `(b, 1, latent_h,
+/// latent_w)` and the dtype of `dtype_like`.
+pub fn prepare_mask(
+    mask: &Tensor,
+    latent_h: usize,
+    latent_w: usize,
+    dtype_like: &Tensor,
+) -> Result<Tensor> {
+    let mask = match mask.rank() {
+        2 => mask.unsqueeze(0)?.unsqueeze(0)?,
+        4 => mask.clone(),
+        rank => candle::bail!("unexpected rank {rank} for the inpainting mask"),
+    };
+    mask.to_dtype(candle::DType::F32)?
+        .upsample_nearest2d(latent_h, latent_w)?
+        .clamp(0f32, 1f32)?
+        .to_dtype(dtype_like.dtype())?
+        .to_device(dtype_like.device())
+}
+
+/// Runs the denoising loop and returns the final latents.
+///
+/// `noise` is a standard normal tensor with the shape of the latents, it is used as the initial
+/// latents for text-to-image and added to the init latents otherwise. `predict_noise` is called
+/// once per step with the scaled latents and the timestep.
+pub fn denoise<F>(
+    scheduler: &mut dyn Scheduler,
+    mode: &DiffusionMode,
+    noise: &Tensor,
+    mut predict_noise: F,
+) -> Result<Tensor>
+where
+    F: FnMut(&Tensor, usize) -> Result<Tensor>,
+{
+    let timesteps = scheduler.timesteps().to_vec();
+    let n_steps = timesteps.len();
+    let (init_latents, mask, t_start) = match mode {
+        DiffusionMode::TextToImage => (None, None, 0),
+        DiffusionMode::ImageToImage {
+            init_latents,
+            strength,
+        } => (Some(init_latents), None, start_step(n_steps, *strength)),
+        DiffusionMode::Inpainting {
+            init_latents,
+            mask,
+            strength,
+        } => (
+            Some(init_latents),
+            Some(mask),
+            start_step(n_steps, *strength),
+        ),
+    };
+    let mut latents = match init_latents {
+        None => (noise * scheduler.init_noise_sigma())?,
+        Some(init_latents) if t_start < n_steps => {
+            scheduler.add_noise(init_latents, noise.clone(), timesteps[t_start])?
+        }
+        Some(init_latents) => return Ok(init_latents.clone()),
+    };
+    scheduler.reset();
+    for (index, &timestep) in timesteps.iter().enumerate().skip(t_start) {
+        let latent_model_input = scheduler.scale_model_input(latents.clone(), timestep)?;
+        let noise_pred = predict_noise(&latent_model_input, timestep)?;
+        latents = scheduler.step(&noise_pred, timestep, &latents)?;
+        if let (Some(init_latents), Some(mask)) = (init_latents, mask) {
+            let known = match timesteps.get(index + 1) {
+                Some(&next_timestep) => {
+                    scheduler.add_noise(init_latents, noise.clone(), next_timestep)?
+                }
+                None => init_latents.clone(),
+            };
+            let mask = mask.broadcast_as(latents.shape())?;
+            latents = ((&latents * &mask)? + (known * (1. - mask)?)?)?;
+        }
+    }
+    Ok(latents)
+}
@@ -19,7 +19,13 @@ pub trait Scheduler {
 
     fn scale_model_input(&self, sample: Tensor, _timestep: usize) -> Result<Tensor>;
 
-    fn step(&self, model_output: &Tensor, timestep: usize, sample: &Tensor) -> Result<Tensor>;
+    /// Performs a backward step during inference, the multistep schedulers keep track of the
+    /// previous model outputs so the timesteps have to be processed in order.
+    fn step(&mut self, model_output: &Tensor, timestep: usize, sample: &Tensor) -> Result<Tensor>;
+
+    /// Clears the state kept between the steps, e.g. the previous model outputs, so that a new
+    /// denoising loop can be started.
+    fn reset(&mut self) {}
 }
 
 /// This represents how beta ranges from its minimum value to the maximum
@@ -75,3 +81,34 @@ pub(crate) fn betas_for_alpha_bar(num_diffusion_timesteps: usize, max_beta: f64)
     let betas_len = betas.len();
     Tensor::from_vec(betas, betas_len, &candle::Device::Cpu)
 }
+
+/// The cumulative products of `1 - beta` for each training timestep.
+pub(crate) fn alphas_cumprod(
+    beta_schedule: BetaSchedule,
+    beta_start: f64,
+    beta_end: f64,
+    train_timesteps: usize,
+) -> Result<Vec<f64>> {
+    let betas = match beta_schedule {
+        BetaSchedule::ScaledLinear => {
+            super::utils::linspace(beta_start.sqrt(), beta_end.sqrt(), train_timesteps)?.sqr()?
+        }
+        BetaSchedule::Linear => super::utils::linspace(beta_start, beta_end, train_timesteps)?,
+        BetaSchedule::SquaredcosCapV2 => betas_for_alpha_bar(train_timesteps, 0.999)?,
+    };
+    let mut alphas_cumprod = Vec::with_capacity(train_timesteps);
+    for beta in betas.to_vec1::<f64>()? {
+        alphas_cumprod.push((1.0 - beta) * *alphas_cumprod.last().unwrap_or(&1f64))
+    }
+    Ok(alphas_cumprod)
+}
+
+/// The `inference_steps` timesteps evenly spaced between `train_timesteps - 1` and 0 in
+/// decreasing order, the final 0 being excluded.
+pub(crate) fn linspace_timesteps(train_timesteps: usize, inference_steps: usize) -> Vec<usize> {
+    let last = (train_timesteps - 1) as f64;
+    (1..=inference_steps)
+        .rev()
+        .map(|i| (i as f64 * last / inference_steps as f64).round() as usize)
+        .collect()
+}
@@ -0,0 +1,257 @@
+//! # UniPC Multistep Scheduler
+//!
+//! A unified predictor-corrector framework: each step runs a multistep predictor and then
+//! refines the previous sample with a corrector that reuses the model output of the current
+//! step, so the correction does not require any additional model evaluation.
+//!
+//! UniPC: A Unified Predictor-Corrector Framework for Fast Sampling of Diffusion Models,
+//! W. Zhao et al, 2023. https://arxiv.org/abs/2302.04867
+use super::dpm_solver_multistep::data_prediction;
+use super::schedulers::{
+    alphas_cumprod, linspace_timesteps, BetaSchedule, PredictionType, Scheduler, SchedulerConfig,
+};
+use candle::{Result, Tensor};
+
+/// The configuration for the UniPC multistep scheduler, this uses the data prediction with the
+/// `bh2` variant of the solver.
+#[derive(Debug, Clone, Copy)]
+pub struct UniPCMultistepSchedulerConfig {
+    /// The value of beta at the beginning of training.
+    pub beta_start: f64,
+    /// The value of beta at the end of training.
+    pub beta_end: f64,
+    /// How beta evolved during training.
+    pub beta_schedule: BetaSchedule,
+    /// prediction type of the scheduler function, one of `epsilon` (predicting
+    /// the noise of the diffusion process), `sample` (directly predicting the noisy sample`)
+    /// or `v_prediction` (see section 2.4 https://imagen.research.google/video/paper.pdf)
+    pub prediction_type: PredictionType,
+    /// number of diffusion steps used to train the model
+    pub train_timesteps: usize,
+    /// The order of the predictor, either 1 or 2, the corrector has one more order.
+    pub solver_order: usize,
+    /// Lower the order for the final steps so that it does not exceed the number of remaining
+    /// steps.
+    pub lower_order_final: bool,
+}
+
+impl Default for UniPCMultistepSchedulerConfig {
+    fn default() -> Self {
+        Self {
+            beta_start: 0.00085f64,
+            beta_end: 0.012f64,
+            beta_schedule: BetaSchedule::ScaledLinear,
+            prediction_type: PredictionType::Epsilon,
+            train_timesteps: 1000,
+            solver_order: 2,
+            lower_order_final: true,
+        }
+    }
+}
+
+impl SchedulerConfig for UniPCMultistepSchedulerConfig {
+    fn build(&self, inference_steps: usize) -> Result<Box<dyn Scheduler>> {
+        Ok(Box::new(UniPCMultistepScheduler::new(
+            inference_steps,
+            *self,
+        )?))
+    }
+}
+
+/// The UniPC multistep scheduler.
+#[derive(Debug, Clone)]
+pub struct UniPCMultistepScheduler {
+    timesteps: Vec<usize>,
+    alphas_cumprod: Vec<f64>,
+    // The data predictions for the previous steps, the most recent last.
+    model_outputs: Vec<Tensor>,
+    // The sample passed to the last predictor step and the order of this step.
+    last_sample: Option<Tensor>,
+    last_order: usize,
+    // The index of the last step, the history is cleared when a step does not follow it, e.g. at
+    // the start of an img2img loop that skips the first timesteps.
+    last_step: Option<usize>,
+    pub config: UniPCMultistepSchedulerConfig,
+}
+
+// The coefficients of the `bh2` variant for an update of `order` with a step `h` in log-SNR,
+// `b[i]` is the weight of the `i`-th order term.
+fn bh2_coefficients(h: f64, order: usize) -> (f64, Vec<f64>) {
+    let hh = -h;
+    let h_phi_1 = hh.exp_m1();
+    let b_h = h_phi_1;
+    let mut h_phi_k = h_phi_1 / hh - 1.;
+    let mut factorial = 1.;
+    let mut b = Vec::with_capacity(order);
+    for i in 1..=order {
+        b.push(h_phi_k * factorial / b_h);
+        factorial *= (i + 1) as f64;
+        h_phi_k = h_phi_k / hh - 1. / factorial;
+    }
+    (b_h, b)
+}
+
+impl UniPCMultistepScheduler {
+    pub fn new(inference_steps: usize, config: UniPCMultistepSchedulerConfig) -> Result<Self> {
+        if !(1..=2).contains(&config.solver_order) {
+            candle::bail!("unsupported solver order {}", config.solver_order)
+        }
+        if inference_steps == 0 || inference_steps > config.train_timesteps {
+            candle::bail!(
+                "invalid number of inference steps {inference_steps} for {} train timesteps",
+                config.train_timesteps
+            )
+        }
+        let alphas_cumprod = alphas_cumprod(
+            config.beta_schedule,
+            config.beta_start,
+            config.beta_end,
+            config.train_timesteps,
+        )?;
+        Ok(Self {
+            timesteps: linspace_timesteps(config.train_timesteps, inference_steps),
+            alphas_cumprod,
+            model_outputs: vec![],
+            last_sample: None,
+            last_order: 1,
+            last_step: None,
+            config,
+        })
+    }
+
+    // The signal and noise scales for the timestep at `index`, the index after the last step
+    // corresponds to timestep 0.
+    fn alpha_sigma(&self, index: usize) -> (f64, f64) {
+        let t = self.timesteps.get(index).copied().unwrap_or(0);
+        let alpha_prod = self.alphas_cumprod[t];
+        (alpha_prod.sqrt(), (1. - alpha_prod).sqrt())
+    }
+
+    fn lambda(&self, index: usize) -> f64 {
+        let (alpha, sigma) = self.alpha_sigma(index);
+        alpha.ln() - sigma.ln()
+    }
+
+    // The common first order part of the predictor and corrector, going from the sample `x` at
+    // step `s0` to step `t`.
+    fn first_order(&self, x: &Tensor, m0: &Tensor, s0: usize, t: usize) -> Result<Tensor> {
+        let (_, sigma_s0) = self.alpha_sigma(s0);
+        let (alpha_t, sigma_t) = self.alpha_sigma(t);
+        let h = self.lambda(t) - self.lambda(s0);
+        (x * (sigma_t / sigma_s0))? - (m0 * (alpha_t * (-h).exp_m1()))?
+    }
+
+    // UniP: predicts the sample at step `s0 + 1` from the sample at step `s0`.
+    fn predictor(&self, sample: &Tensor, s0: usize, order: usize) -> Result<Tensor> {
+        let m0 = &self.model_outputs[self.model_outputs.len() - 1];
+        let x_t = self.first_order(sample, m0, s0, s0 + 1)?;
+        if order == 1 {
+            return Ok(x_t);
+        }
+        let lambda_s0 = self.lambda(s0);
+        let h = self.lambda(s0 + 1) - lambda_s0;
+        let m1 = &self.model_outputs[self.model_outputs.len() - 2];
+        let rk = (self.lambda(s0 - 1) - lambda_s0) / h;
+        let d1 = ((m1 - m0)? / rk)?;
+        let (b_h, _) = bh2_coefficients(h, order);
+        let (alpha_t, _) = self.alpha_sigma(s0 + 1);
+        // For the second order, the single coefficient is 0.5.
+        x_t - (d1 * (alpha_t * b_h * 0.5))?
+    }
+
+    // UniC: corrects the sample at step `t` using the model output `model_t` at this step, the
+    // previous sample was at step `t - 1`.
+    fn corrector(&self, model_t: &Tensor, x_t: &Tensor, t: usize, order: usize) -> Result<Tensor> {
+        let last_sample = match &self.last_sample {
+            Some(last_sample) => last_sample,
+            None => return Ok(x_t.clone()),
+        };
+        let s0 = t - 1;
+        let m0 = &self.model_outputs[self.model_outputs.len() - 1];
+        let x_t_ = self.first_order(last_sample, m0, s0, t)?;
+        let lambda_s0 = self.lambda(s0);
+        let h = self.lambda(t) - lambda_s0;
+        let (b_h, b) = bh2_coefficients(h, order);
+        let (alpha_t, _) = self.alpha_sigma(t);
+        let d1_t = (model_t - m0)?;
+        let correction = if order == 1 {
+            (d1_t * 0.5)?
+        } else {
+            // Solve [[1, 1], [rk, 1]] rhos = b for the two corrector coefficients.
+            let m1 = &self.model_outputs[self.model_outputs.len() - 2];
+            let rk = (self.lambda(s0 - 1) - lambda_s0) / h;
+            let d1 = ((m1 - m0)? / rk)?;
+            let rho_0 = (b[0] - b[1]) / (1. - rk);
+            let rho_1 = b[0] - rho_0;
+            ((d1 * rho_0)? + (d1_t * rho_1)?)?
+        };
+        x_t_ - (correction * (alpha_t * b_h))?
+    }
+}
+
+impl Scheduler for UniPCMultistepScheduler {
+    fn step(&mut self, model_output: &Tensor, timestep: usize, sample: &Tensor) -> Result<Tensor> {
+        let step_index = match self.timesteps.iter().position(|&t| t == timestep) {
+            Some(step_index) => step_index,
+            None => candle::bail!("timestep {timestep} is not part of the schedule"),
+        };
+        if self.last_step.map(|s| s + 1) != Some(step_index) {
+            self.reset()
+        }
+        self.last_step = Some(step_index);
+        let (alpha, sigma) = self.alpha_sigma(step_index);
+        let x0 = data_prediction(
+            self.config.prediction_type,
+            model_output,
+            sample,
+            alpha,
+            sigma,
+        )?;
+        let sample = if self.last_sample.is_some() {
+            self.corrector(&x0, sample, step_index, self.last_order)?
+        } else {
+            sample.clone()
+        };
+        if self.model_outputs.len() == self.config.solver_order {
+            self.model_outputs.remove(0);
+        }
+        self.model_outputs.push(x0);
+
+        let order = if self.config.lower_order_final {
+            self.config
+                .solver_order
+                .min(self.timesteps.len() - step_index)
+        } else {
+            self.config.solver_order
+        };
+        let order = order.min(self.model_outputs.len());
+        let prev_sample = self.predictor(&sample, step_index, order)?;
+        self.last_sample = Some(sample);
+        self.last_order = order;
+        Ok(prev_sample)
+    }
+
+    fn reset(&mut self) {
+        self.model_outputs.clear();
+        self.last_sample = None;
+        self.last_order = 1;
+        self.last_step = None;
+    }
+
+    fn scale_model_input(&self, sample: Tensor, _timestep: usize) -> Result<Tensor> {
+        Ok(sample)
+    }
+
+    fn timesteps(&self) -> &[usize] {
+        self.timesteps.as_slice()
+    }
+
+    fn add_noise(&self, original: &Tensor, noise: Tensor, timestep: usize) -> Result<Tensor> {
+        let alpha_prod = self.alphas_cumprod[timestep];
+        (original * alpha_prod.sqrt())? + (noise * (1. - alpha_prod).sqrt())?
+    }
+
+    fn init_noise_sigma(&self) -> f64 {
+        1.
+    }
+}
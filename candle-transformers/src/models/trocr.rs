@@ -486,10 +486,10 @@ impl TrOCRModel {
         past_kv_len: usize,
     ) -> Result<Tensor> {
         let seq_len = xs.dim(1)?;
-        let mask: Vec<_> = (0..seq_len)
-            .flat_map(|i| (0..seq_len).map(move |j| if j > i { f32::NEG_INFINITY } else { 0f32 }))
-            .collect();
-        let mask = Tensor::from_vec(mask, (seq_len, seq_len), xs.device())?;
+        let mask = candle_nn::attention_mask::to_additive(
+            &candle_nn::attention_mask::causal_mask(seq_len, 0, xs.device())?,
+            DType::F32,
+        )?;
 
         self.decoder
             .forward(xs, Some(encoder_xs), past_kv_len, &mask)
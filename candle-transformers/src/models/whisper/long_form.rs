@@ -0,0 +1,553 @@
+//! Transcription of long recordings, e.g. hour-long meetings, in a single call.
+//!
+//! The audio is first split with a voice activity detector, the speech regions are packed into
+//! windows of up to 30 seconds that are decoded in batches, and the timestamps of the decoded
+//! segments are stitched back onto the timeline of the recording. Optionally, an embedding is
+//! extracted for each segment with a speaker encoder and the segments are clustered by
+//! speaker.
+//!
+//! ```ignore
+//! let config = LongFormConfig {
+//!     batch_size: 8,
+//!     ..Default::default()
+//! };
+//! let mut transcriber = LongFormTranscriber::new(model, tokens, mel_filters, config, &device)?
+//!     .with_vad(EnergyVad::default())
+//!     .with_speaker_encoder(speaker_encoder);
+//! for s in transcriber.transcribe(&pcm)? {
+//!     let text = tokenizer.decode(&s.segment.tokens, true).map_err(E::msg)?;
+//!     println!("[{:.2} -> {:.2}] {:?}: {text}", s.segment.start, s.segment.end, s.speaker);
+//! }
+//! ```
+//!
+//! Unlike with the [`StreamingTranscriber`](super::streaming::StreamingTranscriber), the
+//! windows are decoded independently of each other so the text of a window is not used as a
+//! prompt for the next one.
+use super::streaming::{
+    apply_timestamp_rules, argmax, detect_language, log_sum_exp, window_segments, Segment,
+    SpecialTokens, Task, WhisperModel, WindowSegments, WindowStats,
+};
+use super::{HOP_LENGTH, LOGPROB_THRESHOLD, NO_SPEECH_THRESHOLD, N_FRAMES, N_SAMPLES, SAMPLE_RATE};
+use candle::{Device, IndexOp, Result, Tensor};
+use std::ops::Range;
+
+/// Estimates the probability of speech in some audio.
+pub trait VoiceActivityDetector {
+    /// The number of samples covered by each probability.
+    fn frame_size(&self) -> usize;
+
+    /// The speech probability of each frame of 16kHz mono samples, the last frame can be
+    /// partial.
+    fn speech_probs(&mut self, samples: &[f32]) -> Result<Vec<f32>>;
+}
+
+/// A voice activity detector based on the energy of the frames, this only works on clean
+/// recordings and a model such as silero-vad should be preferred for noisy ones.
+#[derive(Debug, Clone)]
+pub struct EnergyVad {
+    pub frame_size: usize,
+    /// The energy in dB for which the speech probability is 0.5.
+    pub threshold_db: f32,
+}
+
+impl Default for EnergyVad {
+    fn default() -> Self {
+        Self {
+            frame_size: 512,
+            threshold_db: -40.,
+        }
+    }
+}
+
+impl VoiceActivityDetector for EnergyVad {
+    fn frame_size(&self) -> usize {
+        self.frame_size
+    }
+
+    fn speech_probs(&mut self, samples: &[f32]) -> Result<Vec<f32>> {
+        if self.frame_size == 0 {
+            candle::bail!("the vad frame size has to be positive")
+        }
+        let probs = samples
+            .chunks(self.frame_size)
+            .map(|frame| {
+                let energy = frame.iter().map(|x| x * x).sum::<f32>() / frame.len() as f32;
+                let db = 10. * (energy + 1e-10).log10();
+                1. / (1. + (-(db - self.threshold_db) / 2.).exp())
+            })
+            .collect();
+        Ok(probs)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct VadConfig {
+    /// Frames with a speech probability above this value start a speech region.
+    pub threshold: f32,
+    /// Speech regions shorter than this, in seconds, are dropped.
+    pub min_speech_duration: f64,
+    /// A speech region ends after this duration of silence, in seconds.
+    pub min_silence_duration: f64,
+    /// The duration added on both sides of the speech regions, in seconds.
+    pub speech_pad: f64,
+}
+
+impl Default for VadConfig {
+    fn default() -> Self {
+        Self {
+            threshold: 0.5,
+            min_speech_duration: 0.25,
+            min_silence_duration: 0.5,
+            speech_pad: 0.2,
+        }
+    }
+}
+
+fn to_samples(seconds: f64) -> usize {
+    (seconds * SAMPLE_RATE as f64) as usize
+}
+
+/// The sample ranges of the speech regions given the speech probabilities of consecutive frames
+/// of `frame_size` samples. A region ends when the probability stays below `threshold - 0.15`
+/// for `min_silence_duration`, the regions are padded and the overlapping ones are merged.
+pub fn speech_regions(
+    probs: &[f32],
+    frame_size: usize,
+    n_samples: usize,
+    cfg: &VadConfig,
+) -> Vec<Range<usize>> {
+    let neg_threshold = (cfg.threshold - 0.15).max(0.01);
+    let min_silence = to_samples(cfg.min_silence_duration);
+    let mut regions = vec![];
+    let mut start = None;
+    let mut silence_start = None;
+    for (i, &p) in probs.iter().enumerate() {
+        let pos = usize::min(i * frame_size, n_samples);
+        if p >= cfg.threshold {
+            silence_start = None;
+            start.get_or_insert(pos);
+        } else if p < neg_threshold {
+            if let Some(s) = start {
+                let silence = *silence_start.get_or_insert(pos);
+                if pos + frame_size - silence >= min_silence {
+                    regions.push(s..silence);
+                    start = None;
+                    silence_start = None;
+                }
+            }
+        }
+    }
+    if let Some(s) = start {
+        regions.push(s..silence_start.unwrap_or(n_samples))
+    }
+    let min_speech = to_samples(cfg.min_speech_duration);
+    let pad = to_samples(cfg.speech_pad);
+    let mut padded: Vec<Range<usize>> = vec![];
+    for r in regions.into_iter().filter(|r| r.len() >= min_speech) {
+        let r = r.start.saturating_sub(pad)..usize::min(r.end + pad, n_samples);
+        match padded.last_mut() {
+            Some(last) if last.end >= r.start => last.end = r.end,
+            _ => padded.push(r),
+        }
+    }
+    padded
+}
+
+/// Packs consecutive regions into windows of at most `max_len` samples, regions longer than
+/// this are split.
+pub fn pack_windows(regions: &[Range<usize>], max_len: usize) -> Vec<Range<usize>> {
+    let mut windows: Vec<Range<usize>> = vec![];
+    for region in regions.iter() {
+        let mut start = region.start;
+        while start < region.end {
+            let end = usize::min(region.end, start + max_len);
+            match windows.last_mut() {
+                Some(w) if end - w.start <= max_len => w.end = end,
+                _ => windows.push(start..end),
+            }
+            start = end;
+        }
+    }
+    windows
+}
+
+/// Computes an embedding characterizing the voice of the speaker of some audio.
+pub trait SpeakerEncoder {
+    /// The embedding of some 16kHz mono samples, the embeddings of the same speaker are expected
+    /// to have a high cosine similarity.
+    fn embed(&mut self, samples: &[f32]) -> Result<Vec<f32>>;
+}
+
+/// Online clustering of speaker embeddings: an embedding is attributed to the speaker with the
+/// most similar centroid if the cosine similarity is above `threshold`, and to a new speaker
+/// otherwise.
+#[derive(Debug, Clone)]
+pub struct SpeakerClustering {
+    threshold: f32,
+    // The sum of the normalized embeddings of each speaker.
+    centroids: Vec<Vec<f32>>,
+}
+
+fn normalize(xs: &[f32]) -> Vec<f32> {
+    let norm = xs.iter().map(|x| x * x).sum::<f32>().sqrt().max(1e-12);
+    xs.iter().map(|x| x / norm).collect()
+}
+
+impl SpeakerClustering {
+    pub fn new(threshold: f32) -> Self {
+        Self {
+            threshold,
+            centroids: vec![],
+        }
+    }
+
+    pub fn n_speakers(&self) -> usize {
+        self.centroids.len()
+    }
+
+    /// Returns the index of the speaker for `embedding`, updating its centroid.
+    pub fn assign(&mut self, embedding: &[f32]) -> Result<usize> {
+        let embedding = normalize(embedding);
+        let mut best: Option<(usize, f32)> = None;
+        for (idx, centroid) in self.centroids.iter().enumerate() {
+            if centroid.len() != embedding.len() {
+                candle::bail!(
+                    "speaker embedding size mismatch {} <> {}",
+                    embedding.len(),
+                    centroid.len()
+                )
+            }
+            let centroid = normalize(centroid);
+            let sim = centroid
+                .iter()
+                .zip(embedding.iter())
+                .map(|(c, e)| c * e)
+                .sum();
+            match best {
+                Some((_, best_sim)) if best_sim >= sim => {}
+                _ => best = Some((idx, sim)),
+            }
+        }
+        match best {
+            Some((idx, sim)) if sim >= self.threshold => {
+                for (c, e) in self.centroids[idx].iter_mut().zip(embedding.iter()) {
+                    *c += e
+                }
+                Ok(idx)
+            }
+            _ => {
+                self.centroids.push(embedding);
+                Ok(self.centroids.len() - 1)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LongFormConfig {
+    pub task: Task,
+    /// The language token, when `None` and `language_candidates` is not empty, the language is
+    /// detected on the first window of speech.
+    pub language: Option<u32>,
+    pub language_candidates: Vec<u32>,
+    /// The number of windows decoded together.
+    pub batch_size: usize,
+    pub vad: VadConfig,
+    /// The minimal cosine similarity for a segment to be attributed to an existing speaker.
+    pub speaker_threshold: f32,
+    /// Segments shorter than this, in seconds, are not attributed to any speaker.
+    pub min_speaker_duration: f64,
+}
+
+impl Default for LongFormConfig {
+    fn default() -> Self {
+        Self {
+            task: Task::Transcribe,
+            language: None,
+            language_candidates: vec![],
+            batch_size: 4,
+            vad: VadConfig::default(),
+            speaker_threshold: 0.7,
+            min_speaker_duration: 0.5,
+        }
+    }
+}
+
+/// A transcribed segment with its speaker, times are in seconds from the start of the
+/// recording.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TranscriptSegment {
+    pub segment: Segment,
+    /// The index of the speaker, only set when a speaker encoder is used.
+    pub speaker: Option<usize>,
+    pub embedding: Option<Vec<f32>>,
+}
+
+/// Transcribes a full recording, see the module documentation.
+pub struct LongFormTranscriber<M: WhisperModel> {
+    model: M,
+    tokens: SpecialTokens,
+    config: LongFormConfig,
+    mel_filters: Vec<f32>,
+    suppress_tokens: Vec<u32>,
+    device: Device,
+    language: Option<u32>,
+    vad: Option<Box<dyn VoiceActivityDetector>>,
+    speaker_encoder: Option<Box<dyn SpeakerEncoder>>,
+}
+
+impl<M: WhisperModel> LongFormTranscriber<M> {
+    pub fn new(
+        model: M,
+        tokens: SpecialTokens,
+        mel_filters: Vec<f32>,
+        config: LongFormConfig,
+        device: &Device,
+    ) -> Result<Self> {
+        let cfg = model.config();
+        if mel_filters.len() % cfg.num_mel_bins != 0 {
+            candle::bail!(
+                "unexpected mel filters size {} for {} mel bins",
+                mel_filters.len(),
+                cfg.num_mel_bins
+            )
+        }
+        if config.batch_size == 0 {
+            candle::bail!("the batch size has to be positive")
+        }
+        let mut suppress_tokens = cfg.suppress_tokens.clone();
+        suppress_tokens.push(tokens.no_timestamps);
+        Ok(Self {
+            language: config.language,
+            model,
+            tokens,
+            config,
+            mel_filters,
+            suppress_tokens,
+            device: device.clone(),
+            vad: None,
+            speaker_encoder: None,
+        })
+    }
+
+    /// Only transcribes the speech regions found by `vad`, without a detector the whole
+    /// recording is transcribed.
+    pub fn with_vad<V: VoiceActivityDetector + 'static>(mut self, vad: V) -> Self {
+        self.vad = Some(Box::new(vad));
+        self
+    }
+
+    /// Attributes the segments to speakers using the embeddings from `encoder`.
+    pub fn with_speaker_encoder<E: SpeakerEncoder + 'static>(mut self, encoder: E) -> Self {
+        self.speaker_encoder = Some(Box::new(encoder));
+        self
+    }
+
+    pub fn model(&self) -> &M {
+        &self.model
+    }
+
+    /// The language token in use, this is `None` until detection has run when no language
+    /// was set in the configuration.
+    pub fn language(&self) -> Option<u32> {
+        self.language
+    }
+
+    /// The sample ranges of the windows that get decoded for `samples`.
+    pub fn windows(&mut self, samples: &[f32]) -> Result<Vec<Range<usize>>> {
+        let regions = match self.vad.as_mut() {
+            None => std::iter::once(0..samples.len()).collect(),
+            Some(vad) => {
+                let probs = vad.speech_probs(samples)?;
+                speech_regions(&probs, vad.frame_size(), samples.len(), &self.config.vad)
+            }
+        };
+        let mut windows = pack_windows(&regions, N_SAMPLES);
+        windows.retain(|w| w.len() >= HOP_LENGTH);
+        Ok(windows)
+    }
+
+    /// Transcribes some 16kHz mono samples, the segments are returned in order and do not
+    /// overlap.
+    pub fn transcribe(&mut self, samples: &[f32]) -> Result<Vec<TranscriptSegment>> {
+        let windows = self.windows(samples)?;
+        let mut segments = vec![];
+        for batch in windows.chunks(self.config.batch_size) {
+            segments.extend(self.decode_batch(samples, batch)?)
+        }
+        // Stitch the windows, a segment cannot start before the end of the previous one.
+        let mut prev_end = 0f64;
+        for segment in segments.iter_mut() {
+            segment.start = segment.start.max(prev_end);
+            segment.end = segment.end.max(segment.start);
+            for timing in segment.token_timings.iter_mut() {
+                timing.start = timing.start.clamp(segment.start, segment.end);
+                timing.end = timing.end.clamp(timing.start, segment.end);
+            }
+            prev_end = segment.end;
+        }
+        let mut clustering = SpeakerClustering::new(self.config.speaker_threshold);
+        let min_duration = self.config.min_speaker_duration;
+        segments
+            .into_iter()
+            .map(|segment| {
+                let embedding = match self.speaker_encoder.as_mut() {
+                    Some(encoder) if segment.end - segment.start >= min_duration => {
+                        let start = usize::min(to_samples(segment.start), samples.len());
+                        let end = usize::min(to_samples(segment.end), samples.len());
+                        Some(encoder.embed(&samples[start..end])?)
+                    }
+                    _ => None,
+                };
+                let speaker = match embedding.as_ref() {
+                    Some(embedding) => Some(clustering.assign(embedding)?),
+                    None => None,
+                };
+                Ok(TranscriptSegment {
+                    segment,
+                    speaker,
+                    embedding,
+                })
+            })
+            .collect()
+    }
+
+    fn initial_tokens(&self) -> Vec<u32> {
+        let mut tokens = vec![self.tokens.sot];
+        if let Some(language) = self.language {
+            tokens.push(language)
+        }
+        match self.config.task {
+            Task::Transcribe => tokens.push(self.tokens.transcribe),
+            Task::Translate => tokens.push(self.tokens.translate),
+        }
+        tokens
+    }
+
+    // Greedy decoding of a batch of windows, all the sequences get one token per step and the
+    // finished ones are padded with eot.
+    fn decode_batch(&mut self, samples: &[f32], windows: &[Range<usize>]) -> Result<Vec<Segment>> {
+        let num_mel_bins = self.model.config().num_mel_bins;
+        let mels = windows
+            .iter()
+            .map(|w| {
+                let mel = super::audio::pcm_to_mel(
+                    self.model.config(),
+                    &samples[w.clone()],
+                    &self.mel_filters,
+                );
+                let n_frames = mel.len() / num_mel_bins;
+                Tensor::from_vec(mel, (1, num_mel_bins, n_frames), &self.device)?
+                    .narrow(2, 0, N_FRAMES)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if self.language.is_none() && !self.config.language_candidates.is_empty() {
+            let probs = detect_language(
+                &mut self.model,
+                &mels[0],
+                self.tokens.sot,
+                &self.config.language_candidates,
+            )?;
+            self.language = Some(probs[0].0)
+        }
+        let audio_features = self.model.encoder_forward(&Tensor::cat(&mels, 0)?, true)?;
+        let b_size = windows.len();
+        let initial_tokens = self.initial_tokens();
+        let ts_begin = self.tokens.timestamp_begin();
+        let eot = self.tokens.eot;
+        let max_target_positions = self.model.config().max_target_positions;
+        let mut tokens = vec![initial_tokens; b_size];
+        let mut sampled = vec![vec![]; b_size];
+        let mut token_stats: Vec<Vec<(f32, u32)>> = vec![vec![]; b_size];
+        let mut finished = vec![false; b_size];
+        let mut no_speech_probs = vec![f32::NAN; b_size];
+        for i in 0..max_target_positions / 2 {
+            if finished.iter().all(|&f| f) {
+                break;
+            }
+            let seq_len = tokens[0].len();
+            let tokens_t = Tensor::from_vec(tokens.concat(), (b_size, seq_len), &self.device)?;
+            let ys = self
+                .model
+                .decoder_forward(&tokens_t, &audio_features, i == 0)?;
+            if i == 0 {
+                // The no speech probability is predicted after the sot token.
+                let logits = self
+                    .model
+                    .decoder_final_linear(&ys.i((.., 0..1))?)?
+                    .squeeze(1)?
+                    .to_dtype(candle::DType::F32)?
+                    .contiguous()?;
+                no_speech_probs = candle_nn::ops::softmax_last_dim(&logits)?
+                    .i((.., self.tokens.no_speech as usize))?
+                    .to_vec1::<f32>()?;
+            }
+            let logits = self
+                .model
+                .decoder_final_linear(&ys.i((.., seq_len - 1..))?)?
+                .squeeze(1)?
+                .to_dtype(candle::DType::F32)?
+                .to_vec2::<f32>()?;
+            for (idx, logits) in logits.iter().enumerate() {
+                if finished[idx] {
+                    tokens[idx].push(eot);
+                    continue;
+                }
+                let ts_guess =
+                    argmax(&logits[(ts_begin as usize).min(logits.len())..]) as u32 + ts_begin;
+                let mut masked = logits.clone();
+                apply_timestamp_rules(
+                    &self.tokens,
+                    &self.suppress_tokens,
+                    &mut masked,
+                    &sampled[idx],
+                );
+                let next_token = argmax(&masked) as u32;
+                let prob = (logits[next_token as usize] - log_sum_exp(logits)).exp();
+                if next_token == eot || seq_len + 1 >= max_target_positions {
+                    finished[idx] = true;
+                    tokens[idx].push(eot);
+                    continue;
+                }
+                tokens[idx].push(next_token);
+                sampled[idx].push(next_token);
+                token_stats[idx].push((prob, ts_guess));
+            }
+        }
+
+        let mut segments = vec![];
+        for (idx, window) in windows.iter().enumerate() {
+            let token_stats = &token_stats[idx];
+            let avg_logprob = if token_stats.is_empty() {
+                0.
+            } else {
+                token_stats
+                    .iter()
+                    .map(|(p, _)| (*p as f64).ln())
+                    .sum::<f64>()
+                    / token_stats.len() as f64
+            };
+            let no_speech_prob = no_speech_probs[idx] as f64;
+            if no_speech_prob > NO_SPEECH_THRESHOLD && avg_logprob < LOGPROB_THRESHOLD {
+                continue;
+            }
+            let stats = WindowStats {
+                offset: window.start as f64 / SAMPLE_RATE as f64,
+                duration: window.len() as f64 / SAMPLE_RATE as f64,
+                avg_logprob,
+                no_speech_prob,
+            };
+            let WindowSegments {
+                segments: window_segments,
+                open,
+                ..
+            } = window_segments(&self.tokens, &sampled[idx], token_stats, &stats);
+            segments.extend(window_segments);
+            // The whole window has been decoded so the last segment ends with the window.
+            if let Some((start, timings)) = open {
+                segments.push(stats.segment(start, stats.duration, timings))
+            }
+        }
+        Ok(segments)
+    }
+}
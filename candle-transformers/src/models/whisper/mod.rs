@@ -1,6 +1,8 @@
 pub mod audio;
+pub mod long_form;
 pub mod model;
 pub mod quantized_model;
+pub mod streaming;
 
 use serde::Deserialize;
 
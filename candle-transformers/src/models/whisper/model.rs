@@ -1,6 +1,6 @@
 use super::Config;
 use crate::models::with_tracing::{linear, linear_no_bias, Linear};
-use candle::{Device, IndexOp, Result, Tensor, D};
+use candle::{DType, Device, IndexOp, Result, Tensor, D};
 use candle_nn::{embedding, Conv1d, Conv1dConfig, Embedding, LayerNorm, Module, VarBuilder};
 
 fn conv1d(
@@ -325,10 +325,10 @@ impl TextDecoder {
             })
             .collect::<Result<Vec<_>>>()?;
         let ln = layer_norm(n_state, vb.pp("layer_norm"))?;
-        let mask: Vec<_> = (0..n_ctx)
-            .flat_map(|i| (0..n_ctx).map(move |j| if j > i { f32::NEG_INFINITY } else { 0f32 }))
-            .collect();
-        let mask = Tensor::from_vec(mask, (n_ctx, n_ctx), vb.device())?;
+        let mask = candle_nn::attention_mask::to_additive(
+            &candle_nn::attention_mask::causal_mask(n_ctx, 0, vb.device())?,
+            DType::F32,
+        )?;
         Ok(Self {
             token_embedding,
             positional_embedding,
@@ -0,0 +1,628 @@
+//! Streaming transcription on top of the whisper models.
+//!
+//! Audio is pushed in chunks of arbitrary sizes and decoded in windows of up to 30 seconds
+//! with timestamps enabled. Only the segments that have been closed by a timestamp token are
+//! emitted, the audio after the last closed segment is kept around and decoded again once
+//! more samples are available. The text of the emitted segments is used as a prompt when
+//! decoding the following windows.
+use super::SAMPLE_RATE;
+use super::{Config, HOP_LENGTH, LOGPROB_THRESHOLD, NO_SPEECH_THRESHOLD, N_FRAMES, N_SAMPLES};
+use candle::{Device, IndexOp, Result, Tensor};
+
+/// The duration of a timestamp token increment, in seconds.
+pub const TIMESTAMP_RESOLUTION: f64 = 0.02;
+
+// The maximum value for the first timestamp of a window, `<|1.00|>`.
+const MAX_INITIAL_TIMESTAMP: u32 = 50;
+
+/// The operations needed to run the decoding loop, this is implemented for both the
+/// full precision and the quantized models.
+pub trait WhisperModel {
+    fn config(&self) -> &Config;
+    fn encoder_forward(&mut self, x: &Tensor, flush: bool) -> Result<Tensor>;
+    fn decoder_forward(&mut self, x: &Tensor, xa: &Tensor, flush: bool) -> Result<Tensor>;
+    fn decoder_final_linear(&self, x: &Tensor) -> Result<Tensor>;
+}
+
+impl WhisperModel for super::model::Whisper {
+    fn config(&self) -> &Config {
+        &self.config
+    }
+
+    fn encoder_forward(&mut self, x: &Tensor, flush: bool) -> Result<Tensor> {
+        self.encoder.forward(x, flush)
+    }
+
+    fn decoder_forward(&mut self, x: &Tensor, xa: &Tensor, flush: bool) -> Result<Tensor> {
+        self.decoder.forward(x, xa, flush)
+    }
+
+    fn decoder_final_linear(&self, x: &Tensor) -> Result<Tensor> {
+        self.decoder.final_linear(x)
+    }
+}
+
+impl WhisperModel for super::quantized_model::Whisper {
+    fn config(&self) -> &Config {
+        &self.config
+    }
+
+    fn encoder_forward(&mut self, x: &Tensor, flush: bool) -> Result<Tensor> {
+        self.encoder.forward(x, flush)
+    }
+
+    fn decoder_forward(&mut self, x: &Tensor, xa: &Tensor, flush: bool) -> Result<Tensor> {
+        self.decoder.forward(x, xa, flush)
+    }
+
+    fn decoder_final_linear(&self, x: &Tensor) -> Result<Tensor> {
+        self.decoder.final_linear(x)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Task {
+    Transcribe,
+    Translate,
+}
+
+/// The ids of the special tokens used by the decoding loop, these depend on the tokenizer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpecialTokens {
+    pub sot: u32,
+    pub eot: u32,
+    pub transcribe: u32,
+    pub translate: u32,
+    pub start_of_prev: u32,
+    pub no_speech: u32,
+    pub no_timestamps: u32,
+}
+
+impl SpecialTokens {
+    /// The id of the first timestamp token, `<|0.00|>`, timestamp tokens come right after the
+    /// no-timestamps one.
+    pub fn timestamp_begin(&self) -> u32 {
+        self.no_timestamps + 1
+    }
+
+    #[cfg(feature = "tokenizers")]
+    pub fn from_tokenizer(tokenizer: &tokenizers::Tokenizer) -> Result<Self> {
+        let token_id = |token: &str| match tokenizer.token_to_id(token) {
+            None => candle::bail!("no token-id for {token}"),
+            Some(id) => Ok(id),
+        };
+        let no_speech = match super::NO_SPEECH_TOKENS
+            .iter()
+            .find_map(|token| tokenizer.token_to_id(token))
+        {
+            None => candle::bail!("unable to find any non-speech token"),
+            Some(id) => id,
+        };
+        Ok(Self {
+            sot: token_id(super::SOT_TOKEN)?,
+            eot: token_id(super::EOT_TOKEN)?,
+            transcribe: token_id(super::TRANSCRIBE_TOKEN)?,
+            translate: token_id(super::TRANSLATE_TOKEN)?,
+            start_of_prev: token_id("<|startofprev|>")?,
+            no_speech,
+            no_timestamps: token_id(super::NO_TIMESTAMPS_TOKEN)?,
+        })
+    }
+}
+
+/// Runs the language detection pass on a mel spectrogram of shape `(1, n_mels, n_frames)`
+/// and returns the candidate language tokens together with their probabilities, the most
+/// likely language first.
+pub fn detect_language<M: WhisperModel + ?Sized>(
+    model: &mut M,
+    mel: &Tensor,
+    sot_token: u32,
+    language_tokens: &[u32],
+) -> Result<Vec<(u32, f32)>> {
+    if language_tokens.is_empty() {
+        candle::bail!("no candidate language tokens for language detection")
+    }
+    let (_bsize, _, seq_len) = mel.dims3()?;
+    let mel = mel.narrow(2, 0, usize::min(seq_len, N_FRAMES))?;
+    let device = mel.device();
+    let audio_features = model.encoder_forward(&mel, true)?;
+    let tokens = Tensor::new(&[[sot_token]], device)?;
+    let ys = model.decoder_forward(&tokens, &audio_features, true)?;
+    let logits = model.decoder_final_linear(&ys.i(..1)?)?.i(0)?.i(0)?;
+    let logits = logits.index_select(&Tensor::new(language_tokens, device)?, 0)?;
+    let probs = candle_nn::ops::softmax_last_dim(&logits)?.to_vec1::<f32>()?;
+    let mut probs = language_tokens
+        .iter()
+        .copied()
+        .zip(probs)
+        .collect::<Vec<_>>();
+    probs.sort_by(|(_, p1), (_, p2)| p2.total_cmp(p1));
+    Ok(probs)
+}
+
+#[derive(Debug, Clone)]
+pub struct StreamingConfig {
+    pub task: Task,
+    /// The language token, when `None` and `language_candidates` is not empty, the language is
+    /// detected on the first window of audio.
+    pub language: Option<u32>,
+    pub language_candidates: Vec<u32>,
+    /// Use the text of the previous segments as a prompt for the following windows.
+    pub condition_on_previous_text: bool,
+    /// Audio buffers shorter than this, in seconds, are not decoded when flushing.
+    pub min_flush_duration: f64,
+}
+
+impl Default for StreamingConfig {
+    fn default() -> Self {
+        Self {
+            task: Task::Transcribe,
+            language: None,
+            language_candidates: vec![],
+            condition_on_previous_text: true,
+            min_flush_duration: 0.1,
+        }
+    }
+}
+
+/// The timing of a text token, the end time is given by the most likely timestamp token at
+/// the step where the text token was sampled.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TokenTiming {
+    pub token: u32,
+    pub start: f64,
+    pub end: f64,
+    pub prob: f32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Word {
+    pub text: String,
+    pub start: f64,
+    pub end: f64,
+    pub tokens: Vec<u32>,
+}
+
+/// A transcribed segment, times are in seconds from the start of the stream.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    pub start: f64,
+    pub end: f64,
+    /// The text tokens, without any special or timestamp token.
+    pub tokens: Vec<u32>,
+    pub token_timings: Vec<TokenTiming>,
+    pub avg_logprob: f64,
+    pub no_speech_prob: f64,
+}
+
+impl Segment {
+    /// Groups the tokens into words using a decoding function, a token starts a new word when
+    /// its text starts with a whitespace.
+    pub fn words<F: FnMut(&[u32]) -> Result<String>>(&self, mut decode: F) -> Result<Vec<Word>> {
+        let mut words: Vec<Word> = vec![];
+        for timing in self.token_timings.iter() {
+            let piece = decode(&[timing.token])?;
+            match words.last_mut() {
+                Some(word) if !piece.starts_with(char::is_whitespace) => {
+                    word.end = timing.end;
+                    word.tokens.push(timing.token)
+                }
+                _ => words.push(Word {
+                    text: String::new(),
+                    start: timing.start,
+                    end: timing.end,
+                    tokens: vec![timing.token],
+                }),
+            }
+        }
+        for word in words.iter_mut() {
+            word.text = decode(&word.tokens)?.trim().to_string();
+        }
+        Ok(words)
+    }
+}
+
+#[derive(Debug)]
+struct DecodedWindow {
+    segments: Vec<Segment>,
+    // The number of samples from the start of the window that have been consumed.
+    consumed: usize,
+}
+
+/// Transcribes audio pushed in chunks, see the module documentation.
+pub struct StreamingTranscriber<M: WhisperModel> {
+    model: M,
+    tokens: SpecialTokens,
+    config: StreamingConfig,
+    mel_filters: Vec<f32>,
+    suppress_tokens: Vec<u32>,
+    device: Device,
+    language: Option<u32>,
+    buffer: Vec<f32>,
+    buffer_offset: usize,
+    prompt: Vec<u32>,
+}
+
+impl<M: WhisperModel> StreamingTranscriber<M> {
+    pub fn new(
+        model: M,
+        tokens: SpecialTokens,
+        mel_filters: Vec<f32>,
+        config: StreamingConfig,
+        device: &Device,
+    ) -> Result<Self> {
+        let cfg = model.config();
+        if mel_filters.len() % cfg.num_mel_bins != 0 {
+            candle::bail!(
+                "unexpected mel filters size {} for {} mel bins",
+                mel_filters.len(),
+                cfg.num_mel_bins
+            )
+        }
+        let mut suppress_tokens = cfg.suppress_tokens.clone();
+        suppress_tokens.push(tokens.no_timestamps);
+        Ok(Self {
+            language: config.language,
+            model,
+            tokens,
+            config,
+            mel_filters,
+            suppress_tokens,
+            device: device.clone(),
+            buffer: vec![],
+            buffer_offset: 0,
+            prompt: vec![],
+        })
+    }
+
+    pub fn model(&self) -> &M {
+        &self.model
+    }
+
+    /// The language token in use, this is `None` until detection has run when no language
+    /// was set in the configuration.
+    pub fn language(&self) -> Option<u32> {
+        self.language
+    }
+
+    /// The time in seconds of the first sample that has not been transcribed yet.
+    pub fn pending_start(&self) -> f64 {
+        self.buffer_offset as f64 / SAMPLE_RATE as f64
+    }
+
+    /// The duration in seconds of the audio that has not been transcribed yet.
+    pub fn pending_duration(&self) -> f64 {
+        self.buffer.len() as f64 / SAMPLE_RATE as f64
+    }
+
+    /// Adds some 16kHz mono samples to the stream and returns the segments that could be
+    /// completed, a window is only decoded once 30 seconds of audio are available.
+    pub fn push(&mut self, samples: &[f32]) -> Result<Vec<Segment>> {
+        self.buffer.extend_from_slice(samples);
+        let mut segments = vec![];
+        while self.buffer.len() >= N_SAMPLES {
+            segments.extend(self.process_window(false)?)
+        }
+        Ok(segments)
+    }
+
+    /// Decodes all the buffered audio, including the segments that are not closed by a
+    /// timestamp, and resets the stream.
+    pub fn flush(&mut self) -> Result<Vec<Segment>> {
+        let min_samples = (self.config.min_flush_duration * SAMPLE_RATE as f64) as usize;
+        let mut segments = vec![];
+        while !self.buffer.is_empty() && self.buffer.len() >= min_samples.max(HOP_LENGTH) {
+            segments.extend(self.process_window(true)?)
+        }
+        self.buffer_offset += self.buffer.len();
+        self.buffer.clear();
+        self.prompt.clear();
+        Ok(segments)
+    }
+
+    fn process_window(&mut self, is_final: bool) -> Result<Vec<Segment>> {
+        let window_len = usize::min(self.buffer.len(), N_SAMPLES);
+        let num_mel_bins = self.model.config().num_mel_bins;
+        let mel = super::audio::pcm_to_mel(
+            self.model.config(),
+            &self.buffer[..window_len],
+            &self.mel_filters,
+        );
+        let n_frames = mel.len() / num_mel_bins;
+        let mel = Tensor::from_vec(mel, (1, num_mel_bins, n_frames), &self.device)?
+            .narrow(2, 0, N_FRAMES)?;
+        if self.language.is_none() && !self.config.language_candidates.is_empty() {
+            let probs = detect_language(
+                &mut self.model,
+                &mel,
+                self.tokens.sot,
+                &self.config.language_candidates,
+            )?;
+            self.language = Some(probs[0].0)
+        }
+        let window = self.decode_window(&mel, window_len, is_final)?;
+        let consumed = usize::min(window.consumed.max(HOP_LENGTH), self.buffer.len());
+        self.buffer.drain(..consumed);
+        self.buffer_offset += consumed;
+        if self.config.condition_on_previous_text {
+            for segment in window.segments.iter() {
+                self.prompt.extend_from_slice(&segment.tokens)
+            }
+            let max_prompt_len = self.model.config().max_target_positions / 2 - 1;
+            if self.prompt.len() > max_prompt_len {
+                self.prompt.drain(..self.prompt.len() - max_prompt_len);
+            }
+        }
+        Ok(window.segments)
+    }
+
+    fn initial_tokens(&self) -> Vec<u32> {
+        let mut tokens = vec![];
+        if !self.prompt.is_empty() {
+            tokens.push(self.tokens.start_of_prev);
+            tokens.extend_from_slice(&self.prompt);
+        }
+        tokens.push(self.tokens.sot);
+        if let Some(language) = self.language {
+            tokens.push(language)
+        }
+        match self.config.task {
+            Task::Transcribe => tokens.push(self.tokens.transcribe),
+            Task::Translate => tokens.push(self.tokens.translate),
+        }
+        tokens
+    }
+
+    fn decode_window(
+        &mut self,
+        mel: &Tensor,
+        window_len: usize,
+        is_final: bool,
+    ) -> Result<DecodedWindow> {
+        let audio_features = self.model.encoder_forward(mel, true)?;
+        let mut tokens = self.initial_tokens();
+        // The no-speech probability is read from the logits at the sot position.
+        let sot_index = tokens
+            .iter()
+            .rposition(|&t| t == self.tokens.sot)
+            .unwrap_or(0);
+        let ts_begin = self.tokens.timestamp_begin();
+        let max_target_positions = self.model.config().max_target_positions;
+        let sample_len = max_target_positions / 2;
+        let mut sampled: Vec<u32> = vec![];
+        // For each sampled token, its probability and the most likely timestamp at that step.
+        let mut token_stats: Vec<(f32, u32)> = vec![];
+        let mut no_speech_prob = f64::NAN;
+        for i in 0..sample_len {
+            let tokens_t = Tensor::new(tokens.as_slice(), &self.device)?.unsqueeze(0)?;
+            let ys = self
+                .model
+                .decoder_forward(&tokens_t, &audio_features, i == 0)?;
+            if i == 0 {
+                let logits = self
+                    .model
+                    .decoder_final_linear(&ys.i((..1, sot_index..sot_index + 1))?)?
+                    .i(0)?
+                    .i(0)?;
+                no_speech_prob = candle_nn::ops::softmax_last_dim(&logits)?
+                    .i(self.tokens.no_speech as usize)?
+                    .to_scalar::<f32>()? as f64;
+            }
+            let (_, seq_len, _) = ys.dims3()?;
+            let logits = self
+                .model
+                .decoder_final_linear(&ys.i((..1, seq_len - 1..))?)?
+                .i(0)?
+                .i(0)?
+                .to_dtype(candle::DType::F32)?
+                .to_vec1::<f32>()?;
+            let ts_guess =
+                argmax(&logits[(ts_begin as usize).min(logits.len())..]) as u32 + ts_begin;
+            let mut masked = logits.clone();
+            apply_timestamp_rules(&self.tokens, &self.suppress_tokens, &mut masked, &sampled);
+            let next_token = argmax(&masked) as u32;
+            let lse = log_sum_exp(&logits);
+            let prob = (logits[next_token as usize] - lse).exp();
+            if next_token == self.tokens.eot || tokens.len() + 1 >= max_target_positions {
+                break;
+            }
+            tokens.push(next_token);
+            sampled.push(next_token);
+            token_stats.push((prob, ts_guess));
+        }
+        let avg_logprob = if token_stats.is_empty() {
+            0.
+        } else {
+            token_stats
+                .iter()
+                .map(|(p, _)| (*p as f64).ln())
+                .sum::<f64>()
+                / token_stats.len() as f64
+        };
+        let window_duration = window_len as f64 / SAMPLE_RATE as f64;
+        if no_speech_prob > NO_SPEECH_THRESHOLD && avg_logprob < LOGPROB_THRESHOLD {
+            return Ok(DecodedWindow {
+                segments: vec![],
+                consumed: window_len,
+            });
+        }
+        let offset = self.buffer_offset as f64 / SAMPLE_RATE as f64;
+        let stats = WindowStats {
+            offset,
+            duration: window_duration,
+            avg_logprob,
+            no_speech_prob,
+        };
+        let WindowSegments {
+            mut segments,
+            open,
+            last_closed_end,
+        } = window_segments(&self.tokens, &sampled, &token_stats, &stats);
+        let consumed = match (open, last_closed_end) {
+            // The last segment is incomplete, decode it again with more audio.
+            (Some(_), Some(end)) if !is_final => (end * SAMPLE_RATE as f64) as usize,
+            // Without any closed segment the window cannot be decoded again with more audio as
+            // it is already full, so the open segment is emitted up to the end of the window.
+            (Some((start, timings)), _) => {
+                segments.push(stats.segment(start, window_duration, timings));
+                window_len
+            }
+            (None, _) => window_len,
+        };
+        Ok(DecodedWindow { segments, consumed })
+    }
+}
+
+// Masks the logits so that the sampled tokens follow the timestamp rules from
+// https://github.com/openai/whisper/blob/e8622f9afc4eba139bf796c210f5c01081000472/whisper/decoding.py#L439
+pub(super) fn apply_timestamp_rules(
+    tokens: &SpecialTokens,
+    suppress_tokens: &[u32],
+    logits: &mut [f32],
+    sampled: &[u32],
+) {
+    let ts_begin = tokens.timestamp_begin() as usize;
+    let eot = tokens.eot as usize;
+    let is_ts = |t: &u32| *t >= tokens.timestamp_begin();
+    for &t in suppress_tokens.iter() {
+        if let Some(l) = logits.get_mut(t as usize) {
+            *l = f32::NEG_INFINITY
+        }
+    }
+    let last_was_ts = sampled.last().is_some_and(is_ts);
+    let penultimate_was_ts = sampled.len() < 2 || is_ts(&sampled[sampled.len() - 2]);
+    if last_was_ts {
+        if penultimate_was_ts {
+            // Timestamps come in pairs, a text token has to follow a pair.
+            logits[ts_begin..].fill(f32::NEG_INFINITY)
+        } else {
+            // A closing timestamp has to be followed by another timestamp or by eot.
+            logits[..eot].fill(f32::NEG_INFINITY)
+        }
+    }
+    if let Some(&last_ts) = sampled.iter().rev().find(|t| is_ts(t)) {
+        // Timestamps never decrease.
+        let last_ts = if last_was_ts && !penultimate_was_ts {
+            last_ts as usize
+        } else {
+            last_ts as usize + 1
+        };
+        let last_ts = usize::min(last_ts, logits.len());
+        logits[ts_begin..last_ts].fill(f32::NEG_INFINITY)
+    }
+    if sampled.is_empty() {
+        // The window has to start with a timestamp, and not too far in.
+        logits[..ts_begin].fill(f32::NEG_INFINITY);
+        let max_initial = usize::min(ts_begin + MAX_INITIAL_TIMESTAMP as usize + 1, logits.len());
+        logits[max_initial..].fill(f32::NEG_INFINITY)
+    }
+    // When the timestamps are more likely than any text token, sample a timestamp.
+    let ts_logsumexp = log_sum_exp(&logits[ts_begin..]);
+    let text_max = logits[..ts_begin]
+        .iter()
+        .copied()
+        .fold(f32::NEG_INFINITY, f32::max);
+    if ts_logsumexp > text_max {
+        logits[..ts_begin].fill(f32::NEG_INFINITY)
+    }
+}
+
+// The position of a decoded window in the audio stream, with the statistics shared by all its
+// segments.
+pub(super) struct WindowStats {
+    /// The time of the start of the window, in seconds.
+    pub offset: f64,
+    pub duration: f64,
+    pub avg_logprob: f64,
+    pub no_speech_prob: f64,
+}
+
+impl WindowStats {
+    // A segment from times relative to the start of the window.
+    pub fn segment(&self, start: f64, end: f64, mut token_timings: Vec<TokenTiming>) -> Segment {
+        let start = self.offset + start;
+        let end = (self.offset + end).max(start);
+        for timing in token_timings.iter_mut() {
+            timing.start = (self.offset + timing.start).clamp(start, end);
+            timing.end = (self.offset + timing.end).clamp(timing.start, end);
+        }
+        Segment {
+            start,
+            end,
+            tokens: token_timings.iter().map(|t| t.token).collect(),
+            token_timings,
+            avg_logprob: self.avg_logprob,
+            no_speech_prob: self.no_speech_prob,
+        }
+    }
+}
+
+pub(super) struct WindowSegments {
+    /// The segments closed by a timestamp token.
+    pub segments: Vec<Segment>,
+    /// The start time and the token timings of the text after the last closed segment, relative
+    /// to the start of the window.
+    pub open: Option<(f64, Vec<TokenTiming>)>,
+    pub last_closed_end: Option<f64>,
+}
+
+// Splits the tokens sampled for a window into segments using the timestamp tokens,
+// `token_stats` holds the probability of each sampled token and the most likely timestamp at
+// that step.
+pub(super) fn window_segments(
+    tokens: &SpecialTokens,
+    sampled: &[u32],
+    token_stats: &[(f32, u32)],
+    stats: &WindowStats,
+) -> WindowSegments {
+    let ts_begin = tokens.timestamp_begin();
+    let ts_to_s = |t: u32| ((t - ts_begin) as f64 * TIMESTAMP_RESOLUTION).min(stats.duration);
+    let mut segments = vec![];
+    let mut current: Option<(f64, Vec<TokenTiming>)> = None;
+    let mut last_closed_end = None;
+    for (&token, &(prob, ts_guess)) in sampled.iter().zip(token_stats.iter()) {
+        if token >= ts_begin {
+            let t = ts_to_s(token);
+            match current.take() {
+                Some((start, timings)) if !timings.is_empty() => {
+                    segments.push(stats.segment(start, t, timings));
+                    last_closed_end = Some(t);
+                }
+                // An opening timestamp.
+                _ => current = Some((t, vec![])),
+            }
+        } else if token < tokens.eot {
+            // Text without an opening timestamp starts at the end of the previous segment.
+            let start = last_closed_end.unwrap_or(0.);
+            let (start, timings) = current.get_or_insert_with(|| (start, vec![]));
+            let prev_end = timings.last().map_or(*start, |t| t.end);
+            let end = ts_to_s(ts_guess).max(prev_end);
+            timings.push(TokenTiming {
+                token,
+                start: prev_end,
+                end,
+                prob,
+            })
+        }
+    }
+    WindowSegments {
+        segments,
+        open: current.filter(|(_, timings)| !timings.is_empty()),
+        last_closed_end,
+    }
+}
+
+pub(super) fn argmax(xs: &[f32]) -> usize {
+    xs.iter()
+        .enumerate()
+        .max_by(|(_, u), (_, v)| u.total_cmp(v))
+        .map_or(0, |(i, _)| i)
+}
+
+pub(super) fn log_sum_exp(xs: &[f32]) -> f32 {
+    let max = xs.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    if max == f32::NEG_INFINITY {
+        return max;
+    }
+    max + xs.iter().map(|x| (x - max).exp()).sum::<f32>().ln()
+}
@@ -37,37 +37,46 @@ impl Module for Embedding {
 pub struct Linear {
     inner: candle_nn::Linear,
     span: tracing::Span,
+    // The variable prefix, used to record the input activation statistics.
+    name: String,
 }
 
 impl Linear {
-    pub fn from_weights(weights: Tensor, bias: Option<Tensor>) -> Self {
+    /// Builds a linear layer from existing weights, e.g. tied embeddings, `name` is the layer
+    /// path under which the input activations are recorded.
+    pub fn from_weights(weights: Tensor, bias: Option<Tensor>, name: &str) -> Self {
         let inner = candle_nn::Linear::new(weights, bias);
         let span = tracing::span!(tracing::Level::TRACE, "linear");
-        Self { inner, span }
+        let name = name.to_string();
+        Self { inner, span, name }
     }
 }
 
 pub fn linear_b(d1: usize, d2: usize, b: bool, vb: VarBuilder) -> Result<Linear> {
+    let name = vb.prefix();
     let inner = candle_nn::linear_b(d1, d2, b, vb)?;
     let span = tracing::span!(tracing::Level::TRACE, "linear");
-    Ok(Linear { inner, span })
+    Ok(Linear { inner, span, name })
 }
 
 pub fn linear(d1: usize, d2: usize, vb: VarBuilder) -> Result<Linear> {
+    let name = vb.prefix();
     let inner = candle_nn::linear(d1, d2, vb)?;
     let span = tracing::span!(tracing::Level::TRACE, "linear");
-    Ok(Linear { inner, span })
+    Ok(Linear { inner, span, name })
 }
 
 pub fn linear_no_bias(d1: usize, d2: usize, vb: VarBuilder) -> Result<Linear> {
+    let name = vb.prefix();
     let inner = candle_nn::linear_no_bias(d1, d2, vb)?;
     let span = tracing::span!(tracing::Level::TRACE, "linear");
-    Ok(Linear { inner, span })
+    Ok(Linear { inner, span, name })
 }
 
 impl Module for Linear {
     fn forward(&self, xs: &Tensor) -> Result<Tensor> {
         let _enter = self.span.enter();
+        candle_nn::activation_stats::record(&self.name, xs)?;
         self.inner.forward(xs)
     }
 }
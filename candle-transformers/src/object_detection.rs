@@ -1,3 +1,5 @@
+use candle::{DType, Device, Result, Tensor};
+
 /// A bounding box around an object.
 #[derive(Debug, Clone)]
 pub struct Bbox<D> {
@@ -51,6 +53,59 @@ pub fn non_maximum_suppression<D>(bboxes: &mut [Vec<Bbox<D>>], threshold: f32) {
     }
 }
 
+/// Filters the boxes by confidence and applies a per-class non-maximum suppression on the device
+/// of the tensors, returning the kept bounding boxes grouped by class index.
+///
+/// `boxes` has shape `(n, 4)` with `xmin, ymin, xmax, ymax` on the last dimension, `confidences`
+/// has shape `(n,)` and `classes` is a u32 tensor of shape `(n,)`. Only the confidences and the
+/// kept boxes are copied to the host.
+pub fn batched_nms_bboxes<D: Default>(
+    boxes: &Tensor,
+    confidences: &Tensor,
+    classes: &Tensor,
+    nclasses: usize,
+    confidence_threshold: f32,
+    nms_threshold: f32,
+) -> Result<Vec<Vec<Bbox<D>>>> {
+    let candidates = confidences
+        .to_dtype(DType::F32)?
+        .to_vec1::<f32>()?
+        .into_iter()
+        .enumerate()
+        .filter(|(_, c)| *c > confidence_threshold)
+        .map(|(i, _)| i as u32)
+        .collect::<Vec<_>>();
+    let mut bboxes: Vec<Vec<Bbox<D>>> = (0..nclasses).map(|_| vec![]).collect();
+    if candidates.is_empty() {
+        return Ok(bboxes);
+    }
+    let candidates = Tensor::new(candidates, boxes.device())?;
+    let boxes = boxes.to_dtype(DType::F32)?.index_select(&candidates, 0)?;
+    let confidences = confidences
+        .to_dtype(DType::F32)?
+        .index_select(&candidates, 0)?;
+    let classes = classes.index_select(&candidates, 0)?;
+    let keep = candle_nn::detection::batched_nms(&boxes, &confidences, &classes, nms_threshold)?;
+    let boxes = boxes.index_select(&keep, 0)?.to_vec2::<f32>()?;
+    let confidences = confidences.index_select(&keep, 0)?.to_vec1::<f32>()?;
+    let classes = classes.index_select(&keep, 0)?.to_vec1::<u32>()?;
+    for ((b, confidence), class_index) in boxes.into_iter().zip(confidences).zip(classes) {
+        let class_index = class_index as usize;
+        if class_index >= nclasses {
+            candle::bail!("unexpected class index {class_index} for {nclasses} classes")
+        }
+        bboxes[class_index].push(Bbox {
+            xmin: b[0],
+            ymin: b[1],
+            xmax: b[2],
+            ymax: b[3],
+            confidence,
+            data: D::default(),
+        })
+    }
+    Ok(bboxes)
+}
+
 // Updates confidences starting at highest and comparing subsequent boxes.
 fn update_confidences<D>(
     bboxes_for_class: &[Bbox<D>],
@@ -108,3 +163,176 @@ pub fn soft_non_maximum_suppression<D>(
         }
     }
 }
+
+/// The parameters of a letterbox transform, used to map coordinates from the letterboxed image
+/// back to the original image.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Letterbox {
+    /// The ratio between the resized image and the original one.
+    pub scale: f32,
+    /// The number of padding columns added on the left of the resized image.
+    pub pad_left: usize,
+    /// The number of padding rows added on top of the resized image.
+    pub pad_top: usize,
+    /// The height of the original image.
+    pub src_h: usize,
+    /// The width of the original image.
+    pub src_w: usize,
+    /// The height and width of the square output image.
+    pub size: usize,
+}
+
+impl Letterbox {
+    /// Computes the letterbox parameters for an image of size `(src_h, src_w)` resized to fit in
+    /// a `size x size` square while preserving the aspect ratio. Returns an error for empty
+    /// images or an empty output size.
+    pub fn new(src_h: usize, src_w: usize, size: usize) -> Result<Self> {
+        if src_h == 0 || src_w == 0 || size == 0 {
+            candle::bail!("letterbox expects non-empty sizes, got {src_h}x{src_w} to {size}")
+        }
+        let scale = f32::min(size as f32 / src_h as f32, size as f32 / src_w as f32);
+        let (dst_h, dst_w) = Self::resized_dims(src_h, src_w, size);
+        Ok(Self {
+            scale,
+            pad_left: (size - dst_w) / 2,
+            pad_top: (size - dst_h) / 2,
+            src_h,
+            src_w,
+            size,
+        })
+    }
+
+    // The resized dims only rely on integer arithmetic so that they do not depend on float
+    // rounding.
+    fn resized_dims(src_h: usize, src_w: usize, size: usize) -> (usize, usize) {
+        if src_h >= src_w {
+            let dst_w = (src_w * size + src_h / 2) / src_h;
+            (size, dst_w.clamp(1, size))
+        } else {
+            let dst_h = (src_h * size + src_w / 2) / src_w;
+            (dst_h.clamp(1, size), size)
+        }
+    }
+
+    /// The height and width of the resized image, before padding.
+    pub fn resized_hw(&self) -> (usize, usize) {
+        Self::resized_dims(self.src_h, self.src_w, self.size)
+    }
+
+    /// Maps a point from the letterboxed image back to the original image coordinates, the
+    /// result is clamped to the original image bounds.
+    pub fn to_original(&self, x: f32, y: f32) -> (f32, f32) {
+        let x = (x - self.pad_left as f32) / self.scale;
+        let y = (y - self.pad_top as f32) / self.scale;
+        (
+            x.clamp(0., self.src_w as f32),
+            y.clamp(0., self.src_h as f32),
+        )
+    }
+
+    /// Maps a point from the original image coordinates to the letterboxed image.
+    pub fn to_letterboxed(&self, x: f32, y: f32) -> (f32, f32) {
+        let x = x * self.scale + self.pad_left as f32;
+        let y = y * self.scale + self.pad_top as f32;
+        (x, y)
+    }
+
+    /// Maps a bounding box from the letterboxed image back to the original image coordinates.
+    pub fn bbox_to_original<D: Clone>(&self, bbox: &Bbox<D>) -> Bbox<D> {
+        let (xmin, ymin) = self.to_original(bbox.xmin, bbox.ymin);
+        let (xmax, ymax) = self.to_original(bbox.xmax, bbox.ymax);
+        Bbox {
+            xmin,
+            ymin,
+            xmax,
+            ymax,
+            confidence: bbox.confidence,
+            data: bbox.data.clone(),
+        }
+    }
+
+    /// Maps a tensor of boxes from the letterboxed image back to the original image coordinates.
+    ///
+    /// The input tensor should have shape `(..., 4)` with the last dimension containing
+    /// `xmin, ymin, xmax, ymax`. The computation is performed on the device of the tensor.
+    pub fn boxes_to_original(&self, boxes: &Tensor) -> Result<Tensor> {
+        if boxes.dim(candle::D::Minus1)? != 4 {
+            candle::bail!("boxes should have a last dimension of size 4, got {boxes:?}")
+        }
+        let (pl, pt) = (self.pad_left as f32, self.pad_top as f32);
+        let offsets = Tensor::new(&[pl, pt, pl, pt], boxes.device())?.to_dtype(boxes.dtype())?;
+        let (w, h) = (self.src_w as f32, self.src_h as f32);
+        let bounds = Tensor::new(&[w, h, w, h], boxes.device())?.to_dtype(boxes.dtype())?;
+        boxes
+            .broadcast_sub(&offsets)?
+            .affine(1. / self.scale as f64, 0.)?
+            .broadcast_minimum(&bounds)?
+            .relu()
+    }
+}
+
+// Nearest neighbor source indexes computed with integer arithmetic, the pixel centers are
+// aligned the same way as in the `INTER_NEAREST_EXACT` mode of OpenCV.
+fn nearest_indexes(src_len: usize, dst_len: usize, device: &Device) -> Result<Tensor> {
+    if src_len == 0 && dst_len > 0 {
+        candle::bail!("cannot resize an empty dimension to {dst_len}")
+    }
+    let indexes = (0..dst_len)
+        .map(|i| (((2 * i + 1) * src_len / dst_len) / 2).min(src_len - 1) as u32)
+        .collect::<Vec<_>>();
+    Tensor::new(indexes, device)
+}
+
+/// Resizes an image to `(dst_h, dst_w)` using nearest-neighbor sampling.
+///
+/// The source pixel for each destination pixel is computed using integer arithmetic only and the
+/// resizing is performed via index selection on the tensor device, so the result is bit-exact
+/// across backends and works with any dtype, including `u8`. The input tensor should have shape
+/// `(channels, h, w)` or `(batch, channels, h, w)`.
+pub fn resize_nearest_exact(img: &Tensor, dst_h: usize, dst_w: usize) -> Result<Tensor> {
+    let (h_dim, w_dim) = match img.rank() {
+        3 => (1, 2),
+        4 => (2, 3),
+        r => candle::bail!("resize expects an image of rank 3 or 4, got rank {r}"),
+    };
+    let (src_h, src_w) = (img.dim(h_dim)?, img.dim(w_dim)?);
+    let img = if src_h == dst_h {
+        img.clone()
+    } else {
+        img.index_select(&nearest_indexes(src_h, dst_h, img.device())?, h_dim)?
+    };
+    if src_w == dst_w {
+        Ok(img)
+    } else {
+        img.index_select(&nearest_indexes(src_w, dst_w, img.device())?, w_dim)
+    }
+}
+
+/// Letterboxes an image in a `size x size` square, as done in the YOLO preprocessing: the image
+/// is resized preserving its aspect ratio and then padded with `pad_value` on both sides of the
+/// shortest dimension.
+///
+/// The input tensor should have shape `(channels, h, w)` or `(batch, channels, h, w)`. The
+/// returned `Letterbox` can be used to map detections back to the original image coordinates.
+pub fn letterbox(img: &Tensor, size: usize, pad_value: f64) -> Result<(Tensor, Letterbox)> {
+    let rank = img.rank();
+    if rank != 3 && rank != 4 {
+        candle::bail!("letterbox expects an image of rank 3 or 4, got rank {rank}")
+    }
+    let (src_h, src_w) = (img.dim(rank - 2)?, img.dim(rank - 1)?);
+    let lb = Letterbox::new(src_h, src_w, size)?;
+    let (dst_h, dst_w) = lb.resized_hw();
+    let resized = resize_nearest_exact(img, dst_h, dst_w)?;
+    let mut dims = img.dims().to_vec();
+    dims[rank - 2] = size;
+    dims[rank - 1] = size;
+    let canvas = Tensor::ones(dims, img.dtype(), img.device())?.affine(pad_value, 0.)?;
+    let mut ranges = img.dims()[..rank - 2]
+        .iter()
+        .map(|&d| 0..d)
+        .collect::<Vec<_>>();
+    ranges.push(lb.pad_top..lb.pad_top + dst_h);
+    ranges.push(lb.pad_left..lb.pad_left + dst_w);
+    let img = canvas.slice_assign(&ranges, &resized)?;
+    Ok((img, lb))
+}
@@ -1 +1,294 @@
+//! A generic generation loop for causal language models.
+//!
+//! ```ignore
+//! let cancel = CancelToken::new();
+//! let logits_processor = LogitsProcessor::new(299792458, Some(0.8), None);
+//! let mut pipeline = TextGeneration::new(model, logits_processor, &device)
+//!     .with_eos_tokens(vec![eos_token])
+//!     .with_timeout(std::time::Duration::from_secs(30))
+//!     .with_cancel_token(cancel.clone());
+//! // `cancel.cancel()` can be called from another thread, e.g. when the client disconnects.
+//! let output = pipeline.generate_with(&prompt_tokens, 256, |token| {
+//!     print!("{}", tokenizer.decode(&[token], false).unwrap());
+//!     Ok(())
+//! })?;
+//! println!("stopped after {} tokens: {:?}", output.tokens.len(), output.stop_reason);
+//! ```
+use crate::generation::{LogitsProcessor, LogitsProcessorChain, LogitsProcessorTrait};
+use candle::{CancelToken, DType, Device, Result, Tensor};
+use std::ops::ControlFlow;
+use std::time::{Duration, Instant};
 
+/// A language model that processes the tokens incrementally using a kv cache.
+pub trait CausalLm {
+    /// Runs the model on `input_ids` of shape `(1, seq_len)`, these tokens start at position
+    /// `seqlen_offset` in the sequence. Returns the logits for the last position with a shape
+    /// `(1, vocab)` or `(1, 1, vocab)`, or the logits for all the positions with a shape
+    /// `(1, seq_len, vocab)`.
+    fn forward(&mut self, input_ids: &Tensor, seqlen_offset: usize) -> Result<Tensor>;
+
+    fn clear_kv_cache(&mut self);
+}
+
+/// Why a generation stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// `max_new_tokens` tokens have been generated, or the total token budget has been reached.
+    MaxTokens,
+    /// The output ends with the stop sequence with this index.
+    StopSequence(usize),
+    /// The wall-clock limit has been reached.
+    Timeout,
+    /// The cancellation token has been triggered.
+    Cancelled,
+    /// One of the end of sequence tokens has been generated.
+    EosToken,
+}
+
+/// The result of a generation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GenerationOutput {
+    /// The generated tokens, excluding the prompt. This includes the end of sequence token or the
+    /// stop sequence that ended the generation, if any.
+    pub tokens: Vec<u32>,
+    pub stop_reason: StopReason,
+    pub elapsed: Duration,
+}
+
+pub struct TextGeneration<M: CausalLm> {
+    model: M,
+    logits_processor: LogitsProcessor,
+    logits_processors: LogitsProcessorChain,
+    repeat_penalty: f32,
+    repeat_last_n: usize,
+    eos_tokens: Vec<u32>,
+    stop_sequences: Vec<Vec<u32>>,
+    cancel: Option<CancelToken>,
+    timeout: Option<Duration>,
+    max_total_tokens: Option<usize>,
+    prefill_chunk_size: Option<usize>,
+    device: Device,
+}
+
+impl<M: CausalLm> TextGeneration<M> {
+    /// Creates a generation pipeline for a model whose weights are on `device`.
+    pub fn new(model: M, logits_processor: LogitsProcessor, device: &Device) -> Self {
+        Self {
+            model,
+            logits_processor,
+            logits_processors: LogitsProcessorChain::new(),
+            device: device.clone(),
+            repeat_penalty: 1.,
+            repeat_last_n: 64,
+            eos_tokens: vec![],
+            stop_sequences: vec![],
+            cancel: None,
+            timeout: None,
+            max_total_tokens: None,
+            prefill_chunk_size: None,
+        }
+    }
+
+    /// Penalizes the tokens that appear in the last `repeat_last_n` tokens, see
+    /// `utils::apply_repeat_penalty`.
+    pub fn with_repeat_penalty(mut self, repeat_penalty: f32, repeat_last_n: usize) -> Self {
+        self.repeat_penalty = repeat_penalty;
+        self.repeat_last_n = repeat_last_n;
+        self
+    }
+
+    /// Adds a processor applied to the logits before sampling, after the repeat penalty and the
+    /// previously added processors.
+    pub fn with_logits_processor<P: LogitsProcessorTrait + Send + 'static>(
+        mut self,
+        processor: P,
+    ) -> Self {
+        self.logits_processors.push(processor);
+        self
+    }
+
+    /// The tokens that end the generation, they are included in the output.
+    pub fn with_eos_tokens(mut self, eos_tokens: Vec<u32>) -> Self {
+        self.eos_tokens = eos_tokens;
+        self
+    }
+
+    /// The token sequences that end the generation, they are included in the output. The
+    /// empty sequences are ignored.
+    pub fn with_stop_sequences(mut self, stop_sequences: Vec<Vec<u32>>) -> Self {
+        self.stop_sequences = stop_sequences;
+        self
+    }
+
+    /// A wall-clock limit for each generation, checked before each forward pass of the model so
+    /// a single forward pass can exceed it.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// A limit on the number of tokens including the prompt, e.g. the context size of the model.
+    pub fn with_max_total_tokens(mut self, max_total_tokens: usize) -> Self {
+        self.max_total_tokens = Some(max_total_tokens);
+        self
+    }
+
+    /// Processes the prompt by segments of at most `chunk_size` tokens, each segment being
+    /// appended to the kv cache, rather than in a single forward pass. This bounds the size of the
+    /// activations, e.g. the attention scores, on long prompts. The model has to support inputs
+    /// of multiple tokens with a non-zero `seqlen_offset`.
+    pub fn with_prefill_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.prefill_chunk_size = Some(chunk_size.max(1));
+        self
+    }
+
+    /// Sets a token to cancel the generation from another thread. The cancellation is checked
+    /// before each forward pass of the model, the generation then stops and returns the tokens
+    /// generated so far. The kv cache is cleared on cancellation to release its memory.
+    pub fn with_cancel_token(mut self, cancel: CancelToken) -> Self {
+        self.cancel = Some(cancel);
+        self
+    }
+
+    pub fn model(&self) -> &M {
+        &self.model
+    }
+
+    pub fn model_mut(&mut self) -> &mut M {
+        &mut self.model
+    }
+
+    pub fn into_model(self) -> M {
+        self.model
+    }
+
+    // The limits checked before each forward pass.
+    fn check_limits(&mut self, start: Instant) -> Option<StopReason> {
+        if self.cancel.as_ref().is_some_and(|c| c.is_cancelled()) {
+            // Release the memory used by the kv cache.
+            self.model.clear_kv_cache();
+            return Some(StopReason::Cancelled);
+        }
+        if self.timeout.is_some_and(|t| start.elapsed() >= t) {
+            return Some(StopReason::Timeout);
+        }
+        None
+    }
+
+    // Runs the model on the prompt, returning the logits for the last position.
+    fn prefill(
+        &mut self,
+        prompt: &[u32],
+        start: Instant,
+    ) -> Result<ControlFlow<StopReason, Tensor>> {
+        let chunk_size = self.prefill_chunk_size.unwrap_or(prompt.len());
+        let mut logits = None;
+        for (index, chunk) in prompt.chunks(chunk_size).enumerate() {
+            // The limits have already been checked before the first chunk.
+            if index > 0 {
+                if let Some(reason) = self.check_limits(start) {
+                    return Ok(ControlFlow::Break(reason));
+                }
+            }
+            let input = Tensor::new(chunk, &self.device)?.unsqueeze(0)?;
+            logits = Some(self.model.forward(&input, index * chunk_size)?);
+        }
+        match logits {
+            Some(logits) => Ok(ControlFlow::Continue(logits)),
+            None => candle::bail!("the prompt should contain at least one token"),
+        }
+    }
+
+    pub fn generate(&mut self, prompt: &[u32], max_new_tokens: usize) -> Result<GenerationOutput> {
+        self.generate_with(prompt, max_new_tokens, |_| Ok(()))
+    }
+
+    /// Generates up to `max_new_tokens` tokens after `prompt`, calling `on_token` on each
+    /// generated token, e.g. to stream the output. The kv cache of the model is cleared first.
+    ///
+    /// The limits are checked between the decoding steps and the tokens generated so far are
+    /// returned with the reason for stopping.
+    pub fn generate_with<F>(
+        &mut self,
+        prompt: &[u32],
+        max_new_tokens: usize,
+        mut on_token: F,
+    ) -> Result<GenerationOutput>
+    where
+        F: FnMut(u32) -> Result<()>,
+    {
+        if prompt.is_empty() {
+            candle::bail!("the prompt should contain at least one token")
+        }
+        let start = Instant::now();
+        self.model.clear_kv_cache();
+        let mut tokens = prompt.to_vec();
+        let mut generated = vec![];
+        let max_new_tokens = match self.max_total_tokens {
+            None => max_new_tokens,
+            Some(max_total) => max_new_tokens.min(max_total.saturating_sub(prompt.len())),
+        };
+        let mut stop_reason = StopReason::MaxTokens;
+        for index in 0..max_new_tokens {
+            if let Some(reason) = self.check_limits(start) {
+                stop_reason = reason;
+                break;
+            }
+            let logits = if index == 0 {
+                match self.prefill(&tokens, start)? {
+                    ControlFlow::Continue(logits) => logits,
+                    ControlFlow::Break(reason) => {
+                        stop_reason = reason;
+                        break;
+                    }
+                }
+            } else {
+                let start_pos = tokens.len() - 1;
+                let input = Tensor::new(&tokens[start_pos..], &self.device)?.unsqueeze(0)?;
+                self.model.forward(&input, start_pos)?
+            };
+            let logits = logits.squeeze(0)?;
+            let logits = match logits.rank() {
+                2 => logits.get(logits.dim(0)? - 1)?,
+                _ => logits.flatten_all()?,
+            };
+            let logits = logits.to_dtype(DType::F32)?;
+            let logits = if self.repeat_penalty == 1. {
+                logits
+            } else {
+                let start_at = tokens.len().saturating_sub(self.repeat_last_n);
+                crate::utils::apply_repeat_penalty(
+                    &logits,
+                    self.repeat_penalty,
+                    &tokens[start_at..],
+                )?
+            };
+            let logits = if self.logits_processors.is_empty() {
+                logits
+            } else {
+                self.logits_processors.process(&logits, &tokens)?
+            };
+            let next_token = self.logits_processor.sample(&logits)?;
+            tokens.push(next_token);
+            generated.push(next_token);
+            on_token(next_token)?;
+            if self.eos_tokens.contains(&next_token) {
+                stop_reason = StopReason::EosToken;
+                break;
+            }
+            let stop_sequence = self
+                .stop_sequences
+                .iter()
+                .position(|s| !s.is_empty() && generated.ends_with(s));
+            if let Some(index) = stop_sequence {
+                stop_reason = StopReason::StopSequence(index);
+                break;
+            }
+        }
+        Ok(GenerationOutput {
+            tokens: generated,
+            stop_reason,
+            elapsed: start.elapsed(),
+        })
+    }
+}
@@ -110,6 +110,14 @@ impl RmsNorm {
         let weight = weight.dequantize(&weight.device())?;
         Ok(Self { weight, eps, span })
     }
+
+    pub fn prefetch(&self, prefetcher: &crate::utils::Prefetcher) -> Result<Self> {
+        Ok(Self {
+            weight: prefetcher.tensor(&self.weight)?,
+            eps: self.eps,
+            span: self.span.clone(),
+        })
+    }
 }
 
 impl Module for RmsNorm {
@@ -0,0 +1,342 @@
+//! Batched tokenization helpers.
+//!
+//! `TokenizedBatchBuilder` pads and truncates a batch of token sequences and builds the input
+//! ids, attention mask and position ids tensors used by the text models. With the `tokenizers`
+//! feature, the texts can be encoded directly with a `tokenizers::Tokenizer`.
+//!
+//! ```ignore
+//! let batch = TokenizedBatch::builder(pad_id)
+//!     .padding_side(PaddingSide::Left)
+//!     .pad_to_multiple_of(8)
+//!     .max_len(512)
+//!     .device(&device)
+//!     .encode(&tokenizer, &["Hello world", "A longer sentence"], true)?;
+//! let logits = model.forward(&batch.input_ids, &batch.attention_mask)?;
+//! ```
+//!
+//! `ByteTokenizer` and `CharTokenizer` are simple tokenizers for byte and character level
+//! models that do not require a tokenizer file.
+use candle::{DType, Device, Result, Tensor};
+
+/// The side on which the padding tokens are added.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PaddingSide {
+    /// Padding after the tokens, the usual choice for encoders.
+    #[default]
+    Right,
+    /// Padding before the tokens, so that the last token of each sequence is at the same
+    /// position when generating with decoder models.
+    Left,
+}
+
+/// How sequences longer than the maximum length are handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Truncation {
+    /// Keeps the first tokens.
+    #[default]
+    KeepStart,
+    /// Keeps the last tokens, e.g. for the end of a long prompt.
+    KeepEnd,
+    /// Returns an error.
+    Fail,
+}
+
+/// A padded batch of token sequences.
+#[derive(Debug, Clone)]
+pub struct TokenizedBatch {
+    /// The token ids, a u32 tensor of shape `(batch, seq_len)`.
+    pub input_ids: Tensor,
+    /// 1 for the tokens and 0 for the padding, a u32 tensor of shape `(batch, seq_len)`.
+    pub attention_mask: Tensor,
+    /// The position of each token in its sequence, starting at 0 on the first non-padding
+    /// token, the padding has position 0. A u32 tensor of shape `(batch, seq_len)`.
+    pub position_ids: Tensor,
+    /// The number of tokens in each sequence, after truncation and excluding the padding.
+    pub lengths: Vec<usize>,
+    pub padding_side: PaddingSide,
+}
+
+impl TokenizedBatch {
+    pub fn builder(pad_id: u32) -> TokenizedBatchBuilder {
+        TokenizedBatchBuilder::new(pad_id)
+    }
+
+    pub fn batch_size(&self) -> usize {
+        self.lengths.len()
+    }
+
+    pub fn seq_len(&self) -> Result<usize> {
+        self.input_ids.dim(1)
+    }
+
+    /// The index of the last token of each sequence, e.g. to pool the hidden states or to get
+    /// the logits for the next token. This is 0 for empty sequences, an error is returned when
+    /// all the sequences are empty as there is no token to point at.
+    pub fn last_token_indexes(&self) -> Result<Vec<usize>> {
+        let seq_len = self.seq_len()?;
+        if seq_len == 0 {
+            candle::bail!("cannot get the last token indexes of an empty batch")
+        }
+        let indexes = self
+            .lengths
+            .iter()
+            .map(|&len| match self.padding_side {
+                PaddingSide::Left => seq_len - 1,
+                PaddingSide::Right => len.saturating_sub(1),
+            })
+            .collect();
+        Ok(indexes)
+    }
+
+    /// An additive mask of shape `(batch, 1, seq_len, seq_len)` for decoder models, combining
+    /// the causal mask and the padding mask: 0 where attention is allowed and `-inf` elsewhere.
+    /// Padding queries attend to themselves so that the softmax is always well defined.
+    pub fn causal_mask(&self, dtype: DType) -> Result<Tensor> {
+        let (b, l) = self.input_ids.dims2()?;
+        let mask = self.attention_mask.to_vec2::<u32>()?;
+        let mut data = Vec::with_capacity(b * l * l);
+        for row in mask.iter() {
+            for i in 0..l {
+                for j in 0..l {
+                    let allowed = (j <= i && row[j] == 1) || (i == j && row[i] == 0);
+                    data.push(if allowed { 0f32 } else { f32::NEG_INFINITY })
+                }
+            }
+        }
+        Tensor::from_vec(data, (b, 1, l, l), self.input_ids.device())?.to_dtype(dtype)
+    }
+}
+
+/// Builds a `TokenizedBatch`, see the module documentation.
+#[derive(Debug, Clone)]
+pub struct TokenizedBatchBuilder {
+    pad_id: u32,
+    padding_side: PaddingSide,
+    pad_to_multiple_of: Option<usize>,
+    max_len: Option<usize>,
+    truncation: Truncation,
+    device: Device,
+}
+
+impl TokenizedBatchBuilder {
+    pub fn new(pad_id: u32) -> Self {
+        Self {
+            pad_id,
+            padding_side: PaddingSide::Right,
+            pad_to_multiple_of: None,
+            max_len: None,
+            truncation: Truncation::KeepStart,
+            device: Device::Cpu,
+        }
+    }
+
+    pub fn padding_side(mut self, padding_side: PaddingSide) -> Self {
+        self.padding_side = padding_side;
+        self
+    }
+
+    /// Pads the sequences to a length that is a multiple of `multiple`, e.g. 8 to use the
+    /// tensor cores efficiently.
+    pub fn pad_to_multiple_of(mut self, multiple: usize) -> Self {
+        self.pad_to_multiple_of = Some(multiple.max(1));
+        self
+    }
+
+    /// The maximum number of tokens per sequence, longer sequences are truncated.
+    pub fn max_len(mut self, max_len: usize) -> Self {
+        self.max_len = Some(max_len);
+        self
+    }
+
+    pub fn truncation(mut self, truncation: Truncation) -> Self {
+        self.truncation = truncation;
+        self
+    }
+
+    /// The device on which the tensors are created.
+    pub fn device(mut self, device: &Device) -> Self {
+        self.device = device.clone();
+        self
+    }
+
+    fn truncate<'a>(&self, index: usize, ids: &'a [u32]) -> Result<&'a [u32]> {
+        let max_len = match self.max_len {
+            Some(max_len) if ids.len() > max_len => max_len,
+            _ => return Ok(ids),
+        };
+        match self.truncation {
+            Truncation::KeepStart => Ok(&ids[..max_len]),
+            Truncation::KeepEnd => Ok(&ids[ids.len() - max_len..]),
+            Truncation::Fail => candle::bail!(
+                "sequence {index} has {} tokens, above the maximum of {max_len}",
+                ids.len()
+            ),
+        }
+    }
+
+    /// Pads and truncates some token sequences.
+    pub fn build<S: AsRef<[u32]>>(&self, sequences: &[S]) -> Result<TokenizedBatch> {
+        let sequences = sequences
+            .iter()
+            .enumerate()
+            .map(|(i, ids)| self.truncate(i, ids.as_ref()))
+            .collect::<Result<Vec<_>>>()?;
+        let longest = sequences.iter().map(|s| s.len()).max().unwrap_or(0);
+        let seq_len = match self.pad_to_multiple_of {
+            None => longest,
+            Some(multiple) => longest.div_ceil(multiple) * multiple,
+        };
+        let b = sequences.len();
+        let mut input_ids = Vec::with_capacity(b * seq_len);
+        let mut attention_mask = Vec::with_capacity(b * seq_len);
+        let mut position_ids = Vec::with_capacity(b * seq_len);
+        for ids in sequences.iter() {
+            let n_pad = seq_len - ids.len();
+            let pad = std::iter::repeat_n(self.pad_id, n_pad);
+            let positions = 0..ids.len() as u32;
+            match self.padding_side {
+                PaddingSide::Right => {
+                    input_ids.extend(ids.iter().copied().chain(pad));
+                    attention_mask.extend(std::iter::repeat_n(1u32, ids.len()));
+                    attention_mask.extend(std::iter::repeat_n(0u32, n_pad));
+                    position_ids.extend(positions.chain(std::iter::repeat_n(0, n_pad)));
+                }
+                PaddingSide::Left => {
+                    input_ids.extend(pad.chain(ids.iter().copied()));
+                    attention_mask.extend(std::iter::repeat_n(0u32, n_pad));
+                    attention_mask.extend(std::iter::repeat_n(1u32, ids.len()));
+                    position_ids.extend(std::iter::repeat_n(0, n_pad).chain(positions));
+                }
+            }
+        }
+        let shape = (b, seq_len);
+        Ok(TokenizedBatch {
+            input_ids: Tensor::from_vec(input_ids, shape, &self.device)?,
+            attention_mask: Tensor::from_vec(attention_mask, shape, &self.device)?,
+            position_ids: Tensor::from_vec(position_ids, shape, &self.device)?,
+            lengths: sequences.iter().map(|s| s.len()).collect(),
+            padding_side: self.padding_side,
+        })
+    }
+
+    /// Encodes some texts with a tokenizer, then pads and truncates them. The padding and
+    /// truncation configured on the tokenizer, if any, are ignored in favor of the ones of the
+    /// builder.
+    #[cfg(feature = "tokenizers")]
+    pub fn encode<S: AsRef<str>>(
+        &self,
+        tokenizer: &tokenizers::Tokenizer,
+        texts: &[S],
+        add_special_tokens: bool,
+    ) -> Result<TokenizedBatch> {
+        let texts = texts.iter().map(|t| t.as_ref()).collect::<Vec<_>>();
+        let encodings = tokenizer
+            .encode_batch(texts, add_special_tokens)
+            .map_err(|e| candle::Error::Msg(e.to_string()).bt())?;
+        let sequences = encodings
+            .iter()
+            .map(|e| {
+                // Remove the padding that the tokenizer may have added.
+                e.get_ids()
+                    .iter()
+                    .zip(e.get_attention_mask().iter())
+                    .filter(|(_, &m)| m == 1)
+                    .map(|(&id, _)| id)
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        self.build(&sequences)
+    }
+}
+
+/// A byte level tokenizer as used by ByT5: each utf8 byte `b` is mapped to the token
+/// `b + offset`, the tokens below `offset` being reserved for the special tokens.
+#[derive(Debug, Clone, Copy)]
+pub struct ByteTokenizer {
+    offset: u32,
+}
+
+impl ByteTokenizer {
+    pub fn new(offset: u32) -> Self {
+        Self { offset }
+    }
+
+    /// The ByT5 tokenizer: pad 0, eos 1 and unk 2.
+    pub fn byt5() -> Self {
+        Self::new(3)
+    }
+
+    pub fn vocab_size(&self) -> usize {
+        self.offset as usize + 256
+    }
+
+    pub fn encode(&self, text: &str) -> Vec<u32> {
+        text.bytes().map(|b| b as u32 + self.offset).collect()
+    }
+
+    /// Decodes tokens to a string, skipping the special tokens and replacing the invalid utf8
+    /// sequences.
+    pub fn decode(&self, tokens: &[u32]) -> String {
+        let bytes = tokens
+            .iter()
+            .filter(|&&t| t >= self.offset && t < self.offset + 256)
+            .map(|&t| (t - self.offset) as u8)
+            .collect::<Vec<_>>();
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+}
+
+/// A character level tokenizer with a fixed vocabulary, the characters that are not in the
+/// vocabulary are mapped to the `unk` token when there is one.
+#[derive(Debug, Clone)]
+pub struct CharTokenizer {
+    chars: Vec<char>,
+    ids: std::collections::HashMap<char, u32>,
+    unk: Option<u32>,
+}
+
+impl CharTokenizer {
+    /// The token for each character is its index in `vocab`.
+    pub fn new(vocab: &[char], unk: Option<u32>) -> Self {
+        let ids = vocab
+            .iter()
+            .enumerate()
+            .map(|(i, &c)| (c, i as u32))
+            .collect();
+        Self {
+            chars: vocab.to_vec(),
+            ids,
+            unk,
+        }
+    }
+
+    /// Builds a vocabulary from the distinct characters of `text`, sorted.
+    pub fn from_text(text: &str) -> Self {
+        let mut chars = text.chars().collect::<Vec<_>>();
+        chars.sort();
+        chars.dedup();
+        Self::new(&chars, None)
+    }
+
+    pub fn vocab_size(&self) -> usize {
+        self.chars.len()
+    }
+
+    pub fn encode(&self, text: &str) -> Result<Vec<u32>> {
+        text.chars()
+            .map(|c| match (self.ids.get(&c), self.unk) {
+                (Some(&id), _) => Ok(id),
+                (None, Some(unk)) => Ok(unk),
+                (None, None) => candle::bail!("character {c:?} is not in the vocabulary"),
+            })
+            .collect()
+    }
+
+    /// Decodes tokens to a string, skipping the tokens outside of the vocabulary.
+    pub fn decode(&self, tokens: &[u32]) -> String {
+        tokens
+            .iter()
+            .filter_map(|&t| self.chars.get(t as usize))
+            .collect()
+    }
+}
@@ -34,3 +34,286 @@ pub fn repeat_kv(xs: Tensor, n_rep: usize) -> Result<Tensor> {
         Tensor::cat(&vec![&xs; n_rep], 2)?.reshape((b_sz, n_kv_head * n_rep, seq_len, head_dim))
     }
 }
+
+/// Splits the transformer blocks of a model between an accelerator and the cpu, similar to the
+/// `-ngl` option of llama.cpp.
+///
+/// The first `n_gpu_layers` blocks are placed on `device` and the remaining ones on the cpu. The
+/// token embeddings, final norm and output head are only placed on accelerators when all the
+/// blocks are, otherwise they stay on the cpu. Models using a device map move the activations
+/// across the boundaries automatically.
+///
+/// Only [`crate::models::quantized_llama::ModelWeights::from_gguf_with_device_map`] supports a
+/// device map at the moment, this covers the llama and mistral gguf files as both architectures
+/// are loaded by this model. The other models, including the non-quantized `llama` and
+/// `mistral`, place all their weights on a single device.
+///
+/// With [`DeviceMap::with_prefetch`], the weights of the remaining blocks still live on the cpu
+/// but the blocks run on `device`: the weights of the next block are copied to `device` on a
+/// separate stream while the current block computes, hiding the transfer latency.
+///
+/// [`DeviceMap::pipeline`] spreads the blocks over multiple accelerators, e.g. for a model that
+/// does not fit on a single gpu. The embeddings then live on the first device and the output
+/// head on the device of the last block.
+#[derive(Debug, Clone)]
+pub struct DeviceMap {
+    // The accelerators with the number of consecutive blocks placed on each of them.
+    devices: Vec<(candle::Device, usize)>,
+    prefetch: bool,
+}
+
+impl DeviceMap {
+    pub fn new(device: &candle::Device, n_gpu_layers: usize) -> Self {
+        Self::pipeline(&[(device.clone(), n_gpu_layers)])
+    }
+
+    /// Places the blocks on the devices in order, `n_layers` consecutive blocks per device. The
+    /// blocks that are not covered by any device are placed on the cpu.
+    pub fn pipeline(devices: &[(candle::Device, usize)]) -> Self {
+        Self {
+            devices: devices.to_vec(),
+            prefetch: false,
+        }
+    }
+
+    /// Runs the blocks with weights on the cpu on `device`, see the type documentation. This is
+    /// only supported for cuda devices and has no effect on the cpu.
+    pub fn with_prefetch(self, prefetch: bool) -> Self {
+        Self { prefetch, ..self }
+    }
+
+    pub fn prefetch(&self) -> bool {
+        self.prefetch
+    }
+
+    /// Places all the blocks on `device`.
+    pub fn all(device: &candle::Device) -> Self {
+        Self::new(device, usize::MAX)
+    }
+
+    pub fn n_gpu_layers(&self) -> usize {
+        self.devices
+            .iter()
+            .fold(0usize, |acc, (_, n)| acc.saturating_add(*n))
+    }
+
+    /// The device for the block with index `layer_idx`.
+    pub fn layer_device(&self, layer_idx: usize) -> &candle::Device {
+        let mut start = 0usize;
+        for (device, n_layers) in self.devices.iter() {
+            start = start.saturating_add(*n_layers);
+            if layer_idx < start {
+                return device;
+            }
+        }
+        &candle::Device::Cpu
+    }
+
+    /// The device on which the block with index `layer_idx` runs, this differs from the device
+    /// of its weights when prefetching.
+    pub fn compute_device(&self, layer_idx: usize) -> &candle::Device {
+        match self.devices.last() {
+            Some((device, _)) if self.is_prefetched(layer_idx) => device,
+            _ => self.layer_device(layer_idx),
+        }
+    }
+
+    /// Whether the weights of the block with index `layer_idx` have to be prefetched to its
+    /// compute device.
+    pub fn is_prefetched(&self, layer_idx: usize) -> bool {
+        self.prefetch && layer_idx >= self.n_gpu_layers()
+    }
+
+    /// The device for the token embeddings of a model with `n_layers` blocks.
+    pub fn io_device(&self, n_layers: usize) -> &candle::Device {
+        if n_layers <= self.n_gpu_layers() {
+            self.layer_device(0)
+        } else {
+            &candle::Device::Cpu
+        }
+    }
+
+    /// The device for the final norm and output head of a model with `n_layers` blocks.
+    pub fn output_device(&self, n_layers: usize) -> &candle::Device {
+        if n_layers <= self.n_gpu_layers() {
+            self.layer_device(n_layers.saturating_sub(1))
+        } else {
+            &candle::Device::Cpu
+        }
+    }
+
+    /// The device and range of blocks of each pipeline stage for a model with `n_layers` blocks,
+    /// the blocks placed on the cpu form the last stage. Devices without blocks are skipped.
+    pub fn stages(&self, n_layers: usize) -> Vec<(candle::Device, std::ops::Range<usize>)> {
+        let mut stages = vec![];
+        let mut start = 0usize;
+        for (device, len) in self.devices.iter() {
+            let end = start.saturating_add(*len).min(n_layers);
+            if end > start {
+                stages.push((device.clone(), start..end))
+            }
+            start = end;
+        }
+        if start < n_layers {
+            stages.push((candle::Device::Cpu, start..n_layers))
+        }
+        stages
+    }
+}
+
+/// Copies weights living on the cpu to an accelerator ahead of their use.
+///
+/// On cuda devices the copies are queued on a dedicated stream so that they run while the
+/// kernels of the default stream are executing, the values are wrapped in a [`Prefetched`] that
+/// makes the default stream wait for the copies. On other devices the copies are synchronous.
+#[derive(Debug, Clone)]
+pub struct Prefetcher {
+    device: candle::Device,
+    stream: Option<candle::CudaStream>,
+}
+
+impl Prefetcher {
+    pub fn new(device: &candle::Device) -> Result<Self> {
+        let stream = match device {
+            candle::Device::Cuda(cuda) => Some(cuda.new_stream()?),
+            _ => None,
+        };
+        Ok(Self {
+            device: device.clone(),
+            stream,
+        })
+    }
+
+    pub fn device(&self) -> &candle::Device {
+        &self.device
+    }
+
+    pub fn tensor(&self, xs: &Tensor) -> Result<Tensor> {
+        match &self.stream {
+            Some(stream) if xs.device().is_cpu() => xs.to_device_async(stream),
+            _ => xs.to_device(&self.device),
+        }
+    }
+
+    pub fn qtensor(
+        &self,
+        xs: &std::sync::Arc<candle::quantized::QTensor>,
+    ) -> Result<std::sync::Arc<candle::quantized::QTensor>> {
+        if xs.device().same_device(&self.device) {
+            return Ok(xs.clone());
+        }
+        match &self.stream {
+            Some(stream) => Ok(std::sync::Arc::new(xs.to_device_async(stream)?)),
+            None => candle::bail!("cannot prefetch quantized tensors to {:?}", self.device),
+        }
+    }
+
+    /// Wraps a value holding the tensors copied since the last call.
+    pub fn ready<T>(&self, value: T) -> Result<Prefetched<T>> {
+        let event = self.stream.as_ref().map(|s| s.record_event()).transpose()?;
+        Ok(Prefetched {
+            value: Some(value),
+            event,
+        })
+    }
+}
+
+/// A value holding tensors that may still be being copied, see [`Prefetcher`].
+#[derive(Debug)]
+pub struct Prefetched<T> {
+    value: Option<T>,
+    event: Option<candle::CudaEvent>,
+}
+
+impl<T> Prefetched<T> {
+    /// Returns the value, the kernels queued afterwards on the default stream wait for the copies
+    /// to complete. This does not block the host.
+    pub fn wait(mut self) -> Result<T> {
+        if let Some(event) = self.event.take() {
+            event.wait()?
+        }
+        match self.value.take() {
+            Some(value) => Ok(value),
+            None => candle::bail!("prefetched value has already been taken"),
+        }
+    }
+}
+
+impl<T> Drop for Prefetched<T> {
+    fn drop(&mut self) {
+        // The buffers are freed on the default stream, this must happen after the copies.
+        if let Some(event) = self.event.take() {
+            let _ = event.wait();
+        }
+    }
+}
+
+/// The keys and values of the cross-attention layers of an encoder-decoder model.
+///
+/// These only depend on the encoder output so they can be computed once per input and reused
+/// for all the decoding steps rather than being projected again at each step. There is one
+/// `(key, value)` pair per decoder layer, each with shape
+/// `(batch, num_heads, encoder_seq_len, head_dim)`.
+///
+/// When decoding multiple beam hypotheses, the keys and values can be computed for the inputs
+/// and expanded with `repeat_interleave`, then reordered with `index_select` when the beams are
+/// reordered.
+#[derive(Debug, Clone)]
+pub struct CrossAttnKv {
+    kvs: Vec<(Tensor, Tensor)>,
+}
+
+impl CrossAttnKv {
+    pub fn new(kvs: Vec<(Tensor, Tensor)>) -> Self {
+        Self { kvs }
+    }
+
+    pub fn num_layers(&self) -> usize {
+        self.kvs.len()
+    }
+
+    /// The keys and values for the decoder layer with index `layer_idx`.
+    pub fn layer(&self, layer_idx: usize) -> Result<&(Tensor, Tensor)> {
+        match self.kvs.get(layer_idx) {
+            Some(kv) => Ok(kv),
+            None => candle::bail!(
+                "no cross-attention kv for layer {layer_idx}, {} layers",
+                self.kvs.len()
+            ),
+        }
+    }
+
+    pub fn batch_size(&self) -> Result<usize> {
+        match self.kvs.first() {
+            None => Ok(0),
+            Some((k, _)) => k.dim(0),
+        }
+    }
+
+    /// Repeats each batch element `n` times, e.g. for `n` beams per input.
+    pub fn repeat_interleave(&self, n: usize) -> Result<Self> {
+        let repeat = |xs: &Tensor| {
+            let (b, h, s, d) = xs.dims4()?;
+            xs.unsqueeze(1)?
+                .broadcast_as((b, n, h, s, d))?
+                .reshape((b * n, h, s, d))
+        };
+        let kvs = self
+            .kvs
+            .iter()
+            .map(|(k, v)| Ok((repeat(k)?, repeat(v)?)))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self { kvs })
+    }
+
+    /// Selects the batch elements at `indexes`, a u32 tensor, e.g. the source of each beam after
+    /// a beam search step.
+    pub fn index_select(&self, indexes: &Tensor) -> Result<Self> {
+        let kvs = self
+            .kvs
+            .iter()
+            .map(|(k, v)| Ok((k.index_select(indexes, 0)?, v.index_select(indexes, 0)?)))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self { kvs })
+    }
+}
@@ -0,0 +1,142 @@
+use candle::{DType, Device, Result, Tensor};
+use candle_nn::{VarBuilder, VarMap};
+use candle_transformers::models::{marian, t5};
+
+fn randomize(varmap: &VarMap) -> Result<()> {
+    for var in varmap.all_vars() {
+        var.set(&Tensor::randn(0f32, 0.5, var.shape(), var.device())?)?;
+    }
+    Ok(())
+}
+
+fn max_diff(a: &Tensor, b: &Tensor) -> Result<f32> {
+    (a - b)?.abs()?.flatten_all()?.max(0)?.to_scalar::<f32>()
+}
+
+fn tiny_t5() -> Result<t5::T5ForConditionalGeneration> {
+    let cfg = t5::Config {
+        vocab_size: 40,
+        d_model: 16,
+        d_kv: 4,
+        d_ff: 32,
+        num_layers: 2,
+        num_heads: 4,
+        ..Default::default()
+    };
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu);
+    let model = t5::T5ForConditionalGeneration::load(vb, &cfg)?;
+    randomize(&varmap)?;
+    Ok(model)
+}
+
+#[test]
+fn t5_cross_kv() -> Result<()> {
+    let dev = &Device::Cpu;
+    let mut model = tiny_t5()?;
+    let input_ids = Tensor::new(&[[3u32, 7, 11, 5, 1]], dev)?;
+    let encoder_output = model.encode(&input_ids)?;
+    let cross_kv = model.cross_kv(&encoder_output)?;
+    assert_eq!(cross_kv.num_layers(), 2);
+    assert_eq!(cross_kv.layer(0)?.0.dims(), [1, 4, 5, 4]);
+
+    let tokens = [0u32, 9, 4, 17];
+    let mut expected = vec![];
+    for &token in tokens.iter() {
+        let ids = Tensor::new(&[[token]], dev)?;
+        expected.push(model.decode(&ids, &encoder_output)?);
+    }
+    model.clear_kv_cache();
+    for (&token, expected) in tokens.iter().zip(expected.iter()) {
+        let ids = Tensor::new(&[[token]], dev)?;
+        let logits = model.decode_with_cross_kv(&ids, &cross_kv)?;
+        assert!(max_diff(&logits, expected)? < 1e-5);
+    }
+    Ok(())
+}
+
+#[test]
+fn t5_beams() -> Result<()> {
+    let dev = &Device::Cpu;
+    let mut model = tiny_t5()?;
+    let input_ids = Tensor::new(&[[3u32, 7, 11, 5, 1]], dev)?;
+    let encoder_output = model.encode(&input_ids)?;
+    // Three beams sharing the encoder output.
+    let cross_kv = model.cross_kv(&encoder_output)?.repeat_interleave(3)?;
+    assert_eq!(cross_kv.batch_size()?, 3);
+    let steps = [[0u32, 5, 3], [9, 4, 17], [2, 8, 6]];
+    let mut beams = vec![];
+    for step in steps.iter() {
+        let ids = Tensor::new(step, dev)?.unsqueeze(1)?;
+        beams.push(model.decode_with_cross_kv(&ids, &cross_kv)?);
+    }
+    // Each beam matches decoding its tokens on its own.
+    for beam in 0..3 {
+        model.clear_kv_cache();
+        for (step, logits) in steps.iter().zip(beams.iter()) {
+            let ids = Tensor::new(&[[step[beam]]], dev)?;
+            let expected = model.decode(&ids, &encoder_output)?;
+            assert!(max_diff(&logits.narrow(0, beam, 1)?, &expected)? < 1e-5);
+        }
+    }
+
+    // Following beam 2 twice and beam 0 after the first step.
+    model.clear_kv_cache();
+    let ids = Tensor::new(&steps[0], dev)?.unsqueeze(1)?;
+    model.decode_with_cross_kv(&ids, &cross_kv)?;
+    let indexes = Tensor::new(&[2u32, 2, 0], dev)?;
+    model.reorder_kv_cache(&indexes)?;
+    let cross_kv = cross_kv.index_select(&indexes)?;
+    let ids = Tensor::new(&[17u32, 4, 9], dev)?.unsqueeze(1)?;
+    let logits = model.decode_with_cross_kv(&ids, &cross_kv)?;
+    assert!(max_diff(&logits.narrow(0, 0, 1)?, &beams[1].narrow(0, 2, 1)?)? < 1e-5);
+    assert!(max_diff(&logits.narrow(0, 2, 1)?, &beams[1].narrow(0, 0, 1)?)? < 1e-5);
+    Ok(())
+}
+
+#[test]
+fn marian_cross_kv() -> Result<()> {
+    let dev = &Device::Cpu;
+    let cfg = marian::Config {
+        vocab_size: 40,
+        decoder_vocab_size: None,
+        max_position_embeddings: 32,
+        encoder_layers: 2,
+        encoder_ffn_dim: 32,
+        encoder_attention_heads: 4,
+        decoder_layers: 2,
+        decoder_ffn_dim: 32,
+        decoder_attention_heads: 4,
+        use_cache: true,
+        is_encoder_decoder: true,
+        activation_function: candle_nn::Activation::Swish,
+        d_model: 16,
+        decoder_start_token_id: 0,
+        scale_embedding: true,
+        pad_token_id: 0,
+        eos_token_id: 1,
+        forced_eos_token_id: 1,
+        share_encoder_decoder_embeddings: true,
+    };
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, dev);
+    let mut model = marian::MTModel::new(&cfg, vb)?;
+    randomize(&varmap)?;
+    let input_ids = Tensor::new(&[[3u32, 7, 11, 5, 1]], dev)?;
+    let encoder_xs = model.encoder().forward(&input_ids, 0)?;
+    let cross_kv = model.cross_kv(&encoder_xs)?;
+
+    let tokens = [0u32, 9, 4];
+    let mut expected = vec![];
+    for (pos, &token) in tokens.iter().enumerate() {
+        let ids = Tensor::new(&[[token]], dev)?;
+        expected.push(model.decode(&ids, &encoder_xs, pos)?);
+    }
+    model.reset_kv_cache();
+    for (pos, &token) in tokens.iter().enumerate() {
+        let ids = Tensor::new(&[[token]], dev)?;
+        let logits = model.decode_with_cross_kv(&ids, &cross_kv, pos)?;
+        assert!(max_diff(&logits, &expected[pos])? < 1e-5);
+    }
+    Ok(())
+}
@@ -0,0 +1,91 @@
+use candle::{Device, Result, Tensor};
+use candle_transformers::ctc::{
+    ctc_greedy_decode, CtcBeamSearch, CtcBeamSearchConfig, CtcLanguageModel,
+};
+use std::collections::HashMap;
+
+// The log probability of each label sequence, summed over all the alignments.
+fn brute_force(log_probs: &[Vec<f32>], blank: u32) -> HashMap<Vec<u32>, f64> {
+    let (t_len, c) = (log_probs.len(), log_probs[0].len());
+    let mut probs = HashMap::new();
+    for path in 0..c.pow(t_len as u32) {
+        let labels = (0..t_len)
+            .map(|t| ((path / c.pow(t as u32)) % c) as u32)
+            .collect::<Vec<_>>();
+        let log_p = (0..t_len)
+            .map(|t| log_probs[t][labels[t] as usize] as f64)
+            .sum::<f64>();
+        let mut collapsed = labels;
+        collapsed.dedup();
+        collapsed.retain(|&l| l != blank);
+        *probs.entry(collapsed).or_insert(0.) += log_p.exp();
+    }
+    probs
+}
+
+#[test]
+fn greedy() -> Result<()> {
+    let best = [0u32, 1, 1, 0, 1, 2, 2, 0];
+    let log_probs = best
+        .iter()
+        .map(|&b| (0..3).map(|l| if l == b { 0. } else { -5f32 }).collect())
+        .collect::<Vec<Vec<f32>>>();
+    let log_probs = Tensor::new(log_probs, &Device::Cpu)?;
+    assert_eq!(ctc_greedy_decode(&log_probs, 0)?, [1, 1, 2]);
+    Ok(())
+}
+
+#[test]
+fn beam_search() -> Result<()> {
+    let logits = Tensor::randn(0f32, 1., (5, 3), &Device::Cpu)?;
+    let log_probs = candle_nn::ops::log_softmax(&logits, 1)?;
+    let expected = brute_force(&log_probs.to_vec2::<f32>()?, 0);
+    // With a large enough beam, the search is exact.
+    let decoder = CtcBeamSearch::new(CtcBeamSearchConfig {
+        beam_size: 1000,
+        prune_log_prob: f32::NEG_INFINITY,
+        ..Default::default()
+    });
+    let hypotheses = decoder.decode(&log_probs, None)?;
+    assert_eq!(hypotheses.len(), expected.len());
+    for h in hypotheses.iter() {
+        let p = expected[&h.tokens];
+        assert!((h.score as f64 - p.ln()).abs() < 1e-4, "{h:?} {p}");
+    }
+    let best = expected.iter().max_by(|a, b| a.1.total_cmp(b.1)).unwrap().0;
+    assert_eq!(&hypotheses[0].tokens, best);
+
+    let decoder = CtcBeamSearch::new(CtcBeamSearchConfig {
+        beam_size: 2,
+        ..Default::default()
+    });
+    assert_eq!(decoder.decode(&log_probs, None)?.len(), 2);
+    Ok(())
+}
+
+// A language model that strongly discourages the label 2.
+struct NoTwo;
+
+impl CtcLanguageModel for NoTwo {
+    fn log_prob(&mut self, _prefix: &[u32], token: u32) -> Result<f32> {
+        Ok(if token == 2 { -100. } else { -1. })
+    }
+}
+
+#[test]
+fn beam_search_lm() -> Result<()> {
+    let log_probs = Tensor::new(
+        &[[-3f32, -3., -0.1], [-0.1, -3., -3.], [-3., -0.2, -2.]],
+        &Device::Cpu,
+    )?;
+    let decoder = CtcBeamSearch::new(CtcBeamSearchConfig {
+        beam_size: 4,
+        lm_weight: 1.,
+        ..Default::default()
+    });
+    assert_eq!(decoder.decode(&log_probs, None)?[0].tokens, [2, 1]);
+    let hypotheses = decoder.decode(&log_probs, Some(&mut NoTwo))?;
+    assert_eq!(hypotheses[0].tokens, [1]);
+    assert!(hypotheses.iter().all(|h| h.score.is_finite()));
+    Ok(())
+}
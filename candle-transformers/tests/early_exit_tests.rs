@@ -0,0 +1,103 @@
+use candle::{DType, Device, Result, Tensor, D};
+use candle_nn::{VarBuilder, VarMap};
+use candle_transformers::models::llama::{Cache, Config, ExitHead, Llama};
+
+fn tiny_llama() -> Result<(Llama, Config)> {
+    let cfg = Config {
+        hidden_size: 32,
+        intermediate_size: 64,
+        vocab_size: 50,
+        num_hidden_layers: 4,
+        num_attention_heads: 4,
+        num_key_value_heads: 2,
+        use_flash_attn: false,
+        rms_norm_eps: 1e-5,
+        rope_theta: 10_000.,
+        bos_token_id: None,
+        eos_token_id: None,
+        rope_scaling: None,
+        max_position_embeddings: 64,
+    };
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu);
+    let model = Llama::load(vb, &cfg)?;
+    Ok((model, cfg))
+}
+
+fn argmax(logits: &Tensor) -> Result<u32> {
+    logits.squeeze(0)?.argmax(D::Minus1)?.to_scalar::<u32>()
+}
+
+fn greedy(model: &Llama, cfg: &Config, prompt: &[u32], n: usize) -> Result<Vec<u32>> {
+    let mut cache = Cache::new(true, DType::F32, cfg, &Device::Cpu)?;
+    let input = Tensor::new(prompt, &Device::Cpu)?.unsqueeze(0)?;
+    let mut token = argmax(&model.forward(&input, 0, &mut cache)?)?;
+    let mut tokens = vec![token];
+    for i in 1..n {
+        let input = Tensor::new(&[token], &Device::Cpu)?.unsqueeze(0)?;
+        token = argmax(&model.forward(&input, prompt.len() + i - 1, &mut cache)?)?;
+        tokens.push(token)
+    }
+    Ok(tokens)
+}
+
+fn self_speculative(
+    model: &Llama,
+    cfg: &Config,
+    prompt: &[u32],
+    n: usize,
+    exit_layer: usize,
+) -> Result<Vec<u32>> {
+    let mut cache = Cache::new(true, DType::F32, cfg, &Device::Cpu)?;
+    let (last, prompt) = prompt.split_last().unwrap();
+    let input = Tensor::new(prompt, &Device::Cpu)?.unsqueeze(0)?;
+    model.forward(&input, 0, &mut cache)?;
+    let head = ExitHead::default();
+    let mut tokens = vec![];
+    let mut last = *last;
+    let mut index_pos = prompt.len();
+    while tokens.len() < n {
+        let new_tokens =
+            model.self_speculative_step(last, index_pos, &mut cache, 3, exit_layer, &head)?;
+        index_pos += new_tokens.len();
+        last = *new_tokens.last().unwrap();
+        tokens.extend(new_tokens)
+    }
+    tokens.truncate(n);
+    Ok(tokens)
+}
+
+#[test]
+fn early_exit() -> Result<()> {
+    let (model, cfg) = tiny_llama()?;
+    let input = Tensor::new(&[[1u32, 7, 3, 12]], &Device::Cpu)?;
+    let mut cache = Cache::new(false, DType::F32, &cfg, &Device::Cpu)?;
+    let full = model.forward(&input, 0, &mut cache)?;
+    let head = ExitHead::default();
+    let exit = model.forward_early_exit(&input, 0, &mut cache, 4, &head)?;
+    let diff = (full - &exit)?.abs()?.max(D::Minus1)?.max(0)?;
+    assert!(diff.to_scalar::<f32>()? < 1e-5);
+
+    let exit2 = model.forward_early_exit(&input, 0, &mut cache, 2, &head)?;
+    assert_eq!(exit2.dims(), [1, 50]);
+    // Temperature scaling.
+    let hot = model.forward_early_exit(&input, 0, &mut cache, 2, &ExitHead::new(None, 2.))?;
+    let diff = ((exit2 / 2.)? - hot)?.abs()?.max(D::Minus1)?.max(0)?;
+    assert!(diff.to_scalar::<f32>()? < 1e-5);
+    assert!(model
+        .forward_early_exit(&input, 0, &mut cache, 5, &head)
+        .is_err());
+    Ok(())
+}
+
+#[test]
+fn self_speculative_matches_greedy() -> Result<()> {
+    let (model, cfg) = tiny_llama()?;
+    let prompt = [1u32, 7, 3, 12, 5];
+    let expected = greedy(&model, &cfg, &prompt, 12)?;
+    for exit_layer in [1, 2, 4] {
+        let tokens = self_speculative(&model, &cfg, &prompt, 12, exit_layer)?;
+        assert_eq!(tokens, expected, "exit layer {exit_layer}");
+    }
+    Ok(())
+}
@@ -0,0 +1,143 @@
+use candle::{DType, Device, Module, Result, Tensor};
+use candle_transformers::embeddings::{
+    cosine_similarity, pool, Embedder, EmbeddingCache, EmbeddingIndex, EmbeddingModel, Pooling,
+};
+
+fn to_vec2(xs: &Tensor) -> Result<Vec<Vec<f32>>> {
+    xs.to_vec2::<f32>()
+}
+
+#[test]
+fn pooling() -> Result<()> {
+    let dev = &Device::Cpu;
+    let xs = Tensor::arange(0f32, 12., dev)?.reshape((2, 3, 2))?;
+    // The first sequence is right padded, the second one left padded.
+    let mask = Tensor::new(&[[1u32, 1, 0], [0, 1, 1]], dev)?;
+    let mean = pool(&xs, &mask, Pooling::Mean)?;
+    assert_eq!(to_vec2(&mean)?, [[1., 2.], [9., 10.]]);
+    let cls = pool(&xs, &mask, Pooling::Cls)?;
+    assert_eq!(to_vec2(&cls)?, [[0., 1.], [6., 7.]]);
+    let last = pool(&xs, &mask, Pooling::LastToken)?;
+    assert_eq!(to_vec2(&last)?, [[2., 3.], [10., 11.]]);
+    Ok(())
+}
+
+#[test]
+fn similarity_and_index() -> Result<()> {
+    let dev = &Device::Cpu;
+    let docs = Tensor::new(&[[1f32, 0.], [0., 2.], [1., 1.]], dev)?;
+    let sims = cosine_similarity(&docs, &docs)?;
+    let sims = to_vec2(&sims)?;
+    assert!((sims[0][0] - 1.).abs() < 1e-6);
+    assert!(sims[0][1].abs() < 1e-6);
+    assert!((sims[0][2] - 0.5f32.sqrt()).abs() < 1e-6);
+
+    let mut index = EmbeddingIndex::new();
+    assert!(index.is_empty());
+    assert_eq!(index.add(&docs.narrow(0, 0, 2)?)?, 0..2);
+    assert_eq!(index.add(&docs.narrow(0, 2, 1)?)?, 2..3);
+    let queries = Tensor::new(&[[0f32, 3.], [2., 1.9]], dev)?;
+    let hits = index.search(&queries, 2)?;
+    assert_eq!(hits[0].iter().map(|h| h.0).collect::<Vec<_>>(), [1, 2]);
+    assert_eq!(hits[1].iter().map(|h| h.0).collect::<Vec<_>>(), [2, 0]);
+    assert!((hits[0][0].1 - 1.).abs() < 1e-6);
+    assert_eq!(index.search(&queries, 10)?[0].len(), 3);
+
+    // A larger index, the closest entries are scattered among orthogonal ones.
+    let mut docs = [[1f32, 0.]; 3000];
+    docs[1500] = [0., 1.];
+    docs[2900] = [1., 1.];
+    docs[20] = [1., 0.5];
+    let docs = Tensor::new(&docs, dev)?;
+    let mut index = EmbeddingIndex::new();
+    index.add(&docs)?;
+    let hits = index.search(&Tensor::new(&[[0f32, 2.]], dev)?, 3)?;
+    assert_eq!(
+        hits[0].iter().map(|h| h.0).collect::<Vec<_>>(),
+        [1500, 2900, 20]
+    );
+    assert!(index.search(&Tensor::new(&[[0f32, 2.]], dev)?, 0)?[0].is_empty());
+    Ok(())
+}
+
+// A model whose hidden states are the token embeddings.
+struct Lookup(candle_nn::Embedding);
+
+impl EmbeddingModel for Lookup {
+    fn hidden_states(&self, input_ids: &Tensor, _attention_mask: &Tensor) -> Result<Tensor> {
+        self.0.forward(input_ids)
+    }
+}
+
+#[test]
+fn embedder() -> Result<()> {
+    let dev = &Device::Cpu;
+    let table = Tensor::new(&[[0f32, 0.], [3., 0.], [0., 4.], [5., 5.]], dev)?;
+    let model = Lookup(candle_nn::Embedding::new(table, 2));
+    let embedder = Embedder::new(model, Pooling::Mean, 0);
+    let embeddings = embedder.embed_tokens(&[vec![1u32, 2], vec![3]])?;
+    let embeddings = to_vec2(&embeddings)?;
+    // The padding of the second sequence does not contribute to the mean.
+    let s = 0.5f32.sqrt();
+    for (e, expected) in embeddings.iter().zip([[0.6, 0.8], [s, s]]) {
+        assert!((e[0] - expected[0]).abs() < 1e-6 && (e[1] - expected[1]).abs() < 1e-6)
+    }
+
+    let embedder = embedder.with_normalize(false);
+    let embeddings = embedder.embed_tokens(&[vec![1u32, 2]])?;
+    assert_eq!(embeddings.dtype(), DType::F32);
+    assert_eq!(to_vec2(&embeddings)?, [[1.5, 2.]]);
+    Ok(())
+}
+
+// Counts the sequences going through the model.
+struct CountingLookup(candle_nn::Embedding, std::cell::Cell<usize>);
+
+impl EmbeddingModel for CountingLookup {
+    fn hidden_states(&self, input_ids: &Tensor, _attention_mask: &Tensor) -> Result<Tensor> {
+        self.1.set(self.1.get() + input_ids.dim(0)?);
+        self.0.forward(input_ids)
+    }
+}
+
+#[test]
+fn embedder_cache() -> Result<()> {
+    let dev = &Device::Cpu;
+    let table = Tensor::new(&[[0f32, 0.], [3., 0.], [0., 4.], [5., 5.]], dev)?;
+    let model = CountingLookup(candle_nn::Embedding::new(table, 2), Default::default());
+    let embedder = Embedder::new(model, Pooling::Mean, 0).with_normalize(false);
+    assert!(embedder.cache_stats().is_none());
+    let embedder = embedder.with_cache(2);
+    let expected = embedder.embed_tokens(&[vec![1u32, 2], vec![3]])?;
+    assert_eq!(embedder.model().1.get(), 2);
+
+    // The repeated sequences are only embedded once and the cached ones are not recomputed.
+    let embeddings = embedder.embed_tokens(&[vec![3u32], vec![2], vec![1, 2], vec![2]])?;
+    assert_eq!(embedder.model().1.get(), 3);
+    assert_eq!(
+        to_vec2(&embeddings)?,
+        [[5., 5.], [0., 4.], [1.5, 2.], [0., 4.]]
+    );
+    assert_eq!(
+        to_vec2(&embeddings.narrow(0, 2, 1)?)?[0],
+        to_vec2(&expected)?[0]
+    );
+    let stats = embedder.cache_stats().unwrap();
+    assert_eq!((stats.hits, stats.misses), (2, 4));
+    // [3] was evicted when inserting [2], the least recently used entry being [3].
+    assert_eq!((stats.len, stats.capacity, stats.evictions), (2, 2, 1));
+    assert!((stats.hit_rate() - 1. / 3.).abs() < 1e-9);
+
+    embedder.embed_tokens(&[vec![2u32]])?;
+    embedder.embed_tokens(&[vec![3u32]])?;
+    assert_eq!(embedder.model().1.get(), 4);
+    embedder.clear_cache();
+    embedder.embed_tokens(&[vec![2u32]])?;
+    assert_eq!(embedder.model().1.get(), 5);
+
+    let mut cache = EmbeddingCache::new(0);
+    cache.insert(&[1], Tensor::zeros(2, DType::F32, dev)?);
+    assert!(cache.is_empty());
+    assert!(cache.get(&[1]).is_none());
+    Ok(())
+}
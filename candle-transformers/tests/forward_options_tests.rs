@@ -0,0 +1,161 @@
+use candle::{DType, Device, Result, Tensor, D};
+use candle_nn::{VarBuilder, VarMap};
+use candle_transformers::models::outputs::ForwardOptions;
+use candle_transformers::models::{bert, llama};
+
+fn max_abs_diff(a: &Tensor, b: &Tensor) -> Result<f32> {
+    (a - b)?.abs()?.flatten_all()?.max(0)?.to_scalar::<f32>()
+}
+
+// Checks that the attention probabilities sum to one over the key dimension.
+fn check_attention(attn: &Tensor) -> Result<()> {
+    let sums = attn.sum(D::Minus1)?;
+    assert!(max_abs_diff(&sums, &sums.ones_like()?)? < 1e-5);
+    Ok(())
+}
+
+#[test]
+fn llama_forward_options() -> Result<()> {
+    let cfg = llama::Config {
+        hidden_size: 32,
+        intermediate_size: 64,
+        vocab_size: 50,
+        num_hidden_layers: 3,
+        num_attention_heads: 4,
+        num_key_value_heads: 2,
+        use_flash_attn: false,
+        rms_norm_eps: 1e-5,
+        rope_theta: 10_000.,
+        bos_token_id: None,
+        eos_token_id: None,
+        rope_scaling: None,
+        max_position_embeddings: 64,
+    };
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu);
+    let model = llama::Llama::load(vb, &cfg)?;
+    let input = Tensor::new(&[[1u32, 7, 3, 12, 5]], &Device::Cpu)?;
+
+    let mut cache = llama::Cache::new(false, DType::F32, &cfg, &Device::Cpu)?;
+    let logits = model.forward(&input, 0, &mut cache)?;
+    let out = model.forward_with_options(&input, 0, &mut cache, &ForwardOptions::default())?;
+    assert!(max_abs_diff(&logits, &out.output)? < 1e-5);
+    assert!(out.hidden_states.is_none());
+    assert!(out.attentions.is_none());
+
+    let options = ForwardOptions::new()
+        .with_hidden_states(true)
+        .with_attentions(true);
+    let out = model.forward_with_options(&input, 0, &mut cache, &options)?;
+    assert!(max_abs_diff(&logits, &out.output)? < 1e-5);
+    let hidden_states = out.hidden_states.unwrap();
+    assert_eq!(hidden_states.len(), 4);
+    for hs in hidden_states.iter() {
+        assert_eq!(hs.dims(), [1, 5, 32]);
+    }
+    let attentions = out.attentions.unwrap();
+    assert_eq!(attentions.len(), 3);
+    for attn in attentions.iter() {
+        assert_eq!(attn.dims(), [1, 4, 5, 5]);
+        check_attention(attn)?;
+        // The attention is causal.
+        let first = attn.get(0)?.get(0)?.get(0)?.to_vec1::<f32>()?;
+        assert_eq!(first[1..], [0., 0., 0., 0.]);
+    }
+
+    // With a kv cache, the attention covers the past positions.
+    let mut cache = llama::Cache::new(true, DType::F32, &cfg, &Device::Cpu)?;
+    model.forward(&input, 0, &mut cache)?;
+    let next = Tensor::new(&[[4u32]], &Device::Cpu)?;
+    let out = model.forward_with_options(&next, 5, &mut cache, &options)?;
+    assert_eq!(out.attentions.unwrap()[0].dims(), [1, 4, 1, 6]);
+    Ok(())
+}
+
+#[test]
+fn bert_forward_options() -> Result<()> {
+    let cfg: bert::Config = serde_json::from_str(
+        r#"{
+            "vocab_size": 40,
+            "hidden_size": 16,
+            "num_hidden_layers": 2,
+            "num_attention_heads": 2,
+            "intermediate_size": 32,
+            "hidden_act": "gelu",
+            "hidden_dropout_prob": 0.0,
+            "max_position_embeddings": 32,
+            "type_vocab_size": 2,
+            "initializer_range": 0.02,
+            "layer_norm_eps": 1e-12,
+            "pad_token_id": 0,
+            "classifier_dropout": null,
+            "model_type": null
+        }"#,
+    )
+    .unwrap();
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu);
+    let model = bert::BertModel::load(vb, &cfg)?;
+    let input_ids = Tensor::new(&[[2u32, 5, 9, 1], [3, 3, 8, 0]], &Device::Cpu)?;
+    let token_type_ids = input_ids.zeros_like()?;
+    let mask = Tensor::new(&[[1u32, 1, 1, 1], [1, 1, 1, 0]], &Device::Cpu)?;
+
+    let ys = model.forward(&input_ids, &token_type_ids, Some(&mask))?;
+    let options = ForwardOptions::new()
+        .with_hidden_states(true)
+        .with_attentions(true);
+    let out = model.forward_with_options(&input_ids, &token_type_ids, Some(&mask), &options)?;
+    assert!(max_abs_diff(&ys, &out.output)? < 1e-5);
+    let hidden_states = out.hidden_states.unwrap();
+    assert_eq!(hidden_states.len(), 3);
+    assert!(max_abs_diff(&hidden_states[2], &ys)? < 1e-5);
+    let attentions = out.attentions.unwrap();
+    assert_eq!(attentions.len(), 2);
+    for attn in attentions.iter() {
+        assert_eq!(attn.dims(), [2, 2, 4, 4]);
+        check_attention(attn)?;
+        // The padding position is not attended to.
+        let padded = attn.get(1)?.get(0)?.get(0)?.to_vec1::<f32>()?;
+        assert!(padded[3] < 1e-6);
+    }
+    Ok(())
+}
+
+#[test]
+fn llama_error_context() -> Result<()> {
+    let cfg = llama::Config {
+        hidden_size: 32,
+        intermediate_size: 64,
+        vocab_size: 50,
+        num_hidden_layers: 2,
+        num_attention_heads: 4,
+        num_key_value_heads: 2,
+        use_flash_attn: false,
+        rms_norm_eps: 1e-5,
+        rope_theta: 10_000.,
+        bos_token_id: None,
+        eos_token_id: None,
+        rope_scaling: None,
+        max_position_embeddings: 64,
+    };
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu);
+    let model = llama::Llama::load(vb, &cfg)?;
+    let mut cache = llama::Cache::new(false, DType::F32, &cfg, &Device::Cpu)?;
+    // The embeddings have the wrong hidden size, the first block points at its path.
+    let embeds = Tensor::zeros((1, 3, 16), DType::F32, &Device::Cpu)?;
+    let err = model
+        .forward_input_embed(&embeds, 0, &mut cache)
+        .unwrap_err();
+    let context = err.context();
+    assert_eq!(context.len(), 2, "{err}");
+    assert_eq!(context[0].op, Some("rms-norm"));
+    assert_eq!(context[0].shapes[0].dims(), [1, 3, 16]);
+    // The layers built from a VarBuilder record their path.
+    assert_eq!(
+        context[0].module.as_deref(),
+        Some("model.layers.0.input_layernorm")
+    );
+    assert_eq!(context[1].module.as_deref(), Some("model.layers.0"));
+    Ok(())
+}
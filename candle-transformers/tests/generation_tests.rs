@@ -54,3 +54,301 @@ fn sample_with_top_k() -> Result<()> {
     assert_eq!(token, 2);
     Ok(())
 }
+
+// A model that always predicts the last token plus one and cancels its token after a number of
+// forward passes.
+struct Counter {
+    forwards: usize,
+    cache_len: usize,
+    cancel_after: Option<(usize, candle::CancelToken)>,
+    input_lens: Vec<usize>,
+}
+
+impl candle_transformers::pipelines::text_generation::CausalLm for Counter {
+    fn forward(&mut self, input_ids: &Tensor, seqlen_offset: usize) -> Result<Tensor> {
+        assert_eq!(seqlen_offset, self.cache_len);
+        let ids = input_ids.squeeze(0)?.to_vec1::<u32>()?;
+        self.cache_len += ids.len();
+        self.input_lens.push(ids.len());
+        self.forwards += 1;
+        if let Some((n, cancel)) = &self.cancel_after {
+            if self.forwards == *n {
+                cancel.cancel()
+            }
+        }
+        let next = (ids[ids.len() - 1] + 1) % 10;
+        let logits = (0..10u32)
+            .map(|i| if i == next { 1f32 } else { 0. })
+            .collect::<Vec<_>>();
+        Tensor::new(logits, &Device::Cpu)?.reshape((1, 1, 10))
+    }
+
+    fn clear_kv_cache(&mut self) {
+        self.cache_len = 0
+    }
+}
+
+#[test]
+fn text_generation_cancel() -> Result<()> {
+    use candle_transformers::pipelines::text_generation::{StopReason, TextGeneration};
+    let model = Counter {
+        forwards: 0,
+        cache_len: 0,
+        cancel_after: None,
+        input_lens: vec![],
+    };
+    let lp = LogitsProcessor::new(42, None, None);
+    let mut pipeline = TextGeneration::new(model, lp, &Device::Cpu).with_eos_tokens(vec![9]);
+    let output = pipeline.generate(&[3, 4], 10)?;
+    assert_eq!(output.tokens, [5, 6, 7, 8, 9]);
+    assert_eq!(output.stop_reason, StopReason::EosToken);
+    let mut streamed = vec![];
+    let output = pipeline.generate_with(&[1], 3, |t| {
+        streamed.push(t);
+        Ok(())
+    })?;
+    assert_eq!(output.tokens, [2, 3, 4]);
+    assert_eq!(streamed, [2, 3, 4]);
+    assert_eq!(output.stop_reason, StopReason::MaxTokens);
+
+    let cancel = candle::CancelToken::new();
+    let model = Counter {
+        forwards: 0,
+        cache_len: 0,
+        cancel_after: Some((3, cancel.clone())),
+        input_lens: vec![],
+    };
+    let lp = LogitsProcessor::new(42, None, None);
+    let mut pipeline = TextGeneration::new(model, lp, &Device::Cpu).with_cancel_token(cancel);
+    let output = pipeline.generate(&[0], 10)?;
+    assert_eq!(output.tokens, [1, 2, 3]);
+    assert_eq!(output.stop_reason, StopReason::Cancelled);
+    // The kv cache has been released.
+    assert_eq!(pipeline.model().cache_len, 0);
+    Ok(())
+}
+
+#[test]
+fn text_generation_limits() -> Result<()> {
+    use candle_transformers::pipelines::text_generation::{StopReason, TextGeneration};
+    let counter = || Counter {
+        forwards: 0,
+        cache_len: 0,
+        cancel_after: None,
+        input_lens: vec![],
+    };
+    let lp = || LogitsProcessor::new(42, None, None);
+    let mut pipeline = TextGeneration::new(counter(), lp(), &Device::Cpu)
+        .with_eos_tokens(vec![9])
+        .with_stop_sequences(vec![vec![], vec![5, 7], vec![6, 7]]);
+    let output = pipeline.generate(&[3], 10)?;
+    assert_eq!(output.tokens, [4, 5, 6, 7]);
+    assert_eq!(output.stop_reason, StopReason::StopSequence(2));
+
+    // The prompt counts towards the total budget.
+    let mut pipeline = TextGeneration::new(counter(), lp(), &Device::Cpu).with_max_total_tokens(5);
+    let output = pipeline.generate(&[0, 1, 2], 10)?;
+    assert_eq!(output.tokens, [3, 4]);
+    assert_eq!(output.stop_reason, StopReason::MaxTokens);
+    let output = pipeline.generate(&[0, 1, 2, 3, 4, 5], 10)?;
+    assert!(output.tokens.is_empty());
+    assert_eq!(output.stop_reason, StopReason::MaxTokens);
+
+    let mut pipeline =
+        TextGeneration::new(counter(), lp(), &Device::Cpu).with_timeout(std::time::Duration::ZERO);
+    let output = pipeline.generate(&[0], 10)?;
+    assert!(output.tokens.is_empty());
+    assert_eq!(output.stop_reason, StopReason::Timeout);
+    assert_eq!(pipeline.model().forwards, 0);
+    Ok(())
+}
+
+#[test]
+fn text_generation_chunked_prefill() -> Result<()> {
+    use candle_transformers::pipelines::text_generation::{StopReason, TextGeneration};
+    let counter = |cancel_after| Counter {
+        forwards: 0,
+        cache_len: 0,
+        cancel_after,
+        input_lens: vec![],
+    };
+    let lp = || LogitsProcessor::new(42, None, None);
+    let prompt = [0, 1, 2, 3, 4, 5, 6];
+    let mut pipeline =
+        TextGeneration::new(counter(None), lp(), &Device::Cpu).with_prefill_chunk_size(3);
+    let output = pipeline.generate(&prompt, 2)?;
+    assert_eq!(output.tokens, [7, 8]);
+    assert_eq!(pipeline.model().input_lens, [3, 3, 1, 1]);
+
+    // The cancellation is checked between the prompt chunks.
+    let cancel = candle::CancelToken::new();
+    let mut pipeline = TextGeneration::new(counter(Some((2, cancel.clone()))), lp(), &Device::Cpu)
+        .with_prefill_chunk_size(2)
+        .with_cancel_token(cancel);
+    let output = pipeline.generate(&prompt, 2)?;
+    assert!(output.tokens.is_empty());
+    assert_eq!(output.stop_reason, StopReason::Cancelled);
+    assert_eq!(pipeline.model().input_lens, [2, 2]);
+    Ok(())
+}
+
+#[test]
+fn batched_sampling() -> Result<()> {
+    use candle_transformers::generation::{BatchedLogitsProcessor, SamplingParams};
+    let logits = Tensor::new(
+        &[
+            [0.1f32, 2.0, 1.9, -1.0, 0.5],
+            [0.1, 2.0, 1.9, -1.0, 0.5],
+            [0.1, 2.0, 1.9, -1.0, 0.5],
+            [3.0, 2.0, -1.0, -1.0, 2.9],
+        ],
+        &Device::Cpu,
+    )?;
+    let greedy = SamplingParams::default();
+    // A strong repeat penalty on the best token.
+    let repeat = SamplingParams {
+        repeat_penalty: 10.,
+        ..Default::default()
+    };
+    // The frequency penalty applies once per occurrence.
+    let frequency = SamplingParams {
+        frequency_penalty: 0.1,
+        presence_penalty: 0.05,
+        ..Default::default()
+    };
+    let top_k = SamplingParams {
+        temperature: 10.,
+        top_k: Some(2),
+        seed: 1,
+        ..Default::default()
+    };
+    let contexts: [&[u32]; 4] = [&[1, 1], &[1, 1], &[1, 2], &[0, 0]];
+    let mut processor =
+        BatchedLogitsProcessor::new(vec![greedy.clone(), repeat, frequency, top_k.clone()]);
+    let mut samples = vec![];
+    for _ in 0..50 {
+        let tokens = processor.sample(&logits, &contexts)?;
+        assert_eq!(tokens[..3], [1, 2, 1]);
+        samples.push(tokens[3]);
+    }
+    // With a high temperature, all the tokens of the top 2 get sampled.
+    samples.sort();
+    samples.dedup();
+    assert_eq!(samples, [0, 4]);
+
+    // The same settings give the same tokens whatever the rest of the batch.
+    let mut alone = BatchedLogitsProcessor::new(vec![top_k.clone()]);
+    let mut batched = BatchedLogitsProcessor::new(vec![greedy, top_k]);
+    for _ in 0..10 {
+        let t1 = alone.sample(&logits.narrow(0, 3, 1)?, &[&[]])?;
+        let t2 = batched.sample(&logits.narrow(0, 2, 2)?, &[&[], &[]])?;
+        assert_eq!(t1[0], t2[1]);
+    }
+    // A small top-p only keeps the most likely token.
+    let mut processor = BatchedLogitsProcessor::new(vec![SamplingParams {
+        temperature: 1.,
+        top_p: Some(0.1),
+        ..Default::default()
+    }]);
+    assert_eq!(processor.sample(&logits.narrow(0, 3, 1)?, &[&[]])?, [0]);
+    processor.remove(0);
+    assert!(processor.sample(&logits.narrow(0, 3, 1)?, &[&[]]).is_err());
+
+    // Large vocabularies, only the top-k tokens get sampled.
+    let mut large = vec![0f32; 2 * 5000];
+    for (row, token) in [(0, 7), (0, 4000), (0, 4999), (1, 12)] {
+        large[row * 5000 + token] = 10.
+    }
+    let large = Tensor::from_vec(large, (2, 5000), &Device::Cpu)?;
+    let top_k3 = SamplingParams {
+        temperature: 1.,
+        top_k: Some(3),
+        seed: 2,
+        ..Default::default()
+    };
+    let all = SamplingParams {
+        temperature: 1.,
+        ..Default::default()
+    };
+    let mut processor = BatchedLogitsProcessor::new(vec![top_k3, all]);
+    let mut samples = vec![];
+    for _ in 0..50 {
+        let tokens = processor.sample(&large, &[&[], &[]])?;
+        assert!(tokens[1] < 5000);
+        samples.push(tokens[0]);
+    }
+    samples.sort();
+    samples.dedup();
+    assert_eq!(samples, [7, 4000, 4999]);
+    Ok(())
+}
+
+#[test]
+fn logits_processor_chain() -> Result<()> {
+    use candle_transformers::generation::{
+        LogitsProcessorChain, LogitsProcessorTrait, RepeatPenalty,
+    };
+    use candle_transformers::pipelines::text_generation::TextGeneration;
+    // Favors the token that skips one step ahead of the counter.
+    let skip = |logits: &Tensor, tokens: &[u32]| {
+        let target = (tokens[tokens.len() - 1] + 2) % 10;
+        let bias = (0..10u32)
+            .map(|i| if i == target { 2f32 } else { 0. })
+            .collect::<Vec<_>>();
+        logits + Tensor::new(bias, logits.device())?
+    };
+    let counter = Counter {
+        forwards: 0,
+        cache_len: 0,
+        cancel_after: None,
+        input_lens: vec![],
+    };
+    let mut pipeline =
+        TextGeneration::new(counter, LogitsProcessor::new(42, None, None), &Device::Cpu)
+            .with_logits_processor(skip);
+    let output = pipeline.generate(&[0], 4)?;
+    assert_eq!(output.tokens, [2, 4, 6, 8]);
+
+    // The processors are applied in order and all see the context.
+    let mut chain = LogitsProcessorChain::new()
+        .with(RepeatPenalty::new(2., 2))
+        .with(|logits: &Tensor, tokens: &[u32]| logits.affine(1., tokens.len() as f64));
+    assert_eq!(chain.len(), 2);
+    let logits = Tensor::new(&[1f32, 2., -2., 4.], &Device::Cpu)?;
+    let logits = chain.process(&logits, &[0, 2, 3])?;
+    assert_eq!(logits.to_vec1::<f32>()?, [4., 5., -1., 5.]);
+    Ok(())
+}
+
+#[test]
+fn watermark() -> Result<()> {
+    use candle_transformers::generation::watermark::Watermark;
+    use candle_transformers::generation::LogitsProcessorTrait;
+    let mut watermark = Watermark::new(42);
+    let logits = Tensor::zeros(200, candle::DType::F32, &Device::Cpu)?;
+    let mut generate = |watermarked: bool, seed| -> Result<Vec<u32>> {
+        let mut lp = LogitsProcessor::new(seed, Some(1.), None);
+        let mut tokens = vec![0u32];
+        for _ in 0..200 {
+            let logits = if watermarked {
+                watermark.process(&logits, &tokens)?
+            } else {
+                logits.clone()
+            };
+            tokens.push(lp.sample(&logits)?)
+        }
+        Ok(tokens)
+    };
+    let marked = generate(true, 1)?;
+    let unmarked = generate(false, 1)?;
+    let detection = watermark.detect(&marked);
+    assert_eq!(detection.num_tokens_scored, 200);
+    assert!(detection.is_watermarked(4.), "{detection:?}");
+    assert!(detection.green_fraction() > 0.5, "{detection:?}");
+    let detection = watermark.detect(&unmarked);
+    assert!(!detection.is_watermarked(4.), "{detection:?}");
+    // Another key does not detect the watermark.
+    let detection = Watermark::new(43).detect(&marked);
+    assert!(!detection.is_watermarked(4.), "{detection:?}");
+    Ok(())
+}
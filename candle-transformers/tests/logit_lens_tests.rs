@@ -0,0 +1,89 @@
+use candle::{DType, Device, Result, Tensor, D};
+use candle_nn::{VarBuilder, VarMap};
+use candle_transformers::logit_lens::{attention_entropy, LogitLensRecorder};
+use candle_transformers::models::llama::{Cache, Config, Llama};
+use candle_transformers::models::outputs::ForwardOptions;
+
+#[test]
+fn entropy() -> Result<()> {
+    let attn = Tensor::new(
+        &[[1f32, 0., 0., 0.], [0.25, 0.25, 0.25, 0.25]],
+        &Device::Cpu,
+    )?;
+    let entropy = attention_entropy(&attn)?.to_vec1::<f32>()?;
+    assert!(entropy[0].abs() < 1e-6);
+    assert!((entropy[1] - 4f32.ln()).abs() < 1e-6);
+    Ok(())
+}
+
+#[test]
+fn logit_lens_generation() -> Result<()> {
+    let cfg = Config {
+        hidden_size: 32,
+        intermediate_size: 64,
+        vocab_size: 50,
+        num_hidden_layers: 3,
+        num_attention_heads: 4,
+        num_key_value_heads: 2,
+        use_flash_attn: false,
+        rms_norm_eps: 1e-5,
+        rope_theta: 10_000.,
+        bos_token_id: None,
+        eos_token_id: None,
+        rope_scaling: None,
+        max_position_embeddings: 64,
+    };
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu);
+    let model = Llama::load(vb, &cfg)?;
+    let mut cache = Cache::new(true, DType::F32, &cfg, &Device::Cpu)?;
+    let options = ForwardOptions::new()
+        .with_hidden_states(true)
+        .with_attentions(true);
+    let mut recorder = LogitLensRecorder::new(3);
+    let mut tokens = vec![1u32, 7, 3];
+    let mut index_pos = 0;
+    for _ in 0..4 {
+        let input = Tensor::new(&tokens[index_pos..], &Device::Cpu)?.unsqueeze(0)?;
+        let out = model.forward_with_options(&input, index_pos, &mut cache, &options)?;
+        index_pos = tokens.len();
+        let step = recorder.record(&out, |xs| model.project_hidden(xs))?;
+        let next_token = out
+            .output
+            .squeeze(0)?
+            .argmax(D::Minus1)?
+            .to_scalar::<u32>()?;
+        // The lens on the last layer gives the model predictions.
+        let last = step.layers.last().unwrap();
+        assert_eq!(last.top_tokens[0], next_token);
+        assert_eq!(step.layers.len(), 4);
+        assert!(step.layers[0].attention_entropy.is_none());
+        for layer in step.layers[1..].iter() {
+            let entropy = layer.attention_entropy.as_ref().unwrap();
+            assert_eq!(entropy.len(), 4);
+            assert!(entropy
+                .iter()
+                .all(|&e| e >= 0. && e <= (index_pos as f32).ln() + 1e-5));
+        }
+        for layer in step.layers.iter() {
+            assert_eq!(layer.top_tokens.len(), 3);
+            assert!(layer.top_probs.windows(2).all(|w| w[0] >= w[1]));
+        }
+        tokens.push(next_token)
+    }
+    assert_eq!(recorder.steps().len(), 4);
+    let predictions = recorder.layer_predictions(3);
+    assert_eq!(
+        predictions,
+        tokens[3..].iter().map(|&t| Some(t)).collect::<Vec<_>>()
+    );
+    assert!(recorder.convergence_layers().iter().all(|&l| l <= 3));
+
+    // The hidden states are required.
+    let input = Tensor::new(&[[2u32]], &Device::Cpu)?;
+    let out = model.forward_with_options(&input, index_pos, &mut cache, &ForwardOptions::new())?;
+    assert!(recorder
+        .record(&out, |xs| model.project_hidden(xs))
+        .is_err());
+    Ok(())
+}
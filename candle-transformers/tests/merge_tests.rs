@@ -0,0 +1,79 @@
+use candle::test_utils::to_vec1_round;
+use candle::{DType, Device, Result, Tensor};
+use candle_nn::{Init, VarMap};
+use candle_transformers::merge;
+
+#[test]
+fn linear_and_slerp() -> Result<()> {
+    let a = Tensor::new(&[1f32, 0.], &Device::Cpu)?;
+    let b = Tensor::new(&[0f32, 1.], &Device::Cpu)?;
+    let m = merge::linear(&[&a, &b], &[0.25, 0.75])?;
+    assert_eq!(to_vec1_round(&m, 4)?, [0.25, 0.75]);
+
+    // Slerp between orthogonal unit vectors stays on the unit circle.
+    let m = merge::slerp(&a, &b, 0.25)?;
+    assert_eq!(to_vec1_round(&m, 4)?, [0.9239, 0.3827]);
+    let m = merge::slerp(&a, &b, 1. / 3.)?;
+    assert_eq!(to_vec1_round(&m, 4)?, [0.866, 0.5]);
+
+    // Colinear vectors use a linear interpolation.
+    let c = Tensor::new(&[3f32, 0.], &Device::Cpu)?;
+    let m = merge::slerp(&a, &c, 0.5)?;
+    assert_eq!(to_vec1_round(&m, 4)?, [2., 0.]);
+    Ok(())
+}
+
+#[test]
+fn task_arithmetic_and_ties() -> Result<()> {
+    let base = Tensor::new(&[1f32, 1., 1., 1.], &Device::Cpu)?;
+    let ft1 = Tensor::new(&[2f32, 0.9, 1., 0.], &Device::Cpu)?;
+    let ft2 = Tensor::new(&[1.5f32, 1.2, 3., 2.], &Device::Cpu)?;
+    let m = merge::task_arithmetic(&base, &[&ft1, &ft2], &[1., 0.5])?;
+    assert_eq!(to_vec1_round(&m, 4)?, [2.25, 1., 2., 0.5]);
+
+    // Task vectors: [1, -0.1, 0, -1] and [0.5, 0.2, 2, 1].
+    // With density 0.5, the trimmed vectors are [1, 0, 0, -1] and [0, 0, 2, 1]. The elected signs
+    // are [+, 0, +, 0] so the last coordinate, where the task vectors disagree, is dropped.
+    let m = merge::ties(&base, &[&ft1, &ft2], 0.5, 1.)?;
+    assert_eq!(to_vec1_round(&m, 4)?, [2., 1., 3., 1.]);
+
+    // With density 1, the agreeing values are averaged.
+    let m = merge::ties(&base, &[&ft1, &ft2], 1., 1.)?;
+    assert_eq!(to_vec1_round(&m, 4)?, [1.75, 1.2, 3., 1.]);
+    Ok(())
+}
+
+#[test]
+fn merge_varmaps() -> Result<()> {
+    let varmap = |w: f64, step: u32| -> Result<VarMap> {
+        let varmap = VarMap::new();
+        varmap.get(3, "w", Init::Const(w), DType::F32, &Device::Cpu)?;
+        varmap.get(
+            1,
+            "step",
+            Init::Const(step as f64),
+            DType::U32,
+            &Device::Cpu,
+        )?;
+        Ok(varmap)
+    };
+    let (base, ft1, ft2) = (varmap(1., 0)?, varmap(2., 1)?, varmap(4., 2)?);
+    let get = |m: &VarMap, name: &str| m.data().lock().unwrap()[name].as_tensor().clone();
+
+    let m = merge::linear_varmaps(&[&ft1, &ft2], &[0.5, 0.5])?;
+    assert_eq!(to_vec1_round(&get(&m, "w"), 4)?, [3., 3., 3.]);
+    // Integer variables are copied from the first model.
+    assert_eq!(get(&m, "step").to_vec1::<u32>()?, [1]);
+
+    let m = merge::task_arithmetic_varmaps(&base, &[&ft1, &ft2], &[1., 1.])?;
+    assert_eq!(to_vec1_round(&get(&m, "w"), 4)?, [5., 5., 5.]);
+    let m = merge::ties_varmaps(&base, &[&ft1, &ft2], 1., 1.)?;
+    assert_eq!(to_vec1_round(&get(&m, "w"), 4)?, [3., 3., 3.]);
+    let m = merge::slerp_varmaps(&ft1, &ft1, 0.3)?;
+    assert_eq!(to_vec1_round(&get(&m, "w"), 4)?, [2., 2., 2.]);
+
+    let other = VarMap::new();
+    other.get(3, "v", Init::Const(0.), DType::F32, &Device::Cpu)?;
+    assert!(merge::linear_varmaps(&[&ft1, &other], &[0.5, 0.5]).is_err());
+    Ok(())
+}
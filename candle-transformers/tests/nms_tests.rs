@@ -1,6 +1,6 @@
-use candle::Result;
+use candle::{Device, Result, Tensor};
 use candle_transformers::object_detection::{
-    non_maximum_suppression, soft_non_maximum_suppression, Bbox,
+    batched_nms_bboxes, non_maximum_suppression, soft_non_maximum_suppression, Bbox,
 };
 
 #[test]
@@ -220,3 +220,33 @@ fn softnms_equal_confidence_overlap() -> Result<()> {
     assert!(bboxes[0][1].confidence < 0.5);
     Ok(())
 }
+
+#[test]
+fn batched_nms_bboxes_basic() -> Result<()> {
+    let dev = &Device::Cpu;
+    let boxes = Tensor::new(
+        &[
+            [0f32, 0., 10., 10.],
+            [1., 1., 10., 10.],
+            [0., 0., 10., 10.],
+            [20., 20., 30., 30.],
+            [21., 21., 30., 30.],
+        ],
+        dev,
+    )?;
+    let scores = Tensor::new(&[0.9f32, 0.8, 0.7, 0.3, 0.6], dev)?;
+    let classes = Tensor::new(&[0u32, 0, 1, 0, 0], dev)?;
+    let bboxes = batched_nms_bboxes::<()>(&boxes, &scores, &classes, 2, 0.5, 0.5)?;
+    assert_eq!(bboxes.len(), 2);
+    // The second box overlaps the first one, the fourth box is below the threshold and the third
+    // box is kept as it has a different class.
+    let confidences = bboxes
+        .iter()
+        .map(|b| b.iter().map(|b| b.confidence).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    assert_eq!(confidences, [vec![0.9, 0.6], vec![0.7]]);
+    assert_eq!(bboxes[0][1].xmin, 21.);
+    let bboxes = batched_nms_bboxes::<()>(&boxes, &scores, &classes, 2, 1., 0.5)?;
+    assert!(bboxes.iter().all(|b| b.is_empty()));
+    Ok(())
+}
@@ -0,0 +1,71 @@
+use candle::{Device, Result, Tensor};
+use candle_transformers::object_detection::{letterbox, resize_nearest_exact, Bbox, Letterbox};
+
+#[test]
+fn resize_nearest_exact_u8() -> Result<()> {
+    let img = Tensor::new(&[[[1u8, 2, 3], [4, 5, 6]]], &Device::Cpu)?;
+    let resized = resize_nearest_exact(&img, 4, 6)?;
+    assert_eq!(
+        resized.to_vec3::<u8>()?,
+        [[
+            [1, 1, 2, 2, 3, 3],
+            [1, 1, 2, 2, 3, 3],
+            [4, 4, 5, 5, 6, 6],
+            [4, 4, 5, 5, 6, 6]
+        ]]
+    );
+    let resized = resize_nearest_exact(&img, 1, 2)?;
+    assert_eq!(resized.to_vec3::<u8>()?, [[[4, 6]]]);
+    let empty = Tensor::zeros((1, 0, 3), candle::DType::U8, &Device::Cpu)?;
+    assert!(resize_nearest_exact(&empty, 2, 3).is_err());
+    Ok(())
+}
+
+#[test]
+fn letterbox_and_back() -> Result<()> {
+    let img = Tensor::new(&[[[1u8, 2], [3, 4], [5, 6], [7, 8]]], &Device::Cpu)?;
+    let (lb_img, lb) = letterbox(&img, 8, 114.)?;
+    assert_eq!(
+        lb,
+        Letterbox {
+            scale: 2.,
+            pad_left: 2,
+            pad_top: 0,
+            src_h: 4,
+            src_w: 2,
+            size: 8,
+        }
+    );
+    assert_eq!(lb_img.dims(), [1, 8, 8]);
+    let lb_img = lb_img.to_vec3::<u8>()?;
+    assert_eq!(lb_img[0][0], [114, 114, 1, 1, 2, 2, 114, 114]);
+    assert_eq!(lb_img[0][7], [114, 114, 7, 7, 8, 8, 114, 114]);
+
+    let bbox = Bbox {
+        xmin: 2.,
+        ymin: 2.,
+        xmax: 7.,
+        ymax: 6.,
+        confidence: 0.5,
+        data: (),
+    };
+    let bbox = lb.bbox_to_original(&bbox);
+    assert_eq!(
+        (bbox.xmin, bbox.ymin, bbox.xmax, bbox.ymax),
+        (0., 1., 2., 3.)
+    );
+    assert_eq!(lb.to_letterboxed(1., 3.), (4., 6.));
+
+    let boxes = Tensor::new(&[[2f32, 2., 7., 6.], [0., 0., 4., 4.]], &Device::Cpu)?;
+    let boxes = lb.boxes_to_original(&boxes)?;
+    assert_eq!(
+        boxes.to_vec2::<f32>()?,
+        [[0., 1., 2., 3.], [0., 0., 1., 2.]]
+    );
+
+    // Empty images are rejected.
+    let empty = Tensor::zeros((3, 0, 4), candle::DType::U8, &Device::Cpu)?;
+    assert!(letterbox(&empty, 8, 114.).is_err());
+    assert!(Letterbox::new(4, 0, 8).is_err());
+    Ok(())
+}
@@ -0,0 +1,215 @@
+use candle::quantized::{gguf_file, GgmlDType, QTensor};
+use candle::{Device, Result, Tensor};
+use candle_transformers::models::quantized_llama::ModelWeights;
+use candle_transformers::utils::DeviceMap;
+
+const N_LAYERS: usize = 3;
+
+// Writes a tiny random llama model in the gguf format.
+fn tiny_gguf() -> Result<Vec<u8>> {
+    let dev = &Device::Cpu;
+    let (vocab, hidden, ff, kv_dim) = (40, 32, 64, 16);
+    let q = |dims: (usize, usize)| -> Result<QTensor> {
+        QTensor::quantize(&Tensor::randn(0f32, 0.2, dims, dev)?, GgmlDType::F32)
+    };
+    let norm = || {
+        QTensor::quantize(
+            &Tensor::ones(hidden, candle::DType::F32, dev)?,
+            GgmlDType::F32,
+        )
+    };
+    let mut tensors = vec![
+        ("token_embd.weight".to_string(), q((vocab, hidden))?),
+        ("output_norm.weight".to_string(), norm()?),
+        ("output.weight".to_string(), q((vocab, hidden))?),
+    ];
+    for i in 0..N_LAYERS {
+        let p = format!("blk.{i}");
+        tensors.push((format!("{p}.attn_q.weight"), q((hidden, hidden))?));
+        tensors.push((format!("{p}.attn_k.weight"), q((kv_dim, hidden))?));
+        tensors.push((format!("{p}.attn_v.weight"), q((kv_dim, hidden))?));
+        tensors.push((format!("{p}.attn_output.weight"), q((hidden, hidden))?));
+        tensors.push((format!("{p}.ffn_gate.weight"), q((ff, hidden))?));
+        tensors.push((format!("{p}.ffn_down.weight"), q((hidden, ff))?));
+        tensors.push((format!("{p}.ffn_up.weight"), q((ff, hidden))?));
+        tensors.push((format!("{p}.attn_norm.weight"), norm()?));
+        tensors.push((format!("{p}.ffn_norm.weight"), norm()?));
+    }
+    let u32 = gguf_file::Value::U32;
+    let metadata = [
+        ("llama.attention.head_count", u32(4)),
+        ("llama.attention.head_count_kv", u32(2)),
+        ("llama.block_count", u32(N_LAYERS as u32)),
+        ("llama.embedding_length", u32(hidden as u32)),
+        ("llama.rope.dimension_count", u32(8)),
+        (
+            "llama.attention.layer_norm_rms_epsilon",
+            gguf_file::Value::F32(1e-5),
+        ),
+    ];
+    let metadata = metadata.iter().map(|(k, v)| (*k, v)).collect::<Vec<_>>();
+    let tensors = tensors
+        .iter()
+        .map(|(k, v)| (k.as_str(), v))
+        .collect::<Vec<_>>();
+    let mut buf = std::io::Cursor::new(vec![]);
+    gguf_file::write(&mut buf, &metadata, &tensors)?;
+    Ok(buf.into_inner())
+}
+
+fn load(data: &[u8], device_map: &DeviceMap) -> Result<ModelWeights> {
+    let mut reader = std::io::Cursor::new(data);
+    let ct = gguf_file::Content::read(&mut reader)?;
+    ModelWeights::from_gguf_with_device_map(ct, &mut reader, device_map)
+}
+
+#[test]
+fn device_map() {
+    let map = DeviceMap::new(&Device::Cpu, 2);
+    assert_eq!(map.n_gpu_layers(), 2);
+    assert!(map.layer_device(1).is_cpu());
+    assert!(map.io_device(2).is_cpu());
+}
+
+#[test]
+fn partial_offload() -> Result<()> {
+    let data = tiny_gguf()?;
+    let mut full = load(&data, &DeviceMap::all(&Device::Cpu))?;
+    let mut split = load(&data, &DeviceMap::new(&Device::Cpu, 1))?;
+    let prompt = Tensor::new(&[[1u32, 5, 7, 2]], &Device::Cpu)?;
+    let next = Tensor::new(&[[9u32]], &Device::Cpu)?;
+    for (input, index_pos) in [(&prompt, 0), (&next, 4)] {
+        let expected = full.forward(input, index_pos)?;
+        let logits = split.forward(input, index_pos)?;
+        assert_eq!(logits.dims(), [1, 40]);
+        assert_eq!(logits.to_vec2::<f32>()?, expected.to_vec2::<f32>()?);
+    }
+    Ok(())
+}
+
+#[test]
+fn prefetched_offload() -> Result<()> {
+    let data = tiny_gguf()?;
+    let map = DeviceMap::new(&Device::Cpu, 1).with_prefetch(true);
+    assert!(!map.is_prefetched(0) && map.is_prefetched(2));
+    assert!(map.compute_device(2).is_cpu());
+    let mut full = load(&data, &DeviceMap::all(&Device::Cpu))?;
+    let mut prefetched = load(&data, &map)?;
+    let prompt = Tensor::new(&[[3u32, 1, 4]], &Device::Cpu)?;
+    let next = Tensor::new(&[[1u32]], &Device::Cpu)?;
+    for (input, index_pos) in [(&prompt, 0), (&next, 3)] {
+        let expected = full.forward(input, index_pos)?;
+        let logits = prefetched.forward(input, index_pos)?;
+        assert_eq!(logits.to_vec2::<f32>()?, expected.to_vec2::<f32>()?);
+    }
+    Ok(())
+}
+
+#[test]
+fn static_kv_cache() -> Result<()> {
+    let data = tiny_gguf()?;
+    let mut full = load(&data, &DeviceMap::all(&Device::Cpu))?;
+    let mut model = load(&data, &DeviceMap::all(&Device::Cpu))?.with_static_kv_cache(8);
+    let prompt = Tensor::new(&[[3u32, 1, 4]], &Device::Cpu)?;
+    let next = Tensor::new(&[[1u32]], &Device::Cpu)?;
+    for (input, index_pos) in [(&prompt, 0), (&next, 3), (&next, 4)] {
+        let seq_len = input.dim(1)? as u32;
+        let index_pos = index_pos as u32;
+        let positions = Tensor::arange(index_pos, index_pos + seq_len, &Device::Cpu)?;
+        let expected = full.forward(input, index_pos as usize)?;
+        let logits = model.forward_positions(input, &positions)?;
+        let diff = (logits - expected)?.abs()?.flatten_all()?.max(0)?;
+        assert!(diff.to_scalar::<f32>()? < 1e-5);
+    }
+    Ok(())
+}
+
+// The decoding steps with the static kv cache are captured as a CUDA graph and replayed.
+#[cfg(feature = "cuda")]
+#[test]
+fn graphed_decode_cuda() -> Result<()> {
+    use candle_nn::GraphedModule;
+    let data = tiny_gguf()?;
+    let cuda = Device::new_cuda_with_stream(0)?;
+    let mut full = load(&data, &DeviceMap::all(&Device::Cpu))?;
+    let mut model = load(&data, &DeviceMap::all(&cuda))?.with_static_kv_cache(16);
+    let prompt = Tensor::new(&[[3u32, 1, 4]], &Device::Cpu)?;
+    let expected = full.forward(&prompt, 0)?;
+    let positions = Tensor::arange(0u32, 3, &cuda)?;
+    let logits = model.forward_positions(&prompt.to_device(&cuda)?, &positions)?;
+    let close = |logits: &Tensor, expected: &Tensor| -> Result<bool> {
+        let diff = (logits.to_device(&Device::Cpu)? - expected)?.abs()?;
+        Ok(diff.flatten_all()?.max(0)?.to_scalar::<f32>()? < 1e-4)
+    };
+    assert!(close(&logits, &expected)?);
+    let mut graphed = GraphedModule::new(model);
+    for (index_pos, token) in [1u32, 5, 9, 2, 6].into_iter().enumerate() {
+        let index_pos = index_pos + 3;
+        let input = Tensor::new(&[[token]], &Device::Cpu)?;
+        let expected = full.forward(&input, index_pos)?;
+        let position = Tensor::new(&[index_pos as u32], &cuda)?;
+        let logits = graphed.run(&[&input.to_device(&cuda)?, &position], |m, xs| {
+            m.forward_positions(&xs[0], &xs[1])
+        })?;
+        assert!(close(&logits, &expected)?);
+    }
+    assert!(graphed.is_captured());
+    assert_eq!(graphed.replays(), 3);
+    Ok(())
+}
+
+// The offloaded blocks go through the pinned staging buffers of the prefetch stream, the same
+// staging buffers are reused by the copies of the second forward pass.
+#[cfg(feature = "cuda")]
+#[test]
+fn prefetched_offload_cuda() -> Result<()> {
+    let data = tiny_gguf()?;
+    let cuda = Device::new_cuda(0)?;
+    let map = DeviceMap::new(&cuda, 1).with_prefetch(true);
+    assert!(map.compute_device(2).is_cuda());
+    let mut full = load(&data, &DeviceMap::all(&Device::Cpu))?;
+    let mut prefetched = load(&data, &map)?;
+    let prompt = Tensor::new(&[[3u32, 1, 4]], &Device::Cpu)?;
+    let next = Tensor::new(&[[1u32]], &Device::Cpu)?;
+    for (input, index_pos) in [(&prompt, 0), (&next, 3), (&prompt, 4)] {
+        let expected = full.forward(input, index_pos)?;
+        let logits = prefetched.forward(input, index_pos)?;
+        let diff = (logits.to_device(&Device::Cpu)? - expected)?.abs()?;
+        assert!(diff.flatten_all()?.max(0)?.to_scalar::<f32>()? < 1e-4);
+    }
+    Ok(())
+}
+
+#[test]
+fn pipeline_stages() -> Result<()> {
+    use candle_nn::Pipeline;
+    let data = tiny_gguf()?;
+    let map = DeviceMap::pipeline(&[(Device::Cpu, 1), (Device::Cpu, 1)]);
+    let ranges = map.stages(N_LAYERS).into_iter().map(|(_, r)| r);
+    assert_eq!(ranges.collect::<Vec<_>>(), [0..1, 1..2, 2..3]);
+    let stages = load(&data, &map)?.into_pipeline_stages(&map)?;
+    assert_eq!(stages.iter().map(|s| s.n_layers()).sum::<usize>(), N_LAYERS);
+    let mut pipeline = Pipeline::new(stages)?;
+    // One model per micro-batch as the kv caches are held by the models.
+    let mut full = [
+        load(&data, &DeviceMap::all(&Device::Cpu))?,
+        load(&data, &DeviceMap::all(&Device::Cpu))?,
+    ];
+    let prompts = [
+        Tensor::new(&[[1u32, 5, 7], [2, 3, 4]], &Device::Cpu)?,
+        Tensor::new(&[[9u32, 8, 7], [6, 5, 4]], &Device::Cpu)?,
+    ];
+    let next = [
+        Tensor::new(&[[9u32], [1]], &Device::Cpu)?,
+        Tensor::new(&[[3u32], [2]], &Device::Cpu)?,
+    ];
+    for (inputs, index_pos) in [(&prompts, 0), (&next, 3)] {
+        let logits = pipeline.forward(inputs, index_pos)?;
+        for ((input, model), logits) in inputs.iter().zip(full.iter_mut()).zip(logits.iter()) {
+            let expected = model.forward(input, index_pos)?;
+            let diff = (logits - expected)?.abs()?.flatten_all()?.max(0)?;
+            assert!(diff.to_scalar::<f32>()? < 1e-5);
+        }
+    }
+    Ok(())
+}
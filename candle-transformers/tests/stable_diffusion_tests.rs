@@ -0,0 +1,180 @@
+use candle::{Device, Result, Tensor};
+use candle_transformers::models::stable_diffusion::{
+    ddim::DDIMSchedulerConfig,
+    dpm_solver_multistep::DPMSolverMultistepSchedulerConfig,
+    pipeline::{denoise, prepare_mask, DiffusionMode},
+    schedulers::{Scheduler, SchedulerConfig},
+    uni_pc::UniPCMultistepSchedulerConfig,
+};
+
+// The cumulative alphas of the default scaled linear schedule.
+fn alphas_cumprod() -> Vec<f64> {
+    let (start, end) = (0.00085f64.sqrt(), 0.012f64.sqrt());
+    let mut acc = 1.;
+    (0..1000)
+        .map(|i| {
+            let beta = (start + (end - start) * i as f64 / 999.).powi(2);
+            acc *= 1. - beta;
+            acc
+        })
+        .collect()
+}
+
+fn max_abs_diff(a: &Tensor, b: &Tensor) -> Result<f32> {
+    (a - b)?.abs()?.flatten_all()?.max(0)?.to_scalar::<f32>()
+}
+
+// With a denoiser that predicts the exact noise for a known clean sample, the solvers follow
+// the noising trajectory and end on the sample noised to timestep 0.
+#[test]
+fn multistep_schedulers_exact_denoiser() -> Result<()> {
+    let dev = &Device::Cpu;
+    let x0 = Tensor::new(&[[0.5f32, -1.0], [0.25, 2.0]], dev)?;
+    let eps = Tensor::new(&[[1.0f32, -0.5], [0.3, -2.0]], dev)?;
+    let acp = alphas_cumprod();
+    let expected = ((&x0 * acp[0].sqrt())? + (&eps * (1. - acp[0]).sqrt())?)?;
+    let configs: Vec<Box<dyn SchedulerConfig>> = vec![
+        Box::new(DPMSolverMultistepSchedulerConfig::default()),
+        Box::new(DPMSolverMultistepSchedulerConfig {
+            solver_order: 1,
+            ..Default::default()
+        }),
+        Box::new(UniPCMultistepSchedulerConfig::default()),
+        Box::new(UniPCMultistepSchedulerConfig {
+            solver_order: 1,
+            lower_order_final: false,
+            ..Default::default()
+        }),
+    ];
+    for config in configs {
+        for n_steps in [5, 20] {
+            let mut scheduler = config.build(n_steps)?;
+            let timesteps = scheduler.timesteps().to_vec();
+            assert_eq!(timesteps.len(), n_steps);
+            assert_eq!(timesteps[0], 999);
+            let mut latents = scheduler.add_noise(&x0, eps.clone(), timesteps[0])?;
+            for &t in timesteps.iter() {
+                let noise_pred = ((&latents - (&x0 * acp[t].sqrt())?)? / (1. - acp[t]).sqrt())?;
+                latents = scheduler.step(&noise_pred, t, &latents)?;
+            }
+            let diff = max_abs_diff(&latents, &expected)?;
+            assert!(diff < 1e-3, "{config:?} {n_steps}: {diff}");
+        }
+    }
+    Ok(())
+}
+
+// The history of the multistep schedulers is reset when starting a new sample.
+#[test]
+fn multistep_scheduler_reuse() -> Result<()> {
+    let dev = &Device::Cpu;
+    let mut scheduler = UniPCMultistepSchedulerConfig::default().build(4)?;
+    let timesteps = scheduler.timesteps().to_vec();
+    let run = |scheduler: &mut dyn Scheduler| -> Result<Tensor> {
+        let mut latents = Tensor::new(&[1f32, -2., 3.], dev)?;
+        for &t in timesteps.iter() {
+            let noise_pred = (&latents * 0.5)?;
+            latents = scheduler.step(&noise_pred, t, &latents)?;
+        }
+        Ok(latents)
+    };
+    let first = run(scheduler.as_mut())?;
+    let second = run(scheduler.as_mut())?;
+    assert_eq!(first.to_vec1::<f32>()?, second.to_vec1::<f32>()?);
+    assert!(scheduler.step(&first, 3, &first).is_err());
+    Ok(())
+}
+
+// An img2img loop starts partway through the schedule, the history of the previous loops should
+// not be used for its first steps.
+#[test]
+fn multistep_scheduler_img2img() -> Result<()> {
+    let dev = &Device::Cpu;
+    let init_latents = Tensor::new(&[[0.5f32, -1.0], [0.25, 2.0]], dev)?;
+    let noise = Tensor::new(&[[1.0f32, -0.5], [0.3, -2.0]], dev)?;
+    let configs: Vec<Box<dyn SchedulerConfig>> = vec![
+        Box::new(DPMSolverMultistepSchedulerConfig::default()),
+        Box::new(UniPCMultistepSchedulerConfig::default()),
+    ];
+    for config in configs {
+        let img2img = DiffusionMode::ImageToImage {
+            init_latents: init_latents.clone(),
+            strength: 0.5,
+        };
+        let predict = |xs: &Tensor, _| xs * 0.5;
+        let mut fresh = config.build(10)?;
+        let expected = denoise(fresh.as_mut(), &img2img, &noise, predict)?;
+
+        let mut scheduler = config.build(10)?;
+        let text2img = DiffusionMode::TextToImage;
+        denoise(scheduler.as_mut(), &text2img, &noise, predict)?;
+        let latents = denoise(scheduler.as_mut(), &img2img, &noise, predict)?;
+        assert_eq!(max_abs_diff(&latents, &expected)?, 0., "{config:?}");
+
+        // Without the pipeline, the history is also cleared when the steps are not consecutive.
+        let mut scheduler = config.build(10)?;
+        let timesteps = scheduler.timesteps().to_vec();
+        let run = |scheduler: &mut dyn Scheduler, start: usize| -> Result<Tensor> {
+            let mut latents = noise.clone();
+            for &t in timesteps[start..].iter() {
+                latents = scheduler.step(&predict(&latents, t)?, t, &latents)?;
+            }
+            Ok(latents)
+        };
+        let expected = run(config.build(10)?.as_mut(), 5)?;
+        run(scheduler.as_mut(), 0)?;
+        let latents = run(scheduler.as_mut(), 5)?;
+        assert_eq!(max_abs_diff(&latents, &expected)?, 0., "{config:?}");
+    }
+    Ok(())
+}
+
+#[test]
+fn img2img_and_inpainting() -> Result<()> {
+    let dev = &Device::Cpu;
+    let init_latents = Tensor::arange(0f32, 16., dev)?.reshape((1, 1, 4, 4))?;
+    let noise = Tensor::ones((1, 1, 4, 4), candle::DType::F32, dev)?;
+    let config = DDIMSchedulerConfig::default();
+
+    // With a zero strength, the init latents are returned without any denoising step.
+    let mut scheduler = config.build(10)?;
+    let mode = DiffusionMode::ImageToImage {
+        init_latents: init_latents.clone(),
+        strength: 0.,
+    };
+    let mut calls = 0;
+    let latents = denoise(scheduler.as_mut(), &mode, &noise, |xs, _| {
+        calls += 1;
+        xs.zeros_like()
+    })?;
+    assert_eq!(calls, 0);
+    assert_eq!(max_abs_diff(&latents, &init_latents)?, 0.);
+
+    // With a strength of 0.5, only the second half of the schedule is run.
+    let mode = DiffusionMode::ImageToImage {
+        init_latents: init_latents.clone(),
+        strength: 0.5,
+    };
+    let mut timesteps = vec![];
+    denoise(scheduler.as_mut(), &mode, &noise, |xs, t| {
+        timesteps.push(t);
+        xs.zeros_like()
+    })?;
+    assert_eq!(timesteps, scheduler.timesteps()[5..].to_vec());
+
+    // The left half of the mask is repainted, the right half is kept from the init latents.
+    let mask = Tensor::new(&[[1f32, 0.], [1., 0.]], dev)?;
+    let mask = prepare_mask(&mask, 4, 4, &init_latents)?;
+    assert_eq!(mask.dims(), &[1, 1, 4, 4]);
+    let mode = DiffusionMode::Inpainting {
+        init_latents: init_latents.clone(),
+        mask,
+        strength: 1.,
+    };
+    let latents = denoise(scheduler.as_mut(), &mode, &noise, |xs, _| xs.zeros_like())?;
+    let kept = latents.narrow(3, 2, 2)?;
+    assert_eq!(max_abs_diff(&kept, &init_latents.narrow(3, 2, 2)?)?, 0.);
+    let repainted = latents.narrow(3, 0, 2)?;
+    assert!(max_abs_diff(&repainted, &init_latents.narrow(3, 0, 2)?)? > 0.1);
+    Ok(())
+}
@@ -0,0 +1,96 @@
+use candle::{DType, Result};
+use candle_transformers::tokenization::{
+    ByteTokenizer, CharTokenizer, PaddingSide, TokenizedBatch, Truncation,
+};
+
+#[test]
+fn padding() -> Result<()> {
+    let sequences = [vec![5u32, 6, 7], vec![8]];
+    let batch = TokenizedBatch::builder(0).build(&sequences)?;
+    assert_eq!(batch.input_ids.to_vec2::<u32>()?, [[5, 6, 7], [8, 0, 0]]);
+    assert_eq!(
+        batch.attention_mask.to_vec2::<u32>()?,
+        [[1, 1, 1], [1, 0, 0]]
+    );
+    assert_eq!(batch.position_ids.to_vec2::<u32>()?, [[0, 1, 2], [0, 0, 0]]);
+    assert_eq!(batch.last_token_indexes()?, [2, 0]);
+
+    let batch = TokenizedBatch::builder(9)
+        .padding_side(PaddingSide::Left)
+        .pad_to_multiple_of(4)
+        .build(&sequences)?;
+    assert_eq!(
+        batch.input_ids.to_vec2::<u32>()?,
+        [[9, 5, 6, 7], [9, 9, 9, 8]]
+    );
+    assert_eq!(
+        batch.attention_mask.to_vec2::<u32>()?,
+        [[0, 1, 1, 1], [0, 0, 0, 1]]
+    );
+    assert_eq!(
+        batch.position_ids.to_vec2::<u32>()?,
+        [[0, 0, 1, 2], [0, 0, 0, 0]]
+    );
+    assert_eq!(batch.lengths, [3, 1]);
+    assert_eq!(batch.last_token_indexes()?, [3, 3]);
+
+    let mask = batch
+        .causal_mask(DType::F32)?
+        .squeeze(1)?
+        .to_vec3::<f32>()?;
+    let inf = f32::NEG_INFINITY;
+    assert_eq!(
+        mask[1],
+        [
+            [0., inf, inf, inf],
+            [inf, 0., inf, inf],
+            [inf, inf, 0., inf],
+            [inf, inf, inf, 0.]
+        ]
+    );
+    assert_eq!(mask[0][3], [inf, 0., 0., 0.]);
+
+    let empty: [Vec<u32>; 2] = [vec![], vec![]];
+    let batch = TokenizedBatch::builder(0)
+        .padding_side(PaddingSide::Left)
+        .build(&empty)?;
+    assert!(batch.last_token_indexes().is_err());
+    Ok(())
+}
+
+#[test]
+fn truncation() -> Result<()> {
+    let sequences = [vec![1u32, 2, 3, 4, 5], vec![6, 7]];
+    let builder = TokenizedBatch::builder(0).max_len(3);
+    let batch = builder.build(&sequences)?;
+    assert_eq!(batch.input_ids.to_vec2::<u32>()?, [[1, 2, 3], [6, 7, 0]]);
+    let batch = builder
+        .clone()
+        .truncation(Truncation::KeepEnd)
+        .build(&sequences)?;
+    assert_eq!(batch.input_ids.to_vec2::<u32>()?, [[3, 4, 5], [6, 7, 0]]);
+    assert!(builder
+        .truncation(Truncation::Fail)
+        .build(&sequences)
+        .is_err());
+    Ok(())
+}
+
+#[test]
+fn byte_and_char_tokenizers() -> Result<()> {
+    let tokenizer = ByteTokenizer::byt5();
+    let tokens = tokenizer.encode("hé");
+    assert_eq!(tokens, [107, 198, 172]);
+    assert_eq!(tokenizer.decode(&[0, 107, 198, 172, 1]), "hé");
+    assert_eq!(tokenizer.vocab_size(), 259);
+
+    let tokenizer = CharTokenizer::from_text("hello");
+    assert_eq!(tokenizer.vocab_size(), 4);
+    let tokens = tokenizer.encode("hole")?;
+    assert_eq!(tokens, [1, 3, 2, 0]);
+    assert_eq!(tokenizer.decode(&tokens), "hole");
+    assert!(tokenizer.encode("x").is_err());
+    let tokenizer = CharTokenizer::new(&['<', 'a', 'b'], Some(0));
+    assert_eq!(tokenizer.encode("abc")?, [1, 2, 0]);
+    Ok(())
+}
@@ -0,0 +1,101 @@
+use candle::{DType, Device, Module, Result, Tensor};
+use candle_nn::{VarBuilder, VarMap};
+use candle_transformers::models::llava::{config::LLaVAConfig, LLaVA};
+use candle_transformers::models::{llama, siglip};
+
+#[test]
+fn siglip_vision_model() -> Result<()> {
+    let cfg = tiny_siglip_config();
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu);
+    let model = siglip::VisionModel::new(&cfg, vb)?;
+    let pixel_values = Tensor::randn(0f32, 1., (2, 3, 8, 8), &Device::Cpu)?;
+    let xs = model.forward(&pixel_values)?;
+    assert_eq!(xs.dims(), [2, cfg.num_patches(), 16]);
+    let hidden_states = model.forward_hidden_states(&pixel_values)?;
+    assert_eq!(hidden_states.len(), 3);
+    assert_eq!(hidden_states[2].dims(), [2, 4, 16]);
+    Ok(())
+}
+
+fn tiny_siglip_config() -> siglip::VisionConfig {
+    siglip::VisionConfig {
+        hidden_size: 16,
+        intermediate_size: 32,
+        num_hidden_layers: 2,
+        num_attention_heads: 4,
+        num_channels: 3,
+        image_size: 8,
+        patch_size: 4,
+        hidden_act: candle_nn::Activation::GeluPytorchTanh,
+        layer_norm_eps: 1e-6,
+    }
+}
+
+fn tiny_llava_config() -> LLaVAConfig {
+    LLaVAConfig {
+        architectures: vec!["LlavaForConditionalGeneration".to_string()],
+        bos_token_id: 1,
+        eos_token_id: 2,
+        hidden_size: 32,
+        image_aspect_ratio: "square".to_string(),
+        image_crop_resolution: 8,
+        image_grid_pinpoints: vec![],
+        image_split_resolution: 8,
+        intermediate_size: 64,
+        max_position_embeddings: 64,
+        mm_hidden_size: 16,
+        mm_patch_merge_type: "flat".to_string(),
+        mm_projector_type: "mlp2x_gelu".to_string(),
+        mm_use_im_start_end: false,
+        mm_vision_select_feature: "patch".to_string(),
+        mm_vision_select_layer: -2,
+        mm_vision_tower: None,
+        model_type: "llava".to_string(),
+        num_attention_heads: 4,
+        num_hidden_layers: 2,
+        num_key_value_heads: 2,
+        pad_token_id: 0,
+        rms_norm_eps: 1e-5,
+        rope_theta: 10_000.,
+        tokenizer_model_max_length: None,
+        torch_dtype: "float32".to_string(),
+        use_cache: true,
+        vocab_size: 50,
+        image_token_index: -200,
+        hf: true,
+    }
+}
+
+// The image placeholder is replaced by the projected SigLIP patch features.
+#[test]
+fn llava_siglip() -> Result<()> {
+    let dev = &Device::Cpu;
+    let config = tiny_llava_config();
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, dev);
+    let model = LLaVA::load_siglip(vb, &config, &tiny_siglip_config())?;
+    let image = Tensor::randn(0f32, 1., (1, 3, 8, 8), dev)?;
+    let input_ids = Tensor::new(&[[1i64, -200, 5, 6]], dev)?;
+    let embeds = model.prepare_inputs_labels_for_multimodal(&input_ids, &[image.clone()], &[])?;
+    assert_eq!(embeds.dims(), [1, 7, 32]);
+
+    let features = model.encode_images(&image)?.squeeze(0)?;
+    assert_eq!(features.dims(), [4, 32]);
+    let text = model.llama.embed(&Tensor::new(&[1i64, 5, 6], dev)?)?;
+    let expected = Tensor::cat(
+        &[&text.narrow(0, 0, 1)?, &features, &text.narrow(0, 1, 2)?],
+        0,
+    )?;
+    let diff = (embeds.squeeze(0)? - expected)?
+        .abs()?
+        .flatten_all()?
+        .max(0)?
+        .to_scalar::<f32>()?;
+    assert_eq!(diff, 0.);
+
+    let mut cache = llama::Cache::new(false, DType::F32, &config.to_llama_config(), dev)?;
+    let logits = model.forward(&embeds, 0, &mut cache)?;
+    assert_eq!(logits.dims(), [1, 50]);
+    Ok(())
+}
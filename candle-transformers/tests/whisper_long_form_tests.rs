@@ -0,0 +1,178 @@
+use candle::{Device, Result, Tensor};
+use candle_transformers::models::whisper::long_form::{
+    pack_windows, speech_regions, EnergyVad, LongFormConfig, LongFormTranscriber, SpeakerEncoder,
+    VadConfig,
+};
+use candle_transformers::models::whisper::streaming::{SpecialTokens, WhisperModel};
+use candle_transformers::models::whisper::{Config, SAMPLE_RATE};
+
+const TOKENS: SpecialTokens = SpecialTokens {
+    eot: 20,
+    sot: 21,
+    translate: 24,
+    transcribe: 25,
+    start_of_prev: 26,
+    no_speech: 27,
+    no_timestamps: 28,
+};
+const EN: u32 = 22;
+const VOCAB: usize = 29 + 1501;
+
+fn ts(centis: u32) -> u32 {
+    TOKENS.timestamp_begin() + centis / 2
+}
+
+// A fake model predicting <|0.00|> 3 <|1.00|><|1.00|> 4 <|endoftext|> for every sequence of
+// the batch, so the second segment of each window is closed by the end of the window.
+struct BatchedScriptedModel {
+    config: Config,
+    batch_sizes: Vec<usize>,
+}
+
+impl BatchedScriptedModel {
+    fn new() -> Self {
+        let config = Config {
+            num_mel_bins: 80,
+            max_source_positions: 1500,
+            d_model: 4,
+            encoder_attention_heads: 1,
+            encoder_layers: 1,
+            vocab_size: VOCAB,
+            max_target_positions: 448,
+            decoder_attention_heads: 1,
+            decoder_layers: 1,
+            suppress_tokens: vec![],
+        };
+        Self {
+            config,
+            batch_sizes: vec![],
+        }
+    }
+
+    fn next_token(prefix: &[u32]) -> u32 {
+        let script = [ts(0), 3, ts(100), ts(100), 4, TOKENS.eot];
+        match prefix.iter().position(|&t| t == TOKENS.transcribe) {
+            None => TOKENS.transcribe,
+            Some(p) => *script.get(prefix.len() - p - 1).unwrap_or(&TOKENS.eot),
+        }
+    }
+}
+
+impl WhisperModel for BatchedScriptedModel {
+    fn config(&self) -> &Config {
+        &self.config
+    }
+
+    fn encoder_forward(&mut self, x: &Tensor, _flush: bool) -> Result<Tensor> {
+        self.batch_sizes.push(x.dim(0)?);
+        Tensor::zeros((x.dim(0)?, 1500, 4), x.dtype(), x.device())
+    }
+
+    fn decoder_forward(&mut self, x: &Tensor, _xa: &Tensor, _flush: bool) -> Result<Tensor> {
+        let (b_size, seq_len) = x.dims2()?;
+        let mut ys = vec![0f32; b_size * seq_len * VOCAB];
+        for (b, tokens) in x.to_vec2::<u32>()?.iter().enumerate() {
+            for i in 0..seq_len {
+                let next = Self::next_token(&tokens[..=i]);
+                ys[(b * seq_len + i) * VOCAB + next as usize] = 20.;
+            }
+        }
+        Tensor::from_vec(ys, (b_size, seq_len, VOCAB), x.device())
+    }
+
+    fn decoder_final_linear(&self, x: &Tensor) -> Result<Tensor> {
+        Ok(x.clone())
+    }
+}
+
+// Tells the loud speaker apart from the quiet one.
+struct AmplitudeEncoder;
+
+impl SpeakerEncoder for AmplitudeEncoder {
+    fn embed(&mut self, samples: &[f32]) -> Result<Vec<f32>> {
+        let max = samples.iter().fold(0f32, |m, x| m.max(x.abs()));
+        Ok(if max > 0.4 {
+            vec![1., 0.1]
+        } else {
+            vec![0.1, 1.]
+        })
+    }
+}
+
+fn tone(pcm: &mut [f32], start: f64, end: f64, amplitude: f32) {
+    let sr = SAMPLE_RATE as f64;
+    for (i, x) in pcm[(start * sr) as usize..(end * sr) as usize]
+        .iter_mut()
+        .enumerate()
+    {
+        *x = amplitude * (i as f32 * 440. * 2. * std::f32::consts::PI / SAMPLE_RATE as f32).sin()
+    }
+}
+
+#[test]
+fn vad_regions() {
+    let cfg = VadConfig {
+        threshold: 0.5,
+        min_speech_duration: 0.15,
+        min_silence_duration: 0.3,
+        speech_pad: 0.05,
+    };
+    let mut probs = vec![0f32; 24];
+    for i in [2, 3, 4, 11, 20, 21] {
+        probs[i] = 0.9
+    }
+    // Between the two thresholds, the region goes on.
+    probs[22] = 0.4;
+    let regions = speech_regions(&probs, 1600, 24 * 1600, &cfg);
+    assert_eq!(regions, [2400..8800, 31200..37600]);
+
+    let windows = pack_windows(&[0..10, 15..20, 30..55, 60..62], 20);
+    assert_eq!(windows, [0..20, 30..50, 50..62]);
+}
+
+#[test]
+fn long_form_transcription() -> Result<()> {
+    let mut pcm = vec![0f32; 80 * SAMPLE_RATE];
+    tone(&mut pcm, 1., 3., 0.5);
+    tone(&mut pcm, 40., 42., 0.3);
+    tone(&mut pcm, 70., 71., 0.5);
+    let config = LongFormConfig {
+        language: Some(EN),
+        batch_size: 2,
+        ..Default::default()
+    };
+    let filters = vec![0f32; 80 * 201];
+    let model = BatchedScriptedModel::new();
+    let mut transcriber = LongFormTranscriber::new(model, TOKENS, filters, config, &Device::Cpu)?
+        .with_vad(EnergyVad::default())
+        .with_speaker_encoder(AmplitudeEncoder);
+
+    let windows = transcriber.windows(&pcm)?;
+    assert_eq!(windows.len(), 3);
+    let segments = transcriber.transcribe(&pcm)?;
+    // Three windows decoded with a batch size of two.
+    assert_eq!(transcriber.model().batch_sizes, [2, 1]);
+    assert_eq!(segments.len(), 6);
+    for (s, w) in segments.chunks(2).zip(windows.iter()) {
+        let start = w.start as f64 / SAMPLE_RATE as f64;
+        let end = w.end as f64 / SAMPLE_RATE as f64;
+        assert_eq!(s[0].segment.tokens, [3]);
+        assert_eq!(s[1].segment.tokens, [4]);
+        assert!((s[0].segment.start - start).abs() < 1e-6);
+        assert!((s[0].segment.end - (start + 1.)).abs() < 1e-6);
+        assert!((s[1].segment.end - end).abs() < 1e-6);
+    }
+    assert!((segments[0].segment.start - 0.8).abs() < 0.05);
+    assert!((segments[5].segment.end - 71.2).abs() < 0.05);
+    for pair in segments.windows(2) {
+        assert!(pair[0].segment.end <= pair[1].segment.start)
+    }
+
+    // The last segment is too short to be attributed to a speaker.
+    let speakers = segments.iter().map(|s| s.speaker).collect::<Vec<_>>();
+    assert_eq!(
+        speakers,
+        [Some(0), Some(0), Some(1), Some(1), Some(0), None]
+    );
+    Ok(())
+}
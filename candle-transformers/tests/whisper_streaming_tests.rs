@@ -0,0 +1,214 @@
+use candle::{Device, Result, Tensor};
+use candle_transformers::models::whisper::streaming::{
+    Segment, SpecialTokens, StreamingConfig, StreamingTranscriber, TokenTiming, WhisperModel,
+};
+use candle_transformers::models::whisper::{Config, SAMPLE_RATE};
+
+const TOKENS: SpecialTokens = SpecialTokens {
+    eot: 20,
+    sot: 21,
+    translate: 24,
+    transcribe: 25,
+    start_of_prev: 26,
+    no_speech: 27,
+    no_timestamps: 28,
+};
+const EN: u32 = 22;
+const FR: u32 = 23;
+const VOCAB: usize = 29 + 1501;
+
+fn ts(centis: u32) -> u32 {
+    TOKENS.timestamp_begin() + centis / 2
+}
+
+// A fake model that always predicts the tokens of its script after the task token.
+struct ScriptedModel {
+    config: Config,
+    script: Vec<u32>,
+    last_input: Vec<u32>,
+}
+
+impl ScriptedModel {
+    // The default script is <|0.00|> 3 <|1.00|><|1.00|> 4 <|endoftext|>, so the second segment
+    // is never closed by a timestamp.
+    fn new() -> Self {
+        Self::with_script(vec![ts(0), 3, ts(100), ts(100), 4, TOKENS.eot])
+    }
+
+    fn with_script(script: Vec<u32>) -> Self {
+        let config = Config {
+            num_mel_bins: 80,
+            max_source_positions: 1500,
+            d_model: 4,
+            encoder_attention_heads: 1,
+            encoder_layers: 1,
+            vocab_size: VOCAB,
+            max_target_positions: 448,
+            decoder_attention_heads: 1,
+            decoder_layers: 1,
+            suppress_tokens: vec![],
+        };
+        Self {
+            config,
+            script,
+            last_input: vec![],
+        }
+    }
+
+    fn next_token(&self, prefix: &[u32]) -> u32 {
+        if prefix.last() == Some(&TOKENS.sot) {
+            return EN;
+        }
+        match prefix.iter().position(|&t| t == TOKENS.transcribe) {
+            None => 0,
+            Some(p) => *self.script.get(prefix.len() - p - 1).unwrap_or(&TOKENS.eot),
+        }
+    }
+}
+
+impl WhisperModel for ScriptedModel {
+    fn config(&self) -> &Config {
+        &self.config
+    }
+
+    fn encoder_forward(&mut self, x: &Tensor, _flush: bool) -> Result<Tensor> {
+        Tensor::zeros((1, 1500, 4), x.dtype(), x.device())
+    }
+
+    fn decoder_forward(&mut self, x: &Tensor, _xa: &Tensor, _flush: bool) -> Result<Tensor> {
+        let tokens = x.squeeze(0)?.to_vec1::<u32>()?;
+        let mut ys = vec![0f32; tokens.len() * VOCAB];
+        for i in 0..tokens.len() {
+            let next = self.next_token(&tokens[..=i]);
+            ys[i * VOCAB + next as usize] = 20.;
+        }
+        self.last_input = tokens.clone();
+        Tensor::from_vec(ys, (1, tokens.len(), VOCAB), x.device())
+    }
+
+    fn decoder_final_linear(&self, x: &Tensor) -> Result<Tensor> {
+        Ok(x.clone())
+    }
+}
+
+fn seconds(s: f64) -> Vec<f32> {
+    vec![0f32; (s * SAMPLE_RATE as f64) as usize]
+}
+
+#[test]
+fn streaming_windows() -> Result<()> {
+    let config = StreamingConfig {
+        language_candidates: vec![EN, FR],
+        ..Default::default()
+    };
+    let filters = vec![0f32; 80 * 201];
+    let mut st =
+        StreamingTranscriber::new(ScriptedModel::new(), TOKENS, filters, config, &Device::Cpu)?;
+    assert!(st.push(&seconds(10.))?.is_empty());
+    assert!(st.push(&seconds(10.))?.is_empty());
+    assert_eq!(st.language(), None);
+
+    // The first window is decoded once 30s are available, only the closed segment is emitted
+    // and the audio after it is kept.
+    let segments = st.push(&seconds(10.))?;
+    assert_eq!(st.language(), Some(EN));
+    assert_eq!(segments.len(), 1);
+    assert_eq!(segments[0].tokens, [3]);
+    assert_eq!((segments[0].start, segments[0].end), (0., 1.));
+    assert_eq!(st.pending_start(), 1.);
+    assert_eq!(st.pending_duration(), 29.);
+
+    // The second window starts at 1s and is prompted with the previous text.
+    let segments = st.push(&seconds(1.))?;
+    assert_eq!(segments.len(), 1);
+    assert_eq!((segments[0].start, segments[0].end), (1., 2.));
+    assert_eq!(
+        st.model().last_input[..5],
+        [TOKENS.start_of_prev, 3, TOKENS.sot, EN, TOKENS.transcribe]
+    );
+
+    // Flushing emits the unterminated segment too, up to the end of the audio.
+    let segments = st.flush()?;
+    let times = segments
+        .iter()
+        .map(|s| (s.start, s.end))
+        .collect::<Vec<_>>();
+    assert_eq!(times, [(2., 3.), (3., 31.)]);
+    assert_eq!(segments[1].tokens, [4]);
+    for s in segments.iter() {
+        for t in s.token_timings.iter() {
+            assert!(s.start <= t.start && t.start <= t.end && t.end <= s.end)
+        }
+    }
+    assert_eq!(st.pending_start(), 31.);
+    assert_eq!(st.pending_duration(), 0.);
+    Ok(())
+}
+
+#[test]
+fn streaming_open_segments() -> Result<()> {
+    let filters = vec![0f32; 80 * 201];
+    let config = StreamingConfig {
+        language: Some(EN),
+        ..Default::default()
+    };
+    let times = |segments: &[Segment]| {
+        segments
+            .iter()
+            .map(|s| (s.start, s.end, s.tokens.clone()))
+            .collect::<Vec<_>>()
+    };
+
+    // A full window without any closed segment cannot be decoded again with more audio, its
+    // text is emitted up to the end of the window.
+    let model = ScriptedModel::with_script(vec![ts(0), 3, 4, TOKENS.eot]);
+    let mut st =
+        StreamingTranscriber::new(model, TOKENS, filters.clone(), config.clone(), &Device::Cpu)?;
+    let segments = st.push(&seconds(30.))?;
+    assert_eq!(times(&segments), [(0., 30., vec![3, 4])]);
+    assert_eq!(st.pending_start(), 30.);
+
+    // Text following a closed segment without an opening timestamp starts where the previous
+    // segment ended.
+    let model = ScriptedModel::with_script(vec![ts(0), 3, ts(100), 4, TOKENS.eot]);
+    let mut st = StreamingTranscriber::new(model, TOKENS, filters, config, &Device::Cpu)?;
+    st.push(&seconds(5.))?;
+    let segments = st.flush()?;
+    assert_eq!(times(&segments), [(0., 1., vec![3]), (1., 5., vec![4])]);
+    Ok(())
+}
+
+#[test]
+fn segment_words() -> Result<()> {
+    let timing = |token, start, end| TokenTiming {
+        token,
+        start,
+        end,
+        prob: 1.,
+    };
+    let segment = Segment {
+        start: 0.,
+        end: 2.,
+        tokens: vec![3, 4, 5],
+        token_timings: vec![timing(3, 0., 0.4), timing(4, 0.4, 1.2), timing(5, 1.2, 1.5)],
+        avg_logprob: 0.,
+        no_speech_prob: 0.,
+    };
+    let decode = |tokens: &[u32]| -> Result<String> {
+        Ok(tokens
+            .iter()
+            .map(|t| match t {
+                3 => " hello",
+                4 => " wor",
+                _ => "ld",
+            })
+            .collect())
+    };
+    let words = segment.words(decode)?;
+    let words = words
+        .iter()
+        .map(|w| (w.text.as_str(), w.start, w.end))
+        .collect::<Vec<_>>();
+    assert_eq!(words, [("hello", 0., 0.4), ("world", 0.4, 1.5)]);
+    Ok(())
+}
@@ -103,6 +103,13 @@ impl Format {
     }
 }
 
+#[derive(ValueEnum, Debug, Clone, Copy)]
+enum DeltaKind {
+    Dense,
+    Sparse,
+    LowRank,
+}
+
 #[derive(Subcommand, Debug, Clone)]
 enum Command {
     Ls {
@@ -160,6 +167,75 @@ enum Command {
         #[arg(long)]
         out_file: std::path::PathBuf,
     },
+
+    /// Computes the difference between two safetensors checkpoints with the same architecture.
+    Delta {
+        /// The base checkpoint, in safetensors format.
+        base: std::path::PathBuf,
+
+        /// The fine-tuned checkpoint, in safetensors format.
+        target: std::path::PathBuf,
+
+        /// The output file, in safetensors format.
+        #[arg(long)]
+        out_file: std::path::PathBuf,
+
+        /// The representation used for the deltas.
+        #[arg(long, value_enum, default_value_t = DeltaKind::Sparse)]
+        kind: DeltaKind,
+
+        /// For sparse deltas, the differences with an absolute value below this threshold are
+        /// dropped.
+        #[arg(long, default_value_t = 0.)]
+        threshold: f64,
+
+        /// For low-rank deltas, the rank of the approximation.
+        #[arg(long, default_value_t = 16)]
+        rank: usize,
+    },
+
+    /// Applies a delta produced by the `delta` command to a base checkpoint.
+    ApplyDelta {
+        /// The base checkpoint, in safetensors format.
+        base: std::path::PathBuf,
+
+        /// The delta, in safetensors format.
+        delta: std::path::PathBuf,
+
+        /// The output file, in safetensors format.
+        #[arg(long)]
+        out_file: std::path::PathBuf,
+    },
+
+    /// Converts a safetensors, gguf, or pth checkpoint one tensor at a time.
+    Convert {
+        /// The input file(s), multiple files can only be used for sharded safetensors.
+        in_file: Vec<std::path::PathBuf>,
+
+        /// The output file, in safetensors or gguf format.
+        #[arg(long)]
+        out_file: std::path::PathBuf,
+
+        /// Casts the float tensors to this dtype, e.g. f16 or bf16.
+        #[arg(long)]
+        dtype: Option<candle::DType>,
+
+        /// Quantizes the 2d tensors, only for gguf outputs.
+        #[arg(long, value_enum)]
+        quantization: Option<Quantization>,
+
+        /// Renames the tensors, `from=to` replaces all the occurrences of `from`.
+        #[arg(long)]
+        rename: Vec<String>,
+
+        /// Only keeps the tensors matching one of these patterns, `*` matches any sequence.
+        #[arg(long)]
+        include: Vec<String>,
+
+        /// Drops the tensors matching one of these patterns.
+        #[arg(long)]
+        exclude: Vec<String>,
+    },
 }
 
 #[derive(Parser, Debug, Clone)]
@@ -299,17 +375,28 @@ fn run_ls(
             }
         }
         Format::Safetensors => {
-            let tensors = unsafe { candle::safetensors::MmapedSafetensors::new(file)? };
-            let mut tensors = tensors.tensors();
-            tensors.sort_by(|a, b| a.0.cmp(&b.0));
-            for (name, view) in tensors.iter() {
-                let dtype = view.dtype();
-                let dtype = match candle::DType::try_from(dtype) {
-                    Ok(dtype) => format!("{dtype:?}"),
-                    Err(_) => format!("{dtype:?}"),
+            let inspector = unsafe { candle::safetensors::SafetensorsInspector::new(file)? };
+            for info in inspector.tensors()?.iter() {
+                let dtype = match info.candle_dtype() {
+                    Some(dtype) => format!("{dtype:?}"),
+                    None => format!("{:?}", info.dtype),
                 };
-                let shape = view.shape();
-                println!("{name}: [{shape:?}; {dtype}]")
+                let mut extra = String::new();
+                if let Some(quantization) = info.quantization {
+                    extra.push_str(&format!(" {quantization:?}"))
+                }
+                if info.compressed {
+                    extra.push_str(" zstd")
+                }
+                println!("{}: [{:?}; {dtype}]{extra}", info.name, info.shape)
+            }
+            if verbose {
+                let mut metadata = inspector.metadata().into_iter().collect::<Vec<_>>();
+                metadata.sort();
+                for (key, value) in metadata.iter() {
+                    println!("__metadata__ {key}: {value}")
+                }
+                println!("{} parameters", inspector.elem_count()?)
             }
         }
         Format::Pth => {
@@ -420,6 +507,61 @@ fn run_dequantize(
     Ok(())
 }
 
+fn run_delta(
+    base: std::path::PathBuf,
+    target: std::path::PathBuf,
+    out_file: std::path::PathBuf,
+    method: candle_nn::delta::DeltaMethod,
+    device: &Device,
+) -> Result<()> {
+    use candle_nn::delta::{CheckpointDelta, Delta};
+    let base = unsafe { candle::safetensors::MmapedSafetensors::new(base)? };
+    let target = unsafe { candle::safetensors::MmapedSafetensors::new(target)? };
+    let mut names = target
+        .tensors()
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect::<Vec<_>>();
+    names.sort();
+    let mut delta = CheckpointDelta::new();
+    let mut full_size = 0;
+    for name in names.iter() {
+        let target = target.load(name, device)?;
+        full_size += target.elem_count() * target.dtype().size_in_bytes();
+        if let Some(d) = Delta::compute(&base.load(name, device)?, &target, method)? {
+            println!("  {name}: {} bytes", d.size_in_bytes());
+            delta.insert(name, d)
+        }
+    }
+    println!(
+        "patched {}/{} tensors, {} bytes for {full_size} bytes of weights",
+        delta.len(),
+        names.len(),
+        delta.size_in_bytes()
+    );
+    delta.save(out_file)
+}
+
+fn run_apply_delta(
+    base: std::path::PathBuf,
+    delta: std::path::PathBuf,
+    out_file: std::path::PathBuf,
+    device: &Device,
+) -> Result<()> {
+    let delta = candle_nn::delta::CheckpointDelta::load(delta, device)?;
+    let base = unsafe { candle::safetensors::MmapedSafetensors::new(base)? };
+    let mut tensors = std::collections::HashMap::new();
+    for (name, _) in base.tensors() {
+        let tensor = base.load(&name, device)?;
+        let tensor = delta.apply(&name, &tensor)?;
+        tensors.insert(name, tensor);
+    }
+    if let Some(name) = delta.deltas().keys().find(|n| !tensors.contains_key(*n)) {
+        candle::bail!("cannot find tensor {name} in the base checkpoint")
+    }
+    candle::safetensors::save(&tensors, out_file)
+}
+
 fn run_quantize(
     in_files: &[std::path::PathBuf],
     out_file: std::path::PathBuf,
@@ -477,6 +619,39 @@ fn run_quantize(
     Ok(())
 }
 
+fn run_convert(
+    in_files: &[std::path::PathBuf],
+    out_file: std::path::PathBuf,
+    dtype: Option<candle::DType>,
+    quantization: Option<Quantization>,
+    renames: Vec<String>,
+    include: Vec<String>,
+    exclude: Vec<String>,
+) -> Result<()> {
+    let renames = renames
+        .iter()
+        .map(|r| match r.split_once('=') {
+            Some((from, to)) => Ok((from.to_string(), to.to_string())),
+            None => candle::bail!("unexpected rename {r}, expected from=to"),
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let options = candle::convert::ConvertOptions {
+        dtype,
+        quantize: quantization.map(|q| q.dtype()),
+        renames,
+        include,
+        exclude,
+    };
+    let report = candle::convert::convert(in_files, out_file, &options)?;
+    println!(
+        "wrote {} tensors, {} quantized, {} excluded",
+        report.written,
+        report.quantized,
+        report.excluded.len()
+    );
+    Ok(())
+}
+
 fn main() -> anyhow::Result<()> {
     let args = Args::parse();
     let device = Device::Cpu;
@@ -508,6 +683,43 @@ fn main() -> anyhow::Result<()> {
             mode,
         } => run_quantize(&in_file, out_file, quantization, mode, &device)?,
         Command::Dequantize { in_file, out_file } => run_dequantize(in_file, out_file, &device)?,
+        Command::Delta {
+            base,
+            target,
+            out_file,
+            kind,
+            threshold,
+            rank,
+        } => {
+            let method = match kind {
+                DeltaKind::Dense => candle_nn::delta::DeltaMethod::Dense,
+                DeltaKind::Sparse => candle_nn::delta::DeltaMethod::Sparse { threshold },
+                DeltaKind::LowRank => candle_nn::delta::DeltaMethod::LowRank { rank },
+            };
+            run_delta(base, target, out_file, method, &device)?
+        }
+        Command::ApplyDelta {
+            base,
+            delta,
+            out_file,
+        } => run_apply_delta(base, delta, out_file, &device)?,
+        Command::Convert {
+            in_file,
+            out_file,
+            dtype,
+            quantization,
+            rename,
+            include,
+            exclude,
+        } => run_convert(
+            &in_file,
+            out_file,
+            dtype,
+            quantization,
+            rename,
+            include,
+            exclude,
+        )?,
     }
     Ok(())
 }